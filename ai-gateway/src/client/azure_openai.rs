@@ -44,6 +44,7 @@ impl AzureOpenAIClient {
             .map(|v| {
                 Model::new(client_name, &v.name)
                     .set_max_input_tokens(v.max_input_tokens)
+                    .set_max_message_tokens(v.max_message_tokens)
                     .set_capabilities(v.capabilities)
                     .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
             })
@@ -24,6 +24,10 @@ const MODELS: [(&str, usize, &str); 3] = [
 
 const TOKENS_COUNT_FACTORS: TokensCountFactors = (5, 2);
 
+// largest single message sent to the API, a longer message is rejected even when the context
+// has room for it.
+const MAX_MESSAGE_TOKENS: usize = 32000;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClaudeConfig {
     pub name: Option<String>,
@@ -69,6 +73,7 @@ impl ClaudeClient {
                 Model::new(client_name, name)
                     .set_capabilities(capabilities.into())
                     .set_max_input_tokens(Some(max_input_tokens))
+                    .set_max_message_tokens(Some(MAX_MESSAGE_TOKENS))
                     .set_tokens_count_factors(TOKENS_COUNT_FACTORS)
             })
             .collect()
@@ -12,6 +12,8 @@ pub struct Model {
     pub client_name: String,
     pub name: String,
     pub max_input_tokens: Option<usize>,
+    // largest single message the provider accepts, however much of the context is left.
+    pub max_message_tokens: Option<usize>,
     pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
     pub tokens_count_factors: TokensCountFactors,
     pub capabilities: ModelCapabilities,
@@ -30,6 +32,7 @@ impl Model {
             name: name.into(),
             extra_fields: None,
             max_input_tokens: None,
+            max_message_tokens: None,
             tokens_count_factors: Default::default(),
             capabilities: ModelCapabilities::Text,
         }
@@ -91,6 +94,14 @@ impl Model {
         self
     }
 
+    pub fn set_max_message_tokens(mut self, max_message_tokens: Option<usize>) -> Self {
+        match max_message_tokens {
+            None | Some(0) => self.max_message_tokens = None,
+            _ => self.max_message_tokens = max_message_tokens,
+        }
+        self
+    }
+
     pub fn set_tokens_count_factors(mut self, tokens_count_factors: TokensCountFactors) -> Self {
         self.tokens_count_factors = tokens_count_factors;
         self
@@ -162,6 +173,7 @@ impl Model {
 pub struct ModelConfig {
     pub name: String,
     pub max_input_tokens: Option<usize>,
+    pub max_message_tokens: Option<usize>,
     pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
     #[serde(deserialize_with = "deserialize_capabilities")]
     #[serde(default = "default_capabilities")]
@@ -46,6 +46,7 @@ impl OpenAICompatibleClient {
                 Model::new(client_name, &v.name)
                     .set_capabilities(v.capabilities)
                     .set_max_input_tokens(v.max_input_tokens)
+                    .set_max_message_tokens(v.max_message_tokens)
                     .set_extra_fields(v.extra_fields.clone())
                     .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
             })
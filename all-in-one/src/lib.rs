@@ -0,0 +1,110 @@
+use std::env;
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::local_services::{self, LocalRequest, WarpService};
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::path::Tail;
+use warp::{Filter, Rejection, Reply};
+
+// Every service is reachable under its name, e.g. `POST /coordinator/suggest` or
+// `POST /code-search/symbols`, and called by the others as `local://<name>`.
+pub const COORDINATOR: &str = "coordinator";
+pub const CODE_SEARCH: &str = "code-search";
+pub const CODE_UNDERSTANDING: &str = "code-understanding";
+
+pub const DEFAULT_PORT: u16 = 3000;
+
+/// Points the URLs the services use to reach each other to the co-hosted services,
+/// call it after the env file was loaded and before the service configs are read.
+pub fn use_local_service_urls() {
+    let code_search_url = local_services::local_url(CODE_SEARCH);
+    env::set_var("CODE_SEARCH_URL", &code_search_url);
+    env::set_var("SEARCH_SERVER_URL", &code_search_url);
+    env::set_var(
+        "CODE_UNDERSTANDING_URL",
+        local_services::local_url(CODE_UNDERSTANDING),
+    );
+}
+
+/// Hosts the route tree of a service under `/<name>`, for outside callers and the other services alike.
+pub fn host<F>(name: &str, routes: F)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply + Send,
+{
+    local_services::register(name, Arc::new(WarpService::new(routes)));
+}
+
+/// Reads the configuration of every service from the loaded env and hosts them,
+/// code search first since code understanding calls it on startup.
+pub async fn init() -> Result<()> {
+    use_local_service_urls();
+
+    let search_state = Arc::new(code_search::config::initialize_from_env().await?);
+    host(CODE_SEARCH, code_search::routes::search_routes(search_state));
+
+    code_understanding::set_config(code_understanding::config::config_from_env());
+    let understanding_state = Arc::new(code_understanding::init_state().await?);
+    host(
+        CODE_UNDERSTANDING,
+        code_understanding::routes::code_retrieve(understanding_state),
+    );
+
+    coordinator::set_config(coordinator::config_from_env());
+    host(COORDINATOR, coordinator::routes::coordinator());
+
+    Ok(())
+}
+
+/// Forwards `/<name>/<path>` to the hosted service `name`, 404 for services that aren't hosted.
+/// Responses are buffered, streamed responses like the embeddings export arrive in one piece.
+pub fn routes() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let query = warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify();
+    warp::path::param::<String>()
+        .and(warp::path::tail())
+        .and(query)
+        .and(warp::method())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .and_then(forward)
+}
+
+async fn forward(
+    name: String,
+    tail: Tail,
+    query: String,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response<Vec<u8>>, Rejection> {
+    let mut path_and_query = format!("/{}", tail.as_str());
+    if !query.is_empty() {
+        path_and_query.push('?');
+        path_and_query.push_str(&query);
+    }
+    let request = LocalRequest {
+        method: method.to_string(),
+        path_and_query,
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: body.to_vec(),
+    };
+
+    let response = local_services::dispatch(&name, request)
+        .await
+        .map_err(|_| warp::reject::not_found())?;
+    let mut builder = Response::builder()
+        .status(StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+    for (name, value) in &response.headers {
+        builder = builder.header(name, value);
+    }
+    builder
+        .body(response.body)
+        .map_err(|_| warp::reject::reject())
+}
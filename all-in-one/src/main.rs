@@ -0,0 +1,73 @@
+use all_in_one::DEFAULT_PORT;
+use common::shutdown;
+use log::{error, info};
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    // Parse command line arguments
+    let args: Vec<String> = env::args().collect();
+    let mut env_file: Option<String> = None;
+    let mut port = DEFAULT_PORT;
+
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (args[i].as_str(), value) {
+            ("--env-file", Some(value)) => env_file = Some(value.clone()),
+            ("--port", Some(value)) => {
+                port = value.parse().unwrap_or_else(|_| {
+                    error!("--port must be a port number");
+                    std::process::exit(1);
+                })
+            }
+            ("--env-file" | "--port", None) => {
+                error!("{} requires a value", args[i]);
+                std::process::exit(1);
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        i += 2;
+    }
+
+    // one env file holds the configuration of every hosted service.
+    let loaded = match &env_file {
+        Some(path) => dotenv::from_filename(path).map(|_| ()),
+        None => dotenv::dotenv().map(|_| ()),
+    };
+    if let Err(err) = loaded {
+        error!("Failed to load the env file: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = common::auth::init_key_store() {
+        error!("Failed to initialize the API key store: {}", err);
+        std::process::exit(1);
+    }
+
+    // traces are only exported when an OTLP endpoint is configured in the env.
+    if let Err(err) = common::telemetry::init("all-in-one") {
+        error!("Failed to initialize tracing: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = all_in_one::init().await {
+        error!("Failed to initialize the services: {}", err);
+        std::process::exit(1);
+    }
+
+    let shutdown = shutdown::global();
+    shutdown.listen_for_signals();
+
+    let (addr, server) = warp::serve(all_in_one::routes())
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], port), shutdown.wait());
+    info!("Started web server on http://{}", addr);
+
+    shutdown.drain(server, shutdown::drain_timeout()).await;
+    common::telemetry::shutdown();
+    info!("All-in-one shut down");
+}
@@ -0,0 +1,151 @@
+// Boots the all-in-one server with the real coordinator, a mock LLM and mocked code search and
+// code understanding services in place of the stores, then runs a conversation through it.
+// The conversation graph lives in redis, run with a local one:
+// `REDIS_URL=redis://127.0.0.1:6379 cargo test -p all-in-one -- --ignored`
+
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use all_in_one::{host, CODE_SEARCH, CODE_UNDERSTANDING, COORDINATOR};
+use common::CodeUnderstanding;
+use serde_json::{json, Value};
+use warp::Filter;
+
+const QUESTION: &str = "Where is the qdrant upsert retried?";
+const ANSWER: &str = "The upsert is retried three times in `commit_chunks`.";
+const PLAN: &str = "Read the retry count from the config in `commit_chunks`.";
+
+// Answers the task generation prompt with a single question and everything else with the plan.
+fn mock_llm() -> SocketAddr {
+    let route = warp::path!("v1" / "chat" / "completions")
+        .and(warp::post())
+        .and(warp::body::json::<Value>())
+        .map(|body: Value| {
+            let content = if body.to_string().contains("deconstructs it into actionable tasks") {
+                json!({
+                    "tasks": [{
+                        "task": "Make the upsert retries configurable",
+                        "subtasks": [{
+                            "subtask": "Read the retry count from the config",
+                            "questions": [QUESTION]
+                        }]
+                    }]
+                })
+                .to_string()
+            } else {
+                PLAN.to_string()
+            };
+            warp::reply::json(&json!({
+                "choices": [{"message": {"role": "assistant", "content": content}}]
+            }))
+        });
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr
+}
+
+fn write_gateway_config(llm: SocketAddr) -> String {
+    let path = env::temp_dir().join(format!("all-in-one-{}.yaml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        format!(
+            "compress_threshold: 2000\n\
+             clients:\n\
+             \x20 - type: openai-compatible\n\
+             \x20   name: mock\n\
+             \x20   api_base: http://{}/v1\n\
+             \x20   models:\n\
+             \x20     - name: mock-model\n",
+            llm
+        ),
+    )
+    .unwrap();
+    path.to_string_lossy().to_string()
+}
+
+#[tokio::test]
+#[ignore = "needs a redis server at REDIS_URL"]
+async fn test_conversation_through_all_in_one() {
+    env::set_var("AI_GATEWAY_CONFIG_PATH", write_gateway_config(mock_llm()));
+    env::set_var(
+        "REDIS_URL",
+        env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string()),
+    );
+    all_in_one::use_local_service_urls();
+
+    // the stores behind code search and code understanding are mocked through their routes.
+    let questions = Arc::new(AtomicUsize::new(0));
+    let asked = questions.clone();
+    host(
+        CODE_UNDERSTANDING,
+        warp::path("retrieve-code")
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |query: HashMap<String, String>| {
+                asked.fetch_add(1, Ordering::SeqCst);
+                warp::reply::json(&CodeUnderstanding {
+                    context: vec![],
+                    question: query.get("query").cloned().unwrap_or_default(),
+                    answer: ANSWER.to_string(),
+                    outcome: None,
+                    missing_pinned_paths: vec![],
+                    cost_usd: None,
+                    timings: None,
+                    scope_violations: vec![],
+                    citations: Default::default(),
+                    verification: Default::default(),
+                    prompt_versions: Default::default(),
+                    files_involved: Default::default(),
+                    index_mode: Default::default(),
+                })
+            }),
+    );
+    host(
+        CODE_SEARCH,
+        warp::path::end().map(|| "Hello from code search"),
+    );
+    coordinator::set_config(coordinator::config_from_env());
+    host(COORDINATOR, coordinator::routes::coordinator());
+
+    let (addr, server) = warp::serve(all_in_one::routes()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let client = reqwest::Client::new();
+    let base = format!("http://{}", addr);
+
+    // every service answers under its prefix, unknown services are not found.
+    let home = client.get(format!("{}/code-search/", base)).send().await.unwrap();
+    assert_eq!(home.text().await.unwrap(), "Hello from code search");
+    let unknown = client.get(format!("{}/modifier/", base)).send().await.unwrap();
+    assert_eq!(unknown.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let response = client
+        .post(format!("{}/coordinator/suggest", base))
+        .json(&json!({
+            "user_query": "Make the number of upsert retries configurable",
+            "repo_name": "incredible"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let suggestion: Value = response.json().await.unwrap();
+    assert_eq!(suggestion["plan"], PLAN);
+    assert_eq!(suggestion["questions_with_answers"][0]["question"], QUESTION);
+    assert_eq!(suggestion["questions_with_answers"][0]["answer"]["answer"], ANSWER);
+    // the coordinator reached code understanding in process.
+    assert_eq!(questions.load(Ordering::SeqCst), 1);
+
+    let id = suggestion["id"].as_str().unwrap();
+    let messages: Value = client
+        .get(format!("{}/coordinator/conversation/{}/messages", base, id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(messages["total"].as_u64().unwrap() >= 2, "{}", messages);
+}
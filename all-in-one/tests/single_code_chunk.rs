@@ -0,0 +1,77 @@
+// Checks that the crates of the workspace use `common::models::CodeChunk` instead of declaring a
+// `CodeChunk` of their own. Reads the sources of the checkout, the crates themselves don't.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CANONICAL: &str = "common/src/code_chunk.rs";
+
+fn rust_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            rust_files(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            files.push(path);
+        }
+    }
+}
+
+fn declares_code_chunk(source: &str) -> bool {
+    source.lines().any(|line| {
+        let line = line.trim_start();
+        let line = line.strip_prefix("pub(crate) ").unwrap_or(line);
+        let line = line.strip_prefix("pub ").unwrap_or(line);
+        // an alias of the shared type, like the deprecated one of the crate root, is fine.
+        ["struct CodeChunk", "enum CodeChunk"].iter().any(|decl| {
+            line.strip_prefix(decl)
+                .is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+        })
+    })
+}
+
+#[test]
+fn test_code_chunk_is_declared_once() {
+    let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(workspace).unwrap().flatten() {
+        rust_files(&entry.path().join("src"), &mut files);
+    }
+    assert!(
+        files.iter().any(|file| file.ends_with(CANONICAL)),
+        "{} wasn't found in {}",
+        CANONICAL,
+        workspace.display()
+    );
+
+    let declared = files
+        .iter()
+        .filter(|file| !file.ends_with(CANONICAL))
+        .filter(|file| fs::read_to_string(file).is_ok_and(|source| declares_code_chunk(&source)))
+        .map(|file| {
+            file.strip_prefix(workspace)
+                .unwrap_or(file)
+                .display()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    assert!(
+        declared.is_empty(),
+        "CodeChunk is declared outside of {}: {}, use common::models::CodeChunk",
+        CANONICAL,
+        declared.join(", ")
+    );
+}
+
+#[test]
+fn test_code_chunk_declarations_are_found() {
+    assert!(declares_code_chunk("pub struct CodeChunk {\n}"));
+    assert!(declares_code_chunk("    pub(crate) enum CodeChunk {"));
+    assert!(!declares_code_chunk("pub struct CodeChunkRequest {"));
+    assert!(!declares_code_chunk(
+        "pub type CodeChunk = models::CodeChunk;"
+    ));
+}
@@ -4,6 +4,7 @@ use common::ast::{ast_graph::NodeKind, graph_code_pluck::ContentDocument, text_r
 use serde::Serialize;
 
 use crate::snippet::{Snipper, Snippet};
+use crate::token_resolution::TokenResolution;
 use rayon::prelude::*;
 
 #[derive(Debug, Serialize)]
@@ -13,6 +14,8 @@ pub struct FileSymbols {
     pub repo: String,
     /// A collection of symbol locations with context in this file
     pub data: Vec<Occurrence>,
+    /// How the occurrences were found, the scope graph unless it couldn't resolve the token
+    pub method: TokenResolution,
 }
 
 #[derive(Serialize, Debug)]
@@ -37,6 +40,8 @@ pub enum OccurrenceKind {
 }
 pub struct Token<'a> {
     pub repo: String,
+    // the branch the documents were read from, the fallbacks look the definitions up on it.
+    pub branch: String,
     pub relative_path: &'a str,
     pub start_byte: usize,
     pub end_byte: usize,
@@ -100,7 +105,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
         }
     }
 
-    fn is_definition(&self) -> bool {
+    pub fn is_definition(&self) -> bool {
         match self.source_document().symbol_locations() {
             Ok(symbol_locations) => symbol_locations
                 .scope_graph()
@@ -195,6 +200,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
                     file: self.token.relative_path.to_owned(),
                     repo: self.token.repo.clone(),
                     data,
+                    method: TokenResolution::ScopeGraph,
                 })
             }
             Err(_) => None, // Handle the error appropriately, possibly by returning None.
@@ -240,6 +246,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
                                 file: doc.relative_path.to_owned(),
                                 repo: doc.repo_ref.parse().unwrap(),
                                 data,
+                                method: TokenResolution::ScopeGraph,
                             })
                         }
                     }
@@ -280,6 +287,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
                     file: self.token.relative_path.to_owned(),
                     repo: self.token.repo.clone(),
                     data,
+                    method: TokenResolution::ScopeGraph,
                 })
             }
             Err(_) => None,
@@ -330,6 +338,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
                                 file: doc.relative_path.to_owned(),
                                 repo: doc.repo_ref.parse().unwrap(), // Make sure parse().unwrap() is safe or handle it appropriately
                                 data,
+                                method: TokenResolution::ScopeGraph,
                             })
                         } else {
                             None
@@ -366,6 +375,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
                     file: self.token.relative_path.to_owned(),
                     repo: self.token.repo.clone(),
                     data,
+                    method: TokenResolution::ScopeGraph,
                 })
             }
             Err(_) => None,
@@ -373,7 +383,7 @@ impl<'a, 'b> CodeNavigationContext<'a, 'b> {
     }
 }
 
-fn to_occurrence(doc: &ContentDocument, range: TextRange, snipper: Option<Snipper>) -> Snippet {
+pub(crate) fn to_occurrence(doc: &ContentDocument, range: TextRange, snipper: Option<Snipper>) -> Snippet {
     let src = &doc.content;
     let line_end_indices = &doc.line_end_indices;
     let highlight = range.start.byte..range.end.byte;
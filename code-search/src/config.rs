@@ -1,9 +1,13 @@
 use anyhow::Context;
 use lazy_static::lazy_static;
 use std::env;
-use std::sync::RwLock;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use crate::db;
+use crate::search::hybrid::FusionMethod;
+use crate::search::ranking_config::{self, RankingConfigStore};
 use common::docker::is_running_in_docker;
 
 #[derive(Debug, Clone)]
@@ -13,8 +17,34 @@ pub struct Configuration {
     quikwit_db_url: String,
     model_path: String,
     qdrant_api_key: Option<String>,
+    search_fusion: FusionMethod,
+    doc_search_weight: f32,
+    embedded_lang_weight: f32,
+    dedup_threshold: f32,
+    test_code_weight: f32,
+    max_chunks_per_path: usize,
+    recency_half_life_days: f32,
+    deprecated_path_weight: f32,
+    working_copies_dir: Option<String>,
 }
 
+// weight of the doc comment hits on top of the fused vector and keyword scores.
+const DEFAULT_DOC_SEARCH_WEIGHT: f32 = 0.3;
+// weight of the hits on the chunks of the embedded language of the query, e.g. the SQL of a string.
+const DEFAULT_EMBEDDED_LANG_WEIGHT: f32 = 0.5;
+// token similarity from which two chunks of different paths are taken for copies of each other.
+const DEFAULT_DEDUP_THRESHOLD: f32 = 0.85;
+// multiplier of the scores of test and vendored paths, unless the query is about tests.
+const DEFAULT_TEST_CODE_WEIGHT: f32 = 0.5;
+// chunks of a path in the results before the next ones go after the chunks of the other paths.
+const DEFAULT_MAX_CHUNKS_PER_PATH: usize = 3;
+// days after which the recency boost halves the score of a file, when the query asks for it.
+pub(crate) const DEFAULT_RECENCY_HALF_LIFE_DAYS: f32 = 180.0;
+// seconds between two checks of the ranking config file for changes.
+const DEFAULT_RANKING_CONFIG_POLL_SECS: u64 = 10;
+// multiplier of the scores of the paths that look deprecated, e.g. under `legacy/`.
+const DEFAULT_DEPRECATED_PATH_WEIGHT: f32 = 0.9;
+
 pub struct AppState {
     pub db_connection: db::DbConnect,
 }
@@ -27,6 +57,15 @@ lazy_static! {
         quikwit_db_url: String::new(),
         model_path: String::new(),
         qdrant_api_key: None,
+        search_fusion: FusionMethod::default(),
+        doc_search_weight: DEFAULT_DOC_SEARCH_WEIGHT,
+        embedded_lang_weight: DEFAULT_EMBEDDED_LANG_WEIGHT,
+        dedup_threshold: DEFAULT_DEDUP_THRESHOLD,
+        test_code_weight: DEFAULT_TEST_CODE_WEIGHT,
+        max_chunks_per_path: DEFAULT_MAX_CHUNKS_PER_PATH,
+        recency_half_life_days: DEFAULT_RECENCY_HALF_LIFE_DAYS,
+        deprecated_path_weight: DEFAULT_DEPRECATED_PATH_WEIGHT,
+        working_copies_dir: None,
     });
 }
 
@@ -39,12 +78,76 @@ pub async fn initialize_config(env_file: Option<String>) -> anyhow::Result<AppSt
         dotenv::dotenv().context("Failed to load environment file")?;
     }
 
+    initialize_from_env().await
+}
+
+/// Same as `initialize_config`, for an env that was already loaded.
+pub async fn initialize_from_env() -> anyhow::Result<AppState> {
     let config = Configuration {
         symbol_collection_name: common::service_interaction::SYMBOL_COLLECTION_NAME.to_string(), // Set a default or pull from env
         semantic_db_url: env::var("SEMANTIC_DB_URL").context("SEMANTIC_DB_URL must be set")?,
         quikwit_db_url: env::var("QUICKWIT_DB_URL").context("QUICKWIT_DB_URL must be set")?,
         model_path: env::var("MODEL_DIR").context("MODEL_PATH must be set")?,
         qdrant_api_key: env::var("QDRANT_CLOUD_API_KEY").ok(), // Optional, hence `ok()`
+        // how the vector and keyword hits are fused, `weighted` (default) or `rrf`.
+        search_fusion: match env::var("SEARCH_FUSION") {
+            Ok(value) if !value.is_empty() => value.parse()?,
+            _ => FusionMethod::default(),
+        },
+        // 0 disables the search on doc comments.
+        doc_search_weight: match env::var("DOC_SEARCH_WEIGHT") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("DOC_SEARCH_WEIGHT must be a number")?,
+            _ => DEFAULT_DOC_SEARCH_WEIGHT,
+        },
+        // 0 disables the search on the chunks of an embedded language.
+        embedded_lang_weight: match env::var("EMBEDDED_LANG_WEIGHT") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("EMBEDDED_LANG_WEIGHT must be a number")?,
+            _ => DEFAULT_EMBEDDED_LANG_WEIGHT,
+        },
+        // between 0 and 1, the higher the closer two chunks have to be to be collapsed.
+        dedup_threshold: match env::var("DEDUP_THRESHOLD") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("DEDUP_THRESHOLD must be a number")?,
+            _ => DEFAULT_DEDUP_THRESHOLD,
+        },
+        // between 0 and 1, 1 disables the demotion.
+        test_code_weight: match env::var("TEST_CODE_WEIGHT") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("TEST_CODE_WEIGHT must be a number")?,
+            _ => DEFAULT_TEST_CODE_WEIGHT,
+        },
+        // 0 disables the cap.
+        max_chunks_per_path: match env::var("MAX_CHUNKS_PER_PATH") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("MAX_CHUNKS_PER_PATH must be a whole number")?,
+            _ => DEFAULT_MAX_CHUNKS_PER_PATH,
+        },
+        // 0 disables the recency boost, even when the query asks for it.
+        recency_half_life_days: match env::var("RECENCY_HALF_LIFE_DAYS") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("RECENCY_HALF_LIFE_DAYS must be a number")?,
+            _ => DEFAULT_RECENCY_HALF_LIFE_DAYS,
+        },
+        // between 0 and 1, 1 disables the demotion.
+        deprecated_path_weight: match env::var("DEPRECATED_PATH_WEIGHT") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .context("DEPRECATED_PATH_WEIGHT must be a number")?,
+            _ => DEFAULT_DEPRECATED_PATH_WEIGHT,
+        },
+        // folder with a working copy of every repo, named like the repo. The freshness of an
+        // index is probed on the remote of the repo without it.
+        working_copies_dir: env::var("REPO_WORKING_COPIES_DIR")
+            .ok()
+            .filter(|dir| !dir.trim().is_empty()),
     };
     {
         let mut global_config = GLOBAL_CONFIG.write().expect("Failed to acquire write lock");
@@ -55,14 +158,47 @@ pub async fn initialize_config(env_file: Option<String>) -> anyhow::Result<AppSt
             SymbolCollectionName: {},
             SemanticDbUrl: {},
             QuikwitDbUrl: {},
-            ModelPath: {}", 
+            ModelPath: {},
+            SearchFusion: {:?},
+            DocSearchWeight: {},
+            EmbeddedLangWeight: {},
+            DedupThreshold: {},
+            TestCodeWeight: {},
+            MaxChunksPerPath: {},
+            RecencyHalfLifeDays: {},
+            DeprecatedPathWeight: {}", 
             config.symbol_collection_name,
             config.semantic_db_url,
             config.quikwit_db_url,
             config.model_path,
+            config.search_fusion,
+            config.doc_search_weight,
+            config.embedded_lang_weight,
+            config.dedup_threshold,
+            config.test_code_weight,
+            config.max_chunks_per_path,
+            config.recency_half_life_days,
+            config.deprecated_path_weight,
         );
 
     }
+    // the weights tuned by `tune-ranking`, the defaults when unset. 0 checks the file only on
+    // `POST /admin/ranking/reload`.
+    let ranking = Arc::new(RankingConfigStore::new(
+        env::var("RANKING_CONFIG_PATH")
+            .ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(PathBuf::from),
+    ));
+    let ranking_poll_secs = match env::var("RANKING_CONFIG_POLL_SECS") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .context("RANKING_CONFIG_POLL_SECS must be a whole number")?,
+        _ => DEFAULT_RANKING_CONFIG_POLL_SECS,
+    };
+    ranking.clone().watch(Duration::from_secs(ranking_poll_secs));
+    ranking_config::init(ranking);
+
     let db_connection = db::init_db().await?;
 
     Ok(AppState { db_connection })
@@ -92,3 +228,48 @@ pub fn get_model_path() -> String {
 pub fn get_qdrant_api_key() -> Option<String> {
     GLOBAL_CONFIG.read().unwrap().qdrant_api_key.clone()
 }
+
+// Getter for the fusion of vector and keyword search results
+pub fn get_search_fusion() -> FusionMethod {
+    GLOBAL_CONFIG.read().unwrap().search_fusion
+}
+
+// Getter for the weight of the doc comment hits
+pub fn get_doc_search_weight() -> f32 {
+    GLOBAL_CONFIG.read().unwrap().doc_search_weight
+}
+
+// Getter for the weight of the hits on the chunks of an embedded language
+pub fn get_embedded_lang_weight() -> f32 {
+    GLOBAL_CONFIG.read().unwrap().embedded_lang_weight
+}
+
+// Getter for the similarity from which chunks are collapsed as duplicates
+pub fn get_dedup_threshold() -> f32 {
+    GLOBAL_CONFIG.read().unwrap().dedup_threshold
+}
+
+// Getter for the multiplier of the scores of test and vendored code
+pub fn get_test_code_weight() -> f32 {
+    GLOBAL_CONFIG.read().unwrap().test_code_weight
+}
+
+// Getter for the most chunks of a path ahead of the chunks of the other paths
+pub fn get_max_chunks_per_path() -> usize {
+    GLOBAL_CONFIG.read().unwrap().max_chunks_per_path
+}
+
+// Getter for the half-life of the recency boost, in days
+pub fn get_recency_half_life_days() -> f32 {
+    GLOBAL_CONFIG.read().unwrap().recency_half_life_days
+}
+
+// Getter for the multiplier of the scores of deprecated paths
+pub fn get_deprecated_path_weight() -> f32 {
+    GLOBAL_CONFIG.read().unwrap().deprecated_path_weight
+}
+
+// Getter for the folder with the working copies of the repos
+pub fn get_working_copies_dir() -> Option<String> {
+    GLOBAL_CONFIG.read().unwrap().working_copies_dir.clone()
+}
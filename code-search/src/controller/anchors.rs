@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::{convert::Infallible, sync::Arc};
+
+use common::anchors::{AnchorResolution, SnippetAnchor};
+use common::ast::ast_graph::{NodeKind, ScopeGraph};
+use common::ast::graph_code_pluck::ContentDocument;
+use common::branch::requested_branch;
+use common::models::ResolveAnchorsRequest;
+use reqwest::StatusCode;
+
+use crate::{config::AppState, search::code_search::get_file_content};
+
+// Finds the cited lines of the anchors in the current version of their files: at the same lines,
+// elsewhere in the file, or fuzzily within the symbol they were cited in. Every file is fetched
+// once however many anchors it has.
+pub async fn handle_resolve_anchors(
+    request: ResolveAnchorsRequest,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    let branch = requested_branch(request.branch.as_deref());
+    let mut documents: HashMap<String, Option<ContentDocument>> = HashMap::new();
+    let mut resolutions = Vec::with_capacity(request.anchors.len());
+    for cited in &request.anchors {
+        let anchor = match cited.anchor.parse::<SnippetAnchor>() {
+            Ok(anchor) => anchor,
+            Err(e) => {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Invalid anchor {}: {}", cited.anchor, e)),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        };
+        if !documents.contains_key(&cited.path) {
+            match get_file_content(&cited.path, &request.repo, branch, app_state.clone()).await {
+                Ok(document) => {
+                    documents.insert(cited.path.clone(), document);
+                }
+                Err(e) => {
+                    log::error!("Failed to fetch {} of repo {}: {}", cited.path, request.repo, e);
+                    return Ok(warp::reply::with_status(
+                        warp::reply::json(&format!("Error: {}", e)),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ));
+                }
+            }
+        }
+        let resolution = match &documents[&cited.path] {
+            None => AnchorResolution::file_deleted(),
+            Some(document) => {
+                let symbol_lines = anchor.symbol.as_deref().and_then(|symbol| {
+                    let locations = document.symbol_locations().ok()?;
+                    symbol_lines(
+                        locations.scope_graph()?,
+                        document.content.as_bytes(),
+                        symbol,
+                        cited.lines,
+                    )
+                });
+                anchor.relocate(&document.content, cited.lines, symbol_lines)
+            }
+        };
+        resolutions.push(resolution);
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&resolutions),
+        StatusCode::OK,
+    ))
+}
+
+// 1-based lines of the body of the definition named `symbol`, the one starting closest to the
+// cited lines when it is defined more than once.
+fn symbol_lines(
+    graph: &ScopeGraph,
+    src: &[u8],
+    symbol: &str,
+    cited: Option<(usize, usize)>,
+) -> Option<(usize, usize)> {
+    let cited_start = cited.map_or(1, |(start, _)| start);
+    graph
+        .graph
+        .node_indices()
+        .filter(|&idx| match &graph.graph[idx] {
+            NodeKind::Def(def) => def.name(src) == symbol.as_bytes(),
+            _ => false,
+        })
+        .map(|def| {
+            let body = graph
+                .value_of_definition(def)
+                .map(|idx| graph.graph[idx].range())
+                .unwrap_or_else(|| graph.graph[def].range());
+            (body.start.line + 1, body.end.line + 1)
+        })
+        .min_by_key(|(start, _)| start.abs_diff(cited_start))
+}
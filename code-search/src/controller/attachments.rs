@@ -0,0 +1,198 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use common::attachments::{
+    AttachmentIndexRequest, AttachmentIndexResponse, AttachmentSearchRequest,
+    ATTACHMENT_COLLECTION_NAME,
+};
+use common::auth::Tenant;
+use reqwest::StatusCode;
+
+use crate::config::AppState;
+use crate::search::attachments::{
+    attachment_collection, attachment_hit, attachment_point, attachment_search_request,
+    conversation_selector, expired_selector,
+};
+use crate::search::batch::search_one;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Embeds the sections of an attachment of a conversation. The coordinator checked that the
+// conversation belongs to the tenant. The expired sections of every conversation are swept on
+// the way, their conversations are gone.
+pub async fn handle_index_attachment(
+    request: AttachmentIndexRequest,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    log::info!(
+        "Indexing {} attachment sections of conversation {} for tenant {}",
+        request.sections.len(),
+        request.conversation_id,
+        tenant.id
+    );
+    match index_attachment(&request, &app_state).await {
+        Ok(indexed) => Ok(warp::reply::with_status(
+            warp::reply::json(&AttachmentIndexResponse { indexed }),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            log::error!(
+                "Failed to index the attachment of conversation {}: {}",
+                request.conversation_id,
+                e
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+async fn index_attachment(request: &AttachmentIndexRequest, app_state: &AppState) -> Result<usize> {
+    let semantic = &app_state.db_connection.semantic;
+    let mut points = Vec::with_capacity(request.sections.len());
+    let mut dimension = 0;
+    for section in &request.sections {
+        // the section name is embedded with the text, operations are often asked about by path.
+        let vector = semantic.embed(&format!("{}\n{}", section.section, section.text))?;
+        dimension = vector.len() as u64;
+        points.push(attachment_point(
+            &request.conversation_id,
+            request.expires_at,
+            section,
+            vector,
+        ));
+    }
+    if points.is_empty() {
+        return Ok(0);
+    }
+
+    let indexed = points.len();
+    semantic
+        .qdrant
+        .run(|client| {
+            let points = points.clone();
+            async move {
+                if !client.has_collection(ATTACHMENT_COLLECTION_NAME).await? {
+                    client
+                        .create_collection(&attachment_collection(dimension))
+                        .await?;
+                }
+                client
+                    .delete_points(ATTACHMENT_COLLECTION_NAME, &expired_selector(now()), None)
+                    .await?;
+                client
+                    .upsert_points_blocking(ATTACHMENT_COLLECTION_NAME, points, None)
+                    .await?;
+                Ok(())
+            }
+        })
+        .await?;
+    Ok(indexed)
+}
+
+// The sections of the unexpired attachments of a conversation closest to the query.
+pub async fn handle_search_attachments(
+    request: AttachmentSearchRequest,
+    _tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    let semantic = &app_state.db_connection.semantic;
+    let hits = match semantic.embed(&request.query) {
+        Ok(vector) => search_one(
+            &semantic.qdrant,
+            attachment_search_request(
+                &request.conversation_id,
+                vector,
+                request.limit as u64,
+                now(),
+            ),
+        )
+        .await
+        .map(|points| {
+            points
+                .into_iter()
+                .filter_map(attachment_hit)
+                .collect::<Vec<_>>()
+        }),
+        Err(e) => Err(e),
+    };
+
+    match hits {
+        Ok(hits) => Ok(warp::reply::with_status(
+            warp::reply::json(&hits),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            log::error!(
+                "Failed to search the attachments of conversation {}: {}",
+                request.conversation_id,
+                e
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+// Deletes the attachments of a conversation, e.g. when it is archived.
+pub async fn handle_delete_attachments(
+    conversation_id: String,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    log::info!(
+        "Deleting the attachments of conversation {} for tenant {}",
+        conversation_id,
+        tenant.id
+    );
+    let result = app_state
+        .db_connection
+        .semantic
+        .qdrant
+        .run(|client| {
+            let conversation_id = conversation_id.clone();
+            async move {
+                if client.has_collection(ATTACHMENT_COLLECTION_NAME).await? {
+                    client
+                        .delete_points(
+                            ATTACHMENT_COLLECTION_NAME,
+                            &conversation_selector(&conversation_id),
+                            None,
+                        )
+                        .await?;
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+    match result {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&"deleted"),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            log::error!(
+                "Failed to delete the attachments of conversation {}: {}",
+                conversation_id,
+                e
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
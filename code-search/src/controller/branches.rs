@@ -0,0 +1,32 @@
+use std::convert::Infallible;
+
+use common::auth::{self, Tenant};
+use common::hasher::generate_quikwit_index_name;
+use reqwest::StatusCode;
+
+use crate::search::quikwit::get_indexed_branches;
+
+// The branches indexed side by side in the index of the repo, each indexing run of a branch
+// leaves its run manifest among the documents of the branch.
+pub async fn handle_indexed_branches(
+    repo_name: String,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    match get_indexed_branches(&generate_quikwit_index_name(&repo_name), &repo_name).await {
+        Ok(branches) => Ok(warp::reply::with_status(
+            warp::reply::json(&branches),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            log::error!("Failed to list the branches of repo {}: {}", repo_name, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::convert::Infallible;
+
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::hasher::generate_quikwit_index_name;
+use common::links::IndexedCommit;
+use reqwest::StatusCode;
+
+use crate::models::BranchQuery;
+use crate::search::quikwit::get_indexed_commit;
+
+// The commit the branch of the repo was indexed at, callers use it to link to the files the answers quote.
+pub async fn handle_indexed_commit(
+    repo_name: String,
+    query: BranchQuery,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let branch = requested_branch(query.branch.as_deref());
+    match get_indexed_commit(&generate_quikwit_index_name(&repo_name), branch).await {
+        Ok(commit) => Ok(warp::reply::with_status(
+            warp::reply::json(&IndexedCommit { repo_name, commit }),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            log::error!("Failed to fetch the indexed commit of repo {}: {}", repo_name, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
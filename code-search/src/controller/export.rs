@@ -0,0 +1,60 @@
+use std::{convert::Infallible, sync::Arc};
+
+use common::auth::{self, Tenant};
+use futures::StreamExt;
+use reqwest::StatusCode;
+use warp::{http::Response, hyper::Body, Reply};
+
+use crate::{
+    config::AppState,
+    models::EmbeddingExportQuery,
+    search::export::{embedding_pages, encode_pages, ExportFormat},
+};
+
+// Streams the chunk embeddings of a repo for offline analysis, one scroll page at a time.
+// Defaults to jsonl, parquet is only available when built with the `parquet` feature.
+pub async fn handle_embedding_export(
+    repo_name: String,
+    query: EmbeddingExportQuery,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name).into_response());
+    }
+
+    let format = match query.format.as_deref().map(str::parse::<ExportFormat>) {
+        None => ExportFormat::Jsonl,
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return Ok(text_response(StatusCode::BAD_REQUEST, e)),
+    };
+
+    log::info!(
+        "Exporting embeddings of repo {} as {:?} with limit {:?}",
+        repo_name,
+        format,
+        query.limit
+    );
+    let export_repo = repo_name.clone();
+    // the status is already sent once the body streams, a failed page can only end the response early.
+    let body = encode_pages(embedding_pages(app_state, repo_name, query.limit), format).inspect(
+        move |chunk| {
+            if let Err(e) = chunk {
+                log::error!("Embedding export of repo {} failed: {}", export_repo, e);
+            }
+        },
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .body(Body::wrap_stream(body))
+        .expect("Failed to construct response"))
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .expect("Failed to construct response")
+}
@@ -0,0 +1,71 @@
+use std::{convert::Infallible, sync::Arc};
+
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::run_manifest::{RunManifest, RUN_MANIFEST_PATH};
+use reqwest::StatusCode;
+
+use crate::config::{get_working_copies_dir, AppState};
+use crate::freshness::{probe_freshness, GitCli, GitSource};
+use crate::models::BranchQuery;
+use crate::search::code_search::get_file_content;
+
+// How far the index of the branch is behind the head of the branch, from its run manifest and a
+// probe of the working copy or the remote of the repo. A failed probe is answered with an unknown
+// freshness, only a missing manifest fails the request.
+pub async fn handle_index_freshness(
+    repo_name: String,
+    query: BranchQuery,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let branch = requested_branch(query.branch.as_deref());
+    let manifest = match get_file_content(RUN_MANIFEST_PATH, &repo_name, branch, app_state).await {
+        Ok(Some(document)) => match serde_json::from_str::<RunManifest>(&document.content) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                log::error!(
+                    "Failed to parse the run manifest of repo {}: {}",
+                    repo_name,
+                    e
+                );
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Error: {}", e)),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        },
+        Ok(None) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!(
+                    "Repo {} has no run manifest, index it again",
+                    repo_name
+                )),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+        Err(e) => {
+            log::error!(
+                "Failed to fetch the run manifest of repo {}: {}",
+                repo_name,
+                e
+            );
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+
+    let probe = GitSource::of_repo(get_working_copies_dir().as_deref(), &manifest)
+        .map(|source| GitCli { source });
+    let freshness = probe_freshness(probe.as_ref(), &manifest).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&freshness),
+        StatusCode::OK,
+    ))
+}
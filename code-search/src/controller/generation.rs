@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use common::auth::Tenant;
+use common::generation::IndexGeneration;
+use reqwest::StatusCode;
+
+use crate::config::AppState;
+
+// The collections the aliases of the searched collections point to now. The coordinator pins a
+// new conversation to them, its searches then read from them even after a migration switched the
+// aliases.
+pub async fn handle_index_generation(
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    log::debug!("Resolving the index generation for tenant {}", tenant.id);
+    let aliases = app_state
+        .db_connection
+        .semantic
+        .qdrant
+        .run(|client| async move { client.list_aliases().await })
+        .await;
+    match aliases {
+        Ok(response) => {
+            let aliases = response
+                .aliases
+                .into_iter()
+                .map(|alias| (alias.alias_name, alias.collection_name))
+                .collect::<HashMap<_, _>>();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&IndexGeneration::from_aliases(&aliases)),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            log::error!("Failed to list the collection aliases: {}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::{convert::Infallible, sync::Arc};
+
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::run_manifest::{RunManifest, RUN_MANIFEST_PATH};
+use reqwest::StatusCode;
+
+use crate::{config::AppState, models::BranchQuery, search::code_search::get_file_content};
+
+// The manifest of the last ingestion run of the branch of the repo, stored as a document of its quickwit index.
+pub async fn handle_run_manifest(
+    repo_name: String,
+    query: BranchQuery,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let branch = requested_branch(query.branch.as_deref());
+    match get_file_content(RUN_MANIFEST_PATH, &repo_name, branch, app_state).await {
+        Ok(Some(document)) => match serde_json::from_str::<RunManifest>(&document.content) {
+            Ok(manifest) => Ok(warp::reply::with_status(
+                warp::reply::json(&manifest),
+                StatusCode::OK,
+            )),
+            Err(e) => {
+                log::error!("Failed to parse the run manifest of repo {}: {}", repo_name, e);
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Error: {}", e)),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        },
+        Ok(None) => Ok(warp::reply::with_status(
+            warp::reply::json(&format!("Repo {} has no run manifest, index it again", repo_name)),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => {
+            log::error!("Failed to fetch the run manifest of repo {}: {}", repo_name, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -2,4 +2,17 @@ pub mod symbol;
 pub mod span;
 pub mod parentscope;
 pub mod navigator;
-
+pub mod export;
+pub mod owners;
+pub mod commit;
+pub mod summary;
+pub mod manifest;
+pub mod paths;
+pub mod ready;
+pub mod attachments;
+pub mod branches;
+pub mod freshness;
+pub mod generation;
+pub mod terminology;
+pub mod anchors;
+pub mod ranking;
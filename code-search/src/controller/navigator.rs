@@ -8,6 +8,7 @@ use common::{
         language_support::{Language, TSLanguage},
         text_range::TextRange,
     },
+    branch::requested_branch,
     hasher::generate_quikwit_index_name,
     TokenInfoRequest,
 };
@@ -18,8 +19,8 @@ use smallvec::SmallVec;
 use crate::{
     code_navigation::{CodeNavigationContext, FileSymbols, Occurrence, OccurrenceKind, Token}, config::AppState, search::{
         code_search::get_file_content,
-        quikwit::{get_all_files_for_repo, search_quickwit},
-    }, snippet::Snipper
+        quikwit::get_all_files_for_repo,
+    }, snippet::Snipper, token_resolution::{resolve_token, DefinitionSearch, TokenResolution}
 };
 
 pub async fn handle_token_info_fetcher_wrapper(
@@ -47,9 +48,11 @@ async fn handle_token_info_fetcher(
     request: TokenInfoRequest,
     app_state: Arc<AppState>,
 ) -> Result<Vec<FileSymbols>, anyhow::Error> {
+    let branch = requested_branch(request.branch.as_deref());
     let source_document = match get_file_content(
         &request.relative_path.clone(),
         &request.repo_ref.clone(),
+        branch,
         app_state.clone(),
     )
     .await
@@ -64,6 +67,7 @@ async fn handle_token_info_fetcher(
     let all_docs = match get_all_files_for_repo(
         &generate_quikwit_index_name(&request.repo_ref.clone()),
         &request.repo_ref.clone(),
+        branch,
     )
     .await
     {
@@ -115,6 +119,7 @@ pub async fn get_token_info(
     let ctx: CodeNavigationContext<'_, '_> = CodeNavigationContext {
         token: Token {
             repo: repo_ref.clone(),
+            branch: requested_branch(params.branch.as_deref()).to_string(),
             relative_path: params.relative_path.as_str(),
             start_byte: params.start,
             end_byte: params.end,
@@ -124,31 +129,19 @@ pub async fn get_token_info(
         snipper,
     };
 
-    let data = ctx.token_info();
-    if data.is_empty() {
-        search_nav(
-            repo_ref.clone(),
-            ctx.active_token_text(),
-            ctx.active_token_range(),
-            params.branch.as_deref(),
-            source_doc,
-            snipper,
-            app_state,
-        )
-        .await
-    } else {
-        Ok(data)
-    }
+    resolve_token(app_state.as_ref(), &ctx).await
 }
 
-async fn search_nav(
+/// Every hoverable match of `hovered_text` in the files found by a trigram search, the last
+/// tier of `resolve_token`.
+pub async fn search_nav<B: DefinitionSearch>(
     repo_ref: String,
     hovered_text: &str,
     payload_range: std::ops::Range<usize>,
     branch: Option<&str>,
     source_document: &ContentDocument,
     snipper: Option<Snipper>,
-    app_state: Arc<AppState>,
+    backend: &B,
 ) -> anyhow::Result<Vec<FileSymbols>> {
     let associated_langs = match source_document.lang.as_deref().map(TSLanguage::from_id) {
         Some(Language::Supported(config)) => config.language_ids,
@@ -165,7 +158,7 @@ async fn search_nav(
         branch.map(|b| vec![b]),
         associated_langs.to_vec(),
     );
-    let results = match search_quickwit(&repo_ref, &query).await {
+    let results = match backend.search_definitions(&repo_ref, &query).await {
         Ok(results) => results,
         Err(e) => {
             return Err(anyhow!("Failed to search quickwit: {}", e));
@@ -244,6 +237,7 @@ async fn search_nav(
                 file: file.clone(),
                 repo: repo_ref.clone(),
                 data,
+                method: TokenResolution::TextSearch,
             })
         })
         .collect::<Vec<_>>();
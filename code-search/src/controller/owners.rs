@@ -0,0 +1,51 @@
+use std::{convert::Infallible, sync::Arc};
+
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::codeowners::{CodeOwners, PathOwners, CODEOWNERS_LOCATIONS};
+use reqwest::StatusCode;
+
+use crate::{config::AppState, models::OwnersQuery, search::code_search::get_file_content};
+
+// Resolves the owners of a path from the CODEOWNERS file indexed with the repo.
+// Repos without one answer with no owners rather than an error, so callers don't have to tell them apart.
+pub async fn handle_repo_owners(
+    repo_name: String,
+    query: OwnersQuery,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let branch = requested_branch(query.branch.as_deref());
+    for location in CODEOWNERS_LOCATIONS {
+        match get_file_content(location, &repo_name, branch, app_state.clone()).await {
+            Ok(Some(document)) => {
+                let owners = CodeOwners::parse(&document.content).resolve(&query.path, location);
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&owners),
+                    StatusCode::OK,
+                ));
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("Failed to fetch {} of repo {}: {}", location, repo_name, e);
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Error: {}", e)),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        }
+    }
+
+    let unowned = PathOwners {
+        path: query.path,
+        ..Default::default()
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&unowned),
+        StatusCode::OK,
+    ))
+}
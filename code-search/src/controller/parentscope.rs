@@ -2,9 +2,10 @@ use std::sync::Arc;
 
 use crate::{config::AppState, models::ParentScopeRequest};
 use crate::search::code_search::get_file_content;
-use crate::utilities::util::return_byte_range_from_line_numbers;
+use crate::utilities::util::{redact_snippets, return_byte_range_from_line_numbers};
 
 use common::ast::graph_code_pluck::ExtractionConfig;
+use common::branch::requested_branch;
 use log::{debug, error};
 
 pub async fn parent_scope_search(
@@ -19,7 +20,8 @@ pub async fn parent_scope_search(
         path, params.start_line, params.end_line
     );
     // Attempt to retrieve the file content asynchronously based on the provided path and repository name.
-    let source_document = get_file_content(&path, &repo_name, app_state).await;
+    let branch = requested_branch(params.branch.as_deref());
+    let source_document = get_file_content(&path, &repo_name, branch, app_state).await;
 
     match source_document {
         Ok(content) => {
@@ -96,7 +98,7 @@ pub async fn parent_scope_search(
                 // otherwise the scope is greater than the min_lines_to_return specified in the extraction_config
                 // and smaller than the max_lines_limit specified in the extraction_config
                 // we return the extracted content containing the code of the parent scope which contains the given line range
-                let extract_content = sg.expand_scope(
+                let mut extract_content = sg.expand_scope(
                     &params.file,
                     start_byte,
                     end_byte,
@@ -105,6 +107,8 @@ pub async fn parent_scope_search(
                     &extraction_config,
                 );
 
+                redact_snippets([&mut extract_content.content], "/parentscope", &repo_name);
+
                 // return the extracted content
                 Ok(warp::reply::with_status(
                     warp::reply::json(&extract_content),
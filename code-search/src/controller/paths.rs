@@ -0,0 +1,48 @@
+use std::convert::Infallible;
+
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::grounding::IndexedPaths;
+use common::hasher::generate_quikwit_index_name;
+use common::path_class::is_metadata_document;
+use reqwest::StatusCode;
+
+use crate::models::BranchQuery;
+use crate::search::quikwit::get_all_files_for_repo;
+
+// The paths of the files of the branch in the quickwit index of the repo, sorted. The documents the
+// indexer writes about the repo are stored in the index but aren't files of it, see `is_metadata_document`.
+pub async fn handle_indexed_paths(
+    repo_name: String,
+    query: BranchQuery,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let index_name = generate_quikwit_index_name(&repo_name);
+    let branch = requested_branch(query.branch.as_deref());
+    match get_all_files_for_repo(&index_name, &repo_name, branch).await {
+        Ok(documents) => {
+            let mut paths = documents
+                .into_iter()
+                .map(|document| document.relative_path)
+                .filter(|path| !is_metadata_document(path))
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths.dedup();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&IndexedPaths { paths }),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            log::error!("Failed to list the paths of repo {}: {}", repo_name, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use reqwest::StatusCode;
+
+use crate::search::ranking_config;
+
+// Loads the ranking config file again, the next searches use its weights.
+pub async fn handle_reload_ranking(tenant: Tenant) -> Result<impl warp::Reply, Infallible> {
+    match ranking_config::store().reload() {
+        Ok(changed) => {
+            log::info!(
+                "Admin {} reloaded the ranking config, weights changed: {}",
+                tenant.id,
+                changed
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&*ranking_config::current()),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            log::error!("Failed to reload the ranking config: {:#}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error reloading the ranking config: {:#}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
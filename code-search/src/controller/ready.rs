@@ -0,0 +1,35 @@
+use std::{convert::Infallible, sync::Arc};
+
+use common::dependencies::DependencyChecker;
+use reqwest::StatusCode;
+
+use crate::config::AppState;
+use crate::search::quikwit::{list_indexes, QUICKWIT};
+
+// Ready when qdrant lists its collections and quickwit its indexes, checked concurrently. Both
+// calls reconnect like the requests do, `degraded` is set while a client couldn't be rebuilt yet.
+pub async fn handle_ready(app_state: Arc<AppState>) -> Result<impl warp::Reply, Infallible> {
+    let readiness = DependencyChecker::new()
+        .check_readiness("qdrant", move || {
+            let app_state = app_state.clone();
+            async move {
+                app_state
+                    .db_connection
+                    .semantic
+                    .qdrant
+                    .check(|client| async move { client.list_collections().await })
+                    .await
+            }
+        })
+        .check_readiness("quickwit", || {
+            QUICKWIT.check(|client| async move { list_indexes(&client).await })
+        })
+        .readiness()
+        .await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&readiness), status))
+}
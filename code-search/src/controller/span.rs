@@ -2,8 +2,9 @@ use std::sync::Arc;
 extern crate common;
 
 use crate::{config::AppState, search::code_search::get_file_content};
-use crate::utilities::util::pluck_code_by_lines;
-use common::{models::CodeSpanRequest, CodeChunk};
+use crate::utilities::util::{clamp_line_range, pluck_code_by_lines, redact_snippets};
+use common::branch::requested_branch;
+use common::models::{CodeChunk, CodeSpanRequest, SpanRangeError};
 
 /// Asynchronously handles a search request for a specific span within a file in a repository.
 ///
@@ -22,7 +23,8 @@ pub async fn span_search(
     let repo_name = params.repo.clone();
 
     // Attempt to retrieve the file content asynchronously based on the provided path and repository name.
-    let source_document = get_file_content(&path, &repo_name, app_state).await;
+    let branch = requested_branch(params.branch.as_deref());
+    let source_document = get_file_content(&path, &repo_name, branch, app_state).await;
 
     match source_document {
         Ok(content) => {
@@ -43,10 +45,32 @@ pub async fn span_search(
                     if !ranges.is_empty() {
                         // Convert the compacted u8 array of line end indices back to their original u32 format.
                         let line_end_indices = content_doc.fetch_line_indices();
+                        let line_count = line_end_indices.len();
 
-                        let code_chunks: Vec<CodeChunk> = ranges
+                        // Ranges of an older version of the file are clamped to its current length,
+                        // the ones that can't be are rejected with the line count for the caller to correct them.
+                        let clamped = ranges
                             .iter()
-                            .filter_map(|range| {
+                            .map(|range| clamp_line_range(range, line_count))
+                            .collect::<Result<Vec<_>, _>>();
+                        let clamped = match clamped {
+                            Ok(clamped) => clamped,
+                            Err(error) => {
+                                log::warn!("Rejected span of {} in {}: {}", path, repo_name, error);
+                                return Ok(warp::reply::with_status(
+                                    warp::reply::json(&SpanRangeError {
+                                        code: 400,
+                                        error,
+                                        line_count,
+                                    }),
+                                    warp::http::StatusCode::BAD_REQUEST,
+                                ));
+                            }
+                        };
+
+                        let mut code_chunks: Vec<CodeChunk> = clamped
+                            .into_iter()
+                            .filter_map(|(range, adjusted)| {
                                 match pluck_code_by_lines(
                                     &code_file,
                                     &line_end_indices,
@@ -55,9 +79,24 @@ pub async fn span_search(
                                 ) {
                                     Ok(code_chunk) => Some(CodeChunk {
                                         path: path.clone(),
+                                        repo: repo_name.clone(),
+                                        alias: None,
                                         snippet: code_chunk.to_string(),
                                         start_line: range.start,
                                         end_line: range.end,
+                                        byte_range: None,
+                                        symbol: None,
+                                        symbol_type: None,
+                                        score: None,
+                                        source: None,
+                                        doc: None,
+                                        duplicates: Vec::new(),
+                                        adjusted,
+                                        is_test: false,
+                                        is_vendored: false,
+                                        demoted: false,
+                                        overflow: false,
+                                        embedded_lang: None,
                                     }),
                                     Err(e) => {
                                         log::error!("Error processing range {:?}: {}", range, e);
@@ -66,6 +105,11 @@ pub async fn span_search(
                                 }
                             })
                             .collect();
+                        redact_snippets(
+                            code_chunks.iter_mut().map(|chunk| &mut chunk.snippet),
+                            "/span",
+                            &repo_name,
+                        );
 
                         return Ok(warp::reply::with_status(
                             warp::reply::json(&code_chunks),
@@ -75,12 +119,29 @@ pub async fn span_search(
                 }
 
                 // If ranges is None or empty, or if the code above does not execute, return the entire content
+                let mut snippet = code_file.to_string();
+                redact_snippets([&mut snippet], "/span", &repo_name);
                 Ok(warp::reply::with_status(
                     warp::reply::json(&Vec::from([CodeChunk {
                         path: path.clone(),
-                        snippet: code_file.to_string(),
+                        repo: repo_name.clone(),
+                        alias: None,
+                        snippet,
                         start_line: 1,
                         end_line: code_file.lines().count(),
+                        byte_range: None,
+                        symbol: None,
+                        symbol_type: None,
+                        score: None,
+                        source: None,
+                        doc: None,
+                        duplicates: Vec::new(),
+                        adjusted: false,
+                        is_test: false,
+                        is_vendored: false,
+                        demoted: false,
+                        overflow: false,
+                        embedded_lang: None,
                     }])),
                     warp::http::StatusCode::OK,
                 ))
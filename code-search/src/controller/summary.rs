@@ -0,0 +1,48 @@
+use std::{convert::Infallible, sync::Arc};
+
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::repo_summary::{RepoSummary, REPO_SUMMARY_PATH};
+use reqwest::StatusCode;
+
+use crate::{config::AppState, models::BranchQuery, search::code_search::get_file_content};
+
+// The summary generated when the repo was indexed, stored as a document of its quickwit index.
+pub async fn handle_repo_summary(
+    repo_name: String,
+    query: BranchQuery,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let branch = requested_branch(query.branch.as_deref());
+    match get_file_content(REPO_SUMMARY_PATH, &repo_name, branch, app_state).await {
+        Ok(Some(document)) => match serde_json::from_str::<RepoSummary>(&document.content) {
+            Ok(summary) => Ok(warp::reply::with_status(
+                warp::reply::json(&summary),
+                StatusCode::OK,
+            )),
+            Err(e) => {
+                log::error!("Failed to parse the summary of repo {}: {}", repo_name, e);
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Error: {}", e)),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        },
+        Ok(None) => Ok(warp::reply::with_status(
+            warp::reply::json(&format!("Repo {} has no summary, index it again", repo_name)),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => {
+            log::error!("Failed to fetch the summary of repo {}: {}", repo_name, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
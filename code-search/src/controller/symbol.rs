@@ -1,16 +1,24 @@
 use anyhow::Error;
+use common::branch::requested_branch;
+use common::generation::{IndexGeneration, IndexGenerationGone};
 use common::hasher::generate_qdrant_index_name;
+use common::terminology::render_expansions;
 use log::{debug, error, info};
 use reqwest::header::HeaderValue;
 use reqwest::Client;
 
 use std::convert::Infallible;
 use std::sync::Arc;
-use warp::{self, http::StatusCode};
+use warp::{self, http::StatusCode, Reply};
 
-use crate::config::{get_qdrant_api_key, get_semantic_db_url};
-use crate::{config::AppState, models::SymbolSearchRequest};
-use crate::search::code_search::code_search;
+use crate::controller::terminology::load_terminology;
+use crate::config::{get_max_chunks_per_path, get_qdrant_api_key, get_semantic_db_url};
+use crate::{config::AppState, models::{ExactSymbolQuery, SymbolSearchRequest}};
+use crate::search::code_search::{code_search, get_file_content, CodeSearchOptions};
+use crate::search::embedded::searched_embedded_lang;
+use crate::search::symbol_lookup::{exact_symbol_lookup, resolve_lines, GenerationSymbols};
+use crate::utilities::util::redact_snippets;
+use std::collections::HashMap;
 use anyhow::Result;
 use reqwest;
 use serde::{Deserialize, Serialize};
@@ -55,31 +63,186 @@ pub async fn symbol_search(
         return Ok(warp::reply::with_status(
             warp::reply::json(&response),
             warp::http::StatusCode::NOT_FOUND,
-        ));
+        )
+        .into_response());
+    }
+
+    let generation = search_request.index_generation.clone().unwrap_or_default();
+    if let Some(collection) = dropped_collection(&generation).await {
+        return Ok(generation_gone(collection).into_response());
     }
 
+    // a repo without a dictionary, or one that can't be read, is searched as asked.
+    let expansions = if search_request.terminology {
+        match load_terminology(&app_state, &search_request.repo_name).await {
+            Ok(terminology) => terminology
+                .map(|terminology| terminology.expand(&search_request.query))
+                .unwrap_or_default(),
+            Err(e) => {
+                error!(
+                    "Failed to load the terminology of repo {}, searching without it: {}",
+                    search_request.repo_name, e
+                );
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    // the SQL of the string literals when the query asks about a query, see `search::embedded`.
+    let embedded_lang =
+        searched_embedded_lang(search_request.embedded_lang.as_deref(), &search_request.query);
+
     let app_state_clone = Arc::clone(&app_state);
     let db = &app_state_clone.db_connection;
 
     match code_search(
         &search_request.query,
         &search_request.repo_name,
+        requested_branch(search_request.branch.as_deref()),
+        &generation,
+        &CodeSearchOptions {
+            dedupe: search_request.dedupe,
+            include_tests: search_request.include_tests,
+            recency: search_request.recency,
+            max_per_path: search_request
+                .max_per_path
+                .unwrap_or_else(get_max_chunks_per_path),
+            expansions: &expansions,
+            embedded_lang: embedded_lang.as_deref(),
+        },
         &db,
         app_state,
     )
     .await
     {
-        Ok(chunks) => Ok(warp::reply::with_status(
-            warp::reply::json(&chunks),
-            StatusCode::OK,
-        )),
+        Ok((mut chunks, reordered)) => {
+            redact_snippets(
+                chunks.iter_mut().map(|chunk| &mut chunk.snippet),
+                "/symbols",
+                &search_request.repo_name,
+            );
+            // the chunks moved after the other paths are flagged `overflow`, the header tells the
+            // caller the order isn't the score order anymore.
+            let reply = warp::reply::with_header(
+                warp::reply::with_status(warp::reply::json(&chunks), StatusCode::OK),
+                "X-Results-Diversified",
+                reordered.to_string(),
+            );
+            // the expansions the results were searched with, e.g. `helios -> billing engine`.
+            let expansion = render_expansions(&expansions)
+                .replace(|c: char| !c.is_ascii() || c.is_ascii_control(), "?");
+            Ok(warp::reply::with_header(reply, "X-Query-Expansion", expansion).into_response())
+        }
         Err(e) => Ok(warp::reply::with_status(
             warp::reply::json(&format!("Error: {}", e)),
             StatusCode::INTERNAL_SERVER_ERROR,
-        )),
+        )
+        .into_response()),
     }
 }
 
+// Finds every location of a symbol by its exact name, without any embedding similarity.
+pub async fn symbol_exact_lookup(
+    query: ExactSymbolQuery,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if query.name.trim().is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Error: name is empty"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let generation = match query.index_generation.as_deref().map(str::parse::<IndexGeneration>) {
+        Some(Ok(generation)) => Some(generation),
+        Some(Err(e)) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+        None => None,
+    };
+    let dropped = match &generation {
+        Some(generation) => dropped_collection(generation).await,
+        None => None,
+    };
+    if let Some(collection) = dropped {
+        return Ok(generation_gone(collection));
+    }
+
+    let case_sensitive = query.case_sensitive.unwrap_or(true);
+    let branch = requested_branch(query.branch.as_deref());
+    let mut found = match exact_symbol_lookup(
+        &GenerationSymbols::new(app_state.as_ref(), generation.as_ref()),
+        &query.repo_name,
+        branch,
+        &query.name,
+        query.kind.as_deref(),
+        query.container.as_deref(),
+        case_sensitive,
+    )
+    .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Exact lookup of symbol {} failed: {}", query.name, e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+    debug!("Found {} occurrences of symbol {}", found.len(), query.name);
+
+    // byte ranges are converted to lines with the line indices of each file, fetched once per file.
+    let mut line_end_indices = HashMap::new();
+    for occurrence in &found {
+        if line_end_indices.contains_key(&occurrence.path) {
+            continue;
+        }
+        match get_file_content(&occurrence.path, &query.repo_name, branch, app_state.clone())
+            .await
+        {
+            Ok(Some(document)) => {
+                line_end_indices.insert(occurrence.path.clone(), document.fetch_line_indices());
+            }
+            Ok(None) => debug!("File {} of symbol {} not found", occurrence.path, query.name),
+            Err(e) => error!("Failed to fetch file {}: {}", occurrence.path, e),
+        }
+    }
+    resolve_lines(&mut found, &line_end_indices);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&found),
+        StatusCode::OK,
+    ))
+}
+
+// The first collection of the pinned generation that was dropped since it was pinned. A failed
+// check isn't a dropped collection, the search reports its own error then.
+async fn dropped_collection(generation: &IndexGeneration) -> Option<String> {
+    for collection in generation.collections.values() {
+        if let Ok(false) =
+            get_collection_status(get_semantic_db_url(), collection, get_qdrant_api_key()).await
+        {
+            error!("Collection {} of the pinned index generation is gone", collection);
+            return Some(collection.clone());
+        }
+    }
+    None
+}
+
+// 410 Gone with the dropped collection, the coordinator asks to restart or re-pin the conversation.
+fn generation_gone(collection: String) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&IndexGenerationGone { collection }),
+        StatusCode::GONE,
+    )
+}
+
 // check if qdrant collection is available
 async fn get_collection_status(
     mut base_url: String,
@@ -0,0 +1,169 @@
+use std::{convert::Infallible, sync::Arc};
+
+use anyhow::Result;
+use common::auth::{self, Tenant};
+use common::branch::requested_branch;
+use common::terminology::{
+    RepoTerminology, TermSuggestion, Terminology, TERMINOLOGY_COLLECTION_NAME,
+    TERMINOLOGY_SUGGESTIONS_PATH,
+};
+use reqwest::StatusCode;
+
+use crate::config::AppState;
+use crate::search::code_search::get_file_content;
+use crate::search::terminology::{
+    parse_terminology, terminology_collection, terminology_point, terminology_request,
+};
+
+/// The dictionary of the repo, None when it has none.
+pub async fn load_terminology(
+    app_state: &AppState,
+    repo_name: &str,
+) -> Result<Option<Terminology>> {
+    let request = terminology_request(repo_name);
+    app_state
+        .db_connection
+        .semantic
+        .qdrant
+        .run(|client| {
+            let request = request.clone();
+            async move {
+                if !client.has_collection(TERMINOLOGY_COLLECTION_NAME).await? {
+                    return Ok(None);
+                }
+                let response = client.scroll(&request).await?;
+                Ok(response
+                    .result
+                    .into_iter()
+                    .next()
+                    .and_then(parse_terminology))
+            }
+        })
+        .await
+}
+
+// The terms mined from the docs of the default branch when it was indexed.
+async fn load_suggestions(
+    app_state: Arc<AppState>,
+    repo_name: &String,
+) -> Result<Vec<TermSuggestion>> {
+    let document = get_file_content(
+        TERMINOLOGY_SUGGESTIONS_PATH,
+        repo_name,
+        requested_branch(None),
+        app_state,
+    )
+    .await?;
+    match document {
+        Some(document) => Ok(serde_json::from_str(&document.content)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+// The dictionary of the repo along with the mined terms it doesn't have yet.
+pub async fn handle_get_terminology(
+    repo_name: String,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let terminology = match load_terminology(&app_state, &repo_name).await {
+        Ok(terminology) => terminology.unwrap_or_default(),
+        Err(e) => {
+            log::error!(
+                "Failed to fetch the terminology of repo {}: {}",
+                repo_name,
+                e
+            );
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+    // a repo indexed before the terms were mined has no suggestions, the dictionary still holds.
+    let suggestions = load_suggestions(app_state, &repo_name)
+        .await
+        .unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to fetch the terminology suggestions of repo {}: {}",
+                repo_name,
+                e
+            );
+            Vec::new()
+        });
+    let suggestions = terminology.pending(suggestions);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&RepoTerminology {
+            repo_name,
+            terminology,
+            suggestions,
+        }),
+        StatusCode::OK,
+    ))
+}
+
+// Replaces the dictionary of the repo, an empty one clears it.
+pub async fn handle_put_terminology(
+    repo_name: String,
+    terminology: Terminology,
+    tenant: Tenant,
+    app_state: Arc<AppState>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &repo_name));
+    }
+
+    let terminology = terminology.normalized();
+    log::info!(
+        "Storing {} terms for repo {} for tenant {}",
+        terminology.terms.len(),
+        repo_name,
+        tenant.id
+    );
+    match store_terminology(&app_state, &repo_name, &terminology).await {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&terminology),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            log::error!(
+                "Failed to store the terminology of repo {}: {}",
+                repo_name,
+                e
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+async fn store_terminology(
+    app_state: &AppState,
+    repo_name: &str,
+    terminology: &Terminology,
+) -> Result<()> {
+    let point = terminology_point(repo_name, terminology)?;
+    app_state
+        .db_connection
+        .semantic
+        .qdrant
+        .run(|client| {
+            let point = point.clone();
+            async move {
+                if !client.has_collection(TERMINOLOGY_COLLECTION_NAME).await? {
+                    client.create_collection(&terminology_collection()).await?;
+                }
+                client
+                    .upsert_points_blocking(TERMINOLOGY_COLLECTION_NAME, vec![point], None)
+                    .await?;
+                Ok(())
+            }
+        })
+        .await
+}
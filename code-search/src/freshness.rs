@@ -0,0 +1,275 @@
+// Freshness of the index of a branch: the commit it was indexed at against the head of the branch.
+// The head is read from the working copy of the repo when there is one, where the commits in
+// between can be counted, and from the remote recorded in the run manifest otherwise. A probe that
+// fails leaves the freshness unknown, it never fails the request.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use common::freshness::IndexFreshness;
+use common::run_manifest::RunManifest;
+use tokio::process::Command;
+
+// longest a git command of the probe may take, the remote can be slow to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the head of a branch, implemented with the git cli and mocked in tests.
+#[async_trait]
+pub trait GitProbe: Send + Sync {
+    async fn head(&self, branch: &str) -> Result<String>;
+    /// Commits reachable from `head` but not from `base`, None when the probe can't count them.
+    async fn commits_between(&self, base: &str, head: &str) -> Result<Option<usize>>;
+    /// Unix timestamp in seconds of the commit, None when the probe can't read it.
+    async fn commit_time(&self, commit: &str) -> Result<Option<u64>>;
+}
+
+/// Where `GitCli` reads the branches from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitSource {
+    WorkingCopy(PathBuf),
+    // only lists the heads, `git ls-remote`.
+    Remote(String),
+}
+
+impl GitSource {
+    /// The working copy of the repo in `working_copies_dir` if it has one, its remote otherwise.
+    pub fn of_repo(working_copies_dir: Option<&str>, manifest: &RunManifest) -> Option<Self> {
+        let working_copy = working_copies_dir
+            .map(|dir| PathBuf::from(dir).join(&manifest.repo_name))
+            .filter(|path| path.join(".git").exists());
+        match (working_copy, &manifest.remote_url) {
+            (Some(path), _) => Some(GitSource::WorkingCopy(path)),
+            (None, Some(url)) => Some(GitSource::Remote(url.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+pub struct GitCli {
+    pub source: GitSource,
+}
+
+impl GitCli {
+    async fn git(&self, args: &[&str]) -> Result<String> {
+        let mut command = Command::new("git");
+        if let GitSource::WorkingCopy(path) = &self.source {
+            command.arg("-C").arg(path);
+        }
+        // a remote asking for credentials fails the probe instead of waiting for them.
+        command
+            .args(args)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .kill_on_drop(true);
+        let output = tokio::time::timeout(PROBE_TIMEOUT, command.output())
+            .await
+            .map_err(|_| anyhow!("git {} timed out", args.join(" ")))?
+            .context("Failed to run git")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+#[async_trait]
+impl GitProbe for GitCli {
+    async fn head(&self, branch: &str) -> Result<String> {
+        let reference = format!("refs/heads/{}", branch);
+        let head = match &self.source {
+            GitSource::WorkingCopy(_) => {
+                self.git(&[
+                    "rev-parse",
+                    "--verify",
+                    &format!("{}^{{commit}}", reference),
+                ])
+                .await?
+            }
+            GitSource::Remote(url) => {
+                let listed = self.git(&["ls-remote", url, &reference]).await?;
+                listed
+                    .split_whitespace()
+                    .next()
+                    .ok_or_else(|| anyhow!("The remote has no branch {}", branch))?
+                    .to_string()
+            }
+        };
+        Ok(head)
+    }
+
+    async fn commits_between(&self, base: &str, head: &str) -> Result<Option<usize>> {
+        if let GitSource::Remote(_) = self.source {
+            return Ok(None);
+        }
+        let count = self
+            .git(&["rev-list", "--count", &format!("{}..{}", base, head)])
+            .await?;
+        Ok(Some(count.parse()?))
+    }
+
+    async fn commit_time(&self, commit: &str) -> Result<Option<u64>> {
+        if let GitSource::Remote(_) = self.source {
+            return Ok(None);
+        }
+        let time = self.git(&["show", "-s", "--format=%ct", commit]).await?;
+        Ok(Some(time.parse()?))
+    }
+}
+
+/// The freshness of the index the manifest was recorded for, probed with `probe`. Without a
+/// probe, or when it can't read the head of the branch, the freshness is unknown.
+pub async fn probe_freshness<P: GitProbe>(
+    probe: Option<&P>,
+    manifest: &RunManifest,
+) -> IndexFreshness {
+    let mut freshness = IndexFreshness {
+        repo_name: manifest.repo_name.clone(),
+        branch: manifest.branch.clone(),
+        indexed_commit: manifest.indexed_commit.clone(),
+        indexed_commit_at: manifest.indexed_commit_at,
+        ..Default::default()
+    };
+    let Some(probe) = probe else {
+        freshness.unknown_reason = Some("The repo has no working copy or remote".to_string());
+        return freshness;
+    };
+    let head = match probe.head(&manifest.branch).await {
+        Ok(head) => head,
+        Err(e) => {
+            log::warn!(
+                "Failed to probe the head of {} of {}: {}",
+                manifest.branch,
+                manifest.repo_name,
+                e
+            );
+            freshness.unknown_reason = Some(e.to_string());
+            return freshness;
+        }
+    };
+
+    if freshness.indexed_commit_at.is_none() {
+        freshness.indexed_commit_at = probe
+            .commit_time(&manifest.indexed_commit)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!(
+                    "Failed to read the time of {}: {}",
+                    manifest.indexed_commit,
+                    e
+                );
+                None
+            });
+    }
+    freshness.commits_behind = match head == manifest.indexed_commit {
+        true => Some(0),
+        // the indexed commit may be gone from the branch after a force push.
+        false => probe
+            .commits_between(&manifest.indexed_commit, &head)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to count the commits behind {}: {}", head, e);
+                None
+            }),
+    };
+    freshness.head_commit = Some(head);
+    freshness
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockProbe {
+        head: Option<&'static str>,
+        commits_behind: Option<usize>,
+    }
+
+    #[async_trait]
+    impl GitProbe for MockProbe {
+        async fn head(&self, _branch: &str) -> Result<String> {
+            self.head
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Could not read from remote repository"))
+        }
+
+        async fn commits_between(&self, _base: &str, _head: &str) -> Result<Option<usize>> {
+            Ok(self.commits_behind)
+        }
+
+        async fn commit_time(&self, _commit: &str) -> Result<Option<u64>> {
+            Ok(Some(1_699_000_000))
+        }
+    }
+
+    fn manifest() -> RunManifest {
+        RunManifest {
+            repo_name: "acme/api".to_string(),
+            branch: "main".to_string(),
+            indexed_commit: "abc1234".to_string(),
+            ..Default::default()
+        }
+    }
+
+    async fn probe(head: Option<&'static str>, commits_behind: Option<usize>) -> IndexFreshness {
+        let probe = MockProbe {
+            head,
+            commits_behind,
+        };
+        probe_freshness(Some(&probe), &manifest()).await
+    }
+
+    #[tokio::test]
+    async fn test_head_behind_ahead_and_unknown() {
+        let behind = probe(Some("fff0000"), Some(42)).await;
+        assert_eq!(behind.head_commit.as_deref(), Some("fff0000"));
+        assert_eq!(behind.commits_behind, Some(42));
+        assert_eq!(behind.indexed_commit_at, Some(1_699_000_000));
+
+        // the head is the indexed commit, nothing to count.
+        let current = probe(Some("abc1234"), None).await;
+        assert_eq!(current.commits_behind, Some(0));
+
+        // the branch was rewound or force pushed past the indexed commit.
+        let ahead = probe(Some("0001111"), Some(0)).await;
+        assert_eq!(ahead.head_commit.as_deref(), Some("0001111"));
+        assert_eq!(ahead.commits_behind, Some(0));
+
+        let unknown = probe(None, None).await;
+        assert_eq!(unknown.head_commit, None);
+        assert_eq!(
+            unknown.unknown_reason.as_deref(),
+            Some("Could not read from remote repository")
+        );
+
+        let unprobed = probe_freshness::<MockProbe>(None, &manifest()).await;
+        assert_eq!(unprobed.head_commit, None);
+        assert!(unprobed.unknown_reason.is_some());
+    }
+
+    #[test]
+    fn test_working_copy_is_preferred_to_the_remote() {
+        let dir = std::env::temp_dir().join(format!("freshness-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("acme/api/.git")).unwrap();
+        let mut manifest = manifest();
+        manifest.remote_url = Some("https://github.com/acme/api.git".to_string());
+
+        assert_eq!(
+            GitSource::of_repo(dir.to_str(), &manifest),
+            Some(GitSource::WorkingCopy(dir.join("acme/api")))
+        );
+        assert_eq!(
+            GitSource::of_repo(None, &manifest),
+            Some(GitSource::Remote(
+                "https://github.com/acme/api.git".to_string()
+            ))
+        );
+        manifest.remote_url = None;
+        assert_eq!(GitSource::of_repo(None, &manifest), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
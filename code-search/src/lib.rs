@@ -0,0 +1,15 @@
+mod code_navigation;
+pub mod config;
+mod controller;
+mod db;
+mod freshness;
+mod models;
+mod parser;
+pub mod routes;
+mod search;
+mod snippet;
+mod token_resolution;
+pub mod tune_ranking;
+mod utilities;
+
+extern crate reqwest;
@@ -1,21 +1,10 @@
-use config::initialize_config;
+use code_search::config::initialize_config;
+use code_search::routes;
+use common::shutdown;
 use log::error;
 use std::{env, sync::Arc};
 use warp;
 
-mod code_navigation;
-mod config;
-mod controller;
-mod db;
-mod models;
-mod parser;
-mod routes;
-mod search;
-mod snippet;
-mod utilities;
-
-extern crate reqwest;
-
 #[tokio::main]
 async fn main() {
     env_logger::init();
@@ -23,6 +12,16 @@ async fn main() {
     let args: Vec<String> = env::args().collect();
     let mut env_file: Option<String> = None;
 
+    // `code-search tune-ranking --log <log>` tunes the ranking weights offline and exits.
+    if args.get(1).map(String::as_str) == Some("tune-ranking") {
+        dotenv::dotenv().ok();
+        if let Err(err) = code_search::tune_ranking::run(&args[2..]) {
+            error!("Failed to tune the ranking weights: {:#}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if args.len() > 1 {
         for i in 1..args.len() {
             if args[i] == "--env-file" {
@@ -49,8 +48,29 @@ async fn main() {
         }
     };
 
+    // the api keys come from the env file loaded above.
+    if let Err(err) = common::auth::init_key_store() {
+        error!("Failed to initialize the API key store: {}", err);
+        std::process::exit(1);
+    }
+
+    // traces are only exported when an OTLP endpoint is configured in the env.
+    if let Err(err) = common::telemetry::init("code-search") {
+        error!("Failed to initialize tracing: {}", err);
+        std::process::exit(1);
+    }
+
     // set up the api routes
     let search_routes = routes::search_routes(app_state.clone());
 
-    warp::serve(search_routes).run(([0, 0, 0, 0], 3003)).await;
+    let shutdown = shutdown::global();
+    shutdown.listen_for_signals();
+
+    let (addr, server) = warp::serve(search_routes)
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], 3003), shutdown.wait());
+    log::info!("Started web server on http://{}", addr);
+
+    shutdown.drain(server, shutdown::drain_timeout()).await;
+    common::telemetry::shutdown();
+    log::info!("Code search shut down");
 }
@@ -1,10 +1,8 @@
+use common::auth::RepoScoped;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct SymbolSearchRequest {
-    pub query: String,
-    pub repo_name: String,
-}
+// the bodies and queries of the routes other services call, shared with their clients.
+pub use common::models::{ExactSymbolQuery, OwnersQuery, SymbolSearchRequest};
 
 /// Represents a request to fetch the parent scope of a specified code range within a file.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -19,4 +17,28 @@ pub struct ParentScopeRequest {
     pub end_line: usize,
     /// An optional identifier for the request, which can be used for tracking or caching.
     pub id: Option<String>,
-}
\ No newline at end of file
+    /// The branch of the file, the default branch when not set.
+    pub branch: Option<String>,
+}
+
+impl RepoScoped for ParentScopeRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo
+    }
+}
+
+/// Query of the embeddings export, e.g. `?format=jsonl&limit=1000`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EmbeddingExportQuery {
+    /// `jsonl` (default) or `parquet`.
+    pub format: Option<String>,
+    /// Maximum number of records to export, all the chunks of the repo when not set.
+    pub limit: Option<usize>,
+}
+
+/// Query of the routes reading one branch of a repo, e.g. `?branch=release-1.2`. The default
+/// branch when not set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BranchQuery {
+    pub branch: Option<String>,
+}
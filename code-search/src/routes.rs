@@ -1,25 +1,88 @@
 extern crate common;
-use common::models::CodeSpanRequest;
+use common::models::{CodeSpanRequest, ResolveAnchorsRequest};
 use common::TokenInfoRequest;
 
 use std::convert::Infallible;
 use std::sync::Arc;
+use common::attachments::{AttachmentIndexRequest, AttachmentSearchRequest};
+use common::capabilities::{version_route, Capability};
+use common::terminology::Terminology;
+use common::{auth, metrics, telemetry};
 use warp::{self, http::Response, Filter};
 
-use crate::controller::{navigator, parentscope, span, symbol};
+use crate::controller::{
+    anchors, attachments, branches, commit, export, freshness, generation, manifest, navigator,
+    owners, parentscope, paths, ranking, ready, span, summary, symbol, terminology,
+};
 use crate::db::DbConnect;
 // use crate::graph::symbol_ops;
 use crate::config::AppState;
-use crate::models::{ParentScopeRequest, SymbolSearchRequest};
+use crate::models::{
+    BranchQuery, EmbeddingExportQuery, ExactSymbolQuery, OwnersQuery, ParentScopeRequest,
+    SymbolSearchRequest,
+};
 
 pub fn search_routes(
     app_state: Arc<AppState>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    symbol_search(app_state.clone())
+    symbol_exact_lookup(app_state.clone())
+        .or(symbol_search(app_state.clone()))
         .or(health_check())
+        .or(readiness(app_state.clone()))
         .or(span_code_chunk_retrieve(app_state.clone()))
         .or(parent_scope_retrieve(app_state.clone()))
         .or(token_info_fetcher(app_state.clone()))
+        .or(embedding_export(app_state.clone()))
+        .or(repo_owners(app_state.clone()))
+        .or(indexed_commit())
+        .or(repo_summary(app_state.clone()))
+        .or(run_manifest(app_state.clone()))
+        .or(indexed_paths())
+        .or(indexed_branches())
+        .or(index_freshness(app_state.clone()))
+        .or(index_generation(app_state.clone()))
+        .or(get_terminology(app_state.clone()))
+        .or(put_terminology(app_state.clone()))
+        .or(index_attachment(app_state.clone()))
+        .or(search_attachments(app_state.clone()))
+        .or(delete_attachments(app_state.clone()))
+        .or(resolve_anchors(app_state.clone()))
+        .or(reload_ranking())
+        .or(version())
+        .or(metrics::metrics_route())
+        .recover(auth::handle_rejection)
+        .with(warp::log::custom(|info| {
+            metrics::observe_http_request(
+                "code-search",
+                info.path(),
+                info.method().as_str(),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }))
+        .with(warp::trace(telemetry::request_span))
+}
+
+/// GET /version
+/// Crate version and the optional API features of this build, for the handshake of the callers.
+fn version() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    version_route(
+        "code-search",
+        env!("CARGO_PKG_VERSION"),
+        &[
+            Capability::CodeOwners,
+            Capability::IndexedCommit,
+            Capability::RepoSummary,
+            Capability::RunManifest,
+            Capability::IndexedPaths,
+            Capability::Attachments,
+            Capability::Branches,
+            Capability::Freshness,
+            Capability::IndexGeneration,
+            Capability::Terminology,
+            Capability::AnchorResolution,
+        ],
+    )
 }
 
 fn health_check() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -33,6 +96,34 @@ fn health_check() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejec
         })
 }
 
+/// GET /ready
+/// 200 once qdrant lists its collections and quickwit its indexes, 503 otherwise. `degraded` is
+/// set while a database client is being rebuilt after a restart of the database.
+fn readiness(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(ready::handle_ready)
+}
+
+/// GET /symbols/exact?repo_name=<repo>&name=<symbol>&kind=<node kind>&container=<definition>&case_sensitive=<bool>
+/// Returns every occurrence of the symbol with its path, byte and line range, node kind, enclosing definitions and whether it is global.
+/// Filters the symbols by name without any vector search, case-sensitive unless `case_sensitive=false`.
+fn symbol_exact_lookup(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("symbols" / "exact")
+        .and(warp::get())
+        .and(warp::query::<ExactSymbolQuery>())
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(symbol::symbol_exact_lookup)
+}
+
 /// POST /symbols
 fn symbol_search(
     app_state: Arc<AppState>,
@@ -43,6 +134,8 @@ fn symbol_search(
             warp::body::content_length_limit(1024 * 16)
                 .and(warp::body::json::<SymbolSearchRequest>()),
         )
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
         .and(warp::any().map(move || app_state.clone()))
         .and_then(symbol::symbol_search)
 }
@@ -62,6 +155,9 @@ fn symbol_search(
 ///
 /// # Responses
 /// - Returns a `warp::Reply` on success, encapsulating the search results in JSON format.
+///   Ranges overhanging the end of the file are clamped to it, their chunks have `adjusted: true`.
+/// - Returns a 400 with the current `line_count` of the file when a range starts after its end line
+///   or beyond the end of the file.
 /// - Returns a `warp::Rejection` in case of errors or if the search criteria are not met.
 ///
 /// # Example Request
@@ -77,6 +173,8 @@ fn span_code_chunk_retrieve(
     warp::path("span")
         .and(warp::post())
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json::<CodeSpanRequest>()))
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
         .and(warp::any().map(move || app_state.clone()))
         .and_then(span::span_search)
 }
@@ -140,6 +238,8 @@ fn parent_scope_retrieve(
             warp::body::content_length_limit(1024 * 16)
                 .and(warp::body::json::<ParentScopeRequest>()),
         )
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
         .and(warp::any().map(move || app_state.clone()))
         .and_then(parentscope::parent_scope_search) // Assuming you have a corresponding handler in the controller
 }
@@ -152,13 +252,235 @@ fn token_info_fetcher(
         .and(
             warp::body::content_length_limit(1024 * 16).and(warp::body::json::<TokenInfoRequest>()),
         )
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
         .and(warp::any().map(move || app_state.clone()))
         .and_then(navigator::handle_token_info_fetcher_wrapper) // Assuming you have a corresponding handler in the controller
 }
 
+/// GET /repos/{name}/export/embeddings?format=jsonl|parquet&limit=<rows>
+/// Streams `{relative_path, start_line, end_line, content_hash, vector}` records of every chunk of the repo,
+/// e.g. `curl -H "x-api-key: <key>" ".../repos/my-repo/export/embeddings?limit=1000" > embeddings.jsonl`.
+fn embedding_export(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "export" / "embeddings")
+        .and(warp::get())
+        .and(warp::query::<EmbeddingExportQuery>())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(export::handle_embedding_export)
+}
+
+/// GET /repos/{name}/owners?path=<path>
+/// Returns the owners of the path from the CODEOWNERS file of the repo, with the rules they come from.
+/// The later rules of the file win, a repo without CODEOWNERS has no owners.
+fn repo_owners(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "owners")
+        .and(warp::get())
+        .and(warp::query::<OwnersQuery>())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(owners::handle_repo_owners)
+}
+
 /// Provides DbConnect instance wrapped in Arc<Mutex> to the next filter.
 fn with_db(
     db: Arc<DbConnect>,
 ) -> impl Filter<Extract = (Arc<DbConnect>,), Error = Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
+
+/// GET /repos/{name}/commit?branch=<branch>
+/// Returns the commit the branch was indexed at, `commit` is left out for repos indexed before it was recorded.
+fn indexed_commit() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "commit")
+        .and(warp::get())
+        .and(warp::query::<BranchQuery>())
+        .and(auth::authenticate())
+        .and_then(commit::handle_indexed_commit)
+}
+
+/// GET /repos/{name}/summary?branch=<branch>
+/// Returns the summary generated when the branch was indexed: languages, top level directories,
+/// frameworks and the start of the README. 404 for repos indexed before summaries were generated.
+fn repo_summary(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "summary")
+        .and(warp::get())
+        .and(warp::query::<BranchQuery>())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(summary::handle_repo_summary)
+}
+
+/// GET /repos/{name}/manifest?branch=<branch>
+/// Returns the manifest of the last ingestion run of the branch: the indexer version, embedding
+/// model, chunking and filter settings, commit, timings and counts. 404 for repos indexed before
+/// manifests were recorded.
+fn run_manifest(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "manifest")
+        .and(warp::get())
+        .and(warp::query::<BranchQuery>())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(manifest::handle_run_manifest)
+}
+
+/// GET /repos/{name}/paths?branch=<branch>
+/// Returns the relative paths of the indexed files of the branch, sorted.
+fn indexed_paths() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "paths")
+        .and(warp::get())
+        .and(warp::query::<BranchQuery>())
+        .and(auth::authenticate())
+        .and_then(paths::handle_indexed_paths)
+}
+
+/// GET /repos/{name}/branches
+/// Returns the indexed branches of the repo with the commit and run of their last indexing, sorted
+/// by name. The requests that don't name a branch are answered from `default_branch`.
+fn indexed_branches() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "branches")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and_then(branches::handle_indexed_branches)
+}
+
+/// GET /repos/{name}/freshness?branch=<branch>
+/// Returns the commit the branch was indexed at with the head of the branch, how many commits the
+/// index is behind and when the indexed commit was made. The head is probed in the working copy of
+/// the repo in `REPO_WORKING_COPIES_DIR`, or on the remote recorded when it was indexed, which
+/// doesn't count the commits. `head_commit` is left out with an `unknown_reason` when the probe fails.
+fn index_freshness(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "freshness")
+        .and(warp::get())
+        .and(warp::query::<BranchQuery>())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(freshness::handle_index_freshness)
+}
+
+/// GET /index-generation
+/// Returns the collections the aliases of the searched collections point to, e.g.
+/// `{"collections": {"documents": "documents_v2", "documents_symbol": "documents_symbol_v2"}}`.
+/// Searches given them as `index_generation` read from those collections instead of the aliases,
+/// and answer 410 Gone once one of them was dropped.
+fn index_generation(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("index-generation")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(generation::handle_index_generation)
+}
+
+/// GET /repos/{name}/terminology
+/// Returns the dictionary of the repo, `{"repo_name": ..., "terms": {"helios": ["billing engine"]},
+/// "suggestions": [...]}`. The suggestions are the terms mined from the README and docs of the
+/// default branch that the dictionary doesn't have yet, they aren't used until approved into it.
+fn get_terminology(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "terminology")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(terminology::handle_get_terminology)
+}
+
+/// PUT /repos/{name}/terminology
+/// Replaces the dictionary of the repo with `{"terms": {"helios": ["billing engine"]}}`. The
+/// searches of `POST /symbols` add the expansions of the terms their query mentions.
+fn put_terminology(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("repos" / String / "terminology")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024 * 256).and(warp::body::json::<Terminology>()))
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(terminology::handle_put_terminology)
+}
+
+/// POST /admin/ranking/reload
+/// Loads the ranking config of `RANKING_CONFIG_PATH` again and returns the weights the next
+/// searches use, e.g. right after `code-search tune-ranking` wrote it. Needs an admin key.
+fn reload_ranking() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "ranking" / "reload")
+        .and(warp::post())
+        .and(auth::authenticate().and_then(auth::authorize_admin))
+        .and_then(ranking::handle_reload_ranking)
+}
+
+/// POST /attachments
+/// Embeds the sections of a document attached to a conversation, they expire at `expires_at`.
+fn index_attachment(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("attachments")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 1024 * 4)
+                .and(warp::body::json::<AttachmentIndexRequest>()),
+        )
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(attachments::handle_index_attachment)
+}
+
+/// POST /attachments/search
+/// Returns the sections of the unexpired attachments of the conversation closest to the query.
+fn search_attachments(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("attachments" / "search")
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 16)
+                .and(warp::body::json::<AttachmentSearchRequest>()),
+        )
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(attachments::handle_search_attachments)
+}
+
+/// DELETE /attachments/{conversation_id}
+/// Deletes the attachments of the conversation.
+fn delete_attachments(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("attachments" / String)
+        .and(warp::delete())
+        .and(auth::authenticate())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(attachments::handle_delete_attachments)
+}
+
+/// POST /anchors/resolve
+/// Where the cited lines of content anchors are in the current index, one resolution per anchor
+/// in the order of the request: unchanged, moved, changed or deleted with the closest candidate.
+fn resolve_anchors(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("anchors" / "resolve")
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 64)
+                .and(warp::body::json::<ResolveAnchorsRequest>()),
+        )
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(anchors::handle_resolve_anchors)
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use common::attachments::{AttachmentHit, AttachmentSection, ATTACHMENT_COLLECTION_NAME};
+use qdrant_client::qdrant::{
+    points_selector::PointsSelectorOneOf, vectors_config, with_payload_selector, CreateCollection,
+    Distance, FieldCondition, Filter, PointId, PointStruct, PointsSelector, Range, ScoredPoint,
+    SearchPoints, Value, VectorParams, VectorsConfig, WithPayloadSelector,
+};
+
+use crate::search::{
+    payload::{kind_to_value, Embedding},
+    semantic::make_kv_keyword_filter,
+};
+
+/// The collection of the attachment sections, sized on the first embedding written to it.
+pub fn attachment_collection(dimension: u64) -> CreateCollection {
+    CreateCollection {
+        collection_name: ATTACHMENT_COLLECTION_NAME.to_string(),
+        vectors_config: Some(VectorsConfig {
+            config: Some(vectors_config::Config::Params(VectorParams {
+                size: dimension,
+                distance: Distance::Cosine.into(),
+                ..Default::default()
+            })),
+        }),
+        ..Default::default()
+    }
+}
+
+/// A section of an attachment of a conversation as a point. The id is derived from the
+/// conversation, attachment and section, so uploading the same attachment again overwrites it.
+pub fn attachment_point(
+    conversation_id: &str,
+    expires_at: u64,
+    section: &AttachmentSection,
+    vector: Embedding,
+) -> PointStruct {
+    let digest = md5::compute(format!(
+        "{}\n{}\n{}",
+        conversation_id, section.attachment_id, section.section
+    ));
+    let id = u64::from_be_bytes(digest.0[..8].try_into().unwrap());
+
+    let payload: HashMap<String, Value> = HashMap::from([
+        ("conversation_id".to_string(), conversation_id.into()),
+        (
+            "attachment_id".to_string(),
+            section.attachment_id.as_str().into(),
+        ),
+        ("name".to_string(), section.name.as_str().into()),
+        ("section".to_string(), section.section.as_str().into()),
+        ("text".to_string(), section.text.as_str().into()),
+        ("expires_at".to_string(), (expires_at as i64).into()),
+    ]);
+
+    PointStruct {
+        id: Some(PointId::from(id)),
+        vectors: Some(vector.into()),
+        payload,
+    }
+}
+
+/// Search of the sections closest to `vector` among the unexpired attachments of a conversation.
+/// The conversation filter is what keeps the attachments of a conversation out of the others.
+pub fn attachment_search_request(
+    conversation_id: &str,
+    vector: Embedding,
+    limit: u64,
+    now: u64,
+) -> SearchPoints {
+    SearchPoints {
+        collection_name: ATTACHMENT_COLLECTION_NAME.to_string(),
+        vector,
+        limit,
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        filter: Some(Filter {
+            must: vec![
+                make_kv_keyword_filter("conversation_id", conversation_id).into(),
+                expires_after(now).into(),
+            ],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// All the sections of the attachments of a conversation.
+pub fn conversation_selector(conversation_id: &str) -> PointsSelector {
+    PointsSelector {
+        points_selector_one_of: Some(PointsSelectorOneOf::Filter(Filter {
+            must: vec![make_kv_keyword_filter("conversation_id", conversation_id).into()],
+            ..Default::default()
+        })),
+    }
+}
+
+/// The sections of all the conversations that expired at `now`.
+pub fn expired_selector(now: u64) -> PointsSelector {
+    PointsSelector {
+        points_selector_one_of: Some(PointsSelectorOneOf::Filter(Filter {
+            must_not: vec![expires_after(now).into()],
+            ..Default::default()
+        })),
+    }
+}
+
+fn expires_after(now: u64) -> FieldCondition {
+    FieldCondition {
+        key: "expires_at".to_string(),
+        range: Some(Range {
+            gt: Some(now as f64),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Parses a point of the attachment collection, None when its payload misses a field.
+pub fn attachment_hit(point: ScoredPoint) -> Option<AttachmentHit> {
+    let mut payload = point
+        .payload
+        .into_iter()
+        .map(|(key, value)| (key, kind_to_value(value.kind)))
+        .collect::<HashMap<String, serde_json::Value>>();
+    let mut field = |key: &str| match payload.remove(key) {
+        Some(serde_json::Value::String(value)) => Some(value),
+        _ => None,
+    };
+
+    Some(AttachmentHit {
+        section: AttachmentSection {
+            attachment_id: field("attachment_id")?,
+            name: field("name")?,
+            section: field("section")?,
+            text: field("text")?,
+        },
+        score: point.score,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::{condition::ConditionOneOf, r#match::MatchValue, Condition};
+
+    fn section() -> AttachmentSection {
+        AttachmentSection {
+            attachment_id: "a1".to_string(),
+            name: "orders-api.json".to_string(),
+            section: "POST /orders/{id}/refunds".to_string(),
+            text: "Refunds are capped at the amount captured.".to_string(),
+        }
+    }
+
+    fn keyword(condition: &Condition) -> Option<(&str, &str)> {
+        match &condition.condition_one_of {
+            Some(ConditionOneOf::Field(field)) => {
+                match field.r#match.as_ref().and_then(|m| m.match_value.as_ref()) {
+                    Some(MatchValue::Keyword(value)) => Some((field.key.as_str(), value.as_str())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_search_is_limited_to_the_conversation_and_unexpired_sections() {
+        let request = attachment_search_request("conv-1", vec![0.1, 0.2], 3, 1_000);
+        let must = request.filter.unwrap().must;
+        assert_eq!(keyword(&must[0]), Some(("conversation_id", "conv-1")));
+        match &must[1].condition_one_of {
+            Some(ConditionOneOf::Field(field)) => {
+                assert_eq!(field.key, "expires_at");
+                assert_eq!(field.range.as_ref().unwrap().gt, Some(1_000.0));
+            }
+            other => panic!("expected a range condition, got {:?}", other),
+        }
+        assert_eq!(request.collection_name, ATTACHMENT_COLLECTION_NAME);
+
+        let Some(PointsSelectorOneOf::Filter(filter)) =
+            conversation_selector("conv-1").points_selector_one_of
+        else {
+            panic!("expected a filter selector");
+        };
+        assert_eq!(
+            keyword(&filter.must[0]),
+            Some(("conversation_id", "conv-1"))
+        );
+    }
+
+    #[test]
+    fn test_points_are_keyed_by_conversation() {
+        let point = attachment_point("conv-1", 2_000, &section(), vec![0.1, 0.2]);
+        let same = attachment_point("conv-1", 3_000, &section(), vec![0.3, 0.4]);
+        let other = attachment_point("conv-2", 2_000, &section(), vec![0.1, 0.2]);
+        assert_eq!(point.id, same.id);
+        assert_ne!(point.id, other.id);
+
+        let hit = attachment_hit(ScoredPoint {
+            payload: point.payload,
+            score: 0.8,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(hit.section, section());
+        assert!(attachment_hit(ScoredPoint::default()).is_none());
+    }
+}
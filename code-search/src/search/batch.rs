@@ -0,0 +1,183 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use qdrant_client::qdrant::{ScoredPoint, SearchPoints};
+
+pub use common::search_batch::{search_one, BatchSearcher, SearchBatch};
+
+use crate::config::AppState;
+
+#[async_trait]
+impl BatchSearcher for AppState {
+    async fn batch_search(
+        &self,
+        collection_name: &str,
+        searches: Vec<SearchPoints>,
+    ) -> Result<Vec<Vec<ScoredPoint>>> {
+        self.db_connection
+            .semantic
+            .qdrant
+            .batch_search(collection_name, searches)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::semantic::{
+        docs_search_request, embedded_search_request, in_generation, symbol_search_request,
+    };
+    use anyhow::anyhow;
+    use common::branch::BRANCH_FIELD;
+    use common::generation::IndexGeneration;
+    use common::service_interaction::{DOCUMENT_COLLECTION_NAME, SYMBOL_COLLECTION_NAME};
+    use common::task_graph::graph_model::TrackProcessV1;
+    use std::collections::HashMap;
+    use qdrant_client::qdrant::{condition::ConditionOneOf, r#match::MatchValue};
+    use std::sync::Mutex;
+
+    // Answers every search with a single point scored with the limit of the search,
+    // which tells the results of the searches apart.
+    struct MockSearcher {
+        requests: Mutex<Vec<(String, Vec<SearchPoints>)>>,
+        failing_collection: Option<String>,
+    }
+
+    impl MockSearcher {
+        fn new(failing_collection: Option<&str>) -> Self {
+            Self {
+                requests: Mutex::new(Vec::new()),
+                failing_collection: failing_collection.map(str::to_string),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl BatchSearcher for MockSearcher {
+        async fn batch_search(
+            &self,
+            collection_name: &str,
+            searches: Vec<SearchPoints>,
+        ) -> Result<Vec<Vec<ScoredPoint>>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((collection_name.to_string(), searches.clone()));
+            if self.failing_collection.as_deref() == Some(collection_name) {
+                return Err(anyhow!("collection not found"));
+            }
+            Ok(searches
+                .iter()
+                .map(|search| {
+                    vec![ScoredPoint {
+                        score: search.limit as f32,
+                        ..Default::default()
+                    }]
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_doc_and_embedded_searches_share_one_request() {
+        let searcher = MockSearcher::new(None);
+        let mut batch = SearchBatch::new();
+        let docs = batch.add(docs_search_request(vec![0.1; 4], 10, "repo", "main"));
+        let embedded = batch.add(embedded_search_request(
+            vec![0.1; 4],
+            5,
+            "repo",
+            "main",
+            "sql",
+        ));
+
+        let mut results = batch.execute(&searcher).await;
+
+        let requests = searcher.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, DOCUMENT_COLLECTION_NAME);
+        assert_eq!(requests[0].1.len(), 2);
+        assert_eq!(results.take(docs).unwrap()[0].score, 10.0);
+        assert_eq!(results.take(embedded).unwrap()[0].score, 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_questions_of_a_conversation_search_the_generation_it_started_with() {
+        let aliases = |suffix: &str| {
+            HashMap::from([
+                (DOCUMENT_COLLECTION_NAME.to_string(), format!("documents_{}", suffix)),
+                (SYMBOL_COLLECTION_NAME.to_string(), format!("documents_symbol_{}", suffix)),
+            ])
+        };
+        // the conversation records the generation the aliases point to when it starts.
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.initialize_graph();
+        tracker
+            .record_index_generation(IndexGeneration::from_aliases(&aliases("v1")))
+            .unwrap();
+
+        let searcher = MockSearcher::new(None);
+        let ask = |generation: IndexGeneration| {
+            let searcher = &searcher;
+            async move {
+                let mut batch = SearchBatch::new();
+                batch.add(in_generation(
+                    symbol_search_request(vec![0.1; 4], 20, 0, 0.0, "repo", "main"),
+                    &generation,
+                ));
+                batch.add(in_generation(
+                    docs_search_request(vec![0.1; 4], 10, "repo", "main"),
+                    &generation,
+                ));
+                batch.execute(searcher).await;
+            }
+        };
+        ask(tracker.index_generation().unwrap()).await;
+        // a migration switches the aliases between the two questions.
+        let current = IndexGeneration::from_aliases(&aliases("v2"));
+        ask(tracker.index_generation().unwrap()).await;
+
+        let mut searched = searcher
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(collection, _)| collection.clone())
+            .collect::<Vec<_>>();
+        searched.sort();
+        assert_eq!(
+            searched,
+            vec!["documents_symbol_v1", "documents_symbol_v1", "documents_v1", "documents_v1"]
+        );
+        // a conversation started after the switch searches the new generation.
+        assert_eq!(current.collection(DOCUMENT_COLLECTION_NAME), "documents_v2");
+    }
+
+    // the keyword the search filters `key` on.
+    fn filtered_keyword(search: &SearchPoints, key: &str) -> Option<String> {
+        search.filter.as_ref()?.must.iter().find_map(|condition| {
+            match condition.condition_one_of.as_ref()? {
+                ConditionOneOf::Field(field) if field.key == key => {
+                    match field.r#match.as_ref()?.match_value.as_ref()? {
+                        MatchValue::Keyword(keyword) => Some(keyword.clone()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn test_searches_only_match_their_branch() {
+        let on_release =
+            symbol_search_request(vec![0.1; 4], 20, 0, 0.0, "repo", "refs/heads/release-1.2");
+        assert_eq!(
+            filtered_keyword(&on_release, BRANCH_FIELD).as_deref(),
+            Some("release-1.2")
+        );
+        let on_main = docs_search_request(vec![0.1; 4], 10, "repo", "main");
+        assert_eq!(filtered_keyword(&on_main, BRANCH_FIELD).as_deref(), Some("main"));
+        assert_eq!(filtered_keyword(&on_main, "kind").as_deref(), Some("doc"));
+    }
+}
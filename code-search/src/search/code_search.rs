@@ -1,82 +1,435 @@
 use common::ast::graph_code_pluck::{ContentDocument, ExtractedContent};
 use common::ast::symbol::SymbolLocations;
+use common::branch::quickwit_branch_query;
+use common::generation::IndexGeneration;
 use common::hasher::generate_quikwit_index_name;
 use log::debug;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 extern crate common;
 
-use crate::config::AppState;
+use crate::config::{
+    get_dedup_threshold, get_deprecated_path_weight, get_doc_search_weight,
+    get_embedded_lang_weight, get_recency_half_life_days, get_search_fusion,
+    get_test_code_weight, AppState,
+};
 use crate::db::DbConnect;
-use crate::parser::literal::Literal;
-use crate::search::payload::{CodeExtractMeta, PathExtractMeta, SymbolPayload};
+use crate::search::payload::{
+    parse_symbol_points, CodeExtractMeta, PathExtractMeta, Payload, SymbolPayload,
+};
+use crate::search::hybrid::{
+    add_doc_hits, asks_current_behavior, build_keyword_query, classify_query, fuse,
+    keyword_extract_meta, keyword_terms, rank_scores, QueryKind,
+};
+use crate::search::batch::{search_one, SearchBatch};
+use crate::search::dedup::dedupe_chunks;
+use crate::search::demotion::{demote_flagged, demotion_reason, path_classes};
+use crate::search::diversity::diversify;
+use crate::search::embedded::{embedded_only_paths, merge_embedded_hits};
 use crate::search::ranking::rank_symbol_payloads;
+use crate::search::ranking_config;
+use crate::search::recency::rescore_by_recency;
+use crate::search::semantic::{
+    docs_search_request, embedded_search_request, in_generation, symbol_search_request,
+};
+use crate::search::terminology::weigh_expansion_hits;
 use common::models::CodeChunk;
+use common::path_class::{is_metadata_document, mentions_tests};
+use common::terminology::{expanded_text, render_expansions, QueryExpansion, EXPANSION_KEYWORD_WEIGHT};
 
 use anyhow::{anyhow, Error, Result};
 use serde::{Deserialize, Serialize};
 
-use super::quikwit::get_file_from_quickwit;
+use super::quikwit::{get_file_from_quickwit, keyword_search};
 
 const CODE_SEARCH_LIMIT: u64 = 10;
 
+/// How a `code_search` ranks and trims its chunks, from the flags of the request.
+#[derive(Debug, Clone, Copy)]
+pub struct CodeSearchOptions<'a> {
+    pub dedupe: bool,
+    pub include_tests: bool,
+    // prefer the recently changed files, like a query about the current behavior does.
+    pub recency: bool,
+    pub max_per_path: usize,
+    // the expansions of the jargon of the query, embedded and searched along with it.
+    pub expansions: &'a [QueryExpansion],
+    // e.g. the SQL of the string literals, see `search::embedded`.
+    pub embedded_lang: Option<&'a str>,
+}
+
+/// The chunks of the code matching the query, best first, and whether keeping the paths under
+/// `max_per_path` chunks changed their order. The embeddings are searched in the collections of
+/// `generation`, the keywords in the quickwit index of the repo. The files only the `expansions`
+/// find weigh less. The chunks of `embedded_lang` boost their paths and are returned as they were
+/// indexed when nothing else was extracted around them.
 pub async fn code_search(
     query: &String,
     repo_name: &String,
+    branch: &str,
+    generation: &IndexGeneration,
+    options: &CodeSearchOptions<'_>,
     db_client: &DbConnect,
     app_state: Arc<AppState>,
-) -> Result<Vec<CodeChunk>> {
-    // performing semantic search on the symbols.
-    log::debug!("semantic search\n");
-    let results_symbol: Vec<crate::search::payload::SymbolPayload> = semantic_search_symbol(
-        query.into(),
-        CODE_SEARCH_LIMIT,
-        0,
-        0.0,
-        true,
-        db_client,
-        repo_name,
-    )
-    .await?;
+) -> Result<(Vec<CodeChunk>, bool)> {
+    let CodeSearchOptions {
+        dedupe,
+        include_tests,
+        recency,
+        max_per_path,
+        expansions,
+        embedded_lang,
+    } = *options;
+    // identifiers are better found by keywords than by embeddings, the query decides how much each weighs.
+    let query_kind = classify_query(query);
+    // the weights tuned from the citations of the answers, the same for the whole search.
+    let ranking = ranking_config::current();
+    let keyword_weight = ranking.keyword_weights.get(query_kind);
+    let terms = keyword_terms(query);
+    // the expansions are phrases of the keyword query, matched like the quoted text of a query.
+    let expanded_terms = terms
+        .iter()
+        .cloned()
+        .chain(expansions.iter().flat_map(|expansion| expansion.expansions.clone()))
+        .collect::<Vec<_>>();
+    let keyword_query = build_keyword_query(&expanded_terms);
+    let embedded_query = expanded_text(query, expansions);
+    if !expansions.is_empty() {
+        log::info!("Query expanded with {}", render_expansions(expansions));
+    }
+    log::debug!(
+        "hybrid search, query kind: {:?}, keyword query: {:?}",
+        query_kind,
+        keyword_query
+    );
+
+    let index_name = generate_quikwit_index_name(repo_name);
+    let doc_search_weight = get_doc_search_weight();
+    let embedded_lang_weight = get_embedded_lang_weight();
+    let embedded_lang = embedded_lang.filter(|_| embedded_lang_weight > 0.0);
+    let ((results_symbol, doc_hits, embedded_hits), keyword_docs) = tokio::join!(
+        semantic_searches(
+            &embedded_query,
+            doc_search_weight > 0.0,
+            embedded_lang,
+            db_client,
+            repo_name,
+            branch,
+            generation,
+        ),
+        async {
+            match &keyword_query {
+                Some(keyword_query) => {
+                    let keyword_query = quickwit_branch_query(keyword_query, branch);
+                    keyword_search(&index_name, &keyword_query, CODE_SEARCH_LIMIT as i32).await
+                }
+                None => Ok(Vec::new()),
+            }
+        }
+    );
+    // the doc hits only boost the others, the search goes on without them.
+    let doc_hits = doc_hits.unwrap_or_else(|err| {
+        log::error!("doc search failed, using the code hits only: {:?}", err);
+        Vec::new()
+    });
+    let embedded_hits = embedded_hits.unwrap_or_else(|err| {
+        log::error!("embedded language search failed, searching without it: {:?}", err);
+        Vec::new()
+    });
+    if let Some(lang) = embedded_lang {
+        log::debug!("{} chunks of embedded {} found", embedded_hits.len(), lang);
+    }
+
+    // either search is enough to answer, only both failing fails the search.
+    let (results_symbol, keyword_docs) = match (results_symbol, keyword_docs) {
+        (Err(err), Err(keyword_err)) => {
+            log::error!("keyword search error: {:?}", keyword_err);
+            return Err(err);
+        }
+        (Err(err), Ok(docs)) => {
+            log::error!("semantic search failed, using the keyword hits only: {:?}", err);
+            (Vec::new(), docs)
+        }
+        (Ok(symbols), Err(err)) => {
+            log::error!("keyword search failed, using the semantic hits only: {:?}", err);
+            (symbols, Vec::new())
+        }
+        (Ok(symbols), Ok(docs)) => (symbols, docs),
+    };
 
     log::debug!("semantic search results: {:?}", results_symbol);
     // for top 3 symbols, perform semantic search using the symbol as a query and print the results with good formatting
     for symbol in results_symbol.iter().take(10) {
         log::debug!(
-                "Symbol semantic search on chunk: Symbol: {}, Score: {:?}, Relative paths: {:?}, Types: {:?}, isglobals:{:?}, node_types:{:?}",
-                symbol.symbol, symbol.score, symbol.relative_paths, symbol.symbol_types, symbol.is_globals, symbol.node_kinds,
+                "Symbol semantic search on chunk: Symbol: {}, Score: {:?}, Occurrences: {:?}",
+                symbol.symbol, symbol.score, symbol.occurrences,
             );
     }
 
-    let ranked_symbols = rank_symbol_payloads(&results_symbol);
+    let ranked_symbols = rank_symbol_payloads(&results_symbol, &ranking);
 
     // iterate and print the top paths with score
     for meta in ranked_symbols.iter().take(10) {
         log::debug!("Path: {}, Score: {}", meta.path, meta.score);
     }
+
+    // chunks are extracted around the symbols of the file, files without a scope graph can't be extracted.
+    let keyword_docs = keyword_docs
+        .into_iter()
+        .filter(|doc| {
+            matches!(
+                doc.symbol_locations().map(|locations| locations.scope_graph().is_some()),
+                Ok(true)
+            )
+        })
+        .collect::<Vec<_>>();
+    for doc in keyword_docs.iter().take(10) {
+        log::debug!("Keyword hit: Path: {}", doc.relative_path);
+    }
+
+    let (keyword_scores, expansion_only) = if expansions.is_empty() {
+        let scores = keyword_docs
+            .iter()
+            .map(|doc| doc.relative_path.clone())
+            .zip(rank_scores(keyword_docs.len()))
+            .collect::<Vec<_>>();
+        (scores, HashSet::new())
+    } else {
+        weigh_expansion_hits(
+            &keyword_docs
+                .iter()
+                .map(|doc| (doc.relative_path.clone(), doc.content.as_str()))
+                .collect::<Vec<_>>(),
+            &terms,
+        )
+    };
+    let fused = fuse(
+        &ranked_symbols
+            .iter()
+            .map(|meta| (meta.path.clone(), meta.score))
+            .collect::<Vec<_>>(),
+        &keyword_scores,
+        get_search_fusion(),
+        keyword_weight,
+    );
+    let fused = add_doc_hits(
+        fused,
+        &doc_hits
+            .iter()
+            .map(|doc| (doc.relative_path.clone(), doc.score.unwrap_or(0.0)))
+            .collect::<Vec<_>>(),
+        get_search_fusion(),
+        doc_search_weight,
+    );
+    let mut fused = add_doc_hits(
+        fused,
+        &embedded_hits
+            .iter()
+            .map(|hit| (hit.relative_path.clone(), hit.score.unwrap_or(0.0)))
+            .collect::<Vec<_>>(),
+        get_search_fusion(),
+        embedded_lang_weight,
+    );
+    // the metadata documents of the repo aren't code, whichever search found them.
+    fused.retain(|hit| !is_metadata_document(&hit.path));
+    // their chunks are the ones indexed, there is no scope graph to extract others from.
+    let embedded_only = embedded_only_paths(
+        &embedded_hits,
+        ranked_symbols
+            .iter()
+            .map(|meta| meta.path.as_str())
+            .chain(keyword_docs.iter().map(|doc| doc.relative_path.as_str()))
+            .chain(doc_hits.iter().map(|doc| doc.relative_path.as_str())),
+    );
+
+    let chunk_hits = doc_hits
+        .iter()
+        .chain(&embedded_hits)
+        .cloned()
+        .collect::<Vec<_>>();
+    let classes = path_classes(fused.iter().map(|hit| hit.path.as_str()), &chunk_hits);
+
+    let mut path_metas = ranked_symbols
+        .into_iter()
+        .map(|meta| (meta.path.clone(), meta))
+        .collect::<HashMap<_, _>>();
+    let mut keyword_metas = keyword_docs
+        .iter()
+        .map(|doc| {
+            (
+                doc.relative_path.clone(),
+                keyword_extract_meta(&doc.content, &expanded_terms),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let mut doc_metas: HashMap<String, Vec<CodeExtractMeta>> = HashMap::new();
+    for doc in &doc_hits {
+        doc_metas
+            .entry(doc.relative_path.clone())
+            .or_default()
+            .push(doc_extract_meta(doc));
+    }
+    let top_paths = fused
+        .iter()
+        .take(CODE_SEARCH_LIMIT as usize)
+        .filter(|hit| !embedded_only.contains(&hit.path))
+        .map(|hit| {
+            let mut meta = path_metas.remove(&hit.path).unwrap_or_else(|| PathExtractMeta {
+                path: hit.path.clone(),
+                ..Default::default()
+            });
+            meta.score = hit.score;
+            meta.history
+                .push(format!("Fused score {} from {:?} search", hit.score, hit.source));
+            if expansion_only.contains(&hit.path) {
+                meta.history.push(format!(
+                    "Found through the expansion {}, keyword score weighed by {}",
+                    render_expansions(expansions),
+                    EXPANSION_KEYWORD_WEIGHT
+                ));
+            }
+            // the keyword matches are extracted first when the keywords weigh more than the embeddings.
+            let keyword_meta = keyword_metas.remove(&hit.path).unwrap_or_default();
+            if keyword_weight > 0.5 {
+                meta.code_extract_meta.splice(0..0, keyword_meta);
+            } else {
+                meta.code_extract_meta.extend(keyword_meta);
+            }
+            // the definitions documented like the query asks come first unless it names an identifier.
+            let doc_meta = doc_metas.remove(&hit.path).unwrap_or_default();
+            if query_kind == QueryKind::Identifier {
+                meta.code_extract_meta.extend(doc_meta);
+            } else {
+                meta.code_extract_meta.splice(0..0, doc_meta);
+            }
+            // a keyword hit on the symbols only, extract the top of the file.
+            if meta.code_extract_meta.is_empty() {
+                meta.code_extract_meta.push(CodeExtractMeta::default());
+            }
+            meta
+        })
+        .collect::<Vec<_>>();
+    let fused_scores = fused
+        .iter()
+        .map(|hit| (hit.path.clone(), (hit.score, hit.source)))
+        .collect::<HashMap<_, _>>();
+
     // call self.get_scope_graph on top 3 paths from ranked_symbpls
-    let extracted_chunks = process_paths(
-        ranked_symbols.iter().cloned().take(10).collect(),
-        repo_name,
-        app_state,
-    )
-    .await?;
+    let extracted_chunks = process_paths(top_paths, repo_name, branch, app_state).await?;
 
     // Most likely needs to be changed based on API response requirements
     // create codeChunks from the extracted_chunks and append to chunks
+    // the score of a chunk is the fused score of its path until the rerank, so the agent can prioritize
+    // chunks downstream.
     let mut code_chunks = extracted_chunks
         .into_iter()
-        .map(|chunk| {
+        .map(|(chunk, symbol_type)| {
             let relative_path = chunk.path;
+            let fused = fused_scores.get(&relative_path);
+            let doc = chunk_doc(&doc_hits, &relative_path, chunk.start_line, chunk.end_line);
+            let class = classes.get(&relative_path).copied().unwrap_or_default();
 
             CodeChunk {
+                score: fused.map(|(score, _)| *score),
+                source: fused.map(|(_, source)| *source),
                 path: relative_path.clone(),
+                repo: repo_name.clone(),
+                alias: None,
                 snippet: chunk.content,
                 start_line: chunk.start_line as usize,
                 end_line: chunk.end_line as usize,
+                byte_range: Some(chunk.start_byte..chunk.end_byte),
+                symbol: None,
+                symbol_type,
+                doc,
+                duplicates: Vec::new(),
+                adjusted: false,
+                is_test: class.is_test,
+                is_vendored: class.is_vendored,
+                demoted: false,
+                overflow: false,
+                embedded_lang: None,
             }
         })
         .collect::<Vec<_>>();
+    let top_fused_scores = fused
+        .iter()
+        .take(CODE_SEARCH_LIMIT as usize)
+        .filter_map(|hit| Some((hit.path.clone(), *fused_scores.get(&hit.path)?)))
+        .collect::<HashMap<_, _>>();
+    let embedded_chunks =
+        merge_embedded_hits(&mut code_chunks, &embedded_hits, repo_name, &top_fused_scores);
+    code_chunks.extend(embedded_chunks);
+
+    // vendored and copied code would otherwise fill the results with the same snippet. The copies
+    // are collapsed on their retrieval scores, before the rerank.
+    if dedupe {
+        let found = code_chunks.len();
+        code_chunks = dedupe_chunks(code_chunks, get_dedup_threshold());
+        log::debug!("deduped {} chunks to {}", found, code_chunks.len());
+    }
+
+    // the rerank of the paths left: test and vendored code stays in the results with a lower
+    // score, unless the query is about tests.
+    let kept_paths = code_chunks
+        .iter()
+        .map(|chunk| chunk.path.clone())
+        .collect::<HashSet<_>>();
+    let kept = fused
+        .into_iter()
+        .filter(|hit| kept_paths.contains(&hit.path))
+        .collect::<Vec<_>>();
+    let test_code_weight = get_test_code_weight();
+    let (kept, demoted) = if include_tests || mentions_tests(query) {
+        (kept, HashSet::new())
+    } else {
+        demote_flagged(kept, &classes, test_code_weight)
+    };
+    for path in &demoted {
+        log::debug!(
+            "{}: {}",
+            path,
+            demotion_reason(&classes[path], test_code_weight)
+        );
+    }
+
+    // a deprecated implementation would answer a question about the current behavior with dead code.
+    let boost_recency = recency || asks_current_behavior(query);
+    let last_modified = if boost_recency {
+        let paths = kept.iter().map(|hit| hit.path.clone()).collect::<Vec<_>>();
+        db_client
+            .semantic
+            .last_modified(repo_name, branch, &paths, generation)
+            .await
+    } else {
+        HashMap::new()
+    };
+    let (kept, recency_reasons) = rescore_by_recency(
+        kept,
+        &last_modified,
+        boost_recency.then(|| {
+            ranking
+                .recency_half_life_days
+                .unwrap_or_else(get_recency_half_life_days)
+        }),
+        get_deprecated_path_weight(),
+    );
+    for (path, reasons) in &recency_reasons {
+        for reason in reasons {
+            log::debug!("{}: {}", path, reason);
+        }
+    }
+    let reranked = kept
+        .into_iter()
+        .map(|hit| (hit.path, hit.score))
+        .collect::<HashMap<_, _>>();
+    for chunk in code_chunks.iter_mut() {
+        if let Some(score) = reranked.get(&chunk.path) {
+            chunk.score = Some(*score);
+        }
+        chunk.demoted = demoted.contains(&chunk.path);
+    }
 
     // iterate and print the code chunks
     for code_chunk in code_chunks.iter().take(10) {
@@ -90,41 +443,132 @@ pub async fn code_search(
     }
     //chunks.append(&mut codeChunks);
 
-    code_chunks.sort_by(|a, b| a.path.cmp(&b.path).then(a.start_line.cmp(&b.start_line)));
+    // best path first, the chunks of a path in file order.
+    code_chunks.sort_by(|a, b| {
+        b.score
+            .unwrap_or(0.0)
+            .total_cmp(&a.score.unwrap_or(0.0))
+            .then(a.path.cmp(&b.path))
+            .then(a.start_line.cmp(&b.start_line))
+    });
+
+    // a file with many matches would otherwise take all the room of the context.
+    let (code_chunks, reordered) = diversify(code_chunks, max_per_path);
+    if reordered {
+        log::debug!("moved the chunks over {} per path after the other paths", max_per_path);
+    }
 
-    Ok(code_chunks)
+    Ok((code_chunks, reordered))
 }
 
-async fn semantic_search_symbol<'a>(
-    query: Literal<'a>,
-    limit: u64,
-    offset: u64,
-    threshold: f32,
-    retrieve_more: bool,
+// the definition a doc hit documents, extracted like the definition of a symbol hit.
+fn doc_extract_meta(doc: &Payload) -> CodeExtractMeta {
+    CodeExtractMeta {
+        start_byte: doc.start_byte as i64,
+        end_byte: doc.end_byte as i64,
+        node_kind: "doc".to_string(),
+        symbol: doc.symbol.clone().unwrap_or_default(),
+        score: doc.score.unwrap_or(0.0),
+        ..Default::default()
+    }
+}
+
+// the docs of the hit definitions starting within the lines of a chunk.
+fn chunk_doc(
+    doc_hits: &[Payload],
+    path: &str,
+    start_line: usize,
+    end_line: usize,
+) -> Option<String> {
+    let mut docs = doc_hits
+        .iter()
+        .filter(|doc| doc.relative_path == path)
+        .filter(|doc| (start_line..=end_line).contains(&(doc.start_line as usize)))
+        .filter_map(|doc| doc.doc.as_deref())
+        .collect::<Vec<_>>();
+    docs.dedup();
+    (!docs.is_empty()).then(|| docs.join("\n\n"))
+}
+
+// The symbol, doc and embedded language searches of the query embed it once, in the collections
+// of `generation`. The doc and embedded searches share the documents collection and are sent as
+// one batch, the symbol search runs next to it.
+// The symbols are searched with double the limit, they are deduplicated after.
+async fn semantic_searches(
+    query: &str,
+    with_docs: bool,
+    embedded_lang: Option<&str>,
     db_client: &DbConnect,
-    repo_name: &String,
-) -> Result<Vec<SymbolPayload>> {
+    repo_name: &str,
+    branch: &str,
+    generation: &IndexGeneration,
+) -> (
+    Result<Vec<SymbolPayload>>,
+    Result<Vec<Payload>>,
+    Result<Vec<Payload>>,
+) {
     debug!("Repo name inside semantic search symbol: {:?}", repo_name);
-    let semantic_result = db_client
-        .semantic
-        .search_symbol(query, limit, offset, threshold, retrieve_more, repo_name)
-        .await;
-
-    match semantic_result {
-        Ok(result) => Ok(result),
+    let limit = CODE_SEARCH_LIMIT;
+    let vector = match db_client.semantic.embed(query) {
+        Ok(vector) => vector,
         Err(err) => {
             log::error!("semantic search error: {:?}", err);
-            Err(err)
+            return (
+                Err(anyhow!("failed to embed the query: {:?}", err)),
+                Err(anyhow!("failed to embed the query: {:?}", err)),
+                Err(err),
+            );
         }
+    };
+
+    let symbol_search = in_generation(
+        symbol_search_request(vector.clone(), limit * 2, 0, 0.0, repo_name, branch),
+        generation,
+    );
+    let mut batch = SearchBatch::new();
+    let doc_slot = with_docs.then(|| {
+        batch.add(in_generation(
+            docs_search_request(vector.clone(), limit, repo_name, branch),
+            generation,
+        ))
+    });
+    let embedded_slot = embedded_lang.map(|lang| {
+        batch.add(in_generation(
+            embedded_search_request(vector, limit, repo_name, branch, lang),
+            generation,
+        ))
+    });
+    let qdrant = &db_client.semantic.qdrant;
+    let (symbols, mut results) =
+        tokio::join!(search_one(qdrant, symbol_search), batch.execute(qdrant));
+
+    let symbols = symbols.map(parse_symbol_points);
+    if let Err(err) = &symbols {
+        log::error!("semantic search error: {:?}", err);
     }
+    let docs = match doc_slot {
+        Some(slot) => results
+            .take(slot)
+            .map(|points| points.into_iter().map(Payload::from_qdrant).collect()),
+        None => Ok(Vec::new()),
+    };
+    let embedded = match embedded_slot {
+        Some(slot) => results
+            .take(slot)
+            .map(|points| points.into_iter().map(Payload::from_qdrant).collect()),
+        None => Ok(Vec::new()),
+    };
+    (symbols, docs, embedded)
 }
 
 async fn process_paths(
     path_extract_meta: Vec<PathExtractMeta>,
     repo_name: &String,
+    branch: &str,
     app_state: Arc<AppState>,
-) -> Result<Vec<ExtractedContent>, anyhow::Error> {
-    // Initialize an empty vector to store the extracted contents.
+) -> Result<Vec<(ExtractedContent, Option<String>)>, anyhow::Error> {
+    // Initialize an empty vector to store the extracted contents, with the kind of the symbol
+    // they were extracted around.
     let mut results = Vec::new();
 
     // Iterate over each provided path and its associated metadata.
@@ -135,7 +579,7 @@ async fn process_paths(
         // Fetch the content of the file for the current path.
         let app_state_clone = Arc::clone(&app_state);
 
-        let source_document = get_file_content(path, repo_name, app_state_clone).await?;
+        let source_document = get_file_content(path, repo_name, branch, app_state_clone).await?;
 
         // log the error and continue to the next path if the file content is not found.
         if source_document.is_none() {
@@ -189,20 +633,23 @@ async fn process_paths(
             );
 
             // Store the extracted content in the results vector.
-            results.push(extract_content);
+            let symbol_type = Some(code_meta.symbol_type.clone()).filter(|kind| !kind.is_empty());
+            results.push((extract_content, symbol_type));
         }
     }
 
     Ok(results)
 }
 
+/// The document of the file on `branch`.
 pub async fn get_file_content(
     path: &str,
     repo_name: &String,
+    branch: &str,
     app_state: Arc<AppState>,
 ) -> Result<Option<ContentDocument>> {
     let new_index_id = generate_quikwit_index_name(repo_name);
 
-    log::debug!("fetching file content {}\n", path);
-    get_file_from_quickwit(&new_index_id, "relative_path", path).await
+    log::debug!("fetching file content {} on {}\n", path, branch);
+    get_file_from_quickwit(&new_index_id, "relative_path", path, branch).await
 }
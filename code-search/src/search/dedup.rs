@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+
+use common::models::CodeChunk;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// chunks with fewer distinct tokens than this are too small to tell copies from lookalikes.
+const MIN_TOKENS: usize = 8;
+
+lazy_static! {
+    static ref TOKEN: Regex = Regex::new(r"[A-Za-z0-9_]+|[^\sA-Za-z0-9_]").unwrap();
+}
+
+// Distinct tokens of the text, identifiers and numbers are kept whole and every other
+// non-whitespace character is a token, so formatting changes don't matter.
+fn token_set(text: &str) -> HashSet<&str> {
+    TOKEN.find_iter(text).map(|token| token.as_str()).collect()
+}
+
+/// Jaccard similarity of the token sets, 0 when either is too small to compare.
+pub fn similarity(a: &str, b: &str) -> f32 {
+    jaccard(&token_set(a), &token_set(b))
+}
+
+fn jaccard(a: &HashSet<&str>, b: &HashSet<&str>) -> f32 {
+    if a.len() < MIN_TOKENS || b.len() < MIN_TOKENS {
+        return 0.0;
+    }
+    let shared = a.intersection(b).count();
+    shared as f32 / (a.len() + b.len() - shared) as f32
+}
+
+/// Collapses chunks of different paths whose texts are at least `threshold` similar into the
+/// best scored of them, the paths of the others are listed in its `duplicates`.
+/// The kept chunks stay in their original order.
+pub fn dedupe_chunks(chunks: Vec<CodeChunk>, threshold: f32) -> Vec<CodeChunk> {
+    let tokens = chunks
+        .iter()
+        .map(|chunk| token_set(&chunk.snippet))
+        .collect::<Vec<_>>();
+
+    // best chunk first, so every group is represented by its best chunk.
+    let mut order = (0..chunks.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        chunks[*b]
+            .score
+            .unwrap_or(0.0)
+            .total_cmp(&chunks[*a].score.unwrap_or(0.0))
+    });
+
+    // index of the representative of every chunk.
+    let mut representative_of = vec![None; chunks.len()];
+    let mut representatives: Vec<usize> = Vec::new();
+    for index in order {
+        let duplicate_of = representatives.iter().copied().find(|rep| {
+            chunks[*rep].path != chunks[index].path
+                && jaccard(&tokens[*rep], &tokens[index]) >= threshold
+        });
+        match duplicate_of {
+            Some(rep) => representative_of[index] = Some(rep),
+            None => representatives.push(index),
+        }
+    }
+
+    let mut duplicates = vec![Vec::<String>::new(); chunks.len()];
+    for (index, rep) in representative_of.iter().enumerate() {
+        if let Some(rep) = rep {
+            let path = &chunks[index].path;
+            if !duplicates[*rep].contains(path) {
+                duplicates[*rep].push(path.clone());
+            }
+        }
+    }
+
+    chunks
+        .into_iter()
+        .zip(duplicates)
+        .enumerate()
+        .filter(|(index, _)| representative_of[*index].is_none())
+        .map(|(_, (mut chunk, duplicates))| {
+            for path in duplicates {
+                if !chunk.duplicates.contains(&path) {
+                    chunk.duplicates.push(path);
+                }
+            }
+            chunk
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRY: &str = "fn retry<F: Fn() -> Result<u32, Error>>(attempts: usize, f: F) -> Result<u32, Error> {
+    let mut last = None;
+    for _ in 0..attempts {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap())
+}";
+
+    fn chunk(path: &str, snippet: &str, score: f32) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            snippet: snippet.to_string(),
+            start_line: 0,
+            end_line: snippet.lines().count(),
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_near_duplicates_collapse_into_best_chunk() {
+        // the vendored copies are reformatted and have a comment, still the same code.
+        let vendored = format!("// vendored from upstream\n{}", RETRY.replace("    ", "\t"));
+        let connect = "pub fn connect(url: &str) -> Connection {\n    Connection::open(url).expect(\"no db\")\n}";
+        let chunks = vec![
+            chunk("vendor/a/retry.rs", &vendored, 0.5),
+            chunk("src/retry.rs", RETRY, 0.9),
+            chunk("src/db.rs", connect, 0.7),
+            chunk("vendor/b/retry.rs", RETRY, 0.4),
+        ];
+
+        let deduped = dedupe_chunks(chunks, 0.8);
+
+        let paths = deduped
+            .iter()
+            .map(|chunk| (chunk.path.as_str(), chunk.duplicates.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                (
+                    "src/retry.rs",
+                    vec!["vendor/a/retry.rs".to_string(), "vendor/b/retry.rs".to_string()]
+                ),
+                ("src/db.rs", vec![]),
+            ]
+        );
+        assert_eq!(deduped[0].score, Some(0.9));
+    }
+
+    #[test]
+    fn test_distinct_chunks_are_never_merged() {
+        let sum = "fn sum(values: &[u32]) -> u32 {\n    values.iter().copied().sum()\n}";
+        let max = "fn max(values: &[u32]) -> Option<u32> {\n    values.iter().copied().max()\n}";
+        let parse = "fn parse(line: &str) -> Result<Entry, ParseError> {\n    line.split(',').map(str::trim).collect()\n}";
+        let chunks = vec![
+            chunk("src/sum.rs", sum, 0.9),
+            chunk("src/max.rs", max, 0.8),
+            chunk("src/parse.rs", parse, 0.7),
+            chunk("src/retry.rs", RETRY, 0.6),
+        ];
+
+        let deduped = dedupe_chunks(chunks.clone(), 0.85);
+
+        assert_eq!(deduped, chunks);
+    }
+
+    #[test]
+    fn test_same_path_and_tiny_chunks_are_kept() {
+        let chunks = vec![
+            chunk("src/retry.rs", RETRY, 0.9),
+            chunk("src/retry.rs", RETRY, 0.8),
+            chunk("src/a.rs", "}", 0.7),
+            chunk("src/b.rs", "}", 0.6),
+        ];
+
+        assert_eq!(dedupe_chunks(chunks.clone(), 0.85), chunks);
+        assert_eq!(similarity(RETRY, RETRY), 1.0);
+        assert_eq!(similarity("}", "}"), 0.0);
+    }
+}
@@ -0,0 +1,127 @@
+// Test and vendored code match the terms of a question densely and would outrank the code it is
+// about. Their paths keep their place in the results but with a lowered score, unless the query
+// is about tests or the request keeps them.
+
+use std::collections::{HashMap, HashSet};
+
+use common::path_class::PathClass;
+
+use crate::search::hybrid::FusedHit;
+use crate::search::payload::Payload;
+
+/// The score of a flagged path, `weight` is clamped to [0, 1] so that it never promotes one.
+pub fn demoted_score(score: f32, weight: f32) -> f32 {
+    score * weight.clamp(0.0, 1.0)
+}
+
+/// The flags of the paths, classified from the path and from the payloads of the chunk hits,
+/// which carry them on repos indexed since they were introduced.
+pub fn path_classes<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    chunk_hits: &[Payload],
+) -> HashMap<String, PathClass> {
+    let mut classes = paths
+        .into_iter()
+        .map(|path| (path.to_string(), PathClass::of(path)))
+        .collect::<HashMap<_, _>>();
+    for hit in chunk_hits {
+        let class = classes
+            .entry(hit.relative_path.clone())
+            .or_insert_with(|| PathClass::of(&hit.relative_path));
+        class.is_test |= hit.is_test;
+        class.is_vendored |= hit.is_vendored;
+    }
+    classes
+}
+
+/// Lowers the scores of the flagged paths once, whatever their flags, and sorts the hits again.
+/// Returns the paths that were demoted.
+pub fn demote_flagged(
+    mut hits: Vec<FusedHit>,
+    classes: &HashMap<String, PathClass>,
+    weight: f32,
+) -> (Vec<FusedHit>, HashSet<String>) {
+    let mut demoted = HashSet::new();
+    for hit in hits.iter_mut() {
+        if classes.get(&hit.path).map_or(false, PathClass::is_flagged) {
+            hit.score = demoted_score(hit.score, weight);
+            demoted.insert(hit.path.clone());
+        }
+    }
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.path.cmp(&b.path)));
+    (hits, demoted)
+}
+
+/// Why the path was demoted, for the scoring history of the path.
+pub fn demotion_reason(class: &PathClass, weight: f32) -> String {
+    let kind = match (class.is_test, class.is_vendored) {
+        (true, true) => "vendored test code",
+        (true, false) => "test code",
+        _ => "vendored code",
+    };
+    format!("Demoted by {} as {}", weight.clamp(0.0, 1.0), kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::models::RetrievalSource;
+
+    fn hit(path: &str, score: f32) -> FusedHit {
+        FusedHit {
+            path: path.to_string(),
+            score,
+            source: RetrievalSource::Both,
+        }
+    }
+
+    #[test]
+    fn test_demoted_score() {
+        assert_eq!(demoted_score(0.8, 0.5), 0.4);
+        assert_eq!(demoted_score(0.8, 1.0), 0.8);
+        assert_eq!(demoted_score(0.8, 0.0), 0.0);
+        // out of range weights never promote or negate a score.
+        assert_eq!(demoted_score(0.8, 3.0), 0.8);
+        assert_eq!(demoted_score(0.8, -1.0), 0.0);
+    }
+
+    #[test]
+    fn test_flagged_paths_fall_below_the_code_they_test() {
+        let hits = vec![
+            hit("tests/auth_test.rs", 0.9),
+            hit("src/auth.rs", 0.6),
+            hit("vendor/jwt/decode.rs", 0.5),
+            hit("src/session.rs", 0.3),
+        ];
+        let classes = path_classes(hits.iter().map(|h| h.path.as_str()), &[]);
+
+        let (ranked, demoted) = demote_flagged(hits, &classes, 0.5);
+
+        let order = ranked.iter().map(|h| h.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec!["src/auth.rs", "tests/auth_test.rs", "src/session.rs", "vendor/jwt/decode.rs"]
+        );
+        assert_eq!(ranked[1].score, 0.45);
+        assert_eq!(ranked[3].score, 0.25);
+        assert_eq!(demoted.len(), 2);
+        assert!(!demoted.contains("src/auth.rs"));
+    }
+
+    #[test]
+    fn test_payload_flags_are_kept() {
+        // flagged at ingestion time, e.g. by a rule the path alone doesn't show.
+        let flagged = Payload {
+            relative_path: "src/fixtures.rs".to_string(),
+            is_test: true,
+            ..Default::default()
+        };
+        let classes = path_classes(["src/fixtures.rs", "src/main.rs"], &[flagged]);
+        assert!(classes["src/fixtures.rs"].is_test);
+        assert!(!classes["src/main.rs"].is_flagged());
+        assert_eq!(
+            demotion_reason(&classes["src/fixtures.rs"], 0.5),
+            "Demoted by 0.5 as test code"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use common::models::CodeChunk;
+
+/// Keeps at most `max_per_path` chunks of every path ahead of the chunks of the other paths, so
+/// a single file can't fill the results. The chunks are taken greedily in their order, the ones of
+/// a path that already has `max_per_path` go to the overflow: they are flagged `overflow` and
+/// returned after all the others in their original order, for callers with budget left once
+/// every path is represented. 0 keeps the chunks as they are.
+/// Returns the chunks and whether their order changed.
+pub fn diversify(chunks: Vec<CodeChunk>, max_per_path: usize) -> (Vec<CodeChunk>, bool) {
+    if max_per_path == 0 {
+        return (chunks, false);
+    }
+
+    let mut per_path: HashMap<String, usize> = HashMap::new();
+    let mut selected = Vec::with_capacity(chunks.len());
+    let mut overflow = Vec::new();
+    // a chunk selected after one that overflowed moved ahead of it.
+    let mut reordered = false;
+    for mut chunk in chunks {
+        let count = per_path.entry(chunk.path.clone()).or_default();
+        if *count < max_per_path {
+            *count += 1;
+            reordered |= !overflow.is_empty();
+            selected.push(chunk);
+        } else {
+            chunk.overflow = true;
+            overflow.push(chunk);
+        }
+    }
+    selected.extend(overflow);
+    (selected, reordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(path: &str, start_line: usize, score: f32) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            snippet: format!("fn line_{}() {{}}", start_line),
+            start_line,
+            end_line: start_line + 1,
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    fn ranges(chunks: &[CodeChunk]) -> Vec<(&str, usize, bool)> {
+        chunks
+            .iter()
+            .map(|chunk| (chunk.path.as_str(), chunk.start_line, chunk.overflow))
+            .collect()
+    }
+
+    #[test]
+    fn test_paths_are_capped_and_the_rest_overflows() {
+        let chunks = vec![
+            chunk("src/refund.rs", 10, 0.9),
+            chunk("src/refund.rs", 40, 0.9),
+            chunk("src/refund.rs", 80, 0.9),
+            chunk("src/queue.rs", 5, 0.7),
+            chunk("src/queue.rs", 30, 0.7),
+            chunk("src/api.rs", 1, 0.4),
+        ];
+
+        let (diversified, reordered) = diversify(chunks, 2);
+
+        assert!(reordered);
+        assert_eq!(
+            ranges(&diversified),
+            vec![
+                ("src/refund.rs", 10, false),
+                ("src/refund.rs", 40, false),
+                ("src/queue.rs", 5, false),
+                ("src/queue.rs", 30, false),
+                ("src/api.rs", 1, false),
+                ("src/refund.rs", 80, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_order_is_kept_when_no_path_is_over_the_cap() {
+        let chunks = vec![
+            chunk("src/refund.rs", 10, 0.9),
+            chunk("src/queue.rs", 5, 0.7),
+            chunk("src/queue.rs", 30, 0.7),
+            chunk("src/api.rs", 1, 0.4),
+        ];
+
+        assert_eq!(diversify(chunks.clone(), 2), (chunks.clone(), false));
+        assert_eq!(diversify(chunks.clone(), 0), (chunks, false));
+
+        // the chunks over the cap are last already, they are flagged but nothing moves.
+        let chunks = vec![
+            chunk("src/refund.rs", 10, 0.9),
+            chunk("src/queue.rs", 5, 0.7),
+            chunk("src/queue.rs", 30, 0.7),
+        ];
+        let (diversified, reordered) = diversify(chunks, 1);
+        assert!(!reordered);
+        assert_eq!(
+            ranges(&diversified),
+            vec![
+                ("src/refund.rs", 10, false),
+                ("src/queue.rs", 5, false),
+                ("src/queue.rs", 30, true),
+            ]
+        );
+    }
+}
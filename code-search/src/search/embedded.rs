@@ -0,0 +1,164 @@
+// The chunks of a language written inside another one, the SQL of a string literal, the fenced
+// code of markdown or the blocks of a template, are tagged `embedded_lang` at indexing, see
+// `common::embedded_lang`. The files holding them often have no symbol of that language, nor a
+// scope graph to extract code around a hit, so these chunks are searched on their own, boost
+// their paths like the doc hits do, and are returned as they were indexed.
+
+use std::collections::{HashMap, HashSet};
+
+use common::embedded_lang::mentioned_embedded_lang;
+use common::models::{CodeChunk, RetrievalSource};
+
+use crate::search::payload::Payload;
+
+/// The embedded language searched for the query: the one of the request, or else the one the
+/// query names, e.g. `sql` for "where is the orders query defined".
+pub fn searched_embedded_lang(requested: Option<&str>, query: &str) -> Option<String> {
+    match requested.map(str::trim).filter(|lang| !lang.is_empty()) {
+        Some(lang) => Some(lang.to_ascii_lowercase()),
+        None => mentioned_embedded_lang(query).map(str::to_string),
+    }
+}
+
+/// The paths only the embedded hits found, they have nothing to extract chunks around.
+pub fn embedded_only_paths<'a>(
+    hits: &[Payload],
+    found: impl IntoIterator<Item = &'a str>,
+) -> HashSet<String> {
+    let found = found.into_iter().collect::<HashSet<_>>();
+    hits.iter()
+        .map(|hit| hit.relative_path.as_str())
+        .filter(|path| !found.contains(path))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Tags the extracted chunks with the language of the embedded hits overlapping them, and returns
+/// the other hits as chunks of their own, scored like the chunks of their path. Hits of paths
+/// without a score were cut from the results and are left out.
+pub fn merge_embedded_hits(
+    chunks: &mut [CodeChunk],
+    hits: &[Payload],
+    repo: &str,
+    scores: &HashMap<String, (f32, RetrievalSource)>,
+) -> Vec<CodeChunk> {
+    let mut own = Vec::new();
+    for hit in hits {
+        let start_line = hit.start_line as usize;
+        let end_line = hit.end_line as usize;
+        let mut overlapped = false;
+        for chunk in chunks.iter_mut().filter(|chunk| {
+            chunk.path == hit.relative_path
+                && chunk.start_line <= end_line
+                && start_line <= chunk.end_line
+        }) {
+            overlapped = true;
+            if chunk.embedded_lang.is_none() {
+                chunk.embedded_lang = hit.embedded_lang.clone();
+            }
+        }
+        let Some((score, source)) = scores.get(&hit.relative_path) else {
+            continue;
+        };
+        let duplicate = own.iter().any(|chunk: &CodeChunk| {
+            chunk.path == hit.relative_path && chunk.start_line == start_line
+        });
+        if overlapped || duplicate {
+            continue;
+        }
+        own.push(CodeChunk {
+            path: hit.relative_path.clone(),
+            repo: repo.to_string(),
+            snippet: hit.text.clone(),
+            start_line,
+            end_line,
+            byte_range: Some(hit.start_byte as usize..hit.end_byte as usize),
+            score: Some(*score),
+            source: Some(*source),
+            doc: hit.doc.clone(),
+            is_test: hit.is_test,
+            is_vendored: hit.is_vendored,
+            embedded_lang: hit.embedded_lang.clone(),
+            ..Default::default()
+        });
+    }
+    own
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(path: &str, start_line: u64, end_line: u64) -> Payload {
+        Payload {
+            relative_path: path.to_string(),
+            text: format!("SELECT id, total FROM orders -- {}", start_line),
+            start_line,
+            end_line,
+            embedded_lang: Some("sql".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn chunk(path: &str, start_line: usize, end_line: usize) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            snippet: "fn orders() {}".to_string(),
+            start_line,
+            end_line,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_searched_language() {
+        assert_eq!(
+            searched_embedded_lang(Some("SQL"), "how are orders archived"),
+            Some("sql".to_string())
+        );
+        assert_eq!(
+            searched_embedded_lang(None, "where is the orders query defined"),
+            Some("sql".to_string())
+        );
+        assert_eq!(searched_embedded_lang(Some(" "), "how are orders archived"), None);
+    }
+
+    #[test]
+    fn test_overlapping_hits_tag_the_chunks_and_the_others_are_returned() {
+        let mut chunks = vec![chunk("src/orders.rs", 10, 30), chunk("src/lib.rs", 0, 5)];
+        let hits = vec![
+            hit("src/orders.rs", 20, 25),
+            hit("src/orders.rs", 60, 70),
+            hit("README.md", 3, 8),
+            hit("README.md", 3, 8),
+            hit("docs/cut.md", 0, 4),
+        ];
+        let scores = HashMap::from([
+            ("src/orders.rs".to_string(), (0.8, RetrievalSource::Both)),
+            ("README.md".to_string(), (0.4, RetrievalSource::Vector)),
+        ]);
+
+        let own = merge_embedded_hits(&mut chunks, &hits, "acme/shop", &scores);
+
+        assert_eq!(chunks[0].embedded_lang.as_deref(), Some("sql"));
+        assert_eq!(chunks[1].embedded_lang, None);
+        assert_eq!(
+            own.iter()
+                .map(|chunk| (chunk.path.as_str(), chunk.start_line, chunk.score))
+                .collect::<Vec<_>>(),
+            vec![("src/orders.rs", 60, Some(0.8)), ("README.md", 3, Some(0.4))]
+        );
+        assert!(own
+            .iter()
+            .all(|chunk| chunk.embedded_lang.as_deref() == Some("sql") && chunk.repo == "acme/shop"));
+    }
+
+    #[test]
+    fn test_paths_only_the_embedded_hits_found() {
+        let hits = vec![hit("src/orders.rs", 20, 25), hit("README.md", 3, 8)];
+        assert_eq!(
+            embedded_only_paths(&hits, ["src/orders.rs", "src/lib.rs"]),
+            HashSet::from(["README.md".to_string()])
+        );
+    }
+}
@@ -0,0 +1,555 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Instant};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use common::reconnect::Reconnecting;
+use common::{metrics, telemetry};
+use tracing::Instrument;
+use futures::{stream, stream::BoxStream, Stream, StreamExt};
+use qdrant_client::{
+    prelude::QdrantClient,
+    qdrant::{
+        point_id::PointIdOptions, vectors::VectorsOptions, with_payload_selector,
+        with_vectors_selector, Filter, PayloadIncludeSelector, PointId, RetrievedPoint,
+        ScrollPoints, Value, Vectors, WithPayloadSelector, WithVectorsSelector,
+    },
+};
+use serde::Serialize;
+use warp::hyper::body::Bytes;
+
+use crate::config::AppState;
+use crate::search::{payload::kind_to_value, semantic::make_kv_keyword_filter};
+
+/// Points fetched per scroll request, the most the export holds in memory at once.
+pub const EXPORT_PAGE_SIZE: u32 = 256;
+
+const EXPORTED_PAYLOAD_FIELDS: [&str; 4] = ["relative_path", "start_line", "end_line", "content_hash"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Jsonl,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(ExportFormat::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => Err("Parquet export is not enabled in this build, use jsonl".to_string()),
+            _ => Err(format!("Unknown export format: {}, expected jsonl or parquet", format)),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/x-ndjson",
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/// One embedded chunk of the repo, as exported for analytics.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EmbeddingRecord {
+    pub relative_path: String,
+    pub start_line: u64,
+    pub end_line: u64,
+    pub content_hash: String,
+    pub vector: Vec<f32>,
+}
+
+/// One page of points of the scroll API, `next_offset` is None on the last page.
+pub struct ScrollPage {
+    pub points: Vec<RetrievedPoint>,
+    pub next_offset: Option<PointId>,
+}
+
+/// Source of the chunk points of a repo, implemented by the qdrant client and mocked in tests.
+#[async_trait]
+pub trait ChunkScroller: Send + Sync {
+    async fn scroll_chunks(
+        &self,
+        repo_name: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage>;
+}
+
+/// Page of the code chunks of a repo from `offset`, with the exported fields and the vectors.
+pub fn export_scroll_request(repo_name: &str, offset: Option<PointId>, limit: u32) -> ScrollPoints {
+    ScrollPoints {
+        collection_name: common::service_interaction::DOCUMENT_COLLECTION_NAME.to_string(),
+        filter: Some(Filter {
+            must: vec![make_kv_keyword_filter("repo_name", repo_name).into()],
+            // doc chunks share the collection, the chunks indexed before them have no kind.
+            must_not: vec![make_kv_keyword_filter("kind", "doc").into()],
+            ..Default::default()
+        }),
+        offset,
+        limit: Some(limit),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Include(
+                PayloadIncludeSelector {
+                    fields: EXPORTED_PAYLOAD_FIELDS
+                        .iter()
+                        .map(|f| f.to_string())
+                        .collect(),
+                },
+            )),
+        }),
+        with_vectors: Some(WithVectorsSelector {
+            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+        }),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl ChunkScroller for QdrantClient {
+    async fn scroll_chunks(
+        &self,
+        repo_name: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        let request = export_scroll_request(repo_name, offset, limit);
+
+        let start = Instant::now();
+        let response = self
+            .scroll(&request)
+            .instrument(telemetry::db_span("qdrant", "scroll"))
+            .await?;
+        metrics::observe_db_query("qdrant", "scroll", start.elapsed());
+
+        Ok(ScrollPage {
+            points: response.result,
+            next_offset: response.next_page_offset,
+        })
+    }
+}
+
+#[async_trait]
+impl ChunkScroller for Reconnecting<QdrantClient> {
+    async fn scroll_chunks(
+        &self,
+        repo_name: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        self.run(|client| {
+            let offset = offset.clone();
+            async move { client.scroll_chunks(repo_name, offset, limit).await }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ChunkScroller for AppState {
+    async fn scroll_chunks(
+        &self,
+        repo_name: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        self.db_connection
+            .semantic
+            .qdrant
+            .scroll_chunks(repo_name, offset, limit)
+            .await
+    }
+}
+
+struct ScrollState<S> {
+    scroller: Arc<S>,
+    repo_name: String,
+    offset: Option<PointId>,
+    remaining: Option<usize>,
+    done: bool,
+}
+
+/// Streams the embedded chunks of a repo one scroll page at a time, stopping after `limit` records.
+/// The next page is only fetched once the previous one has been consumed, so a slow client
+/// slows down the scroll instead of the records piling up in memory.
+pub fn embedding_pages<S: ChunkScroller + 'static>(
+    scroller: Arc<S>,
+    repo_name: String,
+    limit: Option<usize>,
+) -> impl Stream<Item = Result<Vec<EmbeddingRecord>>> + Send {
+    let state = ScrollState {
+        scroller,
+        repo_name,
+        offset: None,
+        remaining: limit,
+        done: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        if state.done || state.remaining == Some(0) {
+            return None;
+        }
+
+        let page_size = match state.remaining {
+            Some(remaining) => remaining.min(EXPORT_PAGE_SIZE as usize) as u32,
+            None => EXPORT_PAGE_SIZE,
+        };
+        let page = match state
+            .scroller
+            .scroll_chunks(&state.repo_name, state.offset.take(), page_size)
+            .await
+        {
+            Ok(page) => page,
+            Err(e) => {
+                state.done = true;
+                return Some((Err(e), state));
+            }
+        };
+
+        let mut records = page
+            .points
+            .into_iter()
+            .map(parse_embedding_record)
+            .collect::<Result<Vec<_>>>();
+        if let (Ok(records), Some(remaining)) = (&mut records, state.remaining.as_mut()) {
+            records.truncate(*remaining);
+            *remaining -= records.len();
+        }
+
+        state.offset = page.next_offset;
+        state.done = state.offset.is_none() || records.is_err();
+        Some((records, state))
+    })
+    // an empty page only happens on an empty repo, there is nothing to send for it.
+    .filter(|page| futures::future::ready(!matches!(page, Ok(records) if records.is_empty())))
+}
+
+/// Encodes the exported pages in `format`, one chunk of bytes per page.
+pub fn encode_pages(
+    pages: impl Stream<Item = Result<Vec<EmbeddingRecord>>> + Send + 'static,
+    format: ExportFormat,
+) -> BoxStream<'static, Result<Bytes>> {
+    match format {
+        ExportFormat::Jsonl => pages.map(|page| encode_jsonl(&page?)).boxed(),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => parquet_export::encode_parquet(pages).boxed(),
+    }
+}
+
+fn encode_jsonl(records: &[EmbeddingRecord]) -> Result<Bytes> {
+    let mut buffer = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut buffer, record)?;
+        buffer.push(b'\n');
+    }
+    Ok(Bytes::from(buffer))
+}
+
+fn parse_embedding_record(point: RetrievedPoint) -> Result<EmbeddingRecord> {
+    let RetrievedPoint {
+        id,
+        payload,
+        vectors,
+        ..
+    } = point;
+    let id = match id.and_then(|id| id.point_id_options) {
+        Some(PointIdOptions::Uuid(id)) => id,
+        Some(PointIdOptions::Num(id)) => id.to_string(),
+        None => String::new(),
+    };
+
+    let vector = match vectors {
+        Some(Vectors {
+            vectors_options: Some(VectorsOptions::Vector(vector)),
+        }) => vector.data,
+        _ => return Err(anyhow!("Point {} has no vector", id)),
+    };
+
+    Ok(EmbeddingRecord {
+        relative_path: payload_string(&payload, "relative_path")
+            .ok_or_else(|| anyhow!("Point {} has no relative_path", id))?,
+        start_line: payload_number(&payload, "start_line")
+            .ok_or_else(|| anyhow!("Point {} has no start_line", id))?,
+        end_line: payload_number(&payload, "end_line")
+            .ok_or_else(|| anyhow!("Point {} has no end_line", id))?,
+        content_hash: payload_string(&payload, "content_hash").unwrap_or_default(),
+        vector,
+    })
+}
+
+fn payload_string(payload: &HashMap<String, Value>, key: &str) -> Option<String> {
+    match kind_to_value(payload.get(key)?.kind.clone()) {
+        serde_json::Value::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+// line numbers are written as strings at ingestion, integers are accepted too.
+fn payload_number(payload: &HashMap<String, Value>, key: &str) -> Option<u64> {
+    match kind_to_value(payload.get(key)?.kind.clone()) {
+        serde_json::Value::String(value) => value.parse().ok(),
+        serde_json::Value::Number(value) => value.as_u64(),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_export {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use arrow::{
+        array::{ArrayRef, ListArray, StringArray, UInt64Array},
+        datatypes::{DataType, Field, Float32Type, Schema, SchemaRef},
+        record_batch::RecordBatch,
+    };
+    use futures::{stream, Stream, StreamExt};
+    use parquet::arrow::ArrowWriter;
+    use warp::hyper::body::Bytes;
+
+    use super::EmbeddingRecord;
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("relative_path", DataType::Utf8, false),
+            Field::new("start_line", DataType::UInt64, false),
+            Field::new("end_line", DataType::UInt64, false),
+            Field::new("content_hash", DataType::Utf8, false),
+            Field::new(
+                "vector",
+                DataType::List(Arc::new(Field::new("item", DataType::Float32, true))),
+                false,
+            ),
+        ]))
+    }
+
+    fn record_batch(records: &[EmbeddingRecord]) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(
+                records.iter().map(|r| r.relative_path.as_str()),
+            )),
+            Arc::new(UInt64Array::from_iter_values(records.iter().map(|r| r.start_line))),
+            Arc::new(UInt64Array::from_iter_values(records.iter().map(|r| r.end_line))),
+            Arc::new(StringArray::from_iter_values(
+                records.iter().map(|r| r.content_hash.as_str()),
+            )),
+            Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(
+                records
+                    .iter()
+                    .map(|r| Some(r.vector.iter().copied().map(Some))),
+            )),
+        ];
+        Ok(RecordBatch::try_new(schema(), columns)?)
+    }
+
+    /// Writes every page as its own row group and sends the bytes as soon as the group is flushed,
+    /// the footer is sent once the pages run out.
+    pub fn encode_parquet(
+        pages: impl Stream<Item = Result<Vec<EmbeddingRecord>>> + Send + 'static,
+    ) -> impl Stream<Item = Result<Bytes>> + Send {
+        let writer = ArrowWriter::try_new(Vec::new(), schema(), None).map_err(anyhow::Error::from);
+        stream::unfold(
+            (Box::pin(pages), Some(writer)),
+            |(mut pages, writer)| async move {
+                let mut writer = match writer? {
+                    Ok(writer) => writer,
+                    Err(e) => return Some((Err(e), (pages, None))),
+                };
+                match pages.next().await {
+                    Some(Ok(records)) => {
+                        let chunk = record_batch(&records)
+                            .and_then(|batch| Ok(writer.write(&batch)?))
+                            .and_then(|_| Ok(writer.flush()?))
+                            // the writer tracks its offsets itself, the written bytes can be handed out.
+                            .map(|_| Bytes::from(std::mem::take(writer.inner_mut())));
+                        let writer = chunk.is_ok().then_some(Ok(writer));
+                        Some((chunk, (pages, writer)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (pages, None))),
+                    None => {
+                        let footer = writer.into_inner().map(Bytes::from).map_err(Into::into);
+                        Some((footer, (pages, None)))
+                    }
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::Vector;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // serves `total` points in pages, counting the scroll calls.
+    struct MockScroller {
+        total: usize,
+        calls: AtomicUsize,
+        requested_limits: std::sync::Mutex<Vec<u32>>,
+    }
+
+    impl MockScroller {
+        fn new(total: usize) -> Arc<Self> {
+            Arc::new(Self {
+                total,
+                calls: AtomicUsize::new(0),
+                requested_limits: std::sync::Mutex::new(Vec::new()),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    fn point(i: usize) -> RetrievedPoint {
+        RetrievedPoint {
+            id: Some(PointId {
+                point_id_options: Some(PointIdOptions::Num(i as u64)),
+            }),
+            payload: HashMap::from([
+                ("relative_path".to_string(), format!("src/file_{}.rs", i).into()),
+                ("start_line".to_string(), (i * 10).to_string().into()),
+                ("end_line".to_string(), (i * 10 + 9).to_string().into()),
+                ("content_hash".to_string(), format!("hash{}", i).into()),
+            ]),
+            vectors: Some(Vectors {
+                vectors_options: Some(VectorsOptions::Vector(Vector {
+                    data: vec![i as f32, 0.5],
+                    ..Default::default()
+                })),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[async_trait]
+    impl ChunkScroller for MockScroller {
+        async fn scroll_chunks(
+            &self,
+            repo_name: &str,
+            offset: Option<PointId>,
+            limit: u32,
+        ) -> Result<ScrollPage> {
+            assert_eq!(repo_name, "repo-a");
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.requested_limits.lock().unwrap().push(limit);
+
+            let start = match offset.and_then(|id| id.point_id_options) {
+                Some(PointIdOptions::Num(start)) => start as usize,
+                _ => 0,
+            };
+            let end = (start + limit as usize).min(self.total);
+            Ok(ScrollPage {
+                points: (start..end).map(point).collect(),
+                next_offset: (end < self.total).then(|| PointId {
+                    point_id_options: Some(PointIdOptions::Num(end as u64)),
+                }),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pages_are_fetched_as_they_are_consumed() {
+        let scroller = MockScroller::new(EXPORT_PAGE_SIZE as usize * 3 + 10);
+        let mut pages = Box::pin(embedding_pages(scroller.clone(), "repo-a".to_string(), None));
+
+        // nothing is fetched before the client asks for data.
+        assert_eq!(scroller.calls(), 0);
+
+        let first = pages.next().await.unwrap().unwrap();
+        assert_eq!(first.len(), EXPORT_PAGE_SIZE as usize);
+        assert_eq!(scroller.calls(), 1);
+
+        let second = pages.next().await.unwrap().unwrap();
+        assert_eq!(second[0].relative_path, format!("src/file_{}.rs", EXPORT_PAGE_SIZE));
+        assert_eq!(scroller.calls(), 2);
+
+        let rest = pages.collect::<Vec<_>>().await;
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[1].as_ref().unwrap().len(), 10);
+        assert_eq!(scroller.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_row_limit_caps_scroll_requests() {
+        let scroller = MockScroller::new(1000);
+        let pages = embedding_pages(scroller.clone(), "repo-a".to_string(), Some(300))
+            .collect::<Vec<_>>()
+            .await;
+
+        let records = pages.into_iter().map(Result::unwrap).flatten().collect::<Vec<_>>();
+        assert_eq!(records.len(), 300);
+        assert_eq!(
+            *scroller.requested_limits.lock().unwrap(),
+            vec![EXPORT_PAGE_SIZE, 300 - EXPORT_PAGE_SIZE]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_encoding_yields_one_chunk_per_page() {
+        let scroller = MockScroller::new(EXPORT_PAGE_SIZE as usize + 1);
+        let chunks = encode_pages(
+            embedding_pages(scroller, "repo-a".to_string(), None),
+            ExportFormat::Jsonl,
+        )
+        .collect::<Vec<_>>()
+        .await;
+
+        assert_eq!(chunks.len(), 2);
+        let last = String::from_utf8(chunks[1].as_ref().unwrap().to_vec()).unwrap();
+        assert_eq!(
+            last,
+            format!(
+                "{{\"relative_path\":\"src/file_{i}.rs\",\"start_line\":{},\"end_line\":{},\"content_hash\":\"hash{i}\",\"vector\":[{i}.0,0.5]}}\n",
+                EXPORT_PAGE_SIZE * 10,
+                EXPORT_PAGE_SIZE * 10 + 9,
+                i = EXPORT_PAGE_SIZE
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_repo_exports_nothing() {
+        let scroller = MockScroller::new(0);
+        let pages = embedding_pages(scroller.clone(), "repo-a".to_string(), None)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(pages.is_empty());
+        assert_eq!(scroller.calls(), 1);
+    }
+
+    #[test]
+    fn test_export_leaves_the_doc_chunks_out() {
+        use qdrant_client::qdrant::{condition::ConditionOneOf, r#match::MatchValue};
+
+        let request = export_scroll_request("repo-a", None, EXPORT_PAGE_SIZE);
+        let filter = request.filter.unwrap();
+        let excluded = filter
+            .must_not
+            .iter()
+            .filter_map(|condition| match condition.condition_one_of.as_ref()? {
+                ConditionOneOf::Field(field) => {
+                    match field.r#match.as_ref()?.match_value.as_ref()? {
+                        MatchValue::Keyword(keyword) => {
+                            Some((field.key.as_str(), keyword.as_str()))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(excluded, vec![("kind", "doc")]);
+        assert_eq!(filter.must.len(), 1);
+    }
+}
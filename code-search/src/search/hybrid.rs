@@ -0,0 +1,529 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use common::models::RetrievalSource;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::search::payload::CodeExtractMeta;
+
+// constant of reciprocal rank fusion, dampens the gap between the first ranks.
+const RRF_K: f32 = 60.0;
+// keyword matches extracted per file, like the symbols extracted per path.
+const KEYWORD_MATCHES_PER_FILE: usize = 3;
+
+lazy_static! {
+    static ref QUOTED: Regex = Regex::new(r#""([^"]+)"|`([^`]+)`"#).unwrap();
+    static ref WORD: Regex = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    // snake_case, camelCase, PascalCase with more than one hump and qualified paths like `db::init`.
+    static ref IDENTIFIER: Regex = Regex::new(
+        r"^(?:[A-Za-z0-9]*_[A-Za-z0-9_]*|[a-z]+[A-Z][A-Za-z0-9]*|[A-Z][a-z0-9]+[A-Z][A-Za-z0-9]*|[A-Za-z_]\w*(?:(?:::|\.)[A-Za-z_]\w*)+)(?:\(\))?$"
+    )
+    .unwrap();
+    // present tense questions about the code as it is, e.g. "how is the invoice rendered now".
+    static ref CURRENT: Regex =
+        Regex::new(r"(?i)\b(?:current|currently|now|nowadays|today|these days|as of)\b").unwrap();
+}
+
+/// How the vector and keyword result lists are merged into one, set with `SEARCH_FUSION`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FusionMethod {
+    /// Weighted sum of the min-max normalized scores of both lists.
+    #[default]
+    WeightedSum,
+    /// Weighted reciprocal rank fusion, only the ranks count.
+    Rrf,
+}
+
+impl FromStr for FusionMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "weighted" | "weighted_sum" => Ok(FusionMethod::WeightedSum),
+            "rrf" => Ok(FusionMethod::Rrf),
+            other => Err(anyhow!(
+                "SEARCH_FUSION must be `weighted` or `rrf`, got `{}`",
+                other
+            )),
+        }
+    }
+}
+
+/// What the query looks like, decides how much the keyword hits weigh against the vector hits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    /// Quoted text or only identifiers, e.g. `QdrantClientConfig`.
+    Identifier,
+    /// Identifiers within prose, e.g. "where is QdrantClientConfig built".
+    Mixed,
+    /// Prose only, e.g. "how are embeddings stored".
+    Conceptual,
+}
+
+impl QueryKind {
+    /// Weight of the keyword list in the fusion, the vector list gets the rest.
+    pub fn keyword_weight(&self) -> f32 {
+        match self {
+            QueryKind::Identifier => 0.7,
+            QueryKind::Mixed => 0.5,
+            QueryKind::Conceptual => 0.3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FusedHit {
+    pub path: String,
+    pub score: f32,
+    pub source: RetrievalSource,
+}
+
+fn is_identifier(token: &str) -> bool {
+    IDENTIFIER.is_match(token.trim_matches(|c: char| matches!(c, ',' | '?' | '!' | ';')))
+}
+
+pub fn classify_query(query: &str) -> QueryKind {
+    if QUOTED.is_match(query) {
+        return QueryKind::Identifier;
+    }
+    let tokens = query.split_whitespace().collect::<Vec<_>>();
+    let identifiers = tokens.iter().filter(|token| is_identifier(token)).count();
+    match identifiers {
+        0 => QueryKind::Conceptual,
+        n if n == tokens.len() => QueryKind::Identifier,
+        _ => QueryKind::Mixed,
+    }
+}
+
+/// Whether the query asks about the current behavior, which the recently changed files answer
+/// better than the deprecated ones.
+pub fn asks_current_behavior(query: &str) -> bool {
+    CURRENT.is_match(query)
+}
+
+/// Terms of the keyword query: the quoted text and the identifiers of the query,
+/// or its words when it has neither.
+pub fn keyword_terms(query: &str) -> Vec<String> {
+    let mut terms = QUOTED
+        .captures_iter(query)
+        .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+        .map(|quoted| quoted.as_str().trim().to_string())
+        .filter(|quoted| !quoted.is_empty())
+        .collect::<Vec<_>>();
+    let unquoted = QUOTED.replace_all(query, " ");
+    terms.extend(
+        unquoted
+            .split_whitespace()
+            .filter(|token| is_identifier(token))
+            .flat_map(|token| WORD.find_iter(token).map(|word| word.as_str().to_string())),
+    );
+    if terms.is_empty() {
+        terms = WORD
+            .find_iter(&unquoted)
+            .map(|word| word.as_str().to_string())
+            .filter(|word| word.len() > 2)
+            .collect();
+    }
+    let mut seen = HashSet::new();
+    terms.retain(|term| seen.insert(term.to_lowercase()));
+    terms
+}
+
+/// Builds the quickwit query matching any of the terms in the content or the symbols of a file.
+/// Both fields split words on `_` and punctuation, so a term with several words is a phrase
+/// in the content and all of its words in the symbols, which don't record positions.
+pub fn build_keyword_query(terms: &[String]) -> Option<String> {
+    let clauses = terms
+        .iter()
+        .filter_map(|term| {
+            let words = WORD
+                .find_iter(term)
+                .flat_map(|word| word.as_str().split('_'))
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>();
+            match words.len() {
+                0 => None,
+                1 => Some(format!("content:{0} OR symbols:{0}", words[0])),
+                _ => Some(format!(
+                    "content:\"{}\" OR ({})",
+                    words.join(" "),
+                    words
+                        .iter()
+                        .map(|word| format!("symbols:{}", word))
+                        .collect::<Vec<_>>()
+                        .join(" AND ")
+                )),
+            }
+        })
+        .collect::<Vec<_>>();
+    if clauses.is_empty() {
+        return None;
+    }
+    Some(
+        clauses
+            .iter()
+            .map(|clause| format!("({})", clause))
+            .collect::<Vec<_>>()
+            .join(" OR "),
+    )
+}
+
+/// Locations of the terms in the content of a keyword hit, to extract chunks around them.
+pub fn keyword_extract_meta(content: &str, terms: &[String]) -> Vec<CodeExtractMeta> {
+    let mut meta = Vec::new();
+    for term in terms {
+        let Ok(pattern) = Regex::new(&format!(r"(?i)\b{}\b", regex::escape(term))) else {
+            continue;
+        };
+        for found in pattern.find_iter(content) {
+            if meta.len() == KEYWORD_MATCHES_PER_FILE {
+                return meta;
+            }
+            meta.push(CodeExtractMeta {
+                symbol: term.clone(),
+                node_kind: "keyword".to_string(),
+                symbol_type: "keyword".to_string(),
+                is_global: false,
+                score: 0.0,
+                start_byte: found.start() as i64,
+                end_byte: found.end() as i64,
+            });
+        }
+    }
+    meta
+}
+
+/// Min-max normalizes the scores to `[0, 1]`, a list of equal scores normalizes to 1.
+pub fn normalize_scores(scores: &[f32]) -> Vec<f32> {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    scores
+        .iter()
+        .map(|score| {
+            if max - min <= f32::EPSILON {
+                1.0
+            } else {
+                (score - min) / (max - min)
+            }
+        })
+        .collect()
+}
+
+/// Scores of a list that only has an order, like the keyword hits, best first.
+pub fn rank_scores(len: usize) -> Vec<f32> {
+    (0..len).map(|rank| 1.0 / (rank as f32 + 1.0)).collect()
+}
+
+// best hit first, a path listed twice keeps its best score.
+fn ranked(hits: &[(String, f32)]) -> Vec<(String, f32)> {
+    let mut hits = hits.to_vec();
+    hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut seen = HashSet::new();
+    hits.retain(|(path, _)| seen.insert(path.clone()));
+    hits
+}
+
+// What each hit of a ranked list adds to the fused score of its path.
+fn contributions(hits: &[(String, f32)], method: FusionMethod) -> Vec<f32> {
+    match method {
+        FusionMethod::WeightedSum => {
+            normalize_scores(&hits.iter().map(|(_, score)| *score).collect::<Vec<_>>())
+        }
+        FusionMethod::Rrf => (0..hits.len())
+            .map(|rank| 1.0 / (RRF_K + rank as f32 + 1.0))
+            .collect(),
+    }
+}
+
+/// Fuses the vector and keyword hits of the same query into one list, best first.
+pub fn fuse(
+    vector: &[(String, f32)],
+    keyword: &[(String, f32)],
+    method: FusionMethod,
+    keyword_weight: f32,
+) -> Vec<FusedHit> {
+    let keyword_weight = keyword_weight.clamp(0.0, 1.0);
+    let vector_weight = 1.0 - keyword_weight;
+    let vector = ranked(vector);
+    let keyword = ranked(keyword);
+
+    let mut fused: HashMap<String, FusedHit> = HashMap::new();
+    for (index, contribution) in contributions(&vector, method).into_iter().enumerate() {
+        let path = vector[index].0.clone();
+        fused.insert(
+            path.clone(),
+            FusedHit {
+                path,
+                score: vector_weight * contribution,
+                source: RetrievalSource::Vector,
+            },
+        );
+    }
+    for (index, contribution) in contributions(&keyword, method).into_iter().enumerate() {
+        let path = &keyword[index].0;
+        let hit = fused.entry(path.clone()).or_insert_with(|| FusedHit {
+            path: path.clone(),
+            score: 0.0,
+            source: RetrievalSource::Keyword,
+        });
+        hit.score += keyword_weight * contribution;
+        if hit.source == RetrievalSource::Vector {
+            hit.source = RetrievalSource::Both;
+        }
+    }
+
+    let mut fused = fused.into_values().collect::<Vec<_>>();
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.path.cmp(&b.path)));
+    fused
+}
+
+/// Adds the doc comment hits of the query to the fused hits, weighted by `doc_weight` on top of
+/// the vector and keyword scores. A path only found by its docs is a vector hit.
+pub fn add_doc_hits(
+    fused: Vec<FusedHit>,
+    docs: &[(String, f32)],
+    method: FusionMethod,
+    doc_weight: f32,
+) -> Vec<FusedHit> {
+    let docs = ranked(docs);
+    let mut fused = fused
+        .into_iter()
+        .map(|hit| (hit.path.clone(), hit))
+        .collect::<HashMap<_, _>>();
+    for (index, contribution) in contributions(&docs, method).into_iter().enumerate() {
+        let path = &docs[index].0;
+        let hit = fused.entry(path.clone()).or_insert_with(|| FusedHit {
+            path: path.clone(),
+            score: 0.0,
+            source: RetrievalSource::Vector,
+        });
+        hit.score += doc_weight.max(0.0) * contribution;
+        if hit.source == RetrievalSource::Keyword {
+            hit.source = RetrievalSource::Both;
+        }
+    }
+
+    let mut fused = fused.into_values().collect::<Vec<_>>();
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.path.cmp(&b.path)));
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(hits: &[(&str, f32)]) -> Vec<(String, f32)> {
+        hits.iter()
+            .map(|(path, score)| (path.to_string(), *score))
+            .collect()
+    }
+
+    #[test]
+    fn test_normalize_scores() {
+        assert_eq!(normalize_scores(&[1.0, 3.0, 2.0]), vec![0.0, 1.0, 0.5]);
+        assert_eq!(normalize_scores(&[0.3, 0.3]), vec![1.0, 1.0]);
+        assert!(normalize_scores(&[]).is_empty());
+        assert_eq!(rank_scores(3), vec![1.0, 0.5, 1.0 / 3.0]);
+    }
+
+    #[test]
+    fn test_weighted_sum_fusion() {
+        let vector = hits(&[("a.rs", 1.0), ("b.rs", 0.5), ("c.rs", 0.0)]);
+        let keyword = hits(&[("c.rs", 1.0), ("d.rs", 0.5)]);
+
+        let fused = fuse(&vector, &keyword, FusionMethod::WeightedSum, 0.5);
+
+        let scores = fused
+            .iter()
+            .map(|hit| (hit.path.as_str(), hit.score, hit.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scores,
+            vec![
+                ("a.rs", 0.5, RetrievalSource::Vector),
+                ("c.rs", 0.5, RetrievalSource::Both),
+                ("b.rs", 0.25, RetrievalSource::Vector),
+                ("d.rs", 0.0, RetrievalSource::Keyword),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rrf_fusion() {
+        let vector = hits(&[("a.rs", 0.9), ("b.rs", 0.8)]);
+        let keyword = hits(&[("b.rs", 3.0), ("c.rs", 2.0)]);
+
+        let fused = fuse(&vector, &keyword, FusionMethod::Rrf, 0.5);
+
+        assert_eq!(fused[0].path, "b.rs");
+        assert_eq!(fused[0].source, RetrievalSource::Both);
+        let expected = 0.5 / (RRF_K + 2.0) + 0.5 / (RRF_K + 1.0);
+        assert!((fused[0].score - expected).abs() < 1e-6);
+        // first vector hit ranks above the second keyword hit, same weights.
+        assert_eq!(fused[1].path, "a.rs");
+        assert_eq!(fused[2].path, "c.rs");
+        assert_eq!(fused[2].source, RetrievalSource::Keyword);
+    }
+
+    #[test]
+    fn test_doc_hits_boost_documented_paths() {
+        let vector = hits(&[("a.rs", 1.0), ("b.rs", 0.5), ("c.rs", 0.0)]);
+        let keyword = hits(&[("c.rs", 1.0)]);
+        let fused = fuse(&vector, &keyword, FusionMethod::WeightedSum, 0.5);
+        // b.rs is documented twice, only its best doc counts.
+        let docs = hits(&[("b.rs", 0.75), ("b.rs", 0.25), ("c.rs", 0.5), ("e.rs", 0.25)]);
+
+        let boosted = add_doc_hits(fused, &docs, FusionMethod::WeightedSum, 0.5);
+
+        let scores = boosted
+            .iter()
+            .map(|hit| (hit.path.as_str(), hit.score, hit.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scores,
+            vec![
+                ("b.rs", 0.75, RetrievalSource::Vector),
+                ("c.rs", 0.75, RetrievalSource::Both),
+                ("a.rs", 0.5, RetrievalSource::Vector),
+                ("e.rs", 0.0, RetrievalSource::Vector),
+            ]
+        );
+        assert_eq!(
+            add_doc_hits(boosted.clone(), &docs, FusionMethod::WeightedSum, 0.0),
+            boosted
+        );
+    }
+
+    #[test]
+    fn test_keyword_weight_shifts_ranking() {
+        let vector = hits(&[("concept.rs", 0.9), ("exact.rs", 0.2)]);
+        let keyword = hits(&[("exact.rs", 1.0)]);
+
+        let conceptual = fuse(&vector, &keyword, FusionMethod::WeightedSum, 0.3);
+        let identifier = fuse(&vector, &keyword, FusionMethod::WeightedSum, 0.7);
+
+        assert_eq!(conceptual[0].path, "concept.rs");
+        assert_eq!(identifier[0].path, "exact.rs");
+    }
+
+    #[test]
+    fn test_classify_query() {
+        assert_eq!(classify_query("QdrantClientConfig"), QueryKind::Identifier);
+        assert_eq!(classify_query("generate_qdrant_index_name()"), QueryKind::Identifier);
+        assert_eq!(classify_query("search_with db::init_db"), QueryKind::Identifier);
+        assert_eq!(classify_query("\"max hits\" in quickwit"), QueryKind::Identifier);
+        assert_eq!(
+            classify_query("where is QdrantClientConfig built?"),
+            QueryKind::Mixed
+        );
+        assert_eq!(
+            classify_query("how are embeddings stored"),
+            QueryKind::Conceptual
+        );
+        assert_eq!(classify_query("Qdrant"), QueryKind::Conceptual);
+    }
+
+    #[test]
+    fn test_asks_current_behavior() {
+        assert!(asks_current_behavior("how are invoices rendered now?"));
+        assert!(asks_current_behavior("What is the current retry policy"));
+        assert!(asks_current_behavior("which queue do we use today"));
+        assert!(!asks_current_behavior("how are invoices rendered"));
+        assert!(!asks_current_behavior("where is known_hosts parsed"));
+    }
+
+    #[test]
+    fn test_keyword_query() {
+        assert_eq!(
+            keyword_terms("where is QdrantClientConfig built for get_qdrant_client?"),
+            vec!["QdrantClientConfig", "get_qdrant_client"]
+        );
+        assert_eq!(keyword_terms("\"max_hits\" max_hits"), vec!["max_hits"]);
+        assert_eq!(
+            keyword_terms("how are embeddings stored"),
+            vec!["how", "are", "embeddings", "stored"]
+        );
+
+        let query = build_keyword_query(&keyword_terms("QdrantClientConfig get_qdrant_client"));
+        assert_eq!(
+            query.unwrap(),
+            "(content:qdrantclientconfig OR symbols:qdrantclientconfig) OR \
+             (content:\"get qdrant client\" OR (symbols:get AND symbols:qdrant AND symbols:client))"
+        );
+        assert!(build_keyword_query(&[]).is_none());
+    }
+
+    #[test]
+    fn test_keyword_extract_meta() {
+        let content = "let config = QdrantClientConfig::from_url(url);\nqdrantclientconfig";
+        let meta = keyword_extract_meta(content, &["QdrantClientConfig".to_string()]);
+
+        assert_eq!(meta.len(), 2);
+        assert_eq!(meta[0].start_byte, 13);
+        assert_eq!(meta[0].end_byte, 31);
+        assert!(keyword_extract_meta(content, &["Qdrant".to_string()]).is_empty());
+    }
+
+    // Recorded vector and keyword results of identifier queries, with the files that answer them.
+    #[derive(serde::Deserialize)]
+    struct RecallFixture {
+        query: String,
+        relevant: Vec<String>,
+        vector: Vec<(String, f32)>,
+        keyword: Vec<String>,
+    }
+
+    fn recall_at(ranked: &[String], relevant: &[String], k: usize) -> f32 {
+        let found = relevant
+            .iter()
+            .filter(|path| ranked.iter().take(k).any(|hit| hit == *path))
+            .count();
+        found as f32 / relevant.len() as f32
+    }
+
+    #[test]
+    fn test_hybrid_recall_on_identifier_queries() {
+        let fixtures: Vec<RecallFixture> =
+            serde_json::from_str(include_str!("../../eval/fixtures/identifier_queries.json"))
+                .unwrap();
+        let k = 3;
+
+        for method in [FusionMethod::WeightedSum, FusionMethod::Rrf] {
+            let (mut vector_recall, mut hybrid_recall) = (0.0, 0.0);
+            for fixture in &fixtures {
+                let kind = classify_query(&fixture.query);
+                assert_ne!(kind, QueryKind::Conceptual, "{}", fixture.query);
+
+                let vector_ranked = ranked(&fixture.vector)
+                    .into_iter()
+                    .map(|(path, _)| path)
+                    .collect::<Vec<_>>();
+                let keyword = fixture
+                    .keyword
+                    .iter()
+                    .cloned()
+                    .zip(rank_scores(fixture.keyword.len()))
+                    .collect::<Vec<_>>();
+                let hybrid_ranked = fuse(&fixture.vector, &keyword, method, kind.keyword_weight())
+                    .into_iter()
+                    .map(|hit| hit.path)
+                    .collect::<Vec<_>>();
+
+                vector_recall += recall_at(&vector_ranked, &fixture.relevant, k);
+                hybrid_recall += recall_at(&hybrid_ranked, &fixture.relevant, k);
+            }
+            let count = fixtures.len() as f32;
+            assert!(
+                hybrid_recall / count > vector_recall / count,
+                "{:?}: recall@{} {} is not above the vector only {}",
+                method,
+                k,
+                hybrid_recall / count,
+                vector_recall / count
+            );
+        }
+    }
+}
@@ -1,5 +1,17 @@
 pub mod payload;
 pub mod ranking;
+pub mod ranking_config;
 pub mod code_search;
 pub mod semantic;
 pub mod quikwit;
+pub mod export;
+pub mod hybrid;
+pub mod symbol_lookup;
+pub mod dedup;
+pub mod demotion;
+pub mod recency;
+pub mod diversity;
+pub mod batch;
+pub mod attachments;
+pub mod terminology;
+pub mod embedded;
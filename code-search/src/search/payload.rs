@@ -1,8 +1,12 @@
 // import all the necessary modules.
 use std::{borrow::Cow, collections::HashMap, str};
 
+use common::compression;
+use common::embedded_lang::EMBEDDED_LANG_FIELD;
+use common::symbol_payload::SymbolOccurrence;
 use qdrant_client::qdrant::{
-    point_id::PointIdOptions, vectors::VectorsOptions, PointId, ScoredPoint, Value, Vectors,
+    point_id::PointIdOptions, vectors::VectorsOptions, PointId, RetrievedPoint, ScoredPoint, Value,
+    Vectors,
 };
 
 pub type Embedding = Vec<f32>;
@@ -12,14 +16,12 @@ pub type Embedding = Vec<f32>;
 pub struct SymbolPayload {
     pub repo_name: String,
     pub symbol: String,
-
-    pub symbol_types: Vec<String>,
-    pub lang_ids: Vec<String>,
-    pub is_globals: Vec<bool>,
-    pub start_bytes: Vec<i64>,
-    pub end_bytes: Vec<i64>,
-    pub relative_paths: Vec<String>,
-    pub node_kinds: Vec<String>,
+    // stored as the parallel columns of the payload, see `common::symbol_payload`.
+    #[serde(flatten, with = "common::symbol_payload::flat")]
+    pub occurrences: Vec<SymbolOccurrence>,
+    // occurrences of the symbol that didn't fit in the payload at ingestion time.
+    #[serde(default)]
+    pub overflow_count: i64,
 
     #[serde(skip)]
     pub id: Option<String>,
@@ -50,7 +52,8 @@ pub struct PathExtractMeta {
 }
 
 impl SymbolPayload {
-    pub fn from_qdrant(orig: ScoredPoint) -> SymbolPayload {
+    /// Parses a point of the search API, an error when its occurrence columns don't line up.
+    pub fn from_qdrant(orig: ScoredPoint) -> anyhow::Result<SymbolPayload> {
         let ScoredPoint {
             id,
             payload,
@@ -61,6 +64,34 @@ impl SymbolPayload {
 
         parse_symbol_payload(id, vectors, payload, score)
     }
+
+    /// Parses a point of the scroll API, which carries no score.
+    pub fn from_retrieved(orig: RetrievedPoint) -> anyhow::Result<SymbolPayload> {
+        let RetrievedPoint {
+            id,
+            payload,
+            vectors,
+            ..
+        } = orig;
+
+        let mut symbol = parse_symbol_payload(id, vectors, payload, 0.0)?;
+        symbol.score = None;
+        Ok(symbol)
+    }
+}
+
+/// Parses the symbol points of a search, a corrupted point is logged and left out of the results.
+pub fn parse_symbol_points(points: Vec<ScoredPoint>) -> Vec<SymbolPayload> {
+    points
+        .into_iter()
+        .filter_map(|point| match SymbolPayload::from_qdrant(point) {
+            Ok(symbol) => Some(symbol),
+            Err(e) => {
+                log::error!("{:?}", e);
+                None
+            }
+        })
+        .collect()
 }
 
 fn parse_symbol_payload(
@@ -68,7 +99,7 @@ fn parse_symbol_payload(
     vectors: Option<Vectors>,
     payload: HashMap<String, Value>,
     score: f32,
-) -> SymbolPayload {
+) -> anyhow::Result<SymbolPayload> {
     let Some(PointId {
         point_id_options: Some(PointIdOptions::Uuid(id)),
     }) = id
@@ -89,25 +120,17 @@ fn parse_symbol_payload(
         }
     };
 
-    let mut converted = payload
+    let converted = payload
         .into_iter()
         .map(|(key, value)| (key, kind_to_value(value.kind)))
-        .collect::<HashMap<String, serde_json::Value>>();
+        .collect::<serde_json::Map<String, serde_json::Value>>();
 
-    SymbolPayload {
-        repo_name: val_str!(converted, "repo_name"),
-        symbol: val_str!(converted, "symbol"),
-        symbol_types: val_str!(converted, "symbol_type"),
-        lang_ids: val_str!(converted, "lang"),
-        is_globals: val_str!(converted, "is_global"),
-        start_bytes: val_str!(converted, "start_byte"),
-        end_bytes: val_str!(converted, "end_byte"),
-        relative_paths: val_str!(converted, "relative_path"),
-        node_kinds: val_str!(converted, "node_kind"),
-        id: Some(id),
-        score: Some(score),
-        embedding,
-    }
+    let mut symbol: SymbolPayload = serde_json::from_value(serde_json::Value::Object(converted))
+        .map_err(|e| anyhow::anyhow!("Failed to parse the symbol point {}: {}", id, e))?;
+    symbol.id = Some(id);
+    symbol.score = Some(score);
+    symbol.embedding = embedding;
+    Ok(symbol)
 }
 
 #[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -121,6 +144,26 @@ pub struct Payload {
     pub end_line: u64,
     pub start_byte: u64,
     pub end_byte: u64,
+    // doc comments of the definitions in a code chunk, the doc of a doc chunk.
+    #[serde(default)]
+    pub doc: Option<String>,
+    // the documented symbol of a doc chunk.
+    #[serde(default)]
+    pub symbol: Option<String>,
+    // the keys a chunk of a config file is under, e.g. `spec.template.containers`.
+    #[serde(default)]
+    pub key_path: Option<String>,
+    // the language written inside the chunk, e.g. `sql` for a query in a string.
+    #[serde(default)]
+    pub embedded_lang: Option<String>,
+    // test and vendored code, see `common::path_class`.
+    #[serde(default)]
+    pub is_test: bool,
+    #[serde(default)]
+    pub is_vendored: bool,
+    // unix seconds of the last commit changing the file.
+    #[serde(default)]
+    pub last_modified: Option<u64>,
 
     #[serde(skip)]
     pub id: Option<String>,
@@ -155,6 +198,10 @@ impl PartialEq for Payload {
             && self.end_line == other.end_line
             && self.start_byte == other.start_byte
             && self.end_byte == other.end_byte
+            && self.doc == other.doc
+            && self.symbol == other.symbol
+            && self.key_path == other.key_path
+            && self.embedded_lang == other.embedded_lang
 
         // ignoring deserialized fields that will not exist on a newly
         // created payload
@@ -170,7 +217,7 @@ macro_rules! val_parse_str(($hash:ident, $val:expr) => {
 });
 pub(crate) use val_str;
 
-fn kind_to_value(kind: Option<qdrant_client::qdrant::value::Kind>) -> serde_json::Value {
+pub(crate) fn kind_to_value(kind: Option<qdrant_client::qdrant::value::Kind>) -> serde_json::Value {
     use qdrant_client::qdrant::value::Kind;
     match kind {
         Some(Kind::NullValue(_)) => serde_json::Value::Null,
@@ -186,7 +233,12 @@ fn kind_to_value(kind: Option<qdrant_client::qdrant::value::Kind>) -> serde_json
                 .map(|v| kind_to_value(v.kind))
                 .collect(),
         ),
-        Some(Kind::StructValue(_v)) => todo!(),
+        Some(Kind::StructValue(v)) => serde_json::Value::Object(
+            v.fields
+                .into_iter()
+                .map(|(key, value)| (key, kind_to_value(value.kind)))
+                .collect(),
+        ),
         None => serde_json::Value::Null,
     }
 }
@@ -227,14 +279,39 @@ fn parse_payload(
         repo_name: val_str!(converted, "repo_name"),
         relative_path: val_str!(converted, "relative_path"),
         content_hash: val_str!(converted, "content_hash"),
-        text: val_str!(converted, "snippet"),
+        text: compression::take_chunk_text(&mut converted).unwrap_or_else(|e| {
+            log::error!("Failed to read the chunk text: {}", e);
+            String::new()
+        }),
         start_line: val_parse_str!(converted, "start_line"),
         end_line: val_parse_str!(converted, "end_line"),
         start_byte: val_parse_str!(converted, "start_byte"),
         end_byte: val_parse_str!(converted, "end_byte"),
+        // missing on chunks indexed before doc comments were extracted.
+        doc: optional_str(&mut converted, "doc"),
+        symbol: optional_str(&mut converted, "symbol"),
+        key_path: optional_str(&mut converted, "key_path"),
+        // missing on chunks indexed before the embedded languages were detected.
+        embedded_lang: optional_str(&mut converted, EMBEDDED_LANG_FIELD),
+        // missing on chunks indexed before the paths were classified.
+        is_test: optional_bool(&mut converted, "is_test"),
+        is_vendored: optional_bool(&mut converted, "is_vendored"),
+        // missing on chunks indexed before the commit times were recorded.
+        last_modified: converted.remove("last_modified").and_then(|value| value.as_u64()),
 
         id: Some(id),
         score: Some(score),
         embedding,
     }
 }
+
+fn optional_str(converted: &mut HashMap<String, serde_json::Value>, key: &str) -> Option<String> {
+    match converted.remove(key) {
+        Some(serde_json::Value::String(value)) => Some(value),
+        _ => None,
+    }
+}
+
+fn optional_bool(converted: &mut HashMap<String, serde_json::Value>, key: &str) -> bool {
+    matches!(converted.remove(key), Some(serde_json::Value::Bool(true)))
+}
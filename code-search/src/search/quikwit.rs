@@ -3,15 +3,40 @@ use common::ast::graph_code_pluck::ContentDocument;
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::time::Instant;
+
+use common::branch::{quickwit_branch_query, RepoBranches};
+use common::path_class::is_metadata_document;
+use common::reconnect::Reconnecting;
+use common::run_manifest::{RunManifest, RUN_MANIFEST_PATH};
+use common::{metrics, telemetry};
+use lazy_static::lazy_static;
+use tracing::Instrument;
 
 use crate::config::get_quikwit_db_url;
 
+lazy_static! {
+    // Shared by the requests to quickwit, rebuilt with a fresh connection pool when quickwit restarts.
+    pub static ref QUICKWIT: Reconnecting<reqwest::Client> =
+        Reconnecting::new("quickwit", reqwest::Client::new(), || async {
+            Ok(reqwest::Client::new())
+        });
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct BodyRes {
     query: String,
     max_hits: i32,
 }
 
+// hits of a keyword search are sorted by their BM25 score instead of the document order.
+#[derive(Debug, Serialize, Deserialize)]
+struct ScoredBodyRes {
+    query: String,
+    max_hits: i32,
+    sort_by_field: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ApiResponse {
     num_hits: i32,            // Change the type to i32 or another appropriate numeric type
@@ -42,10 +67,11 @@ struct ResultItem {
 pub async fn get_all_files_for_repo(
     index_name: &str,
     repo_name: &str,
+    branch: &str,
 ) -> Result<Vec<ContentDocument>> {
     let base_url = get_quikwit_db_url();
 
-    let query = "*".to_string();
+    let query = quickwit_branch_query("*", branch);
 
     let json_data = BodyRes {
         query,
@@ -55,14 +81,11 @@ pub async fn get_all_files_for_repo(
     let json_string = serde_json::to_string(&json_data).expect("Failed to serialize object");
     let url = format!("{}/api/v1/{}/search", base_url, index_name);
 
-    // create http client and send request 
-    let client = reqwest::Client::new();
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .body(json_string)
-        .send()
+    let start = Instant::now();
+    let response = post_json(url, json_string)
+        .instrument(telemetry::db_span("quickwit", "list_files"))
         .await?;
+    metrics::observe_db_query("quickwit", "list_files", start.elapsed());
 
     let mut response_array: Vec<ContentDocument> = Vec::new();
 
@@ -105,16 +128,19 @@ pub async fn get_all_files_for_repo(
     }
 }
 
+/// The document of `branch` matching the query, the same path has a document on each indexed branch.
 pub async fn get_file_from_quickwit(
     index_name: &str,
     search_field: &str,
     search_query: &str,
+    branch: &str,
 ) -> Result<Option<ContentDocument>> {
     let query = if !search_field.is_empty() {
         format!("{}:{}", search_field, search_query)
     } else {
         search_query.to_owned()
     };
+    let query = quickwit_branch_query(&query, branch);
     let response_array = search_quickwit(index_name, &query).await?;
     let filtered_response_array: Vec<ContentDocument> = response_array
         .into_iter()
@@ -137,23 +163,98 @@ pub async fn search_quickwit(
     index_name: &str,
     query: &str,
 ) -> Result<Vec<ContentDocument>, Error> {
-    let base_url = get_quikwit_db_url(); 
-
     let json_data = BodyRes {
         query: query.to_string(),
         max_hits: 10,
     };
 
     let json_string = serde_json::to_string(&json_data).expect("Failed to serialize object");
+    run_search(index_name, json_string, "search").await
+}
+
+/// Searches the index with a keyword query, the documents are returned best BM25 match first.
+/// The metadata documents of the repo aren't code and are left out.
+pub async fn keyword_search(
+    index_name: &str,
+    query: &str,
+    max_hits: i32,
+) -> Result<Vec<ContentDocument>, Error> {
+    let json_data = ScoredBodyRes {
+        query: query.to_string(),
+        max_hits,
+        sort_by_field: "_score".to_string(),
+    };
+
+    let json_string = serde_json::to_string(&json_data).expect("Failed to serialize object");
+    let documents = run_search(index_name, json_string, "keyword_search").await?;
+    Ok(documents
+        .into_iter()
+        .filter(|document| !is_metadata_document(&document.relative_path))
+        .collect())
+}
+
+/// Commit the branch was indexed at, every document of the branch carries it.
+/// None for branches indexed before the commit was recorded.
+pub async fn get_indexed_commit(index_name: &str, branch: &str) -> Result<Option<String>> {
+    let json_data = BodyRes {
+        query: quickwit_branch_query("*", branch),
+        max_hits: 1,
+    };
+    let json_string = serde_json::to_string(&json_data).expect("Failed to serialize object");
+    let url = format!("{}/api/v1/{}/search", get_quikwit_db_url(), index_name);
+
+    let start = Instant::now();
+    let response = post_json(url, json_string)
+        .instrument(telemetry::db_span("quickwit", "indexed_commit"))
+        .await?;
+    metrics::observe_db_query("quickwit", "indexed_commit", start.elapsed());
+
+    if !response.status().is_success() {
+        error!("Request was not successful: {}", response.status());
+        return Ok(None);
+    }
+    let api_response: ApiResponse = serde_json::from_str(&response.text().await?)?;
+    Ok(api_response
+        .hits
+        .into_iter()
+        .map(|hit| hit.last_commit)
+        .find(|commit| !commit.is_empty()))
+}
+
+/// The branches of the repo, from the run manifests their indexing runs left in the index.
+pub async fn get_indexed_branches(index_name: &str, repo_name: &str) -> Result<RepoBranches> {
+    let json_data = BodyRes {
+        query: format!("relative_path:{}", RUN_MANIFEST_PATH),
+        max_hits: 1000,
+    };
+    let json_string = serde_json::to_string(&json_data).expect("Failed to serialize object");
+    let documents = run_search(index_name, json_string, "indexed_branches").await?;
+    let manifests = documents
+        .into_iter()
+        .filter(|document| document.relative_path == RUN_MANIFEST_PATH)
+        .filter_map(|document| match serde_json::from_str::<RunManifest>(&document.content) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                error!("Failed to parse the run manifest of {}: {}", document.repo_ref, e);
+                None
+            }
+        });
+    Ok(RepoBranches::from_manifests(repo_name, manifests))
+}
+
+async fn run_search(
+    index_name: &str,
+    json_string: String,
+    operation: &str,
+) -> Result<Vec<ContentDocument>, Error> {
+    let base_url = get_quikwit_db_url(); 
     let url = format!("{}/api/v1/{}/search", base_url, index_name);
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .body(json_string)
-        .send()
+    let start = Instant::now();
+    let response = post_json(url, json_string)
+        .instrument(telemetry::db_span("quickwit", operation))
         .await?;
+    metrics::observe_db_query("quickwit", operation, start.elapsed());
 
     let mut response_array: Vec<ContentDocument> = Vec::new();
 
@@ -188,3 +289,32 @@ pub async fn search_quickwit(
 
     Ok(response_array)
 }
+
+// Sends the request with the shared client, once more after reconnecting when quickwit can't be reached.
+async fn post_json(url: String, body: String) -> Result<reqwest::Response> {
+    QUICKWIT
+        .run(|client| {
+            let request = client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            async move { Ok(request.send().await?) }
+        })
+        .await
+}
+
+/// Lists the indexes, the lightweight call the readiness check makes with the shared client.
+pub async fn list_indexes(client: &reqwest::Client) -> Result<usize> {
+    let url = format!("{}/api/v1/indexes", get_quikwit_db_url());
+    let start = Instant::now();
+    let indexes: Vec<serde_json::Value> = client
+        .get(url)
+        .send()
+        .instrument(telemetry::db_span("quickwit", "list_indexes"))
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    metrics::observe_db_query("quickwit", "list_indexes", start.elapsed());
+    Ok(indexes.len())
+}
@@ -2,13 +2,16 @@ extern crate hashbrown;
 extern crate strsim;
 
 use crate::search::payload::{CodeExtractMeta, PathExtractMeta, SymbolPayload};
+use crate::search::ranking_config::RankingConfig;
 use hashbrown::HashMap;
 use strsim::levenshtein;
 
 // declare global variable for POWF_FACTOR
 const POWF_FACTOR: f32 = 3.0;
+// symbols appearing in more files than this start losing weight, see `commonness_weight`.
+const COMMON_SYMBOL_OCCURRENCES: f32 = 20.0;
 
-// create a table for weights of symbols
+// create a table for weights of symbols, the defaults of the ranking config.
 pub fn symbol_weights() -> HashMap<String, f32> {
     let mut weights = HashMap::new();
     weights.insert("variable".to_string(), 1.0);
@@ -20,7 +23,21 @@ pub fn symbol_weights() -> HashMap<String, f32> {
     weights.clone()
 }
 
-pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMeta> {
+// A symbol defined in hundreds of files says little about which of them is relevant,
+// so its score is dampened logarithmically with the total number of occurrences,
+// including the ones dropped at ingestion time and only recorded as `overflow_count`.
+pub fn commonness_weight(payload: &SymbolPayload) -> f32 {
+    let occurrences = payload.occurrences.len() as f32 + payload.overflow_count.max(0) as f32;
+    if occurrences <= COMMON_SYMBOL_OCCURRENCES {
+        return 1.0;
+    }
+    1.0 / (1.0 + (occurrences / COMMON_SYMBOL_OCCURRENCES).ln())
+}
+
+pub fn rank_symbol_payloads(
+    payloads: &Vec<SymbolPayload>,
+    config: &RankingConfig,
+) -> Vec<PathExtractMeta> {
     let mut path_scores: HashMap<String, f32> = HashMap::new();
     let mut path_history: HashMap<String, Vec<String>> = HashMap::new();
     // create map to store the relative_path + symbol string and count the number of times it appears.
@@ -30,21 +47,22 @@ pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMet
 
     for (i, val) in payloads.iter().enumerate() {
         let payload = &payloads[i];
-        let score = payload.score.unwrap_or(0.0);
+        let score = payload.score.unwrap_or(0.0) * commonness_weight(payload);
         // check if node_type is "ref"
-        for (index, path) in payload.relative_paths.iter().enumerate() {
+        for occurrence in payload.occurrences.iter() {
+            let path = &occurrence.path;
             let mut path_score = 0.0;
             let mut history = Vec::new();
             // print the node type and path
-            println!(" in loop {}: {}", payload.node_kinds[index], path);
+            println!(" in loop {}: {}", occurrence.node_kind, path);
             // print symbol type and is global
             println!(
                 "in loop {}: {}",
-                payload.symbol_types[index], payload.is_globals[index]
+                occurrence.symbol_type, occurrence.is_global
             );
 
             // print if the symbol type is ref
-            if payload.node_kinds[index] == "ref".to_string() {
+            if occurrence.node_kind == "ref".to_string() {
                 println!("xxxxxx ref is here");
             }
             // concatenate the relative_path and symbol string and store in path_symbol
@@ -73,12 +91,12 @@ pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMet
                 // and the contribution of the symbol to the path's score.
                 let code_extract_meta = CodeExtractMeta {
                     symbol: payload.symbol.clone(),
-                    node_kind: payload.node_kinds[index].clone(),
-                    symbol_type: payload.symbol_types[index].clone(),
-                    is_global: payload.is_globals[index],
+                    node_kind: occurrence.node_kind.clone(),
+                    symbol_type: occurrence.symbol_type.clone(),
+                    is_global: occurrence.is_global,
                     score: path_score,
-                    start_byte: payload.start_bytes[index],
-                    end_byte: payload.end_bytes[index],
+                    start_byte: occurrence.start_byte as i64,
+                    end_byte: occurrence.end_byte as i64,
                 };
 
                 // store the metadata in the code_extract_meta map
@@ -99,29 +117,21 @@ pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMet
                 continue;
             }
 
-            // Score based on the type of symbol
-            match payload.symbol_types[index].as_str() {
-                "variable" => path_score += 1.0,
-                "function" => path_score += 9.0,
-                "module" => path_score += 8.0,
-                "struct" => path_score += 8.0,
-                "field" => path_score += 3.0,
-                // print the type and add score of 2.0
-                _ => {
-                    println!("Unknown symbol type: {}", payload.symbol_types[index]);
-                    path_score += 2.0;
-                }
+            // Score based on the type of symbol, the types without a weight get the one of `unknown`
+            if !config.symbol_weights.contains_key(&occurrence.symbol_type) {
+                println!("Unknown symbol type: {}", occurrence.symbol_type);
             }
+            path_score += config.symbol_weight(&occurrence.symbol_type);
 
             // multiple path_score by score power of three
             path_score = path_score * score;
             history.push(format!(
                 "Scored {} for symbol {} symbol type {}",
-                path_score, payload.symbol, payload.symbol_types[index]
+                path_score, payload.symbol, occurrence.symbol_type
             ));
 
             // Score based on is_global
-            if payload.is_globals[index] {
+            if occurrence.is_global {
                 let global_score = 500.0 * score.powf(5.0);
                 path_score += global_score;
                 history.push(format!(
@@ -162,12 +172,12 @@ pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMet
                 // and the contribution of the symbol to the path's score.
                 let code_extract_meta = CodeExtractMeta {
                     symbol: payload.symbol.clone(),
-                    node_kind: payload.node_kinds[index].clone(),
-                    symbol_type: payload.symbol_types[index].clone(),
-                    is_global: payload.is_globals[index],
+                    node_kind: occurrence.node_kind.clone(),
+                    symbol_type: occurrence.symbol_type.clone(),
+                    is_global: occurrence.is_global,
                     score: path_score,
-                    start_byte: payload.start_bytes[index],
-                    end_byte: payload.end_bytes[index],
+                    start_byte: occurrence.start_byte as i64,
+                    end_byte: occurrence.end_byte as i64,
                 };
 
                 // store the metadata in the code_extract_meta map
@@ -215,12 +225,12 @@ pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMet
             // and the contribution of the symbol to the path's score.
             let code_extract_meta = CodeExtractMeta {
                 symbol: payload.symbol.clone(),
-                node_kind: payload.node_kinds[index].clone(),
-                symbol_type: payload.symbol_types[index].clone(),
-                is_global: payload.is_globals[index],
+                node_kind: occurrence.node_kind.clone(),
+                symbol_type: occurrence.symbol_type.clone(),
+                is_global: occurrence.is_global,
                 score: path_score,
-                start_byte: payload.start_bytes[index],
-                end_byte: payload.end_bytes[index],
+                start_byte: occurrence.start_byte as i64,
+                end_byte: occurrence.end_byte as i64,
             };
 
             // store the metadata in the code_extract_meta map
@@ -258,3 +268,72 @@ pub fn rank_symbol_payloads(payloads: &Vec<SymbolPayload>) -> Vec<PathExtractMet
 
     final_scores
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::symbol_payload::SymbolOccurrence;
+
+    fn symbol_payload(symbol: &str, path_count: usize, overflow_count: i64, score: f32) -> SymbolPayload {
+        let occurrences = (0..path_count)
+            .map(|i| SymbolOccurrence {
+                path: format!("src/{}/file_{}.rs", symbol, i),
+                start_byte: 0,
+                end_byte: 10,
+                is_global: true,
+                node_kind: "def".to_string(),
+                lang: "rust".to_string(),
+                symbol_type: "function".to_string(),
+                container_path: vec![],
+            })
+            .collect::<Vec<_>>();
+        SymbolPayload {
+            repo_name: "repo".to_string(),
+            symbol: symbol.to_string(),
+            occurrences,
+            overflow_count,
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_commonness_weight() {
+        assert_eq!(commonness_weight(&symbol_payload("rare", 3, 0, 0.9)), 1.0);
+        let capped = commonness_weight(&symbol_payload("hot", 50, 450, 0.9));
+        let uncapped = commonness_weight(&symbol_payload("hot", 50, 0, 0.9));
+        assert!(capped < uncapped);
+        assert!(uncapped < 1.0);
+    }
+
+    #[test]
+    fn test_hot_symbol_ranks_below_rare_symbol() {
+        // same semantic score, but the hot symbol was seen in 500 files.
+        let payloads = vec![
+            symbol_payload("handle", 50, 450, 0.8),
+            symbol_payload("refresh_token", 1, 0, 0.8),
+        ];
+
+        let ranked = rank_symbol_payloads(&payloads, &RankingConfig::default());
+
+        assert_eq!(ranked[0].path, "src/refresh_token/file_0.rs");
+        let hot_score = ranked
+            .iter()
+            .find(|meta| meta.path == "src/handle/file_0.rs")
+            .unwrap()
+            .score;
+        assert!(hot_score < ranked[0].score);
+    }
+
+    #[test]
+    fn test_symbol_weights_come_from_the_ranking_config() {
+        let mut payloads = vec![symbol_payload("retry", 1, 0, 0.8)];
+        payloads[0].occurrences[0].symbol_type = "trait".to_string();
+        let default = rank_symbol_payloads(&payloads, &RankingConfig::default());
+
+        let mut config = RankingConfig::default();
+        config.symbol_weights.insert("trait".to_string(), 8.0);
+        let weighted = rank_symbol_payloads(&payloads, &config);
+        assert!(weighted[0].score > default[0].score);
+    }
+}
@@ -0,0 +1,275 @@
+// The weights of the ranking that are tuned from the citations of the answers, kept in the json
+// file of `RANKING_CONFIG_PATH` and written by the `tune-ranking` job. The searches read the
+// config loaded last: the file is checked for changes every `RANKING_CONFIG_POLL_SECS` and on
+// `POST /admin/ranking/reload`, so new weights apply to the next searches without a restart.
+// Without a file the defaults apply, a file that can't be read keeps the weights loaded before.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::search::hybrid::QueryKind;
+use crate::search::ranking::symbol_weights;
+
+/// Weight of the keyword list in the fusion for each kind of query, the vector list gets the rest.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeywordWeights {
+    pub identifier: f32,
+    pub mixed: f32,
+    pub conceptual: f32,
+}
+
+impl Default for KeywordWeights {
+    fn default() -> Self {
+        Self {
+            identifier: QueryKind::Identifier.keyword_weight(),
+            mixed: QueryKind::Mixed.keyword_weight(),
+            conceptual: QueryKind::Conceptual.keyword_weight(),
+        }
+    }
+}
+
+impl KeywordWeights {
+    pub fn get(&self, kind: QueryKind) -> f32 {
+        match kind {
+            QueryKind::Identifier => self.identifier,
+            QueryKind::Mixed => self.mixed,
+            QueryKind::Conceptual => self.conceptual,
+        }
+    }
+
+    pub fn set(&mut self, kind: QueryKind, weight: f32) {
+        match kind {
+            QueryKind::Identifier => self.identifier = weight,
+            QueryKind::Mixed => self.mixed = weight,
+            QueryKind::Conceptual => self.conceptual = weight,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RankingConfig {
+    // points of an occurrence of a symbol of each type, before the semantic score of the symbol.
+    pub symbol_weights: BTreeMap<String, f32>,
+    pub keyword_weights: KeywordWeights,
+    // overrides `RECENCY_HALF_LIFE_DAYS` when set, a shorter half-life boosts recent files more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recency_half_life_days: Option<f32>,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            symbol_weights: symbol_weights().into_iter().collect(),
+            keyword_weights: KeywordWeights::default(),
+            recency_half_life_days: None,
+        }
+    }
+}
+
+impl RankingConfig {
+    /// The weight of a symbol type, the one of `unknown` for the types without their own.
+    pub fn symbol_weight(&self, symbol_type: &str) -> f32 {
+        self.symbol_weights
+            .get(symbol_type)
+            .or_else(|| self.symbol_weights.get("unknown"))
+            .copied()
+            .unwrap_or(2.0)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the ranking config {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("The ranking config {} is invalid", path.display()))
+    }
+
+    /// Writes the config to `path` through a temporary file, a search never reads half of it.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let partial = path.with_extension("json.partial");
+        std::fs::write(&partial, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+/// The ranking config the searches read, reloaded from its file when it changed.
+pub struct RankingConfigStore {
+    path: Option<PathBuf>,
+    config: RwLock<Arc<RankingConfig>>,
+    // modification time of the file when it was loaded last.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl RankingConfigStore {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let store = Self {
+            path,
+            config: RwLock::new(Arc::new(RankingConfig::default())),
+            modified: Mutex::new(None),
+        };
+        if let Err(e) = store.reload() {
+            log::error!("Keeping the default ranking weights: {:#}", e);
+        }
+        store
+    }
+
+    pub fn current(&self) -> Arc<RankingConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Loads the file again, returns whether the weights changed. Without a file, or once it was
+    /// deleted, the weights stay as they are.
+    pub fn reload(&self) -> Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        if !path.exists() {
+            log::warn!("The ranking config {} doesn't exist", path.display());
+            return Ok(false);
+        }
+        // set before reading, a broken file is reported once rather than on every check.
+        *self.modified.lock().unwrap() = std::fs::metadata(path)?.modified().ok();
+        let config = Arc::new(RankingConfig::read(path)?);
+        let mut current = self.config.write().unwrap();
+        if **current == *config {
+            return Ok(false);
+        }
+        *current = config;
+        log::info!("Loaded the ranking weights of {}", path.display());
+        Ok(true)
+    }
+
+    /// Loads the file again if it was modified since it was loaded last.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let Ok(modified) = std::fs::metadata(path).and_then(|metadata| metadata.modified()) else {
+            return Ok(false);
+        };
+        if *self.modified.lock().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+        self.reload()
+    }
+
+    /// Checks the file for changes every `interval` until the process stops.
+    pub fn watch(self: Arc<Self>, interval: std::time::Duration) {
+        if self.path.is_none() || interval.is_zero() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = self.reload_if_changed() {
+                    log::error!("Keeping the ranking weights loaded before: {:#}", e);
+                }
+            }
+        });
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL_STORE: RwLock<Arc<RankingConfigStore>> =
+        RwLock::new(Arc::new(RankingConfigStore::new(None)));
+}
+
+/// Replaces the store the searches read, at startup.
+pub fn init(store: Arc<RankingConfigStore>) {
+    *GLOBAL_STORE.write().unwrap() = store;
+}
+
+pub fn store() -> Arc<RankingConfigStore> {
+    GLOBAL_STORE.read().unwrap().clone()
+}
+
+/// The ranking weights of the next search.
+pub fn current() -> Arc<RankingConfig> {
+    store().current()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::hybrid::{classify_query, fuse, FusionMethod};
+
+    fn config_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "ranking-{}-{}.json",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    // the paths of a fused search of the query, best first.
+    fn search(store: &RankingConfigStore, query: &str) -> Vec<String> {
+        let weight = store.current().keyword_weights.get(classify_query(query));
+        fuse(
+            &[
+                ("src/vector.rs".to_string(), 0.9),
+                ("src/both.rs".to_string(), 0.2),
+            ],
+            &[
+                ("src/keyword.rs".to_string(), 1.0),
+                ("src/both.rs".to_string(), 0.5),
+            ],
+            FusionMethod::WeightedSum,
+            weight,
+        )
+        .into_iter()
+        .map(|hit| hit.path)
+        .collect()
+    }
+
+    #[test]
+    fn test_missing_fields_keep_their_defaults() {
+        let config: RankingConfig = serde_json::from_str(
+            r#"{"keyword_weights": {"identifier": 0.9, "mixed": 0.5, "conceptual": 0.1}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.symbol_weight("function"), 9.0);
+        assert_eq!(config.symbol_weight("trait"), 2.0);
+        assert_eq!(config.keyword_weights.get(QueryKind::Conceptual), 0.1);
+        assert_eq!(config.recency_half_life_days, None);
+    }
+
+    #[test]
+    fn test_reloaded_weights_apply_to_the_next_searches() {
+        let path = config_path();
+        let store = RankingConfigStore::new(Some(path.clone()));
+        // no file yet, the defaults apply.
+        assert_eq!(*store.current(), RankingConfig::default());
+        let query = "how are the embeddings stored";
+        assert_eq!(search(&store, query)[0], "src/vector.rs");
+
+        let mut tuned = RankingConfig::default();
+        tuned.keyword_weights.set(QueryKind::Conceptual, 0.9);
+        tuned.symbol_weights.insert("function".to_string(), 4.0);
+        tuned.write(&path).unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(store.current().symbol_weight("function"), 4.0);
+        assert_eq!(search(&store, query)[0], "src/keyword.rs");
+        // nothing changed since.
+        assert!(!store.reload_if_changed().unwrap());
+
+        // a broken file keeps the weights loaded before.
+        std::fs::write(&path, "{\"symbol_weights\": ").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(store.current().symbol_weight("function"), 4.0);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!store.reload().unwrap());
+        assert_eq!(search(&store, query)[0], "src/keyword.rs");
+    }
+}
@@ -0,0 +1,204 @@
+// A deprecated implementation is often textually richer than the one replacing it, and questions
+// about the current behavior get answered with dead code. When the query asks about the current
+// behavior, or the request sets `recency`, the score of a path is multiplied by a decay halving
+// every `RECENCY_HALF_LIFE_DAYS` the file was last changed before the most recently changed hit.
+// Paths that look deprecated are slightly demoted on every query.
+
+use std::collections::HashMap;
+
+use crate::search::hybrid::FusedHit;
+
+const SECS_PER_DAY: f32 = 24.0 * 60.0 * 60.0;
+// directories of code kept around after being replaced.
+const DEPRECATED_DIRS: &[&str] = &["legacy", "deprecated"];
+
+/// Multiplier of the score of a file last changed at `last_modified`, 1 for the newest one and
+/// halved every `half_life_days` before it.
+pub fn recency_factor(last_modified: u64, newest: u64, half_life_days: f32) -> f32 {
+    if half_life_days <= 0.0 {
+        return 1.0;
+    }
+    let age_days = newest.saturating_sub(last_modified) as f32 / SECS_PER_DAY;
+    0.5_f32.powf(age_days / half_life_days)
+}
+
+/// Whether the path is under a `legacy/` or `deprecated/` directory, or under a `v1/` directory
+/// with a `v2/` sibling among the other paths.
+pub fn is_deprecated_path(path: &str, paths: &[&str]) -> bool {
+    let dirs = path.split('/').collect::<Vec<_>>();
+    let dirs = &dirs[..dirs.len().saturating_sub(1)];
+    if dirs
+        .iter()
+        .any(|dir| DEPRECATED_DIRS.contains(&dir.to_lowercase().as_str()))
+    {
+        return true;
+    }
+    dirs.iter()
+        .enumerate()
+        .filter(|(_, dir)| dir.eq_ignore_ascii_case("v1"))
+        .any(|(i, _)| {
+            let sibling = dirs[..i]
+                .iter()
+                .chain(["v2", ""].iter())
+                .copied()
+                .collect::<Vec<_>>()
+                .join("/");
+            paths.iter().any(|other| other.starts_with(&sibling))
+        })
+}
+
+/// Multiplies the scores of the hits by their recency when `half_life_days` is set, then by
+/// `deprecated_weight` for the deprecated paths, and sorts the hits again. Hits without a time,
+/// indexed before the times were recorded, keep their score. Returns why the score of each
+/// rescored path changed.
+pub fn rescore_by_recency(
+    mut hits: Vec<FusedHit>,
+    last_modified: &HashMap<String, u64>,
+    half_life_days: Option<f32>,
+    deprecated_weight: f32,
+) -> (Vec<FusedHit>, HashMap<String, Vec<String>>) {
+    let mut reasons: HashMap<String, Vec<String>> = HashMap::new();
+    if let Some(half_life_days) = half_life_days {
+        let newest = hits
+            .iter()
+            .filter_map(|hit| last_modified.get(&hit.path))
+            .max()
+            .copied();
+        for hit in hits.iter_mut() {
+            let (Some(newest), Some(time)) = (newest, last_modified.get(&hit.path)) else {
+                continue;
+            };
+            let factor = recency_factor(*time, newest, half_life_days);
+            hit.score *= factor;
+            reasons.entry(hit.path.clone()).or_default().push(format!(
+                "Boosted by {:.2} for a last change {} days before the newest hit",
+                factor,
+                newest.saturating_sub(*time) / SECS_PER_DAY as u64
+            ));
+        }
+    }
+
+    let weight = deprecated_weight.clamp(0.0, 1.0);
+    if weight < 1.0 {
+        let paths = hits.iter().map(|hit| hit.path.clone()).collect::<Vec<_>>();
+        let paths = paths.iter().map(String::as_str).collect::<Vec<_>>();
+        for hit in hits.iter_mut() {
+            if is_deprecated_path(&hit.path, &paths) {
+                hit.score *= weight;
+                reasons
+                    .entry(hit.path.clone())
+                    .or_default()
+                    .push(format!("Demoted by {} as a deprecated path", weight));
+            }
+        }
+    }
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.path.cmp(&b.path)));
+    (hits, reasons)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::models::RetrievalSource;
+
+    const DAY: u64 = 24 * 60 * 60;
+    const NOW: u64 = 1_700_000_000;
+
+    fn hit(path: &str, score: f32) -> FusedHit {
+        FusedHit {
+            path: path.to_string(),
+            score,
+            source: RetrievalSource::Both,
+        }
+    }
+
+    fn order(hits: &[FusedHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.path.as_str()).collect()
+    }
+
+    // the old implementation still in the tree has the richer text and outscores its replacement.
+    fn old_and_new_implementations() -> (Vec<FusedHit>, HashMap<String, u64>) {
+        let hits = vec![
+            hit("billing/invoice_renderer.py", 0.9),
+            hit("billing/invoice_pdf.py", 0.7),
+            hit("billing/taxes.py", 0.4),
+        ];
+        let last_modified = HashMap::from([
+            ("billing/invoice_renderer.py".to_string(), NOW - 700 * DAY),
+            ("billing/invoice_pdf.py".to_string(), NOW - 3 * DAY),
+            ("billing/taxes.py".to_string(), NOW - 90 * DAY),
+        ]);
+        (hits, last_modified)
+    }
+
+    #[test]
+    fn test_recency_factor() {
+        assert_eq!(recency_factor(NOW, NOW, 180.0), 1.0);
+        assert_eq!(recency_factor(NOW - 180 * DAY, NOW, 180.0), 0.5);
+        assert_eq!(recency_factor(NOW - 360 * DAY, NOW, 180.0), 0.25);
+        // a file changed after the reference doesn't get more than the full score.
+        assert_eq!(recency_factor(NOW + DAY, NOW, 180.0), 1.0);
+        assert_eq!(recency_factor(NOW - 360 * DAY, NOW, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_new_implementation_wins_under_the_boost() {
+        let (hits, last_modified) = old_and_new_implementations();
+
+        let (unboosted, reasons) = rescore_by_recency(hits.clone(), &last_modified, None, 0.9);
+        assert_eq!(unboosted, hits);
+        assert!(reasons.is_empty());
+
+        let (boosted, reasons) = rescore_by_recency(hits, &last_modified, Some(180.0), 0.9);
+        assert_eq!(
+            order(&boosted),
+            vec![
+                "billing/invoice_pdf.py",
+                "billing/taxes.py",
+                "billing/invoice_renderer.py"
+            ]
+        );
+        assert_eq!(
+            reasons["billing/invoice_renderer.py"],
+            vec!["Boosted by 0.07 for a last change 697 days before the newest hit"]
+        );
+        assert_eq!(
+            reasons["billing/invoice_pdf.py"],
+            vec!["Boosted by 1.00 for a last change 0 days before the newest hit"]
+        );
+    }
+
+    #[test]
+    fn test_deprecated_paths_are_demoted() {
+        let paths = [
+            "api/v1/handlers.rs",
+            "api/v2/handlers.rs",
+            "client/v1/session.rs",
+            "src/legacy/auth.rs",
+            "Deprecated/export.py",
+            "src/legacy.rs",
+        ];
+        assert!(is_deprecated_path("api/v1/handlers.rs", &paths));
+        // without a v2 the v1 directory is the current version.
+        assert!(!is_deprecated_path("client/v1/session.rs", &paths));
+        assert!(!is_deprecated_path("api/v2/handlers.rs", &paths));
+        assert!(is_deprecated_path("src/legacy/auth.rs", &paths));
+        assert!(is_deprecated_path("Deprecated/export.py", &paths));
+        assert!(!is_deprecated_path("src/legacy.rs", &paths));
+
+        let hits = vec![
+            hit("api/v1/handlers.rs", 0.8),
+            hit("api/v2/handlers.rs", 0.75),
+        ];
+        let (ranked, reasons) = rescore_by_recency(hits, &HashMap::new(), Some(180.0), 0.9);
+        assert_eq!(
+            order(&ranked),
+            vec!["api/v2/handlers.rs", "api/v1/handlers.rs"]
+        );
+        assert_eq!(
+            reasons["api/v1/handlers.rs"],
+            vec!["Demoted by 0.9 as a deprecated path"]
+        );
+        assert!(!reasons.contains_key("api/v2/handlers.rs"));
+    }
+}
@@ -3,28 +3,40 @@ use crate::{
     search::semantic::SemanticError::QdrantInitializationError,
 };
 use anyhow::Result;
+use common::branch::{is_default_branch, requested_branch, BRANCH_FIELD};
+use common::embedded_lang::EMBEDDED_LANG_FIELD;
+use common::generation::IndexGeneration;
 use common::hasher::generate_qdrant_index_name;
+use common::reconnect::Reconnecting;
+use common::service_interaction::DOCUMENT_COLLECTION_NAME;
+use common::{metrics, telemetry};
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::time::Instant;
 use std::{str, time::Duration};
 use thiserror::Error;
+use tracing::Instrument;
 
 use crate::{
     parser::literal::Literal,
-    search::payload::{Embedding, SymbolPayload},
+    search::batch::search_one,
+    search::payload::{kind_to_value, parse_symbol_points, Embedding, Payload, SymbolPayload},
 };
 
 use qdrant_client::{
     prelude::{QdrantClient, QdrantClientConfig},
     qdrant::{
         r#match::MatchValue, with_payload_selector, with_vectors_selector, Condition,
-        FieldCondition, Filter, Match, ScoredPoint, SearchPoints, WithPayloadSelector,
-        WithVectorsSelector,
+        FieldCondition, Filter, Match, PayloadIncludeSelector, ScoredPoint, ScrollPoints,
+        SearchPoints, WithPayloadSelector, WithVectorsSelector,
     },
 };
 
 pub struct Semantic {
     pub qdrant_collection_name: String,
-    pub qdrant: QdrantClient,
-    tokenizer_onnx: common::tokenizer_onnx::TokenizerOnnx,
+    // rebuilt when qdrant restarts, see `common::reconnect`.
+    pub qdrant: Reconnecting<QdrantClient>,
+    tokenizer_onnx: std::sync::Arc<common::tokenizer_onnx::TokenizerOnnx>,
 }
 
 #[derive(Error, Debug)]
@@ -70,10 +82,12 @@ impl Semantic {
         if qdrant.is_err() {
             return Err(QdrantInitializationError);
         }
-        let qdrant = qdrant.unwrap();
+        let qdrant = Reconnecting::new("qdrant", qdrant.unwrap(), || async {
+            get_qdrant_client().await.map_err(anyhow::Error::from)
+        });
         Ok(Self {
-            qdrant: qdrant.into(),
-            tokenizer_onnx: common::tokenizer_onnx::TokenizerOnnx::new(&get_model_path())?,
+            qdrant,
+            tokenizer_onnx: common::tokenizer_onnx::TokenizerOnnx::shared(&get_model_path())?,
             qdrant_collection_name: get_symbol_collection_name(),
         })
     }
@@ -82,76 +96,94 @@ impl Semantic {
         self.tokenizer_onnx.get_embedding(sequence)
     }
 
-    // function to perform semantic search on the symbols.
+    // function to perform semantic search on the symbols, `limit` of them are retrieved exactly.
+    // A caller that deduplicates them asks for more than it returns.
     pub async fn search_symbol<'a>(
         &self,
         parsed_query: Literal<'a>,
         limit: u64,
         offset: u64,
         threshold: f32,
-        retrieve_more: bool,
         repo_name: &String,
+        branch: &str,
     ) -> anyhow::Result<Vec<SymbolPayload>> {
         let query = parsed_query.as_plain().unwrap();
         let vector = self.embed(&query)?;
 
-        // TODO: Remove the need for `retrieve_more`. It's here because:
-        // In /q `limit` is the maximum number of results returned (the actual number will often be lower due to deduplication)
-        // In /answer we want to retrieve `limit` results exactly
         let results = self
-            .search_with(
-                self.qdrant_collection_name.as_str(),
-                vector.clone(),
-                if retrieve_more { limit * 2 } else { limit }, // Retrieve double `limit` and deduplicate
-                offset,
-                threshold,
-                repo_name,
-            )
+            .search_with(vector.clone(), limit, offset, threshold, repo_name, branch)
             .await
-            .map(|raw| {
-                raw.into_iter()
-                    .map(SymbolPayload::from_qdrant)
-                    .collect::<Vec<_>>()
-            })?;
+            .map(parse_symbol_points)?;
         Ok(results)
     }
 
+    // function to perform semantic search on the doc comments, indexed as chunks of their own.
+    pub async fn search_docs(
+        &self,
+        query: &str,
+        limit: u64,
+        repo_name: &str,
+        branch: &str,
+    ) -> anyhow::Result<Vec<Payload>> {
+        let points = search_one(
+            &self.qdrant,
+            docs_search_request(self.embed(query)?, limit, repo_name, branch),
+        )
+        .await?;
+
+        Ok(points.into_iter().map(Payload::from_qdrant).collect())
+    }
+
+    // function to read when the files were last changed, from one of their chunks. Files that
+    // failed to be read, or indexed before the times were recorded, are left out.
+    pub async fn last_modified(
+        &self,
+        repo_name: &str,
+        branch: &str,
+        paths: &[String],
+        generation: &IndexGeneration,
+    ) -> HashMap<String, u64> {
+        let lookups = paths.iter().map(|path| async move {
+            let mut request = last_modified_request(repo_name, branch, path);
+            request.collection_name = generation.collection(&request.collection_name);
+            let start = Instant::now();
+            let response = self
+                .qdrant
+                .run(|client| {
+                    let request = request.clone();
+                    async move { client.scroll(&request).await }
+                })
+                .instrument(telemetry::db_span("qdrant", "last_modified"))
+                .await;
+            metrics::observe_db_query("qdrant", "last_modified", start.elapsed());
+            let response = response
+                .map_err(|e| log::error!("Failed to read when {} was last changed: {}", path, e))
+                .ok()?;
+            let time = response.result.into_iter().find_map(|point| {
+                kind_to_value(point.payload.get("last_modified")?.kind.clone()).as_u64()
+            })?;
+            Some((path.clone(), time))
+        });
+        join_all(lookups).await.into_iter().flatten().collect()
+    }
+
     pub async fn search_with<'a>(
         &self,
-        collection_name: &str,
         vector: Embedding,
         limit: u64,
         offset: u64,
         threshold: f32,
         repo_name: &String,
+        branch: &str,
     ) -> anyhow::Result<Vec<ScoredPoint>> {
-        let mut conditions: Vec<Condition> = Vec::new();
-
-        conditions.push(make_kv_keyword_filter("repo_name", repo_name).into());
-
-        let search_request = &SearchPoints {
-            limit,
-            vector,
-            collection_name: generate_qdrant_index_name(repo_name),
-            offset: Some(offset),
-            score_threshold: Some(threshold),
-            with_payload: Some(WithPayloadSelector {
-                selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-            }),
-            filter: Some(Filter {
-                must: conditions,
-                ..Default::default()
-            }),
-            with_vectors: Some(WithVectorsSelector {
-                selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
-            }),
-            ..Default::default()
-        };
-
-        let response = self.qdrant.search_points(search_request).await?;
+        let points = search_one(
+            &self.qdrant,
+            symbol_search_request(vector, limit, offset, threshold, repo_name, branch),
+        )
+        .await?;
 
         // iterate through the results and print the score and payload from each entry in the results
-        let mut results = response.result.clone();
+        let mut results = points.clone();
         results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
 
         let _acc = results
@@ -168,10 +200,147 @@ impl Semantic {
             })
             .collect::<Vec<_>>();
 
-        Ok(response.result)
+        Ok(points)
+    }
+}
+
+/// The search reading from the collection `generation` pinned its alias to.
+pub fn in_generation(mut request: SearchPoints, generation: &IndexGeneration) -> SearchPoints {
+    request.collection_name = generation.collection(&request.collection_name);
+    request
+}
+
+/// Search of the symbols of a repo closest to `vector`, in the collection of the repo.
+pub fn symbol_search_request(
+    vector: Embedding,
+    limit: u64,
+    offset: u64,
+    threshold: f32,
+    repo_name: &str,
+    branch: &str,
+) -> SearchPoints {
+    let mut conditions: Vec<Condition> = Vec::new();
+
+    conditions.push(make_kv_keyword_filter("repo_name", repo_name).into());
+    conditions.push(branch_condition(branch));
+
+    SearchPoints {
+        limit,
+        vector,
+        collection_name: generate_qdrant_index_name(repo_name),
+        offset: Some(offset),
+        score_threshold: Some(threshold),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        filter: Some(Filter {
+            must: conditions,
+            ..Default::default()
+        }),
+        with_vectors: Some(WithVectorsSelector {
+            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+        }),
+        ..Default::default()
+    }
+}
+
+/// Search of the doc comment chunks of a repo closest to `vector`.
+pub fn docs_search_request(
+    vector: Embedding,
+    limit: u64,
+    repo_name: &str,
+    branch: &str,
+) -> SearchPoints {
+    SearchPoints {
+        limit,
+        vector,
+        collection_name: DOCUMENT_COLLECTION_NAME.to_string(),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        filter: Some(Filter {
+            must: vec![
+                make_kv_keyword_filter("repo_name", repo_name).into(),
+                branch_condition(branch),
+                make_kv_keyword_filter("kind", "doc").into(),
+            ],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Search of the code chunks of a repo closest to `vector` holding the embedded language
+/// `embedded_lang`, e.g. the SQL of the string literals.
+pub fn embedded_search_request(
+    vector: Embedding,
+    limit: u64,
+    repo_name: &str,
+    branch: &str,
+    embedded_lang: &str,
+) -> SearchPoints {
+    SearchPoints {
+        limit,
+        vector,
+        collection_name: DOCUMENT_COLLECTION_NAME.to_string(),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        filter: Some(Filter {
+            must: vec![
+                make_kv_keyword_filter("repo_name", repo_name).into(),
+                branch_condition(branch),
+                make_kv_keyword_filter(EMBEDDED_LANG_FIELD, embedded_lang).into(),
+            ],
+            ..Default::default()
+        }),
+        ..Default::default()
     }
 }
 
+/// One chunk of a file with only its last change time.
+pub fn last_modified_request(repo_name: &str, branch: &str, path: &str) -> ScrollPoints {
+    ScrollPoints {
+        collection_name: DOCUMENT_COLLECTION_NAME.to_string(),
+        filter: Some(Filter {
+            must: vec![
+                make_kv_keyword_filter("repo_name", repo_name).into(),
+                branch_condition(branch),
+                make_kv_keyword_filter("relative_path", path).into(),
+            ],
+            ..Default::default()
+        }),
+        limit: Some(1),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Include(
+                PayloadIncludeSelector {
+                    fields: vec!["last_modified".to_string()],
+                },
+            )),
+        }),
+        ..Default::default()
+    }
+}
+
+/// Keeps the points of one branch of the repo, they share the collections with its other branches.
+/// The points indexed before branches were recorded have no branch, they belong to the default branch.
+pub(crate) fn branch_condition(branch: &str) -> Condition {
+    let branch = requested_branch(Some(branch));
+    let condition = make_kv_keyword_filter(BRANCH_FIELD, branch).into();
+    if !is_default_branch(branch) {
+        return condition;
+    }
+    Filter {
+        should: vec![
+            condition,
+            make_kv_keyword_filter(BRANCH_FIELD, "").into(),
+            Condition::is_empty(BRANCH_FIELD),
+        ],
+        ..Default::default()
+    }
+    .into()
+}
+
 // Exact match filter
 pub(crate) fn make_kv_keyword_filter(key: &str, value: &str) -> FieldCondition {
     let key = key.to_owned();
@@ -184,3 +353,35 @@ pub(crate) fn make_kv_keyword_filter(key: &str, value: &str) -> FieldCondition {
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::branch::DEFAULT_BRANCH;
+    use qdrant_client::qdrant::condition::ConditionOneOf;
+
+    #[test]
+    fn test_default_branch_keeps_the_points_without_a_branch() {
+        let Some(ConditionOneOf::Field(field)) = branch_condition("release-1.2").condition_one_of
+        else {
+            panic!("Expected a branch field condition");
+        };
+        assert_eq!(field, make_kv_keyword_filter(BRANCH_FIELD, "release-1.2"));
+
+        for branch in ["refs/heads/main", ""] {
+            let Some(ConditionOneOf::Filter(filter)) = branch_condition(branch).condition_one_of
+            else {
+                panic!("Expected a filter on the branch of {:?}", branch);
+            };
+            assert!(filter.must.is_empty());
+            assert_eq!(
+                filter.should,
+                vec![
+                    make_kv_keyword_filter(BRANCH_FIELD, DEFAULT_BRANCH).into(),
+                    make_kv_keyword_filter(BRANCH_FIELD, "").into(),
+                    Condition::is_empty(BRANCH_FIELD),
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use common::generation::IndexGeneration;
+use common::reconnect::Reconnecting;
+use common::service_interaction::SYMBOL_COLLECTION_NAME;
+use common::symbol_payload::CONTAINER_SEPARATOR;
+use common::{metrics, telemetry};
+use tracing::Instrument;
+use qdrant_client::{
+    prelude::QdrantClient,
+    qdrant::{
+        r#match::MatchValue, with_payload_selector, Condition, FieldCondition, Filter, Match,
+        PointId, ScrollPoints, WithPayloadSelector,
+    },
+};
+
+use crate::config::{get_symbol_collection_name, AppState};
+use crate::search::export::ScrollPage;
+use crate::search::payload::SymbolPayload;
+use crate::search::semantic::{branch_condition, make_kv_keyword_filter};
+use crate::utilities::util::get_line_number;
+
+pub use common::models::SymbolMatch;
+
+/// Points fetched per scroll request of an exact lookup.
+pub const LOOKUP_PAGE_SIZE: u32 = 128;
+
+/// Lowercased copy of the symbol name, written by the ingestion for case-insensitive lookups.
+pub const SYMBOL_LOWER_FIELD: &str = "symbol_lower";
+
+/// Source of the symbol points of a repo, implemented by the qdrant client and mocked in tests.
+#[async_trait]
+pub trait SymbolScroller: Send + Sync {
+    async fn scroll_symbols(
+        &self,
+        filter: Filter,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage>;
+}
+
+#[async_trait]
+impl SymbolScroller for QdrantClient {
+    async fn scroll_symbols(
+        &self,
+        filter: Filter,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        scroll_collection(self, get_symbol_collection_name(), filter, offset, limit).await
+    }
+}
+
+// One page of the symbol points of `collection_name` matching the filter.
+async fn scroll_collection(
+    client: &QdrantClient,
+    collection_name: String,
+    filter: Filter,
+    offset: Option<PointId>,
+    limit: u32,
+) -> Result<ScrollPage> {
+    let request = ScrollPoints {
+        collection_name,
+        filter: Some(filter),
+        offset,
+        limit: Some(limit),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let response = client
+        .scroll(&request)
+        .instrument(telemetry::db_span("qdrant", "scroll"))
+        .await?;
+    metrics::observe_db_query("qdrant", "scroll", start.elapsed());
+
+    Ok(ScrollPage {
+        points: response.result,
+        next_offset: response.next_page_offset,
+    })
+}
+
+#[async_trait]
+impl SymbolScroller for Reconnecting<QdrantClient> {
+    async fn scroll_symbols(
+        &self,
+        filter: Filter,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        self.run(|client| {
+            let (filter, offset) = (filter.clone(), offset.clone());
+            async move { client.scroll_symbols(filter, offset, limit).await }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl SymbolScroller for AppState {
+    async fn scroll_symbols(
+        &self,
+        filter: Filter,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        self.db_connection
+            .semantic
+            .qdrant
+            .scroll_symbols(filter, offset, limit)
+            .await
+    }
+}
+
+/// The symbol points of the collection a conversation is pinned to, read instead of the
+/// configured collection.
+pub struct GenerationSymbols<'a> {
+    app_state: &'a AppState,
+    collection: String,
+}
+
+impl<'a> GenerationSymbols<'a> {
+    pub fn new(app_state: &'a AppState, generation: Option<&IndexGeneration>) -> Self {
+        let collection = generation
+            .and_then(|generation| generation.collections.get(SYMBOL_COLLECTION_NAME).cloned())
+            .unwrap_or_else(get_symbol_collection_name);
+        Self {
+            app_state,
+            collection,
+        }
+    }
+}
+
+#[async_trait]
+impl SymbolScroller for GenerationSymbols<'_> {
+    async fn scroll_symbols(
+        &self,
+        filter: Filter,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> Result<ScrollPage> {
+        self.app_state
+            .db_connection
+            .semantic
+            .qdrant
+            .run(|client| {
+                let (filter, offset) = (filter.clone(), offset.clone());
+                let collection = self.collection.clone();
+                async move { scroll_collection(&client, collection, filter, offset, limit).await }
+            })
+            .await
+    }
+}
+
+/// Filter of an exact lookup, no vector search is involved.
+///
+/// Case-insensitive lookups match the lowercased name, symbols indexed before it was written
+/// are matched through the full text index of the name and verified by `occurrences`.
+pub fn exact_symbol_filter(
+    repo_name: &str,
+    branch: &str,
+    name: &str,
+    case_sensitive: bool,
+) -> Filter {
+    let repo_condition: Condition = make_kv_keyword_filter("repo_name", repo_name).into();
+    if case_sensitive {
+        return Filter {
+            must: vec![
+                repo_condition,
+                branch_condition(branch),
+                make_kv_keyword_filter("symbol", name).into(),
+            ],
+            ..Default::default()
+        };
+    }
+
+    Filter {
+        must: vec![repo_condition, branch_condition(branch)],
+        should: vec![
+            make_kv_keyword_filter(SYMBOL_LOWER_FIELD, &name.to_lowercase()).into(),
+            FieldCondition {
+                key: "symbol".to_string(),
+                r#match: Some(Match {
+                    match_value: MatchValue::Text(name.to_string()).into(),
+                }),
+                ..Default::default()
+            }
+            .into(),
+        ],
+        ..Default::default()
+    }
+}
+
+/// Flattens the locations of a symbol payload that match the name and, when set, the kind and
+/// the container. The kind matches either the node kind or the symbol type, ignoring case.
+pub fn occurrences(
+    payload: &SymbolPayload,
+    name: &str,
+    kind: Option<&str>,
+    container: Option<&str>,
+    case_sensitive: bool,
+) -> Vec<SymbolMatch> {
+    let name_matches = if case_sensitive {
+        payload.symbol == name
+    } else {
+        payload.symbol.to_lowercase() == name.to_lowercase()
+    };
+    if !name_matches {
+        return vec![];
+    }
+
+    payload
+        .occurrences
+        .iter()
+        .map(|occurrence| SymbolMatch::new(&payload.symbol, occurrence))
+        .filter(|occurrence| match kind {
+            Some(kind) => {
+                occurrence.node_kind.eq_ignore_ascii_case(kind)
+                    || occurrence.symbol_type.eq_ignore_ascii_case(kind)
+            }
+            None => true,
+        })
+        .filter(|occurrence| match container {
+            Some(container) => in_container(&occurrence.container_path, container, case_sensitive),
+            None => true,
+        })
+        .collect()
+}
+
+/// Whether the innermost definitions of a container path are `container`, e.g. `Config` and
+/// `PaymentService::Config` both match the path `["PaymentService", "Config"]`.
+fn in_container(container_path: &[String], container: &str, case_sensitive: bool) -> bool {
+    let expected = container
+        .split(CONTAINER_SEPARATOR)
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    if expected.is_empty() || expected.len() > container_path.len() {
+        return false;
+    }
+
+    container_path[container_path.len() - expected.len()..]
+        .iter()
+        .zip(expected)
+        .all(|(name, expected)| {
+            if case_sensitive {
+                name == expected
+            } else {
+                name.eq_ignore_ascii_case(expected)
+            }
+        })
+}
+
+/// Scrolls through every matching symbol point of the repo and collects all of its occurrences,
+/// sorted by path and position.
+pub async fn exact_symbol_lookup<S: SymbolScroller>(
+    scroller: &S,
+    repo_name: &str,
+    branch: &str,
+    name: &str,
+    kind: Option<&str>,
+    container: Option<&str>,
+    case_sensitive: bool,
+) -> Result<Vec<SymbolMatch>> {
+    let filter = exact_symbol_filter(repo_name, branch, name, case_sensitive);
+    let mut found = Vec::new();
+    let mut offset = None;
+
+    loop {
+        let page = scroller
+            .scroll_symbols(filter.clone(), offset, LOOKUP_PAGE_SIZE)
+            .await?;
+        for point in page.points {
+            let payload = match SymbolPayload::from_retrieved(point) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    continue;
+                }
+            };
+            found.extend(occurrences(&payload, name, kind, container, case_sensitive));
+        }
+        match page.next_offset {
+            Some(next) => offset = Some(next),
+            None => break,
+        }
+    }
+
+    found.sort_by(|a, b| {
+        a.path
+            .cmp(&b.path)
+            .then(a.start_byte.cmp(&b.start_byte))
+            .then(a.symbol.cmp(&b.symbol))
+    });
+    found.dedup();
+    Ok(found)
+}
+
+/// Converts the byte ranges into lines with the line end indices of each file.
+/// Occurrences in files without indices keep their lines unset.
+pub fn resolve_lines(
+    occurrences: &mut [SymbolMatch],
+    line_end_indices: &HashMap<String, Vec<usize>>,
+) {
+    for occurrence in occurrences.iter_mut() {
+        if let Some(indices) = line_end_indices.get(&occurrence.path) {
+            if indices.is_empty() {
+                continue;
+            }
+            occurrence.start_line = Some(get_line_number(occurrence.start_byte, indices));
+            occurrence.end_line = Some(get_line_number(occurrence.end_byte, indices));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::{point_id::PointIdOptions, RetrievedPoint, Value};
+    use std::sync::Mutex;
+
+    fn symbol_point(id: &str, symbol: &str, paths: &[&str], node_kind: &str) -> RetrievedPoint {
+        let repeated = |value: Value| Value::from(vec![value; paths.len()]);
+        RetrievedPoint {
+            id: Some(PointId {
+                point_id_options: Some(PointIdOptions::Uuid(id.to_string())),
+            }),
+            payload: HashMap::from([
+                ("repo_name".to_string(), Value::from("repo")),
+                ("symbol".to_string(), Value::from(symbol)),
+                ("symbol_type".to_string(), repeated(Value::from("function"))),
+                ("lang".to_string(), repeated(Value::from("rust"))),
+                ("is_global".to_string(), repeated(Value::from(true))),
+                ("start_byte".to_string(), repeated(Value::from(12_i64))),
+                ("end_byte".to_string(), repeated(Value::from(27_i64))),
+                (
+                    "relative_path".to_string(),
+                    Value::from(paths.iter().map(|p| Value::from(*p)).collect::<Vec<_>>()),
+                ),
+                ("node_kind".to_string(), repeated(Value::from(node_kind))),
+            ]),
+            vectors: None,
+            ..Default::default()
+        }
+    }
+
+    // Serves the fixture points two per page, the same way qdrant would after filtering loosely.
+    struct MockScroller {
+        points: Vec<RetrievedPoint>,
+        requests: Mutex<Vec<Filter>>,
+    }
+
+    #[async_trait]
+    impl SymbolScroller for MockScroller {
+        async fn scroll_symbols(
+            &self,
+            filter: Filter,
+            offset: Option<PointId>,
+            _limit: u32,
+        ) -> Result<ScrollPage> {
+            self.requests.lock().unwrap().push(filter);
+            let start = match offset.and_then(|id| id.point_id_options) {
+                Some(PointIdOptions::Num(start)) => start as usize,
+                _ => 0,
+            };
+            let end = (start + 2).min(self.points.len());
+            Ok(ScrollPage {
+                points: self.points[start..end].to_vec(),
+                next_offset: (end < self.points.len()).then(|| PointId {
+                    point_id_options: Some(PointIdOptions::Num(end as u64)),
+                }),
+            })
+        }
+    }
+
+    fn fixture_scroller() -> MockScroller {
+        MockScroller {
+            points: vec![
+                symbol_point(
+                    "6a1f0c1e-0000-4000-8000-000000000001",
+                    "process_entries",
+                    &["src/index.rs", "src/watch.rs"],
+                    "function_item",
+                ),
+                symbol_point(
+                    "6a1f0c1e-0000-4000-8000-000000000002",
+                    "Process_Entries",
+                    &["src/legacy.rs"],
+                    "function_item",
+                ),
+                symbol_point(
+                    "6a1f0c1e-0000-4000-8000-000000000003",
+                    "PROCESS_ENTRIES",
+                    &["src/consts.rs"],
+                    "const_item",
+                ),
+                symbol_point(
+                    "6a1f0c1e-0000-4000-8000-000000000004",
+                    "process_entries_batch",
+                    &["src/batch.rs"],
+                    "function_item",
+                ),
+            ],
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn paths(found: &[SymbolMatch]) -> Vec<&str> {
+        found.iter().map(|o| o.path.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_case_sensitive_lookup() {
+        let scroller = fixture_scroller();
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "process_entries", None, None, true)
+            .await
+            .unwrap();
+
+        assert_eq!(paths(&found), vec!["src/index.rs", "src/watch.rs"]);
+        assert!(found.iter().all(|o| o.symbol == "process_entries" && o.is_global));
+        assert_eq!(found[0].node_kind, "function_item");
+        assert_eq!((found[0].start_byte, found[0].end_byte), (12, 27));
+        // every page was requested with the exact filter.
+        let requests = scroller.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|filter| filter.must.len() == 3 && filter.should.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_lookup() {
+        let scroller = fixture_scroller();
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "Process_entries", None, None, false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            paths(&found),
+            vec!["src/consts.rs", "src/index.rs", "src/legacy.rs", "src/watch.rs"]
+        );
+        assert!(!found.iter().any(|o| o.symbol == "process_entries_batch"));
+
+        let requests = scroller.requests.lock().unwrap();
+        assert_eq!(requests[0].should.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_filters_by_kind() {
+        let scroller = fixture_scroller();
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "process_entries", Some("CONST_ITEM"), None, false)
+            .await
+            .unwrap();
+
+        assert_eq!(paths(&found), vec!["src/consts.rs"]);
+        assert_eq!(found[0].symbol, "PROCESS_ENTRIES");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_filters_by_container() {
+        let mut method = symbol_point(
+            "6a1f0c1e-0000-4000-8000-000000000005",
+            "retry",
+            &["payments/service.py", "payments/client.py", "payments/jobs.py"],
+            "def",
+        );
+        method.payload.insert(
+            "container_path".to_string(),
+            Value::from(vec![
+                Value::from("PaymentService"),
+                Value::from("PaymentClient::Config"),
+                Value::from(""),
+            ]),
+        );
+        let scroller = MockScroller {
+            points: vec![method],
+            requests: Mutex::new(Vec::new()),
+        };
+
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "retry", None, Some("PaymentService"), true)
+            .await
+            .unwrap();
+        assert_eq!(paths(&found), vec!["payments/service.py"]);
+        assert_eq!(found[0].container_path, vec!["PaymentService".to_string()]);
+
+        // the innermost definitions are matched, a partial path is enough.
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "retry", None, Some("config"), false)
+            .await
+            .unwrap();
+        assert_eq!(paths(&found), vec!["payments/client.py"]);
+        assert_eq!(found[0].container_path, vec!["PaymentClient", "Config"]);
+
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "retry", None, Some("PaymentClient"), true)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_skips_points_with_misaligned_columns() {
+        let mut corrupted = symbol_point(
+            "6a1f0c1e-0000-4000-8000-000000000006",
+            "process_entries",
+            &["src/queue.rs", "src/worker.rs"],
+            "function_item",
+        );
+        corrupted
+            .payload
+            .insert("start_byte".to_string(), Value::from(vec![Value::from(12_i64)]));
+        assert!(SymbolPayload::from_retrieved(corrupted.clone()).is_err());
+
+        let mut scroller = fixture_scroller();
+        scroller.points.push(corrupted);
+        let found = exact_symbol_lookup(&scroller, "repo", "main", "process_entries", None, None, true)
+            .await
+            .unwrap();
+        assert_eq!(paths(&found), vec!["src/index.rs", "src/watch.rs"]);
+    }
+
+    #[test]
+    fn test_resolve_lines() {
+        let payload = SymbolPayload::from_retrieved(symbol_point(
+            "6a1f0c1e-0000-4000-8000-000000000001",
+            "process_entries",
+            &["src/index.rs", "src/watch.rs"],
+            "function_item",
+        ))
+        .unwrap();
+        let mut found = occurrences(&payload, "process_entries", None, None, true);
+        let line_end_indices = HashMap::from([("src/index.rs".to_string(), vec![9, 19, 29])]);
+
+        resolve_lines(&mut found, &line_end_indices);
+
+        assert_eq!((found[0].start_line, found[0].end_line), (Some(1), Some(2)));
+        assert_eq!((found[1].start_line, found[1].end_line), (None, None));
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+
+use common::terminology::{Terminology, EXPANSION_KEYWORD_WEIGHT, TERMINOLOGY_COLLECTION_NAME};
+use qdrant_client::qdrant::{
+    vectors_config, with_payload_selector, CreateCollection, Distance, Filter, PointId,
+    PointStruct, RetrievedPoint, ScrollPoints, Value, VectorParams, VectorsConfig,
+    WithPayloadSelector,
+};
+
+use crate::search::{
+    hybrid::rank_scores, payload::kind_to_value, semantic::make_kv_keyword_filter,
+};
+
+/// The collection of the dictionaries. They are only read by repo, the points have a placeholder
+/// vector of one dimension.
+pub fn terminology_collection() -> CreateCollection {
+    CreateCollection {
+        collection_name: TERMINOLOGY_COLLECTION_NAME.to_string(),
+        vectors_config: Some(VectorsConfig {
+            config: Some(vectors_config::Config::Params(VectorParams {
+                size: 1,
+                distance: Distance::Dot.into(),
+                ..Default::default()
+            })),
+        }),
+        ..Default::default()
+    }
+}
+
+/// The dictionary of a repo as a point, the id is derived from the repo so uploading a dictionary
+/// replaces the previous one.
+pub fn terminology_point(
+    repo_name: &str,
+    terminology: &Terminology,
+) -> anyhow::Result<PointStruct> {
+    let digest = md5::compute(format!("terminology\n{}", repo_name));
+    let id = u64::from_be_bytes(digest.0[..8].try_into().unwrap());
+    let payload: HashMap<String, Value> = HashMap::from([
+        ("repo_name".to_string(), repo_name.into()),
+        (
+            "terms".to_string(),
+            serde_json::to_string(&terminology.terms)?.into(),
+        ),
+    ]);
+    Ok(PointStruct {
+        id: Some(PointId::from(id)),
+        vectors: Some(vec![1.0_f32].into()),
+        payload,
+    })
+}
+
+/// Scroll of the point of the dictionary of a repo.
+pub fn terminology_request(repo_name: &str) -> ScrollPoints {
+    ScrollPoints {
+        collection_name: TERMINOLOGY_COLLECTION_NAME.to_string(),
+        filter: Some(Filter {
+            must: vec![make_kv_keyword_filter("repo_name", repo_name).into()],
+            ..Default::default()
+        }),
+        limit: Some(1),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        ..Default::default()
+    }
+}
+
+/// Parses the point of a dictionary, None when its payload misses the terms.
+pub fn parse_terminology(point: RetrievedPoint) -> Option<Terminology> {
+    let terms = match kind_to_value(point.payload.get("terms")?.kind.clone()) {
+        serde_json::Value::String(terms) => terms,
+        _ => return None,
+    };
+    Some(Terminology {
+        terms: serde_json::from_str(&terms).ok()?,
+    })
+}
+
+/// Keyword scores of the hits, in the order quickwit ranked them. A hit whose content mentions
+/// none of the terms of the query itself was only found through an expansion, its score is
+/// multiplied by `EXPANSION_KEYWORD_WEIGHT`. Returns the paths of these hits along with the scores.
+pub fn weigh_expansion_hits(
+    hits: &[(String, &str)],
+    terms: &[String],
+) -> (Vec<(String, f32)>, HashSet<String>) {
+    let terms = terms
+        .iter()
+        .map(|term| term.to_lowercase())
+        .collect::<Vec<_>>();
+    let mut expansion_only = HashSet::new();
+    let scores = hits
+        .iter()
+        .zip(rank_scores(hits.len()))
+        .map(|((path, content), score)| {
+            let content = content.to_lowercase();
+            if terms.iter().any(|term| content.contains(term.as_str())) {
+                (path.clone(), score)
+            } else {
+                expansion_only.insert(path.clone());
+                (path.clone(), score * EXPANSION_KEYWORD_WEIGHT)
+            }
+        })
+        .collect();
+    (scores, expansion_only)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use common::terminology::expanded_text;
+
+    use super::*;
+    use crate::search::hybrid::build_keyword_query;
+
+    // the files of the fixture repo, the billing engine is called helios by its team only.
+    const FILES: [(&str, &str); 3] = [
+        (
+            "billing/engine.rs",
+            "// The billing engine issues the invoices and retries the failed charges.\nfn retry_charge() {}",
+        ),
+        (
+            "docs/onboarding.md",
+            "Ask the payments team about helios before changing the invoices.",
+        ),
+        ("api/orders.rs", "fn create_order() {}"),
+    ];
+
+    // quickwit stand-in: the files mentioning any word of the query, in fixture order.
+    fn keyword_search(query: &str) -> Vec<(String, &'static str)> {
+        let words = query
+            .split(|c: char| !c.is_alphanumeric() && c != ' ')
+            .flat_map(|clause| clause.split(' '))
+            .filter(|word| !word.is_empty() && !["OR", "AND", "content", "symbols"].contains(word))
+            .map(str::to_lowercase)
+            .collect::<HashSet<_>>();
+        FILES
+            .iter()
+            .filter(|(_, content)| {
+                let content = content.to_lowercase();
+                words.iter().any(|word| content.contains(word.as_str()))
+            })
+            .map(|(path, content)| (path.to_string(), *content))
+            .collect()
+    }
+
+    #[test]
+    fn test_expansion_finds_the_code_behind_the_jargon() {
+        let terminology = Terminology {
+            terms: BTreeMap::from([("helios".to_string(), vec!["billing engine".to_string()])]),
+        };
+        let query = "Where does helios live?";
+        let terms = vec!["helios".to_string()];
+
+        let plain = keyword_search(&build_keyword_query(&terms).unwrap());
+        assert_eq!(
+            plain
+                .iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["docs/onboarding.md"]
+        );
+
+        let expansions = terminology.expand(query);
+        assert_eq!(
+            expanded_text(query, &expansions),
+            "Where does helios live? billing engine"
+        );
+        let expanded_terms = terms
+            .iter()
+            .cloned()
+            .chain(
+                expansions
+                    .iter()
+                    .flat_map(|expansion| expansion.expansions.clone()),
+            )
+            .collect::<Vec<_>>();
+        let expanded = keyword_search(&build_keyword_query(&expanded_terms).unwrap());
+        let hits = expanded
+            .iter()
+            .map(|(path, content)| (path.clone(), *content))
+            .collect::<Vec<_>>();
+        let (scores, expansion_only) = weigh_expansion_hits(&hits, &terms);
+
+        // the engine is found, its score halved as only the expansion matched it.
+        assert_eq!(
+            scores,
+            vec![
+                ("billing/engine.rs".to_string(), 0.5),
+                ("docs/onboarding.md".to_string(), 0.5),
+            ]
+        );
+        assert_eq!(
+            expansion_only,
+            HashSet::from(["billing/engine.rs".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_dictionary_round_trips_through_its_point() {
+        let terminology = Terminology {
+            terms: BTreeMap::from([("WF".to_string(), vec!["workflow".to_string()])]),
+        };
+        let point = terminology_point("repo", &terminology).unwrap();
+        assert_eq!(
+            point.id,
+            terminology_point("repo", &Terminology::default())
+                .unwrap()
+                .id
+        );
+        assert_ne!(
+            point.id,
+            terminology_point("other", &terminology).unwrap().id
+        );
+
+        let retrieved = RetrievedPoint {
+            id: point.id,
+            payload: point.payload,
+            ..Default::default()
+        };
+        assert_eq!(parse_terminology(retrieved), Some(terminology));
+    }
+}
@@ -0,0 +1,619 @@
+// Definitions of a token for the files the scope graph can't resolve, e.g. languages with weak
+// scope queries or files indexed with `SymbolLocations::Empty`. The scope graph is tried first,
+// then the exact symbol lookup, a text search for the usual definition patterns and last the
+// trigram search of the token text, every result is labeled with the method that found it.
+
+use std::ops::Range;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use common::ast::{
+    graph_code_pluck::ContentDocument,
+    language_support::{Language, TSLanguage},
+    text_range::TextRange,
+};
+use common::branch::quickwit_branch_query;
+use common::hasher::generate_quikwit_index_name;
+use serde::Serialize;
+
+use crate::code_navigation::{
+    to_occurrence, CodeNavigationContext, FileSymbols, Occurrence, OccurrenceKind,
+};
+use crate::config::AppState;
+use crate::controller::navigator::search_nav;
+use crate::search::quikwit::search_quickwit;
+use crate::search::symbol_lookup::{exact_symbol_lookup, SymbolScroller};
+
+const TYPESCRIPT_KEYWORDS: &[&str] = &[
+    "function",
+    "class",
+    "interface",
+    "type",
+    "enum",
+    "const",
+    "let",
+];
+const JAVASCRIPT_KEYWORDS: &[&str] = &["function", "class", "const", "let"];
+
+/// Keywords introducing a definition, per language id, searched as `<keyword> <name>`.
+pub const DEFINITION_KEYWORDS: &[(&str, &[&str])] = &[
+    ("Rust", &["fn", "struct", "enum", "trait", "type", "mod"]),
+    ("Python", &["def", "class"]),
+    ("Go", &["func", "type"]),
+    ("TypeScript", TYPESCRIPT_KEYWORDS),
+    ("TSX", TYPESCRIPT_KEYWORDS),
+    ("JavaScript", JAVASCRIPT_KEYWORDS),
+    ("JSX", JAVASCRIPT_KEYWORDS),
+    ("Java", &["class", "interface", "enum"]),
+    ("C#", &["class", "interface", "enum", "struct"]),
+    ("Ruby", &["def", "class", "module"]),
+    ("PHP", &["function", "class", "interface", "trait"]),
+    ("C", &["struct", "enum", "union"]),
+    ("C++", &["class", "struct", "enum", "union", "namespace"]),
+];
+
+/// How the occurrences of a token were found, from the most to the least certain.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenResolution {
+    #[default]
+    ScopeGraph,
+    // a symbol of the same name in the symbols collection of the repo.
+    SymbolIndex,
+    // a definition pattern with the name in the text of a file.
+    TextSearch,
+}
+
+/// Text search of the files of a repo, implemented with quickwit and mocked in tests.
+#[async_trait]
+pub trait DefinitionSearch: Send + Sync {
+    async fn search_definitions(&self, repo: &str, query: &str) -> Result<Vec<ContentDocument>>;
+}
+
+#[async_trait]
+impl DefinitionSearch for AppState {
+    async fn search_definitions(&self, repo: &str, query: &str) -> Result<Vec<ContentDocument>> {
+        search_quickwit(&generate_quikwit_index_name(repo), query).await
+    }
+}
+
+/// The occurrences of the token in `ctx`, with the definitions found by the fallbacks when the
+/// scope graph doesn't resolve one. Results are ordered by the certainty of their method.
+pub async fn resolve_token<B: SymbolScroller + DefinitionSearch>(
+    backend: &B,
+    ctx: &CodeNavigationContext<'_, '_>,
+) -> Result<Vec<FileSymbols>> {
+    let mut resolved = ctx.token_info();
+    // the token is a definition itself, the scope graph found its references.
+    if ctx.is_definition() || resolved.iter().any(has_definition) {
+        return Ok(resolved);
+    }
+    let source = &ctx.all_docs[ctx.source_document_idx];
+    let Some((name, range)) = token_identifier(&source.content, ctx.active_token_range()) else {
+        return Ok(resolved);
+    };
+    let token = TokenLocation {
+        path: &source.relative_path,
+        range,
+    };
+
+    let mut fallback = symbol_index_definitions(backend, ctx, name, &token).await?;
+    if fallback.is_empty() {
+        let langs = associated_langs(source);
+        let query = quickwit_branch_query(&definition_query(name, langs), &ctx.token.branch);
+        let docs = backend.search_definitions(&ctx.token.repo, &query).await?;
+        let keywords = definition_keywords(langs);
+        fallback = text_search_definitions(&ctx.token.repo, name, &keywords, &docs, &token, ctx);
+    }
+    if fallback.is_empty() {
+        fallback = search_nav(
+            ctx.token.repo.clone(),
+            name,
+            token.range.clone(),
+            Some(&ctx.token.branch),
+            source,
+            ctx.snipper,
+            backend,
+        )
+        .await?;
+    }
+    log::debug!(
+        "Resolved {} of {} to {} files without the scope graph",
+        name,
+        source.relative_path,
+        fallback.len()
+    );
+    resolved.extend(fallback);
+    resolved.sort_by_key(|file| file.method);
+    Ok(resolved)
+}
+
+// where the token itself is, so that it isn't returned as its own definition.
+struct TokenLocation<'a> {
+    path: &'a str,
+    range: Range<usize>,
+}
+
+impl TokenLocation<'_> {
+    fn overlaps(&self, path: &str, range: &Range<usize>) -> bool {
+        self.path == path && range.start < self.range.end && self.range.start < range.end
+    }
+}
+
+fn has_definition(file: &FileSymbols) -> bool {
+    file.data.iter().any(Occurrence::is_definition)
+}
+
+fn associated_langs(doc: &ContentDocument) -> &'static [&'static str] {
+    match doc.lang.as_deref().map(TSLanguage::from_id) {
+        Some(Language::Supported(config)) => config.language_ids,
+        _ => &[],
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// The identifier in or around the requested byte range, with its own range. The range of a
+/// client is often a bit off, e.g. it includes the `(` after a function name.
+pub fn token_identifier(content: &str, range: Range<usize>) -> Option<(&str, Range<usize>)> {
+    let start = range.start.min(content.len());
+    let end = range.end.clamp(start, content.len());
+    if !content.is_char_boundary(start) || !content.is_char_boundary(end) {
+        return None;
+    }
+
+    // the first identifier of the range, or the one the range sits in.
+    let offset = content[start..end]
+        .find(is_identifier_char)
+        .map(|i| start + i)
+        .unwrap_or(start);
+    let begin = content[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_identifier_char(*c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(offset);
+    let finish = content[offset..]
+        .char_indices()
+        .find(|(_, c)| !is_identifier_char(*c))
+        .map(|(i, _)| offset + i)
+        .unwrap_or(content.len());
+
+    let name = &content[begin..finish];
+    let valid = matches!(name.chars().next(), Some(c) if !c.is_ascii_digit());
+    valid.then_some((name, begin..finish))
+}
+
+async fn symbol_index_definitions<S: SymbolScroller>(
+    scroller: &S,
+    ctx: &CodeNavigationContext<'_, '_>,
+    name: &str,
+    token: &TokenLocation<'_>,
+) -> Result<Vec<FileSymbols>> {
+    let found = exact_symbol_lookup(
+        scroller,
+        &ctx.token.repo,
+        &ctx.token.branch,
+        name,
+        None,
+        None,
+        true,
+    )
+    .await?;
+    let mut files: Vec<FileSymbols> = Vec::new();
+    for occurrence in found {
+        let range = occurrence.start_byte..occurrence.end_byte;
+        if token.overlaps(&occurrence.path, &range) {
+            continue;
+        }
+        // the snippet and the lines come from the indexed file.
+        let Some(doc) = ctx
+            .all_docs
+            .iter()
+            .find(|doc| doc.relative_path == occurrence.path)
+        else {
+            continue;
+        };
+        push_definition(
+            &mut files,
+            &ctx.token.repo,
+            doc,
+            range,
+            TokenResolution::SymbolIndex,
+            ctx,
+        );
+    }
+    Ok(files)
+}
+
+/// The definition keywords of the languages `langs`, the ones of every language when none of them
+/// has keywords, e.g. the file of the token has no known language.
+pub fn definition_keywords(langs: &[&str]) -> Vec<&'static str> {
+    let known = DEFINITION_KEYWORDS
+        .iter()
+        .any(|(lang, _)| langs.contains(lang));
+    let mut keywords = Vec::new();
+    for (lang, lang_keywords) in DEFINITION_KEYWORDS {
+        if known && !langs.contains(lang) {
+            continue;
+        }
+        for keyword in lang_keywords.iter() {
+            if !keywords.contains(keyword) {
+                keywords.push(*keyword);
+            }
+        }
+    }
+    keywords
+}
+
+/// Quickwit query of the files with a definition of `name`, in the languages of the file of the
+/// token. The index only holds the files of the repo, they aren't filtered by repo.
+pub fn definition_query(name: &str, langs: &[&str]) -> String {
+    let patterns = definition_keywords(langs)
+        .iter()
+        .map(|keyword| format!("content:\"{} {}\"", keyword, name))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let mut parts = vec![format!("({})", patterns)];
+    if !langs.is_empty() {
+        let langs = langs
+            .iter()
+            .map(|lang| format!("lang:{}", lang))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        parts.push(format!("({})", langs));
+    }
+    format!("({})", parts.join(" AND "))
+}
+
+// the search only narrows the files down, the definitions are the matches of the patterns.
+fn text_search_definitions(
+    repo: &str,
+    name: &str,
+    keywords: &[&str],
+    docs: &[ContentDocument],
+    token: &TokenLocation<'_>,
+    ctx: &CodeNavigationContext<'_, '_>,
+) -> Vec<FileSymbols> {
+    let pattern = regex::Regex::new(&format!(
+        r"\b(?:{})\s+({})\b",
+        keywords.join("|"),
+        regex::escape(name)
+    ))
+    .expect("the definition pattern is a valid regex");
+
+    let mut files = Vec::new();
+    for doc in docs {
+        for captures in pattern.captures_iter(&doc.content) {
+            let range = captures.get(1).expect("the name is captured").range();
+            if !token.overlaps(&doc.relative_path, &range) {
+                push_definition(
+                    &mut files,
+                    repo,
+                    doc,
+                    range,
+                    TokenResolution::TextSearch,
+                    ctx,
+                );
+            }
+        }
+    }
+    files
+}
+
+fn push_definition(
+    files: &mut Vec<FileSymbols>,
+    repo: &str,
+    doc: &ContentDocument,
+    range: Range<usize>,
+    method: TokenResolution,
+    ctx: &CodeNavigationContext<'_, '_>,
+) {
+    let line_end_indices = doc
+        .fetch_line_indices()
+        .into_iter()
+        .map(|index| index as u32)
+        .collect::<Vec<_>>();
+    let range = TextRange::from_byte_range(range, &line_end_indices);
+    let occurrence = Occurrence {
+        kind: OccurrenceKind::Definition,
+        range,
+        snippet: to_occurrence(doc, range, ctx.snipper),
+    };
+    match files.iter_mut().find(|file| file.file == doc.relative_path) {
+        Some(file) => file.data.push(occurrence),
+        None => files.push(FileSymbols {
+            file: doc.relative_path.clone(),
+            repo: repo.to_string(),
+            data: vec![occurrence],
+            method,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::export::ScrollPage;
+    use common::ast::{symbol::SymbolLocations, CodeFileAST};
+    use qdrant_client::qdrant::{Filter, PointId, RetrievedPoint, Value};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // symbols collection and text search with fixed results, recording the text queries.
+    #[derive(Default)]
+    struct MockBackend {
+        symbols: Vec<RetrievedPoint>,
+        text_hits: Vec<ContentDocument>,
+        queries: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl SymbolScroller for MockBackend {
+        async fn scroll_symbols(
+            &self,
+            _filter: Filter,
+            _offset: Option<PointId>,
+            _limit: u32,
+        ) -> Result<ScrollPage> {
+            Ok(ScrollPage {
+                points: self.symbols.clone(),
+                next_offset: None,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl DefinitionSearch for MockBackend {
+        async fn search_definitions(
+            &self,
+            _repo: &str,
+            query: &str,
+        ) -> Result<Vec<ContentDocument>> {
+            self.queries.lock().unwrap().push(query.to_string());
+            Ok(self.text_hits.clone())
+        }
+    }
+
+    // a file with its scope graph, or `SymbolLocations::Empty` without a language.
+    fn document(path: &str, lang: Option<&str>, content: &str) -> ContentDocument {
+        let symbol_locations = match lang {
+            Some(lang) => CodeFileAST::build_ast(content.as_bytes(), lang)
+                .and_then(CodeFileAST::scope_graph)
+                .map(SymbolLocations::TreeSitter)
+                .unwrap_or_default(),
+            None => SymbolLocations::Empty,
+        };
+        let line_end_indices = content
+            .match_indices('\n')
+            .flat_map(|(i, _)| (i as u32).to_le_bytes())
+            .collect();
+        ContentDocument {
+            repo_name: "repo".to_string(),
+            repo_ref: "repo".to_string(),
+            relative_path: path.to_string(),
+            lang: lang.map(str::to_string),
+            line_end_indices,
+            content: content.to_string(),
+            symbol_locations: bincode::serialize(&symbol_locations).unwrap(),
+            symbols: String::new(),
+        }
+    }
+
+    fn symbol_point(symbol: &str, path: &str, range: Range<usize>) -> RetrievedPoint {
+        RetrievedPoint {
+            payload: HashMap::from([
+                ("repo_name".to_string(), Value::from("repo")),
+                ("symbol".to_string(), Value::from(symbol)),
+                (
+                    "symbol_type".to_string(),
+                    Value::from(vec![Value::from("function")]),
+                ),
+                ("lang".to_string(), Value::from(vec![Value::from("rust")])),
+                (
+                    "is_global".to_string(),
+                    Value::from(vec![Value::from(true)]),
+                ),
+                (
+                    "start_byte".to_string(),
+                    Value::from(vec![Value::from(range.start as i64)]),
+                ),
+                (
+                    "end_byte".to_string(),
+                    Value::from(vec![Value::from(range.end as i64)]),
+                ),
+                (
+                    "relative_path".to_string(),
+                    Value::from(vec![Value::from(path)]),
+                ),
+                (
+                    "node_kind".to_string(),
+                    Value::from(vec![Value::from("function_item")]),
+                ),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn context<'a>(
+        docs: &'a [ContentDocument],
+        path: &'a str,
+        token: &str,
+    ) -> CodeNavigationContext<'a, 'a> {
+        let source_document_idx = docs
+            .iter()
+            .position(|doc| doc.relative_path == path)
+            .unwrap();
+        let start = docs[source_document_idx].content.rfind(token).unwrap();
+        CodeNavigationContext {
+            token: crate::code_navigation::Token {
+                repo: "repo".to_string(),
+                branch: "main".to_string(),
+                relative_path: path,
+                start_byte: start,
+                end_byte: start + token.len(),
+            },
+            all_docs: docs,
+            source_document_idx,
+            snipper: None,
+        }
+    }
+
+    fn definitions(resolved: &[FileSymbols]) -> Vec<(&str, TokenResolution, usize)> {
+        resolved
+            .iter()
+            .flat_map(|file| {
+                file.data
+                    .iter()
+                    .filter(|o| o.is_definition())
+                    .map(move |o| (file.file.as_str(), file.method, o.range.start.byte))
+            })
+            .collect()
+    }
+
+    const CALLER: &str = "fn main() {\n    refresh_token();\n}\n";
+
+    #[tokio::test]
+    async fn test_scope_graph_resolves_local_definitions() {
+        let source = "fn refresh_token() {}\n\nfn main() {\n    refresh_token();\n}\n";
+        let docs = vec![document("src/main.rs", Some("Rust"), source)];
+        let backend = MockBackend::default();
+
+        let resolved = resolve_token(&backend, &context(&docs, "src/main.rs", "refresh_token"))
+            .await
+            .unwrap();
+        assert_eq!(
+            definitions(&resolved),
+            vec![("src/main.rs", TokenResolution::ScopeGraph, 3)]
+        );
+        // the fallbacks aren't needed.
+        assert!(backend.queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_symbol_index_resolves_definitions_of_files_without_scope_graph() {
+        let definition = "pub fn refresh_token() {}\n";
+        let docs = vec![
+            document("src/main.rs", None, CALLER),
+            document("src/auth.rs", None, definition),
+        ];
+        let backend = MockBackend {
+            symbols: vec![
+                symbol_point("refresh_token", "src/auth.rs", 7..20),
+                // the token itself isn't its own definition.
+                symbol_point("refresh_token", "src/main.rs", 16..29),
+            ],
+            ..Default::default()
+        };
+
+        let resolved = resolve_token(&backend, &context(&docs, "src/main.rs", "refresh_token();"))
+            .await
+            .unwrap();
+        assert_eq!(
+            definitions(&resolved),
+            vec![("src/auth.rs", TokenResolution::SymbolIndex, 7)]
+        );
+        assert!(backend.queries.lock().unwrap().is_empty());
+        let json = serde_json::to_value(&resolved[0].method).unwrap();
+        assert_eq!(json, "symbol_index");
+    }
+
+    #[tokio::test]
+    async fn test_text_search_is_the_last_resort() {
+        let docs = vec![document("src/main.rs", None, CALLER)];
+        let backend = MockBackend {
+            text_hits: vec![document(
+                "src/auth.py",
+                None,
+                "# calls refresh_token\ndef refresh_token(session):\n    pass\n",
+            )],
+            ..Default::default()
+        };
+
+        let resolved = resolve_token(&backend, &context(&docs, "src/main.rs", "refresh_token"))
+            .await
+            .unwrap();
+        assert_eq!(
+            definitions(&resolved),
+            vec![("src/auth.py", TokenResolution::TextSearch, 26)]
+        );
+        assert_eq!(resolved[0].data[0].range.start.line, 1);
+        assert_eq!(
+            backend.queries.lock().unwrap().as_slice(),
+            [quickwit_branch_query(&definition_query("refresh_token", &[]), "main")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trigram_search_follows_the_definition_patterns() {
+        let docs = vec![document("src/main.rs", None, CALLER)];
+        let backend = MockBackend {
+            // a parameter, no definition keyword introduces it.
+            text_hits: vec![document(
+                "src/other.rs",
+                Some("Rust"),
+                "fn other(refresh_token: u32) {\n    refresh_token;\n}\n",
+            )],
+            ..Default::default()
+        };
+
+        let resolved = resolve_token(&backend, &context(&docs, "src/main.rs", "refresh_token"))
+            .await
+            .unwrap();
+        assert_eq!(
+            definitions(&resolved),
+            vec![("src/other.rs", TokenResolution::TextSearch, 9)]
+        );
+        assert_eq!(backend.queries.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_found_without_a_definition_anywhere() {
+        let docs = vec![document("src/main.rs", None, CALLER)];
+        let backend = MockBackend::default();
+        let resolved = resolve_token(&backend, &context(&docs, "src/main.rs", "refresh_token"))
+            .await
+            .unwrap();
+        assert!(resolved.is_empty());
+    }
+
+    #[test]
+    fn test_definition_query_is_filtered_by_language() {
+        assert_eq!(
+            definition_query("refresh", &["Python"]),
+            "((content:\"def refresh\" OR content:\"class refresh\") AND (lang:Python))"
+        );
+    }
+
+    #[test]
+    fn test_definition_keywords_are_the_ones_of_the_language() {
+        assert_eq!(definition_keywords(&["Go"]), vec!["func", "type"]);
+        assert_eq!(
+            definition_keywords(&["TypeScript", "TSX"]),
+            TYPESCRIPT_KEYWORDS
+        );
+        assert!(definition_keywords(&["Rust"]).contains(&"struct"));
+        // no keywords for R, every language's are searched.
+        let all = definition_keywords(&["R"]);
+        assert_eq!(all, definition_keywords(&[]));
+        assert!(all.contains(&"fn") && all.contains(&"func") && all.contains(&"const"));
+    }
+
+    #[test]
+    fn test_token_identifier_snaps_to_the_name() {
+        let content = "let x = refresh_token(session);";
+        assert_eq!(
+            token_identifier(content, 8..22),
+            Some(("refresh_token", 8..21))
+        );
+        assert_eq!(
+            token_identifier(content, 10..12),
+            Some(("refresh_token", 8..21))
+        );
+        assert_eq!(token_identifier(content, 6..7), None);
+        assert_eq!(token_identifier(content, 100..120), None);
+    }
+}
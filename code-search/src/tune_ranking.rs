@@ -0,0 +1,526 @@
+// The offline job tuning the ranking from the retrieval feedback log, see
+// `common::retrieval_feedback`. `code-search tune-ranking --log <log>` reads every record of the
+// log, reports how often the chunks of each rank and score were cited per repo, and writes the
+// tuned weights to the ranking config, which the running services pick up without a restart:
+// - the weight of a symbol type follows how much more, or less, often its chunks are cited than
+//   the chunks of the other types;
+// - the keyword weight of a kind of query moves halfway from its default to the share of the
+//   citations going to the keyword hits rather than the vector ones;
+// - the recency half-life shortens when the top chunks of the queries about the current
+//   behavior, the ones the boost applies to, are cited more often than the top chunks of the
+//   other queries, and lengthens when they are cited less.
+// The weights are tuned from the defaults, the job gives the same weights when run again on the
+// same log. Weights with fewer than `--min-samples` chunks behind them keep their current value.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use common::models::RetrievalSource;
+use common::retrieval_feedback::{FeedbackLog, RetrievalFeedback};
+use serde::Serialize;
+
+use crate::config::DEFAULT_RECENCY_HALF_LIFE_DAYS;
+use crate::search::hybrid::{asks_current_behavior, classify_query, QueryKind};
+use crate::search::ranking_config::RankingConfig;
+
+// ranks from this one on are counted together.
+const RANK_BUCKETS: usize = 10;
+const CALIBRATION_BINS: usize = 10;
+// chunks of the best ranks compared by the recency tuning.
+const TOP_RANKS: usize = 3;
+const DEFAULT_MIN_SAMPLES: usize = 20;
+// bounds of the change of a symbol weight or of the recency half-life.
+const MIN_FACTOR: f32 = 0.5;
+const MAX_FACTOR: f32 = 2.0;
+const MIN_KEYWORD_WEIGHT: f32 = 0.1;
+const MAX_KEYWORD_WEIGHT: f32 = 0.9;
+
+/// Retrieved chunks and how many of them were cited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Rate {
+    pub retrieved: usize,
+    pub cited: usize,
+}
+
+impl Rate {
+    fn add(&mut self, cited: bool) {
+        self.retrieved += 1;
+        self.cited += cited as usize;
+    }
+
+    pub fn rate(&self) -> Option<f32> {
+        (self.retrieved > 0).then(|| self.cited as f32 / self.retrieved as f32)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RankRate {
+    // the last bucket counts this rank and the ones after it.
+    pub rank: usize,
+    #[serde(flatten)]
+    pub rate: Rate,
+}
+
+/// The chunks scored within `[min_score, max_score)`, a calibrated score is close to the rate.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CalibrationBin {
+    pub min_score: f32,
+    pub max_score: f32,
+    #[serde(flatten)]
+    pub rate: Rate,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RepoStats {
+    pub answers: usize,
+    pub citation_rate_by_rank: Vec<RankRate>,
+    pub calibration: Vec<CalibrationBin>,
+}
+
+/// Citations of the hits of the keyword and vector searches, the hits both found count for both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct SourceRates {
+    pub keyword: Rate,
+    pub vector: Rate,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FeedbackStats {
+    pub answers: usize,
+    pub repos: BTreeMap<String, RepoStats>,
+    pub symbol_types: BTreeMap<String, Rate>,
+    // keyed by the kind of the query, `identifier`, `mixed` or `conceptual`.
+    pub sources: BTreeMap<String, SourceRates>,
+    // the best ranked chunks of the queries about the current behavior, and of the others.
+    pub top_ranks_current: Rate,
+    pub top_ranks_other: Rate,
+}
+
+fn kind_name(kind: QueryKind) -> &'static str {
+    match kind {
+        QueryKind::Identifier => "identifier",
+        QueryKind::Mixed => "mixed",
+        QueryKind::Conceptual => "conceptual",
+    }
+}
+
+fn rank_rates(records: &[&RetrievalFeedback]) -> Vec<RankRate> {
+    let mut rates = vec![Rate::default(); RANK_BUCKETS];
+    for record in records {
+        for chunk in &record.retrieved {
+            rates[chunk.rank.min(RANK_BUCKETS - 1)].add(record.is_cited(chunk));
+        }
+    }
+    rates
+        .into_iter()
+        .enumerate()
+        .map(|(rank, rate)| RankRate { rank, rate })
+        .collect()
+}
+
+// Equal bins over [0, 1], the scores out of it are counted in the first or the last bin.
+fn calibration(records: &[&RetrievalFeedback]) -> Vec<CalibrationBin> {
+    let width = 1.0 / CALIBRATION_BINS as f32;
+    let mut bins = (0..CALIBRATION_BINS)
+        .map(|i| CalibrationBin {
+            min_score: i as f32 * width,
+            max_score: (i + 1) as f32 * width,
+            rate: Rate::default(),
+        })
+        .collect::<Vec<_>>();
+    for record in records {
+        for chunk in &record.retrieved {
+            let bin = ((chunk.score.clamp(0.0, 1.0) / width) as usize).min(CALIBRATION_BINS - 1);
+            bins[bin].rate.add(record.is_cited(chunk));
+        }
+    }
+    bins
+}
+
+impl FeedbackStats {
+    pub fn compute(records: &[RetrievalFeedback]) -> Self {
+        let mut stats = FeedbackStats {
+            answers: records.len(),
+            ..Default::default()
+        };
+        let mut by_repo: BTreeMap<&str, Vec<&RetrievalFeedback>> = BTreeMap::new();
+        for record in records {
+            by_repo.entry(&record.repo).or_default().push(record);
+            let kind = kind_name(classify_query(&record.query));
+            let current = asks_current_behavior(&record.query);
+            for chunk in &record.retrieved {
+                let cited = record.is_cited(chunk);
+                if let Some(symbol_type) = &chunk.symbol_type {
+                    stats
+                        .symbol_types
+                        .entry(symbol_type.clone())
+                        .or_default()
+                        .add(cited);
+                }
+                let sources = stats.sources.entry(kind.to_string()).or_default();
+                if matches!(
+                    chunk.source,
+                    Some(RetrievalSource::Keyword | RetrievalSource::Both)
+                ) {
+                    sources.keyword.add(cited);
+                }
+                if matches!(
+                    chunk.source,
+                    Some(RetrievalSource::Vector | RetrievalSource::Both)
+                ) {
+                    sources.vector.add(cited);
+                }
+                if chunk.rank < TOP_RANKS {
+                    if current {
+                        stats.top_ranks_current.add(cited);
+                    } else {
+                        stats.top_ranks_other.add(cited);
+                    }
+                }
+            }
+        }
+        stats.repos = by_repo
+            .into_iter()
+            .map(|(repo, records)| {
+                let repo_stats = RepoStats {
+                    answers: records.len(),
+                    citation_rate_by_rank: rank_rates(&records),
+                    calibration: calibration(&records),
+                };
+                (repo.to_string(), repo_stats)
+            })
+            .collect();
+        stats
+    }
+
+    /// The ranking weights the citations call for, starting from the defaults. The weights
+    /// without `min_samples` chunks behind them keep their value in `current`.
+    pub fn tune(&self, current: &RankingConfig, min_samples: usize) -> RankingConfig {
+        let defaults = RankingConfig::default();
+        let mut tuned = current.clone();
+
+        let typed = self
+            .symbol_types
+            .values()
+            .fold(Rate::default(), |all, rate| Rate {
+                retrieved: all.retrieved + rate.retrieved,
+                cited: all.cited + rate.cited,
+            });
+        if let Some(overall) = typed.rate().filter(|rate| *rate > 0.0) {
+            for (symbol_type, rate) in &self.symbol_types {
+                if rate.retrieved < min_samples
+                    || !defaults.symbol_weights.contains_key(symbol_type)
+                {
+                    continue;
+                }
+                let factor =
+                    (rate.rate().unwrap_or_default() / overall).clamp(MIN_FACTOR, MAX_FACTOR);
+                tuned.symbol_weights.insert(
+                    symbol_type.clone(),
+                    defaults.symbol_weight(symbol_type) * factor,
+                );
+            }
+        }
+
+        for kind in [
+            QueryKind::Identifier,
+            QueryKind::Mixed,
+            QueryKind::Conceptual,
+        ] {
+            let Some(sources) = self.sources.get(kind_name(kind)) else {
+                continue;
+            };
+            if sources.keyword.retrieved < min_samples || sources.vector.retrieved < min_samples {
+                continue;
+            }
+            let keyword = sources.keyword.rate().unwrap_or_default();
+            let vector = sources.vector.rate().unwrap_or_default();
+            if keyword + vector == 0.0 {
+                continue;
+            }
+            let share = keyword / (keyword + vector);
+            let weight = (defaults.keyword_weights.get(kind) + share) / 2.0;
+            tuned
+                .keyword_weights
+                .set(kind, weight.clamp(MIN_KEYWORD_WEIGHT, MAX_KEYWORD_WEIGHT));
+        }
+
+        if self.top_ranks_current.retrieved >= min_samples
+            && self.top_ranks_other.retrieved >= min_samples
+        {
+            if let (Some(current_rate), Some(other_rate)) = (
+                self.top_ranks_current.rate(),
+                self.top_ranks_other.rate().filter(|rate| *rate > 0.0),
+            ) {
+                let factor = (current_rate / other_rate).clamp(MIN_FACTOR, MAX_FACTOR);
+                tuned.recency_half_life_days = Some(DEFAULT_RECENCY_HALF_LIFE_DAYS / factor);
+            }
+        }
+        tuned
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct TuneArgs {
+    log: FeedbackLog,
+    config: PathBuf,
+    report: Option<PathBuf>,
+    min_samples: usize,
+}
+
+fn parse_args(args: &[String]) -> Result<TuneArgs> {
+    let mut log = None;
+    let mut config = std::env::var("RANKING_CONFIG_PATH")
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+        .map(PathBuf::from);
+    let mut report = None;
+    let mut min_samples = DEFAULT_MIN_SAMPLES;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| anyhow!("{} requires a value", arg))
+        };
+        match arg.as_str() {
+            "--log" => log = Some(value()?.parse()?),
+            "--config" => config = Some(PathBuf::from(value()?)),
+            "--report" => report = Some(PathBuf::from(value()?)),
+            "--min-samples" => {
+                min_samples = value()?
+                    .parse()
+                    .context("--min-samples must be a whole number")?
+            }
+            other => return Err(anyhow!("Unknown argument {}", other)),
+        }
+    }
+    Ok(TuneArgs {
+        log: log.ok_or_else(|| anyhow!("--log is required"))?,
+        config: config.ok_or_else(|| anyhow!("--config or RANKING_CONFIG_PATH is required"))?,
+        report,
+        min_samples,
+    })
+}
+
+/// Runs `code-search tune-ranking`, `args` are the arguments after the command.
+pub fn run(args: &[String]) -> Result<()> {
+    let args = parse_args(args)?;
+    let redis_url = std::env::var("REDIS_URL").unwrap_or_default();
+    let records = args.log.read_all(&redis_url)?;
+    let stats = FeedbackStats::compute(&records);
+    let current = if args.config.exists() {
+        RankingConfig::read(&args.config)?
+    } else {
+        RankingConfig::default()
+    };
+    let tuned = stats.tune(&current, args.min_samples);
+
+    let report = serde_json::to_string_pretty(&stats)?;
+    match &args.report {
+        Some(path) => std::fs::write(path, report)?,
+        None => println!("{}", report),
+    }
+    if tuned == current {
+        log::info!(
+            "The ranking weights of {} are unchanged",
+            args.config.display()
+        );
+        return Ok(());
+    }
+    tuned.write(&args.config)?;
+    log::info!(
+        "Wrote the ranking weights tuned on {} answers to {}",
+        stats.answers,
+        args.config.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::retrieval_feedback::RetrievedChunk;
+
+    fn retrieved(
+        rank: usize,
+        score: f32,
+        source: RetrievalSource,
+        symbol_type: &str,
+    ) -> RetrievedChunk {
+        RetrievedChunk {
+            id: format!("src/file_{}.rs:0-10", rank),
+            path: format!("src/file_{}.rs", rank),
+            start_line: 0,
+            end_line: 10,
+            rank,
+            score,
+            source: Some(source),
+            symbol_type: Some(symbol_type.to_string()),
+        }
+    }
+
+    // the chunks of ranks 0 and 1 are keyword hits on functions and cited, the ones of ranks 2
+    // and 3 vector hits on variables and not.
+    fn record(repo: &str, query: &str) -> RetrievalFeedback {
+        let retrieved = vec![
+            retrieved(0, 0.95, RetrievalSource::Keyword, "function"),
+            retrieved(1, 0.75, RetrievalSource::Keyword, "function"),
+            retrieved(2, 0.45, RetrievalSource::Vector, "variable"),
+            retrieved(3, 0.15, RetrievalSource::Vector, "variable"),
+        ];
+        RetrievalFeedback {
+            repo: repo.to_string(),
+            query: query.to_string(),
+            task_id: "t".to_string(),
+            question_id: 1,
+            recorded_at: 0,
+            cited: vec![retrieved[0].id.clone(), retrieved[1].id.clone()],
+            retrieved,
+        }
+    }
+
+    fn log() -> Vec<RetrievalFeedback> {
+        let mut records = (0..10)
+            .map(|_| record("acme/api", "where is `validate_token` called"))
+            .collect::<Vec<_>>();
+        records.push(RetrievalFeedback {
+            cited: Vec::new(),
+            ..record("acme/web", "how is the invoice rendered")
+        });
+        records
+    }
+
+    #[test]
+    fn test_statistics_of_a_synthetic_log() {
+        let stats = FeedbackStats::compute(&log());
+
+        assert_eq!(stats.answers, 11);
+        let api = &stats.repos["acme/api"];
+        assert_eq!(api.answers, 10);
+        let by_rank = api
+            .citation_rate_by_rank
+            .iter()
+            .map(|rank| rank.rate.rate())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            &by_rank[..5],
+            &[Some(1.0), Some(1.0), Some(0.0), Some(0.0), None]
+        );
+        assert_eq!(api.citation_rate_by_rank[0].rate.retrieved, 10);
+
+        let calibration = &api.calibration;
+        assert_eq!(calibration.len(), CALIBRATION_BINS);
+        assert_eq!(
+            calibration[9].rate,
+            Rate {
+                retrieved: 10,
+                cited: 10
+            }
+        );
+        assert_eq!(
+            calibration[7].rate,
+            Rate {
+                retrieved: 10,
+                cited: 10
+            }
+        );
+        assert_eq!(
+            calibration[4].rate,
+            Rate {
+                retrieved: 10,
+                cited: 0
+            }
+        );
+        assert_eq!(
+            calibration[1].rate,
+            Rate {
+                retrieved: 10,
+                cited: 0
+            }
+        );
+        assert_eq!(calibration[0].rate.rate(), None);
+
+        // nothing was cited on the other repo.
+        let web = &stats.repos["acme/web"];
+        assert_eq!(
+            web.citation_rate_by_rank[0].rate,
+            Rate {
+                retrieved: 1,
+                cited: 0
+            }
+        );
+
+        assert_eq!(
+            stats.symbol_types["function"],
+            Rate {
+                retrieved: 22,
+                cited: 20
+            }
+        );
+        assert_eq!(
+            stats.symbol_types["variable"],
+            Rate {
+                retrieved: 22,
+                cited: 0
+            }
+        );
+        assert_eq!(
+            stats.sources["identifier"].keyword,
+            Rate {
+                retrieved: 20,
+                cited: 20
+            }
+        );
+        assert_eq!(
+            stats.sources["conceptual"].vector,
+            Rate {
+                retrieved: 2,
+                cited: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_tuning_follows_the_citations() {
+        let stats = FeedbackStats::compute(&log());
+        let defaults = RankingConfig::default();
+
+        let tuned = stats.tune(&defaults, 20);
+        assert!(tuned.symbol_weight("function") > defaults.symbol_weight("function"));
+        assert_eq!(
+            tuned.symbol_weight("variable"),
+            defaults.symbol_weight("variable") * MIN_FACTOR
+        );
+        // only keyword hits were cited on the identifier queries.
+        assert_eq!(
+            tuned.keyword_weights.identifier,
+            (defaults.keyword_weights.identifier + 1.0) / 2.0
+        );
+        // too few samples on the conceptual queries and for the recency.
+        assert_eq!(
+            tuned.keyword_weights.conceptual,
+            defaults.keyword_weights.conceptual
+        );
+        assert_eq!(tuned.recency_half_life_days, None);
+
+        // the same log gives the same weights.
+        assert_eq!(stats.tune(&tuned, 20), tuned);
+    }
+
+    #[test]
+    fn test_arguments_of_the_job() {
+        let args = [
+            "--log",
+            "jsonl:/tmp/feedback.jsonl",
+            "--config",
+            "/tmp/ranking.json",
+        ]
+        .map(String::from);
+        let parsed = parse_args(&args).unwrap();
+        assert_eq!(parsed.log, FeedbackLog::Jsonl("/tmp/feedback.jsonl".into()));
+        assert_eq!(parsed.min_samples, DEFAULT_MIN_SAMPLES);
+        assert!(parse_args(&["--log".to_string()]).is_err());
+    }
+}
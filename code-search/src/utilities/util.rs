@@ -1,6 +1,27 @@
 use std::io::{self, ErrorKind};
+use std::ops::Range;
+use common::redaction::{redact_secrets, redaction_enabled};
 use log::debug;
 
+/// Redacts the secrets of the snippets of a response, unless `REDACT_SECRETS=false`.
+/// The index isn't touched, and the number of redactions is logged for auditing.
+pub fn redact_snippets<'a>(snippets: impl IntoIterator<Item = &'a mut String>, route: &str, repo: &str) {
+    if !redaction_enabled() {
+        return;
+    }
+    let mut count = 0;
+    for snippet in snippets {
+        let redacted = redact_secrets(snippet);
+        if redacted.count > 0 {
+            count += redacted.count;
+            *snippet = redacted.text;
+        }
+    }
+    if count > 0 {
+        log::info!("Redacted {} secrets from the {} response of repo {}", count, route, repo);
+    }
+}
+
 /// Returns the byte range for a specific line range in a document.
 ///
 /// # Arguments
@@ -103,7 +124,42 @@ pub fn pluck_code_by_lines<'a>(
     let (char_start, char_end) =
         return_byte_range_from_line_numbers(indices, start_line, end_line)?;
     // Return the specified substring, which is a range of lines.
-    Ok(&text[char_start..=char_end])
+    // The indices can be out of step with the text, or point inside a multi-byte character.
+    text.get(char_start..=char_end)
+        .or_else(|| text.get(char_start..))
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Line indices do not match the text of the file",
+            )
+        })
+}
+
+/// Clamps a 1-based, inclusive line range of a span request to the lines of the file.
+///
+/// # Returns
+/// * The range to extract, and whether it differs from the requested one: the end is clamped to
+///   the last line of the file, and a start of 0 is read as the first line.
+/// * An error message if the start is after the end, or beyond the end of the file.
+pub fn clamp_line_range(
+    range: &Range<usize>,
+    line_count: usize,
+) -> Result<(Range<usize>, bool), String> {
+    if range.start > range.end {
+        return Err(format!(
+            "Invalid range {}-{}: the start line is after the end line",
+            range.start, range.end
+        ));
+    }
+    let start = range.start.max(1);
+    if start > line_count {
+        return Err(format!(
+            "Invalid range {}-{}: the start line is beyond the end of the file",
+            range.start, range.end
+        ));
+    }
+    let end = range.end.min(line_count);
+    Ok((start..end, start != range.start || end != range.end))
 }
 
 /// Adjusts the byte positions to align with the start and end of lines in a document.
@@ -242,6 +298,38 @@ mod tests {
         assert_eq!(adjusted_end, 10); // Adjusted to the end of the line containing position 15.
     }
 
+    #[test]
+    fn test_clamp_line_range() {
+        // Exact fit, the range is kept as it is.
+        assert_eq!(clamp_line_range(&(1..10), 10), Ok((1..10, false)));
+        assert_eq!(clamp_line_range(&(4..4), 10), Ok((4..4, false)));
+
+        // Overhanging the end of a file that was shortened.
+        assert_eq!(clamp_line_range(&(8..25), 10), Ok((8..10, true)));
+        assert_eq!(clamp_line_range(&(0..5), 10), Ok((1..5, true)));
+
+        // Fully out of range.
+        assert!(clamp_line_range(&(11..20), 10).is_err());
+        assert!(clamp_line_range(&(1..5), 0).is_err());
+
+        // Inverted.
+        let error = clamp_line_range(&(9..3), 10).unwrap_err();
+        assert!(error.contains("start line is after the end line"));
+    }
+
+    #[test]
+    fn test_pluck_code_by_lines_never_panics() {
+        let text = "one\ntwo\nthree\n";
+        let indices = vec![3, 7, 13];
+        assert_eq!(pluck_code_by_lines(text, &indices, Some(2), Some(3)).unwrap(), "two\nthree\n");
+
+        // indices of an older version of the file, longer than the text.
+        let stale = vec![3, 7, 13, 30, 45];
+        assert!(pluck_code_by_lines(text, &stale, Some(1), Some(5)).is_ok());
+        assert!(pluck_code_by_lines(text, &stale, Some(5), Some(5)).is_err());
+        assert!(pluck_code_by_lines(text, &indices, Some(3), Some(9)).is_err());
+    }
+
     #[test]
     fn test_get_line_number() {
         let line_end_indices = setup_line_end_indices();
@@ -5,19 +5,34 @@ use std::sync::Arc;
 use std::time::Duration;
 use tracing::instrument;
 
-use crate::{config::get_ai_gateway_config, AppState};
+use crate::{
+    config::{get_ai_gateway_config, get_quickwit_url, get_redis_url, get_repo_working_copy},
+    AppState,
+};
 use anyhow::{anyhow, Context, Result};
 
 use common::{
-    ai_util::{call_llm, find_first_function_call},
-    prompts,
+    ai_util::{call_llm_metered, find_first_function_call},
+    budget::BudgetMeter,
+    glossary::Glossary,
+    metrics, prompts,
+    repo_summary::{RepoSummary, REPO_SUMMARY_PATH},
+    run_manifest::{IndexMode, RunManifest, RUN_MANIFEST_PATH},
+    terminology::Terminology,
 };
 
-use crate::agent::exchange::{Exchange, SearchStep, Update};
+use crate::agent::call_log::{CallCheck, CallLog};
+use crate::batch::BatchScope;
+use crate::agent::cancellation::AgentRun;
+use crate::agent::digest::HistoryMode;
+use crate::agent::paging::MORE_RESULTS;
+use crate::agent::tools::ask_user::ASK_USER;
+use crate::agent::exchange::{CodeChunk, Exchange, LlmCall, LlmStage, SearchStep, Update};
 use ai_gateway::message::message::{self, MessageRole};
 use ai_gateway::{
     config::AIGatewayConfig,
     function_calling::{Function, FunctionCall},
+    utils::count_tokens,
 };
 
 use crate::agent::transform;
@@ -44,7 +59,7 @@ pub struct ExtractedContent {
     pub end_line: usize,
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ContentDocument {
     pub repo_name: String,
     pub repo_ref: String,
@@ -64,6 +79,13 @@ pub struct FileDocument {
     pub lang: Option<String>,
 }
 
+/// A fuzzy path search hit, `score` is the number of query trigrams matching the path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathMatch {
+    pub file: FileDocument,
+    pub score: usize,
+}
+
 /// A collection of modules that each add methods to `Agent`.
 ///
 /// These methods correspond to `Action` handlers, and often have supporting methods and supporting
@@ -85,24 +107,64 @@ pub struct Agent {
     pub ai_gateway: AIGatewayConfig,
     pub query_id: String,
     pub last_function_call_id: Option<String>,
-    /// Indicate whether the request was answered.
+    /// Preferences the user stated in the conversation, appended to the answer prompt.
+    pub preferences: Option<String>,
+    /// Language the answer is written in, English when None.
+    pub language: Option<String>,
+    /// Whether the code search adds the callers and callees of the functions it finds.
+    pub related_usage: bool,
+    /// Whether the code search keeps the scores of test and vendored code.
+    pub include_tests: bool,
+    /// Whether the code search follows the value the query asks about across files.
+    pub data_flow: bool,
+    /// The function calls made for the query, to catch the model repeating itself.
+    pub calls: CallLog,
+    /// Documents and searches shared with the other questions of a batch request.
+    pub batch: Option<BatchScope>,
+    /// Checks the LLM calls of the query against the budget allowance sent with the request.
+    pub budget: BudgetMeter,
+    /// The key files of the repo, listed in the system prompt so the first search is targeted.
+    pub key_files: Vec<String>,
+    /// The conversation whose attached documents are searched for the answer, None without any.
+    pub attachments: Option<String>,
+    /// Whether the earlier steps are sent to the model as their digests or their full returns.
+    pub history_mode: HistoryMode,
+    /// Collections code search reads from instead of the aliases, the index generation the
+    /// conversation is pinned to. The current generation when not set.
+    pub index_generation: Option<String>,
+    /// The dictionary of the jargon of the repo, None when the searches aren't expanded with it.
+    pub terminology: Option<Terminology>,
+    /// How the earlier answers of the conversation described the symbols they cited, the ones
+    /// the answer context mentions are kept to in the answer prompt.
+    pub glossary: Glossary,
+    /// What the repo was indexed with. A paths-only index has no embeddings or symbols, its code
+    /// is searched by keywords and the answer says it may be incomplete.
+    pub index_mode: IndexMode,
+    /// Tracks whether the request was answered and owns the sub-tasks of the run.
     ///
     /// This is used in the `Drop` handler, in order to track cancelled answer queries.
-    pub complete: bool,
+    pub run: AgentRun,
 }
 
 /// We use a `Drop` implementation to track agent query cancellation.
 ///
 /// Query control flow can be complex, as there are several points where an error may be returned
 /// via `?`. Rather than dealing with this in a complex way, we can simply use `Drop` destructors
-/// to send cancellation messages to our analytics provider.
+/// to clean up after cancelled queries.
 ///
-/// By default, dropping an agent struct will send a cancellation message. However, calling
-/// `.complete()` will "diffuse" tracking, and disable the cancellation message from sending on drop.
+/// By default, dropping an agent struct flushes the partial exchanges to redis so that a retry of
+/// the same question resumes where it stopped. Dropping the `AgentRun` afterwards aborts spawned
+/// sub-tasks and records the cancellation event. Calling `.complete()` will "diffuse" both.
 impl Drop for Agent {
     fn drop(&mut self) {
-        if !self.complete {
-            log::debug!("Dropping agent");
+        if self.run.is_complete() {
+            return;
+        }
+
+        let chunks_gathered = self.exchanges.iter().map(|e| e.code_chunks.len()).sum();
+        self.run.set_chunks_gathered(chunks_gathered);
+        if let Err(e) = self.save_exchanges_to_redis(&get_redis_url()) {
+            error!("Failed to flush exchanges of cancelled query {}: {}", self.query_id, e);
         }
     }
 }
@@ -150,11 +212,46 @@ pub fn get_line_number(byte: usize, line_end_indices: &[usize]) -> usize {
     return line;
 }
 
+/// The system prompt for choosing the next action.
+/// Code pinned by the user is appended so that the model sees it before any search happens.
+pub fn system_prompt<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    pinned_chunks: &[CodeChunk],
+    key_files: &[String],
+) -> String {
+    let mut prompt = match key_files.is_empty() {
+        true => String::new(),
+        false => prompts::key_files_prompt(key_files),
+    };
+    prompt.push_str(&prompts::system(paths));
+    if !pinned_chunks.is_empty() {
+        let chunks = pinned_chunks
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        prompt.push_str(&prompts::pinned_code_prompt(&chunks));
+    }
+    prompt
+}
+
+// The templates `system_prompt` is built from, the function schemas of the step included.
+fn step_templates(pinned_chunks: &[CodeChunk], key_files: &[String]) -> Vec<&'static str> {
+    let mut templates = vec!["functions", "system"];
+    if !key_files.is_empty() {
+        templates.push("key_files_prompt");
+    }
+    if !pinned_chunks.is_empty() {
+        templates.push("pinned_code_prompt");
+    }
+    templates
+}
+
 impl Agent {
     /// Complete this agent, preventing an analytics message from sending on drop.
     pub fn complete(mut self) {
         // Checked in `Drop::drop`
-        self.complete = true;
+        self.run.complete();
     }
 
     /// Update the last exchange
@@ -208,7 +305,18 @@ impl Agent {
     #[instrument(skip(self))]
     pub async fn step(&mut self, action: Action, exchange_exists: bool) -> Result<Option<Action>> {
         log::debug!("\ninside step {:?}\n", action);
+        self.run.set_last_action(action.name());
         if !exchange_exists {
+            let (steps_before, chunks_before) = match self.exchanges.last() {
+                Some(e) => (e.search_steps.len(), e.code_chunks.len()),
+                None => (0, 0),
+            };
+            // the pages left of the last return are dropped once the model moves on.
+            if !matches!(action, Action::Query(_) | Action::MoreResults {}) {
+                if let Some(exchange) = self.exchanges.last_mut() {
+                    exchange.drop_pending_pages();
+                }
+            }
             match &action {
                 Action::Query(s) => s.clone(),
 
@@ -216,48 +324,141 @@ impl Agent {
                     self.answer(paths).await.context("answer action failed")?;
                     return Ok(None);
                 }
+                // the run stops until the user responds, see `tools::ask_user`.
+                Action::AskUser { question, options } => {
+                    self.ask_user(question, options)?;
+                    return Ok(None);
+                }
 
                 Action::Path { query } => self.path_search(query).await?,
+                // a paths-only index has no embeddings or symbols to search.
+                Action::Code { query } | Action::Symbol { name: query, .. }
+                    if !self.index_mode.is_full() =>
+                {
+                    self.keyword_search(query).await?
+                }
                 Action::Code { query } => self.code_search(query).await?,
                 Action::Proc { query, paths } => self.process_files(query, paths).await?,
+                Action::Symbol {
+                    name,
+                    kind,
+                    container,
+                    case_sensitive,
+                } => {
+                    self.symbol_lookup(name, kind.as_deref(), container.as_deref(), *case_sensitive)
+                        .await?
+                }
+                Action::History {
+                    path,
+                    start_line,
+                    end_line,
+                } => self.git_history(path, *start_line, *end_line).await?,
+                Action::MoreResults {} => self.more_results().await?,
             };
+            let response = self
+                .last_exchange()
+                .search_steps
+                .last()
+                .map(|s| s.get_response())
+                .unwrap_or_default();
+            self.calls.record(&action, &response);
+            if self.last_exchange().search_steps.len() > steps_before {
+                self.last_exchange_mut().digest_last_step(chunks_before);
+                if let Some(max_tokens) = self.ai_gateway.model.max_message_tokens {
+                    self.last_exchange_mut()
+                        .page_last_step(chunks_before, max_tokens);
+                }
+            }
         } else {
             debug!("exchange exists.");
         }
 
         let functions = serde_json::from_value::<Vec<Function>>(
-            prompts::functions(self.paths().next().is_some()), // Only add proc if there are paths in context
+            // Only add proc if there are paths in context, history if the repo has a working copy,
+            // more_results while a paged return has pages left, ask_user until the user was asked.
+            // The code search of a paths-only index matches keywords and there is no symbol lookup.
+            prompts::functions(
+                self.paths().next().is_some(),
+                get_repo_working_copy(&self.repo_name).is_some(),
+                self.exchanges.last().map_or(false, Exchange::has_pending_pages),
+                self.calls.can_ask_user(),
+                !self.index_mode.is_full(),
+            ),
         )
         .unwrap();
 
-        let mut history = vec![message::Message::system(&prompts::system(self.paths()))];
+        let pinned_chunks = self.pinned_chunks().cloned().collect::<Vec<_>>();
+        let mut history = vec![message::Message::system(&system_prompt(
+            self.paths(),
+            &pinned_chunks,
+            &self.key_files,
+        ))];
+        let templates = step_templates(&pinned_chunks, &self.key_files);
+        self.last_exchange_mut().record_prompts(&templates);
         history.extend(self.history()?);
+        let history_tokens_saved = self.history_tokens_saved()?;
 
         log::debug!("full history:\n {:?}", history);
 
         //let trimmed_history = trim_history(history.clone())?;
 
         //log::debug!("trimmed history:\n {:?}", trimmed_history);
-        // call the llm
-        let llm_output = call_llm(
-            &get_ai_gateway_config(),
-            None,
-            Some(history),
-            Some(functions),
-        )
-        .await?;
+        loop {
+            // call the llm
+            let llm_output = call_llm_metered(
+                &get_ai_gateway_config(),
+                Some(&self.budget),
+                None,
+                Some(history.clone()),
+                Some(functions.clone()),
+            )
+            .await?;
+            self.last_exchange_mut().llm_calls.push(LlmCall {
+                stage: LlmStage::Step,
+                messages: history.clone(),
+                response: llm_output.clone(),
+                history_tokens_saved,
+            });
 
-        if let Some((function_to_call, id)) = find_first_function_call(&llm_output) {
+            let Some((function_to_call, id)) = find_first_function_call(&llm_output) else {
+                // return error if no function call is found.
+                error!("No FunctionCall found.");
+                return Err(anyhow!("No FunctionCall found."));
+            };
             log::debug!("{:?} next action", function_to_call);
             let action = Action::deserialize_gpt(&function_to_call)
                 .context("failed to deserialize LLM output")?;
 
-            self.last_function_call_id = id;
-            Ok(Some(action))
-        } else {
-            // return error if no function call is found.
-            error!("No FunctionCall found.");
-            return Err(anyhow!("No FunctionCall found."));
+            match self.calls.check(&action) {
+                CallCheck::Run => {
+                    self.last_function_call_id = id;
+                    return Ok(Some(action));
+                }
+                // the call isn't run again, the model is told it was already made and picks again.
+                CallCheck::Redirect(message) => {
+                    log::warn!(
+                        "Repeated {} call for {}, redirecting the model",
+                        action.name(),
+                        self.query_id
+                    );
+                    history.push(message::Message::function_call(id.clone(), &function_to_call));
+                    history.push(message::Message::function_return(
+                        id,
+                        &function_to_call.name,
+                        &message,
+                    ));
+                }
+                CallCheck::Answer => {
+                    log::warn!(
+                        "Model kept repeating calls for {}, answering with the context found so far",
+                        self.query_id
+                    );
+                    self.last_function_call_id = id;
+                    return Ok(Some(Action::Answer {
+                        paths: (0..self.paths().count()).collect(),
+                    }));
+                }
+            }
         }
         // print the next action picked.
 
@@ -267,91 +468,20 @@ impl Agent {
         // log::debug!("functions:\n {:?} \n", &functions);
     }
 
-    /// The full history of messages, including intermediate function calls
+    /// The history of messages of the last exchanges, including intermediate function calls.
     pub fn history(&self) -> Result<Vec<message::Message>> {
-        const ANSWER_MAX_HISTORY_SIZE: usize = 3;
-        const FUNCTION_CALL_INSTRUCTION: &str = "Call a function. Do not answer";
-
-        let history = self
-            .exchanges
-            .iter()
-            .rev()
-            .take(ANSWER_MAX_HISTORY_SIZE)
-            .rev()
-            .try_fold(Vec::new(), |mut acc, e| -> Result<_> {
-                let query = e
-                    .query()
-                    .map(|q| message::Message::user(&q))
-                    .ok_or_else(|| anyhow!("query does not have target"))?;
-
-                let steps = e.search_steps.iter().flat_map(|s| {
-                    let (id, name, arguments) = match s {
-                        SearchStep::Path { id, query, .. } => (
-                            id,
-                            "path".to_owned(),
-                            format!("{{\n \"query\": \"{query}\"\n}}"),
-                        ),
-                        SearchStep::Code { id, query, .. } => (
-                            id,
-                            "code".to_owned(),
-                            format!("{{\n \"query\": \"{query}\"\n}}"),
-                        ),
-                        SearchStep::Proc {
-                            id, query, paths, ..
-                        } => (
-                            id,
-                            "proc".to_owned(),
-                            format!(
-                                "{{\n \"paths\": [{}],\n \"query\": \"{query}\"\n}}",
-                                paths
-                                    .iter()
-                                    .map(|path| self
-                                        .paths()
-                                        .position(|p| p == path)
-                                        .unwrap()
-                                        .to_string())
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ),
-                        ),
-                    };
-
-                    vec![
-                        message::Message::function_call(
-                            id.clone(),
-                            &FunctionCall {
-                                name: name.clone(),
-                                arguments,
-                            },
-                        ),
-                        message::Message::function_return(id.clone(), &name, &s.get_response()),
-                        //message::Message::user(FUNCTION_CALL_INSTRUCTION),
-                    ]
-                });
-
-                let answer = match e.answer() {
-                    // NB: We intentionally discard the summary as it is redundant.
-                    Some((answer, _conclusion, answer_id)) => {
-                        let encoded = transform::encode_summarized(answer, None, "gpt-3.5-turbo")?;
-                        Some(message::Message::function_return(
-                            Some(answer_id.to_string()),
-                            "none",
-                            &encoded,
-                        ))
-                    }
-
-                    None => None,
-                };
+        build_history(&self.exchanges, self.history_mode)
+    }
 
-                acc.extend(
-                    std::iter::once(query)
-                        //.chain(vec![message::Message::user(FUNCTION_CALL_INSTRUCTION)])
-                        .chain(steps)
-                        .chain(answer.into_iter()),
-                );
-                Ok(acc)
-            })?;
-        Ok(history)
+    // Prompt tokens the digests of the earlier steps save on the history, 0 in the full mode.
+    fn history_tokens_saved(&self) -> Result<usize> {
+        if self.history_mode == HistoryMode::Full {
+            return Ok(0);
+        }
+        let saved = history_tokens(&build_history(&self.exchanges, HistoryMode::Full)?)
+            .saturating_sub(history_tokens(&self.history()?));
+        metrics::record_history_tokens_saved(&self.repo_name, saved);
+        Ok(saved)
     }
 
     pub async fn get_file_content(
@@ -359,24 +489,227 @@ impl Agent {
         base_url: &str,
         path: &str,
     ) -> Result<Option<ContentDocument>> {
-        self.app_state
-            .db_connection
-            .get_file_from_quickwit(base_url, &self.repo_name, "relative_path", path)
-            .await
+        let db = &self.app_state.db_connection;
+        let fetch = || db.get_file_from_quickwit(base_url, &self.repo_name, "relative_path", path);
+        match &self.batch {
+            Some(batch) => batch.document(&self.repo_name, path, fetch).await,
+            None => fetch().await,
+        }
+    }
+
+    /// The key files ranked when the repo was indexed, read from its summary. Empty for repos
+    /// indexed before the files were ranked, or when the summary can't be read.
+    pub async fn load_key_files(&self) -> Vec<String> {
+        let document = match self.get_file_content(&get_quickwit_url(), REPO_SUMMARY_PATH).await {
+            Ok(document) => document,
+            Err(e) => {
+                log::warn!("Failed to read the summary of {}: {}", self.repo_name, e);
+                return Vec::new();
+            }
+        };
+        document
+            .and_then(|document| serde_json::from_str::<RepoSummary>(&document.content).ok())
+            .map(|summary| summary.key_files)
+            .unwrap_or_default()
+    }
+
+    /// The mode the repo was indexed with, read from its run manifest. Full for repos indexed
+    /// before the mode was recorded, or when the manifest can't be read.
+    pub async fn load_index_mode(&self) -> IndexMode {
+        let document = match self.get_file_content(&get_quickwit_url(), RUN_MANIFEST_PATH).await {
+            Ok(document) => document,
+            Err(e) => {
+                log::warn!("Failed to read the run manifest of {}: {}", self.repo_name, e);
+                return IndexMode::Full;
+            }
+        };
+        document
+            .and_then(|document| serde_json::from_str::<RunManifest>(&document.content).ok())
+            .map(|manifest| manifest.index_mode)
+            .unwrap_or_default()
     }
 
     pub async fn fuzzy_path_search<'a>(
         &'a self,
         query: &str,
-    ) -> impl Iterator<Item = FileDocument> + 'a {
+    ) -> impl Iterator<Item = PathMatch> + 'a {
         log::debug!("executing fuzzy search {}\n", query);
         self.app_state
             .db_connection
             .fuzzy_path_match(&self.repo_name, "relative_path", query, 50)
             .await
+            .map(|(file, score)| PathMatch { file, score })
     }
 }
 
+/// The history of messages of the last exchanges, including intermediate function calls. In
+/// `HistoryMode::Digest` the function returns of every step but the most recent one are sent as
+/// the digests of the steps.
+pub fn build_history(exchanges: &[Exchange], mode: HistoryMode) -> Result<Vec<message::Message>> {
+    const ANSWER_MAX_HISTORY_SIZE: usize = 3;
+    const FUNCTION_CALL_INSTRUCTION: &str = "Call a function. Do not answer";
+
+    let known_paths = exchanges
+        .iter()
+        .flat_map(|e| e.paths.iter())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let recent = &exchanges[exchanges.len().saturating_sub(ANSWER_MAX_HISTORY_SIZE)..];
+    let history = recent
+        .iter()
+        .enumerate()
+        .try_fold(Vec::new(), |mut acc, (i, e)| -> Result<_> {
+            let query = e
+                .query()
+                .map(|q| message::Message::user(&q))
+                .ok_or_else(|| anyhow!("query does not have target"))?;
+
+            // the most recent step keeps its full return.
+            let last_step = match i + 1 == recent.len() {
+                true => e.search_steps.len().checked_sub(1),
+                false => None,
+            };
+            let mut steps = e.search_steps.iter().enumerate().flat_map(|(step, s)| {
+                let (id, name, arguments) = match s {
+                    SearchStep::Path { id, query, .. } => (
+                        id,
+                        "path".to_owned(),
+                        format!("{{\n \"query\": \"{query}\"\n}}"),
+                    ),
+                    SearchStep::Code { id, query, .. } => (
+                        id,
+                        "code".to_owned(),
+                        format!("{{\n \"query\": \"{query}\"\n}}"),
+                    ),
+                    SearchStep::Proc {
+                        id,
+                        query,
+                        paths,
+                        requested,
+                        ..
+                    } => (
+                        id,
+                        "proc".to_owned(),
+                        format!(
+                            "{{\n \"paths\": [{}],\n \"query\": \"{query}\"\n}}",
+                            // the paths as the model passed them, steps saved before they
+                            // were recorded only had aliases.
+                            if requested.is_empty() {
+                                paths
+                                    .iter()
+                                    .map(|path| known_paths
+                                        .iter()
+                                        .position(|p| p == path)
+                                        .unwrap()
+                                        .to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            } else {
+                                requested
+                                    .iter()
+                                    .map(|path| serde_json::to_string(path).unwrap())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            }
+                        ),
+                    ),
+                    SearchStep::Symbol {
+                        id,
+                        query,
+                        kind,
+                        container,
+                        case_sensitive,
+                        ..
+                    } => {
+                        let mut arguments = serde_json::json!({ "name": query });
+                        if let Some(kind) = kind {
+                            arguments["kind"] = kind.clone().into();
+                        }
+                        if let Some(container) = container {
+                            arguments["container"] = container.clone().into();
+                        }
+                        if let Some(case_sensitive) = case_sensitive {
+                            arguments["case_sensitive"] = (*case_sensitive).into();
+                        }
+                        (id, "symbol".to_owned(), arguments.to_string())
+                    }
+                    SearchStep::History {
+                        id,
+                        query,
+                        start_line,
+                        end_line,
+                        ..
+                    } => (
+                        id,
+                        "history".to_owned(),
+                        serde_json::json!({
+                            "path": query,
+                            "start_line": start_line,
+                            "end_line": end_line,
+                        })
+                        .to_string(),
+                    ),
+                    SearchStep::MoreResults { id, .. } => {
+                        (id, MORE_RESULTS.to_owned(), "{}".to_owned())
+                    }
+                };
+
+                let response = match (mode, e.step_digest(step)) {
+                    (HistoryMode::Digest, Some(digest)) if Some(step) != last_step => {
+                        digest.render()
+                    }
+                    // a paged return was sent a page at a time.
+                    _ => e.sent_response(step).unwrap_or_else(|| s.get_response()),
+                };
+
+                vec![
+                    message::Message::function_call(
+                        id.clone(),
+                        &FunctionCall {
+                            name: name.clone(),
+                            arguments,
+                        },
+                    ),
+                    message::Message::function_return(id.clone(), &name, &response),
+                    //message::Message::user(FUNCTION_CALL_INSTRUCTION),
+                ]
+            })
+            .collect::<Vec<_>>();
+            // the clarifying question follows the steps taken before it was asked, two messages each.
+            if let Some(asked) = &e.clarification {
+                let at = (asked.after_step * 2).min(steps.len());
+                steps.splice(at..at, asked.messages());
+            }
+
+            let answer = match e.answer() {
+                // NB: We intentionally discard the summary as it is redundant.
+                Some((answer, _conclusion, answer_id)) => {
+                    let encoded = transform::encode_summarized(answer, None, "gpt-3.5-turbo")?;
+                    Some(message::Message::function_return(
+                        Some(answer_id.to_string()),
+                        "none",
+                        &encoded,
+                    ))
+                }
+
+                None => None,
+            };
+
+            acc.extend(
+                std::iter::once(query)
+                    //.chain(vec![message::Message::user(FUNCTION_CALL_INSTRUCTION)])
+                    .chain(steps)
+                    .chain(answer.into_iter()),
+            );
+            Ok(acc)
+        })?;
+    Ok(history)
+}
+
+fn history_tokens(history: &[message::Message]) -> usize {
+    history.iter().map(|m| count_tokens(&m.to_string())).sum()
+}
+
 fn trim_history(mut history: Vec<message::Message>) -> Result<Vec<message::Message>> {
     const HEADROOM: usize = 100000;
     const HIDDEN: &str = "[HIDDEN]";
@@ -437,11 +770,54 @@ pub enum Action {
     },
     Proc {
         query: String,
-        paths: Vec<usize>,
+        paths: Vec<PathRef>,
+    },
+    Symbol {
+        name: String,
+        kind: Option<String>,
+        container: Option<String>,
+        case_sensitive: Option<bool>,
+    },
+    History {
+        path: String,
+        start_line: usize,
+        end_line: usize,
     },
+    // the next page of the last paged return.
+    #[serde(rename = "more_results")]
+    MoreResults {},
+    // a clarifying question to the user, the run stops until they respond.
+    #[serde(rename = "ask_user")]
+    AskUser {
+        question: String,
+        #[serde(default)]
+        options: Vec<String>,
+    },
+}
+
+/// A path passed to `proc`, either its alias under the PATHS heading or the path itself.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum PathRef {
+    Alias(usize),
+    Path(String),
 }
 
 impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Query(_) => "query",
+            Action::Path { .. } => "path",
+            Action::Answer { .. } => "answer",
+            Action::Code { .. } => "code",
+            Action::Proc { .. } => "proc",
+            Action::Symbol { .. } => "symbol",
+            Action::History { .. } => "history",
+            Action::MoreResults {} => MORE_RESULTS,
+            Action::AskUser { .. } => ASK_USER,
+        }
+    }
+
     /// Deserialize this action from the GPT-tagged enum variant format.
     ///
     /// We convert (2 examples):
@@ -459,7 +835,7 @@ impl Action {
     /// ```
     ///
     /// So that we can deserialize using the serde-provided "tagged" enum representation.
-    fn deserialize_gpt(call: &FunctionCall) -> Result<Self> {
+    pub(crate) fn deserialize_gpt(call: &FunctionCall) -> Result<Self> {
         let mut map = serde_json::Map::new();
         map.insert(call.name.clone(), serde_json::from_str(&call.arguments)?);
 
@@ -472,6 +848,268 @@ mod tests {
     use pretty_assertions::assert_eq;
 
     use super::*;
+    use crate::agent::exchange::PathGroup;
+    use common::clarification::Clarification;
+    use common::AnswerOutcome;
+
+    const QUERIES: [&str; 3] = [
+        "refund",
+        "retry failed refunds",
+        "where is the retry scheduled",
+    ];
+
+    fn chunk(path: &str, alias: usize, start_line: usize) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            alias: Some(alias),
+            snippet: "    let attempt = refund.attempts + 1;\n    queue.schedule(refund.id, backoff(attempt));\n"
+                .repeat(40),
+            start_line,
+            end_line: start_line + 40,
+            score: None,
+            ..Default::default()
+        }
+    }
+
+    // a path search, a code search and a proc of one of the files found.
+    fn scripted_exchange() -> Exchange {
+        let mut exchange = Exchange::new(
+            "exchange_1".to_string(),
+            "How are failed refunds retried?".to_string(),
+        );
+        let paths = vec!["src/refund.rs".to_string(), "src/retry.rs".to_string()];
+        exchange.paths = paths.clone();
+        exchange.apply_update(Update::StartStep(SearchStep::Path {
+            id: Some("call_1".to_string()),
+            query: QUERIES[0].to_string(),
+            response: "2 paths in 1 directory, grouped by directory:\nsrc/ (2 matches)\n  0: src/refund.rs (rust)\n  1: src/retry.rs (rust)".to_string(),
+            groups: vec![PathGroup {
+                directory: "src".to_string(),
+                matches: 2,
+                paths,
+            }],
+        }));
+        exchange.digest_last_step(0);
+
+        let chunks = vec![chunk("src/refund.rs", 0, 10), chunk("src/retry.rs", 1, 1)];
+        exchange.code_chunks.extend(chunks.clone());
+        exchange.apply_update(Update::StartStep(SearchStep::Code {
+            id: Some("call_2".to_string()),
+            query: QUERIES[1].to_string(),
+            response: chunks.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("\n\n"),
+        }));
+        exchange.digest_last_step(0);
+
+        let proc_chunk = chunk("src/retry.rs", 1, 60);
+        exchange.code_chunks.push(proc_chunk.clone());
+        exchange.apply_update(Update::StartStep(SearchStep::Proc {
+            id: Some("call_3".to_string()),
+            query: QUERIES[2].to_string(),
+            paths: vec!["src/retry.rs".to_string()],
+            requested: vec![PathRef::Alias(1)],
+            response: proc_chunk.to_string(),
+        }));
+        exchange.digest_last_step(2);
+        exchange
+    }
+
+    #[test]
+    fn test_digest_history_is_smaller_and_keeps_the_queries() {
+        let exchanges = vec![scripted_exchange()];
+        let full = build_history(&exchanges, HistoryMode::Full).unwrap();
+        let digest = build_history(&exchanges, HistoryMode::Digest).unwrap();
+
+        assert_eq!(full.len(), digest.len());
+        let (full_tokens, digest_tokens) = (history_tokens(&full), history_tokens(&digest));
+        assert!(
+            digest_tokens * 2 < full_tokens,
+            "{} digest tokens against {} full tokens",
+            digest_tokens,
+            full_tokens
+        );
+
+        let prompt = digest.iter().map(|m| m.to_string()).collect::<Vec<_>>().join("\n");
+        for query in QUERIES {
+            assert!(prompt.contains(query), "{} is missing from the prompt", query);
+        }
+        // the digests repeat the queries of their calls.
+        for query in &QUERIES[..2] {
+            assert!(prompt.contains(&format!("query: {}\n", query)));
+        }
+        assert_eq!(
+            exchanges[0].step_digests[1].render(),
+            "[summary of an earlier code call]\nquery: retry failed refunds\n\
+             paths: src/refund.rs:10-50, src/retry.rs:1-41\nresult: 2 chunks in 2 files"
+        );
+        // the most recent step keeps its full return.
+        assert_eq!(digest.last(), full.last());
+    }
+
+    #[test]
+    fn test_large_code_return_is_sent_a_page_at_a_time() {
+        let mut exchange = Exchange::new(
+            "exchange_1".to_string(),
+            "How are failed refunds retried?".to_string(),
+        );
+        let mut chunks = vec![
+            chunk("src/refund.rs", 0, 10),
+            chunk("src/retry.rs", 1, 1),
+            chunk("src/queue.rs", 2, 5),
+        ];
+        chunks[0].score = Some(0.2);
+        chunks[1].score = Some(0.9);
+        exchange.code_chunks.extend(chunks.clone());
+        let response = chunks.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("\n\n");
+        exchange.apply_update(Update::StartStep(SearchStep::Code {
+            id: Some("call_1".to_string()),
+            query: QUERIES[1].to_string(),
+            response: response.clone(),
+        }));
+        exchange.digest_last_step(0);
+        // room for a single chunk per page.
+        let max_tokens = count_tokens(&chunks[0].to_string()) + 64;
+        exchange.page_last_step(0, max_tokens);
+
+        let paged = exchange.paged_responses[0].clone();
+        assert_eq!(paged.pages, 3);
+        assert!(count_tokens(&paged.first_page) <= max_tokens);
+        // the best scored chunk comes first, the step keeps the full return for the traces.
+        assert!(paged.first_page.starts_with(&chunks[1].to_string()));
+        assert_eq!(exchange.search_steps[0].get_response(), response);
+        let history = build_history(&[exchange.clone()], HistoryMode::Full).unwrap();
+        assert_eq!(
+            history.last(),
+            Some(&message::Message::function_return(
+                Some("call_1".to_string()),
+                "code",
+                &paged.first_page
+            ))
+        );
+
+        let (query, page, content) = exchange.next_page().unwrap();
+        assert_eq!((query.as_str(), page), (QUERIES[1], 2));
+        assert!(content.starts_with(&chunks[0].to_string()));
+        exchange.apply_update(Update::StartStep(SearchStep::MoreResults {
+            id: Some("call_2".to_string()),
+            query,
+            page,
+            response: content.clone(),
+        }));
+        exchange.digest_last_step(3);
+        let history = build_history(&[exchange.clone()], HistoryMode::Digest).unwrap();
+        assert_eq!(
+            history.last(),
+            Some(&message::Message::function_return(
+                Some("call_2".to_string()),
+                MORE_RESULTS,
+                &content
+            ))
+        );
+
+        // the last page is dropped once the model calls another function.
+        assert!(exchange.has_pending_pages());
+        exchange.drop_pending_pages();
+        assert_eq!(exchange.next_page(), None);
+    }
+
+    #[test]
+    fn test_clarifying_question_stops_and_resumes_the_run() {
+        let call = FunctionCall {
+            name: ASK_USER.to_string(),
+            arguments: r#"{"question": "Which retries do you mean?", "options": ["webhook deliveries", "payment refunds"]}"#
+                .to_string(),
+        };
+        let Action::AskUser { question, options } = Action::deserialize_gpt(&call).unwrap() else {
+            panic!("ask_user wasn't read as a clarifying question");
+        };
+
+        let mut exchange = scripted_exchange();
+        exchange.ask_user(Some("call_4".to_string()), Clarification::new(&question, &options));
+        assert_eq!(
+            exchange.outcome(),
+            AnswerOutcome::NeedsClarification {
+                question: "Which retries do you mean?".to_string(),
+                options: options.clone(),
+                suspended: true,
+            }
+        );
+        // the question is kept with the exchanges the run resumes from.
+        let saved: Vec<Exchange> =
+            serde_json::from_str(&serde_json::to_string(&[exchange.clone()]).unwrap()).unwrap();
+        assert_eq!(saved[0].clarification, exchange.clarification);
+        assert!(!prompts::functions(true, false, false, false, false)
+            .to_string()
+            .contains(ASK_USER));
+
+        assert!(exchange.respond_to_clarification("the payment refunds"));
+        assert!(!exchange.respond_to_clarification("the webhook deliveries"));
+        assert_eq!(exchange.pending_clarification(), None);
+        let history = build_history(&[exchange], HistoryMode::Full).unwrap();
+        // the query, three steps of two messages, the question and the response of the user.
+        assert_eq!(history.len(), 10);
+        assert_eq!(
+            history[7],
+            message::Message::function_call(
+                Some("call_4".to_string()),
+                &FunctionCall {
+                    name: ASK_USER.to_string(),
+                    arguments: serde_json::json!({
+                        "question": "Which retries do you mean?",
+                        "options": ["webhook deliveries", "payment refunds"],
+                    })
+                    .to_string(),
+                }
+            )
+        );
+        assert_eq!(
+            history[9],
+            message::Message::user("To clarify \"Which retries do you mean?\": the payment refunds")
+        );
+    }
+
+    #[test]
+    fn test_paths_only_index_is_searched_by_keywords() {
+        let names = |keyword_search: bool| {
+            serde_json::from_value::<Vec<Function>>(prompts::functions(
+                true,
+                false,
+                false,
+                false,
+                keyword_search,
+            ))
+            .unwrap()
+            .into_iter()
+            .map(|function| (function.name, function.description))
+            .collect::<Vec<_>>()
+        };
+
+        let full = names(false);
+        assert!(full.iter().any(|(name, _)| name == "symbol"));
+        assert!(full[0].1.contains("semantically"));
+        // the code search matches the keywords, there are no symbols to look up.
+        let paths_only = names(true);
+        assert_eq!(
+            paths_only
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["code", "path", "none", "proc"]
+        );
+        assert!(paths_only[0].1.contains("keywords"));
+    }
+
+    #[test]
+    fn test_key_files_start_the_system_prompt() {
+        let key_files = vec!["src/main.rs".to_string(), "src/routes.rs".to_string()];
+        let prompt = system_prompt(["src/orders.rs"], &[], &key_files);
+        assert!(prompt.starts_with(
+            "## KNOWN IMPORTANT FILES ##\nThe entrypoints, routes and config of the repo"
+        ));
+        assert!(prompt.contains("\nsrc/main.rs\nsrc/routes.rs\n\n## PATHS ##\nindex, path\n0, src/orders.rs"));
+
+        let prompt = system_prompt(["src/orders.rs"], &[], &[]);
+        assert!(!prompt.contains("KNOWN IMPORTANT FILES"));
+    }
 
     #[test]
     fn test_trimming_history() {
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::agent::agent::Action;
+
+/// Redirections of repeated calls before the agent gives up on searching and answers
+/// with the context it has.
+pub const MAX_REPEAT_REDIRECTIONS: usize = 2;
+
+// Length of the previous result digest in the redirection message.
+const DIGEST_MAX_CHARS: usize = 160;
+
+// Returned for an `ask_user` call once the user can't be asked.
+const ASK_USER_CLOSED: &str = "The user can't be asked a clarifying question for this query anymore. Search further, or answer with the code you found and say which part of the codebase the answer is about.";
+
+/// What to do with the function call the model picked.
+#[derive(Debug, PartialEq)]
+pub enum CallCheck {
+    /// The call wasn't made for this query yet.
+    Run,
+    /// The call was already made with identical arguments, the message is returned to the model
+    /// instead of running it again.
+    Redirect(String),
+    /// The model kept repeating itself, answer with the context gathered so far.
+    Answer,
+}
+
+/// The function calls made for the current query, keyed by their signature, with a digest of
+/// their result.
+#[derive(Debug, Default)]
+pub struct CallLog {
+    calls: HashMap<String, String>,
+    redirections: usize,
+    // the user was asked a clarifying question for the query, or can't be asked one.
+    ask_user_closed: bool,
+}
+
+impl CallLog {
+    /// Records a call the agent ran and its result.
+    pub fn record(&mut self, action: &Action, response: &str) {
+        if let Some(signature) = call_signature(action) {
+            self.calls.insert(signature, digest(response));
+        }
+    }
+
+    /// Closes `ask_user` for the query, the user is asked once at most.
+    pub fn close_ask_user(&mut self) {
+        self.ask_user_closed = true;
+    }
+
+    /// Whether the model can still ask the user a clarifying question.
+    pub fn can_ask_user(&self) -> bool {
+        !self.ask_user_closed
+    }
+
+    /// Checks the next call the model picked against the calls already made.
+    pub fn check(&mut self, action: &Action) -> CallCheck {
+        if matches!(action, Action::AskUser { .. }) && self.ask_user_closed {
+            return self.redirect(ASK_USER_CLOSED.to_string());
+        }
+        let previous = match call_signature(action).and_then(|s| self.calls.get(&s)) {
+            Some(previous) => previous.clone(),
+            None => return CallCheck::Run,
+        };
+        self.redirect(format!(
+            "This function was already called with identical arguments, calling it again returns the same result: {}\nCall a different function or change the arguments, or answer with the code you found.",
+            previous
+        ))
+    }
+
+    // The redirections of repeated and closed calls count together.
+    fn redirect(&mut self, message: String) -> CallCheck {
+        if self.redirections >= MAX_REPEAT_REDIRECTIONS {
+            return CallCheck::Answer;
+        }
+        self.redirections += 1;
+        CallCheck::Redirect(message)
+    }
+
+    pub fn redirections(&self) -> usize {
+        self.redirections
+    }
+}
+
+/// The name and arguments of the call, with the object keys sorted and the whitespace of the
+/// strings collapsed so that calls differing only in formatting are identical.
+/// None for the query, answer and `ask_user` actions, they aren't searches, and for
+/// `more_results` whose repeated calls each read another page.
+pub fn call_signature(action: &Action) -> Option<String> {
+    if matches!(
+        action,
+        Action::Query(_) | Action::Answer { .. } | Action::AskUser { .. } | Action::MoreResults {}
+    ) {
+        return None;
+    }
+    let value = serde_json::to_value(action).ok()?;
+    Some(canonical(&value))
+}
+
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut entries = map
+                .iter()
+                // an absent optional argument is the same call as a null one.
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| format!("{}:{}", Value::String(k.clone()), canonical(v)))
+                .collect::<Vec<_>>();
+            entries.sort();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::Array(values) => format!(
+            "[{}]",
+            values.iter().map(canonical).collect::<Vec<_>>().join(",")
+        ),
+        Value::String(s) => {
+            Value::String(s.split_whitespace().collect::<Vec<_>>().join(" ")).to_string()
+        }
+        other => other.to_string(),
+    }
+}
+
+// The first non-empty line of the result, with the number of lines it had.
+fn digest(response: &str) -> String {
+    let lines = response.lines().filter(|l| !l.trim().is_empty()).count();
+    let first = response
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("(empty result)");
+    let mut digest = first.chars().take(DIGEST_MAX_CHARS).collect::<String>();
+    if first.chars().count() > DIGEST_MAX_CHARS {
+        digest.push('…');
+    }
+    if lines > 1 {
+        digest.push_str(&format!(" ({} lines)", lines));
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ai_gateway::function_calling::FunctionCall;
+
+    // The calls a model stuck on the same search returns, in order.
+    fn scripted_calls() -> Vec<Action> {
+        [
+            ("code", r#"{"query": "retry payment"}"#),
+            ("code", r#"{ "query":"retry   payment " }"#),
+            ("symbol", r#"{"name": "retry", "kind": null}"#),
+            ("code", "{\n \"query\": \"retry\\tpayment\"\n}"),
+            ("code", r#"{"query": "retry payment"}"#),
+        ]
+        .into_iter()
+        .map(|(name, arguments)| {
+            Action::deserialize_gpt(&FunctionCall {
+                name: name.to_owned(),
+                arguments: arguments.to_owned(),
+            })
+            .unwrap()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_signature_ignores_whitespace_and_argument_order() {
+        let calls = [
+            r#"{"name": "retry", "container": "PaymentService"}"#,
+            r#"{"container":"PaymentService",  "name":" retry", "kind": null}"#,
+        ]
+        .map(|arguments| {
+            let action = Action::deserialize_gpt(&FunctionCall {
+                name: "symbol".to_owned(),
+                arguments: arguments.to_owned(),
+            })
+            .unwrap();
+            call_signature(&action)
+        });
+        assert!(calls[0].is_some());
+        assert_eq!(calls[0], calls[1]);
+
+        assert_eq!(call_signature(&Action::Answer { paths: vec![0] }), None);
+    }
+
+    #[test]
+    fn test_repeated_calls_are_redirected_then_answered() {
+        let mut log = CallLog::default();
+        let mut ran = Vec::new();
+        let mut answered = false;
+        for action in scripted_calls() {
+            match log.check(&action) {
+                CallCheck::Run => {
+                    log.record(&action, "src/payment.rs\nfn retry(&self)\n");
+                    ran.push(action.name());
+                }
+                CallCheck::Redirect(message) => {
+                    assert!(message.contains("already called with identical arguments"));
+                    assert!(message.contains("src/payment.rs (2 lines)"));
+                }
+                CallCheck::Answer => {
+                    answered = true;
+                    break;
+                }
+            }
+        }
+        assert_eq!(ran, vec!["code", "symbol"]);
+        assert_eq!(log.redirections(), MAX_REPEAT_REDIRECTIONS);
+        assert!(answered);
+    }
+
+    #[test]
+    fn test_user_is_asked_once() {
+        let ask = Action::deserialize_gpt(&FunctionCall {
+            name: "ask_user".to_owned(),
+            arguments: r#"{"question": "Which retries?", "options": ["webhooks", "refunds"]}"#
+                .to_owned(),
+        })
+        .unwrap();
+        let mut log = CallLog::default();
+        assert!(log.can_ask_user());
+        assert_eq!(call_signature(&ask), None);
+        assert_eq!(log.check(&ask), CallCheck::Run);
+
+        log.close_ask_user();
+        assert!(!log.can_ask_user());
+        match log.check(&ask) {
+            CallCheck::Redirect(message) => assert!(message.starts_with("The user can't be asked")),
+            check => panic!("ask_user wasn't redirected: {:?}", check),
+        }
+        assert_eq!(log.check(&ask), CallCheck::Redirect(ASK_USER_CLOSED.to_string()));
+        assert_eq!(log.check(&ask), CallCheck::Answer);
+    }
+
+    #[test]
+    fn test_digest_is_one_line() {
+        assert_eq!(digest(""), "(empty result)");
+        let long = format!("{}\nsecond", "a".repeat(300));
+        let digest = digest(&long);
+        assert!(!digest.contains('\n'));
+        assert!(digest.ends_with("… (2 lines)"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use common::metrics;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Recorded when an agent is dropped before it answered, e.g. when the client disconnected
+/// or a step returned early with an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancellationEvent {
+    pub query_id: String,
+    pub elapsed: Duration,
+    pub last_action: Option<&'static str>,
+    pub chunks_gathered: usize,
+}
+
+impl CancellationEvent {
+    pub fn record(&self) {
+        let last_action = self.last_action.unwrap_or("none");
+        tracing::warn!(
+            query_id = %self.query_id,
+            elapsed_ms = self.elapsed.as_millis() as u64,
+            last_action,
+            chunks_gathered = self.chunks_gathered,
+            "agent cancelled before answering"
+        );
+        metrics::record_agent_cancellation(last_action);
+    }
+}
+
+/// Tracks a single run of the agent.
+///
+/// Sub-tasks spawned through the run are tied to its cancellation token, dropping the run
+/// without completing it aborts them and records a `CancellationEvent`.
+pub struct AgentRun {
+    query_id: String,
+    started_at: Instant,
+    last_action: Option<&'static str>,
+    chunks_gathered: usize,
+    token: CancellationToken,
+    complete: bool,
+}
+
+impl AgentRun {
+    pub fn new(query_id: &str) -> Self {
+        Self {
+            query_id: query_id.to_string(),
+            started_at: Instant::now(),
+            last_action: None,
+            chunks_gathered: 0,
+            token: CancellationToken::new(),
+            complete: false,
+        }
+    }
+
+    pub fn set_last_action(&mut self, action: &'static str) {
+        self.last_action = Some(action);
+    }
+
+    pub fn set_chunks_gathered(&mut self, chunks_gathered: usize) {
+        self.chunks_gathered = chunks_gathered;
+    }
+
+    /// Marks the run as answered, dropping it no longer cancels anything.
+    pub fn complete(&mut self) {
+        self.complete = true;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Spawns a sub-task that is aborted when the run is cancelled, it then resolves to `None`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let token = self.token.child_token();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => None,
+                output = future => Some(output),
+            }
+        })
+    }
+
+    pub fn cancellation_event(&self) -> CancellationEvent {
+        CancellationEvent {
+            query_id: self.query_id.clone(),
+            elapsed: self.started_at.elapsed(),
+            last_action: self.last_action,
+            chunks_gathered: self.chunks_gathered,
+        }
+    }
+}
+
+impl Drop for AgentRun {
+    fn drop(&mut self) {
+        if !self.complete {
+            self.token.cancel();
+            self.cancellation_event().record();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_dropping_run_mid_flight_cancels_background_tasks() {
+        let cancellations = || {
+            metrics::AGENT_CANCELLATIONS
+                .with_label_values(&["proc"])
+                .get()
+        };
+        let before = cancellations();
+
+        let (search_tx, search_rx) = oneshot::channel();
+        // the request future owns the run the same way the controller owns the agent.
+        let request = tokio::spawn(async move {
+            let mut run = AgentRun::new("task_1");
+            run.set_last_action("proc");
+            run.set_chunks_gathered(3);
+
+            let event = run.cancellation_event();
+            assert_eq!(event.query_id, "task_1");
+            assert_eq!(event.last_action, Some("proc"));
+            assert_eq!(event.chunks_gathered, 3);
+
+            // a parallel search and an LLM call that never resolve.
+            let search = run.spawn(std::future::pending::<()>());
+            search_tx.send(search).unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let search = search_rx.await.unwrap();
+        request.abort();
+        assert!(request.await.unwrap_err().is_cancelled());
+
+        let search_result = tokio::time::timeout(Duration::from_secs(1), search)
+            .await
+            .expect("background search kept running after the run was dropped")
+            .unwrap();
+        assert_eq!(search_result, None);
+        assert!(cancellations() > before);
+    }
+
+    #[tokio::test]
+    async fn test_completed_run_keeps_tasks_running() {
+        let mut run = AgentRun::new("task_2");
+        let token = run.token.clone();
+        let task = run.spawn(async { 42 });
+        run.complete();
+        drop(run);
+
+        assert!(!token.is_cancelled());
+        assert_eq!(task.await.unwrap(), Some(42));
+    }
+}
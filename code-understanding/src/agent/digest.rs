@@ -0,0 +1,263 @@
+// Compact digests of the search steps, sent to the model in place of their function returns. In
+// the digest mode of the history every step but the most recent one is sent as its digest: the
+// tool, its query verbatim so the model doesn't repeat the call, the top paths it found with their
+// lines and a gist of the result. The full returns are still kept on the exchange for the traces.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::exchange::{CodeChunk, SearchStep};
+use super::tools::path::plural;
+
+pub const HISTORY_MODE_ENV: &str = "HISTORY_MODE";
+
+// paths listed in a digest, the model can read the others with `proc`.
+const DIGEST_PATHS: usize = 5;
+// longest gist taken from the first line of a response.
+const GIST_CHARS: usize = 160;
+
+/// How the function returns of the earlier steps are sent to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HistoryMode {
+    // every function return is sent as it is.
+    Full,
+    // every function return but the most recent one is sent as its digest.
+    #[default]
+    Digest,
+}
+
+impl FromStr for HistoryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(HistoryMode::Full),
+            "digest" => Ok(HistoryMode::Digest),
+            other => Err(format!(
+                "{} must be `full` or `digest`, got `{}`",
+                HISTORY_MODE_ENV, other
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DigestPath {
+    pub path: String,
+    // 1-based and inclusive, None for the paths listed without lines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines: Option<(usize, usize)>,
+}
+
+/// What the model is told of a step once a later step was taken.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StepDigest {
+    pub tool: String,
+    pub query: String,
+    pub paths: Vec<DigestPath>,
+    pub gist: String,
+}
+
+impl StepDigest {
+    /// The digest of a finished step, `chunks` are the code chunks the step added to the exchange.
+    /// Steps recorded before the digests were kept have none, their digest is read from the
+    /// response alone.
+    pub fn of(step: &SearchStep, chunks: &[CodeChunk]) -> Self {
+        let response = step.get_response();
+        let (tool, paths, gist) = match step {
+            SearchStep::Path { groups, .. } => (
+                "path",
+                groups
+                    .iter()
+                    .flat_map(|group| group.paths.iter())
+                    .map(|path| DigestPath {
+                        path: path.clone(),
+                        lines: None,
+                    })
+                    .collect(),
+                first_line(&response).unwrap_or_else(|| "no paths matched".to_string()),
+            ),
+            SearchStep::Code { .. } => (
+                "code",
+                chunk_paths(chunks),
+                chunks_gist(chunks).or_else(|| first_line(&response)).unwrap_or_else(|| "no code found".to_string()),
+            ),
+            SearchStep::Proc { paths, .. } => (
+                "proc",
+                match chunks.is_empty() {
+                    true => paths
+                        .iter()
+                        .map(|path| DigestPath {
+                            path: path.clone(),
+                            lines: None,
+                        })
+                        .collect(),
+                    false => chunk_paths(chunks),
+                },
+                chunks_gist(chunks).or_else(|| first_line(&response)).unwrap_or_else(|| "nothing relevant in the files".to_string()),
+            ),
+            SearchStep::Symbol { .. } => {
+                let occurrences = response
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .collect::<Vec<_>>();
+                (
+                    "symbol",
+                    occurrences
+                        .iter()
+                        .filter_map(|line| symbol_path(line))
+                        .collect(),
+                    match occurrences.len() {
+                        0 => "no occurrences".to_string(),
+                        count => plural(count, "occurrence"),
+                    },
+                )
+            }
+            SearchStep::History {
+                query,
+                start_line,
+                end_line,
+                ..
+            } => (
+                "history",
+                vec![DigestPath {
+                    path: query.clone(),
+                    lines: Some((*start_line, *end_line)),
+                }],
+                first_line(&response).unwrap_or_else(|| "no commits".to_string()),
+            ),
+            SearchStep::MoreResults { page, .. } => (
+                "more_results",
+                vec![],
+                match page {
+                    0 => "no more results".to_string(),
+                    page => format!("page {}", page),
+                },
+            ),
+        };
+
+        let mut top_paths: Vec<DigestPath> = Vec::new();
+        for path in paths {
+            if top_paths.len() == DIGEST_PATHS {
+                break;
+            }
+            if !top_paths.contains(&path) {
+                top_paths.push(path);
+            }
+        }
+        Self {
+            tool: tool.to_string(),
+            query: step.get_query(),
+            paths: top_paths,
+            gist,
+        }
+    }
+
+    /// The digest as the content of the function return it replaces.
+    pub fn render(&self) -> String {
+        let mut rendered = format!(
+            "[summary of an earlier {} call]\nquery: {}\n",
+            self.tool, self.query
+        );
+        if !self.paths.is_empty() {
+            let paths = self
+                .paths
+                .iter()
+                .map(|path| match path.lines {
+                    Some((start, end)) => format!("{}:{}-{}", path.path, start, end),
+                    None => path.path.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            rendered.push_str(&format!("paths: {}\n", paths));
+        }
+        rendered.push_str(&format!("result: {}", self.gist));
+        rendered
+    }
+}
+
+// the paths of the chunks with their lines, in the order the model saw them.
+fn chunk_paths(chunks: &[CodeChunk]) -> Vec<DigestPath> {
+    chunks
+        .iter()
+        .filter(|chunk| !chunk.is_empty())
+        .map(|chunk| DigestPath {
+            path: chunk.path.clone(),
+            lines: Some((chunk.start_line, chunk.end_line)),
+        })
+        .collect()
+}
+
+fn chunks_gist(chunks: &[CodeChunk]) -> Option<String> {
+    let chunks = chunks
+        .iter()
+        .filter(|chunk| !chunk.is_empty())
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        return None;
+    }
+    let mut files = chunks.iter().map(|chunk| &chunk.path).collect::<Vec<_>>();
+    files.sort();
+    files.dedup();
+    Some(format!(
+        "{} in {}",
+        plural(chunks.len(), "chunk"),
+        plural(files.len(), "file")
+    ))
+}
+
+fn first_line(response: &str) -> Option<String> {
+    let line = response.lines().map(str::trim).find(|line| !line.is_empty())?;
+    match line.char_indices().nth(GIST_CHARS) {
+        Some((end, _)) => Some(format!("{}...", &line[..end])),
+        None => Some(line.to_string()),
+    }
+}
+
+// the path and lines of an occurrence of the symbol response, `<alias>: <path>:<start>-<end> ...`.
+fn symbol_path(line: &str) -> Option<DigestPath> {
+    let (_, occurrence) = line.split_once(": ")?;
+    let location = occurrence.split_whitespace().next()?;
+    let lines = location.rsplit_once(':').and_then(|(path, lines)| {
+        let (start, end) = lines.split_once('-')?;
+        Some((path, (start.parse().ok()?, end.parse().ok()?)))
+    });
+    Some(match lines {
+        Some((path, lines)) => DigestPath {
+            path: path.to_string(),
+            lines: Some(lines),
+        },
+        None => DigestPath {
+            path: location.to_string(),
+            lines: None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_digest_keeps_the_locations() {
+        let step = SearchStep::Symbol {
+            id: None,
+            query: "retry".to_string(),
+            kind: Some("function".to_string()),
+            container: None,
+            case_sensitive: None,
+            response: "0: src/refund.rs:10-42 function_item PaymentService::retry\n\
+                       3: src/queue.rs function_item retry"
+                .to_string(),
+        };
+        let digest = StepDigest::of(&step, &[]);
+        assert_eq!(
+            digest.render(),
+            "[summary of an earlier symbol call]\nquery: retry\n\
+             paths: src/refund.rs:10-42, src/queue.rs\nresult: 2 occurrences"
+        );
+        assert_eq!("Full".parse::<HistoryMode>(), Ok(HistoryMode::Full));
+        assert!("compact".parse::<HistoryMode>().is_err());
+    }
+}
@@ -1,16 +1,35 @@
 use core::hash;
-use std::{fmt, mem};
+use std::mem;
 
 use chrono::prelude::{DateTime, Utc};
-use common::{task_graph::redis::establish_redis_connection, CodeContext};
+use common::{
+    attachments::AttachmentSection,
+    clarification::Clarification,
+    glossary::GlossaryEntry,
+    prompt_versions::{merge_prompt_versions, PromptVersion},
+    run_manifest::IndexMode,
+    task_graph::redis::establish_redis_connection,
+    terminology::QueryExpansion, verification::VerificationStep, AnswerOutcome, CodeContext,
+};
 
-use super::agent::Agent;
+use super::agent::{Agent, PathRef};
+use super::digest::StepDigest;
+use super::paging::PagedResponse;
+use super::tools::ask_user::ASK_USER;
+use super::tools::packing::PackingDecision;
+use super::tools::data_flow::FlowHop;
+use super::tools::related::RelatedUsage;
+use ai_gateway::function_calling::FunctionCall;
+use ai_gateway::message::message::Message;
+use ai_gateway::utils::count_tokens;
 use crate::{config::get_redis_url, redis};
 use crate::redis::Commands;
 
 use anyhow::{Result, anyhow};
 
 const EXCHANGES_HASH_KEY: &str = "exchange_code_understanding";
+// number of paths reported back when no answer could be found.
+const MAX_CLOSEST_PATHS: usize = 5;
 
 /// A continually updated conversation exchange.
 ///
@@ -23,6 +42,13 @@ pub struct Exchange {
     pub answer: Option<String>,
     pub answer_id: Option<String>,
     pub search_steps: Vec<SearchStep>,
+    // Digests of the finished search steps, in step order, sent to the model in place of their
+    // responses once a later step was taken.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub step_digests: Vec<StepDigest>,
+    // Responses of search steps too large for one message, sent to the model a page at a time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paged_responses: Vec<PagedResponse>,
     pub paths: Vec<String>,
     pub code_chunks: Vec<CodeChunk>,
 
@@ -44,6 +70,154 @@ pub struct Exchange {
 
     // Final context that was used to generate the conclusion
     pub final_context: Vec<CodeContext>,
+
+    // Paths the user pinned to the query, their chunks are part of `code_chunks`.
+    #[serde(default)]
+    pub pinned_paths: Vec<String>,
+    // Pinned paths that couldn't be found in the index.
+    #[serde(default)]
+    pub missing_pinned_paths: Vec<String>,
+
+    // Which code chunks were packed into the answer context and why.
+    #[serde(default)]
+    pub context_packing: Vec<PackingDecision>,
+
+    // Callers and callees of the functions found by the code search, with why they were added.
+    #[serde(default)]
+    pub related_usage: Vec<RelatedUsage>,
+
+    // The functions the value the query asks about goes through, see `tools::data_flow`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data_flow: Vec<FlowHop>,
+
+    // The jargon of the searches and the expansions code search added for it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub query_expansions: Vec<QueryExpansion>,
+
+    // Paths the code search demoted as test or vendored code.
+    #[serde(default)]
+    pub demoted_paths: Vec<String>,
+    // Test files the code search found, the only tests the verification steps may run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub test_paths: Vec<String>,
+
+    // The LLM calls that picked the steps and wrote the answer, with their prompts and responses.
+    #[serde(default)]
+    pub llm_calls: Vec<LlmCall>,
+    // What the answer prompt was built from, enough to build it again without the index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_trace: Option<AnswerTrace>,
+    // The grounded verification steps of the answer, once they were asked for. Kept so that the
+    // same request answered again doesn't ask for them twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Vec<VerificationStep>>,
+    // The prompt templates the LLM calls of the exchange were built from, at their versions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prompt_versions: Vec<PromptVersion>,
+    // The clarifying question the agent stopped to ask, with the response of the user once the
+    // run resumed. The user is asked once per exchange.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clarification: Option<AskedClarification>,
+}
+
+/// A clarifying question of the agent, with where it was asked among the search steps.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct AskedClarification {
+    // id of the `ask_user` function call.
+    pub id: Option<String>,
+    // search steps taken before the question, it follows them in the history.
+    pub after_step: usize,
+    #[serde(flatten)]
+    pub clarification: Clarification,
+}
+
+impl AskedClarification {
+    /// The `ask_user` call and its return in the history of the model, followed by the response
+    /// of the user as their message once there is one.
+    pub fn messages(&self) -> Vec<Message> {
+        let arguments = serde_json::json!({
+            "question": self.clarification.question,
+            "options": self.clarification.options,
+        });
+        let mut messages = vec![
+            Message::function_call(
+                self.id.clone(),
+                &FunctionCall {
+                    name: ASK_USER.to_owned(),
+                    arguments: arguments.to_string(),
+                },
+            ),
+            Message::function_return(
+                self.id.clone(),
+                ASK_USER,
+                "The question was sent to the user, their response follows.",
+            ),
+        ];
+        messages.extend(
+            self.clarification
+                .response_message()
+                .map(|response| Message::user(&response)),
+        );
+        messages
+    }
+}
+
+/// The stage of the agent an LLM call was made for.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LlmStage {
+    // picking the next function to call.
+    Step,
+    // writing the answer article.
+    Answer,
+    // writing the verification steps of the answer.
+    Verification,
+}
+
+/// An LLM call of the exchange, recorded so it can be replayed without the gateway.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct LlmCall {
+    pub stage: LlmStage,
+    pub messages: Vec<Message>,
+    pub response: Vec<Message>,
+    // prompt tokens saved by sending the earlier steps of the history as their digests.
+    #[serde(default)]
+    pub history_tokens_saved: usize,
+}
+
+/// The inputs of the answer prompt, recorded before the answer is generated.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct AnswerTrace {
+    pub repo_name: String,
+    // the model the context was packed for.
+    pub model: String,
+    // the aliases the model answered with, pinned paths included.
+    pub aliases: Vec<usize>,
+    // every path of the conversation, in alias order.
+    pub paths: Vec<String>,
+    // the code chunks of the aliases once merged and grown, the candidates of the packing.
+    pub candidates: Vec<CodeChunk>,
+    pub pinned_paths: Vec<String>,
+    pub related_usage: Vec<RelatedUsage>,
+    // the hops of the data flow, packed after the related usage.
+    #[serde(default)]
+    pub data_flow: Vec<FlowHop>,
+    pub preferences: Option<String>,
+    pub language: Option<String>,
+    pub demoted_only: bool,
+    // what the repo was indexed with, the answer of a paths-only index says it may be incomplete.
+    #[serde(default)]
+    pub index_mode: IndexMode,
+    // the queries and answers of the conversation sent after the prompt.
+    pub history: Vec<Message>,
+    // tokens kept free for the history and the answer.
+    pub reserved_tokens: usize,
+    // the sections of the documents attached to the conversation found for the query.
+    #[serde(default)]
+    pub attachments: Vec<AttachmentSection>,
+    // how the earlier answers of the conversation described the symbols the candidates mention.
+    #[serde(default)]
+    pub glossary: Vec<GlossaryEntry>,
 }
 
 impl Agent {
@@ -111,6 +285,9 @@ impl Exchange {
                 (Some(l @ SearchStep::Path { .. }), r @ SearchStep::Path { .. }) => *l = r,
                 (Some(l @ SearchStep::Code { .. }), r @ SearchStep::Code { .. }) => *l = r,
                 (Some(l @ SearchStep::Proc { .. }), r @ SearchStep::Proc { .. }) => *l = r,
+                (Some(l @ SearchStep::Symbol { .. }), r @ SearchStep::Symbol { .. }) => *l = r,
+                (Some(l @ SearchStep::History { .. }), r @ SearchStep::History { .. }) => *l = r,
+                (Some(l @ SearchStep::MoreResults { .. }), r @ SearchStep::MoreResults { .. }) => *l = r,
                 _ => panic!("Tried to replace a step that was not found"),
             },
             Update::Article(full_text) => {
@@ -126,6 +303,133 @@ impl Exchange {
             Update::Context(chunks) => {
                 self.final_context = chunks;
             }
+            Update::Packing(decisions) => {
+                self.context_packing = decisions;
+            }
+        }
+    }
+
+    /// Records the versions of the prompt templates an LLM call of the exchange was built from.
+    pub fn record_prompts(&mut self, templates: &[&str]) {
+        merge_prompt_versions(&mut self.prompt_versions, templates);
+    }
+
+    /// Records the digest of the last search step, `chunks_before` is the number of code chunks
+    /// the exchange had before the step ran.
+    pub fn digest_last_step(&mut self, chunks_before: usize) {
+        // the steps of an exchange resumed from before the digests were kept are caught up first.
+        while self.step_digests.len() + 1 < self.search_steps.len() {
+            let step = &self.search_steps[self.step_digests.len()];
+            self.step_digests.push(StepDigest::of(step, &[]));
+        }
+        if let Some(step) = self.search_steps.get(self.step_digests.len()) {
+            let chunks = self.code_chunks.get(chunks_before..).unwrap_or_default();
+            self.step_digests.push(StepDigest::of(step, chunks));
+        }
+    }
+
+    /// The digest of the search step at `index`.
+    pub fn step_digest(&self, index: usize) -> Option<StepDigest> {
+        match self.step_digests.get(index) {
+            Some(digest) => Some(digest.clone()),
+            None => self
+                .search_steps
+                .get(index)
+                .map(|step| StepDigest::of(step, &[])),
+        }
+    }
+
+    /// Splits the response of the last search step into pages when it is larger than
+    /// `max_tokens`. The code chunks the step added, from `chunks_before` on, are its results,
+    /// the most relevant ones go on the first page.
+    pub fn page_last_step(&mut self, chunks_before: usize, max_tokens: usize) {
+        let Some(step) = self.search_steps.len().checked_sub(1) else {
+            return;
+        };
+        if !matches!(
+            self.search_steps[step],
+            SearchStep::Code { .. } | SearchStep::Proc { .. }
+        ) {
+            return;
+        }
+        let response = self.search_steps[step].get_response();
+        if count_tokens(&response) <= max_tokens {
+            return;
+        }
+
+        let mut chunks = self
+            .code_chunks
+            .get(chunks_before..)
+            .unwrap_or_default()
+            .iter()
+            .filter(|chunk| !chunk.is_empty())
+            .collect::<Vec<_>>();
+        let rendered = chunks
+            .iter()
+            .map(|chunk| chunk.to_string())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        // the lines before the chunks, e.g. the paths `proc` couldn't read, stay in front.
+        let sections = match response.strip_suffix(&rendered) {
+            Some(preamble) if !rendered.is_empty() => {
+                chunks.sort_by(|a, b| {
+                    b.score
+                        .unwrap_or(f32::MIN)
+                        .total_cmp(&a.score.unwrap_or(f32::MIN))
+                });
+                Some(preamble.trim_end())
+                    .filter(|preamble| !preamble.is_empty())
+                    .map(str::to_string)
+                    .into_iter()
+                    .chain(chunks.iter().map(|chunk| chunk.to_string()))
+                    .collect::<Vec<_>>()
+            }
+            // a response not made of the chunks is cut at its lines.
+            _ => vec![response],
+        };
+        let paged = PagedResponse::new(step, &sections, max_tokens, count_tokens);
+        log::info!(
+            "Split the {} response of {} into {} pages",
+            self.search_steps[step].get_query(),
+            self.id,
+            paged.pages
+        );
+        self.paged_responses.push(paged);
+    }
+
+    /// Takes the next page of the last paged response, with the query of its step and its page
+    /// number. None when no page is left.
+    pub fn next_page(&mut self) -> Option<(String, usize, String)> {
+        let paged = self
+            .paged_responses
+            .iter_mut()
+            .rev()
+            .find(|paged| !paged.remaining.is_empty())?;
+        let number = paged.next_page_number();
+        let page = paged.remaining.remove(0);
+        let query = self.search_steps.get(paged.step)?.get_query();
+        Some((query, number, page))
+    }
+
+    pub fn has_pending_pages(&self) -> bool {
+        self.paged_responses
+            .iter()
+            .any(|paged| !paged.remaining.is_empty())
+    }
+
+    /// Drops the pages the model didn't read, once it called another function.
+    pub fn drop_pending_pages(&mut self) {
+        for paged in &mut self.paged_responses {
+            paged.remaining.clear();
+        }
+    }
+
+    /// The response of the search step at `index` as it is sent to the model, the first page of
+    /// a paged response.
+    pub fn sent_response(&self, index: usize) -> Option<String> {
+        match self.paged_responses.iter().find(|paged| paged.step == index) {
+            Some(paged) => Some(paged.first_page.clone()),
+            None => self.search_steps.get(index).map(SearchStep::get_response),
         }
     }
 
@@ -146,6 +450,74 @@ impl Exchange {
         }
     }
 
+    /// Records the clarifying question the agent stopped to ask, after the steps taken so far.
+    pub fn ask_user(&mut self, id: Option<String>, clarification: Clarification) {
+        self.clarification = Some(AskedClarification {
+            id,
+            after_step: self.search_steps.len(),
+            clarification,
+        });
+    }
+
+    /// The clarifying question the run stopped at, None once the user responded.
+    pub fn pending_clarification(&self) -> Option<&Clarification> {
+        self.clarification
+            .as_ref()
+            .map(|asked| &asked.clarification)
+            .filter(|clarification| clarification.is_pending())
+    }
+
+    /// Records the response of the user to the pending clarifying question, false when the run
+    /// isn't waiting for one.
+    pub fn respond_to_clarification(&mut self, response: &str) -> bool {
+        match &mut self.clarification {
+            Some(asked) if asked.clarification.is_pending() => {
+                asked.clarification.response = Some(response.trim().to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Classify the final state of this exchange into a typed outcome.
+    ///
+    /// A run stopped at a clarifying question waits for the user. When the model can't find
+    /// what it was looking for it answers with nothing but the `[^summary]` footnote. Without
+    /// any code chunks gathered that means nothing was found, with code chunks it means the
+    /// model needs more information from the user.
+    pub fn outcome(&self) -> AnswerOutcome {
+        if let Some(clarification) = self.pending_clarification() {
+            return AnswerOutcome::NeedsClarification {
+                question: clarification.question.clone(),
+                options: clarification.options.clone(),
+                suspended: true,
+            };
+        }
+        let article = self.answer.as_deref().unwrap_or_default();
+        if !is_footnote_only(article) {
+            return AnswerOutcome::Answered {
+                answer: article.to_owned(),
+                contexts: self.final_context.clone(),
+            };
+        }
+
+        if self.code_chunks.is_empty() {
+            AnswerOutcome::NotFound {
+                attempted_queries: self.search_steps.iter().map(|s| s.get_query()).collect(),
+                closest_paths: self.paths.iter().take(MAX_CLOSEST_PATHS).cloned().collect(),
+            }
+        } else {
+            AnswerOutcome::NeedsClarification {
+                question: self
+                    .conclusion
+                    .clone()
+                    .unwrap_or_else(|| strip_summary_footnote(article)),
+                options: Vec::new(),
+                suspended: false,
+            }
+        }
+    }
+
     /// Return a copy of this exchange, with all function call responses redacted.
     ///
     /// This is used to reduce the size of an exchange when we send it over the wire, by removing
@@ -155,6 +527,7 @@ impl Exchange {
         let mut ex = self.clone();
 
         ex.code_chunks.clear();
+        ex.paged_responses.clear();
         ex.paths.clear();
         ex.search_steps = mem::take(&mut ex.search_steps)
             .into_iter()
@@ -173,6 +546,9 @@ pub enum SearchStep {
         id: Option<String>,
         query: String,
         response: String,
+        // the directories listed in the response, in the order the model saw them.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        groups: Vec<PathGroup>,
     },
     Code {
         id: Option<String>,
@@ -183,6 +559,38 @@ pub enum SearchStep {
         id: Option<String>,
         query: String,
         paths: Vec<String>,
+        // the paths as the model passed them, aliases or paths.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        requested: Vec<PathRef>,
+        response: String,
+    },
+    Symbol {
+        id: Option<String>,
+        query: String,
+        #[serde(default)]
+        kind: Option<String>,
+        #[serde(default)]
+        container: Option<String>,
+        #[serde(default)]
+        case_sensitive: Option<bool>,
+        response: String,
+    },
+    History {
+        id: Option<String>,
+        // path of the blamed file.
+        query: String,
+        start_line: usize,
+        end_line: usize,
+        response: String,
+    },
+    // a page of the response of an earlier step, see `Exchange::page_last_step`.
+    #[serde(rename = "more_results")]
+    MoreResults {
+        id: Option<String>,
+        // query of the paged step.
+        query: String,
+        // 1-based, 0 when no page was left.
+        page: usize,
         response: String,
     },
     // Answer {
@@ -192,6 +600,15 @@ pub enum SearchStep {
     // },
 }
 
+/// A directory of the path tool response, with the paths shown for it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct PathGroup {
+    pub directory: String,
+    // every path of the directory that matched, shown or not.
+    pub matches: usize,
+    pub paths: Vec<String>,
+}
+
 impl SearchStep {
     /// Create a "compressed" clone of this step, by redacting all response data.
     ///
@@ -202,6 +619,7 @@ impl SearchStep {
                 id: id.clone(),
                 query: query.clone(),
                 response: "[hidden, compressed]".into(),
+                groups: Vec::new(),
             },
             Self::Code { id, query, .. } => Self::Code {
                 id: id.clone(),
@@ -209,11 +627,50 @@ impl SearchStep {
                 response: "[hidden, compressed]".into(),
             },
             Self::Proc {
-                id, query, paths, ..
+                id,
+                query,
+                paths,
+                requested,
+                ..
             } => Self::Proc {
                 id: id.clone(),
                 query: query.clone(),
                 paths: paths.clone(),
+                requested: requested.clone(),
+                response: "[hidden, compressed]".into(),
+            },
+            Self::Symbol {
+                id,
+                query,
+                kind,
+                container,
+                case_sensitive,
+                ..
+            } => Self::Symbol {
+                id: id.clone(),
+                query: query.clone(),
+                kind: kind.clone(),
+                container: container.clone(),
+                case_sensitive: *case_sensitive,
+                response: "[hidden, compressed]".into(),
+            },
+            Self::History {
+                id,
+                query,
+                start_line,
+                end_line,
+                ..
+            } => Self::History {
+                id: id.clone(),
+                query: query.clone(),
+                start_line: *start_line,
+                end_line: *end_line,
+                response: "[hidden, compressed]".into(),
+            },
+            Self::MoreResults { id, query, page, .. } => Self::MoreResults {
+                id: id.clone(),
+                query: query.clone(),
+                page: *page,
                 response: "[hidden, compressed]".into(),
             },
             // Self::Answer {
@@ -228,41 +685,49 @@ impl SearchStep {
         }
     }
 
+    pub fn get_query(&self) -> String {
+        match self {
+            Self::Path { query, .. } => query.clone(),
+            Self::Code { query, .. } => query.clone(),
+            Self::Proc { query, .. } => query.clone(),
+            Self::Symbol { query, .. } => query.clone(),
+            Self::History { query, .. } => query.clone(),
+            Self::MoreResults { query, .. } => query.clone(),
+        }
+    }
+
     pub fn get_response(&self) -> String {
         match self {
             Self::Path { response, .. } => response.clone(),
             Self::Code { response, .. } => response.clone(),
             Self::Proc { response, .. } => response.clone(),
+            Self::Symbol { response, .. } => response.clone(),
+            Self::History { response, .. } => response.clone(),
+            Self::MoreResults { response, .. } => response.clone(),
             //Self::Answer { response, .. } => response.clone(),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct CodeChunk {
-    pub path: String,
-    #[serde(rename = "alias")]
-    pub alias: usize,
-    #[serde(rename = "snippet")]
-    pub snippet: String,
-    #[serde(rename = "start")]
-    pub start_line: usize,
-    #[serde(rename = "end")]
-    pub end_line: usize,
+// Removes the `[^summary]` footnote definition and references from an article.
+fn strip_summary_footnote(article: &str) -> String {
+    article
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("[^summary]:"))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .replace("[^summary]", "")
+        .trim()
+        .to_owned()
 }
 
-impl CodeChunk {
-    /// Returns true if a code-chunk contains an empty snippet or a snippet with only whitespace
-    pub fn is_empty(&self) -> bool {
-        self.snippet.trim().is_empty()
-    }
+/// Returns true if the article has no content besides the `[^summary]` footnote.
+fn is_footnote_only(article: &str) -> bool {
+    strip_summary_footnote(article).is_empty()
 }
 
-impl fmt::Display for CodeChunk {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {}\n{}", self.alias, self.path, self.snippet)
-    }
-}
+/// The chunk shared with code search, `alias: path` over the snippet in the prompts.
+pub use common::models::CodeChunk;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
 pub struct FocusedChunk {
@@ -281,4 +746,79 @@ pub enum Update {
     Conclude(String),
     Focus(FocusedChunk),
     Context(Vec<CodeContext>),
+    Packing(Vec<PackingDecision>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange_with(article: &str, conclusion: &str, code_chunks: Vec<CodeChunk>) -> Exchange {
+        let mut exchange = Exchange::new("task_1".to_string(), "where is auth?".to_string());
+        exchange.apply_update(Update::StartStep(SearchStep::Code {
+            id: None,
+            query: "authentication middleware".to_string(),
+            response: "[]".to_string(),
+        }));
+        exchange.paths = vec!["src/routes.rs".to_string(), "src/main.rs".to_string()];
+        exchange.code_chunks = code_chunks;
+        exchange.apply_update(Update::Article(article.to_string()));
+        exchange.apply_update(Update::Conclude(conclusion.to_string()));
+        exchange
+    }
+
+    #[test]
+    fn test_footnote_only_detection() {
+        assert!(is_footnote_only(""));
+        assert!(is_footnote_only("[^summary]"));
+        assert!(is_footnote_only(
+            "\n[^summary]: I'm sorry, I couldn't find what you were looking for, could you provide more information?\n"
+        ));
+        assert!(!is_footnote_only(
+            "Auth is handled in `src/auth.rs`.\n\n[^summary]: Auth lives in the middleware."
+        ));
+    }
+
+    #[test]
+    fn test_outcome_not_found_without_code_chunks() {
+        let exchange = exchange_with(
+            "[^summary]: I'm sorry, I couldn't find what you were looking for.",
+            "I'm sorry, I couldn't find what you were looking for.",
+            vec![],
+        );
+
+        assert_eq!(
+            exchange.outcome(),
+            AnswerOutcome::NotFound {
+                attempted_queries: vec!["authentication middleware".to_string()],
+                closest_paths: vec!["src/routes.rs".to_string(), "src/main.rs".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_outcome_clarification_and_answered() {
+        let chunk = CodeChunk {
+            path: "src/routes.rs".to_string(),
+            alias: Some(0),
+            snippet: "fn login() {}".to_string(),
+            start_line: 1,
+            end_line: 1,
+            score: None,
+            ..Default::default()
+        };
+
+        let exchange = exchange_with("", "Which login flow do you mean?", vec![chunk.clone()]);
+        assert_eq!(
+            exchange.outcome(),
+            AnswerOutcome::NeedsClarification {
+                question: "Which login flow do you mean?".to_string(),
+                options: vec![],
+                suspended: false,
+            }
+        );
+
+        let exchange = exchange_with("Login is in `src/routes.rs`.", "Found it.", vec![chunk]);
+        assert!(matches!(exchange.outcome(), AnswerOutcome::Answered { .. }));
+    }
 }
@@ -1,10 +1,24 @@
 pub mod action;
 pub mod agent;
+pub mod call_log;
+pub mod cancellation;
+pub mod digest;
 pub mod exchange;
+pub mod paging;
+pub mod replay;
 pub mod transform;
 pub mod tools {
     pub mod answer;
+    pub mod ask_user;
+    pub mod data_flow;
     pub mod code;
+    pub mod more_results;
     pub mod path;
+    pub mod packing;
+    pub mod pinned;
     pub mod proc;
+    pub mod related;
+    pub mod symbol;
+    pub mod verification;
+    pub mod history;
 }
\ No newline at end of file
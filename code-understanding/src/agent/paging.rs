@@ -0,0 +1,215 @@
+// Function returns too large for a single message. Providers reject a message past their size
+// limit even when the context has room for it, so the return of a `code` or `proc` step past the
+// limit of the model is split into pages: the first one, with the most relevant results, is sent
+// in place of the return and the model reads the others with `more_results`. The pages left are
+// dropped once the model calls another function, the step keeps its full response for the traces.
+
+use serde::{Deserialize, Serialize};
+
+pub const MORE_RESULTS: &str = "more_results";
+
+// tokens kept free on every page for the continuation line.
+const CONTINUATION_TOKENS: usize = 32;
+
+/// The pages of the response of a step.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PagedResponse {
+    // index of the step in the search steps of the exchange.
+    pub step: usize,
+    pub pages: usize,
+    // sent to the model in place of the response of the step.
+    pub first_page: String,
+    // the pages the model didn't read yet, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remaining: Vec<String>,
+}
+
+impl PagedResponse {
+    /// Splits `sections`, most relevant first, into pages of at most `max_tokens` tokens. Every
+    /// page but the last one ends with a line telling the model how to read the next one.
+    pub fn new(
+        step: usize,
+        sections: &[String],
+        max_tokens: usize,
+        count_tokens: impl Fn(&str) -> usize,
+    ) -> Self {
+        let pages = paginate(
+            sections,
+            max_tokens.saturating_sub(CONTINUATION_TOKENS).max(1),
+            &count_tokens,
+        );
+        let mut rendered = Vec::with_capacity(pages.len());
+        for (index, page) in pages.iter().enumerate() {
+            let later = &pages[index + 1..];
+            let mut content = page.join("\n\n");
+            if !later.is_empty() {
+                content.push_str(&format!(
+                    "\n\n[{} more results on {} more pages, call functions.{} to read the next page]",
+                    later.iter().map(Vec::len).sum::<usize>(),
+                    later.len(),
+                    MORE_RESULTS
+                ));
+            }
+            rendered.push(content);
+        }
+        let mut rendered = rendered.into_iter();
+        Self {
+            step,
+            pages: pages.len(),
+            first_page: rendered.next().unwrap_or_default(),
+            remaining: rendered.collect(),
+        }
+    }
+
+    /// Page number of the next page, 1-based.
+    pub fn next_page_number(&self) -> usize {
+        self.pages - self.remaining.len() + 1
+    }
+}
+
+// Packs the sections in order into pages of at most `max_tokens`, a section too long for a page
+// of its own is cut at its lines, and lines too long for a page at their characters.
+fn paginate(
+    sections: &[String],
+    max_tokens: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+) -> Vec<Vec<String>> {
+    // the blank line between two sections.
+    let separator = count_tokens("\n\n");
+    let mut pages: Vec<Vec<String>> = Vec::new();
+    let mut page: Vec<String> = Vec::new();
+    let mut page_tokens = 0;
+
+    for section in sections {
+        for part in split_section(section, max_tokens, count_tokens) {
+            let tokens = count_tokens(&part);
+            if !page.is_empty() && page_tokens + separator + tokens > max_tokens {
+                pages.push(std::mem::take(&mut page));
+                page_tokens = 0;
+            }
+            if !page.is_empty() {
+                page_tokens += separator;
+            }
+            page_tokens += tokens;
+            page.push(part);
+        }
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+fn split_section(
+    section: &str,
+    max_tokens: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+) -> Vec<String> {
+    if count_tokens(section) <= max_tokens {
+        return vec![section.to_string()];
+    }
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    for line in section.lines() {
+        let mut rest = line;
+        loop {
+            if count_tokens(&join_line(&part, rest)) <= max_tokens {
+                part = join_line(&part, rest);
+                break;
+            }
+            // a line that fits a part of its own starts the next one, a longer one is cut.
+            if !part.is_empty() && count_tokens(rest) <= max_tokens {
+                parts.push(std::mem::take(&mut part));
+                continue;
+            }
+            let boundaries = rest
+                .char_indices()
+                .map(|(index, _)| index)
+                .skip(1)
+                .chain(std::iter::once(rest.len()))
+                .collect::<Vec<_>>();
+            let fits = boundaries
+                .partition_point(|end| count_tokens(&join_line(&part, &rest[..*end])) <= max_tokens);
+            let end = match (fits, part.is_empty()) {
+                (0, false) => {
+                    parts.push(std::mem::take(&mut part));
+                    continue;
+                }
+                // not even a character fits, it still goes on a part so the line shrinks.
+                (0, true) => boundaries[0],
+                (fits, _) => boundaries[fits - 1],
+            };
+            parts.push(join_line(&part, &rest[..end]));
+            part.clear();
+            rest = &rest[end..];
+            if rest.is_empty() {
+                break;
+            }
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+fn join_line(part: &str, line: &str) -> String {
+    match part.is_empty() {
+        true => line.to_string(),
+        false => format!("{}\n{}", part, line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a token per word, enough to check the limits.
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    #[test]
+    fn test_pages_stay_under_the_limit_and_keep_every_section() {
+        let sections = (0..10)
+            .map(|i| format!("{}: src/refund_{}.rs\n{}", i, i, "retry refund ".repeat(20)))
+            .collect::<Vec<_>>();
+        let paged = PagedResponse::new(3, &sections, 132, words);
+
+        assert_eq!(paged.pages, 5);
+        assert_eq!(paged.next_page_number(), 2);
+        let pages = std::iter::once(&paged.first_page)
+            .chain(paged.remaining.iter())
+            .collect::<Vec<_>>();
+        for page in &pages {
+            assert!(words(page) <= 132, "{} words", words(page));
+        }
+        // the most relevant sections are on the first page.
+        assert!(paged.first_page.starts_with("0: src/refund_0.rs\n"));
+        assert!(paged
+            .first_page
+            .ends_with("[8 more results on 4 more pages, call functions.more_results to read the next page]"));
+        assert!(!pages.last().unwrap().contains("more_results"));
+        for section in &sections {
+            assert_eq!(pages.iter().filter(|page| page.contains(section.as_str())).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_long_sections_and_lines_are_cut() {
+        let section = format!("0: src/minified.js\n{}", "a ".repeat(250));
+        let paged = PagedResponse::new(0, std::slice::from_ref(&section), 132, words);
+
+        assert_eq!(paged.pages, 3);
+        let text = std::iter::once(paged.first_page.clone())
+            .chain(paged.remaining.clone())
+            .map(|page| page.split("\n\n[").next().unwrap().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(words(&text), words(&section));
+
+        let paged = PagedResponse::new(0, &["fn refund() {}".to_string()], 132, words);
+        assert_eq!((paged.pages, paged.first_page.as_str()), (1, "fn refund() {}"));
+        assert!(paged.remaining.is_empty());
+    }
+}
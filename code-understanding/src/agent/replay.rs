@@ -0,0 +1,395 @@
+// Replay of a stored answer for debugging. The searches and the code they found are taken from
+// the trace of the exchange, only the answer is generated again: with the recorded response of
+// the model for a dry run that needs no network, or with another model through the gateway. The
+// answer prompt can be swapped for a template to see whether a prompt change fixes an answer.
+
+use std::collections::BTreeSet;
+use std::future::Future;
+
+use ai_gateway::config::AIGatewayConfig;
+use ai_gateway::message::message::Message;
+use ai_gateway::utils::count_tokens;
+use anyhow::{anyhow, Result};
+use common::ai_util::extract_single_plaintext_content;
+use common::CodeContext;
+use serde::{Deserialize, Serialize};
+
+use crate::agent::exchange::{Exchange, LlmStage};
+use crate::agent::tools::answer::{build_answer, BuiltAnswer};
+use crate::agent::tools::packing::PackingDecision;
+use crate::agent::transform;
+use crate::config::get_ai_gateway_config;
+
+/// What to change in the replay, the recorded answer is replayed as it was by default.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ReplayOptions {
+    /// Replaces the answer article prompt, `{context}` is replaced with the paths and code.
+    #[serde(default)]
+    pub answer_template: Option<String>,
+    /// Generates the answer with this model through the gateway instead of the recorded response.
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+/// Body of `POST /admin/replay`.
+#[derive(Deserialize, Debug)]
+pub struct ReplayRequest {
+    /// The query the exchanges were stored under, not read when `trace` is set.
+    #[serde(default)]
+    pub query_id: Option<String>,
+    /// Exchanges exported before, replayed instead of the stored ones.
+    #[serde(default)]
+    pub trace: Option<Vec<Exchange>>,
+    /// Index of the exchange replayed, the last one by default.
+    #[serde(default)]
+    pub exchange: Option<usize>,
+    #[serde(flatten)]
+    pub options: ReplayOptions,
+}
+
+/// An answer, the recorded one or its replay.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ReplayedAnswer {
+    pub prompt: String,
+    // the paths and code of the prompt.
+    pub context: String,
+    pub answer: String,
+    pub context_packing: Vec<PackingDecision>,
+    pub final_context: Vec<CodeContext>,
+    pub prompt_tokens: usize,
+    pub answer_tokens: usize,
+}
+
+/// The replay of an answer next to the recorded one, with what changed between them.
+#[derive(Serialize, Debug)]
+pub struct ReplayReport {
+    pub query: String,
+    pub original: ReplayedAnswer,
+    pub replayed: ReplayedAnswer,
+    // `instructions`, `context`, `packing` or `answer`, in that order.
+    pub changed_sections: Vec<String>,
+    pub added_citations: Vec<String>,
+    pub removed_citations: Vec<String>,
+    // packed chunks as `path#Lstart-Lend`.
+    pub added_chunks: Vec<String>,
+    pub removed_chunks: Vec<String>,
+    pub prompt_tokens_delta: i64,
+    pub answer_tokens_delta: i64,
+}
+
+/// Replays the answer of `exchange` with `options`.
+pub async fn replay_exchange(exchange: &Exchange, options: &ReplayOptions) -> Result<ReplayReport> {
+    let model = options.model.clone();
+    replay_with(exchange, options, |messages| async move {
+        let mut config = AIGatewayConfig::from_yaml(&get_ai_gateway_config())?;
+        if let Some(model) = model {
+            // a model without its client is served by the client of the configured one.
+            let model = match model.contains(':') {
+                true => model,
+                false => format!("{}:{}", config.model.client_name, model),
+            };
+            config.set_model(&model)?;
+        }
+        let response = config.use_llm(None, Some(messages), None).await?;
+        extract_single_plaintext_content(&response)
+    })
+    .await
+}
+
+// Replays with `llm` when a model is set, with the recorded response otherwise.
+async fn replay_with<L, Fut>(
+    exchange: &Exchange,
+    options: &ReplayOptions,
+    llm: L,
+) -> Result<ReplayReport>
+where
+    L: FnOnce(Vec<Message>) -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    let trace = exchange.answer_trace.as_ref().ok_or_else(|| {
+        anyhow!("The exchange has no answer trace, it was answered before answers were traced")
+    })?;
+    let recorded = exchange
+        .llm_calls
+        .iter()
+        .rev()
+        .find(|call| call.stage == LlmStage::Answer)
+        .ok_or_else(|| anyhow!("The exchange has no recorded answer call"))?;
+    let recorded_response = extract_single_plaintext_content(&recorded.response)?;
+
+    // the context of the original is built again, the trace only records the whole prompt.
+    let original_build = build_answer(trace, None)?;
+    let original = ReplayedAnswer {
+        prompt: system_prompt(&recorded.messages),
+        context: original_build.context,
+        answer: exchange.answer.clone().unwrap_or_default(),
+        context_packing: exchange.context_packing.clone(),
+        final_context: exchange.final_context.clone(),
+        prompt_tokens: messages_tokens(&recorded.messages),
+        answer_tokens: count_tokens(&recorded_response),
+    };
+
+    let built = build_answer(trace, options.answer_template.as_deref())?;
+    let response = match options.model {
+        Some(_) => llm(built.messages.clone()).await?,
+        None => recorded_response,
+    };
+    let replayed = replayed_answer(built, &response);
+
+    Ok(report(exchange.query.clone(), original, replayed))
+}
+
+fn replayed_answer(built: BuiltAnswer, response: &str) -> ReplayedAnswer {
+    let (answer, _) = transform::decode(response);
+    ReplayedAnswer {
+        prompt_tokens: messages_tokens(&built.messages),
+        answer_tokens: count_tokens(response),
+        prompt: built.prompt,
+        context: built.context,
+        answer,
+        context_packing: built.decisions,
+        final_context: built.final_context,
+    }
+}
+
+fn report(query: String, original: ReplayedAnswer, replayed: ReplayedAnswer) -> ReplayReport {
+    let instructions = |a: &ReplayedAnswer| a.prompt.replacen(&a.context, "", 1);
+    let changed_sections = [
+        (
+            "instructions",
+            instructions(&original) != instructions(&replayed),
+        ),
+        ("context", original.context != replayed.context),
+        (
+            "packing",
+            original.context_packing != replayed.context_packing,
+        ),
+        ("answer", original.answer != replayed.answer),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(section, _)| section.to_owned())
+    .collect();
+
+    let (added_citations, removed_citations) =
+        set_diff(citations(&original.answer), citations(&replayed.answer));
+    let (added_chunks, removed_chunks) = set_diff(
+        packed_chunks(&original.context_packing),
+        packed_chunks(&replayed.context_packing),
+    );
+    ReplayReport {
+        query,
+        changed_sections,
+        added_citations,
+        removed_citations,
+        added_chunks,
+        removed_chunks,
+        prompt_tokens_delta: replayed.prompt_tokens as i64 - original.prompt_tokens as i64,
+        answer_tokens_delta: replayed.answer_tokens as i64 - original.answer_tokens as i64,
+        original,
+        replayed,
+    }
+}
+
+fn system_prompt(messages: &[Message]) -> String {
+    match messages.first() {
+        Some(Message::PlainText { content, .. }) => content.clone(),
+        _ => String::new(),
+    }
+}
+
+fn messages_tokens(messages: &[Message]) -> usize {
+    messages.iter().map(|m| count_tokens(&m.to_string())).sum()
+}
+
+// The targets of the links of an answer that aren't urls, e.g. `src/foo.rs#L50-L54`.
+fn citations(answer: &str) -> BTreeSet<String> {
+    answer
+        .split("](")
+        .skip(1)
+        .filter_map(|rest| rest.split_once(')').map(|(target, _)| target.trim()))
+        .filter(|target| !target.is_empty() && !target.contains("://"))
+        .map(str::to_owned)
+        .collect()
+}
+
+fn packed_chunks(decisions: &[PackingDecision]) -> BTreeSet<String> {
+    decisions
+        .iter()
+        .filter(|d| d.included)
+        .map(|d| format!("{}#L{}-L{}", d.path, d.start_line, d.end_line))
+        .collect()
+}
+
+// What `replayed` has that `original` doesn't, and the other way around.
+fn set_diff(original: BTreeSet<String>, replayed: BTreeSet<String>) -> (Vec<String>, Vec<String>) {
+    (
+        replayed.difference(&original).cloned().collect(),
+        original.difference(&replayed).cloned().collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::exchange::{AnswerTrace, CodeChunk, LlmCall, Update};
+    use crate::agent::tools::packing::PackingReason;
+    use common::run_manifest::IndexMode;
+
+    const RECORDED_RESPONSE: &str =
+        "Failed charges are retried by [`retry_charge`](src/billing/retry.rs#L3-L6), \
+with the delays of [`BACKOFF`](src/billing/policy.rs#L1).\n\n\
+[^summary]: Charges are retried with a fixed backoff.";
+
+    fn chunk(path: &str, alias: usize, snippet: &str, score: f32) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            alias: Some(alias),
+            snippet: snippet.to_string(),
+            start_line: 0,
+            end_line: snippet.lines().count(),
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    // the trace of an answer on a small billing service, recorded like the agent records it.
+    fn fixture_exchange() -> Exchange {
+        let trace = AnswerTrace {
+            repo_name: "acme/billing".to_string(),
+            model: "gpt-4-0613".to_string(),
+            aliases: vec![0, 1],
+            paths: vec![
+                "src/billing/retry.rs".to_string(),
+                "src/billing/policy.rs".to_string(),
+            ],
+            candidates: vec![
+                chunk(
+                    "src/billing/retry.rs",
+                    0,
+                    "use crate::policy::BACKOFF;\n\npub fn retry_charge(charge: &Charge) {\n    for delay in BACKOFF {\n        charge.attempt(delay);\n    }\n}",
+                    0.82,
+                ),
+                chunk(
+                    "src/billing/policy.rs",
+                    1,
+                    "pub const BACKOFF: [u64; 3] = [1, 5, 30];",
+                    0.61,
+                ),
+            ],
+            pinned_paths: Vec::new(),
+            related_usage: Vec::new(),
+            data_flow: Vec::new(),
+            preferences: None,
+            language: None,
+            demoted_only: false,
+            index_mode: IndexMode::Full,
+            history: vec![Message::user("How are failed charges retried?")],
+            reserved_tokens: 1024,
+            attachments: Vec::new(),
+            glossary: Vec::new(),
+        };
+        let built = build_answer(&trace, None).unwrap();
+
+        let mut exchange = Exchange::new(
+            "query_1".to_string(),
+            "How are failed charges retried?".to_string(),
+        );
+        exchange.apply_update(Update::Packing(built.decisions));
+        exchange.apply_update(Update::Context(built.final_context));
+        exchange.apply_update(Update::Article(transform::decode(RECORDED_RESPONSE).0));
+        exchange.llm_calls.push(LlmCall {
+            stage: LlmStage::Answer,
+            messages: built.messages,
+            response: vec![Message::assistant(RECORDED_RESPONSE)],
+            history_tokens_saved: 0,
+        });
+        exchange.answer_trace = Some(trace);
+        exchange
+    }
+
+    // the recorded responses only, no network.
+    async fn offline(_: Vec<Message>) -> Result<String> {
+        Err(anyhow!("the replay called the model"))
+    }
+
+    #[tokio::test]
+    async fn test_recorded_replay_is_identical() {
+        let exchange = fixture_exchange();
+        let report = replay_with(&exchange, &ReplayOptions::default(), offline)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            serde_json::to_string(&report.replayed).unwrap(),
+            serde_json::to_string(&report.original).unwrap()
+        );
+        assert!(report.changed_sections.is_empty());
+        assert!(report.added_citations.is_empty() && report.removed_citations.is_empty());
+        assert!(report.added_chunks.is_empty() && report.removed_chunks.is_empty());
+        assert_eq!(
+            (report.prompt_tokens_delta, report.answer_tokens_delta),
+            (0, 0)
+        );
+        assert!(report
+            .original
+            .context_packing
+            .iter()
+            .all(|d| d.included && d.reason == PackingReason::BestOfPath));
+    }
+
+    #[tokio::test]
+    async fn test_replay_with_another_prompt_only_changes_the_instructions() {
+        let exchange = fixture_exchange();
+        let options = ReplayOptions {
+            answer_template: Some(
+                "{context}\nAnswer in two sentences, link every symbol.".to_string(),
+            ),
+            model: None,
+        };
+        let report = replay_with(&exchange, &options, offline).await.unwrap();
+
+        assert_eq!(report.changed_sections, vec!["instructions".to_string()]);
+        assert_eq!(report.replayed.context, report.original.context);
+        assert!(report
+            .replayed
+            .prompt
+            .ends_with("Answer in two sentences, link every symbol."));
+        assert!(report.prompt_tokens_delta < 0);
+        assert_eq!(report.answer_tokens_delta, 0);
+        assert!(report.added_citations.is_empty() && report.removed_citations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay_with_a_model_reports_the_changed_citations() {
+        let exchange = fixture_exchange();
+        let options = ReplayOptions {
+            answer_template: None,
+            model: Some("gpt-4o".to_string()),
+        };
+        let report = replay_with(&exchange, &options, |_| async {
+            Ok("Charges are retried in [`retry_charge`](src/billing/retry.rs#L3-L6).".to_string())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report.changed_sections, vec!["answer".to_string()]);
+        // the lines of the links are 0-based once decoded.
+        assert_eq!(
+            report.removed_citations,
+            vec!["src/billing/policy.rs#L0".to_string()]
+        );
+        assert!(report.added_citations.is_empty());
+        assert!(report.answer_tokens_delta < 0);
+    }
+
+    #[test]
+    fn test_citations_skip_urls() {
+        let answer =
+            "See [`a`](src/a.rs#L1-L2), [docs](https://example.com/a) and [`a`](src/a.rs#L1-L2).";
+        assert_eq!(
+            citations(answer).into_iter().collect::<Vec<_>>(),
+            vec!["src/a.rs#L1-L2".to_string()]
+        );
+    }
+}
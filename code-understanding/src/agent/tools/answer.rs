@@ -1,25 +1,35 @@
 use anyhow::{anyhow, Context, Result};
 use common::{
-    ai_util::{call_llm, extract_single_plaintext_content},
+    ai_util::{call_llm_metered, extract_single_plaintext_content},
+    attachments::AttachmentSection,
+    language::with_language,
+    preferences::with_preferences,
     prompts,
     task_graph::redis_config::get_redis_url,
     CodeContext,
 };
 use futures::StreamExt;
 use rand::{rngs::OsRng, seq::SliceRandom};
-use std::{collections::HashMap, mem, ops::Range};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    ops::Range,
+};
 use tracing::{debug, info, instrument, trace};
 
 use crate::{
     agent::{
-        exchange::{CodeChunk, FocusedChunk, Update},
+        exchange::{AnswerTrace, CodeChunk, Exchange, FocusedChunk, LlmCall, LlmStage, Update},
+        tools::packing::{pack_chunks, pack_data_flow, pack_related, PackingDecision},
         transform,
     },
     config::{get_ai_gateway_config, get_quickwit_url},
+    helpers::attachment_search::attachment_search,
     search,
 };
 
 use ai_gateway::message::message::{Message, MessageRole};
+use ai_gateway::utils::count_tokens;
 
 use crate::agent::agent::Agent;
 use crate::agent::agent::ANSWER_MODEL;
@@ -31,6 +41,15 @@ impl Agent {
 
         let search_db_url = get_quickwit_url();
         log::debug!(" Aliases from LLM: {:?}", aliases);
+
+        // the user pinned these files to the query, always keep them in the answer context.
+        let mut aliases = aliases.to_vec();
+        for alias in self.pinned_chunks().filter_map(|c| c.alias).collect::<Vec<_>>() {
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+        let aliases = aliases.as_slice();
         debug!("creating article response");
 
         if aliases.len() == 1 {
@@ -51,9 +70,22 @@ impl Agent {
             }))?;
         }
 
-        let context = self.answer_context(aliases, ANSWER_MODEL).await?;
-        let system_prompt = prompts::answer_article_prompt(aliases, &context);
-        let system_message = Message::system(&system_prompt);
+        let history = self.utter_history().collect::<Vec<_>>();
+        let history_tokens = history
+            .iter()
+            .map(|m| count_tokens(&m.to_string()))
+            .sum::<usize>();
+
+        let trace = self
+            .answer_trace(aliases, history, ANSWER_HEADROOM + history_tokens)
+            .await;
+        let built = build_answer(&trace, None)?;
+        self.update(Update::Packing(built.decisions.clone()))?;
+        self.update(Update::Context(built.final_context.clone()))?;
+        // recorded before the call, a failed answer can be replayed too.
+        let exchange = self.exchanges.last_mut().unwrap();
+        exchange.answer_trace = Some(trace);
+        exchange.record_prompts(&built.templates);
 
         // let history = {
         //     let h = self.utter_history().collect::<Vec<_>>();
@@ -61,17 +93,24 @@ impl Agent {
         //         tiktoken_rs::num_tokens_from_messages(ANSWER_MODEL, &[(&system_message).into()])?;
         //     trim_utter_history(h, ANSWER_HEADROOM + system_headroom)?
         // };
-        log::debug!("system answer prompt: {:?}", system_prompt);
-        let history = self.utter_history().collect::<Vec<_>>();
-
-        let messages = Some(system_message)
-            .into_iter()
-            .chain(history.iter().cloned())
-            .collect::<Vec<_>>();
+        log::debug!("system answer prompt: {:?}", built.prompt);
 
         //log::debug!("Answer message: {:?}", messages.clone());
 
-        let llm_output = call_llm(&get_ai_gateway_config(), None, Some(messages), None).await?;
+        let llm_output = call_llm_metered(
+            &get_ai_gateway_config(),
+            Some(&self.budget),
+            None,
+            Some(built.messages.clone()),
+            None,
+        )
+        .await?;
+        self.exchanges.last_mut().unwrap().llm_calls.push(LlmCall {
+            stage: LlmStage::Answer,
+            messages: built.messages,
+            response: llm_output.clone(),
+            history_tokens_saved: 0,
+        });
 
         let response_message = extract_single_plaintext_content(&llm_output)?;
         
@@ -107,107 +146,77 @@ impl Agent {
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    async fn answer_context(&mut self, aliases: &[usize], gpt_model: &str) -> Result<String> {
-        let paths = self.paths().collect::<Vec<_>>();
-
-        let mut s = "".to_owned();
-
-        let mut aliases = aliases
-            .iter()
-            .copied()
-            .filter(|alias| *alias < paths.len())
-            .collect::<Vec<_>>();
-
-        aliases.sort();
-        aliases.dedup();
-
-        debug!(?paths, ?aliases, "created filtered path alias list");
-
-        if !aliases.is_empty() {
-            s += "##### PATHS #####\n";
-
-            for alias in &aliases {
-                let path = &paths[*alias];
-                s += &format!("{path}\n");
-            }
-        }
-
-        let code_chunks = self.canonicalize_code_chunks(&aliases, gpt_model).await;
-
-        // Sometimes, there are just too many code chunks in the context, and deduplication still
-        // doesn't trim enough chunks. So, we enforce a hard limit here that stops adding tokens
-        // early if we reach a heuristic limit.
-        const PROMPT_HEADROOM: usize = 2500;
-        let bpe = tiktoken_rs::get_bpe_from_model(gpt_model)?;
-        let mut remaining_prompt_tokens = tiktoken_rs::get_completion_max_tokens(gpt_model, &s)?;
-
-        // Select as many recent chunks as possible
-        let mut recent_chunks = Vec::new();
-        for chunk in code_chunks.iter().rev() {
-            let snippet = chunk
-                .snippet
-                .lines()
-                .enumerate()
-                .map(|(i, line)| format!("{} {line}\n", i + chunk.start_line + 1))
-                .collect::<String>();
-
-            let formatted_snippet = format!("### {} ###\n{snippet}\n\n", chunk.path);
-
-            let snippet_tokens = bpe.encode_ordinary(&formatted_snippet).len();
-
-            if snippet_tokens >= remaining_prompt_tokens - PROMPT_HEADROOM {
-                info!("breaking at {} tokens", remaining_prompt_tokens);
-                break;
+    /// Gathers what the answer prompt is built from: the code chunks of the aliases merged and
+    /// grown, and the state of the conversation. `reserved_tokens` are kept free for the rest of
+    /// the conversation and the answer itself.
+    #[instrument(skip(self, history))]
+    async fn answer_trace(
+        &mut self,
+        aliases: &[usize],
+        history: Vec<Message>,
+        reserved_tokens: usize,
+    ) -> AnswerTrace {
+        let paths = self.paths().map(str::to_owned).collect::<Vec<_>>();
+        let context_aliases = context_aliases(aliases, paths.len());
+        debug!(?paths, ?context_aliases, "created filtered path alias list");
+
+        let candidates = self
+            .canonicalize_code_chunks(&context_aliases, ANSWER_MODEL)
+            .await;
+        let mut pinned_paths = Vec::new();
+        for chunk in candidates.iter().filter(|c| self.is_pinned(&c.path)) {
+            if !pinned_paths.contains(&chunk.path) {
+                pinned_paths.push(chunk.path.clone());
             }
-
-            recent_chunks.push((chunk.clone(), formatted_snippet));
-
-            remaining_prompt_tokens -= snippet_tokens;
-            debug!("{}", remaining_prompt_tokens);
         }
-
-        // Store the focused chunks to be passed on to the upstream
-        let final_focused_chunks: Vec<CodeContext> = recent_chunks
-            .iter()
-            .map(|(c, _)| CodeContext {
-                path: c.path.clone(),
-                hidden: false,
-                repo: self.repo_name.clone(),
-                branch: None,
-                ranges: vec![c.start_line..c.end_line],
-            })
-            .collect();
-
-        self.update(Update::Context(final_focused_chunks))?;
-
-        // group recent chunks by path alias
-        let mut recent_chunks_by_alias: HashMap<_, _> =
-            recent_chunks
-                .into_iter()
-                .fold(HashMap::new(), |mut map, item| {
-                    map.entry(item.0.alias).or_insert_with(Vec::new).push(item);
-                    map
-                });
-
-        // write the header if we have atleast one chunk
-        if !recent_chunks_by_alias.values().all(Vec::is_empty) {
-            s += "\n##### CODE CHUNKS #####\n\n";
+        let glossary = self
+            .glossary
+            .relevant(candidates.iter().map(|chunk| chunk.snippet.as_str()));
+
+        AnswerTrace {
+            repo_name: self.repo_name.clone(),
+            model: ANSWER_MODEL.to_owned(),
+            aliases: aliases.to_vec(),
+            paths,
+            candidates,
+            pinned_paths,
+            related_usage: self
+                .exchanges
+                .iter()
+                .flat_map(|e| e.related_usage.iter().cloned())
+                .collect(),
+            data_flow: self
+                .exchanges
+                .iter()
+                .flat_map(|e| e.data_flow.iter().cloned())
+                .collect(),
+            preferences: self.preferences.clone(),
+            language: self.language.clone(),
+            demoted_only: self.only_demoted_evidence(),
+            index_mode: self.index_mode,
+            history,
+            reserved_tokens,
+            attachments: self.attachment_sections().await,
+            glossary,
         }
+    }
 
-        // sort by alias, then sort by lines
-        let mut aliases = recent_chunks_by_alias.keys().copied().collect::<Vec<_>>();
-        aliases.sort();
-
-        for alias in aliases {
-            let chunks = recent_chunks_by_alias.get_mut(&alias).unwrap();
-            chunks.sort_by(|a, b| a.0.start_line.cmp(&b.0.start_line));
-            for (_, formatted_snippet) in chunks {
-                s += formatted_snippet;
+    /// The sections of the documents attached to the conversation closest to the query. The
+    /// answer is still written from the code when the search fails.
+    async fn attachment_sections(&self) -> Vec<AttachmentSection> {
+        let Some(conversation_id) = &self.attachments else {
+            return Vec::new();
+        };
+        match attachment_search(&self.get_query(), conversation_id).await {
+            Ok(hits) => hits.into_iter().map(|hit| hit.section).collect(),
+            Err(e) => {
+                log::warn!(
+                    "Failed to search the attachments of conversation {}: {}",
+                    conversation_id, e
+                );
+                Vec::new()
             }
         }
-
-        Ok(s)
     }
 
     /// History of `user`, `assistant` messages. These are the messages that are shown to the user.
@@ -219,28 +228,7 @@ impl Agent {
             .rev()
             .take(ANSWER_MAX_HISTORY_SIZE)
             .rev()
-            .flat_map(|e| {
-                let query = e.query().map(|q| Message::PlainText {
-                    role: MessageRole::User,
-                    content: q,
-                });
-
-                let conclusion = e.answer().map(|(id, answer, conclusion)| {
-                    let encoded =
-                        transform::encode_summarized(answer, Some(conclusion), "gpt-4-0613")
-                            .unwrap();
-
-                    Message::PlainText {
-                        role: MessageRole::Assistant,
-                        content: encoded,
-                    }
-                });
-
-                query
-                    .into_iter()
-                    .chain(conclusion.into_iter())
-                    .collect::<Vec<_>>()
-            })
+            .flat_map(utter_messages)
     }
 
     fn code_chunks(&self) -> impl Iterator<Item = CodeChunk> + '_ {
@@ -268,11 +256,34 @@ impl Agent {
 
         // Note: The end line number here is *not* inclusive.
         let mut spans_by_path = HashMap::<_, Vec<_>>::new();
-        for c in self.code_chunks().filter(|c| aliases.contains(&c.alias)) {
+        // retrieval scores of the original chunks, spans only grow and merge below,
+        // so a canonical chunk keeps the best score of the chunks it contains.
+        let mut scored_spans = Vec::new();
+        // same for the docs of the definitions in the chunks and their duplicates.
+        let mut doc_spans = Vec::new();
+        let mut duplicate_spans = Vec::new();
+        // and the embedded languages of the chunks, e.g. the SQL of a string.
+        let mut embedded_spans = Vec::new();
+        for c in self
+            .code_chunks()
+            .filter(|c| c.alias.is_some_and(|alias| aliases.contains(&alias)))
+        {
             spans_by_path
                 .entry(c.path.clone())
                 .or_default()
                 .push(c.start_line..c.end_line);
+            if let Some(score) = c.score {
+                scored_spans.push((c.path.clone(), c.start_line..c.end_line, score));
+            }
+            if let Some(doc) = &c.doc {
+                doc_spans.push((c.path.clone(), c.start_line..c.end_line, doc.clone()));
+            }
+            for duplicate in &c.duplicates {
+                duplicate_spans.push((c.path.clone(), c.start_line..c.end_line, duplicate.clone()));
+            }
+            if let Some(lang) = &c.embedded_lang {
+                embedded_spans.push((c.path.clone(), c.start_line..c.end_line, lang.clone()));
+            }
         }
 
         log::debug!("Spans by path: ");
@@ -401,20 +412,354 @@ impl Agent {
             .flat_map(|(path, spans)| spans.into_iter().map(move |s| (path.clone(), s)))
             .map(|(path, span)| {
                 let snippet = lines_by_file.get(&path).unwrap()[span.clone()].join("\n");
+                let score = scored_spans
+                    .iter()
+                    .filter(|(p, s, _)| *p == path && span.start <= s.start && s.end <= span.end)
+                    .map(|(_, _, score)| *score)
+                    .reduce(f32::max);
+                let mut docs = Vec::new();
+                for (_, _, doc) in doc_spans
+                    .iter()
+                    .filter(|(p, s, _)| *p == path && span.start <= s.start && s.end <= span.end)
+                {
+                    if !docs.contains(&doc.as_str()) {
+                        docs.push(doc.as_str());
+                    }
+                }
+                let doc = (!docs.is_empty()).then(|| docs.join("\n\n"));
+                let mut duplicates = Vec::new();
+                for (_, _, duplicate) in duplicate_spans
+                    .iter()
+                    .filter(|(p, s, _)| *p == path && span.start <= s.start && s.end <= span.end)
+                {
+                    if !duplicates.contains(duplicate) {
+                        duplicates.push(duplicate.clone());
+                    }
+                }
+
+                let embedded_lang = embedded_spans
+                    .iter()
+                    .find(|(p, s, _)| *p == path && span.start <= s.start && s.end <= span.end)
+                    .map(|(_, _, lang)| lang.clone());
 
                 CodeChunk {
-                    alias: self.get_path_alias(&path),
+                    alias: Some(self.get_path_alias(&path)),
                     path,
                     snippet,
                     start_line: span.start,
                     end_line: span.end,
+                    score,
+                    doc,
+                    duplicates,
+                    embedded_lang,
+                    ..Default::default()
                 }
             })
             .collect()
     }
 }
 
+impl Agent {
+    // Whether the code searched for the query is all test or vendored code that the search demoted,
+    // pinned code aside.
+    fn only_demoted_evidence(&self) -> bool {
+        let Some(exchange) = self.exchanges.last() else {
+            return false;
+        };
+        only_demoted_evidence(
+            exchange
+                .code_chunks
+                .iter()
+                .filter(|c| !c.is_empty() && !self.is_pinned(&c.path)),
+            &exchange.demoted_paths,
+        )
+    }
+}
+
+fn only_demoted_evidence<'a>(
+    mut chunks: impl Iterator<Item = &'a CodeChunk>,
+    demoted_paths: &[String],
+) -> bool {
+    let mut found = false;
+    let all_demoted = chunks.all(|chunk| {
+        found = true;
+        demoted_paths.contains(&chunk.path)
+    });
+    found && all_demoted
+}
+
+/// The answer prompt of a trace and how its context was packed.
+#[derive(Debug, Clone)]
+pub struct BuiltAnswer {
+    // the paths and code of the prompt.
+    pub context: String,
+    pub prompt: String,
+    pub decisions: Vec<PackingDecision>,
+    pub final_context: Vec<CodeContext>,
+    // the prompt followed by the history, as sent to the model.
+    pub messages: Vec<Message>,
+    // the templates of `prompts` the prompt was built from.
+    pub templates: Vec<&'static str>,
+}
+
+// The aliases of the context, sorted and without the ones out of the `paths_len` paths.
+fn context_aliases(aliases: &[usize], paths_len: usize) -> Vec<usize> {
+    let mut aliases = aliases
+        .iter()
+        .copied()
+        .filter(|alias| *alias < paths_len)
+        .collect::<Vec<_>>();
+    aliases.sort();
+    aliases.dedup();
+    aliases
+}
+
+// The query of the exchange, the clarifying question the user responded to and the answer.
+fn utter_messages(e: &Exchange) -> Vec<Message> {
+    let query = e.query().map(|q| Message::PlainText {
+        role: MessageRole::User,
+        content: q,
+    });
+
+    // the answer is written for what the user clarified.
+    let clarification = e.clarification.as_ref().and_then(|asked| {
+        let response = asked.clarification.response_message()?;
+        Some([
+            Message::assistant(&asked.clarification.render()),
+            Message::user(&response),
+        ])
+    });
+
+    let conclusion = e.answer().map(|(id, answer, conclusion)| {
+        let encoded =
+            transform::encode_summarized(answer, Some(conclusion), "gpt-4-0613").unwrap();
+
+        Message::PlainText {
+            role: MessageRole::Assistant,
+            content: encoded,
+        }
+    });
+
+    query
+        .into_iter()
+        .chain(clarification.into_iter().flatten())
+        .chain(conclusion.into_iter())
+        .collect::<Vec<_>>()
+}
+
+/// Builds the answer prompt of a trace. The code chunks are packed by retrieval score in what's
+/// left of the model's context window. `template` replaces the article prompt, see `answer_prompt`.
+/// Doesn't read the index, the same trace always gives the same prompt.
+pub fn build_answer(trace: &AnswerTrace, template: Option<&str>) -> Result<BuiltAnswer> {
+    let aliases = context_aliases(&trace.aliases, trace.paths.len());
+
+    let mut s = "".to_owned();
+    if !aliases.is_empty() {
+        s += "##### PATHS #####\n";
+
+        for alias in &aliases {
+            let path = &trace.paths[*alias];
+            s += &format!("{path}\n");
+        }
+    }
+    if !trace.attachments.is_empty() {
+        s += &prompts::attachments_prompt(&trace.attachments);
+    }
+    if !trace.glossary.is_empty() {
+        s += &prompts::glossary_prompt(&trace.glossary);
+    }
+
+    // The budget is whatever is left of the context window once the prompt scaffolding,
+    // the history and the expected output are accounted for.
+    let bpe = tiktoken_rs::get_bpe_from_model(&trace.model)?;
+    let scaffolding = answer_prompt(
+        &aliases,
+        &s,
+        &AnswerPromptOptions {
+            template,
+            preferences: trace.preferences.as_deref(),
+            language: trace.language.as_deref(),
+            demoted_only: trace.demoted_only,
+            paths_only: !trace.index_mode.is_full(),
+            data_flow: !trace.data_flow.is_empty(),
+        },
+    );
+    let scaffolding_tokens = bpe.encode_ordinary(&scaffolding).len();
+    let budget = tiktoken_rs::model::get_context_size(&trace.model)
+        .saturating_sub(scaffolding_tokens + trace.reserved_tokens);
+
+    let pinned_paths = trace.pinned_paths.iter().cloned().collect::<HashSet<_>>();
+    let (recent_chunks, mut decisions) =
+        pack_chunks(&trace.candidates, &pinned_paths, budget, |text| {
+            bpe.encode_ordinary(text).len()
+        });
+
+    // related usage only gets what the chunks left of the budget.
+    let used = decisions
+        .iter()
+        .filter(|d| d.included)
+        .map(|d| d.tokens)
+        .sum::<usize>();
+    let (related_chunks, related_decisions) = pack_related(
+        &trace.related_usage,
+        &recent_chunks,
+        budget.saturating_sub(used),
+        |text| bpe.encode_ordinary(text).len(),
+    );
+    decisions.extend(related_decisions);
+
+    // the data flow gets what the related usage left.
+    let used = decisions
+        .iter()
+        .filter(|d| d.included)
+        .map(|d| d.tokens)
+        .sum::<usize>();
+    let (flow_hops, flow_decisions) =
+        pack_data_flow(&trace.data_flow, budget.saturating_sub(used), |text| {
+            bpe.encode_ordinary(text).len()
+        });
+    decisions.extend(flow_decisions);
+    info!(
+        budget,
+        packed = recent_chunks.len(),
+        candidates = trace.candidates.len(),
+        related = related_chunks.len(),
+        data_flow = flow_hops.len(),
+        "packed answer context"
+    );
+    for decision in &decisions {
+        debug!(?decision, "context packing decision");
+    }
+
+    // Store the focused chunks to be passed on to the upstream
+    let final_context: Vec<CodeContext> = recent_chunks
+        .iter()
+        .map(|(c, _)| c)
+        .chain(flow_hops.iter().map(|(hop, _)| &hop.chunk))
+        .map(|c| CodeContext {
+            path: c.path.clone(),
+            hidden: false,
+            repo: trace.repo_name.clone(),
+            branch: None,
+            ranges: vec![c.start_line..c.end_line],
+            pinned: pinned_paths.contains(&c.path),
+            owners: Vec::new(),
+        })
+        .collect();
+
+    // group recent chunks by path alias
+    let mut recent_chunks_by_alias: HashMap<_, _> =
+        recent_chunks
+            .into_iter()
+            .fold(HashMap::new(), |mut map, item| {
+                map.entry(item.0.alias).or_insert_with(Vec::new).push(item);
+                map
+            });
+
+    // write the header if we have atleast one chunk
+    if !recent_chunks_by_alias.values().all(Vec::is_empty) {
+        s += "\n##### CODE CHUNKS #####\n\n";
+    }
+
+    // sort by alias, then sort by lines
+    let mut aliases = recent_chunks_by_alias.keys().copied().collect::<Vec<_>>();
+    aliases.sort();
+
+    for alias in aliases {
+        let chunks = recent_chunks_by_alias.get_mut(&alias).unwrap();
+        chunks.sort_by(|a, b| a.0.start_line.cmp(&b.0.start_line));
+        for (_, formatted_snippet) in chunks {
+            s += formatted_snippet;
+        }
+    }
+
+    if !related_chunks.is_empty() {
+        s += "\n##### RELATED USAGE #####\n\n";
+        for (_, formatted_snippet) in &related_chunks {
+            s += formatted_snippet;
+        }
+    }
+
+    if !flow_hops.is_empty() {
+        s += "\n##### DATA FLOW #####\n\n";
+        for (_, formatted_snippet) in &flow_hops {
+            s += formatted_snippet;
+        }
+    }
+
+    let prompt = answer_prompt(
+        &trace.aliases,
+        &s,
+        &AnswerPromptOptions {
+            template,
+            preferences: trace.preferences.as_deref(),
+            language: trace.language.as_deref(),
+            demoted_only: trace.demoted_only,
+            paths_only: !trace.index_mode.is_full(),
+            data_flow: !flow_hops.is_empty(),
+        },
+    );
+    let messages = Some(Message::system(&prompt))
+        .into_iter()
+        .chain(trace.history.iter().cloned())
+        .collect::<Vec<_>>();
+    let templates = [
+        (!trace.attachments.is_empty(), "attachments_prompt"),
+        (!trace.glossary.is_empty(), "glossary_prompt"),
+        (template.is_none(), "answer_article_prompt"),
+        (trace.demoted_only, "demoted_evidence_prompt"),
+        (!trace.index_mode.is_full(), "paths_only_prompt"),
+        (!flow_hops.is_empty(), "data_flow_prompt"),
+    ]
+    .into_iter()
+    .filter_map(|(used, template)| used.then_some(template))
+    .collect();
+    Ok(BuiltAnswer {
+        context: s,
+        prompt,
+        decisions,
+        final_context,
+        messages,
+        templates,
+    })
+}
+
+// What the answer prompt is written with besides the context.
+// `template` replaces the article prompt, its `{context}` is replaced with the paths and code.
+// The prompt has the preferences of the user and is written in `language` (English when None).
+// `demoted_only` tells the model that the code found is all test or vendored code, `paths_only`
+// that it was found in a paths-only index and `data_flow` that the context follows a value.
+#[derive(Clone, Copy, Default)]
+struct AnswerPromptOptions<'a> {
+    template: Option<&'a str>,
+    preferences: Option<&'a str>,
+    language: Option<&'a str>,
+    demoted_only: bool,
+    paths_only: bool,
+    data_flow: bool,
+}
+
 // headroom refers to the amount of space reserved for the rest of the prompt
+fn answer_prompt(aliases: &[usize], context: &str, options: &AnswerPromptOptions<'_>) -> String {
+    let mut prompt = match options.template {
+        Some(template) => template.replace("{context}", context),
+        None => prompts::answer_article_prompt(aliases, context),
+    };
+    if options.demoted_only {
+        prompt.push_str(&prompts::demoted_evidence_prompt());
+    }
+    if options.paths_only {
+        prompt.push_str(&prompts::paths_only_prompt());
+    }
+    if options.data_flow {
+        prompt.push_str(&prompts::data_flow_prompt());
+    }
+    with_language(
+        with_preferences(prompt, options.preferences),
+        options.language,
+    )
+}
+
 fn trim_utter_history(mut history: Vec<Message>, headroom: usize) -> Result<Vec<Message>> {
     let mut tiktoken_msgs: Vec<tiktoken_rs::ChatCompletionRequestMessage> =
         history.iter().map(|m| m.into()).collect::<Vec<_>>();
@@ -452,6 +797,326 @@ fn merge_overlapping(a: &mut Range<usize>, b: Range<usize>) -> Option<Range<usiz
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::agent::tools::data_flow::FlowHop;
+    use crate::agent::tools::packing::PackingReason;
+    use common::citations::{Citation, CitationReport, CitationSource, CitationStatus};
+    use common::glossary::{split_reconciled, Glossary};
+    use common::run_manifest::IndexMode;
+
+    #[test]
+    fn test_answer_prompt_asks_for_the_language_of_the_conversation() {
+        let context = "##### PATHS #####\nsrc/auth/session.rs\n";
+        let in_language = |language| AnswerPromptOptions {
+            language,
+            ..Default::default()
+        };
+        let prompt = answer_prompt(&[0], context, &in_language(Some("Japanese")));
+        assert!(prompt.contains("Respond in Japanese."));
+        assert!(prompt.contains("src/auth/session.rs"));
+
+        assert!(!answer_prompt(&[0], context, &in_language(None)).contains("Respond in"));
+        assert!(!answer_prompt(&[0], context, &in_language(Some("English"))).contains("Respond in"));
+    }
+
+    #[test]
+    fn test_answer_prompt_warns_when_only_demoted_code_was_found() {
+        let chunk = |path: &str| CodeChunk {
+            path: path.to_string(),
+            alias: Some(0),
+            snippet: "assert!(refresh(token).is_ok());".to_string(),
+            start_line: 10,
+            end_line: 10,
+            score: Some(0.4),
+            ..Default::default()
+        };
+        let demoted = vec!["tests/refresh.rs".to_string()];
+
+        assert!(only_demoted_evidence([chunk("tests/refresh.rs")].iter(), &demoted));
+        assert!(!only_demoted_evidence(
+            [chunk("tests/refresh.rs"), chunk("src/refresh.rs")].iter(),
+            &demoted
+        ));
+        assert!(!only_demoted_evidence(std::iter::empty(), &demoted));
+
+        let context = "##### PATHS #####\ntests/refresh.rs\n";
+        let demoted_only = AnswerPromptOptions {
+            demoted_only: true,
+            ..Default::default()
+        };
+        let prompt = answer_prompt(&[0], context, &demoted_only);
+        assert!(prompt.contains("TEST AND VENDORED CODE ONLY"));
+        assert!(!answer_prompt(&[0], context, &Default::default()).contains("TEST AND VENDORED"));
+    }
+
+    #[test]
+    fn test_answer_context_of_an_attached_openapi_spec() {
+        let spec = r#"{
+            "openapi": "3.0.0",
+            "info": {"title": "Orders API", "version": "2.1"},
+            "paths": {"/orders/{id}/refunds": {"post": {"summary": "Refunds are capped at the amount captured"}}}
+        }"#;
+        let sections = common::attachments::chunk_attachment(
+            "a1",
+            "orders-api.json",
+            common::attachments::AttachmentKind::OpenApi,
+            spec,
+        )
+        .unwrap();
+        // the code search found nothing, only the spec knows about refunds.
+        let trace = AnswerTrace {
+            repo_name: "acme/shop".to_string(),
+            model: "gpt-4-0613".to_string(),
+            aliases: Vec::new(),
+            paths: Vec::new(),
+            candidates: Vec::new(),
+            pinned_paths: Vec::new(),
+            related_usage: Vec::new(),
+            data_flow: Vec::new(),
+            preferences: None,
+            language: None,
+            demoted_only: false,
+            index_mode: IndexMode::Full,
+            history: vec![Message::user("How much of an order can be refunded?")],
+            reserved_tokens: 1024,
+            attachments: sections.clone(),
+            glossary: Vec::new(),
+        };
+
+        let built = build_answer(&trace, None).unwrap();
+        assert!(built.prompt.contains("##### ATTACHED DOCUMENTS #####"));
+        assert!(built
+            .prompt
+            .contains("### from attached document orders-api.json, section POST /orders/{id}/refunds ###"));
+        assert!(built.prompt.contains("Refunds are capped at the amount captured"));
+        assert!(!built.context.contains("CODE CHUNKS"));
+        assert_eq!(built.templates, vec!["attachments_prompt", "answer_article_prompt"]);
+        assert!(built.final_context.is_empty());
+
+        let answer = "An order can be refunded up to the amount captured (from attached document orders-api.json, section POST /orders/{id}/refunds).";
+        let cited = common::attachments::cited_sections(answer, &sections);
+        assert_eq!(cited.len(), 1);
+        assert_eq!(cited[0].section, "POST /orders/{id}/refunds");
+    }
+
+    #[test]
+    fn test_answer_of_a_paths_only_index_says_it_may_be_incomplete() {
+        let trace = AnswerTrace {
+            repo_name: "acme/monorepo".to_string(),
+            model: "gpt-4-0613".to_string(),
+            aliases: Vec::new(),
+            paths: Vec::new(),
+            candidates: Vec::new(),
+            pinned_paths: Vec::new(),
+            related_usage: Vec::new(),
+            data_flow: Vec::new(),
+            preferences: None,
+            language: None,
+            demoted_only: false,
+            index_mode: IndexMode::PathsOnly,
+            history: vec![Message::user("Where are refunds capped?")],
+            reserved_tokens: 1024,
+            attachments: Vec::new(),
+            glossary: Vec::new(),
+        };
+
+        let built = build_answer(&trace, None).unwrap();
+        assert!(built.prompt.contains("## PATHS-ONLY INDEX ##"));
+        assert_eq!(
+            built.templates,
+            vec!["answer_article_prompt", "paths_only_prompt"]
+        );
+        // traces recorded before the mode was kept are of full indexes.
+        let mut older = serde_json::to_value(&trace).unwrap();
+        older.as_object_mut().unwrap().remove("index_mode");
+        let older: AnswerTrace = serde_json::from_value(older).unwrap();
+        assert!(!build_answer(&older, None)
+            .unwrap()
+            .prompt
+            .contains("PATHS-ONLY"));
+    }
+
+    #[test]
+    fn test_answer_context_presents_the_data_flow_in_order() {
+        let hop = |hop: usize, path: &str, symbol: &str, snippet: &str, label: &str| FlowHop {
+            hop,
+            chunk: CodeChunk {
+                path: path.to_string(),
+                alias: Some(hop),
+                snippet: snippet.to_string(),
+                start_line: 2,
+                end_line: 2 + snippet.lines().count(),
+                score: None,
+                ..Default::default()
+            },
+            symbol: symbol.to_string(),
+            label: label.to_string(),
+        };
+        let trace = AnswerTrace {
+            repo_name: "acme/shop".to_string(),
+            model: "gpt-4-0613".to_string(),
+            aliases: Vec::new(),
+            paths: Vec::new(),
+            candidates: Vec::new(),
+            pinned_paths: Vec::new(),
+            related_usage: Vec::new(),
+            data_flow: vec![
+                hop(
+                    0,
+                    "src/handler.rs",
+                    "handle",
+                    "fn handle() -> u64 {\n    load_timeout() * 2\n}",
+                    "`handle` uses the value",
+                ),
+                hop(
+                    1,
+                    "src/config.rs",
+                    "load_timeout",
+                    "fn load_timeout() -> u64 {\n    read_timeout_setting() + 1\n}",
+                    "`handle` gets it from `load_timeout`, defined in src/config.rs",
+                ),
+                hop(
+                    2,
+                    "src/settings.rs",
+                    "read_timeout_setting",
+                    "fn read_timeout_setting() -> u64 {\n    30\n}",
+                    "`load_timeout` gets it from `read_timeout_setting`, defined in src/settings.rs",
+                ),
+            ],
+            preferences: None,
+            language: None,
+            demoted_only: false,
+            index_mode: IndexMode::Full,
+            history: vec![Message::user("Where does the timeout come from?")],
+            reserved_tokens: 1024,
+            attachments: Vec::new(),
+            glossary: Vec::new(),
+        };
+
+        let built = build_answer(&trace, None).unwrap();
+        assert!(built.prompt.contains("## DATA FLOW ##"));
+        let section = &built.context[built.context.find("##### DATA FLOW #####").unwrap()..];
+        let positions = ["src/handler.rs", "src/config.rs", "src/settings.rs"]
+            .iter()
+            .map(|path| section.find(&format!("### {path} (data flow, hop")).unwrap())
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        assert!(section.contains("4     read_timeout_setting() + 1"));
+
+        // every hop can be cited by the answer.
+        let cited = built
+            .final_context
+            .iter()
+            .map(|c| c.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(cited, vec!["src/handler.rs", "src/config.rs", "src/settings.rs"]);
+        assert!(built
+            .decisions
+            .iter()
+            .all(|d| d.included && d.reason == PackingReason::DataFlow));
+    }
+
+    #[test]
+    fn test_later_answers_keep_to_the_glossary_of_the_conversation() {
+        let candidate = CodeChunk {
+            path: "ingestion/src/semantic_index.rs".to_string(),
+            alias: Some(0),
+            snippet: "fn commit_chunks(points: Vec<Point>) {\n    retry(3, || upsert(points))\n}".to_string(),
+            start_line: 10,
+            end_line: 12,
+            score: Some(0.9),
+            ..Default::default()
+        };
+        let trace = |query: &str, glossary: &Glossary| AnswerTrace {
+            repo_name: "acme/search".to_string(),
+            model: "gpt-4-0613".to_string(),
+            aliases: vec![0],
+            paths: vec![candidate.path.clone()],
+            candidates: vec![candidate.clone()],
+            pinned_paths: Vec::new(),
+            related_usage: Vec::new(),
+            data_flow: Vec::new(),
+            preferences: None,
+            language: None,
+            demoted_only: false,
+            index_mode: IndexMode::Full,
+            history: vec![Message::user(query)],
+            reserved_tokens: 1024,
+            attachments: Vec::new(),
+            glossary: glossary.relevant([candidate.snippet.as_str()]),
+        };
+        let cited = CitationReport {
+            citations: vec![Citation {
+                path: candidate.path.clone(),
+                source: CitationSource::Link,
+                cited_lines: Some((11, 13)),
+                lines: Some((11, 13)),
+                status: CitationStatus::Valid,
+                out_of_scope: None,
+                url: None,
+                anchor: None,
+                drift: None,
+                enclosing_symbol: Some("commit_chunks".to_string()),
+            }],
+        };
+        let mut glossary = Glossary::default();
+
+        // the first question has nothing to keep to.
+        let built = build_answer(&trace("How are the chunks committed?", &glossary), None).unwrap();
+        assert!(!built.prompt.contains("##### GLOSSARY #####"));
+        let first = "[`commit_chunks`](ingestion/src/semantic_index.rs#L11-L13) retries the upsert three times with a backoff.";
+        assert_eq!(glossary.record_answer(1, first, &cited), 1);
+
+        // the second one is given how the first described the symbol it finds again.
+        let built = build_answer(&trace("What happens when qdrant is down?", &glossary), None).unwrap();
+        assert!(built.prompt.contains("##### GLOSSARY #####"));
+        assert!(built.prompt.contains(
+            "- `commit_chunks` (ingestion/src/semantic_index.rs): `commit_chunks` retries the upsert three times with a backoff."
+        ));
+        assert_eq!(built.templates, vec!["glossary_prompt", "answer_article_prompt"]);
+
+        // it contradicts it anyway, the summary settles on one description.
+        let second = "When qdrant is down `commit_chunks` drops the batch after the first failure.";
+        assert_eq!(glossary.record_answer(2, second, &cited), 1);
+        assert_eq!(glossary.conflicts().len(), 1);
+        let summary = "## Plan\n\n1. Make the retries configurable.\n\n## Reconciled glossary\n\n- `commit_chunks`: retries the upsert three times, then drops the batch.\n";
+        let (summary, reconciled) = split_reconciled(summary);
+        assert_eq!(summary, "## Plan\n\n1. Make the retries configurable.");
+        assert_eq!(glossary.reconcile(&reconciled), 1);
+        assert_eq!(glossary.entries.len(), 1);
+        assert!(glossary.conflicts().is_empty());
+        assert_eq!(
+            glossary.entries[0].description,
+            "retries the upsert three times, then drops the batch."
+        );
+    }
+
+    #[test]
+    fn test_answer_is_written_for_what_the_user_clarified() {
+        let mut exchange = Exchange::new(
+            "exchange_1".to_string(),
+            "How are failed requests retried?".to_string(),
+        );
+        let options = vec!["webhook deliveries".to_string(), "payment refunds".to_string()];
+        exchange.ask_user(
+            Some("call_1".to_string()),
+            common::clarification::Clarification::new("Which retries do you mean?", &options),
+        );
+        // the question alone isn't shown, the run waits for the response.
+        assert_eq!(
+            utter_messages(&exchange),
+            vec![Message::user("How are failed requests retried?")]
+        );
+
+        exchange.respond_to_clarification("the payment refunds");
+        assert_eq!(
+            utter_messages(&exchange),
+            vec![
+                Message::user("How are failed requests retried?"),
+                Message::assistant("Which retries do you mean? (webhook deliveries | payment refunds)"),
+                Message::user("To clarify \"Which retries do you mean?\": the payment refunds"),
+            ]
+        );
+    }
 
     #[test]
     fn test_trimming_utter_history() {
@@ -0,0 +1,26 @@
+use crate::agent::agent::Agent;
+use crate::config::get_redis_url;
+
+use anyhow::Result;
+use common::clarification::Clarification;
+use tracing::instrument;
+
+/// Name of the function that asks the user a clarifying question.
+pub const ASK_USER: &str = "ask_user";
+
+impl Agent {
+    /// Stops the run to ask the user a clarifying question, see `common::clarification`. The
+    /// exchanges are saved so that the request with the response of the user resumes from here.
+    #[instrument(skip(self))]
+    pub fn ask_user(&mut self, question: &str, options: &[String]) -> Result<()> {
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.exchanges
+            .last_mut()
+            .expect("exchange list was empty")
+            .ask_user(last_function_call_id, Clarification::new(question, options));
+        // the user is asked once per question.
+        self.calls.close_ask_user();
+        self.save_exchanges_to_redis(&get_redis_url())?;
+        Ok(())
+    }
+}
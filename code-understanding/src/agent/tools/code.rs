@@ -18,7 +18,35 @@ impl Agent {
             response: String::new(),
         }))?;
 
-        let results_symbol = symbol_search(query, &self.repo_name).await;
+        // the search runs as a sub-task of the agent run, so it stops when the query is cancelled.
+        // in a batch request the questions share the results of the searches they both run.
+        let (search_query, repo_name) = (query.clone(), self.repo_name.clone());
+        let (batch, include_tests) = (self.batch.clone(), self.include_tests);
+        let index_generation = self.index_generation.clone();
+        let expand_terminology = self.terminology.is_some();
+        let results_symbol = match self
+            .run
+            .spawn(async move {
+                let search = || {
+                    symbol_search(
+                        &search_query,
+                        &repo_name,
+                        include_tests,
+                        index_generation.as_deref(),
+                        expand_terminology,
+                    )
+                };
+                match batch {
+                    Some(batch) => batch.search(&search_query, search).await,
+                    None => search().await,
+                }
+            })
+            .await
+        {
+            Ok(Some(result)) => result,
+            Ok(None) => Err(anyhow::anyhow!("Symbol search was cancelled")),
+            Err(e) => Err(anyhow::anyhow!("Symbol search task failed: {}", e)),
+        };
 
         // log and return the error 
         if results_symbol.is_err() {
@@ -35,18 +63,39 @@ impl Agent {
         }
         let code_snippet = results_symbol.unwrap();
 
+        // the jargon code search expanded the query with, the dictionary is the one it reads.
+        let expansions = self
+            .terminology
+            .as_ref()
+            .map(|terminology| terminology.expand(query))
+            .unwrap_or_default();
+        // test and vendored code ranked lower by the search, the answer prompt says when it is all there is.
+        let exchange = self.exchanges.last_mut().unwrap();
+        for expansion in expansions {
+            if !exchange.query_expansions.contains(&expansion) {
+                exchange.query_expansions.push(expansion);
+            }
+        }
+        for chunk in code_snippet.iter().filter(|c| c.demoted) {
+            if !exchange.demoted_paths.contains(&chunk.path) {
+                exchange.demoted_paths.push(chunk.path.clone());
+            }
+        }
+        for chunk in code_snippet.iter().filter(|c| c.is_test) {
+            if !exchange.test_paths.contains(&chunk.path) {
+                exchange.test_paths.push(chunk.path.clone());
+            }
+        }
+
         // log::debug!("Size of semantic search: {}", results.len());
 
         let mut code_chunks = code_snippet
             .into_iter()
             .map(|chunk| {
-                let relative_path = chunk.path.clone(); // Clone relative_path
+                // the chunk of code search is kept whole, only the alias of its path is added.
                 CodeChunk {
-                    path: relative_path,                              // Use the cloned relative_path
-                    alias: self.get_path_alias(&chunk.path), // Use the original relative_path for this call
-                    snippet: chunk.snippet,
-                    start_line: chunk.start_line as usize,
-                    end_line: chunk.end_line as usize,
+                    alias: Some(self.get_path_alias(&chunk.path)),
+                    ..chunk
                 }
             })
             .collect::<Vec<_>>();
@@ -61,6 +110,22 @@ impl Agent {
                 .push(chunk.clone())
         }
 
+        // callers and callees of the functions found, packed after the chunks in the answer.
+        if self.related_usage {
+            let related = self.related_usage(&code_chunks).await;
+            self.exchanges
+                .last_mut()
+                .unwrap()
+                .related_usage
+                .extend(related);
+        }
+
+        // the flow is followed once per exchange, from the first search finding its start.
+        if self.data_flow && self.last_exchange().data_flow.is_empty() {
+            let hops = self.data_flow(&code_chunks).await;
+            self.exchanges.last_mut().unwrap().data_flow = hops;
+        }
+
         let response = code_chunks
             .iter()
             .filter(|c| !c.is_empty())
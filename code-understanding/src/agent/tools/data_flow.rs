@@ -0,0 +1,525 @@
+//! Data flow of the value a question asks about, e.g. "where does the retry timeout come from?".
+//! Such answers need code from several files, which a single code search rarely finds together.
+//! The flow starts at the function defined in the best chunk of the code search and walks
+//! upstream one hop at a time: to the function a value of the current hop is computed by, or to
+//! the function of the file passing it in when the current one calls nothing. Functions defined in
+//! the file come from its scope graph, the ones imported from other files from the symbols
+//! collection. The walk stops after `DATA_FLOW_MAX_HOPS` hops, when there is nowhere left to go, or
+//! when the next hop was already visited.
+
+use std::collections::HashSet;
+use std::future::Future;
+
+use anyhow::Result;
+use common::ast::{
+    ast_graph::{NodeKind, ScopeGraph},
+    symbol::SymbolLocations,
+};
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::agent::agent::Agent;
+use crate::agent::exchange::CodeChunk;
+use crate::agent::tools::related::{body_of, enclosing_function, function_defs, is_call, name_of};
+use crate::config::{get_data_flow_max_hops, get_quickwit_url};
+use crate::helpers::symbol_search::exact_symbol_lookup;
+
+// lines of a function kept as the snippet of its hop.
+const MAX_HOP_LINES: usize = 12;
+
+// Phrases of questions asking where a value comes from or goes through.
+const DATA_FLOW_PHRASES: &[&str] = &[
+    "come from",
+    "comes from",
+    "coming from",
+    "originate",
+    "get set",
+    "gets set",
+    "what sets",
+    "data flow",
+    "flow of",
+    "flows from",
+    "flows into",
+    "passed through",
+    "passed down",
+    "trace the value",
+    "trace where",
+];
+
+/// A function the value goes through, from the code the question is about up to where the value
+/// comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlowHop {
+    // 0 for the code the question is about, one more per hop upstream.
+    pub hop: usize,
+    pub chunk: CodeChunk,
+    // the function of the hop.
+    pub symbol: String,
+    // how the value gets to the previous hop, also its label in the answer context.
+    pub label: String,
+}
+
+/// A file the flow goes through, with the scope graph stored when it was indexed.
+#[derive(Debug, Clone)]
+pub struct FlowFile {
+    pub path: String,
+    pub content: String,
+    pub graph: ScopeGraph,
+}
+
+/// Where the value of a hop comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    // a function of the same file.
+    Local(FlowHop),
+    // a function imported from another file, looked up by its name.
+    External(String),
+}
+
+/// True for questions asking where a value comes from or how it flows through the code.
+pub fn is_data_flow_question(query: &str) -> bool {
+    let query = query.trim().to_lowercase();
+    DATA_FLOW_PHRASES
+        .iter()
+        .any(|phrase| query.contains(phrase))
+}
+
+/// The hop of the first function defined in `lines` of the file, labeled with its name.
+pub fn hop_at(
+    file: &FlowFile,
+    lines: std::ops::Range<usize>,
+    hop: usize,
+    label: impl FnOnce(&str) -> String,
+) -> Option<FlowHop> {
+    let def = function_defs(&file.graph)
+        .filter(|&idx| lines.contains(&file.graph.graph[idx].range().start.line))
+        .min_by_key(|&idx| file.graph.graph[idx].range().start.byte)?;
+    let symbol = name_of(&file.graph, file.content.as_bytes(), def)?;
+    let source = file.content.lines().collect::<Vec<_>>();
+    let start = file.graph.graph[def].range().start.line;
+    let end = (body_of(&file.graph, def).end.line + 1)
+        .min(start + MAX_HOP_LINES)
+        .min(source.len());
+    Some(FlowHop {
+        hop,
+        chunk: CodeChunk {
+            path: file.path.clone(),
+            alias: Some(0),
+            snippet: source[start.min(end)..end].join("\n"),
+            start_line: start,
+            end_line: end,
+            symbol: Some(symbol.clone()),
+            score: None,
+            ..Default::default()
+        },
+        label: label(&symbol),
+        symbol,
+    })
+}
+
+/// Where the value of `from` may come from, best first: the functions it calls, ranked by how
+/// many words of the query their names share, then the functions of the file calling it.
+pub fn upstream(file: &FlowFile, from: &FlowHop, terms: &[String]) -> Vec<Upstream> {
+    let graph = &file.graph;
+    let src = file.content.as_bytes();
+    let Some(def) = find_def(file, from) else {
+        return Vec::new();
+    };
+    let body = body_of(graph, def);
+    let next = from.hop + 1;
+
+    let mut calls: Vec<(usize, NodeIndex, String)> = Vec::new();
+    for idx in graph.graph.node_indices() {
+        let NodeKind::Ref(reference) = &graph.graph[idx] else {
+            continue;
+        };
+        let range = reference.range;
+        if range.start.byte < body.start.byte
+            || range.end.byte > body.end.byte
+            || !is_call(src, &range)
+        {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&src[range.start.byte..range.end.byte]).to_string();
+        if name != from.symbol && !calls.iter().any(|(_, _, n)| *n == name) {
+            calls.push((range.start.byte, idx, name));
+        }
+    }
+    calls.sort_by_key(|(byte, _, name)| (std::cmp::Reverse(shared_terms(name, terms)), *byte));
+
+    let mut upstream = calls
+        .into_iter()
+        .filter_map(|(_, idx, name)| match graph.definitions(idx).next() {
+            Some(callee) => {
+                let line = graph.graph[callee].range().start.line;
+                hop_at(file, line..line + 1, next, |callee| {
+                    format!("`{}` gets it from `{callee}`", from.symbol)
+                })
+                .map(Upstream::Local)
+            }
+            None => Some(Upstream::External(name)),
+        })
+        .collect::<Vec<_>>();
+
+    // the value is passed in by the callers of the function.
+    let mut callers = graph
+        .references(def)
+        .map(|idx| graph.graph[idx].range().start.byte)
+        .filter(|byte| *byte < body.start.byte || *byte >= body.end.byte)
+        .collect::<Vec<_>>();
+    callers.sort();
+    for byte in callers {
+        let Some(caller) = enclosing_function(graph, src, byte) else {
+            continue;
+        };
+        let Some(caller_def) = function_defs(graph)
+            .find(|&idx| name_of(graph, src, idx).as_deref() == Some(caller.as_str()))
+        else {
+            continue;
+        };
+        let line = graph.graph[caller_def].range().start.line;
+        if let Some(hop) = hop_at(file, line..line + 1, next, |caller| {
+            format!("`{caller}` passes it to `{}`", from.symbol)
+        }) {
+            upstream.push(Upstream::Local(hop));
+        }
+    }
+    upstream
+}
+
+/// Walks the flow from the function defined in `cited` for at most `max_hops` hops.
+/// `resolve` reads the file defining a function imported from another file, with the line the
+/// function starts at. A hop already visited is skipped, the value went round in a cycle, and the
+/// walk ends once every candidate of the last hop was skipped.
+pub async fn trace_flow<F, Fut>(
+    start: FlowFile,
+    cited: &CodeChunk,
+    query: &str,
+    max_hops: usize,
+    mut resolve: F,
+) -> Vec<FlowHop>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Option<(FlowFile, usize)>>,
+{
+    let terms = query_terms(query);
+    let Some(first) = hop_at(
+        &start,
+        cited.start_line..cited.end_line.max(cited.start_line + 1),
+        0,
+        |symbol| format!("`{symbol}` uses the value"),
+    ) else {
+        return Vec::new();
+    };
+
+    let mut visited = HashSet::from([hop_key(&first)]);
+    let mut hops = vec![first];
+    let mut file = start;
+    while hops.len() <= max_hops {
+        let from = hops.last().unwrap().clone();
+        let mut next = None;
+        for candidate in upstream(&file, &from, &terms) {
+            let (hop, hop_file) = match candidate {
+                Upstream::Local(hop) => (hop, None),
+                Upstream::External(name) => {
+                    let Some((other, line)) = resolve(name).await else {
+                        continue;
+                    };
+                    let Some(hop) = hop_at(&other, line..line + 1, from.hop + 1, |callee| {
+                        format!(
+                            "`{}` gets it from `{callee}`, defined in {}",
+                            from.symbol, other.path
+                        )
+                    }) else {
+                        continue;
+                    };
+                    (hop, Some(other))
+                }
+            };
+            if visited.insert(hop_key(&hop)) {
+                next = Some((hop, hop_file));
+                break;
+            }
+            info!(symbol = %hop.symbol, path = %hop.chunk.path, "data flow went round in a cycle");
+        }
+        let Some((hop, hop_file)) = next else {
+            break;
+        };
+        if let Some(hop_file) = hop_file {
+            file = hop_file;
+        }
+        hops.push(hop);
+    }
+    hops
+}
+
+fn hop_key(hop: &FlowHop) -> (String, String) {
+    (hop.chunk.path.clone(), hop.symbol.clone())
+}
+
+fn find_def(file: &FlowFile, hop: &FlowHop) -> Option<NodeIndex> {
+    function_defs(&file.graph).find(|&idx| {
+        file.graph.graph[idx].range().start.line == hop.chunk.start_line
+            && name_of(&file.graph, file.content.as_bytes(), idx).as_deref()
+                == Some(hop.symbol.as_str())
+    })
+}
+
+// The lowercase words of the query.
+fn query_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 2)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Words of a snake or camel case name found in the query.
+fn shared_terms(name: &str, terms: &[String]) -> usize {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in name.chars() {
+        if c == '_' || (c.is_uppercase() && !word.is_empty()) {
+            words.push(std::mem::take(&mut word));
+        }
+        if c != '_' {
+            word.extend(c.to_lowercase());
+        }
+    }
+    words.push(word);
+    words.iter().filter(|word| terms.contains(word)).count()
+}
+
+impl Agent {
+    /// The data flow of the value the query asks about, from the best chunk of a code search.
+    /// Nothing when the file of the chunk can't be read or has no scope graph.
+    pub async fn data_flow(&mut self, chunks: &[CodeChunk]) -> Vec<FlowHop> {
+        let Some(cited) = chunks
+            .iter()
+            .filter(|c| !c.is_empty())
+            .max_by(|a, b| a.score.unwrap_or(0.0).total_cmp(&b.score.unwrap_or(0.0)))
+        else {
+            return Vec::new();
+        };
+        let query = self.get_query();
+        let agent = &*self;
+        let start = match agent.flow_file(&cited.path).await {
+            Ok(Some(start)) => start,
+            Ok(None) => return Vec::new(),
+            Err(e) => {
+                warn!("Failed to read {} for the data flow: {}", cited.path, e);
+                return Vec::new();
+            }
+        };
+        let hops = trace_flow(
+            start,
+            cited,
+            &query,
+            get_data_flow_max_hops(),
+            move |name| agent.flow_definition(name),
+        )
+        .await;
+
+        hops.into_iter()
+            .map(|mut hop| {
+                hop.chunk.alias = Some(self.get_path_alias(&hop.chunk.path));
+                info!(
+                    hop = hop.hop,
+                    path = %hop.chunk.path,
+                    start_line = hop.chunk.start_line,
+                    end_line = hop.chunk.end_line,
+                    label = %hop.label,
+                    "added data flow hop"
+                );
+                hop
+            })
+            .collect()
+    }
+
+    async fn flow_file(&self, path: &str) -> Result<Option<FlowFile>> {
+        let Some(doc) = self.get_file_content(&get_quickwit_url(), path).await? else {
+            return Ok(None);
+        };
+        let locations = bincode::deserialize::<SymbolLocations>(&doc.symbol_locations)?;
+        Ok(locations.scope_graph().map(|graph| FlowFile {
+            path: path.to_string(),
+            content: doc.content,
+            graph: graph.clone(),
+        }))
+    }
+
+    // The file defining a function imported from another one, a lookup that fails ends the walk
+    // on this branch only.
+    async fn flow_definition(&self, name: String) -> Option<(FlowFile, usize)> {
+        let occurrences = exact_symbol_lookup(
+            &name,
+            None,
+            None,
+            Some(true),
+            &self.repo_name,
+            self.index_generation.as_deref(),
+        )
+        .await
+            .map_err(|e| warn!("Failed to look up {} for the data flow: {}", name, e))
+            .ok()?;
+        for occurrence in occurrences {
+            let Some(line) = occurrence.start_line else {
+                continue;
+            };
+            match self.flow_file(&occurrence.path).await {
+                Ok(Some(file)) => return Some((file, line)),
+                Ok(None) => continue,
+                Err(e) => warn!(
+                    "Failed to read {} for the data flow: {}",
+                    occurrence.path, e
+                ),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::ast::CodeFileAST;
+    use std::collections::HashMap;
+
+    // the timeout is read in settings.rs, adjusted in config.rs and used in handler.rs.
+    const HANDLER: &str = "use crate::config::load_timeout;\n\nfn handle() -> u64 {\n    let timeout = load_timeout();\n    timeout * 2\n}\n";
+    const CONFIG: &str = "use crate::settings::read_timeout_setting;\n\nfn load_timeout() -> u64 {\n    let raw = read_timeout_setting();\n    raw + 1\n}\n";
+    const SETTINGS: &str = "use crate::handler::handle;\n\nfn read_timeout_setting() -> u64 {\n    let fallback = parse(\"30\");\n    fallback\n}\n\nfn parse(value: &str) -> u64 {\n    value.len() as u64\n}\n\nfn retry() -> u64 {\n    handle()\n}\n";
+
+    fn file(path: &str, content: &str) -> FlowFile {
+        FlowFile {
+            path: path.to_string(),
+            content: content.to_string(),
+            graph: CodeFileAST::build_ast(content.as_bytes(), "Rust")
+                .unwrap()
+                .scope_graph()
+                .unwrap(),
+        }
+    }
+
+    // The definitions of the symbols collection, by name.
+    fn definitions() -> HashMap<String, (FlowFile, usize)> {
+        HashMap::from([
+            (
+                "load_timeout".to_string(),
+                (file("src/config.rs", CONFIG), 2),
+            ),
+            (
+                "read_timeout_setting".to_string(),
+                (file("src/settings.rs", SETTINGS), 2),
+            ),
+            ("handle".to_string(), (file("src/handler.rs", HANDLER), 2)),
+        ])
+    }
+
+    fn cited() -> CodeChunk {
+        CodeChunk {
+            path: "src/handler.rs".to_string(),
+            alias: Some(0),
+            snippet: HANDLER.lines().skip(2).collect::<Vec<_>>().join("\n"),
+            start_line: 2,
+            end_line: 6,
+            score: Some(0.9),
+            ..Default::default()
+        }
+    }
+
+    async fn trace(max_hops: usize) -> Vec<FlowHop> {
+        let definitions = definitions();
+        trace_flow(
+            file("src/handler.rs", HANDLER),
+            &cited(),
+            "Where does the timeout come from?",
+            max_hops,
+            |name| std::future::ready(definitions.get(&name).cloned()),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_value_is_traced_through_three_files() {
+        let hops = trace(3).await;
+
+        let chain = hops
+            .iter()
+            .map(|hop| (hop.hop, hop.chunk.path.as_str(), hop.symbol.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chain,
+            vec![
+                (0, "src/handler.rs", "handle"),
+                (1, "src/config.rs", "load_timeout"),
+                (2, "src/settings.rs", "read_timeout_setting"),
+                (3, "src/settings.rs", "parse"),
+            ]
+        );
+        assert_eq!(hops[0].label, "`handle` uses the value");
+        assert_eq!(
+            hops[1].label,
+            "`handle` gets it from `load_timeout`, defined in src/config.rs"
+        );
+        assert!(hops[1].chunk.snippet.contains("read_timeout_setting()"));
+        assert_eq!(hops[3].label, "`read_timeout_setting` gets it from `parse`");
+    }
+
+    #[tokio::test]
+    async fn test_hop_budget_is_enforced() {
+        let hops = trace(1).await;
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[1].symbol, "load_timeout");
+        assert_eq!(trace(0).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cycles_end_the_walk() {
+        let settings = file("src/settings.rs", SETTINGS);
+        let retry = hop_at(&settings, 11..12, 0, |symbol| symbol.to_string()).unwrap();
+        assert_eq!(retry.symbol, "retry");
+
+        // the flow goes through the other two files and back to settings.rs, where `parse` is only
+        // called by `read_timeout_setting`, already visited.
+        let definitions = definitions();
+        let hops = trace_flow(
+            settings.clone(),
+            &retry.chunk,
+            "what sets the retry",
+            10,
+            |name| std::future::ready(definitions.get(&name).cloned()),
+        )
+        .await;
+        let symbols = hops
+            .iter()
+            .map(|hop| hop.symbol.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            symbols,
+            vec![
+                "retry",
+                "handle",
+                "load_timeout",
+                "read_timeout_setting",
+                "parse"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_flow_questions_are_detected() {
+        assert!(is_data_flow_question(
+            "Where does the retry timeout come from?"
+        ));
+        assert!(is_data_flow_question(
+            "what sets the user id on the request"
+        ));
+        assert!(!is_data_flow_question("How is the retry delay computed?"));
+        assert_eq!(
+            shared_terms("readTimeout_setting", &query_terms("the timeout")),
+            1
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use crate::agent::agent::Agent;
+use crate::config::{get_redact_commit_authors, get_redis_url, get_repo_working_copy};
+use crate::helpers::git_history::{blame_range, format_history};
+
+use crate::agent::exchange::{SearchStep, Update};
+use anyhow::Result;
+use tracing::instrument;
+
+use log::error;
+
+impl Agent {
+    #[instrument(skip(self))]
+    pub async fn git_history(
+        &mut self,
+        path: &String,
+        start_line: usize,
+        end_line: usize,
+    ) -> Result<String> {
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::StartStep(SearchStep::History {
+            id: last_function_call_id,
+            query: path.clone(),
+            start_line,
+            end_line,
+            response: String::new(),
+        }))?;
+
+        // a missing working copy or a failed blame is an answer for the model, not an error of the agent.
+        let response = match get_repo_working_copy(&self.repo_name) {
+            None => format!(
+                "history unavailable: no working copy is configured for the repo {}",
+                self.repo_name
+            ),
+            Some(working_copy) => {
+                let blame_path = path.clone();
+                match self
+                    .run
+                    .spawn(async move {
+                        blame_range(&working_copy, &blame_path, start_line, end_line).await
+                    })
+                    .await
+                {
+                    Ok(Some(Ok(commits))) if commits.is_empty() => format!(
+                        "history unavailable: lines {}-{} of {} are not committed",
+                        start_line, end_line, path
+                    ),
+                    Ok(Some(Ok(commits))) => format_history(&commits, get_redact_commit_authors()),
+                    Ok(Some(Err(e))) => {
+                        error!("Failed to blame {}:{}-{}: {:?}", path, start_line, end_line, e);
+                        format!("history unavailable: {}", e)
+                    }
+                    Ok(None) => return Err(anyhow::anyhow!("History lookup was cancelled")),
+                    Err(e) => return Err(anyhow::anyhow!("History lookup task failed: {}", e)),
+                }
+            }
+        };
+
+        log::debug!("response: {}", response);
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::ReplaceStep(SearchStep::History {
+            id: last_function_call_id,
+            query: path.clone(),
+            start_line,
+            end_line,
+            response: response.clone(),
+        }))?;
+        // save exchanges to redis
+        self.save_exchanges_to_redis(&get_redis_url())?;
+        Ok(response)
+    }
+}
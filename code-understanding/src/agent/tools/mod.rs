@@ -1,4 +0,0 @@
-pub mod answer;
-pub mod code;
-pub mod path;
-pub mod proc;
@@ -0,0 +1,38 @@
+use crate::agent::agent::Agent;
+use crate::config::get_redis_url;
+
+use crate::agent::exchange::{SearchStep, Update};
+use anyhow::Result;
+use tracing::instrument;
+
+impl Agent {
+    /// Returns the next page of the last paged function return, see `Exchange::page_last_step`.
+    #[instrument(skip(self))]
+    pub async fn more_results(&mut self) -> Result<String> {
+        let next = self
+            .exchanges
+            .last_mut()
+            .and_then(|exchange| exchange.next_page());
+        // a call without pages left is told so, it isn't an error of the agent.
+        let (query, page, response) = match next {
+            Some(next) => next,
+            None => (
+                String::new(),
+                0,
+                "no more results: the last function return was complete".to_string(),
+            ),
+        };
+
+        log::debug!("response: {}", response);
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::StartStep(SearchStep::MoreResults {
+            id: last_function_call_id,
+            query,
+            page,
+            response: response.clone(),
+        }))?;
+        // save exchanges to redis
+        self.save_exchanges_to_redis(&get_redis_url())?;
+        Ok(response)
+    }
+}
@@ -0,0 +1,576 @@
+use std::collections::HashSet;
+
+use common::embedded_lang::display_name;
+use serde::{Deserialize, Serialize};
+
+use crate::agent::exchange::CodeChunk;
+use crate::agent::tools::data_flow::FlowHop;
+use crate::agent::tools::related::RelatedUsage;
+
+/// Why a chunk was or wasn't packed into the answer context, recorded on the exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PackingReason {
+    // pinned by the user, packed before anything else.
+    Pinned,
+    // highest scoring chunk of its path, packed so every cited path has some code.
+    BestOfPath,
+    // packed in score order once every path had its best chunk.
+    Score,
+    // over the chunks per path of the search, packed in score order after all the others.
+    Overflow,
+    // packed, but cut at a line boundary to fit in the remaining budget.
+    Truncated,
+    // not even the first line fit in the remaining budget.
+    OverBudget,
+    // caller or callee of a packed chunk, packed in what the chunks left of the budget.
+    RelatedUsage,
+    // hop of the data flow of the query, packed in what the related usage left of the budget.
+    DataFlow,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PackingDecision {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub score: Option<f32>,
+    // tokens the chunk takes in the prompt, after truncation if it was truncated.
+    pub tokens: usize,
+    pub included: bool,
+    pub reason: PackingReason,
+}
+
+/// Formats a chunk the way it appears in the answer prompt, with 1-based line numbers. A chunk
+/// holding an embedded language is labeled with it, e.g. `src/orders.rs (embedded SQL)`.
+pub fn format_snippet(chunk: &CodeChunk) -> String {
+    match &chunk.embedded_lang {
+        Some(lang) => {
+            let header = format!("{} (embedded {})", chunk.path, display_name(lang));
+            format_with_header(chunk, &header)
+        }
+        None => format_with_header(chunk, &chunk.path),
+    }
+}
+
+/// Formats a related snippet like a chunk, labeled with why it was added.
+pub fn format_related(usage: &RelatedUsage) -> String {
+    let header = format!("{} (related usage: {})", usage.chunk.path, usage.reason);
+    format_with_header(&usage.chunk, &header)
+}
+
+/// Formats a hop of the data flow like a chunk, labeled with its position in the flow.
+pub fn format_flow_hop(hop: &FlowHop) -> String {
+    let header = format!(
+        "{} (data flow, hop {}: {})",
+        hop.chunk.path, hop.hop, hop.label
+    );
+    format_with_header(&hop.chunk, &header)
+}
+
+fn format_with_header(chunk: &CodeChunk, header: &str) -> String {
+    let snippet = chunk
+        .snippet
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{} {line}\n", i + chunk.start_line + 1))
+        .collect::<String>();
+
+    // the docs read like a summary of the code, so they come before it.
+    let doc = chunk
+        .doc
+        .as_ref()
+        .map(|doc| {
+            let lines = doc
+                .lines()
+                .map(|line| format!("  {line}\n"))
+                .collect::<String>();
+            format!("Doc:\n{lines}")
+        })
+        .unwrap_or_default();
+
+    // one line for the copies, the code is the same so only where it lives matters.
+    let duplicates = if chunk.duplicates.is_empty() {
+        String::new()
+    } else {
+        format!("Also in: {}\n", chunk.duplicates.join(", "))
+    };
+
+    format!("### {header} ###\n{duplicates}{doc}{snippet}\n\n")
+}
+
+/// Picks the chunks that go into the answer context within `budget` tokens.
+///
+/// Pinned chunks come first, then the best chunk of every path so that each cited path
+/// is represented, then the remaining chunks by descending retrieval score, the ones the search
+/// put in its overflow last.
+/// A chunk that doesn't fit is cut at a line boundary instead of being dropped.
+/// Returns the packed chunks with their formatted snippets, and a decision for every candidate.
+pub fn pack_chunks(
+    chunks: &[CodeChunk],
+    pinned_paths: &HashSet<String>,
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> (Vec<(CodeChunk, String)>, Vec<PackingDecision>) {
+    let mut order = (0..chunks.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&chunks[a], &chunks[b]);
+        pinned_paths
+            .contains(&b.path)
+            .cmp(&pinned_paths.contains(&a.path))
+            .then_with(|| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)))
+            .then_with(|| a.alias.cmp(&b.alias))
+            .then_with(|| a.start_line.cmp(&b.start_line))
+    });
+
+    // the first chunk of each path in that order is its best one.
+    let mut seen_paths = HashSet::new();
+    let (best_of_path, rest): (Vec<usize>, Vec<usize>) = order
+        .into_iter()
+        .partition(|&i| seen_paths.insert(chunks[i].path.clone()));
+    let (rest, overflow): (Vec<usize>, Vec<usize>) =
+        rest.into_iter().partition(|&i| !chunks[i].overflow);
+
+    let mut remaining = budget;
+    let mut packed = Vec::new();
+    let mut decisions = Vec::new();
+    for (i, pass_reason) in best_of_path
+        .into_iter()
+        .map(|i| (i, PackingReason::BestOfPath))
+        .chain(rest.into_iter().map(|i| (i, PackingReason::Score)))
+        .chain(overflow.into_iter().map(|i| (i, PackingReason::Overflow)))
+    {
+        let chunk = &chunks[i];
+        let reason = if pinned_paths.contains(&chunk.path) {
+            PackingReason::Pinned
+        } else {
+            pass_reason
+        };
+
+        let formatted = format_snippet(chunk);
+        let tokens = count_tokens(&formatted);
+        let (chunk, formatted, tokens, reason) = if tokens <= remaining {
+            (chunk.clone(), formatted, tokens, reason)
+        } else {
+            match truncate_to_fit(chunk, remaining, &count_tokens) {
+                Some((chunk, formatted, tokens)) => {
+                    (chunk, formatted, tokens, PackingReason::Truncated)
+                }
+                None => {
+                    decisions.push(PackingDecision {
+                        path: chunk.path.clone(),
+                        start_line: chunk.start_line,
+                        end_line: chunk.end_line,
+                        score: chunk.score,
+                        tokens,
+                        included: false,
+                        reason: PackingReason::OverBudget,
+                    });
+                    continue;
+                }
+            }
+        };
+
+        remaining -= tokens;
+        decisions.push(PackingDecision {
+            path: chunk.path.clone(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            score: chunk.score,
+            tokens,
+            included: true,
+            reason,
+        });
+        packed.push((chunk, formatted));
+    }
+
+    (packed, decisions)
+}
+
+/// Packs the related usage of the packed chunks in `budget`, what the chunks left of it, so a
+/// related snippet never takes the place of a chunk. Snippets that don't fit are dropped, and
+/// the ones of chunks that weren't packed or already in the context are skipped.
+pub fn pack_related(
+    related: &[RelatedUsage],
+    packed: &[(CodeChunk, String)],
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> (Vec<(RelatedUsage, String)>, Vec<PackingDecision>) {
+    let mut remaining = budget;
+    let mut packed_related = Vec::new();
+    let mut decisions = Vec::new();
+    for usage in related {
+        let cited = packed.iter().any(|(c, _)| c.path == usage.cited_path);
+        let in_context = packed.iter().any(|(c, _)| {
+            c.path == usage.chunk.path
+                && c.start_line <= usage.chunk.start_line
+                && usage.chunk.end_line <= c.end_line
+        });
+        if !cited || in_context {
+            continue;
+        }
+
+        let formatted = format_related(usage);
+        let tokens = count_tokens(&formatted);
+        let included = tokens <= remaining;
+        decisions.push(PackingDecision {
+            path: usage.chunk.path.clone(),
+            start_line: usage.chunk.start_line,
+            end_line: usage.chunk.end_line,
+            score: None,
+            tokens,
+            included,
+            reason: if included {
+                PackingReason::RelatedUsage
+            } else {
+                PackingReason::OverBudget
+            },
+        });
+        if included {
+            remaining -= tokens;
+            packed_related.push((usage.clone(), formatted));
+        }
+    }
+    (packed_related, decisions)
+}
+
+/// Packs the hops of the data flow in order in `budget`, what the chunks and the related usage
+/// left of it. The flow is cut at the first hop that doesn't fit, a later hop alone doesn't tell
+/// where the value came from.
+pub fn pack_data_flow(
+    hops: &[FlowHop],
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> (Vec<(FlowHop, String)>, Vec<PackingDecision>) {
+    let mut remaining = budget;
+    let mut packed_hops = Vec::new();
+    let mut decisions = Vec::new();
+    for hop in hops {
+        let formatted = format_flow_hop(hop);
+        let tokens = count_tokens(&formatted);
+        let included = tokens <= remaining && packed_hops.len() == decisions.len();
+        decisions.push(PackingDecision {
+            path: hop.chunk.path.clone(),
+            start_line: hop.chunk.start_line,
+            end_line: hop.chunk.end_line,
+            score: None,
+            tokens,
+            included,
+            reason: if included {
+                PackingReason::DataFlow
+            } else {
+                PackingReason::OverBudget
+            },
+        });
+        if included {
+            remaining -= tokens;
+            packed_hops.push((hop.clone(), formatted));
+        }
+    }
+    (packed_hops, decisions)
+}
+
+// Keeps as many leading lines of the chunk as fit in `budget` tokens.
+fn truncate_to_fit(
+    chunk: &CodeChunk,
+    budget: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+) -> Option<(CodeChunk, String, usize)> {
+    let lines = chunk.snippet.lines().collect::<Vec<_>>();
+    let mut best = None;
+    for line_count in 1..lines.len() {
+        let truncated = CodeChunk {
+            snippet: lines[..line_count].join("\n"),
+            end_line: chunk.start_line + line_count,
+            ..chunk.clone()
+        };
+        let formatted = format_snippet(&truncated);
+        let tokens = count_tokens(&formatted);
+        if tokens > budget {
+            break;
+        }
+        best = Some((truncated, formatted, tokens));
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tools::related::Relation;
+
+    // a chunk of `lines` lines, taking `lines + 3` tokens with `count_lines`
+    // (the header line and the two trailing blank lines).
+    fn chunk(path: &str, alias: usize, start_line: usize, lines: usize, score: f32) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            alias: Some(alias),
+            snippet: (0..lines)
+                .map(|i| format!("line {}", start_line + i))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            start_line,
+            end_line: start_line + lines,
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    fn count_lines(s: &str) -> usize {
+        s.lines().count()
+    }
+
+    fn packed_ranges(packed: &[(CodeChunk, String)]) -> Vec<(&str, usize, usize)> {
+        packed
+            .iter()
+            .map(|(c, _)| (c.path.as_str(), c.start_line, c.end_line))
+            .collect()
+    }
+
+    #[test]
+    fn test_doc_is_rendered_above_the_snippet() {
+        let documented = CodeChunk {
+            doc: Some("Adds two numbers.\nWraps on overflow.".to_string()),
+            ..chunk("src/a.rs", 0, 3, 2, 0.9)
+        };
+
+        assert_eq!(
+            format_snippet(&documented),
+            "### src/a.rs ###\nDoc:\n  Adds two numbers.\n  Wraps on overflow.\n4 line 3\n5 line 4\n\n\n"
+        );
+        assert_eq!(
+            format_snippet(&chunk("src/a.rs", 0, 3, 1, 0.9)),
+            "### src/a.rs ###\n4 line 3\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_embedded_language_is_in_the_header() {
+        let query = CodeChunk {
+            embedded_lang: Some("sql".to_string()),
+            ..chunk("src/orders.rs", 0, 3, 1, 0.9)
+        };
+
+        assert_eq!(
+            format_snippet(&query),
+            "### src/orders.rs (embedded SQL) ###\n4 line 3\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_duplicates_are_listed_under_the_header() {
+        let copied = CodeChunk {
+            duplicates: vec!["vendor/a/a.rs".to_string(), "vendor/b/a.rs".to_string()],
+            ..chunk("src/a.rs", 0, 3, 1, 0.9)
+        };
+
+        assert_eq!(
+            format_snippet(&copied),
+            "### src/a.rs ###\nAlso in: vendor/a/a.rs, vendor/b/a.rs\n4 line 3\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_packs_by_score_within_budget() {
+        // an early low score chunk must not crowd out the one with the answer.
+        let chunks = vec![
+            chunk("src/a.rs", 0, 0, 17, 0.1),
+            chunk("src/a.rs", 0, 100, 7, 0.9),
+            chunk("src/a.rs", 0, 200, 7, 0.5),
+        ];
+
+        let (packed, decisions) = pack_chunks(&chunks, &HashSet::new(), 20, count_lines);
+
+        assert_eq!(
+            packed_ranges(&packed),
+            vec![("src/a.rs", 100, 107), ("src/a.rs", 200, 207)]
+        );
+        let used = decisions.iter().filter(|d| d.included).map(|d| d.tokens).sum::<usize>();
+        assert_eq!(used, 20);
+        assert_eq!(decisions[2].reason, PackingReason::OverBudget);
+        assert!(!decisions[2].included);
+    }
+
+    #[test]
+    fn test_every_path_gets_a_chunk_before_second_chunks() {
+        let chunks = vec![
+            chunk("src/a.rs", 0, 0, 5, 0.9),
+            chunk("src/a.rs", 0, 50, 5, 0.8),
+            chunk("src/b.rs", 1, 0, 5, 0.3),
+        ];
+
+        let (packed, decisions) = pack_chunks(&chunks, &HashSet::new(), 16, count_lines);
+
+        assert_eq!(
+            packed_ranges(&packed),
+            vec![("src/a.rs", 0, 5), ("src/b.rs", 0, 5)]
+        );
+        assert_eq!(decisions[0].reason, PackingReason::BestOfPath);
+        assert_eq!(decisions[1].reason, PackingReason::BestOfPath);
+        assert_eq!(decisions[2].reason, PackingReason::OverBudget);
+    }
+
+    #[test]
+    fn test_overflow_chunks_are_packed_last() {
+        let overflow = |start_line| CodeChunk {
+            overflow: true,
+            ..chunk("src/a.rs", 0, start_line, 5, 0.9)
+        };
+        let chunks = vec![
+            chunk("src/a.rs", 0, 0, 5, 0.9),
+            chunk("src/a.rs", 0, 50, 5, 0.9),
+            overflow(100),
+            overflow(150),
+            chunk("src/b.rs", 1, 0, 5, 0.6),
+            chunk("src/b.rs", 1, 50, 5, 0.6),
+        ];
+
+        let (packed, decisions) = pack_chunks(&chunks, &HashSet::new(), 40, count_lines);
+
+        assert_eq!(
+            packed_ranges(&packed),
+            vec![
+                ("src/a.rs", 0, 5),
+                ("src/b.rs", 0, 5),
+                ("src/a.rs", 50, 55),
+                ("src/b.rs", 50, 55),
+                ("src/a.rs", 100, 105),
+            ]
+        );
+        let reasons = decisions.iter().map(|d| d.reason.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                PackingReason::BestOfPath,
+                PackingReason::BestOfPath,
+                PackingReason::Score,
+                PackingReason::Score,
+                PackingReason::Overflow,
+                PackingReason::OverBudget,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oversized_chunk_is_truncated_at_line_boundary() {
+        let chunks = vec![
+            chunk("src/a.rs", 0, 0, 5, 0.9),
+            chunk("src/b.rs", 1, 10, 40, 0.8),
+        ];
+
+        let (packed, decisions) = pack_chunks(&chunks, &HashSet::new(), 20, count_lines);
+
+        // 8 tokens for the first chunk, the remaining 12 fit 9 lines of the second.
+        assert_eq!(
+            packed_ranges(&packed),
+            vec![("src/a.rs", 0, 5), ("src/b.rs", 10, 19)]
+        );
+        assert!(packed[1].0.snippet.ends_with("line 18"));
+        assert_eq!(decisions[1].reason, PackingReason::Truncated);
+        assert_eq!(decisions[1].tokens, 12);
+    }
+
+    fn related(cited: &str, path: &str, start_line: usize, lines: usize) -> RelatedUsage {
+        RelatedUsage {
+            chunk: CodeChunk {
+                score: None,
+                ..chunk(path, 0, start_line, lines, 0.0)
+            },
+            relation: Relation::Caller,
+            symbol: "b".to_string(),
+            cited_path: cited.to_string(),
+            cited_start_line: 0,
+            reason: "`a` calls `b`".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_related_usage_is_labeled() {
+        assert_eq!(
+            format_related(&related("src/b.rs", "src/a.rs", 3, 1)),
+            "### src/a.rs (related usage: `a` calls `b`) ###\n4 line 3\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_related_usage_never_displaces_chunks() {
+        let chunks = vec![
+            chunk("src/b.rs", 0, 0, 5, 0.9),
+            chunk("src/c.rs", 1, 0, 5, 0.5),
+        ];
+        let (packed, decisions) = pack_chunks(&chunks, &HashSet::new(), 20, count_lines);
+        let used = decisions.iter().filter(|d| d.included).map(|d| d.tokens).sum::<usize>();
+        assert_eq!(used, 16);
+
+        // only the first snippet fits in the 4 tokens the chunks left.
+        let candidates = vec![
+            related("src/b.rs", "src/a.rs", 10, 1),
+            related("src/b.rs", "src/a.rs", 20, 1),
+            // cited chunk wasn't packed.
+            related("src/d.rs", "src/a.rs", 30, 1),
+            // already in the context.
+            related("src/b.rs", "src/c.rs", 1, 2),
+        ];
+        let (packed_related, related_decisions) =
+            pack_related(&candidates, &packed, 20 - used, count_lines);
+
+        assert_eq!(packed.len(), 2);
+        assert_eq!(packed_related.len(), 1);
+        assert_eq!(packed_related[0].0.chunk.start_line, 10);
+        assert_eq!(related_decisions.len(), 2);
+        assert_eq!(related_decisions[0].reason, PackingReason::RelatedUsage);
+        assert_eq!(related_decisions[1].reason, PackingReason::OverBudget);
+        assert!(!related_decisions[1].included);
+    }
+
+    #[test]
+    fn test_data_flow_is_cut_at_the_first_hop_over_budget() {
+        let hop = |hop: usize, path: &str, lines: usize| FlowHop {
+            hop,
+            chunk: CodeChunk {
+                score: None,
+                ..chunk(path, hop, 0, lines, 0.0)
+            },
+            symbol: "load".to_string(),
+            label: "`handle` gets it from `load`".to_string(),
+        };
+        let hops = vec![
+            hop(0, "src/handler.rs", 1),
+            hop(1, "src/config.rs", 5),
+            hop(2, "src/settings.rs", 1),
+        ];
+        assert_eq!(
+            format_flow_hop(&hops[0]),
+            "### src/handler.rs (data flow, hop 0: `handle` gets it from `load`) ###\n1 line 0\n\n\n"
+        );
+
+        // the last hop would fit in what the first one left, but not without the second.
+        let (packed, decisions) = pack_data_flow(&hops, 10, count_lines);
+        assert_eq!(packed.len(), 1);
+        assert_eq!(packed[0].0.chunk.path, "src/handler.rs");
+        let reasons = decisions.iter().map(|d| d.reason.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                PackingReason::DataFlow,
+                PackingReason::OverBudget,
+                PackingReason::OverBudget
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pinned_chunks_come_first() {
+        let chunks = vec![
+            chunk("src/a.rs", 0, 0, 10, 0.9),
+            chunk("src/pinned.rs", 1, 0, 10, 0.0),
+        ];
+        let pinned = HashSet::from(["src/pinned.rs".to_string()]);
+
+        let (packed, decisions) = pack_chunks(&chunks, &pinned, 13, count_lines);
+
+        assert_eq!(packed_ranges(&packed), vec![("src/pinned.rs", 0, 10)]);
+        assert_eq!(decisions[0].reason, PackingReason::Pinned);
+        assert_eq!(decisions[1].reason, PackingReason::OverBudget);
+    }
+}
@@ -1,13 +1,16 @@
-use std::collections::HashSet;
-
 use anyhow::Result;
 use tracing::instrument;
 
-use crate::agent::agent::Agent;
+use crate::agent::agent::{Agent, FileDocument, PathMatch};
 
-use crate::agent::exchange::{SearchStep, Update};
+use crate::agent::exchange::{PathGroup, SearchStep, Update};
 use crate::config::get_redis_url;
 
+// tokens of the path tool response, the directories that don't fit are only counted.
+const MAX_PATH_RESPONSE_TOKENS: usize = 400;
+// files listed under each directory, the best scored first.
+const FILES_PER_DIRECTORY: usize = 3;
+
 impl Agent {
     #[instrument(skip(self))]
     pub async fn path_search(&mut self, query: &String) -> Result<String> {
@@ -16,53 +19,335 @@ impl Agent {
             id: last_function_call_id,
             query: query.clone(),
             response: String::new(),
+            groups: Vec::new(),
         }))?;
 
         // First, perform a lexical search for the path
-        let mut paths = self
-            .fuzzy_path_search(query)
-            .await
-            .map(|c| c.relative_path)
-            .collect::<HashSet<_>>() // TODO: This shouldn't be necessary. Path search should return unique results.
-            .into_iter()
-            .collect::<Vec<_>>();
-
-        // If there are no lexical results, perform a semantic search.
-        if paths.is_empty() {
-            let semantic_paths = self
-                .semantic_search(query.into(), 30, 0, 0.0, true)
-                .await?
-                .into_iter()
-                .map(|chunk| chunk.relative_path)
-                .collect::<HashSet<_>>()
-                .into_iter()
-                .collect();
-
-            paths = semantic_paths;
+        let mut matches = self.fuzzy_path_search(query).await.collect::<Vec<_>>();
+
+        // a paths-only index has no chunks, the paths are found by the keywords in their content.
+        if matches.is_empty() && !self.index_mode.is_full() {
+            matches = self.keyword_path_search(query).await;
         }
 
-        let mut paths = paths
-            .iter()
-            .map(|p| (self.get_path_alias(p), p.to_string()))
-            .collect::<Vec<_>>();
-        paths.sort_by(|a: &(usize, String), b| a.0.cmp(&b.0)); // Sort by alias
+        // If there are no lexical results, perform a semantic search, scoring paths by their chunks.
+        if matches.is_empty() {
+            for chunk in self.semantic_search(query.into(), 30, 0, 0.0, true).await? {
+                match matches
+                    .iter_mut()
+                    .find(|m| m.file.relative_path == chunk.relative_path)
+                {
+                    Some(m) => m.score += 1,
+                    None => matches.push(PathMatch {
+                        file: FileDocument {
+                            relative_path: chunk.relative_path,
+                            repo_name: chunk.repo_name,
+                            // the chunk payloads don't carry the ref.
+                            repo_ref: String::new(),
+                            lang: Some(chunk.lang),
+                        },
+                        score: 1,
+                    }),
+                }
+            }
+        }
 
-        let response = paths
-            .iter()
-            .map(|(alias, path)| format!("{}: {}", alias, path))
-            .collect::<Vec<_>>()
-            .join("\n");
+        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo")?;
+        let known_paths = self.paths().map(str::to_owned).collect::<Vec<_>>();
+        let (response, groups) =
+            format_path_groups(&matches, &known_paths, MAX_PATH_RESPONSE_TOKENS, |text| {
+                bpe.encode_ordinary(text).len()
+            });
+        // the aliases of the response are the next ones, register them in the same order.
+        for path in groups.iter().flat_map(|group| group.paths.iter()) {
+            self.get_path_alias(path);
+        }
 
         let last_function_call_id = self.last_function_call_id.clone();
         self.update(Update::ReplaceStep(SearchStep::Path {
             id: last_function_call_id.clone(),
             query: query.clone(),
             response: response.clone(),
+            groups,
         }))?;
-        // save exchanges to redis 
+        // save exchanges to redis
         self.save_exchanges_to_redis(&get_redis_url())?;
-        
+
         let result = "OK";
         Ok(result.to_string())
     }
+
+    /// The code search of a paths-only index: lists the files containing the most keywords of the
+    /// query like the path search does, the model reads them with proc.
+    #[instrument(skip(self))]
+    pub async fn keyword_search(&mut self, query: &String) -> Result<String> {
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::StartStep(SearchStep::Code {
+            id: last_function_call_id,
+            query: query.clone(),
+            response: String::new(),
+        }))?;
+
+        let matches = self.keyword_path_search(query).await;
+        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo")?;
+        let known_paths = self.paths().map(str::to_owned).collect::<Vec<_>>();
+        let (response, groups) =
+            format_path_groups(&matches, &known_paths, MAX_PATH_RESPONSE_TOKENS, |text| {
+                bpe.encode_ordinary(text).len()
+            });
+        for path in groups.iter().flat_map(|group| group.paths.iter()) {
+            self.get_path_alias(path);
+        }
+
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::ReplaceStep(SearchStep::Code {
+            id: last_function_call_id,
+            query: query.clone(),
+            response: match response.is_empty() {
+                true => "No file contains the keywords of the query".to_string(),
+                false => response,
+            },
+        }))?;
+        self.save_exchanges_to_redis(&get_redis_url())?;
+
+        Ok("OK".to_string())
+    }
+
+    // The files containing the keywords of the query, scored by the number of keywords.
+    async fn keyword_path_search(&self, query: &str) -> Vec<PathMatch> {
+        self.app_state
+            .db_connection
+            .keyword_match(&self.repo_name, query, 50)
+            .await
+            .into_iter()
+            .map(|(file, score)| PathMatch { file, score })
+            .collect()
+    }
+}
+
+// Groups the matches by directory, the directories with the best scores first, and lists the top
+// files of each as `alias: path (lang)` until `max_tokens` is reached.
+// Paths already in `known_paths` keep their alias, the others get the following ones.
+fn format_path_groups(
+    matches: &[PathMatch],
+    known_paths: &[String],
+    max_tokens: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> (String, Vec<PathGroup>) {
+    let mut directories: Vec<(&str, Vec<&PathMatch>)> = Vec::new();
+    for m in matches {
+        let directory = directory_of(&m.file.relative_path);
+        match directories.iter_mut().find(|(d, _)| *d == directory) {
+            Some((_, files)) => {
+                if !files
+                    .iter()
+                    .any(|f| f.file.relative_path == m.file.relative_path)
+                {
+                    files.push(m);
+                }
+            }
+            None => directories.push((directory, vec![m])),
+        }
+    }
+    if directories.is_empty() {
+        return (String::new(), Vec::new());
+    }
+
+    for (_, files) in directories.iter_mut() {
+        files.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| a.file.relative_path.cmp(&b.file.relative_path))
+        });
+    }
+    let total_score = |files: &[&PathMatch]| files.iter().map(|f| f.score).sum::<usize>();
+    directories.sort_by(|(a_dir, a), (b_dir, b)| {
+        total_score(b)
+            .cmp(&total_score(a))
+            .then_with(|| b.len().cmp(&a.len()))
+            .then_with(|| a_dir.cmp(b_dir))
+    });
+
+    let total = directories
+        .iter()
+        .map(|(_, files)| files.len())
+        .sum::<usize>();
+    let mut response = format!(
+        "{} in {}, grouped by directory:\n",
+        plural(total, "path"),
+        plural(directories.len(), "directory")
+    );
+    let mut tokens = count_tokens(&response);
+    let mut new_paths: Vec<&str> = Vec::new();
+    let mut groups = Vec::new();
+
+    for (directory, files) in &directories {
+        let mut aliased = new_paths.clone();
+        let mut text = format!(
+            "{}/ ({})\n",
+            if directory.is_empty() {
+                "."
+            } else {
+                *directory
+            },
+            plural(files.len(), "match")
+        );
+        for f in files.iter().take(FILES_PER_DIRECTORY) {
+            let path = f.file.relative_path.as_str();
+            let alias = match known_paths.iter().position(|p| p == path) {
+                Some(alias) => alias,
+                None => {
+                    aliased.push(path);
+                    known_paths.len() + aliased.len() - 1
+                }
+            };
+            match &f.file.lang {
+                Some(lang) => text.push_str(&format!("  {}: {} ({})\n", alias, path, lang)),
+                None => text.push_str(&format!("  {}: {}\n", alias, path)),
+            }
+        }
+
+        // the first directory is always listed.
+        let group_tokens = count_tokens(&text);
+        if !groups.is_empty() && tokens + group_tokens > max_tokens {
+            break;
+        }
+        tokens += group_tokens;
+        new_paths = aliased;
+        response.push_str(&text);
+        groups.push(PathGroup {
+            directory: directory.to_string(),
+            matches: files.len(),
+            paths: files
+                .iter()
+                .take(FILES_PER_DIRECTORY)
+                .map(|f| f.file.relative_path.clone())
+                .collect(),
+        });
+    }
+
+    if groups.len() < directories.len() {
+        let hidden = &directories[groups.len()..];
+        response.push_str(&format!(
+            "{} more in {} not shown, narrow the query to see them.\n",
+            plural(hidden.iter().map(|(_, files)| files.len()).sum(), "path"),
+            plural(hidden.len(), "directory")
+        ));
+    }
+
+    (response.trim_end().to_owned(), groups)
+}
+
+// parent directory of a repo relative path, empty at the root.
+fn directory_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(directory, _)| directory)
+}
+
+pub(crate) fn plural(count: usize, noun: &str) -> String {
+    match (count, noun.strip_suffix('y')) {
+        (1, _) => format!("1 {}", noun),
+        (_, Some(stem)) => format!("{} {}ies", count, stem),
+        _ if noun.ends_with("ch") => format!("{} {}es", count, noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_match(path: &str, lang: Option<&str>, score: usize) -> PathMatch {
+        PathMatch {
+            file: FileDocument {
+                relative_path: path.to_string(),
+                repo_name: "repo".to_string(),
+                repo_ref: "main".to_string(),
+                lang: lang.map(str::to_string),
+            },
+            score,
+        }
+    }
+
+    fn fixture() -> Vec<PathMatch> {
+        vec![
+            path_match("src/auth/middleware.rs", Some("Rust"), 9),
+            path_match("src/auth/session.rs", Some("Rust"), 4),
+            path_match("src/auth/mod.rs", Some("Rust"), 4),
+            path_match("src/auth/token.rs", Some("Rust"), 2),
+            path_match("web/middleware/auth.ts", Some("TypeScript"), 6),
+            path_match("web/middleware/auth.ts", Some("TypeScript"), 6),
+            path_match("docs/auth.md", None, 3),
+            path_match("AUTHORS", None, 1),
+        ]
+    }
+
+    fn count_words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    #[test]
+    fn test_paths_are_grouped_by_directory() {
+        let known = vec!["web/middleware/auth.ts".to_string()];
+        let (response, groups) = format_path_groups(&fixture(), &known, 1000, count_words);
+
+        assert_eq!(
+            response,
+            "7 paths in 4 directories, grouped by directory:\n\
+             src/auth/ (4 matches)\n  \
+               1: src/auth/middleware.rs (Rust)\n  \
+               2: src/auth/mod.rs (Rust)\n  \
+               3: src/auth/session.rs (Rust)\n\
+             web/middleware/ (1 match)\n  \
+               0: web/middleware/auth.ts (TypeScript)\n\
+             docs/ (1 match)\n  \
+               4: docs/auth.md\n\
+             ./ (1 match)\n  \
+               5: AUTHORS"
+        );
+        assert_eq!(groups.len(), 4);
+        assert_eq!(
+            groups[0],
+            PathGroup {
+                directory: "src/auth".to_string(),
+                matches: 4,
+                paths: vec![
+                    "src/auth/middleware.rs".to_string(),
+                    "src/auth/mod.rs".to_string(),
+                    "src/auth/session.rs".to_string(),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_response_is_capped_to_the_token_budget() {
+        let (response, groups) = format_path_groups(&fixture(), &[], 26, count_words);
+
+        assert_eq!(
+            response,
+            "7 paths in 4 directories, grouped by directory:\n\
+             src/auth/ (4 matches)\n  \
+               0: src/auth/middleware.rs (Rust)\n  \
+               1: src/auth/mod.rs (Rust)\n  \
+               2: src/auth/session.rs (Rust)\n\
+             web/middleware/ (1 match)\n  \
+               3: web/middleware/auth.ts (TypeScript)\n\
+             2 more paths in 2 directories not shown, narrow the query to see them."
+        );
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| g.directory.as_str())
+                .collect::<Vec<_>>(),
+            vec!["src/auth", "web/middleware"]
+        );
+    }
+
+    #[test]
+    fn test_no_matches_give_an_empty_response() {
+        let (response, groups) = format_path_groups(&[], &[], 1000, count_words);
+
+        assert!(response.is_empty());
+        assert!(groups.is_empty());
+    }
 }
@@ -0,0 +1,190 @@
+use std::ops::Range;
+
+use anyhow::Result;
+use common::models::PinnedPath;
+use tiktoken_rs::CoreBPE;
+use tracing::instrument;
+
+use crate::agent::agent::Agent;
+use crate::agent::exchange::CodeChunk;
+use crate::config::{get_quickwit_url, get_redis_url};
+
+// Token budget shared by all the files pinned to a query.
+const MAX_PINNED_TOKENS: usize = 6000;
+// Pinned files are split into chunks of this many lines, so a long file fills the budget gradually.
+const PINNED_CHUNK_LINES: usize = 50;
+
+impl Agent {
+    /// Reads the files pinned by the user and adds them to the current exchange as code chunks,
+    /// before the agent runs any search.
+    /// Paths that don't exist in the index are recorded on the exchange instead.
+    #[instrument(skip(self))]
+    pub async fn pin_paths(&mut self, pinned_paths: &[PinnedPath]) -> Result<()> {
+        let search_db_url = get_quickwit_url();
+        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo")?;
+        let mut remaining_tokens = MAX_PINNED_TOKENS;
+
+        for pinned in pinned_paths {
+            let Some(doc) = self.get_file_content(&search_db_url, &pinned.path).await? else {
+                log::warn!("Pinned path does not exist in the index: {}", pinned.path);
+                self.exchanges
+                    .last_mut()
+                    .unwrap()
+                    .missing_pinned_paths
+                    .push(pinned.to_string());
+                continue;
+            };
+
+            let alias = self.get_path_alias(&pinned.path);
+            let (chunks, used_tokens) = chunk_pinned_file(
+                &pinned.path,
+                alias,
+                &doc.content,
+                pinned.lines.clone(),
+                &bpe,
+                remaining_tokens,
+            );
+            remaining_tokens -= used_tokens;
+
+            let exchange = self.exchanges.last_mut().unwrap();
+            exchange.pinned_paths.push(pinned.path.clone());
+            exchange.code_chunks.extend(chunks);
+        }
+
+        self.save_exchanges_to_redis(&get_redis_url())?;
+        Ok(())
+    }
+
+    /// Code chunks of the files pinned by the user.
+    pub fn pinned_chunks(&self) -> impl Iterator<Item = &CodeChunk> {
+        self.exchanges.iter().flat_map(|e| {
+            e.code_chunks
+                .iter()
+                .filter(|c| e.pinned_paths.contains(&c.path))
+        })
+    }
+
+    pub fn is_pinned(&self, path: &str) -> bool {
+        self.exchanges
+            .iter()
+            .any(|e| e.pinned_paths.iter().any(|p| p == path))
+    }
+}
+
+// Splits the pinned lines of a file into chunks, stopping once the token budget is used up.
+// Returns the chunks along with the number of tokens they take.
+fn chunk_pinned_file(
+    path: &str,
+    alias: usize,
+    content: &str,
+    lines: Option<Range<usize>>,
+    bpe: &CoreBPE,
+    max_tokens: usize,
+) -> (Vec<CodeChunk>, usize) {
+    let file_lines = content.lines().collect::<Vec<_>>();
+    let lines = lines.unwrap_or(0..file_lines.len());
+    let lines = lines.start.min(file_lines.len())..lines.end.min(file_lines.len());
+
+    let mut chunks = Vec::new();
+    let mut used_tokens = 0;
+    for start_line in lines.clone().step_by(PINNED_CHUNK_LINES) {
+        let end_line = (start_line + PINNED_CHUNK_LINES).min(lines.end);
+        let snippet = file_lines[start_line..end_line].join("\n");
+        let tokens = bpe.encode_ordinary(&snippet).len();
+        if used_tokens + tokens > max_tokens {
+            log::info!(
+                "Pinned code budget reached, skipping {} from line {}",
+                path,
+                start_line + 1
+            );
+            break;
+        }
+
+        used_tokens += tokens;
+        chunks.push(CodeChunk {
+            path: path.to_owned(),
+            alias: Some(alias),
+            snippet,
+            start_line,
+            end_line,
+            score: None,
+            ..Default::default()
+        });
+    }
+
+    (chunks, used_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::agent::system_prompt;
+
+    fn sample_file(line_count: usize) -> String {
+        (1..=line_count)
+            .map(|i| format!("let token_{i} = refresh_token(&client, {i});"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_pinned_file_is_chunked_by_lines_and_range() {
+        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo").unwrap();
+        let content = sample_file(120);
+
+        let (chunks, _) = chunk_pinned_file("src/token.rs", 0, &content, None, &bpe, 100_000);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| (c.start_line, c.end_line))
+                .collect::<Vec<_>>(),
+            vec![(0, 50), (50, 100), (100, 120)]
+        );
+
+        // 1-based 10-40 from the request is 9..40.
+        let (chunks, _) =
+            chunk_pinned_file("src/token.rs", 0, &content, Some(9..40), &bpe, 100_000);
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].snippet.starts_with("let token_10 "));
+        assert!(chunks[0].snippet.ends_with("refresh_token(&client, 40);"));
+    }
+
+    #[test]
+    fn test_pinned_file_respects_token_budget() {
+        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo").unwrap();
+        let content = sample_file(500);
+
+        let (all_chunks, all_tokens) =
+            chunk_pinned_file("src/token.rs", 0, &content, None, &bpe, usize::MAX);
+        let budget = all_tokens / 2;
+        let (chunks, used_tokens) =
+            chunk_pinned_file("src/token.rs", 0, &content, None, &bpe, budget);
+
+        assert!(used_tokens <= budget);
+        assert!(!chunks.is_empty());
+        assert!(chunks.len() < all_chunks.len());
+    }
+
+    #[test]
+    fn test_pinned_file_appears_in_first_prompt() {
+        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo").unwrap();
+        let content = "pub fn refresh(token: &Token) -> Token {\n    token.renew()\n}";
+        let (chunks, _) = chunk_pinned_file(
+            "services/auth/token.rs",
+            0,
+            content,
+            None,
+            &bpe,
+            MAX_PINNED_TOKENS,
+        );
+
+        let prompt = system_prompt(["services/auth/token.rs"], &chunks, &[]);
+        assert!(prompt.contains("0, services/auth/token.rs"));
+        assert!(prompt.contains("## USER PROVIDED CODE ##"));
+        assert!(prompt.contains("0: services/auth/token.rs\npub fn refresh(token: &Token) -> Token {"));
+
+        // without pinned code the prompt is left untouched.
+        let prompt = system_prompt(["services/auth/token.rs"], &[], &[]);
+        assert!(!prompt.contains("USER PROVIDED CODE"));
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
-    agent::agent::Agent,
+    agent::agent::{Agent, PathRef},
     config::{get_ai_gateway_config, get_quickwit_url, get_redis_url},
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use futures::{stream, StreamExt};
 use log::error;
 use tiktoken_rs::CoreBPE;
@@ -11,31 +11,43 @@ use tracing::{debug, instrument};
 use crate::agent::exchange::{CodeChunk, SearchStep, Update};
 
 use common::{
-    ai_util::{call_llm, extract_single_plaintext_content},
+    ai_util::{call_llm_metered, extract_single_plaintext_content},
     llm_gateway, prompts,
 };
 
+// Share of the trigrams of a requested path a fuzzy match must have to be taken as the path meant.
+const MIN_PATH_CONFIDENCE: f32 = 0.8;
+
 impl Agent {
     #[instrument(skip(self))]
-    pub async fn process_files(&mut self, query: &str, path_aliases: &[usize]) -> Result<String> {
+    pub async fn process_files(&mut self, query: &str, path_refs: &[PathRef]) -> Result<String> {
         const MAX_CHUNK_LINE_LENGTH: usize = 20;
         const CHUNK_MERGE_DISTANCE: usize = 10;
         const MAX_TOKENS: usize = 15400;
 
-        let paths = path_aliases
-            .iter()
-            .copied()
-            .map(|i| self.paths().nth(i).ok_or(i).map(str::to_owned))
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|i| anyhow!("invalid path alias {i}"))?;
+        // entries that don't resolve are reported back in the response, the others are still read.
+        let mut paths: Vec<String> = Vec::new();
+        let mut errors = Vec::new();
+        for path_ref in path_refs {
+            match self.resolve_path_ref(path_ref).await {
+                Ok(path) if !paths.contains(&path) => paths.push(path),
+                Ok(_) => {}
+                Err(e) => errors.push(e),
+            }
+        }
 
-        debug!(?query, ?paths, "invoking proc");
+        debug!(?query, ?paths, ?errors, "invoking proc");
+        self.exchanges
+            .last_mut()
+            .unwrap()
+            .record_prompts(&["file_explanation"]);
 
         let last_function_call_id = self.last_function_call_id.clone();
         self.update(Update::StartStep(SearchStep::Proc {
             id: last_function_call_id,
             query: query.to_string(),
             paths: paths.clone(),
+            requested: path_refs.to_vec(),
             response: String::new(),
         }))?;
 
@@ -86,8 +98,14 @@ impl Agent {
                 let user_prompt = prompts::file_explanation(query, &path, &contents);
                 debug!(?path, "calling chat API on file");
 
-                let llm_output =
-                    call_llm(&get_ai_gateway_config(), Some(user_prompt), None, None).await?;
+                let llm_output = call_llm_metered(
+                    &get_ai_gateway_config(),
+                    Some(&self_.budget),
+                    Some(user_prompt),
+                    None,
+                    None,
+                )
+                .await?;
 
                 let response_message = extract_single_plaintext_content(&llm_output)?;
                 #[derive(
@@ -177,10 +195,12 @@ impl Agent {
 
                 relevant_chunks.into_iter().map(move |c| CodeChunk {
                     path: path.clone(),
-                    alias,
+                    alias: Some(alias),
                     snippet: c.code,
                     start_line: c.range.start,
                     end_line: c.range.end,
+                    score: None,
+                    ..Default::default()
                 })
             })
             .collect::<Vec<_>>();
@@ -195,10 +215,9 @@ impl Agent {
                 .push(chunk.clone())
         }
 
-        let response = chunks
-            .iter()
-            .filter(|c| !c.is_empty())
-            .map(|c| c.to_string())
+        let response = errors
+            .into_iter()
+            .chain(chunks.iter().filter(|c| !c.is_empty()).map(|c| c.to_string()))
             .collect::<Vec<_>>()
             .join("\n\n");
 
@@ -207,6 +226,7 @@ impl Agent {
             id: last_function_call_id,
             query: query.to_string(),
             paths,
+            requested: path_refs.to_vec(),
             response: response.clone(),
         }))?;
         // save exchanges to redis.
@@ -214,6 +234,89 @@ impl Agent {
 
         Ok(response)
     }
+
+    // The path an entry of `proc` refers to, registered with an alias, or why it doesn't resolve.
+    async fn resolve_path_ref(&mut self, path_ref: &PathRef) -> Result<String, String> {
+        let known_paths = self.paths().map(str::to_owned).collect::<Vec<_>>();
+        // the index is only searched for paths that aren't known yet.
+        let indexed_paths = match path_ref {
+            PathRef::Path(path) if !known_paths.iter().any(|p| p == normalize_path(path)) => {
+                self.fuzzy_path_search(normalize_path(path))
+                    .await
+                    .map(|m| m.file.relative_path)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        let resolved = resolve_path(path_ref, &known_paths, &indexed_paths)?;
+        self.get_path_alias(&resolved);
+        Ok(resolved)
+    }
+}
+
+// Resolves an alias against the known paths, and a path passed as a string to a known path or an
+// indexed one matching it exactly first, then to the indexed path closest to it when it is close
+// enough.
+fn resolve_path(
+    path_ref: &PathRef,
+    known_paths: &[String],
+    indexed_paths: &[String],
+) -> Result<String, String> {
+    let requested = match path_ref {
+        PathRef::Alias(alias) => {
+            return known_paths
+                .get(*alias)
+                .cloned()
+                .ok_or_else(|| format!("{alias}: invalid path alias"));
+        }
+        PathRef::Path(path) => path,
+    };
+    let path = normalize_path(requested);
+    if let Some(exact) = known_paths
+        .iter()
+        .chain(indexed_paths)
+        .find(|p| p.as_str() == path)
+    {
+        return Ok(exact.clone());
+    }
+
+    // the first of the best matches, the fuzzy search ranks them already.
+    let mut best: Option<(&String, f32)> = None;
+    for candidate in indexed_paths {
+        let confidence = path_confidence(path, candidate);
+        if best.map_or(true, |(_, best)| confidence > best) {
+            best = Some((candidate, confidence));
+        }
+    }
+    match best {
+        Some((candidate, confidence)) if confidence >= MIN_PATH_CONFIDENCE => Ok(candidate.clone()),
+        _ => Err(format!("{requested}: no indexed path matches it")),
+    }
+}
+
+// Models write paths with a leading `/` or `./`, the index has them relative to the repo root.
+fn normalize_path(path: &str) -> &str {
+    let path = path.trim();
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.trim_start_matches('/')
+}
+
+// Share of the lowercase trigrams of `requested` found in `candidate`.
+fn path_confidence(requested: &str, candidate: &str) -> f32 {
+    let trigrams = |s: &str| {
+        let chars = s.to_lowercase().chars().collect::<Vec<_>>();
+        chars
+            .windows(3)
+            .map(|w| w.iter().collect::<String>())
+            .collect::<Vec<_>>()
+    };
+    let requested = trigrams(requested);
+    if requested.is_empty() {
+        return 0.0;
+    }
+    let candidate = trigrams(candidate);
+    let found = requested.iter().filter(|t| candidate.contains(t)).count();
+    found as f32 / requested.len() as f32
 }
 
 fn trim_lines_by_tokens(lines: Vec<String>, bpe: CoreBPE, max_tokens: usize) -> Vec<String> {
@@ -239,6 +342,107 @@ fn trim_lines_by_tokens(lines: Vec<String>, bpe: CoreBPE, max_tokens: usize) ->
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::agent::agent::Action;
+    use ai_gateway::function_calling::FunctionCall;
+
+    fn proc_call(arguments: &str) -> Vec<PathRef> {
+        let call = FunctionCall {
+            name: "proc".to_string(),
+            arguments: arguments.to_string(),
+        };
+        match Action::deserialize_gpt(&call).unwrap() {
+            Action::Proc { paths, .. } => paths,
+            action => panic!("expected proc, got {:?}", action),
+        }
+    }
+
+    fn resolve_all(paths: &[PathRef], indexed_paths: &[String]) -> Vec<Result<String, String>> {
+        let known_paths = vec![
+            "src/auth/middleware.rs".to_string(),
+            "src/auth/session.rs".to_string(),
+        ];
+        paths
+            .iter()
+            .map(|path| resolve_path(path, &known_paths, indexed_paths))
+            .collect()
+    }
+
+    #[test]
+    fn test_proc_with_aliases() {
+        let paths = proc_call(r#"{"query": "session expiry", "paths": [1, 0]}"#);
+
+        assert_eq!(paths, vec![PathRef::Alias(1), PathRef::Alias(0)]);
+        assert_eq!(
+            resolve_all(&paths, &[]),
+            vec![
+                Ok("src/auth/session.rs".to_string()),
+                Ok("src/auth/middleware.rs".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_proc_with_paths() {
+        let paths = proc_call(
+            r#"{"query": "session expiry", "paths": ["/src/auth/session.rs", "src/auth/tokens.rs"]}"#,
+        );
+        let indexed = vec![
+            "src/auth/token.rs".to_string(),
+            "web/auth/tokens.ts".to_string(),
+        ];
+
+        assert_eq!(
+            paths,
+            vec![
+                PathRef::Path("/src/auth/session.rs".to_string()),
+                PathRef::Path("src/auth/tokens.rs".to_string())
+            ]
+        );
+        // a known path matches exactly, the closest indexed path is taken for the other.
+        assert_eq!(
+            resolve_all(&paths, &indexed),
+            vec![
+                Ok("src/auth/session.rs".to_string()),
+                Ok("src/auth/token.rs".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_proc_with_aliases_and_paths() {
+        let paths = proc_call(r#"{"query": "login", "paths": [0, "./src/auth/login.rs"]}"#);
+        let indexed = vec!["src/auth/login.rs".to_string()];
+
+        assert_eq!(
+            paths,
+            vec![
+                PathRef::Alias(0),
+                PathRef::Path("./src/auth/login.rs".to_string())
+            ]
+        );
+        assert_eq!(
+            resolve_all(&paths, &indexed),
+            vec![
+                Ok("src/auth/middleware.rs".to_string()),
+                Ok("src/auth/login.rs".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unresolvable_entries_fail_on_their_own() {
+        let paths = proc_call(r#"{"query": "login", "paths": [7, "docs/intro.md", 1]}"#);
+        let indexed = vec!["src/auth/login.rs".to_string()];
+
+        assert_eq!(
+            resolve_all(&paths, &indexed),
+            vec![
+                Err("7: invalid path alias".to_string()),
+                Err("docs/intro.md: no indexed path matches it".to_string()),
+                Ok("src/auth/session.rs".to_string())
+            ]
+        );
+    }
 
     #[test]
     fn test_trim_lines_by_tokens() {
@@ -0,0 +1,448 @@
+//! Related usage of the functions found by the code search: the call sites of a function and the
+//! functions it calls, so the answer can tell how the code found is used.
+//! Call sites come from the references of the stored scope graph of the file, the callees defined
+//! in other files from the symbols collection, which only stores definitions.
+
+use anyhow::Result;
+use common::ast::{
+    ast_graph::{NodeKind, ScopeGraph},
+    symbol::SymbolLocations,
+    text_range::TextRange,
+};
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::agent::agent::Agent;
+use crate::agent::exchange::CodeChunk;
+use crate::config::get_quickwit_url;
+use crate::helpers::symbol_search::exact_symbol_lookup;
+
+// chunks of the code search, by score, whose functions get related usage.
+pub const RELATED_TOP_CHUNKS: usize = 3;
+// related snippets per chunk.
+pub const MAX_RELATED_PER_CHUNK: usize = 3;
+// lines kept around a call site, and below the signature of a callee.
+const CALL_SITE_CONTEXT: usize = 2;
+const CALLEE_LINES: usize = 4;
+
+// Questions asking where files are rather than what the code does.
+const PATH_QUESTION_PREFIXES: &[&str] = &[
+    "which file",
+    "which files",
+    "what file",
+    "what files",
+    "where is the file",
+    "where are the files",
+    "list the files",
+    "list files",
+    "which directory",
+    "which folder",
+    "what directory",
+    "what folder",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Relation {
+    // the snippet calls the cited function.
+    Caller,
+    // the snippet is a function the cited function calls.
+    Callee,
+}
+
+/// A snippet added next to a chunk of the code search because it calls, or is called by, the
+/// function defined in the chunk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelatedUsage {
+    pub chunk: CodeChunk,
+    pub relation: Relation,
+    // the function defined in the cited chunk.
+    pub symbol: String,
+    pub cited_path: String,
+    pub cited_start_line: usize,
+    // why the snippet was added, also used as its label in the answer context.
+    pub reason: String,
+}
+
+/// The related usage found in the file of a cited chunk.
+#[derive(Debug, Default)]
+pub struct FileUsage {
+    pub symbol: String,
+    pub related: Vec<RelatedUsage>,
+    // functions called by the cited one that aren't defined in the file.
+    pub external_callees: Vec<String>,
+}
+
+/// True for questions about where files are, related usage doesn't help answering them.
+pub fn is_path_question(query: &str) -> bool {
+    let query = query.trim().to_lowercase();
+    PATH_QUESTION_PREFIXES
+        .iter()
+        .any(|prefix| query.starts_with(prefix))
+}
+
+/// Finds the callers and callees of the function defined in `cited`, within its file.
+/// Returns None when the chunk doesn't hold the definition of a function.
+pub fn file_usage(graph: &ScopeGraph, content: &str, cited: &CodeChunk) -> Option<FileUsage> {
+    let src = content.as_bytes();
+    let lines = content.lines().collect::<Vec<_>>();
+    let def = function_defs(graph)
+        .filter(|&idx| in_chunk(cited, graph.graph[idx].range().start.line))
+        .min_by_key(|&idx| graph.graph[idx].range().start.byte)?;
+    let symbol = name_of(graph, src, def)?;
+    let body = body_of(graph, def);
+
+    let mut usage = FileUsage {
+        symbol: symbol.clone(),
+        ..Default::default()
+    };
+
+    let mut callers = graph
+        .references(def)
+        .map(|idx| graph.graph[idx].range())
+        .filter(|range| !in_chunk(cited, range.start.line))
+        .collect::<Vec<_>>();
+    callers.sort_by_key(|range| range.start.byte);
+    for range in callers {
+        let line = range.start.line;
+        let start = line.saturating_sub(CALL_SITE_CONTEXT);
+        let end = (line + CALL_SITE_CONTEXT + 1).min(lines.len());
+        let reason = match enclosing_function(graph, src, range.start.byte) {
+            Some(caller) => format!("`{caller}` calls `{symbol}`"),
+            None => format!("calls `{symbol}`"),
+        };
+        usage.related.push(related(
+            cited,
+            &lines,
+            start..end,
+            Relation::Caller,
+            &symbol,
+            reason,
+        ));
+    }
+
+    let mut seen = vec![symbol.clone()];
+    for idx in graph.graph.node_indices() {
+        let NodeKind::Ref(reference) = &graph.graph[idx] else {
+            continue;
+        };
+        let range = reference.range;
+        if range.start.byte < body.start.byte
+            || range.end.byte > body.end.byte
+            || !is_call(src, &range)
+        {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&src[range.start.byte..range.end.byte]).to_string();
+        if seen.contains(&name) {
+            continue;
+        }
+        seen.push(name.clone());
+
+        match graph.definitions(idx).next() {
+            Some(callee) => {
+                let start = graph.graph[callee].range().start.line;
+                let end = (body_of(graph, callee).end.line + 1)
+                    .min(start + CALLEE_LINES)
+                    .min(lines.len());
+                usage.related.push(related(
+                    cited,
+                    &lines,
+                    start..end,
+                    Relation::Callee,
+                    &symbol,
+                    format!("`{symbol}` calls `{name}`, defined here"),
+                ));
+            }
+            None => usage.external_callees.push(name),
+        }
+    }
+
+    Some(usage)
+}
+
+/// Keeps the snippets that don't overlap the chunks already found or each other, at most
+/// `MAX_RELATED_PER_CHUNK` of them, call sites first.
+pub fn select_related(
+    related: Vec<RelatedUsage>,
+    found: &[CodeChunk],
+    selected: &[RelatedUsage],
+) -> Vec<RelatedUsage> {
+    let mut kept: Vec<RelatedUsage> = Vec::new();
+    for usage in related {
+        if kept.len() == MAX_RELATED_PER_CHUNK {
+            break;
+        }
+        let overlaps = found
+            .iter()
+            .chain(selected.iter().map(|r| &r.chunk))
+            .chain(kept.iter().map(|r| &r.chunk))
+            .any(|c| overlap(c, &usage.chunk));
+        if !overlaps && !usage.chunk.is_empty() {
+            kept.push(usage);
+        }
+    }
+    kept
+}
+
+impl Agent {
+    /// Related usage of the functions defined in the best chunks of a code search.
+    /// A file that can't be read or has no scope graph is skipped, the search itself succeeded.
+    pub async fn related_usage(&mut self, chunks: &[CodeChunk]) -> Vec<RelatedUsage> {
+        let mut top = chunks.iter().filter(|c| !c.is_empty()).collect::<Vec<_>>();
+        top.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+        top.truncate(RELATED_TOP_CHUNKS);
+
+        let found = self
+            .exchanges
+            .iter()
+            .flat_map(|e| e.code_chunks.iter().cloned())
+            .collect::<Vec<_>>();
+        let mut selected: Vec<RelatedUsage> = Vec::new();
+        for cited in top {
+            match self.related_usage_of(cited).await {
+                Ok(related) => {
+                    let related = select_related(related, &found, &selected);
+                    for usage in &related {
+                        info!(
+                            path = %usage.chunk.path,
+                            start_line = usage.chunk.start_line,
+                            end_line = usage.chunk.end_line,
+                            relation = ?usage.relation,
+                            cited = %format!("{}:{}", usage.cited_path, usage.cited_start_line),
+                            reason = %usage.reason,
+                            "added related usage"
+                        );
+                    }
+                    selected.extend(related);
+                }
+                Err(e) => warn!("Failed to find the related usage of {}: {}", cited.path, e),
+            }
+        }
+        selected
+    }
+
+    async fn related_usage_of(&mut self, cited: &CodeChunk) -> Result<Vec<RelatedUsage>> {
+        let Some(doc) = self
+            .get_file_content(&get_quickwit_url(), &cited.path)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let locations = bincode::deserialize::<SymbolLocations>(&doc.symbol_locations)?;
+        let Some(graph) = locations.scope_graph() else {
+            return Ok(Vec::new());
+        };
+        let Some(mut usage) = file_usage(graph, &doc.content, cited) else {
+            return Ok(Vec::new());
+        };
+
+        // the callees defined in other files, while there is room left for them.
+        for name in usage.external_callees.clone() {
+            if usage.related.len() >= MAX_RELATED_PER_CHUNK {
+                break;
+            }
+            let occurrences = exact_symbol_lookup(
+                &name,
+                None,
+                None,
+                Some(true),
+                &self.repo_name,
+                self.index_generation.as_deref(),
+            )
+            .await?;
+            let Some(definition) = occurrences
+                .into_iter()
+                .find(|o| o.path != cited.path && o.start_line.is_some())
+            else {
+                continue;
+            };
+            let Some(callee_doc) = self
+                .get_file_content(&get_quickwit_url(), &definition.path)
+                .await?
+            else {
+                continue;
+            };
+            let lines = callee_doc.content.lines().collect::<Vec<_>>();
+            let start = definition.start_line.unwrap_or_default();
+            let end = definition
+                .end_line
+                .map_or(start + CALLEE_LINES, |end| end + 1)
+                .min(start + CALLEE_LINES)
+                .min(lines.len());
+            let mut callee = related(
+                cited,
+                &lines,
+                start..end,
+                Relation::Callee,
+                &usage.symbol,
+                format!("`{}` calls `{name}`, defined here", usage.symbol),
+            );
+            callee.chunk.path = definition.path.clone();
+            callee.chunk.alias = Some(self.get_path_alias(&definition.path));
+            usage.related.push(callee);
+        }
+        Ok(usage.related)
+    }
+}
+
+fn related(
+    cited: &CodeChunk,
+    lines: &[&str],
+    range: std::ops::Range<usize>,
+    relation: Relation,
+    symbol: &str,
+    reason: String,
+) -> RelatedUsage {
+    let start = range.start.min(lines.len());
+    let end = range.end.clamp(start, lines.len());
+    RelatedUsage {
+        chunk: CodeChunk {
+            path: cited.path.clone(),
+            repo: cited.repo.clone(),
+            alias: cited.alias,
+            snippet: lines[start..end].join("\n"),
+            start_line: start,
+            end_line: end,
+            score: None,
+            ..Default::default()
+        },
+        relation,
+        symbol: symbol.to_string(),
+        cited_path: cited.path.clone(),
+        cited_start_line: cited.start_line,
+        reason,
+    }
+}
+
+pub(crate) fn function_defs(graph: &ScopeGraph) -> impl Iterator<Item = NodeIndex> + '_ {
+    graph.graph.node_indices().filter(|&idx| {
+        matches!(graph.graph[idx], NodeKind::Def(_))
+            && matches!(graph.symbol_name_of(idx), Some("function" | "method"))
+    })
+}
+
+pub(crate) fn name_of(graph: &ScopeGraph, src: &[u8], idx: NodeIndex) -> Option<String> {
+    let range = graph.graph[idx].range();
+    let name = src.get(range.start.byte..range.end.byte)?;
+    Some(String::from_utf8_lossy(name).to_string())
+}
+
+// The body of a function, its own name when the graph has no scope for it.
+pub(crate) fn body_of(graph: &ScopeGraph, def: NodeIndex) -> TextRange {
+    graph
+        .value_of_definition(def)
+        .map(|idx| graph.graph[idx].range())
+        .unwrap_or_else(|| graph.graph[def].range())
+}
+
+// The innermost function whose body holds the byte.
+pub(crate) fn enclosing_def(graph: &ScopeGraph, byte: usize) -> Option<NodeIndex> {
+    function_defs(graph)
+        .filter(|&idx| {
+            let body = body_of(graph, idx);
+            body.start.byte <= byte && byte < body.end.byte
+        })
+        .min_by_key(|&idx| body_of(graph, idx).size())
+}
+
+pub(crate) fn enclosing_function(graph: &ScopeGraph, src: &[u8], byte: usize) -> Option<String> {
+    name_of(graph, src, enclosing_def(graph, byte)?)
+}
+
+// A reference followed by an argument list.
+pub(crate) fn is_call(src: &[u8], range: &TextRange) -> bool {
+    src[range.end.byte..]
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map_or(false, |b| *b == b'(')
+}
+
+// Chunks hold at least their first line.
+fn in_chunk(chunk: &CodeChunk, line: usize) -> bool {
+    chunk.start_line <= line && line < chunk.end_line.max(chunk.start_line + 1)
+}
+
+fn overlap(a: &CodeChunk, b: &CodeChunk) -> bool {
+    a.path == b.path && (in_chunk(a, b.start_line) || in_chunk(b, a.start_line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::ast::CodeFileAST;
+
+    const SOURCE: &str =
+        "fn b(x: i32) -> i32 {\n    x * 2\n}\n\nfn a() -> i32 {\n    let y = b(3);\n    y + 1\n}\n";
+
+    fn graph() -> ScopeGraph {
+        CodeFileAST::build_ast(SOURCE.as_bytes(), "Rust")
+            .unwrap()
+            .scope_graph()
+            .unwrap()
+    }
+
+    fn chunk(start_line: usize, end_line: usize) -> CodeChunk {
+        let lines = SOURCE.lines().collect::<Vec<_>>();
+        CodeChunk {
+            path: "src/lib.rs".to_string(),
+            alias: Some(0),
+            snippet: lines[start_line..end_line].join("\n"),
+            start_line,
+            end_line,
+            score: Some(0.9),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_asking_about_the_callee_pulls_its_call_site() {
+        let usage = file_usage(&graph(), SOURCE, &chunk(0, 3)).unwrap();
+
+        assert_eq!(usage.symbol, "b");
+        let caller = &usage.related[0];
+        assert_eq!(caller.relation, Relation::Caller);
+        assert!(caller.chunk.snippet.contains("let y = b(3);"));
+        assert_eq!(caller.reason, "`a` calls `b`");
+        assert_eq!(caller.cited_path, "src/lib.rs");
+        assert!(usage.external_callees.is_empty());
+    }
+
+    #[test]
+    fn test_asking_about_the_caller_pulls_the_callee() {
+        let usage = file_usage(&graph(), SOURCE, &chunk(4, 8)).unwrap();
+
+        assert_eq!(usage.symbol, "a");
+        let callee = usage
+            .related
+            .iter()
+            .find(|r| r.relation == Relation::Callee)
+            .unwrap();
+        assert!(callee.chunk.snippet.starts_with("fn b(x: i32)"));
+        assert_eq!(callee.reason, "`a` calls `b`, defined here");
+    }
+
+    #[test]
+    fn test_chunks_without_a_function_have_no_usage() {
+        assert!(file_usage(&graph(), SOURCE, &chunk(3, 4)).is_none());
+    }
+
+    #[test]
+    fn test_snippets_already_found_are_skipped() {
+        let usage = file_usage(&graph(), SOURCE, &chunk(0, 3)).unwrap();
+
+        // the chunk of `a` is already part of the search results.
+        let kept = select_related(usage.related.clone(), &[chunk(4, 8)], &[]);
+        assert!(kept.is_empty());
+        let kept = select_related(usage.related, &[chunk(0, 3)], &[]);
+        assert_eq!(kept.len(), 1);
+    }
+
+    #[test]
+    fn test_path_questions_are_detected() {
+        assert!(is_path_question("Which files define the payment service?"));
+        assert!(is_path_question("  list files under src/agent"));
+        assert!(!is_path_question("How is the retry delay computed?"));
+    }
+}
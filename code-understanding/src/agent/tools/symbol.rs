@@ -0,0 +1,90 @@
+use crate::agent::agent::Agent;
+use crate::config::get_redis_url;
+use crate::helpers::symbol_search::exact_symbol_lookup;
+
+use crate::agent::exchange::{SearchStep, Update};
+use anyhow::Result;
+use tracing::instrument;
+
+use log::error;
+
+impl Agent {
+    #[instrument(skip(self))]
+    pub async fn symbol_lookup(
+        &mut self,
+        name: &String,
+        kind: Option<&str>,
+        container: Option<&str>,
+        case_sensitive: Option<bool>,
+    ) -> Result<String> {
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::StartStep(SearchStep::Symbol {
+            id: last_function_call_id,
+            query: name.clone(),
+            kind: kind.map(str::to_owned),
+            container: container.map(str::to_owned),
+            case_sensitive,
+            response: String::new(),
+        }))?;
+
+        let (lookup_name, lookup_kind, lookup_container, repo_name) = (
+            name.clone(),
+            kind.map(str::to_owned),
+            container.map(str::to_owned),
+            self.repo_name.clone(),
+        );
+        let index_generation = self.index_generation.clone();
+        let occurrences = match self
+            .run
+            .spawn(async move {
+                exact_symbol_lookup(
+                    &lookup_name,
+                    lookup_kind.as_deref(),
+                    lookup_container.as_deref(),
+                    case_sensitive,
+                    &repo_name,
+                    index_generation.as_deref(),
+                )
+                .await
+            })
+            .await
+        {
+            Ok(Some(Ok(occurrences))) => occurrences,
+            Ok(Some(Err(e))) => {
+                error!("Call to exact symbol lookup API failed: {:?}", e);
+                return Err(e);
+            }
+            Ok(None) => return Err(anyhow::anyhow!("Exact symbol lookup was cancelled")),
+            Err(e) => return Err(anyhow::anyhow!("Exact symbol lookup task failed: {}", e)),
+        };
+
+        // an empty result is a valid answer, the model is told to try a different function.
+        // symbols are shown with the definitions they are in, e.g. `PaymentService::retry`.
+        let response = occurrences
+            .iter()
+            .map(|o| {
+                let alias = self.get_path_alias(&o.path);
+                let lines = match (o.start_line, o.end_line) {
+                    (Some(start), Some(end)) => format!(":{}-{}", start, end),
+                    _ => String::new(),
+                };
+                format!("{}: {}{} {} {}", alias, o.path, lines, o.node_kind, o.breadcrumb())
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        log::debug!("response: {}", response);
+        let last_function_call_id = self.last_function_call_id.clone();
+        self.update(Update::ReplaceStep(SearchStep::Symbol {
+            id: last_function_call_id,
+            query: name.clone(),
+            kind: kind.map(str::to_owned),
+            container: container.map(str::to_owned),
+            case_sensitive,
+            response: response.clone(),
+        }))?;
+        // save exchanges to redis
+        self.save_exchanges_to_redis(&get_redis_url())?;
+        Ok(response)
+    }
+}
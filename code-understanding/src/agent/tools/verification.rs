@@ -0,0 +1,202 @@
+// Verification steps of the answer, see `common::verification`. They are asked for once the answer
+// is written, over the context it was written from, and kept only when grounded: a step running a
+// test cites a test file the code search found, the others cite a file of the answer context. The
+// steps left are checked like the citations of the answer, the ones citing lines their file
+// doesn't have are dropped.
+
+use std::future::Future;
+
+use ai_gateway::message::message::Message;
+use anyhow::{anyhow, Result};
+use common::{
+    ai_util::{call_llm_metered, extract_single_plaintext_content},
+    citations::CitationReport,
+    language::with_language,
+    prompts,
+    verification::{
+        apply_citations, parse_verification_steps, render_verification, VerificationKind,
+        VerificationStep,
+    },
+};
+
+use crate::agent::agent::Agent;
+use crate::agent::exchange::{LlmCall, LlmStage};
+use crate::agent::tools::answer::build_answer;
+use crate::config::{get_ai_gateway_config, get_quickwit_url, get_redis_url};
+
+// The LLM call of the steps and the steps kept.
+struct Verification {
+    messages: Vec<Message>,
+    response: Vec<Message>,
+    steps: Vec<VerificationStep>,
+}
+
+impl Agent {
+    /// The verification steps of the answer of the last exchange, asked for once per exchange.
+    pub async fn verify(&mut self, answer: &str) -> Result<Vec<VerificationStep>> {
+        let exchange = self.get_final_anwer();
+        if let Some(steps) = &exchange.verification {
+            return Ok(steps.clone());
+        }
+        let trace = exchange.answer_trace.as_ref().ok_or_else(|| {
+            anyhow!("The exchange has no answer trace to build the verification context from")
+        })?;
+        // the same trace builds the same context, the steps see the code the answer saw.
+        let built = build_answer(trace, None)?;
+        let context_paths = built
+            .final_context
+            .iter()
+            .map(|context| context.path.clone())
+            .collect::<Vec<_>>();
+
+        let budget = self.budget.clone();
+        let agent = &*self;
+        let verification = verify_with(
+            &built.context,
+            answer,
+            trace.language.as_deref(),
+            &context_paths,
+            &exchange.test_paths,
+            |messages| async move {
+                call_llm_metered(&get_ai_gateway_config(), Some(&budget), None, Some(messages), None)
+                    .await
+            },
+            |path| async move {
+                let document = agent.get_file_content(&get_quickwit_url(), &path).await?;
+                Ok(document.map(|document| document.content.lines().count()))
+            },
+        )
+        .await?;
+        log::info!(
+            "{} grounded verification steps for {}",
+            verification.steps.len(),
+            self.query_id
+        );
+
+        let exchange = self.exchanges.last_mut().unwrap();
+        exchange.llm_calls.push(LlmCall {
+            stage: LlmStage::Verification,
+            messages: verification.messages,
+            response: verification.response,
+            history_tokens_saved: 0,
+        });
+        exchange.verification = Some(verification.steps.clone());
+        exchange.record_prompts(&["verification_prompt"]);
+        self.save_exchanges_to_redis(&get_redis_url())?;
+        Ok(verification.steps)
+    }
+}
+
+// Asks `llm` for the steps of `answer` over `context`, keeps the grounded ones and checks their
+// citations with `fetch`, which returns the number of lines of a file like for the answer.
+async fn verify_with<L, LFut, F, FFut>(
+    context: &str,
+    answer: &str,
+    language: Option<&str>,
+    context_paths: &[String],
+    test_paths: &[String],
+    llm: L,
+    fetch: F,
+) -> Result<Verification>
+where
+    L: FnOnce(Vec<Message>) -> LFut,
+    LFut: Future<Output = Result<Vec<Message>>>,
+    F: FnMut(String) -> FFut,
+    FFut: Future<Output = Result<Option<usize>>>,
+{
+    let prompt = with_language(
+        prompts::verification_prompt(context, answer, test_paths),
+        language,
+    );
+    let messages = vec![Message::system(&prompt)];
+    let response = llm(messages.clone()).await?;
+
+    let (steps, ungrounded): (Vec<_>, Vec<_>) =
+        parse_verification_steps(&extract_single_plaintext_content(&response)?)
+            .into_iter()
+            .partition(|step| is_grounded(step, context_paths, test_paths));
+    for step in &ungrounded {
+        log::debug!("Dropped the ungrounded verification step {:?}", step);
+    }
+
+    let report = CitationReport::resolve(&render_verification(&steps), fetch).await;
+    Ok(Verification {
+        messages,
+        response,
+        steps: apply_citations(steps, &report),
+    })
+}
+
+// Tests are only run from the test files found, the other steps exercise the code of the context.
+fn is_grounded(step: &VerificationStep, context_paths: &[String], test_paths: &[String]) -> bool {
+    match step.kind {
+        VerificationKind::RunTest => test_paths.contains(&step.path),
+        _ => context_paths.contains(&step.path) || test_paths.contains(&step.path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTEXT: &str = "##### PATHS #####\nsrc/refund.rs\n\n##### CODE CHUNKS #####\n\n\
+        src/refund.rs\n11 pub fn retry_refund(refund: &Refund) {\n12     log::info!(\"Retrying refund {}\", refund.id);\n13 }\n";
+
+    const RESPONSE: &str = r#"[
+  {"kind": "run_test", "instruction": "Run the refund tests", "path": "tests/refund_test.rs", "command": "cargo test --test refund_test"},
+  {"kind": "run_test", "instruction": "Run the payment tests", "path": "tests/payment_test.rs", "command": "cargo test --test payment_test"},
+  {"kind": "check_log", "instruction": "Look for the 'Retrying refund' line", "path": "src/refund.rs", "lines": [12, 12]},
+  {"kind": "hit_endpoint", "instruction": "Send a refund request", "path": "src/api/refunds.rs", "lines": [5, 20]}
+]"#;
+
+    async fn line_counts(path: String) -> Result<Option<usize>> {
+        Ok(match path.as_str() {
+            "src/refund.rs" => Some(40),
+            // the test file isn't indexed.
+            "tests/refund_test.rs" => None,
+            _ => Some(100),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_only_grounded_steps_with_valid_citations_are_kept() {
+        let verification = verify_with(
+            CONTEXT,
+            "Refunds are retried in [`retry_refund`](src/refund.rs#L11-L13).",
+            None,
+            &["src/refund.rs".to_string()],
+            &["tests/refund_test.rs".to_string(), "tests/api_test.rs".to_string()],
+            |messages| async move {
+                let Message::PlainText { content, .. } = &messages[0] else {
+                    return Err(anyhow!("the prompt isn't plain text"));
+                };
+                assert!(content.contains("tests/api_test.rs"));
+                assert!(content.contains("Refunds are retried in"));
+                Ok(vec![Message::assistant(RESPONSE)])
+            },
+            line_counts,
+        )
+        .await
+        .unwrap();
+
+        // the payment tests weren't found, the endpoint isn't in the context and the refund tests
+        // aren't indexed.
+        assert_eq!(verification.steps.len(), 1);
+        assert_eq!(verification.steps[0].kind, VerificationKind::CheckLog);
+        assert_eq!(verification.steps[0].lines, Some((12, 12)));
+        assert_eq!(verification.messages.len(), 1);
+
+        let verification = verify_with(
+            CONTEXT,
+            "Refunds are retried in [`retry_refund`](src/refund.rs#L11-L13).",
+            None,
+            &["src/refund.rs".to_string()],
+            &[],
+            |_| async move { Ok(vec![Message::assistant("I can't tell how to check this.")]) },
+            line_counts,
+        )
+        .await
+        .unwrap();
+        assert!(verification.steps.is_empty());
+    }
+}
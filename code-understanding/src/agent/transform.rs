@@ -11,8 +11,9 @@ use std::mem;
 use serde::Deserialize;
 
 
+// A code block of the xml article of the model, rendered to markdown.
 #[derive(serde::Deserialize, Debug)]
-enum CodeChunk {
+enum ArticleCode {
     QuotedCode {
         #[serde(default, rename = "Code")]
         code: String,
@@ -35,10 +36,10 @@ enum CodeChunk {
 
 
 
-impl CodeChunk {
+impl ArticleCode {
     fn to_markdown(&self) -> String {
         let (ty, code, lang, path, start, end) = match self {
-            CodeChunk::QuotedCode {
+            ArticleCode::QuotedCode {
                 code,
                 language,
                 path,
@@ -52,7 +53,7 @@ impl CodeChunk {
                 start_line.map(|n| n.saturating_sub(1)),
                 end_line.map(|n| n.saturating_sub(1)),
             ),
-            CodeChunk::GeneratedCode { code, language } => {
+            ArticleCode::GeneratedCode { code, language } => {
                 ("Generated", code, language, "", None, None)
             }
         };
@@ -86,7 +87,7 @@ fn try_trim_code_xml(xml: &str) -> Result<String> {
     let code_chunk = de::from_str(&xml).context("couldn't parse as XML code block")?;
 
     Ok(match code_chunk {
-        CodeChunk::QuotedCode {
+        ArticleCode::QuotedCode {
             code: _,
             language,
             path,
@@ -111,7 +112,7 @@ fn try_trim_code_xml(xml: &str) -> Result<String> {
             )
         }
 
-        CodeChunk::GeneratedCode { code: _, language } => {
+        ArticleCode::GeneratedCode { code: _, language } => {
             format!(
                 "<GeneratedCode>\n\
                 <Code>[REDACTED]</Code>\n\
@@ -406,7 +407,7 @@ fn sanitize(article: &str) -> String {
 
 fn xml_to_markdown(xml: &str) -> Result<String> {
     let code_chunk =
-        de::from_str::<CodeChunk>(xml).context("failed to deserialize code chunk")?;
+        de::from_str::<ArticleCode>(xml).context("failed to deserialize code chunk")?;
 
     Ok(code_chunk.to_markdown())
 }
@@ -0,0 +1,303 @@
+// Retrieval shared by the questions of a `POST /answer-batch` request. The questions of a task
+// mostly read the same files, the documents they fetch and the searches they run are kept for
+// the lifetime of the batch so that every one of them is only sent to the backend once.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use common::generation::IndexGenerationGone;
+use common::models::CodeChunk;
+use tokio::sync::OnceCell;
+
+use crate::agent::agent::ContentDocument;
+use crate::content_cache::{CacheKey, CacheStatus, ContentCache, ContentCacheConfig};
+
+// a batch is answered against a single index, its documents don't need a generation.
+const BATCH_GENERATION: &str = "batch";
+const MAX_BATCH_DOCUMENTS: usize = 512;
+
+type SharedSearch = Arc<OnceCell<Result<Vec<CodeChunk>, SearchFailure>>>;
+
+// why a shared search failed, a dropped index generation is kept typed for the replies.
+#[derive(Clone, Debug)]
+enum SearchFailure {
+    GenerationGone(IndexGenerationGone),
+    Failed(String),
+}
+
+pub struct BatchContext {
+    documents: ContentCache,
+    searches: Mutex<HashMap<String, SharedSearch>>,
+    // documents and searches sent to the backend for the whole batch.
+    document_fetches: AtomicUsize,
+    search_calls: AtomicUsize,
+}
+
+/// The view of the batch handed to the agent of one question, counts its own cache hits.
+#[derive(Clone)]
+pub struct BatchScope {
+    context: Arc<BatchContext>,
+    stats: Arc<QuestionStats>,
+}
+
+#[derive(Default)]
+struct QuestionStats {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl BatchContext {
+    pub fn new() -> Self {
+        Self {
+            documents: ContentCache::new(ContentCacheConfig {
+                entries: MAX_BATCH_DOCUMENTS,
+                spill_dir: None,
+                spill_bytes: 0,
+                generation_ttl: Duration::ZERO,
+            }),
+            searches: Mutex::new(HashMap::new()),
+            document_fetches: AtomicUsize::new(0),
+            search_calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn scope(self: &Arc<Self>) -> BatchScope {
+        BatchScope {
+            context: self.clone(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// The document of the path, fetched with `fetch` by the first question that reads it.
+    pub async fn document<F, Fut>(
+        &self,
+        repo: &str,
+        path: &str,
+        fetch: F,
+    ) -> Result<(Option<ContentDocument>, CacheStatus)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<ContentDocument>>>,
+    {
+        let key = CacheKey {
+            repo: repo.to_string(),
+            path: path.to_string(),
+            generation: BATCH_GENERATION.to_string(),
+        };
+        let (document, status) = self.documents.get_or_fetch(&key, fetch).await?;
+        if status == CacheStatus::Miss {
+            self.document_fetches.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok((document, status))
+    }
+
+    /// The results of the search of `query`, run with `search` by the first question asking it.
+    pub async fn search<F, Fut>(&self, query: &str, search: F) -> Result<Vec<CodeChunk>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<CodeChunk>>>,
+    {
+        let cell = self
+            .searches
+            .lock()
+            .unwrap()
+            .entry(query.trim().to_string())
+            .or_default()
+            .clone();
+        cell.get_or_init(|| async {
+            self.search_calls.fetch_add(1, Ordering::SeqCst);
+            search().await.map_err(|e| match e.downcast::<IndexGenerationGone>() {
+                Ok(gone) => SearchFailure::GenerationGone(gone),
+                Err(e) => SearchFailure::Failed(e.to_string()),
+            })
+        })
+        .await
+        .clone()
+        .map_err(|failure| match failure {
+            SearchFailure::GenerationGone(gone) => gone.into(),
+            SearchFailure::Failed(e) => anyhow!(e),
+        })
+    }
+
+    pub fn document_fetches(&self) -> usize {
+        self.document_fetches.load(Ordering::SeqCst)
+    }
+
+    pub fn search_calls(&self) -> usize {
+        self.search_calls.load(Ordering::SeqCst)
+    }
+}
+
+impl BatchScope {
+    pub async fn document<F, Fut>(
+        &self,
+        repo: &str,
+        path: &str,
+        fetch: F,
+    ) -> Result<Option<ContentDocument>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<ContentDocument>>>,
+    {
+        let (document, status) = self.context.document(repo, path, fetch).await?;
+        let counter = match status {
+            CacheStatus::Hit => &self.stats.hits,
+            CacheStatus::Miss => &self.stats.misses,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(document)
+    }
+
+    pub async fn search<F, Fut>(&self, query: &str, search: F) -> Result<Vec<CodeChunk>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<CodeChunk>>>,
+    {
+        self.context.search(query, search).await
+    }
+
+    /// Documents of the question read from the batch, and the ones it had to fetch.
+    pub fn document_stats(&self) -> (usize, usize) {
+        (
+            self.stats.hits.load(Ordering::SeqCst),
+            self.stats.misses.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// The distinct paths of the search results, in the order they were found.
+pub fn result_paths(chunks: &[CodeChunk]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    chunks
+        .iter()
+        .filter(|chunk| seen.insert(chunk.path.as_str()))
+        .map(|chunk| chunk.path.clone())
+        .collect()
+}
+
+/// Paths found by the initial searches of at least two questions, sorted.
+pub fn shared_paths(initial_paths: &[Vec<String>]) -> Vec<String> {
+    let mut questions = BTreeMap::<&str, usize>::new();
+    for paths in initial_paths {
+        for path in paths.iter().map(String::as_str).collect::<HashSet<_>>() {
+            *questions.entry(path).or_default() += 1;
+        }
+    }
+    questions
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(path, _)| path.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(path: &str) -> ContentDocument {
+        ContentDocument {
+            repo_name: "acme/api".to_string(),
+            relative_path: path.to_string(),
+            content: format!("// {}", path),
+            ..Default::default()
+        }
+    }
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_paths_found_by_two_questions_are_shared() {
+        let initial = vec![
+            paths(&["src/auth.rs", "src/token.rs", "src/auth.rs"]),
+            paths(&["src/token.rs", "src/db.rs"]),
+            paths(&["src/auth.rs", "src/main.rs"]),
+        ];
+        assert_eq!(
+            shared_paths(&initial),
+            paths(&["src/auth.rs", "src/token.rs"])
+        );
+        assert!(shared_paths(&initial[..1]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_questions_fetch_shared_documents_once() {
+        let context = Arc::new(BatchContext::new());
+        let fetches = AtomicUsize::new(0);
+        // every question reads the two shared files and one of its own.
+        let questions = vec![
+            paths(&["src/auth.rs", "src/token.rs", "src/login.rs"]),
+            paths(&["src/auth.rs", "src/token.rs", "src/logout.rs"]),
+            paths(&["src/auth.rs", "src/token.rs", "src/refresh.rs"]),
+        ];
+
+        let scopes = questions
+            .iter()
+            .map(|_| context.scope())
+            .collect::<Vec<_>>();
+        futures::future::join_all(questions.iter().zip(&scopes).map(|(reads, scope)| {
+            let fetches = &fetches;
+            async move {
+                for path in reads {
+                    let found = scope
+                        .document("acme/api", path, || async {
+                            fetches.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                            Ok(Some(document(path)))
+                        })
+                        .await
+                        .unwrap();
+                    assert_eq!(found.unwrap().relative_path, *path);
+                }
+            }
+        }))
+        .await;
+
+        let reads = questions.iter().map(Vec::len).sum::<usize>();
+        assert_eq!(fetches.load(Ordering::SeqCst), 5);
+        assert!(fetches.load(Ordering::SeqCst) < reads);
+        assert_eq!(context.document_fetches(), 5);
+        let (hits, misses) = scopes
+            .iter()
+            .map(BatchScope::document_stats)
+            .fold((0, 0), |(h, m), (hits, misses)| (h + hits, m + misses));
+        assert_eq!((hits, misses), (4, 5));
+    }
+
+    #[tokio::test]
+    async fn test_the_same_search_runs_once_per_batch() {
+        let context = Arc::new(BatchContext::new());
+        let calls = &AtomicUsize::new(0);
+        let search = move || async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![CodeChunk {
+                path: "src/auth.rs".to_string(),
+                snippet: "fn login() {}".to_string(),
+                start_line: 1,
+                end_line: 1,
+                score: None,
+                ..Default::default()
+            }])
+        };
+
+        let first = context
+            .scope()
+            .search("where is login", search)
+            .await
+            .unwrap();
+        let second = context
+            .scope()
+            .search(" where is login ", search)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(context.search_calls(), 1);
+        assert_eq!(result_paths(&first), paths(&["src/auth.rs"]));
+    }
+}
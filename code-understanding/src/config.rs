@@ -1,8 +1,12 @@
 use anyhow::Context;
+use common::budget::BudgetConfig;
 use common::docker::is_running_in_docker;
+use common::retrieval_feedback::FeedbackLog;
 use log::info;
-use std::{env, fs};
+use std::{collections::HashMap, env, fs, path::PathBuf, time::Duration};
 
+use crate::agent::digest::{HistoryMode, HISTORY_MODE_ENV};
+use crate::content_cache::ContentCacheConfig;
 use crate::CONFIG;
 #[derive(Clone, Debug, Default)]
 pub struct Config {
@@ -14,6 +18,29 @@ pub struct Config {
     pub model_path: String,
     // String containing the yaml configuration of the AI Gateway
     pub ai_gateway_config: String,
+    // git working copies of the indexed repos, used to answer questions from the commit history.
+    pub repo_working_copies: HashMap<String, PathBuf>,
+    // hides the commit authors from the history given to the model.
+    pub redact_commit_authors: bool,
+    // documents kept by the content cache, 0 disables it.
+    pub content_cache_entries: usize,
+    // directory the documents of at least `content_cache_spill_bytes` are cached in.
+    pub content_cache_spill_dir: Option<PathBuf>,
+    pub content_cache_spill_bytes: usize,
+    // seconds the index generation of a repo is trusted before it is looked up again.
+    pub content_cache_generation_ttl_secs: u64,
+    // prices of the models, used to meter the answers against the allowance sent by the coordinator.
+    pub budget: BudgetConfig,
+    // whether the agent sends the earlier steps to the model as their digests.
+    pub history_mode: HistoryMode,
+    // hops the data flow of a value is followed for, see `agent::tools::data_flow`.
+    pub data_flow_max_hops: usize,
+    // model of `POST /explain-symbol`, a small one keeps the hovers fast. The configured one when unset.
+    pub explain_symbol_model: Option<String>,
+    // explanations kept by `POST /explain-symbol`, 0 disables the cache.
+    pub explain_cache_entries: usize,
+    // where the retrieved and cited chunks of every answer are logged, not logged when unset.
+    pub retrieval_feedback_log: Option<FeedbackLog>,
 }
 
 pub fn load_from_env(env_file: Option<String>) -> Config {
@@ -26,6 +53,12 @@ pub fn load_from_env(env_file: Option<String>) -> Config {
         // Load the default .env file, exit if it fails
         dotenv::dotenv().expect("Failed to load environment variables from .env file");
     }
+
+    config_from_env()
+}
+
+/// Reads the configuration from the env, without loading an env file.
+pub fn config_from_env() -> Config {
     // Attempt to retrieve AI gateway configuration path from environment
     let ai_gateway_config_path = env::var("AI_GATEWAY_CONFIG_PATH")
         .expect("AI_GATEWAY_CONFIG_PATH environment variable is not set");
@@ -48,6 +81,50 @@ pub fn load_from_env(env_file: Option<String>) -> Config {
         env::var("SEARCH_SERVER_URL").unwrap_or_else(|_| "http://localhost:3003".to_string());
     let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
     let model_path = env::var("MODEL_DIR").expect("MODEL_DIR environment variable is not set");
+    let repo_working_copies = env::var("REPO_WORKING_COPIES")
+        .map(|value| parse_working_copies(&value))
+        .unwrap_or_default();
+    let redact_commit_authors = env::var("REDACT_COMMIT_AUTHORS")
+        .map(|value| value.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    let content_cache_entries = env::var("CONTENT_CACHE_ENTRIES")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(256);
+    let content_cache_spill_dir = env::var("CONTENT_CACHE_SPILL_DIR")
+        .ok()
+        .filter(|dir| !dir.trim().is_empty())
+        .map(PathBuf::from);
+    let content_cache_spill_bytes = env::var("CONTENT_CACHE_SPILL_BYTES")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(256 * 1024);
+    let content_cache_generation_ttl_secs = env::var("CONTENT_CACHE_GENERATION_TTL_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(30);
+    let budget = BudgetConfig::from_env().expect("The budget environment variables are invalid");
+    let history_mode = env::var(HISTORY_MODE_ENV)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| value.parse().expect("The history mode is invalid"))
+        .unwrap_or_default();
+    let data_flow_max_hops = env::var("DATA_FLOW_MAX_HOPS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(3);
+    let explain_symbol_model = env::var("EXPLAIN_SYMBOL_MODEL")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+    let explain_cache_entries = env::var("EXPLAIN_CACHE_ENTRIES")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(1024);
+    let retrieval_feedback_log = env::var("RETRIEVAL_FEEDBACK_LOG")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| value.parse().expect("RETRIEVAL_FEEDBACK_LOG is invalid"));
 
     Config {
         qdrant_api_key,
@@ -57,9 +134,31 @@ pub fn load_from_env(env_file: Option<String>) -> Config {
         redis_url,
         ai_gateway_config,
         model_path,
+        repo_working_copies,
+        redact_commit_authors,
+        content_cache_entries,
+        content_cache_spill_dir,
+        content_cache_spill_bytes,
+        content_cache_generation_ttl_secs,
+        budget,
+        history_mode,
+        data_flow_max_hops,
+        explain_symbol_model,
+        explain_cache_entries,
+        retrieval_feedback_log,
     }
 }
 
+// Parses `<repo>=<path>` pairs separated by commas, as printed by the ingestion for every indexed repo.
+fn parse_working_copies(value: &str) -> HashMap<String, PathBuf> {
+    value
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(repo, path)| (repo.trim().to_string(), PathBuf::from(path.trim())))
+        .filter(|(repo, path)| !repo.is_empty() && !path.as_os_str().is_empty())
+        .collect()
+}
+
 pub fn get_semantic_url() -> String {
     CONFIG.read().unwrap().semantic_url.clone()
 }
@@ -81,6 +180,10 @@ pub fn get_redis_url() -> String {
     CONFIG.read().unwrap().redis_url.clone()
 }
 
+pub fn get_budget_config() -> BudgetConfig {
+    CONFIG.read().unwrap().budget.clone()
+}
+
 pub fn clone_config() -> Config {
     CONFIG.read().unwrap().clone()
 }
@@ -88,3 +191,47 @@ pub fn clone_config() -> Config {
 pub fn get_model_path() -> String {
     CONFIG.read().unwrap().model_path.clone()
 }
+
+/// The working copy of the repo, `None` when the repo has no configured working copy.
+pub fn get_repo_working_copy(repo_name: &str) -> Option<PathBuf> {
+    CONFIG
+        .read()
+        .unwrap()
+        .repo_working_copies
+        .get(repo_name)
+        .cloned()
+}
+
+pub fn get_history_mode() -> HistoryMode {
+    CONFIG.read().unwrap().history_mode
+}
+
+pub fn get_data_flow_max_hops() -> usize {
+    CONFIG.read().unwrap().data_flow_max_hops
+}
+
+pub fn get_explain_symbol_model() -> Option<String> {
+    CONFIG.read().unwrap().explain_symbol_model.clone()
+}
+
+pub fn get_explain_cache_entries() -> usize {
+    CONFIG.read().unwrap().explain_cache_entries
+}
+
+pub fn get_retrieval_feedback_log() -> Option<FeedbackLog> {
+    CONFIG.read().unwrap().retrieval_feedback_log.clone()
+}
+
+pub fn get_redact_commit_authors() -> bool {
+    CONFIG.read().unwrap().redact_commit_authors
+}
+
+pub fn get_content_cache_config() -> ContentCacheConfig {
+    let config = CONFIG.read().unwrap();
+    ContentCacheConfig {
+        entries: config.content_cache_entries,
+        spill_dir: config.content_cache_spill_dir.clone(),
+        spill_bytes: config.content_cache_spill_bytes,
+        generation_ttl: Duration::from_secs(config.content_cache_generation_ttl_secs),
+    }
+}
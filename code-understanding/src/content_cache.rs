@@ -0,0 +1,410 @@
+// In-memory LRU of the documents fetched from quickwit by path. The same files are read on most
+// questions of a conversation, and every fetch pulls their content and scope graph.
+// Entries are keyed by the index generation of the repo, the run id of its last ingestion run, so
+// a re-index busts them. Large entries can be spilled to disk, concurrent fetches of a missing
+// entry are coalesced into a single backend request.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::OnceCell;
+
+use crate::agent::agent::ContentDocument;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub repo: String,
+    pub path: String,
+    pub generation: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentCacheConfig {
+    // 0 disables the cache.
+    pub entries: usize,
+    // entries whose content is at least `spill_bytes` long are written there instead of memory.
+    pub spill_dir: Option<PathBuf>,
+    pub spill_bytes: usize,
+    // how long the generation of a repo is trusted before it is looked up again.
+    pub generation_ttl: Duration,
+}
+
+enum Stored {
+    Memory(ContentDocument),
+    Disk(PathBuf),
+}
+
+struct Entry {
+    stored: Stored,
+    last_used: u64,
+}
+
+// The result of a fetch shared with the requests coalesced on it, errors as their message.
+type InFlight = Arc<OnceCell<Result<Option<ContentDocument>, String>>>;
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<CacheKey, Entry>,
+    in_flight: HashMap<CacheKey, InFlight>,
+    // generation of every repo and when it was looked up.
+    generations: HashMap<String, (Option<String>, Instant)>,
+    tick: u64,
+}
+
+pub struct ContentCache {
+    config: ContentCacheConfig,
+    state: Mutex<State>,
+}
+
+impl ContentCache {
+    pub fn new(config: ContentCacheConfig) -> Self {
+        if let Some(dir) = &config.spill_dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                log::warn!("Failed to create the content cache spill directory {:?}: {}", dir, e);
+            }
+        }
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.entries > 0
+    }
+
+    /// The generation of the repo, looked up with `lookup` once it is older than the ttl.
+    /// Entries of the previous generations of the repo are dropped when it changes.
+    pub async fn generation<F, Fut>(&self, repo: &str, lookup: F) -> Option<String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<String>>,
+    {
+        {
+            let state = self.state.lock().unwrap();
+            if let Some((generation, looked_up)) = state.generations.get(repo) {
+                if looked_up.elapsed() < self.config.generation_ttl {
+                    return generation.clone();
+                }
+            }
+        }
+
+        let generation = lookup().await;
+        let mut state = self.state.lock().unwrap();
+        let previous = state
+            .generations
+            .insert(repo.to_string(), (generation.clone(), Instant::now()));
+        if previous.map(|(previous, _)| previous) != Some(generation.clone()) {
+            let stale = state
+                .entries
+                .keys()
+                .filter(|key| key.repo == repo && Some(&key.generation) != generation.as_ref())
+                .cloned()
+                .collect::<Vec<_>>();
+            if !stale.is_empty() {
+                log::info!(
+                    "Index generation of {} changed, dropping {} cached documents",
+                    repo,
+                    stale.len()
+                );
+            }
+            for key in stale {
+                if let Some(entry) = state.entries.remove(&key) {
+                    remove_spilled(&entry);
+                }
+            }
+        }
+        generation
+    }
+
+    /// The cached document of the key, or the one returned by `fetch`. Concurrent requests of a
+    /// key that isn't cached wait for the first one's fetch instead of fetching it again, they
+    /// count as hits. Documents that aren't found aren't cached.
+    pub async fn get_or_fetch<F, Fut>(
+        &self,
+        key: &CacheKey,
+        fetch: F,
+    ) -> Result<(Option<ContentDocument>, CacheStatus)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<ContentDocument>>>,
+    {
+        if let Some(document) = self.get(key).await {
+            return Ok((Some(document), CacheStatus::Hit));
+        }
+
+        let cell = self
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut fetched = false;
+        let result = cell
+            .get_or_init(|| {
+                fetched = true;
+                async move { fetch().await.map_err(|e| e.to_string()) }
+            })
+            .await
+            .clone();
+
+        if fetched {
+            let spilled = match &result {
+                Ok(Some(document)) => Some(self.spill(key, document).await),
+                _ => None,
+            };
+            let mut state = self.state.lock().unwrap();
+            state.in_flight.remove(key);
+            if let (Ok(Some(document)), Some(spilled)) = (&result, spilled) {
+                let stored = spilled.unwrap_or_else(|| Stored::Memory(document.clone()));
+                self.insert(&mut state, key.clone(), stored);
+            }
+        }
+
+        let status = if fetched {
+            CacheStatus::Miss
+        } else {
+            CacheStatus::Hit
+        };
+        result.map(|document| (document, status)).map_err(|e| anyhow!(e))
+    }
+
+    async fn get(&self, key: &CacheKey) -> Option<ContentDocument> {
+        let spilled = {
+            let mut state = self.state.lock().unwrap();
+            state.tick += 1;
+            let tick = state.tick;
+            let entry = state.entries.get_mut(key)?;
+            entry.last_used = tick;
+            match &entry.stored {
+                Stored::Memory(document) => return Some(document.clone()),
+                Stored::Disk(path) => path.clone(),
+            }
+        };
+
+        let document = tokio::fs::read(&spilled)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(anyhow::Error::from));
+        match document {
+            Ok(document) => Some(document),
+            Err(e) => {
+                log::warn!("Failed to read the spilled document {:?}: {}", spilled, e);
+                self.state.lock().unwrap().entries.remove(key);
+                None
+            }
+        }
+    }
+
+    // Writes large documents to the spill directory, None when the document is kept in memory.
+    async fn spill(&self, key: &CacheKey, document: &ContentDocument) -> Option<Stored> {
+        let dir = self.config.spill_dir.as_ref()?;
+        if document.content.len() < self.config.spill_bytes {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let path = dir.join(format!("{:016x}.json", hasher.finish()));
+        let written = match serde_json::to_vec(document) {
+            Ok(bytes) => tokio::fs::write(&path, bytes).await.map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        match written {
+            Ok(()) => Some(Stored::Disk(path)),
+            Err(e) => {
+                log::warn!("Failed to spill {} to {:?}, keeping it in memory: {}", key.path, path, e);
+                None
+            }
+        }
+    }
+
+    fn insert(&self, state: &mut State, key: CacheKey, stored: Stored) {
+        state.tick += 1;
+        let last_used = state.tick;
+        state.entries.insert(key, Entry { stored, last_used });
+        while state.entries.len() > self.config.entries {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = state.entries.remove(&oldest) {
+                remove_spilled(&entry);
+            }
+        }
+    }
+}
+
+fn remove_spilled(entry: &Entry) {
+    if let Stored::Disk(path) = &entry.stored {
+        if let Err(e) = std::fs::remove_file(path) {
+            log::warn!("Failed to remove the spilled document {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache(entries: usize) -> ContentCache {
+        ContentCache::new(ContentCacheConfig {
+            entries,
+            spill_dir: None,
+            spill_bytes: 0,
+            generation_ttl: Duration::ZERO,
+        })
+    }
+
+    fn key(path: &str, generation: &str) -> CacheKey {
+        CacheKey {
+            repo: "acme/api".to_string(),
+            path: path.to_string(),
+            generation: generation.to_string(),
+        }
+    }
+
+    fn document(path: &str) -> ContentDocument {
+        ContentDocument {
+            repo_name: "acme/api".to_string(),
+            relative_path: path.to_string(),
+            content: format!("// {}", path),
+            ..Default::default()
+        }
+    }
+
+    async fn fetch_counted(
+        cache: &ContentCache,
+        key: &CacheKey,
+        fetches: &AtomicUsize,
+    ) -> CacheStatus {
+        let (found, status) = cache
+            .get_or_fetch(key, || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(Some(document(&key.path)))
+            })
+            .await
+            .unwrap();
+        assert_eq!(found.unwrap().relative_path, key.path);
+        status
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_are_coalesced() {
+        let cache = cache(8);
+        let fetches = AtomicUsize::new(0);
+        let main = key("src/main.rs", "run-1");
+
+        let statuses = futures::future::join_all(
+            (0..5).map(|_| fetch_counted(&cache, &main, &fetches)),
+        )
+        .await;
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            statuses.iter().filter(|s| **s == CacheStatus::Miss).count(),
+            1
+        );
+        assert_eq!(fetch_counted(&cache, &main, &fetches).await, CacheStatus::Hit);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_entry_is_evicted() {
+        let cache = cache(2);
+        let fetches = AtomicUsize::new(0);
+        let (a, b, c) = (
+            key("a.rs", "run-1"),
+            key("b.rs", "run-1"),
+            key("c.rs", "run-1"),
+        );
+
+        fetch_counted(&cache, &a, &fetches).await;
+        fetch_counted(&cache, &b, &fetches).await;
+        // reading a makes b the least recently used.
+        assert_eq!(fetch_counted(&cache, &a, &fetches).await, CacheStatus::Hit);
+        fetch_counted(&cache, &c, &fetches).await;
+
+        assert_eq!(fetch_counted(&cache, &a, &fetches).await, CacheStatus::Hit);
+        assert_eq!(fetch_counted(&cache, &c, &fetches).await, CacheStatus::Hit);
+        assert_eq!(fetch_counted(&cache, &b, &fetches).await, CacheStatus::Miss);
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_new_generation_drops_the_old_entries() {
+        let cache = cache(8);
+        let fetches = AtomicUsize::new(0);
+
+        let generation = cache
+            .generation("acme/api", || async { Some("run-1".to_string()) })
+            .await
+            .unwrap();
+        fetch_counted(&cache, &key("src/main.rs", &generation), &fetches).await;
+        assert_eq!(cache.state.lock().unwrap().entries.len(), 1);
+
+        // the repo was indexed again.
+        let generation = cache
+            .generation("acme/api", || async { Some("run-2".to_string()) })
+            .await
+            .unwrap();
+        assert!(cache.state.lock().unwrap().entries.is_empty());
+        assert_eq!(
+            fetch_counted(&cache, &key("src/main.rs", &generation), &fetches).await,
+            CacheStatus::Miss
+        );
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_large_documents_are_spilled_to_disk() {
+        let dir = std::env::temp_dir().join(format!("content-cache-{}", uuid::Uuid::new_v4()));
+        let cache = ContentCache::new(ContentCacheConfig {
+            entries: 1,
+            spill_dir: Some(dir.clone()),
+            spill_bytes: 1,
+            generation_ttl: Duration::ZERO,
+        });
+        let fetches = AtomicUsize::new(0);
+
+        fetch_counted(&cache, &key("src/main.rs", "run-1"), &fetches).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(
+            fetch_counted(&cache, &key("src/main.rs", "run-1"), &fetches).await,
+            CacheStatus::Hit
+        );
+
+        // the evicted entry's file is removed.
+        fetch_counted(&cache, &key("src/lib.rs", "run-1"), &fetches).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,38 +1,520 @@
 use crate::agent::exchange::load_exchanges_from_redis;
-use crate::config::get_ai_gateway_config;
+use crate::batch::{result_paths, shared_paths, BatchContext, BatchScope};
+use crate::config::{
+    get_ai_gateway_config, get_budget_config, get_history_mode, get_quickwit_url, get_redis_url,
+    get_retrieval_feedback_log,
+};
+use crate::helpers::symbol_search::symbol_search;
+use crate::helpers::terminology::fetch_terminology;
 use crate::AppState;
 use ai_gateway::config::AIGatewayConfig;
-use common::models::CodeUnderstandRequest;
-use common::CodeUnderstanding;
-use std::time::Duration;
+use common::auth::Tenant;
+use common::budget::{BudgetExceeded, BudgetMeter};
+use common::generation::IndexGenerationGone;
+use common::glossary::Glossary;
+use common::ast::symbol::SymbolLocations;
+use common::citations::{CitationReport, CitationStatus};
+use common::dependencies::DependencyChecker;
+use common::prompt_versions::PromptVersion;
+use common::language::normalize_language;
+use common::models::{
+    BatchAnswer, BatchStreamItem, BatchTrace, CodeUnderstandBatchRequest, CodeUnderstandRequest,
+    ExplainSymbolRequest, PinnedPath,
+};
+use common::redaction::{redact_secrets, redaction_enabled};
+use common::retrieval_feedback::RetrievalFeedback;
+use common::shutdown;
+use common::task_graph::redis::load_task_process_from_redis;
+use common::terminology::Terminology;
+use common::timings::PhaseTimings;
+use common::transport::Transport;
+use common::verification::VerificationStep;
+use common::{AnswerOutcome, CodeUnderstanding};
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
 use crate::agent::agent::Action;
 use crate::agent::agent::Agent;
+use crate::agent::call_log::CallLog;
+use crate::agent::cancellation::AgentRun;
 use crate::agent::exchange::Exchange;
+use crate::agent::replay::{replay_exchange, ReplayRequest};
+use crate::agent::tools::data_flow::is_data_flow_question;
+use crate::agent::tools::related::{enclosing_function, is_path_question};
+use crate::db_client::DbConnect;
+use crate::explain::{call_explain_model, explain_with, find_callee, line_byte, load_file};
 use anyhow::Result;
 use std::convert::Infallible;
 use std::sync::Arc;
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
 use warp::http::StatusCode;
+use warp::hyper::Body;
+use warp::Reply;
 
 use log::error;
 
+// Replays the answer of a stored or posted trace with the options of the request and reports
+// what changed, see `agent::replay`. The searches aren't run again.
+pub async fn handle_replay(
+    req: ReplayRequest,
+    _tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    let exchanges = match (req.trace, &req.query_id) {
+        (Some(trace), _) => trace,
+        (None, Some(query_id)) => match load_exchanges_from_redis(query_id) {
+            Ok(Some(exchanges)) => exchanges,
+            Ok(None) => {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Error: No exchanges stored for {}", query_id)),
+                    StatusCode::NOT_FOUND,
+                ))
+            }
+            Err(e) => {
+                error!("Failed to load the exchanges of {}: {}", query_id, e);
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&format!("Error: {}", e)),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        },
+        (None, None) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Error: Either query_id or trace is required"),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    let index = req.exchange.unwrap_or(exchanges.len().saturating_sub(1));
+    let Some(exchange) = exchanges.get(index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&format!("Error: The trace has no exchange {}", index)),
+            StatusCode::NOT_FOUND,
+        ));
+    };
+    if exchange.answer_trace.is_none() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Error: The exchange was answered before answers were traced"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+    }
+
+    match replay_exchange(exchange, &req.options).await {
+        Ok(report) => Ok(warp::reply::with_status(
+            warp::reply::json(&report),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to replay exchange {} of {}: {}", index, exchange.id, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+// Ready when qdrant lists its collections and quickwit its indexes. Both calls reconnect like the
+// agent's requests do, `degraded` is set while a client couldn't be rebuilt yet.
+pub async fn handle_ready(app_state: Arc<AppState>) -> Result<impl warp::Reply, Infallible> {
+    let qdrant_state = app_state.clone();
+    let readiness = DependencyChecker::new()
+        .check_readiness("qdrant", move || {
+            let app_state = qdrant_state.clone();
+            async move {
+                app_state
+                    .db_connection
+                    .semantic
+                    .qdrant
+                    .check(|client| async move { client.list_collections().await })
+                    .await
+            }
+        })
+        .check_readiness("quickwit", move || {
+            let app_state = app_state.clone();
+            async move {
+                app_state
+                    .db_connection
+                    .http_client
+                    .check(|client| async move { DbConnect::list_indexes(&client).await })
+                    .await
+            }
+        })
+        .readiness()
+        .await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&readiness), status))
+}
+
 pub async fn handle_retrieve_code(
     req: CodeUnderstandRequest,
+    accept: Option<String>,
     app_state: Arc<AppState>,
 ) -> Result<impl warp::Reply, Infallible> {
     log::info!("Query: {}, Repo: {}", req.query, req.repo);
 
+    // reply in msgpack when the caller asks for it, json otherwise.
+    let transport = Transport::from_header(accept.as_deref());
+
     // if query or repo is empty, return bad request.
     if req.query.is_empty() || req.repo.is_empty() {
         log::error!("Query or Repo from the user request is empty");
         return Ok(warp::reply::with_status(
-            warp::reply::json(&format!("Error: Query or Repo is empty")),
+            encode_reply(transport, &format!("Error: Query or Repo is empty")),
             StatusCode::BAD_REQUEST,
         ));
     }
 
+    let pinned_paths = match parse_pinned_paths(&req.pinned_paths) {
+        Ok(pinned_paths) => pinned_paths,
+        Err(e) => {
+            log::error!("Invalid pinned paths in the request: {}", e);
+            return Ok(warp::reply::with_status(
+                encode_reply(transport, &format!("Error: {}", e)),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let budget = BudgetMeter::with_allowance(get_budget_config(), req.budget_allowance());
+    match answer_question(&req, &pinned_paths, app_state, None, budget.clone()).await {
+        Ok(answer) => Ok(warp::reply::with_status(
+            encode_reply(transport, &answer),
+            StatusCode::OK,
+        )),
+        // the coordinator reads the limit that stopped the question from the body.
+        Err((StatusCode::PAYMENT_REQUIRED, message)) => Ok(warp::reply::with_status(
+            match budget.rejection() {
+                Some(exceeded) => encode_reply(transport, &exceeded),
+                None => encode_reply(transport, &format!("Error: {}", message)),
+            },
+            StatusCode::PAYMENT_REQUIRED,
+        )),
+        // the coordinator asks to restart or re-pin the conversation of the dropped collection.
+        Err((StatusCode::GONE, collection)) => Ok(warp::reply::with_status(
+            encode_reply(transport, &IndexGenerationGone { collection }),
+            StatusCode::GONE,
+        )),
+        Err((status, message)) => Ok(warp::reply::with_status(
+            encode_reply(transport, &format!("Error: {}", message)),
+            status,
+        )),
+    }
+}
+
+// Answers the questions of a task against the same repo. The initial search of every question
+// runs first, the documents found by more than one of them are fetched once for the batch, then
+// every question is answered by its own agent reading from the shared documents and searches.
+// A question that fails is reported in its answer, the others are still answered.
+// The answers are streamed in the frames of `BatchStreamItem` as each one is written, in the
+// transport of the Accept header. The questions share the budget allowance of the batch, its cost
+// is the last frame.
+pub async fn handle_answer_batch(
+    req: CodeUnderstandBatchRequest,
+    accept: Option<String>,
+    app_state: Arc<AppState>,
+) -> Result<warp::reply::Response, Infallible> {
+    log::info!("Batch of {} questions, Repo: {}", req.questions.len(), req.repo);
+
+    let transport = Transport::from_header(accept.as_deref());
+
+    if req.repo.is_empty() || req.questions.is_empty() || req.questions.iter().any(|q| q.query.is_empty()) {
+        log::error!("Repo or a query of the batch request is empty");
+        return Ok(warp::reply::with_status(
+            encode_reply(transport, &format!("Error: Repo or a query of the batch is empty")),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    }
+
+    let pinned_paths = match parse_pinned_paths(&req.pinned_paths) {
+        Ok(pinned_paths) => pinned_paths,
+        Err(e) => {
+            log::error!("Invalid pinned paths in the batch request: {}", e);
+            return Ok(warp::reply::with_status(
+                encode_reply(transport, &format!("Error: {}", e)),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response());
+        }
+    };
+
+    let batch = Arc::new(BatchContext::new());
+    let budget = BudgetMeter::with_allowance(get_budget_config(), req.budget);
+
+    // initial retrieval, a failed search leaves the question without initial paths.
+    let initial_paths = join_all(req.questions.iter().map(|question| {
+        let batch = batch.clone();
+        let (repo, include_tests) = (req.repo.clone(), req.include_tests.unwrap_or(false));
+        let index_generation = req.index_generation.as_deref();
+        let expand_terminology = req.terminology.unwrap_or(true);
+        async move {
+            let search = || {
+                symbol_search(&question.query, &repo, include_tests, index_generation, expand_terminology)
+            };
+            match batch.search(&question.query, search).await {
+                Ok(chunks) => result_paths(&chunks),
+                Err(e) => {
+                    log::warn!("Initial search of question {} failed: {}", question.question_id, e);
+                    Vec::new()
+                }
+            }
+        }
+    }))
+    .await;
+
+    // documents found by several questions are fetched before their agents start.
+    let shared = shared_paths(&initial_paths);
+    let base_url = get_quickwit_url();
+    join_all(shared.iter().map(|path| {
+        let (db, repo) = (&app_state.db_connection, &req.repo);
+        let fetch = || db.get_file_from_quickwit(&base_url, repo, "relative_path", path);
+        let batch = batch.clone();
+        async move {
+            if let Err(e) = batch.document(repo, path, fetch).await {
+                log::warn!("Failed to prefetch {} for the batch: {}", path, e);
+            }
+        }
+    }))
+    .await;
+
+    // the answers are written to the reply as they finish, the task outlives the handler.
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, Infallible>>(req.questions.len() + 1);
+    tokio::spawn(async move {
+        let mut answers = req
+            .questions
+            .iter()
+            .zip(initial_paths)
+            .map(|(question, initial_paths)| {
+                let scope = batch.scope();
+                let question_req = req.question_request(question);
+                let (pinned_paths, app_state, shared) = (&pinned_paths, app_state.clone(), &shared);
+                let budget = budget.clone();
+                async move {
+                    let started = Instant::now();
+                    let result =
+                        answer_question(&question_req, pinned_paths, app_state, Some(scope.clone()), budget.clone()).await;
+                    let (document_hits, document_misses) = scope.document_stats();
+                    let trace = BatchTrace {
+                        initial_paths,
+                        shared_paths: shared.clone(),
+                        document_hits,
+                        document_misses,
+                        duration_ms: started.elapsed().as_millis() as u64,
+                    };
+                    let (answer, error, budget_exceeded, index_generation_gone) = match result {
+                        // the spend of the questions is counted once for the whole batch.
+                        Ok(answer) => (Some(CodeUnderstanding { cost_usd: None, ..answer }), None, None, None),
+                        Err((status, message)) => {
+                            error!("Question {} of the batch failed: {}", question.question_id, message);
+                            let exceeded = (status == StatusCode::PAYMENT_REQUIRED)
+                                .then(|| budget.rejection())
+                                .flatten();
+                            let gone = (status == StatusCode::GONE).then(|| IndexGenerationGone {
+                                collection: message.clone(),
+                            });
+                            (None, Some(message), exceeded, gone)
+                        }
+                    };
+                    BatchAnswer {
+                        question_id: question.question_id,
+                        query: question.query.clone(),
+                        answer,
+                        error,
+                        budget_exceeded,
+                        index_generation_gone,
+                        trace,
+                    }
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut answered = 0;
+        while let Some(answer) = answers.next().await {
+            answered += 1;
+            if !send_frame(&tx, transport, &BatchStreamItem::Answer(answer)).await {
+                log::warn!(
+                    "The caller of the batch on {} left, stopping its questions",
+                    req.repo
+                );
+                return;
+            }
+        }
+        let done = BatchStreamItem::Done {
+            cost_usd: budget.spent_usd(),
+        };
+        send_frame(&tx, transport, &done).await;
+
+        log::info!(
+            "Answered a batch of {} questions on {} with {} document fetches and {} searches, {} shared paths",
+            answered,
+            req.repo,
+            batch.document_fetches(),
+            batch.search_calls(),
+            shared.len()
+        );
+    });
+
+    let mut response = warp::reply::Response::new(Body::wrap_stream(ReceiverStream::new(rx)));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(transport.content_type()),
+    );
+    Ok(response)
+}
+
+// Writes a frame of the batch reply, false once the caller stopped reading it.
+async fn send_frame(
+    tx: &mpsc::Sender<Result<Vec<u8>, Infallible>>,
+    transport: Transport,
+    item: &BatchStreamItem,
+) -> bool {
+    match transport.encode_frame(item) {
+        Ok(frame) => tx.send(Ok(frame)).await.is_ok(),
+        Err(e) => {
+            log::error!("Failed to encode a frame of the batch reply: {}", e);
+            true
+        }
+    }
+}
+
+// Explains the function enclosing the requested position without running the agent, see
+// `crate::explain`. The position is the `byte` of the request, or the start of its `line`.
+pub async fn handle_explain_symbol(
+    req: ExplainSymbolRequest,
+    app_state: Arc<AppState>,
+) -> Result<warp::reply::Response, Infallible> {
+    let reply_error = |message: String, status: StatusCode| -> Result<_, Infallible> {
+        Ok(
+            warp::reply::with_status(warp::reply::json(&format!("Error: {}", message)), status)
+                .into_response(),
+        )
+    };
+    let db = &app_state.db_connection;
+    let file = match load_file(db, &req.repo, &req.path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            return reply_error(
+                format!("No scope graph for {} in {}", req.path, req.repo),
+                StatusCode::NOT_FOUND,
+            )
+        }
+        Err(e) => {
+            error!("Failed to read {} to explain it: {}", req.path, e);
+            return reply_error(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let byte = match (req.byte, req.line) {
+        (Some(byte), _) if byte < file.content.len() => byte,
+        (None, Some(line)) => match line_byte(&file.content, line) {
+            Some(byte) => byte,
+            None => {
+                return reply_error(
+                    format!("{} has no line {}", req.path, line),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        },
+        (Some(byte), _) => {
+            return reply_error(
+                format!("{} has no byte {}", req.path, byte),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+        (None, None) => {
+            return reply_error(
+                "Either line or byte is required".to_string(),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+
+    let generation = req.index_generation.as_deref();
+    let explained = explain_with(
+        &app_state.explain_cache,
+        &req.repo,
+        &file,
+        byte,
+        call_explain_model,
+        |name| find_callee(db, &req.repo, generation, name),
+    )
+    .await;
+    match explained {
+        Ok(Some((explanation, status))) => {
+            log::info!(
+                "Explained {} of {}, cache {}",
+                explanation.symbol,
+                req.path,
+                status.as_str()
+            );
+            Ok(warp::reply::with_header(
+                warp::reply::json(&explanation),
+                "X-Cache",
+                status.as_str(),
+            )
+            .into_response())
+        }
+        Ok(None) => reply_error(
+            format!("No function at this position of {}", req.path),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => {
+            error!("Failed to explain a function of {}: {}", req.path, e);
+            reply_error(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// The dictionary of the repo, None when it can't be fetched: the question is then answered
+// without expanding its searches.
+async fn load_terminology(repo: &str) -> Option<Terminology> {
+    match fetch_terminology(repo).await {
+        Ok(terminology) => Some(terminology),
+        Err(e) => {
+            log::warn!("Failed to fetch the terminology of repo {}, not expanding the searches: {}", repo, e);
+            None
+        }
+    }
+}
+
+// The symbols the earlier answers of the conversation described, empty when it can't be read.
+fn load_glossary(task_id: &str) -> Glossary {
+    match load_task_process_from_redis(&get_redis_url(), task_id) {
+        Ok(tracker) => tracker.glossary(),
+        Err(e) => {
+            log::warn!("Failed to read the glossary of conversation {}, answering without it: {}", task_id, e);
+            Glossary::default()
+        }
+    }
+}
+
+fn parse_pinned_paths(pinned_paths: &[String]) -> Result<Vec<PinnedPath>, String> {
+    pinned_paths.iter().map(|path| path.parse::<PinnedPath>()).collect()
+}
+
+// Runs the agent of the question until it answers, or returns the status and message the
+// question is failed with. A question stopped by `budget` fails with 402 Payment Required, one
+// whose pinned index generation was dropped with 410 Gone and the dropped collection.
+async fn answer_question(
+    req: &CodeUnderstandRequest,
+    pinned_paths: &[PinnedPath],
+    app_state: Arc<AppState>,
+    batch: Option<BatchScope>,
+    budget: BudgetMeter,
+) -> Result<CodeUnderstanding, (StatusCode, String)> {
     let task_id = req.task_id.clone();
     let question_id = req.question_id.clone();
+    // the steps of the agent up to the answer count as retrieval.
+    let started = Instant::now();
+    let mut answering = Duration::ZERO;
 
     // concat task and question id as the unique id to store the exchanges
     let query_id = format!("{}_{}", task_id, question_id);
@@ -43,9 +525,9 @@ pub async fn handle_retrieve_code(
 
     if exchanges.is_err() {
         log::error!("Error loading exchanges from redis");
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&format!("Error: {}", "Error loading exchanges from redis")),
+        return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
+            "Error loading exchanges from redis".to_string(),
         ));
     }
     let exchanges = exchanges.unwrap();
@@ -53,12 +535,29 @@ pub async fn handle_retrieve_code(
 
     let mut exchange_exists = false;
     let mut answer_exists = false;
+    // the run stopped at a clarifying question and the request doesn't respond to it.
+    let mut awaiting_clarification = false;
     let exchanges = match exchanges {
-        Some(exchanges) => {
+        // the question was reformulated since the last run, start the agent over for the new query.
+        Some(exchanges) if exchanges.last().map(|e| e.query != req.query).unwrap_or(true) => {
+            log::info!("Query changed for {}, starting a new exchange", query_id);
+            vec![Exchange::new(query_id.clone(), req.query.clone())]
+        }
+        Some(mut exchanges) => {
             // this will skip the first action agentic flow
             // makes the agent workflow resume from where it left off inside step function.
             exchange_exists = true;
-            let exchange = exchanges.last().unwrap();
+            let exchange = exchanges.last_mut().unwrap();
+            // the response of the user is the next message the model reads, see `build_history`.
+            if exchange.pending_clarification().is_some() {
+                match &req.clarification {
+                    Some(response) => {
+                        log::info!("Resuming {} with the response of the user", query_id);
+                        exchange.respond_to_clarification(response);
+                    }
+                    None => awaiting_clarification = true,
+                }
+            }
             // if the answer processing is already done, don't start the agent step function flow agin
             // just return the answer.
             if exchange.answer.is_some() {
@@ -77,35 +576,115 @@ pub async fn handle_retrieve_code(
 
     if ai_gateway.is_err() {
         log::error!("Error getting AI Gateway configuration");
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&format!(
-                "Error: {}",
-                "Error Initializing AI Gateway configuration"
-            )),
+        return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
+            "Error Initializing AI Gateway configuration".to_string(),
         ));
     }
 
     let ai_gateway = ai_gateway.unwrap();
+    // the user is asked once per question, and only by callers that hold the question for them.
+    let mut calls = CallLog::default();
+    if !req.ask_user.unwrap_or(false)
+        || exchanges.last().map_or(false, |e| e.clarification.is_some())
+    {
+        calls.close_ask_user();
+    }
+    let terminology = if req.terminology.unwrap_or(true) {
+        load_terminology(&req.repo).await
+    } else {
+        None
+    };
+    let glossary = if req.glossary.unwrap_or(false) {
+        load_glossary(&task_id)
+    } else {
+        Glossary::default()
+    };
     let mut agent: Agent = Agent {
         app_state: app_state,
         exchanges,
         ai_gateway,
+        run: AgentRun::new(&query_id),
         query_id: query_id,
-        complete: false,
         repo_name: req.repo.clone(),
         last_function_call_id: None,
+        preferences: req.preferences.clone(),
+        language: req.language.as_deref().and_then(normalize_language),
+        related_usage: req
+            .related_usage
+            .unwrap_or_else(|| !is_path_question(&req.query)),
+        include_tests: req.include_tests.unwrap_or(false),
+        data_flow: req
+            .data_flow
+            .unwrap_or_else(|| is_data_flow_question(&req.query)),
+        calls,
+        batch,
+        budget,
+        key_files: Vec::new(),
+        attachments: req.attachments.unwrap_or(false).then(|| task_id.clone()),
+        history_mode: get_history_mode(),
+        index_generation: req.index_generation.clone(),
+        terminology,
+        glossary,
+        index_mode: Default::default(),
     };
 
+    // read the pinned files into the new exchange before the agent starts searching.
+    if !exchange_exists && !pinned_paths.is_empty() {
+        if let Err(e) = agent.pin_paths(pinned_paths).await {
+            error!("Error reading pinned paths: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    }
+
+    // the searches and the answer depend on what the repo was indexed with.
+    agent.index_mode = agent.load_index_mode().await;
+    if !agent.index_mode.is_full() {
+        log::info!(
+            "{} is indexed by paths only, {} is searched by keywords",
+            agent.repo_name,
+            agent.query_id
+        );
+    }
+
+    // the key files of the repo target the first search of the agent.
+    if !answer_exists && !awaiting_clarification {
+        agent.key_files = agent.load_key_files().await;
+    }
+
     // first action
     log::info!("first action {:?}\n", action);
 
     let mut i = 1;
     // return error from the loop if there is an error in the action.
-    if !answer_exists {
+    if awaiting_clarification {
+        log::info!(
+            "{} still waits for the user to clarify it, skipping the step function",
+            agent.query_id
+        );
+    } else if !answer_exists {
         let action_result: Result<(), anyhow::Error> = loop {
+            // wrap up at the step boundary when the service is shutting down,
+            // the exchanges saved so far let the same request resume later.
+            if shutdown::is_shutting_down() {
+                log::warn!("Shutting down, stopping the agent for {} after {} actions", agent.query_id, i);
+                if let Err(e) = agent.save_exchanges_to_redis(&get_redis_url()) {
+                    error!("Failed to save exchanges before shutdown: {}", e);
+                }
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Service is shutting down, retry the request to resume".to_string(),
+                ));
+            }
+
             // Now only focus on the step function inside this loop.
-            match agent.step(action, exchange_exists).await {
+            let answer_step = matches!(action, Action::Answer { .. });
+            let step_started = Instant::now();
+            let stepped = agent.step(action, exchange_exists).await;
+            if answer_step {
+                answering += step_started.elapsed();
+            }
+            match stepped {
                 Ok(next_action) => {
                     match next_action {
                         Some(act) => {
@@ -132,14 +711,18 @@ pub async fn handle_retrieve_code(
         };
 
         // if there is an error in the action, return the error.
-        if action_result.is_err() {
-            let err_msg = action_result.err().unwrap().to_string();
+        if let Err(e) = action_result {
+            let err_msg = e.to_string();
             // log the error
             error!("Error in the step function: {}", err_msg);
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&format!("Error: {}", err_msg)),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            if e.downcast_ref::<BudgetExceeded>().is_some() {
+                // the exchanges saved so far let the question resume once the budget allows it.
+                return Err((StatusCode::PAYMENT_REQUIRED, err_msg));
+            }
+            if let Some(gone) = e.downcast_ref::<IndexGenerationGone>() {
+                return Err((StatusCode::GONE, gone.collection.clone()));
+            }
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err_msg));
         }
     } else {
         log::info!("Answer already exists, skipping the step function");
@@ -150,27 +733,246 @@ pub async fn handle_retrieve_code(
 
 
     }
+    // the run stopped to ask the user, the exchanges were saved when it did.
+    if let Some(clarification) = agent.get_final_anwer().pending_clarification().cloned() {
+        log::info!("Asking the user to clarify {}: {}", req.query, clarification.render());
+        let answer = clarification_answer(req, &agent, started.elapsed());
+        agent.complete();
+        return Ok(answer);
+    }
+
     // These need to be put beind a try catch sort of setup
     let final_answer = match agent.get_final_anwer().answer.clone() {
         Some(ans) => ans,
         None => {
             log::error!("Error getting final answer");
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&format!("Error: {}", "Error getting final answer")),
+            return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
+                "Error getting final answer".to_string(),
             ));
         }
     };
 
     let final_context = agent.get_final_anwer().final_context.clone();
+    let mut outcome = agent.get_final_anwer().outcome();
+    let missing_pinned_paths = agent.get_final_anwer().missing_pinned_paths.clone();
+    log::info!("Outcome for {}: {:?}", req.query, outcome);
+    let cost_usd = agent.budget.spent_usd();
+    let citations = resolve_citations(&agent, &final_answer).await;
+    if matches!(outcome, AnswerOutcome::Answered { .. }) {
+        log_retrieval_feedback(&agent, req, &citations);
+    }
+    let final_answer = redact_answer(citations.apply(&final_answer), &mut outcome, &req.repo);
+    if let AnswerOutcome::Answered { answer, .. } = &mut outcome {
+        *answer = citations.apply(answer);
+    }
+    // the jargon the user knows the code by follows the first mention of what the code calls it.
+    let final_answer = match &agent.terminology {
+        Some(terminology) => {
+            if let AnswerOutcome::Answered { answer, .. } = &mut outcome {
+                *answer = terminology.annotate_answer(answer);
+            }
+            terminology.annotate_answer(&final_answer)
+        }
+        None => final_answer,
+    };
+    let verification = match outcome {
+        AnswerOutcome::Answered { .. } if req.include_verification.unwrap_or(false) => {
+            verify_answer(&mut agent, &final_answer).await
+        }
+        _ => Vec::new(),
+    };
+    let prompt_versions = agent.get_final_anwer().prompt_versions.clone();
+    log::info!(
+        "Prompt templates of {}: {}",
+        req.query,
+        prompt_versions
+            .iter()
+            .map(PromptVersion::render)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let index_mode = agent.index_mode;
     agent.complete();
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&CodeUnderstanding {
-            question: req.query.clone(),
-            answer: final_answer.clone(),
-            context: final_context.clone(),
+    Ok(CodeUnderstanding {
+        question: req.query.clone(),
+        answer: final_answer.clone(),
+        context: final_context.clone(),
+        outcome: Some(outcome),
+        missing_pinned_paths,
+        cost_usd: Some(cost_usd),
+        timings: Some(PhaseTimings {
+            retrieval_ms: started.elapsed().saturating_sub(answering).as_millis() as u64,
+            answer_ms: answering.as_millis() as u64,
+            ..Default::default()
         }),
-        StatusCode::OK,
-    ))
+        scope_violations: vec![],
+        citations,
+        verification,
+        prompt_versions,
+        // set by the coordinator once it checked the scope of the citations.
+        files_involved: Vec::new(),
+        index_mode,
+    })
+}
+
+// The answer of a question whose run stopped at a clarifying question, the question is the answer
+// until the user responds.
+fn clarification_answer(
+    req: &CodeUnderstandRequest,
+    agent: &Agent,
+    elapsed: Duration,
+) -> CodeUnderstanding {
+    let exchange = agent.get_final_anwer();
+    CodeUnderstanding {
+        question: req.query.clone(),
+        answer: exchange
+            .pending_clarification()
+            .map(|clarification| clarification.render())
+            .unwrap_or_default(),
+        context: Vec::new(),
+        outcome: Some(exchange.outcome()),
+        missing_pinned_paths: exchange.missing_pinned_paths.clone(),
+        cost_usd: Some(agent.budget.spent_usd()),
+        timings: Some(PhaseTimings {
+            retrieval_ms: elapsed.as_millis() as u64,
+            ..Default::default()
+        }),
+        scope_violations: vec![],
+        citations: Default::default(),
+        verification: Vec::new(),
+        prompt_versions: exchange.prompt_versions.clone(),
+        files_involved: Vec::new(),
+        index_mode: agent.index_mode,
+    }
+}
+
+// The verification steps of the answer, none when they couldn't be written: the answer is sent
+// without them rather than failed. Their instructions and commands are redacted like the answer.
+async fn verify_answer(agent: &mut Agent, answer: &str) -> Vec<VerificationStep> {
+    match agent.verify(answer).await {
+        Ok(steps) if redaction_enabled() => steps
+            .into_iter()
+            .map(|mut step| {
+                step.instruction = redact_secrets(&step.instruction).text;
+                step.command = step.command.map(|command| redact_secrets(&command).text);
+                step
+            })
+            .collect(),
+        Ok(steps) => steps,
+        Err(e) => {
+            log::warn!("Failed to write the verification steps of {}: {}", agent.query_id, e);
+            Vec::new()
+        }
+    }
+}
+
+// Checks the lines the answer cites against the indexed files, fetching each cited file once, and
+// anchors them to the content of the files with the function enclosing them.
+async fn resolve_citations(agent: &Agent, answer: &str) -> CitationReport {
+    let documents = std::sync::Mutex::new(Vec::new());
+    let mut citations = CitationReport::resolve(answer, |path| {
+        let documents = &documents;
+        async move {
+            let document = agent.get_file_content(&get_quickwit_url(), &path).await?;
+            let line_count = document.as_ref().map(|document| document.content.lines().count());
+            documents.lock().unwrap().extend(document.map(|document| (path, document)));
+            Ok(line_count)
+        }
+    })
+    .await;
+    for (path, document) in documents.into_inner().unwrap() {
+        let graph = bincode::deserialize::<SymbolLocations>(&document.symbol_locations)
+            .ok()
+            .and_then(|locations| locations.scope_graph().cloned());
+        let content = &document.content;
+        citations.anchor(&path, content, |line| {
+            let graph = graph.as_ref()?;
+            enclosing_function(graph, content.as_bytes(), line_byte(content, line)?)
+        });
+    }
+    let (adjusted, invalid) = (
+        citations.count(CitationStatus::Adjusted),
+        citations.count(CitationStatus::Invalid),
+    );
+    if adjusted + invalid > 0 {
+        log::info!(
+            "Answer for {} cites {} files, {} citations adjusted and {} invalid",
+            agent.query_id,
+            citations.paths().len(),
+            adjusted,
+            invalid
+        );
+    }
+    citations
+}
+
+// Appends the chunks the agent retrieved for the question and the ones the answer cites to the
+// retrieval feedback log, when one is configured. A failed append doesn't fail the answer.
+fn log_retrieval_feedback(agent: &Agent, req: &CodeUnderstandRequest, citations: &CitationReport) {
+    let Some(log) = get_retrieval_feedback_log() else {
+        return;
+    };
+    let chunks = agent
+        .exchanges
+        .iter()
+        .flat_map(|exchange| exchange.code_chunks.iter().cloned())
+        .collect::<Vec<_>>();
+    let recorded_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let Some(record) = RetrievalFeedback::new(
+        &req.repo,
+        &req.query,
+        &req.task_id,
+        req.question_id,
+        &chunks,
+        citations,
+        recorded_at,
+    ) else {
+        return;
+    };
+    if let Err(e) = log.append(&get_redis_url(), &record) {
+        log::warn!("Failed to log the retrieval feedback of {}: {}", req.query, e);
+    }
+}
+
+// Redacts the secrets the answer quotes from the code, unless `REDACT_SECRETS=false`.
+// The exchanges saved in redis keep the answer as it was generated.
+fn redact_answer(answer: String, outcome: &mut AnswerOutcome, repo: &str) -> String {
+    if !redaction_enabled() {
+        return answer;
+    }
+    let redacted = redact_secrets(&answer);
+    if let AnswerOutcome::Answered { answer, .. } = outcome {
+        *answer = redact_secrets(answer).text;
+    }
+    if redacted.count > 0 {
+        log::info!("Redacted {} secrets from the answer on repo {}", redacted.count, repo);
+    }
+    redacted.text
+}
+
+// Serializes the reply body in the negotiated transport format.
+fn encode_reply<T: Serialize>(transport: Transport, value: &T) -> warp::reply::Response {
+    if transport == Transport::Json {
+        return warp::reply::json(value).into_response();
+    }
+
+    match transport.encode(value) {
+        Ok(body) => {
+            let mut response = warp::reply::Response::new(body.into());
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(transport.content_type()),
+            );
+            response
+        }
+        Err(e) => {
+            log::error!("Failed to encode {} reply, falling back to json: {}", transport, e);
+            warp::reply::json(value).into_response()
+        }
+    }
 }
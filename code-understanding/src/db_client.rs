@@ -1,23 +1,33 @@
 use crate::agent::agent::{ContentDocument, FileDocument};
-use crate::config::{clone_config, get_quickwit_url};
+use crate::config::{clone_config, get_content_cache_config, get_quickwit_url};
+use crate::content_cache::{CacheKey, ContentCache};
 use crate::helpers::build_fuzzy_regex_filter::build_fuzzy_regex_filter;
 use crate::helpers::case_permutations::case_permutations;
 use crate::helpers::trigrams::trigrams;
 use crate::search;
+use common::branch::{quickwit_branch_query, DEFAULT_BRANCH};
 use common::hasher::generate_quikwit_index_name;
+use common::path_class::is_metadata_document;
+use common::run_manifest::{RunManifest, RUN_MANIFEST_PATH};
+use common::{metrics, telemetry};
+use tracing::Instrument;
 use compact_str::CompactString;
 use log::{debug, error, info};
 use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 use anyhow::Result;
 
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use reqwest::{Client, Error};
+use common::reconnect::Reconnecting;
+use reqwest::Client;
 pub struct DbConnect {
     pub semantic: search::semantic::Semantic,
-    pub http_client: Client,
+    // rebuilt with a fresh connection pool when quickwit restarts, see `common::reconnect`.
+    pub http_client: Reconnecting<Client>,
+    pub content_cache: ContentCache,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,13 +63,16 @@ struct ResultItem {
 impl DbConnect {
     pub async fn new() -> Result<Self, anyhow::Error> {
         let config = clone_config();
-        let http_client = reqwest::Client::new();
+        let http_client = Reconnecting::new("quickwit", Client::new(), || async {
+            Ok(Client::new())
+        });
 
         let semantic = search::semantic::Semantic::initialize(&config).await;
         match semantic {
             Ok(semantic) => Ok(Self {
                 semantic,
                 http_client,
+                content_cache: ContentCache::new(get_content_cache_config()),
             }),
             Err(err) => {
                 error!("Failed to initialize semantic search: {}", err);
@@ -74,6 +87,65 @@ impl DbConnect {
         index_name: &str,
         search_field: &str,
         search_query: &str,
+    ) -> Result<Option<ContentDocument>> {
+        // documents are cached by path, for the generation of the index they were fetched from.
+        if search_field == "relative_path" && self.content_cache.is_enabled() {
+            if let Some(generation) = self.index_generation(base_url, index_name).await {
+                let key = CacheKey {
+                    repo: index_name.to_string(),
+                    path: search_query.to_string(),
+                    generation,
+                };
+                let span = tracing::info_span!(
+                    "content_cache",
+                    repo = %index_name,
+                    path = %search_query,
+                    x_cache = tracing::field::Empty,
+                );
+                let (document, status) = self
+                    .content_cache
+                    .get_or_fetch(&key, || {
+                        self.fetch_file_from_quickwit(base_url, index_name, search_field, search_query)
+                    })
+                    .instrument(span.clone())
+                    .await?;
+                span.record("x_cache", status.as_str());
+                metrics::record_content_cache_lookup(status.as_str());
+                return Ok(document);
+            }
+        }
+        self.fetch_file_from_quickwit(base_url, index_name, search_field, search_query)
+            .await
+    }
+
+    // The run id of the last ingestion run of the repo, None for repos indexed without a run
+    // manifest, whose documents aren't cached.
+    async fn index_generation(&self, base_url: &str, index_name: &str) -> Option<String> {
+        self.content_cache
+            .generation(index_name, || async {
+                match self
+                    .search_quickwit(base_url, index_name, "relative_path", RUN_MANIFEST_PATH)
+                    .await
+                {
+                    Ok(Some(document)) => serde_json::from_str::<RunManifest>(&document.content)
+                        .map(|manifest| manifest.run_id)
+                        .ok(),
+                    Ok(None) => None,
+                    Err(e) => {
+                        error!("Failed to look up the run manifest of {}: {}", index_name, e);
+                        None
+                    }
+                }
+            })
+            .await
+    }
+
+    async fn fetch_file_from_quickwit(
+        &self,
+        base_url: &str,
+        index_name: &str,
+        search_field: &str,
+        search_query: &str,
     ) -> Result<Option<ContentDocument>> {
         let response_array = self
             .search_quickwit(base_url, index_name, search_field, search_query)
@@ -97,15 +169,16 @@ impl DbConnect {
         index_name: &str,
         search_field: &str,
         search_query: &str,
-    ) -> Result<Option<ContentDocument>, Error> {
+    ) -> Result<Option<ContentDocument>> {
         let query = if !search_field.is_empty() {
             format!("{}:{}", search_field, search_query)
         } else {
             search_query.to_owned()
         };
 
+        // the files are read from the default branch, like the answers are written from.
         let json_data = BodyRes {
-            query,
+            query: quickwit_branch_query(&query, DEFAULT_BRANCH),
             max_hits: 10,
         };
 
@@ -118,13 +191,12 @@ impl DbConnect {
         );
         let url = var_name;
 
+        let start = Instant::now();
         let response = self
-            .http_client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(json_string)
-            .send()
+            .post_json(url, json_string)
+            .instrument(telemetry::db_span("quickwit", "get_file"))
             .await?;
+        metrics::observe_db_query("quickwit", "get_file", start.elapsed());
 
         let mut response_array: Vec<ContentDocument> = Vec::new();
 
@@ -172,9 +244,8 @@ impl DbConnect {
         index_name: &str,
         search_field: &str,
         search_query: &str,
-    ) -> Result<Vec<FileDocument>, Error> {
-        let client = Client::new();
-        let base_url = get_quickwit_url(); 
+    ) -> Result<Vec<FileDocument>> {
+        let base_url = get_quickwit_url();
 
         info!("search_query {}", search_query);
 
@@ -185,7 +256,7 @@ impl DbConnect {
         };
 
         let json_data = BodyRes {
-            query,
+            query: quickwit_branch_query(&query, DEFAULT_BRANCH),
             max_hits: 100,
         };
 
@@ -197,12 +268,12 @@ impl DbConnect {
             generate_quikwit_index_name(index_name)
         );
 
-        let response = client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(json_string)
-            .send()
+        let start = Instant::now();
+        let response = self
+            .post_json(url, json_string)
+            .instrument(telemetry::db_span("quickwit", "search"))
             .await?;
+        metrics::observe_db_query("quickwit", "search", start.elapsed());
 
         let mut response_array: Vec<FileDocument> = Vec::new();
 
@@ -234,12 +305,41 @@ impl DbConnect {
         Ok(response_array)
     }
 
+    // Sends the request with the shared client, once more after reconnecting when quickwit can't be reached.
+    async fn post_json(&self, url: String, body: String) -> Result<reqwest::Response> {
+        self.http_client
+            .run(|client| {
+                let request = client
+                    .post(&url)
+                    .header("Content-Type", "application/json")
+                    .body(body.clone());
+                async move { Ok(request.send().await?) }
+            })
+            .await
+    }
+
+    /// Lists the indexes, the lightweight call the readiness check makes with the shared client.
+    pub async fn list_indexes(client: &Client) -> Result<usize> {
+        let url = format!("{}/api/v1/indexes", get_quickwit_url());
+        let start = Instant::now();
+        let indexes: Vec<serde_json::Value> = client
+            .get(url)
+            .send()
+            .instrument(telemetry::db_span("quickwit", "list_indexes"))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        metrics::observe_db_query("quickwit", "list_indexes", start.elapsed());
+        Ok(indexes.len())
+    }
+
     async fn search_with_async(
         &self,
         index_name: &str,
         search_field: &str,
         token: CompactString,
-    ) -> Result<Vec<FileDocument>, Error> {
+    ) -> Result<Vec<FileDocument>> {
         let result = self
             .search_api(index_name, search_field, token.as_str())
             .await?;
@@ -255,7 +355,7 @@ impl DbConnect {
         search_field: &str,
         search_query: &str,
         limit: usize,
-    ) -> impl Iterator<Item = FileDocument> {
+    ) -> impl Iterator<Item = (FileDocument, usize)> {
         let mut counts: HashMap<FileDocument, usize> = HashMap::new();
 
         let hits = trigrams(search_query)
@@ -273,8 +373,8 @@ impl DbConnect {
                     // The key exists, increment its value
                     *entry += 1;
                 } else {
-                    // The key doesn't exist, insert it with an initial value of 0
-                    counts.insert(res.clone(), 0);
+                    // The key doesn't exist, insert it with an initial value of 1
+                    counts.insert(res.clone(), 1);
                 }
             }
         }
@@ -314,7 +414,7 @@ impl DbConnect {
             Some(f) => {
                 for res in new_hit {
                     if f.is_match(&res.0.relative_path) {
-                        filterd_hits.push(res.0);
+                        filterd_hits.push(res);
                     }
                 }
             }
@@ -325,4 +425,103 @@ impl DbConnect {
 
         result
     }
+
+    /// The files containing the keywords of the query, with the number of keywords each
+    /// contains. The content search of the repos indexed by paths only, which have no embeddings.
+    pub async fn keyword_match(
+        &self,
+        index_name: &str,
+        search_query: &str,
+        limit: usize,
+    ) -> Vec<(FileDocument, usize)> {
+        let mut hits = Vec::new();
+        for keyword in keywords(search_query) {
+            match self.search_api(index_name, "content", &keyword).await {
+                Ok(files) => hits.push(files),
+                Err(e) => error!("Keyword search of {} failed: {}", keyword, e),
+            }
+        }
+        rank_keyword_hits(hits, limit)
+    }
+}
+
+// Words of the questions that match most files.
+const KEYWORD_STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "with", "from", "this", "that", "are", "does", "how", "what", "where",
+    "when", "which", "why",
+];
+
+// The words of the query worth searching the content for, identifiers are kept whole.
+fn keywords(query: &str) -> Vec<String> {
+    let mut keywords = Vec::new();
+    for word in query.split(|c: char| !c.is_alphanumeric() && c != '_') {
+        if word.chars().count() >= 3
+            && !KEYWORD_STOP_WORDS.contains(&word.to_lowercase().as_str())
+            && !keywords.iter().any(|k: &String| k == word)
+        {
+            keywords.push(word.to_string());
+        }
+    }
+    keywords
+}
+
+// Counts the keywords each file matched, the files matching the most first. The documents the
+// indexer writes about the repo aren't code, they are left out.
+fn rank_keyword_hits(hits: Vec<Vec<FileDocument>>, limit: usize) -> Vec<(FileDocument, usize)> {
+    let mut counts: HashMap<FileDocument, usize> = HashMap::new();
+    for file in hits
+        .into_iter()
+        .flat_map(|files| files.into_iter().collect::<HashSet<_>>())
+    {
+        *counts.entry(file).or_default() += 1;
+    }
+    let mut ranked = counts
+        .into_iter()
+        .filter(|(file, _)| !is_metadata_document(&file.relative_path))
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(a, a_count), (b, b_count)| {
+        b_count
+            .cmp(a_count)
+            .then_with(|| a.relative_path.cmp(&b.relative_path))
+    });
+    ranked.truncate(limit);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::terminology::TERMINOLOGY_SUGGESTIONS_PATH;
+
+    fn file(path: &str) -> FileDocument {
+        FileDocument {
+            relative_path: path.to_string(),
+            repo_name: "repo".to_string(),
+            repo_ref: "main".to_string(),
+            lang: Some("Rust".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_files_matching_the_most_keywords_come_first() {
+        assert_eq!(
+            keywords("where is retry_payment called? retry, of the refunds"),
+            vec!["retry_payment", "called", "retry", "refunds"]
+        );
+
+        let hits = vec![
+            // a file listed twice for a keyword counts once.
+            vec![
+                file("src/refunds.rs"),
+                file("src/refunds.rs"),
+                file(RUN_MANIFEST_PATH),
+            ],
+            vec![file("src/refunds.rs"), file("src/payments.rs")],
+            vec![file("src/api.rs"), file(TERMINOLOGY_SUGGESTIONS_PATH)],
+        ];
+        assert_eq!(
+            rank_keyword_hits(hits, 2),
+            vec![(file("src/refunds.rs"), 2), (file("src/api.rs"), 1)]
+        );
+    }
 }
@@ -0,0 +1,561 @@
+// Explanations of a single function for the hover and code lens of editors, `POST /explain-symbol`.
+// The function enclosing the requested position is read from the scope graph stored when the file
+// was indexed, with a few lines of the functions it calls: from the same graph when they are defined
+// in the file, from the exact symbol lookup otherwise. The model is asked once, without functions,
+// for a short explanation. Explanations are cached by the content of the file and the range of the
+// function, so a hover over unchanged code never asks the model again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::Mutex;
+
+use ai_gateway::config::AIGatewayConfig;
+use ai_gateway::message::message::Message;
+use ai_gateway::utils::count_tokens;
+use anyhow::Result;
+use common::ai_util::extract_single_plaintext_content;
+use common::ast::ast_graph::{NodeKind, ScopeGraph};
+use common::ast::symbol::SymbolLocations;
+use common::models::{ExplainSymbolResponse, ExplainedCallee, ExplainedParameter};
+use common::prompts;
+use futures::future::join_all;
+use petgraph::graph::NodeIndex;
+use serde::Deserialize;
+
+use crate::agent::tools::data_flow::FlowFile;
+use crate::agent::tools::related::{body_of, enclosing_def, is_call, name_of};
+use crate::config::{get_ai_gateway_config, get_explain_symbol_model, get_quickwit_url};
+use crate::content_cache::CacheStatus;
+use crate::db_client::DbConnect;
+use crate::helpers::symbol_search::exact_symbol_lookup;
+
+// functions called by the explained one shown to the model.
+const EXPLAIN_MAX_CALLEES: usize = 4;
+// lines of a callee shown to the model.
+const CALLEE_LINES: usize = 8;
+// lines of the explained function shown to the model, the rest of a long one is cut.
+const MAX_SOURCE_LINES: usize = 80;
+// words of the summary and parameters kept, whatever the model answered.
+const MAX_SUMMARY_WORDS: usize = 80;
+const MAX_PARAMETERS: usize = 8;
+
+/// A callee defined in another file, as found by the exact symbol lookup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoundCallee {
+    pub path: String,
+    // 0-based line of its definition.
+    pub line: usize,
+    pub snippet: String,
+}
+
+// A function called by the explained one.
+#[derive(Debug, Clone, PartialEq)]
+enum Callee {
+    // defined in the file, at these 0-based lines.
+    Local { name: String, lines: Range<usize> },
+    // imported from another file, looked up by its name.
+    External(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ExplainKey {
+    repo: String,
+    path: String,
+    content_hash: u64,
+    // bytes of the function in the file.
+    range: Range<usize>,
+}
+
+#[derive(Default)]
+struct ExplainState {
+    explanations: HashMap<ExplainKey, (ExplainSymbolResponse, u64)>,
+    tick: u64,
+}
+
+/// The explanations already written, least recently used first out.
+pub struct ExplainCache {
+    // 0 disables the cache.
+    entries: usize,
+    state: Mutex<ExplainState>,
+}
+
+impl ExplainCache {
+    pub fn new(entries: usize) -> Self {
+        Self {
+            entries,
+            state: Mutex::new(ExplainState::default()),
+        }
+    }
+
+    fn get(&self, key: &ExplainKey) -> Option<ExplainSymbolResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let (explanation, last_used) = state.explanations.get_mut(key)?;
+        *last_used = tick;
+        Some(explanation.clone())
+    }
+
+    fn insert(&self, key: ExplainKey, explanation: ExplainSymbolResponse) {
+        if self.entries == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.explanations.len() >= self.entries && !state.explanations.contains_key(&key) {
+            let oldest = state
+                .explanations
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.explanations.remove(&oldest);
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.explanations.insert(key, (explanation, tick));
+    }
+}
+
+// The parts of the explanation the model writes.
+#[derive(Deserialize, Debug, Default)]
+struct Explained {
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    parameters: Vec<ExplainedParameter>,
+    #[serde(default)]
+    returns: Option<String>,
+}
+
+/// The byte of a 1-based line of `content`, at its first character that isn't whitespace.
+pub fn line_byte(content: &str, line: usize) -> Option<usize> {
+    let mut offset = 0;
+    for (i, text) in content.split_inclusive('\n').enumerate() {
+        if i + 1 == line {
+            let indent = text.len() - text.trim_start().len();
+            return Some(offset + indent.min(text.trim_end_matches('\n').len()));
+        }
+        offset += text.len();
+    }
+    None
+}
+
+/// The file with the scope graph stored when it was indexed, None when it isn't indexed or its
+/// language has no scope graph.
+pub async fn load_file(db: &DbConnect, repo: &str, path: &str) -> Result<Option<FlowFile>> {
+    let Some(doc) = db
+        .get_file_from_quickwit(&get_quickwit_url(), repo, "relative_path", path)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let locations = bincode::deserialize::<SymbolLocations>(&doc.symbol_locations)?;
+    Ok(locations.scope_graph().map(|graph| FlowFile {
+        path: path.to_string(),
+        content: doc.content,
+        graph: graph.clone(),
+    }))
+}
+
+/// The first definition of `name` the exact symbol lookup finds, with its first lines.
+pub async fn find_callee(
+    db: &DbConnect,
+    repo: &str,
+    index_generation: Option<&str>,
+    name: String,
+) -> Option<FoundCallee> {
+    let occurrences = exact_symbol_lookup(&name, None, None, Some(true), repo, index_generation)
+        .await
+        .map_err(|e| log::warn!("Failed to look up {} for its explanation: {}", name, e))
+        .ok()?;
+    for occurrence in occurrences {
+        let Some(line) = occurrence.start_line else {
+            continue;
+        };
+        let document = db
+            .get_file_from_quickwit(&get_quickwit_url(), repo, "relative_path", &occurrence.path)
+            .await;
+        match document {
+            Ok(Some(document)) => {
+                return Some(FoundCallee {
+                    path: occurrence.path,
+                    line,
+                    snippet: snippet(&document.content, line..line + CALLEE_LINES),
+                })
+            }
+            Ok(None) => continue,
+            Err(e) => log::warn!(
+                "Failed to read {} for an explanation: {}",
+                occurrence.path,
+                e
+            ),
+        }
+    }
+    None
+}
+
+/// Explains with the model of the route, in a single call without functions.
+pub async fn call_explain_model(messages: Vec<Message>) -> Result<String> {
+    let mut config = AIGatewayConfig::from_yaml(&get_ai_gateway_config())?;
+    if let Some(model) = get_explain_symbol_model() {
+        // a model without its client is served by the client of the configured one.
+        let model = match model.contains(':') {
+            true => model,
+            false => format!("{}:{}", config.model.client_name, model),
+        };
+        config.set_model(&model)?;
+    }
+    let response = config.use_llm(None, Some(messages), None).await?;
+    extract_single_plaintext_content(&response)
+}
+
+/// Explains the innermost function of `file` enclosing `byte`, from the cache when the function
+/// was explained before, with `llm` otherwise. `lookup` finds the callees defined in other files.
+/// None when no function encloses the byte.
+pub async fn explain_with<L, LFut, F, FFut>(
+    cache: &ExplainCache,
+    repo: &str,
+    file: &FlowFile,
+    byte: usize,
+    llm: L,
+    lookup: F,
+) -> Result<Option<(ExplainSymbolResponse, CacheStatus)>>
+where
+    L: FnOnce(Vec<Message>) -> LFut,
+    LFut: Future<Output = Result<String>>,
+    F: Fn(String) -> FFut,
+    FFut: Future<Output = Option<FoundCallee>>,
+{
+    let graph = &file.graph;
+    let src = file.content.as_bytes();
+    let Some(def) = enclosing_def(graph, byte) else {
+        return Ok(None);
+    };
+    let Some(symbol) = name_of(graph, src, def) else {
+        return Ok(None);
+    };
+    let body = body_of(graph, def);
+    let key = ExplainKey {
+        repo: repo.to_string(),
+        path: file.path.clone(),
+        content_hash: content_hash(&file.content),
+        range: body.start.byte..body.end.byte,
+    };
+    if let Some(explanation) = cache.get(&key) {
+        return Ok(Some((
+            ExplainSymbolResponse {
+                tokens_used: 0,
+                ..explanation
+            },
+            CacheStatus::Hit,
+        )));
+    }
+
+    let start = graph.graph[def].range().start.line.min(body.start.line);
+    let source = snippet(
+        &file.content,
+        start..(body.end.line + 1).min(start + MAX_SOURCE_LINES),
+    );
+
+    let mut callees = Vec::new();
+    let mut shown = Vec::new();
+    let mut external = Vec::new();
+    for callee in callees_of(graph, src, def, &symbol) {
+        match callee {
+            Callee::Local { name, lines } => {
+                callees.push(ExplainedCallee {
+                    name: name.clone(),
+                    path: file.path.clone(),
+                    line: lines.start + 1,
+                });
+                shown.push((name, file.path.clone(), snippet(&file.content, lines)));
+            }
+            Callee::External(name) => external.push(name),
+        }
+    }
+    let found = join_all(external.iter().map(|name| lookup(name.clone()))).await;
+    for (name, found) in external.into_iter().zip(found) {
+        let Some(found) = found else {
+            continue;
+        };
+        callees.push(ExplainedCallee {
+            name: name.clone(),
+            path: found.path.clone(),
+            line: found.line + 1,
+        });
+        shown.push((name, found.path, found.snippet));
+    }
+
+    let prompt = prompts::explain_symbol_prompt(&file.path, &symbol, &source, &shown);
+    let response = llm(vec![Message::system(&prompt)]).await?;
+    let explained = parse_explanation(&response);
+
+    let explanation = ExplainSymbolResponse {
+        symbol,
+        start_line: start + 1,
+        end_line: body.end.line + 1,
+        summary: explained.summary,
+        parameters: explained.parameters,
+        returns: explained.returns,
+        callees,
+        tokens_used: count_tokens(&prompt) + count_tokens(&response),
+    };
+    cache.insert(key, explanation.clone());
+    Ok(Some((explanation, CacheStatus::Miss)))
+}
+
+// The functions called in the body of `def`, in the order of their first call, at most
+// `EXPLAIN_MAX_CALLEES` of them.
+fn callees_of(graph: &ScopeGraph, src: &[u8], def: NodeIndex, symbol: &str) -> Vec<Callee> {
+    let body = body_of(graph, def);
+    let mut calls = graph
+        .graph
+        .node_indices()
+        .filter_map(|idx| match &graph.graph[idx] {
+            NodeKind::Ref(reference) => Some((idx, reference.range)),
+            _ => None,
+        })
+        .filter(|(_, range)| {
+            body.start.byte <= range.start.byte
+                && range.end.byte <= body.end.byte
+                && is_call(src, range)
+        })
+        .collect::<Vec<_>>();
+    calls.sort_by_key(|(_, range)| range.start.byte);
+
+    let mut seen = vec![symbol.to_string()];
+    let mut callees = Vec::new();
+    for (idx, range) in calls {
+        let name = String::from_utf8_lossy(&src[range.start.byte..range.end.byte]).to_string();
+        if seen.contains(&name) {
+            continue;
+        }
+        seen.push(name.clone());
+        callees.push(match graph.definitions(idx).next() {
+            Some(callee) => {
+                let start = graph.graph[callee].range().start.line;
+                let end = (body_of(graph, callee).end.line + 1).min(start + CALLEE_LINES);
+                Callee::Local {
+                    name,
+                    lines: start..end,
+                }
+            }
+            None => Callee::External(name),
+        });
+        if callees.len() == EXPLAIN_MAX_CALLEES {
+            break;
+        }
+    }
+    callees
+}
+
+// The JSON object of the response, the whole response as the summary when there is none.
+fn parse_explanation(response: &str) -> Explained {
+    let response = response.trim();
+    let explained = match (response.find('{'), response.rfind('}')) {
+        (Some(start), Some(end)) if start < end => {
+            serde_json::from_str::<Explained>(&response[start..=end])
+                .map_err(|e| log::warn!("Failed to parse the explanation: {}", e))
+                .ok()
+        }
+        _ => None,
+    };
+    let mut explained = explained.unwrap_or_else(|| Explained {
+        summary: response.to_string(),
+        ..Default::default()
+    });
+    explained.summary = cap_words(&explained.summary, MAX_SUMMARY_WORDS);
+    explained.parameters.truncate(MAX_PARAMETERS);
+    explained.returns = explained
+        .returns
+        .map(|returns| returns.trim().to_string())
+        .filter(|returns| !returns.is_empty());
+    explained
+}
+
+fn cap_words(text: &str, max_words: usize) -> String {
+    let words = text.split_whitespace().collect::<Vec<_>>();
+    match words.len() > max_words {
+        true => format!("{}...", words[..max_words].join(" ")),
+        false => words.join(" "),
+    }
+}
+
+fn snippet(content: &str, lines: Range<usize>) -> String {
+    content
+        .lines()
+        .skip(lines.start)
+        .take(lines.end.saturating_sub(lines.start))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::ast::CodeFileAST;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SOURCE: &str = "use crate::billing::charge;\n\nfn settle(amount: u64) -> u64 {\n    fn with_fee(value: u64) -> u64 {\n        let fee = fee_of(value);\n        charge(value + fee)\n    }\n    with_fee(amount)\n}\n\nfn fee_of(value: u64) -> u64 {\n    value / 100\n}\n";
+
+    const RESPONSE: &str = r#"{"summary": "Charges the value with its fee.", "parameters": [{"name": "value", "description": "the amount to charge"}], "returns": "the charged amount"}"#;
+
+    fn file(content: &str) -> FlowFile {
+        FlowFile {
+            path: "src/settle.rs".to_string(),
+            content: content.to_string(),
+            graph: CodeFileAST::build_ast(content.as_bytes(), "Rust")
+                .unwrap()
+                .scope_graph()
+                .unwrap(),
+        }
+    }
+
+    async fn charge(name: String) -> Option<FoundCallee> {
+        (name == "charge").then(|| FoundCallee {
+            path: "src/billing.rs".to_string(),
+            line: 9,
+            snippet: "pub fn charge(amount: u64) -> u64 {".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_explains_the_innermost_function_at_a_line() {
+        let cache = ExplainCache::new(8);
+        let file = file(SOURCE);
+        // the line of the fee, inside the function nested in `settle`.
+        let byte = line_byte(SOURCE, 5).unwrap();
+        assert_eq!(&SOURCE[byte..byte + 3], "let");
+
+        let prompt = &Mutex::new(String::new());
+        let (explanation, status) = explain_with(
+            &cache,
+            "acme/billing",
+            &file,
+            byte,
+            move |messages| async move {
+                *prompt.lock().unwrap() = messages[0].to_string();
+                Ok(RESPONSE.to_string())
+            },
+            charge,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(explanation.symbol, "with_fee");
+        assert_eq!((explanation.start_line, explanation.end_line), (4, 7));
+        assert_eq!(explanation.summary, "Charges the value with its fee.");
+        assert_eq!(explanation.parameters[0].name, "value");
+        assert_eq!(explanation.returns.as_deref(), Some("the charged amount"));
+        assert_eq!(
+            explanation.callees,
+            vec![
+                ExplainedCallee {
+                    name: "fee_of".to_string(),
+                    path: "src/settle.rs".to_string(),
+                    line: 11,
+                },
+                ExplainedCallee {
+                    name: "charge".to_string(),
+                    path: "src/billing.rs".to_string(),
+                    line: 10,
+                },
+            ]
+        );
+        assert!(explanation.tokens_used > 0);
+        // the function enclosing the nested one isn't explained.
+        assert!(!prompt.lock().unwrap().contains("with_fee(amount)"));
+
+        // outside of any function.
+        let byte = line_byte(SOURCE, 1).unwrap();
+        let explained = explain_with(
+            &cache,
+            "acme/billing",
+            &file,
+            byte,
+            |_| async { Ok(RESPONSE.to_string()) },
+            charge,
+        )
+        .await
+        .unwrap();
+        assert!(explained.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cached_explanations_skip_the_model() {
+        let cache = ExplainCache::new(8);
+        let calls = &AtomicUsize::new(0);
+        let explain = |content: &'static str, line: usize| {
+            let cache = &cache;
+            async move {
+                let file = file(content);
+                explain_with(
+                    cache,
+                    "acme/billing",
+                    &file,
+                    line_byte(content, line).unwrap(),
+                    move |_| async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(RESPONSE.to_string())
+                    },
+                    charge,
+                )
+                .await
+                .unwrap()
+                .unwrap()
+            }
+        };
+
+        let (first, status) = explain(SOURCE, 5).await;
+        assert_eq!(status, CacheStatus::Miss);
+        // another line of the same function.
+        let (second, status) = explain(SOURCE, 6).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(second.tokens_used, 0);
+        assert_eq!(
+            second,
+            ExplainSymbolResponse {
+                tokens_used: 0,
+                ..first
+            }
+        );
+
+        // a change of the file is explained again.
+        let changed = "use crate::billing::charge;\n\nfn settle(amount: u64) -> u64 {\n    fn with_fee(value: u64) -> u64 {\n        let fee = fee_of(value) + 1;\n        charge(value + fee)\n    }\n    with_fee(amount)\n}\n\nfn fee_of(value: u64) -> u64 {\n    value / 100\n}\n";
+        let (_, status) = explain(changed, 5).await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_explanations_are_capped() {
+        let long = format!(
+            r#"{{"summary": "{}", "returns": " "}}"#,
+            "word ".repeat(200)
+        );
+        let explained = parse_explanation(&long);
+        assert_eq!(
+            explained.summary.split_whitespace().count(),
+            MAX_SUMMARY_WORDS
+        );
+        assert!(explained.summary.ends_with("..."));
+        assert_eq!(explained.returns, None);
+
+        let explained = parse_explanation("Adds the fee to the value.");
+        assert_eq!(explained.summary, "Adds the fee to the value.");
+        assert!(explained.parameters.is_empty());
+    }
+}
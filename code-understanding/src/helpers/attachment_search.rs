@@ -0,0 +1,40 @@
+use anyhow::Error;
+
+use common::attachments::{AttachmentHit, AttachmentSearchRequest, MAX_ATTACHMENT_HITS};
+use common::local_services;
+
+use crate::config::get_search_server_url;
+use crate::helpers::symbol_search::with_trace_headers;
+
+// The sections of the documents attached to the conversation closest to the query. Code search
+// only returns the sections of the given conversation.
+pub async fn attachment_search(
+    query: &str,
+    conversation_id: &str,
+) -> Result<Vec<AttachmentHit>, Error> {
+    let url = format!("{}/attachments/search", get_search_server_url());
+    let body = AttachmentSearchRequest {
+        conversation_id: conversation_id.to_string(),
+        query: query.to_string(),
+        limit: MAX_ATTACHMENT_HITS,
+    };
+    if local_services::is_local(&url) {
+        return local_services::call_json("POST", &url, Some(&body)).await;
+    }
+
+    let mut request = with_trace_headers(reqwest::Client::new().post(&url).json(&body));
+    if let Some(key) = common::auth::service_api_key() {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await?;
+
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(Error::msg(format!(
+            "Attachment search failed with status code: {:?}, Error: {:?}",
+            response.status(),
+            response.text().await
+        )));
+    }
+
+    Ok(response.json().await?)
+}
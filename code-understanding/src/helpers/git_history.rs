@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use tokio::process::Command;
+
+// Only the commits that touched the most lines of the range are given to the model.
+const MAX_HISTORY_COMMITS: usize = 10;
+// Long commit messages are cut, the subject and first paragraph are usually enough.
+const MAX_MESSAGE_CHARS: usize = 600;
+// Lines that aren't committed yet are blamed on this hash.
+const UNCOMMITTED_HASH: &str = "0000000000000000000000000000000000000000";
+
+/// A commit that last touched some lines of the blamed range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlamedCommit {
+    pub hash: String,
+    pub author: String,
+    // seconds since the epoch.
+    pub author_time: i64,
+    pub message: String,
+    // number of lines of the range last touched by the commit.
+    pub lines: usize,
+}
+
+/// Blames the 1-based, inclusive line range of a file and aggregates the lines per commit,
+/// the commits touching the most lines first.
+pub async fn blame_range(
+    working_copy: &Path,
+    path: &str,
+    start_line: usize,
+    end_line: usize,
+) -> Result<Vec<BlamedCommit>> {
+    if start_line == 0 || end_line < start_line {
+        return Err(anyhow!("Invalid line range {}-{}", start_line, end_line));
+    }
+
+    let porcelain = run_git(
+        working_copy,
+        &[
+            "blame",
+            "--porcelain",
+            "-L",
+            &format!("{},{}", start_line, end_line),
+            "--",
+            path,
+        ],
+    )
+    .await?;
+    let mut commits = parse_blame_porcelain(&porcelain);
+    commits.truncate(MAX_HISTORY_COMMITS);
+    if commits.is_empty() {
+        return Ok(commits);
+    }
+
+    // the porcelain output only carries the subject line, the full messages are read in one call.
+    let mut args = vec!["log", "--no-walk=unsorted", "--format=%H%x00%B%x1e"];
+    args.extend(commits.iter().map(|commit| commit.hash.as_str()));
+    let log = run_git(working_copy, &args).await?;
+    let messages = log
+        .split('\x1e')
+        .filter_map(|entry| entry.trim_start().split_once('\0'))
+        .map(|(hash, message)| (hash.to_string(), message.trim().to_string()))
+        .collect::<HashMap<_, _>>();
+    for commit in commits.iter_mut() {
+        if let Some(message) = messages.get(&commit.hash) {
+            commit.message = message.clone();
+        }
+    }
+
+    Ok(commits)
+}
+
+async fn run_git(working_copy: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(working_copy)
+        .args(args)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Counts the blamed lines per commit. The author and summary headers are only printed
+// the first time a commit shows up, every line starts with `<hash> <orig> <final> [<group>]`
+// and ends with its content prefixed by a tab.
+fn parse_blame_porcelain(porcelain: &str) -> Vec<BlamedCommit> {
+    let mut commits: Vec<BlamedCommit> = Vec::new();
+    let mut current: Option<usize> = None;
+
+    for line in porcelain.lines() {
+        if line.starts_with('\t') {
+            if let Some(index) = current.take() {
+                commits[index].lines += 1;
+            }
+            continue;
+        }
+        if current.is_none() {
+            let hash = line.split(' ').next().unwrap_or_default();
+            if hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                let index = match commits.iter().position(|commit| commit.hash == hash) {
+                    Some(index) => index,
+                    None => {
+                        commits.push(BlamedCommit {
+                            hash: hash.to_string(),
+                            author: String::new(),
+                            author_time: 0,
+                            message: String::new(),
+                            lines: 0,
+                        });
+                        commits.len() - 1
+                    }
+                };
+                current = Some(index);
+            }
+            continue;
+        }
+
+        let commit = &mut commits[current.unwrap()];
+        if let Some(author) = line.strip_prefix("author ") {
+            commit.author = author.to_string();
+        } else if let Some(time) = line.strip_prefix("author-time ") {
+            commit.author_time = time.parse().unwrap_or_default();
+        } else if let Some(summary) = line.strip_prefix("summary ") {
+            commit.message = summary.to_string();
+        }
+    }
+
+    commits.retain(|commit| commit.hash != UNCOMMITTED_HASH);
+    // the sort is stable, commits touching as many lines keep the order of the file.
+    commits.sort_by(|a, b| b.lines.cmp(&a.lines));
+    commits
+}
+
+/// Compact listing of the commits for the model, one header line per commit followed by
+/// its indented message.
+pub fn format_history(commits: &[BlamedCommit], redact_authors: bool) -> String {
+    commits
+        .iter()
+        .map(|commit| {
+            let date = Utc
+                .timestamp_opt(commit.author_time, 0)
+                .single()
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_default();
+            let author = if redact_authors {
+                "[redacted]"
+            } else {
+                commit.author.as_str()
+            };
+            let mut message = commit.message.clone();
+            if message.len() > MAX_MESSAGE_CHARS {
+                let end = (0..=MAX_MESSAGE_CHARS)
+                    .rev()
+                    .find(|&i| message.is_char_boundary(i))
+                    .unwrap_or(0);
+                message.truncate(end);
+                message.push_str("...");
+            }
+            let message = message
+                .lines()
+                .map(|line| format!("    {}", line))
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!(
+                "{} {} {} ({} lines)\n{}",
+                &commit.hash[..commit.hash.len().min(10)],
+                date,
+                author,
+                commit.lines,
+                message
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command as StdCommand;
+
+    fn git(repo: &Path, args: &[&str], date: &str) {
+        let status = StdCommand::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(args)
+            .env("GIT_AUTHOR_DATE", date)
+            .env("GIT_COMMITTER_DATE", date)
+            .status()
+            .expect("git is not installed");
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn commit(repo: &Path, content: &str, author: &str, message: &str, date: &str) {
+        std::fs::write(repo.join("retry.rs"), content).unwrap();
+        git(repo, &["add", "retry.rs"], date);
+        git(
+            repo,
+            &[
+                "-c",
+                &format!("user.name={}", author),
+                "-c",
+                "user.email=dev@example.com",
+                "commit",
+                "-q",
+                "-m",
+                message,
+            ],
+            date,
+        );
+    }
+
+    // Three commits: the file, a retry loop over lines 2-4, then a log line at line 3.
+    fn fixture_repo() -> PathBuf {
+        let repo = std::env::temp_dir().join(format!("git-history-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&repo).unwrap();
+        git(&repo, &["init", "-q"], "2024-01-01T00:00:00Z");
+        commit(
+            &repo,
+            "fn upsert() {\n    client.upsert();\n}\n",
+            "Ada",
+            "Add upsert",
+            "2024-01-01T00:00:00Z",
+        );
+        commit(
+            &repo,
+            "fn upsert() {\n    for _ in 0..3 {\n        client.upsert();\n    }\n}\n",
+            "Grace",
+            "Retry the upsert\n\nQdrant times out under load, three attempts are enough.",
+            "2024-02-01T00:00:00Z",
+        );
+        commit(
+            &repo,
+            "fn upsert() {\n    for _ in 0..3 {\n        log::debug!(\"upsert\");\n        client.upsert();\n    }\n}\n",
+            "Linus",
+            "Log the upsert attempts",
+            "2024-03-01T00:00:00Z",
+        );
+        repo
+    }
+
+    #[tokio::test]
+    async fn test_blame_aggregates_commits_over_range() {
+        let repo = fixture_repo();
+
+        // lines 2-5 are the retry loop with the log line inside.
+        let commits = blame_range(&repo, "retry.rs", 2, 5).await.unwrap();
+        let summary = commits
+            .iter()
+            .map(|commit| (commit.author.as_str(), commit.lines))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![("Grace", 3), ("Linus", 1)]);
+        assert_eq!(
+            commits[0].message,
+            "Retry the upsert\n\nQdrant times out under load, three attempts are enough."
+        );
+
+        let history = format_history(&commits, false);
+        assert!(history.contains("2024-02-01 Grace (3 lines)"), "{}", history);
+        assert!(history.contains("    Qdrant times out under load"), "{}", history);
+        let redacted = format_history(&commits, true);
+        assert!(!redacted.contains("Grace") && redacted.contains("[redacted]"));
+
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blame_outside_repo_fails() {
+        let repo = fixture_repo();
+        assert!(blame_range(&repo, "missing.rs", 1, 2).await.is_err());
+        assert!(blame_range(&repo, "retry.rs", 3, 2).await.is_err());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
+pub mod attachment_search;
 pub mod build_fuzzy_regex_filter;
 pub mod case_permutations;
+pub mod git_history;
 pub mod symbol_search;
 pub mod trigrams;
+pub mod terminology;
@@ -1,28 +1,55 @@
 use reqwest;
-use serde_json::json;
 use anyhow::Error;
 extern crate common;
 
-use common::models::CodeChunk;
+use common::generation::{IndexGeneration, IndexGenerationGone};
+use common::models::{CodeChunk, ExactSymbolQuery, SymbolMatch, SymbolSearchRequest};
+use common::{local_services, telemetry};
 
 use crate::config::get_search_server_url;
 
+// `include_tests` keeps the scores of test and vendored code, which code search lowers otherwise.
+// `index_generation` is the generation the conversation is pinned to, once one of its collections
+// was dropped the search fails with `IndexGenerationGone`. `expand_terminology` adds the
+// expansions of the jargon of the query from the dictionary of the repo.
 pub async fn symbol_search(
     query: &str,
     repo_name: &str,
+    include_tests: bool,
+    index_generation: Option<&str>,
+    expand_terminology: bool,
 ) -> Result<Vec<CodeChunk>, Error> {
     let base_url = get_search_server_url(); 
     let namespace = repo_name;
     let client = reqwest::Client::new();
     let url = format!("{}/symbols", base_url);
+    let body = SymbolSearchRequest {
+        include_tests,
+        index_generation: index_generation
+            .map(str::parse::<IndexGeneration>)
+            .transpose()?,
+        terminology: expand_terminology,
+        ..SymbolSearchRequest::new(query, namespace)
+    };
+    // the search server is hosted in this process, skip the network.
+    if local_services::is_local(&url) {
+        return local_services::call_json("POST", &url, Some(&body)).await;
+    }
 
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .json(&json!({ "query": query, "repo_name": namespace }))
-        .send()
-        .await?;
+    let mut request = with_trace_headers(
+        client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .json(&body),
+    );
+    if let Some(key) = common::auth::service_api_key() {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await?;
 
+    if response.status() == reqwest::StatusCode::GONE {
+        return Err(response.json::<IndexGenerationGone>().await?.into());
+    }
     if response.status() != reqwest::StatusCode::OK {
         // return error message with status code 
         return Err(Error::msg(format!(
@@ -36,3 +63,58 @@ pub async fn symbol_search(
 
     Ok(search_results)
 }
+
+// Looks a symbol up by its exact name instead of by semantic similarity, in the collection of
+// `index_generation` when the conversation is pinned to one.
+pub async fn exact_symbol_lookup(
+    name: &str,
+    kind: Option<&str>,
+    container: Option<&str>,
+    case_sensitive: Option<bool>,
+    repo_name: &str,
+    index_generation: Option<&str>,
+) -> Result<Vec<SymbolMatch>, Error> {
+    let base_url = get_search_server_url();
+    let client = reqwest::Client::new();
+    let url = format!("{}/symbols/exact", base_url);
+
+    let query = ExactSymbolQuery {
+        repo_name: repo_name.to_string(),
+        name: name.to_string(),
+        kind: kind.map(str::to_string),
+        container: container.map(str::to_string),
+        case_sensitive,
+        branch: None,
+        index_generation: index_generation.map(str::to_string),
+    };
+    if local_services::is_local(&url) {
+        let url = client.get(&url).query(&query).build()?.url().clone();
+        return local_services::call_json::<(), _>("GET", url.as_str(), None).await;
+    }
+
+    let mut request = with_trace_headers(client.get(&url).query(&query));
+    if let Some(key) = common::auth::service_api_key() {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await?;
+
+    if response.status() == reqwest::StatusCode::GONE {
+        return Err(response.json::<IndexGenerationGone>().await?.into());
+    }
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(Error::msg(format!(
+            "Exact symbol lookup failed with status code: {:?}, Error: {:?}",
+            response.status(), response.text().await
+        )));
+    }
+
+    Ok(response.json().await?)
+}
+
+// `telemetry::propagate` takes the request builder of the reqwest version of `common`,
+// this service is still on an older one so the headers are added one by one.
+pub(crate) fn with_trace_headers(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    telemetry::trace_headers(&tracing::Span::current())
+        .into_iter()
+        .fold(request, |request, (name, value)| request.header(name, value))
+}
@@ -0,0 +1,38 @@
+use anyhow::Error;
+
+use common::local_services;
+use common::terminology::{RepoTerminology, Terminology};
+
+use crate::config::get_search_server_url;
+use crate::helpers::symbol_search::with_trace_headers;
+
+// The dictionary of the repo, empty when it has none. The suggestions mined from its docs aren't
+// used until they are approved into it.
+pub async fn fetch_terminology(repo_name: &str) -> Result<Terminology, Error> {
+    let url = format!(
+        "{}/repos/{}/terminology",
+        get_search_server_url(),
+        repo_name
+    );
+    if local_services::is_local(&url) {
+        let repo_terminology: RepoTerminology =
+            local_services::call_json("GET", &url, None::<&()>).await?;
+        return Ok(repo_terminology.terminology);
+    }
+
+    let mut request = with_trace_headers(reqwest::Client::new().get(&url));
+    if let Some(key) = common::auth::service_api_key() {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await?;
+
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(Error::msg(format!(
+            "Terminology fetch failed with status code: {:?}, Error: {:?}",
+            response.status(),
+            response.text().await
+        )));
+    }
+
+    Ok(response.json::<RepoTerminology>().await?.terminology)
+}
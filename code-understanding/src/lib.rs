@@ -0,0 +1,76 @@
+use anyhow::Result;
+use config::{get_explain_cache_entries, get_search_server_url, Config};
+use once_cell::sync::Lazy;
+use common::dependencies::DependencyChecker;
+use std::{sync::RwLock, time::Duration};
+
+mod agent;
+mod batch;
+pub mod config;
+mod content_cache;
+mod controller;
+mod db_client;
+mod explain;
+mod helpers;
+mod parser;
+pub mod routes;
+mod search;
+
+use core::result::Result::Ok;
+pub struct AppState {
+    db_connection: db_client::DbConnect, // Assuming DbConnection is your database connection type
+    // explanations of `POST /explain-symbol`, kept for the lifetime of the process.
+    explain_cache: explain::ExplainCache,
+}
+
+// initialize the app state with the configuration and database connection.
+pub async fn init_state() -> Result<AppState, anyhow::Error> {
+    // a search server hosted in the same process is up as soon as its routes are registered.
+    if common::local_services::is_local(&get_search_server_url()) {
+        log::info!("Search server is hosted in process at {}", get_search_server_url());
+    } else {
+        wait_for_search_server().await?;
+    }
+
+    // create new db client.
+    let db_client = match db_client::DbConnect::new().await {
+        Ok(client) => client,
+        Err(_) => {
+            log::error!("Initializing database failed.");
+            return Err(anyhow::anyhow!("Initializing database failed."));
+        }
+    };
+
+    Ok(AppState {
+        db_connection: db_client,
+        explain_cache: explain::ExplainCache::new(get_explain_cache_entries()),
+    })
+}
+
+async fn wait_for_search_server() -> Result<(), anyhow::Error> {
+    let search_url = format!("{}/", get_search_server_url());
+
+    // try once and retry once after 5 seconds, the wait doesn't block the runtime.
+    DependencyChecker::new()
+        .retry(2, Duration::from_secs(5))
+        .http("search-server", &search_url)
+        .wait_until_ready()
+        .await
+        .map_err(|err| {
+            log::error!("Search server is not running after retries. Please start the search server first.");
+            anyhow::anyhow!("{}", err)
+        })?;
+    log::info!("Search server is running at {}", search_url);
+    Ok(())
+}
+
+// global configuration while RwLock is used to ensure thread safety
+// Rwlock makes reads cheap, which is important because we will be reading the configuration a lot, and never mutate it after it is set.
+static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
+    RwLock::new(Config::default())
+});
+
+pub fn set_config(config: Config) {
+    let mut global_config = CONFIG.write().expect("Failed to acquire write lock");
+    *global_config = config;
+}
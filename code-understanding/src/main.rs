@@ -1,73 +1,11 @@
 use anyhow::Result;
-use config::{get_search_server_url, load_from_env, Config};
-use once_cell::sync::Lazy;
-use std::{env, sync::RwLock, thread::sleep, time::Duration};
-
-mod agent;
-mod config;
-mod controller;
-mod db_client;
-mod helpers;
-mod parser;
-mod routes;
-mod search;
+use code_understanding::config::load_from_env;
+use code_understanding::{init_state, routes, set_config};
+use common::shutdown;
+use std::env;
 use std::sync::Arc;
 
 use core::result::Result::Ok;
-use redis;
-struct AppState {
-    db_connection: db_client::DbConnect, // Assuming DbConnection is your database connection type
-}
-
-// initialize the app state with the configuration and database connection.
-async fn init_state() -> Result<AppState, anyhow::Error> {
-    let search_url = format!("{}/", get_search_server_url());
-
-    // Attempt to connect to the search server with retry logic
-    let mut attempts = 0;
-    let max_attempts = 2; // Try once initially and retry once
-    while attempts < max_attempts {
-        let response = reqwest::get(&search_url).await;
-
-        match response {
-            Ok(_) => {
-                log::info!("Search server is running at {}", search_url);
-                break; // Exit the loop on success
-            }
-            Err(e) if attempts < max_attempts - 1 => {
-                log::debug!("Search server not available, waiting 5 seconds before retrying...");
-                sleep(Duration::from_secs(5)); // Wait for 5 seconds
-                attempts += 1; // Increment the retry counter
-            }
-            Err(_) => {
-                log::error!("Search server is not running after retries. Please start the search server first.");
-                return Err(anyhow::anyhow!(
-                    "Search server is not running. Please start the search server first."
-                ));
-            }
-        }
-    }
-
-    // create new db client.
-    let db_client = match db_client::DbConnect::new().await {
-        Ok(client) => client,
-        Err(_) => {
-            log::error!("Initializing database failed.");
-            return Err(anyhow::anyhow!("Initializing database failed."));
-        }
-    };
-
-    Ok(AppState {
-        db_connection: db_client,
-    })
-}
-
-// global configuration while RwLock is used to ensure thread safety
-// Rwlock makes reads cheap, which is important because we will be reading the configuration a lot, and never mutate it after it is set.
-static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
-    RwLock::new(Config::default())
-});
-
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -90,10 +28,18 @@ async fn main() -> Result<()> {
     }
 
     // Load configuration
-    let config = load_from_env(env_file);
-    {
-        let mut global_config = CONFIG.write().expect("Failed to acquire write lock");
-        *global_config = config;
+    set_config(load_from_env(env_file));
+
+    // the api keys come from the env file loaded above.
+    if let Err(err) = common::auth::init_key_store() {
+        log::error!("Failed to initialize the API key store: {}", err);
+        std::process::exit(1);
+    }
+
+    // traces are only exported when an OTLP endpoint is configured in the env.
+    if let Err(err) = common::telemetry::init("code-understanding") {
+        log::error!("Failed to initialize tracing: {}", err);
+        std::process::exit(1);
     }
 
     // initialize the env configurations and database connection.
@@ -108,11 +54,19 @@ async fn main() -> Result<()> {
         }
     };
 
+    let shutdown = shutdown::global();
+    shutdown.listen_for_signals();
+
     let code_retrieve_routes = routes::code_retrieve(app_state);
 
-    warp::serve(code_retrieve_routes)
-        .run(([0, 0, 0, 0], 3002))
-        .await;
+    let (addr, server) = warp::serve(code_retrieve_routes)
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], 3002), shutdown.wait());
+    log::info!("Started web server on http://{}", addr);
+
+    // running agents stop at their next step boundary, their exchanges are already in redis.
+    shutdown.drain(server, shutdown::drain_timeout()).await;
+    common::telemetry::shutdown();
+    log::info!("Code understanding shut down");
 
     Ok(())
 }
@@ -1,27 +1,137 @@
+use crate::agent::replay::ReplayRequest;
 use crate::controller;
 use crate::AppState;
-use common::models::CodeUnderstandRequest;
+use common::models::{CodeUnderstandBatchRequest, CodeUnderstandRequest, ExplainSymbolRequest};
 use std::sync::Arc;
+use common::capabilities::{version_route, Capability};
+use common::prompt_versions::prompt_versions_route;
+use common::{auth, metrics, telemetry, transport};
 use warp::{self, http::Response, Filter};
 
 pub fn code_retrieve(
     app_state: Arc<AppState>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     home_route()
+        .or(readiness(app_state.clone()))
         .or(retrieve_code(app_state.clone()))
+        .or(answer_batch(app_state.clone()))
+        .or(explain_symbol(app_state.clone()))
+        .or(replay())
+        .or(prompt_versions_route())
+        .or(version())
+        .or(metrics::metrics_route())
+        .recover(auth::handle_rejection)
+        .with(warp::log::custom(|info| {
+            metrics::observe_http_request(
+                "code-understanding",
+                info.path(),
+                info.method().as_str(),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }))
+        .with(warp::trace(telemetry::request_span))
 }
 
 /// GET /retrieve-code?query=<query>&repo=<repo_name>
+/// Replies with `application/msgpack` when the `Accept` header asks for it, json otherwise.
 fn retrieve_code(
     app_state: Arc<AppState>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("retrieve-code")
         .and(warp::get())
         .and(warp::query::<CodeUnderstandRequest>())
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
+        .and(warp::header::optional::<String>("accept"))
         .and(warp::any().map(move || app_state.clone()))
         .and_then(controller::handle_retrieve_code)
 }
 
+/// POST /answer-batch
+/// Answers the questions of the body, in json or msgpack, against its repo, sharing the documents
+/// and searches they have in common. Streams one answer or error per question as each one is
+/// written, then the cost of the batch, in the transport the `Accept` header asks for.
+fn answer_batch(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("answer-batch")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(transport::body::<CodeUnderstandBatchRequest>())
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(controller::handle_answer_batch)
+}
+
+/// POST /explain-symbol
+/// Explains the innermost function enclosing the `line` or `byte` of a file in a single call of
+/// the model, for the hovers of editors. Replies from the cache, with `X-Cache: hit`, when the
+/// function of the same content was explained before.
+fn explain_symbol(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("explain-symbol")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json::<ExplainSymbolRequest>())
+        .and(auth::authenticate())
+        .and_then(auth::authorize_repo)
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(controller::handle_explain_symbol)
+}
+
+/// POST /admin/replay
+/// Generates the answer of a traced exchange again, with the recorded response of the model or
+/// another model, and an optional answer prompt template. Replies with the original answer, the
+/// replayed one and what changed between them, in json. Needs the admin scope.
+fn replay() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "replay")
+        .and(warp::post())
+        .and(warp::body::json::<ReplayRequest>())
+        .and(auth::authenticate().and_then(auth::authorize_admin))
+        .and_then(controller::handle_replay)
+}
+
+/// GET /ready
+/// 200 once qdrant lists its collections and quickwit its indexes, 503 otherwise. `degraded` is
+/// set while a database client is being rebuilt after a restart of the database.
+fn readiness(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || app_state.clone()))
+        .and_then(controller::handle_ready)
+}
+
+/// GET /version
+/// Crate version and the optional API features of this build, for the handshake of the coordinator.
+fn version() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    version_route(
+        "code-understanding",
+        env!("CARGO_PKG_VERSION"),
+        &[
+            Capability::PinnedPaths,
+            Capability::Preferences,
+            Capability::Msgpack,
+            Capability::Language,
+            Capability::AnswerBatch,
+            Capability::Budget,
+            Capability::Attachments,
+            Capability::Verification,
+            Capability::IndexGeneration,
+            Capability::Terminology,
+            Capability::ExplainSymbol,
+            Capability::Glossary,
+            Capability::AskUser,
+        ],
+    )
+}
+
 fn home_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path::end() // Matches the root path "/"
         .and(warp::get()) // Only responds to GET requests
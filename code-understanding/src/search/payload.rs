@@ -1,6 +1,8 @@
 // import all the necessary modules.
 use std::{borrow::Cow, collections::HashMap, str};
 
+use common::compression;
+use common::symbol_payload::SymbolOccurrence;
 use qdrant_client::qdrant::{
     point_id::PointIdOptions, vectors::VectorsOptions, PointId, ScoredPoint, Value, Vectors,
 };
@@ -12,14 +14,9 @@ pub type Embedding = Vec<f32>;
 pub struct SymbolPayload {
     pub repo_name: String,
     pub symbol: String,
-
-    pub symbol_types: Vec<String>,
-    pub lang_ids: Vec<String>,
-    pub is_globals: Vec<bool>,
-    pub start_bytes: Vec<i64>,
-    pub end_bytes: Vec<i64>,
-    pub relative_paths: Vec<String>,
-    pub node_kinds: Vec<String>,
+    // stored as the parallel columns of the payload, see `common::symbol_payload`.
+    #[serde(flatten, with = "common::symbol_payload::flat")]
+    pub occurrences: Vec<SymbolOccurrence>,
 
     #[serde(skip)]
     pub id: Option<String>,
@@ -166,7 +163,10 @@ fn parse_payload(
         repo_name: val_str!(converted, "repo_name"),
         relative_path: val_str!(converted, "relative_path"),
         content_hash: val_str!(converted, "content_hash"),
-        text: val_str!(converted, "snippet"),
+        text: compression::take_chunk_text(&mut converted).unwrap_or_else(|e| {
+            log::error!("Failed to read the chunk text: {}", e);
+            String::new()
+        }),
         start_line: val_parse_str!(converted, "start_line"),
         end_line: val_parse_str!(converted, "end_line"),
         start_byte: val_parse_str!(converted, "start_byte"),
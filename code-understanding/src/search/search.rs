@@ -4,6 +4,8 @@ use crate::search::semantic::{
     deduplicate_snippets, make_kv_keyword_filter, Semantic, 
 };
 use anyhow::Result;
+use common::branch::{BRANCH_FIELD, DEFAULT_BRANCH};
+use common::search_batch::search_one;
 use qdrant_client::qdrant::{
     with_payload_selector, with_vectors_selector, Condition, Filter, ScoredPoint, SearchPoints,
     WithPayloadSelector, WithVectorsSelector,
@@ -58,6 +60,44 @@ impl Agent {
     }
 }
 
+/// Search of the code chunks of a repo closest to `vector`, the doc chunks stored next to them
+/// are left out.
+pub fn code_search_request(
+    collection_name: &str,
+    vector: Embedding,
+    limit: u64,
+    offset: u64,
+    threshold: f32,
+    repo_name: &str,
+) -> SearchPoints {
+    let mut conditions: Vec<Condition> = Vec::new();
+
+    conditions.push(make_kv_keyword_filter("repo_name", repo_name).into());
+    // answers are written from the default branch of the repo.
+    conditions.push(make_kv_keyword_filter(BRANCH_FIELD, DEFAULT_BRANCH).into());
+
+    SearchPoints {
+        limit,
+        vector,
+        collection_name: collection_name.to_string(),
+        offset: Some(offset),
+        score_threshold: Some(threshold),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        filter: Some(Filter {
+            must: conditions,
+            // the chunks indexed before the doc chunks existed have no kind.
+            must_not: vec![make_kv_keyword_filter("kind", "doc").into()],
+            ..Default::default()
+        }),
+        with_vectors: Some(WithVectorsSelector {
+            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+        }),
+        ..Default::default()
+    }
+}
+
 impl Semantic {
     pub async fn search_with<'a>(
         &self,
@@ -68,34 +108,14 @@ impl Semantic {
         threshold: f32,
         repo_name: &str,
     ) -> anyhow::Result<Vec<ScoredPoint>> {
-        let mut conditions: Vec<Condition> = Vec::new();
-
-        conditions.push(make_kv_keyword_filter("repo_name", repo_name).into());
-
-        let response = self
-            .qdrant
-            .search_points(&SearchPoints {
-                limit,
-                vector,
-                collection_name: collection_name.to_owned().to_string(),
-                offset: Some(offset),
-                score_threshold: Some(threshold),
-                with_payload: Some(WithPayloadSelector {
-                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-                }),
-                filter: Some(Filter {
-                    must: conditions,
-                    ..Default::default()
-                }),
-                with_vectors: Some(WithVectorsSelector {
-                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
-                }),
-                ..Default::default()
-            })
-            .await?;
+        let points = search_one(
+            &self.qdrant,
+            code_search_request(collection_name, vector, limit, offset, threshold, repo_name),
+        )
+        .await?;
 
         // iterate through the results and print the score and payload from each entry in the results
-        let mut results = response.result.clone();
+        let mut results = points.clone();
         results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
 
         log::debug!("---------xxxxxxxxxxxxxxx----------------");
@@ -116,7 +136,7 @@ impl Semantic {
             })
             .collect::<Vec<_>>();
 
-        Ok(response.result)
+        Ok(points)
     }
 
     pub async fn search<'a>(
@@ -151,3 +171,37 @@ impl Semantic {
         Ok(deduplicate_snippets(results, vector, limit))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::{condition::ConditionOneOf, r#match::MatchValue};
+
+    fn keywords(conditions: &[Condition]) -> Vec<(&str, &str)> {
+        conditions
+            .iter()
+            .filter_map(|condition| match condition.condition_one_of.as_ref()? {
+                ConditionOneOf::Field(field) => {
+                    match field.r#match.as_ref()?.match_value.as_ref()? {
+                        MatchValue::Keyword(keyword) => {
+                            Some((field.key.as_str(), keyword.as_str()))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_code_search_leaves_the_doc_chunks_out() {
+        let request = code_search_request("documents", vec![0.1; 4], 30, 0, 0.0, "repo-a");
+        let filter = request.filter.unwrap();
+        assert_eq!(
+            keywords(&filter.must),
+            vec![("repo_name", "repo-a"), (BRANCH_FIELD, DEFAULT_BRANCH)]
+        );
+        assert_eq!(keywords(&filter.must_not), vec![("kind", "doc")]);
+    }
+}
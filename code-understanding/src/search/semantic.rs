@@ -6,9 +6,10 @@ use std::{
     str,
 };
 // import anyhow from anyhow
-use crate::config::{get_model_path, Config};
+use crate::config::{clone_config, get_model_path, Config};
 use crate::search::payload::{Embedding, Payload};
 use anyhow::Result;
+use common::reconnect::Reconnecting;
 use log::{error, info};
 use qdrant_client::{
     prelude::QdrantClient,
@@ -17,8 +18,9 @@ use qdrant_client::{
 
 pub struct Semantic {
     pub qdrant_collection_name: String,
-    pub qdrant: QdrantClient,
-    pub tokenize_onnx: common::tokenizer_onnx::TokenizerOnnx,
+    // rebuilt when qdrant restarts, see `common::reconnect`.
+    pub qdrant: Reconnecting<QdrantClient>,
+    pub tokenize_onnx: std::sync::Arc<common::tokenizer_onnx::TokenizerOnnx>,
 }
 
 #[derive(Error, Debug)]
@@ -35,31 +37,38 @@ pub enum SemanticError {
     },
 }
 
+// Builds the qdrant client from the config, again whenever qdrant restarted.
+fn qdrant_client(config: &Config) -> Result<QdrantClient> {
+    // Retrieve the Qdrant URL from the config object. We use a reference here to avoid ownership issues.
+    let qdrant_url = &config.semantic_url;
+
+    // Start building the Qdrant client with the URL.
+    let mut qdrant_client_builder = QdrantClient::from_url(qdrant_url);
+
+    // Check if the qdrant_api_key is present in the config. If it is, add it to the client builder.
+    if let Some(ref key) = config.qdrant_api_key {
+        info!("Using Qdrant API key. Using Qdrant with authentication.");
+        qdrant_client_builder = qdrant_client_builder.with_api_key(key.as_str());
+    } else {
+        info!("No Qdrant API key found. Using Qdrant without authentication.")
+    }
+
+    // Finalize building the Qdrant client. If this fails, the error will be propagated by `?`.
+    qdrant_client_builder.build()
+}
+
 impl Semantic {
     // Define an asynchronous function 'initialize' that takes a reference to a Config object and returns a Result.
     // This function initializes the struct it belongs to.
     pub async fn initialize(config: &Config) -> Result<Self, SemanticError> {
-        // Retrieve the Qdrant URL from the config object. We use a reference here to avoid ownership issues.
-        let qdrant_url = &config.semantic_url;
-
-        // Start building the Qdrant client with the URL.
-        let mut qdrant_client_builder = QdrantClient::from_url(qdrant_url);
-
-        // Check if the qdrant_api_key is present in the config. If it is, add it to the client builder.
-        if let Some(ref key) = config.qdrant_api_key {
-            info!("Using Qdrant API key. Using Qdrant with authentication.");
-            qdrant_client_builder = qdrant_client_builder.with_api_key(key.as_str());
-        } else {
-            info!("No Qdrant API key found. Using Qdrant without authentication.")
-        }
-
-        // Finalize building the Qdrant client. If this fails, the error will be propagated by `?`.
-        let qdrant = qdrant_client_builder.build()?;
+        let qdrant = Reconnecting::new("qdrant", qdrant_client(config)?, || async {
+            qdrant_client(&clone_config())
+        });
 
         // Construct and return the new instance, initializing each field.
         Ok(Self {
-            qdrant: qdrant.into(),
-            tokenize_onnx: common::tokenizer_onnx::TokenizerOnnx::new(&get_model_path())?,
+            qdrant,
+            tokenize_onnx: common::tokenizer_onnx::TokenizerOnnx::shared(&get_model_path())?,
             qdrant_collection_name: common::service_interaction::DOCUMENT_COLLECTION_NAME.to_string(), 
         })
     }
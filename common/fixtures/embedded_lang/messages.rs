@@ -0,0 +1,10 @@
+pub const HELP: &str = "Select the orders you want to archive from the list below";
+pub const PROMPT: &str = "Select one of the options from below to continue";
+pub const NOTICE: &str = "Update your profile to set a display name for the team";
+pub const TODO: &str = "create a new table layout for each customer page";
+pub const LABEL: &str = "SELECT id FROM t";
+pub const DELETE_HINT: &str = "Delete the selected items from your cart before checkout";
+
+pub fn describe(order: &Order) -> String {
+    format!("order {} from {} with {} items", order.id, order.customer, order.items.len())
+}
@@ -0,0 +1,17 @@
+use sqlx::PgPool;
+
+const ORDERS_BY_CUSTOMER: &str = "SELECT id, total, created_at FROM orders WHERE customer_id = $1 ORDER BY created_at DESC";
+
+pub async fn archive_order(pool: &PgPool, id: i64) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE orders
+        SET archived = true
+        WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
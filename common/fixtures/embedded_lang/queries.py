@@ -0,0 +1,10 @@
+def top_customers(conn, limit):
+    query = """
+        select c.id, c.name, sum(o.total) as spent
+        from customers c
+        join orders o on o.customer_id = c.id
+        group by c.id, c.name
+        order by spent desc
+        limit %s
+    """
+    return conn.execute(query, (limit,)).fetchall()
@@ -1,12 +1,53 @@
-use ai_gateway::{config::AIGatewayConfig, function_calling::{Function, FunctionCall}, message::message::Message};
+use ai_gateway::{config::AIGatewayConfig, utils::count_tokens, function_calling::{Function, FunctionCall}, message::message::Message};
 use log::debug;
 use anyhow::{Result, anyhow};
 
+use crate::budget::BudgetMeter;
+use crate::{metrics, telemetry};
+use tracing::Instrument;
+
 pub async fn call_llm(gateway_config: &str, user_msg: Option<String>, history: Option<Vec<Message>>, functions: Option<Vec<Function>>) -> Result<Vec<Message>> {
+    call_llm_metered(gateway_config, None, user_msg, history, functions).await
+}
+
+// Same as `call_llm`, with the call checked against the limits of `budget` before it is sent and
+// its cost counted after. Once the run is degraded the call goes to the cheaper model of the budget.
+// A rejected call fails with `BudgetExceeded`, callers can downcast the error to tell it apart.
+pub async fn call_llm_metered(gateway_config: &str, budget: Option<&BudgetMeter>, user_msg: Option<String>, history: Option<Vec<Message>>, functions: Option<Vec<Function>>) -> Result<Vec<Message>> {
     let mut ai_gateway_config = AIGatewayConfig::from_yaml(gateway_config)?;
+    // token usage is estimated with the gateway tokenizer, providers don't report it back through the gateway.
+    let prompt_tokens = user_msg.as_deref().map(count_tokens).unwrap_or_default()
+        + history
+            .as_deref()
+            .map(|history| ai_gateway_config.model.messages_tokens(history))
+            .unwrap_or_default();
+    if let Some(budget) = budget {
+        if let Some(model) = budget.plan(&ai_gateway_config.model.id(), prompt_tokens)? {
+            // a model without its client is served by the client of the configured one.
+            let model = match model.contains(':') {
+                true => model,
+                false => format!("{}:{}", ai_gateway_config.model.client_name, model),
+            };
+            ai_gateway_config.set_model(&model)?;
+        }
+    }
+    let provider = ai_gateway_config.model.client_name.clone();
+    let model = ai_gateway_config.model.name.clone();
+
+    let span = telemetry::llm_span(&provider, &model);
+    span.record("llm.prompt_tokens", prompt_tokens);
     let result = ai_gateway_config
         .use_llm(user_msg, history, functions)
-        .await?;
+        .instrument(span.clone())
+        .await;
+    metrics::record_llm_call(&provider, &model, result.is_ok());
+    let result = result?;
+    let completion_tokens = ai_gateway_config.model.messages_tokens(&result);
+    span.record("llm.completion_tokens", completion_tokens);
+    metrics::record_llm_tokens(&provider, &model, prompt_tokens, completion_tokens);
+    if let Some(budget) = budget {
+        budget.record(&ai_gateway_config.model.id(), prompt_tokens, completion_tokens);
+    }
 
     debug!("LLM response: {:?}", result);
     Ok(result)
@@ -0,0 +1,352 @@
+// Content anchors of the snippets an answer cites. The lines of a citation rot as soon as its file
+// changes, the anchor holds what the lines were: a hash of the cited lines, a hash of the lines
+// around them, a short fingerprint of every cited line and the enclosing symbol when it is known.
+// Code search relocates a snippet from its anchor in the current index: the same lines anywhere in
+// the file first, then the closest lines within the enclosing symbol, else the snippet is reported
+// deleted with the closest candidate.
+// An anchor is written `a1:<lines>:<hash>:<context hash>:<fingerprint>[:<symbol>]`, the symbol
+// last since it can hold any character.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Version of the anchor representation, written as its `a<version>` prefix.
+pub const ANCHOR_VERSION: u32 = 1;
+// lines hashed above and below the cited ones.
+const CONTEXT_LINES: usize = 2;
+// lines of a snippet with a fingerprint, the ones past it are only in the hash.
+const MAX_FINGERPRINT_LINES: usize = 64;
+// share of the fingerprint a candidate has to match for the snippet to count as changed rather
+// than deleted.
+const CHANGED_SIMILARITY: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnchorStatus {
+    // the snippet is at the lines it was cited at.
+    Unchanged,
+    // the same lines are elsewhere in the file.
+    Moved,
+    // the closest lines differ from the cited ones.
+    Changed,
+    // no lines are close enough, or the file is gone.
+    Deleted,
+}
+
+/// Where the snippet of an anchor is in the current version of its file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnchorResolution {
+    pub status: AnchorStatus,
+    // 1-based and inclusive, the closest candidate of a deleted snippet. None when the file is gone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines: Option<(usize, usize)>,
+    // share of the cited lines found at `lines`.
+    pub similarity: f32,
+}
+
+impl AnchorResolution {
+    /// The resolution of an anchor whose file isn't indexed anymore.
+    pub fn file_deleted() -> Self {
+        Self {
+            status: AnchorStatus::Deleted,
+            lines: None,
+            similarity: 0.0,
+        }
+    }
+
+    pub fn drifted(&self) -> bool {
+        self.status != AnchorStatus::Unchanged
+    }
+
+    /// e.g. `moved to L41-L50` or `changed, closest L41-L50 (90% similar)`.
+    pub fn render(&self) -> String {
+        let lines = self
+            .lines
+            .map(|(start, end)| format!("L{}-L{}", start, end));
+        match (self.status, lines) {
+            (AnchorStatus::Unchanged, _) => "unchanged".to_string(),
+            (AnchorStatus::Moved, Some(lines)) => format!("moved to {}", lines),
+            (AnchorStatus::Changed, Some(lines)) => format!(
+                "changed, closest {} ({:.0}% similar)",
+                lines,
+                self.similarity * 100.0
+            ),
+            (AnchorStatus::Deleted, Some(lines)) => format!(
+                "deleted, closest {} ({:.0}% similar)",
+                lines,
+                self.similarity * 100.0
+            ),
+            (_, None) => "deleted".to_string(),
+        }
+    }
+}
+
+/// What the cited lines of a file were, to find them again once the file changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnippetAnchor {
+    pub lines: usize,
+    hash: u64,
+    context: u64,
+    // a byte of the hash of each of the first `MAX_FINGERPRINT_LINES` lines.
+    fingerprint: Vec<u8>,
+    pub symbol: Option<String>,
+}
+
+impl SnippetAnchor {
+    /// The anchor of the 1-based inclusive `lines` of the file, None when they aren't in it.
+    pub fn new(content: &str, lines: (usize, usize), symbol: Option<&str>) -> Option<Self> {
+        let file = normalized_lines(content);
+        let (start, end) = (lines.0.max(1), lines.1.max(lines.0.max(1)));
+        if end > file.len() {
+            return None;
+        }
+        let cited = &file[start - 1..end];
+        Some(Self {
+            lines: cited.len(),
+            hash: hash_lines(cited),
+            context: context_hash(&file, start - 1, end),
+            fingerprint: fingerprint(cited),
+            symbol: symbol.filter(|symbol| !symbol.is_empty()).map(str::to_string),
+        })
+    }
+
+    /// Finds the snippet in the current `content` of its file. `cited` are the lines it was cited
+    /// at and `symbol_lines` the lines of its enclosing symbol in the current file, when found.
+    pub fn relocate(
+        &self,
+        content: &str,
+        cited: Option<(usize, usize)>,
+        symbol_lines: Option<(usize, usize)>,
+    ) -> AnchorResolution {
+        let file = normalized_lines(content);
+        if self.lines == 0 || file.is_empty() {
+            return AnchorResolution::file_deleted();
+        }
+
+        // the same lines, preferring the ones with the same surroundings and then the closest.
+        let exact = (0..=file.len().saturating_sub(self.lines))
+            .filter(|&start| start + self.lines <= file.len())
+            .filter(|&start| hash_lines(&file[start..start + self.lines]) == self.hash)
+            .min_by_key(|&start| {
+                let same_context = context_hash(&file, start, start + self.lines) == self.context;
+                (!same_context, distance(start, cited))
+            });
+        if let Some(start) = exact {
+            let lines = (start + 1, start + self.lines);
+            return AnchorResolution {
+                status: match cited == Some(lines) {
+                    true => AnchorStatus::Unchanged,
+                    false => AnchorStatus::Moved,
+                },
+                lines: Some(lines),
+                similarity: 1.0,
+            };
+        }
+
+        // the window of the same length matching the most fingerprints, within the symbol.
+        let (from, to) = symbol_lines
+            .map(|(start, end)| (start.max(1) - 1, end.min(file.len())))
+            .filter(|(from, to)| from < to)
+            .unwrap_or((0, file.len()));
+        let width = self.lines.min(to - from);
+        let best = (from..=to - width)
+            .map(|start| {
+                let matched = self
+                    .fingerprint
+                    .iter()
+                    .zip(&file[start..start + width])
+                    .filter(|(expected, line)| **expected == line_fingerprint(line))
+                    .count();
+                (start, matched)
+            })
+            .max_by_key(|&(start, matched)| {
+                (matched, std::cmp::Reverse(distance(start, cited)))
+            });
+        let Some((start, matched)) = best else {
+            return AnchorResolution::file_deleted();
+        };
+        let similarity = matched as f32 / self.fingerprint.len().max(1) as f32;
+        AnchorResolution {
+            status: match similarity >= CHANGED_SIMILARITY {
+                true => AnchorStatus::Changed,
+                false => AnchorStatus::Deleted,
+            },
+            lines: (matched > 0).then_some((start + 1, start + width)),
+            similarity,
+        }
+    }
+}
+
+impl fmt::Display for SnippetAnchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "a{}:{}:{:016x}:{:016x}:{}",
+            ANCHOR_VERSION,
+            self.lines,
+            self.hash,
+            self.context,
+            hex::encode(&self.fingerprint)
+        )?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, ":{}", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SnippetAnchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(6, ':');
+        let version = parts.next().unwrap_or_default();
+        if version != format!("a{}", ANCHOR_VERSION) {
+            return Err(format!(
+                "Unsupported anchor version `{}`, this build reads a{}",
+                version, ANCHOR_VERSION
+            ));
+        }
+        let invalid = || format!("Invalid anchor `{}`", s);
+        let lines = parts
+            .next()
+            .and_then(|lines| lines.parse().ok())
+            .ok_or_else(invalid)?;
+        let hash = parts
+            .next()
+            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+            .ok_or_else(invalid)?;
+        let context = parts
+            .next()
+            .and_then(|context| u64::from_str_radix(context, 16).ok())
+            .ok_or_else(invalid)?;
+        let fingerprint = parts
+            .next()
+            .and_then(|fingerprint| hex::decode(fingerprint).ok())
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            lines,
+            hash,
+            context,
+            fingerprint,
+            symbol: parts.next().map(str::to_string),
+        })
+    }
+}
+
+// Lines without their indentation and trailing whitespace, a re-indented snippet is the same one.
+fn normalized_lines(content: &str) -> Vec<&str> {
+    content.lines().map(str::trim).collect()
+}
+
+fn hash_lines(lines: &[&str]) -> u64 {
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("a sha256 digest has 32 bytes"))
+}
+
+// The lines around `start..end` of the file.
+fn context_hash(file: &[&str], start: usize, end: usize) -> u64 {
+    let before = &file[start.saturating_sub(CONTEXT_LINES)..start];
+    let after = &file[end..(end + CONTEXT_LINES).min(file.len())];
+    hash_lines(&[before, &[""][..], after].concat())
+}
+
+// Lines between the 0-based `start` and the first cited line.
+fn distance(start: usize, cited: Option<(usize, usize)>) -> usize {
+    cited.map_or(0, |(cited_start, _)| start.abs_diff(cited_start.saturating_sub(1)))
+}
+
+fn line_fingerprint(line: &str) -> u8 {
+    Sha256::digest(line.as_bytes())[0]
+}
+
+fn fingerprint(lines: &[&str]) -> Vec<u8> {
+    lines
+        .iter()
+        .take(MAX_FINGERPRINT_LINES)
+        .map(|line| line_fingerprint(line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file of `n` numbered filler lines.
+    fn filler(n: usize, name: &str) -> String {
+        (0..n)
+            .map(|i| format!("let {}_{} = {};\n", name, i, i))
+            .collect()
+    }
+
+    const RETRY: &str = "fn retry(charge: &Charge) -> Result<()> {\n    let mut attempts = 0;\n    while attempts < 3 {\n        if charge.submit().is_ok() {\n            return Ok(());\n        }\n        attempts += 1;\n    }\n    Err(anyhow!(\"charge failed\"))\n}\n";
+
+    #[test]
+    fn test_anchor_round_trips() {
+        let content = format!("{}{}{}", filler(10, "a"), RETRY, filler(5, "b"));
+        let anchor = SnippetAnchor::new(&content, (11, 20), Some("billing::retry")).unwrap();
+        let written = anchor.to_string();
+        assert!(written.starts_with("a1:10:"));
+        assert!(written.ends_with(":billing::retry"));
+        assert_eq!(written.parse::<SnippetAnchor>().unwrap(), anchor);
+
+        let without_symbol = SnippetAnchor::new(&content, (11, 20), None).unwrap();
+        assert_eq!(without_symbol.to_string().parse::<SnippetAnchor>().unwrap(), without_symbol);
+        assert!(SnippetAnchor::new(&content, (11, 40), None).is_none());
+        assert!("a2:10:0:0:00".parse::<SnippetAnchor>().is_err());
+        assert!("a1:ten:0:0:00".parse::<SnippetAnchor>().is_err());
+    }
+
+    #[test]
+    fn test_shifted_snippet_is_relocated() {
+        let before = format!("{}{}{}", filler(10, "a"), RETRY, filler(5, "b"));
+        let anchor = SnippetAnchor::new(&before, (11, 20), Some("retry")).unwrap();
+        assert_eq!(
+            anchor.relocate(&before, Some((11, 20)), None).status,
+            AnchorStatus::Unchanged
+        );
+
+        // 30 lines were added above the function, and it was re-indented.
+        let indented = RETRY
+            .lines()
+            .map(|line| format!("    {}\n", line))
+            .collect::<String>();
+        let after = format!("{}{}{}{}", filler(10, "a"), filler(30, "c"), indented, filler(5, "b"));
+        let resolution = anchor.relocate(&after, Some((11, 20)), None);
+        assert_eq!(resolution.status, AnchorStatus::Moved);
+        assert_eq!(resolution.lines, Some((41, 50)));
+        assert_eq!(resolution.similarity, 1.0);
+
+        // a line of the function changed too, it is found within the function.
+        let edited = after.replace("while attempts < 3", "while attempts < MAX_ATTEMPTS");
+        let resolution = anchor.relocate(&edited, Some((11, 20)), Some((41, 50)));
+        assert_eq!(resolution.status, AnchorStatus::Changed);
+        assert_eq!(resolution.lines, Some((41, 50)));
+        assert_eq!(resolution.similarity, 0.9);
+    }
+
+    #[test]
+    fn test_deleted_snippet_is_reported() {
+        let before = format!("{}{}{}", filler(10, "a"), RETRY, filler(5, "b"));
+        let anchor = SnippetAnchor::new(&before, (11, 20), Some("retry")).unwrap();
+
+        let after = format!("{}{}", filler(10, "a"), filler(5, "b"));
+        let resolution = anchor.relocate(&after, Some((11, 20)), None);
+        assert_eq!(resolution.status, AnchorStatus::Deleted);
+        assert!(resolution.similarity < CHANGED_SIMILARITY);
+        assert!(resolution.drifted());
+
+        assert_eq!(
+            anchor.relocate("", Some((11, 20)), None),
+            AnchorResolution::file_deleted()
+        );
+    }
+}
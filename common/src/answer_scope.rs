@@ -0,0 +1,410 @@
+// Guardrails on the files an answer tells to change. The model blends the code it retrieved and
+// sometimes recommends editing files of another repo or outside the directories the conversation
+// is scoped to. The paths of the prescriptive sentences, `modify [x](src/x.rs)` or `add it to
+// `src/y.rs``, and of the quoted code blocks are checked against the scope of the conversation.
+// The sentences are softened to a note on the similar code, the quoted code blocks and the
+// sentences that also recommend changes in scope are only flagged. The links and quoted code
+// blocks are parsed like the links of the answers are.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::grounding::extract_mentions;
+use crate::links::{fence_marker, link_targets, quoted_source, relative_link};
+
+// verbs telling to change a file, matched as whole words of the prose.
+const PRESCRIPTIVE_VERBS: [&str; 17] = [
+    "add",
+    "change",
+    "create",
+    "delete",
+    "edit",
+    "extend",
+    "fix",
+    "implement",
+    "insert",
+    "modify",
+    "move",
+    "patch",
+    "refactor",
+    "remove",
+    "rename",
+    "replace",
+    "update",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopeViolationReason {
+    // the path is neither indexed in the repo of the conversation nor a new file of one of its directories.
+    OtherRepo,
+    // the path is in the repo but outside the paths the conversation is scoped to.
+    OutOfScope,
+}
+
+impl ScopeViolationReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScopeViolationReason::OtherRepo => "other repo",
+            ScopeViolationReason::OutOfScope => "out of scope",
+        }
+    }
+}
+
+/// A path the answer recommends changing although it is outside the scope of the conversation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScopeViolation {
+    pub path: String,
+    pub reason: ScopeViolationReason,
+    // the sentence or the opening line of the quoted code block, as the answer had it.
+    pub statement: String,
+    // true when the statement was replaced by a note, false when it was only flagged.
+    pub rewritten: bool,
+}
+
+/// The paths the answers of a conversation may recommend changing: the ones of its repo, under
+/// the paths it is scoped to if any.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnswerScope {
+    repo_name: String,
+    // directories or files, the whole repo when empty.
+    prefixes: Vec<String>,
+    // None when code search can't list the indexed paths, the paths aren't checked against the repo.
+    indexed: Option<HashSet<String>>,
+    directories: HashSet<String>,
+}
+
+impl AnswerScope {
+    pub fn new(repo_name: &str, prefixes: &[String], indexed_paths: Option<Vec<String>>) -> Self {
+        let prefixes = prefixes
+            .iter()
+            .map(|prefix| normalize(prefix).trim_end_matches('/').to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .collect();
+        let mut directories = HashSet::new();
+        for path in indexed_paths.iter().flatten() {
+            let mut path = path.as_str();
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                if !directories.insert(parent.to_string()) {
+                    break;
+                }
+                path = parent;
+            }
+        }
+        Self {
+            repo_name: repo_name.to_string(),
+            prefixes,
+            indexed: indexed_paths.map(|paths| paths.into_iter().collect()),
+            directories,
+        }
+    }
+
+    /// True when there is nothing to check the paths against.
+    pub fn is_unrestricted(&self) -> bool {
+        self.prefixes.is_empty() && self.indexed.is_none()
+    }
+
+    /// Why changing `path` is out of scope, None when it is in scope. New files are in the repo
+    /// when their directory is, a path can be prefixed with the name of the repo.
+    pub fn check(&self, path: &str) -> Option<ScopeViolationReason> {
+        let path = self.repo_path(path);
+        if let Some(indexed) = &self.indexed {
+            let in_repo = indexed.contains(path)
+                || match path.rsplit_once('/') {
+                    Some((parent, _)) => self.directories.contains(parent),
+                    None => true,
+                };
+            if !in_repo {
+                return Some(ScopeViolationReason::OtherRepo);
+            }
+        }
+        let in_scope = self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| {
+            path == prefix
+                || matches!(path.strip_prefix(prefix.as_str()), Some(rest) if rest.starts_with('/'))
+        });
+        (!in_scope).then_some(ScopeViolationReason::OutOfScope)
+    }
+
+    /// The answer with the prescriptive sentences on paths out of scope softened, and every path
+    /// out of scope it recommends changing. The content of the code blocks is left untouched.
+    pub fn guard(&self, answer: &str) -> (String, Vec<ScopeViolation>) {
+        let mut guarded = String::with_capacity(answer.len());
+        let mut violations = Vec::new();
+        let mut fence: Option<&str> = None;
+
+        for line in answer.split_inclusive('\n') {
+            let trimmed = line.trim_start();
+            match fence {
+                Some(marker) => {
+                    if trimmed.trim_end() == marker {
+                        fence = None;
+                    }
+                    guarded.push_str(line);
+                }
+                None => match fence_marker(trimmed) {
+                    Some(marker) => {
+                        fence = Some(marker);
+                        // dropping the code would lose more than the recommendation, it is only flagged.
+                        if let Some((path, _)) = quoted_source(&trimmed[marker.len()..]) {
+                            if let Some(reason) = self.check(path) {
+                                violations.push(ScopeViolation {
+                                    path: path.to_string(),
+                                    reason,
+                                    statement: trimmed.trim_end().to_string(),
+                                    rewritten: false,
+                                });
+                            }
+                        }
+                        guarded.push_str(line);
+                    }
+                    None => {
+                        let (markup, prose) = line.split_at(markup_len(line));
+                        guarded.push_str(markup);
+                        for sentence in sentences(prose) {
+                            guarded.push_str(&self.guard_sentence(sentence, &mut violations));
+                        }
+                    }
+                },
+            }
+        }
+        (guarded, violations)
+    }
+
+    fn guard_sentence(&self, sentence: &str, violations: &mut Vec<ScopeViolation>) -> String {
+        if !is_prescriptive(sentence) {
+            return sentence.to_string();
+        }
+        let paths = sentence_paths(sentence);
+        let out_of_scope = paths
+            .iter()
+            .filter_map(|path| self.check(path).map(|reason| (path, reason)))
+            .collect::<Vec<_>>();
+        if out_of_scope.is_empty() {
+            return sentence.to_string();
+        }
+
+        // the note would drop the changes the sentence recommends in scope, it is only flagged.
+        let rewritten = out_of_scope.len() == paths.len();
+        for (path, reason) in &out_of_scope {
+            violations.push(ScopeViolation {
+                path: path.to_string(),
+                reason: *reason,
+                statement: sentence.trim().to_string(),
+                rewritten,
+            });
+        }
+        if !rewritten {
+            return sentence.to_string();
+        }
+        let note = format!(
+            "A similar pattern exists in {} (out of scope).",
+            out_of_scope
+                .iter()
+                .map(|(path, _)| format!("`{}`", path))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let trailing = &sentence[sentence.trim_end().len()..];
+        format!("{}{}", note, trailing)
+    }
+
+    // the path in the repo, answers sometimes prefix it with the name of the repo.
+    fn repo_path<'a>(&self, path: &'a str) -> &'a str {
+        let path = normalize(path);
+        if matches!(&self.indexed, Some(indexed) if indexed.contains(path)) {
+            return path;
+        }
+        match path.strip_prefix(self.repo_name.as_str()) {
+            Some(rest) if rest.starts_with('/') => &rest[1..],
+            _ => path,
+        }
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    path.trim().trim_start_matches("./").trim_start_matches('/')
+}
+
+// Leading whitespace, quote and list markers of a line, `- ` or `1. `, kept when a sentence is rewritten.
+fn markup_len(line: &str) -> usize {
+    let trimmed = line.trim_start_matches([' ', '\t', '>']);
+    let indent = line.len() - trimmed.len();
+    let marker = match trimmed.find(|c: char| !c.is_ascii_digit()) {
+        Some(0) if matches!(trimmed.chars().next(), Some('-' | '*' | '+')) => 1,
+        Some(digits)
+            if digits > 0 && matches!(trimmed[digits..].chars().next(), Some('.' | ')')) =>
+        {
+            digits + 1
+        }
+        _ => return indent,
+    };
+    // `**bold**` or `-1` aren't list items.
+    let rest = &trimmed[marker..];
+    if !rest.starts_with(' ') {
+        return indent;
+    }
+    indent + marker + (rest.len() - rest.trim_start().len())
+}
+
+// The sentences of a line along with the whitespace following them, a sentence ends on `.`, `!`
+// or `?` followed by whitespace so that the dots of paths don't split it.
+fn sentences(text: &str) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let ends = matches!(bytes[i], b'.' | b'!' | b'?')
+            && matches!(bytes.get(i + 1), None | Some(b' ' | b'\t' | b'\r' | b'\n'));
+        i += 1;
+        if ends {
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            sentences.push(&text[start..i]);
+            start = i;
+        }
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+// The sentence without its link targets and code spans, their words aren't the verbs of the sentence.
+fn prose(sentence: &str) -> String {
+    let mut prose = String::with_capacity(sentence.len());
+    let mut end = 0;
+    for target in link_targets(sentence) {
+        prose.push_str(&sentence[end..target.start]);
+        end = target.end;
+    }
+    prose.push_str(&sentence[end..]);
+    prose.split('`').step_by(2).collect::<Vec<_>>().join(" ")
+}
+
+fn is_prescriptive(sentence: &str) -> bool {
+    prose(sentence)
+        .split(|c: char| !c.is_alphabetic())
+        .any(|word| PRESCRIPTIVE_VERBS.contains(&word.to_lowercase().as_str()))
+}
+
+// The paths a sentence links to or mentions, in the order they appear.
+fn sentence_paths(sentence: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    let mut text = String::with_capacity(sentence.len());
+    let mut end = 0;
+    for target in link_targets(sentence) {
+        text.push_str(&sentence[end..target.start]);
+        end = target.end;
+        if let Some((path, _)) = relative_link(&sentence[target]) {
+            paths.push(path);
+        }
+    }
+    text.push_str(&sentence[end..]);
+
+    for mention in extract_mentions(&text) {
+        // `src/foo.rs#L10` or `src/foo.rs:10` mention the file.
+        let path = mention.split(['#', ':']).next().unwrap_or_default();
+        let is_file = matches!(path.rsplit_once('/'), Some((_, name)) if name.contains('.'));
+        if is_file && !paths.iter().any(|p| p == path) {
+            paths.push(path.to_string());
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope() -> AnswerScope {
+        AnswerScope::new(
+            "acme/payments",
+            &["src/refunds/".to_string()],
+            Some(vec![
+                "src/refunds/handler.rs".to_string(),
+                "src/refunds/mod.rs".to_string(),
+                "src/invoices/pdf.rs".to_string(),
+                "Cargo.toml".to_string(),
+            ]),
+        )
+    }
+
+    #[test]
+    fn test_paths_are_checked_against_the_repo_and_the_scope() {
+        let scope = scope();
+        assert_eq!(scope.check("src/refunds/handler.rs"), None);
+        assert_eq!(scope.check("./acme/payments/src/refunds/mod.rs"), None);
+        // a new file of a directory in scope.
+        assert_eq!(scope.check("src/refunds/policy.rs"), None);
+        assert_eq!(
+            scope.check("src/invoices/pdf.rs"),
+            Some(ScopeViolationReason::OutOfScope)
+        );
+        assert_eq!(
+            scope.check("billing/src/ledger.rs"),
+            Some(ScopeViolationReason::OtherRepo)
+        );
+        assert!(AnswerScope::default().is_unrestricted());
+        assert_eq!(AnswerScope::default().check("billing/src/ledger.rs"), None);
+    }
+
+    #[test]
+    fn test_recommendations_out_of_scope_are_softened_or_flagged() {
+        let answer = "Refunds are validated in [the handler](src/refunds/handler.rs#L10-L20).\n\
+                      - Modify [the renderer](src/invoices/pdf.rs#L5) to print the refund. \
+                      Then add the policy to `src/refunds/policy.rs`.\n\
+                      You should also update `billing/src/ledger.rs` and `src/refunds/mod.rs`.\n\
+                      ```type:Quoted,lang:Rust,path:src/invoices/pdf.rs,lines:4-9\n\
+                      // modify [this](src/invoices/pdf.rs)\n\
+                      ```\n\
+                      The PDF is rendered in `src/invoices/pdf.rs`.\n";
+
+        let (guarded, violations) = scope().guard(answer);
+
+        assert_eq!(
+            guarded,
+            "Refunds are validated in [the handler](src/refunds/handler.rs#L10-L20).\n\
+             - A similar pattern exists in `src/invoices/pdf.rs` (out of scope). \
+             Then add the policy to `src/refunds/policy.rs`.\n\
+             You should also update `billing/src/ledger.rs` and `src/refunds/mod.rs`.\n\
+             ```type:Quoted,lang:Rust,path:src/invoices/pdf.rs,lines:4-9\n\
+             // modify [this](src/invoices/pdf.rs)\n\
+             ```\n\
+             The PDF is rendered in `src/invoices/pdf.rs`.\n"
+        );
+        assert_eq!(
+            violations,
+            vec![
+                ScopeViolation {
+                    path: "src/invoices/pdf.rs".to_string(),
+                    reason: ScopeViolationReason::OutOfScope,
+                    statement: "Modify [the renderer](src/invoices/pdf.rs#L5) to print the refund."
+                        .to_string(),
+                    rewritten: true,
+                },
+                ScopeViolation {
+                    path: "billing/src/ledger.rs".to_string(),
+                    reason: ScopeViolationReason::OtherRepo,
+                    statement:
+                        "You should also update `billing/src/ledger.rs` and `src/refunds/mod.rs`."
+                            .to_string(),
+                    rewritten: false,
+                },
+                ScopeViolation {
+                    path: "src/invoices/pdf.rs".to_string(),
+                    reason: ScopeViolationReason::OutOfScope,
+                    statement: "```type:Quoted,lang:Rust,path:src/invoices/pdf.rs,lines:4-9"
+                        .to_string(),
+                    rewritten: false,
+                },
+            ]
+        );
+
+        let (unchanged, violations) = AnswerScope::default().guard(answer);
+        assert_eq!(unchanged, answer);
+        assert!(violations.is_empty());
+    }
+}
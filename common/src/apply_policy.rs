@@ -0,0 +1,705 @@
+// Guardrails of the modifier before it writes to a working tree. The deployment defines, per repo,
+// the globs of the paths an apply may write, nothing is writable by default. Lockfiles, CI config
+// and secrets are never writable, and an apply changes at most `max_lines_changed` lines. Every
+// request is a dry run unless it says otherwise: it is checked against the policy and returns what
+// would change with the hash of its change set. A real apply is only accepted for a change set whose
+// dry run passed less than `dry_run_window_secs` ago, once. A file whose change introduces a syntax
+// error in code is held back with the status `rejected_syntax`, see `ast::syntax_check`, the other
+// files of the change set are still written. Dry runs and applies are logged with the hash of the
+// change set and the identity requesting them.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ast::syntax_check::{check_modification, SyntaxCheck};
+
+/// YAML file of the policy, nothing is writable when unset.
+pub const APPLY_POLICY_FILE_ENV: &str = "APPLY_POLICY_FILE";
+
+pub const DEFAULT_MAX_LINES_CHANGED: usize = 500;
+// fifteen minutes.
+pub const DEFAULT_DRY_RUN_WINDOW_SECS: u64 = 15 * 60;
+
+/// Paths no apply may modify whatever the writable globs say: lockfiles, CI config and secrets.
+pub const DEFAULT_PROTECTED_GLOBS: &[&str] = &[
+    "**/Cargo.lock",
+    "**/package-lock.json",
+    "**/yarn.lock",
+    "**/pnpm-lock.yaml",
+    "**/poetry.lock",
+    "**/Gemfile.lock",
+    "**/go.sum",
+    ".github/workflows/**",
+    ".gitlab-ci.yml",
+    ".circleci/**",
+    "**/Jenkinsfile",
+    "**/.env",
+    "**/.env.*",
+    "**/*.pem",
+    "**/*.key",
+    "**/secrets/**",
+];
+
+/// The policy as it is written in the deployment config.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApplyPolicyConfig {
+    // globs of the writable paths, keyed by repo name.
+    #[serde(default)]
+    pub writable: HashMap<String, Vec<String>>,
+    // lines added and removed over all the files of an apply.
+    #[serde(default = "default_max_lines_changed")]
+    pub max_lines_changed: usize,
+    // globs of the paths never modified, they replace the defaults when set.
+    #[serde(default = "default_protected")]
+    pub protected: Vec<String>,
+    // how long a dry run stays valid for the apply of its change set.
+    #[serde(default = "default_dry_run_window_secs")]
+    pub dry_run_window_secs: u64,
+}
+
+fn default_max_lines_changed() -> usize {
+    DEFAULT_MAX_LINES_CHANGED
+}
+
+fn default_protected() -> Vec<String> {
+    DEFAULT_PROTECTED_GLOBS
+        .iter()
+        .map(|glob| glob.to_string())
+        .collect()
+}
+
+fn default_dry_run_window_secs() -> u64 {
+    DEFAULT_DRY_RUN_WINDOW_SECS
+}
+
+impl Default for ApplyPolicyConfig {
+    fn default() -> Self {
+        Self {
+            writable: HashMap::new(),
+            max_lines_changed: DEFAULT_MAX_LINES_CHANGED,
+            protected: default_protected(),
+            dry_run_window_secs: DEFAULT_DRY_RUN_WINDOW_SECS,
+        }
+    }
+}
+
+/// A file as an apply would write it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileChange {
+    pub path: String,
+    // content before the change, None for a new file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    pub modified: String,
+}
+
+impl FileChange {
+    /// Lines removed plus lines added, once the lines both versions start and end with are set
+    /// aside.
+    pub fn lines_changed(&self) -> usize {
+        let original = self
+            .original
+            .as_deref()
+            .unwrap_or_default()
+            .lines()
+            .collect::<Vec<_>>();
+        let modified = self.modified.lines().collect::<Vec<_>>();
+        let prefix = original
+            .iter()
+            .zip(&modified)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = original[prefix..]
+            .iter()
+            .rev()
+            .zip(modified[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        (original.len() - prefix - suffix) + (modified.len() - prefix - suffix)
+    }
+}
+
+/// The changes an apply request writes to a repo.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangeSet {
+    pub repo: String,
+    pub changes: Vec<FileChange>,
+}
+
+impl ChangeSet {
+    /// Hex SHA-256 of the repo and of every file path and content, in path order, so the same
+    /// changes sent in another order have the same hash.
+    pub fn hash(&self) -> String {
+        let mut changes = self.changes.iter().collect::<Vec<_>>();
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut hasher = Sha256::new();
+        hasher.update(self.repo.as_bytes());
+        for change in changes {
+            // lengths keep the boundaries of the fields unambiguous.
+            for field in [
+                Some(change.path.as_str()),
+                change.original.as_deref(),
+                Some(change.modified.as_str()),
+            ] {
+                match field {
+                    Some(field) => {
+                        hasher.update((field.len() as u64 + 1).to_le_bytes());
+                        hasher.update(field.as_bytes());
+                    }
+                    None => hasher.update(0u64.to_le_bytes()),
+                }
+            }
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn lines_changed(&self) -> usize {
+        self.changes.iter().map(FileChange::lines_changed).sum()
+    }
+}
+
+/// A request to the apply endpoint of the modifier.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApplyRequest {
+    #[serde(flatten)]
+    pub change_set: ChangeSet,
+    // only checks the changes and returns what would change, set to false to write them.
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyRule {
+    // the path matches none of the writable globs of the repo.
+    NotWritable,
+    // the path matches a protected glob.
+    Protected,
+    // the apply changes more lines than allowed.
+    MaxLinesChanged,
+    // no dry run of the change set passed within the window.
+    DryRunRequired,
+}
+
+/// A rule an apply breaks, with the file breaking it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RuleViolation {
+    pub rule: PolicyRule,
+    // None for the rules about the whole change set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub detail: String,
+}
+
+/// Every rule of the policy an apply request breaks, nothing was written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PolicyViolation {
+    pub change_set_hash: String,
+    pub violations: Vec<RuleViolation>,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let violations = self
+            .violations
+            .iter()
+            .map(|violation| match &violation.path {
+                Some(path) => format!("{}: {}", path, violation.detail),
+                None => violation.detail.clone(),
+            })
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "The apply policy rejects change set {}: {}",
+            self.change_set_hash,
+            violations.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+/// A file of an accepted request and the lines it changes. A file whose change doesn't parse has
+/// the status `rejected_syntax` with the location of the error and isn't written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlannedChange {
+    pub path: String,
+    pub lines_changed: usize,
+    pub new_file: bool,
+    #[serde(flatten)]
+    pub syntax: SyntaxCheck,
+}
+
+/// What an accepted request changes. A dry run returns it, an apply then writes it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApplyPlan {
+    pub change_set_hash: String,
+    pub dry_run: bool,
+    pub changes: Vec<PlannedChange>,
+    pub lines_changed: usize,
+}
+
+/// The compiled policy.
+#[derive(Clone, Debug)]
+pub struct ApplyPolicy {
+    writable: HashMap<String, GlobSet>,
+    protected: GlobSet,
+    max_lines_changed: usize,
+    dry_run_window_secs: u64,
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        let glob = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob {} in the apply policy", glob))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+impl ApplyPolicy {
+    pub fn new(config: &ApplyPolicyConfig) -> Result<Self> {
+        let writable = config
+            .writable
+            .iter()
+            .map(|(repo, globs)| Ok((repo.clone(), glob_set(globs)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Self {
+            writable,
+            protected: glob_set(&config.protected)?,
+            max_lines_changed: config.max_lines_changed,
+            dry_run_window_secs: config.dry_run_window_secs,
+        })
+    }
+
+    /// The policy of `APPLY_POLICY_FILE`, the default one writing nothing when it isn't set.
+    pub fn from_env() -> Result<Self> {
+        let config = match env::var(APPLY_POLICY_FILE_ENV) {
+            Ok(path) if !path.trim().is_empty() => {
+                let content = std::fs::read_to_string(path.trim())
+                    .with_context(|| format!("Failed to read {}", path))?;
+                serde_yaml::from_str(&content)
+                    .map_err(|e| anyhow!("{} is not a valid apply policy: {}", path, e))?
+            }
+            _ => ApplyPolicyConfig::default(),
+        };
+        Self::new(&config)
+    }
+
+    /// The rules of the policy the change set breaks, the dry run aside.
+    pub fn violations(&self, change_set: &ChangeSet) -> Vec<RuleViolation> {
+        let writable = self.writable.get(&change_set.repo);
+        let mut violations = Vec::new();
+        for change in &change_set.changes {
+            let path = change.path.trim_start_matches('/');
+            if self.protected.is_match(path) {
+                violations.push(RuleViolation {
+                    rule: PolicyRule::Protected,
+                    path: Some(change.path.clone()),
+                    detail: "lockfiles, CI config and secrets are never modified".to_string(),
+                });
+            }
+            // `..` could reach outside of the writable globs.
+            if !writable.map_or(false, |globs| globs.is_match(path))
+                || path.split('/').any(|part| part == "..")
+            {
+                violations.push(RuleViolation {
+                    rule: PolicyRule::NotWritable,
+                    path: Some(change.path.clone()),
+                    detail: format!("not writable in {}", change_set.repo),
+                });
+            }
+        }
+        let lines_changed = change_set.lines_changed();
+        if lines_changed > self.max_lines_changed {
+            violations.push(RuleViolation {
+                rule: PolicyRule::MaxLinesChanged,
+                path: None,
+                detail: format!(
+                    "{} lines changed, at most {} are allowed",
+                    lines_changed, self.max_lines_changed
+                ),
+            });
+        }
+        violations
+    }
+}
+
+/// The policy along with the dry runs that passed, by change set hash.
+pub struct ApplyGuard {
+    policy: ApplyPolicy,
+    // unix seconds of the last dry run of each change set.
+    dry_runs: Mutex<HashMap<String, u64>>,
+}
+
+impl ApplyGuard {
+    pub fn new(policy: ApplyPolicy) -> Self {
+        Self {
+            policy,
+            dry_runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the request before anything is written. A dry run that passes is remembered for the
+    /// apply of its change set, an apply consumes the dry run it is accepted on.
+    pub fn authorize(
+        &self,
+        request: &ApplyRequest,
+        identity: &str,
+        now: u64,
+    ) -> Result<ApplyPlan, PolicyViolation> {
+        let change_set_hash = request.change_set.hash();
+        let mut violations = self.policy.violations(&request.change_set);
+        let mut dry_runs = self.dry_runs.lock().unwrap();
+        dry_runs.retain(|_, at| *at + self.policy.dry_run_window_secs >= now);
+        if violations.is_empty() && !request.dry_run && dry_runs.remove(&change_set_hash).is_none()
+        {
+            violations.push(RuleViolation {
+                rule: PolicyRule::DryRunRequired,
+                path: None,
+                detail: format!(
+                    "no dry run of the change set in the last {} seconds",
+                    self.policy.dry_run_window_secs
+                ),
+            });
+        }
+
+        let stage = if request.dry_run { "Dry run" } else { "Apply" };
+        if !violations.is_empty() {
+            let violation = PolicyViolation {
+                change_set_hash,
+                violations,
+            };
+            log::warn!("{} by {} rejected: {}", stage, identity, violation);
+            return Err(violation);
+        }
+        if request.dry_run {
+            dry_runs.insert(change_set_hash.clone(), now);
+        }
+        log::info!(
+            "{} of change set {} on {} by {} accepted",
+            stage,
+            change_set_hash,
+            request.change_set.repo,
+            identity
+        );
+
+        let changes = request
+            .change_set
+            .changes
+            .iter()
+            .map(|change| {
+                let original = change.original.as_deref().unwrap_or_default();
+                let path = change.path.trim_start_matches('/');
+                let syntax = check_modification(path, original, &change.modified);
+                if let SyntaxCheck::RejectedSyntax(error) = &syntax {
+                    log::warn!(
+                        "Change of {} in change set {} held back, syntax error at line {}, column {}: {}",
+                        change.path,
+                        change_set_hash,
+                        error.line,
+                        error.column,
+                        error.message
+                    );
+                }
+                PlannedChange {
+                    path: change.path.clone(),
+                    lines_changed: change.lines_changed(),
+                    new_file: change.original.is_none(),
+                    syntax,
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(ApplyPlan {
+            change_set_hash,
+            dry_run: request.dry_run,
+            lines_changed: changes.iter().map(|change| change.lines_changed).sum(),
+            changes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn policy(writable: &[&str]) -> ApplyPolicy {
+        let config = ApplyPolicyConfig {
+            writable: HashMap::from([(
+                "repo".to_string(),
+                writable.iter().map(|glob| glob.to_string()).collect(),
+            )]),
+            max_lines_changed: 10,
+            ..ApplyPolicyConfig::default()
+        };
+        ApplyPolicy::new(&config).unwrap()
+    }
+
+    fn change(path: &str, original: &str, modified: &str) -> FileChange {
+        FileChange {
+            path: path.to_string(),
+            original: Some(original.to_string()),
+            modified: modified.to_string(),
+        }
+    }
+
+    fn request(changes: Vec<FileChange>, dry_run: bool) -> ApplyRequest {
+        ApplyRequest {
+            change_set: ChangeSet {
+                repo: "repo".to_string(),
+                changes,
+            },
+            dry_run,
+        }
+    }
+
+    fn rules(violation: &PolicyViolation) -> Vec<(PolicyRule, Option<&str>)> {
+        violation
+            .violations
+            .iter()
+            .map(|violation| (violation.rule.clone(), violation.path.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn test_lines_changed() {
+        assert_eq!(change("a.rs", "a\nb\nc", "a\nB\nc").lines_changed(), 2);
+        assert_eq!(change("a.rs", "a\nb", "a\nb\nc").lines_changed(), 1);
+        assert_eq!(change("a.rs", "a\nb", "a\nb").lines_changed(), 0);
+        let new_file = FileChange {
+            path: "new.rs".to_string(),
+            original: None,
+            modified: "a\nb\nc".to_string(),
+        };
+        assert_eq!(new_file.lines_changed(), 3);
+    }
+
+    #[test]
+    fn test_paths_outside_the_writable_globs_are_rejected() {
+        let guard = ApplyGuard::new(policy(&["src/**"]));
+        let request = request(
+            vec![
+                change("src/lib.rs", "a", "b"),
+                change("docs/guide.md", "a", "b"),
+                change("src/../build.rs", "a", "b"),
+            ],
+            true,
+        );
+
+        let violation = guard.authorize(&request, "dev@acme", NOW).unwrap_err();
+        assert_eq!(
+            rules(&violation),
+            vec![
+                (PolicyRule::NotWritable, Some("docs/guide.md")),
+                (PolicyRule::NotWritable, Some("src/../build.rs")),
+            ]
+        );
+        assert_eq!(violation.change_set_hash, request.change_set.hash());
+    }
+
+    #[test]
+    fn test_protected_paths_are_rejected_even_when_writable() {
+        let guard = ApplyGuard::new(policy(&["**"]));
+        let request = request(
+            vec![
+                change("Cargo.lock", "a", "b"),
+                change(".github/workflows/ci.yml", "a", "b"),
+                change("deploy/.env", "a", "b"),
+                change("deploy/secrets/token.txt", "a", "b"),
+                change("src/main.rs", "a", "b"),
+            ],
+            true,
+        );
+
+        let violation = guard.authorize(&request, "dev@acme", NOW).unwrap_err();
+        assert_eq!(
+            rules(&violation),
+            vec![
+                (PolicyRule::Protected, Some("Cargo.lock")),
+                (PolicyRule::Protected, Some(".github/workflows/ci.yml")),
+                (PolicyRule::Protected, Some("deploy/.env")),
+                (PolicyRule::Protected, Some("deploy/secrets/token.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_too_many_lines_changed_are_rejected() {
+        let guard = ApplyGuard::new(policy(&["src/**"]));
+        let big = (0..11)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let request = request(vec![change("src/lib.rs", "", &big)], true);
+
+        let violation = guard.authorize(&request, "dev@acme", NOW).unwrap_err();
+        assert_eq!(rules(&violation), vec![(PolicyRule::MaxLinesChanged, None)]);
+        assert_eq!(
+            violation.violations[0].detail,
+            "11 lines changed, at most 10 are allowed"
+        );
+    }
+
+    #[test]
+    fn test_empty_allow_list_rejects_everything() {
+        let guard = ApplyGuard::new(ApplyPolicy::new(&ApplyPolicyConfig::default()).unwrap());
+        let request = request(
+            vec![
+                change("README.md", "a", "b"),
+                change("src/lib.rs", "a", "b"),
+            ],
+            true,
+        );
+
+        let violation = guard.authorize(&request, "dev@acme", NOW).unwrap_err();
+        assert_eq!(
+            rules(&violation),
+            vec![
+                (PolicyRule::NotWritable, Some("README.md")),
+                (PolicyRule::NotWritable, Some("src/lib.rs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_needs_a_recent_dry_run_of_the_same_change_set() {
+        let guard = ApplyGuard::new(policy(&["src/**"]));
+        let changes = vec![
+            change("src/lib.rs", "a\nb", "a\nc"),
+            change("src/new.rs", "", "x"),
+        ];
+        let dry_run_required = |result: Result<ApplyPlan, PolicyViolation>| {
+            rules(&result.unwrap_err()) == vec![(PolicyRule::DryRunRequired, None)]
+        };
+
+        // no dry run yet.
+        assert!(dry_run_required(guard.authorize(
+            &request(changes.clone(), false),
+            "dev@acme",
+            NOW
+        )));
+
+        let plan = guard
+            .authorize(&request(changes.clone(), true), "dev@acme", NOW)
+            .unwrap();
+        assert!(plan.dry_run);
+        assert_eq!(plan.lines_changed, 3);
+
+        // another change set than the one of the dry run.
+        let other = vec![change("src/lib.rs", "a\nb", "a\nd")];
+        assert!(dry_run_required(guard.authorize(
+            &request(other, false),
+            "dev@acme",
+            NOW + 60
+        )));
+
+        // the same changes in another order are the same change set.
+        let reordered = changes.iter().rev().cloned().collect::<Vec<_>>();
+        let applied = guard
+            .authorize(&request(reordered, false), "dev@acme", NOW + 60)
+            .unwrap();
+        assert!(!applied.dry_run);
+        assert_eq!(applied.change_set_hash, plan.change_set_hash);
+
+        // a dry run is good for one apply.
+        assert!(dry_run_required(guard.authorize(
+            &request(changes.clone(), false),
+            "dev@acme",
+            NOW + 61
+        )));
+
+        // past the window the dry run has to be done again.
+        guard
+            .authorize(&request(changes.clone(), true), "dev@acme", NOW)
+            .unwrap();
+        assert!(dry_run_required(guard.authorize(
+            &request(changes, false),
+            "dev@acme",
+            NOW + DEFAULT_DRY_RUN_WINDOW_SECS + 1
+        )));
+    }
+
+    #[test]
+    fn test_changes_breaking_the_syntax_are_held_back() {
+        let guard = ApplyGuard::new(policy(&["**"]));
+        let unbalanced = FileChange {
+            path: "src/new.rs".to_string(),
+            original: None,
+            modified: "fn new() {".to_string(),
+        };
+        let request = request(
+            vec![
+                change("src/lib.rs", "fn a() {}\n", "fn a() {\n    let x = ;\n}\n"),
+                unbalanced,
+                change(
+                    "src/main.rs",
+                    "fn a() {}\n",
+                    "fn a() {\n    let x = 1;\n}\n",
+                ),
+                // broken before the change, and not a code file.
+                change("src/old.rs", "fn a( {}", "fn b( {}"),
+                change("README.md", "# Title", "# Title ("),
+            ],
+            true,
+        );
+
+        // the other files of the change set are still accepted.
+        let plan = guard.authorize(&request, "dev@acme", NOW).unwrap();
+        let statuses = plan
+            .changes
+            .iter()
+            .map(|change| (change.path.as_str(), change.syntax.is_rejected()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                ("src/lib.rs", true),
+                ("src/new.rs", true),
+                ("src/main.rs", false),
+                ("src/old.rs", false),
+                ("README.md", false),
+            ]
+        );
+        assert_eq!(plan.changes[4].syntax, SyntaxCheck::Skipped);
+
+        let entry = serde_json::to_value(&plan.changes[0]).unwrap();
+        assert_eq!(entry["status"], "rejected_syntax");
+        assert_eq!(entry["line"], 2);
+    }
+
+    #[test]
+    fn test_requests_are_dry_runs_by_default() {
+        let request: ApplyRequest = serde_json::from_str(
+            r#"{"repo": "repo", "changes": [{"path": "src/lib.rs", "original": "a", "modified": "b"}]}"#,
+        )
+        .unwrap();
+        assert!(request.dry_run);
+
+        let config: ApplyPolicyConfig =
+            serde_yaml::from_str("writable:\n  repo: [\"src/**\"]\nmax_lines_changed: 40\n")
+                .unwrap();
+        assert_eq!(config.max_lines_changed, 40);
+        assert_eq!(config.protected, default_protected());
+        assert_eq!(config.dry_run_window_secs, DEFAULT_DRY_RUN_WINDOW_SECS);
+    }
+}
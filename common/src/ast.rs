@@ -8,6 +8,7 @@ pub mod language_support;
 pub mod reference;
 pub mod scope;
 pub mod symbol;
+pub mod syntax_check;
 pub mod text_range;
 pub mod utils;
 
@@ -95,6 +95,15 @@ impl TSLanguage {
             })
             .map_or(Language::Unsupported, Language::Supported)
     }
+
+    /// Find a tree-sitter language configuration from a file extension, e.g. `rs` or `tsx`.
+    pub fn from_extension(extension: &str) -> Self {
+        ALL_LANGUAGES
+            .iter()
+            .copied()
+            .find(|target| target.file_extensions.contains(&extension))
+            .map_or(Language::Unsupported, Language::Supported)
+    }
 }
 
 pub static ALL_LANGUAGES: &[&TSLanguageConfig] = &[
@@ -0,0 +1,171 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Parser, Tree};
+
+use super::language_support::{Language, TSLanguage, TSLanguageConfig};
+
+/// Location of the first syntax error of a file, 1-based.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Outcome of validating a modification of a file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum SyntaxCheck {
+    /// The modified content parses, or was already broken before the modification.
+    Valid,
+    /// Not a code file in a supported language, e.g. markdown or yaml.
+    Skipped,
+    /// The modification introduced a syntax error.
+    RejectedSyntax(SyntaxError),
+}
+
+impl SyntaxCheck {
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, SyntaxCheck::RejectedSyntax(_))
+    }
+}
+
+/// Checks that modifying `original` into `modified` doesn't introduce syntax errors.
+/// The grammar is picked from the extension of `path`, files in other languages are skipped.
+pub fn check_modification(path: &str, original: &str, modified: &str) -> SyntaxCheck {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let language = match TSLanguage::from_extension(extension) {
+        Language::Supported(language) => language,
+        Language::Unsupported => return SyntaxCheck::Skipped,
+    };
+
+    // a file that didn't parse before can't be held against the modification.
+    if first_syntax_error(language, original).is_some() {
+        return SyntaxCheck::Valid;
+    }
+    if let Some(error) = first_syntax_error(language, modified) {
+        return SyntaxCheck::RejectedSyntax(error);
+    }
+
+    #[cfg(feature = "syn-check")]
+    if extension == "rs" {
+        if let Err(e) = syn::parse_file(modified) {
+            let start = e.span().start();
+            return SyntaxCheck::RejectedSyntax(SyntaxError {
+                line: start.line,
+                column: start.column + 1,
+                message: e.to_string(),
+            });
+        }
+    }
+
+    SyntaxCheck::Valid
+}
+
+/// Parses the source and returns the first error or missing node of the tree.
+pub fn first_syntax_error(language: &TSLanguageConfig, src: &str) -> Option<SyntaxError> {
+    let mut parser = Parser::new();
+    parser.set_language((language.grammar)()).ok()?;
+    // do not permit files that take >1s to parse, like `CodeFileAST`.
+    parser.set_timeout_micros(10u64.pow(6));
+    let tree = parser.parse(src, None)?;
+    find_error(&tree)
+}
+
+fn find_error(tree: &Tree) -> Option<SyntaxError> {
+    let root = tree.root_node();
+    if !root.has_error() {
+        return None;
+    }
+
+    // descend into the first child that contains an error until the error node itself.
+    let mut node = root;
+    loop {
+        if node.is_error() || node.is_missing() {
+            return Some(syntax_error(node));
+        }
+        let mut cursor = node.walk();
+        let child = node
+            .children(&mut cursor)
+            .find(|child| child.has_error() || child.is_missing());
+        match child {
+            Some(child) => node = child,
+            None => return Some(syntax_error(node)),
+        }
+    }
+}
+
+fn syntax_error(node: Node) -> SyntaxError {
+    let start = node.start_position();
+    let message = if node.is_missing() {
+        format!("missing {}", node.kind())
+    } else {
+        "unexpected syntax".to_string()
+    };
+    SyntaxError {
+        line: start.row + 1,
+        column: start.column + 1,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "fn main() {\n    println!(\"hello\");\n}\n";
+
+    #[test]
+    fn test_unbalanced_brace_is_rejected() {
+        let modified = "fn main() {\n    if true {\n        println!(\"hello\");\n}\n";
+        match check_modification("src/main.rs", ORIGINAL, modified) {
+            SyntaxCheck::RejectedSyntax(error) => {
+                assert!(error.line >= 2, "unexpected location {:?}", error);
+            }
+            check => panic!("expected a rejection, got {:?}", check),
+        }
+    }
+
+    #[test]
+    fn test_valid_and_skipped_modifications() {
+        let modified = "fn main() {\n    println!(\"hello, world\");\n}\n";
+        assert_eq!(
+            check_modification("src/main.rs", ORIGINAL, modified),
+            SyntaxCheck::Valid
+        );
+        assert_eq!(
+            check_modification("README.md", "# Title\n", "# Title\n{{{\n"),
+            SyntaxCheck::Skipped
+        );
+        assert_eq!(
+            check_modification("config.yaml", "a: 1\n", "a: [\n"),
+            SyntaxCheck::Skipped
+        );
+    }
+
+    #[test]
+    fn test_already_broken_file_is_not_rejected() {
+        let broken = "fn main() {\n";
+        assert_eq!(
+            check_modification("src/main.rs", broken, "fn main() {\n    let x = 1;\n"),
+            SyntaxCheck::Valid
+        );
+    }
+
+    #[test]
+    fn test_rejection_serializes_with_status() {
+        let check = SyntaxCheck::RejectedSyntax(SyntaxError {
+            line: 3,
+            column: 1,
+            message: "missing }".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_value(&check).unwrap(),
+            serde_json::json!({"status": "rejected_syntax", "line": 3, "column": 1, "message": "missing }"})
+        );
+    }
+}
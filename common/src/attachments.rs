@@ -0,0 +1,370 @@
+// Documents attached to a conversation, e.g. a design doc or the OpenAPI spec of a service the
+// repo talks to. The coordinator splits them into sections, code search embeds the sections in
+// their own collection with the conversation id in the payload, and the sections closest to a
+// question are added to its answer context labeled with where they come from.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The qdrant collection of the attachment sections of all the conversations.
+pub const ATTACHMENT_COLLECTION_NAME: &str = "attachments";
+/// Sections longer than this are split at a line boundary.
+pub const MAX_SECTION_CHARS: usize = 2000;
+/// Sections added to the answer context of a question.
+pub const MAX_ATTACHMENT_HITS: usize = 3;
+
+const OPENAPI_METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "patch", "options", "head", "trace",
+];
+
+/// How an attachment is split into sections.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentKind {
+    // paragraphs, grouped into parts.
+    Text,
+    // one section per heading.
+    Markdown,
+    // one section per operation and schema of an OpenAPI or Swagger JSON spec.
+    OpenApi,
+}
+
+impl AttachmentKind {
+    /// The kind of an attachment from its content type or the extension of its name. JSON is
+    /// only taken as a spec when it has an `openapi` or `swagger` version, as text otherwise.
+    pub fn detect(content_type: Option<&str>, name: &str, content: &str) -> Self {
+        let content_type = content_type.unwrap_or_default().to_ascii_lowercase();
+        let name = name.to_ascii_lowercase();
+        if content_type.contains("json") || name.ends_with(".json") {
+            let is_spec = serde_json::from_str::<Value>(content)
+                .map(|spec| spec.get("openapi").is_some() || spec.get("swagger").is_some())
+                .unwrap_or(false);
+            if is_spec {
+                return AttachmentKind::OpenApi;
+            }
+        }
+        if content_type.contains("markdown") || name.ends_with(".md") || name.ends_with(".markdown")
+        {
+            return AttachmentKind::Markdown;
+        }
+        AttachmentKind::Text
+    }
+}
+
+/// A section of an attachment, the unit that is embedded and retrieved.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttachmentSection {
+    pub attachment_id: String,
+    // the name the attachment was uploaded with, e.g. `orders-api.json`.
+    pub name: String,
+    // the heading path, operation or part of the section, e.g. `POST /orders/{id}/refunds`.
+    pub section: String,
+    pub text: String,
+}
+
+impl AttachmentSection {
+    /// Where the section comes from, the way answers cite it.
+    pub fn provenance(&self) -> String {
+        format!(
+            "from attached document {}, section {}",
+            self.name, self.section
+        )
+    }
+}
+
+/// Splits an attachment into its sections, empty sections are left out.
+pub fn chunk_attachment(
+    attachment_id: &str,
+    name: &str,
+    kind: AttachmentKind,
+    content: &str,
+) -> Result<Vec<AttachmentSection>> {
+    let sections = match kind {
+        AttachmentKind::Text => text_sections(content),
+        AttachmentKind::Markdown => markdown_sections(content),
+        AttachmentKind::OpenApi => openapi_sections(content)?,
+    };
+    Ok(sections
+        .into_iter()
+        .filter(|(_, text)| !text.trim().is_empty())
+        .flat_map(|(section, text)| split_long(section, &text))
+        .map(|(section, text)| AttachmentSection {
+            attachment_id: attachment_id.to_string(),
+            name: name.to_string(),
+            section,
+            text,
+        })
+        .collect())
+}
+
+/// The sections an answer cites by their provenance.
+pub fn cited_sections<'a>(
+    answer: &str,
+    sections: &'a [AttachmentSection],
+) -> Vec<&'a AttachmentSection> {
+    sections
+        .iter()
+        .filter(|section| answer.contains(&section.provenance()))
+        .collect()
+}
+
+// Paragraphs grouped into parts of at most `MAX_SECTION_CHARS`.
+fn text_sections(content: &str) -> Vec<(String, String)> {
+    let mut parts: Vec<String> = Vec::new();
+    for paragraph in content
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+    {
+        match parts.last_mut() {
+            Some(part) if part.len() + paragraph.len() + 2 <= MAX_SECTION_CHARS => {
+                part.push_str("\n\n");
+                part.push_str(paragraph);
+            }
+            _ => parts.push(paragraph.to_string()),
+        }
+    }
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| (format!("part {}", i + 1), part))
+        .collect()
+}
+
+// One section per heading, named by the headings above it, e.g. `Refunds > Limits`. The text
+// before the first heading is the `overview`. Headings in code blocks are text.
+fn markdown_sections(content: &str) -> Vec<(String, String)> {
+    let mut sections = vec![("overview".to_string(), String::new())];
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut in_code = false;
+    for line in content.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        let level = line.chars().take_while(|c| *c == '#').count();
+        let title = line[level..].trim();
+        if !in_code
+            && (1..=6).contains(&level)
+            && line[level..].starts_with(' ')
+            && !title.is_empty()
+        {
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, title.to_string()));
+            let path = headings
+                .iter()
+                .map(|(_, title)| title.as_str())
+                .collect::<Vec<_>>()
+                .join(" > ");
+            sections.push((path, String::new()));
+            continue;
+        }
+        let text = &mut sections.last_mut().unwrap().1;
+        text.push_str(line);
+        text.push('\n');
+    }
+    sections
+}
+
+// The info of the spec, one section per operation, e.g. `GET /orders/{id}`, and one per schema.
+fn openapi_sections(content: &str) -> Result<Vec<(String, String)>> {
+    let spec = serde_json::from_str::<Value>(content)
+        .map_err(|e| anyhow!("The attachment is not valid JSON: {}", e))?;
+    let pretty = |value: &Value| serde_json::to_string_pretty(value).unwrap_or_default();
+
+    let mut sections = Vec::new();
+    if let Some(info) = spec.get("info") {
+        sections.push(("info".to_string(), pretty(info)));
+    }
+    for (path, item) in spec
+        .get("paths")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+    {
+        for method in OPENAPI_METHODS {
+            if let Some(operation) = item.get(*method) {
+                let section = format!("{} {}", method.to_ascii_uppercase(), path);
+                sections.push((section, pretty(operation)));
+            }
+        }
+    }
+    // schemas are under `components` in OpenAPI 3 and `definitions` in Swagger 2.
+    let schemas = spec
+        .pointer("/components/schemas")
+        .or_else(|| spec.get("definitions"))
+        .and_then(Value::as_object);
+    for (name, schema) in schemas.into_iter().flatten() {
+        sections.push((format!("schema {}", name), pretty(schema)));
+    }
+    Ok(sections)
+}
+
+// Splits the text of a section longer than `MAX_SECTION_CHARS` at line boundaries, the parts
+// after the first are named `<section> (part 2)` and so on.
+fn split_long(section: String, text: &str) -> Vec<(String, String)> {
+    let text = text.trim();
+    if text.len() <= MAX_SECTION_CHARS {
+        return vec![(section, text.to_string())];
+    }
+    let mut parts: Vec<String> = vec![String::new()];
+    for line in text.lines() {
+        let part = parts.last_mut().unwrap();
+        if !part.is_empty() && part.len() + line.len() + 1 > MAX_SECTION_CHARS {
+            parts.push(String::new());
+        }
+        let part = parts.last_mut().unwrap();
+        if !part.is_empty() {
+            part.push('\n');
+        }
+        // a single line longer than a section is cut.
+        part.extend(line.chars().take(MAX_SECTION_CHARS));
+    }
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| match i {
+            0 => (section.clone(), part),
+            _ => (format!("{} (part {})", section, i + 1), part),
+        })
+        .collect()
+}
+
+/// Body of `POST /attachments` on code search, the sections of an attachment to embed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttachmentIndexRequest {
+    pub conversation_id: String,
+    pub sections: Vec<AttachmentSection>,
+    // unix seconds after which the sections are deleted.
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttachmentIndexResponse {
+    pub indexed: usize,
+}
+
+/// Body of `POST /attachments/search` on code search.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttachmentSearchRequest {
+    pub conversation_id: String,
+    pub query: String,
+    #[serde(default = "default_hits")]
+    pub limit: usize,
+}
+
+fn default_hits() -> usize {
+    MAX_ATTACHMENT_HITS
+}
+
+/// A section of an attachment of the conversation found for a query.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttachmentHit {
+    pub section: AttachmentSection,
+    pub score: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"{
+        "openapi": "3.0.0",
+        "info": {"title": "Orders API", "version": "2.1"},
+        "paths": {
+            "/orders/{id}/refunds": {
+                "post": {"summary": "Refund an order", "description": "Refunds are capped at the amount captured, partial refunds need an `amount`."},
+                "get": {"summary": "List the refunds of an order"}
+            }
+        },
+        "components": {"schemas": {"Refund": {"type": "object", "required": ["amount"]}}}
+    }"#;
+
+    #[test]
+    fn test_kind_is_detected_from_the_content_type_and_name() {
+        assert_eq!(
+            AttachmentKind::detect(Some("application/json"), "spec", SPEC),
+            AttachmentKind::OpenApi
+        );
+        assert_eq!(
+            AttachmentKind::detect(None, "orders.json", SPEC),
+            AttachmentKind::OpenApi
+        );
+        assert_eq!(
+            AttachmentKind::detect(None, "fixtures.json", r#"{"orders": []}"#),
+            AttachmentKind::Text
+        );
+        assert_eq!(
+            AttachmentKind::detect(None, "design.md", "# Refunds"),
+            AttachmentKind::Markdown
+        );
+        assert_eq!(
+            AttachmentKind::detect(Some("text/plain"), "notes", "refunds"),
+            AttachmentKind::Text
+        );
+    }
+
+    #[test]
+    fn test_openapi_spec_is_split_by_operation() {
+        let sections = chunk_attachment("a1", "orders-api.json", AttachmentKind::OpenApi, SPEC)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.section)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sections,
+            vec![
+                "info",
+                "GET /orders/{id}/refunds",
+                "POST /orders/{id}/refunds",
+                "schema Refund"
+            ]
+        );
+        assert!(chunk_attachment("a1", "bad.json", AttachmentKind::OpenApi, "{").is_err());
+    }
+
+    #[test]
+    fn test_markdown_is_split_by_heading() {
+        let doc = "Design of the refunds.\n\n# Refunds\nRefunds go through the ledger.\n\n## Limits\nAt most 3 refunds per order.\n\n```\n# not a heading\n```\n# Rollout\nBehind a flag.\n";
+        let sections = chunk_attachment("a2", "design.md", AttachmentKind::Markdown, doc).unwrap();
+        let names = sections
+            .iter()
+            .map(|s| s.section.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["overview", "Refunds", "Refunds > Limits", "Rollout"]
+        );
+        assert!(sections[2].text.contains("# not a heading"));
+        assert_eq!(
+            sections[2].provenance(),
+            "from attached document design.md, section Refunds > Limits"
+        );
+    }
+
+    #[test]
+    fn test_long_text_is_split_into_parts() {
+        let paragraph = "word ".repeat(300);
+        let text = [paragraph.trim(); 4].join("\n\n");
+        let sections = chunk_attachment("a3", "notes.txt", AttachmentKind::Text, &text).unwrap();
+        assert_eq!(sections.len(), 4);
+        assert!(sections.iter().all(|s| s.text.len() <= MAX_SECTION_CHARS));
+        assert_eq!(sections[1].section, "part 2");
+
+        let line = "x".repeat(MAX_SECTION_CHARS - 10);
+        let long = format!("{line}\n{line}\n{line}");
+        let parts = split_long("Limits".to_string(), &long);
+        let names = parts.iter().map(|(s, _)| s.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Limits", "Limits (part 2)", "Limits (part 3)"]);
+    }
+
+    #[test]
+    fn test_cited_sections() {
+        let sections =
+            chunk_attachment("a1", "orders-api.json", AttachmentKind::OpenApi, SPEC).unwrap();
+        let answer = "Refunds are capped at the captured amount (from attached document orders-api.json, section POST /orders/{id}/refunds).";
+        let cited = cited_sections(answer, &sections);
+        assert_eq!(cited.len(), 1);
+        assert_eq!(cited[0].section, "POST /orders/{id}/refunds");
+    }
+}
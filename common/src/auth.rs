@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::models::{
+    CodeSpanRequest, CodeUnderstandBatchRequest, CodeUnderstandRequest, ErrorEnvelope,
+    ExplainSymbolRequest, ResolveAnchorsRequest,
+};
+use crate::task_graph::redis::establish_redis_connection;
+use crate::transport::InvalidBody;
+use crate::TokenInfoRequest;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+// Tenant used for every request when no key store is configured, it can access every repo.
+pub const DEFAULT_TENANT_ID: &str = "default";
+// An allowed repo list containing this grants access to every repo.
+const ALL_REPOS: &str = "*";
+/// Scope of the keys allowed on the admin endpoints, e.g. triggering a re-index.
+pub const ADMIN_SCOPE: &str = "admin";
+
+/// The team a request is made on behalf of, resolved from its API key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tenant {
+    #[serde(rename = "tenant_id")]
+    pub id: String,
+    #[serde(default)]
+    pub allowed_repos: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+}
+
+impl Tenant {
+    pub fn can_access_repo(&self, repo_name: &str) -> bool {
+        self.allowed_repos
+            .iter()
+            .any(|repo| repo == ALL_REPOS || repo == repo_name)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.scopes.iter().any(|scope| scope == ADMIN_SCOPE)
+    }
+
+    fn unrestricted() -> Self {
+        Self {
+            id: DEFAULT_TENANT_ID.to_string(),
+            allowed_repos: vec![ALL_REPOS.to_string()],
+            scopes: vec![ADMIN_SCOPE.to_string()],
+        }
+    }
+}
+
+/// Where API keys are looked up, mapping each key to its tenant.
+#[derive(Debug, Clone)]
+pub enum KeyStore {
+    // no key store configured, every request is made as the unrestricted default tenant.
+    Disabled,
+    // keys loaded from a json file of `{"<key>": {"tenant_id": "..", "allowed_repos": [..], "scopes": [..]}}`.
+    Static(HashMap<String, Tenant>),
+    // redis hash mapping each key to the json of its tenant, so keys can be rotated without a restart.
+    Redis { url: String, hash_key: String },
+}
+
+impl KeyStore {
+    /// Reads the key store from `API_KEYS_FILE`, or `API_KEYS_REDIS_HASH` along with `REDIS_URL`.
+    /// Authentication is disabled when neither is set.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        if let Some(path) = var("API_KEYS_FILE") {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read API keys file {}: {}", path, e))?;
+            return Self::from_json(&content);
+        }
+
+        if let Some(hash_key) = var("API_KEYS_REDIS_HASH") {
+            let url = var("REDIS_URL")
+                .ok_or_else(|| anyhow!("`REDIS_URL` must be set to read API keys from redis"))?;
+            return Ok(KeyStore::Redis { url, hash_key });
+        }
+
+        Ok(KeyStore::Disabled)
+    }
+
+    pub fn from_json(content: &str) -> Result<Self> {
+        let keys = serde_json::from_str::<HashMap<String, Tenant>>(content)
+            .map_err(|e| anyhow!("Invalid API keys file: {}", e))?;
+        Ok(KeyStore::Static(keys))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, KeyStore::Disabled)
+    }
+
+    /// Resolves the tenant of an API key, None if the key is unknown.
+    pub fn lookup(&self, key: &str) -> Result<Option<Tenant>> {
+        match self {
+            KeyStore::Disabled => Ok(Some(Tenant::unrestricted())),
+            KeyStore::Static(keys) => Ok(keys.get(key).cloned()),
+            KeyStore::Redis { url, hash_key } => {
+                let mut conn = establish_redis_connection(url)?;
+                let tenant: Option<String> = conn.hget(hash_key, key)?;
+                tenant
+                    .map(|tenant| serde_json::from_str(&tenant))
+                    .transpose()
+                    .map_err(|e| anyhow!("Invalid tenant for API key in redis: {}", e))
+            }
+        }
+    }
+
+    /// Resolves the tenant of an API key like `lookup`, the redis store is read on the blocking
+    /// pool so a slow redis doesn't hold up the async workers.
+    pub async fn lookup_async(self: Arc<Self>, key: String) -> Result<Option<Tenant>> {
+        if !matches!(*self, KeyStore::Redis { .. }) {
+            return self.lookup(&key);
+        }
+        tokio::task::spawn_blocking(move || self.lookup(&key))
+            .await
+            .map_err(|e| anyhow!("API key lookup failed: {}", e))?
+    }
+}
+
+static KEY_STORE: OnceCell<Arc<KeyStore>> = OnceCell::new();
+
+/// Sets up the process wide key store from the environment, call it after the env file is loaded.
+pub fn init_key_store() -> Result<()> {
+    let store = KeyStore::from_env()?;
+    if !store.is_enabled() {
+        log::warn!("No API key store configured, authentication is disabled");
+    }
+    KEY_STORE
+        .set(Arc::new(store))
+        .map_err(|_| anyhow!("Key store already initialized"))
+}
+
+fn global_key_store() -> Arc<KeyStore> {
+    KEY_STORE
+        .get_or_init(|| Arc::new(KeyStore::Disabled))
+        .clone()
+}
+
+/// Key used by the services when calling each other, from `SERVICE_API_KEY`.
+/// It should map to a tenant allowed on every repo, since the caller already checked access.
+pub fn service_api_key() -> Option<String> {
+    std::env::var("SERVICE_API_KEY").ok().filter(|key| !key.is_empty())
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingKey,
+    InvalidKey,
+    RepoNotAllowed(String),
+    AdminRequired,
+    KeyStore(String),
+}
+
+impl warp::reject::Reject for AuthError {}
+
+impl AuthError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => StatusCode::UNAUTHORIZED,
+            AuthError::RepoNotAllowed(_) | AuthError::AdminRequired => StatusCode::FORBIDDEN,
+            AuthError::KeyStore(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            AuthError::MissingKey => "Missing API key".to_string(),
+            AuthError::InvalidKey => "Invalid API key".to_string(),
+            AuthError::RepoNotAllowed(repo) => format!("Access to repo {} is not allowed", repo),
+            AuthError::AdminRequired => format!("The API key needs the {} scope", ADMIN_SCOPE),
+            AuthError::KeyStore(e) => format!("Failed to validate API key: {}", e),
+        }
+    }
+
+    fn reply(&self) -> warp::reply::WithStatus<warp::reply::Json> {
+        warp::reply::with_status(
+            warp::reply::json(&ErrorEnvelope::new(self.status().as_u16(), self.message())),
+            self.status(),
+        )
+    }
+}
+
+/// Extracts the tenant of the request from its `Authorization: Bearer <key>` or `x-api-key` header.
+/// Rejects with `AuthError`, which `handle_rejection` turns into a 401.
+pub fn authenticate() -> impl Filter<Extract = (Tenant,), Error = Rejection> + Clone {
+    authenticate_with(global_key_store())
+}
+
+pub fn authenticate_with(
+    store: Arc<KeyStore>,
+) -> impl Filter<Extract = (Tenant,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .and_then(move |authorization: Option<String>, api_key: Option<String>| {
+            let store = store.clone();
+            async move {
+                let key = authorization
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(str::trim)
+                    .or(api_key.as_deref().map(str::trim));
+
+                let key = match key {
+                    Some(key) if !key.is_empty() => key.to_string(),
+                    _ if !store.is_enabled() => String::new(),
+                    _ => return Err(warp::reject::custom(AuthError::MissingKey)),
+                };
+
+                match store.lookup_async(key).await {
+                    Ok(Some(tenant)) => Ok(tenant),
+                    Ok(None) => Err(warp::reject::custom(AuthError::InvalidKey)),
+                    Err(e) => {
+                        log::error!("Failed to look up API key: {}", e);
+                        Err(warp::reject::custom(AuthError::KeyStore(e.to_string())))
+                    }
+                }
+            }
+        })
+}
+
+/// Requests that target a single repository.
+pub trait RepoScoped {
+    fn repo_name(&self) -> &str;
+}
+
+impl RepoScoped for CodeSpanRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo
+    }
+}
+
+impl RepoScoped for CodeUnderstandRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo
+    }
+}
+
+impl RepoScoped for CodeUnderstandBatchRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo
+    }
+}
+
+impl RepoScoped for ExplainSymbolRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo
+    }
+}
+
+impl RepoScoped for ResolveAnchorsRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo
+    }
+}
+
+impl RepoScoped for TokenInfoRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo_ref
+    }
+}
+
+/// Passes the request through if the tenant can access its repo, rejects with a 403 otherwise.
+/// Meant to follow the request and `authenticate` filters, e.g. `.and_then(auth::authorize_repo)`.
+pub async fn authorize_repo<T: RepoScoped>(request: T, tenant: Tenant) -> Result<T, Rejection> {
+    if tenant.can_access_repo(request.repo_name()) {
+        Ok(request)
+    } else {
+        log::warn!(
+            "Tenant {} denied access to repo {}",
+            tenant.id,
+            request.repo_name()
+        );
+        Err(warp::reject::custom(AuthError::RepoNotAllowed(
+            request.repo_name().to_string(),
+        )))
+    }
+}
+
+/// Passes the tenant through if its key has the admin scope, rejects with a 403 otherwise.
+/// Meant to follow `authenticate`, e.g. `.and_then(auth::authorize_admin)`.
+pub async fn authorize_admin(tenant: Tenant) -> Result<Tenant, Rejection> {
+    if tenant.is_admin() {
+        Ok(tenant)
+    } else {
+        log::warn!("Tenant {} denied access to an admin endpoint", tenant.id);
+        Err(warp::reject::custom(AuthError::AdminRequired))
+    }
+}
+
+/// Turns auth rejections and bodies that fail to decode into the error envelope, other rejections
+/// are passed on.
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if let Some(InvalidBody(message)) = rejection.find::<InvalidBody>() {
+        let status = StatusCode::BAD_REQUEST;
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorEnvelope::new(status.as_u16(), message.clone())),
+            status,
+        ));
+    }
+    match rejection.find::<AuthError>() {
+        Some(error) => Ok(error.reply()),
+        None => Err(rejection),
+    }
+}
+
+/// The 403 of a tenant denied the repo of a request, in the error envelope of the rejections.
+/// For the handlers checking the repo themselves, e.g. when it's a path parameter.
+pub fn repo_forbidden(
+    tenant: &Tenant,
+    repo_name: &str,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    log::warn!("Tenant {} denied access to repo {}", tenant.id, repo_name);
+    AuthError::RepoNotAllowed(repo_name.to_string()).reply()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &str = r#"{
+        "key-a": {"tenant_id": "team-a", "allowed_repos": ["repo-a"]},
+        "key-b": {"tenant_id": "team-b", "allowed_repos": ["repo-b"]},
+        "key-service": {"tenant_id": "services", "allowed_repos": ["*"]},
+        "key-admin": {"tenant_id": "ops", "allowed_repos": ["*"], "scopes": ["admin"]}
+    }"#;
+
+    fn span_route(
+        store: KeyStore,
+    ) -> impl Filter<Extract = impl Reply, Error = std::convert::Infallible> + Clone {
+        warp::path("span")
+            .and(warp::body::json::<CodeSpanRequest>())
+            .and(authenticate_with(Arc::new(store)))
+            .and_then(authorize_repo)
+            .map(|request: CodeSpanRequest| request.repo)
+            .recover(handle_rejection)
+            .recover(|_| async { Ok::<_, std::convert::Infallible>(StatusCode::NOT_FOUND) })
+    }
+
+    fn span_request(repo: &str) -> CodeSpanRequest {
+        CodeSpanRequest {
+            repo: repo.to_string(),
+            branch: None,
+            path: "src/main.rs".to_string(),
+            ranges: None,
+            id: None,
+        }
+    }
+
+    #[test]
+    fn test_key_lookup() {
+        let store = KeyStore::from_json(KEYS).unwrap();
+        let tenant = store.lookup("key-a").unwrap().unwrap();
+        assert_eq!(tenant.id, "team-a");
+        assert!(tenant.can_access_repo("repo-a"));
+        assert!(!tenant.can_access_repo("repo-b"));
+        assert!(store.lookup("key-c").unwrap().is_none());
+
+        let service = store.lookup("key-service").unwrap().unwrap();
+        assert!(service.can_access_repo("repo-b"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_and_invalid_keys_are_rejected() {
+        let routes = span_route(KeyStore::from_json(KEYS).unwrap());
+
+        let missing = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .json(&span_request("repo-a"))
+            .reply(&routes)
+            .await;
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let body: ErrorEnvelope = serde_json::from_slice(missing.body()).unwrap();
+        assert_eq!(body, ErrorEnvelope::new(401, "Missing API key".to_string()));
+
+        let invalid = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .header("authorization", "Bearer key-c")
+            .json(&span_request("repo-a"))
+            .reply(&routes)
+            .await;
+        assert_eq!(invalid.status(), StatusCode::UNAUTHORIZED);
+
+        let valid = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .header(API_KEY_HEADER, "key-a")
+            .json(&span_request("repo-a"))
+            .reply(&routes)
+            .await;
+        assert_eq!(valid.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_cross_tenant_repo_access_is_denied() {
+        let routes = span_route(KeyStore::from_json(KEYS).unwrap());
+
+        let denied = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .header("authorization", "Bearer key-a")
+            .json(&span_request("repo-b"))
+            .reply(&routes)
+            .await;
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        let body: ErrorEnvelope = serde_json::from_slice(denied.body()).unwrap();
+        assert_eq!(
+            body,
+            ErrorEnvelope::new(403, "Access to repo repo-b is not allowed".to_string())
+        );
+
+        let allowed = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .header("authorization", "Bearer key-b")
+            .json(&span_request("repo-b"))
+            .reply(&routes)
+            .await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_scope_is_required() {
+        let routes = warp::path("admin")
+            .and(authenticate_with(Arc::new(KeyStore::from_json(KEYS).unwrap())))
+            .and_then(authorize_admin)
+            .map(|tenant: Tenant| tenant.id)
+            .recover(handle_rejection);
+
+        // a key allowed on every repo isn't an admin key.
+        let denied = warp::test::request()
+            .path("/admin")
+            .header(API_KEY_HEADER, "key-service")
+            .reply(&routes)
+            .await;
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+
+        let allowed = warp::test::request()
+            .path("/admin")
+            .header(API_KEY_HEADER, "key-admin")
+            .reply(&routes)
+            .await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert!(!KeyStore::from_json(KEYS).unwrap().lookup("key-a").unwrap().unwrap().is_admin());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_store_allows_requests_without_key() {
+        let routes = span_route(KeyStore::Disabled);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .json(&span_request("repo-b"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_repo_forbidden_reply_is_an_error_envelope() {
+        let tenant = KeyStore::from_json(KEYS)
+            .unwrap()
+            .lookup("key-a")
+            .unwrap()
+            .unwrap();
+        let response = repo_forbidden(&tenant, "repo-b").into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: ErrorEnvelope = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            ErrorEnvelope::new(403, "Access to repo repo-b is not allowed".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_redis_store_fails_the_request() {
+        let routes = span_route(KeyStore::Redis {
+            url: "redis://127.0.0.1:1".to_string(),
+            hash_key: "api-keys".to_string(),
+        });
+        let response = warp::test::request()
+            .method("POST")
+            .path("/span")
+            .header(API_KEY_HEADER, "key-a")
+            .json(&span_request("repo-a"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: ErrorEnvelope = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.code, 500);
+    }
+
+    // `REDIS_URL=redis://127.0.0.1:6379 cargo test -p common -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn test_redis_key_store() {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let hash_key = format!("api-keys-test-{}", uuid::Uuid::new_v4());
+        let mut conn = establish_redis_connection(&url).unwrap();
+        let keys = serde_json::from_str::<HashMap<String, Tenant>>(KEYS).unwrap();
+        for (key, tenant) in &keys {
+            let _: () = conn
+                .hset(&hash_key, key, serde_json::to_string(tenant).unwrap())
+                .unwrap();
+        }
+        let routes = span_route(KeyStore::Redis {
+            url,
+            hash_key: hash_key.clone(),
+        });
+        let request = |key: &str, repo: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/span")
+                .header(API_KEY_HEADER, key)
+                .json(&span_request(repo))
+        };
+
+        let allowed = request("key-a", "repo-a").reply(&routes).await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        let denied = request("key-a", "repo-b").reply(&routes).await;
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        let body: ErrorEnvelope = serde_json::from_slice(denied.body()).unwrap();
+        assert_eq!(body.code, 403);
+        let invalid = request("key-c", "repo-a").reply(&routes).await;
+        assert_eq!(invalid.status(), StatusCode::UNAUTHORIZED);
+
+        // keys are rotated in redis without a restart.
+        let _: () = conn.hdel(&hash_key, "key-a").unwrap();
+        let rotated = request("key-a", "repo-a").reply(&routes).await;
+        assert_eq!(rotated.status(), StatusCode::UNAUTHORIZED);
+        let _: () = conn.del(&hash_key).unwrap();
+    }
+}
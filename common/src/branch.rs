@@ -0,0 +1,174 @@
+// Branches of a repo indexed side by side. The documents and points of a branch share the quickwit
+// index and the qdrant collections of the repo with the other branches: the quickwit documents
+// carry the branch in `repo_ref`, the points in the `branch` payload field, and the point ids are
+// derived from it, so indexing a branch never overwrites the index of another one.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::run_manifest::RunManifest;
+
+/// Branch of the requests that don't name one.
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// Payload field of the chunk and symbol points holding their branch.
+pub const BRANCH_FIELD: &str = "branch";
+
+/// Field of the quickwit documents holding their branch.
+pub const QUICKWIT_BRANCH_FIELD: &str = "repo_ref";
+
+const HEADS_PREFIX: &str = "refs/heads/";
+
+/// The name a branch is stored under, `refs/heads/release-1.2` and `release-1.2` are the same branch.
+pub fn branch_name(reference: &str) -> &str {
+    let reference = reference.trim();
+    reference.strip_prefix(HEADS_PREFIX).unwrap_or(reference)
+}
+
+/// The branch a request is answered from, the default branch when it doesn't name one.
+pub fn requested_branch(branch: Option<&str>) -> &str {
+    match branch.map(branch_name) {
+        Some(name) if !name.is_empty() => name,
+        _ => DEFAULT_BRANCH,
+    }
+}
+
+/// Whether `branch` is the default branch, a missing or empty branch is.
+pub fn is_default_branch(branch: &str) -> bool {
+    requested_branch(Some(branch)) == DEFAULT_BRANCH
+}
+
+/// Quickwit query matching only the documents of `branch` among the ones matching `query`.
+/// The documents indexed before branches were recorded have an empty `repo_ref`, they belong to
+/// the default branch.
+pub fn quickwit_branch_query(query: &str, branch: &str) -> String {
+    let branch = requested_branch(Some(branch));
+    let mut clause = format!(
+        "{}:\"{}\"",
+        QUICKWIT_BRANCH_FIELD,
+        branch.replace('"', "\\\"")
+    );
+    if is_default_branch(branch) {
+        clause = format!("({} OR {}:\"\")", clause, QUICKWIT_BRANCH_FIELD);
+    }
+    match query.trim() {
+        "" | "*" => clause,
+        query => format!("({}) AND {}", query, clause),
+    }
+}
+
+/// A branch of a repo with what its last indexing run indexed, from the run manifest of the branch.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct IndexedBranch {
+    pub branch: String,
+    pub indexed_commit: String,
+    pub run_id: String,
+    // unix timestamp in seconds.
+    pub finished_at: u64,
+}
+
+/// The indexed branches of a repo, sorted by name.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct RepoBranches {
+    pub repo_name: String,
+    pub default_branch: String,
+    pub branches: Vec<IndexedBranch>,
+}
+
+impl RepoBranches {
+    /// The branches of the run manifests of the repo, a branch indexed more than once is listed
+    /// with its latest run.
+    pub fn from_manifests(
+        repo_name: &str,
+        manifests: impl IntoIterator<Item = RunManifest>,
+    ) -> Self {
+        let mut branches: BTreeMap<String, IndexedBranch> = BTreeMap::new();
+        for manifest in manifests {
+            let branch = branch_name(&manifest.branch).to_string();
+            let newer = branches.get(&branch).map(|indexed| indexed.finished_at);
+            if matches!(newer, Some(finished_at) if finished_at >= manifest.finished_at) {
+                continue;
+            }
+            branches.insert(
+                branch.clone(),
+                IndexedBranch {
+                    branch,
+                    indexed_commit: manifest.indexed_commit,
+                    run_id: manifest.run_id,
+                    finished_at: manifest.finished_at,
+                },
+            );
+        }
+        Self {
+            repo_name: repo_name.to_string(),
+            default_branch: DEFAULT_BRANCH.to_string(),
+            branches: branches.into_values().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branches_are_named_without_their_ref_prefix() {
+        assert_eq!(branch_name("refs/heads/release-1.2"), "release-1.2");
+        assert_eq!(branch_name("release-1.2"), "release-1.2");
+        assert_eq!(requested_branch(Some("refs/heads/main")), "main");
+        assert_eq!(requested_branch(Some(" ")), DEFAULT_BRANCH);
+        assert_eq!(requested_branch(None), DEFAULT_BRANCH);
+
+        assert_eq!(
+            quickwit_branch_query("content:refund", "refs/heads/release-1.2"),
+            "(content:refund) AND repo_ref:\"release-1.2\""
+        );
+    }
+
+    #[test]
+    fn test_default_branch_matches_the_documents_without_a_branch() {
+        assert!(is_default_branch("refs/heads/main"));
+        assert!(is_default_branch(""));
+        assert!(!is_default_branch("release-1.2"));
+
+        assert_eq!(
+            quickwit_branch_query("*", "main"),
+            "(repo_ref:\"main\" OR repo_ref:\"\")"
+        );
+        assert_eq!(
+            quickwit_branch_query("content:refund", ""),
+            "(content:refund) AND (repo_ref:\"main\" OR repo_ref:\"\")"
+        );
+    }
+
+    #[test]
+    fn test_branches_are_listed_with_their_latest_run() {
+        let manifest = |branch: &str, run_id: &str, finished_at: u64| RunManifest {
+            run_id: run_id.to_string(),
+            repo_name: "payments".to_string(),
+            branch: branch.to_string(),
+            indexed_commit: format!("commit-{}", run_id),
+            finished_at,
+            ..Default::default()
+        };
+        let branches = RepoBranches::from_manifests(
+            "payments",
+            vec![
+                manifest("refs/heads/release-1.2", "r2", 200),
+                manifest("refs/heads/main", "m1", 100),
+                manifest("refs/heads/release-1.2", "r1", 150),
+            ],
+        );
+        assert_eq!(branches.default_branch, "main");
+        assert_eq!(
+            branches
+                .branches
+                .iter()
+                .map(|b| (b.branch.as_str(), b.run_id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("main", "m1"), ("release-1.2", "r2")]
+        );
+        assert_eq!(branches.branches[1].indexed_commit, "commit-r2");
+    }
+}
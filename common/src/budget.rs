@@ -0,0 +1,572 @@
+// Spend limits of the LLM calls. Every call is priced with the price table of its model before it
+// is sent: past `degrade_at` of a limit the rest of the run switches to the cheaper model and asks
+// fewer questions, a call that would go beyond the limit is rejected with `BudgetExceeded`.
+// Limits are per conversation, kept on its graph, and per tenant per day, kept in redis.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+
+use crate::task_graph::redis::establish_redis_connection;
+
+pub const MODEL_PRICES_ENV: &str = "MODEL_PRICES";
+pub const CONVERSATION_BUDGET_ENV: &str = "CONVERSATION_BUDGET_USD";
+pub const TENANT_DAILY_BUDGET_ENV: &str = "TENANT_DAILY_BUDGET_USD";
+pub const BUDGET_DEGRADE_AT_ENV: &str = "BUDGET_DEGRADE_AT";
+pub const BUDGET_DEGRADED_MODEL_ENV: &str = "BUDGET_DEGRADED_MODEL";
+
+pub const DEFAULT_DEGRADE_AT: f64 = 0.8;
+// completion tokens a call is priced with before it is sent, the responses rarely go beyond.
+pub const EXPECTED_COMPLETION_TOKENS: usize = 500;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// the counter of a day is kept a day longer than needed, in case of clock skew between services.
+const TENANT_SPEND_TTL_SECS: i64 = 2 * SECONDS_PER_DAY as i64;
+
+/// Price of a model in USD per 1k tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        (prompt_tokens as f64 * self.prompt_per_1k
+            + completion_tokens as f64 * self.completion_per_1k)
+            / 1000.0
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PriceTable(HashMap<String, ModelPrice>);
+
+impl PriceTable {
+    /// Parses `model=prompt:completion` entries separated by commas, e.g.
+    /// `gpt-4-0613=0.03:0.06,openai:gpt-3.5-turbo=0.0005:0.0015`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut prices = HashMap::new();
+        for entry in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let price = entry.split_once('=').and_then(|(model, price)| {
+                let (prompt, completion) = price.split_once(':')?;
+                let price = ModelPrice {
+                    prompt_per_1k: prompt.trim().parse().ok()?,
+                    completion_per_1k: completion.trim().parse().ok()?,
+                };
+                (price.prompt_per_1k >= 0.0 && price.completion_per_1k >= 0.0)
+                    .then(|| (model.trim().to_string(), price))
+            });
+            let (model, price) = price.ok_or_else(|| {
+                anyhow!(
+                    "{} entries must be `model=prompt:completion`, got `{}`",
+                    MODEL_PRICES_ENV,
+                    entry
+                )
+            })?;
+            prices.insert(model, price);
+        }
+        Ok(Self(prices))
+    }
+
+    /// The price of `model`, looked up by its id and then by its name without the client.
+    /// Models missing from the table cost nothing.
+    pub fn price(&self, model: &str) -> ModelPrice {
+        self.0
+            .get(model)
+            .or_else(|| {
+                let (_, name) = model.split_once(':')?;
+                self.0.get(name)
+            })
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn cost(&self, model: &str, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        self.price(model).cost(prompt_tokens, completion_tokens)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BudgetConfig {
+    pub prices: PriceTable,
+    pub conversation_usd: Option<f64>,
+    pub tenant_daily_usd: Option<f64>,
+    // share of a limit past which the run is degraded.
+    pub degrade_at: f64,
+    // model the calls switch to once degraded, they keep their model when None.
+    pub degraded_model: Option<String>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            prices: PriceTable::default(),
+            conversation_usd: None,
+            tenant_daily_usd: None,
+            degrade_at: DEFAULT_DEGRADE_AT,
+            degraded_model: None,
+        }
+    }
+}
+
+impl BudgetConfig {
+    /// Limits left unset are not enforced, the spend is still counted with the prices.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+        let limit = |name: &str| {
+            var(name)
+                .map(|value| {
+                    value
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|limit| *limit >= 0.0)
+                        .ok_or_else(|| anyhow!("{} must be a positive amount of USD", name))
+                })
+                .transpose()
+        };
+
+        let prices = match var(MODEL_PRICES_ENV) {
+            Some(spec) => PriceTable::parse(&spec)?,
+            None => PriceTable::default(),
+        };
+        let degrade_at = match var(BUDGET_DEGRADE_AT_ENV) {
+            Some(ratio) => ratio
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                .ok_or_else(|| anyhow!("{} must be between 0.0 and 1.0", BUDGET_DEGRADE_AT_ENV))?,
+            None => DEFAULT_DEGRADE_AT,
+        };
+
+        Ok(Self {
+            prices,
+            conversation_usd: limit(CONVERSATION_BUDGET_ENV)?,
+            tenant_daily_usd: limit(TENANT_DAILY_BUDGET_ENV)?,
+            degrade_at,
+            degraded_model: var(BUDGET_DEGRADED_MODEL_ENV),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetScope {
+    Conversation,
+    Tenant,
+}
+
+impl BudgetScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetScope::Conversation => "conversation",
+            BudgetScope::Tenant => "tenant",
+        }
+    }
+}
+
+impl fmt::Display for BudgetScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetScope::Conversation => f.write_str("conversation"),
+            BudgetScope::Tenant => f.write_str("daily tenant"),
+        }
+    }
+}
+
+/// A limit and what was spent against it before the run, sent along with the requests to
+/// code understanding so that the calls it makes are checked against the same limit.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BudgetAllowance {
+    pub scope: BudgetScope,
+    pub limit_usd: f64,
+    pub spent_usd: f64,
+}
+
+impl BudgetAllowance {
+    pub fn remaining_usd(&self) -> f64 {
+        (self.limit_usd - self.spent_usd).max(0.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+#[error("The {scope} budget of ${limit_usd:.2} is exceeded: ${spent_usd:.4} spent, ${projected_usd:.4} with the next call")]
+pub struct BudgetExceeded {
+    pub scope: BudgetScope,
+    pub limit_usd: f64,
+    pub spent_usd: f64,
+    pub projected_usd: f64,
+}
+
+/// Spend of a conversation, kept on its graph.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConversationBudget {
+    pub spent_usd: f64,
+    // the last call that was rejected, the conversation stops there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exceeded: Option<BudgetExceeded>,
+    // unix timestamp the conversation was suspended at, nothing is spent on it until it resumes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended_at: Option<u64>,
+}
+
+impl ConversationBudget {
+    pub fn render(&self) -> String {
+        let rendered = match &self.exceeded {
+            Some(exceeded) => format!("${:.4} spent, {}", self.spent_usd, exceeded),
+            None => format!("${:.4} spent", self.spent_usd),
+        };
+        match self.suspended_at {
+            Some(_) => format!("{}, suspended", rendered),
+            None => rendered,
+        }
+    }
+}
+
+/// Checks the calls of a run against its limits and counts what they cost. Clones share the count.
+#[derive(Clone, Debug)]
+pub struct BudgetMeter {
+    config: Arc<BudgetConfig>,
+    limits: Vec<BudgetAllowance>,
+    state: Arc<Mutex<MeterState>>,
+}
+
+#[derive(Debug, Default)]
+struct MeterState {
+    spent_usd: f64,
+    degraded: bool,
+    rejection: Option<BudgetExceeded>,
+}
+
+impl BudgetMeter {
+    /// Counts the spend without enforcing any limit.
+    pub fn unlimited(config: BudgetConfig) -> Self {
+        Self::with_limits(config, vec![])
+    }
+
+    /// The limits of the config, against what the conversation and the tenant spent today.
+    pub fn new(config: BudgetConfig, conversation_spent_usd: f64, tenant_spent_usd: f64) -> Self {
+        let limits = [
+            (
+                BudgetScope::Conversation,
+                config.conversation_usd,
+                conversation_spent_usd,
+            ),
+            (
+                BudgetScope::Tenant,
+                config.tenant_daily_usd,
+                tenant_spent_usd,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(scope, limit_usd, spent_usd)| {
+            Some(BudgetAllowance {
+                scope,
+                limit_usd: limit_usd?,
+                spent_usd,
+            })
+        })
+        .collect();
+        Self::with_limits(config, limits)
+    }
+
+    /// A run limited by the allowance of its caller rather than the limits of the config.
+    pub fn with_allowance(config: BudgetConfig, allowance: Option<BudgetAllowance>) -> Self {
+        Self::with_limits(config, allowance.into_iter().collect())
+    }
+
+    fn with_limits(config: BudgetConfig, limits: Vec<BudgetAllowance>) -> Self {
+        Self {
+            config: Arc::new(config),
+            limits,
+            state: Arc::default(),
+        }
+    }
+
+    /// Checks a call to `model` with a prompt of `prompt_tokens` before it is sent.
+    /// Returns the model to switch to once the run is degraded, the call is rejected when even
+    /// that model would go beyond a limit.
+    pub fn plan(
+        &self,
+        model: &str,
+        prompt_tokens: usize,
+    ) -> Result<Option<String>, BudgetExceeded> {
+        let mut state = self.state.lock().unwrap();
+        let cost = self
+            .config
+            .prices
+            .cost(model, prompt_tokens, EXPECTED_COMPLETION_TOKENS);
+        let exceeded = self.exceeded(state.spent_usd, cost);
+        state.degraded |= exceeded.is_some()
+            || self.limits.iter().any(|limit| {
+                limit.spent_usd + state.spent_usd + cost >= limit.limit_usd * self.config.degrade_at
+            });
+        if !state.degraded {
+            return Ok(None);
+        }
+
+        let (model, exceeded) = match self.config.degraded_model.as_deref() {
+            Some(degraded_model) => {
+                let cost = self.config.prices.cost(
+                    degraded_model,
+                    prompt_tokens,
+                    EXPECTED_COMPLETION_TOKENS,
+                );
+                (
+                    Some(degraded_model.to_string()),
+                    self.exceeded(state.spent_usd, cost),
+                )
+            }
+            None => (None, exceeded),
+        };
+        match exceeded {
+            Some(exceeded) => {
+                state.rejection = Some(exceeded.clone());
+                Err(exceeded)
+            }
+            None => Ok(model),
+        }
+    }
+
+    fn exceeded(&self, spent_usd: f64, cost: f64) -> Option<BudgetExceeded> {
+        self.limits
+            .iter()
+            .find(|limit| limit.spent_usd + spent_usd + cost > limit.limit_usd)
+            .map(|limit| BudgetExceeded {
+                scope: limit.scope,
+                limit_usd: limit.limit_usd,
+                spent_usd: limit.spent_usd + spent_usd,
+                projected_usd: limit.spent_usd + spent_usd + cost,
+            })
+    }
+
+    /// Counts a call that was sent, returns what it cost.
+    pub fn record(&self, model: &str, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        let cost = self
+            .config
+            .prices
+            .cost(model, prompt_tokens, completion_tokens);
+        self.add_spend(cost);
+        cost
+    }
+
+    /// Counts spend reported by another service, e.g. the cost of an answer.
+    pub fn add_spend(&self, usd: f64) {
+        self.state.lock().unwrap().spent_usd += usd.max(0.0);
+    }
+
+    /// Spent by the run so far.
+    pub fn spent_usd(&self) -> f64 {
+        self.state.lock().unwrap().spent_usd
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.state.lock().unwrap().degraded
+    }
+
+    /// The last call that was rejected, for the callers that only see the error as a message.
+    pub fn rejection(&self) -> Option<BudgetExceeded> {
+        self.state.lock().unwrap().rejection.clone()
+    }
+
+    /// The limit with the least left, including what the run spent so far.
+    pub fn allowance(&self) -> Option<BudgetAllowance> {
+        let spent_usd = self.spent_usd();
+        self.limits
+            .iter()
+            .map(|limit| BudgetAllowance {
+                spent_usd: limit.spent_usd + spent_usd,
+                ..*limit
+            })
+            .min_by(|a, b| a.remaining_usd().total_cmp(&b.remaining_usd()))
+    }
+}
+
+/// Days since the epoch, the tenant counters start over every day at midnight UTC.
+pub fn budget_day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECONDS_PER_DAY
+}
+
+pub fn tenant_spend_key(tenant: &str, day: u64) -> String {
+    format!("budget:tenant:{}:{}", tenant, day)
+}
+
+/// Where the daily spend of the tenants is counted.
+pub trait SpendStore {
+    fn tenant_spent(&self, tenant: &str, day: u64) -> Result<f64>;
+    /// Adds to the spend of the day, returns the new total.
+    fn add_tenant_spend(&self, tenant: &str, day: u64, usd: f64) -> Result<f64>;
+}
+
+/// Counters shared by the coordinator instances, each day in its own key expiring after it.
+pub struct RedisSpendStore {
+    pub url: String,
+}
+
+impl SpendStore for RedisSpendStore {
+    fn tenant_spent(&self, tenant: &str, day: u64) -> Result<f64> {
+        let mut conn = establish_redis_connection(&self.url)?;
+        let spent: Option<f64> = conn.get(tenant_spend_key(tenant, day))?;
+        Ok(spent.unwrap_or_default())
+    }
+
+    fn add_tenant_spend(&self, tenant: &str, day: u64, usd: f64) -> Result<f64> {
+        let key = tenant_spend_key(tenant, day);
+        let mut conn = establish_redis_connection(&self.url)?;
+        let spent: f64 = conn.incr(&key, usd)?;
+        conn.expire::<_, ()>(&key, TENANT_SPEND_TTL_SECS)?;
+        Ok(spent)
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySpendStore(Mutex<HashMap<String, f64>>);
+
+impl SpendStore for MemorySpendStore {
+    fn tenant_spent(&self, tenant: &str, day: u64) -> Result<f64> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .get(&tenant_spend_key(tenant, day))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    fn add_tenant_spend(&self, tenant: &str, day: u64, usd: f64) -> Result<f64> {
+        let mut spend = self.0.lock().unwrap();
+        let spent = spend.entry(tenant_spend_key(tenant, day)).or_default();
+        *spent += usd;
+        Ok(*spent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const MODEL: &str = "openai:gpt-4-0613";
+    const CHEAP_MODEL: &str = "openai:gpt-3.5-turbo";
+
+    // 1000 prompt tokens and the expected completion cost $0.06 on the model, $0.00125 on the cheap one.
+    fn config(conversation_usd: Option<f64>, tenant_daily_usd: Option<f64>) -> BudgetConfig {
+        BudgetConfig {
+            prices: PriceTable::parse("gpt-4-0613=0.03:0.06, openai:gpt-3.5-turbo=0.0005:0.0015")
+                .unwrap(),
+            conversation_usd,
+            tenant_daily_usd,
+            degraded_model: Some(CHEAP_MODEL.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_price_table() {
+        let prices = config(None, None).prices;
+        assert_eq!(prices.price("gpt-4-0613"), prices.price(MODEL));
+        assert!((prices.cost(MODEL, 1000, 500) - 0.06).abs() < 1e-9);
+        assert_eq!(prices.cost("anthropic:claude", 1000, 500), 0.0);
+        assert!(PriceTable::parse("gpt-4=0.03").is_err());
+        assert!(PriceTable::parse("gpt-4=-1:0.06").is_err());
+    }
+
+    #[test]
+    fn test_low_cap_degrades_before_rejecting() {
+        let meter = BudgetMeter::new(config(Some(0.2), None), 0.0, 0.0);
+
+        // $0.06 then $0.12 stay below 80% of the cap.
+        for _ in 0..2 {
+            assert_eq!(meter.plan(MODEL, 1000), Ok(None));
+            meter.record(MODEL, 1000, 500);
+        }
+        assert!(!meter.is_degraded());
+
+        // $0.18 would cross it, the call goes to the cheap model.
+        assert_eq!(meter.plan(MODEL, 1000), Ok(Some(CHEAP_MODEL.to_string())));
+        assert!(meter.is_degraded());
+        meter.record(CHEAP_MODEL, 1000, 500);
+        let allowance = meter.allowance().unwrap();
+        assert_eq!(allowance.scope, BudgetScope::Conversation);
+        assert!((allowance.remaining_usd() - 0.07875).abs() < 1e-9);
+
+        // even the cheap model doesn't fit a large prompt anymore.
+        let exceeded = meter.plan(MODEL, 200_000).unwrap_err();
+        assert_eq!(exceeded.scope, BudgetScope::Conversation);
+        assert_eq!(exceeded.limit_usd, 0.2);
+        assert!(exceeded.projected_usd > 0.2);
+        assert_eq!(meter.rejection(), Some(exceeded));
+        assert!((meter.spent_usd() - 0.12125).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_without_a_cheaper_model_calls_are_rejected_at_the_cap() {
+        let config = BudgetConfig {
+            degraded_model: None,
+            ..config(None, Some(1.0))
+        };
+        // the tenant already spent most of its day.
+        let meter = BudgetMeter::new(config, 0.0, 0.9);
+
+        assert_eq!(meter.plan(MODEL, 1000), Ok(None));
+        assert!(meter.is_degraded());
+        meter.record(MODEL, 1000, 500);
+        let exceeded = meter.plan(MODEL, 1000).unwrap_err();
+        assert_eq!(exceeded.scope, BudgetScope::Tenant);
+        assert!((exceeded.spent_usd - 0.96).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_unlimited_meter_only_counts() {
+        let meter = BudgetMeter::unlimited(config(None, None));
+        assert_eq!(meter.plan(MODEL, 1_000_000), Ok(None));
+        meter.record(MODEL, 1000, 500);
+        meter.add_spend(0.5);
+        assert!((meter.spent_usd() - 0.56).abs() < 1e-9);
+        assert_eq!(meter.allowance(), None);
+        assert!(!meter.is_degraded());
+    }
+
+    #[test]
+    fn test_tenant_counter_resets_daily() {
+        let store = MemorySpendStore::default();
+        let now = UNIX_EPOCH + Duration::from_secs(19_000 * SECONDS_PER_DAY + 23 * 3600);
+        let today = budget_day(now);
+        let tomorrow = budget_day(now + Duration::from_secs(3600));
+        assert_eq!(tomorrow, today + 1);
+
+        store.add_tenant_spend("acme", today, 0.4).unwrap();
+        assert_eq!(store.add_tenant_spend("acme", today, 0.5).unwrap(), 0.9);
+        assert_eq!(store.tenant_spent("other", today).unwrap(), 0.0);
+        assert_eq!(store.tenant_spent("acme", tomorrow).unwrap(), 0.0);
+        assert_ne!(
+            tenant_spend_key("acme", today),
+            tenant_spend_key("acme", tomorrow)
+        );
+
+        // the tenant is over its limit today, but not tomorrow.
+        let config = config(None, Some(0.9));
+        let spent_today = store.tenant_spent("acme", today).unwrap();
+        assert!(BudgetMeter::new(config.clone(), 0.0, spent_today)
+            .plan(MODEL, 1000)
+            .is_err());
+        let spent_tomorrow = store.tenant_spent("acme", tomorrow).unwrap();
+        assert_eq!(
+            BudgetMeter::new(config, 0.0, spent_tomorrow).plan(MODEL, 1000),
+            Ok(None)
+        );
+    }
+}
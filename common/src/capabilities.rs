@@ -0,0 +1,209 @@
+// Version handshake between the services. Every service serves `GET /version` with its crate
+// version and the optional API features it supports, callers only use the features advertised
+// by the build they talk to and fall back to the older behaviour otherwise.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+
+pub const VERSION_PATH: &str = "version";
+
+/// Optional API features a service may or may not support, depending on its build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    // `pinned_paths` on `GET /retrieve-code`.
+    PinnedPaths,
+    // `preferences` on `GET /retrieve-code`.
+    Preferences,
+    // msgpack request and response bodies.
+    Msgpack,
+    // `GET /repos/{repo}/owners`.
+    CodeOwners,
+    // `GET /repos/{repo}/commit`.
+    IndexedCommit,
+    // `GET /repos/{repo}/summary`.
+    RepoSummary,
+    // `GET /repos/{repo}/manifest`.
+    RunManifest,
+    // `language` on `GET /retrieve-code`.
+    Language,
+    // `POST /answer-batch`.
+    AnswerBatch,
+    // `GET /repos/{repo}/paths`.
+    IndexedPaths,
+    // budget allowance on `GET /retrieve-code` and `POST /answer-batch`, the cost of the answers.
+    Budget,
+    // `POST /attachments` and `POST /attachments/search` on code search, `attachments` on
+    // `GET /retrieve-code` and `POST /answer-batch` on code understanding.
+    Attachments,
+    // `GET /repos/{repo}/branches` and `branch` on the requests of code search.
+    Branches,
+    // `GET /repos/{repo}/freshness`.
+    Freshness,
+    // `include_verification` on `GET /retrieve-code` and `POST /answer-batch`.
+    Verification,
+    // `GET /index-generation` on code search, `index_generation` on the searches of code search
+    // and on `GET /retrieve-code` and `POST /answer-batch` of code understanding.
+    IndexGeneration,
+    // `GET` and `PUT /repos/{repo}/terminology` and `terminology` on `POST /symbols` of code
+    // search, `terminology` on `GET /retrieve-code` and `POST /answer-batch` of code understanding.
+    Terminology,
+    // `POST /explain-symbol` on code understanding.
+    ExplainSymbol,
+    // `POST /anchors/resolve` on code search.
+    AnchorResolution,
+    // `glossary` on `GET /retrieve-code` and `POST /answer-batch`.
+    Glossary,
+    // `ask_user` and `clarification` on `GET /retrieve-code`.
+    AskUser,
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::PinnedPaths => "pinned-paths",
+            Capability::Preferences => "preferences",
+            Capability::Msgpack => "msgpack",
+            Capability::CodeOwners => "code-owners",
+            Capability::IndexedCommit => "indexed-commit",
+            Capability::RepoSummary => "repo-summary",
+            Capability::RunManifest => "run-manifest",
+            Capability::Language => "language",
+            Capability::AnswerBatch => "answer-batch",
+            Capability::IndexedPaths => "indexed-paths",
+            Capability::Budget => "budget",
+            Capability::Attachments => "attachments",
+            Capability::Branches => "branches",
+            Capability::Freshness => "freshness",
+            Capability::Verification => "verification",
+            Capability::IndexGeneration => "index-generation",
+            Capability::Terminology => "terminology",
+            Capability::ExplainSymbol => "explain-symbol",
+            Capability::AnchorResolution => "anchor-resolution",
+            Capability::Glossary => "glossary",
+            Capability::AskUser => "ask-user",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Services the coordinator talks to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Service {
+    CodeSearch,
+    CodeUnderstanding,
+}
+
+impl Service {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::CodeSearch => "code-search",
+            Service::CodeUnderstanding => "code-understanding",
+        }
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Body of `GET /version`. Capabilities are kept as strings so that the ones added by newer
+/// builds don't fail the handshake of older callers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServiceVersion {
+    pub service: String,
+    pub version: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl ServiceVersion {
+    pub fn new(service: &str, version: &str, capabilities: &[Capability]) -> Self {
+        Self {
+            service: service.to_string(),
+            version: version.to_string(),
+            capabilities: capabilities.iter().map(|c| c.as_str().to_string()).collect(),
+        }
+    }
+
+    /// A build from before the handshake, it has none of the optional features.
+    pub fn legacy(service: Service) -> Self {
+        Self::new(service.name(), "unknown", &[])
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.iter().any(|c| c == capability.as_str())
+    }
+}
+
+/// What a caller knows about the features of a service.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Capabilities {
+    // no handshake was made, e.g. for co-hosted services of the same build, everything is used.
+    Unknown,
+    Advertised(ServiceVersion),
+}
+
+impl Capabilities {
+    pub fn supports(&self, capability: Capability) -> bool {
+        match self {
+            Capabilities::Unknown => true,
+            Capabilities::Advertised(version) => version.supports(capability),
+        }
+    }
+}
+
+/// GET /version, answered without authentication like the health check.
+pub fn version_route(
+    service: &str,
+    version: &str,
+    capabilities: &[Capability],
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let version = ServiceVersion::new(service, version, capabilities);
+    warp::path(VERSION_PATH)
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || warp::reply::json(&version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_version_route() {
+        let route = version_route("code-understanding", "1.2.0", &[Capability::PinnedPaths]);
+        let response = warp::test::request().path("/version").reply(&route).await;
+        assert_eq!(response.status(), 200);
+
+        let version: ServiceVersion = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(version.version, "1.2.0");
+        assert!(version.supports(Capability::PinnedPaths));
+        assert!(!version.supports(Capability::Msgpack));
+    }
+
+    #[test]
+    fn test_unknown_capabilities_are_kept() {
+        let version: ServiceVersion = serde_json::from_value(serde_json::json!({
+            "service": "code-search",
+            "version": "2.0.0",
+            "capabilities": ["code-owners", "something-newer"]
+        }))
+        .unwrap();
+        let capabilities = Capabilities::Advertised(version);
+        assert!(capabilities.supports(Capability::CodeOwners));
+        assert!(!capabilities.supports(Capability::IndexedCommit));
+
+        assert!(Capabilities::Unknown.supports(Capability::IndexedCommit));
+        assert!(!Capabilities::Advertised(ServiceVersion::legacy(Service::CodeSearch))
+            .supports(Capability::CodeOwners));
+    }
+}
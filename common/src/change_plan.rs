@@ -0,0 +1,338 @@
+// Preview of what the modifier would change for a conversation, without producing any diff. Every
+// answered task is planned on its own from its answers and merged code contexts, the entries of
+// all the tasks are then aggregated per file and checked against the indexed paths. The plan is
+// stored on the root of the conversation and generated again on request, e.g. after the tasks
+// changed.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::grounding::{GroundingIndex, MAX_CLOSEST_COMPONENTS};
+
+// entries read from the reply of one task, the rest are dropped.
+const MAX_TASK_CHANGES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Add,
+    Modify,
+    Delete,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Add => "add",
+            ChangeKind::Modify => "modify",
+            ChangeKind::Delete => "delete",
+        }
+    }
+
+    // The kind of a file changed by several tasks: a file added by a task stays added whatever
+    // the later tasks do to it, a file deleted and added again is modified.
+    fn then(self, next: ChangeKind) -> ChangeKind {
+        match (self, next) {
+            (ChangeKind::Add, _) => ChangeKind::Add,
+            (ChangeKind::Delete, ChangeKind::Add) => ChangeKind::Modify,
+            (ChangeKind::Delete, _) | (_, ChangeKind::Delete) => ChangeKind::Delete,
+            _ => ChangeKind::Modify,
+        }
+    }
+}
+
+/// An entry of the plan of one task, as the model wrote it. `depends_on_task` is the number of
+/// the task in the prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedChange {
+    pub path: String,
+    pub change_kind: ChangeKind,
+    pub description: String,
+    #[serde(default)]
+    pub estimated_lines: usize,
+    #[serde(default)]
+    pub depends_on_task: Option<usize>,
+}
+
+/// The JSON array of the reply, without the entries missing a path or a description. Paths are
+/// made relative to the repo root.
+pub fn parse_planned_changes(response: &str) -> Vec<PlannedChange> {
+    let response = response.trim();
+    let json = match (response.find('['), response.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => return Vec::new(),
+    };
+    match serde_json::from_str::<Vec<PlannedChange>>(json) {
+        Ok(changes) => changes
+            .into_iter()
+            .map(|mut change| {
+                change.path = change
+                    .path
+                    .trim()
+                    .trim_start_matches("./")
+                    .trim_start_matches('/')
+                    .to_string();
+                change
+            })
+            .filter(|change| !change.path.is_empty() && !change.description.trim().is_empty())
+            .take(MAX_TASK_CHANGES)
+            .collect(),
+        Err(e) => {
+            log::warn!("Failed to parse the change plan of a task: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// What one task changes in a file. `task` and `depends_on_task` are the node ids of the tasks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskChange {
+    pub task: usize,
+    pub change_kind: ChangeKind,
+    pub description: String,
+    pub estimated_lines: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depends_on_task: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UngroundedReason {
+    // a modified or deleted file the index doesn't have.
+    NotIndexed,
+    // an added file the index already has.
+    AlreadyIndexed,
+}
+
+impl UngroundedReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UngroundedReason::NotIndexed => "not indexed",
+            UngroundedReason::AlreadyIndexed => "already indexed",
+        }
+    }
+}
+
+/// The changes of every task to one file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileChangePlan {
+    pub path: String,
+    pub change_kind: ChangeKind,
+    // summed over the tasks.
+    pub estimated_lines: usize,
+    pub changes: Vec<TaskChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ungrounded: Option<UngroundedReason>,
+    // the indexed paths closest to a path that isn't indexed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub closest: Vec<String>,
+}
+
+/// The files a conversation would change, stored on its root.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ChangePlan {
+    pub files: Vec<FileChangePlan>,
+    pub estimated_lines: usize,
+    // descriptions of the tasks the plan was generated from, in order.
+    pub tasks: Vec<String>,
+    // false when the indexed paths couldn't be listed, the entries then aren't flagged.
+    pub grounding_checked: bool,
+    pub generated_at: u64,
+}
+
+impl ChangePlan {
+    /// Aggregates the changes of the tasks per file, in the order the files are first changed.
+    pub fn aggregate(
+        tasks: Vec<String>,
+        changes: Vec<(String, TaskChange)>,
+        generated_at: u64,
+    ) -> Self {
+        let mut files: Vec<FileChangePlan> = Vec::new();
+        for (path, change) in changes {
+            match files.iter_mut().find(|file| file.path == path) {
+                Some(file) => {
+                    file.change_kind = file.change_kind.then(change.change_kind);
+                    file.estimated_lines += change.estimated_lines;
+                    file.changes.push(change);
+                }
+                None => files.push(FileChangePlan {
+                    path,
+                    change_kind: change.change_kind,
+                    estimated_lines: change.estimated_lines,
+                    changes: vec![change],
+                    ungrounded: None,
+                    closest: Vec::new(),
+                }),
+            }
+        }
+        ChangePlan {
+            estimated_lines: files.iter().map(|file| file.estimated_lines).sum(),
+            files,
+            tasks,
+            grounding_checked: false,
+            generated_at,
+        }
+    }
+
+    /// Flags the files whose kind doesn't match the index: modified or deleted files it doesn't
+    /// have, with the closest ones it has, and added files it already has.
+    pub fn ground(&mut self, indexed_paths: &[String]) {
+        let indexed = indexed_paths.iter().map(String::as_str).collect::<HashSet<_>>();
+        let index = GroundingIndex::new(indexed_paths.to_vec(), None);
+        for file in &mut self.files {
+            let is_indexed = indexed.contains(file.path.as_str());
+            (file.ungrounded, file.closest) = match (file.change_kind, is_indexed) {
+                (ChangeKind::Add, true) => (Some(UngroundedReason::AlreadyIndexed), Vec::new()),
+                (ChangeKind::Modify | ChangeKind::Delete, false) => (
+                    Some(UngroundedReason::NotIndexed),
+                    index.closest(&file.path, MAX_CLOSEST_COMPONENTS),
+                ),
+                _ => (None, Vec::new()),
+            };
+        }
+        self.grounding_checked = true;
+    }
+
+    pub fn ungrounded(&self) -> impl Iterator<Item = &FileChangePlan> {
+        self.files.iter().filter(|file| file.ungrounded.is_some())
+    }
+
+    /// The paths the modifier may change when it is given the plan, the flagged ones are left out.
+    pub fn scope(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|file| file.ungrounded.is_none())
+            .map(|file| file.path.clone())
+            .collect()
+    }
+
+    /// Whether the tasks changed since the plan was generated.
+    pub fn is_stale(&self, tasks: &[String]) -> bool {
+        self.tasks != tasks
+    }
+
+    /// e.g. `src/config.rs (modify, ~12 lines), src/retry.rs (add, ~40 lines, already indexed)`.
+    pub fn render(&self) -> String {
+        self.files
+            .iter()
+            .map(|file| {
+                let mut parts = vec![
+                    file.change_kind.as_str().to_string(),
+                    format!("~{} lines", file.estimated_lines),
+                ];
+                parts.extend(file.ungrounded.map(|reason| reason.as_str().to_string()));
+                format!("{} ({})", file.path, parts.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A request to the from-conversation endpoint of the modifier. With a plan, the modifier only
+/// attempts the files of its scope.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FromConversationRequest {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<ChangePlan>,
+}
+
+impl FromConversationRequest {
+    /// Whether the modifier may change `path`, any path without a plan.
+    pub fn allows(&self, path: &str) -> bool {
+        self.plan
+            .as_ref()
+            .map_or(true, |plan| plan.scope().iter().any(|scoped| scoped == path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(task: usize, change_kind: ChangeKind, estimated_lines: usize) -> TaskChange {
+        TaskChange {
+            task,
+            change_kind,
+            description: format!("change of task {}", task),
+            estimated_lines,
+            depends_on_task: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_planned_changes() {
+        let response = r#"Here is the plan:
+```json
+[
+  {"path": "./src/config.rs", "change_kind": "modify", "description": "Add the retry count", "estimated_lines": 8},
+  {"path": "src/retry.rs", "change_kind": "add", "description": "Retry policy", "estimated_lines": 40, "depends_on_task": 1},
+  {"path": "", "change_kind": "delete", "description": "Nothing"}
+]
+```"#;
+        let changes = parse_planned_changes(response);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].path, "src/config.rs");
+        assert_eq!(changes[1].depends_on_task, Some(1));
+        assert!(parse_planned_changes("No changes needed.").is_empty());
+    }
+
+    #[test]
+    fn test_changes_are_aggregated_per_file() {
+        let plan = ChangePlan::aggregate(
+            vec!["Configure".to_string(), "Retry".to_string()],
+            vec![
+                ("src/config.rs".to_string(), change(2, ChangeKind::Modify, 8)),
+                ("src/retry.rs".to_string(), change(2, ChangeKind::Add, 40)),
+                ("src/config.rs".to_string(), change(7, ChangeKind::Modify, 4)),
+                ("src/retry.rs".to_string(), change(7, ChangeKind::Modify, 10)),
+                ("src/legacy.rs".to_string(), change(7, ChangeKind::Modify, 2)),
+                ("src/legacy.rs".to_string(), change(7, ChangeKind::Delete, 30)),
+            ],
+            0,
+        );
+        assert_eq!(plan.files.len(), 3);
+        assert_eq!(plan.files[0].changes.len(), 2);
+        assert_eq!(plan.files[0].estimated_lines, 12);
+        // an added file stays added, a modified and deleted one is deleted.
+        assert_eq!(plan.files[1].change_kind, ChangeKind::Add);
+        assert_eq!(plan.files[2].change_kind, ChangeKind::Delete);
+        assert_eq!(plan.estimated_lines, 94);
+        assert!(!plan.is_stale(&["Configure".to_string(), "Retry".to_string()]));
+        assert!(plan.is_stale(&["Configure".to_string()]));
+    }
+
+    #[test]
+    fn test_ungrounded_files_are_flagged() {
+        let mut plan = ChangePlan::aggregate(
+            Vec::new(),
+            vec![
+                ("src/config.rs".to_string(), change(2, ChangeKind::Modify, 8)),
+                ("src/confg.rs".to_string(), change(2, ChangeKind::Modify, 3)),
+                ("src/lib.rs".to_string(), change(2, ChangeKind::Add, 5)),
+                ("src/retry.rs".to_string(), change(2, ChangeKind::Add, 40)),
+            ],
+            0,
+        );
+        plan.ground(&["src/config.rs".to_string(), "src/lib.rs".to_string()]);
+
+        assert!(plan.grounding_checked);
+        assert_eq!(plan.files[0].ungrounded, None);
+        assert_eq!(plan.files[1].ungrounded, Some(UngroundedReason::NotIndexed));
+        assert_eq!(plan.files[1].closest[0], "src/config.rs");
+        assert_eq!(plan.files[2].ungrounded, Some(UngroundedReason::AlreadyIndexed));
+        assert_eq!(plan.files[3].ungrounded, None);
+        assert_eq!(plan.ungrounded().count(), 2);
+        assert_eq!(plan.scope(), vec!["src/config.rs", "src/retry.rs"]);
+        let request = FromConversationRequest {
+            id: "conv-1".to_string(),
+            plan: Some(plan.clone()),
+        };
+        assert!(request.allows("src/retry.rs") && !request.allows("src/confg.rs"));
+        assert_eq!(
+            plan.render(),
+            "src/config.rs (modify, ~8 lines), src/confg.rs (modify, ~3 lines, not indexed), src/lib.rs (add, ~5 lines, already indexed), src/retry.rs (add, ~40 lines)"
+        );
+    }
+}
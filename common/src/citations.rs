@@ -0,0 +1,391 @@
+// Citations of an answer, its repo relative links and quoted code blocks, resolved once per answer.
+// The answer is parsed once, every cited file is fetched once however often it is cited, and the
+// line validation, the scope guardrails and the link rewriting all read the same report: code
+// understanding checks the lines against the indexed files and clamps the ranges going past their
+// end, the coordinator adds the scope checks and the urls the links were rewritten to.
+// The cited lines get a content anchor when they are resolved, so the citation can be found again
+// once its file changed, see `crate::anchors`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+
+use crate::anchors::{AnchorResolution, SnippetAnchor};
+use crate::answer_scope::{AnswerScope, ScopeViolationReason};
+use crate::links::{encode_path, rewrite_citations, CitationSite};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CitationSource {
+    Link,
+    QuotedCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CitationStatus {
+    // the file is indexed and has the cited lines.
+    Valid,
+    // the range went past the end of the file, it is clamped to its last line.
+    Adjusted,
+    // the file isn't indexed or the range starts past its end.
+    Invalid,
+    // the file couldn't be fetched, the citation is kept as it is.
+    Unresolved,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Citation {
+    pub path: String,
+    pub source: CitationSource,
+    // 1-based and inclusive, as the answer cited them. None for links to a whole file.
+    pub cited_lines: Option<(usize, usize)>,
+    // the lines after resolution, the answer is rewritten with them.
+    pub lines: Option<(usize, usize)>,
+    pub status: CitationStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub out_of_scope: Option<ScopeViolationReason>,
+    // url the citation was rewritten to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    // content anchor of the resolved lines, missing for links to a whole file and for citations
+    // resolved before the anchors were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<String>,
+    // where the anchor is in the current index, set when the citation is re-resolved and drifted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drift: Option<AnchorResolution>,
+    // the function enclosing the first resolved line, set along with the anchor, see
+    // `crate::glossary`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enclosing_symbol: Option<String>,
+}
+
+/// The citations of an answer in the order they appear in it.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct CitationReport {
+    pub citations: Vec<Citation>,
+}
+
+impl CitationReport {
+    /// The citations of the answer, unresolved.
+    pub fn parse(answer: &str) -> Self {
+        let mut citations = Vec::new();
+        rewrite_citations(answer, |site, path, lines| {
+            citations.push(Citation {
+                path: path.to_string(),
+                source: match site {
+                    CitationSite::Link => CitationSource::Link,
+                    CitationSite::QuotedCode(_) => CitationSource::QuotedCode,
+                },
+                cited_lines: lines,
+                lines,
+                status: CitationStatus::Unresolved,
+                out_of_scope: None,
+                url: None,
+                anchor: None,
+                drift: None,
+                enclosing_symbol: None,
+            });
+            None
+        });
+        Self { citations }
+    }
+
+    /// Resolves the citations of the answer against the files they cite, `fetch` returns the
+    /// number of lines of a file, None when it isn't indexed. It is called once per cited path.
+    pub async fn resolve<F, Fut>(answer: &str, mut fetch: F) -> Self
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = anyhow::Result<Option<usize>>>,
+    {
+        let mut report = Self::parse(answer);
+        let mut line_counts = HashMap::new();
+        for path in report.paths() {
+            match fetch(path.clone()).await {
+                Ok(line_count) => {
+                    line_counts.insert(path, line_count);
+                }
+                Err(e) => log::warn!("Failed to fetch {} to resolve its citations: {}", path, e),
+            }
+        }
+        report.validate(&line_counts);
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.citations.is_empty()
+    }
+
+    /// The distinct paths cited, sorted.
+    pub fn paths(&self) -> Vec<String> {
+        self.citations
+            .iter()
+            .map(|citation| citation.path.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    pub fn count(&self, status: CitationStatus) -> usize {
+        self.citations
+            .iter()
+            .filter(|citation| citation.status == status)
+            .count()
+    }
+
+    // Checks the lines of the citations against the line counts of the fetched files, the paths
+    // missing from `line_counts` stay unresolved.
+    fn validate(&mut self, line_counts: &HashMap<String, Option<usize>>) {
+        for citation in &mut self.citations {
+            let Some(line_count) = line_counts.get(&citation.path) else {
+                continue;
+            };
+            (citation.status, citation.lines) = match (line_count, citation.cited_lines) {
+                (None, lines) => (CitationStatus::Invalid, lines),
+                (Some(_), None) => (CitationStatus::Valid, None),
+                (Some(line_count), Some((start, end))) => {
+                    let (start, end) = (start.max(1), end.max(start.max(1)));
+                    if start > *line_count {
+                        (CitationStatus::Invalid, citation.cited_lines)
+                    } else if end > *line_count {
+                        (CitationStatus::Adjusted, Some((start, *line_count)))
+                    } else if Some((start, end)) != citation.cited_lines {
+                        (CitationStatus::Adjusted, Some((start, end)))
+                    } else {
+                        (CitationStatus::Valid, Some((start, end)))
+                    }
+                }
+            };
+        }
+    }
+
+    /// The answer with the adjusted citations citing their resolved lines, so the later steps
+    /// see the same lines as the report.
+    pub fn apply(&self, answer: &str) -> String {
+        rewrite_citations(answer, |site, path, lines| {
+            let (source, line) = match site {
+                CitationSite::Link => (CitationSource::Link, None),
+                CitationSite::QuotedCode(line) => (CitationSource::QuotedCode, Some(line)),
+            };
+            let citation = self.citations.iter().find(|citation| {
+                citation.status == CitationStatus::Adjusted
+                    && citation.source == source
+                    && citation.path == path
+                    && citation.cited_lines == lines
+            })?;
+            let ((cited_start, cited_end), (start, end)) = (lines?, citation.lines?);
+            Some(match line {
+                None => format!("{}#L{}-L{}", encode_path(path), start, end),
+                // the info string of quoted code has 0-based lines.
+                Some(line) => line.replacen(
+                    &format!(",lines:{}-{}", cited_start - 1, cited_end - 1),
+                    &format!(",lines:{}-{}", start - 1, end - 1),
+                    1,
+                ),
+            })
+        })
+    }
+
+    /// Anchors the valid and adjusted citations of `path` to the lines they resolved to in its
+    /// `content`. `symbol_at` returns the symbol enclosing a 1-based line, when it is known, it is
+    /// recorded as the enclosing symbol of the citation.
+    pub fn anchor(
+        &mut self,
+        path: &str,
+        content: &str,
+        symbol_at: impl Fn(usize) -> Option<String>,
+    ) {
+        for citation in &mut self.citations {
+            if citation.path != path
+                || !matches!(citation.status, CitationStatus::Valid | CitationStatus::Adjusted)
+            {
+                continue;
+            }
+            let Some(lines) = citation.lines else {
+                continue;
+            };
+            let symbol = symbol_at(lines.0);
+            citation.anchor = SnippetAnchor::new(content, lines, symbol.as_deref())
+                .map(|anchor| anchor.to_string());
+            citation.enclosing_symbol = symbol.filter(|symbol| !symbol.is_empty());
+        }
+    }
+
+    /// The citations whose anchor resolved elsewhere than their lines, annotated with `drift`.
+    pub fn drifted(&self) -> impl Iterator<Item = &Citation> {
+        self.citations.iter().filter(|citation| citation.drift.is_some())
+    }
+
+    /// e.g. `src/ranking.rs#L10-L20 (moved to L41-L51), src/lib.rs`.
+    pub fn render(&self) -> String {
+        self.citations
+            .iter()
+            .map(|citation| {
+                let mut rendered = match citation.lines {
+                    Some((start, end)) => format!("{}#L{}-L{}", citation.path, start, end),
+                    None => citation.path.clone(),
+                };
+                if let Some(drift) = &citation.drift {
+                    rendered.push_str(&format!(" ({})", drift.render()));
+                }
+                rendered
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Records the scope check of every cited path.
+    pub fn check_scope(&mut self, scope: &AnswerScope) {
+        for citation in &mut self.citations {
+            citation.out_of_scope = scope.check(&citation.path);
+        }
+    }
+
+    /// Records the url of the citations of `path` at `lines` and returns it, None when they are
+    /// invalid and keep their relative link. Citations missing from the report are rewritten.
+    pub fn link(
+        &mut self,
+        path: &str,
+        lines: Option<(usize, usize)>,
+        url: String,
+    ) -> Option<String> {
+        let mut cited = self
+            .citations
+            .iter_mut()
+            .filter(|citation| citation.path == path && citation.lines == lines)
+            .peekable();
+        if cited.peek().is_none() {
+            return Some(url);
+        }
+        let mut linked = false;
+        for citation in cited {
+            if citation.status != CitationStatus::Invalid {
+                citation.url = Some(url.clone());
+                linked = true;
+            }
+        }
+        linked.then_some(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::links::WebUrlTemplate;
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    const GITHUB: &str = "https://github.com/{org}/{repo}/blob/{commit}/{path}#L{start}-L{end}";
+
+    // cites src/refund.rs five times, its last range goes past the end of the 40 line file.
+    const ANSWER: &str = "The [refund](src/refund.rs#L10-L12) calls [retry](src/refund.rs#L30) \
+                          of [the module](src/refund.rs), see [the tail](src/refund.rs#L35-L60).\n\
+                          ```type:Quoted,lang:Rust,path:src/refund.rs,lines:34-59\n\
+                          fn retry() {}\n\
+                          ```\n\
+                          Read [the docs](docs/missing.md#L1) and [config](config/app.yaml#L90).\n";
+
+    async fn fixture_report(fetches: &RefCell<Vec<String>>) -> CitationReport {
+        let line_counts = HashMap::from([
+            ("src/refund.rs", Some(40)),
+            ("config/app.yaml", Some(12)),
+            ("docs/missing.md", None),
+        ]);
+        CitationReport::resolve(ANSWER, |path| {
+            fetches.borrow_mut().push(path.clone());
+            let line_count = line_counts[path.as_str()];
+            async move { Ok(line_count) }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_every_cited_path_is_fetched_once() {
+        let fetches = RefCell::new(Vec::new());
+        let report = fixture_report(&fetches).await;
+
+        assert_eq!(
+            fetches.into_inner(),
+            vec!["config/app.yaml", "docs/missing.md", "src/refund.rs"]
+        );
+        assert_eq!(
+            report
+                .citations
+                .iter()
+                .filter(|citation| citation.path == "src/refund.rs")
+                .count(),
+            5
+        );
+        assert_eq!(
+            report
+                .citations
+                .iter()
+                .map(|citation| (citation.status, citation.lines))
+                .collect::<Vec<_>>(),
+            vec![
+                (CitationStatus::Valid, Some((10, 12))),
+                (CitationStatus::Valid, Some((30, 30))),
+                (CitationStatus::Valid, None),
+                (CitationStatus::Adjusted, Some((35, 40))),
+                (CitationStatus::Adjusted, Some((35, 40))),
+                (CitationStatus::Invalid, Some((1, 1))),
+                (CitationStatus::Invalid, Some((90, 90))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolved_citations_are_anchored() {
+        let mut report = fixture_report(&RefCell::new(Vec::new())).await;
+        let refund = (1..=40).map(|i| format!("line {}\n", i)).collect::<String>();
+        report.anchor("src/refund.rs", &refund, |line| (line >= 10).then(|| "refund".to_string()));
+
+        let anchors = report
+            .citations
+            .iter()
+            .map(|citation| citation.anchor.as_deref().map(SnippetAnchor::from_str))
+            .collect::<Vec<_>>();
+        // the link to the whole file and the citations of other paths have none.
+        assert!(anchors[2].is_none() && anchors[5].is_none() && anchors[6].is_none());
+        let first = anchors[0].clone().unwrap().unwrap();
+        assert_eq!((first.lines, first.symbol.as_deref()), (3, Some("refund")));
+        assert_eq!(report.citations[0].enclosing_symbol.as_deref(), Some("refund"));
+        assert_eq!(report.citations[2].enclosing_symbol, None);
+        // the adjusted citation is anchored to its clamped lines.
+        let tail = anchors[3].clone().unwrap().unwrap();
+        assert_eq!(tail.lines, 6);
+        assert_eq!(report.drifted().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_later_steps_see_the_adjusted_lines() {
+        let mut report = fixture_report(&RefCell::new(Vec::new())).await;
+        let answer = report.apply(ANSWER);
+        assert!(answer.contains("[the tail](src/refund.rs#L35-L40)"));
+        assert!(answer.contains("```type:Quoted,lang:Rust,path:src/refund.rs,lines:34-39\n"));
+        assert_eq!(CitationReport::parse(&answer).citations.len(), 7);
+
+        report.check_scope(&AnswerScope::new("acme/api", &["src".to_string()], None));
+        assert_eq!(
+            report.citations[6].out_of_scope,
+            Some(ScopeViolationReason::OutOfScope)
+        );
+
+        let template = WebUrlTemplate::new(GITHUB);
+        let linked =
+            template.rewrite_links_with(&answer, "acme/api", "abc123", |path, lines, url| {
+                report.link(path, lines, url)
+            });
+        let tail = "https://github.com/acme/api/blob/abc123/src/refund.rs#L35-L40";
+        assert!(linked.contains(&format!("[the tail]({})", tail)));
+        assert!(linked.contains(&format!("[src/refund.rs#L35-L40]({})\n```", tail)));
+        // the invalid citations keep their relative links.
+        assert!(linked.contains("[the docs](docs/missing.md#L1)"));
+        assert!(linked.contains("[config](config/app.yaml#L90)"));
+        assert_eq!(report.citations[3].url.as_deref(), Some(tail));
+        assert_eq!(report.citations[4].url.as_deref(), Some(tail));
+        assert_eq!(report.citations[5].url, None);
+    }
+}
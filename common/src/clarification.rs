@@ -0,0 +1,89 @@
+// A question the code understanding agent asks the user in the middle of a run, when the code it
+// found belongs to more than one part of the codebase the query could mean. The run of the question
+// stops with its exchanges kept, the coordinator holds the question until the user responds and
+// asks it again with the response. The run resumes where it stopped, with the response as a
+// message of the user. A question is clarified once, the agent answers with what it has after.
+
+use serde::{Deserialize, Serialize};
+
+/// A clarifying question of the agent, with the response of the user once there is one.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Clarification {
+    pub question: String,
+    // short answers the user can pick, the response doesn't have to be one of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+}
+
+impl Clarification {
+    pub fn new(question: &str, options: &[String]) -> Self {
+        Self {
+            question: question.trim().to_string(),
+            options: options
+                .iter()
+                .map(|option| option.trim().to_string())
+                .filter(|option| !option.is_empty())
+                .collect(),
+            response: None,
+        }
+    }
+
+    /// Whether the user hasn't responded yet.
+    pub fn is_pending(&self) -> bool {
+        self.response.is_none()
+    }
+
+    /// e.g. `Which retries do you mean? (webhook deliveries | payment refunds)`.
+    pub fn render(&self) -> String {
+        let mut rendered = self.question.clone();
+        if !self.options.is_empty() {
+            rendered.push_str(&format!(" ({})", self.options.join(" | ")));
+        }
+        rendered
+    }
+
+    /// The message of the user the run resumes with, None while the user hasn't responded.
+    pub fn response_message(&self) -> Option<String> {
+        self.response
+            .as_ref()
+            .map(|response| format!("To clarify \"{}\": {}", self.question, response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clarification_is_answered_once_responded() {
+        let options = vec![
+            " webhook deliveries (src/webhooks/)".to_string(),
+            "".to_string(),
+            "payment refunds (src/refunds/)".to_string(),
+        ];
+        let mut clarification = Clarification::new("Which retries do you mean? ", &options);
+
+        assert!(clarification.is_pending());
+        assert_eq!(clarification.response_message(), None);
+        assert_eq!(
+            clarification.render(),
+            "Which retries do you mean? (webhook deliveries (src/webhooks/) | payment refunds (src/refunds/))"
+        );
+        assert_eq!(
+            serde_json::to_value(&clarification).unwrap(),
+            serde_json::json!({
+                "question": "Which retries do you mean?",
+                "options": ["webhook deliveries (src/webhooks/)", "payment refunds (src/refunds/)"],
+            })
+        );
+
+        clarification.response = Some("the payment refunds".to_string());
+        assert!(!clarification.is_pending());
+        assert_eq!(
+            clarification.response_message().unwrap(),
+            "To clarify \"Which retries do you mean?\": the payment refunds"
+        );
+    }
+}
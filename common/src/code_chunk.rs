@@ -0,0 +1,299 @@
+// The code chunk shared by the services. Code search returns it from `/symbols` and `/span`, code
+// understanding keeps it on the exchanges and renders it into the prompts. Every service used to
+// declare its own, and the conversions between them lost fields and shifted lines; this is the one
+// type left. On the wire it is written with the version of its representation, a chunk without one
+// is the unversioned representation the services sent before, which reads the same.
+
+use std::fmt;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::RetrievalSource;
+
+/// Version of the wire representation of `CodeChunk`.
+pub const CODE_CHUNK_WIRE_VERSION: u32 = 2;
+// the representation sent before it was versioned.
+const UNVERSIONED_WIRE_VERSION: u32 = 1;
+
+/// A snippet of a file of a repo, with where it was found and why.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(into = "CodeChunkWire", try_from = "CodeChunkWire")]
+pub struct CodeChunk {
+    pub path: String,
+    // empty when the repo is implied by the request, e.g. a search of one repo.
+    pub repo: String,
+    // short name of the path in the prompts of the agent, set by code understanding.
+    pub alias: Option<usize>,
+    pub snippet: String,
+    // lines of the snippet in the file, as code search numbers them.
+    pub start_line: usize,
+    pub end_line: usize,
+    // bytes of the snippet in the file, when the search knows them.
+    pub byte_range: Option<Range<usize>>,
+    // the definition enclosing the snippet, e.g. the function a data flow hop goes through.
+    pub symbol: Option<String>,
+    // kind of the symbol the chunk was extracted around, e.g. `function`, set by symbol search.
+    pub symbol_type: Option<String>,
+    // score of the path the chunk was extracted from, set by symbol search.
+    pub score: Option<f32>,
+    // which of the vector and keyword searches found the chunk, set by symbol search.
+    pub source: Option<RetrievalSource>,
+    // doc comments of the definitions in the snippet, shown above it in the answer context.
+    pub doc: Option<String>,
+    // paths of near-identical chunks collapsed into this one, set by symbol search.
+    pub duplicates: Vec<String>,
+    // true when the requested span was clamped to the current length of the file.
+    pub adjusted: bool,
+    // the path is test or vendored code, see `crate::path_class`.
+    pub is_test: bool,
+    pub is_vendored: bool,
+    // true when symbol search lowered the score of the chunk because of the flags above.
+    pub demoted: bool,
+    // true when symbol search moved the chunk after the chunks of the other paths because its
+    // path already had the most chunks a path can have in the results.
+    pub overflow: bool,
+    // the language written inside the snippet, e.g. `sql` for a query in a string, see
+    // `crate::embedded_lang`.
+    pub embedded_lang: Option<String>,
+}
+
+impl CodeChunk {
+    /// Returns true if a code-chunk contains an empty snippet or a snippet with only whitespace
+    pub fn is_empty(&self) -> bool {
+        self.snippet.trim().is_empty()
+    }
+}
+
+// The rendering of the chunk in the prompts of the agent, `alias: path` over the snippet. Changing
+// it changes every prompt with code in it.
+impl fmt::Display for CodeChunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.alias {
+            Some(alias) => write!(f, "{}: {}\n{}", alias, self.path, self.snippet),
+            None => write!(f, "{}\n{}", self.path, self.snippet),
+        }
+    }
+}
+
+/// The wire representation of `CodeChunk`, the field names are the ones the services sent
+/// before it was versioned.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct CodeChunkWire {
+    #[serde(default = "unversioned")]
+    version: u32,
+    path: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    repo: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<usize>,
+    snippet: String,
+    #[serde(rename = "start")]
+    start_line: usize,
+    #[serde(rename = "end")]
+    end_line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    byte_range: Option<Range<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symbol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symbol_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<RetrievalSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    doc: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    duplicates: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    adjusted: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_test: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_vendored: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    demoted: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    overflow: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    embedded_lang: Option<String>,
+}
+
+fn unversioned() -> u32 {
+    UNVERSIONED_WIRE_VERSION
+}
+
+impl From<CodeChunk> for CodeChunkWire {
+    fn from(chunk: CodeChunk) -> Self {
+        Self {
+            version: CODE_CHUNK_WIRE_VERSION,
+            path: chunk.path,
+            repo: chunk.repo,
+            alias: chunk.alias,
+            snippet: chunk.snippet,
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            byte_range: chunk.byte_range,
+            symbol: chunk.symbol,
+            symbol_type: chunk.symbol_type,
+            score: chunk.score,
+            source: chunk.source,
+            doc: chunk.doc,
+            duplicates: chunk.duplicates,
+            adjusted: chunk.adjusted,
+            is_test: chunk.is_test,
+            is_vendored: chunk.is_vendored,
+            demoted: chunk.demoted,
+            overflow: chunk.overflow,
+            embedded_lang: chunk.embedded_lang,
+        }
+    }
+}
+
+impl TryFrom<CodeChunkWire> for CodeChunk {
+    type Error = String;
+
+    // a newer version may have changed what the fields mean, it isn't read as this one.
+    fn try_from(wire: CodeChunkWire) -> Result<Self, Self::Error> {
+        if wire.version > CODE_CHUNK_WIRE_VERSION {
+            return Err(format!(
+                "code chunk of wire version {}, this build reads up to {}",
+                wire.version, CODE_CHUNK_WIRE_VERSION
+            ));
+        }
+        Ok(Self {
+            path: wire.path,
+            repo: wire.repo,
+            alias: wire.alias,
+            snippet: wire.snippet,
+            start_line: wire.start_line,
+            end_line: wire.end_line,
+            byte_range: wire.byte_range,
+            symbol: wire.symbol,
+            symbol_type: wire.symbol_type,
+            score: wire.score,
+            source: wire.source,
+            doc: wire.doc,
+            duplicates: wire.duplicates,
+            adjusted: wire.adjusted,
+            is_test: wire.is_test,
+            is_vendored: wire.is_vendored,
+            demoted: wire.demoted,
+            overflow: wire.overflow,
+            embedded_lang: wire.embedded_lang,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk() -> CodeChunk {
+        CodeChunk {
+            path: "src/billing/retry.rs".to_string(),
+            repo: "acme/api".to_string(),
+            alias: Some(3),
+            snippet: "fn retry() {\n    charge();\n}".to_string(),
+            start_line: 10,
+            end_line: 12,
+            byte_range: Some(120..160),
+            symbol: Some("retry".to_string()),
+            symbol_type: Some("function".to_string()),
+            score: Some(0.75),
+            source: Some(RetrievalSource::Both),
+            doc: Some("Retries the charge.".to_string()),
+            duplicates: vec!["vendor/billing/retry.rs".to_string()],
+            adjusted: true,
+            is_test: false,
+            is_vendored: false,
+            demoted: true,
+            overflow: true,
+            embedded_lang: None,
+        }
+    }
+
+    #[test]
+    fn test_chunk_round_trips_through_the_wire() {
+        let chunk = chunk();
+        let json = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(json["version"], CODE_CHUNK_WIRE_VERSION);
+        assert_eq!(json["start"], 10);
+        assert_eq!(json["end"], 12);
+        assert_eq!(serde_json::from_value::<CodeChunk>(json).unwrap(), chunk);
+
+        let bare = CodeChunk {
+            path: "src/main.rs".to_string(),
+            snippet: "fn main() {}".to_string(),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&bare).unwrap();
+        // the unset fields are left out.
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": CODE_CHUNK_WIRE_VERSION,
+                "path": "src/main.rs",
+                "snippet": "fn main() {}",
+                "start": 0,
+                "end": 0,
+            })
+        );
+        assert_eq!(serde_json::from_value::<CodeChunk>(json).unwrap(), bare);
+    }
+
+    #[test]
+    fn test_unversioned_chunks_are_read() {
+        // a chunk of code search before the representation was versioned.
+        let chunk: CodeChunk = serde_json::from_value(serde_json::json!({
+            "path": "src/main.rs",
+            "snippet": "fn main() {}",
+            "start": 1,
+            "end": 1,
+            "score": 0.5,
+            "adjusted": true
+        }))
+        .unwrap();
+        assert_eq!((chunk.start_line, chunk.end_line), (1, 1));
+        assert_eq!(chunk.score, Some(0.5));
+        assert!(chunk.adjusted);
+
+        // an exchange saved to redis by code understanding, with its alias.
+        let chunk: CodeChunk = serde_json::from_value(serde_json::json!({
+            "path": "src/main.rs",
+            "alias": 0,
+            "snippet": "fn main() {}",
+            "start": 1,
+            "end": 1
+        }))
+        .unwrap();
+        assert_eq!(chunk.alias, Some(0));
+
+        let newer = serde_json::json!({
+            "version": CODE_CHUNK_WIRE_VERSION + 1,
+            "path": "src/main.rs",
+            "snippet": "",
+            "start": 1,
+            "end": 1
+        });
+        assert!(serde_json::from_value::<CodeChunk>(newer).is_err());
+    }
+
+    #[test]
+    fn test_prompt_rendering_is_unchanged() {
+        // the rendering of the chunks of the exchanges the prompts were built from.
+        assert_eq!(
+            chunk().to_string(),
+            "3: src/billing/retry.rs\nfn retry() {\n    charge();\n}"
+        );
+        let chunk = CodeChunk {
+            alias: None,
+            ..chunk()
+        };
+        assert_eq!(
+            chunk.to_string(),
+            "src/billing/retry.rs\nfn retry() {\n    charge();\n}"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// CODEOWNERS files in the GitHub and GitLab syntax: gitignore like patterns followed by owners,
+// the later rules win. GitLab sections (`[Section] @default-owner`) each resolve on their own.
+
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Where the file is looked for, the first one found is used.
+pub const CODEOWNERS_LOCATIONS: [&str; 4] = [
+    ".github/CODEOWNERS",
+    "CODEOWNERS",
+    "docs/CODEOWNERS",
+    ".gitlab/CODEOWNERS",
+];
+
+/// Language the CODEOWNERS file is indexed with, it isn't parsed like source code.
+pub const CODEOWNERS_LANGUAGE: &str = "CODEOWNERS";
+
+pub fn is_codeowners_path(path: &str) -> bool {
+    CODEOWNERS_LOCATIONS.contains(&path)
+}
+
+/// A pattern and the owners of the paths it matches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OwnerRule {
+    pub pattern: String,
+    pub owners: Vec<String>,
+    // GitLab section the rule belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    // 1-based line of the rule in the file.
+    pub line: usize,
+}
+
+impl OwnerRule {
+    // an invalid pattern matches nothing.
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_start_matches('/');
+        pattern_regex(&self.pattern)
+            .map(|regex| regex.is_match(path).unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+/// Owners of a path, as returned by code search. Empty when the repo has no CODEOWNERS file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PathOwners {
+    pub path: String,
+    pub owners: Vec<String>,
+    // the rules the owners come from, in the order of the file.
+    pub rules: Vec<OwnerRule>,
+    // the CODEOWNERS file the rules were read from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// The parsed rules of a CODEOWNERS file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CodeOwners {
+    pub rules: Vec<OwnerRule>,
+}
+
+impl CodeOwners {
+    pub fn parse(content: &str) -> Self {
+        let mut rules = Vec::new();
+        let mut section: Option<String> = None;
+        let mut section_owners: Vec<String> = Vec::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // `[Section]`, `^[Optional section]` or `[Section][2]`, followed by the default owners.
+            if let Some(header) = line.strip_prefix('^').unwrap_or(line).strip_prefix('[') {
+                if let Some((name, rest)) = header.split_once(']') {
+                    let rest = rest.trim_start();
+                    // the number of required approvals isn't kept.
+                    let rest = match rest.strip_prefix('[') {
+                        Some(approvals) => approvals.split_once(']').map_or("", |(_, rest)| rest),
+                        None => rest,
+                    };
+                    section = Some(name.trim().to_string());
+                    section_owners = owners_of(rest);
+                    continue;
+                }
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let pattern = parts.next().unwrap_or_default().replace("\\ ", " ");
+            let mut owners = owners_of(parts.next().unwrap_or_default());
+            if owners.is_empty() {
+                owners = section_owners.clone();
+            }
+            rules.push(OwnerRule {
+                pattern,
+                owners,
+                section: section.clone(),
+                line: index + 1,
+            });
+        }
+
+        Self { rules }
+    }
+
+    /// The rules deciding the owners of `path`, one per section, the later rule of a section wins.
+    /// A matching rule without owners leaves the path unowned in its section.
+    pub fn matching_rules(&self, path: &str) -> Vec<&OwnerRule> {
+        let mut matched: Vec<&OwnerRule> = Vec::new();
+        for rule in self.rules.iter().rev().filter(|rule| rule.matches(path)) {
+            if !matched.iter().any(|other| other.section == rule.section) {
+                matched.push(rule);
+            }
+        }
+        matched.sort_by_key(|rule| rule.line);
+        matched
+    }
+
+    pub fn resolve(&self, path: &str, source: &str) -> PathOwners {
+        PathOwners {
+            path: path.to_string(),
+            owners: self.owners_of(path),
+            rules: self.matching_rules(path).into_iter().cloned().collect(),
+            source: Some(source.to_string()),
+        }
+    }
+
+    /// Owners of `path` over every section, empty when no rule matches.
+    pub fn owners_of(&self, path: &str) -> Vec<String> {
+        let mut owners: Vec<String> = Vec::new();
+        for owner in self
+            .matching_rules(path)
+            .into_iter()
+            .flat_map(|rule| rule.owners.iter())
+        {
+            if !owners.contains(owner) {
+                owners.push(owner.clone());
+            }
+        }
+        owners
+    }
+}
+
+// owners are `@user`, `@org/team` or an email, anything after a `#` is a comment.
+fn owners_of(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .take_while(|owner| !owner.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+// Translates a gitignore like pattern to a regex on the repo relative path.
+// Patterns with a slash before their end are relative to the root, the others match at any depth.
+// A pattern matching a directory matches everything in it, unless it ends with a single `*`.
+fn pattern_regex(pattern: &str) -> Option<Regex> {
+    let directory = pattern.ends_with('/');
+    let trimmed = pattern.trim_end_matches('/');
+    let anchored = trimmed.starts_with('/') || trimmed.contains('/');
+    let trimmed = trimmed.trim_start_matches('/');
+    if trimmed.is_empty() {
+        // `/` alone owns the whole repo.
+        return Regex::new("^.*$").ok();
+    }
+
+    let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let chars = trimmed.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') {
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => {
+                if "\\.+()|[]{}^$".contains(c) {
+                    regex.push('\\');
+                }
+                regex.push(c);
+            }
+        }
+        i += 1;
+    }
+
+    let last_segment = trimmed.rsplit('/').next().unwrap_or_default();
+    if last_segment == "*" && !directory {
+        regex.push('$');
+    } else {
+        regex.push_str("(?:/.*)?$");
+    }
+    Regex::new(&regex).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODEOWNERS: &str = r#"
+# everything falls back to the platform team
+*                       @acme/platform
+*.js                    @acme/frontend
+/docs/                  @acme/docs
+apps/                   @octo
+/build/logs/*           @ops
+**/payments/**          @acme/payments billing@acme.com
+/services/payments/legacy/
+"#;
+
+    #[test]
+    fn test_later_rules_win() {
+        let owners = CodeOwners::parse(CODEOWNERS);
+
+        assert_eq!(owners.owners_of("src/lib.rs"), vec!["@acme/platform"]);
+        assert_eq!(owners.owners_of("web/app.js"), vec!["@acme/frontend"]);
+        assert_eq!(
+            owners.owners_of("services/payments/charge.rs"),
+            vec!["@acme/payments", "billing@acme.com"]
+        );
+        // the last matching rule has no owners, so the path has none.
+        assert!(owners.owners_of("services/payments/legacy/refund.rs").is_empty());
+        assert_eq!(owners.matching_rules("web/app.js")[0].line, 4);
+    }
+
+    #[test]
+    fn test_directory_and_glob_patterns() {
+        let owners = CodeOwners::parse(CODEOWNERS);
+
+        // anchored directory, everything under it.
+        assert_eq!(owners.owners_of("docs/guide/intro.md"), vec!["@acme/docs"]);
+        assert_eq!(owners.owners_of("src/docs/intro.md"), vec!["@acme/platform"]);
+        // unanchored directory, at any depth.
+        assert_eq!(owners.owners_of("apps/web/main.rs"), vec!["@octo"]);
+        assert_eq!(owners.owners_of("src/apps/main.rs"), vec!["@octo"]);
+        // a single `*` doesn't go into subdirectories.
+        assert_eq!(owners.owners_of("build/logs/today.txt"), vec!["@ops"]);
+        assert_eq!(owners.owners_of("build/logs/old/today.txt"), vec!["@acme/platform"]);
+    }
+
+    #[test]
+    fn test_paths_without_a_match() {
+        let owners = CodeOwners::parse("/src/ @core\n*.py @python\n");
+
+        assert!(owners.owners_of("README.md").is_empty());
+        assert!(owners.matching_rules("lib/main.go").is_empty());
+        assert!(CodeOwners::parse("").owners_of("src/main.rs").is_empty());
+    }
+
+    #[test]
+    fn test_gitlab_sections_resolve_separately() {
+        let owners = CodeOwners::parse(
+            "[Backend] @backend\nsrc/\nsrc/api/ @api\n\n^[Docs][2] @docs\n*.md\n",
+        );
+
+        assert_eq!(owners.owners_of("src/api/README.md"), vec!["@api", "@docs"]);
+        assert_eq!(owners.owners_of("src/main.rs"), vec!["@backend"]);
+        assert_eq!(owners.rules[0].section.as_deref(), Some("Backend"));
+    }
+}
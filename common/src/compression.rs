@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Payload field holding the plain chunk text.
+pub const TEXT_FIELD: &str = "snippet";
+/// Payload field holding the zstd compressed, base64 encoded chunk text.
+pub const COMPRESSED_TEXT_FIELD: &str = "text_z";
+/// Marker naming the compression of `COMPRESSED_TEXT_FIELD`, missing on uncompressed payloads.
+pub const COMPRESSION_FIELD: &str = "compression";
+pub const ZSTD: &str = "zstd";
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// How the chunk text is written to the qdrant payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextCompression {
+    /// Plain text in `snippet`, for readers that don't know about compression yet.
+    None,
+    /// zstd at the given level in `text_z`.
+    Zstd(i32),
+}
+
+impl Default for TextCompression {
+    fn default() -> Self {
+        TextCompression::Zstd(DEFAULT_ZSTD_LEVEL)
+    }
+}
+
+/// Parses `none`, `zstd` or `zstd:<level>`.
+impl FromStr for TextCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(TextCompression::None),
+            ZSTD => Ok(TextCompression::default()),
+            value => {
+                let level = value
+                    .strip_prefix("zstd:")
+                    .and_then(|level| level.parse::<i32>().ok())
+                    .filter(|level| zstd::compression_level_range().contains(level))
+                    .ok_or_else(|| {
+                        anyhow!("Unknown compression {}, expected none, zstd or zstd:<level>", value)
+                    })?;
+                Ok(TextCompression::Zstd(level))
+            }
+        }
+    }
+}
+
+impl TextCompression {
+    /// The payload fields holding `text`, either `snippet` or `text_z` along with the marker.
+    pub fn text_fields(self, text: &str) -> Result<Vec<(&'static str, String)>> {
+        match self {
+            TextCompression::None => Ok(vec![(TEXT_FIELD, text.to_string())]),
+            TextCompression::Zstd(level) => Ok(vec![
+                (COMPRESSED_TEXT_FIELD, compress_text(text, level)?),
+                (COMPRESSION_FIELD, ZSTD.to_string()),
+            ]),
+        }
+    }
+}
+
+pub fn compress_text(text: &str, level: i32) -> Result<String> {
+    let compressed = zstd::encode_all(text.as_bytes(), level)?;
+    Ok(STANDARD.encode(compressed))
+}
+
+pub fn decompress_text(encoded: &str) -> Result<String> {
+    let compressed = STANDARD.decode(encoded)?;
+    let text = zstd::decode_all(compressed.as_slice())?;
+    Ok(String::from_utf8(text)?)
+}
+
+/// Reads the chunk text from the string fields of a payload, compressed or not.
+pub fn chunk_text(
+    compression: Option<&str>,
+    compressed: Option<&str>,
+    plain: Option<&str>,
+) -> Result<String> {
+    match (compression, compressed, plain) {
+        (Some(ZSTD), Some(compressed), _) => decompress_text(compressed),
+        (Some(ZSTD), None, _) => Err(anyhow!(
+            "Payload is marked as zstd but has no {}",
+            COMPRESSED_TEXT_FIELD
+        )),
+        (Some(other), _, _) => Err(anyhow!("Unknown payload compression {}", other)),
+        (None, _, Some(plain)) => Ok(plain.to_string()),
+        (None, _, None) => Err(anyhow!("Payload has no {}", TEXT_FIELD)),
+    }
+}
+
+/// Removes the text fields from a payload decoded to json and returns the chunk text.
+pub fn take_chunk_text(payload: &mut HashMap<String, serde_json::Value>) -> Result<String> {
+    let mut take = |field: &str| match payload.remove(field) {
+        Some(serde_json::Value::String(value)) => Some(value),
+        _ => None,
+    };
+    let (compression, compressed, plain) = (
+        take(COMPRESSION_FIELD),
+        take(COMPRESSED_TEXT_FIELD),
+        take(TEXT_FIELD),
+    );
+    chunk_text(compression.as_deref(), compressed.as_deref(), plain.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a representative source file of the repo.
+    const FIXTURE: &str = include_str!("task_graph/state.rs");
+
+    fn json_payload(fields: Vec<(&'static str, String)>) -> HashMap<String, serde_json::Value> {
+        fields
+            .into_iter()
+            .map(|(field, value)| (field.to_string(), serde_json::Value::String(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for text in ["", "fn main() {}\n", "ünïcödé ✓\n", FIXTURE] {
+            let compressed = compress_text(text, DEFAULT_ZSTD_LEVEL).unwrap();
+            assert_eq!(decompress_text(&compressed).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_compressed_and_plain_payloads_read_the_same() {
+        let text = "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n";
+        let compressions = [
+            TextCompression::None,
+            TextCompression::Zstd(1),
+            TextCompression::default(),
+        ];
+        for compression in compressions {
+            let mut payload = json_payload(compression.text_fields(text).unwrap());
+            payload.insert("relative_path".to_string(), "src/lib.rs".into());
+            assert_eq!(take_chunk_text(&mut payload).unwrap(), text);
+            // only the text fields are taken out of the payload.
+            assert_eq!(payload.keys().collect::<Vec<_>>(), vec!["relative_path"]);
+        }
+
+        let mut unknown = json_payload(vec![(COMPRESSION_FIELD, "lz4".to_string())]);
+        assert!(take_chunk_text(&mut unknown).is_err());
+    }
+
+    #[test]
+    fn test_compression_saves_space_on_source_files() {
+        let compressed = compress_text(FIXTURE, DEFAULT_ZSTD_LEVEL).unwrap();
+        // base64 adds a third on top of zstd, source code still shrinks to well under half.
+        assert!(
+            compressed.len() * 2 < FIXTURE.len(),
+            "{} bytes compressed to {}",
+            FIXTURE.len(),
+            compressed.len()
+        );
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!("none".parse::<TextCompression>().unwrap(), TextCompression::None);
+        assert_eq!("ZSTD".parse::<TextCompression>().unwrap(), TextCompression::default());
+        assert_eq!("zstd:19".parse::<TextCompression>().unwrap(), TextCompression::Zstd(19));
+        assert!("zstd:fast".parse::<TextCompression>().is_err());
+        assert!("gzip".parse::<TextCompression>().is_err());
+    }
+}
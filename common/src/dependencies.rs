@@ -0,0 +1,303 @@
+// Checks of the services and stores a service needs before it can serve.
+//
+// The checks run concurrently and each is retried on its own, so a service waits as long as its
+// slowest dependency rather than the sum of them, and reports every dependency it can't reach at
+// once instead of stopping at the first.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use log::{debug, info, warn};
+
+use crate::reconnect::{Readiness, ReadinessCheck};
+
+type CheckFuture = Pin<Box<dyn Future<Output = ReadinessCheck> + Send>>;
+type CheckFn = Box<dyn Fn() -> CheckFuture + Send + Sync>;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Dependency {
+    name: String,
+    check: CheckFn,
+}
+
+/// Runs the checks of the dependencies of a service concurrently, for the startup of the service
+/// and its `/ready` route.
+pub struct DependencyChecker {
+    dependencies: Vec<Dependency>,
+    // tries of every check, the first one included.
+    attempts: usize,
+    // wait between two tries of the same check.
+    retry_delay: Duration,
+    // a try that takes longer fails.
+    timeout: Duration,
+}
+
+impl Default for DependencyChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DependencyChecker {
+    /// Tries every check once with a 10 seconds timeout, what the `/ready` routes need.
+    pub fn new() -> Self {
+        Self {
+            dependencies: Vec::new(),
+            attempts: 1,
+            retry_delay: Duration::ZERO,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn retry(mut self, attempts: usize, delay: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Adds a check that passes when `check` returns `Ok`.
+    pub fn check<F, Fut>(self, name: &str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.check_readiness(name, move || {
+            let result = check();
+            async move {
+                let result = result.await;
+                ReadinessCheck {
+                    name: String::new(),
+                    ok: result.is_ok(),
+                    degraded: false,
+                    error: result.err().map(|e| format!("{:#}", e)),
+                }
+            }
+        })
+    }
+
+    /// Adds a check that reports its own readiness, e.g. the `check` of a reconnecting client
+    /// which keeps its `degraded` flag.
+    pub fn check_readiness<F, Fut>(mut self, name: &str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ReadinessCheck> + Send + 'static,
+    {
+        let owned = name.to_string();
+        self.dependencies.push(Dependency {
+            name: name.to_string(),
+            check: Box::new(move || {
+                let name = owned.clone();
+                let check = check();
+                Box::pin(async move {
+                    ReadinessCheck {
+                        name,
+                        ..check.await
+                    }
+                })
+            }),
+        });
+        self
+    }
+
+    /// Adds a check that passes when `url` answers a GET with a success status.
+    pub fn http(self, name: &str, url: &str) -> Self {
+        let url = url.to_string();
+        self.check(name, move || {
+            let url = url.clone();
+            async move {
+                let response = reqwest::get(&url).await?;
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(anyhow!("{} answered {}", url, response.status()))
+                }
+            }
+        })
+    }
+
+    /// Runs every check concurrently, in the order they were added.
+    pub async fn run(&self) -> Vec<ReadinessCheck> {
+        join_all(self.dependencies.iter().map(|dependency| self.run_one(dependency))).await
+    }
+
+    pub async fn readiness(&self) -> Readiness {
+        Readiness::new(self.run().await)
+    }
+
+    /// Waits for every dependency, the error lists each one that couldn't be reached with its
+    /// last error.
+    pub async fn wait_until_ready(&self) -> Result<(), UnavailableDependencies> {
+        let failures = self
+            .run()
+            .await
+            .into_iter()
+            .filter(|check| !check.ok)
+            .collect::<Vec<_>>();
+        if failures.is_empty() {
+            info!("All {} dependencies are up", self.dependencies.len());
+            Ok(())
+        } else {
+            Err(UnavailableDependencies { failures })
+        }
+    }
+
+    // Tries the check until it passes or runs out of tries, the last failure is returned.
+    async fn run_one(&self, dependency: &Dependency) -> ReadinessCheck {
+        let mut last = None;
+        for attempt in 1..=self.attempts {
+            if attempt > 1 {
+                tokio::time::sleep(self.retry_delay).await;
+            }
+            debug!("Attempt {} to check {}", attempt, dependency.name);
+            let check = tokio::time::timeout(self.timeout, (dependency.check)())
+                .await
+                .unwrap_or_else(|_| ReadinessCheck {
+                    name: dependency.name.clone(),
+                    ok: false,
+                    degraded: false,
+                    error: Some(format!("no answer within {:?}", self.timeout)),
+                });
+            if check.ok {
+                return check;
+            }
+            warn!(
+                "{} is not available, attempt {}/{}: {}",
+                dependency.name,
+                attempt,
+                self.attempts,
+                check.error.as_deref().unwrap_or("check failed")
+            );
+            last = Some(check);
+        }
+        last.expect("every check is tried at least once")
+    }
+}
+
+/// The dependencies a service couldn't reach, with the last error of each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnavailableDependencies {
+    pub failures: Vec<ReadinessCheck>,
+}
+
+impl fmt::Display for UnavailableDependencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} dependencies are unavailable:", self.failures.len())?;
+        for failure in &self.failures {
+            write!(
+                f,
+                "\n  - {}: {}",
+                failure.name,
+                failure.error.as_deref().unwrap_or("check failed")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnavailableDependencies {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Instant;
+    use warp::Filter;
+
+    // A server whose `/health` answers after `delay` with `status`.
+    fn slow_endpoint(delay: Duration, status: u16) -> SocketAddr {
+        let health = warp::path("health").and_then(move || async move {
+            tokio::time::sleep(delay).await;
+            let status = warp::http::StatusCode::from_u16(status).unwrap();
+            Ok::<_, std::convert::Infallible>(warp::reply::with_status("health", status))
+        });
+        let (addr, server) = warp::serve(health).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_checks_run_concurrently() {
+        let mut checker = DependencyChecker::new();
+        for (i, delay) in [300, 400, 500].into_iter().enumerate() {
+            let addr = slow_endpoint(Duration::from_millis(delay), 200);
+            checker = checker.http(&format!("service-{}", i), &format!("http://{}/health", addr));
+        }
+
+        let started = Instant::now();
+        let readiness = checker.readiness().await;
+        let elapsed = started.elapsed();
+
+        assert!(readiness.ready);
+        assert_eq!(
+            readiness.checks.iter().map(|check| check.name.as_str()).collect::<Vec<_>>(),
+            vec!["service-0", "service-1", "service-2"]
+        );
+        // the slowest endpoint, not the 1.2s of the three one after another.
+        assert!(elapsed >= Duration::from_millis(500));
+        assert!(elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_are_concurrent_and_every_failure_is_reported() {
+        let checker = DependencyChecker::new()
+            .timeout(Duration::from_millis(200))
+            .http(
+                "search",
+                &format!("http://{}/health", slow_endpoint(Duration::from_secs(5), 200)),
+            )
+            .http(
+                "understanding",
+                &format!("http://{}/health", slow_endpoint(Duration::from_secs(5), 200)),
+            )
+            .http(
+                "gateway",
+                &format!("http://{}/health", slow_endpoint(Duration::ZERO, 500)),
+            )
+            .check("redis", || async { Ok(()) });
+
+        let started = Instant::now();
+        let err = checker.wait_until_ready().await.unwrap_err();
+        let elapsed = started.elapsed();
+
+        assert!(elapsed < Duration::from_millis(500), "took {:?}", elapsed);
+        assert_eq!(
+            err.failures.iter().map(|check| check.name.as_str()).collect::<Vec<_>>(),
+            vec!["search", "understanding", "gateway"]
+        );
+        let report = err.to_string();
+        assert!(report.starts_with("3 dependencies are unavailable:"));
+        assert!(report.contains("- search: no answer within 200ms"));
+        assert!(report.contains("answered 500 Internal Server Error"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_check_is_retried() {
+        let tries = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = tries.clone();
+        let checker = DependencyChecker::new()
+            .retry(3, Duration::from_millis(10))
+            .check("flaky", move || {
+                let tries = counted.clone();
+                async move {
+                    if tries.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 2 {
+                        Err(anyhow!("connection refused"))
+                    } else {
+                        Ok(())
+                    }
+                }
+            });
+
+        assert!(checker.wait_until_ready().await.is_ok());
+        assert_eq!(tries.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+}
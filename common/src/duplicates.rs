@@ -0,0 +1,37 @@
+// Conversations started on the issue of a recent conversation of the same tenant and repo. The
+// coordinator compares the embedding of the issue of a new conversation with the ones of the
+// recent conversations and offers the most similar one instead of a new run. A conversation the
+// user starts anyway keeps a reference to the one it repeats, for analytics.
+
+use serde::{Deserialize, Serialize};
+
+/// The recent conversation a conversation repeats, attached to the root of the new one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateRef {
+    pub conversation_id: String,
+    // cosine similarity of the two issue descriptions.
+    pub similarity: f32,
+}
+
+impl DuplicateRef {
+    pub fn render(&self) -> String {
+        format!(
+            "duplicate of {} (similarity {:.2})",
+            self.conversation_id, self.similarity
+        )
+    }
+}
+
+/// Cosine similarity of two embeddings, 0 when they differ in length or either is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+    dot / norms
+}
@@ -0,0 +1,513 @@
+// Files often hold a second language the chunking doesn't see: SQL queries in string literals,
+// fenced code in markdown, template blocks in HTML. Ingestion tags a chunk holding one with the
+// `embedded_lang` field and embeds the name of the language with its text, code search filters and
+// boosts on the field, and the answers name the language of the code they cite.
+//
+// The detection is conservative, a chunk is rather left untagged than tagged with a language it
+// doesn't hold: a fence needs a language tag, a string needs to read like a whole statement and a
+// template needs the block tags of a template language.
+
+/// The payload field of the chunks holding an embedded language.
+pub const EMBEDDED_LANG_FIELD: &str = "embedded_lang";
+
+/// Shortest string literal read as a SQL query, shorter ones are names and messages.
+pub const MIN_SQL_LITERAL_LEN: usize = 24;
+
+const MARKDOWN_LANGS: &[&str] = &["markdown", "md", "mdx"];
+const HTML_LANGS: &[&str] = &["html", "htm", "xhtml"];
+// template languages writing HTML, their chunks are tagged with the HTML they hold.
+const TEMPLATE_LANGS: &[&str] = &[
+    "jinja",
+    "jinja2",
+    "django",
+    "htmldjango",
+    "twig",
+    "liquid",
+    "nunjucks",
+    "handlebars",
+];
+// tags of fences that aren't a language.
+const PLAIN_FENCE_TAGS: &[&str] = &["text", "txt", "plain", "plaintext", "none", "output", "log"];
+// spellings of the fence tags and the language they are tagged with.
+const FENCE_ALIASES: &[(&str, &str)] = &[
+    ("js", "javascript"),
+    ("ts", "typescript"),
+    ("py", "python"),
+    ("rs", "rust"),
+    ("rb", "ruby"),
+    ("golang", "go"),
+    ("sh", "bash"),
+    ("shell", "bash"),
+    ("zsh", "bash"),
+    ("yml", "yaml"),
+    ("postgres", "sql"),
+    ("postgresql", "sql"),
+    ("psql", "sql"),
+    ("mysql", "sql"),
+    ("sqlite", "sql"),
+];
+// block tags of jinja like templates, `{% for order in orders %}`.
+const TEMPLATE_TAGS: &[&str] = &[
+    "block", "endblock", "extends", "include", "import", "for", "endfor", "if", "elif", "else",
+    "endif", "macro", "endmacro", "set", "with", "endwith", "raw", "endraw",
+];
+// block helpers of handlebars, `{{#each orders}}`.
+const HANDLEBARS_HELPERS: &[&str] = &["each", "if", "unless", "with"];
+const HTML_ELEMENTS: &[&str] = &[
+    "html", "head", "body", "div", "span", "p", "a", "ul", "ol", "li", "table", "thead", "tbody",
+    "tr", "td", "th", "form", "input", "button", "label", "select", "option", "textarea",
+    "section", "header", "footer", "nav", "main", "img", "h1", "h2", "h3", "h4", "h5", "h6",
+    "script", "style", "link", "meta",
+];
+// words of sentences that don't occur in a query.
+const PROSE_WORDS: &[&str] = &["the", "you", "your", "please", "we", "our"];
+// words of a lowercase query that a sentence starting like one doesn't have.
+const SQL_CLAUSES: &[&str] = &[
+    "where",
+    "join",
+    "values",
+    "limit",
+    "returning",
+    "group",
+    "order",
+    "having",
+];
+// words of a question naming an embedded language.
+const LANG_WORDS: &[(&str, &str)] = &[
+    ("sql", "sql"),
+    ("query", "sql"),
+    ("queries", "sql"),
+    ("html", "html"),
+    ("jinja", "jinja"),
+    ("template", "jinja"),
+    ("templates", "jinja"),
+    ("handlebars", "handlebars"),
+    ("bash", "bash"),
+    ("shell", "bash"),
+];
+const DISPLAY_NAMES: &[(&str, &str)] = &[
+    ("sql", "SQL"),
+    ("html", "HTML"),
+    ("jinja", "Jinja"),
+    ("handlebars", "Handlebars"),
+    ("bash", "Bash"),
+    ("javascript", "JavaScript"),
+    ("typescript", "TypeScript"),
+    ("python", "Python"),
+    ("rust", "Rust"),
+    ("go", "Go"),
+    ("ruby", "Ruby"),
+    ("yaml", "YAML"),
+    ("json", "JSON"),
+    ("toml", "TOML"),
+];
+
+/// The language embedded in a chunk of a file in `lang`, lowercased, e.g. `sql` for a query in a
+/// Rust string: the fenced blocks of markdown, the HTML of a template, the template blocks of
+/// HTML and the SQL string literals of code.
+pub fn embedded_lang(text: &str, lang: &str) -> Option<String> {
+    let lang = lang.to_ascii_lowercase();
+    if MARKDOWN_LANGS.contains(&lang.as_str()) {
+        fenced_lang(text)
+    } else if HTML_LANGS.contains(&lang.as_str()) {
+        template_lang(text).map(str::to_string)
+    } else if TEMPLATE_LANGS.contains(&lang.as_str()) {
+        (html_tag_count(text) >= 2).then(|| "html".to_string())
+    } else if lang != "sql" {
+        string_literals(text)
+            .into_iter()
+            .any(is_sql)
+            .then(|| "sql".to_string())
+    } else {
+        None
+    }
+}
+
+/// The text embedded for a chunk, the name of its embedded language first so a query naming the
+/// language matches it.
+pub fn embedding_text(text: &str, embedded_lang: Option<&str>) -> String {
+    match embedded_lang {
+        Some(lang) => format!("{}\n{}", display_name(lang), text),
+        None => text.to_string(),
+    }
+}
+
+/// e.g. `SQL` for `sql`, the tag itself for the languages without a known name.
+pub fn display_name(lang: &str) -> String {
+    DISPLAY_NAMES
+        .iter()
+        .find(|(tag, _)| tag.eq_ignore_ascii_case(lang))
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| lang.to_string())
+}
+
+/// The embedded language a query asks about, e.g. `sql` for "where is the orders query defined".
+pub fn mentioned_embedded_lang(query: &str) -> Option<&'static str> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .find_map(|word| {
+            LANG_WORDS
+                .iter()
+                .find(|(lang_word, _)| lang_word.eq_ignore_ascii_case(word))
+        })
+        .map(|(_, lang)| *lang)
+}
+
+// The language of the fenced blocks of a markdown chunk, the one with the most lines in the chunk
+// when they differ. Blocks without a tag, and blocks opened before the chunk, aren't tagged.
+fn fenced_lang(text: &str) -> Option<String> {
+    // (fence, tag) of the block the line is in.
+    let mut open: Option<(String, Option<String>)> = None;
+    let mut lines_by_lang: Vec<(String, usize)> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim_start();
+        let fence = fence_of(line);
+        match (&open, fence) {
+            (None, Some((fence, tag))) => open = Some((fence, tag)),
+            (None, None) => {}
+            // a chunk starting in a block sees its closing fence first, a fence with a tag opens
+            // the block that follows.
+            (Some((_, None)), Some((fence, Some(tag)))) => open = Some((fence, Some(tag))),
+            (Some((fence, _)), _)
+                if line.starts_with(fence.as_str()) && line[fence.len()..].trim().is_empty() =>
+            {
+                open = None
+            }
+            (Some((_, Some(tag))), _) => {
+                match lines_by_lang.iter_mut().find(|(lang, _)| lang == tag) {
+                    Some((_, lines)) => *lines += 1,
+                    None => lines_by_lang.push((tag.clone(), 1)),
+                }
+            }
+            (Some((_, None)), _) => {}
+        }
+    }
+    lines_by_lang
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, lines)| *lines)
+        .map(|(lang, _)| lang)
+}
+
+// The fence a line opens a block with and the language of its tag, e.g. ("```", Some("sql")).
+fn fence_of(line: &str) -> Option<(String, Option<String>)> {
+    let marker = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let fence_len = line.chars().take_while(|c| *c == marker).count();
+    if fence_len < 3 {
+        return None;
+    }
+    let fence = line[..fence_len].to_string();
+    let tag = line[fence_len..]
+        .split_whitespace()
+        .next()
+        .map(|tag| {
+            tag.trim_matches(|c| c == '{' || c == '}' || c == '.')
+                .to_ascii_lowercase()
+        })
+        .filter(|tag| {
+            !tag.is_empty()
+                && tag
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '#' | '-' | '_'))
+                && !PLAIN_FENCE_TAGS.contains(&tag.as_str())
+        })
+        .map(|tag| {
+            FENCE_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == tag)
+                .map_or(tag.clone(), |(_, lang)| lang.to_string())
+        });
+    Some((fence, tag))
+}
+
+// `jinja` for the block tags of jinja like templates, `handlebars` for its block helpers. The
+// `{{ value }}` interpolations alone aren't enough, Vue and Angular templates have them too.
+fn template_lang(text: &str) -> Option<&'static str> {
+    let has_block_tag = text.match_indices("{%").any(|(i, _)| {
+        let rest = text[i + 2..].trim_start_matches('-').trim_start();
+        let Some(close) = rest.find("%}") else {
+            return false;
+        };
+        let tag = rest[..close].split_whitespace().next().unwrap_or_default();
+        !rest[..close].contains('\n') && TEMPLATE_TAGS.contains(&tag)
+    });
+    if has_block_tag {
+        return Some("jinja");
+    }
+    let has_helper = text.match_indices("{{#").any(|(i, _)| {
+        let helper = text[i + 3..]
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default();
+        HANDLEBARS_HELPERS.contains(&helper)
+    });
+    has_helper.then_some("handlebars")
+}
+
+// Opening and closing tags of the common HTML elements, `<div class="line">` and `</div>`.
+fn html_tag_count(text: &str) -> usize {
+    text.match_indices('<')
+        .filter(|(i, _)| {
+            let rest = &text[i + 1..];
+            let rest = rest.strip_prefix('/').unwrap_or(rest);
+            let name_len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let (name, after) = rest.split_at(name_len);
+            HTML_ELEMENTS.contains(&name.to_ascii_lowercase().as_str())
+                && after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace())
+        })
+        .count()
+}
+
+// The contents of the string literals of a chunk, without their quotes. Raw and triple quoted
+// strings end at their own delimiter, the others at the same quote when it isn't escaped, and
+// single quoted ones at the end of the line since they are often characters or lifetimes.
+fn string_literals(text: &str) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut literals = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        // `r"..."` and `r#"..."#`.
+        let raw_start = bytes[i] == b'r' && (i == 0 || !is_ident_byte(bytes[i - 1]));
+        if raw_start {
+            let hashes = bytes[i + 1..].iter().take_while(|b| **b == b'#').count();
+            if bytes.get(i + 1 + hashes) == Some(&b'"') {
+                let start = i + 2 + hashes;
+                let close = format!("\"{}", "#".repeat(hashes));
+                let Some(len) = text[start..].find(&close) else {
+                    break;
+                };
+                literals.push(&text[start..start + len]);
+                i = start + len + close.len();
+                continue;
+            }
+        }
+        if bytes[i..].starts_with(b"\"\"\"") || bytes[i..].starts_with(b"'''") {
+            let start = i + 3;
+            let Some(len) = text[start..].find(&text[i..start]) else {
+                break;
+            };
+            literals.push(&text[start..start + len]);
+            i = start + len + 3;
+            continue;
+        }
+        if matches!(bytes[i], b'"' | b'\'' | b'`') {
+            let quote = bytes[i];
+            let start = i + 1;
+            let mut end = start;
+            while end < bytes.len() && bytes[end] != quote {
+                if quote == b'\'' && bytes[end] == b'\n' {
+                    break;
+                }
+                end += if bytes[end] == b'\\' { 2 } else { 1 };
+            }
+            if bytes.get(end) == Some(&quote) {
+                literals.push(&text[start..end]);
+                i = end + 1;
+            } else {
+                i = start;
+            }
+            continue;
+        }
+        i += 1;
+    }
+    literals
+}
+
+fn is_ident_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+// A literal reads like a query when it starts with a statement followed by the keyword the
+// statement needs, e.g. `SELECT ... FROM orders` or `UPDATE orders SET`. The keywords are matched
+// in the case of the first one, "Select the rows from" is a sentence. A lowercase query also needs
+// a clause or the punctuation of one.
+fn is_sql(literal: &str) -> bool {
+    let text = literal.trim();
+    if text.len() < MIN_SQL_LITERAL_LEN {
+        return false;
+    }
+    let words = text
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let Some(first) = words.first() else {
+        return false;
+    };
+    let uppercase = first.chars().all(|c| c.is_ascii_uppercase());
+    if !uppercase && !first.chars().all(|c| c.is_ascii_lowercase()) {
+        return false;
+    }
+    if words
+        .iter()
+        .any(|word| PROSE_WORDS.contains(&word.to_lowercase().as_str()))
+    {
+        return false;
+    }
+    let keyword = |word: &str, expected: &str| {
+        if uppercase {
+            word.eq_ignore_ascii_case(expected) && word.chars().all(|c| c.is_ascii_uppercase())
+        } else {
+            word == expected
+        }
+    };
+    let at = |i: usize, expected: &str| words.get(i).is_some_and(|word| keyword(word, expected));
+    let after = |from: usize, expected: &str| {
+        words
+            .iter()
+            .enumerate()
+            .skip(from)
+            .any(|(i, word)| keyword(word, expected) && i + 1 < words.len())
+    };
+    let statement = match first.to_ascii_lowercase().as_str() {
+        "select" => after(2, "from"),
+        "insert" => at(1, "into"),
+        "update" => at(2, "set"),
+        "delete" => at(1, "from"),
+        "create" => ["table", "index", "view", "unique"]
+            .iter()
+            .any(|expected| at(1, expected)),
+        "alter" => at(1, "table"),
+        "with" => at(2, "as") && after(3, "select"),
+        _ => false,
+    };
+    if !statement {
+        return false;
+    }
+    uppercase
+        || text.contains(['*', ',', '=', '?', ';'])
+        || words.iter().any(|word| SQL_CLAUSES.contains(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS_REPO: &str = include_str!("../fixtures/embedded_lang/orders_repo.rs");
+    const MESSAGES: &str = include_str!("../fixtures/embedded_lang/messages.rs");
+    const QUERIES: &str = include_str!("../fixtures/embedded_lang/queries.py");
+    const README: &str = include_str!("../fixtures/embedded_lang/README.md");
+    const CHANGELOG: &str = include_str!("../fixtures/embedded_lang/CHANGELOG.md");
+    const INVOICE: &str = include_str!("../fixtures/embedded_lang/invoice.html");
+    const PROFILE: &str = include_str!("../fixtures/embedded_lang/profile.html");
+    const RECEIPT: &str = include_str!("../fixtures/embedded_lang/receipt.jinja");
+
+    #[test]
+    fn test_sql_string_literals_are_tagged() {
+        assert_eq!(embedded_lang(ORDERS_REPO, "Rust").as_deref(), Some("sql"));
+        assert_eq!(embedded_lang(QUERIES, "Python").as_deref(), Some("sql"));
+
+        // the raw string on its own, the first query left out.
+        let archive = &ORDERS_REPO[ORDERS_REPO.find("pub async fn").unwrap()..];
+        assert_eq!(embedded_lang(archive, "Rust").as_deref(), Some("sql"));
+    }
+
+    #[test]
+    fn test_sentences_and_short_strings_are_not_sql() {
+        assert_eq!(embedded_lang(MESSAGES, "Rust"), None);
+        for literal in string_literals(MESSAGES) {
+            assert!(!is_sql(literal), "{:?} is not a query", literal);
+        }
+        assert!(!is_sql("SELECT id FROM t"));
+        // the statement needs its keyword.
+        assert!(!is_sql("SELECT everything that matches the filter"));
+        assert!(!is_sql("update orders when the payment settles"));
+        // a lowercase query needs a clause.
+        assert!(!is_sql("select a lot of items from shelf"));
+        assert!(is_sql("select id, total from orders where paid"));
+    }
+
+    #[test]
+    fn test_sql_files_and_markdown_strings_are_not_scanned() {
+        let sql = "SELECT id, total FROM orders WHERE customer_id = $1;";
+        assert_eq!(embedded_lang(sql, "SQL"), None);
+        assert_eq!(
+            embedded_lang(
+                "Run `SELECT id, total FROM orders WHERE paid` to check.",
+                "Markdown"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_string_literals() {
+        let text = r##"let a = "x \" y"; let b = r#"raw "quoted""#; let c = 'c'; fn f<'a>(s: &'a str) {}"##;
+        let literals = string_literals(text);
+        assert_eq!(literals[..3], ["x \\\" y", "raw \"quoted\"", "c"]);
+        assert!(literals.iter().all(|literal| !literal.contains('\n')));
+        assert_eq!(
+            string_literals("q = \"\"\"\nselect 1\n\"\"\""),
+            vec!["\nselect 1\n"]
+        );
+        // an unterminated quote doesn't swallow the rest of the chunk.
+        assert_eq!(string_literals("it's \"done\""), vec!["done"]);
+    }
+
+    #[test]
+    fn test_markdown_fences_are_tagged_with_their_language() {
+        assert_eq!(embedded_lang(README, "Markdown").as_deref(), Some("sql"));
+        // a fence without a tag or with a plain text one isn't a language.
+        assert_eq!(embedded_lang(CHANGELOG, "Markdown"), None);
+        let setup = &README[README.find("## Setup").unwrap()..];
+        assert_eq!(embedded_lang(setup, "Markdown"), None);
+
+        let aliased = "```py\nimport orders\n```\n\n~~~ {.yml}\nkey: value\nother: value\n~~~\n";
+        assert_eq!(embedded_lang(aliased, "markdown").as_deref(), Some("yaml"));
+    }
+
+    #[test]
+    fn test_chunk_starting_in_a_block_is_tagged_from_the_next_fence() {
+        let chunk = "    orders.archive()\n```\n\nThen check them:\n\n```bash\ncurl /orders\n```\n";
+        assert_eq!(embedded_lang(chunk, "Markdown").as_deref(), Some("bash"));
+        // the block it starts in can't be told.
+        assert_eq!(
+            embedded_lang("orders.archive()\nmore()\n```\n", "Markdown"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_template_blocks_are_tagged() {
+        assert_eq!(embedded_lang(INVOICE, "HTML").as_deref(), Some("jinja"));
+        // interpolations and stray percents aren't template blocks.
+        assert_eq!(embedded_lang(PROFILE, "HTML"), None);
+        let handlebars = "<ul>\n{{#each orders}}\n<li>{{id}}</li>\n{{/each}}\n</ul>";
+        assert_eq!(
+            embedded_lang(handlebars, "html").as_deref(),
+            Some("handlebars")
+        );
+    }
+
+    #[test]
+    fn test_html_of_templates_is_tagged() {
+        assert_eq!(embedded_lang(RECEIPT, "Jinja").as_deref(), Some("html"));
+        let no_markup = "{% for line in lines %}\n{{ line.amount }} < {{ limit }}\n{% endfor %}";
+        assert_eq!(embedded_lang(no_markup, "Jinja"), None);
+    }
+
+    #[test]
+    fn test_language_name_is_embedded_first() {
+        assert_eq!(embedding_text("SELECT 1", Some("sql")), "SQL\nSELECT 1");
+        assert_eq!(embedding_text("fn a() {}", None), "fn a() {}");
+        assert_eq!(display_name("elixir"), "elixir");
+    }
+
+    #[test]
+    fn test_queries_naming_an_embedded_language() {
+        assert_eq!(
+            mentioned_embedded_lang("Where is the orders query defined?"),
+            Some("sql")
+        );
+        assert_eq!(
+            mentioned_embedded_lang("Which SQL archives orders"),
+            Some("sql")
+        );
+        assert_eq!(
+            mentioned_embedded_lang("Where is the invoice template"),
+            Some("jinja")
+        );
+        assert_eq!(mentioned_embedded_lang("How are orders archived?"), None);
+    }
+}
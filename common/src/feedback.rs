@@ -0,0 +1,203 @@
+// Ratings of the answers by the users of a conversation. Each rating is kept on the graph next to
+// the answer it was given on, so a retried question keeps the ratings of its earlier answers.
+// Questions whose latest rating is down become eval cases: the question and the paths the user
+// said were the right ones, in the format of the fixtures of the code search eval.
+
+use serde::{Deserialize, Serialize};
+
+use crate::prompt_versions::PromptVersion;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+/// A rating of an answer, stored on a `Feedback` node attached to the answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnswerFeedback {
+    pub rating: Rating,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // paths of the repo the user said answer the question, they become the relevant paths of the
+    // eval case.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub correct_paths: Vec<String>,
+    // 1 for the first answer of the question, one more for every retry.
+    pub answer_version: usize,
+    // unix timestamp in seconds.
+    pub submitted_at: u64,
+}
+
+impl AnswerFeedback {
+    /// e.g. `down on answer 2: The retries are in the queue`.
+    pub fn render(&self) -> String {
+        let rating = match self.rating {
+            Rating::Up => "up",
+            Rating::Down => "down",
+        };
+        match &self.comment {
+            Some(comment) => format!("{} on answer {}: {}", rating, self.answer_version, comment),
+            None => format!("{} on answer {}", rating, self.answer_version),
+        }
+    }
+}
+
+/// The ratings of one question, over all the versions of its answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuestionFeedback {
+    pub question_id: usize,
+    pub question: String,
+    pub up: usize,
+    pub down: usize,
+    // the last rating given, it decides whether the question is an eval case.
+    pub latest: AnswerFeedback,
+    // the prompt templates of the answer the last rating was given on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prompt_versions: Vec<PromptVersion>,
+}
+
+/// Body of `GET /conversations/{id}/feedback`, only the rated questions are listed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedbackSummary {
+    pub up: usize,
+    pub down: usize,
+    // share of the ratings that are up, None before the first rating.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval: Option<f64>,
+    // rated questions whose latest rating is down.
+    pub negative_questions: usize,
+    pub questions: Vec<QuestionFeedback>,
+}
+
+impl FeedbackSummary {
+    pub fn new(questions: Vec<QuestionFeedback>) -> Self {
+        let up = questions.iter().map(|question| question.up).sum::<usize>();
+        let down = questions
+            .iter()
+            .map(|question| question.down)
+            .sum::<usize>();
+        Self {
+            up,
+            down,
+            approval: (up + down > 0).then(|| up as f64 / (up + down) as f64),
+            negative_questions: questions
+                .iter()
+                .filter(|question| question.latest.rating == Rating::Down)
+                .count(),
+            questions,
+        }
+    }
+
+    /// The eval cases of the questions whose latest rating is down.
+    pub fn eval_cases(&self, repo: &str, conversation_id: &str) -> Vec<EvalCase> {
+        self.questions
+            .iter()
+            .filter(|question| question.latest.rating == Rating::Down)
+            .map(|question| EvalCase {
+                repo: repo.to_string(),
+                query: question.question.clone(),
+                relevant: question.latest.correct_paths.clone(),
+                conversation_id: conversation_id.to_string(),
+                question_id: question.question_id,
+                comment: question.latest.comment.clone(),
+                prompt_versions: question.prompt_versions.clone(),
+            })
+            .collect()
+    }
+}
+
+/// A regression case of the eval, `query` and `relevant` like the code search fixtures. Cases
+/// without relevant paths only record that the answer was wrong.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvalCase {
+    pub repo: String,
+    pub query: String,
+    pub relevant: Vec<String>,
+    pub conversation_id: String,
+    pub question_id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // the prompt templates the rated answer was generated with, to tell a regression of a
+    // template from one of the retrieval.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prompt_versions: Vec<PromptVersion>,
+}
+
+/// Name of the fixture file of the eval cases of a conversation.
+pub fn eval_fixture_name(conversation_id: &str) -> String {
+    format!("feedback_{}.json", conversation_id)
+}
+
+/// The fixture file content, a pretty printed JSON array like `identifier_queries.json`.
+pub fn render_eval_fixture(cases: &[EvalCase]) -> String {
+    let mut fixture = serde_json::to_string_pretty(cases)
+        .expect("Eval cases only hold strings and numbers and are always serializable");
+    fixture.push('\n');
+    fixture
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feedback(rating: Rating, correct_paths: &[&str], answer_version: usize) -> AnswerFeedback {
+        AnswerFeedback {
+            rating,
+            comment: Some("Refunds are retried in the queue".to_string()),
+            correct_paths: correct_paths.iter().map(|path| path.to_string()).collect(),
+            answer_version,
+            submitted_at: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn test_summary_and_eval_fixture() {
+        let summary = FeedbackSummary::new(vec![
+            QuestionFeedback {
+                question_id: 4,
+                question: "Where are refunds retried?".to_string(),
+                up: 1,
+                down: 2,
+                latest: feedback(Rating::Down, &["src/refund/queue.rs"], 2),
+                prompt_versions: Vec::new(),
+            },
+            QuestionFeedback {
+                question_id: 7,
+                question: "Who calls the refund api?".to_string(),
+                up: 1,
+                down: 0,
+                latest: feedback(Rating::Up, &[], 1),
+                prompt_versions: Vec::new(),
+            },
+        ]);
+        assert_eq!((summary.up, summary.down), (2, 2));
+        assert_eq!(summary.approval, Some(0.5));
+        assert_eq!(summary.negative_questions, 1);
+        assert_eq!(FeedbackSummary::new(vec![]).approval, None);
+        assert_eq!(
+            summary.questions[0].latest.render(),
+            "down on answer 2: Refunds are retried in the queue"
+        );
+
+        let cases = summary.eval_cases("acme/api", "c1");
+        assert_eq!(eval_fixture_name("c1"), "feedback_c1.json");
+        assert_eq!(
+            render_eval_fixture(&cases),
+            r#"[
+  {
+    "repo": "acme/api",
+    "query": "Where are refunds retried?",
+    "relevant": [
+      "src/refund/queue.rs"
+    ],
+    "conversation_id": "c1",
+    "question_id": 4,
+    "comment": "Refunds are retried in the queue"
+  }
+]
+"#
+        );
+    }
+}
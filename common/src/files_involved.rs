@@ -0,0 +1,362 @@
+// The files an answer cites, listed above it so a reader sees which files matter before reading a
+// long answer, e.g. `Files involved: src/auth/token.rs (the token is checked in validate_token),
+// src/routes/login.rs`. Only the citations code understanding validated and the scope check let
+// through are listed, most cited first. Each file gets the clause of the answer around its first
+// link as its role. The links rewritten to urls are matched by the url recorded on their citation,
+// so the files of an answer read back from the graph are the ones it was sent with.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::citations::{CitationReport, CitationStatus};
+use crate::links::{fence_marker, link_targets, relative_link};
+
+/// Starts the header line, answers starting with it already have one.
+pub const HEADER_PREFIX: &str = "Files involved: ";
+/// Files the header lists, the rest are counted as `and N more`.
+pub const MAX_HEADER_FILES: usize = 10;
+// words of the answer kept as the role of a file.
+const MAX_ROLE_WORDS: usize = 8;
+// the clause of a link ends at these.
+const CLAUSE_DELIMITERS: &[char] = &['.', ',', ';', ':', '!', '?', '(', ')'];
+
+/// A file the answer cites, with what the answer says it does.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileInvolved {
+    pub path: String,
+    // the clause around the first link to the file, cut to a few words. None when the answer
+    // only quotes the file, or links it without a word around the link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    // valid citations of the file.
+    pub citations: usize,
+}
+
+impl FileInvolved {
+    /// e.g. `src/routes/login.rs (the login endpoint)`.
+    pub fn render(&self) -> String {
+        match &self.role {
+            Some(role) => format!("{} ({})", self.path, role),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// The valid, in scope files the answer cites, most cited first and in the order they are first
+/// cited otherwise. Empty for answers with less than two such citations, the header wouldn't
+/// tell more than the answer.
+pub fn files_involved(answer: &str, citations: &CitationReport) -> Vec<FileInvolved> {
+    let cited = citations
+        .citations
+        .iter()
+        .filter(|citation| {
+            matches!(
+                citation.status,
+                CitationStatus::Valid | CitationStatus::Adjusted
+            ) && citation.out_of_scope.is_none()
+        })
+        .collect::<Vec<_>>();
+    if cited.len() < 2 {
+        return Vec::new();
+    }
+
+    let mut files: Vec<FileInvolved> = Vec::new();
+    for citation in cited {
+        match files.iter_mut().find(|file| file.path == citation.path) {
+            Some(file) => file.citations += 1,
+            None => files.push(FileInvolved {
+                path: citation.path.clone(),
+                role: None,
+                citations: 1,
+            }),
+        }
+    }
+    // stable, the files cited as often keep the order they are first cited in.
+    files.sort_by(|a, b| b.citations.cmp(&a.citations));
+
+    let roles = link_roles(answer, citations);
+    for file in &mut files {
+        file.role = roles.get(&file.path).cloned();
+    }
+    files
+}
+
+/// The header line of the files, with the ones past `MAX_HEADER_FILES` counted. None when there
+/// are none.
+pub fn render_header(files: &[FileInvolved]) -> Option<String> {
+    if files.is_empty() {
+        return None;
+    }
+    let mut listed = files
+        .iter()
+        .take(MAX_HEADER_FILES)
+        .map(FileInvolved::render)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if files.len() > MAX_HEADER_FILES {
+        listed.push_str(&format!(", and {} more", files.len() - MAX_HEADER_FILES));
+    }
+    Some(format!("{}{}", HEADER_PREFIX, listed))
+}
+
+/// The answer with the header of `files` above it, as it is when it has no files or already has
+/// a header.
+pub fn prepend_header(answer: &str, files: &[FileInvolved]) -> String {
+    match render_header(files) {
+        Some(header) if !answer.starts_with(HEADER_PREFIX) => format!("{}\n\n{}", header, answer),
+        _ => answer.to_string(),
+    }
+}
+
+/// The answer without its header, for the steps that read what the answer says.
+pub fn strip_header(answer: &str) -> &str {
+    if !answer.starts_with(HEADER_PREFIX) {
+        return answer;
+    }
+    answer
+        .split_once('\n')
+        .map_or("", |(_, rest)| rest.trim_start_matches('\n'))
+}
+
+// The role of every file linked outside code blocks, from its first link with words around it.
+fn link_roles(answer: &str, citations: &CitationReport) -> HashMap<String, String> {
+    let path_of = |target: &str| {
+        relative_link(target).map(|(path, _)| path).or_else(|| {
+            citations
+                .citations
+                .iter()
+                .find(|citation| citation.url.as_deref() == Some(target))
+                .map(|citation| citation.path.clone())
+        })
+    };
+    let mut roles = HashMap::new();
+    let mut fence: Option<&str> = None;
+    for line in strip_header(answer).lines() {
+        let trimmed = line.trim_start();
+        if let Some(marker) = fence {
+            if trimmed.trim_end() == marker {
+                fence = None;
+            }
+            continue;
+        }
+        if let Some(marker) = fence_marker(trimmed) {
+            fence = Some(marker);
+            continue;
+        }
+        for (path, plain, link) in plain_links(line, path_of) {
+            if roles.contains_key(&path) {
+                continue;
+            }
+            if let Some(role) = clause_role(&plain, link, &path) {
+                roles.insert(path, role);
+            }
+        }
+    }
+    roles
+}
+
+// The line with its links replaced by their text, with the path and the range in the plain line
+// of every link `path_of` knows the path of.
+fn plain_links(
+    line: &str,
+    path_of: impl Fn(&str) -> Option<String>,
+) -> Vec<(String, String, std::ops::Range<usize>)> {
+    let mut plain = String::with_capacity(line.len());
+    let mut links = Vec::new();
+    let mut end = 0;
+    for target in link_targets(line) {
+        // `[text](target)`, the text starts at the last `[` before the target.
+        let text_end = target.start - 2;
+        let Some(text_start) = line[end..text_end].rfind('[').map(|i| end + i) else {
+            continue;
+        };
+        plain.push_str(&line[end..text_start]);
+        let start = plain.len();
+        plain.push_str(&line[text_start + 1..text_end]);
+        if let Some(path) = path_of(&line[target.clone()]) {
+            links.push((path, start..plain.len()));
+        }
+        end = target.end + 1;
+    }
+    plain.push_str(line.get(end..).unwrap_or(""));
+    links
+        .into_iter()
+        .map(|(path, range)| (path, plain.clone(), range))
+        .collect()
+}
+
+// The words of the clause of the plain line holding the link, without list markers, emphasis
+// and the path itself. None when the link stands alone.
+fn clause_role(plain: &str, link: std::ops::Range<usize>, path: &str) -> Option<String> {
+    let start = plain[..link.start]
+        .rfind(CLAUSE_DELIMITERS)
+        .map_or(0, |i| i + 1);
+    let end = plain[link.end..]
+        .find(CLAUSE_DELIMITERS)
+        .map_or(plain.len(), |i| link.end + i);
+    let words = plain[start..end]
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c| matches!(c, '`' | '*' | '#' | '"')))
+        .filter(|word| !word.is_empty() && *word != path && *word != "-")
+        .take(MAX_ROLE_WORDS)
+        .collect::<Vec<_>>();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::answer_scope::ScopeViolationReason;
+    use crate::links::{rewrite_citations, CitationSite};
+
+    const ANSWER: &str = "\
+The login flow starts at [the login handler](src/routes/login.rs#L12-L40), which reads the form.
+- The token is checked in [`validate_token`](src/auth/token.rs#L10-L30) before anything else.
+- [`refresh`](src/auth/token.rs#L50-L70) renews the token when it expired.
+- The signing key comes from [settings](config/settings.py#L3-L5).
+
+```type:Quoted,lang:Rust,path:src/auth/token.rs,lines:9-29
+fn validate_token() {}
+```
+
+See also [the docs](docs/auth.md) and [vendored code](vendor/jwt/lib.rs#L1-L2).
+";
+
+    fn report() -> CitationReport {
+        let mut report = CitationReport::parse(ANSWER);
+        for citation in &mut report.citations {
+            citation.status = match citation.path.as_str() {
+                "docs/auth.md" => CitationStatus::Invalid,
+                _ => CitationStatus::Valid,
+            };
+            if citation.path.starts_with("vendor/") {
+                citation.out_of_scope = Some(ScopeViolationReason::OutOfScope);
+            }
+        }
+        report
+    }
+
+    #[test]
+    fn test_files_are_listed_by_citations_with_their_roles() {
+        let files = files_involved(ANSWER, &report());
+
+        assert_eq!(
+            files,
+            vec![
+                FileInvolved {
+                    path: "src/auth/token.rs".to_string(),
+                    role: Some(
+                        "The token is checked in validate_token before anything".to_string()
+                    ),
+                    citations: 3,
+                },
+                FileInvolved {
+                    path: "src/routes/login.rs".to_string(),
+                    role: Some("The login flow starts at the login handler".to_string()),
+                    citations: 1,
+                },
+                FileInvolved {
+                    path: "config/settings.py".to_string(),
+                    role: Some("The signing key comes from settings".to_string()),
+                    citations: 1,
+                },
+            ]
+        );
+        assert_eq!(
+            render_header(&files).unwrap(),
+            "Files involved: src/auth/token.rs (The token is checked in validate_token before anything), \
+             src/routes/login.rs (The login flow starts at the login handler), \
+             config/settings.py (The signing key comes from settings)"
+        );
+        assert_eq!(
+            serde_json::to_value(&files[1]).unwrap(),
+            serde_json::json!({
+                "path": "src/routes/login.rs",
+                "role": "The login flow starts at the login handler",
+                "citations": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_header_is_prepended_once_and_stripped() {
+        let files = files_involved(ANSWER, &report());
+        let answer = prepend_header(ANSWER, &files);
+
+        assert!(answer.starts_with("Files involved: src/auth/token.rs ("));
+        assert_eq!(prepend_header(&answer, &files), answer);
+        assert_eq!(strip_header(&answer), ANSWER);
+        // the header isn't read as the role of the files it lists.
+        assert_eq!(files_involved(&answer, &report()), files);
+    }
+
+    #[test]
+    fn test_links_rewritten_to_urls_keep_their_roles() {
+        let mut linked = report();
+        let answer = rewrite_citations(ANSWER, |site, path, lines| {
+            if !matches!(site, CitationSite::Link) {
+                return None;
+            }
+            let url = format!(
+                "https://github.com/acme/api/blob/4f2a9c1/{}#L{}",
+                path, lines?.0
+            );
+            for citation in &mut linked.citations {
+                if citation.path == path && citation.lines == lines {
+                    citation.url = Some(url.clone());
+                }
+            }
+            Some(url)
+        });
+
+        assert_eq!(
+            files_involved(&answer, &linked),
+            files_involved(ANSWER, &report())
+        );
+    }
+
+    #[test]
+    fn test_single_citation_has_no_header() {
+        let answer = "The token is checked in [`validate_token`](src/auth/token.rs#L10-L30).";
+        let mut report = CitationReport::parse(answer);
+        report.citations[0].status = CitationStatus::Valid;
+
+        assert!(files_involved(answer, &report).is_empty());
+        assert_eq!(prepend_header(answer, &[]), answer);
+    }
+
+    #[test]
+    fn test_header_is_capped() {
+        let files = (0..13)
+            .map(|i| FileInvolved {
+                path: format!("src/file_{}.rs", i),
+                role: None,
+                citations: 1,
+            })
+            .collect::<Vec<_>>();
+
+        let header = render_header(&files).unwrap();
+
+        assert!(header.contains("src/file_9.rs, and 3 more"));
+        assert!(!header.contains("src/file_10.rs"));
+    }
+
+    #[test]
+    fn test_link_alone_has_no_role() {
+        let answer = "- [src/lib.rs](src/lib.rs)\n- [src/main.rs](src/main.rs#L1-L3)\n";
+        let mut report = CitationReport::parse(answer);
+        for citation in &mut report.citations {
+            citation.status = CitationStatus::Valid;
+        }
+
+        let files = files_involved(answer, &report);
+
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|file| file.role.is_none()));
+        assert_eq!(
+            render_header(&files).unwrap(),
+            "Files involved: src/lib.rs, src/main.rs"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// How far the index of a branch is behind the branch. Code search compares the commit the branch
+// was indexed at with the head of the branch, in a working copy of the repo or on its remote. The
+// coordinator notes the freshness on the root of new conversations, and the answers and exports of
+// the conversations past the staleness thresholds carry a banner.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub const STALENESS_THRESHOLDS_ENV: &str = "STALENESS_THRESHOLDS";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Body of `GET /repos/{name}/freshness`. The head of the branch is None when it couldn't be
+/// probed, `unknown_reason` says why.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct IndexFreshness {
+    pub repo_name: String,
+    pub branch: String,
+    pub indexed_commit: String,
+    // unix timestamp in seconds, None when neither the manifest nor the probe has it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexed_commit_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_commit: Option<String>,
+    // None when the probe can't count the commits, e.g. on a remote.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commits_behind: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_reason: Option<String>,
+}
+
+impl IndexFreshness {
+    /// Whole days between the indexed commit and `now`.
+    pub fn age_days(&self, now: u64) -> Option<u64> {
+        self.indexed_commit_at
+            .map(|committed_at| now.saturating_sub(committed_at) / SECONDS_PER_DAY)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FreshnessStatus {
+    Fresh,
+    // past one of the staleness thresholds.
+    Stale,
+    // the head of the branch couldn't be probed.
+    Unknown,
+}
+
+/// What a conversation keeps of the freshness of the index it was started on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FreshnessNote {
+    pub status: FreshnessStatus,
+    pub branch: String,
+    // empty when code search couldn't tell which commit was indexed.
+    pub indexed_commit: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age_days: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commits_behind: Option<usize>,
+}
+
+impl FreshnessNote {
+    /// The note of an index whose freshness couldn't be looked up at all.
+    pub fn unknown(branch: &str) -> Self {
+        Self {
+            status: FreshnessStatus::Unknown,
+            branch: branch.to_string(),
+            indexed_commit: String::new(),
+            age_days: None,
+            commits_behind: None,
+        }
+    }
+
+    /// The warning shown with the answers of a stale index, e.g.
+    /// `Answers are based on commit abc1234, 9 days / 42 commits behind main.`
+    pub fn banner(&self) -> Option<String> {
+        if self.status != FreshnessStatus::Stale {
+            return None;
+        }
+        let behind = [
+            self.age_days.map(|days| format!("{} days", days)),
+            self.commits_behind
+                .map(|commits| format!("{} commits", commits)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" / ");
+        Some(format!(
+            "Answers are based on commit {}, {} behind {}.",
+            self.short_commit(),
+            behind,
+            self.branch
+        ))
+    }
+
+    pub fn render(&self) -> String {
+        match (self.status, self.banner()) {
+            (_, Some(banner)) => banner,
+            (FreshnessStatus::Unknown, None) => format!(
+                "Commit {} of {}, freshness unknown",
+                self.short_commit(),
+                self.branch
+            ),
+            _ => format!("Commit {} of {} is fresh", self.short_commit(), self.branch),
+        }
+    }
+
+    fn short_commit(&self) -> &str {
+        self.indexed_commit.get(..7).unwrap_or(&self.indexed_commit)
+    }
+}
+
+/// How old the indexed commit and how many commits behind the branch the index can be before its
+/// answers are flagged. Parsed from `days=<days>,commits=<commits>`, a missing entry isn't checked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StalenessThresholds {
+    pub max_age_days: Option<u64>,
+    pub max_commits_behind: Option<usize>,
+}
+
+impl Default for StalenessThresholds {
+    fn default() -> Self {
+        Self {
+            max_age_days: Some(7),
+            max_commits_behind: Some(50),
+        }
+    }
+}
+
+impl StalenessThresholds {
+    /// The note of `freshness` as of `now`, a unix timestamp in seconds.
+    pub fn note(&self, freshness: &IndexFreshness, now: u64) -> FreshnessNote {
+        let age_days = freshness.age_days(now);
+        let status = match &freshness.head_commit {
+            None => FreshnessStatus::Unknown,
+            Some(head) if *head == freshness.indexed_commit => FreshnessStatus::Fresh,
+            Some(_) => {
+                let too_old = matches!(
+                    (age_days, self.max_age_days),
+                    (Some(days), Some(max)) if days > max
+                );
+                let too_far_behind = matches!(
+                    (freshness.commits_behind, self.max_commits_behind),
+                    (Some(commits), Some(max)) if commits > max
+                );
+                match too_old || too_far_behind {
+                    true => FreshnessStatus::Stale,
+                    false => FreshnessStatus::Fresh,
+                }
+            }
+        };
+        FreshnessNote {
+            status,
+            branch: freshness.branch.clone(),
+            indexed_commit: freshness.indexed_commit.clone(),
+            age_days,
+            commits_behind: freshness.commits_behind,
+        }
+    }
+}
+
+impl FromStr for StalenessThresholds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = Self {
+            max_age_days: None,
+            max_commits_behind: None,
+        };
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, value) = entry.split_once('=').ok_or_else(|| {
+                format!(
+                    "{} entries must be `days=<days>` or `commits=<commits>`, got `{}`",
+                    STALENESS_THRESHOLDS_ENV, entry
+                )
+            })?;
+            let value = value.trim().parse::<u64>().map_err(|_| {
+                format!(
+                    "The threshold of {} must be a non-negative integer, got `{}`",
+                    name.trim(),
+                    value.trim()
+                )
+            })?;
+            match name.trim() {
+                "days" => thresholds.max_age_days = Some(value),
+                "commits" => thresholds.max_commits_behind = Some(value as usize),
+                name => return Err(format!("Unknown staleness threshold `{}`", name)),
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn freshness(head: Option<&str>, commits_behind: Option<usize>) -> IndexFreshness {
+        IndexFreshness {
+            repo_name: "acme/api".to_string(),
+            branch: "main".to_string(),
+            indexed_commit: "abc1234def".to_string(),
+            indexed_commit_at: Some(NOW - 9 * SECONDS_PER_DAY - 60),
+            head_commit: head.map(str::to_string),
+            commits_behind,
+            unknown_reason: None,
+        }
+    }
+
+    #[test]
+    fn test_thresholds_are_parsed() {
+        let thresholds: StalenessThresholds = "days=3, commits=10".parse().unwrap();
+        assert_eq!(thresholds.max_age_days, Some(3));
+        assert_eq!(thresholds.max_commits_behind, Some(10));
+        let thresholds: StalenessThresholds = "commits=10".parse().unwrap();
+        assert_eq!(thresholds.max_age_days, None);
+        assert!("weeks=2".parse::<StalenessThresholds>().is_err());
+        assert!("days=two".parse::<StalenessThresholds>().is_err());
+    }
+
+    #[test]
+    fn test_only_stale_indexes_get_a_banner() {
+        let thresholds = StalenessThresholds::default();
+
+        let stale = thresholds.note(&freshness(Some("fff0000"), Some(42)), NOW);
+        assert_eq!(stale.status, FreshnessStatus::Stale);
+        assert_eq!(
+            stale.banner().as_deref(),
+            Some("Answers are based on commit abc1234, 9 days / 42 commits behind main.")
+        );
+
+        // the head is the indexed commit, however old it is.
+        let current = thresholds.note(&freshness(Some("abc1234def"), Some(0)), NOW);
+        assert_eq!(current.status, FreshnessStatus::Fresh);
+        assert_eq!(current.banner(), None);
+
+        let thresholds: StalenessThresholds = "days=30,commits=50".parse().unwrap();
+        let behind = thresholds.note(&freshness(Some("fff0000"), Some(42)), NOW);
+        assert_eq!(behind.status, FreshnessStatus::Fresh);
+        // a remote doesn't count the commits, the age alone decides.
+        let remote = StalenessThresholds::default().note(&freshness(Some("fff0000"), None), NOW);
+        assert_eq!(
+            remote.banner().as_deref(),
+            Some("Answers are based on commit abc1234, 9 days behind main.")
+        );
+
+        let unknown = thresholds.note(&freshness(None, None), NOW);
+        assert_eq!(unknown.status, FreshnessStatus::Unknown);
+        assert_eq!(unknown.banner(), None);
+        assert_eq!(
+            unknown.render(),
+            "Commit abc1234 of main, freshness unknown"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// Snapshot-consistent reads. `migrate-embeddings` switches the aliases the services search through
+// to new collections while conversations are running, so two questions of a conversation could be
+// answered from different embeddings. A conversation is pinned to the collections the aliases
+// pointed to when it started, its searches read from them instead of the aliases. The pins are
+// registered in redis under each pinned collection, scored by when they expire, and the migration
+// keeps the pinned collections when it drops the old ones. The quickwit index of a repo is updated
+// in place and isn't pinned.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::unix_now;
+use crate::service_interaction::{DOCUMENT_COLLECTION_NAME, SYMBOL_COLLECTION_NAME};
+use crate::task_graph::redis::establish_redis_connection;
+
+/// Aliases of the collections a conversation is pinned to.
+pub const PINNED_ALIASES: [&str; 2] = [DOCUMENT_COLLECTION_NAME, SYMBOL_COLLECTION_NAME];
+/// How long a pin holds without a question of the conversation refreshing it, a week.
+pub const DEFAULT_PIN_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// The collections the aliases pointed to when a conversation started.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexGeneration {
+    // keyed by alias.
+    pub collections: BTreeMap<String, String>,
+}
+
+impl IndexGeneration {
+    /// The generation the aliases of `PINNED_ALIASES` point to. A collection that isn't behind an
+    /// alias yet is its own generation.
+    pub fn from_aliases(aliases: &HashMap<String, String>) -> Self {
+        let collections = PINNED_ALIASES
+            .iter()
+            .map(|alias| {
+                let collection = aliases
+                    .get(*alias)
+                    .cloned()
+                    .unwrap_or_else(|| alias.to_string());
+                (alias.to_string(), collection)
+            })
+            .collect();
+        Self { collections }
+    }
+
+    /// The collection searched for `alias`, the alias itself when it isn't pinned.
+    pub fn collection(&self, alias: &str) -> String {
+        self.collections
+            .get(alias)
+            .cloned()
+            .unwrap_or_else(|| alias.to_string())
+    }
+
+    pub fn render(&self) -> String {
+        self.collections
+            .iter()
+            .map(|(alias, collection)| format!("{} -> {}", alias, collection))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// `documents=documents_v2,documents_symbol=documents_symbol_v2`, the form of the query parameters.
+impl fmt::Display for IndexGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs = self
+            .collections
+            .iter()
+            .map(|(alias, collection)| format!("{}={}", alias, collection))
+            .collect::<Vec<_>>();
+        f.write_str(&pairs.join(","))
+    }
+}
+
+impl FromStr for IndexGeneration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let collections = s
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((alias, collection)) if !alias.is_empty() && !collection.is_empty() => {
+                    Ok((alias.trim().to_string(), collection.trim().to_string()))
+                }
+                _ => Err(anyhow!(
+                    "Invalid index generation {:?}, expected alias=collection",
+                    pair
+                )),
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        Ok(Self { collections })
+    }
+}
+
+/// A collection of the generation a conversation is pinned to was dropped. The conversation has
+/// to be restarted, or re-pinned to the current generation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+#[error("The collection {collection} of the index generation the conversation is pinned to was dropped, restart the conversation or re-pin it to the current generation")]
+pub struct IndexGenerationGone {
+    pub collection: String,
+}
+
+fn pin_key(collection: &str) -> String {
+    format!("index_generation_pins:{}", collection)
+}
+
+/// Registers the conversation on every collection of its generation for `ttl_secs`, pinning it
+/// again extends the pin.
+pub fn pin_generation(
+    redis_url: &str,
+    conversation_id: &str,
+    generation: &IndexGeneration,
+    ttl_secs: u64,
+) -> Result<()> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let expires_at = unix_now() + ttl_secs;
+    for collection in generation.collections.values() {
+        let key = pin_key(collection);
+        let _: () = conn.zadd(&key, conversation_id, expires_at)?;
+        let _: () = conn.expire(&key, ttl_secs as i64)?;
+    }
+    Ok(())
+}
+
+/// The collections among `collections` a conversation is still pinned to, the expired pins are
+/// removed on the way.
+pub fn pinned_collections(redis_url: &str, collections: &[String]) -> Result<HashSet<String>> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let now = unix_now();
+    let mut pinned = HashSet::new();
+    for collection in collections {
+        let key = pin_key(collection);
+        let _: () = conn.zrembyscore(&key, "-inf", now)?;
+        let pins: usize = conn.zcard(&key)?;
+        if pins > 0 {
+            pinned.insert(collection.clone());
+        }
+    }
+    Ok(pinned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_resolves_the_aliases() {
+        let aliases = HashMap::from([(
+            DOCUMENT_COLLECTION_NAME.to_string(),
+            "documents_v2".to_string(),
+        )]);
+        let generation = IndexGeneration::from_aliases(&aliases);
+        assert_eq!(
+            generation.collection(DOCUMENT_COLLECTION_NAME),
+            "documents_v2"
+        );
+        // still a plain collection, not behind an alias.
+        assert_eq!(
+            generation.collection(SYMBOL_COLLECTION_NAME),
+            SYMBOL_COLLECTION_NAME
+        );
+        assert_eq!(
+            IndexGeneration::default().collection(DOCUMENT_COLLECTION_NAME),
+            DOCUMENT_COLLECTION_NAME
+        );
+    }
+
+    #[test]
+    fn test_generation_round_trips_through_its_parameter() {
+        let generation = IndexGeneration::from_aliases(&HashMap::from([
+            ("documents".to_string(), "documents_v2".to_string()),
+            (
+                "documents_symbol".to_string(),
+                "documents_symbol_v2".to_string(),
+            ),
+        ]));
+        let param = generation.to_string();
+        assert_eq!(
+            param,
+            "documents=documents_v2,documents_symbol=documents_symbol_v2"
+        );
+        assert_eq!(param.parse::<IndexGeneration>().unwrap(), generation);
+        assert_eq!(
+            "".parse::<IndexGeneration>().unwrap(),
+            IndexGeneration::default()
+        );
+        assert!("documents".parse::<IndexGeneration>().is_err());
+    }
+}
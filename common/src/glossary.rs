@@ -0,0 +1,523 @@
+// Glossary of the symbols the answers of a conversation cited. Across a long conversation the
+// answers describe the same function in different words, or contradict each other. Every answer
+// records the symbols enclosing the lines it cites with the sentence it described them in; the
+// next questions get the entries of the symbols in their retrieved code with the instruction to
+// stay consistent with them. A description that disagrees with the recorded one is kept as a
+// conflict, the summary of the tasks reconciles them and lists the glossary as an appendix.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::citations::{CitationReport, CitationStatus};
+use crate::files_involved::strip_header;
+
+/// Descriptions are cut to this many characters, they are meant to be one line.
+pub const MAX_DESCRIPTION_CHARS: usize = 240;
+/// Heading of the block the summary lists the reconciled descriptions under, taken out of the
+/// summary once read.
+pub const RECONCILED_HEADING: &str = "## Reconciled glossary";
+// share of the words two descriptions need in common to agree.
+const AGREEMENT_THRESHOLD: f32 = 0.5;
+// words too common to tell two descriptions apart.
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "with", "that", "this", "from", "into", "its", "are", "was", "which",
+    "when", "then", "than", "them", "they", "their", "also", "each", "all",
+];
+
+/// What an answer said a symbol does.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GlossaryDescription {
+    pub question_id: usize,
+    pub description: String,
+}
+
+/// A symbol cited by the answers of the conversation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GlossaryEntry {
+    pub symbol: String,
+    pub path: String,
+    // the description the next answers stay consistent with, the first one until reconciled.
+    pub description: String,
+    // question whose answer gave the description, None once the summary reconciled it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub question_id: Option<usize>,
+    // later descriptions disagreeing with it, for the summary to reconcile.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<GlossaryDescription>,
+}
+
+impl GlossaryEntry {
+    /// e.g. `` `commit_chunks` (ingestion/src/semantic_index.rs): Retries the upsert three times. ``
+    pub fn render(&self) -> String {
+        format!("`{}` ({}): {}", self.symbol, self.path, self.description)
+    }
+}
+
+/// A description the summary settled on for a symbol with conflicting ones.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReconciledDescription {
+    pub symbol: String,
+    pub description: String,
+}
+
+/// The glossary of a conversation, in the order the symbols were first cited.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Glossary {
+    pub entries: Vec<GlossaryEntry>,
+}
+
+impl Glossary {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records the symbols enclosing the valid citations of the answer to question `question_id`,
+    /// with the sentence of the answer mentioning them. Symbols the answer cites without naming
+    /// them are left out. Returns the number of entries added or given a conflict.
+    pub fn record_answer(
+        &mut self,
+        question_id: usize,
+        answer: &str,
+        citations: &CitationReport,
+    ) -> usize {
+        let mut seen = HashSet::new();
+        let mut changed = 0;
+        for citation in &citations.citations {
+            if !matches!(
+                citation.status,
+                CitationStatus::Valid | CitationStatus::Adjusted
+            ) {
+                continue;
+            }
+            let Some(symbol) = &citation.enclosing_symbol else {
+                continue;
+            };
+            if !seen.insert((symbol.as_str(), citation.path.as_str())) {
+                continue;
+            }
+            // the header of the files involved lists the symbols without describing them.
+            let Some(description) = described_as(strip_header(answer), symbol) else {
+                continue;
+            };
+            if self.record(question_id, symbol, &citation.path, description) {
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    /// Records the description an answer gave a symbol. A symbol seen before keeps its
+    /// description, one disagreeing with it is added to its conflicts. Returns whether the
+    /// glossary changed.
+    pub fn record(
+        &mut self,
+        question_id: usize,
+        symbol: &str,
+        path: &str,
+        description: String,
+    ) -> bool {
+        let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.symbol == symbol && entry.path == path)
+        else {
+            self.entries.push(GlossaryEntry {
+                symbol: symbol.to_string(),
+                path: path.to_string(),
+                description,
+                question_id: Some(question_id),
+                conflicts: Vec::new(),
+            });
+            return true;
+        };
+        let known = std::iter::once(&entry.description)
+            .chain(entry.conflicts.iter().map(|conflict| &conflict.description));
+        for known in known {
+            if agrees(known, &description, symbol) {
+                return false;
+            }
+        }
+        entry.conflicts.push(GlossaryDescription {
+            question_id,
+            description,
+        });
+        true
+    }
+
+    /// The entries of the symbols the code mentions, the ones the next answer has to stay
+    /// consistent with.
+    pub fn relevant<'a>(&self, code: impl IntoIterator<Item = &'a str>) -> Vec<GlossaryEntry> {
+        let code = code.into_iter().collect::<Vec<_>>();
+        self.entries
+            .iter()
+            .filter(|entry| code.iter().any(|code| mentions(code, &entry.symbol)))
+            .cloned()
+            .collect()
+    }
+
+    /// The entries with conflicting descriptions.
+    pub fn conflicts(&self) -> Vec<GlossaryEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.conflicts.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Replaces the descriptions of the entries the summary reconciled and drops their conflicts.
+    /// Returns the number of entries reconciled.
+    pub fn reconcile(&mut self, reconciled: &[ReconciledDescription]) -> usize {
+        let mut count = 0;
+        for entry in &mut self.entries {
+            let Some(settled) = reconciled
+                .iter()
+                .find(|settled| settled.symbol == entry.symbol)
+            else {
+                continue;
+            };
+            entry.description = one_line(&settled.description);
+            entry.question_id = None;
+            entry.conflicts.clear();
+            count += 1;
+        }
+        count
+    }
+
+    /// One line per entry, its conflicts indented under it.
+    pub fn render(&self) -> String {
+        let mut rendered = Vec::new();
+        for entry in &self.entries {
+            rendered.push(format!("- {}", entry.render()));
+            for conflict in &entry.conflicts {
+                rendered.push(format!("  - also described as: {}", conflict.description));
+            }
+        }
+        rendered.join("\n")
+    }
+
+    /// The glossary as a markdown appendix, None when it is empty.
+    pub fn render_appendix(&self) -> Option<String> {
+        (!self.is_empty()).then(|| format!("## Glossary\n\n{}\n", self.render()))
+    }
+}
+
+/// The first sentence of the prose of the answer naming `symbol`, with its links and emphasis
+/// stripped. Quoted code isn't prose and is skipped.
+pub fn described_as(answer: &str, symbol: &str) -> Option<String> {
+    let mut in_code = false;
+    for line in answer.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        let line = strip_links(line);
+        let line = line.trim().trim_start_matches(['-', '*', '#', '>']).trim();
+        for sentence in sentences(line) {
+            if mentions(sentence, symbol) {
+                return Some(one_line(sentence));
+            }
+        }
+    }
+    None
+}
+
+/// The summary without its reconciled block, and the descriptions the block settled on, as
+/// `` - `symbol`: description `` lines under `RECONCILED_HEADING`.
+pub fn split_reconciled(summary: &str) -> (String, Vec<ReconciledDescription>) {
+    let mut kept = Vec::new();
+    let mut reconciled = Vec::new();
+    let mut in_block = false;
+    for line in summary.lines() {
+        let trimmed = line.trim();
+        if trimmed.eq_ignore_ascii_case(RECONCILED_HEADING) {
+            in_block = true;
+            continue;
+        }
+        if in_block {
+            if trimmed.starts_with('#') {
+                in_block = false;
+            } else {
+                if let Some(settled) = parse_reconciled(trimmed) {
+                    reconciled.push(settled);
+                }
+                continue;
+            }
+        }
+        kept.push(line);
+    }
+    (kept.join("\n").trim_end().to_string(), reconciled)
+}
+
+// `` - `symbol`: description ``.
+fn parse_reconciled(line: &str) -> Option<ReconciledDescription> {
+    let line = line.strip_prefix('-')?.trim();
+    let (symbol, description) = line.split_once(':')?;
+    let symbol = symbol.trim().trim_matches('`').trim();
+    let description = description.trim();
+    if symbol.is_empty() || description.is_empty() {
+        return None;
+    }
+    Some(ReconciledDescription {
+        symbol: symbol.to_string(),
+        description: description.to_string(),
+    })
+}
+
+// Whether the descriptions of `symbol` share enough of their words, the symbol itself aside.
+fn agrees(a: &str, b: &str, symbol: &str) -> bool {
+    let words = |text: &str| {
+        text.split(|c: char| !c.is_alphanumeric() && c != '_')
+            .map(str::to_lowercase)
+            .filter(|word| word.len() > 2 && word != symbol && !STOP_WORDS.contains(&word.as_str()))
+            .collect::<HashSet<_>>()
+    };
+    let (a, b) = (words(a), words(b));
+    if a.is_empty() || b.is_empty() {
+        return a == b;
+    }
+    let shared = a.intersection(&b).count() as f32;
+    shared / a.union(&b).count() as f32 >= AGREEMENT_THRESHOLD
+}
+
+// Whether `text` has `symbol` as a whole identifier.
+fn mentions(text: &str, symbol: &str) -> bool {
+    if symbol.is_empty() {
+        return false;
+    }
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(symbol).any(|(start, _)| {
+        !text[..start].chars().next_back().is_some_and(is_ident)
+            && !text[start + symbol.len()..]
+                .chars()
+                .next()
+                .is_some_and(is_ident)
+    })
+}
+
+// `[text](target)` -> `text`.
+fn strip_links(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(open) = rest.find('[') {
+        let Some(close) = rest[open..].find("](").map(|i| open + i) else {
+            break;
+        };
+        let Some(end) = rest[close..].find(')').map(|i| close + i) else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        out.push_str(&rest[open + 1..close]);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Sentences end with a period, question or exclamation mark followed by a space.
+fn sentences(line: &str) -> impl Iterator<Item = &str> {
+    let mut start = 0;
+    let mut ends = Vec::new();
+    let bytes = line.as_bytes();
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(byte, b'.' | b'!' | b'?') && bytes.get(i + 1).map_or(true, |b| *b == b' ') {
+            ends.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < line.len() {
+        ends.push(start..line.len());
+    }
+    ends.into_iter()
+        .map(move |range| line[range].trim())
+        .filter(|sentence| !sentence.is_empty())
+}
+
+fn one_line(text: &str) -> String {
+    let text = text
+        .replace("**", "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    match text.char_indices().nth(MAX_DESCRIPTION_CHARS) {
+        Some((cut, _)) => format!("{}...", text[..cut].trim_end()),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::citations::{Citation, CitationSource};
+
+    fn citation(path: &str, symbol: Option<&str>, status: CitationStatus) -> Citation {
+        Citation {
+            path: path.to_string(),
+            source: CitationSource::Link,
+            cited_lines: Some((10, 20)),
+            lines: Some((10, 20)),
+            status,
+            out_of_scope: None,
+            url: None,
+            anchor: None,
+            drift: None,
+            enclosing_symbol: symbol.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_the_sentence_naming_the_symbol_is_its_description() {
+        let answer = "The ingestion commits the chunks in batches.\n\
+                      ```type:Quoted,lang:Rust,path:ingestion/src/semantic_index.rs,lines:9-19\n\
+                      fn commit_chunks() { retry(3) }\n\
+                      ```\n\
+                      - [`commit_chunks`](ingestion/src/semantic_index.rs#L10-L20) retries the \
+                      upsert **three** times. Then it gives up.\n";
+        assert_eq!(
+            described_as(answer, "commit_chunks").as_deref(),
+            Some("`commit_chunks` retries the upsert three times.")
+        );
+        assert_eq!(described_as(answer, "commit"), None);
+        assert_eq!(described_as(answer, "retry"), None);
+    }
+
+    #[test]
+    fn test_valid_citations_with_a_named_symbol_are_recorded_once() {
+        let answer = "`commit_chunks` retries the upsert three times. `upsert` writes the points.";
+        let citations = CitationReport {
+            citations: vec![
+                citation("src/index.rs", Some("commit_chunks"), CitationStatus::Valid),
+                citation(
+                    "src/index.rs",
+                    Some("commit_chunks"),
+                    CitationStatus::Adjusted,
+                ),
+                citation("src/qdrant.rs", Some("upsert"), CitationStatus::Invalid),
+                citation("src/index.rs", Some("flush"), CitationStatus::Valid),
+                citation("src/lib.rs", None, CitationStatus::Valid),
+            ],
+        };
+        let mut glossary = Glossary::default();
+        assert_eq!(glossary.record_answer(3, answer, &citations), 1);
+        assert_eq!(
+            glossary.entries,
+            vec![GlossaryEntry {
+                symbol: "commit_chunks".to_string(),
+                path: "src/index.rs".to_string(),
+                description: "`commit_chunks` retries the upsert three times.".to_string(),
+                question_id: Some(3),
+                conflicts: Vec::new(),
+            }]
+        );
+        // the same description again changes nothing.
+        assert_eq!(glossary.record_answer(5, answer, &citations), 0);
+    }
+
+    #[test]
+    fn test_disagreeing_descriptions_are_conflicts_until_reconciled() {
+        let mut glossary = Glossary::default();
+        glossary.record(
+            1,
+            "commit_chunks",
+            "src/index.rs",
+            "`commit_chunks` retries the upsert three times with a backoff.".to_string(),
+        );
+        assert!(!glossary.record(
+            2,
+            "commit_chunks",
+            "src/index.rs",
+            "`commit_chunks` retries the upsert three times, with a backoff between tries."
+                .to_string()
+        ));
+        assert!(glossary.record(
+            4,
+            "commit_chunks",
+            "src/index.rs",
+            "`commit_chunks` drops the batch after the first failure.".to_string()
+        ));
+        // another path is another symbol.
+        assert!(glossary.record(
+            4,
+            "commit_chunks",
+            "src/legacy.rs",
+            "`commit_chunks` drops the batch.".to_string()
+        ));
+
+        let conflicts = glossary.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].conflicts[0].question_id, 4);
+        assert!(glossary.render().contains(
+            "  - also described as: `commit_chunks` drops the batch after the first failure."
+        ));
+
+        let summary = "- The chunks are committed in batches.\n\n## Reconciled glossary\n\
+                       - `commit_chunks`: Retries the upsert three times, then drops the batch.\n\n\
+                       ## Critical Clarifying Questions\n- Is three retries enough?";
+        let (summary, reconciled) = split_reconciled(summary);
+        assert_eq!(
+            summary,
+            "- The chunks are committed in batches.\n\n## Critical Clarifying Questions\n- Is three retries enough?"
+        );
+        assert_eq!(glossary.reconcile(&reconciled), 2);
+        assert!(glossary.conflicts().is_empty());
+        assert!(glossary.entries.iter().all(|entry| entry.description
+            == "Retries the upsert three times, then drops the batch."
+            && entry.question_id.is_none()));
+    }
+
+    #[test]
+    fn test_relevant_entries_are_the_ones_the_code_mentions() {
+        let mut glossary = Glossary::default();
+        glossary.record(
+            1,
+            "commit_chunks",
+            "src/index.rs",
+            "commits the chunks.".to_string(),
+        );
+        glossary.record(
+            1,
+            "upsert",
+            "src/qdrant.rs",
+            "writes the points.".to_string(),
+        );
+        let relevant = glossary.relevant([
+            "let points = commit_chunks_batch(&chunks);",
+            "self.upsert(points)",
+        ]);
+        assert_eq!(
+            relevant
+                .iter()
+                .map(|entry| entry.symbol.as_str())
+                .collect::<Vec<_>>(),
+            vec!["upsert"]
+        );
+        assert!(glossary.relevant(["fn main() {}"]).is_empty());
+    }
+
+    #[test]
+    fn test_appendix() {
+        assert_eq!(Glossary::default().render_appendix(), None);
+        let mut glossary = Glossary::default();
+        glossary.record(
+            1,
+            "upsert",
+            "src/qdrant.rs",
+            "`upsert` writes the points.".to_string(),
+        );
+        assert_eq!(
+            glossary.render_appendix().as_deref(),
+            Some("## Glossary\n\n- `upsert` (src/qdrant.rs): `upsert` writes the points.\n")
+        );
+    }
+
+    #[test]
+    fn test_long_descriptions_are_cut() {
+        let long = format!("`upsert` {}", "writes the points ".repeat(30));
+        let described = described_as(&long, "upsert").unwrap();
+        assert!(described.ends_with("..."));
+        assert_eq!(described.chars().count(), MAX_DESCRIPTION_CHARS + 3);
+    }
+}
@@ -0,0 +1,490 @@
+// Generated tasks sometimes name files, modules or types the repo doesn't have. The components a
+// task mentions are matched against the indexed paths and the repo summary, a task whose
+// mentions don't all match closely enough is ungrounded.
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::Task;
+use crate::repo_summary::RepoSummary;
+
+/// Default confidence a mention needs to match a component of the repo.
+pub const DEFAULT_GROUNDING_CONFIDENCE: f32 = 0.7;
+
+/// Closest components proposed for an ungrounded mention.
+pub const MAX_CLOSEST_COMPONENTS: usize = 3;
+
+// parts of a mention shorter than this can't match as a prefix of a longer one, `db` isn't `dbg`.
+const MIN_PREFIX_LEN: usize = 4;
+
+// plain words of a query shorter than this, or in the list below, don't name components.
+const MIN_QUERY_WORD_LEN: usize = 4;
+const QUERY_STOP_WORDS: &[&str] = &[
+    "where", "what", "which", "when", "does", "done", "there", "this", "that", "these", "those",
+    "with", "from", "have", "into", "about", "should", "would", "could", "their", "code", "file",
+    "files", "work", "works", "used", "use", "uses", "find", "show", "explain",
+];
+
+// extensions a word ends with to be read as a file name, `e.g.` and version numbers aren't files.
+const FILE_EXTENSIONS: &[&str] = &[
+    "rs", "go", "py", "js", "jsx", "ts", "tsx", "java", "kt", "rb", "c", "h", "cc", "cpp", "hpp",
+    "cs", "swift", "php", "scala", "toml", "yaml", "yml", "json", "sql", "proto", "md",
+];
+
+/// Body of `GET /repos/{repo}/paths`, the relative paths of the files of the index.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct IndexedPaths {
+    pub paths: Vec<String>,
+}
+
+/// The closest component of the repo to a mention of a task, None when the repo has none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MentionMatch {
+    pub mention: String,
+    pub component: Option<String>,
+    pub confidence: f32,
+}
+
+/// How the components a task mentions matched the repo, stored on the task node.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct TaskGrounding {
+    pub grounded: bool,
+    // the task comes from the retry that was given the closest real components.
+    #[serde(default)]
+    pub repaired: bool,
+    #[serde(default)]
+    pub mentions: Vec<MentionMatch>,
+}
+
+impl TaskGrounding {
+    /// Mentions below the confidence threshold, with the closest components to them.
+    pub fn ungrounded_mentions(&self, threshold: f32) -> impl Iterator<Item = &MentionMatch> {
+        self.mentions
+            .iter()
+            .filter(move |mention| mention.confidence < threshold)
+    }
+
+    pub fn render(&self) -> String {
+        let state = if self.grounded {
+            "grounded"
+        } else {
+            "ungrounded"
+        };
+        let state = if self.repaired {
+            format!("{} after repair", state)
+        } else {
+            state.to_string()
+        };
+        match self.mentions.is_empty() {
+            true => state,
+            false => format!(
+                "{}: {}",
+                state,
+                self.mentions
+                    .iter()
+                    .map(|m| format!("{} ({:.2})", m.mention, m.confidence))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// The components of a repo a task can mention: its indexed paths, and the top level
+/// directories and frameworks of its summary.
+#[derive(Debug, Clone, Default)]
+pub struct GroundingIndex {
+    components: Vec<(String, Vec<String>)>,
+}
+
+impl GroundingIndex {
+    pub fn new(paths: Vec<String>, summary: Option<&RepoSummary>) -> Self {
+        let summary_components = summary.into_iter().flat_map(|summary| {
+            summary
+                .directories
+                .iter()
+                .map(|directory| directory.path.clone())
+                .chain(summary.frameworks.iter().cloned())
+        });
+        let components = paths
+            .into_iter()
+            .chain(summary_components)
+            .map(|component| {
+                let parts = parts(&component);
+                (component, parts)
+            })
+            .collect();
+        Self { components }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    /// The component closest to the mention.
+    pub fn match_mention(&self, mention: &str) -> MentionMatch {
+        let best = self.ranked(mention).into_iter().next();
+        MentionMatch {
+            mention: mention.to_string(),
+            component: best.map(|(component, _)| component.to_string()),
+            confidence: best.map_or(0.0, |(_, confidence)| confidence),
+        }
+    }
+
+    /// The `n` components closest to the mention, closest first.
+    pub fn closest(&self, mention: &str, n: usize) -> Vec<String> {
+        self.ranked(mention)
+            .into_iter()
+            .take(n)
+            .map(|(component, _)| component.to_string())
+            .collect()
+    }
+
+    /// Matches the components the task and its subtasks mention, the task is grounded when all of
+    /// them reach `threshold`. A task that names no component has nothing to check and is grounded.
+    pub fn ground(&self, task: &Task, threshold: f32) -> TaskGrounding {
+        let mentions = task_mentions(task)
+            .iter()
+            .map(|mention| self.match_mention(mention))
+            .collect::<Vec<_>>();
+        TaskGrounding {
+            grounded: mentions.iter().all(|m| m.confidence >= threshold),
+            repaired: false,
+            mentions,
+        }
+    }
+
+    /// How well a query names components of the repo: the best match of its mentions and of its
+    /// longer plain words. 0 for a query without any, e.g. a greeting.
+    pub fn query_confidence(&self, query: &str) -> f32 {
+        let mut terms = extract_mentions(query);
+        for word in query.split_whitespace() {
+            let word = word
+                .trim_matches(|c: char| !c.is_alphanumeric() && c != '_')
+                .to_lowercase();
+            if word.chars().count() >= MIN_QUERY_WORD_LEN
+                && !QUERY_STOP_WORDS.contains(&word.as_str())
+                && !terms.iter().any(|term| term.to_lowercase() == word)
+            {
+                terms.push(word);
+            }
+        }
+        terms
+            .iter()
+            .map(|term| self.match_mention(term).confidence)
+            .fold(0.0, f32::max)
+    }
+
+    fn ranked(&self, mention: &str) -> Vec<(&str, f32)> {
+        let mention_parts = parts(mention);
+        let normalized = normalize_path(mention);
+        let mut ranked = self
+            .components
+            .iter()
+            .map(|(component, component_parts)| {
+                let confidence = if is_path_suffix(component, &normalized) {
+                    1.0
+                } else {
+                    coverage(&mention_parts, component_parts)
+                };
+                (component.as_str(), confidence)
+            })
+            .collect::<Vec<_>>();
+        // shorter components first on ties, `src/auth` before `src/auth/tests/fixtures.rs`.
+        ranked.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then(a.0.len().cmp(&b.0.len()))
+                .then(a.0.cmp(b.0))
+        });
+        ranked
+    }
+}
+
+/// The components the task and its subtasks name: code spans, paths, file names and identifiers
+/// in CamelCase or snake_case, in the order they first appear. Plain words aren't checked.
+pub fn task_mentions(task: &Task) -> Vec<String> {
+    let mut mentions = Vec::new();
+    for text in Some(&task.task)
+        .into_iter()
+        .chain(task.subtasks.iter().map(|subtask| &subtask.subtask))
+    {
+        for mention in extract_mentions(text) {
+            if !mentions.contains(&mention) {
+                mentions.push(mention);
+            }
+        }
+    }
+    mentions
+}
+
+/// The component-like words of a text, see `task_mentions`.
+pub fn extract_mentions(text: &str) -> Vec<String> {
+    let mut mentions = Vec::new();
+    let mut push = |mention: &str| {
+        let mention = mention.trim_matches(|c: char| !c.is_alphanumeric() && c != '_');
+        if !mention.is_empty() && !mentions.iter().any(|m: &String| m == mention) {
+            mentions.push(mention.to_string());
+        }
+    };
+
+    // code spans are mentions as a whole, whatever they look like.
+    let mut rest = text;
+    let mut outside = String::new();
+    while let Some(start) = rest.find('`') {
+        outside.push_str(&rest[..start]);
+        match rest[start + 1..].find('`') {
+            Some(end) => {
+                push(&rest[start + 1..start + 1 + end]);
+                outside.push(' ');
+                rest = &rest[start + end + 2..];
+            }
+            None => {
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    outside.push_str(rest);
+
+    for word in outside.split_whitespace() {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '/');
+        if is_component_like(word) {
+            push(word);
+        }
+    }
+    mentions
+}
+
+fn is_component_like(word: &str) -> bool {
+    if word.len() < 3 || word.starts_with("http") {
+        return false;
+    }
+    let is_path = word.contains('/') && word.split('/').filter(|p| !p.is_empty()).count() > 1;
+    let is_file = word.rsplit_once('.').map_or(false, |(stem, extension)| {
+        !stem.is_empty() && FILE_EXTENSIONS.contains(&extension)
+    });
+    let is_snake_case = word.contains('_') && word.chars().any(|c| c.is_alphabetic());
+    // an uppercase letter after a lowercase one, `TokenRefresher` or `getUser` but not `API`.
+    let is_camel_case = word
+        .chars()
+        .zip(word.chars().skip(1))
+        .any(|(a, b)| a.is_lowercase() && b.is_uppercase());
+    is_path || is_file || is_snake_case || is_camel_case
+}
+
+/// Lowercase words of a mention or component: split on separators and case changes.
+fn parts(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut previous: Option<char> = None;
+    for c in text.chars() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                parts.push(std::mem::take(&mut current));
+            }
+        } else {
+            if c.is_uppercase()
+                && previous.map_or(false, |p| p.is_lowercase())
+                && !current.is_empty()
+            {
+                parts.push(std::mem::take(&mut current));
+            }
+            current.extend(c.to_lowercase());
+        }
+        previous = Some(c);
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+fn normalize_path(text: &str) -> String {
+    text.trim()
+        .trim_start_matches("./")
+        .replace('\\', "/")
+        .to_lowercase()
+}
+
+// `auth/session.rs` names `src/auth/session.rs`, but `session.rs` doesn't name `src/user_session.rs`.
+fn is_path_suffix(component: &str, mention: &str) -> bool {
+    let component = normalize_path(component);
+    !mention.is_empty() && (component == mention || component.ends_with(&format!("/{}", mention)))
+}
+
+/// The mean of the best similarity of each part of the mention to a part of the component.
+fn coverage(mention_parts: &[String], component_parts: &[String]) -> f32 {
+    if mention_parts.is_empty() {
+        return 0.0;
+    }
+    let total = mention_parts
+        .iter()
+        .map(|part| {
+            component_parts
+                .iter()
+                .map(|candidate| part_similarity(part, candidate))
+                .fold(0.0, f32::max)
+        })
+        .sum::<f32>();
+    total / mention_parts.len() as f32
+}
+
+fn part_similarity(a: &str, b: &str) -> f32 {
+    if a == b {
+        return 1.0;
+    }
+    let (shorter, longer) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if shorter.len() >= MIN_PREFIX_LEN && longer.starts_with(shorter) {
+        return 0.9;
+    }
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    1.0 - levenshtein(&a, &b) as f32 / a.len().max(b.len()) as f32
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Subtask;
+    use crate::repo_summary::DirectoryStats;
+
+    fn index() -> GroundingIndex {
+        let paths = [
+            "src/auth/session.rs",
+            "src/auth/token_refresh.rs",
+            "src/api/routes.rs",
+            "src/db/users.rs",
+        ];
+        let summary = RepoSummary {
+            repo_name: "acme/api".to_string(),
+            directories: vec![DirectoryStats {
+                path: "src".to_string(),
+                files: 4,
+                lines: 400,
+            }],
+            frameworks: vec!["Axum".to_string()],
+            ..Default::default()
+        };
+        GroundingIndex::new(
+            paths.iter().map(|p| p.to_string()).collect(),
+            Some(&summary),
+        )
+    }
+
+    fn task(task: &str, subtasks: &[&str]) -> Task {
+        Task {
+            task: task.to_string(),
+            subtasks: subtasks
+                .iter()
+                .map(|subtask| Subtask {
+                    subtask: subtask.to_string(),
+                    questions: vec![],
+                })
+                .collect(),
+            grounding: None,
+        }
+    }
+
+    #[test]
+    fn test_mentions_of_a_task() {
+        assert_eq!(
+            extract_mentions(
+                "Update `refresh_token` in src/auth/session.rs and the TokenRefresher, e.g. for the API."
+            ),
+            vec!["refresh_token", "src/auth/session.rs", "TokenRefresher"]
+        );
+        assert!(
+            extract_mentions("Make the login page faster, see https://acme.dev/docs.").is_empty()
+        );
+
+        let task = task(
+            "Fix the `TokenRefresher`",
+            &["Read routes.rs", "Check the TokenRefresher again"],
+        );
+        assert_eq!(task_mentions(&task), vec!["TokenRefresher", "routes.rs"]);
+    }
+
+    #[test]
+    fn test_mentions_match_paths_and_summary_components() {
+        let index = index();
+        let exact = index.match_mention("auth/session.rs");
+        assert_eq!(exact.component.as_deref(), Some("src/auth/session.rs"));
+        assert_eq!(exact.confidence, 1.0);
+
+        let identifier = index.match_mention("TokenRefresher");
+        assert_eq!(
+            identifier.component.as_deref(),
+            Some("src/auth/token_refresh.rs")
+        );
+        assert!(identifier.confidence >= DEFAULT_GROUNDING_CONFIDENCE);
+
+        assert_eq!(index.match_mention("axum").confidence, 1.0);
+
+        let missing = index.match_mention("PaymentGateway");
+        assert!(missing.confidence < DEFAULT_GROUNDING_CONFIDENCE);
+        assert_eq!(index.closest("UserRepository", 1), vec!["src/db/users.rs"]);
+    }
+
+    #[test]
+    fn test_query_confidence() {
+        let index = index();
+        assert!(
+            index.query_confidence("Where is the session token refreshed?")
+                >= DEFAULT_GROUNDING_CONFIDENCE
+        );
+        assert!(
+            index.query_confidence("How do I deploy this to kubernetes?")
+                < DEFAULT_GROUNDING_CONFIDENCE
+        );
+        assert_eq!(index.query_confidence("Hi!"), 0.0);
+    }
+
+    #[test]
+    fn test_tasks_are_grounded_when_all_their_mentions_match() {
+        let index = index();
+        let grounded = index.ground(
+            &task(
+                "Refresh the session in `session.rs`",
+                &["Update the TokenRefresher"],
+            ),
+            DEFAULT_GROUNDING_CONFIDENCE,
+        );
+        assert!(grounded.grounded);
+        assert_eq!(grounded.mentions.len(), 2);
+
+        let ungrounded = index.ground(
+            &task(
+                "Retry failed charges",
+                &[
+                    "Add a backoff to the `PaymentGateway`",
+                    "Log it in routes.rs",
+                ],
+            ),
+            DEFAULT_GROUNDING_CONFIDENCE,
+        );
+        assert!(!ungrounded.grounded);
+        let missing = ungrounded
+            .ungrounded_mentions(DEFAULT_GROUNDING_CONFIDENCE)
+            .map(|m| m.mention.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(missing, vec!["PaymentGateway"]);
+
+        let plain = index.ground(
+            &task("Make logins faster", &[]),
+            DEFAULT_GROUNDING_CONFIDENCE,
+        );
+        assert!(plain.grounded && plain.mentions.is_empty());
+        assert!(GroundingIndex::new(vec![], None).is_empty());
+    }
+}
@@ -2,15 +2,59 @@ use std::fmt;
 use std::ops::Range;
 
 
+pub mod anchors;
+pub mod answer_scope;
+pub mod apply_policy;
 pub mod ast;
+pub mod attachments;
+pub mod auth;
+pub mod branch;
+pub mod budget;
+pub mod capabilities;
+pub mod change_plan;
+pub mod citations;
+pub mod clarification;
+pub mod clock;
+pub mod code_chunk;
+pub mod codeowners;
+pub mod compression;
+pub mod dependencies;
+pub mod duplicates;
+pub mod embedded_lang;
+pub mod feedback;
+pub mod files_involved;
+pub mod freshness;
+pub mod generation;
+pub mod glossary;
+pub mod grounding;
 pub mod hasher;
+pub mod language;
+pub mod links;
 pub mod llm_gateway;
+pub mod local_services;
 pub mod models;
+pub mod path_class;
+pub mod preferences;
+pub mod prompt_versions;
 pub mod prompts;
+pub mod reconnect;
+pub mod redaction;
+pub mod repo_summary;
+pub mod retrieval_feedback;
+pub mod run_manifest;
+pub mod search_batch;
 pub mod service_interaction;
+pub mod symbol_payload;
+pub mod terminology;
 pub mod ai_util;
 pub mod task_graph;
 pub mod tokenizer_onnx;
+pub mod transport;
+pub mod verification;
+pub mod metrics;
+pub mod telemetry;
+pub mod timings;
+pub mod shutdown;
 pub mod docker;
 pub mod prompt_string_generator {
     use std::future::Future;
@@ -33,6 +77,38 @@ pub struct CodeContext {
     pub repo: String, // Ensure RepoRef is accessible or defined here.
     pub branch: Option<String>,
     pub ranges: Vec<Range<usize>>,
+    // true when the file was pinned by the user rather than found by the agent.
+    #[serde(default)]
+    pub pinned: bool,
+    // owners of the path in the CODEOWNERS file of the repo, set by the coordinator.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub owners: Vec<String>,
+}
+
+/// Typed outcome of the code understanding agent for a single question.
+/// Lets callers tell a real answer apart from the agent giving up, without parsing the answer prose.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AnswerOutcome {
+    Answered {
+        answer: String,
+        contexts: Vec<CodeContext>,
+    },
+    // The agent couldn't find relevant code, keeps what it tried so the question can be reformulated.
+    NotFound {
+        attempted_queries: Vec<String>,
+        closest_paths: Vec<String>,
+    },
+    NeedsClarification {
+        question: String,
+        // short answers the user can pick, only set by the agent asking with `ask_user`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        options: Vec<String>,
+        // the run stopped to ask and resumes with the `clarification` of the next request for
+        // the question, see `clarification`. Otherwise the agent answered with the question.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        suspended: bool,
+    },
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
@@ -40,6 +116,61 @@ pub struct CodeUnderstanding {
     pub context: Vec<CodeContext>,
     pub question: String,
     pub answer: String,
+    // Older code understanding builds don't send the outcome, treat those as plain answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<AnswerOutcome>,
+    // Pinned paths from the request that don't exist in the index.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing_pinned_paths: Vec<String>,
+    // What the LLM calls of the answer cost in USD, not sent by builds without budgets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    // Where the time of the answer went, code understanding sets the retrieval and the answer,
+    // the coordinator the rest. Not sent by builds without timings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<timings::PhaseTimings>,
+    // Paths out of the scope of the conversation the answer recommended changing, set by the
+    // coordinator.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scope_violations: Vec<answer_scope::ScopeViolation>,
+    // The files and lines the answer cited, resolved by code understanding. The coordinator adds
+    // the scope checks and the urls of the links.
+    #[serde(default, skip_serializing_if = "citations::CitationReport::is_empty")]
+    pub citations: citations::CitationReport,
+    // How to check the answer against the cited code, asked for with `include_verification`.
+    // Empty when the model gave no grounded steps.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub verification: Vec<verification::VerificationStep>,
+    // The prompt templates code understanding built the answer with, at their versions. Not sent
+    // by builds without prompt versions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prompt_versions: Vec<prompt_versions::PromptVersion>,
+    // The valid, in scope files the answer cites with their roles, set by the coordinator along
+    // with the header it adds to the answer. Empty for answers with less than two citations.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files_involved: Vec<files_involved::FileInvolved>,
+    // What the repo was indexed with, the answers of a paths-only index were found by path and
+    // keyword search and may miss code. Not sent for full indexes.
+    #[serde(default, skip_serializing_if = "run_manifest::IndexMode::is_full")]
+    pub index_mode: run_manifest::IndexMode,
+}
+
+impl CodeUnderstanding {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.outcome, Some(AnswerOutcome::NotFound { .. }))
+    }
+
+    /// The question the agent stopped its run to ask, None when it answered.
+    pub fn clarification(&self) -> Option<clarification::Clarification> {
+        match &self.outcome {
+            Some(AnswerOutcome::NeedsClarification {
+                question,
+                options,
+                suspended: true,
+            }) => Some(clarification::Clarification::new(question, options)),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for CodeUnderstanding {
@@ -53,6 +184,9 @@ impl fmt::Display for CodeUnderstanding {
                 write!(f, "\tBranch: {}\n", branch)?;
             }
             write!(f, "\tHidden: {}\n", context.hidden)?;
+            if context.pinned {
+                write!(f, "\tPinned: true\n")?;
+            }
             write!(f, "\tRanges: {:?}\n", context.ranges)?;
         }
         Ok(())
@@ -66,30 +200,9 @@ pub struct CodeUnderstandings {
     pub qna: Vec<CodeUnderstanding>,
 }
 
-// Represents a code chunk
-#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct CodeChunk {
-    pub path: String,
-    #[serde(rename = "snippet")]
-    pub snippet: String,
-    #[serde(rename = "start")]
-    pub start_line: usize,
-    #[serde(rename = "end")]
-    pub end_line: usize,
-}
-
-impl std::fmt::Display for CodeChunk {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let lines: Vec<&str> = self.snippet.lines().collect();
-        for (i, line) in lines.iter().enumerate() {
-            // Calculate the line number starting from start_line
-            let line_number = i + self.start_line;
-            writeln!(f, "{}: {}", line_number, line)?;
-        }
-
-        Ok(())
-    }
-}
+/// The chunk of `/span`, now the shared `models::CodeChunk`.
+#[deprecated(note = "use common::models::CodeChunk")]
+pub type CodeChunk = models::CodeChunk;
 
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct TokenInfoRequest {
@@ -0,0 +1,325 @@
+// Rewrites the repo relative links of an answer, `[foo](src/foo.rs#L50)` or `#L50-L60`, and the
+// quoted code blocks to absolute urls of the web UI of the repo, so they work outside our own UI.
+// The parsing of the links and quoted code blocks is shared with the scope guardrails and the
+// citation report of the answers.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Commit a repo was indexed at, empty for repos indexed before it was recorded.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct IndexedCommit {
+    pub repo_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+}
+
+/// Url of a file in the web UI of the repo host, e.g.
+/// `https://github.com/{org}/{repo}/blob/{commit}/{path}#L{start}-L{end}`.
+/// `{org}` and `{repo}` are split from an `org/repo` repo name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebUrlTemplate(String);
+
+impl WebUrlTemplate {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self(template.into())
+    }
+
+    /// Url of `path` at `commit`, `lines` are 1-based and inclusive.
+    /// Without lines the anchor of the template is dropped.
+    pub fn url(
+        &self,
+        repo_name: &str,
+        commit: &str,
+        path: &str,
+        lines: Option<(usize, usize)>,
+    ) -> String {
+        let (org, repo) = repo_name.rsplit_once('/').unwrap_or(("", repo_name));
+        let template = match lines {
+            Some(_) => self.0.as_str(),
+            None => self.0.split('#').next().unwrap_or_default(),
+        };
+        let (start, end) = lines.unwrap_or_default();
+        template
+            .replace("{org}", org)
+            .replace("{repo}", repo)
+            .replace("{commit}", commit)
+            .replace("{path}", &encode_path(path))
+            .replace("{start}", &start.to_string())
+            .replace("{end}", &end.to_string())
+    }
+
+    /// Rewrites the relative links outside of code blocks and adds a link to the source above
+    /// every quoted code block. Absolute urls, anchors of the same page, the content of code
+    /// blocks and generated code are left untouched.
+    pub fn rewrite_links(&self, markdown: &str, repo_name: &str, commit: &str) -> String {
+        self.rewrite_links_with(markdown, repo_name, commit, |_, _, url| Some(url))
+    }
+
+    /// Like `rewrite_links`, `accept` gets the path, lines and url of every citation and returns
+    /// the url it is rewritten to, None leaves the citation as it is.
+    pub fn rewrite_links_with<F>(
+        &self,
+        markdown: &str,
+        repo_name: &str,
+        commit: &str,
+        mut accept: F,
+    ) -> String
+    where
+        F: FnMut(&str, Option<(usize, usize)>, String) -> Option<String>,
+    {
+        rewrite_citations(markdown, |site, path, lines| {
+            let url = accept(path, lines, self.url(repo_name, commit, path, lines))?;
+            Some(match site {
+                CitationSite::Link => url,
+                CitationSite::QuotedCode(line) => {
+                    let (start, end) = lines.unwrap_or_default();
+                    format!("[{}#L{}-L{}]({})\n{}", path, start, end, url, line)
+                }
+            })
+        })
+    }
+}
+
+// Where a citation was found in the markdown.
+pub(crate) enum CitationSite<'a> {
+    // a repo relative link outside of code blocks, replaced by its new target.
+    Link,
+    // the line opening a quoted code block, replaced as a whole.
+    QuotedCode(&'a str),
+}
+
+// Rebuilds the markdown with the citations `rewrite` returns a replacement for, it gets the path
+// and 1-based lines of every citation. The content of code blocks is copied as it is.
+pub(crate) fn rewrite_citations<F>(markdown: &str, mut rewrite: F) -> String
+where
+    F: FnMut(CitationSite<'_>, &str, Option<(usize, usize)>) -> Option<String>,
+{
+    let mut rewritten = String::with_capacity(markdown.len());
+    let mut fence: Option<&str> = None;
+
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match fence {
+            Some(marker) => {
+                if trimmed.trim_end() == marker {
+                    fence = None;
+                }
+                rewritten.push_str(line);
+            }
+            None => match fence_marker(trimmed) {
+                Some(marker) => {
+                    fence = Some(marker);
+                    let replaced = quoted_source(&trimmed[marker.len()..]).and_then(
+                        |(path, lines)| rewrite(CitationSite::QuotedCode(line), path, Some(lines)),
+                    );
+                    rewritten.push_str(replaced.as_deref().unwrap_or(line));
+                }
+                None => {
+                    let mut end = 0;
+                    for target in link_targets(line) {
+                        rewritten.push_str(&line[end..target.start]);
+                        let replaced = relative_link(&line[target.clone()])
+                            .and_then(|(path, lines)| rewrite(CitationSite::Link, &path, lines));
+                        rewritten.push_str(replaced.as_deref().unwrap_or(&line[target.clone()]));
+                        end = target.end;
+                    }
+                    rewritten.push_str(&line[end..]);
+                }
+            },
+        }
+    }
+    rewritten
+}
+
+// The ``` or ~~~ run opening a code block, None when the line doesn't open one.
+pub(crate) fn fence_marker(trimmed: &str) -> Option<&str> {
+    if !trimmed.starts_with("```") && !trimmed.starts_with("~~~") {
+        return None;
+    }
+    let fence = trimmed.as_bytes()[0] as char;
+    Some(&trimmed[..trimmed.find(|c| c != fence).unwrap_or(trimmed.len())])
+}
+
+// Byte ranges of the link targets of a line, `src/foo.rs#L50` in `[foo](src/foo.rs#L50)`.
+pub(crate) fn link_targets(line: &str) -> Vec<Range<usize>> {
+    let mut targets = Vec::new();
+    let mut start = 0;
+    while let Some(open) = line[start..].find("](") {
+        let target_start = start + open + 2;
+        let Some(close) = line[target_start..].find(')') else {
+            break;
+        };
+        targets.push(target_start..target_start + close);
+        start = target_start + close;
+    }
+    targets
+}
+
+// Path and 1-based lines of a link target, None when the target isn't a repo relative path.
+pub(crate) fn relative_link(target: &str) -> Option<(String, Option<(usize, usize)>)> {
+    if target.is_empty()
+        || target.starts_with('#')
+        || target.contains("://")
+        || target.starts_with("mailto:")
+        || target.contains(char::is_whitespace)
+    {
+        return None;
+    }
+    let (path, lines) = match target.split_once('#') {
+        Some((path, anchor)) => (path, Some(line_anchor(anchor)?)),
+        None => (target, None),
+    };
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+    // the answers link to the paths as they are, a percent encoded path is decoded first.
+    Some((decode_path(path), lines))
+}
+
+// `L50` or `L50-L60`, GitLab style `L50-60` too.
+fn line_anchor(anchor: &str) -> Option<(usize, usize)> {
+    let anchor = anchor.strip_prefix('L')?;
+    match anchor.split_once('-') {
+        Some((start, end)) => {
+            let start = start.parse().ok()?;
+            let end = end.strip_prefix('L').unwrap_or(end).parse().ok()?;
+            Some((start, end))
+        }
+        None => {
+            let line = anchor.parse().ok()?;
+            Some((line, line))
+        }
+    }
+}
+
+// Path and 1-based lines of a quoted code block, from the info string written by code
+// understanding: `type:Quoted,lang:Rust,path:src/foo.rs,lines:49-59` with 0-based lines.
+pub(crate) fn quoted_source(info: &str) -> Option<(&str, (usize, usize))> {
+    let info = info.trim();
+    if !info.starts_with("type:Quoted,") {
+        return None;
+    }
+    let path_start = info.find(",path:")? + ",path:".len();
+    let lines_start = info.rfind(",lines:")?;
+    let path = info.get(path_start..lines_start)?;
+    let (start, end) = info[lines_start + ",lines:".len()..].split_once('-')?;
+    let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?);
+    (!path.is_empty()).then_some((path, (start + 1, end + 1)))
+}
+
+// Percent encodes every segment of the path, the separators are kept.
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn decode_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GITHUB: &str = "https://github.com/{org}/{repo}/blob/{commit}/{path}#L{start}-L{end}";
+    const GITLAB: &str = "https://gitlab.com/{org}/{repo}/-/blob/{commit}/{path}#L{start}-{end}";
+
+    #[test]
+    fn test_github_links_are_rewritten() {
+        let template = WebUrlTemplate::new(GITHUB);
+        let answer = "The [handler](src/foo.rs#L50) calls [parse](./src/parse.rs#L10-L20) \
+                      from [the crate](src/lib.rs), see [below](#usage) or [docs](https://docs.rs).\n";
+
+        assert_eq!(
+            template.rewrite_links(answer, "acme/api", "abc123"),
+            "The [handler](https://github.com/acme/api/blob/abc123/src/foo.rs#L50-L50) calls \
+             [parse](https://github.com/acme/api/blob/abc123/src/parse.rs#L10-L20) \
+             from [the crate](https://github.com/acme/api/blob/abc123/src/lib.rs), \
+             see [below](#usage) or [docs](https://docs.rs).\n"
+        );
+    }
+
+    #[test]
+    fn test_gitlab_links_are_rewritten() {
+        let template = WebUrlTemplate::new(GITLAB);
+        let answer = "See [config](config/app.yaml#L3-L7).";
+
+        assert_eq!(
+            template.rewrite_links(answer, "group/service", "def456"),
+            "See [config](https://gitlab.com/group/service/-/blob/def456/config/app.yaml#L3-7)."
+        );
+    }
+
+    #[test]
+    fn test_paths_with_spaces_are_encoded() {
+        let template = WebUrlTemplate::new(GITHUB);
+
+        assert_eq!(
+            template.url(
+                "acme/api",
+                "abc123",
+                "docs/user guide/intro #1.md",
+                Some((4, 4))
+            ),
+            "https://github.com/acme/api/blob/abc123/docs/user%20guide/intro%20%231.md#L4-L4"
+        );
+        assert_eq!(
+            template.rewrite_links("[intro](docs/user%20guide.md#L2)", "acme/api", "abc123"),
+            "[intro](https://github.com/acme/api/blob/abc123/docs/user%20guide.md#L2-L2)"
+        );
+    }
+
+    #[test]
+    fn test_quoted_code_gets_a_source_link_and_generated_code_is_skipped() {
+        let template = WebUrlTemplate::new(GITHUB);
+        let answer = "Here:\n\
+                      ```type:Quoted,lang:Rust,path:src/foo.rs,lines:49-59\n\
+                      // [not](a/link.rs)\n\
+                      ```\n\
+                      ```type:Generated,lang:Rust,path:,lines:0-0\n\
+                      let x = [a](b.rs);\n\
+                      ```\n";
+
+        assert_eq!(
+            template.rewrite_links(answer, "acme/api", "abc123"),
+            "Here:\n\
+             [src/foo.rs#L50-L60](https://github.com/acme/api/blob/abc123/src/foo.rs#L50-L60)\n\
+             ```type:Quoted,lang:Rust,path:src/foo.rs,lines:49-59\n\
+             // [not](a/link.rs)\n\
+             ```\n\
+             ```type:Generated,lang:Rust,path:,lines:0-0\n\
+             let x = [a](b.rs);\n\
+             ```\n"
+        );
+    }
+}
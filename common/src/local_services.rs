@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Serialize};
+use warp::Filter;
+
+use crate::generation::IndexGenerationGone;
+use crate::{auth, telemetry};
+
+// Services hosted in the same process are addressed as `local://<service>/<path>`,
+// e.g. `CODE_SEARCH_URL=local://code-search`. Requests to them never leave the process.
+pub const LOCAL_SCHEME: &str = "local";
+
+static LOCAL_SERVICES: Lazy<RwLock<HashMap<String, Arc<dyn LocalService>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalRequest {
+    pub method: String,
+    // path of the request in the target service, with the query string if any.
+    pub path_and_query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A service that can be called in-process instead of over HTTP.
+#[async_trait]
+pub trait LocalService: Send + Sync {
+    async fn call(&self, request: LocalRequest) -> LocalResponse;
+}
+
+/// Serves the requests with the route tree of a service, the same one it serves over HTTP standalone.
+pub struct WarpService<F> {
+    filter: F,
+}
+
+impl<F> WarpService<F> {
+    pub fn new(filter: F) -> Self {
+        Self { filter }
+    }
+}
+
+#[async_trait]
+impl<F> LocalService for WarpService<F>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    async fn call(&self, request: LocalRequest) -> LocalResponse {
+        // `warp::test` drives the filter on a request built in memory, without a socket.
+        let mut builder = warp::test::request()
+            .method(&request.method)
+            .path(&request.path_and_query);
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder.body(request.body).reply(&self.filter).await;
+
+        LocalResponse {
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: response.body().to_vec(),
+        }
+    }
+}
+
+/// Makes `local://<name>` URLs resolve to the service, replacing any service registered under the name.
+pub fn register(name: &str, service: Arc<dyn LocalService>) {
+    LOCAL_SERVICES
+        .write()
+        .expect("Failed to acquire write lock")
+        .insert(name.to_string(), service);
+}
+
+/// The base URL of a service registered under `name`.
+pub fn local_url(name: &str) -> String {
+    format!("{}://{}", LOCAL_SCHEME, name)
+}
+
+pub fn is_local(url: &str) -> bool {
+    url.starts_with(&format!("{}://", LOCAL_SCHEME))
+}
+
+/// Calls the registered service `name`.
+pub async fn dispatch(name: &str, request: LocalRequest) -> Result<LocalResponse> {
+    let service = LOCAL_SERVICES
+        .read()
+        .expect("Failed to acquire read lock")
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("No service is registered for {}", local_url(name)))?;
+    log::debug!(
+        "Dispatching {} {} to the local service {}",
+        request.method,
+        request.path_and_query,
+        name
+    );
+    Ok(service.call(request).await)
+}
+
+/// Sends the request, in-process when it targets a `local://` URL, over HTTP otherwise.
+pub async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    if request.url().scheme() != LOCAL_SCHEME {
+        return Ok(client.execute(request).await?);
+    }
+
+    let url = request.url();
+    let name = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Local URL {} has no service name", url))?;
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let local_request = LocalRequest {
+        method: request.method().to_string(),
+        path_and_query,
+        headers: request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default(),
+    };
+    let response = dispatch(name, local_request).await?;
+
+    let mut builder = http::Response::builder().status(response.status);
+    for (name, value) in &response.headers {
+        builder = builder.header(name, value);
+    }
+    Ok(reqwest::Response::from(builder.body(response.body)?))
+}
+
+/// Calls a `local://` URL with a json body and decodes the json response, for the services
+/// that don't share the reqwest version of `common`. Non-200 responses are errors.
+pub async fn call_json<B: Serialize, T: DeserializeOwned>(
+    method: &str,
+    url: &str,
+    body: Option<&B>,
+) -> Result<T> {
+    let target = url
+        .strip_prefix(&format!("{}://", LOCAL_SCHEME))
+        .ok_or_else(|| anyhow!("{} is not a local URL", url))?;
+    let (name, path_and_query) = match target.find(['/', '?']) {
+        Some(index) => (&target[..index], target[index..].to_string()),
+        None => (target, "/".to_string()),
+    };
+    let path_and_query = if path_and_query.starts_with('?') {
+        format!("/{}", path_and_query)
+    } else {
+        path_and_query
+    };
+
+    let mut headers = telemetry::trace_headers(&tracing::Span::current())
+        .into_iter()
+        .collect::<Vec<_>>();
+    if let Some(key) = auth::service_api_key() {
+        headers.push(("authorization".to_string(), format!("Bearer {}", key)));
+    }
+    let body = match body {
+        Some(body) => {
+            headers.push(("content-type".to_string(), "application/json".to_string()));
+            serde_json::to_vec(body)?
+        }
+        None => Vec::new(),
+    };
+
+    let response = dispatch(
+        name,
+        LocalRequest {
+            method: method.to_string(),
+            path_and_query,
+            headers,
+            body,
+        },
+    )
+    .await?;
+    // a collection of the index generation the conversation is pinned to was dropped.
+    if response.status == 410 {
+        if let Ok(gone) = serde_json::from_slice::<IndexGenerationGone>(&response.body) {
+            return Err(gone.into());
+        }
+    }
+    if response.status != 200 {
+        return Err(anyhow!(
+            "Local call to {} failed with status code: {}, Error: {}",
+            url,
+            response.status,
+            String::from_utf8_lossy(&response.body)
+        ));
+    }
+    Ok(serde_json::from_slice(&response.body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo_service() -> Arc<dyn LocalService> {
+        let route = warp::path!("echo" / String)
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::body::json::<serde_json::Value>())
+            .map(|name: String, query: HashMap<String, String>, body: serde_json::Value| {
+                warp::reply::json(&serde_json::json!({"name": name, "query": query, "body": body}))
+            });
+        Arc::new(WarpService::new(route))
+    }
+
+    #[tokio::test]
+    async fn test_local_urls_are_dispatched_in_process() {
+        register("echo-send", echo_service());
+
+        let request = reqwest::Client::new()
+            .post(format!("{}/echo/a?page=2", local_url("echo-send")))
+            .json(&serde_json::json!({"query": "retry"}));
+        let response = send(request).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.json::<serde_json::Value>().await.unwrap(),
+            serde_json::json!({"name": "a", "query": {"page": "2"}, "body": {"query": "retry"}})
+        );
+
+        // routes the service doesn't have are answered by the service, like over HTTP.
+        let response = send(reqwest::Client::new().get(format!("{}/missing", local_url("echo-send"))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_call_json() {
+        register("echo-json", echo_service());
+
+        let response: serde_json::Value = call_json(
+            "POST",
+            &format!("{}/echo/b", local_url("echo-json")),
+            Some(&serde_json::json!([1, 2])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["body"], serde_json::json!([1, 2]));
+
+        let unregistered = call_json::<(), serde_json::Value>("GET", "local://nowhere/echo/c", None).await;
+        assert!(unregistered.is_err());
+        assert!(!is_local("http://localhost:3003") && is_local("local://echo-json"));
+    }
+}
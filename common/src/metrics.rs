@@ -0,0 +1,316 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry,
+    register_int_gauge_vec_with_registry, Encoder, HistogramVec, IntCounterVec, IntGaugeVec,
+    Registry, TextEncoder,
+};
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Buckets in seconds, from fast db lookups up to long LLM backed requests.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+// All metrics are registered here so that every service exposes the same names and labels.
+lazy_static! {
+    pub static ref REGISTRY: Registry =
+        Registry::new_custom(Some("incredible".to_string()), None)
+            .expect("Failed to create metrics registry");
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "http_request_duration_seconds",
+        "HTTP request latency in seconds",
+        &["service", "route", "method", "status"],
+        LATENCY_BUCKETS.to_vec(),
+        REGISTRY
+    )
+    .expect("Failed to register http_request_duration_seconds");
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "http_requests_total",
+        "Number of HTTP requests served",
+        &["service", "route", "method", "status"],
+        REGISTRY
+    )
+    .expect("Failed to register http_requests_total");
+    pub static ref LLM_CALLS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "llm_calls_total",
+        "Number of LLM calls",
+        &["provider", "model", "status"],
+        REGISTRY
+    )
+    .expect("Failed to register llm_calls_total");
+    pub static ref LLM_TOKENS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "llm_tokens_total",
+        "Number of LLM tokens, split into prompt and completion",
+        &["provider", "model", "kind"],
+        REGISTRY
+    )
+    .expect("Failed to register llm_tokens_total");
+    pub static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "db_query_duration_seconds",
+        "Qdrant and quickwit query latency in seconds",
+        &["backend", "operation"],
+        LATENCY_BUCKETS.to_vec(),
+        REGISTRY
+    )
+    .expect("Failed to register db_query_duration_seconds");
+    pub static ref INGESTION_FILES_INDEXED: IntCounterVec = register_int_counter_vec_with_registry!(
+        "ingestion_files_indexed_total",
+        "Number of files sent to the quickwit index",
+        &["repo"],
+        REGISTRY
+    )
+    .expect("Failed to register ingestion_files_indexed_total");
+    pub static ref INGESTION_CHUNKS_COMMITTED: IntCounterVec =
+        register_int_counter_vec_with_registry!(
+            "ingestion_chunks_committed_total",
+            "Number of points committed to qdrant",
+            &["collection"],
+            REGISTRY
+        )
+        .expect("Failed to register ingestion_chunks_committed_total");
+    pub static ref INGESTION_FAILURES: IntCounterVec = register_int_counter_vec_with_registry!(
+        "ingestion_failures_total",
+        "Number of failed ingestion steps",
+        &["stage"],
+        REGISTRY
+    )
+    .expect("Failed to register ingestion_failures_total");
+    pub static ref INDEX_SIZE: IntGaugeVec = register_int_gauge_vec_with_registry!(
+        "index_files",
+        "Number of files in the last indexed tree of a repository",
+        &["repo"],
+        REGISTRY
+    )
+    .expect("Failed to register index_files");
+    pub static ref AGENT_CANCELLATIONS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "agent_cancellations_total",
+        "Number of agent runs dropped before answering, by the last action taken",
+        &["last_action"],
+        REGISTRY
+    )
+    .expect("Failed to register agent_cancellations_total");
+    pub static ref AGENT_HISTORY_TOKENS_SAVED: IntCounterVec =
+        register_int_counter_vec_with_registry!(
+            "agent_history_tokens_saved_total",
+            "Prompt tokens saved by sending the earlier agent steps as their digests",
+            &["repo"],
+            REGISTRY
+        )
+        .expect("Failed to register agent_history_tokens_saved_total");
+    pub static ref DB_ROUND_TRIPS_SAVED: IntCounterVec = register_int_counter_vec_with_registry!(
+        "db_round_trips_saved_total",
+        "Number of database requests saved by batching queries together",
+        &["backend"],
+        REGISTRY
+    )
+    .expect("Failed to register db_round_trips_saved_total");
+    pub static ref CONTENT_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "content_cache_lookups_total",
+        "Number of documents looked up in the content cache, by hit or miss",
+        &["result"],
+        REGISTRY
+    )
+    .expect("Failed to register content_cache_lookups_total");
+    pub static ref DB_RECONNECTS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "db_reconnects_total",
+        "Number of times a database client was rebuilt after a connection error, by database and result",
+        &["db", "result"],
+        REGISTRY
+    )
+    .expect("Failed to register db_reconnects_total");
+}
+
+// Keeps the route label bounded by using only the first path segment,
+// e.g. `/span/123` and `/span` are both recorded as `/span`.
+pub fn route_label(path: &str) -> String {
+    match path.trim_start_matches('/').split('/').next() {
+        Some(segment) if !segment.is_empty() => format!("/{}", segment),
+        _ => "/".to_string(),
+    }
+}
+
+pub fn observe_http_request(
+    service: &str,
+    path: &str,
+    method: &str,
+    status: u16,
+    elapsed: Duration,
+) {
+    let route = route_label(path);
+    let status = status.to_string();
+    let labels = [service, route.as_str(), method, status.as_str()];
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+}
+
+pub fn record_llm_call(provider: &str, model: &str, success: bool) {
+    let status = if success { "success" } else { "error" };
+    LLM_CALLS.with_label_values(&[provider, model, status]).inc();
+}
+
+pub fn record_llm_tokens(provider: &str, model: &str, prompt_tokens: usize, completion_tokens: usize) {
+    LLM_TOKENS
+        .with_label_values(&[provider, model, "prompt"])
+        .inc_by(prompt_tokens as u64);
+    LLM_TOKENS
+        .with_label_values(&[provider, model, "completion"])
+        .inc_by(completion_tokens as u64);
+}
+
+pub fn observe_db_query(backend: &str, operation: &str, elapsed: Duration) {
+    DB_QUERY_DURATION
+        .with_label_values(&[backend, operation])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn record_files_indexed(repo: &str, count: usize) {
+    INGESTION_FILES_INDEXED
+        .with_label_values(&[repo])
+        .inc_by(count as u64);
+}
+
+pub fn record_chunks_committed(collection: &str, count: usize) {
+    INGESTION_CHUNKS_COMMITTED
+        .with_label_values(&[collection])
+        .inc_by(count as u64);
+}
+
+pub fn record_ingestion_failure(stage: &str) {
+    INGESTION_FAILURES.with_label_values(&[stage]).inc();
+}
+
+pub fn set_index_size(repo: &str, files: usize) {
+    INDEX_SIZE.with_label_values(&[repo]).set(files as i64);
+}
+
+pub fn record_agent_cancellation(last_action: &str) {
+    AGENT_CANCELLATIONS.with_label_values(&[last_action]).inc();
+}
+
+pub fn record_history_tokens_saved(repo: &str, tokens: usize) {
+    AGENT_HISTORY_TOKENS_SAVED
+        .with_label_values(&[repo])
+        .inc_by(tokens as u64);
+}
+
+pub fn record_round_trips_saved(backend: &str, count: usize) {
+    DB_ROUND_TRIPS_SAVED
+        .with_label_values(&[backend])
+        .inc_by(count as u64);
+}
+
+pub fn record_content_cache_lookup(result: &str) {
+    CONTENT_CACHE_LOOKUPS.with_label_values(&[result]).inc();
+}
+
+pub fn record_db_reconnect(db: &str, result: &str) {
+    DB_RECONNECTS.with_label_values(&[db, result]).inc();
+}
+
+/// Encodes every registered metric in the prometheus text format.
+pub fn gather() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|e| anyhow!("Failed to encode metrics: {}", e))?;
+    String::from_utf8(buffer).map_err(|e| anyhow!("Metrics are not valid utf8: {}", e))
+}
+
+/// GET /metrics
+/// Prometheus scrape endpoint of every service, see `gather`.
+pub fn metrics_route() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| match gather() {
+            Ok(body) => Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", METRICS_CONTENT_TYPE)
+                .body(body)
+                .expect("Failed to construct response"),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(e.to_string())
+                .expect("Failed to construct response"),
+        })
+}
+
+/// Writes the metrics to a file for the node exporter textfile collector.
+/// The file is written next to the target and renamed so the collector never reads a partial file.
+pub fn write_textfile(path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("prom.tmp");
+    std::fs::write(&tmp_path, gather()?)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label(""), "/");
+        assert_eq!(route_label("/span"), "/span");
+        assert_eq!(route_label("/span/123/abc"), "/span");
+    }
+
+    #[test]
+    fn test_gather_exposes_metric_families() {
+        observe_http_request("test", "/symbols", "POST", 200, Duration::from_millis(12));
+        record_llm_call("openai", "gpt-4", true);
+        record_llm_tokens("openai", "gpt-4", 120, 30);
+        observe_db_query("qdrant", "search", Duration::from_millis(3));
+        record_files_indexed("repo", 2);
+        record_chunks_committed("documents", 8);
+        record_ingestion_failure("quickwit");
+        set_index_size("repo", 2);
+        record_agent_cancellation("code");
+        record_round_trips_saved("qdrant", 1);
+
+        let output = gather().unwrap();
+        for family in [
+            "incredible_http_request_duration_seconds",
+            "incredible_http_requests_total",
+            "incredible_llm_calls_total",
+            "incredible_llm_tokens_total",
+            "incredible_db_query_duration_seconds",
+            "incredible_ingestion_files_indexed_total",
+            "incredible_ingestion_chunks_committed_total",
+            "incredible_ingestion_failures_total",
+            "incredible_index_files",
+            "incredible_agent_cancellations_total",
+            "incredible_db_round_trips_saved_total",
+        ] {
+            assert!(
+                output.contains(&format!("# TYPE {} ", family)),
+                "missing metric family {}",
+                family
+            );
+        }
+        assert!(output.contains(r#"kind="prompt""#));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_route() {
+        record_ingestion_failure("route");
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&metrics_route())
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["Content-Type"], METRICS_CONTENT_TYPE);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        assert!(body.contains("incredible_ingestion_failures_total"));
+    }
+}
@@ -1,30 +1,41 @@
 use ai_gateway::message::message::Message; 
-use crate::{CodeContext, CodeUnderstandings};
+use crate::{CodeContext, CodeUnderstanding, CodeUnderstandings};
+use crate::answer_scope::ScopeViolation;
+use crate::auth::RepoScoped;
+use crate::budget::{BudgetAllowance, BudgetExceeded, BudgetScope};
+use crate::generation::{IndexGeneration, IndexGenerationGone};
+use crate::grounding::TaskGrounding;
+use crate::symbol_payload::{SymbolOccurrence, CONTAINER_SEPARATOR};
+use crate::task_graph::graph_model::QuestionWithAnswer;
+use crate::timings::{Phase, PhaseTimings};
 use serde::{de, Deserialize, Serialize};
 use std::fmt;
 use std::ops::Range;
 
-/// Represents a code chunk
-#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct CodeChunk {
-    pub path: String,
-    #[serde(rename = "snippet")]
-    pub snippet: String,
-    #[serde(rename = "start")]
-    pub start_line: usize,
-    #[serde(rename = "end")]
-    pub end_line: usize,
+pub use crate::code_chunk::CodeChunk;
+
+/// The retrieval a search hit came from, `both` when the vector and keyword searches agree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetrievalSource {
+    Vector,
+    Keyword,
+    Both,
 }
 
-impl std::fmt::Display for CodeChunk {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let lines: Vec<&str> = self.snippet.lines().collect();
-        for (i, line) in lines.iter().enumerate() {
-            writeln!(f, "{:4} {}", i + self.start_line, line)?;
-        }
-        Ok(())
+/// Body of error responses shared by the services.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorEnvelope {
+    pub code: u16,
+    pub error: String,
+}
+
+impl ErrorEnvelope {
+    pub fn new(code: u16, error: String) -> Self {
+        Self { code, error }
     }
 }
+
 // Used to get code chunks given the repo, branch, path, range and id.
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
 pub struct CodeSpanRequest {
@@ -36,12 +47,589 @@ pub struct CodeSpanRequest {
     pub id: Option<String>,
 }
 
+/// Body of the 400 response of `/span` for a range that can't be clamped to the file,
+/// `line_count` is the number of lines the file has now.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct SpanRangeError {
+    pub code: u16,
+    pub error: String,
+    pub line_count: usize,
+}
+
+impl fmt::Display for SpanRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (the file has {} lines)", self.error, self.line_count)
+    }
+}
+
+impl std::error::Error for SpanRangeError {}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
 pub struct CodeUnderstandRequest {
     pub query: String,
     pub repo: String,
     pub task_id: String,
     pub question_id: usize, 
+    // Files the user wants the agent to read before searching, see `PinnedPath` for the format.
+    // Sent as a single comma separated query parameter.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "comma_separated"
+    )]
+    pub pinned_paths: Vec<String>,
+    // Preferences the user stated in the conversation, rendered as a block for the answer prompt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferences: Option<String>,
+    // Language the answer is written in, see `common::language`. English when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // Adds the callers and callees of the functions found to the answer context.
+    // On by default, except for questions about where files are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub related_usage: Option<bool>,
+    // Keeps the scores of test and vendored code, which are lowered unless the query is about tests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_tests: Option<bool>,
+    // Follows the value the query asks about across the files it goes through, and answers with
+    // the steps of its flow. On by default for questions asking where a value comes from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_flow: Option<bool>,
+    // Limit the calls of the agent are checked against, see `BudgetAllowance`. Sent as separate
+    // query parameters, the calls are counted but not limited without a scope and a limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_scope: Option<BudgetScope>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_limit_usd: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_spent_usd: Option<f64>,
+    // Whether the conversation `task_id` has attachments to search alongside the code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<bool>,
+    // Adds steps to check the answer against the cited code, see `common::verification`. Off when
+    // not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_verification: Option<bool>,
+    // Collections the searches read from instead of the aliases, the `IndexGeneration` the
+    // conversation is pinned to in its parameter form.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_generation: Option<String>,
+    // Expands the jargon of the searches and annotates it in the answer from the dictionary of the
+    // repo, see `common::terminology`. On when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminology: Option<bool>,
+    // Keeps the answer to how the earlier answers of the conversation `task_id` described the
+    // symbols it cites, see `common::glossary`. Off when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glossary: Option<bool>,
+    // Lets the agent stop to ask the user a clarifying question, see `common::clarification`.
+    // Off when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ask_user: Option<bool>,
+    // The response of the user to the clarifying question the run of the question stopped at,
+    // the run resumes with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clarification: Option<String>,
+}
+
+impl CodeUnderstandRequest {
+    pub fn budget_allowance(&self) -> Option<BudgetAllowance> {
+        Some(BudgetAllowance {
+            scope: self.budget_scope?,
+            limit_usd: self.budget_limit_usd?,
+            spent_usd: self.budget_spent_usd.unwrap_or_default(),
+        })
+    }
+}
+
+/// Body of `POST /answer-batch`, questions of one task answered against the same repo.
+/// The options apply to every question, like the ones of `CodeUnderstandRequest`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CodeUnderstandBatchRequest {
+    pub repo: String,
+    pub task_id: String,
+    pub questions: Vec<BatchQuestion>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_paths: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferences: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub related_usage: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_tests: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_flow: Option<bool>,
+    // shared by the questions, they are answered within the same limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<BudgetAllowance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_verification: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_generation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminology: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glossary: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BatchQuestion {
+    pub question_id: usize,
+    pub query: String,
+}
+
+impl CodeUnderstandBatchRequest {
+    /// The single question request of a question of the batch.
+    pub fn question_request(&self, question: &BatchQuestion) -> CodeUnderstandRequest {
+        CodeUnderstandRequest {
+            query: question.query.clone(),
+            repo: self.repo.clone(),
+            task_id: self.task_id.clone(),
+            question_id: question.question_id,
+            pinned_paths: self.pinned_paths.clone(),
+            preferences: self.preferences.clone(),
+            language: self.language.clone(),
+            related_usage: self.related_usage,
+            include_tests: self.include_tests,
+            data_flow: self.data_flow,
+            budget_scope: self.budget.map(|budget| budget.scope),
+            budget_limit_usd: self.budget.map(|budget| budget.limit_usd),
+            budget_spent_usd: self.budget.map(|budget| budget.spent_usd),
+            attachments: self.attachments,
+            include_verification: self.include_verification,
+            index_generation: self.index_generation.clone(),
+            terminology: self.terminology,
+            glossary: self.glossary,
+            // the questions of a batch are answered without stopping.
+            ask_user: None,
+            clarification: None,
+        }
+    }
+}
+
+/// A frame of the `POST /answer-batch` response, streamed with `Transport::encode_frame`: every
+/// answer as soon as it is written, in the order they finish, then the cost of the batch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStreamItem {
+    Answer(BatchAnswer),
+    // what the LLM calls of the whole batch cost, the questions share the budget of the batch.
+    Done { cost_usd: f64 },
+}
+
+/// The answer to a question of the batch, or the error that stopped it.
+/// A failed question doesn't fail the others.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BatchAnswer {
+    pub question_id: usize,
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<CodeUnderstanding>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // set along with the error when the question was stopped by the budget of the batch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_exceeded: Option<BudgetExceeded>,
+    // set along with the error when a collection of the pinned index generation was dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_generation_gone: Option<IndexGenerationGone>,
+    pub trace: BatchTrace,
+}
+
+/// Body of `POST /explain-symbol`, the function of a file to explain, by line or byte offset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExplainSymbolRequest {
+    pub repo: String,
+    pub path: String,
+    // 1-based line of the file, read when `byte` isn't set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    // byte offset in the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byte: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_generation: Option<String>,
+}
+
+/// Body of the `POST /explain-symbol` response, the explanation of the innermost function
+/// enclosing the requested position.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ExplainSymbolResponse {
+    pub symbol: String,
+    // 1-based lines of the function.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub summary: String,
+    pub parameters: Vec<ExplainedParameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub returns: Option<String>,
+    pub callees: Vec<ExplainedCallee>,
+    // tokens of the prompt and the response of the model, 0 when served from the cache.
+    pub tokens_used: usize,
+}
+
+/// A citation anchor to find again, see `crate::anchors`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CitedAnchor {
+    pub path: String,
+    pub anchor: String,
+    // 1-based lines the citation was resolved to, the matches closest to them are preferred.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines: Option<(usize, usize)>,
+}
+
+/// Body of `POST /anchors/resolve`, answered with the `AnchorResolution` of every anchor in order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResolveAnchorsRequest {
+    pub repo: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    pub anchors: Vec<CitedAnchor>,
+}
+
+/// Body of `POST /symbols`, the code chunks of a repo closest to the query.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SymbolSearchRequest {
+    pub query: String,
+    pub repo_name: String,
+    // collapse near-identical chunks of different paths into the best scored one.
+    #[serde(default = "default_dedupe")]
+    pub dedupe: bool,
+    // keep the scores of test and vendored code, lowered unless the query is about tests.
+    #[serde(default)]
+    pub include_tests: bool,
+    // prefer the recently changed files, on without it when the query asks about the current behavior.
+    #[serde(default)]
+    pub recency: bool,
+    // most chunks of a path ahead of the chunks of the other paths, `MAX_CHUNKS_PER_PATH` when not set.
+    pub max_per_path: Option<usize>,
+    /// Branch searched, the default branch when not set.
+    pub branch: Option<String>,
+    /// Collections searched instead of the aliases, the generation the conversation is pinned to.
+    pub index_generation: Option<IndexGeneration>,
+    // add the expansions of the jargon of the query from the dictionary of the repo.
+    #[serde(default = "default_terminology")]
+    pub terminology: bool,
+    // search the chunks of this embedded language and boost their paths, e.g. `sql`, the
+    // language the query names when not set.
+    pub embedded_lang: Option<String>,
+}
+
+fn default_dedupe() -> bool {
+    true
+}
+
+fn default_terminology() -> bool {
+    true
+}
+
+impl SymbolSearchRequest {
+    /// A search of the default branch with the defaults of the route.
+    pub fn new(query: &str, repo_name: &str) -> Self {
+        Self {
+            query: query.to_string(),
+            repo_name: repo_name.to_string(),
+            dedupe: default_dedupe(),
+            terminology: default_terminology(),
+            ..Default::default()
+        }
+    }
+}
+
+impl RepoScoped for SymbolSearchRequest {
+    fn repo_name(&self) -> &str {
+        &self.repo_name
+    }
+}
+
+/// Query of `GET /symbols/exact`, e.g. `?repo_name=repo&name=process_entries&kind=function_item`
+/// or `?repo_name=repo&name=retry&container=PaymentService`. Answered with the `SymbolMatch`es.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ExactSymbolQuery {
+    pub repo_name: String,
+    /// Name of the symbol, matched as a whole.
+    pub name: String,
+    /// Node kind or symbol type of the occurrences to keep, all of them when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// Innermost enclosing definitions of the occurrences to keep, e.g. `PaymentService` or
+    /// `PaymentService::Config`, all of them when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// Case-sensitive by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+    /// Branch looked up, the default branch when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Collections looked up instead of the aliases, e.g.
+    /// `documents=documents_v2,documents_symbol=documents_symbol_v2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_generation: Option<String>,
+}
+
+impl RepoScoped for ExactSymbolQuery {
+    fn repo_name(&self) -> &str {
+        &self.repo_name
+    }
+}
+
+/// One location of a symbol found by `GET /symbols/exact`, lines are 0-based like the line
+/// indices of the quickwit documents. They are None when the file couldn't be read.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SymbolMatch {
+    pub symbol: String,
+    pub path: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub start_line: Option<usize>,
+    pub end_line: Option<usize>,
+    pub node_kind: String,
+    pub symbol_type: String,
+    pub lang: String,
+    pub is_global: bool,
+    /// Enclosing definitions, outermost first, e.g. `["PaymentService"]` for one of its methods.
+    #[serde(default)]
+    pub container_path: Vec<String>,
+}
+
+impl SymbolMatch {
+    pub fn new(symbol: &str, occurrence: &SymbolOccurrence) -> Self {
+        SymbolMatch {
+            symbol: symbol.to_string(),
+            path: occurrence.path.clone(),
+            start_byte: occurrence.start_byte,
+            end_byte: occurrence.end_byte,
+            start_line: None,
+            end_line: None,
+            node_kind: occurrence.node_kind.clone(),
+            symbol_type: occurrence.symbol_type.clone(),
+            lang: occurrence.lang.clone(),
+            is_global: occurrence.is_global,
+            container_path: occurrence.container_path.clone(),
+        }
+    }
+
+    /// The symbol with its enclosing definitions, e.g. `PaymentService::retry`.
+    pub fn breadcrumb(&self) -> String {
+        self.container_path
+            .iter()
+            .chain(std::iter::once(&self.symbol))
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(CONTAINER_SEPARATOR)
+    }
+}
+
+/// Query of `GET /repos/{repo}/owners`, e.g. `?path=services/payments/charge.rs`. Answered with
+/// the `PathOwners` of the path.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct OwnersQuery {
+    /// Repo relative path of a file or directory.
+    pub path: String,
+    /// Branch whose CODEOWNERS file is read, the default branch when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+/// A step of the conversation reported to its webhook, serialized as `event` and `data`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Milestone {
+    TasksGenerated(TaskList),
+    QuestionAnswered {
+        id: usize,
+        answer: String,
+        // where the time of the answer went, missing for builds without timings.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timings: Option<PhaseTimings>,
+    },
+    AllQuestionsAnswered,
+    SummaryReady {
+        summary: String,
+    },
+    Failed {
+        error: String,
+    },
+    // sent instead of `Failed` when a call would have gone beyond a spend limit.
+    BudgetExceeded(BudgetExceeded),
+    // a phase of answering the question took longer than its threshold.
+    SlaExceeded {
+        question_id: usize,
+        question: String,
+        phase: Phase,
+        duration_ms: u64,
+        threshold_ms: u64,
+    },
+    // the answer of the question recommended changing paths out of the scope of the conversation.
+    ScopeViolations {
+        question_id: usize,
+        question: String,
+        violations: Vec<ScopeViolation>,
+    },
+    // the client wasn't heard from for a while, the questions left wait until it is back.
+    Suspended {
+        queued_questions: usize,
+    },
+    // the client of a suspended conversation is back, its questions are answered again.
+    Resumed,
+    // the agent asked the user what the question means, the questions left wait for the response.
+    ClarificationRequested {
+        question_id: usize,
+        question: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        options: Vec<String>,
+    },
+}
+
+// answers are cut in the payload, the whole answer is on the conversation.
+const MAX_WEBHOOK_ANSWER_CHARS: usize = 1000;
+
+impl Milestone {
+    pub fn question_answered(answer: &QuestionWithAnswer) -> Self {
+        let text = &answer.answer.answer;
+        let truncated = match text.char_indices().nth(MAX_WEBHOOK_ANSWER_CHARS) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text.clone(),
+        };
+        Milestone::QuestionAnswered {
+            id: answer.question_id,
+            answer: truncated,
+            timings: answer.answer.timings,
+        }
+    }
+
+    /// None when the answer stayed in scope.
+    pub fn scope_violations(answer: &QuestionWithAnswer) -> Option<Self> {
+        let violations = &answer.answer.scope_violations;
+        (!violations.is_empty()).then(|| Milestone::ScopeViolations {
+            question_id: answer.question_id,
+            question: answer.question.clone(),
+            violations: violations.clone(),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Milestone::TasksGenerated(_) => "tasks_generated",
+            Milestone::QuestionAnswered { .. } => "question_answered",
+            Milestone::AllQuestionsAnswered => "all_questions_answered",
+            Milestone::SummaryReady { .. } => "summary_ready",
+            Milestone::Failed { .. } => "failed",
+            Milestone::BudgetExceeded(_) => "budget_exceeded",
+            Milestone::SlaExceeded { .. } => "sla_exceeded",
+            Milestone::ScopeViolations { .. } => "scope_violations",
+            Milestone::Suspended { .. } => "suspended",
+            Milestone::Resumed => "resumed",
+            Milestone::ClarificationRequested { .. } => "clarification_requested",
+        }
+    }
+}
+
+/// Body posted to the webhook of a conversation, the milestone flattened next to the id.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub conversation_id: String,
+    #[serde(flatten)]
+    pub milestone: Milestone,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ExplainedParameter {
+    pub name: String,
+    pub description: String,
+}
+
+/// A function called by the explained one, where it is defined.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ExplainedCallee {
+    pub name: String,
+    pub path: String,
+    // 1-based line of its definition.
+    pub line: usize,
+}
+
+/// How a question of the batch used the documents shared with the others.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BatchTrace {
+    // paths found by the initial search of the question.
+    pub initial_paths: Vec<String>,
+    // paths the initial searches of at least two questions found, fetched once for the batch.
+    pub shared_paths: Vec<String>,
+    // documents read by the agent that were already fetched for the batch, and the ones it fetched.
+    pub document_hits: usize,
+    pub document_misses: usize,
+    pub duration_ms: u64,
+}
+
+/// A file pinned by the user, written as `path` or `path:start-end`.
+/// Line numbers are 1-based and inclusive, `lines` holds them as a 0-based, end exclusive range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PinnedPath {
+    pub path: String,
+    pub lines: Option<Range<usize>>,
+}
+
+impl std::str::FromStr for PinnedPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // only treat the suffix as a range if it looks like one, paths may contain colons.
+        if let Some((path, range)) = s.rsplit_once(':') {
+            if let Some((start, end)) = range.split_once('-') {
+                if let (Ok(start), Ok(end)) = (start.trim().parse::<usize>(), end.trim().parse::<usize>()) {
+                    if start == 0 || end < start {
+                        return Err(format!("Invalid line range in pinned path: {}", s));
+                    }
+                    return Ok(PinnedPath {
+                        path: path.to_owned(),
+                        lines: Some(start - 1..end),
+                    });
+                }
+            }
+        }
+
+        if s.is_empty() {
+            return Err("Pinned path is empty".to_string());
+        }
+        Ok(PinnedPath {
+            path: s.to_owned(),
+            lines: None,
+        })
+    }
+}
+
+impl fmt::Display for PinnedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.lines {
+            Some(lines) => write!(f, "{}:{}-{}", self.path, lines.start + 1, lines.end),
+            None => write!(f, "{}", self.path),
+        }
+    }
+}
+
+// (De)serializes a list of strings as one comma separated string, for use in query parameters.
+mod comma_separated {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&values.join(","))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Ok(value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
@@ -86,6 +674,9 @@ pub struct TaskListResponseWithMessage {
 pub struct Task {
     pub task: String,
     pub subtasks: Vec<Subtask>,
+    // set by the coordinator once the components the task mentions are matched against the repo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grounding: Option<TaskGrounding>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -163,6 +754,25 @@ pub struct TaskDetailsWithContext {
     pub answers: Vec<String>,
     pub code_contexts: Vec<CodeContext>,
     pub merged_code_contexts: Vec<CodeContext>,
+    // Questions the agent couldn't find an answer for in the codebase.
+    pub unresolved_questions: Vec<String>,
+    // the questions and answers above grouped by the subtask they were asked for.
+    pub subtasks: Vec<SubtaskDetails>,
+}
+
+/// The questions of a subtask with their answers, and the synthesis of the answers once it was
+/// written.
+#[derive(Debug, Clone)]
+pub struct SubtaskDetails {
+    // Subtask ID is derived from the node index in the graph, like the task ID.
+    pub subtask_id: usize,
+    pub subtask_description: String,
+    pub questions: Vec<String>,
+    pub answers: Vec<String>,
+    pub merged_code_contexts: Vec<CodeContext>,
+    pub unresolved_questions: Vec<String>,
+    // None until the answers of the subtask are synthesized.
+    pub synthesis: Option<String>,
 }
 
 impl fmt::Display for TaskDetailsWithContext {
@@ -183,6 +793,13 @@ impl fmt::Display for TaskDetailsWithContext {
             write!(f, " - {}\n", answer)?;
         }
 
+        if !self.unresolved_questions.is_empty() {
+            write!(f, "Couldn't Determine:\n")?;
+            for question in &self.unresolved_questions {
+                write!(f, " - {}\n", question)?;
+            }
+        }
+
         write!(f, "Code Contexts:\n")?;
         for context in &self.merged_code_contexts {
             write!(f, " - {:?}\n", context)?;
@@ -201,3 +818,239 @@ impl fmt::Display for TasksQuestionsAnswersDetails {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pinned_path() {
+        assert_eq!(
+            "services/auth/token.rs".parse::<PinnedPath>().unwrap(),
+            PinnedPath {
+                path: "services/auth/token.rs".to_string(),
+                lines: None
+            }
+        );
+
+        let pinned = "services/auth/token.rs:10-40".parse::<PinnedPath>().unwrap();
+        assert_eq!(pinned.lines, Some(9..40));
+        assert_eq!(pinned.to_string(), "services/auth/token.rs:10-40");
+
+        assert!("src/main.rs:0-3".parse::<PinnedPath>().is_err());
+        assert!("src/main.rs:9-3".parse::<PinnedPath>().is_err());
+        assert!("".parse::<PinnedPath>().is_err());
+    }
+
+    #[test]
+    fn test_pinned_paths_query_roundtrip() {
+        let request: CodeUnderstandRequest = serde_json::from_value(serde_json::json!({
+            "query": "how are tokens refreshed?",
+            "repo": "repo",
+            "task_id": "task",
+            "question_id": 1,
+            "pinned_paths": "services/auth/token.rs:10-40, src/main.rs"
+        }))
+        .unwrap();
+        assert_eq!(
+            request.pinned_paths,
+            vec!["services/auth/token.rs:10-40", "src/main.rs"]
+        );
+
+        // requests without pinned paths stay valid.
+        let request: CodeUnderstandRequest = serde_json::from_value(serde_json::json!({
+            "query": "q", "repo": "repo", "task_id": "task", "question_id": 1
+        }))
+        .unwrap();
+        assert!(request.pinned_paths.is_empty());
+    }
+
+    // payloads captured from the services, keyed by the route they were sent to or answered by.
+    const PAYLOADS: &str = include_str!("../fixtures/api/payloads.json");
+
+    fn payload(name: &str) -> serde_json::Value {
+        let payloads: serde_json::Value = serde_json::from_str(PAYLOADS).unwrap();
+        payloads
+            .get(name)
+            .unwrap_or_else(|| panic!("no payload {}", name))
+            .clone()
+    }
+
+    // every field of the captured payload is written back under the same name with the same
+    // value, the payload may only gain fields.
+    fn assert_fields_kept(at: &str, captured: &serde_json::Value, written: &serde_json::Value) {
+        use serde_json::Value;
+        match (captured, written) {
+            (Value::Object(captured), Value::Object(written)) => {
+                for (key, value) in captured {
+                    let kept = written
+                        .get(key)
+                        .unwrap_or_else(|| panic!("{}.{} is no longer written", at, key));
+                    assert_fields_kept(&format!("{}.{}", at, key), value, kept);
+                }
+            }
+            (Value::Array(captured), Value::Array(written)) => {
+                assert_eq!(captured.len(), written.len(), "{} changed length", at);
+                for (i, (value, kept)) in captured.iter().zip(written).enumerate() {
+                    assert_fields_kept(&format!("{}[{}]", at, i), value, kept);
+                }
+            }
+            _ => assert_eq!(captured, written, "{} changed", at),
+        }
+    }
+
+    // Reads the captured payload `name` as `T`, writes it back and reads it again.
+    fn round_trip<T: Serialize + de::DeserializeOwned>(name: &str) -> T {
+        let captured = payload(name);
+        let read: T = serde_json::from_value(captured.clone())
+            .unwrap_or_else(|e| panic!("{} no longer reads: {}", name, e));
+        let written = serde_json::to_value(&read).unwrap();
+        assert_fields_kept(name, &captured, &written);
+        let reread: T = serde_json::from_value(written.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&reread).unwrap(),
+            written,
+            "{} doesn't round-trip",
+            name
+        );
+        read
+    }
+
+    #[test]
+    fn test_span_payloads_round_trip() {
+        let request: CodeSpanRequest = round_trip("code_span_request");
+        assert_eq!(request.ranges, Some(vec![10..24]));
+        let error: SpanRangeError = round_trip("span_range_error");
+        assert_eq!(error.line_count, 96);
+    }
+
+    #[test]
+    fn test_symbol_search_payloads_round_trip() {
+        let request: SymbolSearchRequest = round_trip("symbol_search_request");
+        // the fields the clients leave out keep the defaults of the route.
+        assert!(request.dedupe && request.include_tests && !request.terminology);
+        assert_eq!(request.max_per_path, None);
+        assert_eq!(
+            request.index_generation.unwrap().collections["documents_symbol"],
+            "documents_symbol_v2"
+        );
+
+        let chunks: Vec<CodeChunk> = round_trip("code_chunks");
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (10, 24));
+        assert_eq!(chunks[0].source, Some(RetrievalSource::Both));
+        assert!(chunks[1].is_test && chunks[1].demoted);
+
+        let written = serde_json::to_value(SymbolSearchRequest::new("q", "repo")).unwrap();
+        assert_eq!(
+            (&written["dedupe"], &written["terminology"]),
+            (&serde_json::json!(true), &serde_json::json!(true))
+        );
+    }
+
+    #[test]
+    fn test_exact_symbol_payloads_round_trip() {
+        let query: ExactSymbolQuery = round_trip("exact_symbol_query");
+        assert_eq!(query.container.as_deref(), Some("PaymentService"));
+        assert_eq!(query.branch, None);
+
+        let matches: Vec<SymbolMatch> = round_trip("symbol_matches");
+        assert_eq!(matches[0].breadcrumb(), "PaymentService::retry");
+        assert_eq!(
+            (matches[0].start_line, matches[1].start_line),
+            (Some(41), None)
+        );
+        assert_eq!(matches[1].breadcrumb(), "retry");
+
+        // code search builds without container paths.
+        let mut captured = payload("symbol_matches")[0].clone();
+        captured.as_object_mut().unwrap().remove("container_path");
+        let read: SymbolMatch = serde_json::from_value(captured).unwrap();
+        assert!(read.container_path.is_empty());
+    }
+
+    #[test]
+    fn test_question_payloads_round_trip() {
+        let request: CodeUnderstandRequest = round_trip("code_understand_request");
+        assert_eq!(request.pinned_paths.len(), 2);
+        assert_eq!(request.budget_allowance().unwrap().remaining_usd(), 3.75);
+        assert_eq!(request.clarification.as_deref(), Some("the card refunds"));
+
+        let answer: CodeUnderstanding = round_trip("code_understanding");
+        assert!(matches!(
+            answer.outcome,
+            Some(crate::AnswerOutcome::Answered { .. })
+        ));
+        assert_eq!(answer.timings.unwrap().total_ms(), 7635);
+        assert_eq!(answer.scope_violations.len(), 1);
+        assert!(!answer.index_mode.is_full());
+
+        let not_found: crate::AnswerOutcome = round_trip("not_found_outcome");
+        assert!(matches!(not_found, crate::AnswerOutcome::NotFound { .. }));
+        let clarification: crate::AnswerOutcome = round_trip("clarification_outcome");
+        assert!(matches!(
+            clarification,
+            crate::AnswerOutcome::NeedsClarification {
+                suspended: true,
+                ..
+            }
+        ));
+
+        let request: CodeUnderstandBatchRequest = round_trip("answer_batch_request");
+        assert_eq!(request.budget.unwrap().scope, BudgetScope::Tenant);
+        let stream: Vec<BatchStreamItem> = round_trip("answer_batch_stream");
+        let [BatchStreamItem::Answer(answered), BatchStreamItem::Answer(stopped), done] =
+            stream.as_slice()
+        else {
+            panic!("Unexpected batch stream: {:?}", stream);
+        };
+        assert!(answered.answer.is_some());
+        let exceeded = stopped.budget_exceeded.as_ref().unwrap();
+        assert_eq!(exceeded.projected_usd, 5.25);
+        assert_eq!(*done, BatchStreamItem::Done { cost_usd: 0.5 });
+    }
+
+    #[test]
+    fn test_webhook_payloads_round_trip() {
+        let payloads: Vec<WebhookPayload> = round_trip("webhook_payloads");
+        let events: Vec<&str> = payloads
+            .iter()
+            .map(|payload| payload.milestone.name())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "tasks_generated",
+                "question_answered",
+                "all_questions_answered",
+                "summary_ready",
+                "failed",
+                "budget_exceeded",
+                "sla_exceeded",
+                "scope_violations",
+                "suspended",
+                "resumed",
+                "clarification_requested",
+            ]
+        );
+        // the name of each milestone is the event it is written with.
+        for (payload, event) in payloads.iter().zip(&events) {
+            assert_eq!(serde_json::to_value(payload).unwrap()["event"], *event);
+        }
+    }
+
+    #[test]
+    fn test_repo_metadata_payloads_round_trip() {
+        let query: OwnersQuery = round_trip("owners_query");
+        assert_eq!(query.branch.as_deref(), Some("release-1.2"));
+        let owners: crate::codeowners::PathOwners = round_trip("path_owners");
+        assert_eq!(owners.rules[1].section.as_deref(), Some("Payments"));
+        let commit: crate::links::IndexedCommit = round_trip("indexed_commit");
+        assert_eq!(commit.commit.as_deref(), Some("a1b2c3d"));
+        let summary: crate::repo_summary::RepoSummary = round_trip("repo_summary");
+        assert!(!summary.is_empty());
+        let paths: crate::grounding::IndexedPaths = round_trip("indexed_paths");
+        assert_eq!(paths.paths.len(), 2);
+        let freshness: crate::freshness::IndexFreshness = round_trip("index_freshness");
+        assert_eq!(freshness.commits_behind, Some(3));
+    }
+}
@@ -0,0 +1,180 @@
+// Test and vendored code mention the terms of a question densely and outrank the code it is
+// about. Ingestion flags their chunks with the rules below and code search demotes them, unless
+// the question is about tests.
+
+use serde::{Deserialize, Serialize};
+
+use crate::codeowners::is_codeowners_path;
+use crate::repo_summary::REPO_SUMMARY_PATH;
+use crate::run_manifest::RUN_MANIFEST_PATH;
+use crate::terminology::TERMINOLOGY_SUGGESTIONS_PATH;
+
+// directories holding tests, at any depth.
+const TEST_DIRS: &[&str] = &["test", "tests", "__tests__", "spec", "specs", "testdata"];
+const VENDORED_DIRS: &[&str] = &["vendor", "node_modules", "third_party", "third-party"];
+// words of a question that ask about tests.
+const TEST_WORDS: &[&str] = &[
+    "test", "tests", "testing", "tested", "spec", "specs", "unittest",
+];
+
+/// The flags of a path, stored in the `is_test` and `is_vendored` fields of the chunk payloads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathClass {
+    pub is_test: bool,
+    pub is_vendored: bool,
+}
+
+impl PathClass {
+    pub fn of(path: &str) -> Self {
+        PathClass {
+            is_test: is_test_path(path),
+            is_vendored: is_vendored_path(path),
+        }
+    }
+
+    /// Whether the path is demoted by the search.
+    pub fn is_flagged(&self) -> bool {
+        self.is_test || self.is_vendored
+    }
+}
+
+/// `tests/` and the like anywhere in the path, or the test file names of the languages:
+/// `_test.go`, `test_*.py`, `*_test.py`, `conftest.py`, `*.spec.ts`, `*.test.js`, `*_spec.rb`,
+/// `*Test.java`, `*Tests.cs`.
+pub fn is_test_path(path: &str) -> bool {
+    let path = path.replace('\\', "/");
+    let mut components = path.split('/').collect::<Vec<_>>();
+    let Some(file) = components.pop() else {
+        return false;
+    };
+    if components
+        .iter()
+        .any(|dir| TEST_DIRS.contains(&dir.to_ascii_lowercase().as_str()))
+    {
+        return true;
+    }
+
+    let (stem, extension) = file.rsplit_once('.').unwrap_or((file, ""));
+    match extension {
+        "go" => stem.ends_with("_test"),
+        "py" => stem.starts_with("test_") || stem.ends_with("_test") || stem == "conftest",
+        "js" | "jsx" | "ts" | "tsx" | "mjs" | "cjs" => {
+            stem.ends_with(".spec") || stem.ends_with(".test")
+        }
+        "rb" => stem.ends_with("_spec") || stem.ends_with("_test"),
+        "java" | "kt" | "cs" => stem.ends_with("Test") || stem.ends_with("Tests"),
+        "rs" => stem == "tests",
+        _ => false,
+    }
+}
+
+/// Code copied from dependencies: `vendor/`, `node_modules/` or `third_party/` anywhere in the path.
+pub fn is_vendored_path(path: &str) -> bool {
+    path.replace('\\', "/")
+        .split('/')
+        .rev()
+        .skip(1)
+        .any(|dir| VENDORED_DIRS.contains(&dir))
+}
+
+/// Whether the query asks about tests or specs, in which case nothing is demoted.
+pub fn mentions_tests(query: &str) -> bool {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| TEST_WORDS.contains(&word.to_lowercase().as_str()))
+}
+
+/// The documents ingestion writes about the repo and the CODEOWNERS file, indexed next to the
+/// code but not code themselves: searches and path listings leave them out.
+pub fn is_metadata_document(path: &str) -> bool {
+    [
+        REPO_SUMMARY_PATH,
+        RUN_MANIFEST_PATH,
+        TERMINOLOGY_SUGGESTIONS_PATH,
+    ]
+    .contains(&path)
+        || is_codeowners_path(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_test_paths_of_each_language() {
+        for path in [
+            "tests/integration.rs",
+            "crates/parser/tests/fixtures.rs",
+            "src/__tests__/App.tsx",
+            "spec/models/user_spec.rb",
+            "pkg/auth/token_test.go",
+            "app/test_views.py",
+            "app/views_test.py",
+            "conftest.py",
+            "web/src/login.spec.ts",
+            "web/src/login.test.jsx",
+            "src/main/java/com/acme/AuthServiceTest.java",
+            "src/lib/tests.rs",
+        ] {
+            assert!(is_test_path(path), "{} is a test path", path);
+        }
+        for path in [
+            "src/auth/token.go",
+            "app/views.py",
+            "app/contest.py",
+            "web/src/login.ts",
+            "web/src/specification.ts",
+            "src/main/java/com/acme/AuthService.java",
+            "src/testing_utils.rs",
+            "latest/config.yaml",
+        ] {
+            assert!(!is_test_path(path), "{} is not a test path", path);
+        }
+    }
+
+    #[test]
+    fn test_vendored_paths() {
+        assert!(is_vendored_path("vendor/github.com/pkg/errors/errors.go"));
+        assert!(is_vendored_path("web/node_modules/react/index.js"));
+        assert!(is_vendored_path("third_party/zlib/inflate.c"));
+        assert!(!is_vendored_path("src/vendor.rs"));
+        assert!(!is_vendored_path("src/vendors/stripe.rs"));
+
+        let class = PathClass::of("vendor/acme/client_test.go");
+        assert!(class.is_test && class.is_vendored && class.is_flagged());
+        assert!(!PathClass::of("src/main.rs").is_flagged());
+    }
+
+    #[test]
+    fn test_queries_about_tests() {
+        assert!(mentions_tests("Which tests cover the token refresh?"));
+        assert!(mentions_tests("Where is the login spec?"));
+        assert!(mentions_tests("How is the parser Tested"));
+        assert!(!mentions_tests("Where is the latest token refreshed?"));
+        assert!(!mentions_tests("How does the contest scoring work?"));
+    }
+
+    #[test]
+    fn test_metadata_documents() {
+        for path in [
+            ".incredible/repo-summary.json",
+            ".incredible/run-manifest.json",
+            ".incredible/terminology-suggestions.json",
+            ".github/CODEOWNERS",
+            "CODEOWNERS",
+        ] {
+            assert!(
+                is_metadata_document(path),
+                "{} is a metadata document",
+                path
+            );
+        }
+        for path in ["src/CODEOWNERS.rs", "docs/run-manifest.md", "README.md"] {
+            assert!(
+                !is_metadata_document(path),
+                "{} is not a metadata document",
+                path
+            );
+        }
+    }
+}
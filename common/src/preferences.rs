@@ -0,0 +1,249 @@
+// Preferences the user states during a conversation, e.g. "keep answers short" or "respond in
+// Portuguese". They are kept on the conversation and added to every prompt that answers the user.
+
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+const CONCISE_PHRASES: &[&str] = &[
+    "be concise",
+    "be brief",
+    "keep it short",
+    "keep it brief",
+    "keep answers short",
+    "keep the answers short",
+    "keep your answers short",
+    "shorter answers",
+    "short answers",
+    "brief answers",
+    "concise answers",
+    "less verbose",
+    "tl;dr",
+];
+
+const DETAILED_PHRASES: &[&str] = &[
+    "be detailed",
+    "be thorough",
+    "more detail",
+    "more details",
+    "in depth",
+    "in-depth",
+    "detailed answers",
+    "longer answers",
+    "elaborate more",
+];
+
+const LANGUAGES: &[&str] = &[
+    "english",
+    "portuguese",
+    "spanish",
+    "french",
+    "german",
+    "italian",
+    "dutch",
+    "polish",
+    "russian",
+    "ukrainian",
+    "turkish",
+    "arabic",
+    "hebrew",
+    "hindi",
+    "bengali",
+    "chinese",
+    "mandarin",
+    "japanese",
+    "korean",
+    "vietnamese",
+    "indonesian",
+    "swedish",
+    "norwegian",
+    "danish",
+    "finnish",
+    "greek",
+    "czech",
+    "romanian",
+    "hungarian",
+];
+
+lazy_static! {
+    static ref LANGUAGE_REGEX: Regex = Regex::new(
+        r"(?i)\b(?:respond|answer|reply|write|speak|talk|explain)(?:\s+(?:to me|only|always))?\s+in\s+([a-z]+)\b"
+    )
+    .unwrap();
+    static ref FOCUS_REGEX: Regex =
+        Regex::new(r"(?i)\bfocus(?:\s+only)?\s+on\s+([^.;!?\n]+)").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Verbosity {
+    Concise,
+    Detailed,
+}
+
+/// Preferences stated by the user, a later statement overrides an earlier one of the same kind.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Preferences {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verbosity: Option<Verbosity>,
+    // language the answers are written in, e.g. `Portuguese`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // parts of the code or topics the answers should focus on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub focus_areas: Vec<String>,
+}
+
+impl Preferences {
+    /// The preferences stated in a user message, empty when it doesn't state any.
+    pub fn detect(message: &str) -> Self {
+        let lowercase = message.to_lowercase();
+        let verbosity = if CONCISE_PHRASES.iter().any(|p| lowercase.contains(p)) {
+            Some(Verbosity::Concise)
+        } else if DETAILED_PHRASES.iter().any(|p| lowercase.contains(p)) {
+            Some(Verbosity::Detailed)
+        } else {
+            None
+        };
+
+        let language = LANGUAGE_REGEX
+            .captures_iter(message)
+            .filter_map(|captures| captures.ok()?.get(1))
+            .map(|language| language.as_str().to_lowercase())
+            .find(|language| LANGUAGES.contains(&language.as_str()))
+            .map(|language| capitalize(&language));
+
+        let focus_areas = FOCUS_REGEX
+            .captures(message)
+            .ok()
+            .flatten()
+            .and_then(|captures| captures.get(1))
+            .map(|areas| {
+                areas
+                    .as_str()
+                    .split(',')
+                    .flat_map(|area| area.split(" and "))
+                    .map(|area| area.trim().trim_start_matches("the ").to_string())
+                    .filter(|area| !area.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            verbosity,
+            language,
+            focus_areas,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.verbosity.is_none() && self.language.is_none() && self.focus_areas.is_empty()
+    }
+
+    /// Overrides the preferences `newer` states, returns whether anything changed.
+    pub fn merge(&mut self, newer: &Preferences) -> bool {
+        let before = self.clone();
+        if newer.verbosity.is_some() {
+            self.verbosity = newer.verbosity;
+        }
+        if newer.language.is_some() {
+            self.language = newer.language.clone();
+        }
+        if !newer.focus_areas.is_empty() {
+            self.focus_areas = newer.focus_areas.clone();
+        }
+        *self != before
+    }
+
+    /// The block added to the prompts, None without preferences.
+    pub fn render(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut block =
+            String::from("The user stated these preferences, follow them in your answer:\n");
+        match self.verbosity {
+            Some(Verbosity::Concise) => {
+                block.push_str("- Keep the answer concise, only include what is needed.\n")
+            }
+            Some(Verbosity::Detailed) => block.push_str("- Give a detailed, thorough answer.\n"),
+            None => {}
+        }
+        if let Some(language) = &self.language {
+            block.push_str(&format!("- Write the answer in {}.\n", language));
+        }
+        if !self.focus_areas.is_empty() {
+            block.push_str(&format!("- Focus on {}.\n", self.focus_areas.join(", ")));
+        }
+        Some(block.trim_end().to_string())
+    }
+}
+
+/// Appends the rendered preferences to a prompt, the prompt is returned as is without them.
+pub fn with_preferences(prompt: String, preferences: Option<&str>) -> String {
+    match preferences {
+        Some(preferences) if !preferences.trim().is_empty() => {
+            format!("{}\n\n{}", prompt, preferences)
+        }
+        _ => prompt,
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_preferences() {
+        let preferences = Preferences::detect("Please be concise and respond in Portuguese.");
+        assert_eq!(preferences.verbosity, Some(Verbosity::Concise));
+        assert_eq!(preferences.language.as_deref(), Some("Portuguese"));
+
+        let preferences = Preferences::detect("Focus on the retry logic and error handling");
+        assert_eq!(
+            preferences.focus_areas,
+            vec!["retry logic", "error handling"]
+        );
+
+        assert_eq!(
+            Preferences::detect("Explain in more detail").verbosity,
+            Some(Verbosity::Detailed)
+        );
+        assert!(Preferences::detect("how does the indexer work?").is_empty());
+        // "in detail" isn't a language.
+        assert_eq!(Preferences::detect("answer in detail").language, None);
+    }
+
+    #[test]
+    fn test_later_statements_override_earlier_ones() {
+        let mut preferences = Preferences::detect("keep answers short, reply in Spanish");
+        assert!(!preferences.merge(&Preferences::detect("what calls the parser?")));
+
+        assert!(preferences.merge(&Preferences::detect("actually, give longer answers")));
+        assert_eq!(preferences.verbosity, Some(Verbosity::Detailed));
+        assert_eq!(preferences.language.as_deref(), Some("Spanish"));
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(Preferences::default().render(), None);
+        assert_eq!(
+            Preferences::detect("be concise, write in French and focus on the API").render(),
+            Some(
+                "The user stated these preferences, follow them in your answer:\n\
+                 - Keep the answer concise, only include what is needed.\n\
+                 - Write the answer in French.\n\
+                 - Focus on API."
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,241 @@
+// Versions of the prompt templates of `prompts`, recorded with the answers so that a change of
+// answer quality can be traced back to the template change behind it.
+//
+// Every template function is declared with a semantic version and the hash of its source. The
+// hash of the source is computed again at startup, a test fails when a template changed without
+// its declaration being updated: bump the version and set the new hash it reports.
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::Filter;
+
+// compiled in, so the hashes are those of the templates of this build.
+const PROMPTS_SOURCE: &str = include_str!("prompts.rs");
+
+// Lines that start the item after a template, the template ends before them.
+const ITEM_STARTS: &[&str] = &["pub fn ", "fn ", "pub async fn ", "async fn ", "#[cfg(test)]"];
+
+// (template, version, hash of its source), in the order of `prompts.rs`.
+const DECLARED: &[(&str, &str, &str)] = &[
+    ("functions", "1.2.0", "11a7ccd152002f1a"),
+    ("system", "1.1.0", "dcc9918d5d8be30b"),
+    ("demoted_evidence_prompt", "1.0.0", "0033046b62e598cc"),
+    ("paths_only_prompt", "1.0.0", "e015880efa2952ea"),
+    ("data_flow_prompt", "1.0.0", "1524d1d649165921"),
+    ("key_files_prompt", "1.0.0", "cb7c76760bb86fb8"),
+    ("attachments_prompt", "1.0.0", "8f6b279ebe2017aa"),
+    ("glossary_prompt", "1.0.0", "58305d2f59cb3da0"),
+    ("pinned_code_prompt", "1.0.0", "df6a83c46d88b595"),
+    ("file_explanation", "1.0.0", "67cbb7cd84f2f736"),
+    ("answer_article_prompt", "1.1.0", "786c39a48157db66"),
+    ("answer_article_prompt_new", "1.0.0", "4ba3d2504f382bd4"),
+    ("question_concept_generator_prompt", "1.0.0", "373835d65fb8a528"),
+    ("reformulate_not_found_question_prompt", "1.0.0", "938fdaba5a846179"),
+    ("task_grounding_repair_prompt", "1.0.0", "01e3d845aa47eb5d"),
+    ("verification_prompt", "1.0.0", "d6c924dafbad1b6d"),
+    ("explain_symbol_prompt", "1.0.0", "925e5aa43b20c0fd"),
+    ("change_plan_prompt", "1.0.0", "7d03ab378d240a81"),
+    ("classify_follow_up_prompt", "1.0.0", "60880f964869f915"),
+    ("conversation_history_summary_prompt", "1.0.0", "9b948567dc635395"),
+    ("subtask_synthesis_prompt", "1.0.0", "e9db157ecae9415d"),
+    ("create_task_answer_summarization_prompt", "1.1.0", "af41e35300ac4696"),
+    ("glossary_reconciliation_prompt", "1.0.0", "74477b75cf3cbcec"),
+];
+
+static ACTIVE: Lazy<Vec<PromptVersion>> = Lazy::new(|| {
+    DECLARED
+        .iter()
+        .map(|(template, version, _)| PromptVersion {
+            template: template.to_string(),
+            version: version.to_string(),
+            hash: template_source(template)
+                .map(source_hash)
+                .unwrap_or_default(),
+        })
+        .collect()
+});
+
+/// A prompt template at the version it was used with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PromptVersion {
+    // name of the template function in `prompts`.
+    pub template: String,
+    pub version: String,
+    // first 16 hex digits of the SHA-256 of the template source.
+    pub hash: String,
+}
+
+impl PromptVersion {
+    /// e.g. `system@1.2.0 (3f9a0c1d2e4b5a69)`.
+    pub fn render(&self) -> String {
+        format!("{}@{} ({})", self.template, self.version, self.hash)
+    }
+}
+
+/// The templates of this build with their versions, in the order of `prompts.rs`.
+pub fn active_versions() -> &'static [PromptVersion] {
+    &ACTIVE
+}
+
+/// The versions of `templates`, in the order given. Names that aren't templates are skipped.
+pub fn prompt_versions(templates: &[&str]) -> Vec<PromptVersion> {
+    templates
+        .iter()
+        .filter_map(|template| {
+            ACTIVE
+                .iter()
+                .find(|version| version.template == *template)
+                .cloned()
+        })
+        .collect()
+}
+
+/// Adds the versions of `templates` that `versions` doesn't have yet.
+pub fn merge_prompt_versions(versions: &mut Vec<PromptVersion>, templates: &[&str]) {
+    for version in prompt_versions(templates) {
+        if !versions.iter().any(|known| known.template == version.template) {
+            versions.push(version);
+        }
+    }
+}
+
+/// GET /prompts/versions, the templates of the build with their versions. A debug route answered
+/// without authentication like `/version`.
+pub fn prompt_versions_route(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("prompts" / "versions")
+        .and(warp::get())
+        .map(|| warp::reply::json(&active_versions()))
+}
+
+// The source of the template function, from its signature to its closing brace.
+fn template_source(template: &str) -> Option<&'static str> {
+    let signature = format!("pub fn {}", template);
+    let mut offset = 0;
+    let mut start = None;
+    let mut end = PROMPTS_SOURCE.len();
+    for line in PROMPTS_SOURCE.split_inclusive('\n') {
+        match start {
+            None if line.strip_prefix(&signature).is_some_and(|rest| {
+                rest.starts_with('(') || rest.starts_with('<')
+            }) =>
+            {
+                start = Some(offset)
+            }
+            Some(_) if ITEM_STARTS.iter().any(|item| line.starts_with(item)) => {
+                end = offset;
+                break;
+            }
+            _ => {}
+        }
+        offset += line.len();
+    }
+    let mut source = PROMPTS_SOURCE[start?..end].trim_end();
+    // comments and attributes above the next item belong to it.
+    loop {
+        let last_line = source.rfind('\n').map_or(0, |i| i + 1);
+        let line = &source[last_line..];
+        if !(line.starts_with("//") || line.starts_with("#[")) {
+            return Some(source);
+        }
+        source = source[..last_line].trim_end();
+    }
+}
+
+fn source_hash(source: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(source.as_bytes());
+    hasher.finalize()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_template_is_declared() {
+        let templates = PROMPTS_SOURCE
+            .lines()
+            .filter_map(|line| line.strip_prefix("pub fn "))
+            .map(|rest| {
+                rest.split(|c: char| c == '(' || c == '<')
+                    .next()
+                    .unwrap_or(rest)
+            })
+            .collect::<Vec<_>>();
+        let declared = DECLARED
+            .iter()
+            .map(|(template, _, _)| *template)
+            .collect::<Vec<_>>();
+        assert_eq!(templates, declared);
+    }
+
+    // Fails when a template changed without its version being bumped.
+    #[test]
+    fn test_declared_hashes_match_the_templates() {
+        let changed = DECLARED
+            .iter()
+            .zip(active_versions())
+            .filter(|((_, _, hash), active)| *hash != active.hash)
+            .map(|((template, version, hash), active)| {
+                format!(
+                    "{} changed: bump its version {} and set its hash {} to {}",
+                    template, version, hash, active.hash
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(changed.is_empty(), "{}", changed.join("\n"));
+    }
+
+    #[test]
+    fn test_versions_are_semantic() {
+        for (template, version, _) in DECLARED {
+            let parts = version.split('.').collect::<Vec<_>>();
+            assert_eq!(parts.len(), 3, "{} has version {}", template, version);
+            assert!(
+                parts.iter().all(|part| part.parse::<u32>().is_ok()),
+                "{} has version {}",
+                template,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn test_template_source_stops_before_the_next_item() {
+        let source = template_source("data_flow_prompt").unwrap();
+        assert!(source.starts_with("pub fn data_flow_prompt() -> String {"));
+        assert!(source.ends_with('}'));
+        assert!(!source.contains("key_files_prompt"));
+        assert!(template_source("missing_prompt").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_versions_route() {
+        let response = warp::test::request()
+            .path("/prompts/versions")
+            .reply(&prompt_versions_route())
+            .await;
+        assert_eq!(response.status(), 200);
+        let versions: Vec<PromptVersion> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(versions, active_versions());
+    }
+
+    #[test]
+    fn test_versions_are_merged_once() {
+        let mut versions = prompt_versions(&["system", "not_a_template"]);
+        merge_prompt_versions(&mut versions, &["functions", "system"]);
+        assert_eq!(
+            versions
+                .iter()
+                .map(|version| version.template.as_str())
+                .collect::<Vec<_>>(),
+            vec!["system", "functions"]
+        );
+        assert_eq!(versions[0].hash.len(), 16);
+    }
+}
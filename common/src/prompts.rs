@@ -1,10 +1,19 @@
 use log::debug;
 
+use crate::attachments::AttachmentSection;
+use crate::glossary::{GlossaryEntry, RECONCILED_HEADING};
 use crate::models::{
-    CodeChunk, CodeSpanRequest, TaskDetailsWithContext, TasksQuestionsAnswersDetails,
+    CodeChunk, CodeSpanRequest, SpanRangeError, SubtaskDetails, TaskDetailsWithContext,
+    TasksQuestionsAnswersDetails,
 };
 
-pub fn functions(add_proc: bool) -> serde_json::Value {
+pub fn functions(
+    add_proc: bool,
+    add_history: bool,
+    add_more_results: bool,
+    add_ask_user: bool,
+    keyword_search: bool,
+) -> serde_json::Value {
     let mut funcs = serde_json::json!(
         [
             {
@@ -35,6 +44,32 @@ pub fn functions(add_proc: bool) -> serde_json::Value {
                     "required": ["query"]
                 }
             },
+            {
+                "name": "symbol",
+                "description": "Find every definition and reference of a symbol by its exact name. Use when you already know the identifier, results only contain exact matches.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "name": {
+                            "type": "string",
+                            "description": "The exact name of the symbol, e.g. 'process_entries', 'AppState'."
+                        },
+                        "kind": {
+                            "type": "string",
+                            "description": "Optional node kind or symbol type to keep, e.g. 'function_item', 'struct_item'."
+                        },
+                        "container": {
+                            "type": "string",
+                            "description": "Optional class, module or other definition the symbol is in, e.g. 'PaymentService' to find the 'retry' method of that class."
+                        },
+                        "case_sensitive": {
+                            "type": "boolean",
+                            "description": "Whether the name is matched case-sensitively. Defaults to true."
+                        }
+                    },
+                    "required": ["name"]
+                }
+            },
             {
                 "name": "none",
                 "description": "Call this to answer the user. Call this only when you have enough information to answer the user's query.",
@@ -71,8 +106,8 @@ pub fn functions(add_proc: bool) -> serde_json::Value {
                         "paths": {
                             "type": "array",
                             "items": {
-                                "type": "integer",
-                                "description": "The indices of the paths to search. paths.len() <= 5"
+                                "type": ["integer", "string"],
+                                "description": "The indices of the paths to search, or the paths themselves. paths.len() <= 5"
                             }
                         }
                     },
@@ -82,6 +117,81 @@ pub fn functions(add_proc: bool) -> serde_json::Value {
             )
         );
     }
+    if add_history {
+        funcs.as_array_mut().unwrap().push(
+            serde_json::json!(
+            {
+                "name": "history",
+                "description": "Read the commits that last changed a line range of a file, with their dates, authors and messages. Use when the query asks why or when some code was added or changed",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The path of the file, e.g. 'server/src/retry.rs'."
+                        },
+                        "start_line": {
+                            "type": "integer",
+                            "description": "The first line of the range, 1-based."
+                        },
+                        "end_line": {
+                            "type": "integer",
+                            "description": "The last line of the range, inclusive."
+                        }
+                    },
+                    "required": ["path", "start_line", "end_line"]
+                }
+            }
+            )
+        );
+    }
+    if add_more_results {
+        funcs.as_array_mut().unwrap().push(
+            serde_json::json!(
+            {
+                "name": "more_results",
+                "description": "Read the next page of the results of your last function call, when they ended with a line saying more results are left",
+                "parameters": {
+                    "type": "object",
+                    "properties": {}
+                }
+            }
+            )
+        );
+    }
+    if add_ask_user {
+        funcs.as_array_mut().unwrap().push(
+            serde_json::json!(
+            {
+                "name": "ask_user",
+                "description": "Ask the user a clarifying question and stop until they answer. Use only when the code you found belongs to two or more different parts of the codebase that could each be what the query means",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "question": {
+                            "type": "string",
+                            "description": "The question to the user, naming the parts of the codebase you found, e.g. 'Do you mean the retries of the webhook deliveries or of the payment refunds?'"
+                        },
+                        "options": {
+                            "type": "array",
+                            "items": {
+                                "type": "string",
+                                "description": "A short answer the user can pick, e.g. 'webhook deliveries (src/webhooks/)'."
+                            }
+                        }
+                    },
+                    "required": ["question", "options"]
+                }
+            }
+            )
+        );
+    }
+    // a paths-only index has no embeddings or symbols, the code search matches the keywords.
+    if keyword_search {
+        let funcs = funcs.as_array_mut().unwrap();
+        funcs.retain(|func| func["name"] != "symbol");
+        funcs[0]["description"] = "Search the contents of files in a codebase for keywords. Results are the files containing the most of the search terms, read them with proc to find the relevant lines.".into();
+    }
     funcs
 }
 
@@ -106,20 +216,123 @@ pub fn system<'a>(paths: impl IntoIterator<Item = &'a str>) -> String {
 - DO NOT assume the structure of the codebase, or the existence of files or folders
 - Call functions.none with paths that you are confident will help answer the user's query
 - In most cases call functions.code or functions.path functions before calling functions.none
+- If the query mentions an exact identifier such as a function, type or constant name, call functions.symbol with that name instead of functions.code
+- If the query asks why or when some code was added or changed and functions.history is available, find the lines first, then call functions.history with their path and line range
 - If the user is referring to, or asking for, information that is in your history, call functions.none
 - If after attempting to gather information you are still unsure how to answer the query, call functions.none
 - If the query is a greeting, or not a question or an instruction call functions.none
 - When calling functions.code or functions.path, your query should consist of keywords. E.g. if the user says 'What does contextmanager do?', your query should be 'contextmanager'. If the user says 'How is contextmanager used in app', your query should be 'contextmanager app'. If the user says 'What is in the src directory', your query should be 'src'
 - If functions.code or functions.path did not return any relevant information, call them again with a SIGNIFICANTLY different query. The terms in the new query should not overlap with terms in your old one
 - If the output of a function is empty, try calling the function again with DIFFERENT arguments OR try calling a different function
-- Only call functions.proc with path indices that are under the PATHS heading above.
+- Call functions.proc with the indices of the paths under the PATHS heading above. A path that isn't listed there can be passed as a string, e.g. "src/auth/session.rs"
 - Call functions.proc with paths that might contain relevant information. Either because of the path name, or to expand on code that's already been returned by functions.code. Rank these paths based on their relevancy, and pick only the top five paths, and reject others
 - DO NOT call functions.proc with more than 5 paths, it should 5 or less paths
 - DO NOT call functions.proc on the same file more than once
+- If the output of a function ends with a line saying more results are left, call functions.more_results to read them before calling functions.none, unless the results already returned answer the query
+- Search before asking. Only call functions.ask_user, when it is available, if your searches found two or more different parts of the codebase that could each be what the query means. DO NOT call it to ask for information you can search for
 - ALWAYS call a function. DO NOT answer the question directly"#);
     s
 }
 
+// Appended to the agent system prompt when the user pinned files to the question.
+// The chunks are already formatted with their path aliases.
+/// Appended to the answer prompt when all the code found was demoted as test or vendored code.
+pub fn demoted_evidence_prompt() -> String {
+    r#"
+
+## TEST AND VENDORED CODE ONLY ##
+The search only found test or vendored code for this query, the project code it exercises or was copied into wasn't found.
+- Say so at the start of the answer
+- Don't present what the tests or the vendored copies do as the behaviour of the project's own code"#
+        .to_string()
+}
+
+pub fn paths_only_prompt() -> String {
+    r#"
+
+## PATHS-ONLY INDEX ##
+The repository is indexed by paths only, the code was found by its path and keywords rather than by its meaning and some relevant code may have been missed.
+- Say at the end of the answer that it was written from a reduced index and may be incomplete
+- Don't claim that something doesn't exist in the codebase because it wasn't found"#
+        .to_string()
+}
+
+pub fn data_flow_prompt() -> String {
+    r#"
+
+## DATA FLOW ##
+The DATA FLOW section follows the value the query asks about, from the code that uses it (hop 0) up to where it comes from.
+- Present the flow step by step, one step per hop, from where the value comes from to where it is used
+- Link the code of every hop you describe, each hop is in its own file or function
+- If the flow stops before the value's origin is clear, say where it stops"#
+        .to_string()
+}
+
+// Starts the agent system prompt when the repo has key files, see `RepoSummary::key_files`.
+pub fn key_files_prompt(key_files: &[String]) -> String {
+    format!(
+        r#"## KNOWN IMPORTANT FILES ##
+The entrypoints, routes and config of the repo, ranked when it was indexed. They are not search results, use them to target your first function call, e.g. call functions.proc with one of these paths when the query is about what they hold.
+{}
+
+"#,
+        key_files.join("\n")
+    )
+}
+
+// Appended to the answer context when the conversation has attachments close to the query.
+pub fn attachments_prompt(sections: &[AttachmentSection]) -> String {
+    let sections = sections
+        .iter()
+        .map(|section| format!("### {} ###\n{}", section.provenance(), section.text))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    format!(
+        r#"
+
+##### ATTACHED DOCUMENTS #####
+The user attached these documents to the conversation, e.g. specs of the services the code talks to. They are not code of the repo.
+- When the answer uses one, cite it as written in its heading, e.g. (from attached document orders-api.json, section POST /orders)
+- Don't invent file paths or code links for what only an attached document says
+
+{sections}"#
+    )
+}
+
+// Appended to the answer context when earlier answers of the conversation described symbols the
+// retrieved code mentions, see `crate::glossary`.
+pub fn glossary_prompt(entries: &[GlossaryEntry]) -> String {
+    let entries = entries
+        .iter()
+        .map(|entry| format!("- {}", entry.render()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"
+
+##### GLOSSARY #####
+Earlier answers of this conversation described these symbols of the code.
+- Describe them consistently with these descriptions, don't reword or contradict them without a reason
+- If the code contradicts a description, follow the code and say that the earlier description was wrong
+
+{entries}"#
+    )
+}
+
+pub fn pinned_code_prompt(pinned_chunks: &str) -> String {
+    format!(
+        r#"
+
+## USER PROVIDED CODE ##
+The user pinned the following files to the query because they know they're relevant. Each chunk starts with the index of its path under the PATHS heading above.
+- Treat this code as already retrieved, DO NOT call functions.proc or search again just to read these files
+- Prefer including the indices of these paths when calling functions.none
+- Still search for other code if the pinned code isn't enough to answer the query
+
+{pinned_chunks}"#
+    )
+}
+
 pub fn file_explanation(question: &str, path: &str, code: &str) -> String {
     format!(
         r#"Below are some lines from the file /{path}. Each line is numbered.
@@ -167,6 +380,8 @@ Your output will be interpreted as Incredible-markdown which renders with the fo
 - Do NOT output bare symbols. ALL symbols must include a link
   - E.g. Do not simply write `Bar`, write [`Bar`](src/bar.rs#L100-L105).
   - E.g. Do not simply write "Foos are functions that create `Foo` values out of thin air." Instead, write: "Foos are functions that create [`Foo`](src/foo.rs#L80-L120) values out of thin air."
+- When a symbol lookup lists the definitions a symbol is in, name the symbol with them, e.g. [`PaymentService::retry`](src/payments.py#L12-L30) for the `retry` method of the `PaymentService` class
+- When a code chunk is labeled with an embedded language, e.g. `(embedded SQL)`, its code is written in that language inside the file, e.g. a query in a string: name the language when you refer to it
 - Only internal links to the current file work
 - Basic markdown text formatting rules are allowed, and you should use titles to improve readability
 
@@ -194,6 +409,8 @@ Respect these rules at all times:
 - Do NOT output bare symbols. ALL symbols must include a link
   - E.g. Do not simply write `Bar`, write [`Bar`](src/bar.rs#L100-L105).
   - E.g. Do not simply write "Foos are functions that create `Foo` values out of thin air." Instead, write: "Foos are functions that create [`Foo`](src/foo.rs#L80-L120) values out of thin air."
+- When a symbol lookup lists the definitions a symbol is in, name the symbol with them, e.g. [`PaymentService::retry`](src/payments.py#L12-L30) for the `retry` method of the `PaymentService` class
+- When a code chunk is labeled with an embedded language, e.g. `(embedded SQL)`, its code is written in that language inside the file, e.g. a query in a string: name the language when you refer to it, and use it as the `<Language>` when you quote it
 - Link all fields
   - E.g. Do not simply write: "It has one main field: `foo`." Instead, write: "It has one main field: [`foo`](src/foo.rs#L193)."
 - Link all symbols, even when there are multiple in one sentence
@@ -511,7 +728,20 @@ Strictly adhere to the JSON format when constructing the output. This includes:
     }
 }
 
-pub fn question_concept_generator_prompt(issue_desc: &str, repo_name: &str) -> String {
+// `repo_summary` is the condensed summary of the repo generated at index time, it lets the
+// tasks name the real components of the repo.
+pub fn question_concept_generator_prompt(
+    issue_desc: &str,
+    repo_name: &str,
+    repo_summary: Option<&str>,
+) -> String {
+    let repo_context = match repo_summary {
+        Some(summary) if !summary.trim().is_empty() => format!(
+            "Overview of the repository, refer to its real languages, frameworks and directories in the tasks and questions:\n        '''{}'''\n\n        ",
+            summary
+        ),
+        _ => String::new(),
+    };
     let question_concept_generator_prompt = format!(
         r#"#####
 
@@ -602,7 +832,7 @@ pub fn question_concept_generator_prompt(issue_desc: &str, repo_name: &str) -> S
 
         IMPORTANT: If 'ask_user' is populated to clarify the issue, the 'tasks' array must be empty to maintain clear communication and avoid conflicting instructions. This ensures that the tool does not generate tasks based on assumptions or incomplete information. This measure is crucial in ensuring that a junior developer is not misguided by incomplete or ambiguous tasks which could lead to confusion or ineffective problem-solving.
 
-        issue description- '''{issue_desc}'''
+        {repo_context}issue description- '''{issue_desc}'''
         repo_name- '''{repo_name}'''
 
         DO NOT confuse tasks with questions. Tasks should clearly outline 'what' needs to be done, providing enough detail for a junior engineer to understand and execute the tasks without further clarifications. The 'ask_user' prompt is vital for obtaining the necessary clarity and should be used whenever the issue description lacks the specificity needed for task generation.
@@ -616,6 +846,271 @@ pub fn question_concept_generator_prompt(issue_desc: &str, repo_name: &str) -> S
     question_concept_generator_prompt
 }
 
+pub fn reformulate_not_found_question_prompt(
+    question: &str,
+    attempted_queries: &[String],
+    closest_paths: &[String],
+) -> String {
+    let mut prompt = format!(
+        "A code search agent couldn't find any relevant code in the codebase to answer the following question:\n\nQuestion: '{}'\n\n",
+        question
+    );
+
+    if !attempted_queries.is_empty() {
+        prompt += "These search queries were already tried without success:\n";
+        for query in attempted_queries {
+            prompt += &format!("  - {}\n", query);
+        }
+    }
+
+    if !closest_paths.is_empty() {
+        prompt += "\nThese are the closest file paths the agent came across:\n";
+        for path in closest_paths {
+            prompt += &format!("  - {}\n", path);
+        }
+    }
+
+    prompt += "\nRewrite the question so that it's more likely to match the code in the codebase. Use different terminology than the queries already tried, prefer likely identifier, module or file names, and keep the intent of the original question. Respond only with the rewritten question, nothing else.\n";
+
+    prompt
+}
+
+// Asks to generate the tasks again when some of them name components the repo doesn't have.
+// `ungrounded` holds each such task with its unknown mentions and the closest real components to them.
+pub fn task_grounding_repair_prompt(ungrounded: &[(String, Vec<(String, Vec<String>)>)]) -> String {
+    let mut prompt = "Some of the tasks you generated mention components that don't exist in the repository:\n\n".to_string();
+
+    for (task, mentions) in ungrounded {
+        prompt += &format!("Task: '{}'\n", task);
+        for (mention, closest) in mentions {
+            if closest.is_empty() {
+                prompt += &format!("  - '{}' wasn't found\n", mention);
+            } else {
+                prompt += &format!(
+                    "  - '{}' wasn't found, the closest components are: {}\n",
+                    mention,
+                    closest.join(", ")
+                );
+            }
+        }
+    }
+
+    prompt += "\nGenerate the task list again. Refer only to files, modules and types that exist in the repository, use the closest components listed above instead of the missing ones, and keep the tasks that were fine as they are. Respond with the same JSON format as before, nothing else.\n";
+
+    prompt
+}
+
+// Asks for the steps to verify an answer, over the code the answer was written from. `test_paths`
+// are the test files the search found for the query, the only tests the steps may run.
+pub fn verification_prompt(context: &str, answer: &str, test_paths: &[String]) -> String {
+    let tests = if test_paths.is_empty() {
+        "No test files were found for the query, don't suggest running tests.".to_string()
+    } else {
+        format!(
+            "These test files were found for the query, they are the only tests you may suggest running:\n{}",
+            test_paths.join("\n")
+        )
+    };
+    format!(
+        r#"{context}#####
+
+The code above was used to write this answer:
+
+{answer}
+
+#####
+
+{tests}
+
+Write 2 to 4 steps the user can follow to check that the answer is right by exercising the code above, e.g. running a test file, calling a function with a given input, looking for a log line the code writes, or sending a request to an endpoint it serves.
+Respect these rules at all times:
+- Every step must cite the file of the code it exercises, with a path exactly as written above
+- Cite the lines with the line numbers shown above, as [start, end], or leave them out for a whole file
+- Don't invent files, functions, log lines, endpoints or commands that the code above doesn't show
+- Write fewer steps rather than steps you can't ground in the code above, an empty list is fine
+
+Respond only with a JSON array like this one, nothing else:
+[
+  {{"kind": "run_test", "instruction": "Run the refund tests", "path": "tests/refund_test.rs", "command": "cargo test --test refund_test"}},
+  {{"kind": "call_function", "instruction": "Call retry_refund with a refund that failed once, it should be queued again", "path": "src/refund.rs", "lines": [10, 42]}},
+  {{"kind": "check_log", "instruction": "Look for the 'Retrying refund' line in the logs", "path": "src/refund.rs", "lines": [30, 30]}},
+  {{"kind": "hit_endpoint", "instruction": "Send a refund request and check it is retried", "path": "src/api/refunds.rs", "lines": [5, 20], "command": "curl -X POST localhost:8080/refunds"}}
+]
+"#
+    )
+}
+
+// Asks for a short explanation of one function, for the hover of an editor. `callees` are the
+// (name, path, snippet) of the functions it calls, shown as context only.
+pub fn explain_symbol_prompt(
+    path: &str,
+    symbol: &str,
+    source: &str,
+    callees: &[(String, String, String)],
+) -> String {
+    let mut prompt = format!("The function `{symbol}` of {path}:\n\n{source}\n\n");
+    if !callees.is_empty() {
+        prompt += "Functions it calls:\n\n";
+        for (name, path, snippet) in callees {
+            prompt += &format!("`{name}` of {path}:\n{snippet}\n\n");
+        }
+    }
+    prompt += r#"Explain `"#;
+    prompt += symbol;
+    prompt += r#"` to a developer hovering over it in their editor.
+Respect these rules at all times:
+- The summary is at most 3 sentences, it says what the function does and why, not how line by line
+- Describe every parameter in a few words, leave the list empty when it has none
+- Describe what it returns in a few words, or null when it returns nothing
+- Only state what the code above shows
+
+Respond only with a JSON object like this one, nothing else:
+{"summary": "Retries a failed refund with an exponential backoff, until it succeeds or runs out of attempts.", "parameters": [{"name": "refund", "description": "the refund that failed"}], "returns": "whether the refund was queued again"}
+"#;
+    prompt
+}
+
+// Asks for the files one task would change, from its answers and the code they were found in.
+// `tasks` are all the tasks of the conversation in order, `task_number` is the 1-based number of
+// the one to plan among them.
+pub fn change_plan_prompt(
+    user_query: &str,
+    tasks: &[String],
+    task_number: usize,
+    task: &TaskDetailsWithContext,
+) -> String {
+    let mut prompt = format!(
+        "A developer is working on this issue:\n\nIssue: '{}'\n\nIt was broken down into these tasks:\n",
+        user_query
+    );
+    for (i, description) in tasks.iter().enumerate() {
+        prompt += &format!("  {}. {}\n", i + 1, description);
+    }
+
+    prompt += &format!("\nWhat was found in the codebase for task {}:\n", task_number);
+    for (i, question) in task.questions.iter().enumerate() {
+        prompt += &format!("  - Q: {}\n", question);
+        if let Some(answer) = task.answers.get(i) {
+            prompt += &format!("    A: {}\n", answer);
+        }
+    }
+    prompt += "\nThe code the answers were found in:\n";
+    for context in &task.merged_code_contexts {
+        let ranges = context
+            .ranges
+            .iter()
+            .map(|range| format!("{}-{}", range.start, range.end))
+            .collect::<Vec<_>>();
+        if ranges.is_empty() {
+            prompt += &format!("  - {}\n", context.path);
+        } else {
+            prompt += &format!("  - {} (lines {})\n", context.path, ranges.join(", "));
+        }
+    }
+
+    prompt += &format!(
+        r#"
+List the files task {task_number} would change, without writing the changes themselves.
+Respect these rules at all times:
+- Only modify or delete files listed above or named in the answers, with their path exactly as written there
+- Add a file only when no existing file is the right place for the change
+- Describe each change in one sentence
+- Estimate the number of lines added, changed or removed in each file
+- Set depends_on_task to the number of the task whose changes this one needs first, or null
+- An empty list is fine when the task needs no change
+
+Respond only with a JSON array like this one, nothing else:
+[
+  {{"path": "src/config.rs", "change_kind": "modify", "description": "Read the retry count from the environment", "estimated_lines": 12, "depends_on_task": null}},
+  {{"path": "src/retry.rs", "change_kind": "add", "description": "Retry policy with an exponential backoff", "estimated_lines": 40, "depends_on_task": 1}}
+]
+"#
+    );
+
+    prompt
+}
+
+pub fn classify_follow_up_prompt(previous_query: &str, tasks: &[String], message: &str) -> String {
+    let mut prompt = format!(
+        "A user and a code assistant have finished working through the following issue:\n\nIssue: '{}'\n\n",
+        previous_query
+    );
+
+    if !tasks.is_empty() {
+        prompt += "The assistant broke the issue down into these tasks:\n";
+        for (i, task) in tasks.iter().enumerate() {
+            prompt += &format!("  {}. {}\n", i + 1, task);
+        }
+    }
+
+    prompt += &format!("\nThe user now sent this message:\n\nMessage: '{}'\n\n", message);
+    prompt += "Decide whether the message is a FOLLOW_UP question about the issue, its tasks or the code already discussed, or a NEW_ISSUE that needs to be broken down into tasks from scratch. Respond only with FOLLOW_UP or NEW_ISSUE, nothing else.\n";
+
+    prompt
+}
+
+// Summarizes the messages of a conversation that no longer fit in the prompt history.
+// Each message is a (role, content) pair, in conversation order.
+pub fn conversation_history_summary_prompt(messages: &[(String, String)]) -> String {
+    let mut prompt = "A user and a code assistant exchanged the following messages about a codebase:\n\n".to_string();
+
+    for (role, content) in messages {
+        prompt += &format!("{}: {}\n\n", role, content);
+    }
+
+    prompt += "Summarize the conversation in a short paragraph. Keep the issues the user raised, the decisions that were made and the names of files, functions and other identifiers that were mentioned. Respond only with the summary.\n";
+
+    prompt
+}
+
+// Synthesizes the answers of the questions of one subtask, the task summary reads the synthesis
+// instead of the answers. Only the code contexts the answers cited are listed, not their code.
+pub fn subtask_synthesis_prompt(
+    user_query: &str,
+    task_description: &str,
+    subtask: &SubtaskDetails,
+) -> String {
+    let mut prompt = format!(
+        "As a junior software engineer, you're working on an issue with a colleague, who broke it down into tasks and subtasks and answered questions about the codebase for each subtask. Here's the issue you're addressing:\n\nUser Query: '{}'\n\n",
+        user_query
+    );
+
+    prompt += &format!("## Task: {}\n", task_description);
+    prompt += &format!("## Subtask: {}\n\n", subtask.subtask_description);
+
+    prompt += "### Questions and Answers:\n";
+    for (i, question) in subtask.questions.iter().enumerate() {
+        prompt += &format!("  - Q{}: {}\n", i + 1, question);
+        if let Some(answer) = subtask.answers.get(i) {
+            prompt += &format!("    - Answer: {}\n", answer);
+        }
+    }
+
+    if !subtask.merged_code_contexts.is_empty() {
+        prompt += "\n### Code Cited by the Answers:\n";
+        for context in &subtask.merged_code_contexts {
+            let ranges = context
+                .ranges
+                .iter()
+                .map(|range| format!("{}-{}", range.start, range.end))
+                .collect::<Vec<_>>();
+            prompt += &format!("  - {} (lines {})\n", context.path, ranges.join(", "));
+        }
+    }
+
+    if !subtask.unresolved_questions.is_empty() {
+        prompt += "\n### Couldn't Determine:\n";
+        prompt += "No relevant code was found for these questions, don't guess their answers:\n";
+        for question in &subtask.unresolved_questions {
+            prompt += &format!("  - {}\n", question);
+        }
+    }
+
+    prompt += "\nSynthesize the answers into one short paragraph of what this subtask involves in the codebase: the files and functions to work on, how they relate and what is still unknown. Keep the file paths and identifiers the answers use, and don't add anything the answers don't say. Respond only with the synthesis, in at most 150 words.\n";
+
+    prompt
+}
+
 pub fn create_task_answer_summarization_prompt(
     user_query: &str,
     tasks_details: &TasksQuestionsAnswersDetails,
@@ -629,13 +1124,41 @@ pub fn create_task_answer_summarization_prompt(
 
     for (i, task) in tasks_details.tasks.iter().enumerate() {
         prompt += &format!("### Task {}: {}\n", i + 1, task.task_description);
-        prompt += "Key points from the answers:\n";
 
-        // Adding questions and answers for the task in bullet points.
-        for (j, question) in task.questions.iter().enumerate() {
-            prompt += &format!("  - Q{}: {}\n", j + 1, question);
-            if j < task.answers.len() {
-                prompt += &format!("    - Answer: {}\n", task.answers[j]);
+        if task.subtasks.is_empty() {
+            prompt += "Key points from the answers:\n";
+
+            // Adding questions and answers for the task in bullet points.
+            for (j, question) in task.questions.iter().enumerate() {
+                prompt += &format!("  - Q{}: {}\n", j + 1, question);
+                if j < task.answers.len() {
+                    prompt += &format!("    - Answer: {}\n", task.answers[j]);
+                }
+            }
+        }
+
+        // Each subtask is given by the synthesis of its answers, the answers of a subtask that
+        // couldn't be synthesized are given as they are.
+        for (j, subtask) in task.subtasks.iter().enumerate() {
+            if subtask.synthesis.is_none() && subtask.questions.is_empty() {
+                continue;
+            }
+            prompt += &format!(
+                "#### Subtask {}.{}: {}\n",
+                i + 1,
+                j + 1,
+                subtask.subtask_description
+            );
+            match &subtask.synthesis {
+                Some(synthesis) => prompt += &format!("{}\n", synthesis.trim()),
+                None => {
+                    for (k, question) in subtask.questions.iter().enumerate() {
+                        prompt += &format!("  - Q{}: {}\n", k + 1, question);
+                        if let Some(answer) = subtask.answers.get(k) {
+                            prompt += &format!("    - Answer: {}\n", answer);
+                        }
+                    }
+                }
             }
         }
 
@@ -647,6 +1170,21 @@ pub fn create_task_answer_summarization_prompt(
         );
     }
 
+    // Questions the agent couldn't answer from the codebase go in their own section,
+    // so they are not mistaken for findings.
+    let unresolved_questions = tasks_details
+        .tasks
+        .iter()
+        .flat_map(|task| task.unresolved_questions.iter())
+        .collect::<Vec<_>>();
+    if !unresolved_questions.is_empty() {
+        prompt += "\n## Couldn't Determine:\n";
+        prompt += "No relevant code was found in the codebase for the following questions. Do not guess answers for them, list them under a \"Couldn't determine\" section in your summary:\n";
+        for question in unresolved_questions {
+            prompt += &format!("  - {}\n", question);
+        }
+    }
+
     // Section for listing critical clarifying questions.
     prompt += "\n## Critical Clarifying Questions:\n";
     prompt += "Imagine you are in the middle of coding to solve the above tasks. The following questions are crucial for continuing your coding process effectively. List any points that need further clarification, or where information seems to be conflicting, ambiguous, or missing, which could potentially block progress while coding to solve the task:\n";
@@ -660,6 +1198,27 @@ pub fn create_task_answer_summarization_prompt(
     prompt
 }
 
+// Appended to the task summary prompt when answers described the same symbol in conflicting ways,
+// the summary lists the descriptions it settled on under `RECONCILED_HEADING`.
+pub fn glossary_reconciliation_prompt(conflicts: &[GlossaryEntry]) -> String {
+    let mut prompt = "\n## Conflicting Descriptions:\nThe answers above described these symbols in conflicting ways. Check the descriptions against the answers, settle on one description per symbol and use it in your summary:\n".to_string();
+
+    for entry in conflicts {
+        prompt += &format!("  - `{}` ({})\n", entry.symbol, entry.path);
+        prompt += &format!("    - {}\n", entry.description);
+        for conflict in &entry.conflicts {
+            prompt += &format!("    - {}\n", conflict.description);
+        }
+    }
+
+    prompt += &format!(
+        "\nAfter the clarifying questions, end the summary with a section headed `{}` listing the description you settled on for each of these symbols, one line each, as: - `symbol`: description\n",
+        RECONCILED_HEADING
+    );
+
+    prompt
+}
+
 async fn fetch_code_snippet(
     request: CodeSpanRequest,
     code_search_url: &str,
@@ -669,11 +1228,17 @@ async fn fetch_code_snippet(
 
     debug!("API URL: {}", api_url);
     // Making a POST request to the code search API with the given span request
-    let response = client
-        .post(api_url)
-        .json(&request)
-        .send()
-        .await?
+    let mut request_builder = crate::telemetry::propagate(client.post(api_url).json(&request));
+    if let Some(key) = crate::auth::service_api_key() {
+        request_builder = request_builder.bearer_auth(key);
+    }
+    let response = crate::local_services::send(request_builder).await?;
+    // a range cited before the file was shortened past it, the error carries the current line count.
+    if response.status() == reqwest::StatusCode::BAD_REQUEST {
+        let error = response.json::<SpanRangeError>().await?;
+        return Err(error.into());
+    }
+    let response = response
         .error_for_status()? // Checks for HTTP error statuses
         .json::<Vec<CodeChunk>>()
         .await?;
@@ -696,6 +1261,19 @@ pub async fn generate_single_task_summarization_prompt(
         task_detail.task_description
     );
 
+    // the syntheses of the answers of the subtasks, when they were written.
+    let syntheses = task_detail
+        .subtasks
+        .iter()
+        .filter_map(|subtask| Some((&subtask.subtask_description, subtask.synthesis.as_ref()?)))
+        .collect::<Vec<_>>();
+    if !syntheses.is_empty() {
+        prompt += "#### Subtasks:\n";
+        for (subtask, synthesis) in syntheses {
+            prompt += &format!("**Subtask**: {}\n{}\n\n", subtask, synthesis.trim());
+        }
+    }
+
     // Adding code contexts to the prompt
     prompt += "#### Code Contexts:\n";
     // Inside generate_single_task_summarization_prompt function
@@ -708,13 +1286,29 @@ pub async fn generate_single_task_summarization_prompt(
             id: Some(task_detail.task_id.to_string()),
         };
 
-        let code_snippets = fetch_code_snippet(code_span_request, url).await?;
+        let code_snippets = match fetch_code_snippet(code_span_request, url).await {
+            Ok(code_snippets) => code_snippets,
+            Err(e) => match e.downcast_ref::<SpanRangeError>() {
+                Some(error) => {
+                    prompt += &format!(
+                        "**File**: {}\nThe cited lines {:?} no longer exist, the file has {} lines now.\n",
+                        context.path, context.ranges, error.line_count
+                    );
+                    continue;
+                }
+                None => return Err(e),
+            },
+        };
         for snippet in code_snippets {
             //debug!("Code from the API: {}", snippet);
             prompt += &format!(
-                "**File**: {}\n**Code** (Lines {} - {}):\n```\n{}\n```\n",
-                snippet.path, snippet.start_line, snippet.end_line, snippet.snippet
+                "**File**: {}\n**Code** (Lines {} - {}):\n",
+                snippet.path, snippet.start_line, snippet.end_line
             );
+            if snippet.adjusted {
+                prompt += "The file changed since this code was cited, the lines were clamped to its current length and may not match the original context.\n";
+            }
+            prompt += &format!("```\n{}\n```\n", snippet.snippet);
         }
     }
 
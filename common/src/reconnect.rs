@@ -0,0 +1,399 @@
+// Long-lived database clients that survive a restart of the database.
+//
+// The qdrant channel and the quickwit http client keep failing once the server behind them
+// restarted, `Reconnecting` rebuilds the client when an operation fails with a connection error
+// and retries the operation once on the new client.
+
+use std::future::Future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+
+type BuildFuture<C> = Pin<Box<dyn Future<Output = Result<C>> + Send>>;
+type BuildFn<C> = Box<dyn Fn() -> BuildFuture<C> + Send + Sync>;
+
+// Messages of the errors that mean the server can't be reached, e.g. the tonic transport errors
+// the qdrant client wraps into its own.
+const CONNECTION_ERROR_MARKERS: &[&str] = &[
+    "transport error",
+    "connection refused",
+    "connection reset",
+    "broken pipe",
+    "status: unavailable",
+    "error trying to connect",
+];
+
+/// Body of the `/ready` route of the services.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    // a client is reconnecting, or failed to and will try again on the next request.
+    pub degraded: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<ReadinessCheck>) -> Self {
+        Self {
+            ready: checks.iter().all(|check| check.ok),
+            degraded: checks.iter().any(|check| check.degraded),
+            checks,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadinessCheck {
+    pub name: String,
+    pub ok: bool,
+    pub degraded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How the client is rebuilt after a connection error: `attempts` builds at most, waiting
+/// `initial_delay` after the first failed one and doubling the wait up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectBackoff {
+    pub attempts: usize,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A client rebuilt when an operation fails with a connection error.
+///
+/// Operations go through `run`, which retries the failed operation once on the rebuilt client.
+/// The service is reported as degraded from the first connection error until a rebuild succeeds.
+pub struct Reconnecting<C> {
+    name: &'static str,
+    // the client and its generation, bumped on every rebuild.
+    client: RwLock<(u64, Arc<C>)>,
+    build: BuildFn<C>,
+    backoff: ReconnectBackoff,
+    // only one caller rebuilds at a time, the others wait and use its client.
+    rebuilding: tokio::sync::Mutex<()>,
+    degraded: AtomicBool,
+    rebuild_attempts: AtomicUsize,
+}
+
+impl<C: Send + Sync + 'static> Reconnecting<C> {
+    /// `build` creates a new client, it's called with the backoff after a connection error.
+    pub fn new<F, Fut>(name: &'static str, client: C, build: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<C>> + Send + 'static,
+    {
+        Self {
+            name,
+            client: RwLock::new((0, Arc::new(client))),
+            build: Box::new(move || Box::pin(build())),
+            backoff: ReconnectBackoff::default(),
+            rebuilding: tokio::sync::Mutex::new(()),
+            degraded: AtomicBool::new(false),
+            rebuild_attempts: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: ReconnectBackoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The current client, operations that should be retried after a reconnection use `run` instead.
+    pub fn client(&self) -> Arc<C> {
+        self.client.read().1.clone()
+    }
+
+    /// True from the first connection error until the client was rebuilt.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the client was built again, failed builds included.
+    pub fn rebuild_attempts(&self) -> usize {
+        self.rebuild_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Runs the operation on the client. When it fails with a connection error the client is
+    /// rebuilt and the operation is run once more, other errors are returned as they are.
+    pub async fn run<T, F, Fut>(&self, op: F) -> Result<T>
+    where
+        F: Fn(Arc<C>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let (generation, client) = self.client.read().clone();
+        match op(client).await {
+            Err(e) if is_connection_error(&e) => {
+                warn!("Connection to {} failed, reconnecting: {:#}", self.name, e);
+                let client = self.reconnect(generation).await.map_err(|rebuild_error| {
+                    rebuild_error.context(format!(
+                        "Failed to reconnect to {} after: {:#}",
+                        self.name, e
+                    ))
+                })?;
+                op(client).await
+            }
+            result => {
+                if result.is_ok() && self.is_degraded() && self.client.read().0 == generation {
+                    // the server came back without a rebuild, e.g. a transient error.
+                    self.degraded.store(false, Ordering::Relaxed);
+                }
+                result
+            }
+        }
+    }
+
+    /// Readiness of the database, from a lightweight call like listing the collections rather
+    /// than a bare connection. The call reconnects like any other operation.
+    pub async fn check<T, F, Fut>(&self, op: F) -> ReadinessCheck
+    where
+        F: Fn(Arc<C>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let result = self.run(op).await;
+        ReadinessCheck {
+            name: self.name.to_string(),
+            ok: result.is_ok(),
+            degraded: self.is_degraded(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+
+    // Builds a new client unless another caller already replaced the one of `failed_generation`.
+    async fn reconnect(&self, failed_generation: u64) -> Result<Arc<C>> {
+        let _rebuilding = self.rebuilding.lock().await;
+        {
+            let current = self.client.read();
+            if current.0 != failed_generation {
+                return Ok(current.1.clone());
+            }
+        }
+
+        self.degraded.store(true, Ordering::Relaxed);
+        let mut delay = self.backoff.initial_delay;
+        let mut last_error = None;
+        for attempt in 1..=self.backoff.attempts.max(1) {
+            if attempt > 1 {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(self.backoff.max_delay);
+            }
+            self.rebuild_attempts.fetch_add(1, Ordering::Relaxed);
+            match (self.build)().await {
+                Ok(client) => {
+                    let client = Arc::new(client);
+                    *self.client.write() = (failed_generation + 1, client.clone());
+                    self.degraded.store(false, Ordering::Relaxed);
+                    metrics::record_db_reconnect(self.name, "success");
+                    info!("Reconnected to {} after {} attempt(s)", self.name, attempt);
+                    return Ok(client);
+                }
+                Err(e) => {
+                    warn!("Attempt {} to reconnect to {} failed: {:#}", attempt, self.name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        metrics::record_db_reconnect(self.name, "failure");
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No attempt to reconnect was made")))
+    }
+}
+
+/// Whether the error means the server couldn't be reached, as opposed to a failed request.
+pub fn is_connection_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io_error.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::NotConnected
+            ) {
+                return true;
+            }
+        }
+        if let Some(http_error) = cause.downcast_ref::<reqwest::Error>() {
+            if http_error.is_connect() {
+                return true;
+            }
+        }
+        let message = cause.to_string().to_ascii_lowercase();
+        CONNECTION_ERROR_MARKERS
+            .iter()
+            .any(|marker| message.contains(marker))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A client that can't reach its server when built unhealthy.
+    struct MockClient {
+        healthy: bool,
+    }
+
+    impl MockClient {
+        async fn list_collections(&self) -> Result<Vec<String>> {
+            if self.healthy {
+                Ok(vec!["documents".to_string()])
+            } else {
+                Err(std::io::Error::from(ErrorKind::ConnectionRefused).into())
+            }
+        }
+    }
+
+    fn no_delay(attempts: usize) -> ReconnectBackoff {
+        ReconnectBackoff {
+            attempts,
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    // Builds fail until `failures` of them were made, the server is restarting meanwhile.
+    fn restarting(failures: usize, builds: Arc<AtomicUsize>) -> Reconnecting<MockClient> {
+        Reconnecting::new("mock", MockClient { healthy: false }, move || {
+            let build = builds.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                if build <= failures {
+                    Err(std::io::Error::from(ErrorKind::ConnectionRefused).into())
+                } else {
+                    Ok(MockClient { healthy: true })
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_request_succeeds_once_the_server_is_back() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let reconnecting = restarting(3, builds.clone()).with_backoff(no_delay(5));
+
+        let collections = reconnecting
+            .run(|client| async move { client.list_collections().await })
+            .await
+            .unwrap();
+
+        assert_eq!(collections, vec!["documents".to_string()]);
+        assert_eq!(builds.load(Ordering::SeqCst), 4);
+        assert_eq!(reconnecting.rebuild_attempts(), 4);
+        assert!(!reconnecting.is_degraded());
+
+        // the rebuilt client is kept for the next requests.
+        reconnecting
+            .run(|client| async move { client.list_collections().await })
+            .await
+            .unwrap();
+        assert_eq!(reconnecting.rebuild_attempts(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_degraded_until_a_rebuild_succeeds() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let reconnecting = restarting(3, builds.clone()).with_backoff(no_delay(2));
+
+        let result = reconnecting
+            .run(|client| async move { client.list_collections().await })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(reconnecting.rebuild_attempts(), 2);
+        assert!(reconnecting.is_degraded());
+
+        // the third build still fails, the fourth one reaches the server.
+        reconnecting
+            .run(|client| async move { client.list_collections().await })
+            .await
+            .unwrap();
+        assert_eq!(reconnecting.rebuild_attempts(), 4);
+        assert!(!reconnecting.is_degraded());
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_are_not_retried() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let reconnecting = restarting(0, builds.clone()).with_backoff(no_delay(5));
+        let calls = AtomicUsize::new(0);
+
+        let result: Result<()> = reconnecting
+            .run(|_| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Err(anyhow::anyhow!("collection documents not found")) }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(reconnecting.rebuild_attempts(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_of_a_restarting_server() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let reconnecting = restarting(3, builds).with_backoff(no_delay(2));
+
+        let check = reconnecting
+            .check(|client| async move { client.list_collections().await })
+            .await;
+        let readiness = Readiness::new(vec![check]);
+        assert!(!readiness.ready);
+        assert!(readiness.degraded);
+
+        let check = reconnecting
+            .check(|client| async move { client.list_collections().await })
+            .await;
+        assert_eq!(
+            Readiness::new(vec![check]),
+            Readiness {
+                ready: true,
+                degraded: false,
+                checks: vec![ReadinessCheck {
+                    name: "mock".to_string(),
+                    ok: true,
+                    degraded: false,
+                    error: None,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        let refused: anyhow::Error = std::io::Error::from(ErrorKind::ConnectionRefused).into();
+        assert!(is_connection_error(&refused.context("Failed to search")));
+        assert!(is_connection_error(&anyhow::anyhow!(
+            "status: Unavailable, message: \"error trying to connect: tcp connect error\""
+        )));
+        assert!(is_connection_error(&anyhow::anyhow!("transport error")));
+        assert!(!is_connection_error(&anyhow::anyhow!(
+            "Not found: Collection `documents` doesn't exist!"
+        )));
+        let timed_out: anyhow::Error = std::io::Error::from(ErrorKind::TimedOut).into();
+        assert!(!is_connection_error(&timed_out));
+    }
+}
@@ -0,0 +1,156 @@
+// Overview of a repo generated when it is indexed: its languages, top level directories,
+// frameworks and the start of its README. It grounds the prompts in what the repo actually is,
+// so the generated tasks can name its real components.
+
+use serde::{Deserialize, Serialize};
+
+/// Where the summary is stored in the quickwit index of the repo, the path isn't a file of the repo.
+pub const REPO_SUMMARY_PATH: &str = ".incredible/repo-summary.json";
+
+/// Language the summary document is indexed with, it isn't parsed like source code.
+pub const REPO_SUMMARY_LANGUAGE: &str = "REPO_SUMMARY";
+
+/// Bounds of a summary, whatever the size of the repo.
+pub const MAX_SUMMARY_LANGUAGES: usize = 10;
+pub const MAX_SUMMARY_DIRECTORIES: usize = 20;
+pub const MAX_SUMMARY_FRAMEWORKS: usize = 20;
+pub const MAX_README_LINES: usize = 20;
+pub const MAX_README_LINE_CHARS: usize = 200;
+pub const MAX_KEY_FILES: usize = 30;
+
+/// Name the languages and directories beyond the bounds are folded into.
+pub const OTHER_ENTRIES: &str = "other";
+
+/// Default size in characters of the summary added to the prompts.
+pub const CONDENSED_SUMMARY_CHARS: usize = 1500;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct LanguageStats {
+    pub language: String,
+    pub files: usize,
+    pub lines: usize,
+}
+
+/// Indexed files under a top level directory, `.` for the files at the root.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct DirectoryStats {
+    pub path: String,
+    pub files: usize,
+    pub lines: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct RepoSummary {
+    pub repo_name: String,
+    // commit the summary was generated at, empty when it isn't known.
+    #[serde(default)]
+    pub indexed_commit: String,
+    // most lines first.
+    #[serde(default)]
+    pub languages: Vec<LanguageStats>,
+    // most lines first.
+    #[serde(default)]
+    pub directories: Vec<DirectoryStats>,
+    #[serde(default)]
+    pub frameworks: Vec<String>,
+    // first lines of the README at the root of the repo.
+    #[serde(default)]
+    pub readme: Vec<String>,
+    // the entrypoints, routes and config of the repo, most central first. Empty for repos
+    // indexed before the files were ranked.
+    #[serde(default)]
+    pub key_files: Vec<String>,
+}
+
+impl RepoSummary {
+    pub fn is_empty(&self) -> bool {
+        self.languages.is_empty()
+            && self.directories.is_empty()
+            && self.frameworks.is_empty()
+            && self.readme.is_empty()
+            && self.key_files.is_empty()
+    }
+
+    /// Plain text version of the summary for the prompts, cut to `max_chars` characters.
+    /// The README comes last, it's the first thing cut.
+    pub fn condensed(&self, max_chars: usize) -> String {
+        let mut sections = Vec::new();
+        if !self.languages.is_empty() {
+            let languages = self
+                .languages
+                .iter()
+                .map(|l| format!("{} ({} files, {} lines)", l.language, l.files, l.lines))
+                .collect::<Vec<_>>();
+            sections.push(format!("Languages: {}", languages.join(", ")));
+        }
+        if !self.frameworks.is_empty() {
+            sections.push(format!("Frameworks: {}", self.frameworks.join(", ")));
+        }
+        if !self.directories.is_empty() {
+            let directories = self
+                .directories
+                .iter()
+                .map(|d| format!("{} ({} files)", d.path, d.files))
+                .collect::<Vec<_>>();
+            sections.push(format!("Top level directories: {}", directories.join(", ")));
+        }
+        let readme = self
+            .readme
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        if !readme.is_empty() {
+            sections.push(format!("README: {}", readme.join(" ")));
+        }
+
+        let condensed = sections.join("\n");
+        match condensed.char_indices().nth(max_chars) {
+            Some((end, _)) => format!("{}...", &condensed[..end]),
+            None => condensed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary() -> RepoSummary {
+        RepoSummary {
+            repo_name: "acme/shop".to_string(),
+            indexed_commit: "abc123".to_string(),
+            languages: vec![LanguageStats {
+                language: "Rust".to_string(),
+                files: 12,
+                lines: 3400,
+            }],
+            directories: vec![DirectoryStats {
+                path: "src".to_string(),
+                files: 10,
+                lines: 3000,
+            }],
+            frameworks: vec!["Cargo".to_string(), "Axum".to_string()],
+            readme: vec!["# Shop".to_string(), "".to_string(), "Orders and payments.".to_string()],
+            key_files: vec!["src/main.rs".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_condensed_summary() {
+        assert_eq!(
+            summary().condensed(CONDENSED_SUMMARY_CHARS),
+            "Languages: Rust (12 files, 3400 lines)\n\
+             Frameworks: Cargo, Axum\n\
+             Top level directories: src (10 files)\n\
+             README: # Shop Orders and payments."
+        );
+    }
+
+    #[test]
+    fn test_condensed_summary_is_bounded() {
+        let condensed = summary().condensed(20);
+        assert_eq!(condensed, "Languages: Rust (12 ...");
+        assert!(RepoSummary::default().condensed(20).is_empty());
+    }
+}
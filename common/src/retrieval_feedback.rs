@@ -0,0 +1,365 @@
+// What the retrieval of an answer got right, logged to tune the ranking. The valid citations of an
+// answer say which of the chunks symbol search returned were useful: after every answer code
+// understanding appends the query, the retrieved chunks with their scores and ranks, and the ids
+// of the cited ones to the log configured with `RETRIEVAL_FEEDBACK_LOG`, a JSONL file or a redis
+// stream. The log is append-only, the `tune-ranking` job of code search reads it to compute the
+// citation rates and the ranking weights.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::citations::{CitationReport, CitationStatus};
+use crate::models::{CodeChunk, RetrievalSource};
+use crate::task_graph::redis::establish_redis_connection;
+
+// field of the stream entries holding the record.
+const STREAM_FIELD: &str = "record";
+
+/// A chunk symbol search returned for the question.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetrievedChunk {
+    pub id: String,
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    // 0 for the best scored chunk of the answer.
+    pub rank: usize,
+    pub score: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<RetrievalSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol_type: Option<String>,
+}
+
+/// A line of the retrieval feedback log, one per answered question.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetrievalFeedback {
+    pub repo: String,
+    pub query: String,
+    pub task_id: String,
+    pub question_id: usize,
+    // unix timestamp in seconds.
+    pub recorded_at: u64,
+    pub retrieved: Vec<RetrievedChunk>,
+    // ids of the retrieved chunks a valid citation of the answer points into.
+    pub cited: Vec<String>,
+}
+
+/// The id of the chunk of `path` starting and ending at these lines, e.g. `src/lib.rs:10-24`.
+pub fn chunk_id(path: &str, start_line: usize, end_line: usize) -> String {
+    format!("{}:{}-{}", path, start_line, end_line)
+}
+
+impl RetrievalFeedback {
+    /// The record of an answer built from the chunks the agent gathered and the citations of the
+    /// answer. Only the chunks with a score are retrieval results, the pinned paths and the
+    /// chunks the agent read around them aren't ranked. Returns None when nothing was retrieved.
+    pub fn new(
+        repo: &str,
+        query: &str,
+        task_id: &str,
+        question_id: usize,
+        chunks: &[CodeChunk],
+        citations: &CitationReport,
+        recorded_at: u64,
+    ) -> Option<Self> {
+        let mut seen = HashSet::new();
+        let mut scored = chunks
+            .iter()
+            .filter(|chunk| chunk.score.is_some())
+            .filter(|chunk| seen.insert(chunk_id(&chunk.path, chunk.start_line, chunk.end_line)))
+            .collect::<Vec<_>>();
+        if scored.is_empty() {
+            return None;
+        }
+        // stable, the chunks of the same score keep the order the agent gathered them in.
+        scored.sort_by(|a, b| b.score.unwrap().total_cmp(&a.score.unwrap()));
+
+        let retrieved = scored
+            .iter()
+            .enumerate()
+            .map(|(rank, chunk)| RetrievedChunk {
+                id: chunk_id(&chunk.path, chunk.start_line, chunk.end_line),
+                path: chunk.path.clone(),
+                start_line: chunk.start_line,
+                end_line: chunk.end_line,
+                rank,
+                score: chunk.score.unwrap_or_default(),
+                source: chunk.source,
+                symbol_type: chunk.symbol_type.clone(),
+            })
+            .collect::<Vec<_>>();
+        let cited = retrieved
+            .iter()
+            .filter(|chunk| is_cited(chunk, citations))
+            .map(|chunk| chunk.id.clone())
+            .collect();
+        Some(Self {
+            repo: repo.to_string(),
+            query: query.to_string(),
+            task_id: task_id.to_string(),
+            question_id,
+            recorded_at,
+            retrieved,
+            cited,
+        })
+    }
+
+    pub fn is_cited(&self, chunk: &RetrievedChunk) -> bool {
+        self.cited.contains(&chunk.id)
+    }
+}
+
+// Whether a valid citation points into the chunk, a link to the whole file cites all its chunks.
+// The lines of the chunks are 0-based with an exclusive end, the cited ones 1-based and inclusive.
+fn is_cited(chunk: &RetrievedChunk, citations: &CitationReport) -> bool {
+    citations
+        .citations
+        .iter()
+        .filter(|citation| {
+            matches!(
+                citation.status,
+                CitationStatus::Valid | CitationStatus::Adjusted
+            )
+        })
+        .filter(|citation| citation.path == chunk.path)
+        .any(|citation| match citation.lines {
+            Some((start, end)) => {
+                start <= chunk.end_line.max(chunk.start_line + 1) && chunk.start_line < end
+            }
+            None => true,
+        })
+}
+
+/// Where the feedback records are appended, set with `RETRIEVAL_FEEDBACK_LOG` as
+/// `jsonl:<path>` or `redis-stream:<key>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedbackLog {
+    Jsonl(PathBuf),
+    RedisStream(String),
+}
+
+impl FromStr for FeedbackLog {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().split_once(':') {
+            Some(("jsonl", path)) if !path.is_empty() => Ok(FeedbackLog::Jsonl(path.into())),
+            Some(("redis-stream", key)) if !key.is_empty() => {
+                Ok(FeedbackLog::RedisStream(key.to_string()))
+            }
+            _ => Err(anyhow!(
+                "the feedback log must be `jsonl:<path>` or `redis-stream:<key>`, got `{}`",
+                value
+            )),
+        }
+    }
+}
+
+impl FeedbackLog {
+    /// Appends the record to the log, `redis_url` is only used by the streams.
+    pub fn append(&self, redis_url: &str, record: &RetrievalFeedback) -> Result<()> {
+        let line = serde_json::to_string(record)?;
+        match self {
+            FeedbackLog::Jsonl(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line)?;
+            }
+            FeedbackLog::RedisStream(key) => {
+                let mut conn = establish_redis_connection(redis_url)?;
+                redis::cmd("XADD")
+                    .arg(key)
+                    .arg("*")
+                    .arg(STREAM_FIELD)
+                    .arg(line)
+                    .query::<String>(&mut conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every record of the log in the order they were appended. Lines that can't be read, e.g.
+    /// cut by a crash while appending, are skipped.
+    pub fn read_all(&self, redis_url: &str) -> Result<Vec<RetrievalFeedback>> {
+        let lines = match self {
+            FeedbackLog::Jsonl(path) => BufReader::new(std::fs::File::open(path)?)
+                .lines()
+                .collect::<std::io::Result<Vec<_>>>()?,
+            FeedbackLog::RedisStream(key) => {
+                let mut conn = establish_redis_connection(redis_url)?;
+                let entries: Vec<(String, Vec<(String, String)>)> = redis::cmd("XRANGE")
+                    .arg(key)
+                    .arg("-")
+                    .arg("+")
+                    .query(&mut conn)?;
+                entries
+                    .into_iter()
+                    .flat_map(|(_, fields)| fields)
+                    .filter(|(field, _)| field == STREAM_FIELD)
+                    .map(|(_, record)| record)
+                    .collect()
+            }
+        };
+        Ok(lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    log::warn!(
+                        "Skipping a retrieval feedback record that can't be read: {}",
+                        e
+                    );
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::citations::{Citation, CitationSource};
+
+    fn chunk(path: &str, lines: (usize, usize), score: Option<f32>) -> CodeChunk {
+        CodeChunk {
+            path: path.to_string(),
+            snippet: "fn f() {}".to_string(),
+            start_line: lines.0,
+            end_line: lines.1,
+            score,
+            source: score.map(|_| RetrievalSource::Vector),
+            symbol_type: Some("function".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn citation(path: &str, lines: Option<(usize, usize)>, status: CitationStatus) -> Citation {
+        Citation {
+            path: path.to_string(),
+            source: CitationSource::Link,
+            cited_lines: lines,
+            lines,
+            status,
+            out_of_scope: None,
+            url: None,
+            anchor: None,
+            drift: None,
+            enclosing_symbol: None,
+        }
+    }
+
+    #[test]
+    fn test_record_ranks_the_retrieved_chunks_and_marks_the_cited_ones() {
+        let chunks = vec![
+            chunk("src/auth/jwt.rs", (10, 30), Some(0.6)),
+            chunk("src/auth/session.rs", (0, 20), Some(0.9)),
+            // read around a pinned path, not a retrieval result.
+            chunk("src/main.rs", (0, 10), None),
+            chunk("src/auth/jwt.rs", (10, 30), Some(0.6)),
+            chunk("src/auth/jwt.rs", (40, 60), Some(0.3)),
+            chunk("src/billing/retry.rs", (5, 15), Some(0.4)),
+        ];
+        let citations = CitationReport {
+            citations: vec![
+                citation("src/auth/jwt.rs", Some((12, 18)), CitationStatus::Valid),
+                citation("src/billing/retry.rs", None, CitationStatus::Adjusted),
+                citation("src/auth/session.rs", Some((5, 6)), CitationStatus::Invalid),
+            ],
+        };
+
+        let record = RetrievalFeedback::new(
+            "acme/api",
+            "where is the jwt validated",
+            "t",
+            1,
+            &chunks,
+            &citations,
+            7,
+        )
+        .unwrap();
+
+        let ranked = record
+            .retrieved
+            .iter()
+            .map(|chunk| (chunk.id.as_str(), chunk.rank, chunk.score))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranked,
+            vec![
+                ("src/auth/session.rs:0-20", 0, 0.9),
+                ("src/auth/jwt.rs:10-30", 1, 0.6),
+                ("src/billing/retry.rs:5-15", 2, 0.4),
+                ("src/auth/jwt.rs:40-60", 3, 0.3),
+            ]
+        );
+        // the invalid citation doesn't count, the link to the whole file does.
+        assert_eq!(
+            record.cited,
+            vec!["src/auth/jwt.rs:10-30", "src/billing/retry.rs:5-15"]
+        );
+        assert_eq!(record.retrieved[1].symbol_type.as_deref(), Some("function"));
+        assert_eq!(record.retrieved[1].source, Some(RetrievalSource::Vector));
+
+        assert!(RetrievalFeedback::new(
+            "acme/api",
+            "q",
+            "t",
+            1,
+            &[chunk("src/main.rs", (0, 10), None)],
+            &citations,
+            7
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_jsonl_log_appends_and_reads_the_records() {
+        let path =
+            std::env::temp_dir().join(format!("retrieval-feedback-{}.jsonl", uuid::Uuid::new_v4()));
+        let log: FeedbackLog = format!("jsonl:{}", path.display()).parse().unwrap();
+        let chunks = vec![chunk("src/auth/jwt.rs", (10, 30), Some(0.6))];
+        let first = RetrievalFeedback::new(
+            "acme/api",
+            "q1",
+            "t",
+            1,
+            &chunks,
+            &CitationReport::default(),
+            1,
+        )
+        .unwrap();
+        let second = RetrievalFeedback {
+            query: "q2".to_string(),
+            ..first.clone()
+        };
+
+        log.append("", &first).unwrap();
+        log.append("", &second).unwrap();
+        // a line cut by a crash is skipped.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"repo\": \"acme\n")
+            .unwrap();
+
+        assert_eq!(log.read_all("").unwrap(), vec![first, second]);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            "redis-stream:retrieval_feedback"
+                .parse::<FeedbackLog>()
+                .unwrap(),
+            FeedbackLog::RedisStream("retrieval_feedback".to_string())
+        );
+        assert!("kafka:feedback".parse::<FeedbackLog>().is_err());
+    }
+}
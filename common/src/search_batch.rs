@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use qdrant_client::{
+    prelude::QdrantClient,
+    qdrant::{ScoredPoint, SearchBatchPoints, SearchPoints},
+};
+use tracing::Instrument;
+
+use crate::reconnect::Reconnecting;
+use crate::{metrics, telemetry};
+
+/// Runs the searches of one collection in a single request, implemented by the qdrant client
+/// and mocked in tests. The results are returned in the order of the searches.
+#[async_trait]
+pub trait BatchSearcher: Send + Sync {
+    async fn batch_search(
+        &self,
+        collection_name: &str,
+        searches: Vec<SearchPoints>,
+    ) -> Result<Vec<Vec<ScoredPoint>>>;
+}
+
+#[async_trait]
+impl BatchSearcher for QdrantClient {
+    async fn batch_search(
+        &self,
+        collection_name: &str,
+        searches: Vec<SearchPoints>,
+    ) -> Result<Vec<Vec<ScoredPoint>>> {
+        let request = SearchBatchPoints {
+            collection_name: collection_name.to_string(),
+            search_points: searches,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let response = self
+            .search_batch_points(&request)
+            .instrument(telemetry::db_span("qdrant", "search_batch"))
+            .await?;
+        metrics::observe_db_query("qdrant", "search_batch", start.elapsed());
+
+        Ok(response
+            .result
+            .into_iter()
+            .map(|batch| batch.result)
+            .collect())
+    }
+}
+
+// Retried once on a new client when qdrant was restarted.
+#[async_trait]
+impl BatchSearcher for Reconnecting<QdrantClient> {
+    async fn batch_search(
+        &self,
+        collection_name: &str,
+        searches: Vec<SearchPoints>,
+    ) -> Result<Vec<Vec<ScoredPoint>>> {
+        self.run(|client| {
+            let searches = searches.clone();
+            async move { client.batch_search(collection_name, searches).await }
+        })
+        .await
+    }
+}
+
+/// Handle of a search added to a `SearchBatch`, its results are taken with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchSlot(usize);
+
+/// Searches prepared within one step, run together once they are all known.
+///
+/// Every search keeps its own limit, filter and threshold, the searches of a collection are sent
+/// as a single `search_batch` request and the collections are searched concurrently.
+#[derive(Debug, Default)]
+pub struct SearchBatch {
+    searches: Vec<SearchPoints>,
+}
+
+impl SearchBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, search: SearchPoints) -> BatchSlot {
+        self.searches.push(search);
+        BatchSlot(self.searches.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.searches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.searches.is_empty()
+    }
+
+    /// Runs the searches, a failed request only fails the searches of its collection.
+    pub async fn execute<S: BatchSearcher + ?Sized>(self, searcher: &S) -> BatchResults {
+        let query_count = self.searches.len();
+        let mut by_collection: BTreeMap<String, Vec<(usize, SearchPoints)>> = BTreeMap::new();
+        for (slot, search) in self.searches.into_iter().enumerate() {
+            by_collection
+                .entry(search.collection_name.clone())
+                .or_default()
+                .push((slot, search));
+        }
+        let request_count = by_collection.len();
+
+        let responses = join_all(by_collection.into_iter().map(
+            |(collection, searches)| async move {
+                let (slots, searches): (Vec<_>, Vec<_>) = searches.into_iter().unzip();
+                let expected = searches.len();
+                let response = match searcher.batch_search(&collection, searches).await {
+                    Ok(results) if results.len() == expected => Ok(results),
+                    Ok(results) => Err(format!(
+                        "search batch of {} returned {} results for {} searches",
+                        collection,
+                        results.len(),
+                        expected
+                    )),
+                    Err(e) => Err(format!("search batch of {} failed: {:?}", collection, e)),
+                };
+                (slots, response)
+            },
+        ))
+        .await;
+
+        let mut results: Vec<Option<Result<Vec<ScoredPoint>>>> =
+            (0..query_count).map(|_| None).collect();
+        for (slots, response) in responses {
+            match response {
+                Ok(points) => {
+                    for (slot, points) in slots.into_iter().zip(points) {
+                        results[slot] = Some(Ok(points));
+                    }
+                }
+                Err(error) => {
+                    log::error!("{}", error);
+                    for slot in slots {
+                        results[slot] = Some(Err(anyhow!(error.clone())));
+                    }
+                }
+            }
+        }
+
+        let saved = query_count.saturating_sub(request_count);
+        log::debug!(
+            "ran {} qdrant searches in {} requests, saved {} round trips",
+            query_count,
+            request_count,
+            saved
+        );
+        metrics::record_round_trips_saved("qdrant", saved);
+
+        BatchResults { results }
+    }
+}
+
+/// Results of an executed `SearchBatch`, by slot.
+#[derive(Debug)]
+pub struct BatchResults {
+    results: Vec<Option<Result<Vec<ScoredPoint>>>>,
+}
+
+impl BatchResults {
+    pub fn take(&mut self, slot: BatchSlot) -> Result<Vec<ScoredPoint>> {
+        self.results
+            .get_mut(slot.0)
+            .and_then(Option::take)
+            .unwrap_or_else(|| Err(anyhow!("results of search {} were already taken", slot.0)))
+    }
+}
+
+/// Runs a single search through the batch API, for callers with only one query to make.
+pub async fn search_one<S: BatchSearcher + ?Sized>(
+    searcher: &S,
+    search: SearchPoints,
+) -> Result<Vec<ScoredPoint>> {
+    let mut batch = SearchBatch::new();
+    let slot = batch.add(search);
+    batch.execute(searcher).await.take(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Answers every search with a single point scored with the limit of the search,
+    // which tells the results of the searches apart.
+    struct MockSearcher {
+        requests: Mutex<Vec<(String, Vec<SearchPoints>)>>,
+        failing_collection: Option<String>,
+    }
+
+    impl MockSearcher {
+        fn new(failing_collection: Option<&str>) -> Self {
+            Self {
+                requests: Mutex::new(Vec::new()),
+                failing_collection: failing_collection.map(str::to_string),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl BatchSearcher for MockSearcher {
+        async fn batch_search(
+            &self,
+            collection_name: &str,
+            searches: Vec<SearchPoints>,
+        ) -> Result<Vec<Vec<ScoredPoint>>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((collection_name.to_string(), searches.clone()));
+            if self.failing_collection.as_deref() == Some(collection_name) {
+                return Err(anyhow!("collection not found"));
+            }
+            Ok(searches
+                .iter()
+                .map(|search| {
+                    vec![ScoredPoint {
+                        score: search.limit as f32,
+                        ..Default::default()
+                    }]
+                })
+                .collect())
+        }
+    }
+
+    fn search(collection_name: &str, limit: u64, offset: u64, threshold: f32) -> SearchPoints {
+        SearchPoints {
+            collection_name: collection_name.to_string(),
+            vector: vec![0.1; 4],
+            limit,
+            offset: Some(offset),
+            score_threshold: Some(threshold),
+            ..Default::default()
+        }
+    }
+
+    fn scores(points: &[ScoredPoint]) -> Vec<f32> {
+        points.iter().map(|point| point.score).collect()
+    }
+
+    #[tokio::test]
+    async fn test_searches_of_a_collection_are_sent_in_one_batch() {
+        let searcher = MockSearcher::new(None);
+        let mut batch = SearchBatch::new();
+        let first = batch.add(search("documents", 20, 0, 0.3));
+        let other = batch.add(search("documents_symbol", 10, 0, 0.0));
+        let second = batch.add(search("documents", 5, 5, 0.5));
+        assert_eq!(batch.len(), 3);
+
+        let mut results = batch.execute(&searcher).await;
+
+        let requests = searcher.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (_, searches) = requests
+            .iter()
+            .find(|(collection, _)| collection == "documents")
+            .unwrap();
+        // the searches keep their own limits, offsets and thresholds.
+        assert_eq!(
+            searches
+                .iter()
+                .map(|s| (s.limit, s.offset, s.score_threshold))
+                .collect::<Vec<_>>(),
+            vec![(20, Some(0), Some(0.3)), (5, Some(5), Some(0.5))]
+        );
+
+        assert_eq!(scores(&results.take(first).unwrap()), vec![20.0]);
+        assert_eq!(scores(&results.take(other).unwrap()), vec![10.0]);
+        assert_eq!(scores(&results.take(second).unwrap()), vec![5.0]);
+        assert!(results.take(first).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_collection_only_fails_its_searches() {
+        let searcher = MockSearcher::new(Some("documents"));
+        let mut batch = SearchBatch::new();
+        let symbols = batch.add(search("documents_symbol", 20, 0, 0.0));
+        let docs = batch.add(search("documents", 10, 0, 0.0));
+
+        let mut results = batch.execute(&searcher).await;
+
+        assert_eq!(scores(&results.take(symbols).unwrap()), vec![20.0]);
+        assert!(results.take(docs).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_one() {
+        let searcher = MockSearcher::new(None);
+        let points = search_one(&searcher, search("documents", 7, 0, 0.0))
+            .await
+            .unwrap();
+
+        assert_eq!(scores(&points), vec![7.0]);
+        assert_eq!(searcher.requests.lock().unwrap().len(), 1);
+    }
+}
@@ -1,15 +1,28 @@
 use std::collections::HashMap;
+use std::sync::RwLock;
 
-use crate::{models::CodeSpanRequest, CodeChunk};
+use crate::budget::BudgetExceeded;
+use crate::capabilities::{Capabilities, Service, ServiceVersion, VERSION_PATH};
+use crate::generation::IndexGenerationGone;
+use crate::models::{CodeSpanRequest, SpanRangeError};
+use crate::models::CodeChunk;
+use crate::transport::{take_frame, Transport};
+use crate::{auth, local_services, telemetry};
 
 use anyhow::{anyhow, Error, Result};
+use once_cell::sync::Lazy;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use reqwest::{self, Client, Method, StatusCode, Url};
 use serde::{de::DeserializeOwned, Serialize};
-use serde_json::{json, Value}; // Ensure this is accessible, either defined here or imported.
+use serde_json::Value; // Ensure this is accessible, either defined here or imported.
 
 pub static  SYMBOL_COLLECTION_NAME: &str  = "documents_symbol";
 pub static  DOCUMENT_COLLECTION_NAME: &str  = "documents";
 
+// versions of the services from the handshake, read by the feature-gated call sites.
+static SERVICE_VERSIONS: Lazy<RwLock<HashMap<Service, ServiceVersion>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
 // Async function to fetch a specific span of code from a service.
 // search_service_url: The URL of the search service where the code span should be fetched from.
 // request: The data required by the search service to find and return the desired code span, encapsulated in a CodeSpanRequest struct.
@@ -27,11 +40,11 @@ pub async fn fetch_code_span(
     );
 
     // Send the POST request to the search_service_url with the JSON-serialized CodeSpanRequest.
-    let response = client
-        .post(search_service_url)
-        .json(&request)
-        .send()
-        .await?;
+    let mut request_builder = telemetry::propagate(client.post(search_service_url).json(&request));
+    if let Some(key) = auth::service_api_key() {
+        request_builder = request_builder.bearer_auth(key);
+    }
+    let response = local_services::send(request_builder).await?;
 
     match response.status() {
         // Handle successful responses (200-299).
@@ -44,9 +57,16 @@ pub async fn fetch_code_span(
                 .map_err(|e| anyhow!("Failed to deserialize code chunks: {}", e))?;
             Ok(code_chunks)
         }
+        // A range beyond the end of the file, the error carries its current line count.
+        StatusCode::BAD_REQUEST => match response.json::<SpanRangeError>().await {
+            Ok(error) => {
+                log::error!("Client error: {}", error);
+                Err(error.into())
+            }
+            Err(_) => Err(anyhow!("Client error: {}", StatusCode::BAD_REQUEST)),
+        },
         // Handle client errors (400-499).
-        StatusCode::BAD_REQUEST
-        | StatusCode::UNAUTHORIZED
+        StatusCode::UNAUTHORIZED
         | StatusCode::FORBIDDEN
         | StatusCode::NOT_FOUND
         | StatusCode::METHOD_NOT_ALLOWED => {
@@ -96,69 +116,403 @@ pub async fn service_caller<A: Serialize, B: DeserializeOwned>(
     body: Option<A>,
     query_params: Option<HashMap<String, String>>,
 ) -> Result<B, Error> {
+    service_caller_with_transport(url, method, body, query_params, Transport::Json).await
+}
+
+// Same as `service_caller`, but negotiates the body format with the peer.
+// The request body is encoded and the response is requested in `transport`,
+// the response is decoded based on the Content-Type the peer actually sent back,
+// so peers that only speak JSON keep working. If the peer rejects the format
+// with a 415 or 406, the call is retried once with JSON.
+pub async fn service_caller_with_transport<A: Serialize, B: DeserializeOwned>(
+    url: String,
+    method: HttpMethod,
+    body: Option<A>,
+    query_params: Option<HashMap<String, String>>,
+    transport: Transport,
+) -> Result<B, Error> {
+    let response = send_with_transport(url, method, body, query_params, transport).await?;
+    let response_transport = response_transport(&response);
+    let bytes = response.bytes().await?;
+    log::debug!(
+        "Received successful {} response of {} bytes",
+        response_transport,
+        bytes.len()
+    );
+
+    response_transport.decode::<B>(&bytes)
+}
+
+/// The values of a reply streamed in the frames of `Transport::encode_frame`, read as they arrive.
+pub struct FrameStream {
+    response: reqwest::Response,
+    transport: Transport,
+    buffer: Vec<u8>,
+}
+
+impl FrameStream {
+    /// The next value of the reply, None once the reply ended after a complete frame.
+    pub async fn next<B: DeserializeOwned>(&mut self) -> Result<Option<B>, Error> {
+        loop {
+            if let Some(frame) = take_frame(&mut self.buffer) {
+                return self.transport.decode::<B>(&frame).map(Some);
+            }
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None if self.buffer.is_empty() => return Ok(None),
+                None => {
+                    return Err(anyhow!(
+                        "The streamed reply ended in the middle of a frame, {} bytes left",
+                        self.buffer.len()
+                    ))
+                }
+            }
+        }
+    }
+}
+
+// Same as `service_caller_with_transport`, for the replies streamed in frames. The status of the
+// reply is handled before its first frame is read.
+pub async fn service_stream_with_transport<A: Serialize>(
+    url: String,
+    method: HttpMethod,
+    body: Option<A>,
+    query_params: Option<HashMap<String, String>>,
+    transport: Transport,
+) -> Result<FrameStream, Error> {
+    let response = send_with_transport(url, method, body, query_params, transport).await?;
+    Ok(FrameStream {
+        transport: response_transport(&response),
+        response,
+        buffer: Vec::new(),
+    })
+}
+
+// The format of a response, from the Content-Type the peer sent back.
+fn response_transport(response: &reqwest::Response) -> Transport {
+    Transport::from_header(
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok()),
+    )
+}
+
+// Sends the request in `transport`, falling back to JSON on a 415 or 406, and returns the 200
+// response. The other statuses are turned into errors.
+async fn send_with_transport<A: Serialize>(
+    url: String,
+    method: HttpMethod,
+    body: Option<A>,
+    query_params: Option<HashMap<String, String>>,
+    transport: Transport,
+) -> Result<reqwest::Response, Error> {
     // Parse the URL to ensure it's valid
     let url = Url::parse(&url).map_err(|e| anyhow!("Invalid URL: {}", e))?;
 
     // Create a new HTTP client instance
     let client = Client::new();
 
-    log::debug!(
-        "Calling service at {} with method {:?}",
-        url,
-        method.to_reqwest_method()
-    );
+    // If there is a body, serialize it once per transport that might be used.
+    let body_value = match body {
+        Some(body_value) if method.to_reqwest_method() != Method::GET => Some(body_value),
+        Some(_) => {
+            log::warn!("Body provided for a GET request will be ignored.");
+            None
+        }
+        None => None,
+    };
 
-    // Prepare the request with the specified method
-    let mut request_builder = client.request(method.to_reqwest_method(), url);
+    let mut transport = transport;
+    loop {
+        log::debug!(
+            "Calling service at {} with method {:?} over {}",
+            url,
+            method.to_reqwest_method(),
+            transport
+        );
 
-    // Add query parameters if present
-    if let Some(params) = query_params {
-        request_builder = request_builder.query(&params);
-    }
+        // Prepare the request with the specified method
+        let mut request_builder = telemetry::propagate(
+            client
+                .request(method.to_reqwest_method(), url.clone())
+                .header(ACCEPT, transport.content_type()),
+        );
 
-    // If there is a body, serialize it to JSON and attach it to the request.
-    if let Some(body_value) = body {
-        if method.to_reqwest_method() != Method::GET {
-            let json_body = json!(body_value);
-            request_builder = request_builder.json(&json_body);
-        } else {
-            log::warn!("Body provided for a GET request will be ignored.");
+        if let Some(key) = auth::service_api_key() {
+            request_builder = request_builder.bearer_auth(key);
+        }
+
+        // Add query parameters if present
+        if let Some(params) = &query_params {
+            request_builder = request_builder.query(params);
+        }
+
+        if let Some(body_value) = &body_value {
+            request_builder = request_builder
+                .header(CONTENT_TYPE, transport.content_type())
+                .body(transport.encode(body_value)?);
+        }
+
+        let response = local_services::send(request_builder).await?;
+
+        // General response handling
+        match response.status() {
+            StatusCode::OK => return Ok(response),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE | StatusCode::NOT_ACCEPTABLE
+                if transport != Transport::Json =>
+            {
+                log::warn!(
+                    "Service at {} does not support {}, falling back to json",
+                    url,
+                    transport
+                );
+                transport = Transport::Json;
+            }
+            // a call went beyond the budget of the request, the body carries the limit that was hit.
+            StatusCode::PAYMENT_REQUIRED => {
+                let response_transport = response_transport(&response);
+                let bytes = response.bytes().await?;
+                return Err(match response_transport.decode::<BudgetExceeded>(&bytes) {
+                    Ok(exceeded) => {
+                        log::warn!("{}", exceeded);
+                        exceeded.into()
+                    }
+                    Err(_) => anyhow!(
+                        "Error: Response status: {}, Error Message: {}",
+                        StatusCode::PAYMENT_REQUIRED,
+                        String::from_utf8_lossy(&bytes)
+                    ),
+                });
+            }
+            // a collection of the index generation the conversation is pinned to was dropped.
+            StatusCode::GONE => {
+                let response_transport = response_transport(&response);
+                let bytes = response.bytes().await?;
+                return Err(match response_transport.decode::<IndexGenerationGone>(&bytes) {
+                    Ok(gone) => {
+                        log::warn!("{}", gone);
+                        gone.into()
+                    }
+                    Err(_) => anyhow!(
+                        "Error: Response status: {}, Error Message: {}",
+                        StatusCode::GONE,
+                        String::from_utf8_lossy(&bytes)
+                    ),
+                });
+            }
+            StatusCode::BAD_REQUEST
+            | StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => {
+                let error_message = format!(
+                    "Error: Response status: {}, Error Message: {}",
+                    response.status(),
+                    response.text().await?
+                );
+                log::error!("{}", error_message);
+                return Err(anyhow!(error_message));
+            }
+            _ => {
+                let error_message = format!("Unexpected HTTP response: {}", response.status());
+                log::error!("{}", error_message);
+                return Err(anyhow!(error_message));
+            }
         }
     }
+}
+
+// Fetches `GET <base_url>/version`, None when the service predates the handshake and answers 404.
+pub async fn fetch_service_version(base_url: &str) -> Result<Option<ServiceVersion>, Error> {
+    let url = Url::parse(&format!("{}/{}", base_url.trim_end_matches('/'), VERSION_PATH))
+        .map_err(|e| anyhow!("Invalid URL: {}", e))?;
 
-    let response = request_builder.send().await?;
+    let mut request_builder = telemetry::propagate(Client::new().get(url.clone()));
+    if let Some(key) = auth::service_api_key() {
+        request_builder = request_builder.bearer_auth(key);
+    }
+    let response = local_services::send(request_builder).await?;
 
-    // General response handling
     match response.status() {
-        StatusCode::OK => {
-            let res: Value = response.json::<Value>().await?;
-            log::debug!("Received successful response: {:?}", res);
+        StatusCode::OK => Ok(Some(response.json::<ServiceVersion>().await?)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(anyhow!("Unexpected response to the version handshake with {}: {}", url, status)),
+    }
+}
 
-            let res_obj = serde_json::from_value::<B>(res.clone())
-                .map_err(|e| anyhow!("Failed to deserialize: {}", e))?;
-            Ok(res_obj)
-        }
-        StatusCode::BAD_REQUEST
-        | StatusCode::UNAUTHORIZED
-        | StatusCode::FORBIDDEN
-        | StatusCode::NOT_FOUND
-        | StatusCode::METHOD_NOT_ALLOWED
-        | StatusCode::INTERNAL_SERVER_ERROR
-        | StatusCode::BAD_GATEWAY
-        | StatusCode::SERVICE_UNAVAILABLE
-        | StatusCode::GATEWAY_TIMEOUT => {
-            let error_message = format!(
-                "Error: Response status: {}, Error Message: {}",
-                response.status(),
-                response.text().await?
+// Makes the version handshake with a service and caches the result for `capabilities`.
+// Services without the version endpoint are cached as legacy builds without optional features.
+pub async fn handshake(target: Service, base_url: &str) -> Result<ServiceVersion, Error> {
+    let version = fetch_service_version(base_url)
+        .await?
+        .unwrap_or_else(|| ServiceVersion::legacy(target));
+    set_service_version(target, version.clone());
+    Ok(version)
+}
+
+pub fn set_service_version(target: Service, version: ServiceVersion) {
+    SERVICE_VERSIONS
+        .write()
+        .expect("Failed to acquire write lock")
+        .insert(target, version);
+}
+
+// Features of a service as advertised in its handshake, `Unknown` when no handshake was made.
+pub fn capabilities(target: Service) -> Capabilities {
+    match SERVICE_VERSIONS
+        .read()
+        .expect("Failed to acquire read lock")
+        .get(&target)
+    {
+        Some(version) => Capabilities::Advertised(version.clone()),
+        None => Capabilities::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::{version_route, Capability};
+    use crate::local_services::{local_url, register, WarpService};
+    use std::sync::Arc;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        register(
+            "versioned-service",
+            Arc::new(WarpService::new(version_route(
+                "code-understanding",
+                "1.0.0",
+                &[Capability::PinnedPaths],
+            ))),
+        );
+        let version = handshake(Service::CodeUnderstanding, &local_url("versioned-service"))
+            .await
+            .unwrap();
+        assert_eq!(version.version, "1.0.0");
+        assert!(capabilities(Service::CodeUnderstanding).supports(Capability::PinnedPaths));
+        assert!(!capabilities(Service::CodeUnderstanding).supports(Capability::Msgpack));
+    }
+
+    #[tokio::test]
+    async fn test_payment_required_is_returned_as_budget_exceeded() {
+        let exceeded = BudgetExceeded {
+            scope: crate::budget::BudgetScope::Tenant,
+            limit_usd: 5.0,
+            spent_usd: 4.98,
+            projected_usd: 5.04,
+        };
+        let body = exceeded.clone();
+        let route = warp::path("answer").map(move || {
+            warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::PAYMENT_REQUIRED)
+        });
+        register("budget-service", Arc::new(WarpService::new(route)));
+
+        let err = service_caller::<(), serde_json::Value>(
+            format!("{}/answer", local_url("budget-service")),
+            HttpMethod::GET,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<BudgetExceeded>(), Some(&exceeded));
+    }
+
+    #[tokio::test]
+    async fn test_gone_is_returned_as_index_generation_gone() {
+        let gone = IndexGenerationGone {
+            collection: "documents_v1".to_string(),
+        };
+        let body = gone.clone();
+        let route = warp::path("search").map(move || {
+            warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::GONE)
+        });
+        register("gone-service", Arc::new(WarpService::new(route)));
+
+        let err = service_caller::<(), serde_json::Value>(
+            format!("{}/search", local_url("gone-service")),
+            HttpMethod::GET,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<IndexGenerationGone>(), Some(&gone));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_frames_are_read_in_order() {
+        let route = warp::path("stream")
+            .and(warp::header::<String>("accept"))
+            .map(|accept: String| {
+                let transport = Transport::from_header(Some(&accept));
+                let mut body = transport.encode_frame(&"first").unwrap();
+                body.extend(transport.encode_frame(&"second").unwrap());
+                warp::http::Response::builder()
+                    .header("content-type", transport.content_type())
+                    .body(body)
+                    .unwrap()
+            });
+        register("stream-service", Arc::new(WarpService::new(route)));
+
+        for transport in [Transport::Json, Transport::Msgpack] {
+            let mut stream = service_stream_with_transport::<()>(
+                format!("{}/stream", local_url("stream-service")),
+                HttpMethod::GET,
+                None,
+                None,
+                transport,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                stream.next::<String>().await.unwrap(),
+                Some("first".to_string())
             );
-            log::error!("{}", error_message);
-            Err(anyhow!(error_message))
-        }
-        _ => {
-            let error_message = format!("Unexpected HTTP response: {}", response.status());
-            log::error!("{}", error_message);
-            Err(anyhow!(error_message))
+            assert_eq!(
+                stream.next::<String>().await.unwrap(),
+                Some("second".to_string())
+            );
+            assert_eq!(stream.next::<String>().await.unwrap(), None);
         }
     }
+
+    #[tokio::test]
+    async fn test_truncated_stream_is_an_error() {
+        let route = warp::path("truncated").map(|| {
+            let frame = Transport::Json.encode_frame(&"answer").unwrap();
+            frame[..frame.len() - 2].to_vec()
+        });
+        register("truncated-service", Arc::new(WarpService::new(route)));
+
+        let mut stream = service_stream_with_transport::<()>(
+            format!("{}/truncated", local_url("truncated-service")),
+            HttpMethod::GET,
+            None,
+            None,
+            Transport::Json,
+        )
+        .await
+        .unwrap();
+        assert!(stream.next::<String>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_a_service_without_the_version_endpoint() {
+        let home = warp::path::end().map(|| "Hello from code search");
+        register("legacy-service", Arc::new(WarpService::new(home)));
+
+        let version = handshake(Service::CodeSearch, &local_url("legacy-service"))
+            .await
+            .unwrap();
+        assert_eq!(version, ServiceVersion::legacy(Service::CodeSearch));
+        assert!(!capabilities(Service::CodeSearch).supports(Capability::CodeOwners));
+    }
 }
@@ -0,0 +1,183 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// Shutdown state shared between a server and the long running work it spawns.
+///
+/// Servers stop accepting connections once it's triggered and drain in-flight requests,
+/// loops like the agent steps check it at step boundaries to wrap up early.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+static SHUTDOWN: Lazy<Shutdown> = Lazy::new(Shutdown::new);
+
+/// The process wide shutdown handle, triggered by SIGINT/SIGTERM once `listen_for_signals` is called.
+pub fn global() -> &'static Shutdown {
+    &SHUTDOWN
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.is_triggered()
+}
+
+// How long in-flight requests get to finish after a shutdown signal.
+// Read from `SHUTDOWN_DRAIN_TIMEOUT_SECS`, so it has to be called after the env file is loaded.
+pub fn drain_timeout() -> Duration {
+    let secs = std::env::var("SHUTDOWN_DRAIN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once shutdown is triggered, usable as the signal of `bind_with_graceful_shutdown`.
+    pub async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Triggers the shutdown on the first SIGINT or SIGTERM.
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!("Shutdown signal received, draining in-flight requests");
+            shutdown.trigger();
+        });
+    }
+
+    /// Runs the server future until it finishes on its own, or until shutdown is triggered
+    /// and it either drains or runs out of `drain_timeout`.
+    /// Returns false if in-flight work was still running when the timeout hit.
+    pub async fn drain<F: Future<Output = ()>>(&self, server: F, drain_timeout: Duration) -> bool {
+        tokio::pin!(server);
+        tokio::select! {
+            _ = &mut server => return true,
+            _ = self.wait() => {}
+        }
+
+        match tokio::time::timeout(drain_timeout, server).await {
+            Ok(()) => {
+                info!("All in-flight requests finished");
+                true
+            }
+            Err(_) => {
+                warn!(
+                    "Drain timeout of {:?} reached, exiting with requests still in flight",
+                    drain_timeout
+                );
+                false
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    // Starts a server with a slow route, fires a request, shuts down while it's in flight,
+    // and checks the request still completes before the server exits.
+    #[tokio::test]
+    async fn test_in_flight_request_completes_before_exit() {
+        let shutdown = Shutdown::new();
+        let slow = warp::path("slow").and_then(|| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Ok::<_, std::convert::Infallible>("done")
+        });
+
+        let signal = {
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait().await }
+        };
+        let (addr, server) =
+            warp::serve(slow).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), signal);
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { shutdown.drain(server, Duration::from_secs(5)).await })
+        };
+
+        let request = tokio::spawn(async move {
+            reqwest::get(format!("http://{}/slow", addr))
+                .await?
+                .text()
+                .await
+        });
+        // let the request reach the handler before shutting down.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.trigger();
+
+        assert_eq!(request.await.unwrap().unwrap(), "done");
+        assert!(server.await.unwrap());
+        // new connections are refused once drained.
+        assert!(reqwest::get(format!("http://{}/slow", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_gives_up_on_stuck_work() {
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let drained = shutdown
+            .drain(std::future::pending::<()>(), Duration::from_millis(50))
+            .await;
+        assert!(!drained);
+    }
+}
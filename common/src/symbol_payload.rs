@@ -0,0 +1,264 @@
+// Occurrences of a symbol in its qdrant point. The payload stores them as parallel columns, one
+// array per field (`relative_path`, `start_byte`, ...) with the fields of an occurrence at the
+// same index, which is the format the payload indexes and the stored points have. They are read
+// into typed occurrences, columns of different lengths are a parse error rather than occurrences
+// mixing the fields of others. Points are still written flat, points whose payload has an
+// `occurrences` array of objects are read as well.
+
+use serde::{Deserialize, Serialize};
+
+/// Separator of the definitions in the container path written by the ingestion.
+pub const CONTAINER_SEPARATOR: &str = "::";
+
+/// One location of a symbol in the repo.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolOccurrence {
+    pub path: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub is_global: bool,
+    pub node_kind: String,
+    pub lang: String,
+    pub symbol_type: String,
+    /// Enclosing definitions, outermost first, e.g. `["PaymentService"]` for one of its methods.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub container_path: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum OccurrenceError {
+    #[error("the {column} column has {found} values for {expected} paths")]
+    LengthMismatch {
+        column: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    #[error("the {column} column has the negative offset {offset}")]
+    NegativeOffset { column: &'static str, offset: i64 },
+}
+
+/// The occurrences as the parallel columns of the qdrant payload, keyed by the payload fields.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlatOccurrences {
+    #[serde(default)]
+    pub symbol_type: Vec<String>,
+    #[serde(default)]
+    pub lang: Vec<String>,
+    #[serde(default)]
+    pub is_global: Vec<bool>,
+    #[serde(default)]
+    pub start_byte: Vec<i64>,
+    #[serde(default)]
+    pub end_byte: Vec<i64>,
+    #[serde(default)]
+    pub relative_path: Vec<String>,
+    #[serde(default)]
+    pub node_kind: Vec<String>,
+    // definitions joined with `CONTAINER_SEPARATOR`, missing on symbols indexed before the
+    // container paths were recorded.
+    #[serde(default)]
+    pub container_path: Vec<String>,
+}
+
+impl From<&[SymbolOccurrence]> for FlatOccurrences {
+    fn from(occurrences: &[SymbolOccurrence]) -> Self {
+        let mut flat = FlatOccurrences::default();
+        for occurrence in occurrences {
+            flat.symbol_type.push(occurrence.symbol_type.clone());
+            flat.lang.push(occurrence.lang.clone());
+            flat.is_global.push(occurrence.is_global);
+            flat.start_byte.push(occurrence.start_byte as i64);
+            flat.end_byte.push(occurrence.end_byte as i64);
+            flat.relative_path.push(occurrence.path.clone());
+            flat.node_kind.push(occurrence.node_kind.clone());
+            flat.container_path
+                .push(occurrence.container_path.join(CONTAINER_SEPARATOR));
+        }
+        flat
+    }
+}
+
+impl FlatOccurrences {
+    /// The occurrences of the columns, an error unless every column has a value per path.
+    pub fn into_occurrences(self) -> Result<Vec<SymbolOccurrence>, OccurrenceError> {
+        let expected = self.relative_path.len();
+        let check = |column: &'static str, found: usize| {
+            if found == expected {
+                Ok(())
+            } else {
+                Err(OccurrenceError::LengthMismatch {
+                    column,
+                    expected,
+                    found,
+                })
+            }
+        };
+        check("symbol_type", self.symbol_type.len())?;
+        check("lang", self.lang.len())?;
+        check("is_global", self.is_global.len())?;
+        check("start_byte", self.start_byte.len())?;
+        check("end_byte", self.end_byte.len())?;
+        check("node_kind", self.node_kind.len())?;
+        if !self.container_path.is_empty() {
+            check("container_path", self.container_path.len())?;
+        }
+
+        let offset = |column: &'static str, offset: i64| {
+            usize::try_from(offset).map_err(|_| OccurrenceError::NegativeOffset { column, offset })
+        };
+        let mut container_paths = self.container_path.into_iter();
+        (0..expected)
+            .zip(self.relative_path)
+            .map(|(i, path)| {
+                Ok(SymbolOccurrence {
+                    path,
+                    start_byte: offset("start_byte", self.start_byte[i])?,
+                    end_byte: offset("end_byte", self.end_byte[i])?,
+                    is_global: self.is_global[i],
+                    node_kind: self.node_kind[i].clone(),
+                    lang: self.lang[i].clone(),
+                    symbol_type: self.symbol_type[i].clone(),
+                    container_path: container_paths
+                        .next()
+                        .map(|path| {
+                            path.split(CONTAINER_SEPARATOR)
+                                .filter(|name| !name.is_empty())
+                                .map(str::to_owned)
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Serde of the occurrences of a symbol payload, for a field flattened into the payload:
+///
+/// ```ignore
+/// #[serde(flatten, with = "common::symbol_payload::flat")]
+/// pub occurrences: Vec<SymbolOccurrence>,
+/// ```
+///
+/// They are written as the flat columns and read from them or from an `occurrences` array.
+pub mod flat {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{FlatOccurrences, SymbolOccurrence};
+
+    #[derive(Deserialize)]
+    struct Shapes {
+        #[serde(default)]
+        occurrences: Option<Vec<SymbolOccurrence>>,
+        #[serde(flatten)]
+        flat: FlatOccurrences,
+    }
+
+    pub fn serialize<S: Serializer>(
+        occurrences: &[SymbolOccurrence],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        FlatOccurrences::from(occurrences).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<SymbolOccurrence>, D::Error> {
+        let shapes = Shapes::deserialize(deserializer)?;
+        match shapes.occurrences {
+            Some(occurrences) => Ok(occurrences),
+            None => shapes.flat.into_occurrences().map_err(de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        symbol: String,
+        #[serde(flatten, with = "flat")]
+        occurrences: Vec<SymbolOccurrence>,
+    }
+
+    fn flat_payload() -> serde_json::Value {
+        json!({
+            "symbol": "retry",
+            "symbol_type": ["function", "function"],
+            "lang": ["python", "python"],
+            "is_global": [false, true],
+            "start_byte": [20, 120],
+            "end_byte": [80, 200],
+            "relative_path": ["payments/service.py", "payments/jobs.py"],
+            "node_kind": ["def", "def"],
+            "container_path": ["PaymentService::Retries", ""],
+            "symbol_lower": "retry",
+        })
+    }
+
+    #[test]
+    fn test_flat_and_nested_payloads_round_trip() {
+        let payload: Payload = serde_json::from_value(flat_payload()).unwrap();
+        assert_eq!(payload.occurrences.len(), 2);
+        assert_eq!(
+            payload.occurrences[0],
+            SymbolOccurrence {
+                path: "payments/service.py".to_string(),
+                start_byte: 20,
+                end_byte: 80,
+                is_global: false,
+                node_kind: "def".to_string(),
+                lang: "python".to_string(),
+                symbol_type: "function".to_string(),
+                container_path: vec!["PaymentService".to_string(), "Retries".to_string()],
+            }
+        );
+        assert!(payload.occurrences[1].container_path.is_empty());
+
+        // written back flat, the way the payload indexes expect it.
+        let mut written = serde_json::to_value(&payload).unwrap();
+        let mut expected = flat_payload();
+        expected.as_object_mut().unwrap().remove("symbol_lower");
+        assert_eq!(written, expected);
+
+        // the nested shape reads into the same occurrences.
+        let object = written.as_object_mut().unwrap();
+        let flat_columns = serde_json::to_value(FlatOccurrences::default()).unwrap();
+        for column in flat_columns.as_object().unwrap().keys() {
+            object.remove(column);
+        }
+        object.insert(
+            "occurrences".to_string(),
+            serde_json::to_value(&payload.occurrences).unwrap(),
+        );
+        let nested: Payload = serde_json::from_value(written).unwrap();
+        assert_eq!(nested, payload);
+
+        // symbols indexed before the container paths were recorded.
+        let mut old = flat_payload();
+        old.as_object_mut().unwrap().remove("container_path");
+        let old: Payload = serde_json::from_value(old).unwrap();
+        assert!(old.occurrences.iter().all(|o| o.container_path.is_empty()));
+    }
+
+    #[test]
+    fn test_columns_of_different_lengths_are_a_parse_error() {
+        let mut payload = flat_payload();
+        payload["start_byte"] = json!([20]);
+        let err = serde_json::from_value::<Payload>(payload).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("the start_byte column has 1 values for 2 paths"));
+
+        let mut payload = flat_payload();
+        payload["container_path"] = json!(["PaymentService"]);
+        assert!(serde_json::from_value::<Payload>(payload).is_err());
+
+        let mut payload = flat_payload();
+        payload["end_byte"] = json!([80, -1]);
+        assert!(serde_json::from_value::<Payload>(payload).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use uuid::Uuid;
 
 use ai_gateway::message::message::Message; 
 use ai_gateway::message::message::MessageRole; 
+use crate::grounding::TaskGrounding;
 use crate::task_graph::graph_model::TrackProcessV1;
 use crate::task_graph::graph_model::{EdgeV1, NodeV1};
 use anyhow::Result;
@@ -159,4 +160,16 @@ impl TrackProcessV1 {
         graph.add_edge(subtask_node, question_node, EdgeV1::Question);
         Ok(question_node)
     }
+
+    // The grounding isn't part of the task hierarchy, the traversals of the tasks skip it.
+    pub fn add_task_grounding(
+        &mut self,
+        task_node: NodeIndex,
+        grounding: TaskGrounding,
+    ) -> Result<NodeIndex, NodeError> {
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        let grounding_node = graph.add_node(NodeV1::Grounding(grounding));
+        graph.add_edge(task_node, grounding_node, EdgeV1::Grounding);
+        Ok(grounding_node)
+    }
 }
@@ -1,4 +1,5 @@
 use std::ops::Range;
+use std::time::SystemTime;
 
 use crate::task_graph::add_node::NodeError;
 use crate::task_graph::graph_model::{EdgeV1, NodeV1, TrackProcessV1};
@@ -8,7 +9,8 @@ use petgraph::graph::{DiGraph, EdgeIndex, NodeIndex};
 use petgraph::visit::EdgeRef;
 use petgraph::Direction;
 
-use crate::models::{TaskDetailsWithContext, TasksQuestionsAnswersDetails};
+use crate::models::{SubtaskDetails, TaskDetailsWithContext, TasksQuestionsAnswersDetails};
+use crate::prompt_versions::PromptVersion;
 use crate::CodeContext;
 use anyhow::{Result, anyhow};
 
@@ -51,7 +53,8 @@ impl TrackProcessV1 {
         })
     }
 
-    /// Collects details for a specific task including its questions, answers, and code contexts.
+    /// Collects details for a specific task including its questions, answers, and code contexts,
+    /// both for the whole task and grouped by subtask.
     fn collect_task_details(
         &self,
         graph: &DiGraph<NodeV1, EdgeV1>,
@@ -61,25 +64,24 @@ impl TrackProcessV1 {
         let mut questions = Vec::new();
         let mut answers = Vec::new();
         let mut code_contexts = Vec::new();
+        let mut unresolved_questions = Vec::new();
+        let mut subtasks = Vec::new();
 
         // For the given task node, iterate over its connected subtask nodes.
         for edge in graph.edges_directed(node_index, Direction::Outgoing) {
-            if let NodeV1::Subtask(_) = &graph[edge.target()] {
-                // For each subtask node, collect its connected questions.
-                for subtask_edge in graph.edges_directed(edge.target(), Direction::Outgoing) {
-                    if let NodeV1::Question(question) = &graph[subtask_edge.target()] {
-                        questions.push(question.clone());
-                        // Collect answers and code contexts for each question.
-                        self.collect_answers_and_contexts(
-                            graph,
-                            subtask_edge.target(),
-                            &mut answers,
-                            &mut code_contexts,
-                        )?;
-                    }
-                }
+            if let NodeV1::Subtask(subtask_description) = &graph[edge.target()] {
+                let subtask =
+                    self.collect_subtask_details(graph, edge.target(), subtask_description)?;
+                questions.extend(subtask.questions.iter().cloned());
+                answers.extend(subtask.answers.iter().cloned());
+                unresolved_questions.extend(subtask.unresolved_questions.iter().cloned());
+                code_contexts.extend(subtask.code_contexts);
+                subtasks.push(subtask.details);
             }
         }
+        // the edges are walked from the last one added, the subtasks are kept in the order they
+        // were generated in.
+        subtasks.sort_by_key(|subtask| subtask.subtask_id);
 
         // Merge overlapping code contexts to ensure there are no redundant entries.
         let merged_code_contexts = merge_code_contexts(&code_contexts);
@@ -92,6 +94,58 @@ impl TrackProcessV1 {
             answers,
             code_contexts,
             merged_code_contexts,
+            unresolved_questions,
+            subtasks,
+        })
+    }
+
+    /// Collects the questions of a subtask with their answers and code contexts, and the synthesis
+    /// of its answers when it was written.
+    fn collect_subtask_details(
+        &self,
+        graph: &DiGraph<NodeV1, EdgeV1>,
+        subtask_node: NodeIndex,
+        subtask_description: &String,
+    ) -> Result<CollectedSubtask, NodeError> {
+        let mut questions = Vec::new();
+        let mut answers = Vec::new();
+        let mut code_contexts = Vec::new();
+        let mut unresolved_questions = Vec::new();
+
+        for subtask_edge in graph.edges_directed(subtask_node, Direction::Outgoing) {
+            if let NodeV1::Question(question) = &graph[subtask_edge.target()] {
+                // Questions the agent couldn't answer are kept apart, so the answers stay aligned with the questions.
+                if has_not_found_answer(graph, subtask_edge.target()) {
+                    unresolved_questions.push(question.clone());
+                    continue;
+                }
+                questions.push(question.clone());
+                // Collect answers and code contexts for each question.
+                self.collect_answers_and_contexts(
+                    graph,
+                    subtask_edge.target(),
+                    &mut answers,
+                    &mut code_contexts,
+                )?;
+            }
+        }
+
+        let synthesis =
+            subtask_summary_node(graph, subtask_node).and_then(|node| match &graph[node] {
+                NodeV1::SubtaskSummary(synthesis) => Some(synthesis.clone()),
+                _ => None,
+            });
+        Ok(CollectedSubtask {
+            details: SubtaskDetails {
+                subtask_id: subtask_node.index(),
+                subtask_description: subtask_description.clone(),
+                questions,
+                answers,
+                merged_code_contexts: merge_code_contexts(&code_contexts),
+                unresolved_questions,
+                synthesis,
+            },
+            code_contexts,
         })
     }
 
@@ -104,7 +158,10 @@ impl TrackProcessV1 {
         code_contexts: &mut Vec<CodeContext>,
     ) -> Result<(), NodeError> {
         // Iterate over edges from the question node to find connected answer nodes.
-        for answer_edge in graph.edges_directed(question_node, Direction::Outgoing) {
+        for answer_edge in graph
+            .edges_directed(question_node, Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::Answer))
+        {
             if let NodeV1::Answer(answer) = &graph[answer_edge.target()] {
                 answers.push(answer.clone());
                 // For each answer node, collect connected code context nodes.
@@ -133,14 +190,60 @@ impl TrackProcessV1 {
         Err(anyhow!(NodeError::NoSummaryFound))
     }
 
+    /// Attaches the synthesis of the answers of a subtask to it, along with the `prompt_versions`
+    /// it was written with. A synthesis written before is replaced.
+    pub fn record_subtask_summary(
+        &mut self,
+        subtask_id: usize,
+        synthesis: &str,
+        prompt_versions: &[PromptVersion],
+    ) -> Result<(), NodeError> {
+        let subtask = NodeIndex::new(subtask_id);
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        if !matches!(graph.node_weight(subtask), Some(NodeV1::Subtask(_))) {
+            return Err(NodeError::NodeNotFound(format!(
+                "Node {} is not a subtask.",
+                subtask_id
+            )));
+        }
+
+        let summary = match subtask_summary_node(graph, subtask) {
+            Some(existing) => {
+                graph[existing] = NodeV1::SubtaskSummary(synthesis.to_string());
+                existing
+            }
+            None => {
+                let node = graph.add_node(NodeV1::SubtaskSummary(synthesis.to_string()));
+                graph.add_edge(subtask, node, EdgeV1::SubtaskSummary);
+                node
+            }
+        };
+        let versions = graph
+            .edges_directed(summary, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::PromptVersions))
+            .map(|edge| edge.target());
+        match versions {
+            Some(versions) => graph[versions] = NodeV1::PromptVersions(prompt_versions.to_vec()),
+            None if !prompt_versions.is_empty() => {
+                let node = graph.add_node(NodeV1::PromptVersions(prompt_versions.to_vec()));
+                graph.add_edge(summary, node, EdgeV1::PromptVersions);
+            }
+            None => {}
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
     /// Connects the first task in the provided `TasksQuestionsAnswersDetails` to a new `AnswerSummary` node,
     /// ensuring there's only one summary node per task by removing any existing summary nodes.
     /// The summary node is then connected to the parent conversation node of the tasks in the graph.
-    /// so tasks and plan are siblings in the graph. This method also attempts to save the updated graph to Redis.
+    /// so tasks and plan are siblings in the graph. The `prompt_versions` the summary was generated with are
+    /// attached to it. This method also attempts to save the updated graph to Redis.
     pub fn connect_task_to_answer_summary(
         &mut self,
         task_details: &TasksQuestionsAnswersDetails,
         summary: &str,
+        prompt_versions: &[PromptVersion],
     ) -> Result<(), NodeError> {
         // Check if the graph is initialized.
         let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
@@ -179,6 +282,10 @@ impl TrackProcessV1 {
                 answer_summary_node,
                 EdgeV1::SummarizedAnswer,
             );
+            if !prompt_versions.is_empty() {
+                let versions_node = graph.add_node(NodeV1::PromptVersions(prompt_versions.to_vec()));
+                graph.add_edge(answer_summary_node, versions_node, EdgeV1::PromptVersions);
+            }
 
             // Attempt to save the updated graph to Redis.
             self.save_task_process_to_redis(&get_redis_url())
@@ -192,6 +299,32 @@ impl TrackProcessV1 {
     }
 }
 
+// The details of a subtask, with its code contexts before they were merged for the task.
+struct CollectedSubtask {
+    details: SubtaskDetails,
+    code_contexts: Vec<CodeContext>,
+}
+
+fn subtask_summary_node(
+    graph: &DiGraph<NodeV1, EdgeV1>,
+    subtask_node: NodeIndex,
+) -> Option<NodeIndex> {
+    graph
+        .edges_directed(subtask_node, Direction::Outgoing)
+        .find(|edge| matches!(edge.weight(), EdgeV1::SubtaskSummary))
+        .map(|edge| edge.target())
+}
+
+// Checks if the question was resolved by the agent without finding an answer.
+fn has_not_found_answer(graph: &DiGraph<NodeV1, EdgeV1>, question_node: NodeIndex) -> bool {
+    graph
+        .edges_directed(question_node, Direction::Outgoing)
+        .any(|edge| {
+            matches!(edge.weight(), EdgeV1::Answer)
+                && matches!(graph[edge.target()], NodeV1::AnswerNotFound(..))
+        })
+}
+
 pub fn merge_code_contexts(contexts: &Vec<CodeContext>) -> Vec<CodeContext> {
     let mut merged_contexts: Vec<CodeContext> = Vec::new();
 
@@ -0,0 +1,1096 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::str::FromStr;
+
+use ai_gateway::message::message::Message;
+use petgraph::visit::{Dfs, EdgeRef};
+use serde::{Deserialize, Serialize};
+
+use crate::answer_scope::ScopeViolation;
+use crate::budget::ConversationBudget;
+use crate::citations::CitationReport;
+use crate::feedback::FeedbackSummary;
+use crate::freshness::FreshnessNote;
+use crate::glossary::GlossaryEntry;
+use crate::prompt_versions::PromptVersion;
+use crate::timings::PhaseTimings;
+use crate::task_graph::add_node::NodeError;
+use crate::task_graph::graph_model::{EdgeV1, NodeV1, TrackProcessV1};
+use crate::verification::render_verification;
+
+// Node labels are cut to this many characters so that large graphs stay readable.
+// The full content is still available in the json export.
+pub const MAX_LABEL_LEN: usize = 60;
+
+const UNANSWERED_FILL: &str = "#ffe08a";
+const UNANSWERED_STROKE: &str = "#c9a227";
+const FOLLOW_UPS_LABEL: &str = "Follow-ups";
+const OWNERS_LABEL: &str = "Owners involved";
+const TIMINGS_LABEL: &str = "Timings";
+const GLOSSARY_LABEL: &str = "Glossary";
+const SUPPORTING_LABEL: &str = "Supporting Q&A";
+
+/// Output formats for exporting the task graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    #[default]
+    Json,
+}
+
+impl GraphFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            GraphFormat::Dot => "text/vnd.graphviz",
+            GraphFormat::Mermaid => "text/plain",
+            GraphFormat::Json => "application/json",
+        }
+    }
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            "json" => Ok(GraphFormat::Json),
+            other => Err(format!(
+                "Unknown graph format: {}, expected one of dot, mermaid or json",
+                other
+            )),
+        }
+    }
+}
+
+/// Node of the exported graph. `id` is derived from the node index, which is stable
+/// since nodes are never removed from the task graph.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ExportNode {
+    pub id: String,
+    pub kind: &'static str,
+    pub label: String,
+    pub content: String,
+    pub unanswered: bool,
+    // follow-up questions asked after the tasks were answered, along with their answers and code contexts.
+    pub follow_up: bool,
+    // id of the subtask whose synthesis this question, answer or code context supports, it is
+    // shown collapsed under the synthesis.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collapsed_under: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ExportEdge {
+    pub id: String,
+    pub source: String,
+    pub target: String,
+    pub kind: String,
+}
+
+/// Owners of the code cited under a task, from the CODEOWNERS file of the repo.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TaskOwners {
+    pub task: String,
+    pub owners: Vec<String>,
+}
+
+/// Where the time of answering the questions of a task went, summed over its questions.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TaskTimings {
+    pub task: String,
+    // questions of the task with timings.
+    pub questions: usize,
+    pub timings: PhaseTimings,
+}
+
+/// Paths out of the scope of the conversation the answer of a question recommended changing.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct QuestionScopeViolations {
+    pub question: String,
+    pub violations: Vec<ScopeViolation>,
+}
+
+/// The citations of an answer with their anchors, `drift` is only set once they are re-resolved
+/// against the current index.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AnswerCitations {
+    pub answer: String,
+    pub citations: CitationReport,
+}
+
+/// The prompt templates an answer or answer summary was generated with.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AnswerPromptVersions {
+    pub answer: String,
+    pub versions: Vec<PromptVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct GraphExport {
+    pub nodes: Vec<ExportNode>,
+    pub edges: Vec<ExportEdge>,
+    // only the tasks with owners, empty when the repo has no CODEOWNERS file.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub owners: Vec<TaskOwners>,
+    // language the conversation is answered in, missing for conversations created before it was recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // what the conversation spent, and the limit that stopped it if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<ConversationBudget>,
+    // only the tasks with answered questions, empty for conversations answered before the timings were recorded.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub timings: Vec<TaskTimings>,
+    // paths the conversation is scoped to, empty when it spans the whole repo.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scope: Vec<String>,
+    // only the questions whose answer recommended changes out of scope.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scope_violations: Vec<QuestionScopeViolations>,
+    // freshness of the index when the conversation started, missing for conversations created before it was recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freshness: Option<FreshnessNote>,
+    // warning that the index is stale, see `FreshnessNote::banner`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freshness_banner: Option<String>,
+    // ratings of the answers, missing before the first one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feedback: Option<FeedbackSummary>,
+    // only the answers with citations, empty for answers recorded before the citations were.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<AnswerCitations>,
+    // only the answers and summaries generated since the prompt versions were recorded.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub prompt_versions: Vec<AnswerPromptVersions>,
+    // the symbols the answers cited and how they described them, rendered as an appendix.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub glossary: Vec<GlossaryEntry>,
+}
+
+impl GraphExport {
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+            GraphFormat::Json => serde_json::to_string(self)
+                .expect("Graph export only holds strings and is always serializable"),
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph task_graph {\n");
+        out.push_str("    rankdir=TB;\n");
+        out.push_str("    node [shape=box, style=rounded];\n");
+        for node in &self.nodes {
+            let label = escape_dot(&node.label);
+            if node.unanswered {
+                let _ = writeln!(
+                    out,
+                    "    {} [label=\"{}\", style=\"rounded,filled\", fillcolor=\"{}\", color=\"{}\"];",
+                    node.id, label, UNANSWERED_FILL, UNANSWERED_STROKE
+                );
+            } else {
+                let _ = writeln!(out, "    {} [label=\"{}\"];", node.id, label);
+            }
+        }
+        for edge in &self.edges {
+            let _ = writeln!(
+                out,
+                "    {} -> {} [label=\"{}\"];",
+                edge.source, edge.target, edge.kind
+            );
+        }
+        for task in &self.owners {
+            let _ = writeln!(
+                out,
+                "    {}_owners [shape=note, label=\"{}: {}\"];",
+                task.task,
+                OWNERS_LABEL,
+                escape_dot(&task.owners.join(", "))
+            );
+            let _ = writeln!(
+                out,
+                "    {} -> {}_owners [style=dashed, arrowhead=none];",
+                task.task, task.task
+            );
+        }
+        for task in &self.timings {
+            let _ = writeln!(
+                out,
+                "    {}_timings [shape=note, label=\"{}: {}\"];",
+                task.task,
+                TIMINGS_LABEL,
+                escape_dot(&task.render())
+            );
+            let _ = writeln!(
+                out,
+                "    {} -> {}_timings [style=dashed, arrowhead=none];",
+                task.task, task.task
+            );
+        }
+        if !self.glossary.is_empty() {
+            out.push_str("    subgraph cluster_glossary {\n");
+            let _ = writeln!(out, "        label=\"{}\";", GLOSSARY_LABEL);
+            for (i, entry) in self.glossary.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "        glossary_{} [shape=note, label=\"{}\"];",
+                    i,
+                    escape_dot(&glossary_label(entry))
+                );
+            }
+            out.push_str("    }\n");
+        }
+        let follow_ups = self.follow_up_ids();
+        if !follow_ups.is_empty() {
+            out.push_str("    subgraph cluster_follow_ups {\n");
+            let _ = writeln!(out, "        label=\"{}\";", FOLLOW_UPS_LABEL);
+            for id in follow_ups {
+                let _ = writeln!(out, "        {};", id);
+            }
+            out.push_str("    }\n");
+        }
+        for (subtask, ids) in self.collapsed_groups() {
+            let _ = writeln!(out, "    subgraph cluster_supporting_{} {{", subtask);
+            let _ = writeln!(out, "        label=\"{}\";", SUPPORTING_LABEL);
+            out.push_str("        style=dashed;\n");
+            for id in ids {
+                let _ = writeln!(out, "        {};", id);
+            }
+            out.push_str("    }\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::new();
+        out.push_str("flowchart TD\n");
+        for node in &self.nodes {
+            let _ = writeln!(out, "    {}[\"{}\"]", node.id, escape_mermaid(&node.label));
+        }
+        for edge in &self.edges {
+            let _ = writeln!(out, "    {} -->|{}| {}", edge.source, edge.kind, edge.target);
+        }
+        for task in &self.owners {
+            let _ = writeln!(
+                out,
+                "    {}_owners[\"{}: {}\"]",
+                task.task,
+                OWNERS_LABEL,
+                escape_mermaid(&task.owners.join(", "))
+            );
+            let _ = writeln!(out, "    {} -.- {}_owners", task.task, task.task);
+        }
+        for task in &self.timings {
+            let _ = writeln!(
+                out,
+                "    {}_timings[\"{}: {}\"]",
+                task.task,
+                TIMINGS_LABEL,
+                escape_mermaid(&task.render())
+            );
+            let _ = writeln!(out, "    {} -.- {}_timings", task.task, task.task);
+        }
+        if !self.glossary.is_empty() {
+            let _ = writeln!(out, "    subgraph glossary[\"{}\"]", GLOSSARY_LABEL);
+            for (i, entry) in self.glossary.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "        glossary_{}[\"{}\"]",
+                    i,
+                    escape_mermaid(&glossary_label(entry))
+                );
+            }
+            out.push_str("    end\n");
+        }
+        let follow_ups = self.follow_up_ids();
+        if !follow_ups.is_empty() {
+            let _ = writeln!(out, "    subgraph follow_ups[\"{}\"]", FOLLOW_UPS_LABEL);
+            for id in follow_ups {
+                let _ = writeln!(out, "        {}", id);
+            }
+            out.push_str("    end\n");
+        }
+        for (subtask, ids) in self.collapsed_groups() {
+            let _ = writeln!(
+                out,
+                "    subgraph supporting_{}[\"{}\"]",
+                subtask,
+                escape_mermaid(SUPPORTING_LABEL)
+            );
+            for id in ids {
+                let _ = writeln!(out, "        {}", id);
+            }
+            out.push_str("    end\n");
+            let _ = writeln!(
+                out,
+                "    style supporting_{} stroke-dasharray: 5 5",
+                subtask
+            );
+        }
+
+        let unanswered = self
+            .nodes
+            .iter()
+            .filter(|node| node.unanswered)
+            .map(|node| node.id.as_str())
+            .collect::<Vec<_>>();
+        if !unanswered.is_empty() {
+            let _ = writeln!(
+                out,
+                "    classDef unanswered fill:{},stroke:{};",
+                UNANSWERED_FILL, UNANSWERED_STROKE
+            );
+            let _ = writeln!(out, "    class {} unanswered;", unanswered.join(","));
+        }
+        out
+    }
+
+    fn follow_up_ids(&self) -> Vec<&str> {
+        self.nodes
+            .iter()
+            .filter(|node| node.follow_up)
+            .map(|node| node.id.as_str())
+            .collect()
+    }
+
+    // the nodes shown collapsed under each synthesized subtask, in the order of the subtasks.
+    fn collapsed_groups(&self) -> Vec<(&str, Vec<&str>)> {
+        let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+        for node in &self.nodes {
+            let Some(subtask) = node.collapsed_under.as_deref() else {
+                continue;
+            };
+            match groups.iter_mut().find(|(id, _)| *id == subtask) {
+                Some((_, ids)) => ids.push(node.id.as_str()),
+                None => groups.push((subtask, vec![node.id.as_str()])),
+            }
+        }
+        groups
+    }
+}
+
+impl TrackProcessV1 {
+    /// Flattens the task graph into nodes and edges with stable ids, for rendering or custom frontends.
+    pub fn export_graph(&self) -> Result<GraphExport, NodeError> {
+        let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
+
+        // everything reachable from a follow-up edge belongs to the follow-up section.
+        let mut follow_up_nodes = HashSet::new();
+        for edge in graph.edge_references() {
+            if matches!(edge.weight(), EdgeV1::FollowUp) {
+                let mut dfs = Dfs::new(graph, edge.target());
+                while let Some(index) = dfs.next(graph) {
+                    follow_up_nodes.insert(index);
+                }
+            }
+        }
+
+        // once a subtask is synthesized, everything under its questions supports the synthesis.
+        let mut collapsed_nodes = HashMap::new();
+        for subtask in graph.node_indices() {
+            let edges = || graph.edges_directed(subtask, petgraph::Direction::Outgoing);
+            if !edges().any(|edge| matches!(edge.weight(), EdgeV1::SubtaskSummary)) {
+                continue;
+            }
+            for edge in edges().filter(|edge| matches!(edge.weight(), EdgeV1::Question)) {
+                let mut dfs = Dfs::new(graph, edge.target());
+                while let Some(index) = dfs.next(graph) {
+                    collapsed_nodes.insert(index, node_id(subtask.index()));
+                }
+            }
+        }
+
+        let nodes = graph
+            .node_indices()
+            .map(|index| {
+                let node = &graph[index];
+                let content = node_content(node);
+                // questions whose answer is missing or not found are highlighted.
+                let unanswered = matches!(node, NodeV1::Question(_))
+                    && !graph
+                        .edges_directed(index, petgraph::Direction::Outgoing)
+                        .any(|edge| {
+                            matches!(edge.weight(), EdgeV1::Answer)
+                                && matches!(graph[edge.target()], NodeV1::Answer(_))
+                        });
+                ExportNode {
+                    id: node_id(index.index()),
+                    kind: node_kind(node),
+                    label: format!("{}: {}", node_kind(node), truncate_label(&content)),
+                    content,
+                    unanswered,
+                    follow_up: follow_up_nodes.contains(&index),
+                    collapsed_under: collapsed_nodes.get(&index).cloned(),
+                }
+            })
+            .collect();
+
+        let edges = graph
+            .edge_references()
+            .map(|edge| ExportEdge {
+                id: format!("e{}", edge.id().index()),
+                source: node_id(edge.source().index()),
+                target: node_id(edge.target().index()),
+                kind: format!("{:?}", edge.weight()),
+            })
+            .collect();
+
+        // owners of every code context under a task, in the order they are cited.
+        let owners = graph
+            .node_indices()
+            .filter(|index| matches!(graph[*index], NodeV1::Task(_)))
+            .filter_map(|task| {
+                let mut owners: Vec<String> = Vec::new();
+                let mut dfs = Dfs::new(graph, task);
+                while let Some(index) = dfs.next(graph) {
+                    if let NodeV1::CodeContext(context) = &graph[index] {
+                        for owner in &context.owners {
+                            if !owners.contains(owner) {
+                                owners.push(owner.clone());
+                            }
+                        }
+                    }
+                }
+                (!owners.is_empty()).then(|| TaskOwners {
+                    task: node_id(task.index()),
+                    owners,
+                })
+            })
+            .collect();
+
+        // the timings of the questions under a task, follow-ups aren't under any.
+        let timings = graph
+            .node_indices()
+            .filter(|index| matches!(graph[*index], NodeV1::Task(_)))
+            .filter_map(|task| {
+                let mut row = TaskTimings {
+                    task: node_id(task.index()),
+                    questions: 0,
+                    timings: PhaseTimings::default(),
+                };
+                let mut dfs = Dfs::new(graph, task);
+                while let Some(index) = dfs.next(graph) {
+                    if let NodeV1::Timings(timings) = &graph[index] {
+                        row.questions += 1;
+                        row.timings.add(timings);
+                    }
+                }
+                (row.questions > 0).then_some(row)
+            })
+            .collect();
+
+        let scope_violations = graph
+            .node_indices()
+            .filter(|index| matches!(graph[*index], NodeV1::Question(_)))
+            .filter_map(|question| {
+                let violations = self.question_scope_violations(question.index());
+                (!violations.is_empty()).then(|| QuestionScopeViolations {
+                    question: node_id(question.index()),
+                    violations,
+                })
+            })
+            .collect();
+
+        let feedback = self.feedback_summary();
+        Ok(GraphExport {
+            citations: self.answer_citation_reports(),
+            prompt_versions: self.answer_prompt_version_reports(),
+            glossary: self.glossary().entries,
+            nodes,
+            edges,
+            owners,
+            timings,
+            scope: self.scope(),
+            scope_violations,
+            freshness_banner: self.freshness_banner(),
+            freshness: self.freshness(),
+            language: self.language(),
+            budget: self.conversation_budget(),
+            feedback: (!feedback.questions.is_empty()).then_some(feedback),
+        })
+    }
+
+    pub fn render_graph(&self, format: GraphFormat) -> Result<String, NodeError> {
+        Ok(self.export_graph()?.render(format))
+    }
+
+    /// The citations of every answer that recorded them, in the order the answers were added.
+    pub fn answer_citation_reports(&self) -> Vec<AnswerCitations> {
+        let Some(graph) = self.graph.as_ref() else {
+            return Vec::new();
+        };
+        graph
+            .node_indices()
+            .filter(|index| matches!(graph[*index], NodeV1::Answer(_)))
+            .filter_map(|answer| {
+                let citations = self.answer_citations(answer);
+                (!citations.is_empty()).then(|| AnswerCitations {
+                    answer: node_id(answer.index()),
+                    citations,
+                })
+            })
+            .collect()
+    }
+
+    /// The prompt versions of every answer, subtask synthesis and answer summary that recorded
+    /// them, in the order they were added.
+    pub fn answer_prompt_version_reports(&self) -> Vec<AnswerPromptVersions> {
+        let Some(graph) = self.graph.as_ref() else {
+            return Vec::new();
+        };
+        graph
+            .node_indices()
+            .filter(|index| {
+                matches!(
+                    graph[*index],
+                    NodeV1::Answer(_) | NodeV1::AnswerSummary(_) | NodeV1::SubtaskSummary(_)
+                )
+            })
+            .filter_map(|answer| {
+                let versions = self.answer_prompt_versions(answer);
+                (!versions.is_empty()).then(|| AnswerPromptVersions {
+                    answer: node_id(answer.index()),
+                    versions,
+                })
+            })
+            .collect()
+    }
+}
+
+impl TaskTimings {
+    /// e.g. `2 questions, retrieval 16s, answering 83s, 49s per question`.
+    pub fn render(&self) -> String {
+        let per_question = self.timings.total_ms() / self.questions.max(1) as u64;
+        let mut parts = vec![format!(
+            "{} question{}",
+            self.questions,
+            if self.questions == 1 { "" } else { "s" }
+        )];
+        parts.extend((!self.timings.render().is_empty()).then(|| self.timings.render()));
+        parts.push(format!("{}s per question", (per_question + 500) / 1000));
+        parts.join(", ")
+    }
+}
+
+// e.g. `commit_chunks: retries the upsert three times`, cut like the node labels.
+fn glossary_label(entry: &GlossaryEntry) -> String {
+    truncate_label(&format!("{}: {}", entry.symbol, entry.description))
+}
+
+fn node_id(index: usize) -> String {
+    format!("n{}", index)
+}
+
+fn node_kind(node: &NodeV1) -> &'static str {
+    match node {
+        NodeV1::Root(_) => "Root",
+        NodeV1::Conversation(..) => "Conversation",
+        NodeV1::Task(_) => "Task",
+        NodeV1::Subtask(_) => "Subtask",
+        NodeV1::Question(_) => "Question",
+        NodeV1::Answer(_) => "Answer",
+        NodeV1::AnswerNotFound(..) => "AnswerNotFound",
+        NodeV1::AnswerSummary(_) => "AnswerSummary",
+        NodeV1::CodeContext(_) => "CodeContext",
+        NodeV1::Preferences(_) => "Preferences",
+        NodeV1::IndexRun(_) => "IndexRun",
+        NodeV1::Language(_) => "Language",
+        NodeV1::Grounding(_) => "Grounding",
+        NodeV1::Budget(_) => "Budget",
+        NodeV1::Timings(_) => "Timings",
+        NodeV1::Scope(_) => "Scope",
+        NodeV1::ScopeViolations(_) => "ScopeViolations",
+        NodeV1::Freshness(_) => "Freshness",
+        NodeV1::Feedback(_) => "Feedback",
+        NodeV1::Verification(_) => "Verification",
+        NodeV1::DuplicateOf(_) => "DuplicateOf",
+        NodeV1::IndexGeneration(_) => "IndexGeneration",
+        NodeV1::Citations(_) => "Citations",
+        NodeV1::ChangePlan(_) => "ChangePlan",
+        NodeV1::PromptVersions(_) => "PromptVersions",
+        NodeV1::Glossary(_) => "Glossary",
+        NodeV1::Clarification(_) => "Clarification",
+        NodeV1::SubtaskSummary(_) => "SubtaskSummary",
+    }
+}
+
+fn node_content(node: &NodeV1) -> String {
+    match node {
+        NodeV1::Root(uuid) => uuid.clone(),
+        NodeV1::Conversation(role, message, _) => {
+            let text = match message {
+                Message::PlainText { content, .. } => content.clone(),
+                Message::FunctionReturn { name, content, .. } => format!("{} returned {}", name, content),
+                Message::FunctionCall { function_call, .. } => {
+                    format!("calls {}", function_call.name)
+                }
+            };
+            format!("[{}] {}", role, text)
+        }
+        NodeV1::Task(text)
+        | NodeV1::Subtask(text)
+        | NodeV1::Question(text)
+        | NodeV1::Answer(text)
+        | NodeV1::AnswerSummary(text)
+        | NodeV1::SubtaskSummary(text) => text.clone(),
+        NodeV1::AnswerNotFound(attempted_queries, _) => {
+            format!("tried {}", attempted_queries.join(", "))
+        }
+        NodeV1::CodeContext(context) => format!(
+            "{} {}",
+            context.path,
+            context
+                .ranges
+                .iter()
+                .map(|range| format!("{}-{}", range.start, range.end))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+        NodeV1::Preferences(preferences) => preferences.render().unwrap_or_default(),
+        NodeV1::IndexRun(run) => run.render(),
+        NodeV1::Language(language) => language.clone(),
+        NodeV1::Grounding(grounding) => grounding.render(),
+        NodeV1::Budget(budget) => budget.render(),
+        NodeV1::Timings(timings) => timings.render(),
+        NodeV1::Scope(scope) => scope.join(", "),
+        NodeV1::ScopeViolations(violations) => violations
+            .iter()
+            .map(|violation| format!("{} ({})", violation.path, violation.reason.as_str()))
+            .collect::<Vec<_>>()
+            .join(", "),
+        NodeV1::Freshness(note) => note.render(),
+        NodeV1::Feedback(feedback) => feedback.render(),
+        NodeV1::Verification(steps) => render_verification(steps),
+        NodeV1::DuplicateOf(duplicate) => duplicate.render(),
+        NodeV1::IndexGeneration(generation) => generation.render(),
+        NodeV1::Citations(report) => report.render(),
+        NodeV1::ChangePlan(plan) => plan.render(),
+        NodeV1::PromptVersions(versions) => versions
+            .iter()
+            .map(PromptVersion::render)
+            .collect::<Vec<_>>()
+            .join(", "),
+        NodeV1::Glossary(glossary) => glossary.render(),
+        NodeV1::Clarification(clarification) => match &clarification.response {
+            Some(response) => format!("{}: {}", clarification.render(), response),
+            None => clarification.render(),
+        },
+    }
+}
+
+// Collapses whitespace to keep labels on one line and cuts them at `MAX_LABEL_LEN` characters.
+fn truncate_label(content: &str) -> String {
+    let single_line = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if single_line.chars().count() <= MAX_LABEL_LEN {
+        return single_line;
+    }
+    let truncated = single_line.chars().take(MAX_LABEL_LEN).collect::<String>();
+    format!("{}...", truncated.trim_end())
+}
+
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(label: &str) -> String {
+    label.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::answer_scope::ScopeViolationReason;
+    use crate::anchors::{AnchorResolution, AnchorStatus};
+    use crate::verification::{VerificationKind, VerificationStep};
+    use crate::CodeContext;
+    use petgraph::graph::{DiGraph, NodeIndex};
+
+    // Root -> conversation -> task -> subtask -> two questions, one of them answered with a code context.
+    fn fixture_tracker() -> TrackProcessV1 {
+        let mut graph = DiGraph::new();
+        let root = graph.add_node(NodeV1::Root("conv-1".to_string()));
+        let conversation = graph.add_node(NodeV1::Conversation(
+            ai_gateway::message::message::MessageRole::User,
+            Message::user("How does \"search\" work?"),
+            "c-1".to_string(),
+        ));
+        let task = graph.add_node(NodeV1::Task("Understand the search flow".to_string()));
+        let subtask = graph.add_node(NodeV1::Subtask("Find the ranking code".to_string()));
+        let answered = graph.add_node(NodeV1::Question("Where are results ranked?".to_string()));
+        let answer = graph.add_node(NodeV1::Answer(
+            "Results are ranked in ranking.rs by combining the semantic score with the symbol score of each file"
+                .to_string(),
+        ));
+        let context = graph.add_node(NodeV1::CodeContext(CodeContext {
+            path: "src/ranking.rs".to_string(),
+            hidden: false,
+            repo: "repo".to_string(),
+            branch: None,
+            ranges: vec![10..42],
+            pinned: false,
+            owners: Vec::new(),
+        }));
+        let unanswered = graph.add_node(NodeV1::Question("How is the index built?".to_string()));
+
+        graph.add_edge(root, conversation, EdgeV1::NextConversation);
+        graph.add_edge(conversation, task, EdgeV1::Task);
+        graph.add_edge(task, subtask, EdgeV1::Subtask);
+        graph.add_edge(subtask, answered, EdgeV1::Question);
+        graph.add_edge(answered, answer, EdgeV1::Answer);
+        graph.add_edge(answer, context, EdgeV1::CodeContext);
+        graph.add_edge(subtask, unanswered, EdgeV1::Question);
+
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.graph = Some(graph);
+        tracker.root_node = Some(root);
+        tracker
+    }
+
+    #[test]
+    fn test_render_dot() {
+        let expected = r##"digraph task_graph {
+    rankdir=TB;
+    node [shape=box, style=rounded];
+    n0 [label="Root: conv-1"];
+    n1 [label="Conversation: [user] How does \"search\" work?"];
+    n2 [label="Task: Understand the search flow"];
+    n3 [label="Subtask: Find the ranking code"];
+    n4 [label="Question: Where are results ranked?"];
+    n5 [label="Answer: Results are ranked in ranking.rs by combining the semantic s..."];
+    n6 [label="CodeContext: src/ranking.rs 10-42"];
+    n7 [label="Question: How is the index built?", style="rounded,filled", fillcolor="#ffe08a", color="#c9a227"];
+    n0 -> n1 [label="NextConversation"];
+    n1 -> n2 [label="Task"];
+    n2 -> n3 [label="Subtask"];
+    n3 -> n4 [label="Question"];
+    n4 -> n5 [label="Answer"];
+    n5 -> n6 [label="CodeContext"];
+    n3 -> n7 [label="Question"];
+}
+"##;
+        assert_eq!(
+            fixture_tracker().render_graph(GraphFormat::Dot).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_render_mermaid() {
+        let expected = r##"flowchart TD
+    n0["Root: conv-1"]
+    n1["Conversation: [user] How does #quot;search#quot; work?"]
+    n2["Task: Understand the search flow"]
+    n3["Subtask: Find the ranking code"]
+    n4["Question: Where are results ranked?"]
+    n5["Answer: Results are ranked in ranking.rs by combining the semantic s..."]
+    n6["CodeContext: src/ranking.rs 10-42"]
+    n7["Question: How is the index built?"]
+    n0 -->|NextConversation| n1
+    n1 -->|Task| n2
+    n2 -->|Subtask| n3
+    n3 -->|Question| n4
+    n4 -->|Answer| n5
+    n5 -->|CodeContext| n6
+    n3 -->|Question| n7
+    classDef unanswered fill:#ffe08a,stroke:#c9a227;
+    class n7 unanswered;
+"##;
+        assert_eq!(
+            fixture_tracker().render_graph(GraphFormat::Mermaid).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_json_export_keeps_full_content() {
+        let export = fixture_tracker().export_graph().unwrap();
+        assert_eq!(export.nodes.len(), 8);
+        assert_eq!(export.edges.len(), 7);
+
+        let answer = &export.nodes[5];
+        assert_eq!(answer.id, "n5");
+        assert_eq!(answer.kind, "Answer");
+        assert!(answer.content.ends_with("symbol score of each file"));
+        assert!(answer.label.ends_with("..."));
+
+        assert_eq!(
+            export.edges[4],
+            ExportEdge {
+                id: "e4".to_string(),
+                source: "n4".to_string(),
+                target: "n5".to_string(),
+                kind: "Answer".to_string(),
+            }
+        );
+        assert!(export.nodes[7].unanswered);
+        assert!(!export.nodes[4].unanswered);
+    }
+
+    #[test]
+    fn test_follow_ups_render_in_separate_section() {
+        let mut tracker = fixture_tracker();
+        let graph = tracker.graph.as_mut().unwrap();
+        let conversation = graph.add_node(NodeV1::Conversation(
+            ai_gateway::message::message::MessageRole::User,
+            Message::user("What about errors?"),
+            "c-2".to_string(),
+        ));
+        let question = graph.add_node(NodeV1::Question("What about errors?".to_string()));
+        let answer = graph.add_node(NodeV1::Answer("Errors are logged.".to_string()));
+        graph.add_edge(NodeIndex::new(1), conversation, EdgeV1::NextConversation);
+        graph.add_edge(conversation, question, EdgeV1::FollowUp);
+        graph.add_edge(question, answer, EdgeV1::Answer);
+
+        let export = tracker.export_graph().unwrap();
+        let follow_ups = export
+            .nodes
+            .iter()
+            .filter(|node| node.follow_up)
+            .map(|node| node.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(follow_ups, vec!["n9", "n10"]);
+
+        let dot = export.to_dot();
+        assert!(dot.ends_with(
+            "    subgraph cluster_follow_ups {\n        label=\"Follow-ups\";\n        n9;\n        n10;\n    }\n}\n"
+        ));
+        let mermaid = export.to_mermaid();
+        assert!(mermaid.contains("    subgraph follow_ups[\"Follow-ups\"]\n        n9\n        n10\n    end\n"));
+    }
+
+    #[test]
+    fn test_supporting_answers_collapse_under_the_synthesis() {
+        let mut tracker = fixture_tracker();
+        tracker
+            .record_subtask_summary(
+                3,
+                "Ranking combines the semantic and symbol scores in src/ranking.rs.",
+                &crate::prompt_versions::prompt_versions(&["subtask_synthesis_prompt"]),
+            )
+            .unwrap();
+
+        let export = tracker.export_graph().unwrap();
+        let synthesis = &export.nodes[8];
+        assert_eq!(synthesis.kind, "SubtaskSummary");
+        assert_eq!(synthesis.collapsed_under, None);
+        assert_eq!(export.nodes[9].kind, "PromptVersions");
+        let collapsed = export
+            .nodes
+            .iter()
+            .filter(|node| node.collapsed_under.as_deref() == Some("n3"))
+            .map(|node| node.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(collapsed, vec!["n4", "n5", "n6", "n7"]);
+        assert_eq!(export.prompt_versions[0].answer, "n8");
+
+        let dot = export.to_dot();
+        assert!(dot.contains("    n3 -> n8 [label=\"SubtaskSummary\"];\n"));
+        assert!(dot.ends_with(
+            "    subgraph cluster_supporting_n3 {\n        label=\"Supporting Q&A\";\n        style=dashed;\n        n4;\n        n5;\n        n6;\n        n7;\n    }\n}\n"
+        ));
+        let mermaid = export.to_mermaid();
+        assert!(mermaid.contains(
+            "    subgraph supporting_n3[\"Supporting Q&A\"]\n        n4\n        n5\n        n6\n        n7\n    end\n    style supporting_n3 stroke-dasharray: 5 5\n"
+        ));
+        // without a synthesis nothing is collapsed.
+        assert!(fixture_tracker()
+            .export_graph()
+            .unwrap()
+            .nodes
+            .iter()
+            .all(|node| node.collapsed_under.is_none()));
+    }
+
+    #[test]
+    fn test_owners_render_per_task() {
+        let mut tracker = fixture_tracker();
+        let graph = tracker.graph.as_mut().unwrap();
+        if let NodeV1::CodeContext(context) = &mut graph[NodeIndex::new(6)] {
+            context.owners = vec!["@acme/search".to_string(), "@octo".to_string()];
+        }
+        let second = graph.add_node(NodeV1::CodeContext(CodeContext {
+            path: "src/score.rs".to_string(),
+            hidden: false,
+            repo: "repo".to_string(),
+            branch: None,
+            ranges: vec![1..5],
+            pinned: false,
+            owners: vec!["@octo".to_string()],
+        }));
+        graph.add_edge(NodeIndex::new(5), second, EdgeV1::CodeContext);
+
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(
+            export.owners,
+            vec![TaskOwners {
+                task: "n2".to_string(),
+                owners: vec!["@acme/search".to_string(), "@octo".to_string()],
+            }]
+        );
+        assert!(export.to_dot().contains(
+            "    n2_owners [shape=note, label=\"Owners involved: @acme/search, @octo\"];\n    n2 -> n2_owners [style=dashed, arrowhead=none];\n"
+        ));
+        assert!(export
+            .to_mermaid()
+            .contains("    n2_owners[\"Owners involved: @acme/search, @octo\"]\n    n2 -.- n2_owners\n"));
+        // without owners the export is unchanged.
+        assert!(fixture_tracker().export_graph().unwrap().owners.is_empty());
+    }
+
+    #[test]
+    fn test_timings_render_per_task() {
+        let mut tracker = fixture_tracker();
+        let graph = tracker.graph.as_mut().unwrap();
+        for (question, answer_ms) in [(4, 41_400), (7, 20_600)] {
+            let timings = graph.add_node(NodeV1::Timings(PhaseTimings {
+                retrieval_ms: 8_000,
+                answer_ms,
+                ..Default::default()
+            }));
+            graph.add_edge(NodeIndex::new(question), timings, EdgeV1::Timings);
+        }
+
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.timings.len(), 1);
+        assert_eq!(export.timings[0].task, "n2");
+        assert_eq!(export.timings[0].questions, 2);
+        assert_eq!(export.timings[0].timings.answer_ms, 62_000);
+        assert!(export.to_dot().contains(
+            "    n2_timings [shape=note, label=\"Timings: 2 questions, retrieval 16s, answering 62s, 39s per question\"];\n    n2 -> n2_timings [style=dashed, arrowhead=none];\n"
+        ));
+        assert!(export.to_mermaid().contains("    n2 -.- n2_timings\n"));
+        assert!(fixture_tracker().export_graph().unwrap().timings.is_empty());
+    }
+
+    #[test]
+    fn test_scope_violations_are_exported_per_question() {
+        let mut tracker = fixture_tracker();
+        tracker.record_scope(&["src/search".to_string()]).unwrap();
+        let graph = tracker.graph.as_mut().unwrap();
+        let violations = graph.add_node(NodeV1::ScopeViolations(vec![ScopeViolation {
+            path: "billing/src/ledger.rs".to_string(),
+            reason: ScopeViolationReason::OtherRepo,
+            statement: "Update `billing/src/ledger.rs`.".to_string(),
+            rewritten: true,
+        }]));
+        graph.add_edge(NodeIndex::new(4), violations, EdgeV1::ScopeViolations);
+
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.scope, vec!["src/search".to_string()]);
+        assert_eq!(export.scope_violations.len(), 1);
+        assert_eq!(export.scope_violations[0].question, "n4");
+        assert_eq!(export.scope_violations[0].violations[0].path, "billing/src/ledger.rs");
+        assert!(export.to_dot().contains(&format!(
+            "    n{} [label=\"ScopeViolations: billing/src/ledger.rs (other repo)\"];\n",
+            violations.index()
+        )));
+        assert!(fixture_tracker().export_graph().unwrap().scope_violations.is_empty());
+    }
+
+    #[test]
+    fn test_verification_steps_are_exported_with_their_answer() {
+        let mut tracker = fixture_tracker();
+        let graph = tracker.graph.as_mut().unwrap();
+        let verification = graph.add_node(NodeV1::Verification(vec![VerificationStep {
+            kind: VerificationKind::RunTest,
+            instruction: "Run the ranking tests".to_string(),
+            path: "tests/ranking_test.rs".to_string(),
+            lines: None,
+            command: Some("cargo test ranking".to_string()),
+        }]));
+        graph.add_edge(NodeIndex::new(5), verification, EdgeV1::Verification);
+
+        assert_eq!(tracker.answer_verification(NodeIndex::new(5)).len(), 1);
+        let export = tracker.export_graph().unwrap();
+        let node = &export.nodes[verification.index()];
+        assert_eq!(node.kind, "Verification");
+        assert_eq!(
+            node.content,
+            "### Verification steps\n1. Run the ranking tests ([tests/ranking_test.rs](tests/ranking_test.rs)): `cargo test ranking`\n"
+        );
+        assert!(tracker.answer_verification(NodeIndex::new(4)).is_empty());
+    }
+
+    #[test]
+    fn test_citations_are_exported_with_their_answer() {
+        let mut tracker = fixture_tracker();
+        let mut report = CitationReport::parse("See [ranking](src/ranking.rs#L10-L12).");
+        report.citations[0].lines = Some((10, 12));
+        report.citations[0].drift = Some(AnchorResolution {
+            status: AnchorStatus::Moved,
+            lines: Some((40, 42)),
+            similarity: 1.0,
+        });
+        let graph = tracker.graph.as_mut().unwrap();
+        let citations = graph.add_node(NodeV1::Citations(report.clone()));
+        graph.add_edge(NodeIndex::new(5), citations, EdgeV1::Citations);
+
+        assert_eq!(tracker.answer_citations(NodeIndex::new(5)), report);
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.citations.len(), 1);
+        assert_eq!(export.citations[0].answer, "n5");
+        assert_eq!(
+            export.nodes[citations.index()].content,
+            "src/ranking.rs#L10-L12 (moved to L40-L42)"
+        );
+        assert!(fixture_tracker().export_graph().unwrap().citations.is_empty());
+    }
+
+    #[test]
+    fn test_prompt_versions_are_exported_with_their_answer() {
+        let mut tracker = fixture_tracker();
+        let versions = crate::prompt_versions::prompt_versions(&["system", "answer_article_prompt"]);
+        let graph = tracker.graph.as_mut().unwrap();
+        let node = graph.add_node(NodeV1::PromptVersions(versions.clone()));
+        graph.add_edge(NodeIndex::new(5), node, EdgeV1::PromptVersions);
+
+        assert_eq!(tracker.answer_prompt_versions(NodeIndex::new(5)), versions);
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.prompt_versions.len(), 1);
+        assert_eq!(export.prompt_versions[0].answer, "n5");
+        let content = &export.nodes[node.index()].content;
+        assert_eq!(
+            *content,
+            format!("{}, {}", versions[0].render(), versions[1].render())
+        );
+        assert!(fixture_tracker().export_graph().unwrap().prompt_versions.is_empty());
+    }
+
+    #[test]
+    fn test_glossary_renders_as_an_appendix() {
+        let mut tracker = fixture_tracker();
+        let mut glossary = crate::glossary::Glossary::default();
+        glossary.record(
+            4,
+            "rank",
+            "src/ranking.rs",
+            "`rank` combines the \"semantic\" score with the symbol score.".to_string(),
+        );
+        tracker.record_glossary(glossary.clone()).unwrap();
+
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.glossary, glossary.entries);
+        let dot = export.to_dot();
+        assert!(dot.contains("subgraph cluster_glossary {\n        label=\"Glossary\";\n"));
+        assert!(dot.contains(
+            "glossary_0 [shape=note, label=\"rank: `rank` combines the \\\"semantic\\\" score with the symbol s...\"];"
+        ));
+        assert!(export
+            .to_mermaid()
+            .contains("    subgraph glossary[\"Glossary\"]\n        glossary_0[\"rank: `rank` combines the #quot;semantic#quot;"));
+        assert!(!fixture_tracker().export_graph().unwrap().to_dot().contains("glossary"));
+    }
+
+    #[test]
+    fn test_graph_format_from_str() {
+        assert_eq!("DOT".parse::<GraphFormat>().unwrap(), GraphFormat::Dot);
+        assert_eq!("mermaid".parse::<GraphFormat>().unwrap(), GraphFormat::Mermaid);
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+}
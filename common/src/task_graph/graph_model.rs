@@ -1,3 +1,19 @@
+use crate::budget::ConversationBudget;
+use crate::change_plan::ChangePlan;
+use crate::citations::CitationReport;
+use crate::clarification::Clarification;
+use crate::duplicates::DuplicateRef;
+use crate::feedback::AnswerFeedback;
+use crate::freshness::FreshnessNote;
+use crate::generation::IndexGeneration;
+use crate::glossary::Glossary;
+use crate::timings::PhaseTimings;
+use crate::answer_scope::ScopeViolation;
+use crate::grounding::TaskGrounding;
+use crate::preferences::Preferences;
+use crate::prompt_versions::PromptVersion;
+use crate::run_manifest::IndexRunRef;
+use crate::verification::VerificationStep;
 use crate::{CodeContext, CodeUnderstanding};
 use ai_gateway::message::message::{MessageRole, Message};
 use crate::task_graph::redis_config::set_redis_url;
@@ -18,6 +34,10 @@ pub struct TrackProcessV1 {
     pub last_added_conversation_node: Option<NodeIndex>,
     pub time_created: SystemTime,
     pub last_updated: SystemTime,
+    // Tenant that started the conversation, only it can continue or read it.
+    // Missing on conversations created before authentication was added.
+    #[serde(default)]
+    pub tenant_id: Option<String>,
 }
 
 impl TrackProcessV1 {
@@ -32,9 +52,16 @@ impl TrackProcessV1 {
             last_added_conversation_node: None,
             time_created: SystemTime::now(),
             last_updated: SystemTime::now(),
+            tenant_id: None,
         }
     }
 
+    /// Whether the conversation belongs to the tenant.
+    /// Conversations without a tenant predate authentication and only belong to the default tenant.
+    pub fn belongs_to(&self, tenant_id: &str) -> bool {
+        self.tenant_id.as_deref().unwrap_or(crate::auth::DEFAULT_TENANT_ID) == tenant_id
+    }
+
     // Initializes the graph and root node if they haven't been already.
     pub fn initialize_graph(&mut self) {
         if self.graph.is_none() {
@@ -72,8 +99,28 @@ pub enum NodeV1 {
     Subtask(String),          // Represents a subtask under a specific task.
     Question(String),  // Represents a question related to a specific subtask. 
     Answer(String),           // Represents an answer to a question.
+    AnswerNotFound(Vec<String>, Vec<String>), // The agent couldn't find an answer, holds the attempted queries and closest paths.
     AnswerSummary(String),    // Represents a summary of the answer.
     CodeContext(CodeContext), // Represents a code context associated with an answer.
+    Preferences(Preferences), // Preferences the user stated in the conversation, attached to the root.
+    IndexRun(IndexRunRef),    // The ingestion run of the index the conversation is answered against, attached to the root.
+    Language(String),         // Language the conversation is answered in, set on creation and attached to the root.
+    Grounding(TaskGrounding), // How the components a task mentions matched the repo, attached to the task.
+    Budget(ConversationBudget), // What the LLM calls of the conversation cost so far, attached to the root.
+    Timings(PhaseTimings),    // Where the time of answering a question went, attached to the question.
+    Scope(Vec<String>),       // Paths of the repo the conversation is scoped to, set on creation and attached to the root.
+    ScopeViolations(Vec<ScopeViolation>), // Paths out of scope the answer of a question recommended changing, attached to the question.
+    Freshness(FreshnessNote), // How far the index was behind its branch when the conversation started, attached to the root.
+    Feedback(AnswerFeedback), // A rating the user gave an answer, attached to the answer or not-found answer.
+    Verification(Vec<VerificationStep>), // How to check an answer against the code it cites, attached to the answer.
+    DuplicateOf(DuplicateRef), // The recent conversation this one repeats, set when it was started anyway and attached to the root.
+    IndexGeneration(IndexGeneration), // The collections the searches of the conversation are pinned to, set on creation and attached to the root.
+    Citations(CitationReport), // The files and lines an answer cites with their content anchors, attached to the answer.
+    ChangePlan(ChangePlan),   // The files the answered tasks would change, set on request and attached to the root.
+    PromptVersions(Vec<PromptVersion>), // The prompt templates an answer or summary was generated with, attached to it.
+    Glossary(Glossary),       // The symbols the answers cited with how they described them, attached to the root.
+    Clarification(Clarification), // The question the agent stopped to ask the user with their response, attached to the question.
+    SubtaskSummary(String),   // The synthesis of the answers of the questions of a subtask, attached to the subtask.
 }
 
 impl NodeV1 {
@@ -92,8 +139,29 @@ pub enum EdgeV1 {
     Subtask,     // An edge from a task to a specific subtask.
     Question,    // An edge from a subtask to a question about that subtask.
     Answer,      // Connects a question to its answer.
+    SupersededAnswer, // Connects a question to a not-found answer that was replaced by a retry.
     CodeContext, // Connects an answer to its code context.
     SummarizedAnswer, // Connects a conversation node to an answer summary node.
+    FollowUp,    // Connects a user conversation node to a follow-up question asked after the tasks were answered.
+    Preferences, // Connects the root node to the preferences of the conversation.
+    IndexRun,    // Connects the root node to the ingestion run of the index.
+    Language,    // Connects the root node to the language of the conversation.
+    Grounding,   // Connects a task to its grounding.
+    Budget,      // Connects the root node to the spend of the conversation.
+    Timings,     // Connects a question to the timings of its answer.
+    Scope,       // Connects the root node to the scope of the conversation.
+    ScopeViolations, // Connects a question to the scope violations of its answer.
+    Freshness,   // Connects the root node to the freshness of the index.
+    Feedback,    // Connects an answer to a rating of it.
+    Verification, // Connects an answer to its verification steps.
+    DuplicateOf, // Connects the root node to the conversation it repeats.
+    IndexGeneration, // Connects the root node to the index generation it is pinned to.
+    Citations,   // Connects an answer to its citations.
+    ChangePlan,  // Connects the root node to the change plan of the conversation.
+    PromptVersions, // Connects an answer or answer summary to the prompt versions it was generated with.
+    Glossary,    // Connects the root node to the glossary of the conversation.
+    Clarification, // Connects a question to the clarifying question its agent asked.
+    SubtaskSummary, // Connects a subtask to the synthesis of its answers.
     // SummarizedAnswer are siblings of the tasks with same conversation parent.
 }
 
@@ -109,6 +177,10 @@ pub struct ConversationChain {
 pub struct QuestionWithId {
     pub id: usize,
     pub text: String,
+    // the response of the user to the clarifying question the agent stopped at, sent with the
+    // question so that the agent resumes with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clarification: Option<String>,
 }
 
 // Type to keep the question, their IDs along with answers in the form of CodeUnderstanding type
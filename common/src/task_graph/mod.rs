@@ -4,4 +4,5 @@ pub mod redis;
 pub mod state;
 pub mod add_node;
 pub mod answers;
-pub mod redis_config;
\ No newline at end of file
+pub mod redis_config;
+pub mod export;
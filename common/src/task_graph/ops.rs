@@ -1,4 +1,20 @@
+use crate::answer_scope::ScopeViolation;
+use crate::budget::{BudgetExceeded, ConversationBudget};
+use crate::change_plan::ChangePlan;
+use crate::citations::CitationReport;
+use crate::clarification::Clarification;
+use crate::duplicates::DuplicateRef;
+use crate::feedback::{AnswerFeedback, FeedbackSummary, QuestionFeedback, Rating};
+use crate::freshness::FreshnessNote;
+use crate::generation::IndexGeneration;
+use crate::glossary::Glossary;
 use crate::models::TaskList;
+use crate::preferences::Preferences;
+use crate::prompt_versions::PromptVersion;
+use crate::run_manifest::IndexRunRef;
+use crate::timings::{PhaseTimings, QuestionTimings, TimingSummary};
+use crate::verification::VerificationStep;
+use crate::AnswerOutcome;
 use crate::task_graph::add_node::NodeError;
 use crate::task_graph::graph_model::QuestionWithAnswer;
 use crate::task_graph::graph_model::{
@@ -7,9 +23,13 @@ use crate::task_graph::graph_model::{
 use anyhow::Result;
 use log::{debug, error, info};
 use petgraph::graph::NodeIndex;
-use std::time::SystemTime;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::task_graph::redis_config::get_redis_url;
+use crate::task_graph::state::is_follow_up_question;
+use ai_gateway::message::message::Message;
 
 impl TrackProcessV1 {
     /// Extends the graph with a chain of conversation nodes followed by task-related nodes if a task list is provided.
@@ -102,6 +122,9 @@ impl TrackProcessV1 {
             tasks.into_iter().try_for_each(|task| {
                 self.add_task_node(task.task)
                     .and_then(|task_node| {
+                        if let Some(grounding) = task.grounding {
+                            self.add_task_grounding(task_node, grounding)?;
+                        }
                         task.subtasks
                             .into_iter()
                             .try_for_each(|subtask| {
@@ -128,6 +151,75 @@ impl TrackProcessV1 {
         Ok(self)
     }
 
+    /// Adds a follow-up question asked after the tasks were answered. The user message becomes the
+    /// next conversation node and the question hangs directly off it, no tasks are generated.
+    /// The graph isn't persisted, the caller saves it once the question is answered.
+    ///
+    /// # Graph Structure
+    /// ```
+    /// Conversation Node: (last conversation)
+    /// │
+    /// └── NextConversation Edge
+    ///     │
+    ///     └── Conversation Node: User (follow-up message)
+    ///         │
+    ///         └── FollowUp Edge
+    ///             │
+    ///             └── Question Node ── Answer Edge ── Answer Node ── CodeContext Edges
+    /// ```
+    pub fn add_follow_up_question(&mut self, question: &str) -> Result<QuestionWithId, NodeError> {
+        self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
+
+        self.add_user_conversation(Message::user(question))?;
+        let conversation_node = self
+            .last_added_conversation_node
+            .ok_or(NodeError::MissingLastUpdatedNode)?;
+        self.add_and_connect_node(
+            conversation_node,
+            NodeV1::Question(question.to_string()),
+            EdgeV1::FollowUp,
+        )?;
+        let question_node = self.last_added_node.ok_or(NodeError::MissingLastUpdatedNode)?;
+
+        Ok(QuestionWithId {
+            id: question_node.index(),
+            text: question.to_string(),
+            clarification: None,
+        })
+    }
+
+    /// Adds a question answered directly, without decomposing it into tasks. It's attached like a
+    /// follow-up so the conversation reads as answered, later messages are follow-ups of it or
+    /// new issues. Starts the graph on a new conversation, the caller saves it once answered.
+    ///
+    /// # Graph Structure
+    /// ```
+    /// Root Node ── NextConversation Edge ── Conversation Node: User (question)
+    ///                                       │
+    ///                                       └── FollowUp Edge ── Question Node ── Answer Edge ── Answer Node
+    /// ```
+    pub fn add_quick_question(&mut self, question: &str) -> Result<QuestionWithId, NodeError> {
+        if self.graph.is_none() {
+            self.initialize_graph();
+        }
+        self.add_follow_up_question(question)
+    }
+
+    /// Collects the paths of all code contexts found so far in the conversation, without duplicates.
+    pub fn get_code_context_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        if let Some(graph) = self.graph.as_ref() {
+            for node_index in graph.node_indices() {
+                if let NodeV1::CodeContext(context) = &graph[node_index] {
+                    if !paths.contains(&context.path) {
+                        paths.push(context.path.clone());
+                    }
+                }
+            }
+        }
+        paths
+    }
+
     /// Collects all questions from the graph and returns them as `QuestionWithId`.
     ///
     /// # Returns
@@ -142,6 +234,7 @@ impl TrackProcessV1 {
                         Some(QuestionWithId {
                             id: node_index.index(),
                             text: text.clone(),
+                            clarification: None,
                         })
                     } else {
                         None
@@ -158,8 +251,11 @@ impl TrackProcessV1 {
 
         // Iterate over all nodes in the graph.
         for node_index in graph.node_indices() {
-            // Check if the node is a Question node.
+            // Check if the node is a Question node, follow-ups are answered on their own.
             if let Some(NodeV1::Question(question)) = graph.node_weight(node_index) {
+                if is_follow_up_question(graph, node_index) {
+                    continue;
+                }
                 // Check if there's no outgoing edge to an Answer node.
                 let has_answer = graph
                     .edges_directed(node_index, petgraph::Direction::Outgoing)
@@ -170,6 +266,10 @@ impl TrackProcessV1 {
                     unanswered_questions.push(QuestionWithId {
                         id: node_index.index(),
                         text: question.clone(),
+                        // the agent resumes with the response of the user, if it stopped to ask.
+                        clarification: self
+                            .question_clarification(node_index.index())
+                            .and_then(|clarification| clarification.response),
                     });
                 }
             }
@@ -183,32 +283,13 @@ impl TrackProcessV1 {
         answers: &Vec<Result<QuestionWithAnswer>>,
     ) -> Result<(), NodeError> {
         // Check if the graph is initialized.
-        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
 
         // Iterate through the answers, skipping any that are errors.
         for answer_result in answers {
             if let Ok(answer) = answer_result {
                 debug!("Successfully processing an answer: {:?}", answer);
-                // Use the NodeIndex from the answer to directly reference the question node.
-                let question_node_index = NodeIndex::new(answer.question_id);
-                // Ensure the node index points to a valid Question node.
-                if let Some(NodeV1::Question(_)) = graph.node_weight(question_node_index) {
-                    debug!(
-                        "Adding answer to question node with index: {:?}",
-                        question_node_index
-                    );
-                    // Create an Answer node and connect it to the Question node.
-                    let answer_node = graph.add_node(NodeV1::Answer(answer.answer.answer.clone()));
-                    graph.add_edge(question_node_index, answer_node, EdgeV1::Answer);
-
-                    // Add each CodeContext from the answer as a node connected to the Answer node.
-                    for context in answer.answer.context.iter() {
-                        let context_node = graph.add_node(NodeV1::CodeContext(context.clone()));
-                        graph.add_edge(answer_node, context_node, EdgeV1::CodeContext);
-                    }
-                } else {
-                    return Err(NodeError::InvalidQuestionNode);
-                }
+                self.add_answer_node(answer)?;
             } else {
                 error!(
                     "Failed to process an answer due to error: {:?}",
@@ -226,4 +307,1670 @@ impl TrackProcessV1 {
         }
         Ok(())
     }
+
+    /// Connects a single answer to its question node without persisting the graph.
+    /// Answers the agent couldn't find are stored as `AnswerNotFound` nodes so they
+    /// count as resolved, but never get mixed up with real answers in summaries.
+    pub fn add_answer_node(&mut self, answer: &QuestionWithAnswer) -> Result<(), NodeError> {
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        // Use the NodeIndex from the answer to directly reference the question node.
+        let question_node_index = NodeIndex::new(answer.question_id);
+        // Ensure the node index points to a valid Question node.
+        if !matches!(
+            graph.node_weight(question_node_index),
+            Some(NodeV1::Question(_))
+        ) {
+            return Err(NodeError::InvalidQuestionNode);
+        }
+
+        debug!(
+            "Adding answer to question node with index: {:?}",
+            question_node_index
+        );
+        if let Some(timings) = answer.answer.timings {
+            self.set_question_timings(question_node_index, timings)?;
+        }
+        self.set_scope_violations(question_node_index, &answer.answer.scope_violations);
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        if let Some(AnswerOutcome::NotFound {
+            attempted_queries,
+            closest_paths,
+        }) = &answer.answer.outcome
+        {
+            let not_found_node = graph.add_node(NodeV1::AnswerNotFound(
+                attempted_queries.clone(),
+                closest_paths.clone(),
+            ));
+            graph.add_edge(question_node_index, not_found_node, EdgeV1::Answer);
+            return Ok(());
+        }
+
+        // Create an Answer node and connect it to the Question node.
+        let answer_node = graph.add_node(NodeV1::Answer(answer.answer.answer.clone()));
+        graph.add_edge(question_node_index, answer_node, EdgeV1::Answer);
+
+        // Add each CodeContext from the answer as a node connected to the Answer node.
+        for context in answer.answer.context.iter() {
+            let context_node = graph.add_node(NodeV1::CodeContext(context.clone()));
+            graph.add_edge(answer_node, context_node, EdgeV1::CodeContext);
+        }
+        if !answer.answer.verification.is_empty() {
+            let verification_node =
+                graph.add_node(NodeV1::Verification(answer.answer.verification.clone()));
+            graph.add_edge(answer_node, verification_node, EdgeV1::Verification);
+        }
+        if !answer.answer.citations.is_empty() {
+            let citations_node =
+                graph.add_node(NodeV1::Citations(answer.answer.citations.clone()));
+            graph.add_edge(answer_node, citations_node, EdgeV1::Citations);
+        }
+        if !answer.answer.prompt_versions.is_empty() {
+            let versions_node =
+                graph.add_node(NodeV1::PromptVersions(answer.answer.prompt_versions.clone()));
+            graph.add_edge(answer_node, versions_node, EdgeV1::PromptVersions);
+        }
+        // the symbols the answer cites, for the next answers to describe them the same way.
+        let mut glossary = self.glossary();
+        let (question_id, answer) = (question_node_index.index(), &answer.answer);
+        if glossary.record_answer(question_id, &answer.answer, &answer.citations) > 0 {
+            self.record_glossary(glossary)?;
+        }
+        Ok(())
+    }
+
+    /// Finds all questions the agent couldn't answer, along with the queries it tried
+    /// and the closest paths it found.
+    pub fn get_not_found_questions(
+        &self,
+    ) -> Result<Vec<(QuestionWithId, Vec<String>, Vec<String>)>, NodeError> {
+        let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
+
+        Ok(graph
+            .node_indices()
+            .filter_map(|node_index| {
+                let NodeV1::Question(question) = &graph[node_index] else {
+                    return None;
+                };
+                graph
+                    .edges_directed(node_index, petgraph::Direction::Outgoing)
+                    .filter(|edge| matches!(edge.weight(), EdgeV1::Answer))
+                    .find_map(|edge| match &graph[edge.target()] {
+                        NodeV1::AnswerNotFound(attempted_queries, closest_paths) => Some((
+                            QuestionWithId {
+                                id: node_index.index(),
+                                text: question.clone(),
+                                clarification: None,
+                            },
+                            attempted_queries.clone(),
+                            closest_paths.clone(),
+                        )),
+                        _ => None,
+                    })
+            })
+            .collect())
+    }
+
+    /// Marks the not-found answer of a question as superseded and replaces the question text
+    /// with the reformulated query, so the question is picked up again as unanswered.
+    /// The old `AnswerNotFound` node is kept for history, node indices stay stable.
+    pub fn supersede_not_found_answer(
+        &mut self,
+        question_id: usize,
+        reformulated_question: &str,
+    ) -> Result<(), NodeError> {
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        let question_node_index = NodeIndex::new(question_id);
+
+        if !matches!(
+            graph.node_weight(question_node_index),
+            Some(NodeV1::Question(_))
+        ) {
+            return Err(NodeError::InvalidQuestionNode);
+        }
+
+        let not_found_edges = graph
+            .edges_directed(question_node_index, petgraph::Direction::Outgoing)
+            .filter(|edge| {
+                matches!(edge.weight(), EdgeV1::Answer)
+                    && matches!(graph[edge.target()], NodeV1::AnswerNotFound(..))
+            })
+            .map(|edge| edge.id())
+            .collect::<Vec<_>>();
+
+        if not_found_edges.is_empty() {
+            return Err(NodeError::NodeNotFound(format!(
+                "Question {} has no not-found answer to retry.",
+                question_id
+            )));
+        }
+
+        for edge_id in not_found_edges {
+            if let Some(weight) = graph.edge_weight_mut(edge_id) {
+                *weight = EdgeV1::SupersededAnswer;
+            }
+        }
+        graph[question_node_index] = NodeV1::Question(reformulated_question.to_string());
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    /// Preferences the user stated in the conversation, empty when there is no Preferences node.
+    pub fn preferences(&self) -> Preferences {
+        let graph = match self.graph.as_ref() {
+            Some(graph) => graph,
+            None => return Preferences::default(),
+        };
+        match self.preferences_node().map(|node| &graph[node]) {
+            Some(NodeV1::Preferences(preferences)) => preferences.clone(),
+            _ => Preferences::default(),
+        }
+    }
+
+    /// Merges the preferences stated in a user message into the Preferences node of the root,
+    /// creating it on the first statement. Returns whether the preferences changed.
+    /// The node isn't part of the conversation chain, the last added nodes are left as they are.
+    pub fn record_preferences(&mut self, message: &str) -> Result<bool, NodeError> {
+        let stated = Preferences::detect(message);
+        if stated.is_empty() {
+            return Ok(false);
+        }
+        let existing = self.preferences_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        let changed = match existing {
+            Some(node) => match &mut graph[node] {
+                NodeV1::Preferences(preferences) => preferences.merge(&stated),
+                _ => false,
+            },
+            None => {
+                let node = graph.add_node(NodeV1::Preferences(stated));
+                graph.add_edge(root_node, node, EdgeV1::Preferences);
+                true
+            }
+        };
+        if changed {
+            debug!("Preferences of the conversation updated: {:?}", self.preferences());
+            self.last_updated = SystemTime::now();
+        }
+        Ok(changed)
+    }
+
+    fn preferences_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Preferences))
+            .map(|edge| edge.target())
+    }
+
+    /// The ingestion run of the index the conversation is answered against, None for
+    /// conversations started before it was recorded or on repos indexed without a manifest.
+    pub fn index_run(&self) -> Option<IndexRunRef> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.index_run_node()?] {
+            NodeV1::IndexRun(run) => Some(run.clone()),
+            _ => None,
+        }
+    }
+
+    /// Attaches the ingestion run of the index to the root, replacing the one recorded before.
+    /// Like the preferences, the node isn't part of the conversation chain.
+    pub fn record_index_run(&mut self, run: IndexRunRef) -> Result<(), NodeError> {
+        let existing = self.index_run_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(node) => graph[node] = NodeV1::IndexRun(run),
+            None => {
+                let node = graph.add_node(NodeV1::IndexRun(run));
+                graph.add_edge(root_node, node, EdgeV1::IndexRun);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn index_run_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::IndexRun))
+            .map(|edge| edge.target())
+    }
+
+    /// Language the conversation is answered in: the one the user stated last in a message,
+    /// otherwise the one the conversation was created with. None when neither is known.
+    pub fn language(&self) -> Option<String> {
+        self.preferences().language.or_else(|| {
+            let graph = self.graph.as_ref()?;
+            match &graph[self.language_node()?] {
+                NodeV1::Language(language) => Some(language.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    /// Attaches the language of the conversation to the root, replacing the one recorded before.
+    /// Like the preferences, the node isn't part of the conversation chain.
+    pub fn record_language(&mut self, language: &str) -> Result<(), NodeError> {
+        let existing = self.language_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        let node = NodeV1::Language(language.to_string());
+        match existing {
+            Some(existing) => graph[existing] = node,
+            None => {
+                let node = graph.add_node(node);
+                graph.add_edge(root_node, node, EdgeV1::Language);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn language_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Language))
+            .map(|edge| edge.target())
+    }
+
+    /// What the LLM calls of the conversation cost so far, None before its first spend was recorded.
+    pub fn conversation_budget(&self) -> Option<ConversationBudget> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.budget_node()?] {
+            NodeV1::Budget(budget) => Some(budget.clone()),
+            _ => None,
+        }
+    }
+
+    /// Adds the spend of a run to the conversation. Like the preferences, the node isn't part
+    /// of the conversation chain.
+    pub fn record_spend(&mut self, usd: f64) -> Result<(), NodeError> {
+        let mut budget = self.conversation_budget().unwrap_or_default();
+        budget.spent_usd += usd;
+        self.set_budget(budget)
+    }
+
+    /// Records the limit the conversation went beyond, the last one is kept.
+    pub fn record_budget_exceeded(&mut self, exceeded: BudgetExceeded) -> Result<(), NodeError> {
+        let mut budget = self.conversation_budget().unwrap_or_default();
+        budget.exceeded = Some(exceeded);
+        self.set_budget(budget)
+    }
+
+    /// Records that the client of the conversation went away while its questions were answered,
+    /// see `ConversationProcessingStage::Suspended`. The time of the first suspension is kept.
+    pub fn record_suspended(&mut self, at: u64) -> Result<(), NodeError> {
+        let mut budget = self.conversation_budget().unwrap_or_default();
+        budget.suspended_at.get_or_insert(at);
+        self.set_budget(budget)
+    }
+
+    /// Resumes a suspended conversation, returns false when it wasn't suspended.
+    pub fn record_resumed(&mut self) -> Result<bool, NodeError> {
+        let Some(mut budget) = self.conversation_budget() else {
+            return Ok(false);
+        };
+        if budget.suspended_at.take().is_none() {
+            return Ok(false);
+        }
+        self.set_budget(budget)?;
+        Ok(true)
+    }
+
+    /// When the conversation was suspended, None while it is alive.
+    pub fn suspended_at(&self) -> Option<u64> {
+        self.conversation_budget()?.suspended_at
+    }
+
+    fn set_budget(&mut self, budget: ConversationBudget) -> Result<(), NodeError> {
+        let existing = self.budget_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(node) => graph[node] = NodeV1::Budget(budget),
+            None => {
+                let node = graph.add_node(NodeV1::Budget(budget));
+                graph.add_edge(root_node, node, EdgeV1::Budget);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn budget_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Budget))
+            .map(|edge| edge.target())
+    }
+
+    /// Where the time of answering the question went, None for questions answered before
+    /// the timings were recorded.
+    pub fn question_timings(&self, question_id: usize) -> Option<PhaseTimings> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.timings_node(NodeIndex::new(question_id))?] {
+            NodeV1::Timings(timings) => Some(*timings),
+            _ => None,
+        }
+    }
+
+    /// Adds the time the answer of the question took to be saved, once it was saved. Like the
+    /// rest of the graph, it is persisted with the next save.
+    pub fn record_persistence_time(
+        &mut self,
+        question_id: usize,
+        persistence_ms: u64,
+    ) -> Result<PhaseTimings, NodeError> {
+        let mut timings = self.question_timings(question_id).unwrap_or_default();
+        timings.persistence_ms = persistence_ms;
+        self.set_question_timings(NodeIndex::new(question_id), timings)?;
+        Ok(timings)
+    }
+
+    /// Timings of the answered questions in graph order, None before the first one was recorded.
+    pub fn timing_summary(&self) -> Option<TimingSummary> {
+        let graph = self.graph.as_ref()?;
+        let questions = graph
+            .node_indices()
+            .filter_map(|index| match &graph[index] {
+                NodeV1::Question(question) => Some(QuestionTimings {
+                    question_id: index.index(),
+                    question: question.clone(),
+                    timings: self.question_timings(index.index())?,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        (!questions.is_empty()).then(|| TimingSummary::new(questions))
+    }
+
+    // the timings of a retried question replace the ones of its previous answer.
+    fn set_question_timings(
+        &mut self,
+        question: NodeIndex,
+        timings: PhaseTimings,
+    ) -> Result<(), NodeError> {
+        let existing = self.timings_node(question);
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        if !matches!(graph.node_weight(question), Some(NodeV1::Question(_))) {
+            return Err(NodeError::InvalidQuestionNode);
+        }
+
+        match existing {
+            Some(node) => graph[node] = NodeV1::Timings(timings),
+            None => {
+                let node = graph.add_node(NodeV1::Timings(timings));
+                graph.add_edge(question, node, EdgeV1::Timings);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn timings_node(&self, question: NodeIndex) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph.node_weight(question)?;
+        graph
+            .edges_directed(question, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Timings))
+            .map(|edge| edge.target())
+    }
+
+    /// Paths of the repo the conversation is scoped to, empty when it spans the whole repo.
+    pub fn scope(&self) -> Vec<String> {
+        let Some(graph) = self.graph.as_ref() else {
+            return Vec::new();
+        };
+        match self.scope_node().map(|node| &graph[node]) {
+            Some(NodeV1::Scope(scope)) => scope.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Attaches the scope of the conversation to the root, replacing the one recorded before.
+    /// Like the language, the node isn't part of the conversation chain.
+    pub fn record_scope(&mut self, scope: &[String]) -> Result<(), NodeError> {
+        let existing = self.scope_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        let node = NodeV1::Scope(scope.to_vec());
+        match existing {
+            Some(existing) => graph[existing] = node,
+            None => {
+                let node = graph.add_node(node);
+                graph.add_edge(root_node, node, EdgeV1::Scope);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn scope_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Scope))
+            .map(|edge| edge.target())
+    }
+
+    /// The recent conversation this one repeats, None unless it was started despite one.
+    pub fn duplicate_of(&self) -> Option<DuplicateRef> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.duplicate_of_node()?] {
+            NodeV1::DuplicateOf(duplicate) => Some(duplicate.clone()),
+            _ => None,
+        }
+    }
+
+    /// Attaches the conversation this one repeats to the root, replacing the one recorded before.
+    /// Like the scope, the node isn't part of the conversation chain.
+    pub fn record_duplicate_of(&mut self, duplicate: DuplicateRef) -> Result<(), NodeError> {
+        let existing = self.duplicate_of_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(node) => graph[node] = NodeV1::DuplicateOf(duplicate),
+            None => {
+                let node = graph.add_node(NodeV1::DuplicateOf(duplicate));
+                graph.add_edge(root_node, node, EdgeV1::DuplicateOf);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn duplicate_of_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::DuplicateOf))
+            .map(|edge| edge.target())
+    }
+
+    /// The collections the searches of the conversation are pinned to, None for conversations
+    /// started before generations were pinned or against a code search that doesn't serve them.
+    pub fn index_generation(&self) -> Option<IndexGeneration> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.index_generation_node()?] {
+            NodeV1::IndexGeneration(generation) => Some(generation.clone()),
+            _ => None,
+        }
+    }
+
+    /// Pins the conversation to `generation`, replacing the one recorded before when it is
+    /// re-pinned. Like the index run, the node isn't part of the conversation chain.
+    pub fn record_index_generation(&mut self, generation: IndexGeneration) -> Result<(), NodeError> {
+        let existing = self.index_generation_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(node) => graph[node] = NodeV1::IndexGeneration(generation),
+            None => {
+                let node = graph.add_node(NodeV1::IndexGeneration(generation));
+                graph.add_edge(root_node, node, EdgeV1::IndexGeneration);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn index_generation_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::IndexGeneration))
+            .map(|edge| edge.target())
+    }
+
+    /// The change plan generated last, None before one was requested.
+    pub fn change_plan(&self) -> Option<ChangePlan> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.change_plan_node()?] {
+            NodeV1::ChangePlan(plan) => Some(plan.clone()),
+            _ => None,
+        }
+    }
+
+    /// Attaches the change plan to the root, replacing the one generated before.
+    pub fn record_change_plan(&mut self, plan: ChangePlan) -> Result<(), NodeError> {
+        let existing = self.change_plan_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(existing) => graph[existing] = NodeV1::ChangePlan(plan),
+            None => {
+                let node = graph.add_node(NodeV1::ChangePlan(plan));
+                graph.add_edge(root_node, node, EdgeV1::ChangePlan);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn change_plan_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::ChangePlan))
+            .map(|edge| edge.target())
+    }
+
+    /// The symbols the answers of the conversation cited, empty before the first one.
+    pub fn glossary(&self) -> Glossary {
+        let Some(graph) = self.graph.as_ref() else {
+            return Glossary::default();
+        };
+        match self.glossary_node().map(|node| &graph[node]) {
+            Some(NodeV1::Glossary(glossary)) => glossary.clone(),
+            _ => Glossary::default(),
+        }
+    }
+
+    /// Attaches the glossary to the root, replacing the one recorded before. Like the scope, the
+    /// node isn't part of the conversation chain.
+    pub fn record_glossary(&mut self, glossary: Glossary) -> Result<(), NodeError> {
+        let existing = self.glossary_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(existing) => graph[existing] = NodeV1::Glossary(glossary),
+            None => {
+                let node = graph.add_node(NodeV1::Glossary(glossary));
+                graph.add_edge(root_node, node, EdgeV1::Glossary);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn glossary_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Glossary))
+            .map(|edge| edge.target())
+    }
+
+    /// The clarifying question the agent of the question stopped to ask, None when it didn't.
+    pub fn question_clarification(&self, question_id: usize) -> Option<Clarification> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.clarification_node(NodeIndex::new(question_id))?] {
+            NodeV1::Clarification(clarification) => Some(clarification.clone()),
+            _ => None,
+        }
+    }
+
+    /// The unanswered question whose agent waits for the response of the user, with its
+    /// clarifying question. The questions are answered one at a time, there is one at most.
+    pub fn pending_clarification(&self) -> Option<(usize, Clarification)> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .node_indices()
+            .filter(|node| matches!(graph[*node], NodeV1::Question(_)))
+            .filter(|node| {
+                !graph
+                    .edges_directed(*node, Direction::Outgoing)
+                    .any(|edge| matches!(edge.weight(), EdgeV1::Answer))
+            })
+            .find_map(|node| {
+                self.question_clarification(node.index())
+                    .filter(Clarification::is_pending)
+                    .map(|clarification| (node.index(), clarification))
+            })
+    }
+
+    /// Attaches the clarifying question the agent of the question stopped to ask, replacing the
+    /// one recorded before.
+    pub fn record_clarification(
+        &mut self,
+        question_id: usize,
+        clarification: Clarification,
+    ) -> Result<(), NodeError> {
+        let question = NodeIndex::new(question_id);
+        let existing = self.clarification_node(question);
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        if !matches!(graph.node_weight(question), Some(NodeV1::Question(_))) {
+            return Err(NodeError::InvalidQuestionNode);
+        }
+
+        match existing {
+            Some(existing) => graph[existing] = NodeV1::Clarification(clarification),
+            None => {
+                let node = graph.add_node(NodeV1::Clarification(clarification));
+                graph.add_edge(question, node, EdgeV1::Clarification);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    /// Records the message of the user as the response to the pending clarifying question,
+    /// returns the question it answers. None when no question waits for one.
+    pub fn record_clarification_response(
+        &mut self,
+        response: &str,
+    ) -> Result<Option<usize>, NodeError> {
+        let Some((question_id, mut clarification)) = self.pending_clarification() else {
+            return Ok(None);
+        };
+        clarification.response = Some(response.trim().to_string());
+        self.record_clarification(question_id, clarification)?;
+        Ok(Some(question_id))
+    }
+
+    fn clarification_node(&self, question: NodeIndex) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph.node_weight(question)?;
+        graph
+            .edges_directed(question, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Clarification))
+            .map(|edge| edge.target())
+    }
+
+    /// How far the index was behind its branch when the conversation started, None for
+    /// conversations started before it was recorded.
+    pub fn freshness(&self) -> Option<FreshnessNote> {
+        let graph = self.graph.as_ref()?;
+        match &graph[self.freshness_node()?] {
+            NodeV1::Freshness(note) => Some(note.clone()),
+            _ => None,
+        }
+    }
+
+    /// The warning shown with the answers of the conversation when its index is stale.
+    pub fn freshness_banner(&self) -> Option<String> {
+        self.freshness()?.banner()
+    }
+
+    /// Attaches the freshness of the index to the root, replacing the one recorded before.
+    /// Like the scope, the node isn't part of the conversation chain.
+    pub fn record_freshness(&mut self, note: FreshnessNote) -> Result<(), NodeError> {
+        let existing = self.freshness_node();
+        let root_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+
+        match existing {
+            Some(existing) => graph[existing] = NodeV1::Freshness(note),
+            None => {
+                let node = graph.add_node(NodeV1::Freshness(note));
+                graph.add_edge(root_node, node, EdgeV1::Freshness);
+            }
+        }
+        self.last_updated = SystemTime::now();
+        Ok(())
+    }
+
+    fn freshness_node(&self) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph
+            .edges_directed(self.root_node?, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Freshness))
+            .map(|edge| edge.target())
+    }
+
+    /// Paths out of scope the answer of the question recommended changing.
+    pub fn question_scope_violations(&self, question_id: usize) -> Vec<ScopeViolation> {
+        let Some(graph) = self.graph.as_ref() else {
+            return Vec::new();
+        };
+        match self
+            .scope_violations_node(NodeIndex::new(question_id))
+            .map(|node| &graph[node])
+        {
+            Some(NodeV1::ScopeViolations(violations)) => violations.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The verification steps of an answer node, empty when it has none.
+    pub fn answer_verification(&self, answer_node: NodeIndex) -> Vec<VerificationStep> {
+        let Some(graph) = self.graph.as_ref() else {
+            return Vec::new();
+        };
+        graph
+            .edges_directed(answer_node, Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::Verification))
+            .find_map(|edge| match &graph[edge.target()] {
+                NodeV1::Verification(steps) => Some(steps.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The citations of the answer with their anchors, empty for answers recorded without them.
+    pub fn answer_citations(&self, answer_node: NodeIndex) -> CitationReport {
+        let Some(graph) = self.graph.as_ref() else {
+            return CitationReport::default();
+        };
+        graph
+            .edges_directed(answer_node, Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::Citations))
+            .find_map(|edge| match &graph[edge.target()] {
+                NodeV1::Citations(report) => Some(report.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The prompt templates the answer or answer summary was generated with, empty for the ones
+    /// recorded without them.
+    pub fn answer_prompt_versions(&self, answer_node: NodeIndex) -> Vec<PromptVersion> {
+        let Some(graph) = self.graph.as_ref() else {
+            return Vec::new();
+        };
+        graph
+            .edges_directed(answer_node, Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::PromptVersions))
+            .find_map(|edge| match &graph[edge.target()] {
+                NodeV1::PromptVersions(versions) => Some(versions.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    // the violations of a retried question replace the ones of its previous answer, the node is
+    // only added once there is one.
+    fn set_scope_violations(&mut self, question: NodeIndex, violations: &[ScopeViolation]) {
+        let existing = self.scope_violations_node(question);
+        let Some(graph) = self.graph.as_mut() else {
+            return;
+        };
+        match existing {
+            Some(node) => graph[node] = NodeV1::ScopeViolations(violations.to_vec()),
+            None if !violations.is_empty() => {
+                let node = graph.add_node(NodeV1::ScopeViolations(violations.to_vec()));
+                graph.add_edge(question, node, EdgeV1::ScopeViolations);
+            }
+            None => {}
+        }
+    }
+
+    fn scope_violations_node(&self, question: NodeIndex) -> Option<NodeIndex> {
+        let graph = self.graph.as_ref()?;
+        graph.node_weight(question)?;
+        graph
+            .edges_directed(question, Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::ScopeViolations))
+            .map(|edge| edge.target())
+    }
+
+    /// Attaches a rating to the current answer of the question, found or not. The answer version
+    /// counts the answers the question had so far, so ratings of a retried question stay apart.
+    pub fn record_feedback(
+        &mut self,
+        question_id: usize,
+        rating: Rating,
+        comment: Option<String>,
+        correct_paths: Vec<String>,
+    ) -> Result<AnswerFeedback, NodeError> {
+        let graph = self.graph.as_mut().ok_or(NodeError::GraphNotInitialized)?;
+        let question = NodeIndex::new(question_id);
+        if !matches!(graph.node_weight(question), Some(NodeV1::Question(_))) {
+            return Err(NodeError::InvalidQuestionNode);
+        }
+
+        let answers = graph
+            .edges_directed(question, Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::Answer | EdgeV1::SupersededAnswer))
+            .map(|edge| (edge.id(), edge.target(), matches!(edge.weight(), EdgeV1::Answer)))
+            .collect::<Vec<_>>();
+        // a question answered again keeps its earlier answers, the current one was added last.
+        let answer = answers
+            .iter()
+            .filter(|(_, _, current)| *current)
+            .max_by_key(|(edge, _, _)| *edge)
+            .map(|(_, answer, _)| *answer)
+            .ok_or_else(|| {
+                NodeError::NodeNotFound(format!("Question {} has no answer to rate.", question_id))
+            })?;
+
+        let feedback = AnswerFeedback {
+            rating,
+            comment: comment.filter(|comment| !comment.trim().is_empty()),
+            correct_paths,
+            answer_version: answers.len(),
+            submitted_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+        };
+        let node = graph.add_node(NodeV1::Feedback(feedback.clone()));
+        graph.add_edge(answer, node, EdgeV1::Feedback);
+        self.last_updated = SystemTime::now();
+        Ok(feedback)
+    }
+
+    /// The ratings of all the answers of the question, None before the first one.
+    pub fn question_feedback(&self, question_id: usize) -> Option<QuestionFeedback> {
+        let graph = self.graph.as_ref()?;
+        let question = NodeIndex::new(question_id);
+        let NodeV1::Question(text) = graph.node_weight(question)? else {
+            return None;
+        };
+        let mut ratings = graph
+            .edges_directed(question, Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::Answer | EdgeV1::SupersededAnswer))
+            .flat_map(|answer| {
+                graph
+                    .edges_directed(answer.target(), Direction::Outgoing)
+                    .map(move |edge| (answer.target(), edge))
+            })
+            .filter_map(|(answer, edge)| match &graph[edge.target()] {
+                NodeV1::Feedback(feedback) => Some((edge.target(), answer, feedback)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // nodes are only ever added, the latest rating is the one added last.
+        ratings.sort_by_key(|(node, _, _)| *node);
+        let up = ratings
+            .iter()
+            .filter(|(_, _, feedback)| feedback.rating == Rating::Up)
+            .count();
+        let (_, rated_answer, latest) = ratings.last()?;
+        Some(QuestionFeedback {
+            question_id,
+            question: text.clone(),
+            up,
+            down: ratings.len() - up,
+            latest: (*latest).clone(),
+            prompt_versions: self.answer_prompt_versions(*rated_answer),
+        })
+    }
+
+    /// The ratings of the conversation, by question in graph order.
+    pub fn feedback_summary(&self) -> FeedbackSummary {
+        FeedbackSummary::new(
+            self.get_questions_with_ids()
+                .iter()
+                .filter_map(|question| self.question_feedback(question.id))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grounding::{MentionMatch, TaskGrounding};
+    use crate::models::{Subtask, Task};
+    use crate::task_graph::state::ConversationProcessingStage;
+    use crate::CodeUnderstanding;
+    use ai_gateway::message::message::{Message, MessageRole};
+
+    // Builds a graph with a single task and subtask holding the given questions, without touching redis.
+    fn tracker_with_questions(questions: &[&str]) -> (TrackProcessV1, Vec<NodeIndex>) {
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.initialize_graph();
+        tracker
+            .add_user_conversation(Message::user("how does search work?"))
+            .unwrap();
+        let task = tracker.add_task_node("task".to_string()).unwrap();
+        let subtask = tracker.add_subtask_node("subtask".to_string(), task).unwrap();
+        let question_nodes = questions
+            .iter()
+            .map(|q| tracker.add_question_node(q.to_string(), subtask).unwrap())
+            .collect();
+        (tracker, question_nodes)
+    }
+
+    fn answer(question_id: NodeIndex, outcome: Option<AnswerOutcome>) -> QuestionWithAnswer {
+        QuestionWithAnswer {
+            question_id: question_id.index(),
+            question: "q".to_string(),
+            answer: CodeUnderstanding {
+                context: vec![],
+                question: "q".to_string(),
+                answer: "answer".to_string(),
+                outcome,
+                missing_pinned_paths: vec![],
+                cost_usd: None,
+                timings: None,
+                scope_violations: vec![],
+                citations: Default::default(),
+                verification: Default::default(),
+                prompt_versions: Default::default(),
+                files_involved: Default::default(),
+                index_mode: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_not_found_answers_count_as_resolved() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        tracker.add_answer_node(&answer(questions[0], None)).unwrap();
+        assert_eq!(
+            tracker.check_question_completion(),
+            ConversationProcessingStage::QuestionsPartiallyAnswered
+        );
+
+        let not_found = AnswerOutcome::NotFound {
+            attempted_queries: vec!["search internals".to_string()],
+            closest_paths: vec!["src/search.rs".to_string()],
+        };
+        tracker
+            .add_answer_node(&answer(questions[1], Some(not_found.clone())))
+            .unwrap();
+
+        assert_eq!(
+            tracker.check_question_completion(),
+            ConversationProcessingStage::AllQuestionsAnswered
+        );
+        assert!(tracker.get_unanswered_questions().unwrap().is_empty());
+
+        let not_found_questions = tracker.get_not_found_questions().unwrap();
+        assert_eq!(not_found_questions.len(), 1);
+        assert_eq!(not_found_questions[0].0.id, questions[1].index());
+
+        let with_answers = tracker.get_current_questions_with_answers().unwrap();
+        assert_eq!(with_answers.len(), 2);
+        assert!(with_answers
+            .iter()
+            .any(|qa| qa.answer.outcome == Some(not_found.clone())));
+    }
+
+    #[test]
+    fn test_superseding_not_found_answer_reopens_question() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1"]);
+        let not_found = AnswerOutcome::NotFound {
+            attempted_queries: vec![],
+            closest_paths: vec![],
+        };
+        tracker
+            .add_answer_node(&answer(questions[0], Some(not_found)))
+            .unwrap();
+
+        tracker
+            .supersede_not_found_answer(questions[0].index(), "reformulated q1")
+            .unwrap();
+
+        assert_eq!(
+            tracker.check_question_completion(),
+            ConversationProcessingStage::TasksAndQuestionsGenerated
+        );
+        let unanswered = tracker.get_unanswered_questions().unwrap();
+        assert_eq!(unanswered.len(), 1);
+        assert_eq!(unanswered[0].text, "reformulated q1");
+        assert!(tracker.get_not_found_questions().unwrap().is_empty());
+
+        // a question that was answered can't be retried as not found.
+        tracker.add_answer_node(&answer(questions[0], None)).unwrap();
+        assert!(tracker
+            .supersede_not_found_answer(questions[0].index(), "again")
+            .is_err());
+    }
+
+    #[test]
+    fn test_conversation_isolation_between_tenants() {
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        tracker.tenant_id = Some("team-a".to_string());
+        assert!(tracker.belongs_to("team-a"));
+        assert!(!tracker.belongs_to("team-b"));
+        assert!(!tracker.belongs_to(crate::auth::DEFAULT_TENANT_ID));
+
+        // the tenant survives the round trip through redis serialization.
+        let restored: TrackProcessV1 =
+            serde_json::from_str(&serde_json::to_string(&tracker).unwrap()).unwrap();
+        assert!(restored.belongs_to("team-a"));
+        assert!(!restored.belongs_to("team-b"));
+
+        // conversations saved before tenants existed only belong to the default tenant.
+        let mut legacy = serde_json::to_value(&tracker).unwrap();
+        legacy.as_object_mut().unwrap().remove("tenant_id");
+        let legacy: TrackProcessV1 = serde_json::from_value(legacy).unwrap();
+        assert!(legacy.belongs_to(crate::auth::DEFAULT_TENANT_ID));
+        assert!(!legacy.belongs_to("team-a"));
+    }
+
+    #[test]
+    fn test_follow_up_hangs_off_conversation_without_tasks() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1"]);
+        let mut first = answer(questions[0], None);
+        first.answer.context = vec![crate::CodeContext {
+            path: "src/search.rs".to_string(),
+            hidden: false,
+            repo: "repo".to_string(),
+            branch: None,
+            ranges: vec![1..10],
+            pinned: false,
+            owners: Vec::new(),
+        }];
+        tracker.add_answer_node(&first).unwrap();
+        let tasks_before = tracker.get_current_tasks().unwrap();
+
+        let follow_up = tracker
+            .add_follow_up_question("what about error handling in task 1?")
+            .unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::FollowUpPending
+        );
+
+        // the follow-up is attached to the new user conversation node, not to a subtask.
+        let graph = tracker.graph.as_ref().unwrap();
+        let conversation = tracker.last_added_conversation_node.unwrap();
+        let parents = graph
+            .edges_directed(NodeIndex::new(follow_up.id), petgraph::Direction::Incoming)
+            .map(|edge| (edge.source(), format!("{:?}", edge.weight())))
+            .collect::<Vec<_>>();
+        assert_eq!(parents, vec![(conversation, "FollowUp".to_string())]);
+        assert_eq!(
+            tracker.last_user_query().as_deref(),
+            Some("what about error handling in task 1?")
+        );
+
+        // task questions are not affected by the pending follow-up.
+        assert!(tracker.get_unanswered_questions().unwrap().is_empty());
+        assert_eq!(
+            tracker.check_question_completion(),
+            ConversationProcessingStage::AllQuestionsAnswered
+        );
+        assert_eq!(tracker.get_code_context_paths(), vec!["src/search.rs".to_string()]);
+
+        tracker
+            .add_answer_node(&answer(NodeIndex::new(follow_up.id), None))
+            .unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::FollowUpAnswered
+        );
+        assert_eq!(
+            tracker.last_follow_up_answer().unwrap().unwrap().question_id,
+            follow_up.id
+        );
+        assert_eq!(tracker.get_follow_ups().unwrap().len(), 1);
+        assert_eq!(tracker.get_current_questions_with_answers().unwrap().len(), 1);
+        assert_eq!(
+            format!("{:?}", tracker.get_current_tasks().unwrap()),
+            format!("{:?}", tasks_before)
+        );
+    }
+
+    #[test]
+    fn test_quick_question_on_a_new_conversation() {
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        let question = tracker
+            .add_quick_question("where is the JWT validated?")
+            .unwrap();
+        assert!(tracker.get_root_node_uuid().is_some());
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::FollowUpPending
+        );
+
+        tracker
+            .add_answer_node(&answer(NodeIndex::new(question.id), None))
+            .unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::FollowUpAnswered
+        );
+        assert_eq!(
+            tracker.last_user_query().as_deref(),
+            Some("where is the JWT validated?")
+        );
+        // no tasks, the answer is only listed with the follow-ups.
+        assert!(tracker.get_current_tasks().unwrap().tasks.unwrap().is_empty());
+        assert!(tracker.get_current_questions_with_answers().unwrap().is_empty());
+        assert_eq!(tracker.get_follow_ups().unwrap().len(), 1);
+        assert_eq!(tracker.graph.as_ref().unwrap().node_count(), 4);
+    }
+
+    // Adds the conversation nodes in reverse order, connected in conversation order.
+    fn tracker_with_interleaved_conversation(contents: &[&str]) -> TrackProcessV1 {
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.initialize_graph();
+        let root = tracker.root_node.unwrap();
+        let graph = tracker.graph.as_mut().unwrap();
+
+        let mut nodes = vec![None; contents.len()];
+        // later messages get lower node indices.
+        for i in (0..contents.len()).rev() {
+            // unrelated nodes in between, as tasks and answers are added during the conversation.
+            graph.add_node(NodeV1::Task(format!("task {}", i)));
+            let role = if i % 2 == 0 { MessageRole::User } else { MessageRole::Assistant };
+            nodes[i] = Some(graph.add_node(NodeV1::Conversation(
+                role,
+                Message::new_text(role, contents[i]),
+                i.to_string(),
+            )));
+        }
+
+        let mut parent = root;
+        for node in nodes.into_iter().map(Option::unwrap) {
+            graph.add_edge(parent, node, EdgeV1::NextConversation);
+            parent = node;
+        }
+        tracker.last_added_conversation_node = Some(parent);
+        tracker
+    }
+
+    fn contents(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| match message {
+                Message::PlainText { content, .. } => content.clone(),
+                _ => String::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_conversation_messages_follow_next_conversation_edges() {
+        let conversation = ["q1", "a1", "q2", "a2", "q3"];
+        let tracker = tracker_with_interleaved_conversation(&conversation);
+
+        // node indices are not in conversation order.
+        let chain = tracker.conversation_node_chain().unwrap();
+        assert!(chain.windows(2).all(|pair| pair[0] > pair[1]));
+
+        let messages = tracker.collect_conversation_messages().unwrap().messages;
+        assert_eq!(contents(&messages), conversation);
+        assert_eq!(tracker.last_user_query().as_deref(), Some("q3"));
+    }
+
+    #[test]
+    fn test_conversation_messages_pagination_boundaries() {
+        let conversation = ["q1", "a1", "q2", "a2", "q3"];
+        let tracker = tracker_with_interleaved_conversation(&conversation);
+
+        let first = tracker.collect_conversation_messages_page(0, 2).unwrap();
+        assert_eq!(contents(&first.messages), ["q1", "a1"]);
+        assert_eq!((first.total, first.next_offset), (5, Some(2)));
+
+        // the last page ends exactly at the end of the conversation.
+        let last = tracker.collect_conversation_messages_page(3, 2).unwrap();
+        assert_eq!(contents(&last.messages), ["a2", "q3"]);
+        assert_eq!(last.next_offset, None);
+
+        let partial = tracker.collect_conversation_messages_page(4, 10).unwrap();
+        assert_eq!(contents(&partial.messages), ["q3"]);
+        assert_eq!(partial.next_offset, None);
+
+        let past_end = tracker.collect_conversation_messages_page(5, 2).unwrap();
+        assert!(past_end.messages.is_empty());
+        assert_eq!(past_end.next_offset, None);
+
+        let empty = tracker.collect_conversation_messages_page(0, 0).unwrap();
+        assert!(empty.messages.is_empty());
+        assert_eq!(empty.next_offset, Some(0));
+    }
+
+    #[test]
+    fn test_prompt_history_keeps_most_recent_messages() {
+        let tracker = tracker_with_interleaved_conversation(&["q1", "a1", "q2", "a2", "q3"]);
+
+        let history = tracker.prompt_history(2).unwrap();
+        assert_eq!(contents(&history.older), ["q1", "a1", "q2"]);
+        assert_eq!(contents(&history.recent), ["a2", "q3"]);
+
+        let history = tracker.prompt_history(10).unwrap();
+        assert!(history.older.is_empty());
+        assert_eq!(history.recent.len(), 5);
+    }
+
+    #[test]
+    fn test_preferences_are_recorded_on_the_root() {
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        // conversations started before preferences existed have no node.
+        assert_eq!(tracker.preferences(), Preferences::default());
+        let last_added_node = tracker.last_added_node;
+        let stage = tracker.last_conversation_processing_stage().0;
+
+        assert!(!tracker.record_preferences("where is the ranking done?").unwrap());
+        assert!(tracker.record_preferences("be concise please").unwrap());
+        assert!(tracker.record_preferences("and respond in German").unwrap());
+        assert!(!tracker.record_preferences("keep it short").unwrap());
+
+        let preferences = tracker.preferences();
+        assert_eq!(preferences.verbosity, Some(crate::preferences::Verbosity::Concise));
+        assert_eq!(preferences.language.as_deref(), Some("German"));
+        assert_eq!(tracker.last_added_node, last_added_node);
+
+        // a single node holds the merged preferences.
+        let graph = tracker.graph.as_ref().unwrap();
+        let preference_nodes = graph
+            .node_indices()
+            .filter(|node| matches!(graph[*node], NodeV1::Preferences(_)))
+            .count();
+        assert_eq!(preference_nodes, 1);
+        assert_eq!(tracker.last_conversation_processing_stage().0, stage);
+    }
+
+    #[test]
+    fn test_index_run_is_recorded_on_the_root() {
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        assert_eq!(tracker.index_run(), None);
+        let last_added_node = tracker.last_added_node;
+        let stage = tracker.last_conversation_processing_stage().0;
+
+        let run = |run_id: &str| IndexRunRef {
+            run_id: run_id.to_string(),
+            indexer_version: "0.1.0".to_string(),
+            indexed_commit: "4f2a9c1d0b7e".to_string(),
+            embedding_model: "model".to_string(),
+            index_mode: Default::default(),
+            web_url_template: None,
+        };
+        tracker.record_index_run(run("run-1")).unwrap();
+        tracker.record_index_run(run("run-2")).unwrap();
+
+        assert_eq!(tracker.index_run(), Some(run("run-2")));
+        assert_eq!(tracker.last_added_node, last_added_node);
+        assert_eq!(tracker.last_conversation_processing_stage().0, stage);
+        let graph = tracker.graph.as_ref().unwrap();
+        let run_nodes = graph
+            .node_indices()
+            .filter(|node| matches!(graph[*node], NodeV1::IndexRun(_)))
+            .count();
+        assert_eq!(run_nodes, 1);
+    }
+
+    #[test]
+    fn test_language_is_recorded_on_the_root() {
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        assert_eq!(tracker.language(), None);
+        let last_added_node = tracker.last_added_node;
+        let stage = tracker.last_conversation_processing_stage().0;
+
+        tracker.record_language("German").unwrap();
+        tracker.record_language("Japanese").unwrap();
+
+        assert_eq!(tracker.language().as_deref(), Some("Japanese"));
+        assert_eq!(tracker.last_added_node, last_added_node);
+        assert_eq!(tracker.last_conversation_processing_stage().0, stage);
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.language.as_deref(), Some("Japanese"));
+        assert_eq!(export.nodes.iter().filter(|node| node.kind == "Language").count(), 1);
+
+        // a language the user states in a message overrides the one of the conversation.
+        tracker.record_preferences("please reply in Spanish").unwrap();
+        assert_eq!(tracker.language().as_deref(), Some("Spanish"));
+    }
+
+    #[test]
+    fn test_spend_is_recorded_on_the_root() {
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        assert_eq!(tracker.conversation_budget(), None);
+        let last_added_node = tracker.last_added_node;
+        let stage = tracker.last_conversation_processing_stage().0;
+
+        tracker.record_spend(0.25).unwrap();
+        tracker.record_spend(0.5).unwrap();
+        let exceeded = BudgetExceeded {
+            scope: crate::budget::BudgetScope::Conversation,
+            limit_usd: 1.0,
+            spent_usd: 0.75,
+            projected_usd: 1.1,
+        };
+        tracker.record_budget_exceeded(exceeded.clone()).unwrap();
+
+        let budget = tracker.conversation_budget().unwrap();
+        assert_eq!(budget.spent_usd, 0.75);
+        assert_eq!(budget.exceeded, Some(exceeded));
+        assert_eq!(tracker.last_added_node, last_added_node);
+        assert_eq!(tracker.last_conversation_processing_stage().0, stage);
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.budget, Some(budget));
+        assert_eq!(export.nodes.iter().filter(|node| node.kind == "Budget").count(), 1);
+    }
+
+    #[test]
+    fn test_suspended_conversation_holds_its_pending_questions() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        assert_eq!(tracker.suspended_at(), None);
+        assert!(!tracker.record_resumed().unwrap());
+
+        tracker.record_spend(0.25).unwrap();
+        tracker.record_suspended(100).unwrap();
+        tracker.record_suspended(200).unwrap();
+        assert_eq!(tracker.suspended_at(), Some(100));
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::Suspended
+        );
+        // the answers that were on their way are still recorded.
+        tracker.add_answer_node(&answer(questions[0], None)).unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::Suspended
+        );
+        let budget = tracker.conversation_budget().unwrap();
+        assert_eq!(budget.spent_usd, 0.25);
+        assert_eq!(budget.render(), "$0.2500 spent, suspended");
+
+        assert!(tracker.record_resumed().unwrap());
+        assert_eq!(tracker.suspended_at(), None);
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::QuestionsPartiallyAnswered
+        );
+        assert_eq!(tracker.get_unanswered_questions().unwrap().len(), 1);
+
+        // nothing is held back once every question is answered.
+        tracker.record_suspended(300).unwrap();
+        tracker.add_answer_node(&answer(questions[1], None)).unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::AllQuestionsAnswered
+        );
+    }
+
+    #[test]
+    fn test_clarification_holds_the_conversation_until_the_user_responds() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        tracker.add_answer_node(&answer(questions[0], None)).unwrap();
+        assert_eq!(tracker.record_clarification_response("ignored").unwrap(), None);
+
+        let options = vec!["webhook deliveries".to_string(), "payment refunds".to_string()];
+        tracker
+            .record_clarification(
+                questions[1].index(),
+                Clarification::new("Which retries do you mean?", &options),
+            )
+            .unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::AwaitingClarification
+        );
+        assert_eq!(
+            tracker.pending_clarification().map(|(question, _)| question),
+            Some(questions[1].index())
+        );
+        assert_eq!(tracker.get_unanswered_questions().unwrap()[0].clarification, None);
+
+        assert_eq!(
+            tracker.record_clarification_response(" the payment refunds ").unwrap(),
+            Some(questions[1].index())
+        );
+        assert_eq!(tracker.pending_clarification(), None);
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::QuestionsPartiallyAnswered
+        );
+        // the question is asked again with the response.
+        let unanswered = tracker.get_unanswered_questions().unwrap();
+        assert_eq!(unanswered.len(), 1);
+        assert_eq!(unanswered[0].clarification.as_deref(), Some("the payment refunds"));
+        assert!(tracker
+            .record_clarification(questions[0].index() + 100, Clarification::default())
+            .is_err());
+
+        tracker.add_answer_node(&answer(questions[1], None)).unwrap();
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::AllQuestionsAnswered
+        );
+    }
+
+    #[test]
+    fn test_timings_are_kept_on_the_question() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        assert_eq!(tracker.timing_summary(), None);
+
+        let timed = |question, answer_ms| {
+            let mut answer = answer(question, None);
+            answer.answer.timings = Some(PhaseTimings {
+                queue_wait_ms: 100,
+                retrieval_ms: 8_000,
+                answer_ms,
+                persistence_ms: 0,
+            });
+            answer
+        };
+        tracker.add_answer_node(&timed(questions[0], 41_000)).unwrap();
+        // a retry replaces the timings of the previous answer.
+        tracker.add_answer_node(&timed(questions[0], 12_000)).unwrap();
+        tracker.add_answer_node(&answer(questions[1], None)).unwrap();
+        let timings = tracker.record_persistence_time(questions[0].index(), 35).unwrap();
+        assert_eq!(timings.answer_ms, 12_000);
+        assert_eq!(timings.persistence_ms, 35);
+
+        assert_eq!(tracker.question_timings(questions[0].index()), Some(timings));
+        assert_eq!(tracker.question_timings(questions[1].index()), None);
+        assert_eq!(
+            tracker.check_question_completion(),
+            ConversationProcessingStage::AllQuestionsAnswered
+        );
+
+        let summary = tracker.collect_conversation_messages_page(0, 10).unwrap().timings.unwrap();
+        assert_eq!(summary.questions.len(), 1);
+        assert_eq!(summary.questions[0].question, "q1");
+        assert_eq!(summary.breakdown, "queued 100ms, retrieval 8.0s, answering 12s, saving 35ms");
+        assert!(matches!(
+            tracker.record_persistence_time(questions[0].index() + 100, 35),
+            Err(NodeError::InvalidQuestionNode)
+        ));
+    }
+
+    #[test]
+    fn test_scope_and_its_violations_are_recorded() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        assert!(tracker.scope().is_empty());
+        tracker.record_scope(&["src/refunds".to_string()]).unwrap();
+        assert_eq!(tracker.scope(), vec!["src/refunds".to_string()]);
+
+        let mut violating = answer(questions[0], None);
+        violating.answer.scope_violations = vec![ScopeViolation {
+            path: "src/invoices/pdf.rs".to_string(),
+            reason: crate::answer_scope::ScopeViolationReason::OutOfScope,
+            statement: "Modify `src/invoices/pdf.rs`.".to_string(),
+            rewritten: true,
+        }];
+        tracker.add_answer_node(&violating).unwrap();
+        tracker.add_answer_node(&answer(questions[1], None)).unwrap();
+        assert_eq!(
+            tracker.question_scope_violations(questions[0].index()),
+            violating.answer.scope_violations
+        );
+        assert!(tracker.question_scope_violations(questions[1].index()).is_empty());
+
+        // a retry without violations clears the ones of the previous answer.
+        tracker.add_answer_node(&answer(questions[0], None)).unwrap();
+        assert!(tracker.question_scope_violations(questions[0].index()).is_empty());
+    }
+
+    #[test]
+    fn test_freshness_is_recorded_on_the_root() {
+        use crate::freshness::FreshnessStatus;
+
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        assert_eq!(tracker.freshness(), None);
+        tracker.record_freshness(FreshnessNote::unknown("main")).unwrap();
+        assert_eq!(tracker.freshness_banner(), None);
+
+        let stale = FreshnessNote {
+            status: FreshnessStatus::Stale,
+            branch: "main".to_string(),
+            indexed_commit: "abc1234def".to_string(),
+            age_days: Some(9),
+            commits_behind: Some(42),
+        };
+        tracker.record_freshness(stale.clone()).unwrap();
+        assert_eq!(tracker.freshness(), Some(stale));
+        assert_eq!(
+            tracker.freshness_banner().as_deref(),
+            Some("Answers are based on commit abc1234, 9 days / 42 commits behind main.")
+        );
+    }
+
+    #[test]
+    fn test_regenerated_change_plan_replaces_the_previous_one() {
+        let (mut tracker, _) = tracker_with_questions(&["q1"]);
+        assert_eq!(tracker.change_plan(), None);
+        let first = ChangePlan {
+            tasks: vec!["Configure the retries".to_string()],
+            ..Default::default()
+        };
+        tracker.record_change_plan(first.clone()).unwrap();
+        assert_eq!(tracker.change_plan(), Some(first));
+
+        let nodes = tracker.graph.as_ref().unwrap().node_count();
+        let second = ChangePlan {
+            tasks: vec!["Configure the retries per collection".to_string()],
+            ..Default::default()
+        };
+        tracker.record_change_plan(second.clone()).unwrap();
+        assert_eq!(tracker.change_plan(), Some(second));
+        assert_eq!(tracker.graph.as_ref().unwrap().node_count(), nodes);
+    }
+
+    #[test]
+    fn test_cited_symbols_are_kept_in_one_glossary_on_the_root() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        let cited = |question: NodeIndex, text: &str| {
+            let mut cited = answer(question, None);
+            cited.answer.answer = text.to_string();
+            cited.answer.citations = CitationReport::parse(text);
+            for citation in &mut cited.answer.citations.citations {
+                citation.status = crate::citations::CitationStatus::Valid;
+                citation.enclosing_symbol = Some("commit_chunks".to_string());
+            }
+            cited
+        };
+        tracker
+            .add_answer_node(&cited(
+                questions[0],
+                "[`commit_chunks`](src/index.rs#L10-L20) retries the upsert three times.",
+            ))
+            .unwrap();
+        let nodes = tracker.graph.as_ref().unwrap().node_count();
+        tracker
+            .add_answer_node(&cited(
+                questions[1],
+                "[`commit_chunks`](src/index.rs#L12) drops the batch on the first failure.",
+            ))
+            .unwrap();
+
+        let glossary = tracker.glossary();
+        assert_eq!(glossary.entries.len(), 1);
+        assert_eq!(glossary.entries[0].question_id, Some(questions[0].index()));
+        assert_eq!(
+            glossary.entries[0].conflicts[0].description,
+            "`commit_chunks` drops the batch on the first failure."
+        );
+        // the answer, its citations and no second glossary.
+        assert_eq!(tracker.graph.as_ref().unwrap().node_count(), nodes + 2);
+    }
+
+    #[test]
+    fn test_feedback_is_kept_on_the_rated_answer() {
+        let (mut tracker, questions) = tracker_with_questions(&["q1", "q2"]);
+        assert!(matches!(
+            tracker.record_feedback(questions[0].index(), Rating::Up, None, vec![]),
+            Err(NodeError::NodeNotFound(_))
+        ));
+
+        let not_found = answer(
+            questions[0],
+            Some(AnswerOutcome::NotFound {
+                attempted_queries: vec!["refund retry".to_string()],
+                closest_paths: vec![],
+            }),
+        );
+        tracker.add_answer_node(&not_found).unwrap();
+        tracker.add_answer_node(&answer(questions[1], None)).unwrap();
+        let first = tracker
+            .record_feedback(questions[0].index(), Rating::Down, Some(" ".to_string()), vec![])
+            .unwrap();
+        assert_eq!((first.answer_version, first.comment), (1, None));
+
+        // the retry is the second version, the rating of the first one is kept.
+        tracker
+            .supersede_not_found_answer(questions[0].index(), "reformulated q1")
+            .unwrap();
+        tracker.add_answer_node(&answer(questions[0], None)).unwrap();
+        let second = tracker
+            .record_feedback(
+                questions[0].index(),
+                Rating::Down,
+                Some("The retries are in the queue".to_string()),
+                vec!["src/refund/queue.rs".to_string()],
+            )
+            .unwrap();
+        assert_eq!(second.answer_version, 2);
+        tracker
+            .record_feedback(questions[1].index(), Rating::Up, None, vec![])
+            .unwrap();
+
+        let rated = tracker.question_feedback(questions[0].index()).unwrap();
+        assert_eq!((rated.up, rated.down), (0, 2));
+        assert_eq!(rated.question, "reformulated q1");
+        assert_eq!(rated.latest, second);
+
+        let summary = tracker.feedback_summary();
+        assert_eq!((summary.up, summary.down), (1, 2));
+        assert_eq!(summary.negative_questions, 1);
+        let cases = summary.eval_cases("repo", "c1");
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].relevant, vec!["src/refund/queue.rs".to_string()]);
+        assert!(matches!(
+            tracker.record_feedback(questions[1].index() + 100, Rating::Up, None, vec![]),
+            Err(NodeError::InvalidQuestionNode)
+        ));
+    }
+
+    #[test]
+    fn test_task_grounding_is_kept_on_the_task_node() {
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.initialize_graph();
+        tracker
+            .add_user_conversation(Message::user("retry failed charges"))
+            .unwrap();
+        let grounding = TaskGrounding {
+            grounded: false,
+            repaired: false,
+            mentions: vec![MentionMatch {
+                mention: "PaymentGateway".to_string(),
+                component: Some("src/api/routes.rs".to_string()),
+                confidence: 0.3,
+            }],
+        };
+        let task = |text: &str, grounding: Option<TaskGrounding>| Task {
+            task: text.to_string(),
+            subtasks: vec![Subtask {
+                subtask: "subtask".to_string(),
+                questions: vec!["q1".to_string()],
+            }],
+            grounding,
+        };
+        tracker
+            .integrate_tasks(TaskList {
+                tasks: Some(vec![
+                    task("Add a backoff to the PaymentGateway", Some(grounding.clone())),
+                    task("Log the retries", None),
+                ]),
+                ask_user: None,
+            })
+            .unwrap();
+
+        let tasks = tracker.get_current_tasks().unwrap().tasks.unwrap();
+        let ungrounded = tasks
+            .iter()
+            .find(|t| t.task == "Add a backoff to the PaymentGateway")
+            .unwrap();
+        assert_eq!(ungrounded.grounding.as_ref(), Some(&grounding));
+        assert_eq!(ungrounded.subtasks.len(), 1);
+        let plain = tasks.iter().find(|t| t.task == "Log the retries").unwrap();
+        assert!(plain.grounding.is_none());
+        // the grounding node isn't a question of the task.
+        assert_eq!(tracker.get_unanswered_questions().unwrap().len(), 2);
+        let export = tracker.export_graph().unwrap();
+        assert_eq!(export.nodes.iter().filter(|node| node.kind == "Grounding").count(), 1);
+    }
 }
@@ -12,7 +12,7 @@ impl TrackProcessV1 {
 
         // Use the UUID from the root node as part of the key.
         if let Some(uuid) = self.get_root_node_uuid() {
-            let key = format!("taskprocess:{}", uuid);
+            let key = task_process_key(&uuid);
             let value = serde_json::to_string(self)?;
             conn.set(&key, value)?;
             info!("TaskProcess saved to Redis with UUID: {}", uuid.to_string());
@@ -28,9 +28,16 @@ impl TrackProcessV1 {
         }
     }
 }
+
+/// Key of the graph of the conversation `uuid`, the graph is gone once the conversation was
+/// archived.
+pub fn task_process_key(uuid: &str) -> String {
+    format!("taskprocess:{}", uuid)
+}
+
  /// Reads and deserializes a TaskProcessV1 instance from Redis by UUID.
  pub fn load_task_process_from_redis(url: &str, uuid: &str) -> Result<TrackProcessV1> {
-    let key = format!("taskprocess:{}", uuid);
+    let key = task_process_key(uuid);
     let mut conn = establish_redis_connection(url)?;
     let value: String = conn.get(&key)?;
     let task_process: TrackProcessV1 = serde_json::from_str(&value)?;
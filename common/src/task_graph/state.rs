@@ -1,17 +1,56 @@
 use crate::llm_gateway::api::Messages;
-use ai_gateway::message::message::Message;
+use ai_gateway::message::message::{Message, MessageRole};
 use crate::task_graph::add_node::NodeError;
+use crate::task_graph::export::AnswerCitations;
 use crate::task_graph::graph_model::EdgeV1;
-use crate::task_graph::graph_model::{NodeV1, QuestionWithAnswer, TrackProcessV1};
+use crate::task_graph::graph_model::{NodeV1, QuestionWithAnswer, QuestionWithId, TrackProcessV1};
 use crate::CodeContext;
 use log::debug;
 use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::Dfs;
 use petgraph::visit::EdgeRef;
 use petgraph::Direction;
+use serde::{Deserialize, Serialize};
 
+use crate::files_involved::files_involved;
+use crate::grounding::TaskGrounding;
 use crate::models::{Subtask, Task, TaskList};
-use crate::CodeUnderstanding;
+use crate::preferences::Preferences;
+use crate::timings::TimingSummary;
+use crate::{AnswerOutcome, CodeUnderstanding};
+
+/// A page of the conversation messages, in conversation order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConversationMessagesPage {
+    pub messages: Vec<Message>,
+    pub offset: usize,
+    // number of messages in the whole conversation.
+    pub total: usize,
+    // offset of the next page, missing on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+    // preferences the user stated in the conversation.
+    #[serde(default, skip_serializing_if = "Preferences::is_empty")]
+    pub preferences: Preferences,
+    // where the time of the answered questions went so far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<TimingSummary>,
+    // warning that the answers are based on a stale index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freshness_banner: Option<String>,
+    // citations whose lines changed since they were cited, only set when the anchors were
+    // re-resolved on request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drifted_citations: Vec<AnswerCitations>,
+}
+
+/// Prior messages of a conversation to include when building an LLM prompt.
+#[derive(Debug, Clone, Default)]
+pub struct PromptHistory {
+    // messages beyond the most recent ones, to be summarized into a single note.
+    pub older: Vec<Message>,
+    pub recent: Vec<Message>,
+}
 
 /// Enum representing the various stages following the last conversation.
 #[derive(Debug, PartialEq)]
@@ -25,6 +64,10 @@ pub enum ConversationProcessingStage {
     SummarizeAnswers, // Move to this state after all the questions are answered, but yet to be summarized.
     AnswersSummarized, // Move to this state after all the questions are answered and then summarized.
     QuestionsPartiallyAnswered, // s
+    FollowUpPending, // A follow-up question was added after the tasks were answered, its answer is pending.
+    FollowUpAnswered, // The follow-up question on the last conversation node is answered.
+    Suspended, // Questions are pending but the client went away, they are answered once it is back.
+    AwaitingClarification, // The agent of a question stopped to ask the user a clarifying question, the next message of the user responds to it.
     Unknown,           // State cannot be determined or does not fit the other categories.
 }
 
@@ -42,28 +85,50 @@ impl TrackProcessV1 {
         let question_nodes = graph
             .node_indices()
             .filter_map(|node_idx| match graph.node_weight(node_idx) {
-                Some(NodeV1::Question(..)) => Some(node_idx),
+                Some(NodeV1::Question(..)) if !is_follow_up_question(graph, node_idx) => {
+                    Some(node_idx)
+                }
                 _ => None,
             })
             .collect::<Vec<NodeIndex>>();
 
         let mut answered_questions = 0;
+        let mut not_found_questions = 0;
 
         if question_nodes.is_empty() {
             debug!("No question nodes found in the graph.");
             return ConversationProcessingStage::GenerateTasksAndQuestions;
         }
 
+        // questions the agent couldn't find an answer for are resolved, they just don't have an answer.
         for question_node in &question_nodes {
-            let has_answer = graph
-                .edges_directed(*question_node, Direction::Outgoing)
-                .any(|edge| matches!(graph.node_weight(edge.target()), Some(NodeV1::Answer(..))));
-
-            if has_answer {
-                answered_questions += 1;
+            for edge in graph.edges_directed(*question_node, Direction::Outgoing) {
+                if !matches!(edge.weight(), EdgeV1::Answer) {
+                    continue;
+                }
+                match graph.node_weight(edge.target()) {
+                    Some(NodeV1::Answer(..)) => {
+                        answered_questions += 1;
+                        break;
+                    }
+                    Some(NodeV1::AnswerNotFound(..)) => {
+                        answered_questions += 1;
+                        not_found_questions += 1;
+                        break;
+                    }
+                    _ => {}
+                }
             }
         }
 
+        if not_found_questions > 0 {
+            debug!(
+                "{} of {} questions are resolved without an answer.",
+                not_found_questions,
+                question_nodes.len()
+            );
+        }
+
         if answered_questions == question_nodes.len() {
             // Check if there is an AnswerSummary node.
             let has_answer_summary = graph.node_indices().any(|node_idx| {
@@ -106,6 +171,20 @@ impl TrackProcessV1 {
 
                 // Proceed if there are more nodes beyond the root.
                 if let Some(last_conversation_node_id) = self.last_added_conversation_node {
+                    // Follow-up questions hang directly off the conversation node, no tasks are generated for them.
+                    let follow_up_question = graph
+                        .edges_directed(last_conversation_node_id, petgraph::Direction::Outgoing)
+                        .find(|edge| matches!(edge.weight(), EdgeV1::FollowUp))
+                        .map(|edge| edge.target());
+                    if let Some(question_node) = follow_up_question {
+                        let stage = if has_answer(graph, question_node) {
+                            ConversationProcessingStage::FollowUpAnswered
+                        } else {
+                            ConversationProcessingStage::FollowUpPending
+                        };
+                        return (self.suspended(stage), Some(last_conversation_node_id));
+                    }
+
                     // Check for the existence of a 'Process' edge to a Task node from the last conversation node.
                     let conversation_to_task_edge_exists = graph
                         .edges_directed(last_conversation_node_id, petgraph::Direction::Outgoing)
@@ -119,13 +198,20 @@ impl TrackProcessV1 {
                         // 1. TasksAndQuestionsGenerated - Tasks and questions are generated, but answers are pending.
                         // 2. AllQuestionsAnswered - Tasks are generated and all questions are answered.
                         // 3. QuestionsPartiallyAnswered - Tasks are generated and some questions are answered., but some are pending.
-                        self.check_question_completion()
+                        // Unless the agent of a question waits for the user to clarify it.
+                        match self.pending_clarification() {
+                            Some(_) => ConversationProcessingStage::AwaitingClarification,
+                            None => self.check_question_completion(),
+                        }
                     } else {
                         // If no Process edge to a Task node is found, we're awaiting user input or further actions.
                         ConversationProcessingStage::AwaitingUserInput
                     };
 
-                    (processing_stage, Some(last_conversation_node_id))
+                    (
+                        self.suspended(processing_stage),
+                        Some(last_conversation_node_id),
+                    )
                 } else {
                     // If there's no last conversation node ID available, the stage is unknown.
                     (ConversationProcessingStage::Unknown, None)
@@ -138,6 +224,21 @@ impl TrackProcessV1 {
         }
     }
 
+    // A stage with questions pending is suspended while the conversation is, the stages with
+    // nothing left to answer aren't held back.
+    fn suspended(&self, stage: ConversationProcessingStage) -> ConversationProcessingStage {
+        let pending = matches!(
+            stage,
+            ConversationProcessingStage::TasksAndQuestionsGenerated
+                | ConversationProcessingStage::QuestionsPartiallyAnswered
+                | ConversationProcessingStage::FollowUpPending
+        );
+        match self.suspended_at() {
+            Some(_) if pending => ConversationProcessingStage::Suspended,
+            _ => stage,
+        }
+    }
+
     /// Extracts a `TaskListResponse` by traversing the graph from the root node and collecting tasks.
     // Helper function to extract questions for a given subtask node.
     fn extract_questions(
@@ -233,6 +334,7 @@ impl TrackProcessV1 {
                     Some(Task {
                         task: task_description.clone(),
                         subtasks,
+                        grounding: task_grounding(graph, task_node),
                     })
                 } else {
                     None
@@ -291,6 +393,7 @@ impl TrackProcessV1 {
                 tasks.push(Task {
                     task: task_desc.clone(),
                     subtasks,
+                    grounding: task_grounding(graph, node_idx),
                 });
             }
         }
@@ -326,34 +429,88 @@ impl TrackProcessV1 {
             .collect())
     }
 
-    // Update the usage in get_current_questions_with_answers to handle the Result.
+    /// Builds the answer of a single question, `None` while the question is unanswered.
+    pub fn question_with_answer(
+        &self,
+        node_idx: NodeIndex,
+    ) -> Result<Option<QuestionWithAnswer>, NodeError> {
+        let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
+        let NodeV1::Question(question) = &graph[node_idx] else {
+            return Err(NodeError::InvalidQuestionNode);
+        };
+
+        let answer_edge = graph
+            .edges_directed(node_idx, petgraph::Direction::Outgoing)
+            .find(|edge| matches!(edge.weight(), EdgeV1::Answer));
+
+        let Some(edge) = answer_edge else {
+            return Ok(None);
+        };
+        match &graph[edge.target()] {
+            NodeV1::Answer(answer_text) => {
+                let contexts = self.get_contexts_for_answer(edge.target())?;
+                let citations = self.answer_citations(edge.target());
+
+                Ok(Some(QuestionWithAnswer {
+                    question_id: node_idx.index(),
+                    question: question.clone(),
+                    answer: CodeUnderstanding {
+                        context: contexts,
+                        question: question.clone(),
+                        answer: answer_text.clone(),
+                        outcome: None,
+                        missing_pinned_paths: vec![],
+                        cost_usd: None,
+                        timings: None,
+                        scope_violations: self.question_scope_violations(node_idx.index()),
+                        files_involved: files_involved(answer_text, &citations),
+                        // the index the conversation is answered against.
+                        index_mode: self
+                            .index_run()
+                            .map(|run| run.index_mode)
+                            .unwrap_or_default(),
+                        citations,
+                        verification: self.answer_verification(edge.target()),
+                        prompt_versions: self.answer_prompt_versions(edge.target()),
+                    },
+                }))
+            }
+            NodeV1::AnswerNotFound(attempted_queries, closest_paths) => Ok(Some(QuestionWithAnswer {
+                question_id: node_idx.index(),
+                question: question.clone(),
+                answer: CodeUnderstanding {
+                    context: vec![],
+                    question: question.clone(),
+                    answer: String::new(),
+                    outcome: Some(AnswerOutcome::NotFound {
+                        attempted_queries: attempted_queries.clone(),
+                        closest_paths: closest_paths.clone(),
+                    }),
+                    missing_pinned_paths: vec![],
+                    cost_usd: None,
+                    timings: None,
+                    scope_violations: vec![],
+                    citations: Default::default(),
+                    verification: Default::default(),
+                    prompt_versions: Default::default(),
+                    files_involved: Default::default(),
+                    index_mode: self.index_run().map(|run| run.index_mode).unwrap_or_default(),
+                },
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    // Collects the answered questions of the tasks, follow-up questions are left out.
     pub fn get_current_questions_with_answers(&self) -> Result<Vec<QuestionWithAnswer>, NodeError> {
         let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
 
         let mut questions_with_answers = Vec::new();
 
         for node_idx in graph.node_indices() {
-            if let NodeV1::Question(question) = &graph[node_idx] {
-                let answer_edge = graph
-                    .edges_directed(node_idx, petgraph::Direction::Outgoing)
-                    .find(|edge| matches!(edge.weight(), EdgeV1::Answer));
-
-                if let Some(edge) = answer_edge {
-                    if let NodeV1::Answer(answer_text) = &graph[edge.target()] {
-                        // Handle the error or unwrap the result.
-                        let contexts = self.get_contexts_for_answer(edge.target())?;
-
-                        let question_with_answer = QuestionWithAnswer {
-                            question_id: node_idx.index(),
-                            question: question.clone(),
-                            answer: CodeUnderstanding {
-                                context: contexts,
-                                question: question.clone(),
-                                answer: answer_text.clone(),
-                            },
-                        };
-                        questions_with_answers.push(question_with_answer);
-                    }
+            if matches!(graph[node_idx], NodeV1::Question(_)) && !is_follow_up_question(graph, node_idx) {
+                if let Some(question_with_answer) = self.question_with_answer(node_idx)? {
+                    questions_with_answers.push(question_with_answer);
                 }
             }
         }
@@ -361,32 +518,155 @@ impl TrackProcessV1 {
         Ok(questions_with_answers)
     }
 
-    // collects the history of conversations in the form of Messages, which is the
-    // desired format for the response to the user.
-    pub fn collect_conversation_messages(&self) -> Result<Messages, NodeError> {
-        // Verify that the graph is initialized and the root node exists.
+    // Collects the answered follow-up questions in the order they were asked.
+    pub fn get_follow_ups(&self) -> Result<Vec<QuestionWithAnswer>, NodeError> {
         let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
 
-        let mut messages = Vec::new();
+        let mut follow_ups = Vec::new();
+        for node_idx in graph.node_indices() {
+            if matches!(graph[node_idx], NodeV1::Question(_)) && is_follow_up_question(graph, node_idx) {
+                if let Some(question_with_answer) = self.question_with_answer(node_idx)? {
+                    follow_ups.push(question_with_answer);
+                }
+            }
+        }
+
+        Ok(follow_ups)
+    }
+
+    // Finds the follow-up question hanging off the last conversation node.
+    pub fn last_follow_up_question(&self) -> Option<QuestionWithId> {
+        let graph = self.graph.as_ref()?;
+        let conversation_node = self.last_added_conversation_node?;
+        graph
+            .edges_directed(conversation_node, petgraph::Direction::Outgoing)
+            .filter(|edge| matches!(edge.weight(), EdgeV1::FollowUp))
+            .find_map(|edge| match &graph[edge.target()] {
+                NodeV1::Question(question) => Some(QuestionWithId {
+                    id: edge.target().index(),
+                    text: question.clone(),
+                    clarification: None,
+                }),
+                _ => None,
+            })
+    }
+
+    // Finds the answer of the follow-up question on the last conversation node.
+    pub fn last_follow_up_answer(&self) -> Result<Option<QuestionWithAnswer>, NodeError> {
+        match self.last_follow_up_question() {
+            Some(question) => self.question_with_answer(NodeIndex::new(question.id)),
+            None => Ok(None),
+        }
+    }
 
-        for node_index in graph.node_indices() {
-            if let Some(NodeV1::Conversation(source, message, _)) = graph.node_weight(node_index) {
-                // Depending on the message variant, extract the content.
-                let message_content = match message {
-                    Message::PlainText { content, .. } => content,
-                    //Message::FunctionReturn { content, .. } => content,
-                    // Here, it's treated as an empty string or you can choose to skip adding to messages.
-                    _ => "",
-                };
+    // Follows the NextConversation edges from the root to list the conversation nodes in
+    // the order the messages were exchanged, regardless of the node insertion order.
+    pub fn conversation_node_chain(&self) -> Result<Vec<NodeIndex>, NodeError> {
+        let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
+        let mut current_node = self.root_node.ok_or(NodeError::RootNodeNotFound)?;
 
-                let message = Message::new_text(*source, message_content);
-                messages.push(message);
+        let mut chain = Vec::new();
+        // the chain can't be longer than the graph, this guards against a corrupted cycle.
+        while chain.len() < graph.node_count() {
+            let next_node = graph
+                .edges_directed(current_node, Direction::Outgoing)
+                .find(|edge| matches!(edge.weight(), EdgeV1::NextConversation))
+                .map(|edge| edge.target());
+            match next_node {
+                Some(next_node) => {
+                    chain.push(next_node);
+                    current_node = next_node;
+                }
+                None => break,
             }
         }
 
+        Ok(chain)
+    }
+
+    // collects the history of conversations in the form of Messages, which is the
+    // desired format for the response to the user.
+    pub fn collect_conversation_messages(&self) -> Result<Messages, NodeError> {
+        let graph = self.graph.as_ref().ok_or(NodeError::GraphNotInitialized)?;
+
+        let messages = self
+            .conversation_node_chain()?
+            .into_iter()
+            .filter_map(|node_index| match graph.node_weight(node_index) {
+                Some(NodeV1::Conversation(source, message, _)) => {
+                    Some(conversation_message(*source, message))
+                }
+                _ => None,
+            })
+            .collect();
+
         Ok(Messages { messages })
     }
 
+    // Returns `limit` conversation messages starting at `offset`, in conversation order.
+    pub fn collect_conversation_messages_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<ConversationMessagesPage, NodeError> {
+        let messages = self.collect_conversation_messages()?.messages;
+        let total = messages.len();
+        let page = messages
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect::<Vec<_>>();
+        let end = offset.saturating_add(page.len());
+
+        Ok(ConversationMessagesPage {
+            messages: page,
+            offset,
+            total,
+            next_offset: if end < total { Some(end) } else { None },
+            preferences: self.preferences(),
+            timings: self.timing_summary(),
+            freshness_banner: self.freshness_banner(),
+            drifted_citations: Vec::new(),
+        })
+    }
+
+    // Splits the user and assistant messages of the conversation into the `max_recent` most recent
+    // ones, which are sent to the LLM as they are, and the older ones that have to be summarized.
+    // System messages hold the prompts that produced the answers and are left out.
+    pub fn prompt_history(&self, max_recent: usize) -> Result<PromptHistory, NodeError> {
+        let mut older = self
+            .collect_conversation_messages()?
+            .messages
+            .into_iter()
+            .filter(|message| match message {
+                Message::PlainText { role, content } => {
+                    (role.is_user() || role.is_assistant()) && !content.is_empty()
+                }
+                _ => false,
+            })
+            .collect::<Vec<_>>();
+        let recent = older.split_off(older.len().saturating_sub(max_recent));
+
+        Ok(PromptHistory { older, recent })
+    }
+
+    // Finds the content of the most recent user message in the conversation.
+    pub fn last_user_query(&self) -> Option<String> {
+        let graph = self.graph.as_ref()?;
+        self.conversation_node_chain()
+            .ok()?
+            .into_iter()
+            .rev()
+            .find_map(|node_index| match graph.node_weight(node_index) {
+                Some(NodeV1::Conversation(source, Message::PlainText { content, .. }, _))
+                    if source.is_user() =>
+                {
+                    Some(content.clone())
+                }
+                _ => None,
+            })
+    }
+
     // print the nodes and edges of the graph in a hierarchical manner.
     pub fn print_graph_hierarchy(&self) {
         // If the graph is not initialized, return early.
@@ -420,3 +700,36 @@ impl TrackProcessV1 {
         }
     }
 }
+
+// Follow-up questions are connected to a conversation node instead of a subtask.
+pub(crate) fn is_follow_up_question(graph: &DiGraph<NodeV1, EdgeV1>, question_node: NodeIndex) -> bool {
+    graph
+        .edges_directed(question_node, Direction::Incoming)
+        .any(|edge| matches!(edge.weight(), EdgeV1::FollowUp))
+}
+
+// Whether the question is resolved, either with an answer or as not found.
+fn has_answer(graph: &DiGraph<NodeV1, EdgeV1>, question_node: NodeIndex) -> bool {
+    graph
+        .edges_directed(question_node, Direction::Outgoing)
+        .any(|edge| matches!(edge.weight(), EdgeV1::Answer))
+}
+
+// The grounding of the task, None for tasks generated before they were grounded.
+fn task_grounding(graph: &DiGraph<NodeV1, EdgeV1>, task_node: NodeIndex) -> Option<TaskGrounding> {
+    graph
+        .edges_directed(task_node, Direction::Outgoing)
+        .find_map(|edge| match &graph[edge.target()] {
+            NodeV1::Grounding(grounding) => Some(grounding.clone()),
+            _ => None,
+        })
+}
+
+// Converts a conversation node message into a plain text message, only plain text is kept.
+fn conversation_message(source: MessageRole, message: &Message) -> Message {
+    let content = match message {
+        Message::PlainText { content, .. } => content.as_str(),
+        _ => "",
+    };
+    Message::new_text(source, content)
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::env;
+
+use anyhow::{anyhow, Result};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{field, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+// Collector the spans are exported to over OTLP/gRPC, e.g. `http://otel-collector:4317`.
+// Tracing is disabled when it isn't set.
+pub const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+// Share of the new traces that are recorded, between 0.0 and 1.0. Requests that join a trace
+// started upstream follow the sampling decision of the caller.
+pub const SAMPLING_RATIO_ENV: &str = "OTEL_TRACES_SAMPLER_ARG";
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryConfig {
+    pub otlp_endpoint: Option<String>,
+    pub sampling_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            sampling_ratio: 1.0,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn from_env() -> Result<Self> {
+        let otlp_endpoint = env::var(OTLP_ENDPOINT_ENV)
+            .ok()
+            .filter(|endpoint| !endpoint.trim().is_empty());
+        let sampling_ratio = match env::var(SAMPLING_RATIO_ENV) {
+            Ok(ratio) => ratio
+                .parse::<f64>()
+                .ok()
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                .ok_or_else(|| anyhow!("{} must be between 0.0 and 1.0", SAMPLING_RATIO_ENV))?,
+            Err(_) => 1.0,
+        };
+
+        Ok(Self {
+            otlp_endpoint,
+            sampling_ratio,
+        })
+    }
+}
+
+/// Installs the OTLP exporter configured through the env, call it after the env file was loaded.
+/// Returns false without installing anything when no endpoint is configured.
+pub fn init(service_name: &str) -> Result<bool> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let config = TelemetryConfig::from_env()?;
+    let Some(endpoint) = config.otlp_endpoint else {
+        log::info!("{} is not set, traces are not exported", OTLP_ENDPOINT_ENV);
+        return Ok(false);
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    config.sampling_ratio,
+                ))))
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service_name.to_string(),
+                )])),
+        )
+        .install_batch(runtime::Tokio)?;
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+
+    log::info!(
+        "Exporting traces of {} to {} with a sampling ratio of {}",
+        service_name,
+        endpoint,
+        config.sampling_ratio
+    );
+    Ok(true)
+}
+
+/// Flushes the spans that haven't been exported yet, call it before the service exits.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Span around an incoming warp request, used with `warp::trace(telemetry::request_span)`.
+/// The span joins the trace of the caller when the request carries a `traceparent` header.
+pub fn request_span(info: warp::trace::Info) -> Span {
+    let request_id = info
+        .request_headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        otel.kind = "server",
+        http.method = %info.method(),
+        http.route = %info.path(),
+        request_id = %request_id,
+    );
+    span.set_parent(global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(info.request_headers()))
+    }));
+    span
+}
+
+/// Adds the `traceparent` header of the current span to an outbound request, so the service
+/// that receives it joins the same trace.
+pub fn propagate(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    trace_headers(&Span::current())
+        .into_iter()
+        .fold(request, |request, (name, value)| request.header(name, value))
+}
+
+/// Trace context headers of the span, empty when the span isn't recorded.
+pub fn trace_headers(span: &Span) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut headers)
+    });
+    headers
+}
+
+/// Span around an LLM call, the token counts are recorded once the call returned.
+pub fn llm_span(provider: &str, model: &str) -> Span {
+    tracing::info_span!(
+        "llm_call",
+        otel.kind = "client",
+        llm.provider = %provider,
+        llm.model = %model,
+        llm.prompt_tokens = field::Empty,
+        llm.completion_tokens = field::Empty,
+    )
+}
+
+/// Span around a qdrant or quickwit query, named like the `db_query_duration_seconds` labels.
+pub fn db_span(backend: &str, operation: &str) -> Span {
+    tracing::info_span!(
+        "db_query",
+        otel.kind = "client",
+        db.system = %backend,
+        db.operation = %operation,
+    )
+}
+
+struct HeaderExtractor<'a>(&'a warp::http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use warp::Filter;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    // Records every span in memory, the global subscriber is left untouched.
+    fn test_subscriber() -> impl tracing::Subscriber + Send + Sync {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = TracerProvider::builder().build().tracer("test");
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    fn trace_id(span: &Span) -> String {
+        span.context().span().span_context().trace_id().to_string()
+    }
+
+    #[test]
+    fn test_outgoing_requests_carry_traceparent() {
+        tracing::subscriber::with_default(test_subscriber(), || {
+            let span = tracing::info_span!("suggest");
+            let _entered = span.enter();
+
+            let request = propagate(reqwest::Client::new().get("http://code-search/symbols"))
+                .build()
+                .unwrap();
+            let traceparent = request
+                .headers()
+                .get("traceparent")
+                .expect("traceparent header is missing")
+                .to_str()
+                .unwrap();
+            assert!(traceparent.starts_with(&format!("00-{}-", trace_id(&span))));
+        });
+
+        // without a recorded span there is nothing to propagate.
+        let request = propagate(reqwest::Client::new().get("http://code-search/symbols"))
+            .build()
+            .unwrap();
+        assert!(request.headers().get("traceparent").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_incoming_traceparent_is_honored() {
+        let _guard = tracing::subscriber::set_default(test_subscriber());
+        let route = warp::path("suggest")
+            .map(|| trace_id(&Span::current()))
+            .with(warp::trace(request_span));
+
+        let response = warp::test::request()
+            .path("/suggest")
+            .header("traceparent", format!("00-{}-00f067aa0ba902b7-01", TRACE_ID))
+            .header(REQUEST_ID_HEADER, "req-1")
+            .reply(&route)
+            .await;
+        assert_eq!(response.body(), TRACE_ID);
+
+        // requests without a traceparent start a new trace.
+        let response = warp::test::request().path("/suggest").reply(&route).await;
+        assert_ne!(response.body(), TRACE_ID);
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        env::remove_var(OTLP_ENDPOINT_ENV);
+        env::set_var(SAMPLING_RATIO_ENV, "0.25");
+        assert_eq!(
+            TelemetryConfig::from_env().unwrap(),
+            TelemetryConfig {
+                otlp_endpoint: None,
+                sampling_ratio: 0.25,
+            }
+        );
+
+        env::set_var(SAMPLING_RATIO_ENV, "2");
+        assert!(TelemetryConfig::from_env().is_err());
+        env::remove_var(SAMPLING_RATIO_ENV);
+    }
+}
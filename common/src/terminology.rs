@@ -0,0 +1,444 @@
+// Jargon of a repo. Users ask about "helios" when the code says "billing engine", and the keywords
+// and the embedding of the question never meet the code. A repo can have a dictionary of terms and
+// their expansions: code search appends the expansions of the terms a query mentions to the
+// embedded text and to the keyword query, where the hits found only through them weigh less, and
+// code understanding puts the term in parentheses after the first mention of an expansion in the
+// answer. Ingestion mines "X (also known as Y)" in the READMEs and docs for candidate terms, which
+// are only used once approved into the dictionary.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// Collection of the dictionaries, one point per repo.
+pub const TERMINOLOGY_COLLECTION_NAME: &str = "repo_terminology";
+/// Path of the document holding the terms mined by ingestion, in the quickwit index of the repo.
+pub const TERMINOLOGY_SUGGESTIONS_PATH: &str = ".incredible/terminology-suggestions.json";
+/// Language of the suggestions document, it isn't parsed like source code.
+pub const TERMINOLOGY_SUGGESTIONS_LANGUAGE: &str = "TERMINOLOGY_SUGGESTIONS";
+/// Expansions of a term added to a query, the first ones of the dictionary.
+pub const MAX_EXPANSIONS_PER_TERM: usize = 3;
+/// Expansions added to a query over all of its terms.
+pub const MAX_QUERY_EXPANSIONS: usize = 6;
+/// Multiplier of the keyword score of the files found only through an expansion.
+pub const EXPANSION_KEYWORD_WEIGHT: f32 = 0.5;
+
+lazy_static! {
+    // `Helios (also known as the billing engine)`, `WF (aka workflow)`, `Helios, also known as the
+    // billing engine,`.
+    static ref ALSO_KNOWN_AS: Regex = Regex::new(
+        r"(?i)\b([A-Za-z][\w-]*)\s*(?:\(|,\s*)(?:also known as|also called|a\.?k\.?a\.?)\s+(?:the |an? )?([^(),.;:\n]{2,60}?)\s*[),.;:\n]"
+    )
+    .unwrap();
+}
+
+/// Terms of a repo and what they stand for, e.g. `helios` -> `billing engine`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Terminology {
+    // expansions by term, in the order they are added to the queries.
+    pub terms: BTreeMap<String, Vec<String>>,
+}
+
+/// A term of a query and the expansions added for it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueryExpansion {
+    pub term: String,
+    pub expansions: Vec<String>,
+}
+
+impl QueryExpansion {
+    pub fn render(&self) -> String {
+        format!("{} -> {}", self.term, self.expansions.join(", "))
+    }
+}
+
+/// A term mined from the docs of a repo, used once approved into its dictionary.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TermSuggestion {
+    pub term: String,
+    pub expansion: String,
+    pub path: String,
+    // 1-based line of the mention.
+    pub line: usize,
+}
+
+/// The dictionary of a repo with the suggestions it doesn't have yet, as the admin endpoint
+/// returns them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RepoTerminology {
+    pub repo_name: String,
+    #[serde(flatten)]
+    pub terminology: Terminology,
+    #[serde(default)]
+    pub suggestions: Vec<TermSuggestion>,
+}
+
+// Byte ranges of `needle` in `haystack` as a whole word, ignoring ASCII case.
+fn word_matches(haystack: &str, needle: &str) -> Vec<Range<usize>> {
+    let needle = needle.trim();
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    let lower = haystack.to_ascii_lowercase();
+    let needle = needle.to_ascii_lowercase();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    lower
+        .match_indices(&needle)
+        .map(|(start, _)| start..start + needle.len())
+        .filter(|range| {
+            !lower[..range.start]
+                .chars()
+                .next_back()
+                .map_or(false, is_word)
+                && !lower[range.end..].chars().next().map_or(false, is_word)
+        })
+        .collect()
+}
+
+// Byte ranges of the fenced code blocks and inline code of a markdown answer.
+fn code_spans(text: &str) -> Vec<Range<usize>> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    let mut fence_start: Option<usize> = None;
+    for line in text.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            match fence_start.take() {
+                Some(start) => spans.push(start..offset + line.len()),
+                None => fence_start = Some(offset),
+            }
+        } else if fence_start.is_none() {
+            let mut inline_start: Option<usize> = None;
+            for (i, c) in line.char_indices() {
+                if c == '`' {
+                    match inline_start.take() {
+                        Some(start) => spans.push(offset + start..offset + i + 1),
+                        None => inline_start = Some(i),
+                    }
+                }
+            }
+        }
+        offset += line.len();
+    }
+    if let Some(start) = fence_start {
+        spans.push(start..text.len());
+    }
+    spans
+}
+
+impl Terminology {
+    /// Trims the terms and expansions, drops the empty ones and the expansions repeating their
+    /// term.
+    pub fn normalized(self) -> Self {
+        let terms = self
+            .terms
+            .into_iter()
+            .map(|(term, expansions)| {
+                let term = term.trim().to_string();
+                let mut kept: Vec<String> = Vec::new();
+                for expansion in expansions.iter().map(|expansion| expansion.trim()) {
+                    if !expansion.is_empty()
+                        && !expansion.eq_ignore_ascii_case(&term)
+                        && !kept
+                            .iter()
+                            .any(|other| other.eq_ignore_ascii_case(expansion))
+                    {
+                        kept.push(expansion.to_string());
+                    }
+                }
+                (term, kept)
+            })
+            .filter(|(term, expansions)| !term.is_empty() && !expansions.is_empty())
+            .collect();
+        Self { terms }
+    }
+
+    /// The expansions of the terms the query mentions as whole words, at most
+    /// `MAX_EXPANSIONS_PER_TERM` per term and `MAX_QUERY_EXPANSIONS` in all. Expansions the query
+    /// already mentions aren't added.
+    pub fn expand(&self, query: &str) -> Vec<QueryExpansion> {
+        let mut left = MAX_QUERY_EXPANSIONS;
+        let mut mentioned = self
+            .terms
+            .iter()
+            .filter_map(|(term, expansions)| {
+                let first = word_matches(query, term).first()?.start;
+                Some((first, term, expansions))
+            })
+            .collect::<Vec<_>>();
+        // the terms mentioned first get the room first.
+        mentioned.sort_by_key(|(first, _, _)| *first);
+        mentioned
+            .into_iter()
+            .filter_map(|(_, term, expansions)| {
+                let expansions = expansions
+                    .iter()
+                    .filter(|expansion| word_matches(query, expansion).is_empty())
+                    .take(MAX_EXPANSIONS_PER_TERM.min(left))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                left -= expansions.len();
+                (!expansions.is_empty()).then(|| QueryExpansion {
+                    term: term.clone(),
+                    expansions,
+                })
+            })
+            .collect()
+    }
+
+    /// Puts the term in parentheses after the first mention of one of its expansions, e.g.
+    /// "the billing engine (helios)". Code blocks and inline code are left as they are, as are the
+    /// mentions already followed by the term.
+    pub fn annotate_answer(&self, answer: &str) -> String {
+        let code = code_spans(answer);
+        let in_code = |range: &Range<usize>| {
+            code.iter()
+                .any(|span| span.start <= range.start && range.end <= span.end)
+        };
+        let mut insertions = self
+            .terms
+            .iter()
+            .filter_map(|(term, expansions)| {
+                let first = expansions
+                    .iter()
+                    .filter_map(|expansion| {
+                        word_matches(answer, expansion)
+                            .into_iter()
+                            .find(|range| !in_code(range))
+                    })
+                    .min_by_key(|range| range.start)?;
+                let annotation = format!(" ({})", term);
+                let annotated = answer[first.end..]
+                    .to_ascii_lowercase()
+                    .starts_with(&annotation.to_ascii_lowercase());
+                (!annotated).then_some((first.end, annotation))
+            })
+            .collect::<Vec<_>>();
+        // from the end, so the positions of the earlier insertions hold.
+        insertions.sort_by(|a, b| b.0.cmp(&a.0));
+        let mut annotated = answer.to_string();
+        for (position, annotation) in insertions {
+            annotated.insert_str(position, &annotation);
+        }
+        annotated
+    }
+
+    /// The suggestions whose term isn't in the dictionary, one per term.
+    pub fn pending(&self, suggestions: Vec<TermSuggestion>) -> Vec<TermSuggestion> {
+        let mut pending: Vec<TermSuggestion> = Vec::new();
+        for suggestion in suggestions {
+            let known = self
+                .terms
+                .keys()
+                .chain(pending.iter().map(|other| &other.term))
+                .any(|term| term.eq_ignore_ascii_case(&suggestion.term));
+            if !known {
+                pending.push(suggestion);
+            }
+        }
+        pending
+    }
+}
+
+/// The text embedded for a query: the query followed by the expansions of its terms.
+pub fn expanded_text(query: &str, expansions: &[QueryExpansion]) -> String {
+    let mut text = query.to_string();
+    for expansion in expansions
+        .iter()
+        .flat_map(|expansion| &expansion.expansions)
+    {
+        text.push(' ');
+        text.push_str(expansion);
+    }
+    text
+}
+
+pub fn render_expansions(expansions: &[QueryExpansion]) -> String {
+    expansions
+        .iter()
+        .map(QueryExpansion::render)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Whether terms are mined from the file: the READMEs and the markdown files under `docs/`.
+pub fn is_terminology_source(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    let file_name = lower.rsplit('/').next().unwrap_or_default();
+    file_name.starts_with("readme")
+        || ((lower.starts_with("docs/") || lower.contains("/docs/"))
+            && (lower.ends_with(".md") || lower.ends_with(".mdx")))
+}
+
+/// The terms the document introduces as "X (also known as Y)", "X (aka Y)" or "X, also known
+/// as Y".
+pub fn mine_suggestions(path: &str, content: &str) -> Vec<TermSuggestion> {
+    let mut suggestions = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        // the pattern ends on a delimiter, the end of the line is one.
+        let line = format!("{}\n", line);
+        for captures in ALSO_KNOWN_AS.captures_iter(&line).flatten() {
+            let (Some(term), Some(expansion)) = (captures.get(1), captures.get(2)) else {
+                continue;
+            };
+            let (term, expansion) = (term.as_str().trim(), expansion.as_str().trim());
+            if term.eq_ignore_ascii_case(expansion) {
+                continue;
+            }
+            suggestions.push(TermSuggestion {
+                term: term.to_string(),
+                expansion: expansion.to_string(),
+                path: path.to_string(),
+                line: index + 1,
+            });
+        }
+    }
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terminology() -> Terminology {
+        Terminology {
+            terms: BTreeMap::from([
+                (
+                    "helios".to_string(),
+                    vec!["billing engine".to_string(), "invoicing".to_string()],
+                ),
+                ("WF".to_string(), vec!["workflow".to_string()]),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_query_is_expanded_with_the_terms_it_mentions() {
+        let expansions = terminology().expand("Why does Helios retry the WF twice?");
+        assert_eq!(
+            render_expansions(&expansions),
+            "helios -> billing engine, invoicing; WF -> workflow"
+        );
+        assert_eq!(
+            expanded_text("Why does Helios retry?", &expansions[..1]),
+            "Why does Helios retry? billing engine invoicing"
+        );
+        // a term inside a word isn't mentioned.
+        assert!(terminology().expand("heliosphere workflows").is_empty());
+        // nor is an expansion the query already has.
+        assert_eq!(
+            terminology().expand("helios billing engine")[0].expansions,
+            vec!["invoicing"]
+        );
+    }
+
+    #[test]
+    fn test_expansions_are_capped() {
+        let many = Terminology {
+            terms: BTreeMap::from([
+                (
+                    "alpha".to_string(),
+                    (0..10).map(|i| format!("alpha expansion {}", i)).collect(),
+                ),
+                (
+                    "beta".to_string(),
+                    (0..10).map(|i| format!("beta expansion {}", i)).collect(),
+                ),
+                (
+                    "gamma".to_string(),
+                    (0..10).map(|i| format!("gamma expansion {}", i)).collect(),
+                ),
+            ]),
+        };
+        let expansions = many.expand("gamma then alpha then beta");
+        assert_eq!(
+            expansions
+                .iter()
+                .map(|expansion| (expansion.term.as_str(), expansion.expansions.len()))
+                .collect::<Vec<_>>(),
+            vec![("gamma", 3), ("alpha", 3)]
+        );
+    }
+
+    #[test]
+    fn test_answer_annotates_the_first_mention_only() {
+        let answer = "The `billing engine` client is built first. The billing engine then \
+                      retries, and the billing engine gives up after 3 attempts.";
+        assert_eq!(
+            terminology().annotate_answer(answer),
+            "The `billing engine` client is built first. The billing engine (helios) then \
+             retries, and the billing engine gives up after 3 attempts."
+        );
+        // already annotated.
+        let annotated = "The Billing Engine (helios) retries.";
+        assert_eq!(terminology().annotate_answer(annotated), annotated);
+        // the earliest mention of any expansion of the term.
+        assert_eq!(
+            terminology().annotate_answer("Invoicing calls the billing engine. A workflow ends."),
+            "Invoicing (helios) calls the billing engine. A workflow (WF) ends."
+        );
+        assert_eq!(
+            terminology().annotate_answer("```\nbilling engine\n```\nNothing else."),
+            "```\nbilling engine\n```\nNothing else."
+        );
+    }
+
+    #[test]
+    fn test_terms_are_mined_from_the_docs() {
+        let readme = "# Payments\n\
+                      Helios (also known as the billing engine) issues the invoices.\n\
+                      Every WF (aka workflow) has an owner.\n\
+                      The ledger, also called the journal, is append only.\n\
+                      Nothing (also known as nothing) here.\n";
+        let suggestions = mine_suggestions("README.md", readme);
+        assert_eq!(
+            suggestions
+                .iter()
+                .map(|s| (s.term.as_str(), s.expansion.as_str(), s.line))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Helios", "billing engine", 2),
+                ("WF", "workflow", 3),
+                ("ledger", "journal", 4),
+            ]
+        );
+        assert!(is_terminology_source("README.md"));
+        assert!(is_terminology_source("services/billing/docs/glossary.md"));
+        assert!(!is_terminology_source("src/docs.rs"));
+
+        let pending = terminology().pending(suggestions);
+        assert_eq!(pending.len(), 3);
+        let known = Terminology {
+            terms: BTreeMap::from([("helios".to_string(), vec!["billing".to_string()])]),
+        };
+        assert_eq!(
+            known.pending(mine_suggestions("README.md", readme)).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_dictionary_is_normalized() {
+        let terminology = Terminology {
+            terms: BTreeMap::from([
+                (
+                    " helios ".to_string(),
+                    vec![
+                        "billing engine ".to_string(),
+                        "".to_string(),
+                        "Billing Engine".to_string(),
+                        "helios".to_string(),
+                    ],
+                ),
+                ("empty".to_string(), vec![" ".to_string()]),
+            ]),
+        }
+        .normalized();
+        assert_eq!(
+            terminology.terms,
+            BTreeMap::from([("helios".to_string(), vec!["billing engine".to_string()])])
+        );
+    }
+}
@@ -0,0 +1,288 @@
+// Where the time of answering a question goes. Code understanding reports how long the agent
+// retrieved code and how long it wrote the answer, the coordinator adds how long the question
+// waited to be sent and how long its answer took to be written to the graph. The timings are
+// kept on the question and checked against per phase thresholds.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub const SLA_THRESHOLDS_ENV: &str = "SLA_THRESHOLDS";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    QueueWait,
+    Retrieval,
+    Answer,
+    Persistence,
+}
+
+impl Phase {
+    // in the order a question goes through them.
+    pub const ALL: [Phase; 4] = [
+        Phase::QueueWait,
+        Phase::Retrieval,
+        Phase::Answer,
+        Phase::Persistence,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::QueueWait => "queue_wait",
+            Phase::Retrieval => "retrieval",
+            Phase::Answer => "answer",
+            Phase::Persistence => "persistence",
+        }
+    }
+
+    // how the phase reads in the rendered breakdown.
+    fn label(&self) -> &'static str {
+        match self {
+            Phase::QueueWait => "queued",
+            Phase::Retrieval => "retrieval",
+            Phase::Answer => "answering",
+            Phase::Persistence => "saving",
+        }
+    }
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Phase::ALL
+            .into_iter()
+            .find(|phase| phase.as_str() == s.trim())
+            .ok_or_else(|| {
+                format!(
+                    "Unknown phase: {}, expected one of queue_wait, retrieval, answer or persistence",
+                    s
+                )
+            })
+    }
+}
+
+/// Milliseconds spent in each phase of answering a question.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseTimings {
+    #[serde(default)]
+    pub queue_wait_ms: u64,
+    #[serde(default)]
+    pub retrieval_ms: u64,
+    #[serde(default)]
+    pub answer_ms: u64,
+    #[serde(default)]
+    pub persistence_ms: u64,
+}
+
+impl PhaseTimings {
+    pub fn get(&self, phase: Phase) -> u64 {
+        match phase {
+            Phase::QueueWait => self.queue_wait_ms,
+            Phase::Retrieval => self.retrieval_ms,
+            Phase::Answer => self.answer_ms,
+            Phase::Persistence => self.persistence_ms,
+        }
+    }
+
+    pub fn total_ms(&self) -> u64 {
+        Phase::ALL.iter().map(|phase| self.get(*phase)).sum()
+    }
+
+    pub fn add(&mut self, other: &PhaseTimings) {
+        self.queue_wait_ms += other.queue_wait_ms;
+        self.retrieval_ms += other.retrieval_ms;
+        self.answer_ms += other.answer_ms;
+        self.persistence_ms += other.persistence_ms;
+    }
+
+    /// e.g. `retrieval 8s, answering 41s`, the phases that took no time are left out.
+    pub fn render(&self) -> String {
+        Phase::ALL
+            .iter()
+            .filter(|phase| self.get(**phase) > 0)
+            .map(|phase| format!("{} {}", phase.label(), format_duration(self.get(*phase))))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// whole seconds past 10s, the breakdown is read at a glance.
+fn format_duration(ms: u64) -> String {
+    match ms {
+        0..=999 => format!("{}ms", ms),
+        1_000..=9_999 => format!("{:.1}s", ms as f64 / 1000.0),
+        _ => format!("{}s", (ms + 500) / 1000),
+    }
+}
+
+/// A phase of a question that took longer than its threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowPhase {
+    pub phase: Phase,
+    pub duration_ms: u64,
+    pub threshold_ms: u64,
+}
+
+/// How long each phase of a question is expected to take at most, phases without a threshold
+/// aren't checked. Parsed from `phase=seconds` entries separated by commas, e.g.
+/// `queue_wait=30,retrieval=20,answer=60`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SlaThresholds(BTreeMap<Phase, u64>);
+
+impl SlaThresholds {
+    pub fn get(&self, phase: Phase) -> Option<u64> {
+        self.0.get(&phase).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The phases of `timings` beyond their threshold, in the order the question went through them.
+    pub fn exceeded(&self, timings: &PhaseTimings) -> Vec<SlowPhase> {
+        self.0
+            .iter()
+            .filter(|(phase, threshold_ms)| timings.get(**phase) > **threshold_ms)
+            .map(|(phase, threshold_ms)| SlowPhase {
+                phase: *phase,
+                duration_ms: timings.get(*phase),
+                threshold_ms: *threshold_ms,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for SlaThresholds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = BTreeMap::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (phase, seconds) = entry.split_once('=').ok_or_else(|| {
+                format!(
+                    "{} entries must be `phase=seconds`, got `{}`",
+                    SLA_THRESHOLDS_ENV, entry
+                )
+            })?;
+            let seconds = seconds
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|seconds| *seconds > 0.0)
+                .ok_or_else(|| {
+                    format!(
+                        "The threshold of {} must be a positive number of seconds, got `{}`",
+                        phase.trim(),
+                        seconds.trim()
+                    )
+                })?;
+            thresholds.insert(phase.parse()?, (seconds * 1000.0).round() as u64);
+        }
+        Ok(Self(thresholds))
+    }
+}
+
+/// The timings of an answered question.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuestionTimings {
+    pub question_id: usize,
+    pub question: String,
+    pub timings: PhaseTimings,
+}
+
+/// Where the time of a conversation went so far, per question and in total.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimingSummary {
+    pub total: PhaseTimings,
+    // the total as it is shown to the user, e.g. `retrieval 8s, answering 41s`.
+    pub breakdown: String,
+    pub questions: Vec<QuestionTimings>,
+}
+
+impl TimingSummary {
+    pub fn new(questions: Vec<QuestionTimings>) -> Self {
+        let mut total = PhaseTimings::default();
+        for question in &questions {
+            total.add(&question.timings);
+        }
+        Self {
+            total,
+            breakdown: total.render(),
+            questions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings() -> PhaseTimings {
+        PhaseTimings {
+            queue_wait_ms: 0,
+            retrieval_ms: 8_200,
+            answer_ms: 41_400,
+            persistence_ms: 35,
+        }
+    }
+
+    #[test]
+    fn test_breakdown_leaves_out_the_phases_without_time() {
+        assert_eq!(
+            timings().render(),
+            "retrieval 8.2s, answering 41s, saving 35ms"
+        );
+        assert_eq!(PhaseTimings::default().render(), "");
+
+        let summary = TimingSummary::new(vec![
+            QuestionTimings {
+                question_id: 4,
+                question: "Where are the tokens refreshed?".to_string(),
+                timings: timings(),
+            },
+            QuestionTimings {
+                question_id: 5,
+                question: "Who calls the refresh?".to_string(),
+                timings: PhaseTimings {
+                    queue_wait_ms: 1_500,
+                    ..timings()
+                },
+            },
+        ]);
+        assert_eq!(summary.total.retrieval_ms, 16_400);
+        assert_eq!(summary.total.total_ms(), 100_770);
+        assert_eq!(
+            summary.breakdown,
+            "queued 1.5s, retrieval 16s, answering 83s, saving 70ms"
+        );
+    }
+
+    #[test]
+    fn test_only_the_phases_beyond_their_threshold_are_slow() {
+        let thresholds: SlaThresholds = "answer=30, retrieval=10,persistence=0.5".parse().unwrap();
+        assert_eq!(thresholds.get(Phase::Answer), Some(30_000));
+        assert_eq!(thresholds.get(Phase::QueueWait), None);
+
+        assert_eq!(
+            thresholds.exceeded(&timings()),
+            vec![SlowPhase {
+                phase: Phase::Answer,
+                duration_ms: 41_400,
+                threshold_ms: 30_000,
+            }]
+        );
+        assert!(SlaThresholds::default().exceeded(&timings()).is_empty());
+
+        assert!("".parse::<SlaThresholds>().unwrap().is_empty());
+        assert!("answer".parse::<SlaThresholds>().is_err());
+        assert!("answer=-1".parse::<SlaThresholds>().is_err());
+        assert!("thinking=10".parse::<SlaThresholds>().is_err());
+    }
+}
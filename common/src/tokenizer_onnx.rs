@@ -1,10 +1,18 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
 use anyhow::Result;
+use once_cell::sync::Lazy;
 use ndarray::Axis;
 use ort::{CPUExecutionProvider, GraphOptimizationLevel, Session};
 use tokenizers::Tokenizer;
 
 pub type Embedding = Vec<f32>;
 
+// sessions loaded per model path, services hosted in one process share a single session.
+static SHARED_SESSIONS: Lazy<Mutex<HashMap<String, Arc<TokenizerOnnx>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 // create a struct for onnx and tokenizer container
 pub struct TokenizerOnnx {
     pub tokenizer: Tokenizer,
@@ -19,6 +27,17 @@ impl TokenizerOnnx {
         Ok(Self { tokenizer, session })
     }
 
+    /// The session of the model, loaded on the first call for `model_path` and reused afterwards.
+    pub fn shared(model_path: &str) -> Result<Arc<Self>> {
+        let mut sessions = SHARED_SESSIONS.lock().expect("Failed to acquire lock");
+        if let Some(session) = sessions.get(model_path) {
+            return Ok(session.clone());
+        }
+        let session = Arc::new(Self::new(model_path)?);
+        sessions.insert(model_path.to_string(), session.clone());
+        Ok(session)
+    }
+
     pub fn get_embedding(&self, sequence: &str) -> anyhow::Result<Embedding> {
         let tokenizer_output = self.tokenizer.encode(sequence, true).unwrap();
 
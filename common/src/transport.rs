@@ -0,0 +1,285 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+// length prefix of the frames of a streamed reply, in big endian.
+const FRAME_HEADER_LEN: usize = 4;
+
+/// Wire format used for request and response bodies between services.
+/// Both formats go through serde, so the same model structs are used for either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Transport {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Transport::Json => JSON_CONTENT_TYPE,
+            Transport::Msgpack => MSGPACK_CONTENT_TYPE,
+        }
+    }
+
+    // Picks the transport from a Content-Type or Accept header value.
+    // Anything that doesn't explicitly mention msgpack is treated as JSON.
+    pub fn from_header(header: Option<&str>) -> Self {
+        match header {
+            Some(value) if value.contains(MSGPACK_CONTENT_TYPE) => Transport::Msgpack,
+            _ => Transport::Json,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Transport::Json => serde_json::to_vec(value)
+                .map_err(|e| anyhow!("Failed to serialize json body: {}", e)),
+            // named encoding keeps struct fields as map keys so that
+            // `skip_serializing_if` and `rename` behave the same as in JSON.
+            Transport::Msgpack => rmp_serde::to_vec_named(value)
+                .map_err(|e| anyhow!("Failed to serialize msgpack body: {}", e)),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Transport::Json => serde_json::from_slice(bytes)
+                .map_err(|e| anyhow!("Failed to deserialize json body: {}", e)),
+            Transport::Msgpack => rmp_serde::from_slice(bytes)
+                .map_err(|e| anyhow!("Failed to deserialize msgpack body: {}", e)),
+        }
+    }
+
+    /// Encodes one value of a streamed reply, prefixed with its length so that the reader can
+    /// split the frames whatever chunks they arrive in.
+    pub fn encode_frame<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let body = self.encode(value)?;
+        let len = u32::try_from(body.len())
+            .map_err(|_| anyhow!("Frame of {} bytes is too large", body.len()))?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + body.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+}
+
+/// Removes the first complete frame of `encode_frame` from the buffer and returns its body,
+/// None while the buffer holds only part of it.
+pub fn take_frame(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let header: [u8; FRAME_HEADER_LEN] = buffer.get(..FRAME_HEADER_LEN)?.try_into().ok()?;
+    let end = FRAME_HEADER_LEN + u32::from_be_bytes(header) as usize;
+    if buffer.len() < end {
+        return None;
+    }
+    let body = buffer[FRAME_HEADER_LEN..end].to_vec();
+    buffer.drain(..end);
+    Some(body)
+}
+
+/// A request body that doesn't decode in the format of its Content-Type.
+#[derive(Debug)]
+pub struct InvalidBody(pub String);
+
+impl warp::reject::Reject for InvalidBody {}
+
+/// Decodes the request body in the format of its Content-Type, msgpack or json.
+pub fn body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    warp::header::exact_ignore_case("content-type", MSGPACK_CONTENT_TYPE)
+        .and(warp::body::bytes())
+        .and_then(|bytes: Bytes| async move {
+            Transport::Msgpack
+                .decode::<T>(&bytes)
+                .map_err(|e| warp::reject::custom(InvalidBody(e.to_string())))
+        })
+        .or(warp::body::json::<T>())
+        .unify()
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Transport::Json),
+            "msgpack" => Ok(Transport::Msgpack),
+            other => Err(anyhow!("Unknown transport: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Json => write!(f, "json"),
+            Transport::Msgpack => write!(f, "msgpack"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CodeContext, CodeUnderstanding};
+    use std::time::Instant;
+
+    // Builds a payload of roughly `target_bytes` worth of answer text and contexts,
+    // close to what the coordinator receives for a question with many contexts.
+    fn sample_payload(target_bytes: usize) -> CodeUnderstanding {
+        let line = "fn handle_request(req: Request) -> Result<Response> { todo!() }\n";
+        let answer = line.repeat(target_bytes / 2 / line.len());
+        let context = (0..(target_bytes / 2 / 64))
+            .map(|i| CodeContext {
+                path: format!("src/module_{}/file_{}.rs", i % 50, i),
+                hidden: i % 7 == 0,
+                repo: "incredible".to_string(),
+                branch: if i % 3 == 0 { Some("main".to_string()) } else { None },
+                ranges: vec![i..i + 10, i + 20..i + 40],
+                pinned: i % 11 == 0,
+                owners: Vec::new(),
+            })
+            .collect();
+
+        CodeUnderstanding {
+            context,
+            question: "How are requests handled?".to_string(),
+            answer,
+            outcome: None,
+            missing_pinned_paths: vec![],
+            cost_usd: None,
+            timings: None,
+            scope_violations: vec![],
+            citations: Default::default(),
+            verification: Default::default(),
+            prompt_versions: Default::default(),
+            files_involved: Default::default(),
+            index_mode: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_transports_roundtrip_identically() {
+        let payload = sample_payload(64 * 1024);
+
+        let from_json: CodeUnderstanding = Transport::Json
+            .decode(&Transport::Json.encode(&payload).unwrap())
+            .unwrap();
+        let from_msgpack: CodeUnderstanding = Transport::Msgpack
+            .decode(&Transport::Msgpack.encode(&payload).unwrap())
+            .unwrap();
+
+        assert_eq!(from_json, payload);
+        assert_eq!(from_msgpack, payload);
+        assert_eq!(from_json, from_msgpack);
+    }
+
+    #[test]
+    fn test_transport_from_header() {
+        assert_eq!(Transport::from_header(None), Transport::Json);
+        assert_eq!(
+            Transport::from_header(Some("application/json")),
+            Transport::Json
+        );
+        assert_eq!(
+            Transport::from_header(Some("application/msgpack, application/json;q=0.5")),
+            Transport::Msgpack
+        );
+        assert_eq!("MsgPack".parse::<Transport>().unwrap(), Transport::Msgpack);
+        assert!("grpc".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn test_frames_split_across_chunks() {
+        let first = sample_payload(1024);
+        let second = CodeUnderstanding {
+            question: "Where are the routes?".to_string(),
+            ..sample_payload(64)
+        };
+
+        for transport in [Transport::Json, Transport::Msgpack] {
+            let mut stream = transport.encode_frame(&first).unwrap();
+            stream.extend(transport.encode_frame(&second).unwrap());
+
+            // the frames come back whole however the stream is cut.
+            let mut buffer = Vec::new();
+            let mut frames = Vec::new();
+            for chunk in stream.chunks(7) {
+                buffer.extend_from_slice(chunk);
+                while let Some(frame) = take_frame(&mut buffer) {
+                    frames.push(transport.decode::<CodeUnderstanding>(&frame).unwrap());
+                }
+            }
+            assert_eq!(frames, vec![first.clone(), second.clone()]);
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_partial_frame_stays_in_the_buffer() {
+        let frame = Transport::Json.encode_frame(&"answer").unwrap();
+        let mut buffer = frame[..frame.len() - 1].to_vec();
+        assert_eq!(take_frame(&mut buffer), None);
+        assert_eq!(buffer.len(), frame.len() - 1);
+
+        buffer.push(frame[frame.len() - 1]);
+        assert_eq!(take_frame(&mut buffer), Some(br#""answer""#.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_body_in_either_transport() {
+        let route = body::<CodeUnderstanding>().map(|payload: CodeUnderstanding| payload.question);
+        let payload = sample_payload(256);
+
+        for transport in [Transport::Json, Transport::Msgpack] {
+            let question = warp::test::request()
+                .method("POST")
+                .header("content-type", transport.content_type())
+                .body(transport.encode(&payload).unwrap())
+                .filter(&route)
+                .await
+                .unwrap();
+            assert_eq!(question, payload.question);
+        }
+
+        let rejection = warp::test::request()
+            .method("POST")
+            .header("content-type", MSGPACK_CONTENT_TYPE)
+            .body("not msgpack")
+            .filter(&route)
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<InvalidBody>().is_some());
+    }
+
+    // Compares payload size and encode + decode time for a ~2MB context payload.
+    // Run with `cargo test -p common --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_transport_roundtrip_2mb() {
+        let payload = sample_payload(2 * 1024 * 1024);
+        let iterations = 20;
+
+        for transport in [Transport::Json, Transport::Msgpack] {
+            let size = transport.encode(&payload).unwrap().len();
+            let start = Instant::now();
+            for _ in 0..iterations {
+                let bytes = transport.encode(&payload).unwrap();
+                let decoded: CodeUnderstanding = transport.decode(&bytes).unwrap();
+                assert_eq!(decoded.context.len(), payload.context.len());
+            }
+            println!(
+                "{}: payload size {} bytes, avg round trip {:?}",
+                transport,
+                size,
+                start.elapsed() / iterations
+            );
+        }
+    }
+}
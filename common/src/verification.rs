@@ -0,0 +1,165 @@
+// Verification steps of an answer: how the user can check the behaviour it describes, grounded in
+// the code the answer was written from. Code understanding asks for them once the answer is
+// written, keeps the steps citing code of its context and drops the ones whose citations don't
+// resolve, like the citations of the answer. An answer without grounded steps has none.
+
+use serde::{Deserialize, Serialize};
+
+use crate::citations::{CitationReport, CitationStatus};
+use crate::links::encode_path;
+
+// more steps than this are cut, the section is a short checklist.
+pub const MAX_VERIFICATION_STEPS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationKind {
+    // run an existing test file of the repo.
+    RunTest,
+    // call a function of the cited code.
+    CallFunction,
+    // look for a log line the cited code writes.
+    CheckLog,
+    // hit an endpoint the cited code serves.
+    HitEndpoint,
+}
+
+impl VerificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerificationKind::RunTest => "run test",
+            VerificationKind::CallFunction => "call function",
+            VerificationKind::CheckLog => "check log",
+            VerificationKind::HitEndpoint => "hit endpoint",
+        }
+    }
+}
+
+/// A step to verify an answer, citing the file of the code it exercises.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VerificationStep {
+    pub kind: VerificationKind,
+    pub instruction: String,
+    pub path: String,
+    // 1-based and inclusive, None for a whole file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines: Option<(usize, usize)>,
+    // the command to run, the test command or the request to send.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+impl VerificationStep {
+    /// The step as a markdown list item linking to its code, e.g.
+    /// `Run the refund tests ([tests/refund.rs](tests/refund.rs#L10-L40)): `cargo test refund``.
+    pub fn render(&self) -> String {
+        let target = match self.lines {
+            Some((start, end)) => format!("{}#L{}-L{}", encode_path(&self.path), start, end),
+            None => encode_path(&self.path),
+        };
+        let mut item = format!("{} ([{}]({}))", self.instruction.trim(), self.path, target);
+        if let Some(command) = &self.command {
+            item.push_str(&format!(": `{}`", command.trim()));
+        }
+        item
+    }
+}
+
+/// The markdown section of the steps, empty without steps.
+pub fn render_verification(steps: &[VerificationStep]) -> String {
+    if steps.is_empty() {
+        return String::new();
+    }
+    let mut section = "### Verification steps\n".to_string();
+    for (i, step) in steps.iter().enumerate() {
+        section.push_str(&format!("{}. {}\n", i + 1, step.render()));
+    }
+    section
+}
+
+/// The steps of the JSON array the model answered with, in a code block or not. A response that
+/// isn't such an array has no steps, the steps without a path are dropped.
+pub fn parse_verification_steps(response: &str) -> Vec<VerificationStep> {
+    let response = response.trim();
+    let json = match (response.find('['), response.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => return Vec::new(),
+    };
+    match serde_json::from_str::<Vec<VerificationStep>>(json) {
+        Ok(steps) => steps
+            .into_iter()
+            .filter(|step| !step.path.trim().is_empty() && !step.instruction.trim().is_empty())
+            .take(MAX_VERIFICATION_STEPS)
+            .collect(),
+        Err(e) => {
+            log::warn!("Failed to parse the verification steps: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Drops the steps whose citation is invalid in `report`, the report of the rendered steps, and
+/// moves the adjusted ones to their resolved lines.
+pub fn apply_citations(steps: Vec<VerificationStep>, report: &CitationReport) -> Vec<VerificationStep> {
+    steps
+        .into_iter()
+        .filter_map(|mut step| {
+            let citation = report
+                .citations
+                .iter()
+                .find(|citation| citation.path == step.path && citation.cited_lines == step.lines);
+            match citation.map(|citation| (citation.status, citation.lines)) {
+                Some((CitationStatus::Invalid, _)) => None,
+                Some((CitationStatus::Adjusted, lines)) => {
+                    step.lines = lines;
+                    Some(step)
+                }
+                _ => Some(step),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const RESPONSE: &str = r#"```json
+[
+  {"kind": "run_test", "instruction": "Run the refund tests", "path": "tests/refund_test.rs", "command": "cargo test refund"},
+  {"kind": "call_function", "instruction": "Call retry_refund with a failed refund", "path": "src/refund.rs", "lines": [10, 60]},
+  {"kind": "check_log", "instruction": "Look for the retry log line", "path": "src/missing.rs", "lines": [3, 3]},
+  {"kind": "hit_endpoint", "instruction": "Send a refund request", "path": ""}
+]
+```"#;
+
+    #[tokio::test]
+    async fn test_steps_are_parsed_and_checked_like_citations() {
+        let steps = parse_verification_steps(RESPONSE);
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].kind, VerificationKind::RunTest);
+        assert_eq!(steps[1].lines, Some((10, 60)));
+
+        let line_counts = HashMap::from([
+            ("tests/refund_test.rs", Some(80)),
+            ("src/refund.rs", Some(40)),
+            ("src/missing.rs", None),
+        ]);
+        let report = CitationReport::resolve(&render_verification(&steps), |path| {
+            let line_count = line_counts[path.as_str()];
+            async move { Ok(line_count) }
+        })
+        .await;
+        let steps = apply_citations(steps, &report);
+
+        assert_eq!(
+            render_verification(&steps),
+            "### Verification steps\n\
+             1. Run the refund tests ([tests/refund_test.rs](tests/refund_test.rs)): `cargo test refund`\n\
+             2. Call retry_refund with a failed refund ([src/refund.rs](src/refund.rs#L10-L40))\n"
+        );
+        assert_eq!(render_verification(&[]), "");
+        assert!(parse_verification_steps("Run the tests of the refunds.").is_empty());
+    }
+}
@@ -0,0 +1,1038 @@
+// Admission of the questions sent to code understanding. At most `active` questions are answered
+// at once and `per_conversation` of them for the same conversation. The others wait in a queue
+// served by priority, then in arrival order: the questions a user waits on, quick answers and
+// follow-ups, go before the questions of generated tasks. A question arriving at a full queue is
+// turned away with the time to retry after, and so is every question once the coordinator is
+// shutting down, while the queued ones are still answered as it drains.
+// The queue is saved to redis as it changes. The questions left in it by a restart are reported as
+// interrupted on the status of their conversation until it asks again.
+// A conversation with questions in the admission is alive while its client is heard from: a
+// request of the user, a keepalive or an open event stream. One not heard from for `idle` seconds
+// is suspended, its queued questions wait without being let in and the questions being answered
+// no longer hold their slots. It resumes with the next sign of its client.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use common::budget::{BudgetExceeded, BudgetMeter};
+use common::clock::unix_now;
+use common::shutdown::{self, Shutdown};
+use common::task_graph::redis::establish_redis_connection;
+use futures::Stream;
+use once_cell::sync::OnceCell;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, watch};
+
+use crate::configuration::{get_answer_admission, get_redis_url};
+
+pub const ANSWER_ADMISSION_ENV: &str = "ANSWER_ADMISSION";
+
+const QUEUE_STATE_KEY: &str = "answer_queue:state";
+// time a question is assumed to take before any was answered.
+const DEFAULT_SERVICE_MS: f64 = 30_000.0;
+// weight of the last answered question in the average time of a question.
+const SERVICE_SMOOTHING: f64 = 0.2;
+// a conversation not heard from for this long is suspended.
+const DEFAULT_MAX_IDLE: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    // a user waits on the answer, quick answers and follow-ups.
+    Interactive,
+    // the questions of generated tasks.
+    Bulk,
+}
+
+/// The questions answered at once, overall and per conversation, the questions that can wait and
+/// how long a conversation can go unheard from before it is suspended.
+/// Parsed from `active=<n>,per_conversation=<n>,queue=<n>,idle=<secs>`, a missing entry keeps its
+/// default and `idle=0` never suspends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdmissionConfig {
+    pub max_active: usize,
+    pub max_per_conversation: usize,
+    pub max_queue_depth: usize,
+    pub max_idle: Option<Duration>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_active: 8,
+            max_per_conversation: 2,
+            max_queue_depth: 200,
+            max_idle: Some(DEFAULT_MAX_IDLE),
+        }
+    }
+}
+
+impl FromStr for AdmissionConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, value) = entry.split_once('=').ok_or_else(|| {
+                format!(
+                    "{} entries must be `active=<n>`, `per_conversation=<n>`, `queue=<n>` or `idle=<secs>`, got `{}`",
+                    ANSWER_ADMISSION_ENV, entry
+                )
+            })?;
+            let value = value.trim().parse::<usize>().map_err(|_| {
+                format!(
+                    "The {} of {} must be a non-negative integer, got `{}`",
+                    name.trim(),
+                    ANSWER_ADMISSION_ENV,
+                    value.trim()
+                )
+            })?;
+            match name.trim() {
+                "active" => config.max_active = value,
+                "per_conversation" => config.max_per_conversation = value,
+                "queue" => config.max_queue_depth = value,
+                "idle" => {
+                    config.max_idle = (value > 0).then(|| Duration::from_secs(value as u64))
+                }
+                name => return Err(format!("Unknown {} entry `{}`", ANSWER_ADMISSION_ENV, name)),
+            }
+        }
+        if config.max_active == 0 || config.max_per_conversation == 0 {
+            return Err(format!(
+                "The active and per_conversation limits of {} must be at least 1",
+                ANSWER_ADMISSION_ENV
+            ));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    QueueFull,
+    ShuttingDown,
+}
+
+/// A question turned away, to be asked again after `retry_after_secs`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdmissionRejected {
+    pub reason: RejectionReason,
+    pub retry_after_secs: u64,
+}
+
+impl fmt::Display for AdmissionRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            RejectionReason::QueueFull => write!(
+                f,
+                "Too many questions are waiting to be answered, retry in {} seconds",
+                self.retry_after_secs
+            ),
+            RejectionReason::ShuttingDown => write!(
+                f,
+                "The coordinator is shutting down, retry in {} seconds",
+                self.retry_after_secs
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AdmissionRejected {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueState {
+    Idle,
+    Queued,
+    Answering,
+    // its questions were queued when the coordinator restarted, they have to be asked again.
+    Interrupted,
+    // its client wasn't heard from for a while, its queued questions wait until it is back.
+    Suspended,
+}
+
+/// Where the questions of a conversation are, returned by its status endpoint and events.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub state: QueueState,
+    pub queued_questions: usize,
+    pub active_questions: usize,
+    // 1-based place of its first queued question among all the queued questions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_wait_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueuedQuestion {
+    pub conversation_id: String,
+    pub priority: Priority,
+    // unix timestamp in seconds.
+    pub queued_at: u64,
+}
+
+/// The queue as it is saved, in the order it is served.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub queued: Vec<QueuedQuestion>,
+    pub active: usize,
+}
+
+/// Where the queue is saved.
+pub trait QueueStore: Send + Sync {
+    fn save(&self, snapshot: &QueueSnapshot) -> Result<()>;
+    fn load(&self) -> Result<Option<QueueSnapshot>>;
+}
+
+pub struct RedisQueueStore {
+    redis_url: String,
+}
+
+impl RedisQueueStore {
+    pub fn new(redis_url: &str) -> Self {
+        Self {
+            redis_url: redis_url.to_string(),
+        }
+    }
+}
+
+impl QueueStore for RedisQueueStore {
+    fn save(&self, snapshot: &QueueSnapshot) -> Result<()> {
+        let mut conn = establish_redis_connection(&self.redis_url)?;
+        let _: () = conn.set(QUEUE_STATE_KEY, serde_json::to_string(snapshot)?)?;
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<QueueSnapshot>> {
+        let mut conn = establish_redis_connection(&self.redis_url)?;
+        let value: Option<String> = conn.get(QUEUE_STATE_KEY)?;
+        Ok(value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()?)
+    }
+}
+
+/// The priority a flow's questions are queued with. A flow past its budget isn't queued at all,
+/// a degraded one waits behind the others.
+pub fn budgeted_priority(
+    priority: Priority,
+    budget: &BudgetMeter,
+) -> Result<Priority, BudgetExceeded> {
+    if let Some(exceeded) = budget.rejection() {
+        return Err(exceeded);
+    }
+    if let Some(allowance) = budget.allowance().filter(|a| a.remaining_usd() <= 0.0) {
+        return Err(BudgetExceeded {
+            scope: allowance.scope,
+            limit_usd: allowance.limit_usd,
+            spent_usd: allowance.spent_usd,
+            projected_usd: allowance.spent_usd,
+        });
+    }
+    Ok(match budget.is_degraded() {
+        true => Priority::Bulk,
+        false => priority,
+    })
+}
+
+struct Waiter {
+    seq: u64,
+    question: QueuedQuestion,
+    sender: oneshot::Sender<AdmissionPermit>,
+}
+
+struct State {
+    // in the order they are served, by priority and then by arrival.
+    waiting: Vec<Waiter>,
+    active: usize,
+    active_by_conversation: HashMap<String, usize>,
+    next_seq: u64,
+    // moving average of the time a question takes, in milliseconds.
+    service_ms: f64,
+    interrupted: HashSet<String>,
+    // when the conversations were last heard from, by a request, a keepalive or an event stream.
+    last_seen: HashMap<String, Instant>,
+    // event streams open per conversation, a conversation with one is alive.
+    streams: HashMap<String, usize>,
+    suspended: HashSet<String>,
+    // questions of suspended conversations still being answered without holding a slot.
+    released: HashMap<String, usize>,
+}
+
+impl State {
+    fn active_of(&self, conversation_id: &str) -> usize {
+        self.active_by_conversation
+            .get(conversation_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // the conversations with questions queued or being answered.
+    fn busy(&self) -> HashSet<String> {
+        self.waiting
+            .iter()
+            .map(|waiter| waiter.question.conversation_id.clone())
+            .chain(self.active_by_conversation.keys().cloned())
+            .collect()
+    }
+}
+
+pub struct Admission {
+    config: AdmissionConfig,
+    state: Mutex<State>,
+    // bumped on every change of the queue, the status streams and the store writer follow it.
+    changes: watch::Sender<u64>,
+    store: Arc<dyn QueueStore>,
+    shutdown: Shutdown,
+}
+
+static ADMISSION: OnceCell<Arc<Admission>> = OnceCell::new();
+
+/// The admission of the process, configured with `ANSWER_ADMISSION` and saving to `REDIS_URL`.
+pub fn global() -> Arc<Admission> {
+    ADMISSION
+        .get_or_init(|| {
+            Admission::new(
+                get_answer_admission(),
+                Arc::new(RedisQueueStore::new(&get_redis_url())),
+                shutdown::global().clone(),
+            )
+        })
+        .clone()
+}
+
+impl Admission {
+    /// Has to be called on a tokio runtime, the queue is saved by a task of its own.
+    pub fn new(
+        config: AdmissionConfig,
+        store: Arc<dyn QueueStore>,
+        shutdown: Shutdown,
+    ) -> Arc<Self> {
+        let (changes, _) = watch::channel(0);
+        let admission = Arc::new(Self {
+            config,
+            state: Mutex::new(State {
+                waiting: Vec::new(),
+                active: 0,
+                active_by_conversation: HashMap::new(),
+                next_seq: 0,
+                service_ms: DEFAULT_SERVICE_MS,
+                interrupted: HashSet::new(),
+                last_seen: HashMap::new(),
+                streams: HashMap::new(),
+                suspended: HashSet::new(),
+                released: HashMap::new(),
+            }),
+            changes,
+            store,
+            shutdown,
+        });
+        tokio::spawn(save_changes(
+            Arc::downgrade(&admission),
+            admission.changes.subscribe(),
+        ));
+        admission
+    }
+
+    /// Waits until a question of the conversation can be answered. The question is answered while
+    /// the permit is kept, dropping it lets the next one in.
+    pub async fn admit(
+        self: &Arc<Self>,
+        conversation_id: &str,
+        priority: Priority,
+    ) -> Result<AdmissionPermit, AdmissionRejected> {
+        let (sender, receiver) = oneshot::channel();
+        let seq = {
+            let mut state = self.state.lock().unwrap();
+            if self.shutdown.is_triggered() {
+                return Err(AdmissionRejected {
+                    reason: RejectionReason::ShuttingDown,
+                    retry_after_secs: self.wait_secs(&state, 1),
+                });
+            }
+            if state.waiting.len() >= self.config.max_queue_depth {
+                let retry_after_secs = self.wait_secs(&state, state.waiting.len() + 1);
+                return Err(AdmissionRejected {
+                    reason: RejectionReason::QueueFull,
+                    retry_after_secs,
+                });
+            }
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            let at = state
+                .waiting
+                .partition_point(|waiter| waiter.question.priority <= priority);
+            state.waiting.insert(
+                at,
+                Waiter {
+                    seq,
+                    question: QueuedQuestion {
+                        conversation_id: conversation_id.to_string(),
+                        priority,
+                        queued_at: unix_now(),
+                    },
+                    sender,
+                },
+            );
+            state.interrupted.remove(conversation_id);
+            // a conversation is alive when it starts asking.
+            state
+                .last_seen
+                .entry(conversation_id.to_string())
+                .or_insert_with(Instant::now);
+            seq
+        };
+        // takes the question out of the queue if the request goes away while it waits.
+        let _ticket = QueueTicket {
+            admission: Arc::downgrade(self),
+            seq,
+        };
+        self.dispatch();
+
+        let mut permit = receiver.await.map_err(|_| AdmissionRejected {
+            reason: RejectionReason::ShuttingDown,
+            retry_after_secs: 1,
+        })?;
+        permit.started = Some(Instant::now());
+        Ok(permit)
+    }
+
+    /// Where the questions of the conversation are.
+    pub fn status(&self, conversation_id: &str) -> QueueStatus {
+        let state = self.state.lock().unwrap();
+        let queued_questions = state
+            .waiting
+            .iter()
+            .filter(|waiter| waiter.question.conversation_id == conversation_id)
+            .count();
+        let suspended = state.suspended.contains(conversation_id);
+        // the questions of a suspended conversation aren't let in, they have no place to wait for.
+        let position = state
+            .waiting
+            .iter()
+            .position(|waiter| waiter.question.conversation_id == conversation_id)
+            .filter(|_| !suspended)
+            .map(|index| index + 1);
+        let active_questions = state.active_of(conversation_id);
+        let queue_state = match (queued_questions, active_questions) {
+            _ if suspended => QueueState::Suspended,
+            (0, 0) if state.interrupted.contains(conversation_id) => QueueState::Interrupted,
+            (0, 0) => QueueState::Idle,
+            (0, _) => QueueState::Answering,
+            _ => QueueState::Queued,
+        };
+        QueueStatus {
+            state: queue_state,
+            queued_questions,
+            active_questions,
+            position,
+            estimated_wait_secs: position.map(|position| self.wait_secs(&state, position)),
+        }
+    }
+
+    /// The status of the conversation every time it changes, starting with the current one.
+    pub fn status_updates(
+        self: &Arc<Self>,
+        conversation_id: String,
+    ) -> impl Stream<Item = QueueStatus> {
+        let changes = self.changes.subscribe();
+        futures::stream::unfold(
+            (self.clone(), changes, None::<QueueStatus>),
+            move |(admission, mut changes, last)| {
+                let conversation_id = conversation_id.clone();
+                async move {
+                    loop {
+                        let status = admission.status(&conversation_id);
+                        if last.as_ref() != Some(&status) {
+                            return Some((status.clone(), (admission, changes, Some(status))));
+                        }
+                        changes.changed().await.ok()?;
+                    }
+                }
+            },
+        )
+    }
+
+    /// Records that the client of the conversation was heard from. Returns true when this resumed
+    /// the conversation, its queued questions are let in again.
+    pub fn heartbeat(self: &Arc<Self>, conversation_id: &str) -> bool {
+        let resumed = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .last_seen
+                .insert(conversation_id.to_string(), Instant::now());
+            state.suspended.remove(conversation_id)
+        };
+        if resumed {
+            self.dispatch();
+        }
+        resumed
+    }
+
+    /// Keeps the conversation alive while the guard is kept, e.g. by an open event stream.
+    pub fn watch(self: &Arc<Self>, conversation_id: &str) -> LivenessGuard {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .streams
+            .entry(conversation_id.to_string())
+            .or_default() += 1;
+        LivenessGuard {
+            admission: self.clone(),
+            conversation_id: conversation_id.to_string(),
+        }
+    }
+
+    pub fn is_suspended(&self, conversation_id: &str) -> bool {
+        self.state.lock().unwrap().suspended.contains(conversation_id)
+    }
+
+    /// Suspends the conversations with questions in the admission that weren't heard from within
+    /// the idle time before `now`, returns them. Their slots go to the other conversations.
+    pub fn sweep(self: &Arc<Self>, now: Instant) -> Vec<String> {
+        let Some(max_idle) = self.config.max_idle else {
+            return Vec::new();
+        };
+        let mut suspended = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let busy = state.busy();
+            let idle = |state: &State, conversation_id: &str| {
+                !state.streams.contains_key(conversation_id)
+                    && state.last_seen.get(conversation_id).map_or(true, |seen| {
+                        now.saturating_duration_since(*seen) > max_idle
+                    })
+            };
+            for conversation_id in &busy {
+                if state.suspended.contains(conversation_id) || !idle(&*state, conversation_id) {
+                    continue;
+                }
+                let active = state
+                    .active_by_conversation
+                    .remove(conversation_id)
+                    .unwrap_or_default();
+                state.active = state.active.saturating_sub(active);
+                if active > 0 {
+                    *state
+                        .released
+                        .entry(conversation_id.clone())
+                        .or_default() += active;
+                }
+                state.suspended.insert(conversation_id.clone());
+                suspended.push(conversation_id.clone());
+            }
+            // the conversations that are done are forgotten once they would have been suspended.
+            let forgotten = state
+                .last_seen
+                .keys()
+                .filter(|conversation_id| {
+                    !busy.contains(*conversation_id) && idle(&*state, conversation_id)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            for conversation_id in forgotten {
+                state.last_seen.remove(&conversation_id);
+                state.suspended.remove(&conversation_id);
+            }
+        }
+        if !suspended.is_empty() {
+            log::info!("Suspended the idle conversations {:?}", suspended);
+            self.dispatch();
+        }
+        suspended
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let state = self.state.lock().unwrap();
+        QueueSnapshot {
+            queued: state
+                .waiting
+                .iter()
+                .map(|waiter| waiter.question.clone())
+                .collect(),
+            active: state.active,
+        }
+    }
+
+    /// Saves the queue right away, the last save of a draining coordinator.
+    pub fn persist(&self) -> Result<()> {
+        self.store.save(&self.snapshot())
+    }
+
+    /// Marks the conversations left in the queue saved by the previous process as interrupted,
+    /// their requests went away with it.
+    pub fn recover(&self) -> Result<()> {
+        let Some(snapshot) = self.store.load()? else {
+            return Ok(());
+        };
+        if !snapshot.queued.is_empty() {
+            log::warn!(
+                "{} questions were still queued when the coordinator stopped",
+                snapshot.queued.len()
+            );
+        }
+        self.state.lock().unwrap().interrupted.extend(
+            snapshot
+                .queued
+                .into_iter()
+                .map(|question| question.conversation_id),
+        );
+        self.changed();
+        Ok(())
+    }
+
+    // Lets the queued questions in while there is room, in the order they are served. A question
+    // whose conversation is at its limit or suspended doesn't hold back the ones behind it.
+    fn dispatch(self: &Arc<Self>) {
+        let mut unclaimed = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let mut index = 0;
+            while state.active < self.config.max_active && index < state.waiting.len() {
+                let conversation_id = &state.waiting[index].question.conversation_id;
+                if state.suspended.contains(conversation_id)
+                    || state.active_of(conversation_id) >= self.config.max_per_conversation
+                {
+                    index += 1;
+                    continue;
+                }
+                let waiter = state.waiting.remove(index);
+                let conversation_id = waiter.question.conversation_id;
+                state.active += 1;
+                *state
+                    .active_by_conversation
+                    .entry(conversation_id.clone())
+                    .or_default() += 1;
+                let permit = AdmissionPermit {
+                    admission: self.clone(),
+                    conversation_id,
+                    started: None,
+                };
+                // the request went away meanwhile, its permit is released once the lock is.
+                if let Err(permit) = waiter.sender.send(permit) {
+                    unclaimed.push(permit);
+                }
+            }
+        }
+        drop(unclaimed);
+        self.changed();
+    }
+
+    fn release(self: &Arc<Self>, conversation_id: &str, took: Option<Duration>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            // the slot of a question of a suspended conversation was already given up.
+            if let Some(released) = state.released.get_mut(conversation_id) {
+                *released -= 1;
+                if *released == 0 {
+                    state.released.remove(conversation_id);
+                }
+            } else {
+                state.active = state.active.saturating_sub(1);
+                if let Some(active) = state.active_by_conversation.get_mut(conversation_id) {
+                    *active = active.saturating_sub(1);
+                    if *active == 0 {
+                        state.active_by_conversation.remove(conversation_id);
+                    }
+                }
+            }
+            if let Some(took) = took {
+                state.service_ms = (1.0 - SERVICE_SMOOTHING) * state.service_ms
+                    + SERVICE_SMOOTHING * took.as_millis() as f64;
+            }
+        }
+        self.dispatch();
+    }
+
+    // Seconds until the question at `position` of the queue is let in, when the questions before
+    // it take the average time.
+    fn wait_secs(&self, state: &State, position: usize) -> u64 {
+        let wait_ms = position as f64 * state.service_ms / self.config.max_active as f64;
+        (wait_ms / 1000.0).ceil().max(1.0) as u64
+    }
+
+    fn changed(&self) {
+        self.changes.send_modify(|version| *version += 1);
+    }
+}
+
+/// A question let in by the admission, the next one is let in once it is dropped.
+pub struct AdmissionPermit {
+    admission: Arc<Admission>,
+    conversation_id: String,
+    // none until the request took it, a permit nobody took doesn't count in the average time.
+    started: Option<Instant>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        let took = self.started.map(|started| started.elapsed());
+        self.admission.release(&self.conversation_id, took);
+    }
+}
+
+/// An open event stream of a conversation, it is heard from until the guard is dropped.
+pub struct LivenessGuard {
+    admission: Arc<Admission>,
+    conversation_id: String,
+}
+
+impl Drop for LivenessGuard {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        if let Some(streams) = state.streams.get_mut(&self.conversation_id) {
+            *streams -= 1;
+            if *streams == 0 {
+                state.streams.remove(&self.conversation_id);
+            }
+        }
+        // the idle time starts when the stream closes.
+        state
+            .last_seen
+            .insert(self.conversation_id.clone(), Instant::now());
+    }
+}
+
+struct QueueTicket {
+    admission: Weak<Admission>,
+    seq: u64,
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let Some(admission) = self.admission.upgrade() else {
+            return;
+        };
+        let removed = {
+            let mut state = admission.state.lock().unwrap();
+            let before = state.waiting.len();
+            state.waiting.retain(|waiter| waiter.seq != self.seq);
+            state.waiting.len() != before
+        };
+        if removed {
+            admission.changed();
+        }
+    }
+}
+
+// Saves the queue after its changes, a burst of changes is saved once.
+async fn save_changes(admission: Weak<Admission>, mut changes: watch::Receiver<u64>) {
+    while changes.changed().await.is_ok() {
+        let Some(admission) = admission.upgrade() else {
+            return;
+        };
+        if let Err(e) = admission.persist() {
+            log::error!("Failed to save the answer queue: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct MemoryQueueStore(Mutex<Option<QueueSnapshot>>);
+
+    impl QueueStore for MemoryQueueStore {
+        fn save(&self, snapshot: &QueueSnapshot) -> Result<()> {
+            *self.0.lock().unwrap() = Some(snapshot.clone());
+            Ok(())
+        }
+
+        fn load(&self) -> Result<Option<QueueSnapshot>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    fn new_admission(config: &str, store: Arc<MemoryQueueStore>) -> Arc<Admission> {
+        Admission::new(config.parse().unwrap(), store, Shutdown::new())
+    }
+
+    // code understanding answering `capacity` questions at once, it fails the ones beyond.
+    struct Downstream {
+        capacity: usize,
+        running: AtomicUsize,
+        most_running: AtomicUsize,
+        answered: Mutex<Vec<String>>,
+    }
+
+    impl Downstream {
+        async fn answer(&self, question: String) {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            assert!(running <= self.capacity, "{} questions at once", running);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            self.answered.lock().unwrap().push(question);
+            self.running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Asks the question once the ones before it are queued, so the queue order is known.
+    async fn ask(
+        admission: Arc<Admission>,
+        downstream: Arc<Downstream>,
+        conversation_id: &'static str,
+        priority: Priority,
+        question: String,
+    ) {
+        let _permit = admission.admit(conversation_id, priority).await.unwrap();
+        downstream.answer(question).await;
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    #[tokio::test]
+    async fn test_flood_is_answered_by_priority_within_capacity() {
+        let store = Arc::new(MemoryQueueStore::default());
+        let admission = new_admission("active=2,per_conversation=2,queue=10", store.clone());
+        let downstream = Arc::new(Downstream {
+            capacity: 2,
+            running: AtomicUsize::new(0),
+            most_running: AtomicUsize::new(0),
+            answered: Mutex::new(Vec::new()),
+        });
+
+        let mut asked = Vec::new();
+        // the task breakdowns of four conversations flood the queue, then two quick answers come.
+        for (i, conversation_id) in ["c1", "c2", "c3", "c4"].into_iter().enumerate() {
+            for question in 0..2 {
+                asked.push(tokio::spawn(ask(
+                    admission.clone(),
+                    downstream.clone(),
+                    conversation_id,
+                    Priority::Bulk,
+                    format!("bulk {}.{}", i + 1, question),
+                )));
+                settle().await;
+            }
+        }
+        let status = admission.status("c3");
+        assert_eq!(status.state, QueueState::Queued);
+        // c1 is answered, c2 waits first and c3 behind it.
+        assert_eq!((status.position, status.queued_questions), (Some(3), 2));
+        assert!(status.estimated_wait_secs.unwrap() >= 1);
+        assert_eq!(admission.status("c1").state, QueueState::Answering);
+        for question in 0..2 {
+            asked.push(tokio::spawn(ask(
+                admission.clone(),
+                downstream.clone(),
+                "quick",
+                Priority::Interactive,
+                format!("quick {}", question),
+            )));
+            settle().await;
+        }
+        // the quick answers wait first, the task breakdowns move back.
+        assert_eq!(admission.status("quick").position, Some(1));
+        assert_eq!(admission.status("c3").position, Some(5));
+        assert_eq!(store.load().unwrap().unwrap().queued.len(), 8);
+
+        for asked in asked {
+            asked.await.unwrap();
+        }
+        let answered = downstream.answered.lock().unwrap().clone();
+        assert_eq!(downstream.most_running.load(Ordering::SeqCst), 2);
+        assert_eq!(answered.len(), 10);
+        // the two questions of c1 were answered before the quick answers came.
+        let mut first = answered[..2].to_vec();
+        first.sort();
+        assert_eq!(first, vec!["bulk 1.0", "bulk 1.1"]);
+        let mut quick = answered[2..4].to_vec();
+        quick.sort();
+        assert_eq!(quick, vec!["quick 0", "quick 1"]);
+        assert_eq!(admission.status("c4").state, QueueState::Idle);
+        settle().await;
+        assert_eq!(store.load().unwrap(), Some(QueueSnapshot::default()));
+    }
+
+    #[tokio::test]
+    async fn test_conversation_limit_and_full_queue() {
+        let admission = new_admission(
+            "active=3,per_conversation=1,queue=2",
+            Arc::new(MemoryQueueStore::default()),
+        );
+
+        let first = admission.admit("c1", Priority::Bulk).await.unwrap();
+        // c1 is at its limit, its next question waits while c2 is let in.
+        let waiting = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit("c1", Priority::Bulk).await.map(|_| ()) }
+        });
+        settle().await;
+        let second = admission.admit("c2", Priority::Bulk).await.unwrap();
+        assert_eq!(admission.status("c1").position, Some(1));
+        assert_eq!(admission.status("c1").active_questions, 1);
+
+        let _third = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit("c2", Priority::Bulk).await.map(|_| ()) }
+        });
+        settle().await;
+        let rejected = admission
+            .admit("c3", Priority::Interactive)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(rejected.reason, RejectionReason::QueueFull);
+        assert!(rejected.retry_after_secs >= 1);
+
+        drop(first);
+        waiting.await.unwrap().unwrap();
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn test_queue_drains_on_shutdown_and_restart_is_reported() {
+        let store = Arc::new(MemoryQueueStore::default());
+        let shutdown = Shutdown::new();
+        let admission = Admission::new(
+            "active=1,queue=5".parse().unwrap(),
+            store.clone(),
+            shutdown.clone(),
+        );
+
+        let permit = admission.admit("c1", Priority::Bulk).await.unwrap();
+        let queued = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit("c2", Priority::Bulk).await.map(|_| ()) }
+        });
+        settle().await;
+        shutdown.trigger();
+        let rejected = admission
+            .admit("c3", Priority::Interactive)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(rejected.reason, RejectionReason::ShuttingDown);
+        // the queued question is still answered.
+        drop(permit);
+        queued.await.unwrap().unwrap();
+
+        // a coordinator that stopped with c4 queued.
+        store
+            .save(&QueueSnapshot {
+                queued: vec![QueuedQuestion {
+                    conversation_id: "c4".to_string(),
+                    priority: Priority::Bulk,
+                    queued_at: 0,
+                }],
+                active: 1,
+            })
+            .unwrap();
+        let restarted = new_admission("", store);
+        restarted.recover().unwrap();
+        assert_eq!(restarted.status("c4").state, QueueState::Interrupted);
+        let _permit = restarted.admit("c4", Priority::Bulk).await.unwrap();
+        assert_eq!(restarted.status("c4").state, QueueState::Answering);
+    }
+
+    #[tokio::test]
+    async fn test_idle_conversation_is_suspended_and_resumed() {
+        let admission = new_admission(
+            "active=2,per_conversation=2,queue=10,idle=60",
+            Arc::new(MemoryQueueStore::default()),
+        );
+        let downstream = Arc::new(Downstream {
+            capacity: 2,
+            running: AtomicUsize::new(0),
+            most_running: AtomicUsize::new(0),
+            answered: Mutex::new(Vec::new()),
+        });
+
+        // the fan-out of c1, two questions are answered and two wait.
+        let mut asked = Vec::new();
+        for question in 0..4 {
+            asked.push(tokio::spawn(ask(
+                admission.clone(),
+                downstream.clone(),
+                "c1",
+                Priority::Bulk,
+                format!("c1 {}", question),
+            )));
+            settle().await;
+        }
+        assert_eq!(admission.status("c1").active_questions, 2);
+        assert!(admission.sweep(Instant::now()).is_empty());
+
+        // the client of c1 went away mid fan-out.
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(admission.sweep(later), vec!["c1".to_string()]);
+        let status = admission.status("c1");
+        assert_eq!(status.state, QueueState::Suspended);
+        assert_eq!((status.queued_questions, status.position), (2, None));
+        // the slots are released while the two questions finish.
+        assert_eq!(admission.snapshot().active, 0);
+        let first = admission.admit("c2", Priority::Bulk).await.unwrap();
+        let second = admission.admit("c2", Priority::Bulk).await.unwrap();
+        drop((first, second));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(downstream.answered.lock().unwrap().len(), 2);
+        assert_eq!(admission.status("c1").queued_questions, 2);
+        assert_eq!(admission.snapshot().active, 0);
+
+        // the client is back, the remaining questions are answered.
+        assert!(admission.heartbeat("c1"));
+        assert!(!admission.heartbeat("c1"));
+        for asked in asked {
+            asked.await.unwrap();
+        }
+        let mut answered = downstream.answered.lock().unwrap().clone();
+        answered.sort();
+        assert_eq!(answered, vec!["c1 0", "c1 1", "c1 2", "c1 3"]);
+        assert_eq!(admission.status("c1").state, QueueState::Idle);
+        assert_eq!(admission.snapshot().active, 0);
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_keeps_conversation_alive() {
+        let admission = new_admission("idle=60", Arc::new(MemoryQueueStore::default()));
+        let permit = admission.admit("c1", Priority::Interactive).await.unwrap();
+        let stream = admission.watch("c1");
+        let later = Instant::now() + Duration::from_secs(61);
+        assert!(admission.sweep(later).is_empty());
+        // the idle time starts once the stream is closed.
+        drop(stream);
+        assert!(admission.sweep(Instant::now()).is_empty());
+        assert_eq!(admission.sweep(later + Duration::from_secs(61)), vec!["c1".to_string()]);
+        drop(permit);
+        assert_eq!(admission.snapshot().active, 0);
+
+        let never = new_admission("idle=0", Arc::new(MemoryQueueStore::default()));
+        let _permit = never.admit("c1", Priority::Bulk).await.unwrap();
+        assert!(never.sweep(later).is_empty());
+    }
+
+    #[test]
+    fn test_config_is_parsed() {
+        let config: AdmissionConfig = "active=4, queue=20".parse().unwrap();
+        assert_eq!(
+            config,
+            AdmissionConfig {
+                max_active: 4,
+                max_per_conversation: 2,
+                max_queue_depth: 20,
+                max_idle: Some(DEFAULT_MAX_IDLE),
+            }
+        );
+        let config: AdmissionConfig = "idle=0".parse().unwrap();
+        assert_eq!(config.max_idle, None);
+        assert!("active=0".parse::<AdmissionConfig>().is_err());
+        assert!("workers=4".parse::<AdmissionConfig>().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// Documents attached to a conversation. The coordinator splits them into sections that code search
+// embeds under the conversation id, and keeps a record of them in redis that expires with them, so
+// the questions of the conversation only ask for the attachments while it has some.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use common::attachments::{chunk_attachment, AttachmentKind, AttachmentSection};
+use common::task_graph::redis::establish_redis_connection;
+use redis::Commands;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::models::AttachmentRequest;
+
+/// What is recorded of an attachment of a conversation, and returned when it is added.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AttachmentRecord {
+    pub attachment_id: String,
+    pub name: String,
+    pub kind: AttachmentKind,
+    // the sections the document was split into, e.g. `POST /orders/{id}/refunds`.
+    pub sections: Vec<String>,
+    // unix seconds after which the attachment is deleted.
+    pub expires_at: u64,
+}
+
+fn attachments_key(conversation_id: &str) -> String {
+    format!("attachments:{}", conversation_id)
+}
+
+/// Checks the size of the document and splits it into sections. Fails with the status the
+/// request is rejected with.
+pub fn prepare_attachment(
+    attachment_id: &str,
+    request: &AttachmentRequest,
+    max_bytes: usize,
+) -> Result<(AttachmentKind, Vec<AttachmentSection>), (StatusCode, String)> {
+    if request.name.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The attachment has no name".to_string(),
+        ));
+    }
+    if request.content.len() > max_bytes {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "The attachment is {} bytes, attachments are limited to {} bytes",
+                request.content.len(),
+                max_bytes
+            ),
+        ));
+    }
+
+    let kind = AttachmentKind::detect(
+        request.content_type.as_deref(),
+        &request.name,
+        &request.content,
+    );
+    let sections = chunk_attachment(attachment_id, &request.name, kind, &request.content)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if sections.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The attachment is empty".to_string(),
+        ));
+    }
+    Ok((kind, sections))
+}
+
+/// Unix seconds `ttl_secs` from now.
+pub fn expires_at(ttl_secs: u64) -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+        + ttl_secs
+}
+
+/// Records the attachment, the record of the conversation expires with its latest attachment.
+pub fn save_attachment(
+    redis_url: &str,
+    conversation_id: &str,
+    record: &AttachmentRecord,
+    ttl_secs: u64,
+) -> Result<()> {
+    let key = attachments_key(conversation_id);
+    let mut conn = establish_redis_connection(redis_url)?;
+    let _: () = conn.hset(&key, &record.attachment_id, serde_json::to_string(record)?)?;
+    let _: () = conn.expire(&key, ttl_secs as usize)?;
+    Ok(())
+}
+
+/// Whether the conversation has unexpired attachments. A failed lookup counts as none, the
+/// questions are then answered from the code only.
+pub fn conversation_has_attachments(redis_url: &str, conversation_id: &str) -> bool {
+    let exists = establish_redis_connection(redis_url)
+        .and_then(|mut conn| conn.exists::<_, bool>(attachments_key(conversation_id)));
+    exists.unwrap_or_else(|e| {
+        log::error!(
+            "Failed to look up the attachments of conversation {}: {}",
+            conversation_id,
+            e
+        );
+        false
+    })
+}
+
+/// Forgets the attachments of the conversation.
+pub fn delete_attachment_records(redis_url: &str, conversation_id: &str) -> Result<()> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let _: () = conn.del(attachments_key(conversation_id))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str, content: &str) -> AttachmentRequest {
+        AttachmentRequest {
+            name: name.to_string(),
+            content_type: None,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_attachments_are_checked_before_they_are_indexed() {
+        let spec = r#"{"openapi": "3.0.0", "paths": {"/orders": {"get": {"summary": "List the orders"}}}}"#;
+        let (kind, sections) =
+            prepare_attachment("a1", &request("orders.json", spec), 1024).unwrap();
+        assert_eq!(kind, AttachmentKind::OpenApi);
+        assert_eq!(sections[0].section, "GET /orders");
+        assert_eq!(sections[0].attachment_id, "a1");
+
+        let (status, _) = prepare_attachment("a1", &request("orders.json", spec), 16).unwrap_err();
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (status, _) =
+            prepare_attachment("a1", &request("notes.txt", "\n\n  \n"), 1024).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = prepare_attachment("a1", &request(" ", "notes"), 1024).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
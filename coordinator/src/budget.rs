@@ -0,0 +1,127 @@
+// Spend of the flows run on a conversation. A flow starts with a meter holding what the
+// conversation and its tenant spent so far, its calls are checked against the limits as they are
+// made, and what it spent is added to the graph and to the daily counter of the tenant once it ends.
+
+use std::time::SystemTime;
+
+use common::auth::Tenant;
+use common::budget::{budget_day, BudgetMeter, RedisSpendStore, SpendStore};
+use common::models::TaskList;
+use common::task_graph::graph_model::TrackProcessV1;
+use log::{error, info, warn};
+
+use crate::configuration::{get_budget_config, get_redis_url};
+use crate::controller::error::AgentProcessingError;
+
+// questions kept per subtask once the conversation is degraded.
+pub const DEGRADED_QUESTIONS_PER_SUBTASK: usize = 1;
+
+fn spend_store() -> RedisSpendStore {
+    RedisSpendStore {
+        url: get_redis_url(),
+    }
+}
+
+/// The meter of a flow on the conversation, the spend of the tenant is only read when it has a limit.
+pub fn conversation_meter(tracker: &TrackProcessV1, tenant: &Tenant) -> BudgetMeter {
+    let config = get_budget_config();
+    let conversation_spent = tracker
+        .conversation_budget()
+        .map_or(0.0, |budget| budget.spent_usd);
+    let tenant_spent = match config.tenant_daily_usd {
+        Some(_) => spend_store()
+            .tenant_spent(&tenant.id, budget_day(SystemTime::now()))
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Failed to read the spend of tenant {}, counting from zero: {}",
+                    tenant.id, e
+                );
+                0.0
+            }),
+        None => 0.0,
+    };
+    BudgetMeter::new(config, conversation_spent, tenant_spent)
+}
+
+/// Records what the flow spent, and the limit that stopped it when `error` is one, on the graph
+/// of the conversation and saves it. The spend is added to the daily counter of the tenant.
+pub fn settle_budget(
+    tracker: &mut TrackProcessV1,
+    tenant: &Tenant,
+    meter: &BudgetMeter,
+    error: Option<&anyhow::Error>,
+) {
+    let spent = meter.spent_usd();
+    let exceeded = error.and_then(AgentProcessingError::budget_exceeded);
+    if spent == 0.0 && exceeded.is_none() {
+        return;
+    }
+
+    // flows that failed before the graph was created have no conversation to record it on.
+    if tracker.get_root_node_uuid().is_some() {
+        let recorded = tracker.record_spend(spent).and_then(|_| match exceeded {
+            Some(exceeded) => tracker.record_budget_exceeded(exceeded.clone()),
+            None => Ok(()),
+        });
+        if let Err(e) = recorded {
+            error!("Failed to record the spend on the conversation: {}", e);
+        } else if let Err(e) = tracker.save_task_process_to_redis(&get_redis_url()) {
+            error!("Failed to save the spend of the conversation: {}", e);
+        }
+    }
+    if spent > 0.0 {
+        match spend_store().add_tenant_spend(&tenant.id, budget_day(SystemTime::now()), spent) {
+            Ok(total) => info!(
+                "Tenant {} spent ${:.4} today, ${:.4} on this flow",
+                tenant.id, total, spent
+            ),
+            Err(e) => error!("Failed to count the spend of tenant {}: {}", tenant.id, e),
+        }
+    }
+}
+
+/// Keeps the first `per_subtask` questions of every subtask.
+pub fn trim_questions(task_list: &mut TaskList, per_subtask: usize) {
+    for subtask in task_list
+        .tasks
+        .iter_mut()
+        .flatten()
+        .flat_map(|task| task.subtasks.iter_mut())
+    {
+        subtask.questions.truncate(per_subtask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::models::{Subtask, Task};
+
+    #[test]
+    fn test_degraded_conversation_asks_fewer_questions() {
+        let subtask = |questions: &[&str]| Subtask {
+            subtask: "subtask".to_string(),
+            questions: questions.iter().map(|q| q.to_string()).collect(),
+        };
+        let mut task_list = TaskList {
+            tasks: Some(vec![Task {
+                task: "Retry failed charges".to_string(),
+                subtasks: vec![subtask(&["q1", "q2", "q3"]), subtask(&["q4"])],
+                grounding: None,
+            }]),
+            ask_user: None,
+        };
+
+        trim_questions(&mut task_list, DEGRADED_QUESTIONS_PER_SUBTASK);
+
+        let questions = task_list.tasks.unwrap()[0]
+            .subtasks
+            .iter()
+            .map(|subtask| subtask.questions.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            questions,
+            vec![vec!["q1".to_string()], vec!["q4".to_string()]]
+        );
+    }
+}
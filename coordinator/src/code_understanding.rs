@@ -1,34 +1,166 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use thiserror::Error; 
 
-use common::{models::CodeUnderstandRequest, service_interaction::{service_caller, HttpMethod}, task_graph::graph_model::{QuestionWithAnswer, QuestionWithId}, CodeUnderstanding};
+use common::models::{
+    BatchAnswer, BatchQuestion, BatchStreamItem, CitedAnchor, CodeUnderstandBatchRequest,
+    ResolveAnchorsRequest,
+};
+use common::{models::CodeUnderstandRequest, service_interaction::{service_caller_with_transport, HttpMethod}, task_graph::graph_model::{QuestionWithAnswer, QuestionWithId, TrackProcessV1}, CodeUnderstanding};
+use common::{codeowners::PathOwners, links::WebUrlTemplate, service_interaction::service_caller, AnswerOutcome};
+use common::anchors::AnchorResolution;
+use common::answer_scope::AnswerScope;
+use common::files_involved::{files_involved, prepend_header};
+use common::budget::{BudgetAllowance, BudgetMeter};
+use common::branch::DEFAULT_BRANCH;
+use common::freshness::{FreshnessNote, IndexFreshness};
+use common::generation::{pin_generation, IndexGeneration, DEFAULT_PIN_TTL_SECS};
+use common::grounding::{GroundingIndex, IndexedPaths};
+use common::repo_summary::{RepoSummary, CONDENSED_SUMMARY_CHARS};
+use common::run_manifest::{IndexRunRef, RunManifest};
+use common::task_graph::export::AnswerCitations;
+use common::capabilities::{Capabilities, Capability, Service};
+use common::service_interaction::{capabilities, service_stream_with_transport};
+use common::transport::Transport;
 use futures::future::join_all;
 use tokio::sync::mpsc;
 
-use crate::{configuration::get_code_understanding_url, controller::error::AgentProcessingError};
+use crate::{configuration::{get_code_search_url, get_code_understanding_transport, get_code_understanding_url, get_redis_url, get_staleness_thresholds, get_web_url_template}, controller::error::AgentProcessingError};
+use crate::admission::{self, budgeted_priority, Priority};
+use crate::attachments::conversation_has_attachments;
+use crate::timings::answer_timings;
+
+// key files pinned to a quick answer with a low retrieval confidence.
+const QUICK_ANSWER_KEY_FILES: usize = 3;
 
 // Asynchronously retrieves answers for a set of questions from a codebase,
 // optionally in parallel, and immediately tries to save each answer to Redis as it is received.
+// In parallel, the questions of a subtask are sent as one batch to code understanding builds that
+// advertise it, so that the files they have in common are retrieved once.
+// The questions are answered within what is left of `budget`, which counts the cost of the answers.
+// The answers are checked against the repo and the paths of `scope`, see `guard_answer`.
+// Every request to code understanding waits for its turn in the admission queue with `priority`,
+// a flow past its budget is turned away before it is queued.
+// With `include_verification` the answers come with steps to check them against the cited code,
+// from code understanding builds that advertise them.
+// With `index_generation` the questions search the collections the conversation is pinned to, and
+// the pin is extended.
+// With `index_run`, the run recorded on the conversation, the links of the answers point to the
+// files at the commit it indexed, see `link_answer`.
+// The questions asked one by one with `can_interrupt` may stop at a clarifying question for the
+// user, with code understanding builds that advertise it, see `CodeUnderstanding::clarification`.
 pub async fn get_codebase_answers_for_questions(
     repo_name: String,
     task_id: String,
     generated_questions: &[QuestionWithId],
-    parallel: bool,
     tx: mpsc::Sender<Result<QuestionWithAnswer, AgentProcessingError>>,
-    can_interrupt: bool,
+    budget: &BudgetMeter,
+    options: &AnswerOptions<'_>,
 ) ->  Result<(), AgentProcessingError> {
+    let AnswerOptions {
+        parallel,
+        can_interrupt,
+        pinned_paths,
+        preferences,
+        language,
+        scope,
+        priority,
+        include_verification,
+        index_generation,
+        index_run,
+    } = *options;
+    // the rejections are sent like the errors of the questions, the flows answering in the
+    // background only read the channel.
+    let priority = match budgeted_priority(priority, budget) {
+        Ok(priority) => priority,
+        Err(exceeded) => {
+            tx.send(Err(AgentProcessingError::BudgetExceeded(exceeded)))
+                .await
+                .expect("Failed to send result to channel");
+            return Ok(());
+        }
+    };
+    let index_generation = pinned_generation(&task_id, index_generation);
+    let index_generation = index_generation.as_deref();
     let code_understanding_url = format!("{}/retrieve-code", get_code_understanding_url());
+    let scope = &AnswerScope::new(&repo_name, scope, fetch_indexed_paths(&repo_name).await);
+    let flow = QuestionFlow {
+        url: code_understanding_url,
+        repo_name: &repo_name,
+        task_id: &task_id,
+        pinned_paths,
+        preferences,
+        language,
+        scope,
+        budget,
+        priority,
+        // the questions asked one by one wait for the ones before them.
+        queued: Instant::now(),
+        include_verification,
+        index_generation,
+        index_run,
+    };
 
     if parallel {
+        // questions the batch answered before it failed aren't asked again.
+        let mut answered = HashSet::new();
+        if generated_questions.len() > 1
+            && capabilities(Service::CodeUnderstanding).supports(Capability::AnswerBatch)
+        {
+            let mut request = batch_request(
+                &repo_name,
+                &task_id,
+                generated_questions,
+                pinned_paths,
+                preferences,
+                language,
+                budget
+                    .allowance()
+                    .filter(|_| capabilities(Service::CodeUnderstanding).supports(Capability::Budget)),
+            );
+            request.attachments = uses_attachments(&task_id).then_some(true);
+            request.include_verification = uses_verification(include_verification).then_some(true);
+            request.glossary = uses_glossary().then_some(true);
+            request.index_generation = index_generation.map(str::to_string);
+            let permit = match admission::global().admit(&task_id, priority).await {
+                Ok(permit) => permit,
+                Err(rejected) => {
+                    tx.send(Err(AgentProcessingError::Overloaded(rejected)))
+                        .await
+                        .expect("Failed to send result to channel");
+                    return Ok(());
+                }
+            };
+            let url = format!("{}/answer-batch", get_code_understanding_url());
+            let streamed =
+                answer_batch(url, request, scope, budget, index_run, &tx, &mut answered).await;
+            drop(permit);
+            match streamed {
+                Ok(()) if answered.len() == generated_questions.len() => return Ok(()),
+                Ok(()) => log::warn!(
+                    "The batch on {} left {} questions unanswered, asking them one by one",
+                    repo_name,
+                    generated_questions.len() - answered.len()
+                ),
+                Err(e) => log::warn!(
+                    "Batch answering failed on {} after {} answers, asking the rest one by one: {}",
+                    repo_name,
+                    answered.len(),
+                    e
+                ),
+            }
+        }
+
         // Parallel processing
-        join_all(generated_questions.iter().map(|question_with_id| {
-            let url = code_understanding_url.clone();
-            let repo = repo_name.clone();
-            let task_id = task_id.clone();
+        let unanswered = generated_questions
+            .iter()
+            .filter(|question_with_id| !answered.contains(&question_with_id.id));
+        let flow = &flow;
+        join_all(unanswered.map(|question_with_id| {
             let tx = tx.clone();
             async move {
-                let result = handle_question(url, repo, question_with_id, task_id).await;
+                let result = handle_question(flow, question_with_id, false).await;
                 tx.send(result)
                     .await
                     .expect("Failed to send result to channel");
@@ -38,13 +170,7 @@ pub async fn get_codebase_answers_for_questions(
     } else {
         // Sequential processing, potentially ending early on error
         for question_with_id in generated_questions {
-            let result = handle_question(
-                code_understanding_url.clone(),
-                repo_name.clone(),
-                question_with_id,
-                task_id.clone(),
-            )
-            .await;
+            let result = handle_question(&flow, question_with_id, can_interrupt).await;
             tx.send(result)
                 .await
                 .expect("Failed to send result to channel");
@@ -64,33 +190,105 @@ pub async fn get_codebase_answers_for_questions(
     Ok(())
 }
 
-async fn handle_question(
+/// How the questions of `get_codebase_answers_for_questions` are asked, the same for all of them.
+/// The questions are sent one by one unless `parallel`, see there for the others.
+#[derive(Clone, Copy)]
+pub struct AnswerOptions<'a> {
+    pub parallel: bool,
+    pub can_interrupt: bool,
+    pub pinned_paths: &'a [String],
+    pub preferences: Option<&'a str>,
+    pub language: Option<&'a str>,
+    pub scope: &'a [String],
+    pub priority: Priority,
+    pub include_verification: bool,
+    pub index_generation: Option<&'a IndexGeneration>,
+    pub index_run: Option<&'a IndexRunRef>,
+}
+
+// what the questions sent by one `get_codebase_answers_for_questions` share, with the priority
+// left by the budget and the generation the conversation is pinned to.
+struct QuestionFlow<'a> {
     url: String,
-    repo_name: String,
+    repo_name: &'a str,
+    task_id: &'a str,
+    pinned_paths: &'a [String],
+    preferences: Option<&'a str>,
+    language: Option<&'a str>,
+    scope: &'a AnswerScope,
+    budget: &'a BudgetMeter,
+    priority: Priority,
+    queued: Instant,
+    include_verification: bool,
+    index_generation: Option<&'a str>,
+    index_run: Option<&'a IndexRunRef>,
+}
+
+async fn handle_question(
+    flow: &QuestionFlow<'_>,
     question_with_id: &QuestionWithId,
-    task_id: String,
+    ask_user: bool,
 ) -> Result<QuestionWithAnswer, AgentProcessingError> {
-    let mut query_params = HashMap::new();
-    query_params.insert("query".to_string(), question_with_id.text.clone());
-    query_params.insert("repo".to_string(), repo_name);
-    query_params.insert("question_id".to_string(), question_with_id.id.to_string());
-    query_params.insert("task_id".to_string(), task_id.to_string());
+    let code_understanding = capabilities(Service::CodeUnderstanding);
+    let mut query_params = question_query_params(
+        flow.repo_name,
+        question_with_id,
+        flow.task_id,
+        flow.pinned_paths,
+        flow.preferences,
+        flow.language,
+        &code_understanding,
+    );
+    if code_understanding.supports(Capability::Budget) {
+        query_params.extend(budget_query_params(flow.budget.allowance()));
+    }
+    if uses_attachments(flow.task_id) {
+        query_params.insert("attachments".to_string(), "true".to_string());
+    }
+    if uses_verification(flow.include_verification) {
+        query_params.insert("include_verification".to_string(), "true".to_string());
+    }
+    if uses_glossary() {
+        query_params.insert("glossary".to_string(), "true".to_string());
+    }
+    if let Some(index_generation) = flow.index_generation {
+        query_params.insert("index_generation".to_string(), index_generation.to_string());
+    }
+    if ask_user && code_understanding.supports(Capability::AskUser) {
+        query_params.insert("ask_user".to_string(), "true".to_string());
+    }
 
-    let response = service_caller::<CodeUnderstandRequest, CodeUnderstanding>(
-        url,
+    let permit = admission::global()
+        .admit(flow.task_id, flow.priority)
+        .await
+        .map_err(AgentProcessingError::Overloaded)?;
+    let sent = Instant::now();
+    let response = service_caller_with_transport::<CodeUnderstandRequest, CodeUnderstanding>(
+        flow.url.clone(),
         HttpMethod::GET,
         None,
         Some(query_params),
+        supported_transport(get_code_understanding_transport(), &code_understanding),
     ).await;
+    drop(permit);
 
     // Call the code understanding service and map the response
-    response
-        .map(|answer| QuestionWithAnswer {
-            question_id: question_with_id.id,
-            question: question_with_id.text.clone(),
-            answer,
-        })
-        .map_err(AgentProcessingError::from)
+    let mut answer = response.map_err(AgentProcessingError::from)?;
+    answer.timings = Some(answer_timings(
+        answer.timings,
+        sent.duration_since(flow.queued),
+        sent.elapsed(),
+    ));
+    flow.budget.add_spend(answer.cost_usd.unwrap_or_default());
+    attach_owners(flow.repo_name, &mut answer).await;
+    guard_answer(flow.scope, &mut answer);
+    list_files_involved(&mut answer);
+    link_answer(flow.repo_name, flow.index_run, &mut answer);
+    Ok(QuestionWithAnswer {
+        question_id: question_with_id.id,
+        question: question_with_id.text.clone(),
+        answer,
+    })
     // send a dummy answer
     // Ok(QuestionWithAnswer{
     //     question_id: question_with_id.id,
@@ -101,4 +299,995 @@ async fn handle_question(
     //         answer: "Dummy answer".to_string(),
     //     }
     // })
-}
\ No newline at end of file
+}
+
+// Answers the questions of the batch in one request, in the configured transport. The answers are
+// streamed back as code understanding writes them, each one is sent to `tx` and its question id
+// added to `answered` as soon as it is read. The answers get their owners, scope checks and links
+// like the ones of single questions, a question that failed in the batch is sent as its error.
+async fn answer_batch(
+    url: String,
+    request: CodeUnderstandBatchRequest,
+    scope: &AnswerScope,
+    budget: &BudgetMeter,
+    index_run: Option<&IndexRunRef>,
+    tx: &mpsc::Sender<Result<QuestionWithAnswer, AgentProcessingError>>,
+    answered: &mut HashSet<usize>,
+) -> Result<(), AgentProcessingError> {
+    let repo_name = request.repo.clone();
+    let transport = supported_transport(
+        get_code_understanding_transport(),
+        &capabilities(Service::CodeUnderstanding),
+    );
+    let mut stream =
+        service_stream_with_transport(url, HttpMethod::POST, Some(request), None, transport)
+            .await?;
+
+    while let Some(item) = stream.next::<BatchStreamItem>().await? {
+        let batch_answer = match item {
+            BatchStreamItem::Answer(batch_answer) => batch_answer,
+            BatchStreamItem::Done { cost_usd } => {
+                budget.add_spend(cost_usd);
+                return Ok(());
+            }
+        };
+        log::debug!(
+            "Question {} of the batch: {:?}",
+            batch_answer.question_id,
+            batch_answer.trace
+        );
+        answered.insert(batch_answer.question_id);
+        let result = batch_answer_result(&repo_name, scope, index_run, batch_answer).await;
+        tx.send(result)
+            .await
+            .expect("Failed to send result to channel");
+    }
+    Err(AgentProcessingError::CodeUnderStandingAgentCallFailed(
+        "The batch reply ended before its cost".to_string(),
+    ))
+}
+
+// The answer of a question of the batch, checked and linked, or the error that stopped it.
+async fn batch_answer_result(
+    repo_name: &str,
+    scope: &AnswerScope,
+    index_run: Option<&IndexRunRef>,
+    batch_answer: BatchAnswer,
+) -> Result<QuestionWithAnswer, AgentProcessingError> {
+    if let Some(exceeded) = batch_answer.budget_exceeded {
+        return Err(AgentProcessingError::BudgetExceeded(exceeded));
+    }
+    if let Some(gone) = batch_answer.index_generation_gone {
+        return Err(AgentProcessingError::IndexGenerationGone(gone));
+    }
+    let Some(mut answer) = batch_answer.answer else {
+        let error = batch_answer
+            .error
+            .unwrap_or_else(|| "The batch returned no answer".to_string());
+        return Err(AgentProcessingError::CodeUnderStandingAgentCallFailed(
+            error,
+        ));
+    };
+    attach_owners(repo_name, &mut answer).await;
+    guard_answer(scope, &mut answer);
+    list_files_involved(&mut answer);
+    link_answer(repo_name, index_run, &mut answer);
+    Ok(QuestionWithAnswer {
+        question_id: batch_answer.question_id,
+        question: batch_answer.query,
+        answer,
+    })
+}
+
+fn batch_request(
+    repo_name: &str,
+    task_id: &str,
+    questions: &[QuestionWithId],
+    pinned_paths: &[String],
+    preferences: Option<&str>,
+    language: Option<&str>,
+    budget: Option<BudgetAllowance>,
+) -> CodeUnderstandBatchRequest {
+    CodeUnderstandBatchRequest {
+        repo: repo_name.to_string(),
+        task_id: task_id.to_string(),
+        questions: questions
+            .iter()
+            .map(|question| BatchQuestion {
+                question_id: question.id,
+                query: question.text.clone(),
+            })
+            .collect(),
+        pinned_paths: pinned_paths.to_vec(),
+        preferences: preferences.map(str::to_string),
+        language: language.map(str::to_string),
+        related_usage: None,
+        include_tests: None,
+        data_flow: None,
+        budget,
+        attachments: None,
+        include_verification: None,
+        index_generation: None,
+        terminology: None,
+        glossary: None,
+    }
+}
+
+// The budget parameters of a question, left out when the conversation has no limit.
+fn budget_query_params(allowance: Option<BudgetAllowance>) -> HashMap<String, String> {
+    let Some(allowance) = allowance else {
+        return HashMap::new();
+    };
+    HashMap::from([
+        ("budget_scope".to_string(), allowance.scope.as_str().to_string()),
+        ("budget_limit_usd".to_string(), allowance.limit_usd.to_string()),
+        ("budget_spent_usd".to_string(), allowance.spent_usd.to_string()),
+    ])
+}
+
+// Query parameters of the code understanding request for a question.
+// The optional parameters are left out for builds that don't advertise them.
+fn question_query_params(
+    repo_name: &str,
+    question_with_id: &QuestionWithId,
+    task_id: &str,
+    pinned_paths: &[String],
+    preferences: Option<&str>,
+    language: Option<&str>,
+    code_understanding: &Capabilities,
+) -> HashMap<String, String> {
+    let mut query_params = HashMap::new();
+    query_params.insert("query".to_string(), question_with_id.text.clone());
+    query_params.insert("repo".to_string(), repo_name.to_string());
+    query_params.insert("question_id".to_string(), question_with_id.id.to_string());
+    query_params.insert("task_id".to_string(), task_id.to_string());
+    if !pinned_paths.is_empty() && code_understanding.supports(Capability::PinnedPaths) {
+        query_params.insert("pinned_paths".to_string(), pinned_paths.join(","));
+    }
+    if let Some(preferences) = preferences {
+        if code_understanding.supports(Capability::Preferences) {
+            query_params.insert("preferences".to_string(), preferences.to_string());
+        }
+    }
+    if let Some(language) = language {
+        if code_understanding.supports(Capability::Language) {
+            query_params.insert("language".to_string(), language.to_string());
+        }
+    }
+    // the run of the question resumes with the response of the user to its clarifying question.
+    if let Some(clarification) = &question_with_id.clarification {
+        if code_understanding.supports(Capability::AskUser) {
+            query_params.insert("clarification".to_string(), clarification.clone());
+        }
+    }
+    query_params
+}
+
+// Whether the questions of the conversation search its attachments, it needs some and a code
+// understanding build that advertises them.
+fn uses_attachments(conversation_id: &str) -> bool {
+    capabilities(Service::CodeUnderstanding).supports(Capability::Attachments)
+        && conversation_has_attachments(&get_redis_url(), conversation_id)
+}
+
+// The parameter of the generation the questions search, sent to code understanding builds that
+// advertise it. The pin of the conversation is extended on the way, a failure only shortens it.
+fn pinned_generation(conversation_id: &str, generation: Option<&IndexGeneration>) -> Option<String> {
+    let generation = generation
+        .filter(|_| capabilities(Service::CodeUnderstanding).supports(Capability::IndexGeneration))?;
+    if let Err(e) = pin_generation(&get_redis_url(), conversation_id, generation, DEFAULT_PIN_TTL_SECS) {
+        log::warn!("Failed to extend the index generation pin of {}: {}", conversation_id, e);
+    }
+    Some(generation.to_string())
+}
+
+// Verification steps are only asked from code understanding builds that advertise them, older
+// builds answer without them.
+fn uses_verification(include_verification: bool) -> bool {
+    include_verification && capabilities(Service::CodeUnderstanding).supports(Capability::Verification)
+}
+
+// The answers keep to the glossary of the conversation with code understanding builds that
+// advertise it, they read it from the graph saved as each answer comes in.
+fn uses_glossary() -> bool {
+    capabilities(Service::CodeUnderstanding).supports(Capability::Glossary)
+}
+
+// msgpack is only used with code understanding builds that advertise it.
+fn supported_transport(transport: Transport, code_understanding: &Capabilities) -> Transport {
+    if transport == Transport::Msgpack && !code_understanding.supports(Capability::Msgpack) {
+        return Transport::Json;
+    }
+    transport
+}
+
+// Sets the owners of every code context of the answer, from the CODEOWNERS file of the repo.
+async fn attach_owners(repo_name: &str, answer: &mut CodeUnderstanding) {
+    if !capabilities(Service::CodeSearch).supports(Capability::CodeOwners) {
+        return;
+    }
+    let mut paths = answer.context.iter().map(|c| c.path.clone()).collect::<Vec<_>>();
+    if let Some(AnswerOutcome::Answered { contexts, .. }) = &answer.outcome {
+        paths.extend(contexts.iter().map(|c| c.path.clone()));
+    }
+    paths.sort();
+    paths.dedup();
+
+    let owners = join_all(paths.iter().map(|path| fetch_owners(repo_name, path))).await;
+    let owners_by_path = paths.into_iter().zip(owners).collect::<HashMap<_, _>>();
+
+    let outcome_contexts = match &mut answer.outcome {
+        Some(AnswerOutcome::Answered { contexts, .. }) => Some(contexts),
+        _ => None,
+    };
+    for context in answer
+        .context
+        .iter_mut()
+        .chain(outcome_contexts.into_iter().flatten())
+    {
+        context.owners = owners_by_path.get(&context.path).cloned().unwrap_or_default();
+    }
+}
+
+// The owners are a hint, a failed lookup leaves the context without them.
+async fn fetch_owners(repo_name: &str, path: &str) -> Vec<String> {
+    let url = format!("{}/repos/{}/owners", get_code_search_url(), repo_name);
+    let query_params = HashMap::from([("path".to_string(), path.to_string())]);
+    match service_caller::<(), PathOwners>(url, HttpMethod::GET, None, Some(query_params)).await {
+        Ok(owners) => owners.owners,
+        Err(e) => {
+            log::warn!("Failed to look up the owners of {} in {}: {}", path, repo_name, e);
+            Vec::new()
+        }
+    }
+}
+
+// Softens the sentences of the answer recommending changes out of the scope of the conversation and
+// records the paths on the answer for the graph and the webhook. It runs before the links are
+// rewritten to urls, on the paths as the answer cites them.
+fn guard_answer(scope: &AnswerScope, answer: &mut CodeUnderstanding) {
+    if scope.is_unrestricted() {
+        return;
+    }
+    answer.citations.check_scope(scope);
+    let (guarded, violations) = scope.guard(&answer.answer);
+    answer.answer = guarded;
+    if let Some(AnswerOutcome::Answered { answer, .. }) = &mut answer.outcome {
+        *answer = scope.guard(answer).0;
+    }
+    answer.scope_violations = violations;
+}
+
+// Lists the files the answer cites in a header above it and in `files_involved`, from the
+// citations left valid and in scope. It runs after the scope checks and before the links are
+// rewritten to urls.
+fn list_files_involved(answer: &mut CodeUnderstanding) {
+    answer.files_involved = files_involved(&answer.answer, &answer.citations);
+    if answer.files_involved.is_empty() {
+        return;
+    }
+    answer.answer = prepend_header(&answer.answer, &answer.files_involved);
+    if let Some(AnswerOutcome::Answered { answer: outcome, .. }) = &mut answer.outcome {
+        *outcome = prepend_header(outcome, &answer.files_involved);
+    }
+}
+
+// Rewrites the relative links and quoted code of the answer to urls of the web UI of the repo, at
+// the commit of the index run recorded on the conversation, and records the urls on its
+// citations. The template the run was indexed with is used, or the one of the coordinator. The
+// graph and its exports keep the rewritten answer. Without a template or a recorded run the links
+// are left as they are.
+fn link_answer(repo_name: &str, index_run: Option<&IndexRunRef>, answer: &mut CodeUnderstanding) {
+    let Some(run) = index_run.filter(|run| !run.indexed_commit.is_empty()) else {
+        log::debug!(
+            "No index run recorded for {}, the links of the answer are kept",
+            repo_name
+        );
+        return;
+    };
+    let Some(template) = run
+        .web_url_template
+        .clone()
+        .map(WebUrlTemplate::new)
+        .or_else(get_web_url_template)
+    else {
+        return;
+    };
+    let commit = run.indexed_commit.as_str();
+
+    // the citations code understanding found invalid keep their relative links.
+    let citations = &mut answer.citations;
+    answer.answer =
+        template.rewrite_links_with(&answer.answer, repo_name, commit, |path, lines, url| {
+            citations.link(path, lines, url)
+        });
+    if let Some(AnswerOutcome::Answered { answer: outcome, .. }) = &mut answer.outcome {
+        *outcome = template.rewrite_links_with(outcome, repo_name, commit, |path, lines, url| {
+            citations.link(path, lines, url)
+        });
+    }
+}
+
+/// Summary of the repo generated when it was indexed, for the task generation prompt and the
+/// grounding of the tasks. None for repos indexed before summaries were generated or when code
+/// search can't serve it.
+pub async fn fetch_repo_summary(repo_name: &str) -> Option<RepoSummary> {
+    if !capabilities(Service::CodeSearch).supports(Capability::RepoSummary) {
+        return None;
+    }
+    let url = format!("{}/repos/{}/summary", get_code_search_url(), repo_name);
+    match service_caller::<(), RepoSummary>(url, HttpMethod::GET, None, None).await {
+        Ok(summary) if !summary.is_empty() => Some(summary),
+        Ok(_) => None,
+        Err(e) => {
+            log::warn!("No summary of {}, the tasks are generated without it: {}", repo_name, e);
+            None
+        }
+    }
+}
+
+/// The condensed summary added to the task generation prompt.
+pub fn condensed_repo_summary(summary: Option<&RepoSummary>) -> Option<String> {
+    summary.map(|summary| summary.condensed(CONDENSED_SUMMARY_CHARS))
+}
+
+/// The indexed paths of the repo and the components of its summary, the generated tasks are
+/// grounded against them. None when code search can't list the paths, the tasks aren't checked.
+pub async fn fetch_grounding_index(
+    repo_name: &str,
+    summary: Option<&RepoSummary>,
+) -> Option<GroundingIndex> {
+    let paths = fetch_indexed_paths(repo_name).await?;
+    Some(GroundingIndex::new(paths, summary)).filter(|index| !index.is_empty())
+}
+
+/// The indexed paths of the repo, None when code search can't list them.
+pub async fn fetch_indexed_paths(repo_name: &str) -> Option<Vec<String>> {
+    if !capabilities(Service::CodeSearch).supports(Capability::IndexedPaths) {
+        return None;
+    }
+    let url = format!("{}/repos/{}/paths", get_code_search_url(), repo_name);
+    match service_caller::<(), IndexedPaths>(url, HttpMethod::GET, None, None).await {
+        Ok(indexed) => Some(indexed.paths),
+        Err(e) => {
+            log::warn!("No indexed paths of {}, the tasks and answers aren't checked against them: {}", repo_name, e);
+            None
+        }
+    }
+}
+
+/// The top key files of the repo, pinned to a quick answer whose query names none of the indexed
+/// components, so the agent starts from the entrypoints and routes rather than blind.
+/// Empty when the query matches the index or when code search can't tell.
+pub async fn low_confidence_pins(repo_name: &str, query: &str, threshold: f32) -> Vec<String> {
+    let Some(summary) = fetch_repo_summary(repo_name).await else {
+        return Vec::new();
+    };
+    if summary.key_files.is_empty() {
+        return Vec::new();
+    }
+    match fetch_grounding_index(repo_name, Some(&summary)).await {
+        Some(index) => key_file_pins(&summary, &index, query, threshold),
+        None => Vec::new(),
+    }
+}
+
+fn key_file_pins(summary: &RepoSummary, index: &GroundingIndex, query: &str, threshold: f32) -> Vec<String> {
+    let confidence = index.query_confidence(query);
+    if confidence >= threshold {
+        return Vec::new();
+    }
+    log::info!(
+        "Retrieval confidence {:.2} of {:?} is low, pinning the key files of {}",
+        confidence,
+        query,
+        summary.repo_name
+    );
+    summary.key_files.iter().take(QUICK_ANSWER_KEY_FILES).cloned().collect()
+}
+
+/// The ingestion run of the index of the repo, recorded on new conversations.
+/// None for repos indexed before manifests were recorded or when code search can't serve it.
+pub async fn fetch_index_run(repo_name: &str) -> Option<IndexRunRef> {
+    if !capabilities(Service::CodeSearch).supports(Capability::RunManifest) {
+        return None;
+    }
+    let url = format!("{}/repos/{}/manifest", get_code_search_url(), repo_name);
+    match service_caller::<(), RunManifest>(url, HttpMethod::GET, None, None).await {
+        Ok(manifest) => Some(manifest.reference()),
+        Err(e) => {
+            log::warn!("No run manifest of {}, the conversation won't record it: {}", repo_name, e);
+            None
+        }
+    }
+}
+
+/// The collections the aliases of code search point to now, None when code search can't tell.
+/// The conversation then searches through the aliases.
+pub async fn fetch_index_generation() -> Option<IndexGeneration> {
+    if !capabilities(Service::CodeSearch).supports(Capability::IndexGeneration) {
+        return None;
+    }
+    let url = format!("{}/index-generation", get_code_search_url());
+    match service_caller::<(), IndexGeneration>(url, HttpMethod::GET, None, None).await {
+        Ok(generation) => Some(generation),
+        Err(e) => {
+            log::warn!("No index generation, the conversation won't be pinned to one: {}", e);
+            None
+        }
+    }
+}
+
+/// Pins the conversation to the current index generation: records it on the root, replacing the
+/// one it was pinned to before, and registers the pin so that the collections aren't dropped while
+/// the conversation reads them.
+pub async fn pin_index_generation(tracker: &mut TrackProcessV1) -> Result<(), anyhow::Error> {
+    let Some(generation) = fetch_index_generation().await else {
+        return Ok(());
+    };
+    log::info!("Pinning the conversation to {}", generation.render());
+    tracker.record_index_generation(generation.clone())?;
+    if let Some(conversation_id) = tracker.get_root_node_uuid() {
+        if let Err(e) =
+            pin_generation(&get_redis_url(), &conversation_id, &generation, DEFAULT_PIN_TTL_SECS)
+        {
+            log::warn!("Failed to register the index generation pin of {}: {}", conversation_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// How far the index of the repo is behind its branch, recorded on new conversations. A failed
+/// lookup is noted as an unknown freshness, None when code search can't probe it.
+pub async fn fetch_freshness(repo_name: &str) -> Option<FreshnessNote> {
+    if !capabilities(Service::CodeSearch).supports(Capability::Freshness) {
+        return None;
+    }
+    let url = format!("{}/repos/{}/freshness", get_code_search_url(), repo_name);
+    match service_caller::<(), IndexFreshness>(url, HttpMethod::GET, None, None).await {
+        Ok(freshness) => {
+            if let Some(reason) = &freshness.unknown_reason {
+                log::info!("Freshness of the index of {} is unknown: {}", repo_name, reason);
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0);
+            Some(get_staleness_thresholds().note(&freshness, now))
+        }
+        Err(e) => {
+            log::warn!("Failed to look up the freshness of the index of {}: {}", repo_name, e);
+            Some(FreshnessNote::unknown(DEFAULT_BRANCH))
+        }
+    }
+}
+
+/// Re-resolves the anchored citations of the answers against the current index of the repo and
+/// records the drift of the ones whose lines changed. The citations stay as they were recorded
+/// when code search can't resolve anchors.
+pub async fn resolve_citation_drift(repo_name: &str, answers: &mut [AnswerCitations]) {
+    if !capabilities(Service::CodeSearch).supports(Capability::AnchorResolution) {
+        return;
+    }
+    let (cited, anchors): (Vec<(usize, usize)>, Vec<CitedAnchor>) = answers
+        .iter()
+        .enumerate()
+        .flat_map(|(answer, report)| {
+            report
+                .citations
+                .citations
+                .iter()
+                .enumerate()
+                .filter_map(move |(index, citation)| {
+                    let anchor = CitedAnchor {
+                        path: citation.path.clone(),
+                        anchor: citation.anchor.clone()?,
+                        lines: citation.lines,
+                    };
+                    Some(((answer, index), anchor))
+                })
+        })
+        .unzip();
+    if anchors.is_empty() {
+        return;
+    }
+
+    let url = format!("{}/anchors/resolve", get_code_search_url());
+    let request = ResolveAnchorsRequest {
+        repo: repo_name.to_string(),
+        branch: None,
+        anchors,
+    };
+    match service_caller::<ResolveAnchorsRequest, Vec<AnchorResolution>>(
+        url,
+        HttpMethod::POST,
+        Some(request),
+        None,
+    )
+    .await
+    {
+        Ok(resolutions) => {
+            for ((answer, index), resolution) in cited.into_iter().zip(resolutions) {
+                answers[answer].citations.citations[index].drift =
+                    resolution.drifted().then_some(resolution);
+            }
+        }
+        Err(e) => log::warn!("Failed to resolve the citation anchors of {}: {}", repo_name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::budget::BudgetScope;
+    use common::capabilities::ServiceVersion;
+    use common::grounding::DEFAULT_GROUNDING_CONFIDENCE;
+
+    #[test]
+    fn test_answer_request_carries_the_preferences() {
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1/");
+        tracker.initialize_graph();
+        assert!(tracker.record_preferences("Be concise please.").unwrap());
+
+        let preferences = tracker.preferences().render();
+        let question = QuestionWithId {
+            id: 1,
+            text: "Where are the tokens refreshed?".to_string(),
+            clarification: None,
+        };
+        let query_params = question_query_params(
+            "repo",
+            &question,
+            "task",
+            &[],
+            preferences.as_deref(),
+            None,
+            &Capabilities::Unknown,
+        );
+
+        assert!(query_params["preferences"].contains("Keep the answer concise"));
+
+        let without = question_query_params(
+            "repo",
+            &question,
+            "task",
+            &[],
+            None,
+            None,
+            &Capabilities::Unknown,
+        );
+        assert!(!without.contains_key("preferences"));
+    }
+
+    #[test]
+    fn test_answer_request_carries_the_language() {
+        let question = QuestionWithId {
+            id: 1,
+            text: "Wo werden die Tokens erneuert?".to_string(),
+            clarification: None,
+        };
+        let query_params = question_query_params(
+            "repo",
+            &question,
+            "task",
+            &[],
+            None,
+            Some("German"),
+            &Capabilities::Unknown,
+        );
+        assert_eq!(query_params["language"], "German");
+
+        let old = Capabilities::Advertised(ServiceVersion::legacy(Service::CodeUnderstanding));
+        let query_params =
+            question_query_params("repo", &question, "task", &[], None, Some("German"), &old);
+        assert!(!query_params.contains_key("language"));
+    }
+
+    #[test]
+    fn test_answer_request_carries_the_clarification() {
+        let question = QuestionWithId {
+            id: 1,
+            text: "How are failed requests retried?".to_string(),
+            clarification: Some("the payment refunds".to_string()),
+        };
+        let query_params =
+            question_query_params("repo", &question, "task", &[], None, None, &Capabilities::Unknown);
+        assert_eq!(query_params["clarification"], "the payment refunds");
+
+        let old = Capabilities::Advertised(ServiceVersion::legacy(Service::CodeUnderstanding));
+        let query_params = question_query_params("repo", &question, "task", &[], None, None, &old);
+        assert!(!query_params.contains_key("clarification"));
+    }
+
+    #[test]
+    fn test_answer_request_carries_the_budget_allowance() {
+        let allowance = BudgetAllowance {
+            scope: BudgetScope::Conversation,
+            limit_usd: 0.5,
+            spent_usd: 0.125,
+        };
+        let query_params = budget_query_params(Some(allowance));
+        assert_eq!(query_params["budget_scope"], "conversation");
+        assert_eq!(query_params["budget_limit_usd"], "0.5");
+        assert_eq!(query_params["budget_spent_usd"], "0.125");
+        assert!(budget_query_params(None).is_empty());
+
+        let request = batch_request("repo", "task", &[], &[], None, None, Some(allowance));
+        let question = BatchQuestion {
+            question_id: 1,
+            query: "Where are the tokens refreshed?".to_string(),
+        };
+        assert_eq!(request.question_request(&question).budget_allowance(), Some(allowance));
+    }
+
+    #[test]
+    fn test_batch_request_keeps_the_question_ids() {
+        let questions = vec![
+            QuestionWithId {
+                id: 3,
+                text: "Where are the tokens refreshed?".to_string(),
+                clarification: None,
+            },
+            QuestionWithId {
+                id: 4,
+                text: "How long are the tokens valid?".to_string(),
+                clarification: None,
+            },
+        ];
+        let request = batch_request(
+            "repo",
+            "task",
+            &questions,
+            &["src/auth.rs".to_string()],
+            None,
+            Some("German"),
+            None,
+        );
+
+        let ids = request.questions.iter().map(|q| q.question_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![3, 4]);
+        assert_eq!(request.questions[1].query, questions[1].text);
+        assert_eq!(request.pinned_paths, vec!["src/auth.rs".to_string()]);
+        assert_eq!(request.language.as_deref(), Some("German"));
+        assert_eq!(request.question_request(&request.questions[0]).question_id, 3);
+    }
+
+    #[test]
+    fn test_key_files_are_pinned_when_the_query_names_nothing_indexed() {
+        let summary = RepoSummary {
+            repo_name: "acme/shop".to_string(),
+            key_files: ["src/main.rs", "src/routes.rs", "src/config.rs", "src/orders.rs"]
+                .iter()
+                .map(|path| path.to_string())
+                .collect(),
+            ..Default::default()
+        };
+        let paths = ["src/main.rs", "src/routes.rs", "src/config.rs", "src/orders.rs", "src/payments.rs"];
+        let index = GroundingIndex::new(paths.iter().map(|path| path.to_string()).collect(), Some(&summary));
+
+        let pins = key_file_pins(&summary, &index, "How does this app start up?", DEFAULT_GROUNDING_CONFIDENCE);
+        assert_eq!(pins, vec!["src/main.rs", "src/routes.rs", "src/config.rs"]);
+
+        let pins = key_file_pins(&summary, &index, "Where are the payments refunded?", DEFAULT_GROUNDING_CONFIDENCE);
+        assert!(pins.is_empty());
+    }
+
+    #[test]
+    fn test_answer_and_outcome_get_the_files_involved() {
+        let text = "Refunds start in [the handler](src/refunds.rs#L4-L20), \
+                    the [`Ledger`](src/ledger.rs#L1-L9) records them.";
+        let mut citations = common::citations::CitationReport::parse(text);
+        for citation in &mut citations.citations {
+            citation.status = common::citations::CitationStatus::Valid;
+        }
+        let mut answer = CodeUnderstanding {
+            context: vec![],
+            question: "How are refunds recorded?".to_string(),
+            answer: text.to_string(),
+            outcome: Some(AnswerOutcome::Answered {
+                answer: text.to_string(),
+                contexts: vec![],
+            }),
+            missing_pinned_paths: vec![],
+            cost_usd: None,
+            timings: None,
+            scope_violations: vec![],
+            citations,
+            verification: Default::default(),
+            prompt_versions: Default::default(),
+            files_involved: Default::default(),
+            index_mode: Default::default(),
+        };
+
+        list_files_involved(&mut answer);
+
+        let header = "Files involved: src/refunds.rs (Refunds start in the handler), \
+                      src/ledger.rs (the Ledger records them)";
+        assert_eq!(answer.answer, format!("{}\n\n{}", header, text));
+        assert_eq!(
+            answer.outcome,
+            Some(AnswerOutcome::Answered {
+                answer: format!("{}\n\n{}", header, text),
+                contexts: vec![],
+            })
+        );
+        assert_eq!(answer.files_involved.len(), 2);
+    }
+
+    #[test]
+    fn test_answer_links_point_to_the_commit_of_the_recorded_run() {
+        let text = "Refunds start in [the handler](src/refunds.rs#L4-L20).";
+        let answer = || CodeUnderstanding {
+            context: vec![],
+            question: "How are refunds recorded?".to_string(),
+            answer: text.to_string(),
+            outcome: None,
+            missing_pinned_paths: vec![],
+            cost_usd: None,
+            timings: None,
+            scope_violations: vec![],
+            citations: Default::default(),
+            verification: Default::default(),
+            prompt_versions: Default::default(),
+            files_involved: Default::default(),
+            index_mode: Default::default(),
+        };
+        let run = IndexRunRef {
+            run_id: "run-1".to_string(),
+            indexer_version: "0.1.0".to_string(),
+            indexed_commit: "4f2a9c1d".to_string(),
+            embedding_model: "model".to_string(),
+            index_mode: Default::default(),
+            web_url_template: Some(
+                "https://github.com/{org}/{repo}/blob/{commit}/{path}#L{start}-L{end}".to_string(),
+            ),
+        };
+
+        let mut linked = answer();
+        link_answer("acme/api", Some(&run), &mut linked);
+        assert_eq!(
+            linked.answer,
+            "Refunds start in \
+             [the handler](https://github.com/acme/api/blob/4f2a9c1d/src/refunds.rs#L4-L20)."
+        );
+
+        // no run recorded, or one indexed without a template, keeps the relative links.
+        let mut unlinked = answer();
+        link_answer("acme/api", None, &mut unlinked);
+        assert_eq!(unlinked.answer, text);
+        let untemplated = IndexRunRef {
+            web_url_template: None,
+            ..run
+        };
+        link_answer("acme/api", Some(&untemplated), &mut unlinked);
+        assert_eq!(unlinked.answer, text);
+    }
+
+    #[test]
+    fn test_old_code_understanding_gets_the_older_request() {
+        let old = Capabilities::Advertised(ServiceVersion::legacy(Service::CodeUnderstanding));
+        let question = QuestionWithId {
+            id: 1,
+            text: "Where are the tokens refreshed?".to_string(),
+            clarification: None,
+        };
+        let pinned_paths = vec!["src/auth.rs:10-40".to_string()];
+
+        let query_params = question_query_params(
+            "repo",
+            &question,
+            "task",
+            &pinned_paths,
+            Some("- Keep the answer concise."),
+            None,
+            &old,
+        );
+        assert!(!query_params.contains_key("pinned_paths"));
+        assert!(!query_params.contains_key("preferences"));
+        assert_eq!(query_params["query"], question.text);
+        assert_eq!(supported_transport(Transport::Msgpack, &old), Transport::Json);
+
+        let current = Capabilities::Advertised(ServiceVersion::new(
+            "code-understanding",
+            "1.0.0",
+            &[Capability::PinnedPaths, Capability::Msgpack],
+        ));
+        let query_params =
+            question_query_params("repo", &question, "task", &pinned_paths, None, None, &current);
+        assert_eq!(query_params["pinned_paths"], "src/auth.rs:10-40");
+        assert_eq!(supported_transport(Transport::Msgpack, &current), Transport::Msgpack);
+    }
+
+    // code understanding taking `delay` to answer, without reporting its own timings.
+    fn slow_code_understanding(
+        delay: std::time::Duration,
+    ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        use warp::Filter;
+        warp::path("retrieve-code")
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(move |params: HashMap<String, String>| async move {
+                tokio::time::sleep(delay).await;
+                Ok::<_, std::convert::Infallible>(warp::reply::json(&CodeUnderstanding {
+                    context: vec![],
+                    question: params.get("query").cloned().unwrap_or_default(),
+                    answer: "In refresh.rs".to_string(),
+                    outcome: None,
+                    missing_pinned_paths: vec![],
+                    cost_usd: None,
+                    timings: None,
+                    scope_violations: vec![],
+                    citations: Default::default(),
+                    verification: Default::default(),
+                    prompt_versions: Default::default(),
+                    files_involved: Default::default(),
+                    index_mode: Default::default(),
+                }))
+            })
+    }
+
+    #[tokio::test]
+    async fn test_questions_asked_one_by_one_record_their_wait() {
+        use common::local_services::{local_url, register, WarpService};
+        use common::service_interaction::set_service_version;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let delay = Duration::from_millis(200);
+        register(
+            "timings-code-understanding",
+            Arc::new(WarpService::new(slow_code_understanding(delay))),
+        );
+        // code search has none of the optional features, no owners or links are looked up.
+        set_service_version(Service::CodeSearch, ServiceVersion::legacy(Service::CodeSearch));
+
+        let url = format!("{}/retrieve-code", local_url("timings-code-understanding"));
+        let budget = BudgetMeter::unlimited(Default::default());
+        let scope = AnswerScope::default();
+        let flow = QuestionFlow {
+            url,
+            repo_name: "repo",
+            task_id: "task",
+            pinned_paths: &[],
+            preferences: None,
+            language: None,
+            scope: &scope,
+            budget: &budget,
+            priority: Priority::Interactive,
+            queued: Instant::now(),
+            include_verification: false,
+            index_generation: None,
+            index_run: None,
+        };
+        let mut timings = Vec::new();
+        for (id, text) in ["Where are the tokens refreshed?", "Who calls the refresh?"]
+            .iter()
+            .enumerate()
+        {
+            let question = QuestionWithId {
+                id,
+                text: text.to_string(),
+                clarification: None,
+            };
+            let answer = handle_question(&flow, &question, false).await.unwrap();
+            timings.push(answer.answer.timings.unwrap());
+        }
+
+        let delay_ms = delay.as_millis() as u64;
+        // the whole round trip is answering when code understanding doesn't report its timings.
+        assert!(timings[0].answer_ms >= delay_ms, "{:?}", timings[0]);
+        assert_eq!(timings[0].retrieval_ms, 0);
+        assert!(timings[0].queue_wait_ms < delay_ms, "{:?}", timings[0]);
+        // the second question waited for the first one.
+        assert!(timings[1].queue_wait_ms >= delay_ms, "{:?}", timings[1]);
+        assert!(timings[1].answer_ms >= delay_ms, "{:?}", timings[1]);
+    }
+
+    // code understanding streaming the answer of the first question, the error of the second, then
+    // the cost of the batch, cut before the cost when `truncated`.
+    fn streaming_code_understanding(
+        truncated: bool,
+    ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        use warp::Filter;
+        warp::path("answer-batch")
+            .and(warp::body::json())
+            .map(move |request: CodeUnderstandBatchRequest| batch_stream(&request, truncated))
+    }
+
+    fn batch_stream(request: &CodeUnderstandBatchRequest, truncated: bool) -> Vec<u8> {
+        let (first, second) = (&request.questions[0], &request.questions[1]);
+        let answer = serde_json::from_value::<CodeUnderstanding>(serde_json::json!({
+            "context": [],
+            "question": first.query,
+            "answer": "In refresh.rs",
+        }))
+        .unwrap();
+        let items = [
+            BatchStreamItem::Answer(BatchAnswer {
+                question_id: first.question_id,
+                query: first.query.clone(),
+                answer: Some(answer),
+                error: None,
+                budget_exceeded: None,
+                index_generation_gone: None,
+                trace: Default::default(),
+            }),
+            BatchStreamItem::Answer(BatchAnswer {
+                question_id: second.question_id,
+                query: second.query.clone(),
+                answer: None,
+                error: Some("The model timed out".to_string()),
+                budget_exceeded: None,
+                index_generation_gone: None,
+                trace: Default::default(),
+            }),
+            BatchStreamItem::Done { cost_usd: 0.5 },
+        ];
+        let mut body = Vec::new();
+        for item in &items {
+            body.extend(Transport::Json.encode_frame(item).unwrap());
+        }
+        if truncated {
+            body.truncate(body.len() - 3);
+        }
+        body
+    }
+
+    #[tokio::test]
+    async fn test_batch_answers_are_sent_as_they_are_read() {
+        use common::local_services::{local_url, register, WarpService};
+        use common::service_interaction::set_service_version;
+        use std::sync::Arc;
+
+        set_service_version(Service::CodeSearch, ServiceVersion::legacy(Service::CodeSearch));
+        let questions = [
+            QuestionWithId {
+                id: 3,
+                text: "Where are the tokens refreshed?".to_string(),
+                clarification: None,
+            },
+            QuestionWithId {
+                id: 4,
+                text: "Who calls the refresh?".to_string(),
+                clarification: None,
+            },
+        ];
+
+        for truncated in [false, true] {
+            let name = format!("batch-code-understanding-{}", truncated);
+            register(
+                &name,
+                Arc::new(WarpService::new(streaming_code_understanding(truncated))),
+            );
+            let request = batch_request("repo", "task", &questions, &[], None, None, None);
+            let budget = BudgetMeter::unlimited(Default::default());
+            let (tx, mut rx) = mpsc::channel(questions.len());
+            let mut answered = HashSet::new();
+
+            let streamed = answer_batch(
+                format!("{}/answer-batch", local_url(&name)),
+                request,
+                &AnswerScope::default(),
+                &budget,
+                None,
+                &tx,
+                &mut answered,
+            )
+            .await;
+            drop(tx);
+
+            // both answers are sent before the end of the reply, the failed question as its error.
+            let first = rx.recv().await.unwrap().unwrap();
+            assert_eq!(first.question_id, 3);
+            assert_eq!(first.answer.answer, "In refresh.rs");
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                Err(AgentProcessingError::CodeUnderStandingAgentCallFailed(_))
+            ));
+            assert!(rx.recv().await.is_none());
+            assert_eq!(answered, HashSet::from([3, 4]));
+            // the cost frame was cut from the truncated reply.
+            assert_eq!(streamed.is_err(), truncated);
+            assert_eq!(budget.spent_usd(), if truncated { 0.0 } else { 0.5 });
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// Version handshake with the downstream services at startup. The optional features the
+// coordinator relies on with its configuration are checked against the ones they advertise,
+// the call sites read the cached capabilities and fall back when a feature is missing.
+
+use anyhow::{anyhow, Result};
+use common::capabilities::{Capabilities, Capability, Service};
+use common::service_interaction::handshake;
+use common::transport::Transport;
+use log::{info, warn};
+
+use crate::configuration::{
+    get_code_search_url, get_code_understanding_transport, get_code_understanding_url,
+    get_require_downstream_capabilities,
+};
+
+/// A capability the coordinator uses, with what it does without it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequiredCapability {
+    pub service: Service,
+    pub capability: Capability,
+    pub fallback: &'static str,
+}
+
+/// Capabilities used with the configured transport.
+pub fn required_capabilities(transport: Transport) -> Vec<RequiredCapability> {
+    let mut required = vec![
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::PinnedPaths,
+            fallback: "pinned paths are not sent with the questions",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Preferences,
+            fallback: "the preferences of the user are not applied to the answers",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Language,
+            fallback: "the answers to the questions are written in English",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::AnswerBatch,
+            fallback: "the questions of a subtask are sent one request each",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Budget,
+            fallback: "the answers are not limited by the budget of the conversation and their cost isn't counted",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Attachments,
+            fallback: "the documents attached to a conversation are not used in its answers",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Verification,
+            fallback: "the answers of the task breakdowns have no verification steps",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::IndexGeneration,
+            fallback: "the questions of a conversation search the current index generation",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Glossary,
+            fallback: "the answers don't keep to how the earlier answers described the symbols",
+        },
+        RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::AskUser,
+            fallback: "the agent picks what an ambiguous question means instead of asking the user",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::CodeOwners,
+            fallback: "the code contexts of the answers have no owners",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::RepoSummary,
+            fallback: "the tasks are generated without the summary of the repo",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::RunManifest,
+            fallback: "conversations don't record the index run they are answered against, their links stay relative",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::IndexedPaths,
+            fallback: "the generated tasks aren't grounded against the paths of the repo",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::Attachments,
+            fallback: "documents can't be attached to conversations",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::Freshness,
+            fallback: "the freshness of the index of new conversations is unknown",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::IndexGeneration,
+            fallback: "conversations aren't pinned to the index generation they started with",
+        },
+        RequiredCapability {
+            service: Service::CodeSearch,
+            capability: Capability::Terminology,
+            fallback: "the jargon of the repos isn't expanded in the searches",
+        },
+    ];
+    if transport == Transport::Msgpack {
+        required.push(RequiredCapability {
+            service: Service::CodeUnderstanding,
+            capability: Capability::Msgpack,
+            fallback: "json is used instead of msgpack",
+        });
+    }
+    required
+}
+
+/// The required capabilities of `service` it doesn't support.
+pub fn missing_capabilities<'a>(
+    required: &'a [RequiredCapability],
+    service: Service,
+    capabilities: &Capabilities,
+) -> Vec<&'a RequiredCapability> {
+    required
+        .iter()
+        .filter(|required| required.service == service && !capabilities.supports(required.capability))
+        .collect()
+}
+
+/// Makes the handshake with code search and code understanding and caches their capabilities.
+/// A missing capability is logged as a warning, or fails the startup when the configuration
+/// requires the downstream capabilities. A failed handshake leaves the features in use.
+pub async fn check_downstream_compatibility() -> Result<()> {
+    let required = required_capabilities(get_code_understanding_transport());
+
+    let mut missing = Vec::new();
+    for (service, url) in [
+        (Service::CodeSearch, get_code_search_url()),
+        (Service::CodeUnderstanding, get_code_understanding_url()),
+    ] {
+        let version = match handshake(service, &url).await {
+            Ok(version) => version,
+            Err(e) => {
+                warn!("Version handshake with {} at {} failed, its optional features are used as is: {}", service, url, e);
+                continue;
+            }
+        };
+        info!(
+            "{} {} supports {:?}",
+            service, version.version, version.capabilities
+        );
+
+        let capabilities = Capabilities::Advertised(version.clone());
+        for required in missing_capabilities(&required, service, &capabilities) {
+            warn!(
+                "********** {} {} does not support `{}`, {}. Upgrade {} to use it. **********",
+                service, version.version, required.capability, required.fallback, service
+            );
+            missing.push(format!("{} {}", service, required.capability));
+        }
+    }
+
+    if !missing.is_empty() && get_require_downstream_capabilities() {
+        return Err(anyhow!(
+            "Downstream services are missing required capabilities: {}",
+            missing.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::capabilities::ServiceVersion;
+
+    #[test]
+    fn test_missing_capabilities_of_an_old_service() {
+        let required = required_capabilities(Transport::Msgpack);
+        let old = Capabilities::Advertised(ServiceVersion::new(
+            "code-understanding",
+            "0.9.0",
+            &[Capability::PinnedPaths],
+        ));
+
+        let missing = missing_capabilities(&required, Service::CodeUnderstanding, &old)
+            .into_iter()
+            .map(|required| required.capability)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            missing,
+            vec![
+                Capability::Preferences,
+                Capability::Language,
+                Capability::AnswerBatch,
+                Capability::Budget,
+                Capability::Attachments,
+                Capability::Verification,
+                Capability::IndexGeneration,
+                Capability::Glossary,
+                Capability::AskUser,
+                Capability::Msgpack,
+            ]
+        );
+
+        let legacy = Capabilities::Advertised(ServiceVersion::legacy(Service::CodeSearch));
+        let missing = missing_capabilities(&required, Service::CodeSearch, &legacy)
+            .into_iter()
+            .map(|required| required.capability)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            missing,
+            vec![
+                Capability::CodeOwners,
+                Capability::RepoSummary,
+                Capability::RunManifest,
+                Capability::IndexedPaths,
+                Capability::Attachments,
+                Capability::Freshness,
+                Capability::IndexGeneration,
+                Capability::Terminology,
+            ]
+        );
+    }
+}
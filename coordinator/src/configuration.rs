@@ -1,3 +1,12 @@
+use common::budget::BudgetConfig;
+use common::freshness::StalenessThresholds;
+use common::links::WebUrlTemplate;
+use common::timings::SlaThresholds;
+use common::transport::Transport;
+
+use crate::admission::AdmissionConfig;
+use crate::duplicates::DuplicateConfig;
+use crate::similar_questions::SimilarQuestionsConfig;
 use crate::CONFIG;
 
 #[allow(unused)]
@@ -5,8 +14,50 @@ use crate::CONFIG;
 pub struct Configuration {
     pub code_search_url: String,
     pub code_understanding_url: String,
+    // body format used when talking to the code understanding service
+    pub code_understanding_transport: Transport,
     pub redis_url: String,
     pub ai_gateway_config: String,
+    // most recent conversation messages sent along with a prompt, older ones are summarized.
+    pub max_prompt_history_messages: usize,
+    // subtasks whose answers are synthesized at once before the task summary.
+    pub subtask_synthesis_concurrency: usize,
+    // links of the answers are rewritten to urls of this template, left relative when unset.
+    pub web_url_template: Option<String>,
+    // refuse to start when a downstream service lacks a capability the configuration needs,
+    // instead of warning and falling back.
+    pub require_downstream_capabilities: bool,
+    // new conversations that read like a simple lookup are answered directly on /suggest,
+    // without generating tasks.
+    pub auto_quick_answer: bool,
+    // command running the ingestion binary for the admin re-index jobs, e.g.
+    // `/app/ingestion --env-file /app/.env`. The repo arguments are appended to it.
+    pub indexer_command: Option<String>,
+    // folder the indexer runs in, it has the `repo` folder with the checked out repos.
+    pub indexer_workdir: Option<String>,
+    // confidence a component mentioned by a generated task needs to match a path of the repo.
+    pub grounding_confidence: f32,
+    // prices of the models and spend limits of the conversations and tenants.
+    pub budget: BudgetConfig,
+    // largest document accepted as an attachment of a conversation.
+    pub attachment_max_bytes: usize,
+    // seconds an attachment of a conversation is kept after it was added.
+    pub attachment_ttl_secs: u64,
+    // how long each phase of answering a question is expected to take, slower phases are
+    // reported on the webhook of the conversation.
+    pub sla_thresholds: SlaThresholds,
+    // how old and how far behind its branch the index can be before the answers warn about it.
+    pub staleness_thresholds: StalenessThresholds,
+    // questions answered at once by code understanding, overall and per conversation, and the
+    // questions that can wait for them.
+    pub answer_admission: AdmissionConfig,
+    // directory the eval cases of the negatively rated answers are written to, one file per
+    // conversation. Not written when unset.
+    pub eval_fixtures_dir: Option<String>,
+    // when a new conversation repeats a recent one and how far back it is looked for.
+    pub duplicate_detection: DuplicateConfig,
+    // which questions asked before are suggested while a query is typed, and how many are kept.
+    pub similar_questions: SimilarQuestionsConfig,
 }
 
 pub fn get_redis_url() -> String {
@@ -21,7 +72,85 @@ pub fn get_code_understanding_url() -> String {
     CONFIG.read().unwrap().code_understanding_url.clone()
 }
 
+pub fn get_code_understanding_transport() -> Transport {
+    CONFIG.read().unwrap().code_understanding_transport
+}
+
 pub fn get_ai_gateway_config() -> String {
     log::debug!("Reading AI Gateway config");
     CONFIG.read().unwrap().ai_gateway_config.clone()
-}
\ No newline at end of file
+}
+
+pub fn get_max_prompt_history_messages() -> usize {
+    CONFIG.read().unwrap().max_prompt_history_messages
+}
+
+pub fn get_require_downstream_capabilities() -> bool {
+    CONFIG.read().unwrap().require_downstream_capabilities
+}
+
+pub fn get_auto_quick_answer() -> bool {
+    CONFIG.read().unwrap().auto_quick_answer
+}
+
+pub fn get_indexer_command() -> Option<String> {
+    CONFIG.read().unwrap().indexer_command.clone()
+}
+
+pub fn get_indexer_workdir() -> Option<String> {
+    CONFIG.read().unwrap().indexer_workdir.clone()
+}
+
+pub fn get_grounding_confidence() -> f32 {
+    CONFIG.read().unwrap().grounding_confidence
+}
+
+pub fn get_budget_config() -> BudgetConfig {
+    CONFIG.read().unwrap().budget.clone()
+}
+
+pub fn get_attachment_max_bytes() -> usize {
+    CONFIG.read().unwrap().attachment_max_bytes
+}
+
+pub fn get_attachment_ttl_secs() -> u64 {
+    CONFIG.read().unwrap().attachment_ttl_secs
+}
+
+// at least one, a synthesis is never left waiting for a free slot.
+pub fn get_subtask_synthesis_concurrency() -> usize {
+    CONFIG.read().unwrap().subtask_synthesis_concurrency.max(1)
+}
+
+pub fn get_sla_thresholds() -> SlaThresholds {
+    CONFIG.read().unwrap().sla_thresholds.clone()
+}
+
+pub fn get_staleness_thresholds() -> StalenessThresholds {
+    CONFIG.read().unwrap().staleness_thresholds
+}
+
+pub fn get_answer_admission() -> AdmissionConfig {
+    CONFIG.read().unwrap().answer_admission
+}
+
+pub fn get_eval_fixtures_dir() -> Option<String> {
+    CONFIG.read().unwrap().eval_fixtures_dir.clone()
+}
+
+pub fn get_duplicate_detection() -> DuplicateConfig {
+    CONFIG.read().unwrap().duplicate_detection.clone()
+}
+
+pub fn get_similar_questions() -> SimilarQuestionsConfig {
+    CONFIG.read().unwrap().similar_questions.clone()
+}
+
+pub fn get_web_url_template() -> Option<WebUrlTemplate> {
+    CONFIG
+        .read()
+        .unwrap()
+        .web_url_template
+        .clone()
+        .map(WebUrlTemplate::new)
+}
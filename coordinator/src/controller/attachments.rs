@@ -0,0 +1,166 @@
+use std::convert::Infallible;
+
+use common::attachments::{AttachmentIndexRequest, AttachmentIndexResponse};
+use common::auth::Tenant;
+use common::capabilities::{Capability, Service};
+use common::service_interaction::{capabilities, service_caller, HttpMethod};
+use common::task_graph::redis::load_task_process_from_redis;
+use log::{error, info};
+use reqwest::StatusCode;
+
+use crate::attachments::{
+    delete_attachment_records, expires_at, prepare_attachment, save_attachment, AttachmentRecord,
+};
+use crate::configuration::{
+    get_attachment_max_bytes, get_attachment_ttl_secs, get_code_search_url, get_redis_url,
+};
+use crate::controller::error::error_reply;
+use crate::models::AttachmentRequest;
+
+// Whether the conversation exists and belongs to the tenant, a conversation of another tenant
+// is reported as not found so its existence isn't leaked.
+fn owns_conversation(id: &str, tenant: &Tenant) -> bool {
+    match load_task_process_from_redis(&get_redis_url(), id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => true,
+        Ok(_) => {
+            error!(
+                "Tenant {} tried to use the attachments of conversation {} of another tenant",
+                tenant.id, id
+            );
+            false
+        }
+        Err(e) => {
+            error!("Failed to load conversation {} from Redis: {}", id, e);
+            false
+        }
+    }
+}
+
+// Attaches a document to a conversation, its sections are searched with the next questions of
+// the conversation until it expires.
+pub async fn handle_add_attachment_wrapper(
+    id: String,
+    request: AttachmentRequest,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if !owns_conversation(&id, &tenant) {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            format!("Conversation not found: {}", id),
+        ));
+    }
+    if !capabilities(Service::CodeSearch).supports(Capability::Attachments) {
+        return Ok(error_reply(
+            StatusCode::NOT_IMPLEMENTED,
+            "The code search service doesn't support attachments, upgrade it to attach documents"
+                .to_string(),
+        ));
+    }
+
+    let attachment_id = uuid::Uuid::new_v4().to_string();
+    let (kind, sections) =
+        match prepare_attachment(&attachment_id, &request, get_attachment_max_bytes()) {
+            Ok(prepared) => prepared,
+            Err((status, message)) => return Ok(error_reply(status, message)),
+        };
+
+    let ttl_secs = get_attachment_ttl_secs();
+    let record = AttachmentRecord {
+        attachment_id,
+        name: request.name.clone(),
+        kind,
+        sections: sections.iter().map(|s| s.section.clone()).collect(),
+        expires_at: expires_at(ttl_secs),
+    };
+    let indexed = service_caller::<AttachmentIndexRequest, AttachmentIndexResponse>(
+        format!("{}/attachments", get_code_search_url()),
+        HttpMethod::POST,
+        Some(AttachmentIndexRequest {
+            conversation_id: id.clone(),
+            sections,
+            expires_at: record.expires_at,
+        }),
+        None,
+    )
+    .await;
+    if let Err(e) = indexed {
+        error!(
+            "Failed to index attachment {} of conversation {}: {}",
+            request.name, id, e
+        );
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error indexing the attachment: {}", e),
+        ));
+    }
+    if let Err(e) = save_attachment(&get_redis_url(), &id, &record, ttl_secs) {
+        error!(
+            "Failed to record attachment {} of conversation {}: {}",
+            request.name, id, e
+        );
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error recording the attachment: {}", e),
+        ));
+    }
+
+    info!(
+        "Attached {} to conversation {} as {} sections",
+        record.name,
+        id,
+        record.sections.len()
+    );
+    Ok(warp::reply::with_status(
+        warp::reply::json(&record),
+        StatusCode::CREATED,
+    ))
+}
+
+// Deletes the attachments of a conversation, e.g. when it is archived. The conversation itself
+// is kept.
+pub async fn handle_delete_attachments_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if !owns_conversation(&id, &tenant) {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            format!("Conversation not found: {}", id),
+        ));
+    }
+
+    // the record goes first, the questions stop asking for the attachments even if the
+    // sections outlive a failed delete until they expire.
+    if let Err(e) = delete_attachment_records(&get_redis_url(), &id) {
+        error!(
+            "Failed to delete the attachment records of conversation {}: {}",
+            id, e
+        );
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error deleting the attachments: {}", e),
+        ));
+    }
+    let deleted = service_caller::<(), String>(
+        format!("{}/attachments/{}", get_code_search_url(), id),
+        HttpMethod::DELETE,
+        None,
+        None,
+    )
+    .await;
+    if let Err(e) = deleted {
+        error!(
+            "Failed to delete the attachments of conversation {}: {}",
+            id, e
+        );
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error deleting the attachments: {}", e),
+        ));
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&"deleted"),
+        StatusCode::OK,
+    ))
+}
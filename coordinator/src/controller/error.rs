@@ -1,4 +1,22 @@
+use common::budget::BudgetExceeded;
+use common::generation::IndexGenerationGone;
+use common::models::ErrorEnvelope;
+use reqwest::StatusCode;
 use thiserror::Error;
+use warp::Reply;
+
+use crate::admission::{AdmissionRejected, RejectionReason};
+
+/// Reply to a request a controller turns down, with an `ErrorEnvelope` body.
+pub fn error_reply(
+    status: StatusCode,
+    message: String,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorEnvelope::new(status.as_u16(), message)),
+        status,
+    )
+}
 
 #[derive(Debug, Error)]
 pub enum AgentProcessingError {
@@ -8,10 +26,107 @@ pub enum AgentProcessingError {
     CodeUnderStandingAgentCallFailed(String),
     #[error("Network error: {0}")]
     NetworkError(String),
+    // also returned when the conversation belongs to another tenant, so its existence isn't leaked.
+    #[error("Conversation not found: {0}")]
+    ConversationNotFound(String),
+    #[error("Invalid callback URL, expected an http(s) URL: {0}")]
+    InvalidCallbackUrl(String),
+    #[error("Invalid callback URL, the host is internal: {0}")]
+    InternalCallbackUrl(String),
+    #[error("{0}")]
+    BudgetExceeded(BudgetExceeded),
+    // code understanding is saturated or the coordinator is shutting down.
+    #[error("{0}")]
+    Overloaded(AdmissionRejected),
+    // the conversation is pinned to an index generation that was dropped, it has to be restarted
+    // or re-pinned with `repin_generation`.
+    #[error("{0}")]
+    IndexGenerationGone(IndexGenerationGone),
 }
 
 impl From<anyhow::Error> for AgentProcessingError {
     fn from(err: anyhow::Error) -> Self {
-        AgentProcessingError::NetworkError(err.to_string())
+        let err = match err.downcast::<BudgetExceeded>() {
+            Ok(exceeded) => return AgentProcessingError::BudgetExceeded(exceeded),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<IndexGenerationGone>() {
+            Ok(gone) => return AgentProcessingError::IndexGenerationGone(gone),
+            Err(err) => err,
+        };
+        match err.downcast::<AdmissionRejected>() {
+            Ok(rejected) => AgentProcessingError::Overloaded(rejected),
+            Err(err) => AgentProcessingError::NetworkError(err.to_string()),
+        }
     }
-}
\ No newline at end of file
+}
+
+impl AgentProcessingError {
+    /// Status code for an error returned by the suggest and retry flows.
+    pub fn status_code(err: &anyhow::Error) -> StatusCode {
+        match err.downcast_ref::<AgentProcessingError>() {
+            Some(AgentProcessingError::ConversationNotFound(_)) => StatusCode::NOT_FOUND,
+            Some(AgentProcessingError::InvalidCallbackUrl(_)) => StatusCode::BAD_REQUEST,
+            Some(AgentProcessingError::InternalCallbackUrl(_)) => StatusCode::BAD_REQUEST,
+            Some(AgentProcessingError::Overloaded(rejected)) => match rejected.reason {
+                RejectionReason::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+                RejectionReason::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            },
+            Some(AgentProcessingError::IndexGenerationGone(_)) => StatusCode::GONE,
+            _ if Self::budget_exceeded(err).is_some() => StatusCode::PAYMENT_REQUIRED,
+            _ if err.downcast_ref::<IndexGenerationGone>().is_some() => StatusCode::GONE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The limit a flow was stopped by, whether it was hit by a call of the coordinator
+    /// or reported by code understanding.
+    pub fn budget_exceeded(err: &anyhow::Error) -> Option<&BudgetExceeded> {
+        match err.downcast_ref::<AgentProcessingError>() {
+            Some(AgentProcessingError::BudgetExceeded(exceeded)) => Some(exceeded),
+            _ => err.downcast_ref::<BudgetExceeded>(),
+        }
+    }
+
+    /// Seconds an overloaded flow should be retried after.
+    pub fn retry_after(err: &anyhow::Error) -> Option<u64> {
+        match err.downcast_ref::<AgentProcessingError>() {
+            Some(AgentProcessingError::Overloaded(rejected)) => Some(rejected.retry_after_secs),
+            _ => None,
+        }
+    }
+
+    /// Reply to an error of the suggest, quick answer and retry flows, with a `Retry-After`
+    /// header when the question was turned away by the admission.
+    pub fn reply(err: &anyhow::Error, message: &str) -> warp::reply::Response {
+        let reply = warp::reply::with_status(warp::reply::json(&message), Self::status_code(err));
+        match Self::retry_after(err) {
+            Some(secs) => warp::reply::with_header(reply, "Retry-After", secs.to_string())
+                .into_response(),
+            None => reply.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_reply_has_an_error_envelope() {
+        let reply = error_reply(
+            StatusCode::NOT_FOUND,
+            "Conversation not found: c1".to_string(),
+        )
+        .into_response();
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+        let body = warp::hyper::body::to_bytes(reply.into_body())
+            .await
+            .unwrap();
+        let envelope: ErrorEnvelope = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            envelope,
+            ErrorEnvelope::new(404, "Conversation not found: c1".to_string())
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::convert::Infallible;
+use std::path::Path;
+
+use common::auth::Tenant;
+use common::feedback::{eval_fixture_name, render_eval_fixture, Rating};
+use common::task_graph::add_node::NodeError;
+use common::task_graph::graph_model::TrackProcessV1;
+use common::task_graph::redis::load_task_process_from_redis;
+use log::{error, info};
+use reqwest::StatusCode;
+
+use crate::configuration::{get_eval_fixtures_dir, get_redis_url};
+use crate::controller::error::error_reply;
+use crate::models::FeedbackRequest;
+
+// The conversation when it exists and belongs to the tenant, a conversation of another tenant is
+// reported as not found so its existence isn't leaked.
+fn load_conversation(
+    id: &str,
+    tenant: &Tenant,
+) -> Result<TrackProcessV1, warp::reply::WithStatus<warp::reply::Json>> {
+    match load_task_process_from_redis(&get_redis_url(), id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => return Ok(tracker),
+        Ok(_) => error!(
+            "Tenant {} tried to use the feedback of conversation {} of another tenant",
+            tenant.id, id
+        ),
+        Err(e) => error!("Failed to load conversation {} from Redis: {}", id, e),
+    }
+    Err(error_reply(
+        StatusCode::NOT_FOUND,
+        format!("Conversation not found: {}", id),
+    ))
+}
+
+// Rates the current answer of a question of the conversation. A down rating, or an up rating
+// replacing one, rewrites the eval cases of the conversation.
+pub async fn handle_feedback_wrapper(
+    id: String,
+    question_id: usize,
+    request: FeedbackRequest,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    let mut tracker = match load_conversation(&id, &tenant) {
+        Ok(tracker) => tracker,
+        Err(reply) => return Ok(reply),
+    };
+    let had_negative = matches!(
+        tracker.question_feedback(question_id),
+        Some(rated) if rated.latest.rating == Rating::Down
+    );
+
+    let feedback = match tracker.record_feedback(
+        question_id,
+        request.rating,
+        request.comment,
+        request.correct_paths,
+    ) {
+        Ok(feedback) => feedback,
+        Err(NodeError::InvalidQuestionNode) => {
+            return Ok(error_reply(
+                StatusCode::NOT_FOUND,
+                format!("Question not found: {}", question_id),
+            ))
+        }
+        // the question is still being answered, there is nothing to rate yet.
+        Err(NodeError::NodeNotFound(message)) => {
+            return Ok(error_reply(StatusCode::CONFLICT, message))
+        }
+        Err(e) => {
+            error!("Failed to record feedback on conversation {}: {}", id, e);
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error recording feedback: {}", e),
+            ));
+        }
+    };
+    if let Err(e) = tracker.save_task_process_to_redis(&get_redis_url()) {
+        error!("Failed to save the feedback of conversation {}: {}", id, e);
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error saving feedback: {}", e),
+        ));
+    }
+
+    if let Some(dir) = get_eval_fixtures_dir() {
+        if had_negative || feedback.rating == Rating::Down {
+            // the rating is saved, a fixture that can't be written is only logged.
+            if let Err(e) = write_eval_fixture(Path::new(&dir), &id, &tracker) {
+                error!(
+                    "Failed to write the eval cases of conversation {}: {}",
+                    id, e
+                );
+            }
+        }
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&feedback),
+        StatusCode::CREATED,
+    ))
+}
+
+// Lists the ratings of the conversation by question, with the totals.
+pub async fn handle_feedback_summary_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    let tracker = match load_conversation(&id, &tenant) {
+        Ok(tracker) => tracker,
+        Err(reply) => return Ok(reply),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&tracker.feedback_summary()),
+        StatusCode::OK,
+    ))
+}
+
+// Replaces the fixture of the conversation with its current eval cases, the fixture is removed
+// once none of its questions is rated down anymore.
+fn write_eval_fixture(dir: &Path, id: &str, tracker: &TrackProcessV1) -> std::io::Result<()> {
+    let path = dir.join(eval_fixture_name(id));
+    let cases = tracker.feedback_summary().eval_cases(&tracker.repo, id);
+    if cases.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, render_eval_fixture(&cases))?;
+    info!(
+        "Wrote {} eval cases of conversation {} to {}",
+        cases.len(),
+        id,
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::feedback::EvalCase;
+    use common::task_graph::graph_model::QuestionWithAnswer;
+    use common::CodeUnderstanding;
+
+    #[test]
+    fn test_eval_fixture_follows_the_latest_ratings() {
+        let mut tracker = TrackProcessV1::new("acme/api", "redis://127.0.0.1:6379");
+        let question = tracker
+            .add_quick_question("Where are refunds retried?")
+            .unwrap()
+            .id;
+        tracker
+            .add_answer_node(&QuestionWithAnswer {
+                question_id: question,
+                question: "Where are refunds retried?".to_string(),
+                answer: CodeUnderstanding {
+                    context: vec![],
+                    question: "Where are refunds retried?".to_string(),
+                    answer: "In the worker".to_string(),
+                    outcome: None,
+                    missing_pinned_paths: vec![],
+                    cost_usd: None,
+                    timings: None,
+                    scope_violations: vec![],
+                    citations: Default::default(),
+                    verification: Default::default(),
+                    prompt_versions: Default::default(),
+                    files_involved: Default::default(),
+                    index_mode: Default::default(),
+                },
+            })
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("eval-fixtures-{}", std::process::id()));
+        let fixture = dir.join("feedback_c1.json");
+        tracker
+            .record_feedback(
+                question,
+                Rating::Down,
+                None,
+                vec!["src/refund/queue.rs".to_string()],
+            )
+            .unwrap();
+        write_eval_fixture(&dir, "c1", &tracker).unwrap();
+        let cases: Vec<EvalCase> =
+            serde_json::from_str(&std::fs::read_to_string(&fixture).unwrap()).unwrap();
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].repo, "acme/api");
+        assert_eq!(cases[0].query, "Where are refunds retried?");
+        assert_eq!(cases[0].relevant, vec!["src/refund/queue.rs".to_string()]);
+
+        // rated up again, the question is no eval case anymore.
+        tracker
+            .record_feedback(question, Rating::Up, None, vec![])
+            .unwrap();
+        write_eval_fixture(&dir, "c1", &tracker).unwrap();
+        assert!(!fixture.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use common::task_graph::export::GraphFormat;
+use common::task_graph::redis::load_task_process_from_redis;
+use log::error;
+use reqwest::StatusCode;
+use warp::http::Response;
+
+use crate::code_understanding::resolve_citation_drift;
+use crate::configuration::get_redis_url;
+use crate::models::GraphQuery;
+
+// Renders the task graph of a conversation for debugging, as graphviz dot, a mermaid flowchart
+// or json with stable node and edge ids. Defaults to json when no format is given.
+// `resolve_anchors=true` looks the cited lines up in the current index and annotates the citations
+// that moved, changed or were deleted since.
+pub async fn handle_graph_export_wrapper(
+    id: String,
+    query: GraphQuery,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    let format = match query.format.as_deref().map(str::parse::<GraphFormat>) {
+        None => GraphFormat::default(),
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return Ok(text_response(StatusCode::BAD_REQUEST, e)),
+    };
+
+    let tracker = match load_task_process_from_redis(&get_redis_url(), &id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => tracker,
+        Ok(_) => {
+            error!("Tenant {} tried to export conversation {} of another tenant", tenant.id, id);
+            return Ok(text_response(
+                StatusCode::NOT_FOUND,
+                format!("Conversation not found: {}", id),
+            ));
+        }
+        Err(e) => {
+            error!("Failed to load conversation {} from Redis: {}", id, e);
+            return Ok(text_response(
+                StatusCode::NOT_FOUND,
+                format!("Conversation not found: {}", id),
+            ));
+        }
+    };
+
+    match tracker.export_graph() {
+        Ok(mut export) => {
+            if query.resolve_anchors.unwrap_or(false) {
+                resolve_citation_drift(&tracker.repo, &mut export.citations).await;
+            }
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", format.content_type())
+                .body(export.render(format))
+                .expect("Failed to construct response"))
+        }
+        Err(e) => {
+            error!("Failed to export the graph of conversation {}: {}", id, e);
+            Ok(text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error exporting graph: {}", e),
+            ))
+        }
+    }
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(body)
+        .expect("Failed to construct response")
+}
@@ -0,0 +1,71 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use common::task_graph::redis::load_task_process_from_redis;
+use log::error;
+use reqwest::StatusCode;
+
+use crate::code_understanding::resolve_citation_drift;
+use crate::configuration::get_redis_url;
+use crate::models::MessagesQuery;
+
+// messages returned per page when the request doesn't set a limit.
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+// Returns a page of the conversation history in the order the messages were exchanged,
+// clients follow `next_offset` until it is missing to read the whole conversation.
+// `resolve_anchors=true` adds the citations whose lines changed since they were cited.
+pub async fn handle_messages_wrapper(
+    id: String,
+    query: MessagesQuery,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    let tracker = match load_task_process_from_redis(&get_redis_url(), &id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => tracker,
+        Ok(_) => {
+            error!("Tenant {} tried to read conversation {} of another tenant", tenant.id, id);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Conversation not found: {}", id)),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+        Err(e) => {
+            error!("Failed to load conversation {} from Redis: {}", id, e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Conversation not found: {}", id)),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+
+    match tracker.collect_conversation_messages_page(offset, limit) {
+        Ok(mut page) => {
+            if query.resolve_anchors.unwrap_or(false) {
+                let mut answers = tracker.answer_citation_reports();
+                resolve_citation_drift(&tracker.repo, &mut answers).await;
+                page.drifted_citations = answers
+                    .into_iter()
+                    .filter_map(|mut answer| {
+                        answer.citations.citations.retain(|citation| citation.drift.is_some());
+                        (!answer.citations.is_empty()).then_some(answer)
+                    })
+                    .collect();
+            }
+            Ok(warp::reply::with_status(
+                warp::reply::json(&page),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            error!("Failed to collect the messages of conversation {}: {}", id, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error collecting messages: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -1,2 +1,15 @@
+pub mod quick_answer;
+pub mod retry;
 pub mod suggest;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod graph;
+pub mod messages;
+pub mod status;
+pub mod reindex;
+pub mod webhooks;
+pub mod attachments;
+pub mod feedback;
+pub mod terminology;
+pub mod plan;
+pub mod ready;
+pub mod suggest_questions;
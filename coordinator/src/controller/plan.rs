@@ -0,0 +1,142 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use common::task_graph::graph_model::TrackProcessV1;
+use common::task_graph::redis::load_task_process_from_redis;
+use log::{error, info};
+use reqwest::StatusCode;
+use warp::Reply;
+
+use crate::budget::{conversation_meter, settle_budget};
+use crate::code_understanding::fetch_indexed_paths;
+use crate::configuration::get_redis_url;
+use crate::controller::error::{error_reply, AgentProcessingError};
+use crate::llm_ops::plan::generate_change_plan;
+use crate::models::PlanResponse;
+
+// The conversation when it exists and belongs to the tenant, a conversation of another tenant is
+// reported as not found so its existence isn't leaked.
+fn load_conversation(id: &str, tenant: &Tenant) -> Result<TrackProcessV1, warp::reply::Response> {
+    match load_task_process_from_redis(&get_redis_url(), id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => return Ok(tracker),
+        Ok(_) => error!(
+            "Tenant {} tried to plan conversation {} of another tenant",
+            tenant.id, id
+        ),
+        Err(e) => error!("Failed to load conversation {} from Redis: {}", id, e),
+    }
+    Err(error_reply(
+        StatusCode::NOT_FOUND,
+        format!("Conversation not found: {}", id),
+    )
+    .into_response())
+}
+
+// Generates the change plan of the answered tasks of the conversation, replacing the one generated
+// before, and stores it on the graph. Nothing is planned before a task has an answer.
+pub async fn handle_generate_plan_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    let mut tracker = match load_conversation(&id, &tenant) {
+        Ok(tracker) => tracker,
+        Err(reply) => return Ok(reply),
+    };
+    let details = match tracker.collect_tasks_questions_answers_contexts() {
+        Ok(details) => details,
+        Err(e) => {
+            error!("Failed to collect the tasks of conversation {}: {}", id, e);
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error collecting the tasks: {}", e),
+            )
+            .into_response());
+        }
+    };
+    if details.tasks.iter().all(|task| task.answers.is_empty()) {
+        return Ok(error_reply(
+            StatusCode::CONFLICT,
+            format!("Conversation {} has no answered tasks to plan", id),
+        )
+        .into_response());
+    }
+    let user_query = tracker.last_user_query().unwrap_or_default();
+
+    let indexed_paths = fetch_indexed_paths(&tracker.repo).await;
+    let budget = conversation_meter(&tracker, &tenant);
+    let planned =
+        generate_change_plan(&user_query, &details, indexed_paths.as_deref(), &budget).await;
+    settle_budget(&mut tracker, &tenant, &budget, planned.as_ref().err());
+    let plan = match planned {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("Failed to plan the changes of conversation {}: {}", id, e);
+            let message = format!("Error generating the plan: {}", e);
+            return Ok(AgentProcessingError::reply(&e, &message));
+        }
+    };
+    info!(
+        "Planned {} files for conversation {}, {} of them flagged",
+        plan.files.len(),
+        id,
+        plan.ungrounded().count()
+    );
+
+    let saved = tracker
+        .record_change_plan(plan.clone())
+        .map_err(anyhow::Error::from)
+        .and_then(|_| tracker.save_task_process_to_redis(&get_redis_url()));
+    if let Err(e) = saved {
+        error!("Failed to save the change plan of conversation {}: {}", id, e);
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error saving the plan: {}", e),
+        )
+        .into_response());
+    }
+    Ok(warp::reply::json(&PlanResponse {
+        id,
+        plan,
+        stale: false,
+    })
+    .into_response())
+}
+
+// The change plan generated last, `stale` when the tasks changed since.
+pub async fn handle_get_plan_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    let tracker = match load_conversation(&id, &tenant) {
+        Ok(tracker) => tracker,
+        Err(reply) => return Ok(reply),
+    };
+    let Some(plan) = tracker.change_plan() else {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            format!("Conversation {} has no change plan yet", id),
+        )
+        .into_response());
+    };
+    let tasks = match tracker.collect_tasks_questions_answers_contexts() {
+        Ok(details) => details
+            .tasks
+            .into_iter()
+            .map(|task| task.task_description)
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            error!("Failed to collect the tasks of conversation {}: {}", id, e);
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error collecting the tasks: {}", e),
+            )
+            .into_response());
+        }
+    };
+    Ok(warp::reply::json(&PlanResponse {
+        id,
+        stale: plan.is_stale(&tasks),
+        plan,
+    })
+    .into_response())
+}
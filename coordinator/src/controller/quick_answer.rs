@@ -0,0 +1,314 @@
+use common::auth::{self, Tenant};
+use common::budget::BudgetMeter;
+use common::language::resolve_language;
+use common::task_graph::graph_model::{QuestionWithAnswer, TrackProcessV1};
+use common::task_graph::redis::load_task_process_from_redis;
+use common::task_graph::state::ConversationProcessingStage;
+use log::{error, info};
+use reqwest::StatusCode;
+use std::convert::Infallible;
+use warp::Reply;
+use tokio::sync::mpsc;
+
+use crate::admission::Priority;
+use crate::budget::{conversation_meter, settle_budget};
+use crate::code_understanding::{
+    fetch_freshness, fetch_index_run, get_codebase_answers_for_questions, low_confidence_pins,
+    pin_index_generation, AnswerOptions,
+};
+use crate::configuration::{get_grounding_confidence, get_redis_url};
+use crate::controller::error::AgentProcessingError;
+use crate::models::{QuickAnswerRequest, SuggestResponse};
+use crate::similar_questions::record_questions;
+
+pub async fn handle_quick_answer_wrapper(
+    request: QuickAnswerRequest,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    if !tenant.can_access_repo(&request.repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &request.repo_name).into_response());
+    }
+
+    match handle_quick_answer_core(request, &tenant).await {
+        Ok(response) => Ok(warp::reply::with_status(
+            warp::reply::json(&response),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(e) => {
+            log::error!("Error processing quick answer request: {}", e);
+            let error_message = format!("Error processing request: {}", e);
+            Ok(AgentProcessingError::reply(&e, &error_message))
+        }
+    }
+}
+
+// Answers the query on a new conversation, or on an answered one like a follow-up.
+// Conversations whose tasks are still being answered are continued on /suggest.
+async fn handle_quick_answer_core(
+    request: QuickAnswerRequest,
+    tenant: &Tenant,
+) -> Result<SuggestResponse, anyhow::Error> {
+    let redis_url: &str = &get_redis_url();
+    let mut tracker = match &request.id {
+        Some(uuid) => {
+            let tracker = load_task_process_from_redis(redis_url, uuid).map_err(|e| {
+                let err_msg = format!("Failed to load the conversation from Redis: {}", e);
+                error!("{}", err_msg);
+                anyhow::anyhow!(err_msg)
+            })?;
+            if !tracker.belongs_to(&tenant.id) {
+                error!("Tenant {} tried to continue conversation {} of another tenant", tenant.id, uuid);
+                return Err(AgentProcessingError::ConversationNotFound(uuid.clone()).into());
+            }
+            let (state, _) = tracker.last_conversation_processing_stage();
+            if !matches!(
+                state,
+                ConversationProcessingStage::AnswersSummarized
+                    | ConversationProcessingStage::AllQuestionsAnswered
+                    | ConversationProcessingStage::FollowUpAnswered
+            ) {
+                return Err(anyhow::anyhow!(
+                    "Conversation {} is still being processed, continue it on /suggest",
+                    uuid
+                ));
+            }
+            tracker
+        }
+        None => {
+            let mut tracker = TrackProcessV1::new(&request.repo_name, redis_url);
+            tracker.tenant_id = Some(tenant.id.clone());
+            tracker
+        }
+    };
+
+    let budget = conversation_meter(&tracker, tenant);
+    let question = QuickQuestion {
+        repo_name: &request.repo_name,
+        query: &request.user_query,
+        pinned_paths: &request.pinned_paths,
+        language: request.language.as_deref(),
+        scope: &request.scope,
+        include_verification: request.include_verification.unwrap_or(false),
+    };
+    let answered = answer_quick_question(&mut tracker, &question, &budget).await;
+    settle_budget(&mut tracker, tenant, &budget, answered.as_ref().err());
+    let (answer, missing_pinned_paths) = answered?;
+    tracker.save_task_process_to_redis(redis_url)?;
+    if let Err(e) = record_questions(redis_url, &tracker) {
+        error!("Failed to record the questions for the suggestions: {}", e);
+    }
+
+    Ok(quick_answer_response(&tracker, answer, missing_pinned_paths))
+}
+
+/// The query of a quick answer and how it is asked, see `answer_quick_question`.
+#[derive(Clone, Copy)]
+pub(crate) struct QuickQuestion<'a> {
+    pub repo_name: &'a str,
+    pub query: &'a str,
+    pub pinned_paths: &'a [String],
+    pub language: Option<&'a str>,
+    pub scope: &'a [String],
+    pub include_verification: bool,
+}
+
+/// Sends the query to code understanding as a single question and records it in the graph, as a
+/// user message with the question and its answer. The graph isn't saved.
+/// Returns the answer and the pinned paths code understanding couldn't find.
+/// `language` is the one requested for a new conversation, it is detected from the query otherwise.
+/// `scope` is recorded on a new conversation, the answers of existing ones are checked against theirs.
+/// Without pinned paths, the key files of the repo are pinned when the query names nothing indexed.
+/// `include_verification` asks for steps to verify the answer.
+pub(crate) async fn answer_quick_question(
+    tracker: &mut TrackProcessV1,
+    question: &QuickQuestion<'_>,
+    budget: &BudgetMeter,
+) -> Result<(QuestionWithAnswer, Vec<String>), anyhow::Error> {
+    let QuickQuestion {
+        repo_name,
+        query,
+        pinned_paths,
+        language,
+        scope,
+        include_verification,
+    } = *question;
+    let new_conversation = tracker.get_root_node_uuid().is_none();
+    let question = tracker.add_quick_question(query)?;
+    tracker.record_preferences(query)?;
+    if new_conversation {
+        if let Some(run) = fetch_index_run(repo_name).await {
+            tracker.record_index_run(run)?;
+        }
+        pin_index_generation(tracker).await?;
+        if let Some(freshness) = fetch_freshness(repo_name).await {
+            tracker.record_freshness(freshness)?;
+        }
+        if let Some(language) = resolve_language(language, query) {
+            tracker.record_language(&language)?;
+        }
+        if !scope.is_empty() {
+            tracker.record_scope(scope)?;
+        }
+    }
+    let task_id = tracker
+        .get_root_node_uuid()
+        .ok_or_else(|| anyhow::anyhow!("The conversation has no root node"))?;
+    info!("Answering {:?} directly on conversation {}", query, task_id);
+    let pinned_paths = match pinned_paths.is_empty() {
+        true => low_confidence_pins(repo_name, query, get_grounding_confidence()).await,
+        false => pinned_paths.to_vec(),
+    };
+
+    let (tx, mut rx) = mpsc::channel(1);
+    get_codebase_answers_for_questions(
+        repo_name.to_string(),
+        task_id,
+        &[question],
+        tx,
+        budget,
+        &AnswerOptions {
+            parallel: false,
+            can_interrupt: false,
+            pinned_paths: &pinned_paths,
+            preferences: tracker.preferences().render().as_deref(),
+            language: tracker.language().as_deref(),
+            scope: &tracker.scope(),
+            priority: Priority::Interactive,
+            include_verification,
+            index_generation: tracker.index_generation().as_ref(),
+            index_run: tracker.index_run().as_ref(),
+        },
+    )
+    .await?;
+
+    let answer = match rx.recv().await {
+        Some(Ok(answer)) => answer,
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err(anyhow::anyhow!("No answer received for the question.")),
+    };
+    tracker.add_answer_node(&answer)?;
+    let missing_pinned_paths = answer.answer.missing_pinned_paths.clone();
+    Ok((answer, missing_pinned_paths))
+}
+
+pub(crate) fn quick_answer_response(
+    tracker: &TrackProcessV1,
+    answer: QuestionWithAnswer,
+    missing_pinned_paths: Vec<String>,
+) -> SuggestResponse {
+    SuggestResponse {
+        id: tracker.get_root_node_uuid().unwrap_or_default(),
+        plan: None,
+        ask_user: None,
+        tasks: None,
+        questions_with_answers: None,
+        missing_pinned_paths,
+        follow_up: None,
+        quick_answer: Some(answer),
+        freshness_banner: tracker.freshness_banner(),
+        possible_duplicate: None,
+        clarification: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Configuration;
+    use common::capabilities::{Service, ServiceVersion};
+    use common::local_services::{local_url, register, WarpService};
+    use common::service_interaction::set_service_version;
+    use common::{CodeContext, CodeUnderstanding};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use warp::Filter;
+
+    // code understanding answering every question with a single context.
+    fn mock_code_understanding(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("retrieve-code")
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .map(|params: HashMap<String, String>| {
+                let question = params.get("query").cloned().unwrap_or_default();
+                warp::reply::json(&CodeUnderstanding {
+                    context: vec![CodeContext {
+                        path: "src/auth/jwt.rs".to_string(),
+                        hidden: false,
+                        repo: params.get("repo").cloned().unwrap_or_default(),
+                        branch: None,
+                        ranges: vec![10..42],
+                        pinned: false,
+                        owners: Vec::new(),
+                    }],
+                    question,
+                    answer: "The JWT is validated in `validate_token`.".to_string(),
+                    outcome: None,
+                    missing_pinned_paths: vec!["src/missing.rs".to_string()],
+                    cost_usd: None,
+                    timings: None,
+                    scope_violations: vec![],
+                    citations: Default::default(),
+                    verification: Default::default(),
+                    prompt_versions: Default::default(),
+                    files_involved: Default::default(),
+                    index_mode: Default::default(),
+                })
+            })
+    }
+
+    #[tokio::test]
+    async fn test_quick_answer_records_the_question_and_answer() {
+        register(
+            "quick-answer-code-understanding",
+            Arc::new(WarpService::new(mock_code_understanding())),
+        );
+        crate::set_config(Configuration {
+            code_understanding_url: local_url("quick-answer-code-understanding"),
+            code_search_url: local_url("quick-answer-code-search"),
+            ..Default::default()
+        });
+        // code search has none of the optional features, no owners or links are looked up.
+        set_service_version(
+            Service::CodeSearch,
+            ServiceVersion::legacy(Service::CodeSearch),
+        );
+
+        let mut tracker = TrackProcessV1::new("acme/api", "redis://127.0.0.1:6379");
+        let pinned_paths = ["src/missing.rs".to_string()];
+        let question = QuickQuestion {
+            repo_name: "acme/api",
+            query: "Where is the JWT validated?",
+            pinned_paths: &pinned_paths,
+            language: None,
+            scope: &[],
+            include_verification: false,
+        };
+        let (answer, missing) = answer_quick_question(
+            &mut tracker,
+            &question,
+            &BudgetMeter::unlimited(Default::default()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(answer.question, "Where is the JWT validated?");
+        assert_eq!(answer.answer.context[0].path, "src/auth/jwt.rs");
+        assert_eq!(missing, vec!["src/missing.rs".to_string()]);
+
+        // the conversation reads as answered, follow-ups and exports find the answer.
+        assert_eq!(
+            tracker.last_conversation_processing_stage().0,
+            ConversationProcessingStage::FollowUpAnswered
+        );
+        let follow_ups = tracker.get_follow_ups().unwrap();
+        assert_eq!(follow_ups.len(), 1);
+        assert_eq!(follow_ups[0].answer.answer, answer.answer.answer);
+        assert_eq!(tracker.get_code_context_paths(), vec!["src/auth/jwt.rs".to_string()]);
+
+        let response = quick_answer_response(&tracker, answer, missing);
+        assert_eq!(Some(response.id), tracker.get_root_node_uuid());
+        assert!(response.tasks.is_none());
+    }
+}
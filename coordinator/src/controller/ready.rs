@@ -0,0 +1,17 @@
+use std::convert::Infallible;
+
+use reqwest::StatusCode;
+
+use crate::dependencies::dependency_checker;
+
+// Ready when code search and code understanding answer and Redis takes a write, checked
+// concurrently. The AI gateway isn't called, a completion per probe would be billed.
+pub async fn handle_ready() -> Result<impl warp::Reply, Infallible> {
+    let readiness = dependency_checker(false).readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&readiness), status))
+}
@@ -0,0 +1,96 @@
+use std::convert::Infallible;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+use common::auth::{self, Tenant};
+use log::error;
+use reqwest::StatusCode;
+
+use crate::models::ReindexRequest;
+use crate::reindex::ReindexManager;
+
+// Queues a re-index of the repo and returns the job, the indexing runs in the background.
+pub async fn handle_trigger_reindex(
+    request: ReindexRequest,
+    tenant: Tenant,
+    manager: Arc<ReindexManager>,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Err(e) = validate(&request) {
+        return Ok(reply(&e, StatusCode::BAD_REQUEST));
+    }
+    if !tenant.can_access_repo(&request.repo) {
+        return Ok(auth::repo_forbidden(&tenant, &request.repo));
+    }
+
+    match manager.submit(request) {
+        Ok(job) => {
+            log::info!(
+                "Tenant {} queued re-index job {} of {}",
+                tenant.id,
+                job.id,
+                job.repo
+            );
+            Ok(reply(&job, StatusCode::ACCEPTED))
+        }
+        Err(e) => {
+            error!("Failed to queue the re-index job: {}", e);
+            Ok(reply(
+                &format!("Failed to queue the re-index job: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+// Status, progress and phase timings of a re-index job, with its manifest or error once it finished.
+pub async fn handle_reindex_status(
+    id: String,
+    tenant: Tenant,
+    manager: Arc<ReindexManager>,
+) -> Result<impl warp::Reply, Infallible> {
+    match manager.job(&id) {
+        Ok(Some(job)) if !tenant.can_access_repo(&job.repo) => {
+            Ok(auth::repo_forbidden(&tenant, &job.repo))
+        }
+        Ok(Some(job)) => Ok(reply(&job, StatusCode::OK)),
+        Ok(None) => Ok(reply(
+            &format!("Re-index job not found: {}", id),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => {
+            error!("Failed to load re-index job {}: {}", id, e);
+            Ok(reply(
+                &format!("Error loading the re-index job: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+fn validate(request: &ReindexRequest) -> Result<(), String> {
+    if request.repo.trim().is_empty() {
+        return Err("repo must be set".to_string());
+    }
+    if request.commit.is_some() {
+        return Err(
+            "The indexer only indexes the head of a branch, pass the branch of the commit instead"
+                .to_string(),
+        );
+    }
+    // the folder is joined to the `repo` folder of the indexer, it can't point outside of it.
+    let folder = request.repo_folder.as_deref().unwrap_or(&request.repo);
+    let inside = Path::new(folder)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if folder.is_empty() || !inside {
+        return Err(format!("Invalid repo folder: {}", folder));
+    }
+    Ok(())
+}
+
+fn reply<T: serde::Serialize>(
+    body: &T,
+    status: StatusCode,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(body), status)
+}
@@ -0,0 +1,115 @@
+use common::auth::Tenant;
+use common::budget::BudgetMeter;
+use common::task_graph::graph_model::{QuestionWithId, TrackProcessV1};
+use common::task_graph::redis::load_task_process_from_redis;
+use log::{debug, error, info};
+use reqwest::StatusCode;
+use std::convert::Infallible;
+use warp::Reply;
+
+use crate::budget::{conversation_meter, settle_budget};
+use crate::configuration::get_redis_url;
+use crate::controller::error::AgentProcessingError;
+use crate::controller::suggest::handle_suggest_core;
+use crate::llm_ops::reformulate::reformulate_not_found_question;
+use crate::models::{RetryRequest, SuggestRequest, SuggestResponse};
+
+pub async fn handle_retry_wrapper(
+    request: RetryRequest,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    match handle_retry_core(request, &tenant).await {
+        Ok(response) => Ok(warp::reply::with_status(
+            warp::reply::json(&response),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(e) => {
+            log::error!("Error processing retry request: {}", e);
+            let error_message = format!("Error processing request: {}", e);
+            Ok(AgentProcessingError::reply(&e, &error_message))
+        }
+    }
+}
+
+// Retries only the questions the code understanding agent couldn't find an answer for.
+// Each of them is reformulated using the queries the agent already tried,
+// the not-found answer is superseded, and the regular suggest flow picks them up again.
+async fn handle_retry_core(
+    request: RetryRequest,
+    tenant: &Tenant,
+) -> Result<SuggestResponse, anyhow::Error> {
+    let redis_url: &str = &get_redis_url();
+    let mut tracker = load_task_process_from_redis(redis_url, &request.id).map_err(|e| {
+        let err_msg = format!("Failed to load the conversation from Redis: {}", e);
+        error!("{}", err_msg);
+        anyhow::anyhow!(err_msg)
+    })?;
+    if !tracker.belongs_to(&tenant.id) {
+        error!("Tenant {} tried to retry conversation {} of another tenant", tenant.id, request.id);
+        return Err(AgentProcessingError::ConversationNotFound(request.id).into());
+    }
+
+    let not_found_questions = tracker.get_not_found_questions()?;
+    if not_found_questions.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No unanswered questions to retry for conversation: {}",
+            request.id
+        ));
+    }
+    info!(
+        "Retrying {} questions without answers for conversation {}",
+        not_found_questions.len(),
+        request.id
+    );
+
+    // the reformulations are counted on the conversation before the suggest flow continues it.
+    let budget = conversation_meter(&tracker, tenant);
+    let reformulated = reformulate_questions(&mut tracker, not_found_questions, &budget).await;
+    settle_budget(&mut tracker, tenant, &budget, reformulated.as_ref().err());
+    reformulated?;
+    tracker.save_task_process_to_redis(redis_url)?;
+
+    let user_query = tracker
+        .last_user_query()
+        .ok_or_else(|| anyhow::anyhow!("No user query found in the conversation"))?;
+
+    handle_suggest_core(
+        SuggestRequest {
+            id: Some(request.id),
+            user_query,
+            repo_name: tracker.repo.clone(),
+            pinned_paths: request.pinned_paths,
+            // the webhook saved with the conversation is used.
+            callback_url: None,
+            callback_secret: None,
+            // the language and the scope recorded on the conversation are used.
+            language: None,
+            scope: Vec::new(),
+            include_verification: None,
+            force: false,
+            repin_generation: false,
+        },
+        tenant,
+    )
+    .await
+}
+
+async fn reformulate_questions(
+    tracker: &mut TrackProcessV1,
+    not_found_questions: Vec<(QuestionWithId, Vec<String>, Vec<String>)>,
+    budget: &BudgetMeter,
+) -> Result<(), anyhow::Error> {
+    for (question, attempted_queries, closest_paths) in not_found_questions {
+        let reformulated = reformulate_not_found_question(
+            &question.text,
+            &attempted_queries,
+            &closest_paths,
+            budget,
+        )
+        .await?;
+        debug!("Retrying question {} as: {}", question.id, reformulated);
+        tracker.supersede_not_found_answer(question.id, &reformulated)?;
+    }
+    Ok(())
+}
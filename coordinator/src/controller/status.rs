@@ -0,0 +1,103 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use common::task_graph::redis::load_task_process_from_redis;
+use futures::StreamExt;
+use log::error;
+use reqwest::StatusCode;
+use serde::Serialize;
+use warp::sse::Event;
+use warp::Reply;
+
+use crate::admission::{self, QueueState, QueueStatus};
+use crate::configuration::get_redis_url;
+use crate::liveness;
+
+#[derive(Serialize)]
+struct ConversationStatus {
+    id: String,
+    queue: QueueStatus,
+}
+
+#[derive(Serialize)]
+struct KeepAlive {
+    id: String,
+    // the conversation was suspended and this resumed it.
+    resumed: bool,
+    queue: QueueStatus,
+}
+
+// Returns where the questions of the conversation are in the admission queue.
+pub async fn handle_status_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    if let Some(not_found) = check_access(&id, &tenant) {
+        return Ok(not_found);
+    }
+    let queue = admission::global().status(&id);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ConversationStatus { id, queue }),
+        StatusCode::OK,
+    )
+    .into_response())
+}
+
+// Streams the queue status of the conversation as server-sent `queue` events, one per change, and
+// `suspended` events when its questions wait for its client. The conversation is alive while the
+// stream is open.
+pub async fn handle_events_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    if let Some(not_found) = check_access(&id, &tenant) {
+        return Ok(not_found);
+    }
+    liveness::keep_alive(&id).await;
+    let guard = admission::global().watch(&id);
+    let events = admission::global().status_updates(id).map(move |status| {
+        let _alive = &guard;
+        let event = match status.state {
+            QueueState::Suspended => "suspended",
+            _ => "queue",
+        };
+        Event::default().event(event).json_data(status)
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+}
+
+// Keeps a conversation of a client without an event stream alive, resuming it when it was suspended.
+pub async fn handle_keepalive_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    if let Some(not_found) = check_access(&id, &tenant) {
+        return Ok(not_found);
+    }
+    let resumed = liveness::keep_alive(&id).await;
+    let queue = admission::global().status(&id);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&KeepAlive { id, resumed, queue }),
+        StatusCode::OK,
+    )
+    .into_response())
+}
+
+// The not found reply when the conversation doesn't exist or belongs to another tenant.
+fn check_access(id: &str, tenant: &Tenant) -> Option<warp::reply::Response> {
+    match load_task_process_from_redis(&get_redis_url(), id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => return None,
+        Ok(_) => error!(
+            "Tenant {} tried to read the status of conversation {} of another tenant",
+            tenant.id, id
+        ),
+        Err(e) => error!("Failed to load conversation {} from Redis: {}", id, e),
+    }
+    Some(
+        warp::reply::with_status(
+            warp::reply::json(&format!("Conversation not found: {}", id)),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    )
+}
@@ -1,59 +1,117 @@
 use tokio::sync::mpsc;
 use rand::Rng;
 
-use crate::code_understanding::get_codebase_answers_for_questions;
-use crate::llm_ops::tasks_questions::generate_tasks_and_questions;
+use crate::admission::Priority;
+use crate::budget::{conversation_meter, settle_budget, trim_questions, DEGRADED_QUESTIONS_PER_SUBTASK};
+use crate::code_understanding::{
+    condensed_repo_summary, fetch_freshness, fetch_grounding_index, fetch_index_run,
+    fetch_repo_summary, get_codebase_answers_for_questions, pin_index_generation, AnswerOptions,
+};
+use crate::liveness;
+use crate::llm_ops::follow_up::{classify_follow_up, MessageKind};
+use crate::llm_ops::tasks_questions::{generate_tasks_and_questions, TaskPrompt};
 use ai_gateway::message::message::Message;
 use anyhow::Result;
+use common::auth::{self, Tenant};
+use common::budget::BudgetMeter;
+use common::language::resolve_language;
 use common::models::{
      TaskList, TaskListResponseWithMessage,
 };
 
 use crate::controller::error::AgentProcessingError;
-use crate::configuration::get_redis_url;
-use crate::llm_ops::summarize::generate_summarized_answer_for_task;
+use crate::duplicates::{check_duplicate, possible_duplicate, remember_conversation};
+use crate::configuration::{get_auto_quick_answer, get_max_prompt_history_messages, get_redis_url};
+use crate::controller::quick_answer::{answer_quick_question, quick_answer_response, QuickQuestion};
+use crate::llm_ops::query_route::{route_query, QueryRoute};
+use crate::llm_ops::summarize::{
+    generate_summarized_answer_for_task, prompt_history_messages,
+    subtask_synthesis_prompt_versions, summary_prompt_versions, synthesize_subtasks,
+};
 use common::task_graph::graph_model::{
     ConversationChain, TrackProcessV1,
 };
+use common::shutdown;
 use common::task_graph::redis::load_task_process_from_redis;
 use common::task_graph::state::ConversationProcessingStage;
 use log::{debug, error, info};
 use reqwest::StatusCode;
 use std::convert::Infallible;
+use warp::Reply;
+use std::time::Instant;
 
 use crate::models::{SuggestResponse, SuggestRequest};
+use crate::timings::{record_answer, report_slow_phases};
+use crate::similar_questions::record_questions;
+use crate::webhook::{report_scope_violations, ConversationWebhook, Milestone};
 
 pub async fn handle_suggest_wrapper(
     request: SuggestRequest,
-) -> Result<impl warp::Reply, Infallible> {
-    match handle_suggest_core(request).await {
+    tenant: Tenant,
+) -> Result<warp::reply::Response, Infallible> {
+    if !tenant.can_access_repo(&request.repo_name) {
+        return Ok(auth::repo_forbidden(&tenant, &request.repo_name).into_response());
+    }
+
+    match handle_suggest_core(request, &tenant).await {
         Ok(response) => Ok(warp::reply::with_status(
             warp::reply::json(&response),
             StatusCode::OK,
-        )),
+        )
+        .into_response()),
         Err(e) => {
             log::error!("Error processing modify code request: {}", e);
             // TODO: Convert the error message into a structured error response
             let error_message = format!("Error processing request: {}", e);
-            Ok(warp::reply::with_status(
-                warp::reply::json(&error_message),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ))
+            Ok(AgentProcessingError::reply(&e, &error_message))
         }
     }
 }
 
-async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse, anyhow::Error> {
+pub(crate) async fn handle_suggest_core(
+    request: SuggestRequest,
+    tenant: &Tenant,
+) -> Result<SuggestResponse, anyhow::Error> {
+    // milestones are posted to the webhook of the conversation, if it has one, once the
+    // conversation is known to belong to the tenant.
+    let mut webhook = ConversationWebhook::requested(
+        &get_redis_url(),
+        request.callback_url.clone(),
+        request.callback_secret.clone(),
+    )?;
+
+    let result = process_suggest(request, tenant, &mut webhook).await;
+    if let Err(e) = &result {
+        let milestone = match AgentProcessingError::budget_exceeded(e) {
+            Some(exceeded) => Milestone::BudgetExceeded(exceeded.clone()),
+            None => Milestone::Failed {
+                error: e.to_string(),
+            },
+        };
+        webhook.notify(milestone).await;
+    }
+    result
+}
+
+async fn process_suggest(
+    request: SuggestRequest,
+    tenant: &Tenant,
+    webhook: &mut ConversationWebhook,
+) -> Result<SuggestResponse, anyhow::Error> {
     // if the request.uuid exists, load the conversation from the conversations API
-    let convo_id = request.id;
+    let convo_id = request.id.clone();
 
     let redis_url: &str = &get_redis_url();
+    // the issue of a new conversation, compared with the recent conversations of the tenant.
+    let mut duplicate_check = None;
     let mut tracker = if convo_id.is_some() {
         let uuid = convo_id.clone().unwrap();
         info!(
             "Conversation ID exists, loading the conversation from Redis: {}",
             uuid
         );
+        // a message of the user resumes the conversation if it was suspended.
+        liveness::keep_alive(&uuid).await;
 
         // load the conversation from the redis
         let tracker = load_task_process_from_redis(redis_url, &uuid);
@@ -66,16 +124,143 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
             error!("{}", err_msg);
             return Err(anyhow::anyhow!("{}", err_msg));
         }
-        tracker.unwrap()
+        let tracker = tracker.unwrap();
+        if !tracker.belongs_to(&tenant.id) {
+            error!("Tenant {} tried to continue conversation {} of another tenant", tenant.id, uuid);
+            return Err(AgentProcessingError::ConversationNotFound(uuid).into());
+        }
+        webhook.set_conversation_id(Some(uuid));
+        tracker
     } else {
         info!("No conversation ID provided, New conversation initiated.");
+        // a recent conversation on the same issue is offered instead of starting another run.
+        duplicate_check =
+            check_duplicate(redis_url, &tenant.id, &request.repo_name, &request.user_query);
+        if let Some(duplicate) = duplicate_check
+            .as_ref()
+            .and_then(|check| check.blocking(request.force))
+        {
+            info!("New conversation on {} is a {}", request.repo_name, duplicate.render());
+            return Ok(SuggestResponse {
+                possible_duplicate: Some(possible_duplicate(redis_url, &tenant.id, duplicate)),
+                ..Default::default()
+            });
+        }
         // create a new tracker
-        TrackProcessV1::new(&request.repo_name, redis_url)
+        let mut tracker = TrackProcessV1::new(&request.repo_name, redis_url);
+        tracker.tenant_id = Some(tenant.id.clone());
+        tracker
     };
+
+    // the spend of the flow is recorded on the conversation whether it succeeds or not.
+    let budget = conversation_meter(&tracker, tenant);
+    let repo_name = request.repo_name.clone();
+    let result = advance_conversation(request, &mut tracker, webhook, &budget).await;
+    settle_budget(&mut tracker, tenant, &budget, result.as_ref().err());
+    if let Some(check) = duplicate_check {
+        if let Err(e) = remember_conversation(redis_url, &mut tracker, &tenant.id, &repo_name, check) {
+            error!("Failed to record the conversation for duplicate detection: {}", e);
+        }
+    }
+    if result.is_ok() {
+        if let Err(e) = record_questions(redis_url, &tracker) {
+            error!("Failed to record the questions for the suggestions: {}", e);
+        }
+    }
+    result
+}
+
+// Runs the stages of the conversation from the one it stopped at, until there is a response
+// for the user. The LLM calls are checked against `budget`, once it is degraded the tasks are
+// generated with fewer questions.
+async fn advance_conversation(
+    request: SuggestRequest,
+    tracker: &mut TrackProcessV1,
+    webhook: &mut ConversationWebhook,
+    budget: &BudgetMeter,
+) -> Result<SuggestResponse, anyhow::Error> {
+    let convo_id = request.id.clone();
+    let redis_url: &str = &get_redis_url();
+
+    // simple lookups on a new conversation are answered directly, without generating tasks.
+    if convo_id.is_none()
+        && get_auto_quick_answer()
+        && route_query(&request.user_query) == QueryRoute::QuickAnswer
+    {
+        info!("Answering {:?} without generating tasks", request.user_query);
+        let question = QuickQuestion {
+            repo_name: &request.repo_name,
+            query: &request.user_query,
+            pinned_paths: &request.pinned_paths,
+            language: request.language.as_deref(),
+            scope: &request.scope,
+            include_verification: false,
+        };
+        let (mut answer, missing_pinned_paths) =
+            answer_quick_question(tracker, &question, budget).await?;
+        let started = Instant::now();
+        tracker.save_task_process_to_redis(redis_url)?;
+        let persistence_ms = started.elapsed().as_millis() as u64;
+        answer.answer.timings = Some(tracker.record_persistence_time(answer.question_id, persistence_ms)?);
+        webhook.set_conversation_id(tracker.get_root_node_uuid());
+        webhook.notify(Milestone::question_answered(&answer)).await;
+        report_slow_phases(webhook, &answer).await;
+        report_scope_violations(webhook, &answer).await;
+        return Ok(quick_answer_response(tracker, answer, missing_pinned_paths));
+    }
     // get the state of the conversation
     let (mut state, node_index) = tracker.last_conversation_processing_stage();
+    // pinned paths the code understanding service couldn't find in the index.
+    let mut missing_pinned_paths: Vec<String> = Vec::new();
+
+    // a new message on an answered conversation is either a follow-up question about it,
+    // which is answered without generating new tasks, or a new issue.
+    let answered = matches!(
+        state,
+        ConversationProcessingStage::AnswersSummarized
+            | ConversationProcessingStage::AllQuestionsAnswered
+            | ConversationProcessingStage::FollowUpAnswered
+    );
+    let previous_query = tracker.last_user_query().unwrap_or_default();
+    if answered && previous_query != request.user_query {
+        let tasks = tracker
+            .get_current_tasks()?
+            .tasks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|task| task.task)
+            .collect::<Vec<_>>();
+        state = match classify_follow_up(&previous_query, &tasks, &request.user_query, budget).await {
+            MessageKind::FollowUp => {
+                info!("Answering follow-up question on conversation {:?}", convo_id);
+                tracker.add_follow_up_question(&request.user_query)?;
+                ConversationProcessingStage::FollowUpPending
+            }
+            MessageKind::NewIssue => {
+                info!("New issue on conversation {:?}, generating tasks.", convo_id);
+                ConversationProcessingStage::GenerateTasksAndQuestions
+            }
+        };
+    }
+
+    // preferences stated in the message apply to it and to the rest of the conversation.
+    if state != ConversationProcessingStage::GraphNotInitialized {
+        tracker.record_preferences(&request.user_query)?;
+    }
+    // the conversation reads the current index generation from this message on.
+    if request.repin_generation && state != ConversationProcessingStage::GraphNotInitialized {
+        pin_index_generation(tracker).await?;
+    }
 
     loop {
+        // stop between stages when shutting down, the graph is saved so the conversation can continue later.
+        if shutdown::is_shutting_down() {
+            tracker.save_task_process_to_redis(redis_url)?;
+            let err_msg = "Coordinator is shutting down, send the request again to continue the conversation.";
+            error!("{}", err_msg);
+            return Err(anyhow::anyhow!(err_msg));
+        }
+
         match state {
             ConversationProcessingStage::OnlyRootNodeExists => {
                 error!("Only root node exists, no conversation has happened yet. Invalid state, create new conversation");
@@ -84,12 +269,57 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
             ConversationProcessingStage::GraphNotInitialized => {
                 debug!("Graph not initialized, initializing the graph and setting the next state to GenerateTasksAndQuestions");
                 &tracker.initialize_graph();
+                tracker.record_preferences(&request.user_query)?;
+                if let Some(run) = fetch_index_run(&request.repo_name).await {
+                    tracker.record_index_run(run)?;
+                }
+                pin_index_generation(tracker).await?;
+                if let Some(freshness) = fetch_freshness(&request.repo_name).await {
+                    if let Some(banner) = freshness.banner() {
+                        info!("{}", banner);
+                    }
+                    tracker.record_freshness(freshness)?;
+                }
+                if let Some(language) =
+                    resolve_language(request.language.as_deref(), &request.user_query)
+                {
+                    info!("Conversation is answered in {}", language);
+                    tracker.record_language(&language)?;
+                }
+                if !request.scope.is_empty() {
+                    info!("Conversation is scoped to {:?}", request.scope);
+                    tracker.record_scope(&request.scope)?;
+                }
+                webhook.set_conversation_id(tracker.get_root_node_uuid());
                 state = ConversationProcessingStage::GenerateTasksAndQuestions;
             }
             ConversationProcessingStage::GenerateTasksAndQuestions => {
                 // get the generated questions from the LLM or the file based on the data modes
+                // prior messages of the conversation, the older ones summarized into a single note.
+                let history = match tracker.prompt_history(get_max_prompt_history_messages()) {
+                    Ok(history) => prompt_history_messages(history, budget).await,
+                    Err(_) => Vec::new(),
+                };
+                let preferences = tracker.preferences().render();
+                let language = tracker.language();
+                let summary = fetch_repo_summary(&request.repo_name).await;
+                let repo_summary = condensed_repo_summary(summary.as_ref());
+                // the tasks naming components the repo doesn't have are repaired or flagged.
+                let grounding = fetch_grounding_index(&request.repo_name, summary.as_ref()).await;
                 let generated_questions_with_llm_messages: TaskListResponseWithMessage =
-                    generate_tasks_and_questions(&request.user_query, &request.repo_name).await?;
+                    generate_tasks_and_questions(
+                        &TaskPrompt {
+                            user_query: &request.user_query,
+                            repo_name: &request.repo_name,
+                            preferences: preferences.as_deref(),
+                            language: language.as_deref(),
+                            repo_summary: repo_summary.as_deref(),
+                        },
+                        history,
+                        grounding.as_ref(),
+                        budget,
+                    )
+                    .await?;
 
                 debug!(
                     "Generated questions: {:?}",
@@ -99,7 +329,7 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                 // the response contains the generated questions and the messages
                 // the messages contain the system prompt which was used to generate the questions
                 // also the response of the assistant for the prompt used to generate questions.
-                let generated_questions: TaskList = generated_questions_with_llm_messages.task_list;
+                let mut generated_questions: TaskList = generated_questions_with_llm_messages.task_list;
                 let messages = generated_questions_with_llm_messages.messages;
 
                 if generated_questions.ask_user.is_none() && generated_questions.tasks.is_none() {
@@ -110,6 +340,10 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                     error!("{}", error_message);
                     return Err(anyhow::anyhow!(error_message));
                 }
+                if budget.is_degraded() {
+                    info!("The conversation is close to its budget, asking fewer questions.");
+                    trim_questions(&mut generated_questions, DEGRADED_QUESTIONS_PER_SUBTASK);
+                }
 
                 let user_system_assistant_conversation = ConversationChain {
                     user_message: Message::user(&request.user_query),
@@ -145,10 +379,22 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                         plan: None,
                         ask_user: generated_questions.ask_user.clone(),
                         questions_with_answers: None,
+                        missing_pinned_paths: missing_pinned_paths.clone(),
+                        follow_up: None,
+                        quick_answer: None,
+                        freshness_banner: tracker.freshness_banner(),
+                        possible_duplicate: None,
+                        clarification: None,
                     });
                 }
                 // the tasks and questions are successfully generated, move to find answers for the questions.
                 debug!("Tasks and Questions generated successfully, moving onto finding answers for the generated questions.");
+                webhook
+                    .notify(Milestone::TasksGenerated(TaskList {
+                        tasks: generated_questions.tasks.clone(),
+                        ask_user: None,
+                    }))
+                    .await;
                 state = ConversationProcessingStage::TasksAndQuestionsGenerated;
             }
             ConversationProcessingStage::TasksAndQuestionsGenerated => {
@@ -165,14 +411,33 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                 let question_count = questions_list.len();
                 let repo_name = request.repo_name.clone();
                 let task_id = tracker.get_root_node_uuid().unwrap();
+                let pinned_paths = request.pinned_paths.clone();
+                let preferences = tracker.preferences().render();
+                let language = tracker.language();
+                let scope = tracker.scope();
+                let budget = budget.clone();
+                let include_verification = request.include_verification.unwrap_or(true);
+                let index_generation = tracker.index_generation();
+                let index_run = tracker.index_run();
                 let handle = tokio::spawn(async move {
                     if let Err(e) = get_codebase_answers_for_questions(
                         repo_name,
                         task_id,
                         &questions_list,
-                        false,
                         tx,
-                        true,
+                        &budget,
+                        &AnswerOptions {
+                            parallel: false,
+                            can_interrupt: true,
+                            pinned_paths: &pinned_paths,
+                            preferences: preferences.as_deref(),
+                            language: language.as_deref(),
+                            scope: &scope,
+                            priority: Priority::Bulk,
+                            include_verification,
+                            index_generation: index_generation.as_ref(),
+                            index_run: index_run.as_ref(),
+                        },
                     )
                     .await
                     {
@@ -184,10 +449,50 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                 let mut answers = Vec::new();
                 while let Some(result) = rx.recv().await {
                     match result {
-                        Ok(answer) => {
+                        Ok(mut answer) => {
                             debug!("Received answer: {:?}", answer);
+                            // the agent stopped to ask the user, the question is answered once the
+                            // user responds, the ones after it wait.
+                            if let Some(clarification) = answer.answer.clarification() {
+                                info!(
+                                    "Question {} needs the user to clarify it: {}",
+                                    answer.question_id,
+                                    clarification.render()
+                                );
+                                handle.abort();
+                                tracker.record_clarification(answer.question_id, clarification.clone())?;
+                                tracker.save_task_process_to_redis(redis_url)?;
+                                webhook
+                                    .notify(Milestone::ClarificationRequested {
+                                        question_id: answer.question_id,
+                                        question: clarification.question.clone(),
+                                        options: clarification.options.clone(),
+                                    })
+                                    .await;
+                                return Ok(SuggestResponse {
+                                    id: tracker.get_root_node_uuid().unwrap(),
+                                    tasks: Some(tracker.get_current_tasks()?),
+                                    questions_with_answers: Some(tracker.get_current_questions_with_answers()?),
+                                    plan: None,
+                                    ask_user: Some(clarification.render()),
+                                    missing_pinned_paths: missing_pinned_paths.clone(),
+                                    follow_up: None,
+                                    quick_answer: None,
+                                    freshness_banner: tracker.freshness_banner(),
+                                    possible_duplicate: None,
+                                    clarification: Some(clarification),
+                                });
+                            }
+                            for path in &answer.answer.missing_pinned_paths {
+                                if !missing_pinned_paths.contains(path) {
+                                    missing_pinned_paths.push(path.clone());
+                                }
+                            }
                             // save the answer to the graph
-                            tracker.extend_graph_with_answers(&vec![Ok(answer.clone())])?;
+                            record_answer(tracker, &mut answer)?;
+                            webhook.notify(Milestone::question_answered(&answer)).await;
+                            report_slow_phases(webhook, &answer).await;
+                            report_scope_violations(webhook, &answer).await;
                             answers.push(answer);
                         }
                         Err(e) => {
@@ -202,12 +507,18 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                                         questions_with_answers: Some(tracker.get_current_questions_with_answers()?),
                                         plan: None,
                                         ask_user: None,
+                                        missing_pinned_paths: missing_pinned_paths.clone(),
+                                        follow_up: None,
+                                        quick_answer: None,
+                                        freshness_banner: tracker.freshness_banner(),
+                                        possible_duplicate: None,
+                                        clarification: None,
                                     });
                                 }
                                 _ => {
                                     debug!("Error: {:?}", e);
                                     // return error 
-                                    return Err(anyhow::anyhow!("{}", e));
+                                    return Err(e.into());
                                 }
                             }
 
@@ -217,6 +528,7 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
 
                 // Wait for all processing to complete
                 handle.await?;
+                webhook.notify(Milestone::AllQuestionsAnswered).await;
                 state = ConversationProcessingStage::AllQuestionsAnswered;
             }
             // you start with this state because the previous conversation with the user ended
@@ -240,16 +552,42 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
             // Summarize answers after all the answers are fetched.
             ConversationProcessingStage::SummarizeAnswers => {
                 debug!("Summarizing answers for the tasks and questions.");
-                let tasks_qna_context = tracker.collect_tasks_questions_answers_contexts()?;
+                let mut tasks_qna_context = tracker.collect_tasks_questions_answers_contexts()?;
+                // the answers of each subtask are synthesized first, the summary reads the syntheses.
+                let syntheses =
+                    synthesize_subtasks(&request.user_query, &mut tasks_qna_context, budget)
+                        .await?;
+                let synthesis_versions = subtask_synthesis_prompt_versions();
+                for (subtask_id, synthesis) in &syntheses {
+                    tracker.record_subtask_summary(*subtask_id, synthesis, &synthesis_versions)?;
+                }
+                let mut glossary = tracker.glossary();
+                let prompt_versions = summary_prompt_versions(&glossary);
 
                 let summary = generate_summarized_answer_for_task(
                     request.user_query.clone(),
                     &tasks_qna_context,
+                    tracker.preferences().render().as_deref(),
+                    tracker.language().as_deref(),
+                    &mut glossary,
+                    budget,
                 )
                 .await?;
+                if !glossary.is_empty() {
+                    tracker.record_glossary(glossary)?;
+                }
 
                 // connect the summary to the graph, this will also save the summary to the redis.
-                tracker.connect_task_to_answer_summary(&tasks_qna_context, &summary)?;
+                tracker.connect_task_to_answer_summary(
+                    &tasks_qna_context,
+                    &summary,
+                    &prompt_versions,
+                )?;
+                webhook
+                    .notify(Milestone::SummaryReady {
+                        summary: summary.clone(),
+                    })
+                    .await;
 
                 return Ok(SuggestResponse {
                     id: tracker.get_root_node_uuid().unwrap(),
@@ -257,13 +595,109 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                     tasks: Some(tracker.get_current_tasks()?),
                     questions_with_answers: Some(tracker.get_current_questions_with_answers()?),
                     ask_user: None,
+                    missing_pinned_paths: missing_pinned_paths.clone(),
+                    follow_up: None,
+                    quick_answer: None,
+                    freshness_banner: tracker.freshness_banner(),
+                    possible_duplicate: None,
+                    clarification: None,
                 });
             }
+            // the message of the user lifts the suspension as it comes in, one still on the graph,
+            // e.g. after a restart of the coordinator, is lifted here. The unanswered questions are asked again.
+            ConversationProcessingStage::Suspended => {
+                info!("Resuming suspended conversation {:?}", convo_id);
+                if tracker.record_resumed()? {
+                    webhook.notify(Milestone::Resumed).await;
+                }
+                (state, _) = tracker.last_conversation_processing_stage();
+            }
+            // the message of the user is the response to the clarifying question, the question is
+            // asked again with it and the agent resumes where it stopped.
+            ConversationProcessingStage::AwaitingClarification => {
+                if let Some(question_id) = tracker.record_clarification_response(&request.user_query)? {
+                    info!("Question {} clarified by the user, resuming its answer", question_id);
+                }
+                state = ConversationProcessingStage::TasksAndQuestionsGenerated;
+            }
             ConversationProcessingStage::QuestionsPartiallyAnswered => {
                 debug!("Some Questions are unanswered, continuing to find answers.");
                 state = ConversationProcessingStage::TasksAndQuestionsGenerated;
             }
 
+            ConversationProcessingStage::FollowUpPending => {
+                let follow_up = tracker.last_follow_up_question().ok_or_else(|| {
+                    anyhow::anyhow!("No follow-up question found on the last conversation.")
+                })?;
+                // the code found for the tasks is pinned, code understanding still retrieves fresh code on top of it.
+                let mut pinned_paths = tracker.get_code_context_paths();
+                for path in &request.pinned_paths {
+                    if !pinned_paths.contains(path) {
+                        pinned_paths.push(path.clone());
+                    }
+                }
+                debug!("Answering follow-up question {:?} with pinned paths {:?}", follow_up, pinned_paths);
+
+                let (tx, mut rx) = mpsc::channel(1);
+                get_codebase_answers_for_questions(
+                    request.repo_name.clone(),
+                    tracker.get_root_node_uuid().unwrap(),
+                    &[follow_up],
+                    tx,
+                    budget,
+                    &AnswerOptions {
+                        parallel: false,
+                        can_interrupt: false,
+                        pinned_paths: &pinned_paths,
+                        preferences: tracker.preferences().render().as_deref(),
+                        language: tracker.language().as_deref(),
+                        scope: &tracker.scope(),
+                        priority: Priority::Interactive,
+                        include_verification: false,
+                        index_generation: tracker.index_generation().as_ref(),
+                        index_run: tracker.index_run().as_ref(),
+                    },
+                )
+                .await?;
+
+                match rx.recv().await {
+                    Some(Ok(mut answer)) => {
+                        for path in &answer.answer.missing_pinned_paths {
+                            if !missing_pinned_paths.contains(path) {
+                                missing_pinned_paths.push(path.clone());
+                            }
+                        }
+                        // save the answer to the graph, this also saves the follow-up question.
+                        record_answer(tracker, &mut answer)?;
+                        report_slow_phases(webhook, &answer).await;
+                        report_scope_violations(webhook, &answer).await;
+                    }
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err(anyhow::anyhow!("No answer received for the follow-up question.")),
+                }
+                state = ConversationProcessingStage::FollowUpAnswered;
+            }
+            ConversationProcessingStage::FollowUpAnswered => {
+                let follow_up = tracker.last_follow_up_answer()?.ok_or_else(|| {
+                    anyhow::anyhow!("The follow-up question on the last conversation isn't answered.")
+                })?;
+
+                return Ok(SuggestResponse {
+                    id: tracker.get_root_node_uuid().unwrap(),
+                    // conversations can be followed up before their answers were summarized.
+                    plan: tracker.get_summary().ok(),
+                    tasks: Some(tracker.get_current_tasks()?),
+                    questions_with_answers: Some(tracker.get_current_questions_with_answers()?),
+                    ask_user: None,
+                    missing_pinned_paths: missing_pinned_paths.clone(),
+                    follow_up: Some(follow_up),
+                    quick_answer: None,
+                    freshness_banner: tracker.freshness_banner(),
+                    possible_duplicate: None,
+                    clarification: None,
+                });
+            }
+
             ConversationProcessingStage::AnswersSummarized => {
                 tracker.print_graph_hierarchy();
                 // nothing more to do,return the answers.
@@ -306,6 +740,12 @@ async fn handle_suggest_core(request: SuggestRequest) -> Result<SuggestResponse,
                     questions_with_answers: Some(tracker.get_current_questions_with_answers()?),
                     plan: Some(plan),
                     ask_user: None,
+                    missing_pinned_paths: missing_pinned_paths.clone(),
+                    follow_up: None,
+                    quick_answer: None,
+                    freshness_banner: tracker.freshness_banner(),
+                    possible_duplicate: None,
+                    clarification: None,
                 });
             }
         }
@@ -0,0 +1,37 @@
+use common::auth::{self, Tenant};
+use log::error;
+use reqwest::StatusCode;
+use std::convert::Infallible;
+
+use crate::configuration::get_redis_url;
+use crate::models::{SuggestQuestionsQuery, SuggestQuestionsResponse};
+use crate::similar_questions::{suggest_questions, DEFAULT_SUGGESTION_LIMIT, MAX_SUGGESTION_LIMIT};
+
+// The questions asked before on the repo by the tenant that are the most similar to the query,
+// for the UI to offer while the user types.
+pub async fn handle_suggest_questions_wrapper(
+    query: SuggestQuestionsQuery,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if !tenant.can_access_repo(&query.repo) {
+        return Ok(auth::repo_forbidden(&tenant, &query.repo));
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SUGGESTION_LIMIT)
+        .clamp(1, MAX_SUGGESTION_LIMIT);
+    match suggest_questions(&get_redis_url(), &tenant.id, &query.repo, &query.q, limit) {
+        Ok(questions) => Ok(warp::reply::with_status(
+            warp::reply::json(&SuggestQuestionsResponse { questions }),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to suggest questions for repo {}: {}", query.repo, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error suggesting questions: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
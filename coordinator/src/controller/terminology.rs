@@ -0,0 +1,91 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use common::capabilities::{Capability, Service};
+use common::service_interaction::{capabilities, service_caller, HttpMethod};
+use common::terminology::{RepoTerminology, Terminology};
+use log::{error, info};
+use reqwest::StatusCode;
+
+use crate::configuration::get_code_search_url;
+use crate::controller::error::error_reply;
+
+fn unsupported() -> Option<warp::reply::WithStatus<warp::reply::Json>> {
+    (!capabilities(Service::CodeSearch).supports(Capability::Terminology)).then(|| {
+        error_reply(
+            StatusCode::NOT_IMPLEMENTED,
+            "The code search service doesn't support terminology dictionaries, upgrade it to \
+             expand the jargon of the repos"
+                .to_string(),
+        )
+    })
+}
+
+// The dictionary of the repo with the terms mined from its docs that aren't approved yet.
+pub async fn handle_get_terminology(
+    repo: String,
+    _tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Some(reply) = unsupported() {
+        return Ok(reply);
+    }
+    let terminology = service_caller::<(), RepoTerminology>(
+        format!("{}/repos/{}/terminology", get_code_search_url(), repo),
+        HttpMethod::GET,
+        None,
+        None,
+    )
+    .await;
+    match terminology {
+        Ok(terminology) => Ok(warp::reply::with_status(
+            warp::reply::json(&terminology),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to fetch the terminology of repo {}: {}", repo, e);
+            Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error fetching the terminology: {}", e),
+            ))
+        }
+    }
+}
+
+// Replaces the dictionary of the repo, approving a suggestion is adding it to the terms.
+pub async fn handle_put_terminology(
+    repo: String,
+    terminology: Terminology,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Some(reply) = unsupported() {
+        return Ok(reply);
+    }
+    let stored = service_caller::<Terminology, Terminology>(
+        format!("{}/repos/{}/terminology", get_code_search_url(), repo),
+        HttpMethod::PUT,
+        Some(terminology),
+        None,
+    )
+    .await;
+    match stored {
+        Ok(terminology) => {
+            info!(
+                "Admin {} set {} terms for repo {}",
+                tenant.id,
+                terminology.terms.len(),
+                repo
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&terminology),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            error!("Failed to store the terminology of repo {}: {}", repo, e);
+            Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error storing the terminology: {}", e),
+            ))
+        }
+    }
+}
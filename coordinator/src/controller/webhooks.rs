@@ -0,0 +1,52 @@
+use std::convert::Infallible;
+
+use common::auth::Tenant;
+use common::task_graph::redis::load_task_process_from_redis;
+use log::error;
+use reqwest::StatusCode;
+
+use crate::configuration::get_redis_url;
+use crate::webhook::load_delivery_log;
+
+// Returns the deliveries to the webhook of a conversation, oldest first, to debug an integration
+// that missed a milestone.
+pub async fn handle_webhook_log_wrapper(
+    id: String,
+    tenant: Tenant,
+) -> Result<impl warp::Reply, Infallible> {
+    let redis_url = get_redis_url();
+    match load_task_process_from_redis(&redis_url, &id) {
+        Ok(tracker) if tracker.belongs_to(&tenant.id) => {}
+        Ok(_) => {
+            error!(
+                "Tenant {} tried to read the webhook log of conversation {} of another tenant",
+                tenant.id, id
+            );
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Conversation not found: {}", id)),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+        Err(e) => {
+            error!("Failed to load conversation {} from Redis: {}", id, e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Conversation not found: {}", id)),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+
+    match load_delivery_log(&redis_url, &id) {
+        Ok(log) => Ok(warp::reply::with_status(
+            warp::reply::json(&log),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to load the webhook log of conversation {}: {}", id, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!("Error loading the webhook log: {}", e)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// The services and stores the coordinator can't answer without, checked concurrently at startup
+// and by the `/ready` route.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use common::ai_util::call_llm;
+use common::dependencies::DependencyChecker;
+use common::task_graph::redis::establish_redis_connection;
+
+use crate::configuration::{
+    get_ai_gateway_config, get_code_search_url, get_code_understanding_url, get_redis_url,
+};
+
+// a dependency that isn't up yet gets one more try at startup.
+const STARTUP_ATTEMPTS: usize = 2;
+const STARTUP_RETRY_DELAY: Duration = Duration::from_secs(5);
+// the smoke test of the AI gateway waits for a completion.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Code search, code understanding and Redis. `smoke_test_llm` adds a completion through the AI
+/// gateway, only done at startup since every call is billed.
+pub fn dependency_checker(smoke_test_llm: bool) -> DependencyChecker {
+    let checker = DependencyChecker::new()
+        .http("code-search", &get_code_search_url())
+        .http("code-understanding", &get_code_understanding_url())
+        .check("redis", || async {
+            let url = get_redis_url();
+            tokio::task::spawn_blocking(move || establish_redis_connection(&url))
+                .await?
+                .map(|_| ())
+                .map_err(|e| anyhow!("failed to connect to Redis: {}", e))
+        });
+    if !smoke_test_llm {
+        return checker;
+    }
+    checker.check("ai-gateway", || async {
+        let test_msg = "What LLM model are you?".to_string();
+        let response = call_llm(&get_ai_gateway_config(), Some(test_msg), None, None).await?;
+        log::info!("Successful LLM response: {:?}", response);
+        Ok(())
+    })
+}
+
+/// The checker of the startup, it retries the dependencies still starting.
+pub fn startup_checker() -> DependencyChecker {
+    dependency_checker(true)
+        .retry(STARTUP_ATTEMPTS, STARTUP_RETRY_DELAY)
+        .timeout(STARTUP_TIMEOUT)
+}
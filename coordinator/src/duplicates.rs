@@ -0,0 +1,303 @@
+// Detection of new conversations repeating a recent one. Teams paste the same incident into new
+// conversations, each one a full run. The issue of a new conversation is embedded with the model in
+// `MODEL_DIR` and compared with the recent conversations of the same tenant and repo, which redis
+// keeps newest first under a key of the tenant, so conversations of other tenants are never
+// compared. Only the `max_candidates` newest ones started within the lookback window are compared,
+// the check costs one embedding and one redis read.
+
+use anyhow::Result;
+use common::clock::unix_now;
+use common::duplicates::{cosine_similarity, DuplicateRef};
+use common::task_graph::graph_model::TrackProcessV1;
+use common::task_graph::redis::{establish_redis_connection, load_task_process_from_redis};
+use common::tokenizer_onnx::{Embedding, TokenizerOnnx};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::get_duplicate_detection;
+use crate::models::PossibleDuplicate;
+
+const DEFAULT_SIMILARITY_THRESHOLD: f32 = 0.92;
+// a day.
+const DEFAULT_LOOKBACK_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_CANDIDATES: usize = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateConfig {
+    // directory of the embedding model, duplicates aren't detected without one.
+    pub model_dir: Option<String>,
+    // cosine similarity of the issues from which a recent conversation is a duplicate.
+    pub threshold: f32,
+    // how old a conversation can be and still be offered as a duplicate.
+    pub lookback_secs: u64,
+    // recent conversations compared per tenant and repo, the newest ones.
+    pub max_candidates: usize,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> Self {
+        Self {
+            model_dir: None,
+            threshold: DEFAULT_SIMILARITY_THRESHOLD,
+            lookback_secs: DEFAULT_LOOKBACK_SECS,
+            max_candidates: DEFAULT_MAX_CANDIDATES,
+        }
+    }
+}
+
+/// A conversation as it is kept to be compared with the ones started after it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RecentConversation {
+    pub conversation_id: String,
+    pub tenant_id: String,
+    pub embedding: Embedding,
+    // unix seconds.
+    pub created_at: u64,
+}
+
+/// The embedding of the issue of a new conversation and the recent conversation it repeats.
+#[derive(Clone, Debug)]
+pub struct DuplicateCheck {
+    pub embedding: Embedding,
+    pub duplicate: Option<DuplicateRef>,
+}
+
+impl DuplicateCheck {
+    /// The duplicate the conversation stops at, none when the user asked to start it anyway.
+    pub fn blocking(&self, force: bool) -> Option<&DuplicateRef> {
+        self.duplicate.as_ref().filter(|_| !force)
+    }
+}
+
+fn recent_key(tenant_id: &str, repo_name: &str) -> String {
+    format!("recent_conversations:{}:{}", tenant_id, repo_name)
+}
+
+/// The most similar of the candidates of the tenant started within the lookback window, when it
+/// is at least `threshold` similar to the issue.
+pub fn find_duplicate(
+    embedding: &[f32],
+    tenant_id: &str,
+    candidates: &[RecentConversation],
+    config: &DuplicateConfig,
+    now: u64,
+) -> Option<DuplicateRef> {
+    candidates
+        .iter()
+        .take(config.max_candidates)
+        .filter(|candidate| candidate.tenant_id == tenant_id)
+        .filter(|candidate| candidate.created_at + config.lookback_secs >= now)
+        .map(|candidate| DuplicateRef {
+            conversation_id: candidate.conversation_id.clone(),
+            similarity: cosine_similarity(embedding, &candidate.embedding),
+        })
+        .filter(|duplicate| duplicate.similarity >= config.threshold)
+        .max_by(|a, b| a.similarity.total_cmp(&b.similarity))
+}
+
+/// The newest `max_candidates` conversations of the tenant on the repo.
+pub fn load_recent_conversations(
+    redis_url: &str,
+    tenant_id: &str,
+    repo_name: &str,
+    max_candidates: usize,
+) -> Result<Vec<RecentConversation>> {
+    if max_candidates == 0 {
+        return Ok(Vec::new());
+    }
+    let mut conn = establish_redis_connection(redis_url)?;
+    let values: Vec<String> = conn.lrange(
+        recent_key(tenant_id, repo_name),
+        0,
+        max_candidates as isize - 1,
+    )?;
+    Ok(values
+        .iter()
+        .filter_map(|value| serde_json::from_str(value).ok())
+        .collect())
+}
+
+/// Records a new conversation, the list keeps the newest candidates and expires with the
+/// lookback window once no conversation is started on the repo.
+pub fn record_recent_conversation(
+    redis_url: &str,
+    repo_name: &str,
+    conversation: &RecentConversation,
+    config: &DuplicateConfig,
+) -> Result<()> {
+    let key = recent_key(&conversation.tenant_id, repo_name);
+    let mut conn = establish_redis_connection(redis_url)?;
+    let _: () = conn.lpush(&key, serde_json::to_string(conversation)?)?;
+    let _: () = conn.ltrim(&key, 0, config.max_candidates.max(1) as isize - 1)?;
+    let _: () = conn.expire(&key, config.lookback_secs as usize)?;
+    Ok(())
+}
+
+/// Embeds the issue of a new conversation and looks for a recent conversation repeating it.
+/// None when detection is off or failed, the conversation then starts as usual.
+pub fn check_duplicate(
+    redis_url: &str,
+    tenant_id: &str,
+    repo_name: &str,
+    issue: &str,
+) -> Option<DuplicateCheck> {
+    let config = get_duplicate_detection();
+    let model_dir = config.model_dir.as_deref()?;
+    let check = || -> Result<DuplicateCheck> {
+        let embedding = TokenizerOnnx::shared(model_dir)?.get_embedding(issue)?;
+        let candidates =
+            load_recent_conversations(redis_url, tenant_id, repo_name, config.max_candidates)?;
+        let duplicate = find_duplicate(&embedding, tenant_id, &candidates, &config, unix_now());
+        Ok(DuplicateCheck {
+            embedding,
+            duplicate,
+        })
+    };
+    check()
+        .map_err(|e| log::error!("Failed to check for a duplicate conversation: {}", e))
+        .ok()
+}
+
+/// Links a conversation started despite a duplicate to it, and records the conversation for the
+/// ones started after it.
+pub fn remember_conversation(
+    redis_url: &str,
+    tracker: &mut TrackProcessV1,
+    tenant_id: &str,
+    repo_name: &str,
+    check: DuplicateCheck,
+) -> Result<()> {
+    let Some(conversation_id) = tracker.get_root_node_uuid() else {
+        return Ok(());
+    };
+    if let Some(duplicate) = check.duplicate {
+        log::info!(
+            "Conversation {} was started despite {}",
+            conversation_id,
+            duplicate.render()
+        );
+        tracker.record_duplicate_of(duplicate)?;
+        tracker.save_task_process_to_redis(redis_url)?;
+    }
+    let conversation = RecentConversation {
+        conversation_id,
+        tenant_id: tenant_id.to_string(),
+        embedding: check.embedding,
+        created_at: unix_now(),
+    };
+    record_recent_conversation(
+        redis_url,
+        repo_name,
+        &conversation,
+        &get_duplicate_detection(),
+    )
+}
+
+/// The duplicate as it is offered to the user, with the summary of its answers once they were
+/// summarized.
+pub fn possible_duplicate(
+    redis_url: &str,
+    tenant_id: &str,
+    duplicate: &DuplicateRef,
+) -> PossibleDuplicate {
+    let summary = load_task_process_from_redis(redis_url, &duplicate.conversation_id)
+        .ok()
+        .filter(|tracker| tracker.belongs_to(tenant_id))
+        .and_then(|tracker| tracker.get_summary().ok());
+    PossibleDuplicate {
+        conversation_id: duplicate.conversation_id.clone(),
+        similarity: duplicate.similarity,
+        summary,
+        force: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recent(
+        id: &str,
+        tenant_id: &str,
+        embedding: Vec<f32>,
+        created_at: u64,
+    ) -> RecentConversation {
+        RecentConversation {
+            conversation_id: id.to_string(),
+            tenant_id: tenant_id.to_string(),
+            embedding,
+            created_at,
+        }
+    }
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_near_duplicate_is_found_within_the_window() {
+        let config = DuplicateConfig::default();
+        let issue = vec![0.9, 0.1, 0.4];
+        let candidates = vec![
+            recent("unrelated", "acme", vec![0.0, 1.0, 0.0], NOW - 60),
+            recent("same-incident", "acme", vec![0.88, 0.12, 0.41], NOW - 600),
+            // the same incident, pasted two days ago.
+            recent(
+                "last-week",
+                "acme",
+                vec![0.9, 0.1, 0.4],
+                NOW - 2 * DEFAULT_LOOKBACK_SECS,
+            ),
+        ];
+
+        let duplicate = find_duplicate(&issue, "acme", &candidates, &config, NOW).unwrap();
+        assert_eq!(duplicate.conversation_id, "same-incident");
+        assert!(duplicate.similarity > 0.99);
+
+        // past the cap of candidates the older conversations aren't compared.
+        let capped = DuplicateConfig {
+            max_candidates: 1,
+            ..config.clone()
+        };
+        assert_eq!(
+            find_duplicate(&issue, "acme", &candidates, &capped, NOW),
+            None
+        );
+        assert_eq!(
+            find_duplicate(&[0.0, 0.0, 1.0], "acme", &candidates, &config, NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn test_forced_conversation_is_linked_to_its_duplicate() {
+        let check = DuplicateCheck {
+            embedding: vec![0.9, 0.1, 0.4],
+            duplicate: Some(DuplicateRef {
+                conversation_id: "same-incident".to_string(),
+                similarity: 0.97,
+            }),
+        };
+        assert!(check.blocking(false).is_some());
+        assert_eq!(check.blocking(true), None);
+
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.initialize_graph();
+        tracker
+            .record_duplicate_of(check.duplicate.clone().unwrap())
+            .unwrap();
+        assert_eq!(tracker.duplicate_of(), check.duplicate);
+    }
+
+    #[test]
+    fn test_conversations_of_other_tenants_are_never_compared() {
+        let config = DuplicateConfig::default();
+        let issue = vec![0.9, 0.1, 0.4];
+        let candidates = vec![recent("other-tenant", "globex", issue.clone(), NOW - 60)];
+
+        assert_eq!(
+            find_duplicate(&issue, "acme", &candidates, &config, NOW),
+            None
+        );
+        assert!(find_duplicate(&issue, "globex", &candidates, &config, NOW).is_some());
+        assert_ne!(recent_key("acme", "repo"), recent_key("globex", "repo"));
+    }
+}
@@ -0,0 +1,255 @@
+use common::budget::BudgetConfig;
+use common::docker::is_running_in_docker;
+use common::grounding::DEFAULT_GROUNDING_CONFIDENCE;
+use common::freshness::STALENESS_THRESHOLDS_ENV;
+use common::timings::SLA_THRESHOLDS_ENV;
+use admission::ANSWER_ADMISSION_ENV;
+use configuration::Configuration;
+use duplicates::DuplicateConfig;
+use similar_questions::SimilarQuestionsConfig;
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
+use std::{env, fs};
+
+pub mod admission;
+mod attachments;
+mod budget;
+mod code_understanding;
+pub mod compatibility;
+pub mod configuration;
+mod controller;
+pub mod dependencies;
+mod duplicates;
+pub mod liveness;
+mod llm_ops;
+mod models;
+pub mod reindex;
+pub mod routes;
+mod similar_questions;
+mod timings;
+mod utility;
+mod webhook;
+
+// prior conversation messages sent along with a prompt when MAX_PROMPT_HISTORY_MESSAGES isn't set.
+const DEFAULT_MAX_PROMPT_HISTORY_MESSAGES: usize = 10;
+const DEFAULT_SUBTASK_SYNTHESIS_CONCURRENCY: usize = 4;
+const DEFAULT_ATTACHMENT_MAX_BYTES: usize = 256 * 1024;
+// a week.
+const DEFAULT_ATTACHMENT_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+// global configuration while RwLock is used to ensure thread safety
+// Rwlock makes reads cheap, which is important because we will be reading the configuration a lot, and never mutate it after it is set.
+
+static CONFIG: Lazy<RwLock<Configuration>> = Lazy::new(|| {
+    // Directly load the configuration when initializing CONFIG.
+    RwLock::new(Configuration::default())
+});
+
+pub fn load_from_env(env_file: Option<String>) -> Configuration {
+    // Check if running inside Docker first
+    if is_running_in_docker() {
+        log::debug!("Running coorindator Docker container");
+    }
+
+    // load the .env file from the specified path if provided, otherwise load the default .env file
+    if let Some(env_path) = env_file {
+        dotenv::from_filename(&env_path)
+            .expect(format!("Failed to load environment variables from {}", env_path).as_str());
+        info!("Loaded environment variables from {}", env_path);
+    } else {
+        dotenv::dotenv().expect("Failed to load environment variables from .env file");
+    }
+
+    config_from_env()
+}
+
+/// Reads the configuration from the env, without loading an env file.
+pub fn config_from_env() -> Configuration {
+    let ai_gateway_config_path = env::var("AI_GATEWAY_CONFIG_PATH")
+        .expect("AI_GATEWAY_CONFIG_PATH environment variable is not set");
+
+    let ai_gateway_config = fs::read_to_string(&ai_gateway_config_path).expect(&format!(
+        "Failed to read AI Gateway config file at: {}",
+        ai_gateway_config_path
+    ));
+
+    info!("AI Gateway configuration loaded successfully.");
+
+    // json stays the default, msgpack is opt-in since it needs a code understanding build that supports it.
+    let code_understanding_transport = env::var("CODE_UNDERSTANDING_TRANSPORT")
+        .map(|transport| {
+            transport
+                .parse()
+                .expect("CODE_UNDERSTANDING_TRANSPORT must be either `json` or `msgpack`")
+        })
+        .unwrap_or_default();
+
+    let max_prompt_history_messages = env::var("MAX_PROMPT_HISTORY_MESSAGES")
+        .map(|max| {
+            max.parse()
+                .expect("MAX_PROMPT_HISTORY_MESSAGES must be a non-negative integer")
+        })
+        .unwrap_or(DEFAULT_MAX_PROMPT_HISTORY_MESSAGES);
+
+    let grounding_confidence = env::var("GROUNDING_CONFIDENCE")
+        .map(|confidence| {
+            confidence
+                .parse::<f32>()
+                .ok()
+                .filter(|confidence| (0.0..=1.0).contains(confidence))
+                .expect("GROUNDING_CONFIDENCE must be a number between 0 and 1")
+        })
+        .unwrap_or(DEFAULT_GROUNDING_CONFIDENCE);
+
+    let duplicate_defaults = DuplicateConfig::default();
+    let duplicate_detection = DuplicateConfig {
+        model_dir: env::var("MODEL_DIR")
+            .ok()
+            .filter(|dir| !dir.trim().is_empty()),
+        threshold: env::var("DUPLICATE_SIMILARITY_THRESHOLD")
+            .map(|threshold| {
+                threshold
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|threshold| (0.0..=1.0).contains(threshold))
+                    .expect("DUPLICATE_SIMILARITY_THRESHOLD must be a number between 0 and 1")
+            })
+            .unwrap_or(duplicate_defaults.threshold),
+        lookback_secs: env::var("DUPLICATE_LOOKBACK_SECS")
+            .map(|lookback| {
+                lookback
+                    .parse()
+                    .expect("DUPLICATE_LOOKBACK_SECS must be a non-negative integer")
+            })
+            .unwrap_or(duplicate_defaults.lookback_secs),
+        max_candidates: env::var("DUPLICATE_MAX_CANDIDATES")
+            .map(|max| {
+                max.parse()
+                    .expect("DUPLICATE_MAX_CANDIDATES must be a non-negative integer")
+            })
+            .unwrap_or(duplicate_defaults.max_candidates),
+    };
+
+    let similar_defaults = SimilarQuestionsConfig::default();
+    let similar_questions = SimilarQuestionsConfig {
+        model_dir: duplicate_detection.model_dir.clone(),
+        min_query_chars: env::var("SIMILAR_QUESTIONS_MIN_CHARS")
+            .map(|min| {
+                min.parse()
+                    .expect("SIMILAR_QUESTIONS_MIN_CHARS must be a non-negative integer")
+            })
+            .unwrap_or(similar_defaults.min_query_chars),
+        min_similarity: env::var("SIMILAR_QUESTIONS_MIN_SIMILARITY")
+            .map(|similarity| {
+                similarity
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|similarity| (0.0..=1.0).contains(similarity))
+                    .expect("SIMILAR_QUESTIONS_MIN_SIMILARITY must be a number between 0 and 1")
+            })
+            .unwrap_or(similar_defaults.min_similarity),
+        max_questions: env::var("SIMILAR_QUESTIONS_MAX")
+            .map(|max| {
+                max.parse()
+                    .expect("SIMILAR_QUESTIONS_MAX must be a non-negative integer")
+            })
+            .unwrap_or(similar_defaults.max_questions),
+        archive_url: env::var("CONVERSATION_ARCHIVE_URL")
+            .ok()
+            .filter(|url| !url.trim().is_empty()),
+    };
+
+    Configuration {
+        code_search_url: env::var("CODE_SEARCH_URL")
+            .expect("CODE_SEARCH_URL environment variable is not set"),
+        code_understanding_url: env::var("CODE_UNDERSTANDING_URL")
+            .expect("CODE_UNDERSTANDING_URL environment variable is not set"),
+        code_understanding_transport,
+        redis_url: env::var("REDIS_URL").expect("REDIS_URL environment variable is not set"),
+        ai_gateway_config,
+        max_prompt_history_messages,
+        subtask_synthesis_concurrency: env::var("SUBTASK_SYNTHESIS_CONCURRENCY")
+            .map(|concurrency| {
+                concurrency
+                    .parse()
+                    .ok()
+                    .filter(|concurrency| *concurrency > 0)
+                    .expect("SUBTASK_SYNTHESIS_CONCURRENCY must be a positive integer")
+            })
+            .unwrap_or(DEFAULT_SUBTASK_SYNTHESIS_CONCURRENCY),
+        web_url_template: env::var("WEB_URL_TEMPLATE")
+            .ok()
+            .filter(|template| !template.trim().is_empty()),
+        require_downstream_capabilities: env::var("REQUIRE_DOWNSTREAM_CAPABILITIES")
+            .map(|required| {
+                required
+                    .parse()
+                    .expect("REQUIRE_DOWNSTREAM_CAPABILITIES must be either `true` or `false`")
+            })
+            .unwrap_or(false),
+        auto_quick_answer: env::var("AUTO_QUICK_ANSWER")
+            .map(|enabled| {
+                enabled
+                    .parse()
+                    .expect("AUTO_QUICK_ANSWER must be either `true` or `false`")
+            })
+            .unwrap_or(true),
+        indexer_command: env::var("INDEXER_COMMAND")
+            .ok()
+            .filter(|command| !command.trim().is_empty()),
+        indexer_workdir: env::var("INDEXER_WORKDIR")
+            .ok()
+            .filter(|workdir| !workdir.trim().is_empty()),
+        grounding_confidence,
+        budget: BudgetConfig::from_env().expect("The budget environment variables are invalid"),
+        attachment_max_bytes: env::var("ATTACHMENT_MAX_BYTES")
+            .map(|max| {
+                max.parse()
+                    .expect("ATTACHMENT_MAX_BYTES must be a non-negative integer")
+            })
+            .unwrap_or(DEFAULT_ATTACHMENT_MAX_BYTES),
+        attachment_ttl_secs: env::var("ATTACHMENT_TTL_SECS")
+            .map(|ttl| {
+                ttl.parse()
+                    .expect("ATTACHMENT_TTL_SECS must be a non-negative integer")
+            })
+            .unwrap_or(DEFAULT_ATTACHMENT_TTL_SECS),
+        sla_thresholds: env::var(SLA_THRESHOLDS_ENV)
+            .map(|thresholds| {
+                thresholds
+                    .parse()
+                    .unwrap_or_else(|e| panic!("{} is invalid: {}", SLA_THRESHOLDS_ENV, e))
+            })
+            .unwrap_or_default(),
+        // unset or empty keeps the default thresholds, 7 days and 50 commits.
+        staleness_thresholds: env::var(STALENESS_THRESHOLDS_ENV)
+            .ok()
+            .filter(|thresholds| !thresholds.trim().is_empty())
+            .map(|thresholds| {
+                thresholds
+                    .parse()
+                    .unwrap_or_else(|e| panic!("{} is invalid: {}", STALENESS_THRESHOLDS_ENV, e))
+            })
+            .unwrap_or_default(),
+        answer_admission: env::var(ANSWER_ADMISSION_ENV)
+            .ok()
+            .filter(|admission| !admission.trim().is_empty())
+            .map(|admission| {
+                admission
+                    .parse()
+                    .unwrap_or_else(|e| panic!("{} is invalid: {}", ANSWER_ADMISSION_ENV, e))
+            })
+            .unwrap_or_default(),
+        eval_fixtures_dir: env::var("EVAL_FIXTURES_DIR")
+            .ok()
+            .filter(|dir| !dir.trim().is_empty()),
+        duplicate_detection,
+        similar_questions,
+    }
+}
+
+pub fn set_config(config: Configuration) {
+    let mut global_config = CONFIG.write().expect("Failed to acquire write lock");
+    *global_config = config;
+}
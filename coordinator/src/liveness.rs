@@ -0,0 +1,82 @@
+// Suspension of the conversations whose client went away. The admission tells which conversations
+// weren't heard from, this records the suspension on their graph and reports it to their webhook,
+// and resumes them when their client is back.
+
+use std::time::{Duration, Instant};
+
+use common::clock::unix_now;
+use common::task_graph::redis::load_task_process_from_redis;
+use log::{error, info};
+
+use crate::admission;
+use crate::configuration::get_redis_url;
+use crate::webhook::{ConversationWebhook, Milestone};
+
+// how often the idle conversations are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Suspends the idle conversations every `SWEEP_INTERVAL`, has to be called on a tokio runtime.
+pub fn spawn_sweeper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            for conversation_id in admission::global().sweep(Instant::now()) {
+                suspend(&conversation_id).await;
+            }
+        }
+    });
+}
+
+/// Records that the client of the conversation was heard from, resuming the conversation when it
+/// was suspended. Returns true when it was.
+pub async fn keep_alive(conversation_id: &str) -> bool {
+    if !admission::global().heartbeat(conversation_id) {
+        return false;
+    }
+    info!("Conversation {} resumed", conversation_id);
+    let redis_url = get_redis_url();
+    match load_task_process_from_redis(&redis_url, conversation_id) {
+        Ok(mut tracker) => {
+            let saved = tracker
+                .record_resumed()
+                .map_err(anyhow::Error::from)
+                .and_then(|_| tracker.save_task_process_to_redis(&redis_url));
+            if let Err(e) = saved {
+                error!("Failed to record the resumption of conversation {}: {}", conversation_id, e);
+            }
+        }
+        Err(e) => error!("Failed to load conversation {} from Redis: {}", conversation_id, e),
+    }
+    notify(conversation_id, Milestone::Resumed).await;
+    true
+}
+
+// The questions being answered finish and are recorded by their flow, only the queued ones wait.
+async fn suspend(conversation_id: &str) {
+    let queued_questions = admission::global().status(conversation_id).queued_questions;
+    info!(
+        "Conversation {} wasn't heard from, suspended with {} questions queued",
+        conversation_id, queued_questions
+    );
+    let redis_url = get_redis_url();
+    match load_task_process_from_redis(&redis_url, conversation_id) {
+        Ok(mut tracker) => {
+            let saved = tracker
+                .record_suspended(unix_now())
+                .map_err(anyhow::Error::from)
+                .and_then(|_| tracker.save_task_process_to_redis(&redis_url));
+            if let Err(e) = saved {
+                error!("Failed to record the suspension of conversation {}: {}", conversation_id, e);
+            }
+        }
+        Err(e) => error!("Failed to load conversation {} from Redis: {}", conversation_id, e),
+    }
+    notify(conversation_id, Milestone::Suspended { queued_questions }).await;
+}
+
+async fn notify(conversation_id: &str, milestone: Milestone) {
+    ConversationWebhook::saved(&get_redis_url(), conversation_id)
+        .notify(milestone)
+        .await;
+}
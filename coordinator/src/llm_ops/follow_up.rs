@@ -0,0 +1,137 @@
+use common::ai_util::{call_llm_metered, extract_single_plaintext_content};
+use common::budget::BudgetMeter;
+use common::prompts::classify_follow_up_prompt;
+use log::{debug, warn};
+
+use crate::configuration::get_ai_gateway_config;
+
+// Messages longer than this many words read like a new issue description rather than a question.
+const MAX_FOLLOW_UP_WORDS: usize = 40;
+
+const QUESTION_WORDS: &[&str] = &[
+    "what", "why", "how", "where", "which", "who", "when", "does", "do", "is", "are", "can",
+    "could", "should", "would", "explain", "clarify", "show",
+];
+
+// Verbs that ask for new work to be done instead of asking about the work already done.
+pub(crate) const NEW_ISSUE_VERBS: &[&str] = &[
+    "add", "implement", "build", "create", "refactor", "migrate", "fix", "replace", "remove",
+    "rewrite", "support", "introduce",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    FollowUp,
+    NewIssue,
+}
+
+// Decides whether a message sent on an answered conversation is a follow-up question
+// about it or a new issue. Falls back to a heuristic when the LLM call fails.
+pub async fn classify_follow_up(
+    previous_query: &str,
+    tasks: &[String],
+    message: &str,
+    budget: &BudgetMeter,
+) -> MessageKind {
+    let prompt = classify_follow_up_prompt(previous_query, tasks, message);
+
+    let llm_output = match call_llm_metered(&get_ai_gateway_config(), Some(budget), Some(prompt), None, None).await {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to classify the message with the LLM, using the heuristic: {}", e);
+            return classify_follow_up_heuristic(message);
+        }
+    };
+
+    let kind = extract_single_plaintext_content(&llm_output)
+        .ok()
+        .and_then(|content| parse_message_kind(&content));
+    match kind {
+        Some(kind) => {
+            debug!("Message '{}' classified as {:?}", message, kind);
+            kind
+        }
+        None => {
+            warn!("Unexpected classification from the LLM, using the heuristic.");
+            classify_follow_up_heuristic(message)
+        }
+    }
+}
+
+fn parse_message_kind(content: &str) -> Option<MessageKind> {
+    let content = content.trim().to_ascii_uppercase();
+    if content.contains("FOLLOW_UP") {
+        Some(MessageKind::FollowUp)
+    } else if content.contains("NEW_ISSUE") {
+        Some(MessageKind::NewIssue)
+    } else {
+        None
+    }
+}
+
+// Short messages phrased as a question are follow-ups, long ones or ones asking for
+// new work are new issues.
+pub fn classify_follow_up_heuristic(message: &str) -> MessageKind {
+    let words = message
+        .split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_ascii_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+
+    if words.is_empty() || words.len() > MAX_FOLLOW_UP_WORDS {
+        return MessageKind::NewIssue;
+    }
+
+    let first_word = words[0].as_str();
+    if NEW_ISSUE_VERBS.contains(&first_word) {
+        return MessageKind::NewIssue;
+    }
+
+    if message.trim_end().ends_with('?') || QUESTION_WORDS.contains(&first_word) {
+        MessageKind::FollowUp
+    } else {
+        MessageKind::NewIssue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heuristic_classification() {
+        assert_eq!(
+            classify_follow_up_heuristic("what about error handling in task 2?"),
+            MessageKind::FollowUp
+        );
+        assert_eq!(
+            classify_follow_up_heuristic("Explain the retry logic in more detail"),
+            MessageKind::FollowUp
+        );
+        assert_eq!(
+            classify_follow_up_heuristic("Add rate limiting to the search API"),
+            MessageKind::NewIssue
+        );
+        assert_eq!(
+            classify_follow_up_heuristic("The export endpoint times out for large repositories"),
+            MessageKind::NewIssue
+        );
+        assert_eq!(classify_follow_up_heuristic("   "), MessageKind::NewIssue);
+
+        let long_question = format!("why {}?", "word ".repeat(MAX_FOLLOW_UP_WORDS));
+        assert_eq!(
+            classify_follow_up_heuristic(&long_question),
+            MessageKind::NewIssue
+        );
+    }
+
+    #[test]
+    fn test_parse_message_kind() {
+        assert_eq!(parse_message_kind(" follow_up\n"), Some(MessageKind::FollowUp));
+        assert_eq!(parse_message_kind("NEW_ISSUE."), Some(MessageKind::NewIssue));
+        assert_eq!(parse_message_kind("maybe"), None);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod follow_up;
+pub mod plan;
+pub mod query_route;
+pub mod reformulate;
 pub mod summarize;
-pub mod tasks_questions;
\ No newline at end of file
+pub mod tasks_questions;
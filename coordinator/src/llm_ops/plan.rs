@@ -0,0 +1,190 @@
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ai_gateway::message::message::Message;
+use common::ai_util::{call_llm_metered, extract_single_plaintext_content};
+use common::budget::BudgetMeter;
+use common::change_plan::{parse_planned_changes, ChangePlan, TaskChange};
+use common::models::TasksQuestionsAnswersDetails;
+use common::prompts::change_plan_prompt;
+use futures::future::join_all;
+use log::debug;
+
+use crate::configuration::get_ai_gateway_config;
+
+/// Plans the changes of every task with answered questions, one call per task, and aggregates
+/// them per file. The files are checked against `indexed_paths` when code search could list them.
+pub async fn generate_change_plan(
+    user_query: &str,
+    details: &TasksQuestionsAnswersDetails,
+    indexed_paths: Option<&[String]>,
+    budget: &BudgetMeter,
+) -> Result<ChangePlan, anyhow::Error> {
+    plan_with(user_query, details, indexed_paths, |prompt| async move {
+        call_llm_metered(&get_ai_gateway_config(), Some(budget), Some(prompt), None, None).await
+    })
+    .await
+}
+
+async fn plan_with<L, LFut>(
+    user_query: &str,
+    details: &TasksQuestionsAnswersDetails,
+    indexed_paths: Option<&[String]>,
+    llm: L,
+) -> Result<ChangePlan, anyhow::Error>
+where
+    L: Fn(String) -> LFut,
+    LFut: Future<Output = Result<Vec<Message>, anyhow::Error>>,
+{
+    let tasks = details
+        .tasks
+        .iter()
+        .map(|task| task.task_description.clone())
+        .collect::<Vec<_>>();
+    let answered = details
+        .tasks
+        .iter()
+        .enumerate()
+        .filter(|(_, task)| !task.answers.is_empty())
+        .collect::<Vec<_>>();
+    let responses = join_all(
+        answered
+            .iter()
+            .map(|(i, task)| llm(change_plan_prompt(user_query, &tasks, i + 1, task))),
+    )
+    .await;
+
+    let mut changes = Vec::new();
+    for ((i, task), response) in answered.into_iter().zip(responses) {
+        let response = extract_single_plaintext_content(&response?)?;
+        debug!("Change plan of task {}: {}", task.task_id, response);
+        for planned in parse_planned_changes(&response) {
+            // tasks are numbered from 1 in the prompt, a task doesn't depend on itself.
+            let depends_on_task = planned
+                .depends_on_task
+                .filter(|number| *number != i + 1)
+                .and_then(|number| details.tasks.get(number.checked_sub(1)?))
+                .map(|task| task.task_id);
+            changes.push((
+                planned.path,
+                TaskChange {
+                    task: task.task_id,
+                    change_kind: planned.change_kind,
+                    description: planned.description,
+                    estimated_lines: planned.estimated_lines,
+                    depends_on_task,
+                },
+            ));
+        }
+    }
+
+    let generated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default();
+    let mut plan = ChangePlan::aggregate(tasks, changes, generated_at);
+    if let Some(indexed_paths) = indexed_paths {
+        plan.ground(indexed_paths);
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::change_plan::{ChangeKind, UngroundedReason};
+    use common::models::TaskDetailsWithContext;
+
+    fn task(task_id: usize, description: &str, answered: bool) -> TaskDetailsWithContext {
+        TaskDetailsWithContext {
+            task_id,
+            task_description: description.to_string(),
+            questions: vec![format!("Where is {} done?", description)],
+            answers: if answered {
+                vec![format!("{} is done in `src/upsert.rs`.", description)]
+            } else {
+                Vec::new()
+            },
+            code_contexts: Vec::new(),
+            merged_code_contexts: Vec::new(),
+            unresolved_questions: Vec::new(),
+            subtasks: Vec::new(),
+        }
+    }
+
+    // Answers the prompt of every task with the plan written for it.
+    async fn mock_llm(prompt: String) -> Result<Vec<Message>, anyhow::Error> {
+        let content = if prompt.contains("List the files task 1 would change") {
+            r#"[
+  {"path": "src/upsert.rs", "change_kind": "modify", "description": "Read the retry count from the config", "estimated_lines": 10},
+  {"path": "src/config.rs", "change_kind": "modify", "description": "Add the retry count", "estimated_lines": 6}
+]"#
+        } else if prompt.contains("List the files task 3 would change") {
+            r#"[
+  {"path": "src/upsert.rs", "change_kind": "modify", "description": "Log every retry", "estimated_lines": 4, "depends_on_task": 1},
+  {"path": "src/retry_metrics.rs", "change_kind": "modify", "description": "Count the retries", "estimated_lines": 20}
+]"#
+        } else {
+            panic!("unexpected prompt: {}", prompt);
+        };
+        Ok(vec![Message::assistant(content)])
+    }
+
+    #[tokio::test]
+    async fn test_plan_is_aggregated_per_file_and_grounded() {
+        let details = TasksQuestionsAnswersDetails {
+            root_node_id: 0,
+            tasks: vec![
+                task(2, "Make the retries configurable", true),
+                // not answered yet, it isn't planned.
+                task(5, "Document the retries", false),
+                task(7, "Report the retries", true),
+            ],
+            answer_summary: None,
+        };
+        let indexed = vec!["src/upsert.rs".to_string(), "src/config.rs".to_string()];
+
+        let plan = plan_with(
+            "Make the upsert retries configurable",
+            &details,
+            Some(&indexed),
+            mock_llm,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(plan.tasks.len(), 3);
+        assert_eq!(plan.files.len(), 3);
+        let upsert = &plan.files[0];
+        assert_eq!(upsert.path, "src/upsert.rs");
+        assert_eq!(upsert.change_kind, ChangeKind::Modify);
+        assert_eq!(upsert.estimated_lines, 14);
+        assert_eq!(
+            upsert.changes.iter().map(|change| change.task).collect::<Vec<_>>(),
+            vec![2, 7]
+        );
+        // the task number of the prompt is mapped to the id of the task.
+        assert_eq!(upsert.changes[1].depends_on_task, Some(2));
+        assert_eq!(plan.estimated_lines, 40);
+
+        assert!(plan.grounding_checked);
+        assert_eq!(plan.files[1].ungrounded, None);
+        assert_eq!(plan.files[2].path, "src/retry_metrics.rs");
+        assert_eq!(plan.files[2].ungrounded, Some(UngroundedReason::NotIndexed));
+        assert_eq!(plan.scope(), vec!["src/upsert.rs", "src/config.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_plan_is_not_grounded_without_the_indexed_paths() {
+        let details = TasksQuestionsAnswersDetails {
+            root_node_id: 0,
+            tasks: vec![task(2, "Make the retries configurable", true)],
+            answer_summary: None,
+        };
+        let plan = plan_with("Make the upsert retries configurable", &details, None, mock_llm)
+            .await
+            .unwrap();
+        assert!(!plan.grounding_checked);
+        assert_eq!(plan.ungrounded().count(), 0);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::llm_ops::follow_up::NEW_ISSUE_VERBS;
+
+// Queries longer than this many words are decomposed into tasks.
+pub(crate) const MAX_QUICK_ANSWER_WORDS: usize = 20;
+
+// First words of a lookup, e.g. "where is the JWT validated?".
+const LOOKUP_WORDS: &[&str] = &[
+    "where", "what", "which", "who", "when", "how", "does", "do", "is", "are", "can", "find",
+    "show",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryRoute {
+    // answered as a single question, without generating tasks.
+    QuickAnswer,
+    // decomposed into tasks, subtasks and questions.
+    Decompose,
+}
+
+// Decides whether a new query is a simple lookup that can be answered directly.
+// Errs on the side of decomposing: a single question asking for work to be done, or asking more
+// than one thing, would miss the tasks that answer it.
+pub fn route_query(query: &str) -> QueryRoute {
+    let words = query
+        .split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_ascii_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+
+    if words.is_empty() || words.len() > MAX_QUICK_ANSWER_WORDS {
+        return QueryRoute::Decompose;
+    }
+    if words.iter().any(|word| NEW_ISSUE_VERBS.contains(&word.as_str())) {
+        return QueryRoute::Decompose;
+    }
+    if query.matches('?').count() > 1 {
+        return QueryRoute::Decompose;
+    }
+
+    if query.trim_end().ends_with('?') || LOOKUP_WORDS.contains(&words[0].as_str()) {
+        QueryRoute::QuickAnswer
+    } else {
+        QueryRoute::Decompose
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookups_are_answered_directly() {
+        assert_eq!(route_query("Where is the JWT validated?"), QueryRoute::QuickAnswer);
+        assert_eq!(
+            route_query("which service stores the conversation graph"),
+            QueryRoute::QuickAnswer
+        );
+        assert_eq!(
+            route_query("The retry endpoint reformulates questions?"),
+            QueryRoute::QuickAnswer
+        );
+    }
+
+    #[test]
+    fn test_work_and_compound_queries_are_decomposed() {
+        assert_eq!(
+            route_query("Add rate limiting to the search API"),
+            QueryRoute::Decompose
+        );
+        // a work verb anywhere in the query asks for more than a lookup.
+        assert_eq!(
+            route_query("how should I implement pagination for the export?"),
+            QueryRoute::Decompose
+        );
+        assert_eq!(
+            route_query("Where are tokens refreshed? Who calls it?"),
+            QueryRoute::Decompose
+        );
+        assert_eq!(
+            route_query("The export endpoint times out for large repositories"),
+            QueryRoute::Decompose
+        );
+        assert_eq!(route_query("  ?  "), QueryRoute::Decompose);
+    }
+
+    #[test]
+    fn test_word_limit_boundary() {
+        let at_limit = format!("where {}?", "word ".repeat(MAX_QUICK_ANSWER_WORDS - 1).trim_end());
+        assert_eq!(route_query(&at_limit), QueryRoute::QuickAnswer);
+
+        let over_limit = format!("where {}?", "word ".repeat(MAX_QUICK_ANSWER_WORDS).trim_end());
+        assert_eq!(route_query(&over_limit), QueryRoute::Decompose);
+    }
+}
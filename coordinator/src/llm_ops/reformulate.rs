@@ -0,0 +1,33 @@
+use common::ai_util::{call_llm_metered, extract_single_plaintext_content};
+use common::budget::BudgetMeter;
+use common::prompts::reformulate_not_found_question_prompt;
+use log::debug;
+
+use crate::configuration::get_ai_gateway_config;
+
+// Rewrites a question the code understanding agent couldn't answer,
+// using what the agent already tried to steer the next attempt elsewhere.
+pub async fn reformulate_not_found_question(
+    question: &str,
+    attempted_queries: &[String],
+    closest_paths: &[String],
+    budget: &BudgetMeter,
+) -> Result<String, anyhow::Error> {
+    let prompt = reformulate_not_found_question_prompt(question, attempted_queries, closest_paths);
+
+    let llm_output = call_llm_metered(&get_ai_gateway_config(), Some(budget), Some(prompt), None, None).await?;
+
+    let reformulated = extract_single_plaintext_content(&llm_output)?
+        .trim()
+        .trim_matches('"')
+        .to_string();
+    debug!("Reformulated question '{}' into '{}'", question, reformulated);
+
+    if reformulated.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Empty reformulation for question: {}",
+            question
+        ));
+    }
+    Ok(reformulated)
+}
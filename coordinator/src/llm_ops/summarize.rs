@@ -1,27 +1,217 @@
-use common::ai_util::{call_llm, extract_single_plaintext_content};
-use log::debug;
+use std::future::Future;
 
+use ai_gateway::message::message::Message;
+use common::ai_util::{call_llm_metered, extract_single_plaintext_content};
+use common::budget::{BudgetExceeded, BudgetMeter};
+use common::glossary::{split_reconciled, Glossary};
+use common::task_graph::state::PromptHistory;
+use futures::stream::{self, StreamExt};
+use log::{debug, warn};
+
+use common::language::with_language;
 use common::models::TasksQuestionsAnswersDetails;
-use common::prompts::create_task_answer_summarization_prompt;
+use common::preferences::with_preferences;
+use common::prompt_versions::{prompt_versions, PromptVersion};
+use common::prompts::{
+    conversation_history_summary_prompt, create_task_answer_summarization_prompt,
+    glossary_reconciliation_prompt, subtask_synthesis_prompt,
+};
+
+use crate::configuration::{get_ai_gateway_config, get_subtask_synthesis_concurrency};
+
+/// Synthesizes the answers of every subtask that has answers and no synthesis yet, one call per
+/// subtask and at most `SUBTASK_SYNTHESIS_CONCURRENCY` at once. The syntheses are set on `details`,
+/// so the task summary reads them instead of the answers, and returned by subtask id to be
+/// recorded on the graph. A subtask whose synthesis fails keeps its answers in the summary, the
+/// budget running out fails them all.
+pub async fn synthesize_subtasks(
+    user_query: &str,
+    details: &mut TasksQuestionsAnswersDetails,
+    budget: &BudgetMeter,
+) -> Result<Vec<(usize, String)>, anyhow::Error> {
+    synthesize_with(
+        user_query,
+        details,
+        get_subtask_synthesis_concurrency(),
+        |prompt| async move {
+            call_llm_metered(&get_ai_gateway_config(), Some(budget), Some(prompt), None, None).await
+        },
+    )
+    .await
+}
+
+async fn synthesize_with<L, LFut>(
+    user_query: &str,
+    details: &mut TasksQuestionsAnswersDetails,
+    concurrency: usize,
+    llm: L,
+) -> Result<Vec<(usize, String)>, anyhow::Error>
+where
+    L: Fn(String) -> LFut,
+    LFut: Future<Output = Result<Vec<Message>, anyhow::Error>>,
+{
+    let prompts = details
+        .tasks
+        .iter()
+        .flat_map(|task| {
+            task.subtasks
+                .iter()
+                .filter(|subtask| subtask.synthesis.is_none() && !subtask.answers.is_empty())
+                .map(move |subtask| {
+                    (
+                        subtask.subtask_id,
+                        subtask_synthesis_prompt(user_query, &task.task_description, subtask),
+                    )
+                })
+        })
+        .collect::<Vec<_>>();
+    let llm = &llm;
+    let responses = stream::iter(prompts)
+        .map(|(subtask_id, prompt)| async move { (subtask_id, llm(prompt).await) })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
 
-use crate::configuration::get_ai_gateway_config;
+    let mut syntheses = Vec::new();
+    for (subtask_id, response) in responses {
+        match response.and_then(|response| extract_single_plaintext_content(&response)) {
+            Ok(synthesis) => {
+                debug!("Synthesis of subtask {}: {}", subtask_id, synthesis);
+                syntheses.push((subtask_id, synthesis.trim().to_string()));
+            }
+            Err(e) if e.downcast_ref::<BudgetExceeded>().is_some() => return Err(e),
+            Err(e) => warn!(
+                "Failed to synthesize the answers of subtask {}, summarizing them as they are: {}",
+                subtask_id, e
+            ),
+        }
+    }
+    // in the order of the graph rather than the order they were written in.
+    syntheses.sort_by_key(|(subtask_id, _)| *subtask_id);
 
+    for subtask in details
+        .tasks
+        .iter_mut()
+        .flat_map(|task| task.subtasks.iter_mut())
+    {
+        if let Some((_, synthesis)) = syntheses.iter().find(|(id, _)| *id == subtask.subtask_id) {
+            subtask.synthesis = Some(synthesis.clone());
+        }
+    }
+    Ok(syntheses)
+}
+
+/// The versions of the templates the subtask syntheses are written with.
+pub fn subtask_synthesis_prompt_versions() -> Vec<PromptVersion> {
+    prompt_versions(&["subtask_synthesis_prompt"])
+}
+
+// The conflicting descriptions of `glossary` are reconciled by the summary, which ends with the
+// glossary as an appendix.
 pub async fn generate_summarized_answer_for_task(
     user_query: String,
     task: &TasksQuestionsAnswersDetails,
+    preferences: Option<&str>,
+    language: Option<&str>,
+    glossary: &mut Glossary,
+    budget: &BudgetMeter,
 ) -> Result<String, anyhow::Error> {
     // Construct the summarization prompt for the given task and user query.
-    let summarization_prompt = create_task_answer_summarization_prompt(&user_query, &task);
+    let summarization_prompt =
+        task_summary_prompt(&user_query, task, preferences, language, glossary);
 
     //debug!("Summarization prompt: {}", summarization_prompt);
 
-    let llm_output = call_llm(&get_ai_gateway_config(), Some(summarization_prompt), None, None).await?;
+    let llm_output = call_llm_metered(&get_ai_gateway_config(), Some(budget), Some(summarization_prompt), None, None).await?;
 
     let response_message = extract_single_plaintext_content(&llm_output)?;
     debug!("Summarized answer: {}", response_message);
+    Ok(reconcile_summary(&response_message, glossary))
+}
+
+/// The versions of the templates the task summary is generated with, the reconciliation is only
+/// asked for when the glossary has conflicts.
+pub fn summary_prompt_versions(glossary: &Glossary) -> Vec<PromptVersion> {
+    let mut templates = vec!["create_task_answer_summarization_prompt"];
+    if !glossary.conflicts().is_empty() {
+        templates.push("glossary_reconciliation_prompt");
+    }
+    prompt_versions(&templates)
+}
+
+// The summary is written in `language`, English when None.
+fn task_summary_prompt(
+    user_query: &str,
+    task: &TasksQuestionsAnswersDetails,
+    preferences: Option<&str>,
+    language: Option<&str>,
+    glossary: &Glossary,
+) -> String {
+    let mut prompt = create_task_answer_summarization_prompt(user_query, task);
+    let conflicts = glossary.conflicts();
+    if !conflicts.is_empty() {
+        prompt += &glossary_reconciliation_prompt(&conflicts);
+    }
+    with_language(with_preferences(prompt, preferences), language)
+}
+
+// Moves the descriptions the summary settled on into the glossary, and replaces the block they
+// were written in with the glossary appendix.
+fn reconcile_summary(summary: &str, glossary: &mut Glossary) -> String {
+    let (mut summary, reconciled) = split_reconciled(summary);
+    let count = glossary.reconcile(&reconciled);
+    if count > 0 {
+        debug!("Summary reconciled the descriptions of {} symbols", count);
+    }
+    if let Some(appendix) = glossary.render_appendix() {
+        summary.push_str("\n\n");
+        summary.push_str(&appendix);
+    }
+    summary
+}
+
+// Summarizes the messages that no longer fit in the prompt history into a single note.
+pub async fn summarize_conversation_history(
+    messages: &[Message],
+    budget: &BudgetMeter,
+) -> Result<String, anyhow::Error> {
+    let messages = messages
+        .iter()
+        .filter_map(|message| match message {
+            Message::PlainText { role, content } => Some((role.to_string(), content.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let summarization_prompt = conversation_history_summary_prompt(&messages);
+
+    let llm_output = call_llm_metered(&get_ai_gateway_config(), Some(budget), Some(summarization_prompt), None, None).await?;
+
+    let response_message = extract_single_plaintext_content(&llm_output)?;
+    debug!("Summarized conversation history: {}", response_message);
     Ok(response_message)
 }
 
+// Builds the prior messages sent along with a prompt: the older messages summarized into a single
+// system note, followed by the most recent ones. The older messages are dropped when they can't be summarized.
+pub async fn prompt_history_messages(history: PromptHistory, budget: &BudgetMeter) -> Vec<Message> {
+    let mut messages = Vec::with_capacity(history.recent.len() + 1);
+    if !history.older.is_empty() {
+        match summarize_conversation_history(&history.older, budget).await {
+            Ok(summary) => messages.push(Message::system(&format!(
+                "Summary of the earlier conversation: {}",
+                summary
+            ))),
+            Err(e) => warn!(
+                "Failed to summarize {} earlier messages, leaving them out: {}",
+                history.older.len(),
+                e
+            ),
+        }
+    }
+    messages.extend(history.recent);
+    messages
+}
+
 // pub async fn generate_single_task_summarization_(
 //     user_query: &str,
 //     search_url: &str,
@@ -77,3 +267,251 @@ pub async fn generate_summarized_answer_for_task(
 //     debug!("Summarized answer: {}", choices_str);
 //     Ok(choices_str)
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::budget::BudgetScope;
+    use common::task_graph::graph_model::{EdgeV1, NodeV1, TrackProcessV1};
+    use petgraph::graph::{DiGraph, NodeIndex};
+    use petgraph::visit::EdgeRef;
+    use petgraph::Direction;
+
+    const QUERY: &str = "Make the upsert retries configurable";
+    const LOOP_ANSWER: &str = "`retry_upsert` in src/upsert.rs loops over the attempts.";
+    const DELAY_ANSWER: &str = "The delay between the attempts is a fixed 500ms.";
+    const SETTINGS_ANSWER: &str = "`IndexConfig` in src/config.rs has no retry field.";
+    const LOOP_SYNTHESIS: &str =
+        "`retry_upsert` (src/upsert.rs) retries three times with a fixed 500ms delay.";
+    const SETTINGS_SYNTHESIS: &str =
+        "`IndexConfig` (src/config.rs) needs a field for the retries, the count is hard-coded.";
+
+    // Root -> conversation -> task -> two subtasks, the first with two answered questions and the
+    // second with one.
+    fn fixture_tracker() -> TrackProcessV1 {
+        let mut graph = DiGraph::new();
+        let root = graph.add_node(NodeV1::Root("conv-1".to_string()));
+        let conversation = graph.add_node(NodeV1::Conversation(
+            ai_gateway::message::message::MessageRole::User,
+            Message::user(QUERY),
+            "c-1".to_string(),
+        ));
+        let task = graph.add_node(NodeV1::Task(
+            "Make the retry count configurable".to_string(),
+        ));
+        graph.add_edge(root, conversation, EdgeV1::NextConversation);
+        graph.add_edge(conversation, task, EdgeV1::Task);
+        let subtasks = [
+            (
+                "Find the retry loop",
+                vec![
+                    ("Where are the upserts retried?", LOOP_ANSWER),
+                    ("How long is waited between the attempts?", DELAY_ANSWER),
+                ],
+            ),
+            (
+                "Read the retry settings",
+                vec![("Where are the index settings read?", SETTINGS_ANSWER)],
+            ),
+        ];
+        for (description, questions) in subtasks {
+            let subtask = graph.add_node(NodeV1::Subtask(description.to_string()));
+            graph.add_edge(task, subtask, EdgeV1::Subtask);
+            for (question, answer) in questions {
+                let question = graph.add_node(NodeV1::Question(question.to_string()));
+                let answer = graph.add_node(NodeV1::Answer(answer.to_string()));
+                graph.add_edge(subtask, question, EdgeV1::Question);
+                graph.add_edge(question, answer, EdgeV1::Answer);
+            }
+        }
+
+        let mut tracker = TrackProcessV1::new("repo", "redis://127.0.0.1:6379");
+        tracker.graph = Some(graph);
+        tracker.root_node = Some(root);
+        tracker
+    }
+
+    // Answers the synthesis prompt of each subtask with the synthesis written for it.
+    async fn scripted_llm(prompt: String) -> Result<Vec<Message>, anyhow::Error> {
+        let content = if prompt.contains("## Subtask: Find the retry loop") {
+            assert!(prompt.contains(LOOP_ANSWER) && prompt.contains(DELAY_ANSWER));
+            assert!(!prompt.contains(SETTINGS_ANSWER));
+            LOOP_SYNTHESIS
+        } else if prompt.contains("## Subtask: Read the retry settings") {
+            assert!(!prompt.contains(LOOP_ANSWER));
+            SETTINGS_SYNTHESIS
+        } else {
+            panic!("unexpected prompt: {}", prompt);
+        };
+        Ok(vec![Message::assistant(content)])
+    }
+
+    async fn unexpected_llm(prompt: String) -> Result<Vec<Message>, anyhow::Error> {
+        panic!("unexpected prompt: {}", prompt);
+    }
+
+    #[tokio::test]
+    async fn test_summary_reads_the_syntheses_of_the_subtasks() {
+        let mut tracker = fixture_tracker();
+        let mut details = tracker.collect_tasks_questions_answers_contexts().unwrap();
+        let syntheses = synthesize_with(QUERY, &mut details, 2, scripted_llm)
+            .await
+            .unwrap();
+        assert_eq!(
+            syntheses
+                .iter()
+                .map(|(_, synthesis)| synthesis.as_str())
+                .collect::<Vec<_>>(),
+            vec![LOOP_SYNTHESIS, SETTINGS_SYNTHESIS]
+        );
+        for (subtask_id, synthesis) in &syntheses {
+            tracker
+                .record_subtask_summary(
+                    *subtask_id,
+                    synthesis,
+                    &subtask_synthesis_prompt_versions(),
+                )
+                .unwrap();
+        }
+
+        // each subtask has its synthesis attached, with the versions of its prompt.
+        let graph = tracker.graph.as_ref().unwrap();
+        for (subtask_id, synthesis) in &syntheses {
+            let subtask = NodeIndex::new(*subtask_id);
+            assert!(matches!(graph[subtask], NodeV1::Subtask(_)));
+            let summaries = graph
+                .edges_directed(subtask, Direction::Outgoing)
+                .filter(|edge| matches!(edge.weight(), EdgeV1::SubtaskSummary))
+                .map(|edge| edge.target())
+                .collect::<Vec<_>>();
+            assert_eq!(summaries.len(), 1);
+            assert!(
+                matches!(&graph[summaries[0]], NodeV1::SubtaskSummary(text) if text == synthesis)
+            );
+            let versions = graph
+                .edges_directed(summaries[0], Direction::Outgoing)
+                .find(|edge| matches!(edge.weight(), EdgeV1::PromptVersions))
+                .map(|edge| &graph[edge.target()]);
+            assert!(matches!(versions, Some(NodeV1::PromptVersions(versions))
+                if versions[0].template == "subtask_synthesis_prompt"));
+        }
+
+        // read back from the graph, the subtasks are not synthesized again.
+        let mut details = tracker.collect_tasks_questions_answers_contexts().unwrap();
+        let task = &details.tasks[0];
+        assert_eq!(task.answers.len(), 3);
+        assert_eq!(task.subtasks.len(), 2);
+        assert_eq!(task.subtasks[0].subtask_description, "Find the retry loop");
+        assert_eq!(task.subtasks[0].answers.len(), 2);
+        assert_eq!(
+            task.subtasks[1].synthesis.as_deref(),
+            Some(SETTINGS_SYNTHESIS)
+        );
+        let again = synthesize_with(QUERY, &mut details, 2, unexpected_llm)
+            .await
+            .unwrap();
+        assert!(again.is_empty());
+
+        let prompt = task_summary_prompt(QUERY, &details, None, None, &Glossary::default());
+        assert!(prompt.contains(&format!(
+            "#### Subtask 1.1: Find the retry loop\n{}\n",
+            LOOP_SYNTHESIS
+        )));
+        assert!(prompt.contains(&format!(
+            "#### Subtask 1.2: Read the retry settings\n{}\n",
+            SETTINGS_SYNTHESIS
+        )));
+        for answer in [LOOP_ANSWER, DELAY_ANSWER, SETTINGS_ANSWER] {
+            assert!(!prompt.contains(answer));
+        }
+        assert!(!prompt.contains("Key points from the answers:"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_synthesis_keeps_the_answers_of_its_subtask() {
+        let mut details = fixture_tracker()
+            .collect_tasks_questions_answers_contexts()
+            .unwrap();
+        let syntheses = synthesize_with(QUERY, &mut details, 1, |prompt: String| async move {
+            if prompt.contains("## Subtask: Read the retry settings") {
+                return Err(anyhow::anyhow!("The gateway timed out"));
+            }
+            scripted_llm(prompt).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(syntheses.len(), 1);
+        assert_eq!(details.tasks[0].subtasks[1].synthesis, None);
+        let prompt = task_summary_prompt(QUERY, &details, None, None, &Glossary::default());
+        assert!(prompt.contains(LOOP_SYNTHESIS));
+        assert!(!prompt.contains(LOOP_ANSWER));
+        assert!(prompt.contains(&format!("    - Answer: {}\n", SETTINGS_ANSWER)));
+
+        // a budget that runs out stops the summary.
+        let mut details = fixture_tracker()
+            .collect_tasks_questions_answers_contexts()
+            .unwrap();
+        let error = synthesize_with(QUERY, &mut details, 2, |_| async {
+            Err::<Vec<Message>, _>(anyhow::Error::new(BudgetExceeded {
+                scope: BudgetScope::Conversation,
+                limit_usd: 1.0,
+                spent_usd: 0.98,
+                projected_usd: 1.04,
+            }))
+        })
+        .await
+        .unwrap_err();
+        assert!(error.downcast_ref::<BudgetExceeded>().is_some());
+    }
+
+    #[test]
+    fn test_summary_prompt_asks_for_the_language_of_the_conversation() {
+        let task = TasksQuestionsAnswersDetails {
+            root_node_id: 0,
+            tasks: Vec::new(),
+            answer_summary: None,
+        };
+        let glossary = Glossary::default();
+        let prompt = task_summary_prompt("ログインを直して", &task, None, Some("Japanese"), &glossary);
+        assert!(prompt.contains("Respond in Japanese."));
+        assert!(!task_summary_prompt("fix the login", &task, None, None, &glossary).contains("Respond in"));
+    }
+
+    #[test]
+    fn test_summary_reconciles_the_conflicting_descriptions() {
+        let task = TasksQuestionsAnswersDetails {
+            root_node_id: 0,
+            tasks: Vec::new(),
+            answer_summary: None,
+        };
+        let mut glossary = Glossary::default();
+        glossary.record(1, "commit_chunks", "src/index.rs", "`commit_chunks` retries the upsert three times.".to_string());
+        glossary.record(2, "flush", "src/index.rs", "`flush` writes the pending chunks.".to_string());
+        assert!(!task_summary_prompt("fix the ingestion", &task, None, None, &glossary)
+            .contains("## Conflicting Descriptions:"));
+        assert_eq!(summary_prompt_versions(&glossary).len(), 1);
+
+        glossary.record(3, "commit_chunks", "src/index.rs", "`commit_chunks` drops the batch after the first failure.".to_string());
+        let prompt = task_summary_prompt("fix the ingestion", &task, None, None, &glossary);
+        assert!(prompt.contains("## Conflicting Descriptions:"));
+        assert!(prompt.contains("    - `commit_chunks` drops the batch after the first failure."));
+        assert!(!prompt.contains("`flush` writes"));
+        assert_eq!(
+            summary_prompt_versions(&glossary)
+                .iter()
+                .map(|version| version.template.as_str())
+                .collect::<Vec<_>>(),
+            vec!["create_task_answer_summarization_prompt", "glossary_reconciliation_prompt"]
+        );
+
+        let response = "## Plan\n\n1. Retry the upsert.\n\n## Reconciled glossary\n- `commit_chunks`: retries the upsert three times, then drops the batch.\n";
+        let summary = reconcile_summary(response, &mut glossary);
+        assert!(glossary.conflicts().is_empty());
+        assert_eq!(
+            summary,
+            "## Plan\n\n1. Retry the upsert.\n\n## Glossary\n\n\
+             - `commit_chunks` (src/index.rs): retries the upsert three times, then drops the batch.\n\
+             - `flush` (src/index.rs): `flush` writes the pending chunks.\n"
+        );
+    }
+}
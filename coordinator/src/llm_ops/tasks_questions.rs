@@ -1,47 +1,346 @@
+use std::future::Future;
+
 use common::ai_util::extract_single_plaintext_content;
+use common::grounding::{GroundingIndex, MAX_CLOSEST_COMPONENTS};
 use common::models::TaskList;
 use common::models::TaskListResponseWithMessage;
+use common::language::with_language;
+use common::preferences::with_preferences;
 use common::prompts;
-use log::error;
+use log::{error, warn};
 
 use ai_gateway::message::message::Message;
-use common::ai_util::call_llm;
-use crate::configuration::get_ai_gateway_config;
+use common::ai_util::call_llm_metered;
+use common::budget::BudgetMeter;
+use crate::configuration::{get_ai_gateway_config, get_grounding_confidence};
+
+/// What the prompt generating the tasks and questions of a query is written from.
+#[derive(Clone, Copy)]
+pub struct TaskPrompt<'a> {
+    pub user_query: &'a str,
+    pub repo_name: &'a str,
+    // the rendered preferences block of the conversation.
+    pub preferences: Option<&'a str>,
+    // the tasks and questions are written in it, English when None.
+    pub language: Option<&'a str>,
+    // the condensed summary of the repo, the tasks are generated without it when None.
+    pub repo_summary: Option<&'a str>,
+}
 
+// `history` holds the prior messages of the conversation, they are sent before the prompt
+// but are not part of the returned messages.
+// With a `grounding` index the tasks are grounded against the components of the repo, see `generate_task_list`.
+// The calls are checked against the limits of `budget`.
 pub async fn generate_tasks_and_questions(
-    user_query: &str,
-    repo_name: &str,
+    prompt: &TaskPrompt<'_>,
+    history: Vec<Message>,
+    grounding: Option<&GroundingIndex>,
+    budget: &BudgetMeter,
 ) -> Result<TaskListResponseWithMessage, anyhow::Error> {
-    let system_prompt: String = prompts::question_concept_generator_prompt(user_query, repo_name);
-    let system_message = Message::user(&system_prompt);
-    // append the system message to the message history
-    let mut messages = Some(system_message.clone()).into_iter().collect::<Vec<_>>();
+    let system_prompt = tasks_and_questions_prompt(
+        prompt.user_query,
+        prompt.repo_name,
+        prompt.preferences,
+        prompt.language,
+        prompt.repo_summary,
+    );
+    let gateway_config = &get_ai_gateway_config();
+    generate_task_list(
+        history,
+        Message::user(&system_prompt),
+        grounding.map(|index| (index, get_grounding_confidence())),
+        move |prompt_messages| async move {
+            let response_messages = call_llm_metered(gateway_config, Some(budget), None, Some(prompt_messages), None).await?;
+            extract_single_plaintext_content(&response_messages)
+        },
+    )
+    .await
+}
 
-    let response_messages = call_llm(&get_ai_gateway_config(), None, Some(messages.clone()), None).await?;
+// Sends the prompt with `llm` and parses the task list it responds with. The components each task
+// mentions are then matched against the grounding index: when some of the tasks don't reach the
+// confidence threshold, the model is asked once more with the closest real components of their
+// mentions. The tasks still ungrounded after that are kept, flagged by their grounding.
+// The returned messages are the prompt and the response the tasks were taken from.
+async fn generate_task_list<L, Fut>(
+    history: Vec<Message>,
+    system_message: Message,
+    grounding: Option<(&GroundingIndex, f32)>,
+    mut llm: L,
+) -> Result<TaskListResponseWithMessage, anyhow::Error>
+where
+    L: FnMut(Vec<Message>) -> Fut,
+    Fut: Future<Output = Result<String, anyhow::Error>>,
+{
+    let prompt_messages = history
+        .iter()
+        .cloned()
+        .chain(Some(system_message.clone()))
+        .collect::<Vec<_>>();
+    let mut response = llm(prompt_messages).await?;
+    log::debug!("Choices: {}", response);
+    let mut task_list = parse_task_list(&response)?;
 
-    let response = extract_single_plaintext_content(&response_messages)?;
-    // create assistant message and add it to the messages
-    let assistant_message = Message::assistant(&response);
-    messages.push(assistant_message);
+    if let Some((index, threshold)) = grounding {
+        ground_tasks(&mut task_list, index, threshold, false);
+        if let Some(repair_prompt) = repair_prompt(&task_list, index, threshold) {
+            let repair_messages = history
+                .into_iter()
+                .chain([
+                    system_message.clone(),
+                    Message::assistant(&response),
+                    Message::user(&repair_prompt),
+                ])
+                .collect::<Vec<_>>();
+            let repaired = llm(repair_messages)
+                .await
+                .and_then(|repaired| parse_task_list(&repaired).map(|list| (repaired, list)));
+            match repaired {
+                Ok((repaired, mut repaired_list))
+                    if repaired_list.tasks.as_ref().map_or(false, |tasks| !tasks.is_empty()) =>
+                {
+                    ground_tasks(&mut repaired_list, index, threshold, true);
+                    response = repaired;
+                    task_list = repaired_list;
+                }
+                Ok(_) => warn!("The repaired task list has no tasks, the ungrounded tasks are kept flagged"),
+                Err(e) => warn!("Failed to repair the ungrounded tasks, they are kept flagged: {}", e),
+            }
+        }
+    }
 
-    log::debug!("Choices: {}", response);
+    Ok(TaskListResponseWithMessage {
+        task_list,
+        messages: vec![system_message, Message::assistant(&response)],
+    })
+}
+
+fn parse_task_list(response: &str) -> Result<TaskList, anyhow::Error> {
+    serde_json::from_str(response).map_err(|e| {
+        error!("Failed to parse response from the gateway: {}", e);
+        anyhow::anyhow!("Failed to parse response from the gateway: {}", e)
+    })
+}
 
-    let response_task_list: Result<TaskList, serde_json::Error> = serde_json::from_str(&response);
+fn ground_tasks(task_list: &mut TaskList, index: &GroundingIndex, threshold: f32, repaired: bool) {
+    for task in task_list.tasks.iter_mut().flatten() {
+        let mut grounding = index.ground(task, threshold);
+        grounding.repaired = repaired;
+        if !grounding.grounded {
+            warn!("Task '{}' is ungrounded: {}", task.task, grounding.render());
+        }
+        task.grounding = Some(grounding);
+    }
+}
+
+// None when all the tasks are grounded.
+fn repair_prompt(task_list: &TaskList, index: &GroundingIndex, threshold: f32) -> Option<String> {
+    let ungrounded = task_list
+        .tasks
+        .iter()
+        .flatten()
+        .filter_map(|task| {
+            let grounding = task.grounding.as_ref().filter(|grounding| !grounding.grounded)?;
+            let mentions = grounding
+                .ungrounded_mentions(threshold)
+                .map(|m| (m.mention.clone(), index.closest(&m.mention, MAX_CLOSEST_COMPONENTS)))
+                .collect::<Vec<_>>();
+            Some((task.task.clone(), mentions))
+        })
+        .collect::<Vec<_>>();
+    (!ungrounded.is_empty()).then(|| prompts::task_grounding_repair_prompt(&ungrounded))
+}
+
+fn tasks_and_questions_prompt(
+    user_query: &str,
+    repo_name: &str,
+    preferences: Option<&str>,
+    language: Option<&str>,
+    repo_summary: Option<&str>,
+) -> String {
+    with_language(
+        with_preferences(
+            prompts::question_concept_generator_prompt(user_query, repo_name, repo_summary),
+            preferences,
+        ),
+        language,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use common::grounding::DEFAULT_GROUNDING_CONFIDENCE;
+    use common::repo_summary::{DirectoryStats, RepoSummary};
+
+    // the index of a small payments service.
+    fn fixture_index() -> GroundingIndex {
+        let paths = [
+            "src/billing/payment.rs",
+            "src/billing/retry_policy.rs",
+            "src/api/routes.rs",
+            "src/db/invoices.rs",
+        ];
+        let summary = RepoSummary {
+            repo_name: "acme/billing".to_string(),
+            directories: vec![DirectoryStats {
+                path: "src".to_string(),
+                files: 4,
+                lines: 600,
+            }],
+            frameworks: vec!["Axum".to_string()],
+            ..Default::default()
+        };
+        GroundingIndex::new(paths.iter().map(|p| p.to_string()).collect(), Some(&summary))
+    }
 
-    match response_task_list {
-        Ok(task_list) => {
-            //log::debug!("Task list: {:?}", task_list);
-            Ok(TaskListResponseWithMessage {
-                task_list: task_list,
-                messages,
+    fn task_list(tasks: &[(&str, &str)]) -> String {
+        let tasks = tasks
+            .iter()
+            .map(|(task, subtask)| {
+                serde_json::json!({
+                    "task": task,
+                    "subtasks": [{"subtask": subtask, "questions": ["Where is it done?"]}],
+                })
             })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "tasks": tasks }).to_string()
+    }
+
+    // one task about the real retry policy, one about a payment gateway the repo doesn't have.
+    fn first_response() -> String {
+        task_list(&[
+            ("Retry failed charges", "Update the backoff in `retry_policy.rs`"),
+            ("Route charges through the gateway", "Add a timeout to the `PaymentGateway`"),
+        ])
+    }
+
+    // Answers with the scripted responses in order and records the prompts it was sent.
+    struct ScriptedLlm {
+        responses: RefCell<VecDeque<String>>,
+        prompts: RefCell<Vec<Vec<Message>>>,
+    }
+
+    impl ScriptedLlm {
+        fn new(responses: Vec<String>) -> Self {
+            Self {
+                responses: RefCell::new(responses.into()),
+                prompts: RefCell::default(),
+            }
         }
-        Err(e) => {
-            error!("Failed to parse response from the gateway: {}", e);
-            Err(anyhow::anyhow!(
-                "Failed to parse response from the gateway: {}",
-                e
-            ))
+
+        fn call(&self, messages: Vec<Message>) -> impl Future<Output = Result<String, anyhow::Error>> {
+            self.prompts.borrow_mut().push(messages);
+            let response = self.responses.borrow_mut().pop_front();
+            async move { response.ok_or_else(|| anyhow::anyhow!("no scripted response left")) }
+        }
+
+        fn last_prompt(&self) -> String {
+            match self.prompts.borrow().last().and_then(|messages| messages.last()) {
+                Some(Message::PlainText { content, .. }) => content.clone(),
+                _ => String::new(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hallucinated_task_is_repaired_with_the_closest_components() {
+        let index = fixture_index();
+        let repaired = task_list(&[
+            ("Retry failed charges", "Update the backoff in `retry_policy.rs`"),
+            ("Route charges through the payment module", "Add a timeout in `payment.rs`"),
+        ]);
+        let llm = ScriptedLlm::new(vec![first_response(), repaired.clone()]);
+
+        let generated = generate_task_list(
+            vec![],
+            Message::user("generate the tasks"),
+            Some((&index, DEFAULT_GROUNDING_CONFIDENCE)),
+            |messages| llm.call(messages),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(llm.prompts.borrow().len(), 2);
+        let repair_prompt = llm.last_prompt();
+        assert!(repair_prompt.contains("'PaymentGateway' wasn't found"));
+        assert!(repair_prompt.contains("the closest components are: src/billing/payment.rs"));
+        assert!(!repair_prompt.contains("Retry failed charges"));
+
+        let tasks = generated.task_list.tasks.unwrap();
+        assert_eq!(tasks.len(), 2);
+        for task in &tasks {
+            let grounding = task.grounding.as_ref().unwrap();
+            assert!(grounding.grounded && grounding.repaired, "{} is grounded", task.task);
         }
+        assert_eq!(generated.messages.len(), 2);
+        assert!(matches!(
+            &generated.messages[1],
+            Message::PlainText { content, .. } if *content == repaired
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_task_still_ungrounded_after_the_retry_is_flagged() {
+        let index = fixture_index();
+        let llm = ScriptedLlm::new(vec![first_response(), first_response(), first_response()]);
+
+        let generated = generate_task_list(
+            vec![],
+            Message::user("generate the tasks"),
+            Some((&index, DEFAULT_GROUNDING_CONFIDENCE)),
+            |messages| llm.call(messages),
+        )
+        .await
+        .unwrap();
+
+        // a single retry.
+        assert_eq!(llm.prompts.borrow().len(), 2);
+        let tasks = generated.task_list.tasks.unwrap();
+        let grounded = tasks[0].grounding.as_ref().unwrap();
+        assert!(grounded.grounded);
+        let flagged = tasks[1].grounding.as_ref().unwrap();
+        assert!(!flagged.grounded);
+        assert_eq!(
+            flagged
+                .ungrounded_mentions(DEFAULT_GROUNDING_CONFIDENCE)
+                .map(|m| m.mention.as_str())
+                .collect::<Vec<_>>(),
+            vec!["PaymentGateway"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tasks_are_not_grounded_without_an_index() {
+        let llm = ScriptedLlm::new(vec![first_response()]);
+
+        let generated = generate_task_list(vec![], Message::user("generate the tasks"), None, |messages| {
+            llm.call(messages)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(llm.prompts.borrow().len(), 1);
+        assert!(generated
+            .task_list
+            .tasks
+            .unwrap()
+            .iter()
+            .all(|task| task.grounding.is_none()));
+    }
+
+    #[test]
+    fn test_prompt_asks_for_the_language_of_the_conversation() {
+        let query = "Die Sitzung läuft nach dem Login ab, wenn der Token erneuert wird.";
+        let prompt = tasks_and_questions_prompt(query, "repo", None, Some("German"), None);
+        assert!(prompt.contains("Respond in German."));
+
+        let prompt = tasks_and_questions_prompt(query, "repo", None, Some("English"), None);
+        assert!(!prompt.contains("Respond in"));
+        let prompt = tasks_and_questions_prompt(query, "repo", None, None, None);
+        assert!(!prompt.contains("Respond in"));
     }
 }
@@ -1,120 +1,10 @@
 use anyhow::Result;
-use common::ai_util::call_llm;
-use common::docker::is_running_in_docker;
-use common::task_graph::redis::establish_redis_connection;
-use configuration::Configuration;
-use std::sync::{RwLock, RwLockWriteGuard};
-use std::thread::sleep;
-use std::time::Duration;
-use std::{env, fs, process};
+use common::shutdown;
+use coordinator::compatibility::check_downstream_compatibility;
+use coordinator::{load_from_env, routes, set_config};
+use std::env;
 
 use log::{error, info};
-use once_cell::sync::Lazy;
-
-mod code_understanding;
-mod configuration;
-mod controller;
-mod llm_ops;
-mod models;
-mod routes;
-mod utility;
-
-use core::result::Result::Ok;
-
-use crate::configuration::{
-    get_ai_gateway_config, get_code_search_url, get_code_understanding_url, get_redis_url,
-};
-
-// global configuration while RwLock is used to ensure thread safety
-// Rwlock makes reads cheap, which is important because we will be reading the configuration a lot, and never mutate it after it is set.
-
-static CONFIG: Lazy<RwLock<Configuration>> = Lazy::new(|| {
-    // Directly load the configuration when initializing CONFIG.
-    RwLock::new(Configuration::default())
-});
-
-/// Performs a health check on a given URL with a retry if the first attempt fails.
-///
-/// # Arguments
-/// * `url` - The URL to check for service availability.
-///
-/// # Returns
-/// Returns `true` if the service is up and running, otherwise `false`.
-async fn health_check(url: &str) -> bool {
-    let max_attempts = 2; // Total attempts: 1 initial + 1 retry
-    let retry_delay = Duration::from_secs(5); // Delay 5 seconds before retry
-
-    for attempt in 1..=max_attempts {
-        log::debug!("Attempt {} to check health of {}", attempt, url);
-        match reqwest::get(url).await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    log::info!("Service at {} is up and running.", url);
-                    return true;
-                } else {
-                    log::warn!(
-                        "Service at {} returned non-success status: {}",
-                        url,
-                        response.status()
-                    );
-                }
-            }
-            Err(e) => {
-                log::error!("Failed to reach service at {}: {}", url, e);
-            }
-        }
-
-        if attempt < max_attempts {
-            log::debug!(
-                "Waiting for {} seconds before retrying...",
-                retry_delay.as_secs()
-            );
-            sleep(retry_delay); // Wait for some time before the next retry
-        }
-    }
-
-    log::warn!(
-        "Service at {} is not responding after {} attempts.",
-        url,
-        max_attempts
-    );
-    false // Return false if all attempts fail
-}
-
-pub fn load_from_env(env_file: Option<String>) -> Configuration {
-    // Check if running inside Docker first
-    if is_running_in_docker() {
-        log::debug!("Running coorindator Docker container");
-    }
-
-    // load the .env file from the specified path if provided, otherwise load the default .env file
-    if let Some(env_path) = env_file {
-        dotenv::from_filename(&env_path)
-            .expect(format!("Failed to load environment variables from {}", env_path).as_str());
-        info!("Loaded environment variables from {}", env_path);
-    } else {
-        dotenv::dotenv().expect("Failed to load environment variables from .env file");
-    }
-
-    let ai_gateway_config_path = env::var("AI_GATEWAY_CONFIG_PATH")
-        .expect("AI_GATEWAY_CONFIG_PATH environment variable is not set");
-
-    let ai_gateway_config = fs::read_to_string(&ai_gateway_config_path).expect(&format!(
-        "Failed to read AI Gateway config file at: {}",
-        ai_gateway_config_path
-    ));
-
-    info!("AI Gateway configuration loaded successfully.");
-
-    Configuration {
-        code_search_url: env::var("CODE_SEARCH_URL")
-            .expect("CODE_SEARCH_URL environment variable is not set"),
-        code_understanding_url: env::var("CODE_UNDERSTANDING_URL")
-            .expect("CODE_UNDERSTANDING_URL environment variable is not set"),
-        redis_url: env::var("REDIS_URL").expect("REDIS_URL environment variable is not set"),
-        ai_gateway_config,
-    }
-}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -138,47 +28,62 @@ async fn main() -> Result<()> {
     }
 
     // Load configuration
-    let config = load_from_env(env_file);
-    {
-        let mut global_config: RwLockWriteGuard<Configuration> =
-            CONFIG.write().expect("Failed to acquire write lock");
-        *global_config = config;
+    set_config(load_from_env(env_file));
+
+    // the api keys come from the env file loaded above.
+    if let Err(err) = common::auth::init_key_store() {
+        error!("Failed to initialize the API key store: {}", err);
+        std::process::exit(1);
+    }
+
+    // traces are only exported when an OTLP endpoint is configured in the env.
+    if let Err(err) = common::telemetry::init("coordinator") {
+        error!("Failed to initialize tracing: {}", err);
+        std::process::exit(1);
     }
 
-    info!("Testing AI Gateway");
-    let test_msg = "What LLM model are you?".to_string();
-    // Test if the AI gateway is initialized properly, debug log the error and end the program
-    let llm_test_output = call_llm(&get_ai_gateway_config(), Some(test_msg), None, None)
+    // code search, code understanding, Redis and the AI gateway are checked at once, every one
+    // that can't be reached is reported before exiting.
+    if let Err(err) = coordinator::dependencies::startup_checker()
+        .wait_until_ready()
         .await
-        .map_err(|e| {
-            error!("Failed to start AI Gateway: {:?}", e);
-            panic!("AI Gateway initialization failed");
-        });
+    {
+        error!("{}", err);
+        std::process::exit(1);
+    }
 
-    info!("Successful LLM response: {:?}", llm_test_output.unwrap());
-    // health check code search url and code understanding url
-    let code_search_url = get_code_search_url();
-    let code_understanding_url = get_code_understanding_url();
+    // the optional features of the downstream services are gated on their advertised capabilities.
+    if let Err(err) = check_downstream_compatibility().await {
+        error!("{}", err);
+        std::process::exit(1);
+    }
 
-    if !health_check(&code_search_url).await {
-        panic!("Code search service is not available, please run the code search service first");
+    // the re-index jobs queued before a restart run again, the ones that were running are failed.
+    if let Err(err) = coordinator::reindex::global().recover() {
+        error!("Failed to recover the re-index jobs: {}", err);
     }
-    if !health_check(&code_understanding_url).await {
-        panic!("Code understanding service is not available, please run the code understanding service first");
+    // the questions queued before a restart are reported as interrupted on their conversations.
+    if let Err(err) = coordinator::admission::global().recover() {
+        error!("Failed to recover the answer queue: {}", err);
     }
-    info!("All dependent services are up!");
+    // the conversations whose client went away are suspended until it is back.
+    coordinator::liveness::spawn_sweeper();
 
-    // test redis connection
-    let _conn = establish_redis_connection(&get_redis_url()).map_err(|e| {
-        error!("Failed to establish Redis connection, check if Redis is running and is accessible: {:?}", e);
-        panic!("Failed to establish Redis connection: {:?}", e);
-    });
+    let shutdown = shutdown::global();
+    shutdown.listen_for_signals();
 
     let coordinator_routes = routes::coordinator();
-    warp::serve(coordinator_routes)
-        .run(([0, 0, 0, 0], 3004))
-        .await;
-    info!("Started web server on http://localhost:3004");
+    let (addr, server) = warp::serve(coordinator_routes)
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], 3004), shutdown.wait());
+    info!("Started web server on http://{}", addr);
+
+    // conversation graphs are saved to redis as they change, so only in-flight requests need to finish.
+    shutdown.drain(server, shutdown::drain_timeout()).await;
+    if let Err(err) = coordinator::admission::global().persist() {
+        error!("Failed to save the answer queue: {}", err);
+    }
+    common::telemetry::shutdown();
+    info!("Coordinator shut down");
 
     Ok(())
 }
@@ -1,3 +1,6 @@
+use common::change_plan::ChangePlan;
+use common::clarification::Clarification;
+use common::feedback::Rating;
 use common::models::TaskList;
 use serde::{Deserialize, Serialize};
 
@@ -8,6 +11,91 @@ pub struct SuggestRequest {
     pub id: Option<String>,
     pub user_query: String,
     pub repo_name: String,
+    // Files the agent should read up front for every question, as `path` or `path:start-end`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_paths: Vec<String>,
+    // URL posted to on the milestones of the conversation, kept for the later requests on it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    // key of the HMAC-SHA256 `X-Signature` of the webhook payloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_secret: Option<String>,
+    // language of the tasks, questions and answers of a new conversation, as a name or an ISO 639-1
+    // code. Detected from the issue description when not set, ignored on existing conversations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // directories or files of the repo a new conversation is scoped to, the answers recommending
+    // changes elsewhere are softened or flagged. Ignored on existing conversations.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scope: Vec<String>,
+    // whether the answers of the task breakdown come with steps to verify them against the cited
+    // code, on when not set. Follow-ups and quick answers are answered without them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_verification: Option<bool>,
+    // starts a new conversation even when a recent one of the tenant is about the same issue.
+    #[serde(default)]
+    pub force: bool,
+    // pins an existing conversation to the current index generation, after its questions were
+    // refused because the generation it was pinned to was dropped.
+    #[serde(default)]
+    pub repin_generation: bool,
+}
+
+// Query parameters of GET /conversation/{id}/graph
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GraphQuery {
+    // one of dot, mermaid or json
+    pub format: Option<String>,
+    // re-resolves the anchors of the citations against the current index and reports their drift.
+    pub resolve_anchors: Option<bool>,
+}
+
+// Query parameters of GET /conversation/{id}/messages
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MessagesQuery {
+    // index of the first message to return, 0 when not set.
+    pub offset: Option<usize>,
+    // number of messages to return, capped by the handler.
+    pub limit: Option<usize>,
+    // adds the citations whose lines changed since they were cited, see `GraphQuery`.
+    pub resolve_anchors: Option<bool>,
+}
+
+// Body of the responses of /conversation/{id}/plan.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlanResponse {
+    pub id: String,
+    pub plan: ChangePlan,
+    // the tasks changed since the plan was generated, POST the plan again to update it.
+    pub stale: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RetryRequest {
+    // id of the conversation whose unanswered questions should be retried
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_paths: Vec<String>,
+}
+
+// Body of POST /quick-answer, the query is answered as a single question without generating tasks.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct QuickAnswerRequest {
+    // conversation to ask the question on, a new one is started when not set.
+    pub id: Option<String>,
+    pub user_query: String,
+    pub repo_name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_paths: Vec<String>,
+    // language of a new conversation, see `SuggestRequest`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // scope of a new conversation, see `SuggestRequest`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scope: Vec<String>,
+    // steps to verify the answer against the cited code, off when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_verification: Option<bool>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -23,4 +111,127 @@ pub struct SuggestResponse {
     pub tasks: Option<TaskList>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub questions_with_answers: Option<Vec<QuestionWithAnswer>>,
+    // pinned paths from the request that don't exist in the index of the repo.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing_pinned_paths: Vec<String>,
+    // answer to a follow-up question asked after the tasks were answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follow_up: Option<QuestionWithAnswer>,
+    // answer to a query answered directly, without generating tasks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quick_answer: Option<QuestionWithAnswer>,
+    // warning that the answers are based on a stale index, e.g.
+    // `Answers are based on commit abc1234, 9 days / 42 commits behind main.`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freshness_banner: Option<String>,
+    // a recent conversation about the same issue, returned instead of starting a new one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub possible_duplicate: Option<PossibleDuplicate>,
+    // the clarifying question the agent stopped at, also in `ask_user`. The next message of the
+    // user is taken as the response and the questions are answered on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clarification: Option<Clarification>,
+}
+
+// A recent conversation of the tenant on the same repo whose issue reads like the new one.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PossibleDuplicate {
+    pub conversation_id: String,
+    // cosine similarity of the two issues.
+    pub similarity: f32,
+    // summary of the answers of the conversation, once they were summarized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    // send the request again with `force` set to start the new conversation anyway.
+    pub force: bool,
+}
+
+// Query parameters of GET /suggest-questions
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SuggestQuestionsQuery {
+    pub repo: String,
+    // what the user typed so far.
+    pub q: String,
+    // number of questions to return, capped by the handler.
+    pub limit: Option<usize>,
+}
+
+// Where a question asked before stands, as its conversation last recorded it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuestionStatus {
+    Unanswered,
+    Answered,
+    // the agent couldn't find relevant code for it.
+    NotFound,
+}
+
+// A question asked before on the repo by the tenant, suggested while the user types a query.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SimilarQuestion {
+    pub conversation_id: String,
+    pub question_id: usize,
+    pub question: String,
+    // cosine similarity of the question and the query typed so far.
+    pub similarity: f32,
+    pub status: QuestionStatus,
+    // beginning of the answer, once it was answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_snippet: Option<String>,
+    // the graph of the conversation is no longer kept, the question links to the archive instead.
+    pub archived: bool,
+    // set on the archived questions when an archive url is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_url: Option<String>,
+}
+
+// Body of the responses of GET /suggest-questions, the most similar questions first.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SuggestQuestionsResponse {
+    pub questions: Vec<SimilarQuestion>,
+}
+
+// Body of POST /admin/reindex
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReindexRequest {
+    // repo id the repo is indexed and searched under.
+    pub repo: String,
+    // folder of the repo inside the `repo` folder of the indexer, the repo id when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo_folder: Option<String>,
+    // full reference name, e.g. `refs/heads/main`, the indexer's default when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    // the indexer only indexes the head of a branch, a request with a commit is rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    // resumes from the checkpoint of the repo, skipping the files already committed.
+    #[serde(default)]
+    pub incremental: bool,
+    // only checks the request and records the indexer command, nothing is indexed.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// Body of POST /conversation/{id}/questions/{qid}/feedback, a rating of the current answer of the
+// question.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FeedbackRequest {
+    pub rating: Rating,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // paths of the repo that answer the question, the relevant paths of its eval case.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub correct_paths: Vec<String>,
+}
+
+// Body of POST /conversation/{id}/attachments, a text, markdown or OpenAPI JSON document.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AttachmentRequest {
+    // file name the answers cite the document by, e.g. `orders-api.json`.
+    pub name: String,
+    // e.g. `text/markdown` or `application/json`, guessed from the name when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub content: String,
 }
@@ -0,0 +1,860 @@
+// Re-indexing jobs triggered from the admin endpoints. The jobs of a repo run one at a time, the
+// ones requested meanwhile wait in its queue. Every change of a job is saved to redis, the status
+// endpoint reads it from there and the jobs still queued are picked up again after a restart.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use common::clock::unix_now;
+use common::run_manifest::RunManifest;
+use common::task_graph::redis::establish_redis_connection;
+use futures::future::BoxFuture;
+use once_cell::sync::OnceCell;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::mpsc;
+
+use crate::configuration::{get_indexer_command, get_indexer_workdir, get_redis_url};
+use crate::models::ReindexRequest;
+
+// ids of the jobs that are queued or running.
+const ACTIVE_JOBS_KEY: &str = "reindex_jobs:active";
+// finished jobs are kept this long to be looked up.
+const FINISHED_JOB_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+// lines of the indexer output kept on the job.
+const OUTPUT_TAIL_LINES: usize = 50;
+// progress is saved at most this often, a phase change or the end of the job is saved right away.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Succeeded | JobStatus::Failed)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub files_done: usize,
+    // files found so far, final once the tree is walked.
+    pub files_total: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: String,
+    // unix timestamp in milliseconds.
+    pub started_at_ms: u64,
+    // none while the phase runs.
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReindexJob {
+    pub id: String,
+    pub repo: String,
+    pub repo_folder: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    pub incremental: bool,
+    pub dry_run: bool,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub progress: JobProgress,
+    #[serde(default)]
+    pub phases: Vec<PhaseTiming>,
+    // manifest of the indexing run, once it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<RunManifest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // last lines of the indexer output.
+    #[serde(default)]
+    pub output: Vec<String>,
+    // unix timestamps in seconds.
+    pub queued_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+impl ReindexJob {
+    pub fn new(request: ReindexRequest) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            repo_folder: request.repo_folder.unwrap_or_else(|| request.repo.clone()),
+            repo: request.repo,
+            branch: request.branch,
+            incremental: request.incremental,
+            dry_run: request.dry_run,
+            status: JobStatus::Queued,
+            progress: JobProgress::default(),
+            phases: Vec::new(),
+            manifest: None,
+            error: None,
+            output: Vec::new(),
+            queued_at: unix_now(),
+            started_at: None,
+            finished_at: None,
+        }
+    }
+
+    pub fn phase(&self) -> Option<&str> {
+        self.phases
+            .last()
+            .filter(|phase| phase.duration_ms.is_none())
+            .map(|phase| phase.phase.as_str())
+    }
+
+    // Applies an event of the indexer, true when it should be saved right away.
+    fn apply(&mut self, event: IndexEvent) -> bool {
+        match event {
+            IndexEvent::Phase(phase) => {
+                self.end_phase();
+                self.phases.push(PhaseTiming {
+                    phase,
+                    started_at_ms: unix_now_ms(),
+                    duration_ms: None,
+                });
+                true
+            }
+            IndexEvent::Progress { done, total } => {
+                self.progress = JobProgress {
+                    files_done: done,
+                    files_total: total,
+                };
+                false
+            }
+            IndexEvent::Output(line) => {
+                self.output.push(line);
+                if self.output.len() > OUTPUT_TAIL_LINES {
+                    self.output.remove(0);
+                }
+                false
+            }
+        }
+    }
+
+    fn end_phase(&mut self) {
+        if let Some(phase) = self.phases.last_mut().filter(|p| p.duration_ms.is_none()) {
+            phase.duration_ms = Some(unix_now_ms().saturating_sub(phase.started_at_ms));
+        }
+    }
+
+    fn finish(&mut self, result: Result<Option<RunManifest>>) {
+        self.end_phase();
+        match result {
+            Ok(manifest) => {
+                self.status = JobStatus::Succeeded;
+                self.manifest = manifest;
+            }
+            Err(e) => {
+                self.status = JobStatus::Failed;
+                self.error = Some(e.to_string());
+            }
+        }
+        self.finished_at = Some(unix_now());
+    }
+}
+
+/// What the indexer reports while it runs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IndexEvent {
+    // a phase starts, the previous one ends.
+    Phase(String),
+    Progress { done: usize, total: usize },
+    Output(String),
+}
+
+/// Runs the indexing of a job, reporting its progress on `events`.
+/// Resolves to the manifest of the run, none for a dry run.
+pub trait Indexer: Send + Sync {
+    fn run(
+        &self,
+        job: ReindexJob,
+        events: mpsc::UnboundedSender<IndexEvent>,
+    ) -> BoxFuture<'static, Result<Option<RunManifest>>>;
+}
+
+/// Where the jobs are saved.
+pub trait JobStore: Send + Sync {
+    fn save(&self, job: &ReindexJob) -> Result<()>;
+    fn load(&self, id: &str) -> Result<Option<ReindexJob>>;
+    /// The jobs that were queued or running.
+    fn unfinished(&self) -> Result<Vec<ReindexJob>>;
+}
+
+pub struct RedisJobStore {
+    redis_url: String,
+}
+
+impl RedisJobStore {
+    pub fn new(redis_url: &str) -> Self {
+        Self {
+            redis_url: redis_url.to_string(),
+        }
+    }
+}
+
+fn job_key(id: &str) -> String {
+    format!("reindex_job:{}", id)
+}
+
+impl JobStore for RedisJobStore {
+    fn save(&self, job: &ReindexJob) -> Result<()> {
+        let mut conn = establish_redis_connection(&self.redis_url)?;
+        let value = serde_json::to_string(job)?;
+        if job.status.is_finished() {
+            let _: () = conn.set_ex(job_key(&job.id), value, FINISHED_JOB_TTL_SECS as usize)?;
+            let _: () = conn.srem(ACTIVE_JOBS_KEY, &job.id)?;
+        } else {
+            let _: () = conn.set(job_key(&job.id), value)?;
+            let _: () = conn.sadd(ACTIVE_JOBS_KEY, &job.id)?;
+        }
+        Ok(())
+    }
+
+    fn load(&self, id: &str) -> Result<Option<ReindexJob>> {
+        let mut conn = establish_redis_connection(&self.redis_url)?;
+        let value: Option<String> = conn.get(job_key(id))?;
+        Ok(value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()?)
+    }
+
+    fn unfinished(&self) -> Result<Vec<ReindexJob>> {
+        let mut conn = establish_redis_connection(&self.redis_url)?;
+        let ids: Vec<String> = conn.smembers(ACTIVE_JOBS_KEY)?;
+        let mut jobs = Vec::new();
+        for id in ids {
+            match self.load(&id)? {
+                Some(job) => jobs.push(job),
+                None => {
+                    let _: () = conn.srem(ACTIVE_JOBS_KEY, &id)?;
+                }
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+pub struct ReindexManager {
+    store: Arc<dyn JobStore>,
+    indexer: Arc<dyn Indexer>,
+    // ids of the jobs waiting for each repo, a repo has an entry while one of its jobs runs.
+    queues: Mutex<HashMap<String, VecDeque<String>>>,
+}
+
+static MANAGER: OnceCell<Arc<ReindexManager>> = OnceCell::new();
+
+/// The manager of the process, running the indexer of `INDEXER_COMMAND` and saving to `REDIS_URL`.
+pub fn global() -> Arc<ReindexManager> {
+    MANAGER
+        .get_or_init(|| {
+            ReindexManager::new(
+                Arc::new(RedisJobStore::new(&get_redis_url())),
+                Arc::new(BinaryIndexer::from_config()),
+            )
+        })
+        .clone()
+}
+
+impl ReindexManager {
+    pub fn new(store: Arc<dyn JobStore>, indexer: Arc<dyn Indexer>) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            indexer,
+            queues: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Queues a job for the request, it starts right away unless a job of the repo is running.
+    pub fn submit(self: &Arc<Self>, request: ReindexRequest) -> Result<ReindexJob> {
+        let job = ReindexJob::new(request);
+        self.store.save(&job)?;
+        self.enqueue(&job);
+        Ok(job)
+    }
+
+    pub fn job(&self, id: &str) -> Result<Option<ReindexJob>> {
+        self.store.load(id)
+    }
+
+    /// Queues the jobs saved before a restart again. The ones that were running are failed, the
+    /// indexer process went away with the coordinator.
+    pub fn recover(self: &Arc<Self>) -> Result<()> {
+        let mut jobs = self.store.unfinished()?;
+        jobs.sort_by_key(|job| job.queued_at);
+        for mut job in jobs {
+            match job.status {
+                JobStatus::Running => {
+                    job.finish(Err(anyhow!(
+                        "Interrupted by a restart of the coordinator, trigger the re-index again"
+                    )));
+                    self.store.save(&job)?;
+                }
+                _ => {
+                    log::info!("Queued re-index job {} of {} again", job.id, job.repo);
+                    self.enqueue(&job);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(self: &Arc<Self>, job: &ReindexJob) {
+        let mut queues = self.queues.lock().unwrap();
+        match queues.get_mut(&job.repo) {
+            Some(queue) => queue.push_back(job.id.clone()),
+            None => {
+                queues.insert(job.repo.clone(), VecDeque::from([job.id.clone()]));
+                tokio::spawn(self.clone().work(job.repo.clone()));
+            }
+        }
+    }
+
+    // Runs the queued jobs of the repo one after the other.
+    async fn work(self: Arc<Self>, repo: String) {
+        loop {
+            let id = {
+                let mut queues = self.queues.lock().unwrap();
+                match queues.get_mut(&repo).and_then(VecDeque::pop_front) {
+                    Some(id) => id,
+                    None => {
+                        queues.remove(&repo);
+                        return;
+                    }
+                }
+            };
+            match self.store.load(&id) {
+                Ok(Some(job)) if !job.status.is_finished() => self.run_job(job).await,
+                Ok(_) => log::warn!("Re-index job {} is gone or already finished", id),
+                Err(e) => log::error!("Failed to load re-index job {}: {}", id, e),
+            }
+        }
+    }
+
+    async fn run_job(&self, mut job: ReindexJob) {
+        log::info!("Starting re-index job {} of {}", job.id, job.repo);
+        job.status = JobStatus::Running;
+        job.started_at = Some(unix_now());
+        self.save(&job);
+
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let run = tokio::spawn(self.indexer.run(job.clone(), sender));
+        let mut last_saved = Instant::now();
+        // the channel closes when the indexer is done with the run.
+        while let Some(event) = events.recv().await {
+            if job.apply(event) || last_saved.elapsed() >= SAVE_INTERVAL {
+                self.save(&job);
+                last_saved = Instant::now();
+            }
+        }
+        let result = run
+            .await
+            .unwrap_or_else(|e| Err(anyhow!("The indexer task failed: {}", e)));
+        job.finish(result);
+        match &job.error {
+            None => log::info!("Re-index job {} of {} succeeded", job.id, job.repo),
+            Some(e) => log::error!("Re-index job {} of {} failed: {}", job.id, job.repo, e),
+        }
+        self.save(&job);
+    }
+
+    // A job that can't be saved keeps running, its status is only stale.
+    fn save(&self, job: &ReindexJob) {
+        if let Err(e) = self.store.save(job) {
+            log::error!("Failed to save re-index job {}: {}", job.id, e);
+        }
+    }
+}
+
+/// Runs the ingestion binary, reading the progress from its output and the manifest from the
+/// file it writes with `--run-manifest-file`.
+pub struct BinaryIndexer {
+    // program and leading arguments, none when `INDEXER_COMMAND` isn't set.
+    command: Option<Vec<String>>,
+    workdir: Option<PathBuf>,
+}
+
+impl BinaryIndexer {
+    pub fn from_config() -> Self {
+        Self {
+            command: get_indexer_command()
+                .map(|command| command.split_whitespace().map(str::to_string).collect()),
+            workdir: get_indexer_workdir().map(PathBuf::from),
+        }
+    }
+
+    fn arguments(&self, job: &ReindexJob, manifest_path: &Path) -> Vec<String> {
+        let mut arguments = vec![
+            "--repo-folder".to_string(),
+            job.repo_folder.clone(),
+            "--repo-id".to_string(),
+            job.repo.clone(),
+            "--run-manifest-file".to_string(),
+            manifest_path.to_string_lossy().to_string(),
+        ];
+        if let Some(branch) = &job.branch {
+            arguments.extend(["--branch".to_string(), branch.clone()]);
+        }
+        if job.incremental {
+            arguments.push("--resume".to_string());
+        }
+        arguments
+    }
+}
+
+impl Indexer for BinaryIndexer {
+    fn run(
+        &self,
+        job: ReindexJob,
+        events: mpsc::UnboundedSender<IndexEvent>,
+    ) -> BoxFuture<'static, Result<Option<RunManifest>>> {
+        let command = self.command.clone();
+        let workdir = self.workdir.clone();
+        let manifest_path = std::env::temp_dir().join(format!("reindex-{}.json", job.id));
+        let arguments = self.arguments(&job, &manifest_path);
+
+        Box::pin(async move {
+            let Some((program, leading)) = command.as_deref().and_then(|c| c.split_first()) else {
+                return Err(anyhow!(
+                    "INDEXER_COMMAND isn't set, the coordinator can't run the indexer"
+                ));
+            };
+
+            let repo_path = workdir
+                .clone()
+                .unwrap_or_default()
+                .join("repo")
+                .join(&job.repo_folder);
+            if !repo_path.is_dir() {
+                return Err(anyhow!("The repo folder {:?} doesn't exist", repo_path));
+            }
+            if job.dry_run {
+                let _ = events.send(IndexEvent::Phase("dry_run".to_string()));
+                let line = [std::slice::from_ref(program), leading, arguments.as_slice()].concat();
+                let _ = events.send(IndexEvent::Output(line.join(" ")));
+                return Ok(None);
+            }
+
+            let mut process = tokio::process::Command::new(program);
+            process
+                .args(leading)
+                .args(&arguments)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true);
+            if let Some(workdir) = &workdir {
+                process.current_dir(workdir);
+            }
+            if std::env::var_os("RUST_LOG").is_none() {
+                process.env("RUST_LOG", "info");
+            }
+            let mut child = process
+                .spawn()
+                .map_err(|e| anyhow!("Failed to start the indexer {}: {}", program, e))?;
+
+            let _ = events.send(IndexEvent::Phase("walk".to_string()));
+            let stdout = tokio::spawn(forward_output(
+                child.stdout.take(),
+                events.clone(),
+                Some(OutputParser::default()),
+            ));
+            let stderr = tokio::spawn(forward_output(child.stderr.take(), events.clone(), None));
+            let status = child.wait().await?;
+            let _ = tokio::join!(stdout, stderr);
+
+            if !status.success() {
+                return Err(anyhow!(
+                    "The indexer exited with {}",
+                    describe_exit_code(status.code())
+                ));
+            }
+            let manifest = tokio::fs::read_to_string(&manifest_path)
+                .await
+                .map_err(|e| anyhow!("Failed to read the run manifest: {}", e))?;
+            let _ = tokio::fs::remove_file(&manifest_path).await;
+            Ok(Some(serde_json::from_str(&manifest)?))
+        })
+    }
+}
+
+async fn forward_output(
+    output: Option<impl AsyncRead + Unpin>,
+    events: mpsc::UnboundedSender<IndexEvent>,
+    mut parser: Option<OutputParser>,
+) {
+    let Some(output) = output else {
+        return;
+    };
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(parser) = parser.as_mut() {
+            for event in parser.parse(&line) {
+                let _ = events.send(event);
+            }
+        }
+        let _ = events.send(IndexEvent::Output(line));
+    }
+}
+
+// Progress of the indexer from the lines it prints: a `<path>: File (<oid>)` line for every file
+// found while the tree is walked, then a `Committing finished` line for every file embedded.
+#[derive(Default)]
+struct OutputParser {
+    files_total: usize,
+    files_done: usize,
+    embedding: bool,
+}
+
+impl OutputParser {
+    fn parse(&mut self, line: &str) -> Vec<IndexEvent> {
+        let mut events = Vec::new();
+        if line.trim() == "Committing finished" {
+            if !self.embedding {
+                self.embedding = true;
+                events.push(IndexEvent::Phase("embed".to_string()));
+            }
+            self.files_done += 1;
+        } else if line.ends_with(')') && line.contains(": File (") {
+            self.files_total += 1;
+        } else {
+            return events;
+        }
+        events.push(IndexEvent::Progress {
+            done: self.files_done,
+            total: self.files_total,
+        });
+        events
+    }
+}
+
+// The exit codes of the ingestion binary, see its `IngestionError`.
+fn describe_exit_code(code: Option<i32>) -> String {
+    let reason = match code {
+        Some(2) => "git error, check the repo folder and branch",
+        Some(3) => "io error",
+        Some(4) => "embedding error",
+        Some(5) => "qdrant error",
+        Some(6) => "quickwit error",
+        Some(7) => "checkpoint error",
+        Some(8) => "some files failed to be indexed",
+        Some(_) => "error",
+        None => return "no exit code, it was killed".to_string(),
+    };
+    format!("code {}: {}", code.unwrap_or_default(), reason)
+}
+
+fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::auth::{authenticate_with, handle_rejection, KeyStore, API_KEY_HEADER};
+    use tokio::sync::Semaphore;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    const KEYS: &str = r#"{
+        "key-admin": {"tenant_id": "ops", "allowed_repos": ["*"], "scopes": ["admin"]},
+        "key-service": {"tenant_id": "services", "allowed_repos": ["*"]},
+        "key-web-admin": {"tenant_id": "web", "allowed_repos": ["acme/web"], "scopes": ["admin"]}
+    }"#;
+
+    #[derive(Default)]
+    struct MemoryJobStore {
+        jobs: Mutex<HashMap<String, ReindexJob>>,
+    }
+
+    impl JobStore for MemoryJobStore {
+        fn save(&self, job: &ReindexJob) -> Result<()> {
+            self.jobs
+                .lock()
+                .unwrap()
+                .insert(job.id.clone(), job.clone());
+            Ok(())
+        }
+
+        fn load(&self, id: &str) -> Result<Option<ReindexJob>> {
+            Ok(self.jobs.lock().unwrap().get(id).cloned())
+        }
+
+        fn unfinished(&self) -> Result<Vec<ReindexJob>> {
+            let jobs = self.jobs.lock().unwrap();
+            Ok(jobs
+                .values()
+                .filter(|job| !job.status.is_finished())
+                .cloned()
+                .collect())
+        }
+    }
+
+    // Reports a walk of 3 files and the first one embedded, then waits for a permit to finish.
+    struct StubIndexer {
+        gate: Arc<Semaphore>,
+    }
+
+    impl Indexer for StubIndexer {
+        fn run(
+            &self,
+            job: ReindexJob,
+            events: mpsc::UnboundedSender<IndexEvent>,
+        ) -> BoxFuture<'static, Result<Option<RunManifest>>> {
+            let gate = self.gate.clone();
+            Box::pin(async move {
+                events.send(IndexEvent::Phase("walk".to_string()))?;
+                events.send(IndexEvent::Progress { done: 0, total: 3 })?;
+                events.send(IndexEvent::Progress { done: 1, total: 3 })?;
+                // the phase change saves the progress reported before it.
+                events.send(IndexEvent::Phase("embed".to_string()))?;
+                gate.acquire().await?.forget();
+                events.send(IndexEvent::Progress { done: 3, total: 3 })?;
+                Ok(Some(RunManifest {
+                    repo_name: job.repo,
+                    ..Default::default()
+                }))
+            })
+        }
+    }
+
+    fn manager(store: Arc<MemoryJobStore>, gate: Arc<Semaphore>) -> Arc<ReindexManager> {
+        ReindexManager::new(store, Arc::new(StubIndexer { gate }))
+    }
+
+    async fn trigger<F>(
+        routes: &F,
+        key: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value)
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/reindex")
+            .header(API_KEY_HEADER, key)
+            .json(&body)
+            .reply(routes)
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap_or_default();
+        (response.status(), body)
+    }
+
+    async fn status<F>(routes: &F, id: &str) -> (StatusCode, Option<ReindexJob>)
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        let response = warp::test::request()
+            .path(&format!("/admin/reindex/{}", id))
+            .header(API_KEY_HEADER, "key-admin")
+            .reply(routes)
+            .await;
+        (
+            response.status(),
+            serde_json::from_slice(response.body()).ok(),
+        )
+    }
+
+    // Polls the status endpoint until the job matches.
+    async fn wait_for<F>(routes: &F, id: &str, matches: impl Fn(&ReindexJob) -> bool) -> ReindexJob
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        for _ in 0..500 {
+            if let (StatusCode::OK, Some(job)) = status(routes, id).await {
+                if matches(&job) {
+                    return job;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Re-index job {} never reached the expected state", id);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_endpoints_reflect_the_progress_of_the_jobs() {
+        let gate = Arc::new(Semaphore::new(0));
+        let routes = crate::routes::admin_routes(
+            manager(Arc::new(MemoryJobStore::default()), gate.clone()),
+            authenticate_with(Arc::new(KeyStore::from_json(KEYS).unwrap())),
+        )
+        .recover(handle_rejection)
+        .recover(|_| async { Ok::<_, std::convert::Infallible>(StatusCode::NOT_FOUND) });
+
+        let (code, _) = trigger(
+            &routes,
+            "key-service",
+            serde_json::json!({"repo": "acme/api"}),
+        )
+        .await;
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        let (code, _) = trigger(
+            &routes,
+            "key-admin",
+            serde_json::json!({"repo": "acme/api", "commit": "a1b2c3"}),
+        )
+        .await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        let (code, _) = trigger(
+            &routes,
+            "key-admin",
+            serde_json::json!({"repo": "acme/api", "repo_folder": "../etc"}),
+        )
+        .await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+
+        let (code, first) = trigger(
+            &routes,
+            "key-admin",
+            serde_json::json!({"repo": "acme/api", "incremental": true}),
+        )
+        .await;
+        assert_eq!(code, StatusCode::ACCEPTED);
+        assert_eq!(first["status"], "queued");
+        let first = first["id"].as_str().unwrap().to_string();
+        let (_, second) = trigger(
+            &routes,
+            "key-admin",
+            serde_json::json!({"repo": "acme/api"}),
+        )
+        .await;
+        let second = second["id"].as_str().unwrap().to_string();
+
+        let running = wait_for(&routes, &first, |job| job.phase() == Some("embed")).await;
+        assert_eq!(running.status, JobStatus::Running);
+        assert!(running.incremental);
+        assert_eq!(
+            running.progress,
+            JobProgress {
+                files_done: 1,
+                files_total: 3
+            }
+        );
+        assert_eq!(running.phases[0].phase, "walk");
+        assert!(running.phases[0].duration_ms.is_some());
+        assert!(running.phases[1].duration_ms.is_none());
+        // the job of a repo is only shown to the tenants of the repo.
+        let response = warp::test::request()
+            .path(&format!("/admin/reindex/{}", first))
+            .header(API_KEY_HEADER, "key-web-admin")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        // one job of a repo runs at a time.
+        let (_, queued) = status(&routes, &second).await;
+        assert_eq!(queued.unwrap().status, JobStatus::Queued);
+
+        gate.add_permits(1);
+        let done = wait_for(&routes, &first, |job| job.status.is_finished()).await;
+        assert_eq!(done.status, JobStatus::Succeeded);
+        assert_eq!(done.progress.files_done, 3);
+        assert_eq!(done.manifest.unwrap().repo_name, "acme/api");
+        assert!(done.phases.iter().all(|phase| phase.duration_ms.is_some()));
+        assert!(done.finished_at.is_some());
+
+        wait_for(&routes, &second, |job| job.status == JobStatus::Running).await;
+        gate.add_permits(1);
+        let done = wait_for(&routes, &second, |job| job.status.is_finished()).await;
+        assert_eq!(done.status, JobStatus::Succeeded);
+
+        let (code, _) = status(&routes, "unknown").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_recover_queues_the_saved_jobs_again() {
+        let store = Arc::new(MemoryJobStore::default());
+        let request = |repo: &str| ReindexRequest {
+            repo: repo.to_string(),
+            ..Default::default()
+        };
+        let mut interrupted = ReindexJob::new(request("acme/api"));
+        interrupted.status = JobStatus::Running;
+        let queued = ReindexJob::new(request("acme/web"));
+        store.save(&interrupted).unwrap();
+        store.save(&queued).unwrap();
+
+        let gate = Arc::new(Semaphore::new(1));
+        let manager = manager(store.clone(), gate);
+        manager.recover().unwrap();
+
+        let interrupted = manager.job(&interrupted.id).unwrap().unwrap();
+        assert_eq!(interrupted.status, JobStatus::Failed);
+        assert!(interrupted
+            .error
+            .unwrap()
+            .contains("Interrupted by a restart"));
+        for _ in 0..500 {
+            if manager
+                .job(&queued.id)
+                .unwrap()
+                .unwrap()
+                .status
+                .is_finished()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            manager.job(&queued.id).unwrap().unwrap().status,
+            JobStatus::Succeeded
+        );
+        assert!(store.unfinished().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_progress_is_read_from_the_indexer_output() {
+        let mut parser = OutputParser::default();
+        let lines = [
+            "Skipping node_modules/left-pad/index.js",
+            "src/main.rs: File (4b825dc642cb6eb9a060e54bf8d69288fbee4904)",
+            "src/lib.rs: File (5c1b14949828006ed75a3e8858957f86a2f7e2eb)",
+            "src: Dir (9a3c7b4e2f0d8e1a6b5c4d3e2f1a0b9c8d7e6f5a)",
+            "Committing finished",
+            "Counter value: 1",
+            "Committing finished",
+        ];
+        let events = lines
+            .iter()
+            .flat_map(|line| parser.parse(line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                IndexEvent::Progress { done: 0, total: 1 },
+                IndexEvent::Progress { done: 0, total: 2 },
+                IndexEvent::Phase("embed".to_string()),
+                IndexEvent::Progress { done: 1, total: 2 },
+                IndexEvent::Progress { done: 2, total: 2 },
+            ]
+        );
+        assert_eq!(
+            describe_exit_code(Some(8)),
+            "code 8: some files failed to be indexed"
+        );
+    }
+}
@@ -1,10 +1,59 @@
-use crate::{controller::suggest, models::SuggestRequest};
+use std::sync::Arc;
+
+use crate::{
+    controller::{
+        attachments, feedback, graph, messages, plan, quick_answer, ready, reindex, retry,
+        status, suggest, suggest_questions, terminology, webhooks,
+    },
+    models::{
+        AttachmentRequest, FeedbackRequest, GraphQuery, MessagesQuery, QuickAnswerRequest,
+        ReindexRequest, RetryRequest, SuggestQuestionsQuery, SuggestRequest,
+    },
+    reindex::ReindexManager,
+};
+use common::auth::Tenant;
+use common::capabilities::version_route;
+use common::prompt_versions::prompt_versions_route;
+use common::terminology::Terminology;
+use common::{auth, metrics, telemetry};
 use warp::{self, http::Response, Filter};
 
 extern crate common;
 
 pub fn coordinator() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    home_route().or(perform_suggest())
+    home_route()
+        .or(perform_suggest())
+        .or(perform_quick_answer())
+        .or(similar_questions())
+        .or(perform_retry())
+        .or(export_graph())
+        .or(conversation_messages())
+        .or(generate_plan())
+        .or(get_plan())
+        .or(conversation_status())
+        .or(conversation_events())
+        .or(conversation_keepalive())
+        .or(webhook_log())
+        .or(add_attachment())
+        .or(delete_attachments())
+        .or(rate_answer())
+        .or(conversation_feedback())
+        .or(admin_routes(crate::reindex::global(), auth::authenticate()))
+        .or(readiness())
+        .or(prompt_versions_route())
+        .or(version())
+        .or(metrics::metrics_route())
+        .recover(auth::handle_rejection)
+        .with(warp::log::custom(|info| {
+            metrics::observe_http_request(
+                "coordinator",
+                info.path(),
+                info.method().as_str(),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }))
+        .with(warp::trace(telemetry::request_span))
 }
 
 /// POST /suggest
@@ -16,9 +65,267 @@ fn perform_suggest(
             warp::body::content_length_limit(1024 * 16)
                 .and(warp::body::json::<SuggestRequest>()),
         )
+        .and(auth::authenticate())
         .and_then(suggest::handle_suggest_wrapper)
 }
 
+/// POST /quick-answer
+/// Answers the query as a single question without generating tasks, for simple lookups like
+/// `{"user_query": "Where is the JWT validated?", "repo_name": "..."}`. Pass `id` to ask it on
+/// an answered conversation, the answer is recorded in the conversation graph either way.
+fn perform_quick_answer(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("quick-answer")
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 16)
+                .and(warp::body::json::<QuickAnswerRequest>()),
+        )
+        .and(auth::authenticate())
+        .and_then(quick_answer::handle_quick_answer_wrapper)
+}
+
+/// GET /suggest-questions?repo=...&q=...&limit=5
+/// The questions asked before on the repo that are the most similar to the partial query `q`,
+/// with the status and the beginning of their answers. Questions of archived conversations link
+/// to the archive.
+fn similar_questions(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("suggest-questions")
+        .and(warp::get())
+        .and(warp::query::<SuggestQuestionsQuery>())
+        .and(auth::authenticate())
+        .and_then(suggest_questions::handle_suggest_questions_wrapper)
+}
+
+/// POST /retry
+/// Re-asks the questions of a conversation that the agent couldn't find an answer for,
+/// with a reformulated query. Example body: `{"id": "<conversation id>"}`
+fn perform_retry() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("retry")
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 16)
+                .and(warp::body::json::<RetryRequest>()),
+        )
+        .and(auth::authenticate())
+        .and_then(retry::handle_retry_wrapper)
+}
+
+/// GET /conversation/{id}/graph?format=dot|mermaid|json
+/// Exports the task graph of a conversation, e.g. `curl .../graph?format=dot | dot -Tsvg`.
+fn export_graph() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "graph")
+        .and(warp::get())
+        .and(warp::query::<GraphQuery>())
+        .and(auth::authenticate())
+        .and_then(graph::handle_graph_export_wrapper)
+}
+
+/// GET /conversation/{id}/messages?offset=0&limit=50
+/// Pages through the messages of a conversation, in conversation order.
+fn conversation_messages(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "messages")
+        .and(warp::get())
+        .and(warp::query::<MessagesQuery>())
+        .and(auth::authenticate())
+        .and_then(messages::handle_messages_wrapper)
+}
+
+/// POST /conversation/{id}/plan
+/// Previews the files the answered tasks would change, with the kind and size of every change,
+/// without running the modifier. Generating it again replaces the stored plan.
+fn generate_plan() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "plan")
+        .and(warp::post())
+        .and(auth::authenticate())
+        .and_then(plan::handle_generate_plan_wrapper)
+}
+
+/// GET /conversation/{id}/plan
+/// The stored change plan, e.g. for the modifier to scope the files it changes.
+fn get_plan() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "plan")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and_then(plan::handle_get_plan_wrapper)
+}
+
+/// GET /conversation/{id}/status
+/// Where the questions of a conversation are in the admission queue, e.g.
+/// `{"id": "...", "queue": {"state": "queued", "queued_questions": 2, "active_questions": 0, "position": 5, "estimated_wait_secs": 20}}`.
+fn conversation_status(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "status")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and_then(status::handle_status_wrapper)
+}
+
+/// GET /conversation/{id}/events
+/// Server-sent `queue` events with the status above, sent every time it changes.
+fn conversation_events(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "events")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and_then(status::handle_events_wrapper)
+}
+
+/// POST /conversation/{id}/keepalive
+/// Keeps the conversation of a client without an event stream alive, e.g.
+/// `{"id": "...", "resumed": false, "queue": {...}}` with the status above.
+fn conversation_keepalive(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "keepalive")
+        .and(warp::post())
+        .and(auth::authenticate())
+        .and_then(status::handle_keepalive_wrapper)
+}
+
+/// GET /conversation/{id}/webhooks
+/// Lists the deliveries to the webhook of a conversation with their attempts and last status.
+fn webhook_log() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "webhooks")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and_then(webhooks::handle_webhook_log_wrapper)
+}
+
+/// POST /conversation/{id}/attachments
+/// Attaches a text, markdown or OpenAPI JSON document to a conversation, e.g.
+/// `{"name": "orders-api.json", "content_type": "application/json", "content": "..."}`.
+/// Its sections are searched alongside the code for the next questions until it expires.
+fn add_attachment() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "attachments")
+        .and(warp::post())
+        .and(
+            // the configured limit of the document is checked by the handler.
+            warp::body::content_length_limit(1024 * 1024 * 4)
+                .and(warp::body::json::<AttachmentRequest>()),
+        )
+        .and(auth::authenticate())
+        .and_then(attachments::handle_add_attachment_wrapper)
+}
+
+/// DELETE /conversation/{id}/attachments
+/// Deletes the attachments of a conversation.
+fn delete_attachments() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "attachments")
+        .and(warp::delete())
+        .and(auth::authenticate())
+        .and_then(attachments::handle_delete_attachments_wrapper)
+}
+
+/// POST /conversation/{id}/questions/{qid}/feedback
+/// Rates the current answer of a question, e.g.
+/// `{"rating": "down", "comment": "The retries are in the queue", "correct_paths": ["src/refund/queue.rs"]}`.
+fn rate_answer() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "questions" / usize / "feedback")
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 64)
+                .and(warp::body::json::<FeedbackRequest>()),
+        )
+        .and(auth::authenticate())
+        .and_then(feedback::handle_feedback_wrapper)
+}
+
+/// GET /conversation/{id}/feedback
+/// The ratings of the answers of a conversation by question, with the up and down totals.
+fn conversation_feedback(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("conversation" / String / "feedback")
+        .and(warp::get())
+        .and(auth::authenticate())
+        .and_then(feedback::handle_feedback_summary_wrapper)
+}
+
+/// The admin endpoints, they need an API key with the admin scope.
+pub(crate) fn admin_routes(
+    manager: Arc<ReindexManager>,
+    authenticate: impl Filter<Extract = (Tenant,), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    trigger_reindex(manager.clone(), authenticate.clone())
+        .or(reindex_status(manager, authenticate.clone()))
+        .or(get_terminology(authenticate.clone()))
+        .or(put_terminology(authenticate))
+}
+
+/// POST /admin/reindex
+/// Queues a re-index of a repo, e.g. `{"repo": "acme/api", "branch": "refs/heads/main", "incremental": true}`,
+/// and returns the job. The jobs of a repo run one at a time, `dry_run` only checks the request.
+fn trigger_reindex(
+    manager: Arc<ReindexManager>,
+    authenticate: impl Filter<Extract = (Tenant,), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "reindex")
+        .and(warp::post())
+        .and(
+            warp::body::content_length_limit(1024 * 16)
+                .and(warp::body::json::<ReindexRequest>()),
+        )
+        .and(authenticate.and_then(auth::authorize_admin))
+        .and(warp::any().map(move || manager.clone()))
+        .and_then(reindex::handle_trigger_reindex)
+}
+
+/// GET /admin/reindex/{job_id}
+/// Status, file progress and phase timings of a re-index job, with the run manifest or the error
+/// once it finished.
+fn reindex_status(
+    manager: Arc<ReindexManager>,
+    authenticate: impl Filter<Extract = (Tenant,), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "reindex" / String)
+        .and(warp::get())
+        .and(authenticate.and_then(auth::authorize_admin))
+        .and(warp::any().map(move || manager.clone()))
+        .and_then(reindex::handle_reindex_status)
+}
+
+/// GET /admin/repos/{repo}/terminology
+/// The jargon dictionary of the repo, e.g. `{"terms": {"helios": ["billing engine"]}}`, with the
+/// `suggestions` mined from its README and docs when it was indexed that aren't in it yet.
+fn get_terminology(
+    authenticate: impl Filter<Extract = (Tenant,), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "repos" / String / "terminology")
+        .and(warp::get())
+        .and(authenticate.and_then(auth::authorize_admin))
+        .and_then(terminology::handle_get_terminology)
+}
+
+/// PUT /admin/repos/{repo}/terminology
+/// Replaces the dictionary of the repo. The searches of the questions add the expansions of the
+/// terms they mention, and the answers name the term after the first mention of its expansion.
+fn put_terminology(
+    authenticate: impl Filter<Extract = (Tenant,), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "repos" / String / "terminology")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024 * 256).and(warp::body::json::<Terminology>()))
+        .and(authenticate.and_then(auth::authorize_admin))
+        .and_then(terminology::handle_put_terminology)
+}
+
+/// GET /ready
+/// 200 once code search and code understanding answer and Redis takes a write, 503 with the
+/// failing checks otherwise.
+fn readiness() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(ready::handle_ready)
+}
+
+/// GET /version
+/// Crate version of the coordinator, it has no optional API features yet.
+fn version() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    version_route("coordinator", env!("CARGO_PKG_VERSION"), &[])
+}
+
 fn home_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path::end() // Matches the root path "/"
         .and(warp::get()) // Only responds to GET requests
@@ -29,3 +336,34 @@ fn home_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejecti
                 .expect("Failed to construct response")
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics_scrape_after_request() {
+        let routes = coordinator();
+
+        let home = warp::test::request()
+            .method("GET")
+            .path("/")
+            .reply(&routes)
+            .await;
+        assert_eq!(home.status(), 200);
+
+        let scrape = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&routes)
+            .await;
+        assert_eq!(scrape.status(), 200);
+
+        let body = String::from_utf8(scrape.body().to_vec()).unwrap();
+        assert!(body.contains("# TYPE incredible_http_request_duration_seconds histogram"));
+        assert!(body.contains("# TYPE incredible_http_requests_total counter"));
+        assert!(body.contains(r#"service="coordinator""#));
+        // label pairs are encoded in alphabetical order.
+        assert!(body.contains(r#"method="GET",route="/",service="coordinator",status="200""#));
+    }
+}
@@ -0,0 +1,511 @@
+// Suggestions of the questions asked before on a repo, for the UIs offering them while the user
+// types a query so that a question already answered isn't asked again. The questions of a
+// conversation are embedded with the model in `MODEL_DIR` once they are in its graph, and kept in
+// redis under a key of the tenant and repo, so the questions of other tenants are never read. A
+// partial query costs one embedding and one redis read, queries shorter than `min_query_chars`
+// none. The questions outlive the graph of their conversation: once it was archived out of redis
+// they are still suggested with their last answer, linking to the archive.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use common::auth::DEFAULT_TENANT_ID;
+use common::clock::unix_now;
+use common::duplicates::cosine_similarity;
+use common::files_involved::strip_header;
+use common::task_graph::graph_model::{QuestionWithAnswer, TrackProcessV1};
+use common::task_graph::redis::{establish_redis_connection, task_process_key};
+use common::tokenizer_onnx::{Embedding, TokenizerOnnx};
+use petgraph::graph::NodeIndex;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::get_similar_questions;
+use crate::models::{QuestionStatus, SimilarQuestion};
+
+const DEFAULT_MIN_QUERY_CHARS: usize = 8;
+const DEFAULT_MIN_SIMILARITY: f32 = 0.5;
+const DEFAULT_MAX_QUESTIONS: usize = 5000;
+pub const DEFAULT_SUGGESTION_LIMIT: usize = 5;
+pub const MAX_SUGGESTION_LIMIT: usize = 20;
+const ANSWER_SNIPPET_CHARS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimilarQuestionsConfig {
+    // directory of the embedding model, no question is recorded or suggested without one.
+    pub model_dir: Option<String>,
+    // shorter queries get no suggestions, they match too many questions to be useful.
+    pub min_query_chars: usize,
+    // cosine similarity a question needs with the query to be suggested.
+    pub min_similarity: f32,
+    // questions kept per tenant and repo, the oldest ones are dropped past it.
+    pub max_questions: usize,
+    // url of an archived conversation, `{conversation_id}` is replaced with its id.
+    pub archive_url: Option<String>,
+}
+
+impl Default for SimilarQuestionsConfig {
+    fn default() -> Self {
+        Self {
+            model_dir: None,
+            min_query_chars: DEFAULT_MIN_QUERY_CHARS,
+            min_similarity: DEFAULT_MIN_SIMILARITY,
+            max_questions: DEFAULT_MAX_QUESTIONS,
+            archive_url: None,
+        }
+    }
+}
+
+/// A question of a conversation as it is kept to be suggested.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AskedQuestion {
+    pub conversation_id: String,
+    pub question_id: usize,
+    pub tenant_id: String,
+    pub question: String,
+    pub embedding: Embedding,
+    pub status: QuestionStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_snippet: Option<String>,
+    // unix seconds.
+    pub asked_at: u64,
+}
+
+fn questions_key(tenant_id: &str, repo_name: &str) -> String {
+    format!("asked_questions:{}:{}", tenant_id, repo_name)
+}
+
+fn question_field(conversation_id: &str, question_id: usize) -> String {
+    format!("{}:{}", conversation_id, question_id)
+}
+
+/// True when the query is too short to be worth an embedding.
+pub fn is_too_short(query: &str, min_query_chars: usize) -> bool {
+    query.trim().chars().count() < min_query_chars
+}
+
+// The status of a question and the beginning of its answer, without the files involved header.
+fn answer_status(answer: Option<&QuestionWithAnswer>) -> (QuestionStatus, Option<String>) {
+    let Some(answer) = answer else {
+        return (QuestionStatus::Unanswered, None);
+    };
+    if answer.answer.is_not_found() {
+        return (QuestionStatus::NotFound, None);
+    }
+    let text = strip_header(&answer.answer.answer)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let snippet = match text.char_indices().nth(ANSWER_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    };
+    (
+        QuestionStatus::Answered,
+        Some(snippet).filter(|s| !s.is_empty()),
+    )
+}
+
+/// The questions of the conversation that are new or changed since they were recorded as `known`,
+/// keyed by question id. Only the new questions are embedded.
+pub fn changed_questions(
+    tracker: &TrackProcessV1,
+    conversation_id: &str,
+    known: &HashMap<usize, AskedQuestion>,
+    mut embed: impl FnMut(&str) -> Result<Embedding>,
+    now: u64,
+) -> Result<Vec<AskedQuestion>> {
+    let tenant_id = tracker.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT_ID);
+    let mut changed = Vec::new();
+    for question in tracker.get_questions_with_ids() {
+        let answer = tracker.question_with_answer(NodeIndex::new(question.id))?;
+        let (status, answer_snippet) = answer_status(answer.as_ref());
+        let recorded = match known.get(&question.id) {
+            Some(known) if known.question == question.text => {
+                if known.status == status && known.answer_snippet == answer_snippet {
+                    continue;
+                }
+                AskedQuestion {
+                    status,
+                    answer_snippet,
+                    ..known.clone()
+                }
+            }
+            _ => AskedQuestion {
+                conversation_id: conversation_id.to_string(),
+                question_id: question.id,
+                tenant_id: tenant_id.to_string(),
+                embedding: embed(&question.text)?,
+                question: question.text,
+                status,
+                answer_snippet,
+                asked_at: now,
+            },
+        };
+        changed.push(recorded);
+    }
+    Ok(changed)
+}
+
+/// Records the questions of the conversation for the suggestions, with the status of their
+/// answers. Does nothing when no embedding model is configured.
+pub fn record_questions(redis_url: &str, tracker: &TrackProcessV1) -> Result<()> {
+    let config = get_similar_questions();
+    let Some(model_dir) = config.model_dir.as_deref() else {
+        return Ok(());
+    };
+    let Some(conversation_id) = tracker.get_root_node_uuid() else {
+        return Ok(());
+    };
+    let questions = tracker.get_questions_with_ids();
+    if questions.is_empty() {
+        return Ok(());
+    }
+
+    let tenant_id = tracker.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT_ID);
+    let key = questions_key(tenant_id, &tracker.repo);
+    let fields: Vec<String> = questions
+        .iter()
+        .map(|question| question_field(&conversation_id, question.id))
+        .collect();
+    let mut conn = establish_redis_connection(redis_url)?;
+    let values: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(&key)
+        .arg(&fields)
+        .query(&mut conn)?;
+    let known = values
+        .iter()
+        .flatten()
+        .filter_map(|value| serde_json::from_str::<AskedQuestion>(value).ok())
+        .map(|question| (question.question_id, question))
+        .collect();
+
+    let model = TokenizerOnnx::shared(model_dir)?;
+    let changed = changed_questions(
+        tracker,
+        &conversation_id,
+        &known,
+        |text| model.get_embedding(text),
+        unix_now(),
+    )?;
+    if changed.is_empty() {
+        return Ok(());
+    }
+    let items = changed
+        .iter()
+        .map(|question| {
+            Ok((
+                question_field(&question.conversation_id, question.question_id),
+                serde_json::to_string(question)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let _: () = conn.hset_multiple(&key, &items)?;
+
+    let count: usize = conn.hlen(&key)?;
+    if count > config.max_questions {
+        let all: HashMap<String, String> = conn.hgetall(&key)?;
+        let mut recorded: Vec<(String, u64)> = all
+            .into_iter()
+            .map(|(field, value)| {
+                let asked_at = serde_json::from_str::<AskedQuestion>(&value)
+                    .map(|question| question.asked_at)
+                    .unwrap_or_default();
+                (field, asked_at)
+            })
+            .collect();
+        recorded.sort_by(|a, b| b.1.cmp(&a.1));
+        let oldest: Vec<String> = recorded
+            .into_iter()
+            .skip(config.max_questions)
+            .map(|(field, _)| field)
+            .collect();
+        let _: () = conn.hdel(&key, oldest)?;
+    }
+    Ok(())
+}
+
+/// The questions of the tenant at least `min_similarity` similar to the query, the most similar
+/// first. A question asked in several conversations is suggested once, answered ones first.
+pub fn rank_questions<'a>(
+    embedding: &[f32],
+    tenant_id: &str,
+    questions: &'a [AskedQuestion],
+    min_similarity: f32,
+    limit: usize,
+) -> Vec<(&'a AskedQuestion, f32)> {
+    let mut ranked: Vec<(&AskedQuestion, f32)> = questions
+        .iter()
+        .filter(|question| question.tenant_id == tenant_id)
+        .map(|question| (question, cosine_similarity(embedding, &question.embedding)))
+        .filter(|(_, similarity)| *similarity >= min_similarity)
+        .collect();
+    ranked.sort_by(|(a, a_similarity), (b, b_similarity)| {
+        b_similarity
+            .total_cmp(a_similarity)
+            .then_with(|| {
+                (b.status == QuestionStatus::Answered).cmp(&(a.status == QuestionStatus::Answered))
+            })
+            .then_with(|| b.asked_at.cmp(&a.asked_at))
+    });
+    let mut seen = HashSet::new();
+    ranked
+        .into_iter()
+        .filter(|(question, _)| seen.insert(question.question.trim().to_lowercase()))
+        .take(limit)
+        .collect()
+}
+
+/// The question as it is suggested, `archived` when the graph of its conversation is gone.
+pub fn similar_question(
+    question: &AskedQuestion,
+    similarity: f32,
+    archived: bool,
+    archive_url: Option<&str>,
+) -> SimilarQuestion {
+    SimilarQuestion {
+        conversation_id: question.conversation_id.clone(),
+        question_id: question.question_id,
+        question: question.question.clone(),
+        similarity,
+        status: question.status,
+        answer_snippet: question.answer_snippet.clone(),
+        archived,
+        archive_url: archive_url
+            .filter(|_| archived)
+            .map(|template| template.replace("{conversation_id}", &question.conversation_id)),
+    }
+}
+
+/// The questions asked before on the repo by the tenant that are the most similar to the query
+/// typed so far. Empty for short queries and when no embedding model is configured.
+pub fn suggest_questions(
+    redis_url: &str,
+    tenant_id: &str,
+    repo_name: &str,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<SimilarQuestion>> {
+    let config = get_similar_questions();
+    if is_too_short(query, config.min_query_chars) {
+        return Ok(Vec::new());
+    }
+    let Some(model_dir) = config.model_dir.as_deref() else {
+        return Ok(Vec::new());
+    };
+    let embedding = TokenizerOnnx::shared(model_dir)?.get_embedding(query.trim())?;
+    let mut conn = establish_redis_connection(redis_url)?;
+    let values: Vec<String> = conn.hvals(questions_key(tenant_id, repo_name))?;
+    let questions: Vec<AskedQuestion> = values
+        .iter()
+        .filter_map(|value| serde_json::from_str(value).ok())
+        .collect();
+
+    // whether the graph of each conversation is still kept, checked once per conversation.
+    let mut archived_conversations = HashMap::new();
+    let mut suggested = Vec::new();
+    for (question, similarity) in rank_questions(
+        &embedding,
+        tenant_id,
+        &questions,
+        config.min_similarity,
+        limit,
+    ) {
+        let archived = match archived_conversations.get(&question.conversation_id) {
+            Some(archived) => *archived,
+            None => {
+                let exists: bool = conn.exists(task_process_key(&question.conversation_id))?;
+                archived_conversations.insert(question.conversation_id.clone(), !exists);
+                !exists
+            }
+        };
+        suggested.push(similar_question(
+            question,
+            similarity,
+            archived,
+            config.archive_url.as_deref(),
+        ));
+    }
+    Ok(suggested)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::task_graph::graph_model::QuestionWithId;
+    use common::CodeUnderstanding;
+
+    fn asked(id: usize, tenant_id: &str, question: &str, embedding: Vec<f32>) -> AskedQuestion {
+        AskedQuestion {
+            conversation_id: format!("convo-{}", id),
+            question_id: id,
+            tenant_id: tenant_id.to_string(),
+            question: question.to_string(),
+            embedding,
+            status: QuestionStatus::Answered,
+            answer_snippet: Some("In validate_token.".to_string()),
+            asked_at: 1_700_000_000 + id as u64,
+        }
+    }
+
+    fn answer(question: &QuestionWithId, text: &str) -> QuestionWithAnswer {
+        QuestionWithAnswer {
+            question_id: question.id,
+            question: question.text.clone(),
+            answer: CodeUnderstanding {
+                context: vec![],
+                question: question.text.clone(),
+                answer: text.to_string(),
+                outcome: None,
+                missing_pinned_paths: vec![],
+                cost_usd: None,
+                timings: None,
+                scope_violations: vec![],
+                citations: Default::default(),
+                verification: Default::default(),
+                prompt_versions: Default::default(),
+                files_involved: Default::default(),
+                index_mode: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_questions_of_other_tenants_are_never_suggested() {
+        let questions = vec![
+            asked(
+                1,
+                "globex",
+                "Where is the JWT validated?",
+                vec![0.9, 0.1, 0.4],
+            ),
+            asked(2, "acme", "How are refunds retried?", vec![0.0, 1.0, 0.0]),
+        ];
+        let query = vec![0.9, 0.1, 0.4];
+
+        assert!(rank_questions(&query, "acme", &questions, 0.5, 5).is_empty());
+        let ranked = rank_questions(&query, "globex", &questions, 0.5, 5);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0.question_id, 1);
+        assert_ne!(
+            questions_key("acme", "repo"),
+            questions_key("globex", "repo")
+        );
+    }
+
+    #[test]
+    fn test_most_similar_questions_are_suggested_first() {
+        let mut unanswered = asked(
+            4,
+            "acme",
+            "where is the jwt validated?",
+            vec![0.9, 0.1, 0.4],
+        );
+        unanswered.status = QuestionStatus::Unanswered;
+        let questions = vec![
+            asked(1, "acme", "How are refunds retried?", vec![0.0, 1.0, 0.0]),
+            asked(2, "acme", "Where is the JWT signed?", vec![0.7, 0.3, 0.5]),
+            asked(
+                3,
+                "acme",
+                "Where is the JWT validated?",
+                vec![0.9, 0.1, 0.4],
+            ),
+            unanswered,
+        ];
+        let query = vec![0.88, 0.12, 0.41];
+
+        let ranked = rank_questions(&query, "acme", &questions, 0.5, 5);
+        let ids: Vec<usize> = ranked
+            .iter()
+            .map(|(question, _)| question.question_id)
+            .collect();
+        // the same question asked again is suggested once, with its answer.
+        assert_eq!(ids, vec![3, 2]);
+        assert!(ranked[0].1 > ranked[1].1);
+        assert_eq!(rank_questions(&query, "acme", &questions, 0.5, 1).len(), 1);
+
+        assert!(is_too_short("  how  ", DEFAULT_MIN_QUERY_CHARS));
+        assert!(!is_too_short("how is the jwt", DEFAULT_MIN_QUERY_CHARS));
+    }
+
+    #[test]
+    fn test_archived_questions_link_to_the_archive() {
+        let question = asked(
+            3,
+            "acme",
+            "Where is the JWT validated?",
+            vec![0.9, 0.1, 0.4],
+        );
+        let template = Some("https://app.example.com/archive/{conversation_id}");
+
+        let live = similar_question(&question, 0.97, false, template);
+        assert!(!live.archived);
+        assert_eq!(live.archive_url, None);
+
+        let archived = similar_question(&question, 0.97, true, template);
+        assert!(archived.archived);
+        assert_eq!(
+            archived.archive_url.as_deref(),
+            Some("https://app.example.com/archive/convo-3")
+        );
+        assert_eq!(archived.status, QuestionStatus::Answered);
+        assert_eq!(
+            archived.answer_snippet.as_deref(),
+            Some("In validate_token.")
+        );
+        // without a template the question is still suggested, without a link.
+        assert_eq!(
+            similar_question(&question, 0.97, true, None).archive_url,
+            None
+        );
+    }
+
+    #[test]
+    fn test_only_new_questions_are_embedded() {
+        let mut tracker = TrackProcessV1::new("acme/api", "redis://127.0.0.1:6379");
+        tracker.tenant_id = Some("acme".to_string());
+        tracker.initialize_graph();
+        let question = tracker
+            .add_quick_question("Where is the JWT validated?")
+            .unwrap();
+        let conversation_id = tracker.get_root_node_uuid().unwrap();
+
+        let mut embedded = Vec::new();
+        let mut embed = |text: &str| -> Result<Embedding> {
+            embedded.push(text.to_string());
+            Ok(vec![1.0, 0.0])
+        };
+        let changed =
+            changed_questions(&tracker, &conversation_id, &HashMap::new(), &mut embed, 10).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].status, QuestionStatus::Unanswered);
+        assert_eq!(changed[0].tenant_id, "acme");
+
+        // the answer updates the recorded question without embedding it again.
+        tracker
+            .add_answer_node(&answer(
+                &question,
+                "The JWT is validated in validate_token.",
+            ))
+            .unwrap();
+        let known = HashMap::from([(question.id, changed[0].clone())]);
+        let changed =
+            changed_questions(&tracker, &conversation_id, &known, &mut embed, 20).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].status, QuestionStatus::Answered);
+        assert_eq!(
+            changed[0].answer_snippet.as_deref(),
+            Some("The JWT is validated in validate_token.")
+        );
+        assert_eq!(changed[0].asked_at, 10);
+
+        let known = HashMap::from([(question.id, changed[0].clone())]);
+        assert!(
+            changed_questions(&tracker, &conversation_id, &known, &mut embed, 30)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(embedded, vec!["Where is the JWT validated?"]);
+    }
+}
@@ -0,0 +1,165 @@
+// Phase timings of the answered questions. Code understanding reports the retrieval and the
+// answer, the queue wait and the persistence are measured here. Phases slower than their
+// threshold in `SLA_THRESHOLDS` are logged and reported on the webhook of the conversation.
+
+use std::time::{Duration, Instant};
+
+use common::task_graph::add_node::NodeError;
+use common::task_graph::graph_model::{QuestionWithAnswer, TrackProcessV1};
+use common::timings::{PhaseTimings, SlaThresholds};
+
+use crate::configuration::get_sla_thresholds;
+use crate::webhook::{ConversationWebhook, Milestone};
+
+/// Timings of an answer sent after waiting `queue_wait`, which took `round_trip` to come back.
+/// Builds of code understanding without timings have the whole round trip counted as answering.
+pub fn answer_timings(
+    reported: Option<PhaseTimings>,
+    queue_wait: Duration,
+    round_trip: Duration,
+) -> PhaseTimings {
+    let reported = reported.unwrap_or(PhaseTimings {
+        answer_ms: round_trip.as_millis() as u64,
+        ..Default::default()
+    });
+    PhaseTimings {
+        queue_wait_ms: queue_wait.as_millis() as u64,
+        ..reported
+    }
+}
+
+/// Saves the answer to the graph and sets how long that took on its timings.
+pub fn record_answer(
+    tracker: &mut TrackProcessV1,
+    answer: &mut QuestionWithAnswer,
+) -> Result<(), NodeError> {
+    let started = Instant::now();
+    tracker.extend_graph_with_answers(&vec![Ok(answer.clone())])?;
+    let timings = tracker
+        .record_persistence_time(answer.question_id, started.elapsed().as_millis() as u64)?;
+    answer.answer.timings = Some(timings);
+    Ok(())
+}
+
+/// A warning for each phase of the answer slower than its threshold.
+pub fn slow_phases(answer: &QuestionWithAnswer, thresholds: &SlaThresholds) -> Vec<Milestone> {
+    let Some(timings) = &answer.answer.timings else {
+        return Vec::new();
+    };
+    thresholds
+        .exceeded(timings)
+        .into_iter()
+        .map(|slow| Milestone::SlaExceeded {
+            question_id: answer.question_id,
+            question: answer.question.clone(),
+            phase: slow.phase,
+            duration_ms: slow.duration_ms,
+            threshold_ms: slow.threshold_ms,
+        })
+        .collect()
+}
+
+pub async fn report_slow_phases(webhook: &mut ConversationWebhook, answer: &QuestionWithAnswer) {
+    for warning in slow_phases(answer, &get_sla_thresholds()) {
+        if let Milestone::SlaExceeded {
+            phase,
+            duration_ms,
+            threshold_ms,
+            ..
+        } = &warning
+        {
+            log::warn!(
+                "The {} of question {} {:?} took {}ms, beyond its threshold of {}ms",
+                phase.as_str(),
+                answer.question_id,
+                answer.question,
+                duration_ms,
+                threshold_ms
+            );
+        }
+        webhook.notify(warning).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::timings::Phase;
+    use common::CodeUnderstanding;
+
+    fn answer(timings: Option<PhaseTimings>) -> QuestionWithAnswer {
+        QuestionWithAnswer {
+            question_id: 4,
+            question: "Where are the tokens refreshed?".to_string(),
+            answer: CodeUnderstanding {
+                context: vec![],
+                question: "Where are the tokens refreshed?".to_string(),
+                answer: "In refresh.rs".to_string(),
+                outcome: None,
+                missing_pinned_paths: vec![],
+                cost_usd: None,
+                timings,
+                scope_violations: vec![],
+                citations: Default::default(),
+                verification: Default::default(),
+                prompt_versions: Default::default(),
+                files_involved: Default::default(),
+                index_mode: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip_is_answering_without_reported_timings() {
+        let reported = PhaseTimings {
+            retrieval_ms: 8_000,
+            answer_ms: 41_000,
+            ..Default::default()
+        };
+        let timings = answer_timings(
+            Some(reported),
+            Duration::from_millis(250),
+            Duration::from_secs(50),
+        );
+        assert_eq!(timings.queue_wait_ms, 250);
+        assert_eq!((timings.retrieval_ms, timings.answer_ms), (8_000, 41_000));
+
+        let timings = answer_timings(None, Duration::ZERO, Duration::from_secs(50));
+        assert_eq!((timings.retrieval_ms, timings.answer_ms), (0, 50_000));
+    }
+
+    #[test]
+    fn test_only_slow_phases_are_warned_about() {
+        let thresholds: SlaThresholds = "retrieval=10,answer=30".parse().unwrap();
+        let warnings = slow_phases(
+            &answer(Some(PhaseTimings {
+                retrieval_ms: 8_000,
+                answer_ms: 41_000,
+                ..Default::default()
+            })),
+            &thresholds,
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&warnings[0]).unwrap(),
+            serde_json::json!({
+                "event": "sla_exceeded",
+                "data": {
+                    "question_id": 4,
+                    "question": "Where are the tokens refreshed?",
+                    "phase": "answer",
+                    "duration_ms": 41_000,
+                    "threshold_ms": 30_000
+                }
+            })
+        );
+        assert!(matches!(
+            warnings[0],
+            Milestone::SlaExceeded {
+                phase: Phase::Answer,
+                ..
+            }
+        ));
+        assert!(slow_phases(&answer(None), &thresholds).is_empty());
+    }
+}
@@ -0,0 +1,776 @@
+// Webhook callbacks on the milestones of a conversation, for integrations that can't keep an SSE
+// connection open while the tasks are answered. The milestones are delivered in the background,
+// in order, and only to receivers outside of the network of the coordinator.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use common::clock::unix_now;
+use common::task_graph::graph_model::QuestionWithAnswer;
+use common::task_graph::redis::establish_redis_connection;
+use hmac::{Hmac, Mac};
+use redis::Commands;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::task::JoinHandle;
+
+use crate::controller::error::AgentProcessingError;
+
+// declared in `common::models` with the other payloads the services exchange.
+pub use common::models::{Milestone, WebhookPayload};
+
+/// Header carrying `sha256=<hex hmac of the body>` when the webhook has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+const MAX_ATTEMPTS: u32 = 3;
+// doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub callback_url: String,
+    // key of the HMAC-SHA256 signature of the payloads, unsigned without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl WebhookConfig {
+    pub fn new(callback_url: String, secret: Option<String>) -> Result<Self, AgentProcessingError> {
+        let url = match reqwest::Url::parse(&callback_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+            _ => return Err(AgentProcessingError::InvalidCallbackUrl(callback_url)),
+        };
+        // the receiver is reached from the coordinator, it can't be one of its neighbours.
+        match url.host_str() {
+            Some(host) if !is_internal_host(host) => Ok(Self {
+                callback_url,
+                secret: secret.filter(|secret| !secret.is_empty()),
+            }),
+            _ => Err(AgentProcessingError::InternalCallbackUrl(callback_url)),
+        }
+    }
+}
+
+// localhost, or an address of the host or of a private network.
+fn is_internal_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal_ip(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            host == "localhost" || host.ends_with(".localhost")
+        }
+    }
+}
+
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // shared address space of carrier-grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local, fc00::/7, and link-local, fe80::/10.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// The host of the callback URL is checked again once resolved, a public name can point to an
+// internal address.
+async fn check_resolved_host(callback_url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(callback_url).map_err(|e| e.to_string())?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err(format!("No host in {}", callback_url));
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .collect::<Vec<_>>();
+    match addresses
+        .iter()
+        .find(|address| is_internal_ip(address.ip()))
+    {
+        Some(address) => Err(format!(
+            "{} resolves to the internal address {}",
+            host,
+            address.ip()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Outcome of delivering a milestone, kept next to the conversation for debugging.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub event: String,
+    pub attempts: u32,
+    // status of the last attempt, none when the receiver couldn't be reached.
+    pub status: Option<u16>,
+    pub delivered: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // unix timestamp of the first attempt.
+    pub sent_at: u64,
+}
+
+/// `sha256=<hex>` HMAC-SHA256 of the body, keyed with the secret of the webhook.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts the payload to the webhook, retrying server errors and unreachable receivers
+/// with a doubling `backoff`. Other client errors aren't retried.
+pub async fn deliver(
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    payload: &WebhookPayload,
+    backoff: Duration,
+) -> DeliveryRecord {
+    let mut record = DeliveryRecord {
+        event: payload.milestone.name().to_string(),
+        attempts: 0,
+        status: None,
+        delivered: false,
+        error: None,
+        sent_at: unix_now(),
+    };
+    let body = match serde_json::to_vec(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            record.error = Some(format!("Failed to serialize the payload: {}", e));
+            return record;
+        }
+    };
+
+    let mut delay = backoff;
+    for attempt in 1..=MAX_ATTEMPTS {
+        record.attempts = attempt;
+        let mut request = client
+            .post(&config.callback_url)
+            .timeout(REQUEST_TIMEOUT)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(secret) = &config.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+        }
+
+        match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                record.status = Some(status.as_u16());
+                if status.is_success() {
+                    record.delivered = true;
+                    record.error = None;
+                    return record;
+                }
+                record.error = Some(format!("Webhook responded with {}", status));
+                if !status.is_server_error() {
+                    return record;
+                }
+            }
+            Err(e) => {
+                record.status = None;
+                record.error = Some(format!("Failed to reach the webhook: {}", e));
+            }
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    record
+}
+
+fn config_key(conversation_id: &str) -> String {
+    format!("webhook:{}", conversation_id)
+}
+
+fn delivery_log_key(conversation_id: &str) -> String {
+    format!("webhook_log:{}", conversation_id)
+}
+
+pub fn save_webhook_config(
+    redis_url: &str,
+    conversation_id: &str,
+    config: &WebhookConfig,
+) -> Result<()> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let _: () = conn.set(config_key(conversation_id), serde_json::to_string(config)?)?;
+    Ok(())
+}
+
+pub fn load_webhook_config(
+    redis_url: &str,
+    conversation_id: &str,
+) -> Result<Option<WebhookConfig>> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let value: Option<String> = conn.get(config_key(conversation_id))?;
+    Ok(value.map(|value| serde_json::from_str(&value)).transpose()?)
+}
+
+pub fn append_delivery_log(
+    redis_url: &str,
+    conversation_id: &str,
+    record: &DeliveryRecord,
+) -> Result<()> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let _: () = conn.rpush(delivery_log_key(conversation_id), serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// The deliveries to the webhook of a conversation, oldest first.
+pub fn load_delivery_log(redis_url: &str, conversation_id: &str) -> Result<Vec<DeliveryRecord>> {
+    let mut conn = establish_redis_connection(redis_url)?;
+    let values: Vec<String> = conn.lrange(delivery_log_key(conversation_id), 0, -1)?;
+    values
+        .iter()
+        .map(|value| Ok(serde_json::from_str(value)?))
+        .collect()
+}
+
+/// The webhook of a conversation, either from the request or saved when the conversation was created.
+pub struct ConversationWebhook {
+    redis_url: String,
+    // set once the conversation is known to belong to the tenant of the request, nothing is
+    // delivered before.
+    conversation_id: Option<String>,
+    config: Option<WebhookConfig>,
+    // a webhook from the request is saved once the conversation has an id.
+    unsaved: bool,
+    client: reqwest::Client,
+    // the delivery of the previous milestone, the next one waits for it to keep their order.
+    last_delivery: Option<JoinHandle<()>>,
+    // the tests deliver to receivers on the loopback.
+    allow_internal_hosts: bool,
+}
+
+impl ConversationWebhook {
+    /// The webhook of a request, the saved one of the conversation is used without it.
+    pub fn requested(
+        redis_url: &str,
+        callback_url: Option<String>,
+        secret: Option<String>,
+    ) -> Result<Self, AgentProcessingError> {
+        let config = callback_url
+            .filter(|url| !url.is_empty())
+            .map(|url| WebhookConfig::new(url, secret))
+            .transpose()?;
+
+        Ok(Self {
+            redis_url: redis_url.to_string(),
+            conversation_id: None,
+            unsaved: config.is_some(),
+            config,
+            client: reqwest::Client::builder()
+                // a redirect could lead the delivery to an internal host.
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap_or_default(),
+            last_delivery: None,
+            allow_internal_hosts: false,
+        })
+    }
+
+    /// The webhook saved with a conversation, for the milestones the coordinator reaches on its own.
+    pub fn saved(redis_url: &str, conversation_id: &str) -> Self {
+        let mut webhook =
+            Self::requested(redis_url, None, None).expect("a webhook without a URL is valid");
+        webhook.set_conversation_id(Some(conversation_id.to_string()));
+        webhook
+    }
+
+    /// Attaches the webhook to the conversation, to be called once the tenant of the request is
+    /// known to own it. Without a webhook in the request, the one saved with it is loaded.
+    pub fn set_conversation_id(&mut self, conversation_id: Option<String>) {
+        if self.config.is_none() && conversation_id != self.conversation_id {
+            if let Some(id) = &conversation_id {
+                self.config = load_webhook_config(&self.redis_url, id).unwrap_or_else(|e| {
+                    log::error!("Failed to load the webhook of conversation {}: {}", id, e);
+                    None
+                });
+            }
+        }
+        self.conversation_id = conversation_id;
+    }
+
+    /// Delivers the milestone in the background and logs the delivery, a failed delivery doesn't
+    /// fail the conversation.
+    pub async fn notify(&mut self, milestone: Milestone) {
+        let (Some(config), Some(conversation_id)) = (&self.config, self.conversation_id.clone())
+        else {
+            return;
+        };
+        if self.unsaved {
+            match save_webhook_config(&self.redis_url, &conversation_id, config) {
+                Ok(()) => self.unsaved = false,
+                Err(e) => log::error!(
+                    "Failed to save the webhook of conversation {}: {}",
+                    conversation_id,
+                    e
+                ),
+            }
+        }
+
+        let payload = WebhookPayload {
+            conversation_id: conversation_id.clone(),
+            milestone,
+        };
+        let previous = self.last_delivery.take();
+        let client = self.client.clone();
+        let config = config.clone();
+        let redis_url = self.redis_url.clone();
+        let allow_internal_hosts = self.allow_internal_hosts;
+        self.last_delivery = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let checked = match allow_internal_hosts {
+                true => Ok(()),
+                false => check_resolved_host(&config.callback_url).await,
+            };
+            let record = match checked {
+                Ok(()) => deliver(&client, &config, &payload, INITIAL_BACKOFF).await,
+                Err(error) => DeliveryRecord {
+                    event: payload.milestone.name().to_string(),
+                    attempts: 0,
+                    status: None,
+                    delivered: false,
+                    error: Some(error),
+                    sent_at: unix_now(),
+                },
+            };
+            if !record.delivered {
+                log::warn!(
+                    "Webhook delivery of {} for conversation {} failed after {} attempts: {:?}",
+                    record.event,
+                    conversation_id,
+                    record.attempts,
+                    record.error
+                );
+            }
+            if let Err(e) = append_delivery_log(&redis_url, &conversation_id, &record) {
+                log::error!(
+                    "Failed to log the webhook delivery of conversation {}: {}",
+                    conversation_id,
+                    e
+                );
+            }
+        }));
+    }
+}
+
+/// Logs the paths out of scope the answer recommended changing and reports them to reviewers on
+/// the webhook of the conversation.
+pub async fn report_scope_violations(
+    webhook: &mut ConversationWebhook,
+    answer: &QuestionWithAnswer,
+) {
+    let Some(milestone) = Milestone::scope_violations(answer) else {
+        return;
+    };
+    for violation in &answer.answer.scope_violations {
+        log::warn!(
+            "The answer of question {} recommends changing {} ({}), the statement was {}",
+            answer.question_id,
+            violation.path,
+            violation.reason.as_str(),
+            if violation.rewritten { "softened" } else { "kept" }
+        );
+    }
+    webhook.notify(milestone).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::answer_scope::ScopeViolation;
+    use common::budget::BudgetExceeded;
+    use std::sync::{Arc, Mutex};
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    #[derive(Clone, Debug)]
+    struct Received {
+        signature: Option<String>,
+        body: Vec<u8>,
+    }
+
+    // a webhook receiver answering with the given statuses in turn, then 200.
+    async fn receiver(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses));
+        let log = received.clone();
+        let route = warp::post()
+            .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature: Option<String>, body: warp::hyper::body::Bytes| {
+                log.lock().unwrap().push(Received {
+                    signature,
+                    body: body.to_vec(),
+                });
+                let mut statuses = statuses.lock().unwrap();
+                let status = if statuses.is_empty() {
+                    200
+                } else {
+                    statuses.remove(0)
+                };
+                warp::reply::with_status("", StatusCode::from_u16(status).unwrap())
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hook", addr), received)
+    }
+
+    // `WebhookConfig::new` turns loopback receivers away.
+    fn local_config(callback_url: String, secret: Option<&str>) -> WebhookConfig {
+        WebhookConfig {
+            callback_url,
+            secret: secret.map(ToString::to_string),
+        }
+    }
+
+    fn payload(milestone: Milestone) -> WebhookPayload {
+        WebhookPayload {
+            conversation_id: "convo".to_string(),
+            milestone,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_payload() {
+        let (url, received) = receiver(vec![]).await;
+        let config = local_config(url, Some("secret"));
+
+        let record = deliver(
+            &reqwest::Client::new(),
+            &config,
+            &payload(Milestone::QuestionAnswered {
+                id: 2,
+                answer: "It is in main.rs".to_string(),
+                timings: None,
+            }),
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert!(record.delivered);
+        assert_eq!((record.attempts, record.status), (1, Some(200)));
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let body: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "conversation_id": "convo",
+                "event": "question_answered",
+                "data": {"id": 2, "answer": "It is in main.rs"}
+            })
+        );
+        assert_eq!(
+            received[0].signature.as_deref(),
+            Some(sign("secret", &received[0].body).as_str())
+        );
+        assert_ne!(
+            received[0].signature.as_deref(),
+            Some(sign("other", &received[0].body).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unit_milestone_without_secret_is_unsigned() {
+        let (url, received) = receiver(vec![]).await;
+        let config = local_config(url, None);
+
+        let record = deliver(
+            &reqwest::Client::new(),
+            &config,
+            &payload(Milestone::AllQuestionsAnswered),
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert!(record.delivered);
+        let received = received.lock().unwrap();
+        assert!(received[0].signature.is_none());
+        let body: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"conversation_id": "convo", "event": "all_questions_answered"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let (url, received) = receiver(vec![503, 500]).await;
+        let config = local_config(url, None);
+
+        let record = deliver(
+            &reqwest::Client::new(),
+            &config,
+            &payload(Milestone::SummaryReady {
+                summary: "done".to_string(),
+            }),
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert!(record.delivered);
+        assert_eq!((record.attempts, record.status), (3, Some(200)));
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_three_attempts_and_on_client_errors() {
+        let (url, received) = receiver(vec![500, 502, 503, 504]).await;
+        let config = local_config(url, None);
+        let failed = payload(Milestone::Failed {
+            error: "boom".to_string(),
+        });
+
+        let client = reqwest::Client::new();
+        let record = deliver(&client, &config, &failed, Duration::from_millis(1)).await;
+        assert!(!record.delivered);
+        assert_eq!((record.attempts, record.status), (3, Some(503)));
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        let (url, received) = receiver(vec![404]).await;
+        let config = local_config(url, None);
+        let record = deliver(&client, &config, &failed, Duration::from_millis(1)).await;
+        assert!(!record.delivered);
+        assert_eq!((record.attempts, record.status), (1, Some(404)));
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_budget_exceeded_payload() {
+        let exceeded = BudgetExceeded {
+            scope: common::budget::BudgetScope::Conversation,
+            limit_usd: 1.0,
+            spent_usd: 0.98,
+            projected_usd: 1.04,
+        };
+        let body = serde_json::to_value(payload(Milestone::BudgetExceeded(exceeded))).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "conversation_id": "convo",
+                "event": "budget_exceeded",
+                "data": {"scope": "conversation", "limit_usd": 1.0, "spent_usd": 0.98, "projected_usd": 1.04}
+            })
+        );
+    }
+
+    #[test]
+    fn test_suspension_payloads() {
+        let body = serde_json::to_value(payload(Milestone::Suspended { queued_questions: 2 })).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "conversation_id": "convo",
+                "event": "suspended",
+                "data": {"queued_questions": 2}
+            })
+        );
+        let body = serde_json::to_value(payload(Milestone::Resumed)).unwrap();
+        assert_eq!(body, serde_json::json!({"conversation_id": "convo", "event": "resumed"}));
+    }
+
+    #[test]
+    fn test_clarification_requested_payload() {
+        let milestone = Milestone::ClarificationRequested {
+            question_id: 2,
+            question: "Which retries do you mean?".to_string(),
+            options: vec!["webhook deliveries".to_string(), "payment refunds".to_string()],
+        };
+        assert_eq!(milestone.name(), "clarification_requested");
+        assert_eq!(
+            serde_json::to_value(payload(milestone)).unwrap(),
+            serde_json::json!({
+                "conversation_id": "convo",
+                "event": "clarification_requested",
+                "data": {
+                    "question_id": 2,
+                    "question": "Which retries do you mean?",
+                    "options": ["webhook deliveries", "payment refunds"]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_scope_violations_payload() {
+        use common::answer_scope::ScopeViolationReason;
+        use common::CodeUnderstanding;
+
+        let mut answer = QuestionWithAnswer {
+            question_id: 3,
+            question: "How are refunds validated?".to_string(),
+            answer: CodeUnderstanding {
+                context: vec![],
+                question: "How are refunds validated?".to_string(),
+                answer: "A similar pattern exists in `src/invoices/pdf.rs` (out of scope).".to_string(),
+                outcome: None,
+                missing_pinned_paths: vec![],
+                cost_usd: None,
+                timings: None,
+                scope_violations: vec![],
+                citations: Default::default(),
+                verification: Default::default(),
+                prompt_versions: Default::default(),
+                files_involved: Default::default(),
+                index_mode: Default::default(),
+            },
+        };
+        assert!(Milestone::scope_violations(&answer).is_none());
+
+        answer.answer.scope_violations = vec![ScopeViolation {
+            path: "src/invoices/pdf.rs".to_string(),
+            reason: ScopeViolationReason::OutOfScope,
+            statement: "Modify `src/invoices/pdf.rs`.".to_string(),
+            rewritten: true,
+        }];
+        let milestone = Milestone::scope_violations(&answer).unwrap();
+        assert_eq!(milestone.name(), "scope_violations");
+        assert_eq!(
+            serde_json::to_value(payload(milestone)).unwrap(),
+            serde_json::json!({
+                "conversation_id": "convo",
+                "event": "scope_violations",
+                "data": {
+                    "question_id": 3,
+                    "question": "How are refunds validated?",
+                    "violations": [{
+                        "path": "src/invoices/pdf.rs",
+                        "reason": "out_of_scope",
+                        "statement": "Modify `src/invoices/pdf.rs`.",
+                        "rewritten": true
+                    }]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_callback_url() {
+        assert!(WebhookConfig::new("ftp://example.com".to_string(), None).is_err());
+        assert!(WebhookConfig::new("not a url".to_string(), None).is_err());
+        let config =
+            WebhookConfig::new("https://example.com/hook".to_string(), Some(String::new()));
+        assert_eq!(config.unwrap().secret, None);
+    }
+
+    #[test]
+    fn test_internal_callback_urls_are_rejected() {
+        for url in [
+            "http://localhost:8080/hook",
+            "http://LOCALHOST./hook",
+            "http://admin.localhost/hook",
+            "http://127.0.0.1/hook",
+            "http://10.0.0.4/hook",
+            "http://172.16.0.1/hook",
+            "http://192.168.1.10/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(
+                matches!(
+                    WebhookConfig::new(url.to_string(), None),
+                    Err(AgentProcessingError::InternalCallbackUrl(_))
+                ),
+                "{} was accepted",
+                url
+            );
+        }
+        assert!(WebhookConfig::new("https://hooks.example.com/hook".to_string(), None).is_ok());
+        assert!(WebhookConfig::new("http://93.184.216.34:8080/hook".to_string(), None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_resolved_host_is_checked() {
+        let loopback = check_resolved_host("http://127.0.0.1:9/hook").await;
+        assert!(loopback.is_err());
+        assert!(check_resolved_host("http://localhost/hook").await.is_err());
+        let public = check_resolved_host("http://93.184.216.34/hook").await;
+        assert!(public.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_notify_delivers_in_the_background_in_order() {
+        let (url, received) = receiver(vec![503]).await;
+        let mut webhook =
+            ConversationWebhook::requested("redis://127.0.0.1:1", None, None).unwrap();
+        webhook.config = Some(local_config(url, None));
+        webhook.conversation_id = Some("convo".to_string());
+        webhook.allow_internal_hosts = true;
+
+        // the first delivery is retried after the backoff, notify doesn't wait for it.
+        let started = std::time::Instant::now();
+        webhook
+            .notify(Milestone::Failed {
+                error: "boom".to_string(),
+            })
+            .await;
+        webhook.notify(Milestone::AllQuestionsAnswered).await;
+        assert!(started.elapsed() < INITIAL_BACKOFF);
+
+        webhook.last_delivery.take().unwrap().await.unwrap();
+        let events = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|received| {
+                let body: serde_json::Value = serde_json::from_slice(&received.body).unwrap();
+                body["event"].as_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec!["failed", "failed", "all_questions_answered"]);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_delivered_before_the_conversation_is_attached() {
+        let (url, received) = receiver(vec![]).await;
+        let mut webhook =
+            ConversationWebhook::requested("redis://127.0.0.1:1", None, None).unwrap();
+        webhook.config = Some(local_config(url, None));
+        webhook.allow_internal_hosts = true;
+
+        webhook.notify(Milestone::AllQuestionsAnswered).await;
+        assert!(webhook.last_delivery.is_none());
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
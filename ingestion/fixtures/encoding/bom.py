@@ -0,0 +1,3 @@
+﻿# Grüße aus Köln
+def greet():
+    return "héllo"
@@ -0,0 +1,3 @@
+# Caf crme, faade
+def order():
+    return "caf"
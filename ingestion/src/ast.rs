@@ -2,8 +2,10 @@ use tree_sitter::{Parser, Tree};
 pub mod ast_graph;
 pub mod debug;
 pub mod def;
+pub mod doc_comment;
 pub mod import;
 pub mod language_support;
+pub mod query_pack;
 pub mod reference;
 pub mod scope;
 pub mod symbol;
@@ -14,6 +16,14 @@ use language_support::TSLanguage;
 use language_support::TSLanguageConfig;
 
 use crate::ast::ast_graph::{EdgeKind, ScopeGraph};
+use crate::ast::doc_comment::DocComment;
+
+// Counts `build_ast` calls on the current thread, lets tests check which files skip parsing.
+#[cfg(test)]
+thread_local! {
+    pub static BUILD_AST_CALLS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
 pub struct CodeFileAST<'a> {
     /// The original source that was used to generate this file.
     src: &'a [u8],
@@ -31,22 +41,27 @@ pub enum CodeFileASTError {
     ParseTimeout,
     LanguageMismatch,
     QueryError(tree_sitter::QueryError),
-    FileTooLarge,
 }
 
 impl<'a> CodeFileAST<'a> {
     /// Create a TreeSitterFile out of a sourcefile
     pub fn build_ast(src: &'a [u8], lang_id: &str) -> Result<Self, CodeFileASTError> {
-        // no scope-res for files larger than 500kb
-        if src.len() > 500 * 10usize.pow(3) {
-            return Err(CodeFileASTError::FileTooLarge);
-        }
+        #[cfg(test)]
+        BUILD_AST_CALLS.with(|calls| calls.set(calls.get() + 1));
 
         let language = match TSLanguage::from_id(lang_id) {
             Language::Supported(language) => Ok(language),
             Language::Unsupported => Err(CodeFileASTError::UnsupportedLanguage),
         }?;
+        Self::build_ast_for(src, language)
+    }
 
+    /// Create a TreeSitterFile parsed and queried with the given language configuration.
+    /// The size of the file isn't checked here, the indexed files are within their `SizeLimits`.
+    pub fn build_ast_for(
+        src: &'a [u8],
+        language: &'static TSLanguageConfig,
+    ) -> Result<Self, CodeFileASTError> {
         let mut parser = Parser::new();
         parser
             .set_language((language.grammar)())
@@ -66,6 +81,14 @@ impl<'a> CodeFileAST<'a> {
             language,
         })
     }
+    /// Doc comments and docstrings of the definitions in this file.
+    pub fn doc_comments(&self) -> Vec<DocComment> {
+        let Some(lang_id) = self.language.language_ids.first() else {
+            return Vec::new();
+        };
+        doc_comment::extract_doc_comments(self.tree.root_node(), self.src, lang_id)
+    }
+
     /// Produce a lexical scope-graph for this TreeSitterFile.
     pub fn scope_graph(self) -> Result<ScopeGraph, CodeFileASTError> {
         let query = self
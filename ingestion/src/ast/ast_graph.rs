@@ -26,6 +26,12 @@ use tree_sitter::{Node, Query, QueryCursor};
 
 use super::symbol::SymbolMetaData;
 
+/// Symbol kinds of the definitions that can hold other definitions, e.g. classes and functions.
+const CONTAINER_SYMBOLS: &[&str] = &[
+    "class", "function", "method", "generator", "func", "module", "package", "struct", "enum",
+    "union", "interface", "record", "trait", "namespace",
+];
+
 /// The algorithm used to resolve scopes.
 ///
 /// The resolution method may be parametrized on language.
@@ -394,6 +400,7 @@ impl ScopeGraph {
                             return None;
                         }
                         let node_kind = String::from("def");
+                        let container_path = self.container_path(src, idx);
                         Some(SymbolMetaData {
                             repo_name: repo_name.clone(),
                             relative_path: relative_path.clone(),
@@ -403,6 +410,7 @@ impl ScopeGraph {
                             symbol_type: std::str::from_utf8(def.name(src)).unwrap().to_owned(),
                             range: def.range,
                             node_kind: node_kind.clone(),
+                            container_path,
                         })
                     }
                     // NodeKind::Ref(r) => {
@@ -466,6 +474,81 @@ impl ScopeGraph {
         }
     }
 
+    // The names of the definitions enclosing a def, outermost first, e.g. `["PaymentService"]`
+    // for the `retry` method of the class. Walks up the scopes from the one the def is defined in,
+    // collecting the definition owning each of them.
+    pub fn container_path(&self, src: &[u8], def_idx: NodeIndex<u32>) -> Vec<String> {
+        let mut owners = Vec::new();
+        let mut scope = self
+            .graph
+            .edges_directed(def_idx, Direction::Outgoing)
+            .find(|edge| *edge.weight() == EdgeKind::DefToScope)
+            .map(|edge| edge.target());
+        while let Some(current) = scope {
+            if let Some(owner) = self.scope_owner(current) {
+                if owner != def_idx && owners.last() != Some(&owner) {
+                    owners.push(owner);
+                }
+            }
+            scope = self.parent_scope(current);
+        }
+
+        owners
+            .into_iter()
+            .rev()
+            .filter_map(|idx| match &self.graph[idx] {
+                NodeKind::Def(def) => std::str::from_utf8(def.name(src)).ok().map(str::to_owned),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The container definition owning a scope: either a def hoisted out of it, like the name of a
+    // python function, or the last one before it in the parent scope, like the name of a python
+    // class before its body. Scopes separated from that def by another scope have no owner.
+    fn scope_owner(&self, scope: NodeIndex<u32>) -> Option<NodeIndex<u32>> {
+        let scope_range = self.graph[scope].range();
+        let parent = self.parent_scope(scope)?;
+        let sibling_scopes = self
+            .graph
+            .edges_directed(parent, Direction::Incoming)
+            .filter(|edge| *edge.weight() == EdgeKind::ScopeToScope)
+            .map(|edge| self.graph[edge.source()].range())
+            .collect::<Vec<_>>();
+        let containers = self
+            .graph
+            .edges_directed(parent, Direction::Incoming)
+            .filter(|edge| *edge.weight() == EdgeKind::DefToScope)
+            .map(|edge| edge.source())
+            .filter(|&idx| {
+                self.symbol_name_of(idx)
+                    .map_or(false, |kind| CONTAINER_SYMBOLS.contains(&kind))
+            });
+
+        let mut preceding: Option<(NodeIndex<u32>, usize)> = None;
+        for idx in containers {
+            let range = self.graph[idx].range();
+            if scope_range.contains(&range) {
+                return Some(idx);
+            }
+            // defs hoisted out of a sibling scope own that scope, not the ones after it.
+            if range.end.byte > scope_range.start.byte
+                || sibling_scopes.iter().any(|sibling| sibling.contains(&range))
+            {
+                continue;
+            }
+            if preceding.map_or(true, |(_, end)| range.end.byte > end) {
+                preceding = Some((idx, range.end.byte));
+            }
+        }
+
+        let (owner, end) = preceding?;
+        let scope_between = sibling_scopes.iter().any(|sibling| {
+            sibling.start.byte >= end && sibling.start.byte < scope_range.start.byte
+        });
+        (!scope_between).then_some(owner)
+    }
+
     // is the given ref/def a direct child of the root scope
     pub fn is_top_level(&self, idx: NodeIndex<u32>) -> bool {
         self.graph.contains_edge(idx, self.root_idx)
@@ -628,7 +711,7 @@ fn scope_res_generic(
         if let Some(ranges) = capture_map.get(&index) {
             for range in ranges {
                 // if the symbol is present, is it one of the supported symbols for this language?
-                let symbol_id = symbol.and_then(|s| namespaces.symbol_id_of(s));
+                let symbol_id = symbol.and_then(|s| namespaces.symbol_id_of(language.symbol_kind(s)));
                 let local_def = LocalDef::new(*range, symbol_id);
 
                 match scoping {
@@ -645,7 +728,7 @@ fn scope_res_generic(
         if let Some(ranges) = capture_map.get(&index) {
             for range in ranges {
                 // if the symbol is present, is it one of the supported symbols for this language?
-                let symbol_id = symbol.and_then(|s| namespaces.symbol_id_of(s));
+                let symbol_id = symbol.and_then(|s| namespaces.symbol_id_of(language.symbol_kind(s)));
                 let ref_ = Reference::new(*range, symbol_id);
 
                 scope_graph.insert_ref(ref_, src);
@@ -1069,6 +1152,56 @@ foo + 1"#
         )
     }
 
+    #[test]
+    fn container_path_of_python_class_members() {
+        let src = r#"
+class PaymentService:
+    retries = 3
+
+    def retry(self, payment):
+        attempt = 0
+        return attempt
+
+    class Config:
+        def load(self):
+            pass
+
+def charge(amount):
+    return amount
+
+for item in []:
+    pass
+"#
+        .as_bytes();
+        let graph = crate::ast::CodeFileAST::build_ast(src, "Python")
+            .unwrap()
+            .scope_graph()
+            .unwrap();
+        let metadata = graph.symbols_metadata(
+            src,
+            "repo".to_string(),
+            "Python".to_string(),
+            "payments.py".to_string(),
+        );
+        let container_of = |name: &str| {
+            metadata
+                .iter()
+                .find(|meta| meta.symbol_type == name)
+                .map(|meta| meta.container_path.join("::"))
+                .unwrap()
+        };
+
+        assert_eq!(container_of("PaymentService"), "");
+        assert_eq!(container_of("retries"), "PaymentService");
+        assert_eq!(container_of("retry"), "PaymentService");
+        assert_eq!(container_of("attempt"), "PaymentService::retry");
+        assert_eq!(container_of("Config"), "PaymentService");
+        assert_eq!(container_of("load"), "PaymentService::Config");
+        assert_eq!(container_of("charge"), "");
+        // the loop after the function isn't part of it.
+        assert_eq!(container_of("item"), "");
+    }
+
     #[test]
     fn hoverable_ranges() {
         let mut s = ScopeGraph::new(r(0, 50), DUMMY_LANG_ID);
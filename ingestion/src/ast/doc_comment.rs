@@ -0,0 +1,379 @@
+// Doc comments and docstrings of definitions, indexed next to the code so that searching for
+// what a symbol does finds it even when the code itself doesn't use those words.
+
+use tree_sitter::Node;
+
+/// The documentation of a definition, with the range of the definition it documents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocComment {
+    pub symbol: String,
+    pub doc: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    // 0-based, like the chunk ranges.
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DocStyle {
+    // `///` lines or a `/** */` block before the item, attributes in between are skipped.
+    Rust,
+    // a string as the first statement of the body.
+    Python,
+    // a `/** */` block before the declaration or the statement exporting it.
+    JsDoc,
+}
+
+impl DocStyle {
+    fn from_id(lang_id: &str) -> Option<Self> {
+        match lang_id {
+            "Rust" => Some(Self::Rust),
+            "Python" => Some(Self::Python),
+            "JavaScript" | "JSX" | "TypeScript" | "TSX" => Some(Self::JsDoc),
+            _ => None,
+        }
+    }
+
+    fn definitions(self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &[
+                "function_item",
+                "function_signature_item",
+                "struct_item",
+                "enum_item",
+                "union_item",
+                "trait_item",
+                "type_item",
+                "const_item",
+                "static_item",
+                "mod_item",
+                "macro_definition",
+            ],
+            Self::Python => &["function_definition", "class_definition"],
+            Self::JsDoc => &[
+                "function_declaration",
+                "generator_function_declaration",
+                "class_declaration",
+                "abstract_class_declaration",
+                "method_definition",
+                "interface_declaration",
+                "type_alias_declaration",
+                "enum_declaration",
+                "variable_declarator",
+            ],
+        }
+    }
+
+    // Nodes wrapping a definition, the comment precedes the outermost of them.
+    fn wrappers(self) -> &'static [&'static str] {
+        match self {
+            Self::JsDoc => &["export_statement", "lexical_declaration", "variable_declaration"],
+            _ => &[],
+        }
+    }
+
+    // Nodes allowed between the comment and the definition.
+    fn skipped(self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &["attribute_item"],
+            _ => &[],
+        }
+    }
+
+    // The doc text of a comment node, `None` if it isn't a doc comment.
+    fn comment_text(self, kind: &str, text: &str) -> Option<String> {
+        match (self, kind) {
+            (Self::Rust, "line_comment") => text
+                .trim_end()
+                .strip_prefix("///")
+                .filter(|rest| !rest.starts_with('/'))
+                .map(|rest| rest.strip_prefix(' ').unwrap_or(rest).to_string()),
+            (Self::Rust, "block_comment") | (Self::JsDoc, "comment") => text
+                .strip_prefix("/**")
+                .filter(|rest| !rest.starts_with('*'))
+                .and_then(|rest| rest.strip_suffix("*/"))
+                .map(clean_block_comment),
+            _ => None,
+        }
+    }
+
+    // Whether several comments in a row make up the doc, like `///` lines.
+    fn is_line_comment(kind: &str) -> bool {
+        kind == "line_comment"
+    }
+}
+
+fn clean_block_comment(body: &str) -> String {
+    body.lines()
+        .map(|line| {
+            let line = line.trim();
+            let line = line.strip_prefix('*').unwrap_or(line);
+            line.strip_prefix(' ').unwrap_or(line)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+// Removes the quotes and the common indentation of a python string literal.
+fn clean_docstring(literal: &str) -> Option<String> {
+    let literal = literal.trim_start_matches(|c: char| "rRuU".contains(c));
+    let body = ["\"\"\"", "'''", "\"", "'"].iter().find_map(|quote| {
+        literal
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote))
+    })?;
+
+    let mut lines = body.lines();
+    let first = lines.next().unwrap_or_default().trim();
+    let rest = lines.collect::<Vec<_>>();
+    let indent = rest
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let doc = std::iter::once(first)
+        .chain(
+            rest.iter()
+                .map(|line| line.get(indent..).unwrap_or_default().trim_end()),
+        )
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(doc.trim().to_string())
+}
+
+fn docstring(node: Node, src: &[u8]) -> Option<String> {
+    let body = node.child_by_field_name("body")?;
+    let mut cursor = body.walk();
+    let first = body
+        .named_children(&mut cursor)
+        .find(|child| child.kind() != "comment")?;
+    if first.kind() != "expression_statement" {
+        return None;
+    }
+    let literal = first.named_child(0).filter(|child| child.kind() == "string")?;
+    clean_docstring(literal.utf8_text(src).ok()?)
+}
+
+fn preceding_doc(style: DocStyle, node: Node, src: &[u8]) -> Option<String> {
+    let mut anchor = node;
+    while let Some(parent) = anchor.parent() {
+        if !style.wrappers().contains(&parent.kind()) {
+            break;
+        }
+        anchor = parent;
+    }
+
+    // collected bottom up.
+    let mut lines = Vec::new();
+    let mut next_row = anchor.start_position().row;
+    let mut sibling = anchor.prev_named_sibling();
+    while let Some(current) = sibling {
+        // the comment has to end on the line above, a blank line detaches it.
+        if current.end_position().row + 1 < next_row {
+            break;
+        }
+        next_row = current.start_position().row;
+        sibling = current.prev_named_sibling();
+        if style.skipped().contains(&current.kind()) {
+            continue;
+        }
+
+        let Some(text) = current
+            .utf8_text(src)
+            .ok()
+            .and_then(|text| style.comment_text(current.kind(), text))
+        else {
+            break;
+        };
+        lines.push(text);
+        if !DocStyle::is_line_comment(current.kind()) {
+            break;
+        }
+    }
+
+    lines.reverse();
+    let doc = lines.join("\n").trim().to_string();
+    Some(doc)
+}
+
+fn symbol_name(node: Node, src: &[u8]) -> Option<String> {
+    let name = node.child_by_field_name("name")?;
+    Some(name.utf8_text(src).ok()?.to_string())
+}
+
+fn collect(style: DocStyle, node: Node, src: &[u8], docs: &mut Vec<DocComment>) {
+    if style.definitions().contains(&node.kind()) {
+        let doc = match style {
+            DocStyle::Python => docstring(node, src),
+            DocStyle::Rust | DocStyle::JsDoc => preceding_doc(style, node, src),
+        };
+        let symbol = symbol_name(node, src);
+        if let (Some(doc), Some(symbol)) = (doc.filter(|doc| !doc.is_empty()), symbol) {
+            docs.push(DocComment {
+                symbol,
+                doc,
+                start_byte: node.start_byte(),
+                end_byte: node.end_byte(),
+                start_line: node.start_position().row,
+                end_line: node.end_position().row,
+            });
+        }
+    }
+
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        collect(style, child, src, docs);
+    }
+}
+
+/// Doc comments of the definitions under `root`, in source order. Languages without a known doc
+/// comment convention have none.
+pub fn extract_doc_comments(root: Node, src: &[u8], lang_id: &str) -> Vec<DocComment> {
+    let Some(style) = DocStyle::from_id(lang_id) else {
+        return Vec::new();
+    };
+    let mut docs = Vec::new();
+    collect(style, root, src, &mut docs);
+    docs
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::CodeFileAST;
+
+    fn docs(src: &str, lang_id: &str) -> Vec<(String, String, usize, usize)> {
+        CodeFileAST::build_ast(src.as_bytes(), lang_id)
+            .unwrap()
+            .doc_comments()
+            .into_iter()
+            .map(|doc| (doc.symbol, doc.doc, doc.start_line, doc.end_line))
+            .collect()
+    }
+
+    fn doc(
+        symbol: &str,
+        doc: &str,
+        start_line: usize,
+        end_line: usize,
+    ) -> (String, String, usize, usize) {
+        (symbol.to_string(), doc.to_string(), start_line, end_line)
+    }
+
+    #[test]
+    fn test_rust_doc_comments() {
+        let src = r#"//! crate docs are not attached to an item.
+
+/// Adds two numbers.
+///
+/// Wraps on overflow.
+#[inline]
+pub fn add(a: u8, b: u8) -> u8 {
+    a.wrapping_add(b)
+}
+
+// a plain comment is not documentation.
+fn plain() {}
+
+/** A point in space. */
+struct Point {
+    x: f32,
+}
+
+/// detached by the blank line.
+
+fn detached() {}
+"#;
+        assert_eq!(
+            docs(src, "Rust"),
+            vec![
+                doc("add", "Adds two numbers.\n\nWraps on overflow.", 6, 8),
+                doc("Point", "A point in space.", 14, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_python_docstrings() {
+        let src = r#"def load(path):
+    """Load the index at `path`.
+
+    Returns None when it doesn't exist.
+    """
+    return None
+
+
+class Store:
+    '''Keeps documents in memory.'''
+
+    def get(self, key):
+        # not a docstring
+        return key
+"#;
+        assert_eq!(
+            docs(src, "Python"),
+            vec![
+                doc(
+                    "load",
+                    "Load the index at `path`.\n\nReturns None when it doesn't exist.",
+                    0,
+                    5
+                ),
+                doc("Store", "Keeps documents in memory.", 8, 13),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jsdoc_comments() {
+        let src = r#"/**
+ * Fetches a repo by name.
+ * @param {string} name
+ */
+export async function fetchRepo(name) {
+  return name;
+}
+
+/** Default page size. */
+const PAGE_SIZE = 20;
+
+// not jsdoc
+function plain() {}
+
+class Client {
+  /** Closes the connection. */
+  close() {}
+}
+"#;
+        assert_eq!(
+            docs(src, "JavaScript"),
+            vec![
+                doc("fetchRepo", "Fetches a repo by name.\n@param {string} name", 4, 6),
+                doc("PAGE_SIZE", "Default page size.", 9, 9),
+                doc("close", "Closes the connection.", 16, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_typescript_doc_comments() {
+        let src = r#"/** Options of a search request. */
+export interface SearchOptions {
+  limit: number;
+}
+"#;
+        assert_eq!(
+            docs(src, "TypeScript"),
+            vec![doc("SearchOptions", "Options of a search request.", 1, 3)]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_language_has_no_docs() {
+        assert!(docs("/** Docs. */\nint main() { return 0; }\n", "C").is_empty());
+    }
+}
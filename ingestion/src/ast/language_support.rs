@@ -3,6 +3,8 @@ extern crate once_cell;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 
+use crate::ast::query_pack;
+
 
 mod c;
 mod c_sharp;
@@ -50,6 +52,20 @@ pub struct TSLanguageConfig {
     /// Namespaces defined by this language,
     /// E.g.: type namespace, variable namespace, function namespace
     pub namespaces: NameSpaces,
+
+    /// Capture kinds of the scope query mapped onto a symbol of the namespaces,
+    /// E.g.: ("nonlocal", "variable"). Only query packs declare them.
+    pub symbol_kinds: &'static [(&'static str, &'static str)],
+}
+
+impl TSLanguageConfig {
+    /// The symbol a capture kind of the scope query stands for.
+    pub fn symbol_kind<'a>(&self, kind: &'a str) -> &'a str {
+        self.symbol_kinds
+            .iter()
+            .find(|(capture, _)| *capture == kind)
+            .map_or(kind, |(_, symbol)| symbol)
+    }
 }
 
 #[derive(Debug)]
@@ -66,6 +82,18 @@ impl MemoizedQuery {
         }
     }
 
+    /// A query compiled ahead, e.g. while its query pack was validated.
+    pub fn compiled(scope_query: &'static str, query: tree_sitter::Query) -> Self {
+        let slot = OnceCell::new();
+        let _ = slot.set(query);
+        Self { slot, scope_query }
+    }
+
+    /// The source of the query.
+    pub fn source(&self) -> &'static str {
+        self.scope_query
+    }
+
     /// Get a reference to the relevant tree sitter compiled query.
     ///
     /// This method compiles the query if it has not already been compiled.
@@ -87,9 +115,11 @@ impl TSLanguage {
     /// See [0] for a list of valid language identifiers.
     ///
     /// [0]: https://github.com/monkslc/hyperpolyglot/blob/master/src/codegen/languages.rs
+    /// The languages with a query pack installed are looked up in it first.
     pub fn from_id(lang_id: &str) -> Self {
-        ALL_LANGUAGES
+        query_pack::installed_languages()
             .iter()
+            .chain(ALL_LANGUAGES.iter())
             .copied()
             .find(|target| {
                 target
@@ -33,6 +33,7 @@ pub static C: TSLanguageConfig = TSLanguageConfig {
         // misc.
         "label",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
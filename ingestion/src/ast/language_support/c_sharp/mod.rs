@@ -25,6 +25,7 @@ pub static C_SHARP: TSLanguageConfig = TSLanguageConfig {
         // namespaces
         "namespace",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
@@ -37,6 +37,7 @@ pub static CPP: TSLanguageConfig = TSLanguageConfig {
         "label",
         "alias",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
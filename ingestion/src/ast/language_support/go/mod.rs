@@ -22,6 +22,7 @@ pub static GO: TSLanguageConfig = TSLanguageConfig {
         &["member"],
         &["label"],
     ],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
@@ -29,6 +29,7 @@ pub static JAVA: TSLanguageConfig = TSLanguageConfig {
         // misc.
         "label",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
@@ -28,6 +28,7 @@ pub static JAVASCRIPT: TSLanguageConfig = TSLanguageConfig {
         // misc.
         "label",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
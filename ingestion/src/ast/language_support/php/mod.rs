@@ -30,6 +30,7 @@ pub static PHP: TSLanguageConfig = TSLanguageConfig {
         // namespacing
         "namespace",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
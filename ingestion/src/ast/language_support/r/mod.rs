@@ -14,6 +14,7 @@ pub static R: TSLanguageConfig = TSLanguageConfig {
         // variables
         "variable",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
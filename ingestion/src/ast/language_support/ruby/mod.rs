@@ -19,6 +19,7 @@ pub static RUBY: TSLanguageConfig = TSLanguageConfig {
         // everything is an object
         &["variable", "constant", "class", "method", "module"],
     ],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
@@ -33,6 +33,7 @@ pub static RUST: TSLanguageConfig = TSLanguageConfig {
         "label",
         "lifetime",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
@@ -34,6 +34,7 @@ pub static TYPESCRIPT: TSLanguageConfig = TSLanguageConfig {
         // misc.
         "label",
     ]],
+    symbol_kinds: &[],
 };
 
 #[cfg(test)]
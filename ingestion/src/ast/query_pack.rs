@@ -0,0 +1,429 @@
+// Query packs: tree-sitter queries of some languages loaded from a directory at startup in place
+// of the ones compiled into the indexer, so the symbol extraction of a language can be improved
+// without a new build. The directory holds a `manifest.toml` next to the `.scm` files:
+//
+//     version = "2024-06-01"
+//
+//     [languages.Python]
+//     scopes = "python/scopes.scm"
+//     # optional, the compiled-in hoverables query when unset.
+//     hoverables = "python/hoverables.scm"
+//     # optional, must be the compiled-in namespaces of the language.
+//     namespaces = [["class", "function", "parameter", "variable"]]
+//     # optional, capture kinds of the scopes query mapped onto a symbol of the namespaces.
+//     kinds = { nonlocal = "variable" }
+//
+// The languages without a pack keep their compiled-in queries. The namespaces can't change: the
+// scope graphs stored in the index are read by search with the compiled-in ones, so new capture
+// kinds are mapped onto their symbols with `kinds` instead.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::ast::language_support::{
+    MemoizedQuery, NameSpaceMethods, NameSpaces, TSLanguageConfig, ALL_LANGUAGES,
+};
+
+/// Name of the manifest in the directory of the query packs.
+pub const QUERY_PACK_MANIFEST: &str = "manifest.toml";
+
+static INSTALLED: OnceCell<QueryPacks> = OnceCell::new();
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackManifest {
+    version: String,
+    #[serde(default)]
+    languages: BTreeMap<String, LanguagePack>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LanguagePack {
+    // paths relative to the directory of the manifest.
+    scopes: PathBuf,
+    hoverables: Option<PathBuf>,
+    namespaces: Option<Vec<Vec<String>>>,
+    #[serde(default)]
+    kinds: BTreeMap<String, String>,
+}
+
+/// The languages of a query pack, their queries compiled against their grammars.
+pub struct QueryPacks {
+    pub version: String,
+    pub languages: Vec<&'static TSLanguageConfig>,
+}
+
+impl QueryPacks {
+    /// Names of the languages of the pack, e.g. `["Python"]`.
+    pub fn language_names(&self) -> Vec<String> {
+        self.languages
+            .iter()
+            .filter_map(|language| language.language_ids.first())
+            .map(|id| id.to_string())
+            .collect()
+    }
+}
+
+impl fmt::Debug for QueryPacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryPacks")
+            .field("version", &self.version)
+            .field("languages", &self.language_names())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPackError {
+    // language of the pack, `manifest` for the errors of the manifest itself.
+    pub language: String,
+    pub message: String,
+}
+
+impl QueryPackError {
+    fn new(language: &str, message: impl Into<String>) -> Self {
+        Self {
+            language: language.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for QueryPackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.language, self.message)
+    }
+}
+
+// A language of the pack that passed validation, not leaked yet.
+struct CheckedLanguage {
+    base: &'static TSLanguageConfig,
+    scopes: (String, tree_sitter::Query),
+    hoverables: Option<(String, tree_sitter::Query)>,
+    kinds: BTreeMap<String, String>,
+}
+
+/// Loads the query packs of `dir` and checks every query against the grammar of its language.
+/// All the errors of the packs are returned, not only the first one.
+pub fn load_query_packs(dir: &Path) -> Result<QueryPacks, Vec<QueryPackError>> {
+    let manifest_path = dir.join(QUERY_PACK_MANIFEST);
+    let manifest: PackManifest = std::fs::read_to_string(&manifest_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| toml::from_str(&content).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            vec![QueryPackError::new(
+                "manifest",
+                format!("{}: {}", manifest_path.display(), e),
+            )]
+        })?;
+
+    let mut checked = Vec::new();
+    let mut errors = Vec::new();
+    for (name, pack) in manifest.languages {
+        match check_language(dir, &name, pack) {
+            Ok(language) => checked.push(language),
+            Err(messages) => errors.extend(
+                messages
+                    .into_iter()
+                    .map(|message| QueryPackError::new(&name, message)),
+            ),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(QueryPacks {
+        version: manifest.version,
+        languages: checked.into_iter().map(leak_language).collect(),
+    })
+}
+
+/// Makes `TSLanguage::from_id` find the languages of the packs before the compiled-in ones.
+/// The packs are installed once per process, later calls keep the first ones.
+pub fn install_query_packs(packs: QueryPacks) -> &'static QueryPacks {
+    INSTALLED.get_or_init(|| packs)
+}
+
+pub fn installed_query_packs() -> Option<&'static QueryPacks> {
+    INSTALLED.get()
+}
+
+pub(crate) fn installed_languages() -> &'static [&'static TSLanguageConfig] {
+    INSTALLED
+        .get()
+        .map_or(&[], |packs| packs.languages.as_slice())
+}
+
+fn check_language(
+    dir: &Path,
+    name: &str,
+    pack: LanguagePack,
+) -> Result<CheckedLanguage, Vec<String>> {
+    let Some(base) = ALL_LANGUAGES.iter().copied().find(|language| {
+        language
+            .language_ids
+            .iter()
+            .any(|id| id.eq_ignore_ascii_case(name))
+    }) else {
+        return Err(vec![format!(
+            "not a supported language, the supported ones are {}",
+            ALL_LANGUAGES
+                .iter()
+                .filter_map(|language| language.language_ids.first())
+                .copied()
+                .collect::<Vec<_>>()
+                .join(", ")
+        )]);
+    };
+
+    let mut errors = Vec::new();
+    if let Some(namespaces) = &pack.namespaces {
+        let compiled = base
+            .namespaces
+            .iter()
+            .map(|namespace| namespace.to_vec())
+            .collect::<Vec<_>>();
+        if *namespaces != compiled {
+            errors.push(format!(
+                "namespaces {:?} aren't the compiled-in {:?}, map new capture kinds onto those with `kinds`",
+                namespaces, compiled
+            ));
+        }
+    }
+    for (kind, symbol) in &pack.kinds {
+        if base.namespaces.symbol_id_of(symbol).is_none() {
+            errors.push(format!(
+                "kind `{}` is mapped onto `{}`, which is in none of the namespaces",
+                kind, symbol
+            ));
+        }
+    }
+
+    let scopes = compile(dir, &pack.scopes, base.grammar);
+    if let Ok((_, query)) = &scopes {
+        errors.extend(query.capture_names().iter().filter_map(|capture| {
+            capture_error(capture, base.namespaces, &pack.kinds)
+                .map(|message| format!("{}: {}", pack.scopes.display(), message))
+        }));
+    }
+    let hoverables = pack
+        .hoverables
+        .as_ref()
+        .map(|path| compile(dir, path, base.grammar))
+        .transpose();
+
+    match (scopes, hoverables) {
+        (Ok(scopes), Ok(hoverables)) if errors.is_empty() => Ok(CheckedLanguage {
+            base,
+            scopes,
+            hoverables,
+            kinds: pack.kinds,
+        }),
+        (scopes, hoverables) => {
+            errors.extend(scopes.err());
+            errors.extend(hoverables.err());
+            Err(errors)
+        }
+    }
+}
+
+fn compile(
+    dir: &Path,
+    path: &Path,
+    grammar: fn() -> tree_sitter::Language,
+) -> Result<(String, tree_sitter::Query), String> {
+    let source = std::fs::read_to_string(dir.join(path))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let query = tree_sitter::Query::new(grammar(), &source)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok((source, query))
+}
+
+// Captures are read by `scope_res_generic`, a definition needs a scoping it knows and the kinds of
+// the definitions and the references have to resolve to a symbol of the namespaces.
+fn capture_error(
+    capture: &str,
+    namespaces: NameSpaces,
+    kinds: &BTreeMap<String, String>,
+) -> Option<String> {
+    let kind = match capture.split('.').collect::<Vec<_>>().as_slice() {
+        ["hoist" | "global" | "local", "definition", kind] | ["local", "reference", kind] => *kind,
+        [scoping, "definition", ..] if !matches!(*scoping, "hoist" | "global" | "local") => {
+            return Some(format!(
+                "capture @{} has the scoping `{}`, one of hoist, global or local is expected",
+                capture, scoping
+            ))
+        }
+        _ => return None,
+    };
+    let symbol = kinds.get(kind).map_or(kind, String::as_str);
+    match namespaces.symbol_id_of(symbol) {
+        Some(_) => None,
+        None => Some(format!(
+            "capture @{} has the kind `{}`, which is in none of the namespaces, map it with `kinds`",
+            capture, symbol
+        )),
+    }
+}
+
+// The configurations of the languages live as long as the process, like the compiled-in ones.
+fn leak_language(language: CheckedLanguage) -> &'static TSLanguageConfig {
+    let CheckedLanguage {
+        base,
+        scopes: (scopes, scope_query),
+        hoverables,
+        kinds,
+    } = language;
+    let hoverable_query = match hoverables {
+        Some((source, query)) => MemoizedQuery::compiled(leak_str(source), query),
+        None => MemoizedQuery::new(base.hoverable_query.source()),
+    };
+    let symbol_kinds = kinds
+        .into_iter()
+        .map(|(kind, symbol)| (leak_str(kind), leak_str(symbol)))
+        .collect::<Vec<_>>();
+
+    Box::leak(Box::new(TSLanguageConfig {
+        language_ids: base.language_ids,
+        file_extensions: base.file_extensions,
+        grammar: base.grammar,
+        scope_query: MemoizedQuery::compiled(leak_str(scopes), scope_query),
+        hoverable_query,
+        namespaces: base.namespaces,
+        symbol_kinds: Box::leak(symbol_kinds.into_boxed_slice()),
+    }))
+}
+
+fn leak_str(value: String) -> &'static str {
+    Box::leak(value.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::CodeFileAST;
+
+    const PYTHON_SCOPES: &str = include_str!("language_support/python/scopes.scm");
+
+    fn pack_dir(manifest: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("query-pack-{}", uuid::Uuid::new_v4()));
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(QUERY_PACK_MANIFEST), manifest).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_python_pack_extracts_the_extra_definitions() {
+        let scopes = format!(
+            "{}\n;; nonlocal a\n(nonlocal_statement\n  (identifier) @local.definition.nonlocal)\n",
+            PYTHON_SCOPES
+        );
+        let dir = pack_dir(
+            r#"
+version = "2024-06-01"
+
+[languages.Python]
+scopes = "python/scopes.scm"
+kinds = { nonlocal = "variable" }
+"#,
+            &[("python/scopes.scm", scopes.as_str())],
+        );
+        let packs = load_query_packs(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(packs.version, "2024-06-01");
+        assert_eq!(packs.language_names(), vec!["Python"]);
+
+        let src = r#"def counter():
+    count = 0
+
+    def increment():
+        nonlocal count
+        count += 1
+        return count
+
+    return increment
+"#
+        .as_bytes();
+        let definitions_of_count = |ast: CodeFileAST| {
+            ast.scope_graph()
+                .unwrap()
+                .symbols_metadata(
+                    src,
+                    "repo".to_string(),
+                    "Python".to_string(),
+                    "counter.py".to_string(),
+                )
+                .into_iter()
+                .filter(|meta| meta.symbol_type == "count")
+                .map(|meta| (meta.range.start.line, meta.symbol))
+                .collect::<Vec<_>>()
+        };
+
+        let compiled = definitions_of_count(CodeFileAST::build_ast(src, "Python").unwrap());
+        assert_eq!(compiled, vec![(1, "variable".to_string())]);
+        let packed =
+            definitions_of_count(CodeFileAST::build_ast_for(src, packs.languages[0]).unwrap());
+        assert_eq!(
+            packed,
+            vec![(1, "variable".to_string()), (4, "variable".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_every_error_of_the_packs_is_reported() {
+        let dir = pack_dir(
+            r#"
+version = "broken"
+
+[languages.Python]
+scopes = "python/scopes.scm"
+
+[languages.Rust]
+scopes = "rust/scopes.scm"
+namespaces = [["function"]]
+
+[languages.Cobol]
+scopes = "cobol/scopes.scm"
+"#,
+            &[
+                (
+                    "python/scopes.scm",
+                    "(function_definition (identifier) @hoist.definition.function",
+                ),
+                (
+                    "rust/scopes.scm",
+                    "(function_item name: (identifier) @local.definition.decorator)\n(function_item name: (identifier) @hoisted.definition)",
+                ),
+            ],
+        );
+        let errors = load_query_packs(&dir).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let languages = errors
+            .iter()
+            .map(|e| e.language.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(languages, vec!["Cobol", "Python", "Rust", "Rust", "Rust"]);
+        assert!(errors[0].message.starts_with("not a supported language"));
+        assert!(errors[1].message.starts_with("python/scopes.scm: "));
+        assert!(errors[2].message.starts_with("namespaces [[\"function\"]]"));
+        assert!(errors[3].message.contains("@local.definition.decorator"));
+        assert!(errors[4].message.contains("the scoping `hoisted`"));
+
+        let missing = pack_dir("version = \"empty\"\n[languages.Python]\n", &[]);
+        let errors = load_query_packs(&missing).unwrap_err();
+        std::fs::remove_dir_all(&missing).unwrap();
+        assert_eq!(errors[0].language, "manifest");
+    }
+}
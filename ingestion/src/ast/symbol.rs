@@ -25,6 +25,9 @@ pub struct SymbolMetaData {
     //pub relative_path: String,
     pub is_global: bool,
     pub node_kind: String,
+    // names of the enclosing definitions, outermost first, e.g. the class of a method.
+    #[serde(default)]
+    pub container_path: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -49,6 +52,9 @@ pub struct SymbolValue {
     pub start_byte: usize,
     pub end_byte: usize,
     pub node_kind: String,
+    // names of the enclosing definitions, outermost first, empty for symbols indexed before it was recorded.
+    #[serde(default)]
+    pub container_path: Vec<String>,
 }
 
 use std::collections::HashMap;
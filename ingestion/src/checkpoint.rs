@@ -0,0 +1,497 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{IngestionError, Result};
+use crate::SemanticPayload;
+
+pub const DEFAULT_CHECKPOINT_EVERY_FILES: usize = 50;
+pub const DEFAULT_CHECKPOINT_EVERY_SECS: u64 = 30;
+
+/// The collections the points of a run are written to, the ones the aliases pointed to when it started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CollectionGeneration {
+    pub chunks: String,
+    pub symbols: String,
+}
+
+/// How far an indexing run got, enough to resume it after a crash without committing a file twice.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexCheckpoint {
+    pub run_id: String,
+    pub repo_name: String,
+    pub branch: String,
+    pub generation: CollectionGeneration,
+    // relative paths whose chunks are in qdrant.
+    pub committed: BTreeSet<String>,
+    // the symbols are written once for the whole repo, after the chunks.
+    pub symbols_committed: bool,
+    // relative paths whose quickwit document was acknowledged, the repo summary and the
+    // terminology suggestions included.
+    #[serde(default)]
+    pub quickwit_documents: BTreeSet<String>,
+}
+
+impl IndexCheckpoint {
+    pub fn new(repo_name: &str, branch: &str, generation: CollectionGeneration) -> Self {
+        Self {
+            run_id: uuid::Uuid::new_v4().to_string(),
+            repo_name: repo_name.to_string(),
+            branch: branch.to_string(),
+            generation,
+            committed: BTreeSet::new(),
+            symbols_committed: false,
+            quickwit_documents: BTreeSet::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read checkpoint {:?}", path))?;
+        let checkpoint = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid checkpoint {:?}", path))?;
+        Ok(Some(checkpoint))
+    }
+
+    // written to a temporary file first, a crash while saving keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // A checkpoint of another repo, branch or collection generation can't be resumed,
+    // its committed paths say nothing about what is in the collections now.
+    fn check_resumable(
+        &self,
+        repo_name: &str,
+        branch: &str,
+        generation: &CollectionGeneration,
+    ) -> anyhow::Result<()> {
+        if self.repo_name != repo_name || self.branch != branch {
+            return Err(anyhow!(
+                "the checkpoint is for {} on {}, not {} on {}",
+                self.repo_name,
+                self.branch,
+                repo_name,
+                branch
+            ));
+        }
+        if self.generation != *generation {
+            return Err(anyhow!(
+                "the checkpoint was written to {:?}, the collections are now {:?}",
+                self.generation,
+                generation
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Where and how often the progress of a run is saved.
+#[derive(Debug, Clone)]
+pub struct CheckpointOptions {
+    pub path: PathBuf,
+    // continue the run saved at `path` instead of starting over.
+    pub resume: bool,
+    pub every_files: usize,
+    pub every: Duration,
+}
+
+impl CheckpointOptions {
+    // next to the repo folder, e.g. `repo/langchain.checkpoint.json`.
+    pub fn default_path(disk_path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.checkpoint.json", disk_path.display()))
+    }
+}
+
+/// Keeps the checkpoint of a run and saves it every `every_files` files or `every`, whichever comes first.
+pub struct Checkpointer {
+    options: CheckpointOptions,
+    checkpoint: IndexCheckpoint,
+    // whether the run continues a crashed one, the files it didn't record may be partly written.
+    resumed: bool,
+    unsaved: usize,
+    last_save: Instant,
+}
+
+impl Checkpointer {
+    /// Loads the checkpoint to resume from, or starts a new run replacing any previous checkpoint.
+    pub fn start(
+        options: CheckpointOptions,
+        repo_name: &str,
+        branch: &str,
+        generation: CollectionGeneration,
+    ) -> anyhow::Result<Self> {
+        let previous = IndexCheckpoint::load(&options.path)?;
+        let (checkpoint, resumed) = match previous {
+            Some(previous) if options.resume => {
+                previous
+                    .check_resumable(repo_name, branch, &generation)
+                    .with_context(|| format!("Can't resume from {:?}", options.path))?;
+                log::info!(
+                    "Resuming run {} with {} files already committed",
+                    previous.run_id,
+                    previous.committed.len()
+                );
+                (previous, true)
+            }
+            previous => {
+                if options.resume {
+                    log::info!("No checkpoint at {:?}, indexing from the start", options.path);
+                } else if previous.is_some() {
+                    log::warn!(
+                        "Discarding the checkpoint at {:?}, pass --resume to continue it",
+                        options.path
+                    );
+                }
+                (IndexCheckpoint::new(repo_name, branch, generation), false)
+            }
+        };
+
+        let checkpointer = Self {
+            options,
+            checkpoint,
+            resumed,
+            unsaved: 0,
+            last_save: Instant::now(),
+        };
+        checkpointer.save()?;
+        Ok(checkpointer)
+    }
+
+    pub fn checkpoint(&self) -> &IndexCheckpoint {
+        &self.checkpoint
+    }
+
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    pub fn is_committed(&self, path: &str) -> bool {
+        self.checkpoint.committed.contains(path)
+    }
+
+    pub fn record(&mut self, path: &str) -> anyhow::Result<()> {
+        self.checkpoint.committed.insert(path.to_string());
+        self.unsaved += 1;
+        self.save_if_due()
+    }
+
+    pub fn is_document_committed(&self, path: &str) -> bool {
+        self.checkpoint.quickwit_documents.contains(path)
+    }
+
+    /// Records the documents quickwit acknowledged, saved as often as the committed files.
+    pub fn record_documents(&mut self, paths: Vec<String>) -> anyhow::Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        self.unsaved += paths.len();
+        self.checkpoint.quickwit_documents.extend(paths);
+        self.save_if_due()
+    }
+
+    fn save_if_due(&mut self) -> anyhow::Result<()> {
+        if self.unsaved >= self.options.every_files
+            || self.last_save.elapsed() >= self.options.every
+        {
+            self.save()?;
+            self.unsaved = 0;
+            self.last_save = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn symbols_committed(&mut self) -> anyhow::Result<()> {
+        self.checkpoint.symbols_committed = true;
+        self.save()
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.checkpoint.save(&self.options.path)
+    }
+
+    /// Saves the unsaved progress, the checkpoint is only deleted once every file is committed,
+    /// so a run with failed files can be resumed to retry them.
+    pub fn finish(self, failed: usize) -> anyhow::Result<()> {
+        if failed > 0 {
+            self.save()?;
+            log::warn!(
+                "{} files failed to commit, run again with --resume to retry them",
+                failed
+            );
+            return Ok(());
+        }
+        std::fs::remove_file(&self.options.path)
+            .with_context(|| format!("Failed to delete checkpoint {:?}", self.options.path))
+    }
+}
+
+/// Writes the chunks of a file to the chunk collection.
+#[async_trait(?Send)]
+pub trait FileCommitter {
+    // Removes whatever an interrupted run wrote for the file, so it isn't stored twice.
+    async fn discard(&mut self, path: &str) -> anyhow::Result<()>;
+    async fn commit(&mut self, payload: &SemanticPayload) -> anyhow::Result<()>;
+}
+
+/// Commits the files the checkpoint doesn't have yet and records each of them.
+/// A file failing to commit is left out of the checkpoint and the others are still committed,
+/// the errors of the failed files are returned.
+pub async fn commit_files<C: FileCommitter>(
+    payloads: &[SemanticPayload],
+    committer: &mut C,
+    checkpointer: &mut Checkpointer,
+) -> Result<Vec<IngestionError>> {
+    let mut failed = Vec::new();
+    for payload in payloads {
+        if checkpointer.is_committed(&payload.path) {
+            continue;
+        }
+        // files committed after the last save of the crashed run aren't in the checkpoint.
+        if checkpointer.resumed() {
+            committer
+                .discard(&payload.path)
+                .await
+                .map_err(IngestionError::QdrantCommit)?;
+        }
+        match committer.commit(payload).await {
+            Ok(()) => checkpointer
+                .record(&payload.path)
+                .map_err(IngestionError::Checkpoint)?,
+            Err(e) => {
+                log::error!("Failed to commit {}: {:?}", payload.path, e);
+                failed.push(IngestionError::per_file(&payload.path, e));
+            }
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::{Arc, Mutex};
+
+    use futures::FutureExt;
+
+    use super::*;
+
+    // chunk points per path, as many as the file has lines.
+    #[derive(Clone, Default)]
+    struct MockCollection {
+        points: Arc<Mutex<HashMap<String, usize>>>,
+        committed: Arc<Mutex<Vec<String>>>,
+        // panics after that many commits, like a crashed process.
+        crash_after: Option<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl FileCommitter for MockCollection {
+        async fn discard(&mut self, path: &str) -> anyhow::Result<()> {
+            self.points.lock().unwrap().remove(path);
+            Ok(())
+        }
+
+        async fn commit(&mut self, payload: &SemanticPayload) -> anyhow::Result<()> {
+            if payload.buffer.is_empty() {
+                return Err(anyhow!("nothing to embed"));
+            }
+            let done = self.committed.lock().unwrap().len();
+            let mut points = self.points.lock().unwrap();
+            let file_points = points.entry(payload.path.clone()).or_default();
+            if self.crash_after == Some(done) {
+                // the first chunk of the file made it in before the crash.
+                *file_points += 1;
+                drop(points);
+                panic!("crashed while committing {}", payload.path);
+            }
+            *file_points += payload.buffer.lines().count();
+            self.committed.lock().unwrap().push(payload.path.clone());
+            Ok(())
+        }
+    }
+
+    fn payloads(count: usize) -> Vec<SemanticPayload> {
+        (0..count)
+            .map(|i| SemanticPayload {
+                path: format!("src/file_{}.rs", i),
+                buffer: "fn a() {}\nfn b() {}\nfn c() {}\n".to_string(),
+                semantic_hash: format!("hash_{}", i),
+                language: "Rust".to_string(),
+                doc_comments: Vec::new(),
+                last_modified: None,
+            })
+            .collect()
+    }
+
+    fn generation() -> CollectionGeneration {
+        CollectionGeneration {
+            chunks: "documents".to_string(),
+            symbols: "documents_symbol".to_string(),
+        }
+    }
+
+    fn options(resume: bool, every_files: usize, path: &Path) -> CheckpointOptions {
+        CheckpointOptions {
+            path: path.to_path_buf(),
+            resume,
+            every_files,
+            every: Duration::from_secs(3600),
+        }
+    }
+
+    fn checkpoint_path() -> PathBuf {
+        std::env::temp_dir().join(format!("index-{}.checkpoint.json", uuid::Uuid::new_v4()))
+    }
+
+    // runs a first pass that panics after `crash_after` commits.
+    async fn crashed_run(
+        collection: &MockCollection,
+        crash_after: usize,
+        every_files: usize,
+        path: &Path,
+    ) {
+        let mut collection = MockCollection {
+            crash_after: Some(crash_after),
+            ..collection.clone()
+        };
+        let options = options(false, every_files, path);
+        let run = AssertUnwindSafe(async move {
+            let payloads = payloads(10);
+            let mut checkpointer =
+                Checkpointer::start(options, "repo", "main", generation()).unwrap();
+            commit_files(&payloads, &mut collection, &mut checkpointer).await
+        });
+        assert!(run.catch_unwind().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resumed_run_commits_the_remaining_files() {
+        let path = checkpoint_path();
+        let collection = MockCollection::default();
+        crashed_run(&collection, 4, 1, &path).await;
+
+        let saved = IndexCheckpoint::load(&path).unwrap().unwrap();
+        assert_eq!(saved.committed.len(), 4);
+
+        collection.committed.lock().unwrap().clear();
+        let mut checkpointer =
+            Checkpointer::start(options(true, 1, &path), "repo", "main", generation()).unwrap();
+        assert_eq!(checkpointer.checkpoint().run_id, saved.run_id);
+        let failed = commit_files(&payloads(10), &mut collection.clone(), &mut checkpointer)
+            .await
+            .unwrap();
+        checkpointer.finish(failed.len()).unwrap();
+
+        let expected = (4..10).map(|i| format!("src/file_{}.rs", i)).collect::<Vec<_>>();
+        assert_eq!(*collection.committed.lock().unwrap(), expected);
+        // three points per file, the partly written file isn't stored twice.
+        let points = collection.points.lock().unwrap();
+        assert_eq!(points.len(), 10);
+        assert!(points.values().all(|count| *count == 3));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_files_committed_after_the_last_save_are_not_duplicated() {
+        let path = checkpoint_path();
+        let collection = MockCollection::default();
+        // saved after 3 files, files 4 and 5 are committed but not in the checkpoint.
+        crashed_run(&collection, 5, 3, &path).await;
+        assert_eq!(IndexCheckpoint::load(&path).unwrap().unwrap().committed.len(), 3);
+
+        let mut checkpointer =
+            Checkpointer::start(options(true, 3, &path), "repo", "main", generation()).unwrap();
+        let failed = commit_files(&payloads(10), &mut collection.clone(), &mut checkpointer)
+            .await
+            .unwrap();
+        checkpointer.finish(failed.len()).unwrap();
+
+        let points = collection.points.lock().unwrap();
+        assert_eq!(points.len(), 10);
+        assert!(points.values().all(|count| *count == 3));
+    }
+
+    #[tokio::test]
+    async fn test_failed_files_keep_the_checkpoint() {
+        let path = checkpoint_path();
+        let mut files = payloads(3);
+        files[1].buffer.clear();
+        let mut checkpointer =
+            Checkpointer::start(options(false, 50, &path), "repo", "main", generation()).unwrap();
+        let failed = commit_files(&files, &mut MockCollection::default(), &mut checkpointer)
+            .await
+            .unwrap();
+        checkpointer.finish(failed.len()).unwrap();
+
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(
+            &failed[0],
+            IngestionError::PerFile { path, .. } if path == "src/file_1.rs"
+        ));
+        let saved = IndexCheckpoint::load(&path).unwrap().unwrap();
+        assert!(!saved.committed.contains("src/file_1.rs"));
+        assert_eq!(saved.committed.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_acknowledged_documents_are_saved_per_file() {
+        let path = checkpoint_path();
+        let mut checkpointer =
+            Checkpointer::start(options(false, 2, &path), "repo", "main", generation()).unwrap();
+        checkpointer
+            .record_documents(vec!["src/a.rs".to_string()])
+            .unwrap();
+        // saved once two files are recorded, whether chunks or documents.
+        assert!(IndexCheckpoint::load(&path)
+            .unwrap()
+            .unwrap()
+            .quickwit_documents
+            .is_empty());
+        checkpointer
+            .record_documents(vec!["src/b.rs".to_string()])
+            .unwrap();
+
+        let resumed =
+            Checkpointer::start(options(true, 2, &path), "repo", "main", generation()).unwrap();
+        assert!(resumed.is_document_committed("src/a.rs"));
+        assert!(resumed.is_document_committed("src/b.rs"));
+        assert!(!resumed.is_document_committed("src/c.rs"));
+        resumed.finish(0).unwrap();
+
+        // a checkpoint saved before the documents were recorded per file sends them all again.
+        let mut old =
+            serde_json::to_value(IndexCheckpoint::new("repo", "main", generation())).unwrap();
+        let fields = old.as_object_mut().unwrap();
+        fields.remove("quickwit_documents");
+        fields.insert("quickwit_committed".to_string(), true.into());
+        let old: IndexCheckpoint = serde_json::from_value(old).unwrap();
+        assert!(old.quickwit_documents.is_empty());
+    }
+
+    #[test]
+    fn test_checkpoint_of_another_generation_is_not_resumed() {
+        let path = checkpoint_path();
+        IndexCheckpoint::new("repo", "main", generation()).save(&path).unwrap();
+
+        let switched = CollectionGeneration {
+            chunks: "documents_v2".to_string(),
+            symbols: "documents_symbol_v2".to_string(),
+        };
+        assert!(Checkpointer::start(options(true, 1, &path), "repo", "main", switched).is_err());
+        assert!(Checkpointer::start(options(true, 1, &path), "other", "main", generation()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
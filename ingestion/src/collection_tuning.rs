@@ -0,0 +1,457 @@
+// Qdrant parameters of the collections, set with `QDRANT_COLLECTION_TUNING`. A setting left out
+// keeps the default of qdrant, so without the variable the collections are created as before.
+// A collection created by the run gets every setting. An existing one only gets those qdrant
+// applies to the points it already has, the storage settings are reported instead.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use qdrant_client::prelude::QdrantClient;
+use qdrant_client::qdrant::{
+    quantization_config, quantization_config_diff, vectors_config, CollectionConfig,
+    CreateCollection, Disabled, HnswConfigDiff, OptimizersConfigDiff, QuantizationConfig,
+    QuantizationConfigDiff, QuantizationType, ScalarQuantization,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CollectionTuning {
+    // edges per node of the HNSW graph.
+    pub m: Option<u64>,
+    // neighbours considered while the HNSW graph is built.
+    pub ef_construct: Option<u64>,
+    pub on_disk_vectors: Option<bool>,
+    pub on_disk_payload: Option<bool>,
+    // kilobytes of vectors a segment holds before it is indexed, and before it is memory mapped.
+    pub indexing_threshold: Option<u64>,
+    pub memmap_threshold: Option<u64>,
+    // int8 scalar quantization of the vectors, `false` drops it from an existing collection.
+    pub scalar_int8: Option<bool>,
+}
+
+impl CollectionTuning {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets the parameters on the request creating a collection.
+    pub fn apply(&self, create: &mut CreateCollection) {
+        create.hnsw_config = self.hnsw_config();
+        create.optimizers_config = self.optimizers_config();
+        create.on_disk_payload = self.on_disk_payload;
+        create.quantization_config =
+            self.scalar_int8
+                .filter(|quantized| *quantized)
+                .map(|_| QuantizationConfig {
+                    quantization: Some(quantization_config::Quantization::Scalar(scalar_int8())),
+                });
+        if let Some(vectors_config::Config::Params(params)) = create
+            .vectors_config
+            .as_mut()
+            .and_then(|vectors| vectors.config.as_mut())
+        {
+            params.on_disk = self.on_disk_vectors;
+        }
+    }
+
+    /// What changing an existing collection to these parameters takes. The settings qdrant only
+    /// applies to new segments are left out with a warning.
+    pub fn plan_update(&self, collection: &str, current: &CollectionConfig) -> TuningUpdate {
+        let mut update = TuningUpdate {
+            applied: *self,
+            ..Default::default()
+        };
+
+        let hnsw = current.hnsw_config.clone().unwrap_or_default();
+        if changes(self.m, hnsw.m) || changes(self.ef_construct, hnsw.ef_construct) {
+            update.hnsw = self.hnsw_config();
+        }
+        let optimizers = current.optimizer_config.clone().unwrap_or_default();
+        if changes(self.indexing_threshold, optimizers.indexing_threshold)
+            || changes(self.memmap_threshold, optimizers.memmap_threshold)
+        {
+            update.optimizers = self.optimizers_config();
+        }
+        let quantized = matches!(
+            current.quantization_config.as_ref().and_then(|config| config.quantization.as_ref()),
+            Some(quantization_config::Quantization::Scalar(scalar))
+                if scalar.r#type == QuantizationType::Int8 as i32
+        );
+        update.quantization = match self.scalar_int8 {
+            Some(true) if !quantized => {
+                Some(quantization_config_diff::Quantization::Scalar(scalar_int8()))
+            }
+            Some(false) if quantized => Some(quantization_config_diff::Quantization::Disabled(
+                Disabled {},
+            )),
+            _ => None,
+        }
+        .map(|quantization| QuantizationConfigDiff {
+            quantization: Some(quantization),
+        });
+
+        let params = current.params.clone().unwrap_or_default();
+        let vectors_on_disk = match params.vectors_config.and_then(|vectors| vectors.config) {
+            Some(vectors_config::Config::Params(vectors)) => vectors.on_disk.unwrap_or(false),
+            _ => false,
+        };
+        if let Some(on_disk) = self
+            .on_disk_vectors
+            .filter(|on_disk| *on_disk != vectors_on_disk)
+        {
+            update
+                .warnings
+                .push(storage_warning(collection, "on_disk_vectors", on_disk));
+            update.applied.on_disk_vectors = None;
+        }
+        if let Some(on_disk) = self
+            .on_disk_payload
+            .filter(|on_disk| *on_disk != params.on_disk_payload)
+        {
+            update
+                .warnings
+                .push(storage_warning(collection, "on_disk_payload", on_disk));
+            update.applied.on_disk_payload = None;
+        }
+        update
+    }
+
+    fn hnsw_config(&self) -> Option<HnswConfigDiff> {
+        (self.m.is_some() || self.ef_construct.is_some()).then(|| HnswConfigDiff {
+            m: self.m,
+            ef_construct: self.ef_construct,
+            ..Default::default()
+        })
+    }
+
+    fn optimizers_config(&self) -> Option<OptimizersConfigDiff> {
+        (self.indexing_threshold.is_some() || self.memmap_threshold.is_some()).then(|| {
+            OptimizersConfigDiff {
+                indexing_threshold: self.indexing_threshold,
+                memmap_threshold: self.memmap_threshold,
+                ..Default::default()
+            }
+        })
+    }
+}
+
+/// The changes of a live collection, and the parameters it has once they are made.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TuningUpdate {
+    pub hnsw: Option<HnswConfigDiff>,
+    pub optimizers: Option<OptimizersConfigDiff>,
+    pub quantization: Option<QuantizationConfigDiff>,
+    pub applied: CollectionTuning,
+    pub warnings: Vec<String>,
+}
+
+impl TuningUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.hnsw.is_none() && self.optimizers.is_none() && self.quantization.is_none()
+    }
+}
+
+/// Gives an existing collection the parameters of `tuning` qdrant can change on it. Nothing here
+/// fails the run, the collection is still searchable as it is. Returns the parameters it has.
+pub async fn tune_collection(
+    client: &QdrantClient,
+    collection: &str,
+    tuning: &CollectionTuning,
+) -> CollectionTuning {
+    if tuning.is_default() {
+        return *tuning;
+    }
+    let current = match client.collection_info(collection).await {
+        Ok(response) => response.result.and_then(|info| info.config),
+        Err(e) => {
+            log::warn!(
+                "Failed to read the parameters of {}, leaving them as they are: {}",
+                collection,
+                e
+            );
+            return CollectionTuning::default();
+        }
+    };
+    let Some(current) = current else {
+        log::warn!(
+            "Qdrant returned no parameters for {}, leaving them as they are",
+            collection
+        );
+        return CollectionTuning::default();
+    };
+
+    let update = tuning.plan_update(collection, &current);
+    for warning in &update.warnings {
+        log::warn!("{}", warning);
+    }
+    if update.is_empty() {
+        return update.applied;
+    }
+    match client
+        .update_collection(
+            collection,
+            update.optimizers.as_ref(),
+            None,
+            update.hnsw.as_ref(),
+            None,
+            update.quantization.as_ref(),
+        )
+        .await
+    {
+        Ok(_) => {
+            log::info!(
+                "Updated the parameters of {} to {}",
+                collection,
+                update.applied
+            );
+            update.applied
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to update the parameters of {}, leaving them as they are: {}",
+                collection,
+                e
+            );
+            CollectionTuning::default()
+        }
+    }
+}
+
+impl FromStr for CollectionTuning {
+    type Err = anyhow::Error;
+
+    /// The settings left out keep the default of qdrant.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tuning = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected <setting>=<value>, got {}", pair))?;
+            let (name, value) = (name.trim(), value.trim());
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("{} must be a number", name))
+            };
+            let flag = || {
+                value
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("{} must be true or false", name))
+            };
+            match name {
+                "m" => tuning.m = Some(number()?),
+                "ef_construct" => tuning.ef_construct = Some(number()?),
+                "on_disk_vectors" => tuning.on_disk_vectors = Some(flag()?),
+                "on_disk_payload" => tuning.on_disk_payload = Some(flag()?),
+                "indexing_threshold" => tuning.indexing_threshold = Some(number()?),
+                "memmap_threshold" => tuning.memmap_threshold = Some(number()?),
+                "scalar_int8" => tuning.scalar_int8 = Some(flag()?),
+                other => return Err(anyhow!("unknown collection tuning setting {}", other)),
+            }
+        }
+        if let Some(ef_construct) = tuning.ef_construct.filter(|ef| *ef < 4) {
+            return Err(anyhow!(
+                "ef_construct must be at least 4, got {}",
+                ef_construct
+            ));
+        }
+        Ok(tuning)
+    }
+}
+
+// The settings that are set, in the form they are configured with.
+impl Display for CollectionTuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let settings = [
+            ("m", self.m.map(|m| m.to_string())),
+            ("ef_construct", self.ef_construct.map(|ef| ef.to_string())),
+            (
+                "on_disk_vectors",
+                self.on_disk_vectors.map(|on_disk| on_disk.to_string()),
+            ),
+            (
+                "on_disk_payload",
+                self.on_disk_payload.map(|on_disk| on_disk.to_string()),
+            ),
+            (
+                "indexing_threshold",
+                self.indexing_threshold.map(|kb| kb.to_string()),
+            ),
+            (
+                "memmap_threshold",
+                self.memmap_threshold.map(|kb| kb.to_string()),
+            ),
+            (
+                "scalar_int8",
+                self.scalar_int8.map(|quantized| quantized.to_string()),
+            ),
+        ];
+        let settings = settings
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{}={}", name, value?)))
+            .collect::<Vec<_>>();
+        f.write_str(&settings.join(","))
+    }
+}
+
+fn changes(configured: Option<u64>, current: Option<u64>) -> bool {
+    configured.is_some() && configured != current
+}
+
+fn scalar_int8() -> ScalarQuantization {
+    ScalarQuantization {
+        r#type: QuantizationType::Int8.into(),
+        ..Default::default()
+    }
+}
+
+// Qdrant only writes the segments created after the change with the new storage, the points of
+// the collection would be split across both.
+fn storage_warning(collection: &str, setting: &str, value: bool) -> String {
+    format!(
+        "{}={} is not applied to the existing collection {}, qdrant would only store its new segments that way. Re-create the collection to apply it, e.g. with migrate-embeddings",
+        setting, value, collection
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Repository;
+    use qdrant_client::qdrant::{CollectionParams, VectorParams, VectorsConfig};
+
+    fn tuning() -> CollectionTuning {
+        "m=32, ef_construct=200, on_disk_vectors=true, on_disk_payload=true, indexing_threshold=40000, memmap_threshold=80000, scalar_int8=true"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_settings_are_parsed_and_rendered_back() {
+        let tuning = tuning();
+        assert_eq!(tuning.m, Some(32));
+        assert_eq!(tuning.scalar_int8, Some(true));
+        assert_eq!(
+            tuning.to_string().parse::<CollectionTuning>().unwrap(),
+            tuning
+        );
+        assert_eq!(CollectionTuning::default().to_string(), "");
+        assert_eq!(
+            "".parse::<CollectionTuning>().unwrap(),
+            CollectionTuning::default()
+        );
+
+        assert!("m=many".parse::<CollectionTuning>().is_err());
+        assert!("ef_construct=2".parse::<CollectionTuning>().is_err());
+        assert!("product_quantization=true"
+            .parse::<CollectionTuning>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_create_collection_carries_the_configured_parameters() {
+        let create = Repository::collection_config("documents_v2".to_string(), 384, &tuning());
+
+        let hnsw = create.hnsw_config.unwrap();
+        assert_eq!((hnsw.m, hnsw.ef_construct), (Some(32), Some(200)));
+        let optimizers = create.optimizers_config.unwrap();
+        assert_eq!(optimizers.indexing_threshold, Some(40000));
+        assert_eq!(optimizers.memmap_threshold, Some(80000));
+        assert_eq!(create.on_disk_payload, Some(true));
+        match create.quantization_config.unwrap().quantization {
+            Some(quantization_config::Quantization::Scalar(scalar)) => {
+                assert_eq!(scalar.r#type, QuantizationType::Int8 as i32)
+            }
+            other => panic!("expected scalar quantization, got {:?}", other),
+        }
+        match create.vectors_config.unwrap().config {
+            Some(vectors_config::Config::Params(params)) => {
+                assert_eq!(params.size, 384);
+                assert_eq!(params.on_disk, Some(true));
+            }
+            other => panic!("expected vector params, got {:?}", other),
+        }
+
+        // without settings the request is the one sent before the tuning existed.
+        let plain = Repository::collection_config(
+            "documents_v2".to_string(),
+            384,
+            &CollectionTuning::default(),
+        );
+        assert_eq!(plain.hnsw_config, None);
+        assert_eq!(plain.optimizers_config, None);
+        assert_eq!(plain.on_disk_payload, None);
+        assert_eq!(plain.quantization_config, None);
+    }
+
+    #[test]
+    fn test_storage_settings_of_an_existing_collection_are_warned_about() {
+        let current = CollectionConfig {
+            params: Some(CollectionParams {
+                on_disk_payload: false,
+                vectors_config: Some(VectorsConfig {
+                    config: Some(vectors_config::Config::Params(VectorParams {
+                        size: 384,
+                        ..Default::default()
+                    })),
+                }),
+                ..Default::default()
+            }),
+            hnsw_config: Some(HnswConfigDiff {
+                m: Some(16),
+                ef_construct: Some(100),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let update = tuning().plan_update("documents", &current);
+
+        assert_eq!(update.hnsw.as_ref().unwrap().m, Some(32));
+        assert_eq!(
+            update.optimizers.as_ref().unwrap().indexing_threshold,
+            Some(40000)
+        );
+        assert!(matches!(
+            update.quantization.as_ref().unwrap().quantization,
+            Some(quantization_config_diff::Quantization::Scalar(_))
+        ));
+        assert_eq!(update.warnings.len(), 2);
+        assert!(update.warnings[0].starts_with(
+            "on_disk_vectors=true is not applied to the existing collection documents"
+        ));
+        assert!(update.warnings[1].starts_with("on_disk_payload=true"));
+        assert_eq!(
+            update.applied.to_string(),
+            "m=32,ef_construct=200,indexing_threshold=40000,memmap_threshold=80000,scalar_int8=true"
+        );
+
+        // a collection that already has the parameters is left alone.
+        let same = "m=16,on_disk_payload=false"
+            .parse::<CollectionTuning>()
+            .unwrap();
+        let update = same.plan_update("documents", &current);
+        assert!(update.is_empty());
+        assert!(update.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_quantization_is_dropped_when_turned_off() {
+        let current = CollectionConfig {
+            quantization_config: Some(QuantizationConfig {
+                quantization: Some(quantization_config::Quantization::Scalar(scalar_int8())),
+            }),
+            ..Default::default()
+        };
+        let off = "scalar_int8=false".parse::<CollectionTuning>().unwrap();
+        assert!(matches!(
+            off.plan_update("symbols", &current)
+                .quantization
+                .unwrap()
+                .quantization,
+            Some(quantization_config_diff::Quantization::Disabled(_))
+        ));
+        let on = "scalar_int8=true".parse::<CollectionTuning>().unwrap();
+        assert!(on.plan_update("symbols", &current).is_empty());
+    }
+}
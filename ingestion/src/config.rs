@@ -1,18 +1,103 @@
 use dotenv::dotenv;
 use lazy_static::lazy_static;
+use std::collections::BTreeMap;
 use std::env;
 use std::sync::RwLock;
 
+use common::compression::TextCompression;
 use common::docker::is_running_in_docker;
 
+use crate::collection_tuning::CollectionTuning;
+use crate::key_files::KeyFileWeights;
+use crate::semantic_index::chunk_quality::ChunkQualityThresholds;
+use crate::semantic_index::chunking::ChunkOverlap;
+use crate::size_limits::{SizeLimitOverride, SizeLimits};
+
 #[derive(Debug, Default)]
 pub struct Config {
     pub qdrant_url: String,
     pub quickwit_url: String,
-    pub yaml_config_path: String,
+    // where the generated quickwit index config is written, not written when unset.
+    pub yaml_config_path: Option<String>,
     pub model_path: String,
+    // max number of occurrences stored on a single symbol point, the rest is only counted.
+    pub symbol_occurrence_limit: usize,
+    // symbol names too common to be worth an embedding, chunk search covers them instead.
+    pub symbol_stop_list: Vec<String>,
+    pub size_limits: SizeLimits,
+    // tenant owning the indexed repo, stored on every document so search can be scoped to it.
+    pub tenant_id: String,
+    // how the chunk text is written to the qdrant payloads.
+    pub payload_compression: TextCompression,
+    // also writes every doc comment as a chunk of its own, next to the code chunk carrying it.
+    pub index_doc_chunks: bool,
+    // extensions of the config files indexed and chunked on their keys, the default ones when unset.
+    pub config_file_extensions: Option<Vec<String>>,
+    // quickwit batches sent at once while the repo is walked, as many more wait for their turn.
+    pub quickwit_max_in_flight_batches: usize,
+    // where the embeddings of the chunks are cached across runs, only cached in memory when unset.
+    pub embedding_cache_dir: Option<String>,
+    // entries of the embedding cache on disk, the least recently used ones are evicted.
+    pub embedding_cache_max_entries: usize,
+    // weights the key files of the repo summary are ranked with.
+    pub key_file_weights: KeyFileWeights,
+    // thresholds under which a chunk isn't embedded, it is still in the quickwit content.
+    pub chunk_quality_thresholds: ChunkQualityThresholds,
+    // how consecutive chunks of a file overlap.
+    pub chunk_overlap: ChunkOverlap,
+    // directory of the tree-sitter query packs replacing the compiled-in queries of their languages.
+    pub query_packs_dir: Option<String>,
+    // commits walked back from the indexed one for when each file was last changed.
+    pub last_modified_max_commits: usize,
+    // qdrant parameters of the collections, qdrant's defaults when unset.
+    pub collection_tuning: CollectionTuning,
+    // searches sent to a migrated collection once the alias points at it, none when unset.
+    pub qdrant_warmup_searches: usize,
+    // url of the files in the web UI of the repo host, recorded in the run manifest so the answers
+    // link to the indexed commit. The links are left relative when unset.
+    pub web_url_template: Option<String>,
 }
 
+const DEFAULT_SYMBOL_OCCURRENCE_LIMIT: usize = 50;
+const DEFAULT_SYMBOL_STOP_LIST: &str =
+    "new,init,get,set,default,from,into,main,run,build,len,is_empty,clone,to_string,fmt,drop,test,setup,update,value,data";
+const DEFAULT_CONFIG_FILE_EXTENSIONS: &str = "yaml,yml,toml,json";
+const DEFAULT_QUICKWIT_MAX_IN_FLIGHT_BATCHES: usize = 10;
+const DEFAULT_EMBEDDING_CACHE_MAX_ENTRIES: usize = 1_000_000;
+const DEFAULT_LAST_MODIFIED_MAX_COMMITS: usize = 10_000;
+
+// Environment of the ingestion recorded in the run manifest, the secrets are redacted.
+const MANIFEST_ENV_VARS: &[&str] = &[
+    "SEMANTIC_DB_URL",
+    "QUICKWIT_DB_URL",
+    "QUICKWIT_YAML_CONFIG_PATH",
+    "MODEL_DIR",
+    "SYMBOL_OCCURRENCE_LIMIT",
+    "SYMBOL_STOP_LIST",
+    "MAX_FILE_BYTES",
+    "MAX_LINE_COUNT",
+    "SIZE_LIMIT_OVERRIDES",
+    "TENANT_ID",
+    "PAYLOAD_COMPRESSION",
+    "INDEX_DOC_CHUNKS",
+    "CONFIG_FILE_EXTENSIONS",
+    "QUICKWIT_MAX_IN_FLIGHT_BATCHES",
+    "EMBEDDING_CACHE_DIR",
+    "EMBEDDING_CACHE_MAX_ENTRIES",
+    "KEY_FILE_WEIGHTS",
+    "CHUNK_QUALITY_THRESHOLDS",
+    "CHUNK_OVERLAP",
+    "QUERY_PACKS_DIR",
+    "LAST_MODIFIED_MAX_COMMITS",
+    "QDRANT_COLLECTION_TUNING",
+    "QDRANT_WARMUP_SEARCHES",
+    "WEB_URL_TEMPLATE",
+    "SERVICE_API_KEY",
+    "QDRANT_API_KEY",
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_HEADERS",
+];
+
 lazy_static! {
     static ref GLOBAL_CONFIG: RwLock<Config> = RwLock::new(Config::default());
 }
@@ -30,8 +115,95 @@ pub fn initialize_config(env_file: Option<String>) {
         quickwit_url: env::var("QUICKWIT_DB_URL")
             .expect("`QUICKWIT_URL` environment variable must be set"),
         yaml_config_path: env::var("QUICKWIT_YAML_CONFIG_PATH")
-            .expect("`YAML_CONFIG_PATH` environment variable must be set"),
+            .ok()
+            .filter(|path| !path.trim().is_empty()),
         model_path: env::var("MODEL_DIR").expect("`MODEL_PATH` environment variable must be set"),
+        symbol_occurrence_limit: env::var("SYMBOL_OCCURRENCE_LIMIT")
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(DEFAULT_SYMBOL_OCCURRENCE_LIMIT),
+        symbol_stop_list: parse_stop_list(
+            &env::var("SYMBOL_STOP_LIST").unwrap_or_else(|_| DEFAULT_SYMBOL_STOP_LIST.to_string()),
+        ),
+        size_limits: size_limits_from_env(),
+        tenant_id: env::var("TENANT_ID")
+            .ok()
+            .filter(|tenant_id| !tenant_id.is_empty())
+            .unwrap_or_else(|| common::auth::DEFAULT_TENANT_ID.to_string()),
+        payload_compression: env::var("PAYLOAD_COMPRESSION")
+            .ok()
+            .filter(|compression| !compression.trim().is_empty())
+            .map(|compression| {
+                compression
+                    .parse()
+                    .expect("PAYLOAD_COMPRESSION must be none, zstd or zstd:<level>")
+            })
+            .unwrap_or_default(),
+        index_doc_chunks: env::var("INDEX_DOC_CHUNKS")
+            .map(|enabled| enabled.trim().eq_ignore_ascii_case("true"))
+            .unwrap_or(false),
+        config_file_extensions: env::var("CONFIG_FILE_EXTENSIONS")
+            .ok()
+            .map(|extensions| parse_extensions(&extensions)),
+        quickwit_max_in_flight_batches: env::var("QUICKWIT_MAX_IN_FLIGHT_BATCHES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(DEFAULT_QUICKWIT_MAX_IN_FLIGHT_BATCHES),
+        embedding_cache_dir: env::var("EMBEDDING_CACHE_DIR")
+            .ok()
+            .filter(|dir| !dir.trim().is_empty()),
+        embedding_cache_max_entries: env::var("EMBEDDING_CACHE_MAX_ENTRIES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(DEFAULT_EMBEDDING_CACHE_MAX_ENTRIES),
+        key_file_weights: env::var("KEY_FILE_WEIGHTS")
+            .ok()
+            .map(|weights| {
+                weights
+                    .parse()
+                    .expect("KEY_FILE_WEIGHTS must be <signal>=<weight> pairs of symbols, references, path and size")
+            })
+            .unwrap_or_default(),
+        chunk_quality_thresholds: env::var("CHUNK_QUALITY_THRESHOLDS")
+            .ok()
+            .map(|thresholds| {
+                thresholds
+                    .parse()
+                    .expect("CHUNK_QUALITY_THRESHOLDS must be <feature>=<threshold> pairs of non_whitespace, code_tokens and comments")
+            })
+            .unwrap_or_default(),
+        chunk_overlap: env::var("CHUNK_OVERLAP")
+            .ok()
+            .map(|overlap| {
+                overlap
+                    .parse()
+                    .expect("CHUNK_OVERLAP must be <setting>=<value> pairs of target, min and max")
+            })
+            .unwrap_or_default(),
+        query_packs_dir: env::var("QUERY_PACKS_DIR")
+            .ok()
+            .filter(|dir| !dir.trim().is_empty()),
+        last_modified_max_commits: env::var("LAST_MODIFIED_MAX_COMMITS")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_LAST_MODIFIED_MAX_COMMITS),
+        collection_tuning: env::var("QDRANT_COLLECTION_TUNING")
+            .ok()
+            .map(|tuning| {
+                tuning
+                    .parse()
+                    .expect("QDRANT_COLLECTION_TUNING must be <setting>=<value> pairs of m, ef_construct, on_disk_vectors, on_disk_payload, indexing_threshold, memmap_threshold and scalar_int8")
+            })
+            .unwrap_or_default(),
+        qdrant_warmup_searches: env::var("QDRANT_WARMUP_SEARCHES")
+            .ok()
+            .and_then(|searches| searches.parse().ok())
+            .unwrap_or_default(),
+        web_url_template: env::var("WEB_URL_TEMPLATE")
+            .ok()
+            .filter(|template| !template.trim().is_empty()),
     };
 
     let mut global_config = GLOBAL_CONFIG.write().expect("Failed to acquire write lock");
@@ -46,10 +218,165 @@ pub fn get_quickwit_url() -> String {
     GLOBAL_CONFIG.read().unwrap().quickwit_url.clone()
 }
 
-pub fn get_yaml_config_path() -> String {
+pub fn get_yaml_config_path() -> Option<String> {
     GLOBAL_CONFIG.read().unwrap().yaml_config_path.clone()
 }
 
 pub fn get_model_path() -> String {
     GLOBAL_CONFIG.read().unwrap().model_path.clone()
 }
+
+pub fn get_symbol_occurrence_limit() -> usize {
+    GLOBAL_CONFIG.read().unwrap().symbol_occurrence_limit
+}
+
+pub fn get_symbol_stop_list() -> Vec<String> {
+    GLOBAL_CONFIG.read().unwrap().symbol_stop_list.clone()
+}
+
+// Comma separated list of symbol names, an empty value disables the stop-list.
+fn parse_stop_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|symbol| symbol.trim().to_lowercase())
+        .filter(|symbol| !symbol.is_empty())
+        .collect()
+}
+
+pub fn get_payload_compression() -> TextCompression {
+    GLOBAL_CONFIG.read().unwrap().payload_compression
+}
+
+pub fn get_index_doc_chunks() -> bool {
+    GLOBAL_CONFIG.read().unwrap().index_doc_chunks
+}
+
+pub fn get_config_file_extensions() -> Vec<String> {
+    GLOBAL_CONFIG
+        .read()
+        .unwrap()
+        .config_file_extensions
+        .clone()
+        .unwrap_or_else(|| parse_extensions(DEFAULT_CONFIG_FILE_EXTENSIONS))
+}
+
+// Comma separated list of extensions, an empty value indexes no config file.
+fn parse_extensions(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+pub fn get_quickwit_max_in_flight_batches() -> usize {
+    GLOBAL_CONFIG
+        .read()
+        .unwrap()
+        .quickwit_max_in_flight_batches
+        .max(1)
+}
+
+pub fn get_embedding_cache_dir() -> Option<String> {
+    GLOBAL_CONFIG.read().unwrap().embedding_cache_dir.clone()
+}
+
+pub fn get_embedding_cache_max_entries() -> usize {
+    GLOBAL_CONFIG
+        .read()
+        .unwrap()
+        .embedding_cache_max_entries
+        .max(1)
+}
+
+pub fn get_key_file_weights() -> KeyFileWeights {
+    GLOBAL_CONFIG.read().unwrap().key_file_weights
+}
+
+pub fn get_chunk_quality_thresholds() -> ChunkQualityThresholds {
+    GLOBAL_CONFIG.read().unwrap().chunk_quality_thresholds
+}
+
+pub fn get_chunk_overlap() -> ChunkOverlap {
+    GLOBAL_CONFIG.read().unwrap().chunk_overlap
+}
+
+pub fn get_query_packs_dir() -> Option<String> {
+    GLOBAL_CONFIG.read().unwrap().query_packs_dir.clone()
+}
+
+pub fn get_last_modified_max_commits() -> usize {
+    GLOBAL_CONFIG.read().unwrap().last_modified_max_commits
+}
+
+pub fn get_collection_tuning() -> CollectionTuning {
+    GLOBAL_CONFIG.read().unwrap().collection_tuning
+}
+
+pub fn get_qdrant_warmup_searches() -> usize {
+    GLOBAL_CONFIG.read().unwrap().qdrant_warmup_searches
+}
+
+pub fn get_web_url_template() -> Option<String> {
+    GLOBAL_CONFIG.read().unwrap().web_url_template.clone()
+}
+
+pub fn get_tenant_id() -> String {
+    GLOBAL_CONFIG.read().unwrap().tenant_id.clone()
+}
+
+/// Uses the tenant given on the command line instead of `TENANT_ID` from the env file.
+pub fn override_tenant_id(tenant_id: String) {
+    GLOBAL_CONFIG.write().expect("Failed to acquire write lock").tenant_id = tenant_id;
+}
+
+pub fn get_size_limits() -> SizeLimits {
+    GLOBAL_CONFIG.read().unwrap().size_limits.clone()
+}
+
+/// Applies the size limits given on the command line on top of the ones from the env file.
+/// Overrides from the command line are checked before the ones from the env file.
+pub fn override_size_limits(
+    max_file_bytes: Option<u64>,
+    max_lines: Option<usize>,
+    overrides: Vec<SizeLimitOverride>,
+) {
+    let mut global_config = GLOBAL_CONFIG.write().expect("Failed to acquire write lock");
+    let limits = &mut global_config.size_limits;
+    if let Some(max_file_bytes) = max_file_bytes {
+        limits.max_file_bytes = max_file_bytes;
+    }
+    if let Some(max_lines) = max_lines {
+        limits.max_lines = max_lines;
+    }
+    limits.overrides.splice(0..0, overrides);
+}
+
+// `MAX_FILE_BYTES`, `MAX_LINE_COUNT` and `SIZE_LIMIT_OVERRIDES`, the latter a comma separated
+// list of `<glob>=<max_file_bytes>:<max_lines>`.
+fn size_limits_from_env() -> SizeLimits {
+    let defaults = SizeLimits::default();
+    SizeLimits {
+        max_file_bytes: env::var("MAX_FILE_BYTES")
+            .ok()
+            .map(|bytes| bytes.parse().expect("`MAX_FILE_BYTES` must be a number"))
+            .unwrap_or(defaults.max_file_bytes),
+        max_lines: env::var("MAX_LINE_COUNT")
+            .ok()
+            .map(|lines| lines.parse().expect("`MAX_LINE_COUNT` must be a number"))
+            .unwrap_or(defaults.max_lines),
+        overrides: env::var("SIZE_LIMIT_OVERRIDES")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|o| !o.trim().is_empty())
+            .map(|o| o.parse().expect("Invalid `SIZE_LIMIT_OVERRIDES`"))
+            .collect(),
+    }
+}
+
+/// The environment variables of the ingestion that are set, with the secrets redacted.
+pub fn config_snapshot() -> BTreeMap<String, String> {
+    common::run_manifest::redact_config(
+        MANIFEST_ENV_VARS
+            .iter()
+            .filter_map(|name| env::var(name).ok().map(|value| (name.to_string(), value))),
+    )
+}
@@ -0,0 +1,421 @@
+// Embeddings of the chunk texts already embedded, so identical chunks (license headers, generated
+// code, copied helpers) are only embedded once. The cache is keyed by the hash of the model and of
+// the text, never the path, a new model misses on every entry instead of reusing stale vectors.
+// The entries are kept in memory for the run and, when `EMBEDDING_CACHE_DIR` is set, in a sled
+// store that persists across runs. Both are bounded and evict the least recently used entries.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::tokenizer_onnx::Embedding;
+use once_cell::sync::Lazy;
+
+use crate::config::{get_embedding_cache_dir, get_embedding_cache_max_entries, get_model_path};
+use crate::run_manifest::model_hash;
+
+// Entries kept in memory, a 384 dimensions embedding takes 1.5KB.
+const MEMORY_MAX_ENTRIES: usize = 50_000;
+
+type CacheKey = [u8; 32];
+
+static GLOBAL_CACHE: Lazy<Arc<EmbeddingCache>> = Lazy::new(|| {
+    let model_path = get_model_path();
+    let model_id = format!("{}:{}", model_path, model_hash(Path::new(&model_path)));
+    let dir = get_embedding_cache_dir();
+    Arc::new(EmbeddingCache::open(
+        &model_id,
+        dir.as_deref().map(Path::new),
+        get_embedding_cache_max_entries(),
+    ))
+});
+
+/// The cache of the process, for the model in `MODEL_DIR`.
+pub fn global() -> Arc<EmbeddingCache> {
+    GLOBAL_CACHE.clone()
+}
+
+/// Lookups of the cache since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses ({:.1}% hit rate)",
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+pub struct EmbeddingCache {
+    model_id: String,
+    memory: Mutex<LruMap>,
+    store: Option<DiskStore>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl EmbeddingCache {
+    /// Opens the cache of the model, with the entries stored in `dir` when it is set.
+    /// A store that can't be opened is logged and the cache only lives in memory.
+    pub fn open(model_id: &str, dir: Option<&Path>, max_entries: usize) -> Self {
+        let store = dir.and_then(|dir| match DiskStore::open(dir, max_entries) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                log::warn!(
+                    "Failed to open the embedding cache in {:?}, it isn't persisted: {}",
+                    dir,
+                    e
+                );
+                None
+            }
+        });
+        Self {
+            model_id: model_id.to_string(),
+            memory: Mutex::new(LruMap::new(MEMORY_MAX_ENTRIES.min(max_entries.max(1)))),
+            store,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// The embedding of the text from the cache, embedded with `embed` and stored on a miss.
+    pub fn embed_with(
+        &self,
+        text: &str,
+        embed: impl FnOnce(&str) -> anyhow::Result<Embedding>,
+    ) -> anyhow::Result<Embedding> {
+        let key = self.key(text);
+        let in_memory = self.memory.lock().unwrap().get(&key);
+        let cached = in_memory.or_else(|| {
+            let embedding = self.store.as_ref()?.get(&key)?;
+            self.memory.lock().unwrap().insert(key, embedding.clone());
+            Some(embedding)
+        });
+        if let Some(embedding) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(embedding);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let embedding = embed(text)?;
+        self.memory.lock().unwrap().insert(key, embedding.clone());
+        if let Some(store) = &self.store {
+            store.insert(&key, &embedding);
+        }
+        Ok(embedding)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn key(&self, text: &str) -> CacheKey {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.model_id.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(text.as_bytes());
+        *hasher.finalize().as_bytes()
+    }
+}
+
+// Entries with the tick of their last use, the lowest tick is evicted first.
+struct LruMap {
+    entries: HashMap<CacheKey, (Embedding, u64)>,
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    max_entries: usize,
+}
+
+impl LruMap {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            max_entries,
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<Embedding> {
+        self.tick += 1;
+        let (embedding, tick) = self.entries.get_mut(key)?;
+        self.recency.remove(tick);
+        *tick = self.tick;
+        self.recency.insert(self.tick, *key);
+        Some(embedding.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, embedding: Embedding) {
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key, (embedding, self.tick)) {
+            self.recency.remove(&tick);
+        }
+        self.recency.insert(self.tick, key);
+        while self.entries.len() > self.max_entries {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+}
+
+// The sled store: the embeddings by key, and the keys by the id of their last use for the eviction.
+struct DiskStore {
+    db: sled::Db,
+    vectors: sled::Tree,
+    last_used: sled::Tree,
+    recency: sled::Tree,
+    // the entries of `vectors`, counted at open: `sled::Tree::len` scans the whole tree.
+    entries: AtomicUsize,
+    max_entries: usize,
+}
+
+impl DiskStore {
+    fn open(dir: &Path, max_entries: usize) -> sled::Result<Self> {
+        let db = sled::open(dir)?;
+        let vectors = db.open_tree("vectors")?;
+        Ok(Self {
+            entries: AtomicUsize::new(vectors.len()),
+            vectors,
+            last_used: db.open_tree("last_used")?,
+            recency: db.open_tree("recency")?,
+            db,
+            max_entries: max_entries.max(1),
+        })
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Embedding> {
+        let bytes = match self.vectors.get(key) {
+            Ok(bytes) => bytes?,
+            Err(e) => {
+                log::warn!("Failed to read the embedding cache: {}", e);
+                return None;
+            }
+        };
+        let embedding = bincode::deserialize::<Embedding>(&bytes).ok()?;
+        if let Err(e) = self.touch(key) {
+            log::warn!("Failed to update the embedding cache: {}", e);
+        }
+        Some(embedding)
+    }
+
+    // The cache only saves work, failing to write to it doesn't fail the run.
+    fn insert(&self, key: &CacheKey, embedding: &Embedding) {
+        let result = bincode::serialize(embedding)
+            .map_err(|e| sled::Error::Unsupported(e.to_string()))
+            .and_then(|bytes| self.vectors.insert(key, bytes))
+            .and_then(|previous| {
+                if previous.is_none() {
+                    self.entries.fetch_add(1, Ordering::Relaxed);
+                }
+                self.touch(key)
+            })
+            .and_then(|_| self.evict());
+        if let Err(e) = result {
+            log::warn!("Failed to write to the embedding cache: {}", e);
+        }
+    }
+
+    fn touch(&self, key: &CacheKey) -> sled::Result<()> {
+        let id = self.db.generate_id()?.to_be_bytes();
+        if let Some(previous) = self.last_used.insert(key, &id)? {
+            self.recency.remove(previous)?;
+        }
+        self.recency.insert(id, key)?;
+        Ok(())
+    }
+
+    fn evict(&self) -> sled::Result<()> {
+        while self.entries.load(Ordering::Relaxed) > self.max_entries {
+            let Some((_, oldest)) = self.recency.pop_min()? else {
+                break;
+            };
+            if self.vectors.remove(&oldest)?.is_some() {
+                self.entries.fetch_sub(1, Ordering::Relaxed);
+            }
+            self.last_used.remove(&oldest)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::semantic_index::{commit_cached_chunks, ChunkedFile, PointSink, SemanticIndex};
+    use qdrant_client::qdrant::PointStruct;
+
+    const LICENSE: &str = "// Copyright 2024 The Authors.\n// Licensed under the Apache License, Version 2.0.\n";
+    const HELPER: &str = "fn clamp(v: i32) -> i32 {\n    v.max(0).min(10)\n}\n";
+
+    // Embeds every chunk of the files through the cache, counting the calls to the embedder.
+    fn index(cache: &EmbeddingCache, files: &[String], calls: &Cell<usize>) {
+        for file in files {
+            for chunk in SemanticIndex::by_lines(file, 2) {
+                cache
+                    .embed_with(chunk.data, |text| {
+                        calls.set(calls.get() + 1);
+                        Ok(vec![text.len() as f32; 4])
+                    })
+                    .unwrap();
+            }
+        }
+    }
+
+    fn fixture() -> Vec<String> {
+        vec![
+            format!("{}{}", LICENSE, HELPER),
+            // a copy of the first file.
+            format!("{}{}", LICENSE, HELPER),
+            format!("{}fn other() {{}}\n", LICENSE),
+        ]
+    }
+
+    fn unique_chunks(files: &[String]) -> usize {
+        files
+            .iter()
+            .flat_map(|file| SemanticIndex::by_lines(file, 2))
+            .map(|chunk| chunk.data.to_string())
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+    }
+
+    // Counts the points upserted.
+    #[derive(Default)]
+    struct CountingSink {
+        points: std::sync::Mutex<usize>,
+    }
+
+    #[async_trait::async_trait]
+    impl PointSink for CountingSink {
+        async fn upsert_points(
+            &self,
+            _collection: &str,
+            points: Vec<PointStruct>,
+        ) -> anyhow::Result<()> {
+            *self.points.lock().unwrap() += points.len();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identical_chunks_are_embedded_once() {
+        let files = fixture();
+        let cache = EmbeddingCache::open("model:abc", None, 100);
+        let calls = Cell::new(0);
+        let sink = Some(CountingSink::default());
+        for (i, content) in files.iter().enumerate() {
+            let path = format!("src/file_{}.rs", i);
+            let file = ChunkedFile {
+                repo_name: "repo",
+                relative_path: &path,
+                branch: "refs/heads/main",
+                semantic_hash: content,
+                lang: "Rust",
+                key_paths: &[],
+                doc_comments: &[],
+                last_modified: None,
+            };
+            let chunks = SemanticIndex::by_lines(content, 2);
+            commit_cached_chunks(
+                &chunks,
+                &file,
+                &cache,
+                |text| {
+                    calls.set(calls.get() + 1);
+                    Ok(vec![text.len() as f32; 4])
+                },
+                &sink,
+            )
+            .await
+            .unwrap();
+        }
+
+        let chunks = files
+            .iter()
+            .map(|f| SemanticIndex::by_lines(f, 2).len())
+            .sum::<usize>();
+        let unique = unique_chunks(&files);
+        assert!(unique < chunks);
+        // every chunk is committed, only the distinct ones are embedded.
+        assert_eq!(*sink.unwrap().points.lock().unwrap(), chunks);
+        assert_eq!(calls.get(), unique);
+        let stats = cache.stats();
+        assert_eq!(stats.misses, unique);
+        assert_eq!(stats.hits, chunks - unique);
+    }
+
+    #[test]
+    fn test_store_persists_across_runs_for_the_same_model() {
+        let dir = std::env::temp_dir().join(format!("embedding-cache-{}", uuid::Uuid::new_v4()));
+        let files = fixture();
+        {
+            let cache = EmbeddingCache::open("model:abc", Some(&dir), 100);
+            index(&cache, &files, &Cell::new(0));
+        }
+
+        let calls = Cell::new(0);
+        let cache = EmbeddingCache::open("model:abc", Some(&dir), 100);
+        index(&cache, &files, &calls);
+        assert_eq!(calls.get(), 0);
+        drop(cache);
+
+        // another model doesn't reuse the vectors of the first one.
+        let calls = Cell::new(0);
+        let cache = EmbeddingCache::open("model:def", Some(&dir), 100);
+        index(&cache, &files, &calls);
+        assert_eq!(calls.get(), unique_chunks(&files));
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_least_recently_used_entries_are_evicted() {
+        let dir = std::env::temp_dir().join(format!("embedding-cache-{}", uuid::Uuid::new_v4()));
+        let store = DiskStore::open(&dir, 2).unwrap();
+        let key = |i: u8| [i; 32];
+        store.insert(&key(1), &vec![1.0]);
+        store.insert(&key(2), &vec![2.0]);
+        // 1 is used again, 2 is the least recently used when 3 comes in.
+        assert!(store.get(&key(1)).is_some());
+        store.insert(&key(3), &vec![3.0]);
+        assert_eq!(store.vectors.len(), 2);
+        assert_eq!(store.entries.load(Ordering::Relaxed), 2);
+        assert!(store.get(&key(2)).is_none());
+        assert!(store.get(&key(1)).is_some());
+
+        let mut memory = LruMap::new(2);
+        memory.insert(key(1), vec![1.0]);
+        memory.insert(key(2), vec![2.0]);
+        assert!(memory.get(&key(1)).is_some());
+        memory.insert(key(3), vec![3.0]);
+        assert!(memory.get(&key(2)).is_none());
+        assert!(memory.get(&key(3)).is_some());
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
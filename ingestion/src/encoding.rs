@@ -0,0 +1,225 @@
+// Decoding of the content of the files to index. The content is decoded once, before it is
+// hashed, parsed and chunked, so a file in another encoding is indexed like its UTF-8 version:
+// a UTF-8 byte order mark is stripped, UTF-16 is read from its byte order mark or from the NUL
+// bytes next to its ASCII characters, and text that is mostly ASCII with a few other bytes is
+// read as latin-1. Only the content that is none of them is left out of the index.
+
+use std::borrow::Cow;
+use std::fmt;
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+// bytes looked at to tell UTF-16 without a byte order mark.
+const UTF16_SAMPLE_BYTES: usize = 4096;
+// share of the characters of the sample that must be ASCII with a NUL byte on the same side.
+const MIN_UTF16_ASCII_SHARE: f32 = 0.7;
+// share of the bytes of a latin-1 file that can be outside ASCII.
+const MAX_LATIN1_HIGH_SHARE: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 with a byte order mark",
+            Encoding::Utf16Le => "UTF-16 LE",
+            Encoding::Utf16Be => "UTF-16 BE",
+            Encoding::Latin1 => "latin-1",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The content of a file as UTF-8, borrowed when it already was.
+#[derive(Debug)]
+pub struct Decoded<'a> {
+    pub text: Cow<'a, str>,
+    pub encoding: Encoding,
+}
+
+/// Why the content of a file couldn't be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Undecodable {
+    // an odd number of bytes, or a surrogate without its pair.
+    InvalidUtf16(Encoding),
+    // NUL bytes outside UTF-16, e.g. an image or a compiled file.
+    Binary,
+    // too many bytes outside ASCII for latin-1 text, another encoding or binary content.
+    NotText,
+}
+
+impl fmt::Display for Undecodable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Undecodable::InvalidUtf16(encoding) => write!(f, "it is not valid {}", encoding),
+            Undecodable::Binary => f.write_str("it has NUL bytes, it looks binary"),
+            Undecodable::NotText => f.write_str(
+                "it is not valid UTF-8 and has too many bytes outside ASCII to be latin-1",
+            ),
+        }
+    }
+}
+
+/// Decodes the content of a file into UTF-8 text.
+pub fn decode(bytes: &[u8]) -> Result<Decoded<'_>, Undecodable> {
+    if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+        return match std::str::from_utf8(rest) {
+            Ok(text) => Ok(Decoded {
+                text: Cow::Borrowed(text),
+                encoding: Encoding::Utf8Bom,
+            }),
+            Err(_) => decode_latin1(rest),
+        };
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16_LE_BOM) {
+        return decode_utf16(rest, Encoding::Utf16Le);
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16_BE_BOM) {
+        return decode_utf16(rest, Encoding::Utf16Be);
+    }
+    // ASCII text in UTF-16 is valid UTF-8 too, with a NUL byte after or before each character.
+    if let Some(encoding) = utf16_without_bom(bytes) {
+        return decode_utf16(bytes, encoding);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(Decoded {
+            text: Cow::Borrowed(text),
+            encoding: Encoding::Utf8,
+        }),
+        Err(_) => decode_latin1(bytes),
+    }
+}
+
+fn utf16_without_bom(bytes: &[u8]) -> Option<Encoding> {
+    if bytes.len() < 2 || bytes.len() % 2 != 0 {
+        return None;
+    }
+    let sample = &bytes[..bytes.len().min(UTF16_SAMPLE_BYTES)];
+    let units = sample.len() / 2;
+    let (mut little, mut big) = (0, 0);
+    for unit in sample.chunks_exact(2) {
+        match (unit[0], unit[1]) {
+            (ascii, 0) if ascii != 0 && ascii.is_ascii() => little += 1,
+            (0, ascii) if ascii != 0 && ascii.is_ascii() => big += 1,
+            _ => {}
+        }
+    }
+    let share = |count: usize| count as f32 / units as f32;
+    if share(little) >= MIN_UTF16_ASCII_SHARE {
+        Some(Encoding::Utf16Le)
+    } else if share(big) >= MIN_UTF16_ASCII_SHARE {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+fn decode_utf16(bytes: &[u8], encoding: Encoding) -> Result<Decoded<'static>, Undecodable> {
+    if bytes.len() % 2 != 0 {
+        return Err(Undecodable::InvalidUtf16(encoding));
+    }
+    let units = bytes.chunks_exact(2).map(|unit| match encoding {
+        Encoding::Utf16Be => u16::from_be_bytes([unit[0], unit[1]]),
+        _ => u16::from_le_bytes([unit[0], unit[1]]),
+    });
+    let text = char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .map_err(|_| Undecodable::InvalidUtf16(encoding))?;
+    Ok(Decoded {
+        text: Cow::Owned(text),
+        encoding,
+    })
+}
+
+fn decode_latin1(bytes: &[u8]) -> Result<Decoded<'static>, Undecodable> {
+    if bytes.contains(&0) {
+        return Err(Undecodable::Binary);
+    }
+    let high = bytes.iter().filter(|byte| !byte.is_ascii()).count();
+    if high as f32 > bytes.len() as f32 * MAX_LATIN1_HIGH_SHARE {
+        return Err(Undecodable::NotText);
+    }
+    // the 256 code points of latin-1 are the first 256 of unicode.
+    Ok(Decoded {
+        text: Cow::Owned(bytes.iter().map(|&byte| byte as char).collect()),
+        encoding: Encoding::Latin1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUST: &str = "// Größe der Datei in Bytes.\nfn file_size() -> usize {\n    42\n}\n";
+
+    fn decoded(bytes: &[u8]) -> (String, Encoding) {
+        let decoded = decode(bytes).unwrap();
+        (decoded.text.into_owned(), decoded.encoding)
+    }
+
+    #[test]
+    fn test_fixtures_are_decoded() {
+        assert_eq!(
+            decoded(include_bytes!("../fixtures/encoding/bom.py")),
+            (
+                "# Grüße aus Köln\ndef greet():\n    return \"héllo\"\n".to_string(),
+                Encoding::Utf8Bom
+            )
+        );
+        assert_eq!(
+            decoded(include_bytes!("../fixtures/encoding/utf16le.rs")),
+            (RUST.to_string(), Encoding::Utf16Le)
+        );
+        // without a byte order mark.
+        assert_eq!(
+            decoded(include_bytes!("../fixtures/encoding/utf16be.rs")),
+            (RUST.to_string(), Encoding::Utf16Be)
+        );
+        assert_eq!(
+            decoded(include_bytes!("../fixtures/encoding/latin1.py")),
+            (
+                "# Café crème, façade\ndef order():\n    return \"café\"\n".to_string(),
+                Encoding::Latin1
+            )
+        );
+        assert_eq!(
+            decode(include_bytes!("../fixtures/encoding/binary.rs")).unwrap_err(),
+            Undecodable::Binary
+        );
+    }
+
+    #[test]
+    fn test_utf8_is_borrowed() {
+        let decoded = decode(RUST.as_bytes()).unwrap();
+        assert!(matches!(decoded.text, Cow::Borrowed(_)));
+        assert_eq!(decoded.encoding, Encoding::Utf8);
+        assert_eq!(decode(b"").unwrap().encoding, Encoding::Utf8);
+    }
+
+    #[test]
+    fn test_undecodable_content() {
+        // a surrogate without its pair.
+        assert_eq!(
+            decode(&[0xFF, 0xFE, 0x00, 0xD8, 0x41, 0x00]).unwrap_err(),
+            Undecodable::InvalidUtf16(Encoding::Utf16Le)
+        );
+        assert_eq!(
+            decode(&[0xFE, 0xFF, 0x00, 0x41, 0x00]).unwrap_err(),
+            Undecodable::InvalidUtf16(Encoding::Utf16Be)
+        );
+        // mostly bytes outside ASCII, not latin-1 text.
+        assert_eq!(
+            decode(&[0x61, 0xE9, 0xE8, 0xE0, 0x0A]).unwrap_err(),
+            Undecodable::NotText
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use thiserror::Error;
+
+/// Errors of an indexing run. The ones returned stop the run, `PerFile` errors are collected
+/// while the tree is walked and the files are committed, the run goes on without the file.
+#[derive(Error, Debug)]
+pub enum IngestionError {
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("embedding failed: {0}")]
+    Embedding(anyhow::Error),
+
+    #[error("qdrant commit failed: {0}")]
+    QdrantCommit(anyhow::Error),
+
+    #[error("quickwit commit failed: {0}")]
+    QuickwitCommit(anyhow::Error),
+
+    #[error("checkpoint error: {0}")]
+    Checkpoint(anyhow::Error),
+
+    #[error("invalid query packs: {0}")]
+    QueryPack(anyhow::Error),
+
+    #[error("snapshot failed: {0}")]
+    Snapshot(anyhow::Error),
+
+    #[error("failed to index {path}: {source}")]
+    PerFile {
+        path: String,
+        source: anyhow::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, IngestionError>;
+
+impl IngestionError {
+    pub fn per_file(path: &str, source: impl Into<anyhow::Error>) -> Self {
+        IngestionError::PerFile {
+            path: path.to_string(),
+            source: source.into(),
+        }
+    }
+
+    /// Exit code of the ingestion binary for the error, each variant has its own so scripts
+    /// running the indexing can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            IngestionError::Git(_) => 2,
+            IngestionError::Io(_) => 3,
+            IngestionError::Embedding(_) => 4,
+            IngestionError::QdrantCommit(_) => 5,
+            IngestionError::QuickwitCommit(_) => 6,
+            IngestionError::Checkpoint(_) => 7,
+            IngestionError::PerFile { .. } => 8,
+            IngestionError::QueryPack(_) => 9,
+            IngestionError::Snapshot(_) => 10,
+        }
+    }
+
+    /// What went wrong and what to check, printed by the binary before it exits.
+    pub fn message(&self) -> String {
+        let hint = match self {
+            IngestionError::Git(_) => {
+                "Check that --repo-folder is a git repository inside ./repo and that --branch exists."
+            }
+            IngestionError::Io(_) => "Check the paths passed to the indexer and their permissions.",
+            IngestionError::Embedding(_) => "Check that MODEL_DIR has the embedding model.",
+            IngestionError::QdrantCommit(_) => "Is qdrant running on QDRANT_URL?",
+            IngestionError::QuickwitCommit(_) => "Is quickwit running on QUICKWIT_URL?",
+            IngestionError::Checkpoint(_) => {
+                "Check the --checkpoint file, or delete it to start the run over."
+            }
+            IngestionError::PerFile { .. } => "Run again with --resume to retry the file.",
+            IngestionError::QueryPack(_) => {
+                "Run with --validate-queries to list the errors of the query packs in QUERY_PACKS_DIR."
+            }
+            IngestionError::Snapshot(_) => {
+                "Check the --archive, qdrant on QDRANT_URL and quickwit on QUICKWIT_URL. An interrupted import resumes from its --checkpoint."
+            }
+        };
+        format!("{}\n{}", self, hint)
+    }
+}
+
+// The semantic index still reports its failures as boxed errors.
+pub fn boxed(error: Box<dyn std::error::Error>) -> anyhow::Error {
+    anyhow::anyhow!("{}", error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_variant_has_its_own_exit_code() {
+        let errors = [
+            IngestionError::Git(git2::Error::from_str("reference not found")),
+            IngestionError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, "missing")),
+            IngestionError::Embedding(anyhow::anyhow!("model missing")),
+            IngestionError::QdrantCommit(anyhow::anyhow!("connection refused")),
+            IngestionError::QuickwitCommit(anyhow::anyhow!("connection refused")),
+            IngestionError::Checkpoint(anyhow::anyhow!("invalid json")),
+            IngestionError::per_file("src/main.rs", anyhow::anyhow!("blob not found")),
+            IngestionError::QueryPack(anyhow::anyhow!("Python: invalid query")),
+            IngestionError::Snapshot(anyhow::anyhow!("schema version 2")),
+        ];
+        let mut codes = errors.iter().map(|e| e.exit_code()).collect::<Vec<_>>();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(codes.iter().all(|code| *code > 1));
+
+        assert!(errors[6].message().starts_with("failed to index src/main.rs: blob not found"));
+    }
+}
@@ -1,30 +1,330 @@
-// yaml_modifier.rs
+// Index config of the quickwit index of a repo, one document per file as described by `FileFields`.
 
 use std::fs;
-use std::io::{self, Read, Write};
 use std::path::Path;
 
-pub fn replace_index_id_in_yaml(yaml_path: &Path, new_index_id: &str) -> io::Result<()> {
-    // Read the YAML file into a String
-    let mut yaml_content = String::new();
-    let mut file = fs::File::open(yaml_path)?;
-    file.read_to_string(&mut yaml_content)?;
-
-    // Replace the index_id value
-    let old_index_id_pattern = "index_id: ";
-    if let Some(start) = yaml_content.find(old_index_id_pattern) {
-        let end = yaml_content[start..]
-            .find('\n')
-            .unwrap_or(yaml_content.len());
-        yaml_content.replace_range(
-            start + old_index_id_pattern.len()..start + end,
-            new_index_id,
-        );
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const QUICKWIT_CONFIG_VERSION: &str = "0.6";
+const COMMIT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexSchema {
+    pub version: String,
+    pub index_id: String,
+    pub doc_mapping: DocMapping,
+    pub search_settings: SearchSettings,
+    pub indexing_settings: IndexingSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocMapping {
+    pub field_mappings: Vec<FieldMapping>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchSettings {
+    pub default_search_fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexingSettings {
+    pub commit_timeout_secs: u64,
+}
+
+// Only the options set here are checked against an existing index, quickwit fills in the others.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldMapping {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fast: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored: Option<bool>,
+}
+
+impl FieldMapping {
+    fn new(name: &str, field_type: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            field_type: field_type.to_string(),
+            tokenizer: None,
+            record: None,
+            fast: None,
+            stored: None,
+        }
+    }
+
+    // text matched as a whole, e.g. hashes and refs.
+    fn raw(name: &str) -> Self {
+        Self {
+            tokenizer: Some("raw".to_string()),
+            fast: Some(true),
+            ..Self::new(name, "text")
+        }
     }
 
-    // Write the modified data back to the YAML file
-    let mut file = fs::File::create(yaml_path)?;
-    file.write_all(yaml_content.as_bytes())?;
+    // text searched by words, with positions for phrase queries.
+    fn searchable(name: &str) -> Self {
+        Self {
+            tokenizer: Some("default".to_string()),
+            record: Some("position".to_string()),
+            ..Self::new(name, "text")
+        }
+    }
+}
+
+/// The index config for the documents of a repo, with a field mapping for every `FileFields` member.
+pub fn generate_index_schema(index_id: &str) -> IndexSchema {
+    let field_mappings = vec![
+        FieldMapping {
+            stored: Some(true),
+            ..FieldMapping::raw("tenant_id")
+        },
+        // searched by words and filtered or sorted on the whole value.
+        FieldMapping {
+            fast: Some(true),
+            stored: Some(true),
+            ..FieldMapping::searchable("repo_name")
+        },
+        FieldMapping::raw("repo_disk_path"),
+        FieldMapping::raw("repo_ref"),
+        FieldMapping {
+            fast: Some(true),
+            ..FieldMapping::searchable("relative_path")
+        },
+        FieldMapping::raw("last_commit"),
+        FieldMapping::raw("lang"),
+        FieldMapping::new("is_directory", "bool"),
+        FieldMapping::new("avg_line_length", "f64"),
+        FieldMapping::new("line_end_indices", "array<u64>"),
+        FieldMapping {
+            stored: Some(true),
+            ..FieldMapping::searchable("content")
+        },
+        FieldMapping::new("symbol_locations", "array<u64>"),
+        FieldMapping::raw("unique_hash"),
+        FieldMapping {
+            tokenizer: Some("default".to_string()),
+            ..FieldMapping::new("symbols", "text")
+        },
+    ];
 
+    IndexSchema {
+        version: QUICKWIT_CONFIG_VERSION.to_string(),
+        index_id: index_id.to_string(),
+        doc_mapping: DocMapping { field_mappings },
+        search_settings: SearchSettings {
+            default_search_fields: ["relative_path", "repo_name", "content", "lang", "symbols"]
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+        },
+        indexing_settings: IndexingSettings {
+            commit_timeout_secs: COMMIT_TIMEOUT_SECS,
+        },
+    }
+}
+
+/// Writes the schema as a quickwit index config yaml, e.g. to create the index with the quickwit CLI.
+pub fn persist_index_schema(schema: &IndexSchema, path: &Path) -> Result<()> {
+    fs::write(path, serde_yaml::to_string(schema)?)?;
     Ok(())
 }
+
+/// A field of an existing index that doesn't match the expected schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMismatch {
+    pub field: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl std::fmt::Display for FieldMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, found {}",
+            self.field, self.expected, self.found
+        )
+    }
+}
+
+// quickwit reports fast text fields either as `true` or with their normalizer.
+fn is_fast(value: Option<&Value>) -> bool {
+    match value {
+        Some(Value::Bool(fast)) => *fast,
+        Some(Value::Object(_)) => true,
+        _ => false,
+    }
+}
+
+/// Compares the field mappings of an existing index, as returned by quickwit, with the schema.
+/// Fields of the index that aren't in the schema are reported too, ingestion never writes them.
+pub fn diff_field_mappings(schema: &IndexSchema, existing: &[Value]) -> Vec<FieldMismatch> {
+    let mut mismatches = Vec::new();
+    let mut mismatch = |field: &str, expected: String, found: String| {
+        mismatches.push(FieldMismatch {
+            field: field.to_string(),
+            expected,
+            found,
+        })
+    };
+
+    for expected in &schema.doc_mapping.field_mappings {
+        let Some(found) = existing
+            .iter()
+            .find(|mapping| mapping["name"].as_str() == Some(expected.name.as_str()))
+        else {
+            mismatch(&expected.name, format!("type {}", expected.field_type), "no field".to_string());
+            continue;
+        };
+
+        let found_type = found["type"].as_str().unwrap_or_default();
+        if found_type != expected.field_type {
+            mismatch(
+                &expected.name,
+                format!("type {}", expected.field_type),
+                format!("type {}", found_type),
+            );
+            continue;
+        }
+        for (option, expected_value) in [
+            ("tokenizer", &expected.tokenizer),
+            ("record", &expected.record),
+        ] {
+            if let Some(expected_value) = expected_value {
+                let found_value = found[option].as_str().unwrap_or("none");
+                if found_value != expected_value {
+                    mismatch(
+                        &expected.name,
+                        format!("{} {}", option, expected_value),
+                        format!("{} {}", option, found_value),
+                    );
+                }
+            }
+        }
+        if let Some(fast) = expected.fast {
+            if is_fast(found.get("fast")) != fast {
+                mismatch(
+                    &expected.name,
+                    format!("fast {}", fast),
+                    format!("fast {}", !fast),
+                );
+            }
+        }
+        if let Some(stored) = expected.stored {
+            // quickwit stores fields unless told otherwise.
+            let found_stored = found["stored"].as_bool().unwrap_or(true);
+            if found_stored != stored {
+                mismatch(
+                    &expected.name,
+                    format!("stored {}", stored),
+                    format!("stored {}", found_stored),
+                );
+            }
+        }
+    }
+
+    for found in existing {
+        let name = found["name"].as_str().unwrap_or_default();
+        if !schema
+            .doc_mapping
+            .field_mappings
+            .iter()
+            .any(|expected| expected.name == name)
+        {
+            mismatch(
+                name,
+                "no field".to_string(),
+                format!("type {}", found["type"].as_str().unwrap_or_default()),
+            );
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileFields;
+
+    #[test]
+    fn test_schema_maps_every_file_field() {
+        let fields = FileFields {
+            tenant_id: String::new(),
+            repo_name: String::new(),
+            repo_disk_path: String::new(),
+            repo_ref: String::new(),
+            relative_path: String::new(),
+            last_commit: String::new(),
+            lang: String::new(),
+            is_directory: false,
+            avg_line_length: 0.0,
+            line_end_indices: vec![],
+            content: String::new(),
+            symbol_locations: vec![],
+            unique_hash: String::new(),
+            symbols: String::new(),
+        };
+        let document = serde_json::to_value(&fields).unwrap();
+        let mut document_fields = document
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        document_fields.sort();
+
+        let schema = generate_index_schema("repo");
+        let mut mapped = schema
+            .doc_mapping
+            .field_mappings
+            .iter()
+            .map(|mapping| mapping.name.clone())
+            .collect::<Vec<_>>();
+        mapped.sort();
+
+        assert_eq!(mapped, document_fields);
+        assert_eq!(schema.index_id, "repo");
+    }
+
+    #[test]
+    fn test_diff_field_mappings() {
+        let schema = generate_index_schema("repo");
+        let mut existing = serde_json::to_value(&schema.doc_mapping.field_mappings)
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+        assert!(diff_field_mappings(&schema, &existing).is_empty());
+
+        // quickwit expands the defaults and reports fast text fields with their normalizer.
+        existing[4]["fast"] = serde_json::json!({"normalizer": "raw"});
+        existing[4]["indexed"] = Value::Bool(true);
+        assert!(diff_field_mappings(&schema, &existing).is_empty());
+
+        existing[10]["tokenizer"] = Value::String("raw".to_string());
+        existing.retain(|mapping| mapping["name"] != "symbols");
+        existing.push(serde_json::json!({"name": "branches", "type": "text"}));
+        let mismatches = diff_field_mappings(&schema, &existing)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mismatches,
+            vec![
+                "content: expected tokenizer default, found tokenizer raw",
+                "symbols: expected type text, found no field",
+                "branches: expected no field, found type text",
+            ]
+        );
+    }
+}
@@ -1,5 +1,11 @@
 use blake3::Hasher;
 use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Version of the scheme the qdrant point ids are derived with, stored in the `id_scheme` payload
+/// field of every point. Points without it have the random ids of the indexers before it.
+pub const POINT_ID_SCHEME: i64 = 1;
+pub const ID_SCHEME_FIELD: &str = "id_scheme";
 
 pub fn compute_hashes(relative_path: PathBuf, buffer: &str, branch_list: &str) -> (String, String) {
     // Create the semantic hash
@@ -25,9 +31,51 @@ pub fn compute_hashes(relative_path: PathBuf, buffer: &str, branch_list: &str) -
     (semantic_hash, tantivy_hash)
 }
 
+/// Id of a chunk point, the same for the same chunk of the same file content on every run,
+/// so indexing it again overwrites the point instead of adding a copy.
+pub fn chunk_point_id(
+    repo_name: &str,
+    relative_path: &str,
+    branch: &str,
+    kind: &str,
+    byte_range: std::ops::Range<u64>,
+    content_hash: &str,
+) -> Uuid {
+    point_id(&[
+        "chunk",
+        repo_name,
+        relative_path,
+        branch,
+        kind,
+        &byte_range.start.to_string(),
+        &byte_range.end.to_string(),
+        content_hash,
+    ])
+}
+
+/// Id of a symbol point. There is one point per name and branch holding the occurrences of every
+/// kind, so the kinds aren't part of it.
+pub fn symbol_point_id(repo_name: &str, branch: &str, symbol: &str) -> Uuid {
+    point_id(&["symbol", repo_name, branch, symbol])
+}
+
+// A version 8 uuid from the hash of the parts, qdrant only takes integers and uuids as ids.
+fn point_id(parts: &[&str]) -> Uuid {
+    let mut hash = Hasher::new();
+    hash.update(&POINT_ID_SCHEME.to_le_bytes());
+    for part in parts {
+        // parts are separated so ("ab", "c") and ("a", "bc") don't collide.
+        hash.update(&(part.len() as u64).to_le_bytes());
+        hash.update(part.as_bytes());
+    }
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash.finalize().as_bytes()[..16]);
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::compute_hashes;
+    use super::*;
     use std::path::PathBuf;
 
     #[test]
@@ -51,4 +99,24 @@ mod tests {
         assert_eq!(semantic_hash, expected_semantic_hash);
         assert_eq!(tantivy_hash, expected_tantivy_hash);
     }
+
+    #[test]
+    fn test_point_ids_only_change_with_their_inputs() {
+        let id = |path: &str, range: std::ops::Range<u64>, hash: &str| {
+            chunk_point_id("repo", path, "refs/heads/main", "code", range, hash)
+        };
+        assert_eq!(id("src/a.rs", 0..10, "h1"), id("src/a.rs", 0..10, "h1"));
+        assert_ne!(id("src/a.rs", 0..10, "h1"), id("src/a.rs", 0..11, "h1"));
+        assert_ne!(id("src/a.rs", 0..10, "h1"), id("src/a.rs", 0..10, "h2"));
+        assert_ne!(id("src/a.rs", 0..10, "h1"), id("src/b.rs", 0..10, "h1"));
+        assert_ne!(
+            chunk_point_id("repo", "src/a.rs", "refs/heads/main", "code", 0..10, "h1"),
+            chunk_point_id("repo", "src/a.rs", "refs/heads/dev", "code", 0..10, "h1")
+        );
+        let symbol_id = |branch: &str, symbol: &str| symbol_point_id("repo", branch, symbol);
+        assert_eq!(symbol_id("main", "retry"), symbol_id("main", "retry"));
+        assert_ne!(symbol_id("main", "retry"), symbol_id("main", "Retry"));
+        assert_ne!(symbol_id("main", "retry"), symbol_id("release-1.2", "retry"));
+        assert_eq!(id("src/a.rs", 0..10, "h1").get_version_num(), 8);
+    }
 }
@@ -4,6 +4,9 @@ use smallvec::SmallVec;
 use std::collections::HashMap;
 use std::path::Path;
 
+use crate::config::get_config_file_extensions;
+use crate::semantic_index::config_chunking::CONFIG_LANGUAGES;
+
 pub fn index_filter<P: AsRef<Path>>(p: &P) -> bool {
     let path = p.as_ref();
 
@@ -49,6 +52,16 @@ pub fn index_filter<P: AsRef<Path>>(p: &P) -> bool {
         return false;
     }
 
+    // Config files are indexed unless their extension is left out of `CONFIG_FILE_EXTENSIONS`.
+    // Example: with `CONFIG_FILE_EXTENSIONS=yaml,yml`, "Cargo.toml" returns false
+    if CONFIG_LANGUAGES.iter().any(|(config_ext, _)| config_ext.eq_ignore_ascii_case(&ext))
+        && !get_config_file_extensions()
+            .iter()
+            .any(|enabled| enabled.eq_ignore_ascii_case(&ext))
+    {
+        return false;
+    }
+
     // Defining vendor patterns to match against the file path.
     static VENDOR_PATTERNS: Lazy<HashMap<&'static str, SmallVec<[Regex; 1]>>> = Lazy::new(|| {
         let patterns: &[(&[&str], &[&str])] = &[
@@ -143,7 +156,9 @@ mod tests {
             ("path/with/.git/inside", false),
             ("path/with.git/inside", true),
             ("path/with/some_exe.com", false),
-            (".github/workflows//dependencies.yml", false),
+            (".github/workflows//dependencies.yml", true),
+            ("deploy/api.yaml", true),
+            ("Cargo.toml", true),
             // TODO: Directories and some files inside node_modules still gets read, take a look at it later.
             // commenting the test case for now.
             ("node_modules/undefsafe/workflows", false),
@@ -1,98 +1,398 @@
-use crate::config::{get_quickwit_url, get_yaml_config_path};
+use crate::config::{get_quickwit_max_in_flight_batches, get_quickwit_url, get_yaml_config_path};
 use crate::FileFields;
 use anyhow::{anyhow, Result};
+use common::branch::quickwit_branch_query;
+use common::hasher::generate_quikwit_index_name;
+use common::metrics;
+use common::run_manifest::{RunManifest, RUN_MANIFEST_PATH};
 use futures::stream::StreamExt;
 use itertools::Itertools;
 use std::error::Error;
 use std::fs::{self, File};
+use std::future::Future;
 use std::io::Write;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-use crate::generate_index_schema;
-
-pub async fn process_entries(all_entries: Vec<FileFields>, repo_name: &str) {
-    // println!("creating yaml schema");
-    // read yaml config path from env
-    let yaml_config_path = get_yaml_config_path();
-    let path = Path::new(&yaml_config_path);
-    generate_index_schema::replace_index_id_in_yaml(path, repo_name);
-    let quickwit_url = get_quickwit_url();
-    let url = &format!("{}/api/v1/indexes", &quickwit_url);
-
-    log::debug!("Sending first yaml to server...");
-    let response = send_yaml_to_server(&get_yaml_config_path(), url).await;
-    match response {
-        Ok(_) => {
-            log::info!("Successfully sent yaml to server");
+use crate::generate_index_schema::{
+    diff_field_mappings, generate_index_schema, persist_index_schema,
+};
+
+// What the index run did to the quickwit index of the repo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuickwitIndexSummary {
+    pub index_id: String,
+    // whether the index was created by this run.
+    pub created: bool,
+    pub documents: usize,
+}
+
+/// Creates the quickwit index of the repo from the generated schema when it doesn't exist yet.
+/// An existing index must have the same field mappings, otherwise the documents would be
+/// ingested into a schema search doesn't expect. Returns whether the index was created.
+pub async fn ensure_index(quickwit_url: &str, index_id: &str) -> Result<bool> {
+    let schema = generate_index_schema(index_id);
+    if let Some(path) = get_yaml_config_path() {
+        if let Err(e) = persist_index_schema(&schema, Path::new(&path)) {
+            log::warn!("Failed to write the index config to {}: {}", path, e);
         }
-        Err(e) => {
-            log::error!("Failed to send yaml to server: Aborting {:?}", e);
+    }
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("{}/api/v1/indexes/{}", quickwit_url, index_id))
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        log::info!("Creating quickwit index {}", index_id);
+        let response = client
+            .post(format!("{}/api/v1/indexes", quickwit_url))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&schema)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Failed to create quickwit index {}: {}",
+                index_id,
+                response.text().await?
+            ));
         }
+        return Ok(true);
+    }
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Failed to fetch quickwit index {}: {}",
+            index_id,
+            response.text().await?
+        ));
     }
-    // println!("out");
-    let entries = all_entries.clone();
-    let iter = entries.iter();
-    let chunks = iter.chunks(3);
-    let all_entries_stream = futures::stream::iter(&chunks);
 
-    // let all_entries_stream = futures::stream::iter(&entries.iter().chunks(3));
+    let metadata: serde_json::Value = response.json().await?;
+    let existing = metadata["index_config"]["doc_mapping"]["field_mappings"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let mismatches = diff_field_mappings(&schema, &existing);
+    if !mismatches.is_empty() {
+        return Err(anyhow!(
+            "Quickwit index {} doesn't match the expected schema, delete it or fix the fields:\n  {}",
+            index_id,
+            mismatches.iter().map(ToString::to_string).join("\n  ")
+        ));
+    }
+    log::info!("Quickwit index {} exists with the expected schema", index_id);
+    Ok(false)
+}
 
-    all_entries_stream
-        .for_each_concurrent(Some(10), |chunk| async {
-            let url = format!(
-                "{}/api/v1/{}/ingest?commit=force",
-                &quickwit_url, &repo_name
-            );
+// documents sent to quickwit in a single ingest request.
+const INGEST_BATCH_SIZE: usize = 3;
 
-            let json_data_vec: Result<Vec<String>, _> = chunk
-                .into_iter()
-                .map(|record| serde_json::to_string(record))
-                .collect();
-
-            match json_data_vec {
-                Ok(data_vec) => {
-                    let batch_data = data_vec.join("\n");
-                    match send_content_to_server(&batch_data, &url).await {
-                        Ok(response) => {
-                            // Handle the response immediately if necessary.
-                            println!("Successfully sent data: {:?}", response);
-                        }
-                        Err(e) => {
-                            println!("Failed to send data: {:?}", e);
-                        }
+// Sends the file documents to the quickwit index of the repo in small concurrent batches.
+pub async fn ingest_entries(entries: impl IntoIterator<Item = FileFields>, repo_name: &str) {
+    let mut sink = QuickwitSink::new(repo_name, get_quickwit_max_in_flight_batches());
+    for entry in entries {
+        sink.push(entry).await;
+    }
+    sink.finish().await;
+}
+
+/// Streams the file documents to quickwit while the repo is walked, instead of holding all of
+/// them until the end. At most `max_in_flight` batches are sent at once and as many wait for
+/// their turn, `push` waits when quickwit falls behind, so the documents in memory stay bounded
+/// however large the repo is. A document is dropped once its batch is sent.
+pub struct QuickwitSink {
+    batch: Vec<FileFields>,
+    batch_size: usize,
+    // None when the documents are only counted.
+    sender: Option<mpsc::Sender<Vec<FileFields>>>,
+    ingester: Option<JoinHandle<()>>,
+    // relative paths of the documents quickwit acknowledged, until they are taken.
+    acknowledged: Arc<Mutex<Vec<String>>>,
+    documents: usize,
+}
+
+impl QuickwitSink {
+    pub fn new(repo_name: &str, max_in_flight: usize) -> Self {
+        let url = format!(
+            "{}/api/v1/{}/ingest?commit=force",
+            get_quickwit_url(),
+            generate_quikwit_index_name(repo_name)
+        );
+        let repo_name = repo_name.to_string();
+        Self::with_sender(INGEST_BATCH_SIZE, max_in_flight, move |batch| {
+            let url = url.clone();
+            let repo_name = repo_name.clone();
+            async move { send_batch(&batch, &url, &repo_name).await }
+        })
+    }
+
+    // for a resumed run whose documents quickwit already has.
+    pub fn counting() -> Self {
+        Self {
+            batch: Vec::new(),
+            batch_size: INGEST_BATCH_SIZE,
+            sender: None,
+            ingester: None,
+            acknowledged: Arc::default(),
+            documents: 0,
+        }
+    }
+
+    // `send` tells whether quickwit acknowledged the batch.
+    pub(crate) fn with_sender<S, F>(batch_size: usize, max_in_flight: usize, send: S) -> Self
+    where
+        S: Fn(Vec<FileFields>) -> F + Send + 'static,
+        F: Future<Output = bool> + Send + 'static,
+    {
+        let max_in_flight = max_in_flight.max(1);
+        let (sender, receiver) = mpsc::channel::<Vec<FileFields>>(max_in_flight);
+        let batches = futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|batch| (batch, receiver))
+        });
+        let acknowledged: Arc<Mutex<Vec<String>>> = Arc::default();
+        let sent = acknowledged.clone();
+        let ingester = tokio::spawn(batches.for_each_concurrent(
+            Some(max_in_flight),
+            move |batch| {
+                let paths = batch
+                    .iter()
+                    .map(|fields| fields.relative_path.clone())
+                    .collect::<Vec<_>>();
+                let send = send(batch);
+                let sent = sent.clone();
+                async move {
+                    if send.await {
+                        sent.lock().unwrap().extend(paths);
                     }
                 }
+            },
+        ));
+        Self {
+            batch: Vec::with_capacity(batch_size),
+            batch_size,
+            sender: Some(sender),
+            ingester: Some(ingester),
+            acknowledged,
+            documents: 0,
+        }
+    }
+
+    /// Counts a document quickwit already has from the run being resumed, without sending it.
+    pub fn skip(&mut self) {
+        self.documents += 1;
+    }
+
+    /// The relative paths of the documents quickwit acknowledged since the last call.
+    pub fn take_acknowledged(&self) -> Vec<String> {
+        std::mem::take(&mut *self.acknowledged.lock().unwrap())
+    }
+
+    pub async fn push(&mut self, fields: FileFields) {
+        self.documents += 1;
+        if self.sender.is_none() {
+            return;
+        }
+        self.batch.push(fields);
+        if self.batch.len() >= self.batch_size {
+            self.flush().await;
+        }
+    }
+
+    // waits for a free slot when all the batches are taken.
+    async fn flush(&mut self) {
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        if batch.is_empty() {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            if sender.send(batch).await.is_err() {
+                log::error!("The quickwit ingester stopped, the remaining documents are not sent");
+                metrics::record_ingestion_failure("quickwit");
+            }
+        }
+    }
+
+    /// Sends the last batch and waits for every batch to be sent, returns the number of documents.
+    pub async fn finish(&mut self) -> usize {
+        self.flush().await;
+        self.sender.take();
+        if let Some(ingester) = self.ingester.take() {
+            if let Err(e) = ingester.await {
+                log::error!("The quickwit ingester failed: {}", e);
+            }
+        }
+        self.documents
+    }
+}
+
+// Whether quickwit acknowledged the batch.
+async fn send_batch(batch: &[FileFields], url: &str, repo_name: &str) -> bool {
+    let json_data_vec: Result<Vec<String>, _> = batch
+        .iter()
+        .map(|record| serde_json::to_string(record))
+        .collect();
+
+    match json_data_vec {
+        Ok(data_vec) => {
+            let batch_data = data_vec.join("\n");
+            match send_content_to_server(&batch_data, url).await {
+                Ok(response) => {
+                    // Handle the response immediately if necessary.
+                    println!("Successfully sent data: {:?}", response);
+                    metrics::record_files_indexed(repo_name, data_vec.len());
+                    true
+                }
                 Err(e) => {
-                    println!("Error serializing data: {:?}", e);
+                    println!("Failed to send data: {:?}", e);
+                    metrics::record_ingestion_failure("quickwit");
+                    false
                 }
             }
-        })
-        .await;
+        }
+        Err(e) => {
+            println!("Error serializing data: {:?}", e);
+            metrics::record_ingestion_failure("serialize");
+            false
+        }
+    }
 }
 
-async fn send_yaml_to_server(yaml_path: &str, url: &str) -> anyhow::Result<()> {
-    println!("Reading YAML file...");
-    // Read the YAML file content
-    let yaml_content = fs::read_to_string(yaml_path)?;
+// Removes the document of a single file of the branch from the quickwit index of the repo
+// by creating a delete task on the relative path.
+pub async fn delete_file_document(repo_name: &str, branch: &str, relative_path: &str) -> Result<()> {
+    let query = format!("relative_path:\"{}\"", relative_path.replace('"', "\\\""));
+    delete_documents(repo_name, &quickwit_branch_query(&query, branch))
+        .await
+        .map_err(|e| anyhow!("Failed to delete {} from quickwit: {}", relative_path, e))
+}
 
-    println!("Making POST request...");
-    // Make the POST request
-    let client = reqwest::Client::new();
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/yaml")
-        .body(yaml_content)
+/// Removes every document of the branch from the quickwit index of the repo, the documents of
+/// the other branches stay.
+pub async fn delete_branch_documents(repo_name: &str, branch: &str) -> Result<()> {
+    delete_documents(repo_name, &quickwit_branch_query("*", branch))
+        .await
+        .map_err(|e| anyhow!("Failed to delete the branch {} from quickwit: {}", branch, e))
+}
+
+async fn delete_documents(repo_name: &str, query: &str) -> Result<()> {
+    let url = format!(
+        "{}/api/v1/{}/delete-tasks",
+        get_quickwit_url(),
+        generate_quikwit_index_name(repo_name)
+    );
+    let body = serde_json::json!({ "query": query });
+
+    let response = reqwest::Client::new()
+        .post(&url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
         .send()
         .await?;
 
-    // Print the response status and text
-    println!("Status Yaml: {}", response.status());
-    // println!("Response Yaml: {}", response.text().await?);
+    if !response.status().is_success() {
+        return Err(anyhow!("{}", response.text().await?));
+    }
+    Ok(())
+}
+
+/// A page of the documents of the quickwit index of the repo as quickwit returns them, along with
+/// the number of documents in the index.
+pub async fn search_documents(
+    repo_name: &str,
+    start_offset: u64,
+    max_hits: u32,
+) -> Result<(u64, Vec<serde_json::Value>)> {
+    let url = format!(
+        "{}/api/v1/{}/search",
+        get_quickwit_url(),
+        generate_quikwit_index_name(repo_name)
+    );
+    let response = reqwest::Client::new()
+        .get(&url)
+        .query(&[
+            ("query", "*".to_string()),
+            ("start_offset", start_offset.to_string()),
+            ("max_hits", max_hits.to_string()),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Failed to read the documents of {} from quickwit: {}",
+            repo_name,
+            response.text().await?
+        ));
+    }
+    let mut body: serde_json::Value = response.json().await?;
+    let hits = match body["hits"].take() {
+        serde_json::Value::Array(hits) => hits,
+        _ => Vec::new(),
+    };
+    Ok((body["num_hits"].as_u64().unwrap_or(0), hits))
+}
+
+/// The manifest of the last run of the branch, None when the branch wasn't indexed yet.
+pub async fn fetch_run_manifest(repo_name: &str, branch: &str) -> Result<Option<RunManifest>> {
+    let url = format!(
+        "{}/api/v1/{}/search",
+        get_quickwit_url(),
+        generate_quikwit_index_name(repo_name)
+    );
+    let query = format!("relative_path:\"{}\"", RUN_MANIFEST_PATH);
+    let response = reqwest::Client::new()
+        .get(&url)
+        .query(&[
+            ("query", quickwit_branch_query(&query, branch)),
+            ("max_hits", "1".to_string()),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Failed to read the run manifest of {} from quickwit: {}",
+            repo_name,
+            response.text().await?
+        ));
+    }
+    let body: serde_json::Value = response.json().await?;
+    match body["hits"][0]["content"].as_str() {
+        Some(content) => Ok(Some(serde_json::from_str(content)?)),
+        None => Ok(None),
+    }
+}
 
-    // return error if status is not 200
-    if response.status() != 200 {
-        return Err(anyhow!("Error sending yaml to server"));
+/// Ingests the documents into the quickwit index of the repo and waits for them to be committed,
+/// unlike the sink a failed request is returned.
+pub async fn ingest_documents(repo_name: &str, documents: &[serde_json::Value]) -> Result<()> {
+    let url = format!(
+        "{}/api/v1/{}/ingest?commit=force",
+        get_quickwit_url(),
+        generate_quikwit_index_name(repo_name)
+    );
+    let body = documents
+        .iter()
+        .map(serde_json::Value::to_string)
+        .join("\n");
+    let response = reqwest::Client::new()
+        .post(&url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        metrics::record_ingestion_failure("quickwit");
+        return Err(anyhow!(
+            "Failed to ingest {} documents of {}: {}",
+            documents.len(),
+            repo_name,
+            response.text().await?
+        ));
     }
+    metrics::record_files_indexed(repo_name, documents.len());
     Ok(())
 }
 
@@ -139,8 +439,178 @@ async fn send_content_to_server(content: &str, url: &str) -> Result<()> {
         .await?;
 
     // Print the response status and text
-    println!("Status Json: {}", response.status());
-    println!("Response Json: {}", response.text().await?);
+    let status = response.status();
+    println!("Status Json: {}", status);
+    let text = response.text().await?;
+    println!("Response Json: {}", text);
+    // the documents of a batch quickwit turned away aren't recorded as sent.
+    if !status.is_success() {
+        return Err(anyhow!("Quickwit responded with {}: {}", status, text));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    // quickwit admin API with a single index, `None` until it is created.
+    fn mock_quickwit(index: Option<serde_json::Value>) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let get = warp::path!("api" / "v1" / "indexes" / String)
+            .and(warp::get())
+            .map(move |_index_id: String| match &index {
+                Some(metadata) => warp::reply::with_status(
+                    warp::reply::json(metadata),
+                    warp::http::StatusCode::OK,
+                ),
+                None => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({"message": "index not found"})),
+                    warp::http::StatusCode::NOT_FOUND,
+                ),
+            });
+        let requests = created.clone();
+        let create = warp::path!("api" / "v1" / "indexes")
+            .and(warp::post())
+            .and(warp::body::json::<serde_json::Value>())
+            .map(move |config: serde_json::Value| {
+                requests.lock().unwrap().push(config.clone());
+                warp::reply::json(&serde_json::json!({"index_config": config}))
+            });
+        let (addr, server) = warp::serve(get.or(create)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), created)
+    }
+
+    fn index_metadata(field_mappings: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "index_uid": "repo:01H",
+            "index_config": {
+                "index_id": "repo",
+                "doc_mapping": {"field_mappings": field_mappings}
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_ensure_index_creates_missing_index() {
+        let (url, created) = mock_quickwit(None);
+
+        assert!(ensure_index(&url, "repo").await.unwrap());
+
+        let created = created.lock().unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0]["index_id"], "repo");
+        assert_eq!(
+            created[0],
+            serde_json::to_value(generate_index_schema("repo")).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ensure_index_keeps_matching_index() {
+        let schema = generate_index_schema("repo");
+        let (url, created) = mock_quickwit(Some(index_metadata(
+            serde_json::to_value(&schema.doc_mapping.field_mappings).unwrap(),
+        )));
+
+        assert!(!ensure_index(&url, "repo").await.unwrap());
+        assert!(created.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_index_fails_on_mismatched_schema() {
+        let schema = generate_index_schema("repo");
+        let mut field_mappings = serde_json::to_value(&schema.doc_mapping.field_mappings).unwrap();
+        for mapping in field_mappings.as_array_mut().unwrap() {
+            if mapping["name"] == "content" {
+                mapping["tokenizer"] = serde_json::json!("raw");
+            }
+        }
+        let (url, created) = mock_quickwit(Some(index_metadata(field_mappings)));
+
+        let error = ensure_index(&url, "repo").await.unwrap_err().to_string();
+
+        assert!(error.contains("Quickwit index repo doesn't match"), "{}", error);
+        assert!(
+            error.contains("content: expected tokenizer default, found tokenizer raw"),
+            "{}",
+            error
+        );
+        assert!(created.lock().unwrap().is_empty());
+    }
+
+    fn file_fields(i: usize) -> FileFields {
+        FileFields {
+            tenant_id: "tenant".to_string(),
+            repo_name: "repo".to_string(),
+            repo_disk_path: "/repo".to_string(),
+            repo_ref: String::new(),
+            relative_path: format!("src/file_{}.rs", i),
+            last_commit: String::new(),
+            lang: "Rust".to_string(),
+            is_directory: false,
+            avg_line_length: 10.0,
+            line_end_indices: Vec::new(),
+            content: "fn main() {}\n".repeat(100),
+            symbol_locations: Vec::new(),
+            unique_hash: i.to_string(),
+            symbols: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_documents_stay_within_the_in_flight_bound() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (batch_size, max_in_flight) = (3, 4);
+        let sent = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak_in_flight = Arc::new(AtomicUsize::new(0));
+
+        // a slow quickwit, the walk has to wait for it.
+        let (sent_by_ingester, in_flight_by_ingester, peak) =
+            (sent.clone(), in_flight.clone(), peak_in_flight.clone());
+        let mut sink = QuickwitSink::with_sender(batch_size, max_in_flight, move |batch| {
+            let (sent, in_flight, peak) = (
+                sent_by_ingester.clone(),
+                in_flight_by_ingester.clone(),
+                peak.clone(),
+            );
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+                sent.fetch_add(batch.len(), Ordering::SeqCst);
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                true
+            }
+        });
+
+        // the batches waiting and in flight, and the one being filled.
+        let bound = (2 * max_in_flight + 1) * batch_size;
+        let mut peak_held = 0;
+        for i in 0..1000 {
+            sink.push(file_fields(i)).await;
+            peak_held = peak_held.max(i + 1 - sent.load(Ordering::SeqCst));
+        }
+
+        assert_eq!(sink.finish().await, 1000);
+        assert_eq!(sent.load(Ordering::SeqCst), 1000);
+        assert!(peak_in_flight.load(Ordering::SeqCst) <= max_in_flight);
+        assert!(peak_held <= bound, "{} documents held, bound {}", peak_held, bound);
+    }
+
+    #[tokio::test]
+    async fn test_counting_sink_sends_nothing() {
+        let mut sink = QuickwitSink::counting();
+        for i in 0..10 {
+            sink.push(file_fields(i)).await;
+        }
+
+        assert_eq!(sink.finish().await, 10);
+    }
+}
@@ -0,0 +1,421 @@
+// Ranks the indexed files of a repo by how central they look: how many definitions they hold, how
+// many other files use them, and whether their names are the ones of entrypoints, routing tables
+// or config. The top files are stored in the repo summary, so the agent knows about them before
+// its first search, see `common::repo_summary`.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use common::path_class::PathClass;
+use common::repo_summary::MAX_KEY_FILES;
+
+// names shorter than this are too generic to count as a use of their definition.
+const MIN_REFERENCE_NAME_CHARS: usize = 3;
+// a name defined in more files than this, e.g. `handler`, doesn't tell which of them is used.
+const MAX_DEFINING_FILES: usize = 3;
+
+// File stems of entrypoints, routing tables and config.
+const ENTRYPOINT_STEMS: &[&str] = &[
+    "main",
+    "lib",
+    "index",
+    "app",
+    "server",
+    "__main__",
+    "manage",
+    "program",
+    "application",
+];
+const ROUTING_STEMS: &[&str] = &[
+    "routes",
+    "router",
+    "routing",
+    "urls",
+    "handlers",
+    "controller",
+    "controllers",
+    "api",
+    "endpoints",
+];
+const CONFIG_STEMS: &[&str] = &["config", "configuration", "settings", "constants"];
+// how much each kind of name says about the file.
+const PATH_HINTS: &[(&[&str], f64)] = &[
+    (ENTRYPOINT_STEMS, 1.0),
+    (ROUTING_STEMS, 0.8),
+    (CONFIG_STEMS, 0.6),
+];
+// score of the files under a directory named like a routing table, e.g. `routes/orders.rs`.
+const ROUTING_DIRECTORY_HINT: f64 = 0.4;
+
+/// Weights of the signals the files are ranked with, set with `KEY_FILE_WEIGHTS`,
+/// e.g. `symbols=1,references=2,path=1.5,size=0.5`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyFileWeights {
+    // definitions in the file.
+    pub symbols: f64,
+    // other files using its definitions.
+    pub references: f64,
+    // entrypoint, routing and config file names.
+    pub path: f64,
+    // taken off the larger files, so a file isn't ranked on its size alone.
+    pub size: f64,
+}
+
+impl Default for KeyFileWeights {
+    fn default() -> Self {
+        Self {
+            symbols: 1.0,
+            references: 2.0,
+            path: 1.5,
+            size: 0.5,
+        }
+    }
+}
+
+impl FromStr for KeyFileWeights {
+    type Err = anyhow::Error;
+
+    /// The weights left out keep their default.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut weights = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected <signal>=<weight>, got {}", pair))?;
+            let value = value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|value| *value >= 0.0)
+                .ok_or_else(|| {
+                    anyhow!("the weight of {} must be a positive number", name.trim())
+                })?;
+            let weight = match name.trim() {
+                "symbols" => &mut weights.symbols,
+                "references" => &mut weights.references,
+                "path" => &mut weights.path,
+                "size" => &mut weights.size,
+                other => return Err(anyhow!("unknown key file signal {}", other)),
+            };
+            *weight = value;
+        }
+        Ok(weights)
+    }
+}
+
+/// A ranked file with the signals it was ranked with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedFile {
+    pub path: String,
+    pub score: f64,
+    pub symbols: usize,
+    pub referenced_by: usize,
+    pub lines: usize,
+}
+
+#[derive(Debug)]
+struct FileStats {
+    path: String,
+    lines: usize,
+    symbols: usize,
+    // names of the top level definitions, the ones other files can use.
+    definitions: Vec<String>,
+    identifiers: HashSet<String>,
+}
+
+/// Collects the definitions and identifiers of the indexed files while the tree is walked.
+/// Test and vendored files are left out.
+#[derive(Debug, Default)]
+pub struct KeyFilesBuilder {
+    // names never counted as a use, e.g. `new` or `main`, see `SYMBOL_STOP_LIST`.
+    ignored_names: HashSet<String>,
+    files: Vec<FileStats>,
+}
+
+impl KeyFilesBuilder {
+    pub fn new(ignored_names: &[String]) -> Self {
+        Self {
+            ignored_names: ignored_names
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Counts an indexed file with its number of definitions and the names of its top level ones.
+    pub fn add_file<'a>(
+        &mut self,
+        path: &str,
+        content: &str,
+        symbols: usize,
+        definitions: impl IntoIterator<Item = &'a str>,
+    ) {
+        if PathClass::of(path).is_flagged() {
+            return;
+        }
+        let mut definitions = definitions
+            .into_iter()
+            .filter(|name| self.counts_as_reference(name))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        definitions.sort();
+        definitions.dedup();
+        let identifiers = content
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .filter(|word| self.counts_as_reference(word))
+            .map(str::to_string)
+            .collect();
+        self.files.push(FileStats {
+            path: path.to_string(),
+            lines: content.lines().count(),
+            symbols,
+            definitions,
+            identifiers,
+        });
+    }
+
+    fn counts_as_reference(&self, name: &str) -> bool {
+        name.chars().count() >= MIN_REFERENCE_NAME_CHARS
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && !self.ignored_names.contains(&name.to_lowercase())
+    }
+
+    /// The files, most central first.
+    pub fn rank(&self, weights: &KeyFileWeights) -> Vec<RankedFile> {
+        let referenced_by = self.referenced_by();
+        let max_symbols = self
+            .files
+            .iter()
+            .map(|file| file.symbols)
+            .max()
+            .unwrap_or(0);
+        let max_references = referenced_by.iter().copied().max().unwrap_or(0);
+        let max_lines = self.files.iter().map(|file| file.lines).max().unwrap_or(0);
+
+        let mut ranked = self
+            .files
+            .iter()
+            .zip(referenced_by)
+            .map(|(file, referenced_by)| {
+                let score = weights.symbols * log_share(file.symbols, max_symbols)
+                    + weights.references * share(referenced_by, max_references)
+                    + weights.path * path_score(&file.path)
+                    - weights.size * log_share(file.lines, max_lines);
+                RankedFile {
+                    path: file.path.clone(),
+                    score,
+                    symbols: file.symbols,
+                    referenced_by,
+                    lines: file.lines,
+                }
+            })
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        ranked
+    }
+
+    /// The paths of the top `MAX_KEY_FILES` files.
+    pub fn build(self, weights: &KeyFileWeights) -> Vec<String> {
+        self.rank(weights)
+            .into_iter()
+            .take(MAX_KEY_FILES)
+            .map(|file| file.path)
+            .collect()
+    }
+
+    // The number of other files using a definition of each file, a file is counted once
+    // whatever the number of definitions it uses.
+    fn referenced_by(&self) -> Vec<usize> {
+        let mut defining_files: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, file) in self.files.iter().enumerate() {
+            for name in &file.definitions {
+                defining_files.entry(name.as_str()).or_default().push(i);
+            }
+        }
+
+        let mut referenced_by = vec![0; self.files.len()];
+        for (i, file) in self.files.iter().enumerate() {
+            let used = file
+                .identifiers
+                .iter()
+                .filter_map(|identifier| defining_files.get(identifier.as_str()))
+                .filter(|files| files.len() <= MAX_DEFINING_FILES)
+                .flatten()
+                .filter(|defining| **defining != i)
+                .collect::<HashSet<_>>();
+            for defining in used {
+                referenced_by[*defining] += 1;
+            }
+        }
+        referenced_by
+    }
+}
+
+fn share(value: usize, max: usize) -> f64 {
+    if max == 0 {
+        return 0.0;
+    }
+    value as f64 / max as f64
+}
+
+// On a log scale, a file with twice the definitions of another isn't twice as central.
+fn log_share(value: usize, max: usize) -> f64 {
+    if max == 0 {
+        return 0.0;
+    }
+    (1.0 + value as f64).ln() / (1.0 + max as f64).ln()
+}
+
+fn path_score(path: &str) -> f64 {
+    let (directories, file_name) = match path.rsplit_once('/') {
+        Some((directories, file_name)) => (directories, file_name),
+        None => ("", path),
+    };
+    let stem = file_name
+        .split('.')
+        .next()
+        .unwrap_or(file_name)
+        .to_lowercase();
+    let words = stem
+        .split(['_', '-'])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let hinted =
+        |names: &[&str]| names.contains(&stem.as_str()) || words.iter().any(|w| names.contains(w));
+
+    PATH_HINTS
+        .iter()
+        .find(|(names, _)| hinted(names))
+        .map(|(_, score)| *score)
+        .or_else(|| {
+            directories
+                .split('/')
+                .any(|directory| ROUTING_STEMS.contains(&directory.to_lowercase().as_str()))
+                .then_some(ROUTING_DIRECTORY_HINT)
+        })
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small web service: its entrypoint, routes and config, the handlers the routes call, a
+    // leaf helper used by one handler, and its tests.
+    const FIXTURE_REPO: &[(&str, &str, &[&str])] = &[
+        (
+            "src/main.rs",
+            "mod config;\nmod routes;\n\nfn main() {\n    let settings = config::load_settings();\n    routes::build_router(settings);\n}\n",
+            &["main"],
+        ),
+        (
+            "src/routes.rs",
+            "use crate::orders::{create_order, list_orders};\nuse crate::payments::charge_card;\n\npub fn build_router(settings: Settings) -> Router {\n    Router::new()\n        .route(\"/orders\", post(create_order).get(list_orders))\n        .route(\"/charge\", post(charge_card))\n}\n",
+            &["build_router"],
+        ),
+        (
+            "src/config.rs",
+            "pub struct Settings { pub port: u16 }\n\npub fn load_settings() -> Settings {\n    Settings { port: 8080 }\n}\n",
+            &["Settings", "load_settings"],
+        ),
+        (
+            "src/orders.rs",
+            "use crate::format::format_money;\n\npub fn create_order() {}\n\npub fn list_orders() {\n    format_money(100);\n}\n\nfn order_total() {}\n",
+            &["create_order", "list_orders", "order_total"],
+        ),
+        (
+            "src/payments.rs",
+            "pub fn charge_card() {}\n\nfn refund_card() {}\n",
+            &["charge_card", "refund_card"],
+        ),
+        (
+            "src/util/format.rs",
+            "pub fn format_money(cents: u64) -> String {\n    format!(\"{}.{:02}\", cents / 100, cents % 100)\n}\n\npub fn pad_left() {}\n",
+            &["format_money", "pad_left"],
+        ),
+        (
+            "tests/orders_test.rs",
+            "use shop::orders::{create_order, list_orders};\nuse shop::routes::build_router;\nuse shop::util::format::format_money;\n",
+            &["test_orders"],
+        ),
+    ];
+
+    fn rank_fixture_repo(weights: &KeyFileWeights) -> Vec<RankedFile> {
+        let mut builder = KeyFilesBuilder::new(&["main".to_string(), "new".to_string()]);
+        for (path, content, definitions) in FIXTURE_REPO {
+            builder.add_file(
+                path,
+                content,
+                definitions.len(),
+                definitions.iter().copied(),
+            );
+        }
+        builder.rank(weights)
+    }
+
+    fn position(ranked: &[RankedFile], path: &str) -> usize {
+        ranked.iter().position(|file| file.path == path).unwrap()
+    }
+
+    #[test]
+    fn test_entrypoint_and_routes_outrank_leaf_utilities() {
+        let ranked = rank_fixture_repo(&KeyFileWeights::default());
+
+        let leaf = position(&ranked, "src/util/format.rs");
+        assert!(position(&ranked, "src/main.rs") < leaf, "{:#?}", ranked);
+        assert!(position(&ranked, "src/routes.rs") < leaf, "{:#?}", ranked);
+        assert!(position(&ranked, "src/payments.rs") > position(&ranked, "src/routes.rs"));
+        // the test file uses the helper, but test code doesn't count.
+        assert!(ranked.iter().all(|file| !file.path.starts_with("tests/")));
+        let format = &ranked[leaf];
+        assert_eq!(format.referenced_by, 1);
+        let routes = &ranked[position(&ranked, "src/routes.rs")];
+        assert_eq!(routes.referenced_by, 1);
+        let orders = &ranked[position(&ranked, "src/orders.rs")];
+        assert_eq!(orders.referenced_by, 1);
+    }
+
+    #[test]
+    fn test_weights_change_the_ranking() {
+        // on the references alone the config, used by the entrypoint and the routes, comes first.
+        let weights = KeyFileWeights {
+            symbols: 0.0,
+            references: 1.0,
+            path: 0.0,
+            size: 0.0,
+        };
+        let ranked = rank_fixture_repo(&weights);
+        assert_eq!(ranked[0].path, "src/config.rs");
+        assert_eq!(ranked.last().unwrap().path, "src/main.rs");
+
+        let weights = "references=0, path=3".parse::<KeyFileWeights>().unwrap();
+        assert_eq!(
+            weights,
+            KeyFileWeights {
+                references: 0.0,
+                path: 3.0,
+                ..Default::default()
+            }
+        );
+        assert_eq!(rank_fixture_repo(&weights)[0].path, "src/main.rs");
+        assert!("symbols=-1".parse::<KeyFileWeights>().is_err());
+        assert!("imports=1".parse::<KeyFileWeights>().is_err());
+    }
+
+    #[test]
+    fn test_key_files_are_bounded() {
+        let mut builder = KeyFilesBuilder::new(&[]);
+        for i in 0..MAX_KEY_FILES + 10 {
+            let path = format!("src/module_{}.rs", i);
+            builder.add_file(&path, "fn run() {}\n", 1, ["run"]);
+        }
+        let key_files = builder.build(&KeyFileWeights::default());
+        assert_eq!(key_files.len(), MAX_KEY_FILES);
+    }
+}
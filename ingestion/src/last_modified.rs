@@ -0,0 +1,131 @@
+// When each file of the indexed commit was last changed, so code search can prefer the recently
+// changed implementation of two that answer the same question. The history is walked from the
+// indexed commit newest first, every commit diffed with its first parent, and the first commit
+// changing a path is the one that last modified it. Only `max_commits` commits are walked, the
+// files untouched by them are given the time of the oldest walked commit, which they are older
+// than.
+
+use std::collections::HashMap;
+
+use git2::{Commit, Repository, Sort};
+
+/// Unix seconds of the last commit changing each file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LastModified {
+    by_path: HashMap<String, u64>,
+    // time of the oldest walked commit when the history was cut short, none when it was all walked.
+    older_than: Option<u64>,
+}
+
+impl LastModified {
+    /// When the file was last changed, at most the time of the oldest walked commit for the files
+    /// older than the walked history.
+    pub fn of(&self, path: &str) -> Option<u64> {
+        self.by_path.get(path).copied().or(self.older_than)
+    }
+}
+
+fn commit_time(commit: &Commit) -> u64 {
+    u64::try_from(commit.time().seconds()).unwrap_or_default()
+}
+
+/// Walks the last `max_commits` commits of the history of `head` for the time each file was last
+/// changed at.
+pub fn last_modified(
+    repo: &Repository,
+    head: &Commit,
+    max_commits: usize,
+) -> Result<LastModified, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TIME)?;
+    revwalk.push(head.id())?;
+
+    let mut last_modified = LastModified::default();
+    let mut walked = 0;
+    let mut oldest = None;
+    for oid in revwalk {
+        if walked == max_commits {
+            last_modified.older_than = oldest.or_else(|| Some(commit_time(head)));
+            break;
+        }
+        let commit = repo.find_commit(oid?)?;
+        let time = commit_time(&commit);
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        for delta in diff.deltas() {
+            let Some(path) = delta.new_file().path().and_then(|path| path.to_str()) else {
+                continue;
+            };
+            last_modified
+                .by_path
+                .entry(path.to_string())
+                .or_insert(time);
+        }
+        oldest = Some(time);
+        walked += 1;
+    }
+    Ok(last_modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Commits the files on top of the head at the given time.
+    fn commit_at(repo: &Repository, files: &[(&str, &str)], time: i64) -> git2::Oid {
+        let parent = repo
+            .head()
+            .ok()
+            .and_then(|head| head.target())
+            .map(|oid| repo.find_commit(oid).unwrap());
+        let mut builder = repo
+            .treebuilder(parent.as_ref().map(|p| p.tree().unwrap()).as_ref())
+            .unwrap();
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            builder.insert(*path, blob, 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let signature =
+            git2::Signature::new("dev", "dev@example.com", &git2::Time::new(time, 0)).unwrap();
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "change",
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_files_get_the_time_of_the_last_commit_changing_them() {
+        let disk_path = std::env::temp_dir().join(format!("repo-{}", uuid::Uuid::new_v4()));
+        let repo = Repository::init(&disk_path).unwrap();
+        commit_at(
+            &repo,
+            &[("legacy.rs", "fn pay() {}"), ("lib.rs", "mod a;")],
+            1_000,
+        );
+        commit_at(&repo, &[("lib.rs", "mod a;\nmod b;")], 2_000);
+        let head = commit_at(&repo, &[("payments.rs", "fn pay_v2() {}")], 3_000);
+        let head = repo.find_commit(head).unwrap();
+
+        let times = last_modified(&repo, &head, 100).unwrap();
+        assert_eq!(times.of("legacy.rs"), Some(1_000));
+        assert_eq!(times.of("lib.rs"), Some(2_000));
+        assert_eq!(times.of("payments.rs"), Some(3_000));
+        assert_eq!(times.of("missing.rs"), None);
+
+        // past the cap the older files are at most as recent as the oldest walked commit.
+        let capped = last_modified(&repo, &head, 2).unwrap();
+        assert_eq!(capped.of("legacy.rs"), Some(2_000));
+        assert_eq!(capped.of("payments.rs"), Some(3_000));
+        std::fs::remove_dir_all(&disk_path).unwrap();
+    }
+}
@@ -1,11 +1,12 @@
 // Import necessary modules from Rust's standard library
-use clap::Parser;
-use config::get_qdrant_url;
+use clap::{CommandFactory, Parser, Subcommand};
+use common::metrics;
+use common::hasher::generate_quikwit_index_name;
+use config::{get_qdrant_url, get_quickwit_url};
 use serde::Serialize;
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio;
 // Import the index_filter module
 mod index_filter;
@@ -23,9 +24,32 @@ mod index_processor;
 extern crate git2;
 mod ast;
 use crate::ast::symbol::{SymbolKey, SymbolLocations, SymbolValue};
+use crate::ast::doc_comment::DocComment;
+use crate::ast::query_pack::{self, install_query_packs};
 use crate::ast::CodeFileAST;
-use crate::config::initialize_config;
-use crate::semantic_index::{SemanticError, SemanticIndex};
+use common::branch::{branch_name, BRANCH_FIELD};
+use common::clock::unix_now;
+use common::codeowners::{self, CodeOwners};
+use common::embedded_lang::EMBEDDED_LANG_FIELD;
+use common::repo_summary::REPO_SUMMARY_PATH;
+use common::terminology::{is_terminology_source, TERMINOLOGY_SUGGESTIONS_PATH};
+use common::run_manifest::{public_remote_url, IndexMode, RunManifest, RUN_MANIFEST_PATH};
+use crate::collection_tuning::CollectionTuning;
+use crate::checkpoint::{
+    commit_files, CheckpointOptions, Checkpointer, CollectionGeneration, FileCommitter,
+};
+use crate::last_modified::LastModified;
+use crate::repo_summary::RepoSummaryBuilder;
+use crate::terminology::TerminologyMiner;
+use crate::config::{
+    get_collection_tuning, get_last_modified_max_commits, get_qdrant_warmup_searches,
+    get_query_packs_dir, get_quickwit_max_in_flight_batches,
+    get_size_limits, get_tenant_id, initialize_config, override_size_limits, override_tenant_id,
+};
+use crate::encoding::{self, Encoding, Undecodable};
+use crate::error::{boxed, IngestionError, Result};
+use crate::semantic_index::{ChunkedFile, SemanticError, SemanticIndex};
+use crate::size_limits::{SizeLimitOverride, SizeLimits, SkippedForSize};
 // Importing necessary types from the git2 crate
 use git2::{ObjectType, Repository as GitRepository};
 use qdrant_client::prelude::{QdrantClient, QdrantClientConfig};
@@ -33,9 +57,23 @@ use qdrant_client::qdrant::CollectionOperationResponse;
 use qdrant_client::qdrant::{
     vectors_config, CreateCollection, Distance, FieldType, VectorParams, VectorsConfig,
 };
-use tracing::debug;
-
+use tracing::{debug, Instrument};
+
+mod checkpoint;
+mod collection_tuning;
+mod embedding_cache;
+mod encoding;
+mod error;
+mod key_files;
+mod last_modified;
+mod migrate;
+mod repo_summary;
+mod run_manifest;
 mod semantic_index;
+mod size_limits;
+mod snapshot;
+mod terminology;
+mod watch;
 // Enum to represent the file type
 #[derive(Clone)]
 enum FileType {
@@ -44,12 +82,13 @@ enum FileType {
     Other,
 }
 
-pub const AVG_LINE_LEN: u64 = 30;
-pub const MAX_LINE_COUNT: u64 = 20000;
-pub const MAX_FILE_LEN: u64 = AVG_LINE_LEN * MAX_LINE_COUNT;
 static COLLECTION_NAME: &str = common::service_interaction::DOCUMENT_COLLECTION_NAME;
 static COLLECTION_NAME_SYMBOLS: &str = common::service_interaction::SYMBOL_COLLECTION_NAME;
 const EMBEDDING_DIM: usize = 384;
+// indexed when --branch isn't set.
+const DEFAULT_BRANCH: &str = "refs/heads/main";
+// files of the walk whose chunks are committed at once.
+const MAX_PENDING_PAYLOADS: usize = 50;
 
 // data structure to represent a repository  file or directory or other.
 #[derive(Clone)]
@@ -89,6 +128,7 @@ pub struct CodeFile {
 
 #[derive(Debug, Clone, Serialize)]
 pub struct FileFields {
+    tenant_id: String,
     repo_name: String,
     repo_disk_path: String,
     repo_ref: String,
@@ -127,13 +167,6 @@ impl fmt::Debug for FileType {
     }
 }
 
-// Define a type alias 'Result<T>' for a Result with a dynamic Error type
-type Result<T> = std::result::Result<T, Box<dyn Error>>;
-
-// Define some structures to represent various components of a repository.
-struct RepoMetadata;
-struct IndexWriter;
-
 // Repository struct represents a repository with a disk path.
 pub struct Repository {
     disk_path: PathBuf,
@@ -143,8 +176,27 @@ pub struct Repository {
     repo_entries: Vec<RepoEntry>,             // The repo_entries Vec
     qdrant_client_code_chunk: Option<QdrantClient>,
     qdrant_client_symbol: Option<QdrantClient>,
+    // files of the walk waiting for their chunks to be committed.
     semantic_payloads: Vec<SemanticPayload>,
+    // files waiting at most, the memory of the walk is bounded by it.
+    max_pending_payloads: usize,
     symbol_meta_payload: HashMap<SymbolKey, Vec<SymbolValue>>,
+    // files left out of the last traversal for being over the size limits.
+    skipped_for_size: Vec<SkippedForSize>,
+    // files left out of the last traversal because their content couldn't be decoded, and why.
+    skipped_undecodable: Vec<(String, Undecodable)>,
+    // files with chunks left out of the embeddings as low quality, and how many of them.
+    low_quality_chunks: Vec<(String, usize)>,
+    // files that failed to be read or committed in the last traversal, the run went on without them.
+    file_errors: Vec<IngestionError>,
+    // manifest of the last traversal, stored with the index.
+    run_manifest: Option<RunManifest>,
+    // branch of the last traversal, the chunk point ids are derived from it.
+    branch: String,
+    // qdrant parameters the collections were given, rendered by collection.
+    collection_tuning: BTreeMap<String, String>,
+    // a paths-only run has no qdrant clients, only the quickwit documents are written.
+    index_mode: IndexMode,
 }
 
 pub struct SemanticPayload {
@@ -152,6 +204,9 @@ pub struct SemanticPayload {
     buffer: String,
     semantic_hash: String,
     language: String,
+    doc_comments: Vec<DocComment>,
+    // unix seconds of the last commit changing the file, none when the history couldn't be read.
+    last_modified: Option<u64>,
 }
 
 #[derive(Clone)]
@@ -160,109 +215,59 @@ struct EntryData {
     git_id: git2::Oid, // Assuming GitID is a type you have defined elsewhere
 }
 
-// Define an enum to represent possible errors that can occur with a repository.
-pub enum RepositoryError {
-    InvalidPath,
-    GitError(git2::Error), // Include git2::Error as a variant
-}
-
-// Implement the Display trait for RepositoryError.
-// This allows us to print out the error message associated with the error.
-impl fmt::Display for RepositoryError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            RepositoryError::InvalidPath => write!(f, "Invalid repository disk path."),
-            RepositoryError::GitError(err) => write!(f, "Git error: {}", err), // Print underlying git2::Error
-        }
-    }
-}
-
-// Implement the Debug trait for RepositoryError.
-// This allows us to print detailed information about the error, useful in debugging.
-impl fmt::Debug for RepositoryError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            RepositoryError::InvalidPath => write!(f, "Invalid repository disk path."),
-            RepositoryError::GitError(err) => write!(f, "Git error: {}", err), // Print underlying git2::Error
-        }
-    }
-}
-
-/*
-The source method of the Error trait is used to expose the underlying cause of an error.
-In other words, it's used when one error is directly caused by another, and you want to provide access to that underlying "source" error.
-
-In the given code, the RepositoryError::GitError variant includes an underlying git2::Error.
-This is a specific error that comes from the git2 crate, and it makes sense to expose it as the source of the RepositoryError::GitError.
-
-On the other hand, the RepositoryError::InvalidPath variant doesn't encapsulate another error.
-It's a standalone error that represents an invalid path. Since there is no underlying error to expose, the implementation of the source method returns None for this variant.
-
-This pattern is common when implementing the Error trait. You expose underlying errors where they exist and return None for cases where there is no underlying error. It allows consumers of your error type to potentially explore a chain of errors, drilling down into the root cause, if there is such a chain to explore.
-In the case of RepositoryError::InvalidPath, there is no such chain, so None is the appropriate value to return.
-
-*/
-impl Error for RepositoryError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match self {
-            RepositoryError::GitError(err) => Some(err), // Return underlying git2::Error
-            _ => None,
-        }
-    }
-}
-
-/*
-1. **`impl From<git2::Error> for RepositoryError`**: This line starts the implementation of the `From` trait for converting from `git2::Error` into `RepositoryError`.
- The `From` trait is a standard Rust trait used to define conversions between types.
-
-2. **`fn from(err: git2::Error) -> Self`**: This line defines the function signature for the required `from` method of the `From` trait.
-   - `err: git2::Error` is the input parameter, the error type from the `git2` crate that we want to convert.
-   - `-> Self` means that the return type of the function is the type for which `From` is implemented, in this case, `RepositoryError`.
-
-3. **`RepositoryError::GitError(err)`**: Inside the function body, we are constructing a `RepositoryError::GitError` variant, passing in the original `git2::Error` (`err`).
- This is assuming that `RepositoryError` is an enum with a variant `GitError` that takes a `git2::Error` as a parameter.
-
-The purpose of this code is to provide a way to easily convert a `git2::Error` into a `RepositoryError`. Once this implementation is in place, you can use the `from` function directly, or rely on the `Into` trait, which is automatically available wherever `From` is implemented.
-
-This kind of pattern is common when working with different libraries that have their own error types, and you want to unify them into a single application-specific error type.
-By doing this, you can handle errors from different sources in a consistent way, making your code more robust and easier to maintain.
-*/
-impl From<git2::Error> for RepositoryError {
-    fn from(err: git2::Error) -> Self {
-        RepositoryError::GitError(err)
-    }
-}
-
 impl Repository {
-    pub fn collection_config(collection_name: String) -> CreateCollection {
-        CreateCollection {
+    pub fn collection_config(
+        collection_name: String,
+        dimension: u64,
+        tuning: &CollectionTuning,
+    ) -> CreateCollection {
+        let mut create = CreateCollection {
             collection_name: collection_name,
             vectors_config: Some(VectorsConfig {
                 config: Some(vectors_config::Config::Params(VectorParams {
-                    size: EMBEDDING_DIM as u64,
+                    size: dimension,
                     distance: Distance::Cosine.into(),
                     ..Default::default()
                 })),
             }),
             ..Default::default()
+        };
+        tuning.apply(&mut create);
+        create
+    }
+
+    // Symbol names are matched as a whole by the exact symbol lookup, everything else is full text.
+    pub fn index_field_type(index: &str) -> FieldType {
+        match index {
+            "symbol" | "symbol_lower" | "kind" | EMBEDDED_LANG_FIELD | BRANCH_FIELD => {
+                FieldType::Keyword
+            }
+            _ => FieldType::Text,
         }
     }
 
     // Note: Changed from &self to no self argument.
+    // Also returns the qdrant parameters the collection has from `QDRANT_COLLECTION_TUNING`.
     async fn init_qdrant_client(
         qdrant_url: &str,
         collection_name: &str,
         indexes: Vec<String>,
-    ) -> Result<QdrantClient> {
-        let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(qdrant_url))).unwrap();
+    ) -> Result<(QdrantClient, CollectionTuning)> {
+        let tuning = get_collection_tuning();
+        let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(qdrant_url)))
+            .map_err(IngestionError::QdrantCommit)?;
 
         // check if the collection exists, create it if it doesn't.
-        match qdrant.has_collection(collection_name).await {
+        let applied = match qdrant.has_collection(collection_name).await {
             Ok(false) => {
                 let CollectionOperationResponse { result, time } = qdrant
-                    .create_collection(&Repository::collection_config(collection_name.to_string()))
+                    .create_collection(&Repository::collection_config(
+                        collection_name.to_string(),
+                        EMBEDDING_DIM as u64,
+                        &tuning,
+                    ))
                     .await
-                    .unwrap();
+                    .map_err(IngestionError::QdrantCommit)?;
 
                 debug!(
                     time,
@@ -271,23 +276,38 @@ impl Repository {
                     "created qdrant collection"
                 );
 
-                assert!(result);
+                if !result {
+                    return Err(IngestionError::QdrantCommit(anyhow::anyhow!(
+                        "collection {} was not created",
+                        collection_name
+                    )));
+                }
+                tuning
             }
-            Ok(true) => {}
+            Ok(true) => collection_tuning::tune_collection(&qdrant, collection_name, &tuning).await,
             Err(e) => {
                 // print the error  message from Err.
                 println!("Error: {:?}", e);
                 // return early with error
 
-                return Err(Box::new(SemanticError::QdrantInitializationError));
+                return Err(IngestionError::QdrantCommit(
+                    SemanticError::QdrantInitializationError.into(),
+                ));
             }
-        }
+        };
 
         //iterate through the indexes and create field indexes
         for index in indexes.iter() {
             let result = qdrant
-                .create_field_index(collection_name, index, FieldType::Text, None, None)
-                .await?;
+                .create_field_index(
+                    collection_name,
+                    index,
+                    Repository::index_field_type(index),
+                    None,
+                    None,
+                )
+                .await
+                .map_err(IngestionError::QdrantCommit)?;
         }
         /*
                 // At this point, all futures have succeeded and their results are in the `results` vector.
@@ -307,31 +327,67 @@ impl Repository {
                     )
                     .await?;
         */
-        Ok(qdrant)
+        Ok((qdrant, applied))
     }
 
     // Note: Changed from &mut self to no self argument, and modified the return type.
-    pub async fn new(disk_path: PathBuf, repo_name: String) -> Result<Self> {
+    pub async fn new(disk_path: PathBuf, repo_name: String, index_mode: IndexMode) -> Result<Self> {
         let indexes_chunk = vec![
             "repo_name".to_string(),
             "content_hash".to_string(),
             "relative_path".to_string(),
+            "kind".to_string(),
+            EMBEDDED_LANG_FIELD.to_string(),
+            BRANCH_FIELD.to_string(),
         ];
 
-        let indexes_symbols = vec!["repo_name".to_string(), "symbol".to_string()];
+        let indexes_symbols = vec![
+            "repo_name".to_string(),
+            "symbol".to_string(),
+            "symbol_lower".to_string(),
+            BRANCH_FIELD.to_string(),
+        ];
         let git_repo = GitRepository::open(&disk_path)?;
-        let qdrant_client_chunks = Some(
+        // nothing is embedded, the collections aren't created.
+        if index_mode == IndexMode::PathsOnly {
+            return Ok(Self {
+                disk_path,
+                repo_name,
+                git_repo,
+                file_entries: HashMap::new(),
+                repo_entries: Vec::new(),
+                qdrant_client_code_chunk: None,
+                qdrant_client_symbol: None,
+                semantic_payloads: Vec::new(),
+                max_pending_payloads: MAX_PENDING_PAYLOADS,
+                symbol_meta_payload: HashMap::new(),
+                skipped_for_size: Vec::new(),
+                skipped_undecodable: Vec::new(),
+                low_quality_chunks: Vec::new(),
+                file_errors: Vec::new(),
+                run_manifest: None,
+                branch: DEFAULT_BRANCH.to_string(),
+                collection_tuning: BTreeMap::new(),
+                index_mode,
+            });
+        }
+        let (qdrant_client_chunks, chunk_tuning) =
             Repository::init_qdrant_client(&get_qdrant_url(), COLLECTION_NAME, indexes_chunk)
-                .await?,
-        );
-        let qdrant_client_symbols = Some(
-            Repository::init_qdrant_client(
-                &get_qdrant_url(),
-                COLLECTION_NAME_SYMBOLS,
-                indexes_symbols,
-            )
-            .await?,
-        );
+                .await?;
+        let (qdrant_client_symbols, symbol_tuning) = Repository::init_qdrant_client(
+            &get_qdrant_url(),
+            COLLECTION_NAME_SYMBOLS,
+            indexes_symbols,
+        )
+        .await?;
+        let collection_tuning = [
+            (COLLECTION_NAME, chunk_tuning),
+            (COLLECTION_NAME_SYMBOLS, symbol_tuning),
+        ]
+        .into_iter()
+        .filter(|(_, tuning)| !tuning.is_default())
+        .map(|(collection, tuning)| (collection.to_string(), tuning.to_string()))
+        .collect();
 
         Ok(Self {
             disk_path,
@@ -339,265 +395,903 @@ impl Repository {
             git_repo,
             file_entries: HashMap::new(),
             repo_entries: Vec::new(),
-            qdrant_client_code_chunk: qdrant_client_chunks,
-            qdrant_client_symbol: qdrant_client_symbols,
+            qdrant_client_code_chunk: Some(qdrant_client_chunks),
+            qdrant_client_symbol: Some(qdrant_client_symbols),
             semantic_payloads: Vec::new(),
+            max_pending_payloads: MAX_PENDING_PAYLOADS,
             symbol_meta_payload: HashMap::new(),
+            skipped_for_size: Vec::new(),
+            skipped_undecodable: Vec::new(),
+            low_quality_chunks: Vec::new(),
+            file_errors: Vec::new(),
+            run_manifest: None,
+            branch: DEFAULT_BRANCH.to_string(),
+            collection_tuning,
+            index_mode,
         })
     }
 
-    pub async fn traverse(&mut self, repo_path: &str, repo_name: &str, branch: &str) -> Result<()> {
-        // Find the reference to the main branch
-
-        // Create a Vec to store all the RepoEntry::File entries
-        let mut all_entries: Vec<FileFields> = Vec::new();
+    pub async fn traverse(
+        &mut self,
+        repo_path: &str,
+        repo_name: &str,
+        branch: &str,
+        checkpoint_options: CheckpointOptions,
+    ) -> Result<index_processor::QuickwitIndexSummary> {
+        // the quickwit index is checked before anything is embedded, a wrong schema fails the run early.
+        let index_id = generate_quikwit_index_name(repo_name);
+        let created = index_processor::ensure_index(&get_quickwit_url(), &index_id)
+            .instrument(tracing::info_span!("ensure_quickwit_index"))
+            .await
+            .map_err(IngestionError::QuickwitCommit)?;
 
         let head_ref = self.git_repo.find_reference(branch)?;
-        let head_commit = self.git_repo.find_commit(head_ref.target().unwrap())?;
+        let head_target = head_ref
+            .target()
+            .ok_or_else(|| git2::Error::from_str(&format!("{} is not a direct reference", branch)))?;
+        let head_commit = self.git_repo.find_commit(head_target)?;
         let tree = head_commit.tree()?;
+        // code search hands the commit out so answers can link to the files at the indexed version.
+        let indexed_commit = head_commit.id().to_string();
+        let run_start = std::time::Instant::now();
+        let mut manifest = run_manifest::start_run_manifest(repo_name, branch, &indexed_commit);
+        // code search compares them with the head of the branch to tell how stale the index is.
+        manifest.indexed_commit_at = u64::try_from(head_commit.time().seconds()).ok();
+        manifest.remote_url = self
+            .git_repo
+            .find_remote("origin")
+            .ok()
+            .and_then(|remote| remote.url().map(public_remote_url));
+        manifest.collection_tuning = self.collection_tuning.clone();
+        manifest.index_mode = self.index_mode;
+        self.branch = branch.to_string();
         // let rt = tokio::runtime::Builder::new_current_thread()
         //     .enable_all()
         //     .build()
         //     .unwrap();
 
+        let size_limits = get_size_limits();
+
+        // the files committed before a crash are skipped when resuming, see `--resume`.
+        let generation = self
+            .collection_generation()
+            .await
+            .map_err(IngestionError::QdrantCommit)?;
+        let mut checkpointer =
+            Checkpointer::start(checkpoint_options, repo_name, branch, generation)
+                .map_err(IngestionError::Checkpoint)?;
+
+        // the documents of a paths-only run have no symbols, a full run replaces them all.
+        if !created
+            && checkpointer.checkpoint().quickwit_documents.is_empty()
+            && self.index_mode.is_full()
+        {
+            self.upgrade_paths_only(repo_name, branch).await;
+        }
+
+        // the documents go to quickwit while the tree is walked, only their number is kept. The
+        // ones the resumed run already sent are skipped.
+        let mut quickwit_sink =
+            index_processor::QuickwitSink::new(repo_name, get_quickwit_max_in_flight_batches());
+
+        // The walk only lists the entries, they are read and processed one at a time after it
+        // so the documents can be streamed out.
+        let mut walked: Vec<(String, ObjectType, git2::Oid)> = Vec::new();
+        // manifests, READMEs and docs left out of the index, only read for the repo summary and
+        // the terminology suggestions.
+        let mut summary_only: Vec<(String, git2::Oid)> = Vec::new();
+        let mut summary = RepoSummaryBuilder::new();
+        let mut terminology = TerminologyMiner::default();
+
         // Walk through the tree, visiting each entry in a pre-order traversal
         let mut counter = 0;
         // Walk through the given Git tree, using pre-order traversal.
-        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
-            // If the entry has a name, get its path.
-            if let Some(name) = entry.name() {
-                let path = format!("{}{}", root, name);
-
-                // If the file at the given path should not be indexed, skip it.
-                if !index_filter(&path) {
-                    println!("Skipping {}", path);
-                    return git2::TreeWalkResult::Ok;
-                }
-
-                // Determine the type of file (directory, regular file, or other).
-                let file_type = match entry.kind().unwrap() {
-                    ObjectType::Tree => FileType::Dir,
-                    ObjectType::Blob => FileType::File,
-                    _ => FileType::Other,
-                };
-
-                // Retrieve the Git ID for the entry.
-                let git_id = entry.id();
-                println!("{}: {:?} ({})", path, file_type, git_id);
-                let entry_data = EntryData { file_type, git_id };
-
-                // Store the file entry information into the `file_entries` HashMap.
-                self.file_entries.insert(path.clone(), entry_data);
-
-                let path = format!("{}{}", root, name);
-                println!("Path path path: {}", path);
-
-                // Match the object type of the entry.
-                let object_type = entry.kind().unwrap();
-                match object_type {
-                    // If it's a directory, push it to the `repo_entries` Vec.
-                    ObjectType::Tree => {
-                        self.repo_entries.push(RepoEntry::Dir(CodeDir { path }));
-                    }
-                    // If it's a regular file (blob in Git terms), process the file.
-                    ObjectType::Blob => {
-                        let blob = self.git_repo.find_blob(git_id).unwrap();
-                        let path_buf = PathBuf::from(&path);
-                        let content_buffer = blob.content();
-
-                        // Skip the file if its size exceeds the maximum allowed file length.
-                        if content_buffer.len() > MAX_FILE_LEN as usize {
-                            println!("Skipping file due to size: {}", path);
-                            return git2::TreeWalkResult::Ok;
+        tracing::info_span!("walk_tree").in_scope(|| {
+            tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                // If the entry has a name, get its path.
+                if let Some(name) = entry.name() {
+                    let path = format!("{}{}", root, name);
+
+                    // If the file at the given path should not be indexed, skip it.
+                    if !index_filter(&path) {
+                        if entry.kind() == Some(ObjectType::Blob)
+                            && (repo_summary::is_summary_input(&path)
+                                || is_terminology_source(&path))
+                        {
+                            summary_only.push((path.clone(), entry.id()));
                         }
+                        println!("Skipping {}", path);
+                        return git2::TreeWalkResult::Ok;
+                    }
 
-                        // Convert the content of the blob into a UTF-8 string.
-                        let mut buffer = std::str::from_utf8(content_buffer)
-                            .unwrap_or("")
-                            .to_string();
-
-                        // Compute the relative path for the file.
-                        let relative_path = PathBuf::from(&path)
-                            .strip_prefix(&self.disk_path)
-                            .map(ToOwned::to_owned)
-                            .unwrap_or(PathBuf::from(&path));
-
-                        // Compute the semantic and tantivy hashes for the file. NOTE: "main" is hardcoded.
-                        let (semantic_hash, tantivy_hash) =
-                            compute_hashes(relative_path, &buffer, "main");
-
-                        // Detect the programming language of the file.
-                        let language = util::detect_language(&path_buf, blob.content())
-                            .map(|s| s.to_string())
-                            .unwrap_or("Unknown".to_string());
-
-                        // If the language is unsupported, skip the file.
-                        if language == "Unknown" {
-                            print!("Unsupported language: {}", language);
-                            return git2::TreeWalkResult::Ok;
-                        }
+                    // Determine the type of file (directory, regular file, or other).
+                    let kind = entry.kind().unwrap_or(ObjectType::Any);
+                    let file_type = match kind {
+                        ObjectType::Tree => FileType::Dir,
+                        ObjectType::Blob => FileType::File,
+                        _ => FileType::Other,
+                    };
 
-                        // Build a syntax-aware representation of the file.
-                        let symbol_locations = {
-                            let scope_graph = CodeFileAST::build_ast(blob.content(), &language)
-                                .and_then(CodeFileAST::scope_graph);
+                    // Retrieve the Git ID for the entry.
+                    let git_id = entry.id();
+                    println!("{}: {:?} ({})", path, file_type, git_id);
+                    let entry_data = EntryData { file_type, git_id };
 
-                            // Return the graph if it exists or return an empty representation.
-                            match scope_graph {
-                                Ok(graph) => SymbolLocations::TreeSitter(graph),
-                                Err(err) => SymbolLocations::Empty,
-                            }
-                        };
+                    // Store the file entry information into the `file_entries` HashMap.
+                    self.file_entries.insert(path.clone(), entry_data);
 
-                        // Extract symbols from the syntax-aware representation.
-                        let symbols = symbol_locations
-                            .list()
-                            .iter()
-                            .map(|sym| buffer[sym.range.start.byte..sym.range.end.byte].to_owned())
-                            .collect::<HashSet<_>>()
-                            .into_iter()
-                            .collect::<Vec<_>>()
-                            .join("\n");
-
-                        // Collect and aggregate metadata for each symbol in the file.
-                        // This is to utilize the symbols during code search and perform ranking.
-                        self.symbol_meta_payload = symbol_locations
-                            .list_metadata(blob.content(), repo_name, &language, &path)
-                            .into_iter()
-                            .fold(self.symbol_meta_payload.clone(), |mut meta_map, meta| {
-                                let meta_key = SymbolKey {
-                                    symbol: meta.symbol_type.clone(),
-                                    repo_name: meta.repo_name.clone(),
-                                };
-
-                                let meta_value = SymbolValue {
-                                    symbol_type: meta.symbol.clone(),
-                                    language_id: meta.language_id.clone(),
-                                    relative_path: meta.relative_path.clone(),
-                                    start_byte: meta.range.start.byte.clone(),
-                                    end_byte: meta.range.end.byte.clone(),
-                                    is_global: meta.is_global.clone(),
-                                    node_kind: meta.node_kind.clone(),
-                                };
-
-                                meta_map
-                                    .entry(meta_key)
-                                    .or_insert_with(Vec::new)
-                                    .push(meta_value);
-
-                                meta_map
-                            });
+                    walked.push((path, kind, git_id));
+                }
+                // Continue walking through the tree.
+                git2::TreeWalkResult::Ok
+            })
+        })?;
+
+        // code search prefers the recently changed files for questions about the current behavior.
+        let times = last_modified::last_modified(
+            &self.git_repo,
+            &head_commit,
+            get_last_modified_max_commits(),
+        )
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to read when the files were last changed: {}", e);
+            Default::default()
+        });
+
+        // the chunks are committed while the tree is walked, see `process_walked`. The committer
+        // holds the qdrant client until the files left after the walk are committed.
+        let chunk_repo_name = self.repo_name.clone();
+        let qdrant_client = self.qdrant_client_code_chunk.take();
+        let mut low_quality_chunks = Vec::new();
+        let mut committer = ChunkCommitter {
+            repo_name: &chunk_repo_name,
+            branch,
+            qdrant_client: &qdrant_client,
+            counter: &mut counter,
+            low_quality_chunks: &mut low_quality_chunks,
+            elapsed: std::time::Duration::ZERO,
+        };
+        let walked = self
+            .process_walked(
+                walked,
+                &WalkInputs {
+                    repo_name,
+                    repo_path,
+                    indexed_commit: &indexed_commit,
+                    size_limits: &size_limits,
+                    times: &times,
+                },
+                FileMiners {
+                    summary: &mut summary,
+                    terminology: &mut terminology,
+                },
+                &mut quickwit_sink,
+                &mut committer,
+                &mut checkpointer,
+            )
+            .await;
+        let committed = match &walked {
+            Ok(_) if self.index_mode.is_full() => {
+                commit_files(&self.semantic_payloads, &mut committer, &mut checkpointer).await
+            }
+            _ => Ok(Vec::new()),
+        };
+        let chunks_elapsed = committer.elapsed;
+        self.qdrant_client_code_chunk = qdrant_client;
+        self.low_quality_chunks.extend(low_quality_chunks);
+        // the chunks are committed, the content of the files isn't needed anymore.
+        self.semantic_payloads = Vec::new();
+        self.file_errors.extend(walked?);
+        self.file_errors.extend(committed?);
+
+        for (path, git_id) in summary_only {
+            if let Ok(blob) = self.git_repo.find_blob(git_id) {
+                summary.add_summary_input(&path, blob.content());
+                terminology.add_file(&path, blob.content());
+            }
+        }
+        // the summary of the previous run is replaced, so it always describes the indexed commit.
+        if !created && !checkpointer.is_document_committed(REPO_SUMMARY_PATH) {
+            if let Err(e) =
+                index_processor::delete_file_document(repo_name, branch, REPO_SUMMARY_PATH).await
+            {
+                log::warn!("Failed to delete the previous summary of {}: {}", repo_name, e);
+            }
+        }
+        if !created && !checkpointer.is_document_committed(TERMINOLOGY_SUGGESTIONS_PATH) {
+            if let Err(e) = index_processor::delete_file_document(
+                repo_name,
+                branch,
+                TERMINOLOGY_SUGGESTIONS_PATH,
+            )
+            .await
+            {
+                log::warn!(
+                    "Failed to delete the previous terminology suggestions of {}: {}",
+                    repo_name,
+                    e
+                );
+            }
+        }
+        let summary = summary.build(repo_name, &indexed_commit);
+        let repo_documents = [
+            repo_summary::repo_summary_fields(&summary, repo_path),
+            terminology.suggestions_fields(repo_name, repo_path, &indexed_commit),
+        ];
+        for fields in repo_documents {
+            if checkpointer.is_document_committed(&fields.relative_path) {
+                quickwit_sink.skip();
+                continue;
+            }
+            quickwit_sink
+                .push(FileFields {
+                    repo_ref: branch_name(branch).to_string(),
+                    ..fields
+                })
+                .await;
+        }
 
-                        println!("Symbol Meta Payload: {:?}", self.symbol_meta_payload);
+        let documents = quickwit_sink
+            .finish()
+            .instrument(tracing::info_span!("index_quickwit"))
+            .await;
+        // a paths-only run has nothing after the documents, they are streamed again on resume so
+        // a full run resuming its checkpoint writes them with the symbols.
+        if self.index_mode.is_full() {
+            checkpointer
+                .record_documents(quickwit_sink.take_acknowledged())
+                .map_err(IngestionError::Checkpoint)?;
+        }
+        manifest.timings.documents_ms = run_start
+            .elapsed()
+            .saturating_sub(chunks_elapsed)
+            .as_millis() as u64;
+        manifest.timings.chunks_ms = chunks_elapsed.as_millis() as u64;
+
+        let symbols_start = std::time::Instant::now();
+        if self.index_mode.is_full() && !checkpointer.checkpoint().symbols_committed {
+            let mut index = SemanticIndex::new(&counter).map_err(IngestionError::Embedding)?;
+            // send self.symbolMetaPayload to commit_symbol_metadata function to commit the metadata.
+            let result = index
+                .commit_symbol_metadata(
+                    &self.symbol_meta_payload,
+                    branch,
+                    &self.qdrant_client_symbol,
+                )
+                .instrument(tracing::info_span!("commit_symbols"))
+                .await;
 
-                        // Ensure the content ends with a newline.
-                        if !buffer.ends_with('\n') {
-                            buffer += "\n";
-                        }
+            if let Err(e) = result {
+                println!("Error: {:?}", e);
+            }
+            checkpointer
+                .symbols_committed()
+                .map_err(IngestionError::Checkpoint)?;
+        }
 
-                        // Compute line ending indices for the file.
-                        let line_end_indices = buffer
-                            .match_indices('\n')
-                            .flat_map(|(i, _)| u32::to_le_bytes(i as u32))
-                            .collect::<Vec<_>>();
+        manifest.timings.symbols_ms = symbols_start.elapsed().as_millis() as u64;
+
+        self.report_skipped_for_size();
+        self.report_skipped_undecodable();
+        self.report_low_quality_chunks();
+        self.report_file_errors();
+
+        metrics::set_index_size(repo_name, documents);
+        checkpointer
+            .finish(self.file_errors.len())
+            .map_err(IngestionError::Checkpoint)?;
+
+        manifest.counts.files_indexed = self
+            .repo_entries
+            .iter()
+            .filter(|entry| matches!(entry, RepoEntry::File(_)))
+            .count();
+        manifest.counts.files_skipped_for_size = self.skipped_for_size.len();
+        manifest.counts.files_skipped_undecodable = self.skipped_undecodable.len();
+        manifest.counts.files_failed = self.file_errors.len();
+        manifest.counts.chunks_dropped_low_quality = self
+            .low_quality_chunks
+            .iter()
+            .map(|(_, dropped)| dropped)
+            .sum();
+        manifest.counts.documents = documents;
+        manifest.counts.symbols = self.symbol_meta_payload.len();
+        let cache_stats = embedding_cache::global().stats();
+        log::info!("Embedding cache: {}", cache_stats);
+        manifest.counts.embedding_cache_hits = cache_stats.hits;
+        manifest.counts.embedding_cache_misses = cache_stats.misses;
+        manifest.timings.total_ms = run_start.elapsed().as_millis() as u64;
+        manifest.finished_at = unix_now();
+        // the manifest of the previous run is replaced, it describes the index as it is now.
+        if !created {
+            if let Err(e) =
+                index_processor::delete_file_document(repo_name, branch, RUN_MANIFEST_PATH).await
+            {
+                log::warn!("Failed to delete the previous run manifest of {}: {}", repo_name, e);
+            }
+        }
+        index_processor::ingest_entries(
+            [run_manifest::run_manifest_fields(&manifest, repo_path)],
+            repo_name,
+        )
+        .await;
+        log::info!("Run {} of {} finished", manifest.run_id, repo_name);
+        self.run_manifest = Some(manifest);
+
+        Ok(index_processor::QuickwitIndexSummary {
+            index_id,
+            created,
+            documents,
+        })
+    }
+}
 
-                        // Skip files that have too many lines.
-                        if line_end_indices.len() > MAX_LINE_COUNT as usize {
-                            return git2::TreeWalkResult::Ok;
-                        }
+impl Repository {
+    // Deletes the documents of the branch when its last run was paths-only, so they are replaced
+    // by the ones of this run instead of showing up twice.
+    async fn upgrade_paths_only(&self, repo_name: &str, branch: &str) {
+        match index_processor::fetch_run_manifest(repo_name, branch).await {
+            Ok(Some(previous)) if previous.index_mode == IndexMode::PathsOnly => {
+                log::info!(
+                    "Upgrading {} from the paths-only run {} to a full index",
+                    repo_name,
+                    previous.run_id
+                );
+                if let Err(e) = index_processor::delete_branch_documents(repo_name, branch).await {
+                    log::warn!("Failed to delete the paths-only documents of {}: {}", repo_name, e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to read the previous run manifest of {}: {}", repo_name, e),
+        }
+    }
 
-                        let lines_avg = buffer.len() as f64 / buffer.lines().count() as f64;
+    // The collections behind the aliases the points are written through.
+    async fn collection_generation(&self) -> anyhow::Result<CollectionGeneration> {
+        let aliases = match &self.qdrant_client_code_chunk {
+            Some(client) => migrate::MigrationStore::aliases(client).await?,
+            None => HashMap::new(),
+        };
+        let resolve =
+            |alias: &str| aliases.get(alias).cloned().unwrap_or_else(|| alias.to_string());
+        Ok(CollectionGeneration {
+            chunks: resolve(COLLECTION_NAME),
+            symbols: resolve(COLLECTION_NAME_SYMBOLS),
+        })
+    }
 
-                        // Convert the path from PathBuf to &str and process further.
-                        if let Some(path_str) = path_buf.as_path().to_str() {
-                            println!("Path as &str: {}", path_str);
+    // Reads the walked entries into the repo entries and payloads, the documents are handed to the
+    // sink as they are built. Once `max_pending_payloads` files wait for their chunks, they are
+    // committed with `committer`, the ones left at the end are for the caller to commit. A file
+    // that can't be read, processed or committed is left out and its error returned, the others
+    // are still indexed.
+    async fn process_walked<C: FileCommitter>(
+        &mut self,
+        walked: Vec<(String, ObjectType, git2::Oid)>,
+        inputs: &WalkInputs<'_>,
+        miners: FileMiners<'_>,
+        quickwit_sink: &mut index_processor::QuickwitSink,
+        committer: &mut C,
+        checkpointer: &mut Checkpointer,
+    ) -> Result<Vec<IngestionError>> {
+        let WalkInputs {
+            repo_name,
+            repo_path,
+            indexed_commit,
+            size_limits,
+            times,
+        } = *inputs;
+        let FileMiners {
+            summary,
+            terminology,
+        } = miners;
+        let mut file_errors = Vec::new();
+        for (path, object_type, git_id) in walked {
+            // Match the object type of the entry.
+            let fields = match object_type {
+                // If it's a directory, push it to the `repo_entries` Vec.
+                ObjectType::Tree => {
+                    self.repo_entries.push(RepoEntry::Dir(CodeDir { path }));
+                    continue;
+                }
+                // If it's a regular file (blob in Git terms), process the file.
+                ObjectType::Blob => {
+                    // the blob is dropped before the document is handed to quickwit.
+                    let blob = match self.git_repo.find_blob(git_id) {
+                        Ok(blob) => blob,
+                        Err(e) => {
+                            log::error!("Failed to read {}: {}", path, e);
+                            file_errors.push(IngestionError::per_file(&path, e));
+                            continue;
+                        }
+                    };
+                    if repo_summary::is_summary_input(&path) {
+                        summary.add_summary_input(&path, blob.content());
+                    }
+                    terminology.add_file(&path, blob.content());
+
+                    // not source code, only kept for code search to resolve owners from.
+                    if codeowners::is_codeowners_path(&path) {
+                        codeowners_fields(&path, blob.content(), repo_name, repo_path)
+                    } else {
+                        // Skip the file if it's too large, in an unsupported language or undecodable.
+                        let processed = match process_file_content(
+                            &path,
+                            blob.content(),
+                            repo_name,
+                            repo_path,
+                            &self.disk_path,
+                            size_limits,
+                            self.index_mode,
+                        ) {
+                            Ok(processed) => processed,
+                            Err(SkipReason::Size(skipped)) => {
+                                self.skipped_for_size.push(skipped);
+                                continue;
+                            }
+                            Err(SkipReason::Undecodable(reason)) => {
+                                self.skipped_undecodable.push((path, reason));
+                                continue;
+                            }
+                            Err(SkipReason::Unsupported) => continue,
+                            Err(SkipReason::Failed(e)) => {
+                                log::error!("Failed to process {}: {}", path, e);
+                                file_errors.push(IngestionError::per_file(&path, e));
+                                continue;
+                            }
+                        };
 
-                            // Create a struct to store various semantic data.
+                        summary.add_definitions(
+                            &path,
+                            &processed.code_file.buffer,
+                            processed.symbol_metas.len(),
+                            processed
+                                .symbol_metas
+                                .iter()
+                                .filter(|(_, meta_value)| meta_value.is_global)
+                                .map(|(meta_key, _)| meta_key.symbol.as_str()),
+                        );
+
+                        // Aggregate metadata for each symbol in the file.
+                        // This is to utilize the symbols during code search and perform ranking.
+                        for (meta_key, meta_value) in processed.symbol_metas {
+                            self.symbol_meta_payload
+                                .entry(meta_key)
+                                .or_insert_with(Vec::new)
+                                .push(meta_value);
+                        }
+
+                        // nothing is embedded in a paths-only run.
+                        if self.index_mode.is_full() {
                             self.semantic_payloads.push(SemanticPayload {
-                                path: path_str.to_string(),
-                                buffer: buffer.clone(),
-                                semantic_hash: semantic_hash.clone(),
-                                language: language.clone(),
+                                last_modified: times.of(&path),
+                                ..processed.semantic_payload
                             });
-                        } else {
-                            println!("Path is not valid UTF-8");
-                            return git2::TreeWalkResult::Ok;
+                            if self.semantic_payloads.len() >= self.max_pending_payloads {
+                                let errors =
+                                    commit_files(&self.semantic_payloads, committer, checkpointer)
+                                        .await?;
+                                file_errors.extend(errors);
+                                self.semantic_payloads.clear();
+                            }
                         }
+                        summary.add_indexed_file(
+                            &path,
+                            &processed.code_file.language,
+                            &processed.code_file.buffer,
+                        );
 
-                        // Create a struct to store various fields about the file.
-                        let file_fields = FileFields {
-                            repo_name: repo_name.to_string(),
-                            // use the disk path of the repo.
-                            repo_disk_path: repo_path.to_string(),
-                            repo_ref: String::new(),
-                            lang: language.clone(),
-                            relative_path: path.clone(),
-                            last_commit: String::new(),
-                            is_directory: false,
-                            avg_line_length: lines_avg,
-                            line_end_indices: line_end_indices.clone(),
-                            content: buffer.clone(),
-                            symbol_locations: bincode::serialize(&symbol_locations).unwrap(),
-                            unique_hash: tantivy_hash.clone(),
-                            symbols: symbols.clone(),
-                        };
-
-                        // Store the file data in the all_entries Vec.
-                        all_entries.push(file_fields.clone());
-
-                        // Add the processed file to the repo_entries Vec.
+                        // Add the processed file to the repo_entries Vec, without its content.
                         self.repo_entries.push(RepoEntry::File(CodeFile {
-                            path,
-                            buffer,
-                            semantic_hash,
-                            tantivy_hash,
-                            language,
+                            buffer: String::new(),
+                            ..processed.code_file
                         }));
+                        Some(processed.file_fields)
                     }
-                    // If it's neither a directory nor a regular file, store it as "Other".
-                    _ => self.repo_entries.push(RepoEntry::Other),
                 }
+                // If it's neither a directory nor a regular file, store it as "Other".
+                _ => {
+                    self.repo_entries.push(RepoEntry::Other);
+                    continue;
+                }
+            };
+
+            if let Some(mut fields) = fields {
+                if checkpointer.is_document_committed(&fields.relative_path) {
+                    quickwit_sink.skip();
+                    continue;
+                }
+                fields.last_commit = indexed_commit.to_string();
+                fields.repo_ref = branch_name(&self.branch).to_string();
+                quickwit_sink.push(fields).await;
             }
-            // Continue walking through the tree.
-            git2::TreeWalkResult::Ok
-        })?;
+            // a paths-only run doesn't record them, see `traverse`.
+            if self.index_mode.is_full() {
+                if let Err(e) = checkpointer.record_documents(quickwit_sink.take_acknowledged()) {
+                    log::error!("Failed to save the quickwit progress: {}", e);
+                }
+            }
+        }
+        Ok(file_errors)
+    }
 
-        // iterate through self.semanticPayloads and call the tokenize_and_commit function
-        for payload in &self.semantic_payloads {
-            let mut index = SemanticIndex::new(&counter)?;
-            let result = index
-                .tokenize_and_commit(
-                    &payload.buffer,
-                    &self.repo_name,
-                    &payload.path,
-                    &payload.semantic_hash,
-                    &payload.language,
-                    &self.qdrant_client_code_chunk,
-                )
-                .await;
-            // print saying committing finished.
-            println!("Committing finished");
-            // increment the counter
-            counter += 1;
+    // Lists the files that failed, the checkpoint is kept so `--resume` retries them.
+    fn report_file_errors(&self) {
+        if self.file_errors.is_empty() {
+            return;
+        }
+        log::warn!("{} files failed to be indexed:", self.file_errors.len());
+        for error in &self.file_errors {
+            log::warn!("  {}", error);
+        }
+    }
+
+    /// The files that failed in the last traversal, the rest of the repo was indexed.
+    pub fn file_errors(&self) -> &[IngestionError] {
+        &self.file_errors
+    }
+
+    // Lists the files skipped for their size along with the limits that applied,
+    // so the limits can be tuned with `--max-file-bytes`, `--max-lines` or a per path override.
+    fn report_skipped_for_size(&self) {
+        if self.skipped_for_size.is_empty() {
+            return;
+        }
+        log::warn!(
+            "Skipped {} files over the size limits:",
+            self.skipped_for_size.len()
+        );
+        for skipped in &self.skipped_for_size {
+            log::warn!("  {}", skipped);
+        }
+    }
+
+    // Lists the files whose content couldn't be decoded, with the reason of each.
+    fn report_skipped_undecodable(&self) {
+        if self.skipped_undecodable.is_empty() {
+            return;
+        }
+        log::warn!(
+            "Skipped {} files that couldn't be decoded:",
+            self.skipped_undecodable.len()
+        );
+        for (path, reason) in &self.skipped_undecodable {
+            log::warn!("  {}: {}", path, reason);
+        }
+    }
+
+    // Lists the files with chunks left out of the embeddings, their license header or chunks
+    // under `CHUNK_QUALITY_THRESHOLDS`. Their whole content is still searched in full text.
+    fn report_low_quality_chunks(&self) {
+        if self.low_quality_chunks.is_empty() {
+            return;
+        }
+        log::info!(
+            "Dropped low quality chunks of {} files:",
+            self.low_quality_chunks.len()
+        );
+        for (path, dropped) in &self.low_quality_chunks {
+            log::info!("  {}: {} chunks", path, dropped);
+        }
+    }
+}
+
+// What the files of a walk are read with, see `process_walked`.
+#[derive(Clone, Copy)]
+struct WalkInputs<'a> {
+    repo_name: &'a str,
+    repo_path: &'a str,
+    indexed_commit: &'a str,
+    size_limits: &'a SizeLimits,
+    times: &'a LastModified,
+}
 
-            println!("Counter value: {}", counter);
+// What learns from the content of the walked files besides the indexes.
+struct FileMiners<'a> {
+    summary: &'a mut RepoSummaryBuilder,
+    terminology: &'a mut TerminologyMiner,
+}
+
+// Embeds the chunks of a file into the chunk collection.
+struct ChunkCommitter<'a> {
+    repo_name: &'a str,
+    branch: &'a str,
+    qdrant_client: &'a Option<QdrantClient>,
+    counter: &'a mut usize,
+    // files with chunks dropped as low quality, and how many of them.
+    low_quality_chunks: &'a mut Vec<(String, usize)>,
+    // time spent committing the chunks, the walk goes on in between.
+    elapsed: std::time::Duration,
+}
+
+#[async_trait::async_trait(?Send)]
+impl FileCommitter for ChunkCommitter<'_> {
+    async fn discard(&mut self, path: &str) -> anyhow::Result<()> {
+        if let Some(client) = self.qdrant_client {
+            let selector = watch::file_points_selector(self.repo_name, self.branch, path);
+            client.delete_points(COLLECTION_NAME, &selector, None).await?;
         }
+        Ok(())
+    }
 
-        let mut index = SemanticIndex::new(&counter)?;
-        // send self.symbolMetaPayload to commit_symbol_metadata function to commit the metadata.
+    async fn commit(&mut self, payload: &SemanticPayload) -> anyhow::Result<()> {
+        let start = std::time::Instant::now();
+        let mut index = SemanticIndex::new(self.counter)?;
         let result = index
-            .commit_symbol_metadata(&self.symbol_meta_payload, &self.qdrant_client_symbol)
+            .tokenize_and_commit(
+                &payload.buffer,
+                ChunkedFile {
+                    repo_name: self.repo_name,
+                    relative_path: &payload.path,
+                    branch: self.branch,
+                    semantic_hash: &payload.semantic_hash,
+                    lang: &payload.language,
+                    key_paths: &[],
+                    doc_comments: &payload.doc_comments,
+                    last_modified: payload.last_modified,
+                },
+                self.qdrant_client,
+            )
+            .instrument(tracing::info_span!("embed_chunks", path = %payload.path))
             .await;
+        // print saying committing finished.
+        println!("Committing finished");
+        // increment the counter
+        *self.counter += 1;
+
+        println!("Counter value: {}", self.counter);
+        self.elapsed += start.elapsed();
+        let dropped = result.map_err(boxed)?;
+        if dropped > 0 {
+            self.low_quality_chunks.push((payload.path.clone(), dropped));
+        }
+        Ok(())
+    }
+}
+
+// Why a file was left out of the index.
+#[derive(Debug)]
+pub enum SkipReason {
+    Size(SkippedForSize),
+    // content that is neither UTF-8, UTF-16 nor latin-1 text, see `crate::encoding`.
+    Undecodable(Undecodable),
+    // unsupported language, or a path that is not valid UTF-8.
+    Unsupported,
+    // the file couldn't be processed, it's recorded as a failure of the run.
+    Failed(anyhow::Error),
+}
+
+// The quickwit document of a CODEOWNERS file, with the decoded file as content.
+// None when the file couldn't be decoded.
+pub fn codeowners_fields(
+    path: &str,
+    content_buffer: &[u8],
+    repo_name: &str,
+    repo_path: &str,
+) -> Option<FileFields> {
+    let decoded = match encoding::decode(content_buffer) {
+        Ok(decoded) => decoded.text,
+        Err(reason) => {
+            log::warn!("Skipping {}, {}", path, reason);
+            return None;
+        }
+    };
+    let buffer = decoded.as_ref();
+    let owners = CodeOwners::parse(buffer);
+    log::info!("Found {} ownership rules in {}", owners.rules.len(), path);
+
+    let line_end_indices = buffer
+        .match_indices('\n')
+        .flat_map(|(i, _)| u32::to_le_bytes(i as u32))
+        .collect::<Vec<_>>();
+    let (_, tantivy_hash) = compute_hashes(PathBuf::from(path), buffer, "main");
+    let symbol_locations = match bincode::serialize(&SymbolLocations::Empty) {
+        Ok(symbol_locations) => symbol_locations,
+        Err(e) => {
+            log::error!("Skipping {}, failed to serialize its symbols: {}", path, e);
+            return None;
+        }
+    };
+
+    Some(FileFields {
+        tenant_id: get_tenant_id(),
+        repo_name: repo_name.to_string(),
+        repo_disk_path: repo_path.to_string(),
+        repo_ref: String::new(),
+        relative_path: path.to_string(),
+        last_commit: String::new(),
+        lang: codeowners::CODEOWNERS_LANGUAGE.to_string(),
+        is_directory: false,
+        avg_line_length: buffer.len() as f64 / buffer.lines().count().max(1) as f64,
+        line_end_indices,
+        content: buffer.to_string(),
+        symbol_locations,
+        unique_hash: tantivy_hash,
+        symbols: String::new(),
+    })
+}
+
+// Everything derived from a single file that is needed to index it.
+pub struct ProcessedFile {
+    pub file_fields: FileFields,
+    pub semantic_payload: SemanticPayload,
+    pub symbol_metas: Vec<(SymbolKey, SymbolValue)>,
+    pub code_file: CodeFile,
+}
 
-        if let Err(e) = result {
-            println!("Error: {:?}", e);
+// Builds the quickwit fields, semantic payload and symbol metadata for a single file.
+// Returns the reason if the file should not be indexed. A paths-only run doesn't parse the file,
+// it has no symbols or doc comments.
+pub fn process_file_content(
+    path: &str,
+    content_buffer: &[u8],
+    repo_name: &str,
+    repo_path: &str,
+    disk_path: &Path,
+    size_limits: &SizeLimits,
+    index_mode: IndexMode,
+) -> std::result::Result<ProcessedFile, SkipReason> {
+    let path_buf = PathBuf::from(path);
+
+    // Skip files over the size limits before any decoding or parsing work is done on them.
+    if let Err(skipped) = size_limits.check(path, content_buffer) {
+        println!("Skipping file due to size: {}", skipped);
+        return Err(SkipReason::Size(skipped));
+    }
+
+    // Decode the content of the blob into a UTF-8 string, everything below reads the decoded
+    // text: the hashes, the parsing, the chunks and the quickwit document.
+    let decoded = match encoding::decode(content_buffer) {
+        Ok(decoded) => decoded,
+        Err(reason) => {
+            log::warn!("Skipping {}, {}", path, reason);
+            return Err(SkipReason::Undecodable(reason));
         }
+    };
+    if decoded.encoding != Encoding::Utf8 {
+        log::info!("Decoded {} from {}", path, decoded.encoding);
+    }
+    let decoded = decoded.text.into_owned();
+    let content_buffer = decoded.as_bytes();
+    let mut buffer = decoded.clone();
+
+    // Compute the relative path for the file.
+    let relative_path = PathBuf::from(path)
+        .strip_prefix(disk_path)
+        .map(ToOwned::to_owned)
+        .unwrap_or(PathBuf::from(path));
+
+    // Compute the semantic and tantivy hashes for the file. NOTE: "main" is hardcoded.
+    let (semantic_hash, tantivy_hash) = compute_hashes(relative_path, &buffer, "main");
+
+    // Detect the programming language of the file.
+    let language = util::detect_language(&path_buf, content_buffer)
+        .map(|s| s.to_string())
+        .unwrap_or("Unknown".to_string());
+
+    // If the language is unsupported, skip the file.
+    if language == "Unknown" {
+        print!("Unsupported language: {}", language);
+        return Err(SkipReason::Unsupported);
+    }
 
-        // index to quickwit
-        index_processor::process_entries(all_entries, repo_name).await;
+    // Build a syntax-aware representation of the file.
+    let ast = index_mode
+        .is_full()
+        .then(|| CodeFileAST::build_ast(content_buffer, &language).ok())
+        .flatten();
+    let doc_comments = ast
+        .as_ref()
+        .map(CodeFileAST::doc_comments)
+        .unwrap_or_default();
+    let symbol_locations = {
+        let scope_graph = ast.map(CodeFileAST::scope_graph);
+
+        // Return the graph if it exists or return an empty representation.
+        match scope_graph {
+            Some(Ok(graph)) => SymbolLocations::TreeSitter(graph),
+            _ => SymbolLocations::Empty,
+        }
+    };
+
+    // Extract symbols from the syntax-aware representation.
+    let symbols = symbol_locations
+        .list()
+        .iter()
+        .map(|sym| buffer[sym.range.start.byte..sym.range.end.byte].to_owned())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // Collect metadata for each symbol in the file.
+    let symbol_metas = symbol_locations
+        .list_metadata(content_buffer, repo_name, &language, path)
+        .into_iter()
+        .map(|meta| {
+            let meta_key = SymbolKey {
+                symbol: meta.symbol_type.clone(),
+                repo_name: meta.repo_name.clone(),
+            };
+
+            let meta_value = SymbolValue {
+                symbol_type: meta.symbol.clone(),
+                language_id: meta.language_id.clone(),
+                relative_path: meta.relative_path.clone(),
+                start_byte: meta.range.start.byte,
+                end_byte: meta.range.end.byte,
+                is_global: meta.is_global,
+                node_kind: meta.node_kind.clone(),
+                container_path: meta.container_path.clone(),
+            };
+
+            (meta_key, meta_value)
+        })
+        .collect::<Vec<_>>();
 
-        Ok(())
+    // Ensure the content ends with a newline.
+    if !buffer.ends_with('\n') {
+        buffer += "\n";
     }
+
+    // Compute line ending indices for the file.
+    let line_end_indices = buffer
+        .match_indices('\n')
+        .flat_map(|(i, _)| u32::to_le_bytes(i as u32))
+        .collect::<Vec<_>>();
+
+    let lines_avg = buffer.len() as f64 / buffer.lines().count() as f64;
+
+    // Convert the path from PathBuf to &str and process further.
+    let Some(path_str) = path_buf.as_path().to_str() else {
+        println!("Path is not valid UTF-8");
+        return Err(SkipReason::Unsupported);
+    };
+
+    // Create a struct to store various semantic data.
+    let semantic_payload = SemanticPayload {
+        path: path_str.to_string(),
+        buffer: buffer.clone(),
+        semantic_hash: semantic_hash.clone(),
+        language: language.clone(),
+        doc_comments,
+        last_modified: None,
+    };
+
+    let symbol_locations = bincode::serialize(&symbol_locations)
+        .map_err(|e| SkipReason::Failed(anyhow::anyhow!("failed to serialize the symbols: {}", e)))?;
+
+    // Create a struct to store various fields about the file.
+    let file_fields = FileFields {
+        tenant_id: get_tenant_id(),
+        repo_name: repo_name.to_string(),
+        // use the disk path of the repo.
+        repo_disk_path: repo_path.to_string(),
+        repo_ref: String::new(),
+        lang: language.clone(),
+        relative_path: path.to_string(),
+        last_commit: String::new(),
+        is_directory: false,
+        avg_line_length: lines_avg,
+        line_end_indices,
+        content: buffer.clone(),
+        symbol_locations,
+        unique_hash: tantivy_hash.clone(),
+        symbols,
+    };
+
+    Ok(ProcessedFile {
+        file_fields,
+        semantic_payload,
+        symbol_metas,
+        code_file: CodeFile {
+            path: path.to_string(),
+            buffer,
+            semantic_hash,
+            tantivy_hash,
+            language,
+        },
+    })
 }
 
 // Define a structure to represent an Indexer.
@@ -607,69 +1301,947 @@ impl Indexer {
     async fn index_repository(
         &self,
         disk_path: PathBuf,
-        _metadata: &RepoMetadata,
-        _writer: &IndexWriter,
         repo_name: String,
         branch: &str,
-    ) -> Result<()> {
+        checkpoint: CheckpointOptions,
+        index_mode: IndexMode,
+    ) -> Result<Repository> {
         // Create a new Repository instance using the `new` method.
-        let repo_path_string = disk_path.to_str().unwrap().to_string();
-        let mut repo = Repository::new(disk_path, repo_name.clone()).await?;
+        let repo_path_string = disk_path.to_string_lossy().to_string();
+        let mut repo = Repository::new(disk_path, repo_name.clone(), index_mode).await?;
         // Call the traverse method to list the files in the repository.
-        repo.traverse(&repo_path_string, &repo_name.clone(), branch)
+        let summary = repo
+            .traverse(&repo_path_string, &repo_name.clone(), branch, checkpoint)
             .await?;
+        log::info!(
+            "Indexed {} files of {} into the quickwit index {}{}",
+            summary.documents,
+            repo_name,
+            summary.index_id,
+            if summary.created { " (created)" } else { "" }
+        );
         // Print the disk path of the repository.
         print!("Indexing repository at path: {:?}", repo.disk_path);
         println!("Indexing repository at path: {:?}", repo.disk_path);
+        // the code-understanding service blames the working copy to answer questions about the
+        // history, it finds it through `REPO_WORKING_COPIES` in its env.
+        let remote = repo
+            .git_repo
+            .find_remote("origin")
+            .ok()
+            .and_then(|remote| remote.url().map(str::to_owned))
+            .unwrap_or_else(|| "none".to_string());
+        log::info!(
+            "Working copy of {}: REPO_WORKING_COPIES={}={} (remote: {})",
+            repo_name,
+            repo_name,
+            repo.disk_path.display(),
+            remote
+        );
 
-        Ok(())
+        Ok(repo)
     }
 }
 
 /// Application to process repository data
 #[derive(Parser, Debug)]
 #[command(version = "0.1", about = "Index repository data", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     #[clap(long)]
     env_file: Option<String>,
     /// Name to the repository folder inside ./repo/ directory
-    #[arg(long, help = "Sets the repository folder to process")]
-    repo_folder: String,
+    #[arg(
+        long,
+        required_unless_present = "validate_queries",
+        help = "Sets the repository folder to process"
+    )]
+    repo_folder: Option<String>,
 
     /// Identifier for the repository, used to later perform search and agent operations on the repo.
-    #[arg(long, help = "Sets the repository ID")]
-    repo_id: String,
+    #[arg(
+        long,
+        required_unless_present = "validate_queries",
+        help = "Sets the repository ID"
+    )]
+    repo_id: Option<String>,
 
     #[arg(long, help = "Sets the branch to be indexed")]
     branch: Option<String>,
+
+    /// Tenant owning the repository, overrides `TENANT_ID` from the env file.
+    #[arg(long, help = "Sets the tenant the repository is indexed for")]
+    tenant_id: Option<String>,
+
+    /// Files larger than this are not indexed, overrides `MAX_FILE_BYTES` from the env file.
+    #[arg(long, help = "Sets the maximum size of an indexed file in bytes")]
+    max_file_bytes: Option<u64>,
+
+    /// Files with more lines than this are not indexed, overrides `MAX_LINE_COUNT` from the env file.
+    #[arg(long, help = "Sets the maximum number of lines of an indexed file")]
+    max_lines: Option<usize>,
+
+    /// Custom limits for the paths matching a glob, as `<glob>=<max_file_bytes>:<max_lines>`.
+    /// Can be repeated, the first matching glob wins.
+    #[arg(long, help = "Sets custom size limits for paths matching a glob")]
+    size_limit_override: Vec<SizeLimitOverride>,
+
+    /// File the ingestion metrics are written to in the prometheus text format,
+    /// e.g. for the node exporter textfile collector.
+    #[arg(long, help = "Writes ingestion metrics to the given file")]
+    metrics_textfile: Option<PathBuf>,
+
+    /// File the manifest of the run is written to, it is stored in the index of the repo either way.
+    #[arg(long, help = "Writes the run manifest to the given file")]
+    run_manifest_file: Option<PathBuf>,
+
+    /// Continues the run saved in the checkpoint, skipping the files it already committed.
+    #[arg(long, help = "Resumes an interrupted indexing run from its checkpoint")]
+    resume: bool,
+
+    /// Progress file of the run, defaults to `<repo folder>.checkpoint.json` next to the repo.
+    /// It's deleted once every file is indexed.
+    #[arg(long, help = "Sets the checkpoint file of the indexing run")]
+    checkpoint: Option<PathBuf>,
+
+    /// The checkpoint is saved after this many files or seconds, whichever comes first.
+    #[arg(long, default_value_t = checkpoint::DEFAULT_CHECKPOINT_EVERY_FILES)]
+    checkpoint_every_files: usize,
+
+    #[arg(long, default_value_t = checkpoint::DEFAULT_CHECKPOINT_EVERY_SECS)]
+    checkpoint_every_secs: u64,
+
+    /// `paths-only` writes the documents and the manifest without chunks, embeddings or symbols,
+    /// for repos too large to embed. A later `full` run upgrades the repo in place.
+    #[arg(long, default_value_t = IndexMode::Full, help = "Sets what is indexed, full or paths-only")]
+    index_mode: IndexMode,
+
+    /// Directory of the tree-sitter query packs, overrides `QUERY_PACKS_DIR` from the env file.
+    /// The languages without a pack are indexed with the compiled-in queries.
+    #[arg(long, help = "Sets the directory of the tree-sitter query packs")]
+    query_packs: Option<PathBuf>,
+
+    /// Parses every query pack against the grammar of its language, reports the errors and exits
+    /// without indexing.
+    #[arg(long, help = "Validates the query packs and exits")]
+    validate_queries: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Index the repository, then keep re-indexing files as they change in the working tree.
+    Watch {
+        /// Window in milliseconds used to coalesce rapid saves into one re-index.
+        #[arg(long, default_value_t = watch::DEFAULT_DEBOUNCE_MS)]
+        debounce_ms: u64,
+    },
+    /// Re-embed the stored chunks and symbols with the model in `MODEL_DIR` into new collections,
+    /// then point the collection aliases to them. Doesn't need a repository.
+    MigrateEmbeddings {
+        /// The new collections are named `<collection>_<target-suffix>`, e.g. `documents_v2`.
+        #[arg(long)]
+        target_suffix: String,
+        /// Progress file, running the migration again with it resumes where it stopped.
+        #[arg(long, default_value = "migrate-embeddings.checkpoint.json")]
+        checkpoint: PathBuf,
+        #[arg(long, default_value_t = migrate::DEFAULT_MIGRATION_PAGE_SIZE)]
+        page_size: u32,
+        /// Drops the old collections when they aren't behind an alias yet, so the alias can take their name.
+        #[arg(long)]
+        drop_source: bool,
+        /// Drops the collections the aliases pointed to before the switch, except the ones
+        /// conversations are still pinned to in the redis of --pins-redis-url.
+        #[arg(long, requires = "pins_redis_url")]
+        drop_previous: bool,
+        /// Redis of the coordinator, where conversations register the collections they read.
+        #[arg(long)]
+        pins_redis_url: Option<String>,
+        /// Gives the points indexed with random ids the ids the indexer derives from their content,
+        /// the copies of a chunk are merged. The chunk ids depend on --branch.
+        #[arg(long)]
+        stable_ids: bool,
+    },
+    /// Removes the points and documents of --branch of the repo --repo-id from the indexes, the
+    /// other branches of the repo stay. Doesn't need a repository folder.
+    DeleteBranch,
+    /// Writes the points and quickwit documents of every branch of the repo --repo-id to a
+    /// snapshot archive, to load them in another environment without embedding the repo again.
+    /// Doesn't need a repository folder.
+    ExportIndex {
+        #[arg(long)]
+        archive: PathBuf,
+        #[arg(long, default_value_t = snapshot::DEFAULT_SNAPSHOT_PAGE_SIZE)]
+        page_size: u32,
+    },
+    /// Loads a snapshot archive into the collections the aliases point to and the quickwit index
+    /// of its repo. The snapshot must be embedded with the model in `MODEL_DIR`.
+    ImportIndex {
+        #[arg(long)]
+        archive: PathBuf,
+        /// Progress file, running the import again with it resumes where it stopped. Defaults to
+        /// the archive path with the extension `import.json`.
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+        #[arg(long, default_value_t = snapshot::DEFAULT_SNAPSHOT_PAGE_SIZE)]
+        page_size: u32,
+    },
+}
+
+// Failing to write metrics shouldn't fail the indexing, so errors are only logged.
+pub fn write_metrics_textfile(path: Option<&Path>) {
+    if let Some(path) = path {
+        if let Err(e) = metrics::write_textfile(path) {
+            log::error!("Failed to write metrics to {:?}: {}", path, e);
+        }
+    }
 }
 
 #[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        log::error!("{:?}", e);
+        eprintln!("{}", e.message());
+        std::process::exit(e.exit_code());
+    }
+}
 
-async fn main() -> Result<()> {
+async fn run() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
     initialize_config(args.env_file);
+    // traces are only exported when an OTLP endpoint is configured in the env.
+    if let Err(err) = common::telemetry::init("ingestion") {
+        log::warn!("Failed to initialize tracing, traces are not exported: {}", err);
+    }
+    override_size_limits(args.max_file_bytes, args.max_lines, args.size_limit_override);
+    if let Some(tenant_id) = args.tenant_id {
+        override_tenant_id(tenant_id);
+    }
+
+    if let Some(Command::MigrateEmbeddings {
+        target_suffix,
+        checkpoint,
+        page_size,
+        drop_source,
+        drop_previous,
+        pins_redis_url,
+        stable_ids,
+    }) = args.command
+    {
+        let options = migrate::MigrationOptions {
+            target_suffix,
+            checkpoint_path: checkpoint,
+            page_size,
+            drop_source,
+            drop_previous,
+            pinned: HashSet::new(),
+            stable_ids,
+            branch: args.branch.unwrap_or_else(|| DEFAULT_BRANCH.to_string()),
+            warmup_searches: get_qdrant_warmup_searches(),
+        };
+        return migrate_embeddings(options, pins_redis_url).await;
+    }
 
-    log::info!("Processing repository folder: {}", args.repo_folder);
-    log::info!("Using repository ID: {}", args.repo_id);
+    if let Some(Command::ExportIndex { archive, page_size }) = args.command {
+        let Some(repo_id) = args.repo_id else {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--repo-id is required to export an index",
+                )
+                .exit();
+        };
+        let options = snapshot::ExportOptions {
+            repo_name: repo_id,
+            archive,
+            page_size,
+            embedding: run_manifest::environment_embedding(),
+        };
+        return export_index(options).await;
+    }
+
+    if let Some(Command::ImportIndex {
+        archive,
+        checkpoint,
+        page_size,
+    }) = args.command
+    {
+        let options = snapshot::ImportOptions {
+            checkpoint_path: checkpoint.unwrap_or_else(|| archive.with_extension("import.json")),
+            archive,
+            page_size,
+            embedding: run_manifest::environment_embedding(),
+        };
+        return import_index(options).await;
+    }
+
+    if let Some(Command::DeleteBranch) = args.command {
+        let (Some(repo_id), Some(branch)) = (args.repo_id, args.branch) else {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--repo-id and --branch are required to delete a branch",
+                )
+                .exit();
+        };
+        return delete_branch(&repo_id, &branch).await;
+    }
+
+    let query_packs_dir = args
+        .query_packs
+        .or_else(|| get_query_packs_dir().map(PathBuf::from));
+    if args.validate_queries {
+        let Some(dir) = query_packs_dir else {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--query-packs or QUERY_PACKS_DIR is required to validate the query packs",
+                )
+                .exit();
+        };
+        let packs = load_query_packs(&dir)?;
+        println!(
+            "The query packs {} of {} are valid",
+            packs.version,
+            packs.language_names().join(", ")
+        );
+        return Ok(());
+    }
+    if let Some(dir) = query_packs_dir {
+        let packs = install_query_packs(load_query_packs(&dir)?);
+        log::info!(
+            "Using the query packs {} for {}",
+            packs.version,
+            packs.language_names().join(", ")
+        );
+    }
+
+    // only the migration, the branch deletion and the snapshots run without a repository.
+    let (Some(repo_folder), Some(repo_id)) = (args.repo_folder, args.repo_id) else {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--repo-folder and --repo-id are required",
+            )
+            .exit();
+    };
+
+    log::info!("Processing repository folder: {}", repo_folder);
+    log::info!("Using repository ID: {}", repo_id);
 
     // defaults to main branch if branch is not set.
-    let branch = args.branch.unwrap_or_else(|| "refs/heads/main".to_string());
+    let branch = args.branch.unwrap_or_else(|| DEFAULT_BRANCH.to_string());
 
     // Path to the repository
-    let repo_base_path = env::current_dir()?.join("repo").join(&args.repo_folder);
+    let repo_base_path = env::current_dir()?.join("repo").join(&repo_folder);
     log::info!("Full repository path: {:?}", repo_base_path);
 
+    let checkpoint = CheckpointOptions {
+        path: args
+            .checkpoint
+            .unwrap_or_else(|| CheckpointOptions::default_path(&repo_base_path)),
+        resume: args.resume,
+        every_files: args.checkpoint_every_files,
+        every: std::time::Duration::from_secs(args.checkpoint_every_secs),
+    };
+
     // Instantiate an Indexer.
     let indexer = Indexer;
 
-    // Instantiate some additional components of a repository.
-    let metadata = RepoMetadata;
-    let writer = IndexWriter;
-
     // Use the indexer to index the repository, passing the disk path.
-    indexer
-        .index_repository(repo_base_path, &metadata, &writer, args.repo_id, &branch)
+    // root span of the run, the phases of the indexing are its children.
+    let run_span = tracing::info_span!("index_repository", repo = %repo_id, branch = %branch);
+    let repo = indexer
+        .index_repository(
+            repo_base_path,
+            repo_id,
+            &branch,
+            checkpoint,
+            args.index_mode,
+        )
+        .instrument(run_span)
+        .await;
+    // flush the spans of the run, the re-indexing of the watch mode isn't traced.
+    common::telemetry::shutdown();
+    let mut repo = repo?;
+    write_metrics_textfile(args.metrics_textfile.as_deref());
+    if let Some(manifest) = &repo.run_manifest {
+        run_manifest::write_run_manifest(args.run_manifest_file.as_deref(), manifest);
+    }
+
+    if let Some(Command::Watch { debounce_ms }) = args.command {
+        watch::watch_repository(
+            &repo,
+            std::time::Duration::from_millis(debounce_ms),
+            args.metrics_textfile.as_deref(),
+        )
+        .await?;
+    }
+
+    // the rest of the repo is indexed, the run still exits with the error of the first failed file.
+    match repo.file_errors.len() {
+        0 => Ok(()),
+        failed => {
+            eprintln!("{} files failed to be indexed, the first one:", failed);
+            Err(repo.file_errors.remove(0))
+        }
+    }
+}
+
+// Every error of the packs is printed, the run fails with their count.
+fn load_query_packs(dir: &Path) -> Result<query_pack::QueryPacks> {
+    query_pack::load_query_packs(dir).map_err(|errors| {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        IngestionError::QueryPack(anyhow::anyhow!(
+            "{} errors in the query packs of {:?}",
+            errors.len(),
+            dir
+        ))
+    })
+}
+
+async fn migrate_embeddings(
+    mut options: migrate::MigrationOptions,
+    pins_redis_url: Option<String>,
+) -> Result<()> {
+    let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(&get_qdrant_url())))
+        .map_err(IngestionError::QdrantCommit)?;
+    // the collections behind the aliases now are the ones the switch replaces.
+    if let (true, Some(redis_url)) = (options.drop_previous, pins_redis_url) {
+        let current = migrate::MigrationStore::aliases(&qdrant)
+            .await
+            .map_err(IngestionError::QdrantCommit)?
+            .into_values()
+            .collect::<Vec<_>>();
+        options.pinned = common::generation::pinned_collections(&redis_url, &current)
+            .map_err(IngestionError::QdrantCommit)?;
+        log::info!("Collections pinned by conversations: {:?}", options.pinned);
+    }
+    let semantic = SemanticIndex::new(&0).map_err(IngestionError::Embedding)?;
+    let reports = migrate::migrate_embeddings(&qdrant, |text| semantic.embed(text), &options)
+        .await
+        .map_err(IngestionError::QdrantCommit)?;
+    for report in reports {
+        log::info!(
+            "{}: {} points in {}, {} points in {} ({} merged), alias switched: {}, warm-up searches: {}",
+            report.alias,
+            report.source_count,
+            report.source,
+            report.target_count,
+            report.target,
+            report.merged,
+            report.alias_switched,
+            report.warmup_searches
+        );
+    }
+    Ok(())
+}
+
+async fn export_index(options: snapshot::ExportOptions) -> Result<()> {
+    let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(&get_qdrant_url())))
+        .map_err(IngestionError::QdrantCommit)?;
+    let manifest = snapshot::export_index(&qdrant, &options)
+        .await
+        .map_err(IngestionError::Snapshot)?;
+    log::info!(
+        "Wrote snapshot {} of {} to {:?}",
+        manifest.snapshot_id,
+        manifest.repo_name,
+        options.archive
+    );
+    Ok(())
+}
+
+async fn import_index(options: snapshot::ImportOptions) -> Result<()> {
+    let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(&get_qdrant_url())))
+        .map_err(IngestionError::QdrantCommit)?;
+    let reports = snapshot::import_index(&qdrant, &options)
+        .await
+        .map_err(IngestionError::Snapshot)?;
+    for report in reports {
+        log::info!(
+            "{:?}: {} imported into {}",
+            report.kind,
+            report.imported,
+            report.target
+        );
+    }
+    Ok(())
+}
+
+async fn delete_branch(repo_name: &str, branch: &str) -> Result<()> {
+    let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(&get_qdrant_url())))
+        .map_err(IngestionError::QdrantCommit)?;
+    let selector = watch::branch_points_selector(repo_name, branch);
+    for collection_name in [COLLECTION_NAME, COLLECTION_NAME_SYMBOLS] {
+        qdrant
+            .delete_points(collection_name, &selector, None)
+            .await
+            .map_err(IngestionError::QdrantCommit)?;
+    }
+    index_processor::delete_branch_documents(repo_name, branch)
         .await
+        .map_err(IngestionError::QuickwitCommit)?;
+    log::info!("Deleted the branch {} of {}", branch_name(branch), repo_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The checkpoint of a new run, in the temp dir until it is finished.
+    fn test_checkpointer() -> Checkpointer {
+        let options = CheckpointOptions {
+            path: std::env::temp_dir()
+                .join(format!("index-{}.checkpoint.json", uuid::Uuid::new_v4())),
+            resume: false,
+            every_files: 50,
+            every: std::time::Duration::from_secs(3600),
+        };
+        let generation = CollectionGeneration {
+            chunks: COLLECTION_NAME.to_string(),
+            symbols: COLLECTION_NAME_SYMBOLS.to_string(),
+        };
+        Checkpointer::start(options, "repo", "main", generation).unwrap()
+    }
+
+    // Records the files whose chunks it is asked to commit, nothing is embedded.
+    #[derive(Default)]
+    struct RecordingCommitter {
+        committed: Vec<String>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl FileCommitter for RecordingCommitter {
+        async fn discard(&mut self, _path: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn commit(&mut self, payload: &SemanticPayload) -> anyhow::Result<()> {
+            self.committed.push(payload.path.clone());
+            Ok(())
+        }
+    }
+
+    // A repository with one committed file, without qdrant clients.
+    fn test_repository() -> (Repository, git2::Oid) {
+        let disk_path = std::env::temp_dir().join(format!("repo-{}", uuid::Uuid::new_v4()));
+        let git_repo = GitRepository::init(&disk_path).unwrap();
+        let blob = git_repo.blob(b"fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+        let repo = Repository {
+            disk_path,
+            repo_name: "repo".to_string(),
+            git_repo,
+            file_entries: HashMap::new(),
+            repo_entries: Vec::new(),
+            qdrant_client_code_chunk: None,
+            qdrant_client_symbol: None,
+            semantic_payloads: Vec::new(),
+            max_pending_payloads: MAX_PENDING_PAYLOADS,
+            symbol_meta_payload: HashMap::new(),
+            skipped_for_size: Vec::new(),
+            skipped_undecodable: Vec::new(),
+            low_quality_chunks: Vec::new(),
+            file_errors: Vec::new(),
+            run_manifest: None,
+            branch: DEFAULT_BRANCH.to_string(),
+            collection_tuning: BTreeMap::new(),
+            index_mode: IndexMode::Full,
+        };
+        (repo, blob)
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_blob_is_recorded_and_the_walk_goes_on() {
+        let (mut repo, blob) = test_repository();
+        // an entry whose object is missing from the object database.
+        let missing = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let walked = vec![
+            ("src/broken.rs".to_string(), ObjectType::Blob, missing),
+            ("src/main.rs".to_string(), ObjectType::Blob, blob),
+        ];
+
+        let mut summary = RepoSummaryBuilder::new();
+        let mut sink = index_processor::QuickwitSink::counting();
+        let mut checkpointer = test_checkpointer();
+        let errors = repo
+            .process_walked(
+                walked,
+                &WalkInputs {
+                    repo_name: "repo",
+                    repo_path: "/tmp/repo",
+                    indexed_commit: "abc123",
+                    size_limits: &SizeLimits::default(),
+                    times: &LastModified::default(),
+                },
+                FileMiners {
+                    summary: &mut summary,
+                    terminology: &mut TerminologyMiner::default(),
+                },
+                &mut sink,
+                &mut RecordingCommitter::default(),
+                &mut checkpointer,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0],
+            IngestionError::PerFile { path, .. } if path == "src/broken.rs"
+        ));
+        assert_eq!(errors[0].exit_code(), 8);
+        // the file after the corrupt one is still indexed.
+        assert_eq!(repo.semantic_payloads.len(), 1);
+        assert_eq!(repo.semantic_payloads[0].path, "src/main.rs");
+        assert_eq!(sink.finish().await, 1);
+        checkpointer.finish(0).unwrap();
+        std::fs::remove_dir_all(&repo.disk_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_documents_quickwit_has_are_not_sent_again() {
+        let (mut repo, blob) = test_repository();
+        let other = repo.git_repo.blob(b"fn other() {}\n").unwrap();
+        let walked = vec![
+            ("src/main.rs".to_string(), ObjectType::Blob, blob),
+            ("src/other.rs".to_string(), ObjectType::Blob, other),
+        ];
+
+        // a resumed run, quickwit acknowledged the document of src/main.rs before the crash.
+        let mut checkpointer = test_checkpointer();
+        checkpointer
+            .record_documents(vec!["src/main.rs".to_string()])
+            .unwrap();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = sent.clone();
+        let mut sink = index_processor::QuickwitSink::with_sender(1, 1, move |batch| {
+            let received = received.clone();
+            async move {
+                let mut received = received.lock().unwrap();
+                received.extend(batch.into_iter().map(|fields| fields.relative_path));
+                true
+            }
+        });
+        let errors = repo
+            .process_walked(
+                walked,
+                &WalkInputs {
+                    repo_name: "repo",
+                    repo_path: "/tmp/repo",
+                    indexed_commit: "abc123",
+                    size_limits: &SizeLimits::default(),
+                    times: &LastModified::default(),
+                },
+                FileMiners {
+                    summary: &mut RepoSummaryBuilder::new(),
+                    terminology: &mut TerminologyMiner::default(),
+                },
+                &mut sink,
+                &mut RecordingCommitter::default(),
+                &mut checkpointer,
+            )
+            .await
+            .unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(sink.finish().await, 2);
+        assert_eq!(*sent.lock().unwrap(), vec!["src/other.rs".to_string()]);
+        // the acknowledged documents are recorded, to be skipped by the next resume.
+        checkpointer
+            .record_documents(sink.take_acknowledged())
+            .unwrap();
+        assert!(checkpointer.is_document_committed("src/other.rs"));
+        checkpointer.finish(0).unwrap();
+        std::fs::remove_dir_all(&repo.disk_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunks_are_committed_during_the_walk() {
+        let (mut repo, _) = test_repository();
+        repo.max_pending_payloads = 3;
+        let walked = (0..10)
+            .map(|i| {
+                let content = format!("fn file_{}() {{\n    println!(\"{}\");\n}}\n", i, i);
+                let blob = repo.git_repo.blob(content.as_bytes()).unwrap();
+                (format!("src/file_{}.rs", i), ObjectType::Blob, blob)
+            })
+            .collect();
+
+        let mut committer = RecordingCommitter::default();
+        let mut sink = index_processor::QuickwitSink::counting();
+        let mut checkpointer = test_checkpointer();
+        let errors = repo
+            .process_walked(
+                walked,
+                &WalkInputs {
+                    repo_name: "repo",
+                    repo_path: "/tmp/repo",
+                    indexed_commit: "abc123",
+                    size_limits: &SizeLimits::default(),
+                    times: &LastModified::default(),
+                },
+                FileMiners {
+                    summary: &mut RepoSummaryBuilder::new(),
+                    terminology: &mut TerminologyMiner::default(),
+                },
+                &mut sink,
+                &mut committer,
+                &mut checkpointer,
+            )
+            .await
+            .unwrap();
+
+        assert!(errors.is_empty());
+        // the files are committed 3 at a time, the last one is left to the caller.
+        assert_eq!(committer.committed.len(), 9);
+        assert!(checkpointer.is_committed("src/file_8.rs"));
+        assert_eq!(repo.semantic_payloads.len(), 1);
+        assert_eq!(repo.semantic_payloads[0].path, "src/file_9.rs");
+        // the entries are kept without their content.
+        assert_eq!(repo.repo_entries.len(), 10);
+        assert!(repo.repo_entries.iter().all(|entry| match entry {
+            RepoEntry::File(file) => file.buffer.is_empty(),
+            _ => false,
+        }));
+        assert_eq!(sink.finish().await, 10);
+        checkpointer.finish(0).unwrap();
+        std::fs::remove_dir_all(&repo.disk_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_paths_only_run_writes_documents_without_embeddings() {
+        let (repo, blob) = test_repository();
+        let disk_path = repo.disk_path.clone();
+        drop(repo);
+        // no qdrant is running, creating a collection would fail the run.
+        let mut repo = Repository::new(disk_path, "repo".to_string(), IndexMode::PathsOnly)
+            .await
+            .unwrap();
+        assert!(repo.qdrant_client_code_chunk.is_none());
+        assert!(repo.qdrant_client_symbol.is_none());
+        assert!(repo.collection_tuning.is_empty());
+
+        let calls = || crate::ast::BUILD_AST_CALLS.with(|calls| calls.get());
+        let before = calls();
+        let mut sink = index_processor::QuickwitSink::counting();
+        let mut checkpointer = test_checkpointer();
+        let errors = repo
+            .process_walked(
+                vec![("src/main.rs".to_string(), ObjectType::Blob, blob)],
+                &WalkInputs {
+                    repo_name: "repo",
+                    repo_path: "/tmp/repo",
+                    indexed_commit: "abc123",
+                    size_limits: &SizeLimits::default(),
+                    times: &LastModified::default(),
+                },
+                FileMiners {
+                    summary: &mut RepoSummaryBuilder::new(),
+                    terminology: &mut TerminologyMiner::default(),
+                },
+                &mut sink,
+                &mut RecordingCommitter::default(),
+                &mut checkpointer,
+            )
+            .await
+            .unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(calls(), before);
+        assert!(repo.semantic_payloads.is_empty());
+        assert!(repo.symbol_meta_payload.is_empty());
+        // the document is still written, with the content the keyword search reads.
+        assert_eq!(repo.repo_entries.len(), 1);
+        assert_eq!(sink.finish().await, 1);
+        checkpointer.finish(0).unwrap();
+        std::fs::remove_dir_all(&repo.disk_path).unwrap();
+    }
+
+    #[test]
+    fn test_decoded_files_are_indexed_like_their_utf8_version() {
+        let process = |path: &str, content: &[u8]| {
+            process_file_content(
+                path,
+                content,
+                "repo",
+                "/tmp/repo",
+                Path::new("/tmp/repo"),
+                &SizeLimits::default(),
+                IndexMode::Full,
+            )
+            .unwrap()
+        };
+        let utf8 = process(
+            "src/size.rs",
+            "// Größe der Datei in Bytes.\nfn file_size() -> usize {\n    42\n}\n".as_bytes(),
+        );
+        for fixture in [
+            &include_bytes!("../fixtures/encoding/utf16le.rs")[..],
+            &include_bytes!("../fixtures/encoding/utf16be.rs")[..],
+        ] {
+            let decoded = process("src/size.rs", fixture);
+            assert_eq!(decoded.file_fields.content, utf8.file_fields.content);
+            assert_eq!(
+                decoded.file_fields.line_end_indices,
+                utf8.file_fields.line_end_indices
+            );
+            assert_eq!(
+                decoded.file_fields.unique_hash,
+                utf8.file_fields.unique_hash
+            );
+            assert_eq!(
+                decoded.file_fields.symbol_locations,
+                utf8.file_fields.symbol_locations
+            );
+            assert_eq!(
+                decoded.semantic_payload.buffer,
+                utf8.semantic_payload.buffer
+            );
+            assert_eq!(
+                decoded.semantic_payload.semantic_hash,
+                utf8.semantic_payload.semantic_hash
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_files_in_other_encodings_are_indexed_with_their_content() {
+        let (mut repo, _) = test_repository();
+        let fixture = |name: &str| {
+            let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/encoding");
+            std::fs::read(fixtures.join(name)).unwrap()
+        };
+        let fixtures = [
+            ("src/greet.py", fixture("bom.py")),
+            ("src/size.rs", fixture("utf16le.rs")),
+            ("src/order.py", fixture("latin1.py")),
+            ("src/binary.rs", fixture("binary.rs")),
+        ];
+        let walked = fixtures
+            .iter()
+            .map(|(path, content)| {
+                let blob = repo.git_repo.blob(content).unwrap();
+                (path.to_string(), ObjectType::Blob, blob)
+            })
+            .collect();
+
+        let mut sink = index_processor::QuickwitSink::counting();
+        let mut checkpointer = test_checkpointer();
+        let errors = repo
+            .process_walked(
+                walked,
+                &WalkInputs {
+                    repo_name: "repo",
+                    repo_path: "/tmp/repo",
+                    indexed_commit: "abc123",
+                    size_limits: &SizeLimits::default(),
+                    times: &LastModified::default(),
+                },
+                FileMiners {
+                    summary: &mut RepoSummaryBuilder::new(),
+                    terminology: &mut TerminologyMiner::default(),
+                },
+                &mut sink,
+                &mut RecordingCommitter::default(),
+                &mut checkpointer,
+            )
+            .await
+            .unwrap();
+
+        assert!(errors.is_empty());
+        let buffers = repo
+            .semantic_payloads
+            .iter()
+            .map(|payload| (payload.path.as_str(), payload.buffer.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buffers,
+            vec![
+                (
+                    "src/greet.py",
+                    "# Grüße aus Köln\ndef greet():\n    return \"héllo\"\n"
+                ),
+                (
+                    "src/size.rs",
+                    "// Größe der Datei in Bytes.\nfn file_size() -> usize {\n    42\n}\n"
+                ),
+                (
+                    "src/order.py",
+                    "# Café crème, façade\ndef order():\n    return \"café\"\n"
+                ),
+            ]
+        );
+        // the binary file is left out with its reason, it is counted in the run manifest.
+        assert_eq!(
+            repo.skipped_undecodable,
+            vec![("src/binary.rs".to_string(), Undecodable::Binary)]
+        );
+        assert_eq!(sink.finish().await, 3);
+        checkpointer.finish(0).unwrap();
+        std::fs::remove_dir_all(&repo.disk_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_committer_is_recorded_and_the_run_completes() {
+        struct FailingCommitter;
+
+        #[async_trait::async_trait(?Send)]
+        impl FileCommitter for FailingCommitter {
+            async fn discard(&mut self, _path: &str) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            async fn commit(&mut self, payload: &SemanticPayload) -> anyhow::Result<()> {
+                if payload.path.ends_with("b.rs") {
+                    return Err(anyhow::anyhow!("qdrant rejected the points"));
+                }
+                Ok(())
+            }
+        }
+
+        let payloads = ["src/a.rs", "src/b.rs", "src/c.rs"]
+            .iter()
+            .map(|path| SemanticPayload {
+                path: path.to_string(),
+                buffer: "fn a() {}\n".to_string(),
+                semantic_hash: format!("hash-{}", path),
+                language: "Rust".to_string(),
+                doc_comments: Vec::new(),
+                last_modified: None,
+            })
+            .collect::<Vec<_>>();
+        let path = std::env::temp_dir()
+            .join(format!("index-{}.checkpoint.json", uuid::Uuid::new_v4()));
+        let options = CheckpointOptions {
+            path: path.clone(),
+            resume: false,
+            every_files: 50,
+            every: std::time::Duration::from_secs(3600),
+        };
+        let generation = CollectionGeneration {
+            chunks: COLLECTION_NAME.to_string(),
+            symbols: COLLECTION_NAME_SYMBOLS.to_string(),
+        };
+        let mut checkpointer = Checkpointer::start(options, "repo", "main", generation).unwrap();
+
+        let errors = commit_files(&payloads, &mut FailingCommitter, &mut checkpointer)
+            .await
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("src/b.rs"));
+        assert!(checkpointer.is_committed("src/a.rs"));
+        assert!(checkpointer.is_committed("src/c.rs"));
+
+        checkpointer.finish(errors.len()).unwrap();
+        // kept for `--resume` to retry the failed file.
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
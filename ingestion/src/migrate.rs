@@ -0,0 +1,1107 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use common::branch::{branch_name, BRANCH_FIELD};
+use common::{compression, metrics, telemetry};
+use tracing::Instrument;
+use common::tokenizer_onnx::Embedding;
+use qdrant_client::prelude::QdrantClient;
+use qdrant_client::qdrant::{
+    point_id::PointIdOptions, value::Kind, with_payload_selector, CountPoints,
+    PointId, PointStruct, RetrievedPoint, ScrollPoints, SearchPoints, Value, WithPayloadSelector,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_collection_tuning;
+use crate::hash::{self, ID_SCHEME_FIELD, POINT_ID_SCHEME};
+use crate::{Repository, COLLECTION_NAME, COLLECTION_NAME_SYMBOLS};
+
+// Points scrolled, embedded and written per step, progress is checkpointed after each of them.
+pub const DEFAULT_MIGRATION_PAGE_SIZE: u32 = 256;
+
+// Questions like the ones code search gets, sent to a collection after the switch so the first
+// searches of the services don't wait on its segments to be read from disk.
+const WARMUP_QUERIES: &[&str] = &[
+    "where is the request handler defined",
+    "how are errors returned to the caller",
+    "database connection setup",
+    "parse the configuration file",
+    "authentication middleware",
+    "retry with backoff",
+    "unit tests for the parser",
+    "serialize the response to json",
+];
+
+/// A collection to re-embed, addressed by the name the services use for it.
+pub struct MigratedCollection {
+    pub alias: &'static str,
+    // payload fields holding the embedded text, the first one present is used.
+    pub text_fields: &'static [&'static str],
+    pub indexes: &'static [&'static str],
+    // derives the stable id of a point from its payload and the indexed branch, see `hash`.
+    pub stable_id: fn(&HashMap<String, Value>, &str) -> anyhow::Result<uuid::Uuid>,
+}
+
+pub const MIGRATED_COLLECTIONS: [MigratedCollection; 2] = [
+    MigratedCollection {
+        alias: COLLECTION_NAME,
+        text_fields: &["snippet", "text"],
+        indexes: &["repo_name", "content_hash", "relative_path", BRANCH_FIELD],
+        stable_id: chunk_stable_id,
+    },
+    MigratedCollection {
+        alias: COLLECTION_NAME_SYMBOLS,
+        text_fields: &["symbol"],
+        indexes: &["repo_name", "symbol", "symbol_lower", BRANCH_FIELD],
+        stable_id: symbol_stable_id,
+    },
+];
+
+/// Qdrant operations the migration needs, implemented by the qdrant client and mocked in tests.
+#[async_trait]
+pub trait MigrationStore: Send + Sync {
+    /// Maps every alias to the collection it points to.
+    async fn aliases(&self) -> anyhow::Result<HashMap<String, String>>;
+    /// One page of points with their payload, along with the offset of the next page.
+    async fn scroll(
+        &self,
+        collection: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)>;
+    async fn ensure_collection(
+        &self,
+        collection: &str,
+        dimension: u64,
+        indexes: &[&str],
+    ) -> anyhow::Result<()>;
+    /// Returns once the points are written, so the checkpoint never gets ahead of the data.
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()>;
+    async fn count(&self, collection: &str) -> anyhow::Result<u64>;
+    async fn delete_collection(&self, collection: &str) -> anyhow::Result<()>;
+    async fn create_alias(&self, alias: &str, collection: &str) -> anyhow::Result<()>;
+    async fn delete_alias(&self, alias: &str) -> anyhow::Result<()>;
+    /// The nearest points are read but not returned, only the reading matters to the warm-up.
+    async fn search(&self, collection: &str, vector: Vec<f32>, limit: u64) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl MigrationStore for QdrantClient {
+    async fn aliases(&self) -> anyhow::Result<HashMap<String, String>> {
+        Ok(self
+            .list_aliases()
+            .await?
+            .aliases
+            .into_iter()
+            .map(|alias| (alias.alias_name, alias.collection_name))
+            .collect())
+    }
+
+    async fn scroll(
+        &self,
+        collection: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)> {
+        let start = Instant::now();
+        let response = QdrantClient::scroll(
+            self,
+            &ScrollPoints {
+                collection_name: collection.to_string(),
+                offset,
+                limit: Some(limit),
+                with_payload: Some(WithPayloadSelector {
+                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+                }),
+                ..Default::default()
+            },
+        )
+        .instrument(telemetry::db_span("qdrant", "scroll"))
+        .await?;
+        metrics::observe_db_query("qdrant", "scroll", start.elapsed());
+        Ok((response.result, response.next_page_offset))
+    }
+
+    async fn ensure_collection(
+        &self,
+        collection: &str,
+        dimension: u64,
+        indexes: &[&str],
+    ) -> anyhow::Result<()> {
+        if self.has_collection(collection).await? {
+            return Ok(());
+        }
+        self.create_collection(&Repository::collection_config(
+            collection.to_string(),
+            dimension,
+            &get_collection_tuning(),
+        ))
+        .await?;
+        for index in indexes {
+            self.create_field_index(
+                collection,
+                *index,
+                Repository::index_field_type(index),
+                None,
+                None,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+        let count = points.len();
+        self.upsert_points_blocking(collection, points, None).await?;
+        metrics::record_chunks_committed(collection, count);
+        Ok(())
+    }
+
+    async fn count(&self, collection: &str) -> anyhow::Result<u64> {
+        let response = QdrantClient::count(
+            self,
+            &CountPoints {
+                collection_name: collection.to_string(),
+                exact: Some(true),
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(response.result.map(|result| result.count).unwrap_or(0))
+    }
+
+    async fn delete_collection(&self, collection: &str) -> anyhow::Result<()> {
+        QdrantClient::delete_collection(self, collection).await?;
+        Ok(())
+    }
+
+    async fn create_alias(&self, alias: &str, collection: &str) -> anyhow::Result<()> {
+        QdrantClient::create_alias(self, collection, alias).await?;
+        Ok(())
+    }
+
+    async fn delete_alias(&self, alias: &str) -> anyhow::Result<()> {
+        QdrantClient::delete_alias(self, alias).await?;
+        Ok(())
+    }
+
+    async fn search(&self, collection: &str, vector: Vec<f32>, limit: u64) -> anyhow::Result<()> {
+        let start = Instant::now();
+        self.search_points(&SearchPoints {
+            collection_name: collection.to_string(),
+            vector,
+            limit,
+            ..Default::default()
+        })
+        .instrument(telemetry::db_span("qdrant", "search"))
+        .await?;
+        metrics::observe_db_query("qdrant", "search", start.elapsed());
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Offset {
+    Uuid(String),
+    Num(u64),
+}
+
+impl From<PointId> for Offset {
+    fn from(id: PointId) -> Self {
+        match id.point_id_options {
+            Some(PointIdOptions::Num(num)) => Offset::Num(num),
+            Some(PointIdOptions::Uuid(uuid)) => Offset::Uuid(uuid),
+            None => Offset::Num(0),
+        }
+    }
+}
+
+impl From<Offset> for PointId {
+    fn from(offset: Offset) -> Self {
+        match offset {
+            Offset::Num(num) => PointId::from(num),
+            Offset::Uuid(uuid) => PointId::from(uuid),
+        }
+    }
+}
+
+/// How far the migration of one collection got, enough to resume it after a crash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CollectionProgress {
+    pub source: String,
+    pub target: String,
+    // offset of the next page to scroll, None before the first page and after the last one.
+    pub next_offset: Option<Offset>,
+    pub migrated: u64,
+    pub done: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    // keyed by the alias of the migrated collection.
+    pub collections: HashMap<String, CollectionProgress>,
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read checkpoint {:?}", path))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid checkpoint {:?}", path))
+    }
+
+    // written to a temporary file first, a crash while saving keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+pub struct MigrationOptions {
+    // the new collections are named `<alias>_<target_suffix>`.
+    pub target_suffix: String,
+    pub checkpoint_path: PathBuf,
+    pub page_size: u32,
+    // the first migration has to drop the source collection, as the alias takes its name.
+    pub drop_source: bool,
+    // drops the collection an alias pointed to before the switch, unless it is in `pinned`.
+    pub drop_previous: bool,
+    // collections conversations are still pinned to, see `common::generation`.
+    pub pinned: HashSet<String>,
+    // gives the points indexed with random ids their stable id, merging the copies of a chunk.
+    pub stable_ids: bool,
+    // branch the chunks were indexed from, part of their stable id.
+    pub branch: String,
+    // searches sent through an alias once it points to the new collection, none when 0.
+    pub warmup_searches: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub alias: String,
+    pub source: String,
+    pub target: String,
+    pub source_count: u64,
+    pub target_count: u64,
+    // points merged into another one once given their stable id.
+    pub merged: u64,
+    pub alias_switched: bool,
+    // warm-up searches that went through, see `warm_up`.
+    pub warmup_searches: usize,
+}
+
+/// Re-embeds the stored text of every chunk and symbol point with `embed` into new collections,
+/// keeping the point ids and payloads, then points the aliases the services use to them.
+/// Progress is saved after every page, running it again resumes where it stopped.
+pub async fn migrate_embeddings<S: MigrationStore>(
+    store: &S,
+    embed: impl Fn(&str) -> anyhow::Result<Embedding>,
+    options: &MigrationOptions,
+) -> anyhow::Result<Vec<MigrationReport>> {
+    let dimension = embed("dimension probe")?.len() as u64;
+    log::info!("Re-embedding with a model of dimension {}", dimension);
+
+    let mut checkpoint = Checkpoint::load(&options.checkpoint_path)?;
+    let mut reports = Vec::new();
+    for collection in MIGRATED_COLLECTIONS.iter() {
+        let report = migrate_collection(store, &embed, options, collection, dimension, &mut checkpoint)
+            .await?;
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+async fn migrate_collection<S: MigrationStore>(
+    store: &S,
+    embed: &impl Fn(&str) -> anyhow::Result<Embedding>,
+    options: &MigrationOptions,
+    collection: &MigratedCollection,
+    dimension: u64,
+    checkpoint: &mut Checkpoint,
+) -> anyhow::Result<MigrationReport> {
+    let alias = collection.alias;
+    let aliases = store.aliases().await?;
+    let mut progress = match checkpoint.collections.get(alias) {
+        Some(progress) => {
+            log::info!(
+                "Resuming migration of {} into {} after {} points",
+                progress.source,
+                progress.target,
+                progress.migrated
+            );
+            progress.clone()
+        }
+        None => CollectionProgress {
+            source: aliases.get(alias).cloned().unwrap_or_else(|| alias.to_string()),
+            target: format!("{}_{}", alias, options.target_suffix),
+            next_offset: None,
+            migrated: 0,
+            done: false,
+        },
+    };
+    if progress.source == progress.target {
+        return Err(anyhow!(
+            "{} already points to {}, use a different target suffix",
+            alias,
+            progress.target
+        ));
+    }
+
+    let source_count = store.count(&progress.source).await?;
+    store
+        .ensure_collection(&progress.target, dimension, collection.indexes)
+        .await?;
+
+    // copies of a point left by runs with random ids end up on the same stable id, the ones
+    // written after the first are merged into it.
+    let (mut written, mut merged) = if options.stable_ids && progress.migrated > 0 {
+        written_stable_ids(store, collection, options, &progress).await?
+    } else {
+        (HashSet::new(), 0)
+    };
+
+    while !progress.done {
+        let (points, next_offset) = store
+            .scroll(
+                &progress.source,
+                progress.next_offset.clone().map(PointId::from),
+                options.page_size,
+            )
+            .await?;
+
+        let migrated = points
+            .into_iter()
+            .map(|point| {
+                let point = if options.stable_ids {
+                    let point = with_stable_id(point, collection, &options.branch)?;
+                    if !written.insert(point_key(&point.id)) {
+                        merged += 1;
+                    }
+                    point
+                } else {
+                    point
+                };
+                reembed_point(point, collection.text_fields, embed)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let page_len = migrated.len() as u64;
+        if !migrated.is_empty() {
+            store.upsert(&progress.target, migrated).await?;
+        }
+
+        progress.migrated += page_len;
+        progress.done = next_offset.is_none();
+        progress.next_offset = next_offset.map(Offset::from);
+        checkpoint
+            .collections
+            .insert(alias.to_string(), progress.clone());
+        checkpoint.save(&options.checkpoint_path)?;
+
+        log::info!(
+            "Migrated {}/{} points of {} into {}",
+            progress.migrated,
+            source_count,
+            progress.source,
+            progress.target
+        );
+    }
+
+    let target_count = store.count(&progress.target).await?;
+    if target_count + merged != source_count {
+        return Err(anyhow!(
+            "{} has {} points but {} has {}, not switching {}",
+            progress.source,
+            source_count,
+            progress.target,
+            target_count,
+            alias
+        ));
+    }
+
+    let alias_switched = switch_alias(store, alias, &progress, &aliases, options).await?;
+    let warmup_searches = if alias_switched {
+        warm_up(store, alias, embed, options.warmup_searches).await
+    } else {
+        0
+    };
+    Ok(MigrationReport {
+        alias: alias.to_string(),
+        source: progress.source,
+        target: progress.target,
+        source_count,
+        target_count,
+        merged,
+        alias_switched,
+        warmup_searches,
+    })
+}
+
+// The stable ids of the points migrated before a resume and how many of them were merged,
+// read again from the pages of the source they came from.
+async fn written_stable_ids<S: MigrationStore>(
+    store: &S,
+    collection: &MigratedCollection,
+    options: &MigrationOptions,
+    progress: &CollectionProgress,
+) -> anyhow::Result<(HashSet<String>, u64)> {
+    let mut written = HashSet::new();
+    let mut merged = 0;
+    let mut seen = 0;
+    let mut offset = None;
+    while seen < progress.migrated {
+        let (points, next_offset) = store
+            .scroll(&progress.source, offset, options.page_size)
+            .await?;
+        for point in points.into_iter().take((progress.migrated - seen) as usize) {
+            seen += 1;
+            let point = with_stable_id(point, collection, &options.branch)?;
+            if !written.insert(point_key(&point.id)) {
+                merged += 1;
+            }
+        }
+        match next_offset {
+            Some(next) => offset = Some(next),
+            None => break,
+        }
+    }
+    Ok((written, merged))
+}
+
+fn point_key(id: &Option<PointId>) -> String {
+    match id.clone().map(Offset::from) {
+        Some(Offset::Uuid(uuid)) => uuid,
+        Some(Offset::Num(num)) => num.to_string(),
+        None => String::new(),
+    }
+}
+
+// Sends `searches` of the warm-up queries through the alias, so the segments of the collection
+// it now points to are paged in before the services search it. A failed search is only logged,
+// the collection is already in use. Returns how many went through.
+async fn warm_up<S: MigrationStore>(
+    store: &S,
+    alias: &str,
+    embed: &impl Fn(&str) -> anyhow::Result<Embedding>,
+    searches: usize,
+) -> usize {
+    let start = Instant::now();
+    let mut done = 0;
+    for query in WARMUP_QUERIES.iter().cycle().take(searches) {
+        let searched = match embed(query) {
+            Ok(vector) => store.search(alias, vector, 10).await,
+            Err(e) => Err(e),
+        };
+        match searched {
+            Ok(()) => done += 1,
+            Err(e) => log::warn!("Warm-up search of {} failed: {}", alias, e),
+        }
+    }
+    if searches > 0 {
+        log::info!("Warmed up {} with {} searches in {:?}", alias, done, start.elapsed());
+    }
+    done
+}
+
+// Points `alias` to the new collection. When the source is still a plain collection named like the
+// alias, it has to be dropped first, which is only done when asked to. The collection the alias
+// pointed to before is dropped when asked to, unless a conversation is pinned to it.
+async fn switch_alias<S: MigrationStore>(
+    store: &S,
+    alias: &str,
+    progress: &CollectionProgress,
+    aliases: &HashMap<String, String>,
+    options: &MigrationOptions,
+) -> anyhow::Result<bool> {
+    let previous = match aliases.get(alias) {
+        Some(current) if *current == progress.target => return Ok(true),
+        Some(current) => {
+            store.delete_alias(alias).await?;
+            Some(current)
+        }
+        None if options.drop_source => {
+            store.delete_collection(&progress.source).await?;
+            None
+        }
+        None => {
+            log::warn!(
+                "{} is a collection, not an alias. Run again with --drop-source to replace it with an alias to {}",
+                alias,
+                progress.target
+            );
+            return Ok(false);
+        }
+    };
+    store.create_alias(alias, &progress.target).await?;
+    log::info!("{} now points to {}", alias, progress.target);
+
+    match previous {
+        Some(previous) if options.drop_previous && options.pinned.contains(previous) => {
+            log::info!("Keeping {}, conversations are still pinned to it", previous);
+        }
+        Some(previous) if options.drop_previous => {
+            store.delete_collection(previous).await?;
+            log::info!("Dropped {}", previous);
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+// A point indexed with a random id gets the id the indexer derives now, points that already
+// have it are left as they are.
+fn with_stable_id(
+    mut point: RetrievedPoint,
+    collection: &MigratedCollection,
+    branch: &str,
+) -> anyhow::Result<RetrievedPoint> {
+    if point.payload.contains_key(ID_SCHEME_FIELD) {
+        return Ok(point);
+    }
+    let id = (collection.stable_id)(&point.payload, branch)
+        .with_context(|| format!("No stable id for point {:?}", point.id))?;
+    point.id = Some(PointId::from(id.to_string()));
+    point
+        .payload
+        .insert(ID_SCHEME_FIELD.to_string(), Value::from(POINT_ID_SCHEME));
+    // the id is the one of the point on the branch, so is its payload.
+    point
+        .payload
+        .entry(BRANCH_FIELD.to_string())
+        .or_insert_with(|| Value::from(branch_name(branch)));
+    Ok(point)
+}
+
+fn payload_string<'a>(payload: &'a HashMap<String, Value>, field: &str) -> anyhow::Result<&'a str> {
+    match payload.get(field).and_then(|value| value.kind.as_ref()) {
+        Some(Kind::StringValue(value)) => Ok(value.as_str()),
+        _ => Err(anyhow!("missing {}", field)),
+    }
+}
+
+fn chunk_stable_id(payload: &HashMap<String, Value>, branch: &str) -> anyhow::Result<uuid::Uuid> {
+    let byte = |field| -> anyhow::Result<u64> { Ok(payload_string(payload, field)?.parse()?) };
+    // chunks stored before doc chunks existed have no kind.
+    let kind = payload_string(payload, "kind").unwrap_or("code");
+    Ok(hash::chunk_point_id(
+        payload_string(payload, "repo_name")?,
+        payload_string(payload, "relative_path")?,
+        branch,
+        kind,
+        byte("start_byte")?..byte("end_byte")?,
+        payload_string(payload, "content_hash")?,
+    ))
+}
+
+fn symbol_stable_id(payload: &HashMap<String, Value>, branch: &str) -> anyhow::Result<uuid::Uuid> {
+    Ok(hash::symbol_point_id(
+        payload_string(payload, "repo_name")?,
+        branch,
+        payload_string(payload, "symbol")?,
+    ))
+}
+
+// Same id and payload, with the vector of the stored text under the new model.
+fn reembed_point(
+    point: RetrievedPoint,
+    text_fields: &[&str],
+    embed: &impl Fn(&str) -> anyhow::Result<Embedding>,
+) -> anyhow::Result<PointStruct> {
+    let string_field = |field: &str| match point.payload.get(field).and_then(|value| value.kind.as_ref()) {
+        Some(Kind::StringValue(text)) => Some(text.as_str()),
+        _ => None,
+    };
+    // compressed chunks keep their text in `text_z`, it is embedded decompressed.
+    let text = match string_field(compression::COMPRESSION_FIELD) {
+        Some(marker) => compression::chunk_text(
+            Some(marker),
+            string_field(compression::COMPRESSED_TEXT_FIELD),
+            None,
+        )?,
+        None => text_fields
+            .iter()
+            .find_map(|field| string_field(field))
+            .ok_or_else(|| anyhow!("Point {:?} has none of the fields {:?}", point.id, text_fields))?
+            .to_string(),
+    };
+
+    Ok(PointStruct {
+        vectors: Some(embed(&text)?.into()),
+        id: point.id,
+        payload: point.payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockStore {
+        // collection -> id -> (payload, vector), ids are scrolled in order.
+        collections: Mutex<HashMap<String, std::collections::BTreeMap<u64, (HashMap<String, Value>, Vec<f32>)>>>,
+        aliases: Mutex<HashMap<String, String>>,
+        scrolled_offsets: Mutex<Vec<Option<u64>>>,
+        // fails the upsert once this many upserts went through.
+        fail_after_upserts: Mutex<Option<usize>>,
+        // (searched name, collection it resolved to) of every search.
+        searches: Mutex<Vec<(String, String)>>,
+        // points the upserts silently drop, like a write lost on the way.
+        lost_points: Mutex<usize>,
+    }
+
+    impl MockStore {
+        fn with_points(collection: &str, points: Vec<(u64, HashMap<String, Value>)>) -> Self {
+            let store = Self::default();
+            store.insert(collection, points);
+            store
+        }
+
+        fn insert(&self, collection: &str, points: Vec<(u64, HashMap<String, Value>)>) {
+            self.collections.lock().unwrap().insert(
+                collection.to_string(),
+                points
+                    .into_iter()
+                    .map(|(id, payload)| (id, (payload, vec![0.0; 2])))
+                    .collect(),
+            );
+        }
+
+        fn points(&self, collection: &str) -> Vec<(u64, HashMap<String, Value>, Vec<f32>)> {
+            self.collections.lock().unwrap()[collection]
+                .iter()
+                .map(|(id, (payload, vector))| (*id, payload.clone(), vector.clone()))
+                .collect()
+        }
+    }
+
+    fn num(id: &Option<PointId>) -> u64 {
+        match id.clone().map(Offset::from) {
+            Some(Offset::Num(num)) => num,
+            // the stable ids, folded into the numeric keys of the mock.
+            Some(Offset::Uuid(uuid)) => uuid.parse::<uuid::Uuid>().unwrap().as_u128() as u64,
+            None => panic!("point without an id"),
+        }
+    }
+
+    #[async_trait]
+    impl MigrationStore for MockStore {
+        async fn aliases(&self) -> anyhow::Result<HashMap<String, String>> {
+            Ok(self.aliases.lock().unwrap().clone())
+        }
+
+        async fn scroll(
+            &self,
+            collection: &str,
+            offset: Option<PointId>,
+            limit: u32,
+        ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)> {
+            let start = offset.as_ref().map(|_| num(&offset)).unwrap_or(0);
+            self.scrolled_offsets
+                .lock()
+                .unwrap()
+                .push(offset.as_ref().map(|_| start));
+            let collections = self.collections.lock().unwrap();
+            let mut page = collections[collection].range(start..);
+            let points = page
+                .by_ref()
+                .take(limit as usize)
+                .map(|(id, (payload, _))| RetrievedPoint {
+                    id: Some(PointId::from(*id)),
+                    payload: payload.clone(),
+                    ..Default::default()
+                })
+                .collect();
+            let next = page.next().map(|(id, _)| PointId::from(*id));
+            Ok((points, next))
+        }
+
+        async fn ensure_collection(
+            &self,
+            collection: &str,
+            _dimension: u64,
+            _indexes: &[&str],
+        ) -> anyhow::Result<()> {
+            self.collections
+                .lock()
+                .unwrap()
+                .entry(collection.to_string())
+                .or_default();
+            Ok(())
+        }
+
+        async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+            {
+                let mut fail_after = self.fail_after_upserts.lock().unwrap();
+                match fail_after.as_mut() {
+                    Some(0) => return Err(anyhow!("qdrant went away")),
+                    Some(remaining) => *remaining -= 1,
+                    None => {}
+                }
+            }
+            let mut collections = self.collections.lock().unwrap();
+            let target = collections.get_mut(collection).unwrap();
+            let mut lost = self.lost_points.lock().unwrap();
+            for point in points {
+                if *lost > 0 {
+                    *lost -= 1;
+                    continue;
+                }
+                let vector = match point.vectors.unwrap().vectors_options {
+                    Some(qdrant_client::qdrant::vectors::VectorsOptions::Vector(v)) => v.data,
+                    _ => panic!("expected a single vector"),
+                };
+                target.insert(num(&point.id), (point.payload, vector));
+            }
+            Ok(())
+        }
+
+        async fn count(&self, collection: &str) -> anyhow::Result<u64> {
+            Ok(self.collections.lock().unwrap()[collection].len() as u64)
+        }
+
+        async fn delete_collection(&self, collection: &str) -> anyhow::Result<()> {
+            self.collections.lock().unwrap().remove(collection);
+            Ok(())
+        }
+
+        async fn create_alias(&self, alias: &str, collection: &str) -> anyhow::Result<()> {
+            self.aliases
+                .lock()
+                .unwrap()
+                .insert(alias.to_string(), collection.to_string());
+            Ok(())
+        }
+
+        async fn delete_alias(&self, alias: &str) -> anyhow::Result<()> {
+            self.aliases.lock().unwrap().remove(alias);
+            Ok(())
+        }
+
+        async fn search(&self, collection: &str, _vector: Vec<f32>, _limit: u64) -> anyhow::Result<()> {
+            let resolved = self
+                .aliases
+                .lock()
+                .unwrap()
+                .get(collection)
+                .cloned()
+                .unwrap_or_else(|| collection.to_string());
+            self.searches
+                .lock()
+                .unwrap()
+                .push((collection.to_string(), resolved));
+            Ok(())
+        }
+    }
+
+    fn chunk_points(count: u64) -> Vec<(u64, HashMap<String, Value>)> {
+        (0..count)
+            .map(|i| {
+                let payload = HashMap::from([
+                    ("repo_name".to_string(), Value::from("repo-a")),
+                    ("relative_path".to_string(), Value::from(format!("src/file_{}.rs", i))),
+                    ("snippet".to_string(), Value::from("x".repeat(i as usize % 7 + 1))),
+                    ("start_line".to_string(), Value::from((i * 10).to_string())),
+                ]);
+                (i, payload)
+            })
+            .collect()
+    }
+
+    fn symbol_points(count: u64) -> Vec<(u64, HashMap<String, Value>)> {
+        (0..count)
+            .map(|i| {
+                let payload = HashMap::from([
+                    ("repo_name".to_string(), Value::from("repo-a")),
+                    ("symbol".to_string(), Value::from(format!("symbol_{}", i))),
+                    ("overflow_count".to_string(), Value::from(i as i64)),
+                ]);
+                (i, payload)
+            })
+            .collect()
+    }
+
+    // chunks with what their stable id is made of, the points after `unique` copy the first ones.
+    fn duplicated_chunk_points(unique: u64, copies: u64) -> Vec<(u64, HashMap<String, Value>)> {
+        (0..unique + copies)
+            .map(|i| {
+                let payload = HashMap::from([
+                    ("repo_name".to_string(), Value::from("repo-a")),
+                    ("relative_path".to_string(), Value::from(format!("src/file_{}.rs", i % unique))),
+                    ("content_hash".to_string(), Value::from("hash")),
+                    ("start_byte".to_string(), Value::from("0")),
+                    ("end_byte".to_string(), Value::from("120")),
+                    ("snippet".to_string(), Value::from("fn a() {}")),
+                ]);
+                (i, payload)
+            })
+            .collect()
+    }
+
+    // a three dimensional "model", unlike the two dimensions of the mock's old vectors.
+    fn embed(text: &str) -> anyhow::Result<Embedding> {
+        Ok(vec![text.len() as f32, 1.0, 2.0])
+    }
+
+    fn options(name: &str, drop_source: bool) -> MigrationOptions {
+        let checkpoint_path =
+            std::env::temp_dir().join(format!("{}-{}.json", name, uuid::Uuid::new_v4()));
+        MigrationOptions {
+            target_suffix: "v2".to_string(),
+            checkpoint_path,
+            page_size: 64,
+            drop_source,
+            drop_previous: false,
+            pinned: HashSet::new(),
+            stable_ids: false,
+            branch: "refs/heads/main".to_string(),
+            warmup_searches: 0,
+        }
+    }
+
+    #[test]
+    fn test_random_ids_are_replaced_with_stable_ids() {
+        let chunk = |id: u64| {
+            let payload = HashMap::from([
+                ("repo_name".to_string(), Value::from("repo-a")),
+                ("relative_path".to_string(), Value::from("src/lib.rs")),
+                ("content_hash".to_string(), Value::from("hash")),
+                ("start_byte".to_string(), Value::from("0")),
+                ("end_byte".to_string(), Value::from("120")),
+                ("snippet".to_string(), Value::from("fn a() {}")),
+            ]);
+            RetrievedPoint {
+                id: Some(PointId::from(id)),
+                payload,
+                ..Default::default()
+            }
+        };
+        let chunks = &MIGRATED_COLLECTIONS[0];
+
+        // two copies of the same chunk left by runs with random ids.
+        let first = with_stable_id(chunk(1), chunks, "refs/heads/main").unwrap();
+        let copy = with_stable_id(chunk(2), chunks, "refs/heads/main").unwrap();
+        let expected =
+            hash::chunk_point_id("repo-a", "src/lib.rs", "refs/heads/main", "code", 0..120, "hash");
+        assert_eq!(first.id, Some(PointId::from(expected.to_string())));
+        assert_eq!(copy.id, first.id);
+        assert_eq!(first.payload[ID_SCHEME_FIELD], Value::from(POINT_ID_SCHEME));
+        assert_eq!(first.payload[BRANCH_FIELD], Value::from("main"));
+
+        // points that already have a stable id keep it.
+        let again = with_stable_id(first.clone(), chunks, "refs/heads/dev").unwrap();
+        assert_eq!(again.id, first.id);
+
+        let mut incomplete = chunk(3);
+        incomplete.payload.remove("content_hash");
+        assert!(with_stable_id(incomplete, chunks, "refs/heads/main").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migration_preserves_ids_and_payloads() {
+        let store = MockStore::with_points(COLLECTION_NAME, chunk_points(300));
+        store.insert(COLLECTION_NAME_SYMBOLS, symbol_points(150));
+        let options = options("migrate-preserve", true);
+
+        let reports = migrate_embeddings(&store, embed, &options).await.unwrap();
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].source_count, 300);
+        assert_eq!(reports[0].target_count, 300);
+        assert_eq!(reports[1].target_count, 150);
+        assert!(reports.iter().all(|report| report.alias_switched));
+
+        let chunks = store.points("documents_v2");
+        let originals = chunk_points(300);
+        for ((id, payload, vector), (original_id, original_payload)) in chunks.iter().zip(&originals) {
+            assert_eq!(id, original_id);
+            assert_eq!(payload, original_payload);
+            assert_eq!(vector, &vec![(*id % 7 + 1) as f32, 1.0, 2.0]);
+        }
+        let symbols = store.points("documents_symbol_v2");
+        assert_eq!(symbols[42].1, symbol_points(150)[42].1);
+        assert_eq!(symbols[42].2, vec!["symbol_42".len() as f32, 1.0, 2.0]);
+
+        // the old collections are replaced by aliases to the new ones.
+        let aliases = store.aliases.lock().unwrap().clone();
+        assert_eq!(aliases[COLLECTION_NAME], "documents_v2");
+        assert_eq!(aliases[COLLECTION_NAME_SYMBOLS], "documents_symbol_v2");
+        assert!(!store.collections.lock().unwrap().contains_key(COLLECTION_NAME));
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migration_resumes_from_checkpoint() {
+        let store = MockStore::with_points(COLLECTION_NAME, chunk_points(300));
+        store.insert(COLLECTION_NAME_SYMBOLS, symbol_points(10));
+        *store.fail_after_upserts.lock().unwrap() = Some(2);
+        let options = options("migrate-resume", false);
+
+        assert!(migrate_embeddings(&store, embed, &options).await.is_err());
+        let checkpoint = Checkpoint::load(&options.checkpoint_path).unwrap();
+        let progress = &checkpoint.collections[COLLECTION_NAME];
+        assert_eq!(progress.migrated, 128);
+        assert_eq!(progress.next_offset, Some(Offset::Num(128)));
+
+        *store.fail_after_upserts.lock().unwrap() = None;
+        store.scrolled_offsets.lock().unwrap().clear();
+        let reports = migrate_embeddings(&store, embed, &options).await.unwrap();
+
+        // the second run starts at the page that failed instead of the beginning.
+        assert_eq!(store.scrolled_offsets.lock().unwrap()[0], Some(128));
+        assert_eq!(reports[0].target_count, 300);
+        assert_eq!(store.points("documents_v2").len(), 300);
+        // without --drop-source the plain collections are left in place.
+        assert!(reports.iter().all(|report| !report.alias_switched));
+        assert!(store.aliases.lock().unwrap().is_empty());
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migration_switches_existing_alias() {
+        let store = MockStore::with_points("documents_v1", chunk_points(20));
+        store.insert("documents_symbol_v1", symbol_points(5));
+        store.create_alias(COLLECTION_NAME, "documents_v1").await.unwrap();
+        store
+            .create_alias(COLLECTION_NAME_SYMBOLS, "documents_symbol_v1")
+            .await
+            .unwrap();
+        let options = options("migrate-alias", false);
+
+        let reports = migrate_embeddings(&store, embed, &options).await.unwrap();
+
+        assert_eq!(reports[0].source, "documents_v1");
+        assert_eq!(store.aliases.lock().unwrap()[COLLECTION_NAME], "documents_v2");
+        // the previous collection stays around to switch back to.
+        assert_eq!(store.points("documents_v1").len(), 20);
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pinned_previous_collection_is_kept() {
+        let store = MockStore::with_points("documents_v1", chunk_points(20));
+        store.insert("documents_symbol_v1", symbol_points(5));
+        store.create_alias(COLLECTION_NAME, "documents_v1").await.unwrap();
+        store
+            .create_alias(COLLECTION_NAME_SYMBOLS, "documents_symbol_v1")
+            .await
+            .unwrap();
+        // a conversation still reads the chunks of the previous generation.
+        let options = MigrationOptions {
+            drop_previous: true,
+            pinned: HashSet::from(["documents_v1".to_string()]),
+            ..options("migrate-pinned", false)
+        };
+
+        migrate_embeddings(&store, embed, &options).await.unwrap();
+
+        assert_eq!(store.points("documents_v1").len(), 20);
+        assert!(!store.collections.lock().unwrap().contains_key("documents_symbol_v1"));
+        assert_eq!(
+            store.aliases.lock().unwrap()[COLLECTION_NAME_SYMBOLS],
+            "documents_symbol_v2"
+        );
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_collections_are_warmed_up_after_the_switch() {
+        let store = MockStore::with_points("documents_v1", chunk_points(20));
+        store.insert("documents_symbol_v1", symbol_points(5));
+        store.create_alias(COLLECTION_NAME, "documents_v1").await.unwrap();
+        store
+            .create_alias(COLLECTION_NAME_SYMBOLS, "documents_symbol_v1")
+            .await
+            .unwrap();
+        let options = MigrationOptions {
+            warmup_searches: 3,
+            ..options("migrate-warmup", false)
+        };
+
+        let reports = migrate_embeddings(&store, embed, &options).await.unwrap();
+
+        assert_eq!(reports[0].warmup_searches, 3);
+        let searches = store.searches.lock().unwrap().clone();
+        assert_eq!(searches.len(), 6);
+        // searched through the alias, which already points to the new collection.
+        assert!(searches[..3]
+            .iter()
+            .all(|(name, resolved)| name == COLLECTION_NAME && resolved == "documents_v2"));
+        assert!(searches[3..].iter().all(|(_, resolved)| resolved == "documents_symbol_v2"));
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_warm_up_without_searches_or_switch() {
+        let store = MockStore::with_points(COLLECTION_NAME, chunk_points(10));
+        store.insert(COLLECTION_NAME_SYMBOLS, symbol_points(5));
+        // the alias can't take the name of the collection without --drop-source.
+        let options = MigrationOptions {
+            warmup_searches: 3,
+            ..options("migrate-no-switch", false)
+        };
+        let reports = migrate_embeddings(&store, embed, &options).await.unwrap();
+        assert!(!reports[0].alias_switched);
+        assert_eq!(reports[0].warmup_searches, 0);
+
+        let store = MockStore::with_points(COLLECTION_NAME, chunk_points(10));
+        store.insert(COLLECTION_NAME_SYMBOLS, symbol_points(5));
+        let unset = options("migrate-no-warmup", true);
+        migrate_embeddings(&store, embed, &unset).await.unwrap();
+
+        assert!(store.searches.lock().unwrap().is_empty());
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+        std::fs::remove_file(&unset.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_merged_duplicates_are_counted_while_copying() {
+        let store = MockStore::with_points(COLLECTION_NAME, duplicated_chunk_points(6, 4));
+        store.insert(COLLECTION_NAME_SYMBOLS, symbol_points(5));
+        // the first run stops after a page, the duplicates are spread over both runs.
+        *store.fail_after_upserts.lock().unwrap() = Some(1);
+        let options = MigrationOptions {
+            stable_ids: true,
+            page_size: 4,
+            ..options("migrate-merged", true)
+        };
+
+        assert!(migrate_embeddings(&store, embed, &options).await.is_err());
+        *store.fail_after_upserts.lock().unwrap() = None;
+        let reports = migrate_embeddings(&store, embed, &options).await.unwrap();
+
+        assert_eq!(reports[0].source_count, 10);
+        assert_eq!(reports[0].target_count, 6);
+        assert_eq!(reports[0].merged, 4);
+        assert!(reports[0].alias_switched);
+        assert_eq!(reports[1].merged, 0);
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_points_lost_while_copying_keep_the_alias() {
+        let store = MockStore::with_points(COLLECTION_NAME, duplicated_chunk_points(6, 4));
+        store.insert(COLLECTION_NAME_SYMBOLS, symbol_points(5));
+        *store.lost_points.lock().unwrap() = 1;
+        let options = MigrationOptions {
+            stable_ids: true,
+            ..options("migrate-lost", true)
+        };
+
+        let error = migrate_embeddings(&store, embed, &options)
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("not switching"));
+        assert!(store.aliases.lock().unwrap().is_empty());
+        std::fs::remove_file(&options.checkpoint_path).unwrap();
+    }
+}
@@ -0,0 +1,445 @@
+// Builds the summary of the repo while its tree is walked, see `common::repo_summary`.
+// The summary is indexed as a document of its own, next to the files of the repo.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use common::repo_summary::{
+    DirectoryStats, LanguageStats, RepoSummary, MAX_README_LINES, MAX_README_LINE_CHARS,
+    MAX_SUMMARY_DIRECTORIES, MAX_SUMMARY_FRAMEWORKS, MAX_SUMMARY_LANGUAGES, OTHER_ENTRIES,
+    REPO_SUMMARY_LANGUAGE, REPO_SUMMARY_PATH,
+};
+
+use crate::ast::symbol::SymbolLocations;
+use crate::config::{get_key_file_weights, get_symbol_stop_list, get_tenant_id};
+use crate::encoding;
+use crate::hash::compute_hashes;
+use crate::key_files::KeyFilesBuilder;
+use crate::FileFields;
+
+// Manifests are looked for at the root and one directory down, e.g. `web/package.json`.
+const MAX_MANIFEST_DEPTH: usize = 2;
+
+// A manifest file, the tool it belongs to and the frameworks found by its dependencies.
+struct Manifest {
+    file_name: &'static str,
+    tool: &'static str,
+    frameworks: &'static [(&'static str, &'static str)],
+}
+
+#[rustfmt::skip]
+const MANIFESTS: &[Manifest] = &[
+    Manifest {
+        file_name: "Cargo.toml",
+        tool: "Cargo",
+        frameworks: &[
+            ("tokio", "Tokio"), ("actix-web", "Actix Web"), ("axum", "Axum"), ("warp", "Warp"),
+            ("rocket", "Rocket"), ("tonic", "Tonic"), ("diesel", "Diesel"), ("sqlx", "SQLx"),
+            ("tauri", "Tauri"), ("bevy", "Bevy"),
+        ],
+    },
+    Manifest {
+        file_name: "package.json",
+        tool: "npm",
+        frameworks: &[
+            ("react", "React"), ("next", "Next.js"), ("vue", "Vue"), ("@angular/core", "Angular"),
+            ("svelte", "Svelte"), ("express", "Express"), ("@nestjs/core", "NestJS"),
+            ("electron", "Electron"),
+        ],
+    },
+    Manifest {
+        file_name: "go.mod",
+        tool: "Go modules",
+        frameworks: &[
+            ("github.com/gin-gonic/gin", "Gin"), ("github.com/labstack/echo", "Echo"),
+            ("github.com/gofiber/fiber", "Fiber"), ("google.golang.org/grpc", "gRPC"),
+            ("gorm.io/gorm", "GORM"), ("github.com/spf13/cobra", "Cobra"),
+        ],
+    },
+    Manifest {
+        file_name: "requirements.txt",
+        tool: "pip",
+        frameworks: &[
+            ("django", "Django"), ("flask", "Flask"), ("fastapi", "FastAPI"), ("torch", "PyTorch"),
+            ("tensorflow", "TensorFlow"),
+        ],
+    },
+    Manifest {
+        file_name: "pyproject.toml",
+        tool: "pyproject",
+        frameworks: &[
+            ("django", "Django"), ("flask", "Flask"), ("fastapi", "FastAPI"), ("torch", "PyTorch"),
+            ("tensorflow", "TensorFlow"),
+        ],
+    },
+    Manifest {
+        file_name: "Gemfile",
+        tool: "Bundler",
+        frameworks: &[("rails", "Rails"), ("sinatra", "Sinatra")],
+    },
+    Manifest {
+        file_name: "pom.xml",
+        tool: "Maven",
+        frameworks: &[("spring-boot", "Spring Boot"), ("quarkus", "Quarkus")],
+    },
+    Manifest {
+        file_name: "build.gradle",
+        tool: "Gradle",
+        frameworks: &[("org.springframework.boot", "Spring Boot"), ("com.android.application", "Android")],
+    },
+    Manifest {
+        file_name: "build.gradle.kts",
+        tool: "Gradle",
+        frameworks: &[("org.springframework.boot", "Spring Boot"), ("com.android.application", "Android")],
+    },
+    Manifest {
+        file_name: "composer.json",
+        tool: "Composer",
+        frameworks: &[("laravel/framework", "Laravel"), ("symfony/framework-bundle", "Symfony")],
+    },
+    Manifest {
+        file_name: "Dockerfile",
+        tool: "Docker",
+        frameworks: &[],
+    },
+];
+
+fn manifest(path: &str) -> Option<&'static Manifest> {
+    if path.split('/').count() > MAX_MANIFEST_DEPTH {
+        return None;
+    }
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    MANIFESTS.iter().find(|m| m.file_name == file_name)
+}
+
+fn is_readme(path: &str) -> bool {
+    let stem = path.split('.').next().unwrap_or(path);
+    !path.contains('/') && stem.eq_ignore_ascii_case("readme")
+}
+
+/// Whether the summary needs the file even when it isn't indexed, e.g. a filtered out `Cargo.toml`.
+pub fn is_summary_input(path: &str) -> bool {
+    is_readme(path) || manifest(path).is_some()
+}
+
+/// The tool and frameworks of a manifest, from the names of its dependencies.
+pub fn detect_frameworks(path: &str, content: &str) -> Vec<&'static str> {
+    let Some(manifest) = manifest(path) else {
+        return Vec::new();
+    };
+    let dependencies = dependency_names(manifest.file_name, content);
+
+    let mut frameworks = vec![manifest.tool];
+    for (dependency, framework) in manifest.frameworks {
+        let found = dependencies.iter().any(|name| {
+            name == dependency
+                || name.starts_with(&format!("{}-", dependency))
+                || name.starts_with(&format!("{}/", dependency))
+        });
+        if found && !frameworks.contains(framework) {
+            frameworks.push(framework);
+        }
+    }
+    frameworks
+}
+
+// The dependencies of the manifests with a structured format are read from their tables, the
+// other manifests are split into words so that e.g. `django>=4.2` gives `django`.
+fn dependency_names(file_name: &str, content: &str) -> Vec<String> {
+    match file_name {
+        "Cargo.toml" => {
+            let Ok(manifest) = content.parse::<toml::Table>() else {
+                return Vec::new();
+            };
+            let workspace = manifest.get("workspace").and_then(|w| w.as_table());
+            ["dependencies", "dev-dependencies", "build-dependencies"]
+                .iter()
+                .flat_map(|table| {
+                    let members = manifest.get(*table);
+                    let workspace = workspace.and_then(|w| w.get(*table));
+                    members.into_iter().chain(workspace)
+                })
+                .filter_map(|table| table.as_table())
+                .flat_map(|table| table.keys().cloned())
+                .collect()
+        }
+        "package.json" | "composer.json" => {
+            let Ok(manifest) = serde_json::from_str::<serde_json::Value>(content) else {
+                return Vec::new();
+            };
+            [
+                "dependencies",
+                "devDependencies",
+                "peerDependencies",
+                "require",
+                "require-dev",
+            ]
+            .iter()
+            .filter_map(|table| manifest.get(*table).and_then(|t| t.as_object()))
+            .flat_map(|table| table.keys().cloned())
+            .collect()
+        }
+        _ => content
+            .split(|c: char| c.is_whitespace() || "\"'=<>~!;,()[]{}:".contains(c))
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect(),
+    }
+}
+
+/// Collects the statistics of the indexed files and the manifests and README of the repo.
+#[derive(Debug, Default)]
+pub struct RepoSummaryBuilder {
+    languages: HashMap<String, LanguageStats>,
+    directories: HashMap<String, DirectoryStats>,
+    frameworks: Vec<String>,
+    readme: Vec<String>,
+    key_files: KeyFilesBuilder,
+}
+
+impl RepoSummaryBuilder {
+    pub fn new() -> Self {
+        Self {
+            key_files: KeyFilesBuilder::new(&get_symbol_stop_list()),
+            ..Default::default()
+        }
+    }
+
+    /// Reads the frameworks of a manifest or the first lines of the README, other files are ignored.
+    pub fn add_summary_input(&mut self, path: &str, content: &[u8]) {
+        let Ok(decoded) = encoding::decode(content) else {
+            return;
+        };
+        let content = decoded.text.as_ref();
+        if is_readme(path) {
+            // the first README found is kept, e.g. README.md over README.rst.
+            if self.readme.is_empty() {
+                self.readme = content
+                    .lines()
+                    .take(MAX_README_LINES)
+                    .map(|line| line.chars().take(MAX_README_LINE_CHARS).collect())
+                    .collect();
+            }
+            return;
+        }
+        for framework in detect_frameworks(path, content) {
+            if self.frameworks.len() < MAX_SUMMARY_FRAMEWORKS
+                && !self.frameworks.iter().any(|f| f == framework)
+            {
+                self.frameworks.push(framework.to_string());
+            }
+        }
+    }
+
+    /// Counts an indexed file in the statistics of its language and top level directory.
+    pub fn add_indexed_file(&mut self, path: &str, language: &str, content: &str) {
+        let lines = content.lines().count();
+        let stats = self
+            .languages
+            .entry(language.to_string())
+            .or_insert_with(|| LanguageStats {
+                language: language.to_string(),
+                ..Default::default()
+            });
+        stats.files += 1;
+        stats.lines += lines;
+
+        let directory = match path.split_once('/') {
+            Some((directory, _)) => directory,
+            None => ".",
+        };
+        let stats = self
+            .directories
+            .entry(directory.to_string())
+            .or_insert_with(|| DirectoryStats {
+                path: directory.to_string(),
+                ..Default::default()
+            });
+        stats.files += 1;
+        stats.lines += lines;
+    }
+
+    /// Counts the definitions of an indexed file, the key files are ranked on them, see `key_files`.
+    pub fn add_definitions<'a>(
+        &mut self,
+        path: &str,
+        content: &str,
+        symbols: usize,
+        top_level: impl IntoIterator<Item = &'a str>,
+    ) {
+        self.key_files.add_file(path, content, symbols, top_level);
+    }
+
+    /// The summary, the languages and directories beyond the bounds are folded into `other`.
+    pub fn build(self, repo_name: &str, indexed_commit: &str) -> RepoSummary {
+        let mut languages = self.languages.into_values().collect::<Vec<_>>();
+        languages.sort_by(|a, b| b.lines.cmp(&a.lines).then_with(|| a.language.cmp(&b.language)));
+        if languages.len() > MAX_SUMMARY_LANGUAGES {
+            let folded = languages.split_off(MAX_SUMMARY_LANGUAGES - 1);
+            languages.push(LanguageStats {
+                language: OTHER_ENTRIES.to_string(),
+                files: folded.iter().map(|l| l.files).sum(),
+                lines: folded.iter().map(|l| l.lines).sum(),
+            });
+        }
+
+        let mut directories = self.directories.into_values().collect::<Vec<_>>();
+        directories.sort_by(|a, b| b.lines.cmp(&a.lines).then_with(|| a.path.cmp(&b.path)));
+        if directories.len() > MAX_SUMMARY_DIRECTORIES {
+            let folded = directories.split_off(MAX_SUMMARY_DIRECTORIES - 1);
+            directories.push(DirectoryStats {
+                path: OTHER_ENTRIES.to_string(),
+                files: folded.iter().map(|d| d.files).sum(),
+                lines: folded.iter().map(|d| d.lines).sum(),
+            });
+        }
+
+        RepoSummary {
+            repo_name: repo_name.to_string(),
+            indexed_commit: indexed_commit.to_string(),
+            languages,
+            directories,
+            frameworks: self.frameworks,
+            readme: self.readme,
+            key_files: self.key_files.build(&get_key_file_weights()),
+        }
+    }
+}
+
+/// The quickwit document of the summary, with the summary as JSON content.
+pub fn repo_summary_fields(summary: &RepoSummary, repo_path: &str) -> FileFields {
+    let content = serde_json::to_string(summary).expect("Failed to serialize the repo summary");
+    let line_end_indices = content
+        .match_indices('\n')
+        .flat_map(|(i, _)| u32::to_le_bytes(i as u32))
+        .collect::<Vec<_>>();
+    let (_, tantivy_hash) = compute_hashes(PathBuf::from(REPO_SUMMARY_PATH), &content, "main");
+
+    FileFields {
+        tenant_id: get_tenant_id(),
+        repo_name: summary.repo_name.clone(),
+        repo_disk_path: repo_path.to_string(),
+        repo_ref: String::new(),
+        relative_path: REPO_SUMMARY_PATH.to_string(),
+        last_commit: summary.indexed_commit.clone(),
+        lang: REPO_SUMMARY_LANGUAGE.to_string(),
+        is_directory: false,
+        avg_line_length: content.len() as f64,
+        line_end_indices,
+        content,
+        symbol_locations: bincode::serialize(&SymbolLocations::Empty).unwrap(),
+        unique_hash: tantivy_hash,
+        symbols: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::detect_language;
+    use std::path::Path;
+
+    // A small service with a Rust backend and a React frontend.
+    const FIXTURE_REPO: &[(&str, &str)] = &[
+        (
+            "Cargo.toml",
+            "[package]\nname = \"shop\"\n\n[dependencies]\naxum = \"0.7\"\ntokio = { version = \"1\", features = [\"full\"] }\nserde = \"1\"\n",
+        ),
+        (
+            "README.md",
+            "# Shop\n\nOrders, payments and the storefront of the shop.\n",
+        ),
+        (
+            "src/main.rs",
+            "mod orders;\n\n#[tokio::main]\nasync fn main() {\n    orders::serve().await;\n}\n",
+        ),
+        (
+            "src/orders.rs",
+            "pub async fn serve() {}\n",
+        ),
+        (
+            "web/package.json",
+            "{\"name\": \"storefront\", \"description\": \"not vue\", \"dependencies\": {\"react\": \"^18.0.0\", \"react-dom\": \"^18.0.0\"}}",
+        ),
+        (
+            "web/src/App.js",
+            "export function App() {\n  return null;\n}\n",
+        ),
+        (
+            "deploy/nested/go.mod",
+            "module example.com/tools\n\nrequire github.com/gin-gonic/gin v1.9.0\n",
+        ),
+    ];
+
+    // Feeds the fixture files to the builder the way the walk of the tree does.
+    fn summarize_fixture_repo() -> RepoSummary {
+        let mut builder = RepoSummaryBuilder::new();
+        for (path, content) in FIXTURE_REPO {
+            if is_summary_input(path) {
+                builder.add_summary_input(path, content.as_bytes());
+            }
+            if let Some(language) = detect_language(Path::new(path), content.as_bytes()) {
+                builder.add_indexed_file(path, language, content);
+            }
+        }
+        builder.build("acme/shop", "abc123")
+    }
+
+    #[test]
+    fn test_detects_the_frameworks_of_the_manifests() {
+        let summary = summarize_fixture_repo();
+        // go.mod is too deep to be read, the description of package.json isn't a dependency.
+        assert_eq!(
+            summary.frameworks,
+            vec!["Cargo", "Tokio", "Axum", "npm", "React"]
+        );
+        assert_eq!(
+            summary.readme,
+            vec!["# Shop", "", "Orders, payments and the storefront of the shop."]
+        );
+    }
+
+    #[test]
+    fn test_counts_the_files_by_language_and_directory() {
+        let summary = summarize_fixture_repo();
+        let rust = summary
+            .languages
+            .iter()
+            .find(|l| l.language == "Rust")
+            .unwrap();
+        assert_eq!((rust.files, rust.lines), (2, 7));
+        let src = summary.directories.iter().find(|d| d.path == "src").unwrap();
+        assert_eq!((src.files, src.lines), (2, 7));
+        assert!(summary.directories.iter().any(|d| d.path == "web"));
+    }
+
+    #[test]
+    fn test_summary_lands_in_the_metadata_document() {
+        let summary = summarize_fixture_repo();
+        let fields = repo_summary_fields(&summary, "/repos/shop");
+
+        assert_eq!(fields.relative_path, REPO_SUMMARY_PATH);
+        assert_eq!(fields.lang, REPO_SUMMARY_LANGUAGE);
+        assert_eq!(fields.repo_name, "acme/shop");
+        assert_eq!(fields.last_commit, "abc123");
+        let stored: RepoSummary = serde_json::from_str(&fields.content).unwrap();
+        assert_eq!(stored, summary);
+    }
+
+    #[test]
+    fn test_summary_is_bounded() {
+        let mut builder = RepoSummaryBuilder::new();
+        for i in 0..MAX_SUMMARY_LANGUAGES + 5 {
+            let path = format!("dir{}/file", i);
+            builder.add_indexed_file(&path, &format!("lang{}", i), "a\nb\n");
+        }
+        builder.add_summary_input("README", "line\n".repeat(100).as_bytes());
+
+        let summary = builder.build("repo", "");
+        assert_eq!(summary.languages.len(), MAX_SUMMARY_LANGUAGES);
+        let other = summary.languages.last().unwrap();
+        assert_eq!((other.language.as_str(), other.files), (OTHER_ENTRIES, 6));
+        assert_eq!(summary.directories.len(), MAX_SUMMARY_LANGUAGES + 5);
+        assert_eq!(summary.readme.len(), MAX_README_LINES);
+    }
+}
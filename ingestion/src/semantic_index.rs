@@ -3,26 +3,52 @@ use std::ops::Range;
 extern crate tracing;
 use anyhow::Result;
 use tracing::{debug, error,  warn};
-mod chunking;
+pub mod chunk_quality;
+pub mod chunking;
+pub mod config_chunking;
 mod text_range;
 mod vector_payload;
+use crate::ast::doc_comment::DocComment;
 use crate::ast::symbol::{SymbolKey, SymbolValue};
-use crate::config::get_model_path;
-use chunking::{add_token_range, Chunk, DEDUCT_SPECIAL_TOKENS};
+use crate::embedding_cache::{self, EmbeddingCache};
+use crate::hash::{self, symbol_point_id};
+use crate::watch::file_points_filter;
+use crate::config::{
+    get_chunk_overlap, get_chunk_quality_thresholds, get_index_doc_chunks, get_model_path, get_payload_compression,
+    get_symbol_occurrence_limit, get_symbol_stop_list,
+};
+use chunking::{add_token_range, plan_chunks, point, Chunk, DEDUCT_SPECIAL_TOKENS};
 use qdrant_client::prelude::QdrantClient;
-use qdrant_client::qdrant::{PointId, PointStruct};
+use qdrant_client::qdrant::{
+    points_selector::PointsSelectorOneOf, with_payload_selector, with_vectors_selector, Filter,
+    PointId, PointStruct, PointsIdsList, PointsSelector, RetrievedPoint, ScrollPoints,
+    WithPayloadSelector, WithVectorsSelector,
+};
 use std::collections::HashMap;
 use std::fmt;
 use text_range::{Point, TextRange};
 use thiserror::Error;
-use uuid::Uuid;
-use vector_payload::{Payload, SymbolPayload};
+use vector_payload::{ChunkKind, Payload, SymbolPayload};
 
+use common::branch::branch_name;
+use common::compression::TextCompression;
+use common::embedded_lang;
+use common::metrics;
+use common::path_class::PathClass;
 use common::tokenizer_onnx::{Embedding, TokenizerOnnx};
+use common::symbol_payload::SymbolOccurrence;
+
+/// Bounds in tokens of the chunks of a file.
+pub const CHUNK_TOKEN_BOUNDS: Range<usize> = 50..256;
+
+/// How consecutive chunks overlap, recorded in the run manifest.
+pub fn chunk_overlap() -> String {
+    get_chunk_overlap().to_string()
+}
 
 pub struct SemanticIndex {
     tokenizer_onnx: TokenizerOnnx,
-    overlap: chunking::OverlapStrategy,
+    overlap: chunking::ChunkOverlap,
     counter: usize,
 }
 use crate::{COLLECTION_NAME, COLLECTION_NAME_SYMBOLS};
@@ -65,42 +91,60 @@ impl SemanticIndex {
         
         Ok(Self {
             tokenizer_onnx: TokenizerOnnx::new(&get_model_path())?,
-            overlap: chunking::OverlapStrategy::default(),
+            overlap: get_chunk_overlap(),
             counter: *counter,
         })
     }
 
+    // identical chunks of different files are only embedded once, see `embedding_cache`.
     pub fn embed(&self, sequence: &str) -> anyhow::Result<Embedding> {
-        self.tokenizer_onnx.get_embedding(sequence)
+        embedding_cache::global()
+            .embed_with(sequence, |text| self.tokenizer_onnx.get_embedding(text))
     }
 
+    // Returns how many chunks of the file were left out as low quality, see `chunk_quality`.
     pub async fn tokenize_and_commit<'a>(
         &mut self,
         buffer: &'a str,
-        repo_name: &'a str,
-        path: &str,
-        semantic_hash: &str,
-        lang_str: &str,
-        qdrant_client: &Option<QdrantClient>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Tokenize
-        let chunks = self.tokenize_chunk(
+        file: ChunkedFile<'a>,
+        qdrant_client: &Option<impl PointSink>,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        // Tokenize, config files on their keys.
+        let (repo_name, path, lang) = (file.repo_name, file.relative_path, file.lang);
+        let chunks = if config_chunking::is_config_language(lang) {
+            self.tokenize_config(buffer, repo_name, path, lang, CHUNK_TOKEN_BOUNDS)
+        } else {
+            self.tokenize_chunk(buffer, repo_name, path, CHUNK_TOKEN_BOUNDS)
+                .into_iter()
+                .map(|chunk| (chunk, None))
+                .collect()
+        };
+
+        // the license header and the chunks without code aren't worth an embedding.
+        let (chunks, dropped) = chunk_quality::filter_chunks(
+            chunks,
             buffer,
-            repo_name,
-            path,
-            50..256,
+            lang,
+            &get_chunk_quality_thresholds(),
+            |text| {
+                self.tokenizer_onnx
+                    .tokenizer
+                    .encode(text, false)
+                    .map_or(0, |encoding| encoding.get_ids().len())
+            },
         );
+        if dropped > 0 {
+            debug!("dropped {} low quality chunks of {}", dropped, path);
+        }
+        let (chunks, key_paths): (Vec<_>, Vec<_>) = chunks.into_iter().unzip();
 
         // Commit
-        self.commit_chunks(
-            chunks,
-            repo_name,
-            path,
-            semantic_hash,
-            lang_str,
-            qdrant_client,
-        )
-        .await
+        let file = ChunkedFile {
+            key_paths: &key_paths,
+            ..file
+        };
+        self.commit_chunks(chunks, &file, qdrant_client).await?;
+        Ok(dropped)
     }
 
     // takes the hash map containing the symbol metadata and commits it to the qdrant database.
@@ -108,209 +152,38 @@ impl SemanticIndex {
     pub async fn commit_symbol_metadata(
         &mut self,
         symbol_meta_hash_map: &HashMap<SymbolKey, Vec<SymbolValue>>,
-        qdrant_client: &Option<QdrantClient>,
+        branch: &str,
+        qdrant_client: &Option<impl PointSink>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        //let mut temp_payloads = Vec::new();
-
         let embedder = |c: &str| {
             debug!("generating embedding");
             self.embed(c)
         };
-
-        // iterate through the symbolMeta hashmap and create SymbolPayload from the symbolMeta hashmap.
-
-        let mut symbol_meta_payload: Vec<PointStruct> = symbol_meta_hash_map
-            .iter()
-            .map(|(key, values)| {
-                // iterate the values and create the vectors containing relative paths, start_bytes, end_bytes, and is_global.
-                // is_global is a vector of bools which signifies whether the symbol is declared in the root scope or not.
-                // relative_paths is a vector of strings which signifies the relative path of the file in which the symbol is declared.
-                // start_bytes and end_bytes are vectors of i64 which signifies the start and end bytes of the symbol in the file.
-                let (
-                    start_bytes,
-                    end_bytes,
-                    relative_paths,
-                    is_global_vec,
-                    language_ids,
-                    symbol_types,
-                    node_kinds,
-                ) = values.iter().fold(
-                    (
-                        Vec::new(),
-                        Vec::new(),
-                        Vec::new(),
-                        Vec::new(),
-                        Vec::new(),
-                        Vec::new(),
-                        Vec::new(),
-                    ),
-                    |(
-                        mut start_acc,
-                        mut end_acc,
-                        mut paths_acc,
-                        mut global_acc,
-                        mut language_ids,
-                        mut symbol_types,
-                        mut node_kinds,
-                    ),
-                     value| {
-                        start_acc.push(value.start_byte as i64);
-                        end_acc.push(value.end_byte as i64);
-                        paths_acc.push(value.relative_path.clone());
-                        global_acc.push(value.is_global);
-                        language_ids.push(value.language_id.clone());
-                        symbol_types.push(value.symbol_type.clone());
-                        node_kinds.push(value.node_kind.clone());
-
-                        (
-                            start_acc,
-                            end_acc,
-                            paths_acc,
-                            global_acc,
-                            language_ids,
-                            symbol_types,
-                            node_kinds,
-                        )
-                    },
-                );
-
-                // create the SymbolPayload from the key and the vectors created above.
-                // this format is required by qdrant.
-                let symbol_qdrant_meta = SymbolPayload {
-                    lang_ids: language_ids,
-                    repo_name: key.repo_name.clone(),
-                    symbol: key.symbol.clone(),
-                    symbol_types: symbol_types,
-                    is_globals: is_global_vec,
-                    start_bytes: start_bytes,
-                    end_bytes: end_bytes,
-                    relative_paths: relative_paths,
-                    node_kinds: node_kinds,
-                    ..Default::default()
-                };
-
-                let id = Uuid::new_v4();
-                println!("id: {}", id);
-                // we find the embedding vector using the symbol from the ast.
-                return PointStruct {
-                    id: Some(PointId::from(id.to_string())),
-                    vectors: Some(embedder(&key.symbol).unwrap().into()),
-                    payload: symbol_qdrant_meta.convert_to_qdrant_fields(),
-                };
-            })
-            .collect();
-
-        println!("finished iterating on the chuks and creating temp payload");
-
-        //self.qdrantPayload.extend(symbol_meta_payload);
-        // print length of the payload
-        println!("length of the payload: {}", symbol_meta_payload.len());
-
-        // commit the data to Qdrant.
-        let new: Vec<_> = std::mem::take(symbol_meta_payload.as_mut());
-
-        // qdrant doesn't like empty payloads.
-        if let Some(ref client) = qdrant_client {
-            // qdrant doesn't like empty payloads.
-            if !new.is_empty() {
-                println!("Just before committing to the database.");
-                client
-                    .upsert_points_batch(COLLECTION_NAME_SYMBOLS, new, None, 10)
-                    .await
-                    .map_err(|_| Box::new(CommitError::QdrantError))?;
-            }
-            println!("finished committing symbol to qdrant");
-        } else {
-            // Handle the case where qdrant_client is None if necessary
-            return Err(Box::new(CommitError::NoQdrantClient));
-        }
-        // sleep for 3 seconds if counter % 10 is 0.
-        // TODO: remove this once we have a better way to handle this.
-        // if self.counter % 9 == 0 {
-        //     println!("sleeping for 4 seconds: {}", self.counter);
-        //     std::thread::sleep(std::time::Duration::from_secs(4));
-        // }
-        // if self.counter % 69 == 0 {
-        //     println!("sleeping for 20 seconds: {}", self.counter);
-        //     std::thread::sleep(std::time::Duration::from_secs(20));
-        // }
-        Ok(())
+        commit_symbol_points(
+            symbol_meta_hash_map,
+            branch,
+            get_symbol_occurrence_limit(),
+            &get_symbol_stop_list(),
+            embedder,
+            qdrant_client,
+        )
+        .await
     }
 
-    pub async fn commit_chunks<'s>(
+    pub async fn commit_chunks(
         &mut self,
         chunks: Vec<Chunk<'_>>,
-        repo_name: &'s str,
-        relative_path: &str,
-        semantic_hash: &str,
-        lang_str: &str,
-        qdrant_client: &Option<QdrantClient>,
+        file: &ChunkedFile<'_>,
+        qdrant_client: &Option<impl PointSink>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut temp_payloads = Vec::new();
-
-        let embedder = |c: &str| {
-            debug!("generating embedding");
-            self.embed(c)
-        };
-        chunks.iter().for_each(|chunk| {
-            let payload = Payload {
-                repo_name: repo_name.to_owned(),
-                relative_path: relative_path.to_owned(),
-                content_hash: semantic_hash.to_string(),
-                text: chunk.data.to_owned(),
-                lang: lang_str.to_ascii_lowercase(),
-                start_line: chunk.range.start.line as u64,
-                end_line: chunk.range.end.line as u64,
-                start_byte: chunk.range.start.byte as u64,
-                end_byte: chunk.range.end.byte as u64,
-                ..Default::default()
-            };
-
-            let id = Uuid::new_v4();
-            println!("id: {}", id);
-            let qdrant_payload = PointStruct {
-                id: Some(PointId::from(id.to_string())),
-                vectors: Some(embedder(chunk.data).unwrap().into()),
-                payload: payload.convert_to_qdrant_fields(),
-            };
-
-            temp_payloads.push(qdrant_payload);
-        });
-        println!("finished iterating on the chuks and creating temp payload");
-
-        // self.qdrantPayload.extend(temp_payloads);
-        // print length of the payload
-        println!("length of the payload: {}", temp_payloads.len());
-
-        // commit the data to Qdrant.
-        let new: Vec<_> = std::mem::take(temp_payloads.as_mut());
-
-        // qdrant doesn't like empty payloads.
-        if let Some(ref client) = qdrant_client {
-            // qdrant doesn't like empty payloads.
-            if !new.is_empty() {
-                println!("Just before committing to the database.");
-                client
-                    .upsert_points_batch(COLLECTION_NAME, new, None, 10)
-                    .await
-                    .map_err(|_| Box::new(CommitError::QdrantError))?;
-            }
-            println!("finished committing to qdrant");
-        } else {
-            // Handle the case where qdrant_client is None if necessary
-            return Err(Box::new(CommitError::NoQdrantClient));
-        }
-        // sleep for 3 seconds if counter % 10 is 0.
-        // TODO: remove this once we have a better way to handle this.
-        // if self.counter % 9 == 0 {
-        //     println!("sleeping for 4 seconds: {}", self.counter);
-        //     std::thread::sleep(std::time::Duration::from_secs(4));
-        // }
-        // if self.counter % 69 == 0 {
-        //     println!("sleeping for 20 seconds: {}", self.counter);
-        //     std::thread::sleep(std::time::Duration::from_secs(20));
-        // }
-        Ok(())
+        commit_cached_chunks(
+            &chunks,
+            file,
+            &embedding_cache::global(),
+            |text| self.tokenizer_onnx.get_embedding(text),
+            qdrant_client,
+        )
+        .await
     }
 
     pub fn tokenize_chunk<'s>(
@@ -358,8 +231,6 @@ impl SemanticIndex {
         }
 
         let max_tokens = token_bounds.end - DEDUCT_SPECIAL_TOKENS - repo_tokens;
-        let max_newline_tokens = max_tokens * 3 / 4; //TODO: make this configurable
-        let max_boundary_tokens = max_tokens * 7 / 8; //TODO: make this configurable
         debug!("max tokens reduced to {max_tokens}");
 
         let offsets_len = offsets.len() - 1;
@@ -370,71 +241,89 @@ impl SemanticIndex {
             offsets
         };
         let ids = encoding.get_ids();
+        let ranges = plan_chunks(
+            offsets.len(),
+            min_tokens,
+            max_tokens,
+            &self.overlap,
+            |i| src[offsets[i - 1].0..offsets[i].0].contains('\n'),
+            |i| {
+                !self
+                    .tokenizer_onnx
+                    .tokenizer
+                    .id_to_token(ids[i])
+                    .map_or(false, |s| s.starts_with("##"))
+            },
+        );
         let mut chunks = Vec::new();
-        let mut start = 0;
         let (mut last_line, mut last_byte) = (0, 0);
-        loop {
-            let next_limit = start + max_tokens;
-            let end_limit = if next_limit >= offsets_len {
-                offsets_len
-            } else if let Some(next_newline) = (start + max_newline_tokens..next_limit)
-                .rfind(|&i| src[offsets[i].0..offsets[i + 1].0].contains('\n'))
-            {
-                next_newline
-            } else if let Some(next_boundary) =
-                (start + max_boundary_tokens..next_limit).rfind(|&i| {
-                    !self
-                        .tokenizer_onnx
-                        .tokenizer
-                        .id_to_token(ids[i + 1])
-                        .map_or(false, |s| s.starts_with("##"))
-                })
-            {
-                next_boundary
-            } else {
-                next_limit
-            };
-            if end_limit - start >= min_tokens {
-                add_token_range(
-                    &mut chunks,
-                    src,
-                    offsets,
-                    start..end_limit + 1,
-                    &mut last_line,
-                    &mut last_byte,
-                );
+        for range in ranges {
+            add_token_range(
+                &mut chunks,
+                src,
+                offsets,
+                range,
+                &mut last_line,
+                &mut last_byte,
+            );
+        }
+        chunks
+    }
+
+    // Config files are split on their keys, each chunk with the key path it is under. The sections
+    // too large for a chunk are chunked by tokens, and the files that don't parse like code.
+    pub fn tokenize_config<'s>(
+        &self,
+        src: &'s str,
+        repo_name: &'s str,
+        file: &str,
+        language: &str,
+        token_bounds: Range<usize>,
+    ) -> Vec<(Chunk<'s>, Option<String>)> {
+        let repo_plus_file = repo_name.to_owned() + "\t" + file + "\n";
+        let repo_tokens = match self.tokenizer_onnx.tokenizer.encode(repo_plus_file, true) {
+            Ok(encoding) => encoding.get_ids().len(),
+            Err(e) => {
+                error!("failure during encoding repo + file {:?}", e);
+                return Vec::new();
+            }
+        };
+        let max_tokens = token_bounds
+            .end
+            .saturating_sub(DEDUCT_SPECIAL_TOKENS + repo_tokens);
+        let fits = |text: &str| {
+            self.tokenizer_onnx
+                .tokenizer
+                .encode(text, false)
+                .map_or(false, |encoding| encoding.get_ids().len() <= max_tokens)
+        };
+
+        let Some(sections) = config_chunking::config_chunks(src, language, fits) else {
+            debug!("{} doesn't parse as {}, chunking it by tokens", file, language);
+            return self
+                .tokenize_chunk(src, repo_name, file, token_bounds)
+                .into_iter()
+                .map(|chunk| (chunk, None))
+                .collect();
+        };
+
+        let mut chunks = Vec::new();
+        for section in sections {
+            let text = &src[section.range.clone()];
+            if !section.oversized {
+                let start = point(src, section.range.start, 0, 0);
+                let end = point(src, section.range.end, 0, 0);
+                chunks.push((Chunk::new(text, start, end), section.key_path));
+                continue;
             }
-            if end_limit == offsets_len {
-                return chunks;
+            // the ranges of the token chunks are relative to the section.
+            for chunk in self.tokenize_chunk(text, repo_name, file, token_bounds.clone()) {
+                let start = point(src, section.range.start + chunk.range.start.byte, 0, 0);
+                let end = point(src, section.range.start + chunk.range.end.byte, 0, 0);
+                chunks.push((Chunk::new(chunk.data, start, end), section.key_path.clone()));
             }
-            let diff = self.overlap.next_subdivision(end_limit - start);
-            let mid = start + diff;
-            // find nearest newlines or boundaries, set start accordingly
-            let next_newline_diff =
-                (mid..end_limit).find(|&i| src[offsets[i].0..offsets[i + 1].0].contains('\n'));
-            let prev_newline_diff = (start + (diff / 2)..mid)
-                .rfind(|&i| src[offsets[i].0..offsets[i + 1].0].contains('\n'))
-                .map(|t| t + 1);
-            start = match (next_newline_diff, prev_newline_diff) {
-                (Some(n), None) | (None, Some(n)) => n,
-                (Some(n), Some(p)) => {
-                    if n - mid < mid - p {
-                        n
-                    } else {
-                        p
-                    }
-                }
-                (None, None) => (mid..end_limit)
-                    .find(|&i| {
-                        !self
-                            .tokenizer_onnx
-                            .tokenizer
-                            .id_to_token(ids[i + 1])
-                            .map_or(false, |s| s.starts_with("##"))
-                    })
-                    .unwrap_or(mid),
-            };
         }
+        chunks
     }
 
     pub fn by_lines(src: &str, size: usize) -> Vec<Chunk<'_>> {
@@ -470,3 +359,892 @@ impl SemanticIndex {
             .collect()
     }
 }
+
+/// Where the points of a commit are upserted, the qdrant client or a recording client in tests.
+#[async_trait::async_trait]
+pub trait PointSink: Sync {
+    async fn upsert_points(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl PointSink for QdrantClient {
+    async fn upsert_points(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+        self.upsert_points_batch(collection, points, None, 10).await?;
+        Ok(())
+    }
+}
+
+// Points read per scroll request when collecting the symbol points of a file.
+const SCROLL_PAGE_SIZE: u32 = 256;
+
+/// The reads and deletes the re-index of a single file needs besides the upserts.
+#[async_trait::async_trait]
+pub trait PointStore: PointSink {
+    // every point of the collection matching the filter, with its payload and vectors.
+    async fn scroll_matching(
+        &self,
+        collection: &str,
+        filter: Filter,
+    ) -> anyhow::Result<Vec<RetrievedPoint>>;
+    async fn delete_matching(
+        &self,
+        collection: &str,
+        selector: PointsSelector,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl PointStore for QdrantClient {
+    async fn scroll_matching(
+        &self,
+        collection: &str,
+        filter: Filter,
+    ) -> anyhow::Result<Vec<RetrievedPoint>> {
+        let mut points = Vec::new();
+        let mut offset = None;
+        loop {
+            let response = self
+                .scroll(&ScrollPoints {
+                    collection_name: collection.to_string(),
+                    filter: Some(filter.clone()),
+                    offset,
+                    limit: Some(SCROLL_PAGE_SIZE),
+                    with_payload: Some(WithPayloadSelector {
+                        selector_options: Some(with_payload_selector::SelectorOptions::Enable(
+                            true,
+                        )),
+                    }),
+                    with_vectors: Some(WithVectorsSelector {
+                        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
+                            true,
+                        )),
+                    }),
+                    ..Default::default()
+                })
+                .await?;
+            points.extend(response.result);
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => return Ok(points),
+            }
+        }
+    }
+
+    async fn delete_matching(
+        &self,
+        collection: &str,
+        selector: PointsSelector,
+    ) -> anyhow::Result<()> {
+        self.delete_points(collection, &selector, None).await?;
+        Ok(())
+    }
+}
+
+/// The file the chunks of a commit come from, the ids of their points are derived from it.
+pub struct ChunkedFile<'a> {
+    pub repo_name: &'a str,
+    pub relative_path: &'a str,
+    pub branch: &'a str,
+    pub semantic_hash: &'a str,
+    pub lang: &'a str,
+    // the keys of each chunk of a config file, empty for code.
+    pub key_paths: &'a [Option<String>],
+    pub doc_comments: &'a [DocComment],
+    // unix seconds of the last commit changing the file.
+    pub last_modified: Option<u64>,
+}
+
+// Embeds the symbols and upserts them, one point per name.
+// The ids only depend on the repo, the branch and the name, committing the same symbols again
+// overwrites their points.
+async fn commit_symbol_points(
+    symbol_meta_hash_map: &HashMap<SymbolKey, Vec<SymbolValue>>,
+    branch: &str,
+    occurrence_limit: usize,
+    stop_list: &[String],
+    embedder: impl Fn(&str) -> anyhow::Result<Embedding>,
+    qdrant_client: &Option<impl PointSink>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // iterate through the symbolMeta hashmap and create SymbolPayload from the symbolMeta hashmap.
+    // symbols in the stop-list are skipped, they are too common for their embedding to carry any signal.
+    let new = symbol_meta_hash_map
+        .iter()
+        .filter(|(key, _)| {
+            let skip = is_stop_symbol(&key.symbol, stop_list);
+            if skip {
+                debug!("skipping stop-list symbol: {}", key.symbol);
+            }
+            !skip
+        })
+        .map(|(key, values)| {
+            let symbol_qdrant_meta = SymbolPayload {
+                branch: branch_name(branch).to_string(),
+                ..build_symbol_payload(key, values, occurrence_limit)
+            };
+            let id = symbol_point_id(&key.repo_name, branch, &key.symbol);
+            // we find the embedding vector using the symbol from the ast.
+            Ok(PointStruct {
+                id: Some(PointId::from(id.to_string())),
+                vectors: Some(embedder(&key.symbol)?.into()),
+                payload: symbol_qdrant_meta.convert_to_qdrant_fields(),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    println!("length of the payload: {}", new.len());
+    upsert_points(qdrant_client, COLLECTION_NAME_SYMBOLS, new).await?;
+    println!("finished committing symbol to qdrant");
+    Ok(())
+}
+
+/// A file of the repo with the symbols of its content, empty once the file is deleted.
+pub struct FileSymbols<'a> {
+    pub relative_path: &'a str,
+    pub symbols: &'a HashMap<SymbolKey, Vec<SymbolValue>>,
+}
+
+/// Replaces the occurrences of a file in the symbol points of the branch with the symbols of its
+/// new content. A symbol point holds the occurrences of every file: the ones of the other files
+/// stay, a point left without any is deleted and only the symbols without a point yet are
+/// embedded.
+pub async fn replace_file_symbol_points(
+    store: &Option<impl PointStore>,
+    repo_name: &str,
+    branch: &str,
+    file: FileSymbols<'_>,
+    occurrence_limit: usize,
+    stop_list: &[String],
+    embedder: impl Fn(&str) -> anyhow::Result<Embedding>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let FileSymbols {
+        relative_path,
+        symbols,
+    } = file;
+    let Some(client) = store else {
+        return Err(Box::new(CommitError::NoQdrantClient));
+    };
+    let previous = client
+        .scroll_matching(
+            COLLECTION_NAME_SYMBOLS,
+            file_points_filter(repo_name, branch, relative_path),
+        )
+        .await?;
+
+    // the occurrences of the other files, with the vector and the overflow of their point.
+    let mut merged = HashMap::<SymbolKey, Vec<SymbolValue>>::new();
+    let mut existing = HashMap::new();
+    for point in previous {
+        let payload = SymbolPayload::from_qdrant_fields(point.payload)?;
+        let key = SymbolKey {
+            symbol: payload.symbol,
+            repo_name: payload.repo_name,
+        };
+        let others = payload
+            .occurrences
+            .into_iter()
+            .filter(|occurrence| occurrence.path != relative_path)
+            .map(symbol_value)
+            .collect();
+        merged.insert(key.clone(), others);
+        existing.insert(key, (point.vectors, payload.overflow_count));
+    }
+    for (key, values) in symbols {
+        if is_stop_symbol(&key.symbol, stop_list) {
+            continue;
+        }
+        merged
+            .entry(key.clone())
+            .or_default()
+            .extend(values.iter().cloned());
+    }
+
+    let mut emptied = Vec::new();
+    let mut points = Vec::new();
+    for (key, values) in merged {
+        let id = PointId::from(symbol_point_id(&key.repo_name, branch, &key.symbol).to_string());
+        if values.is_empty() {
+            emptied.push(id);
+            continue;
+        }
+        let (vectors, overflow_count) = match existing.remove(&key) {
+            Some((Some(vectors), overflow_count)) => (vectors, overflow_count),
+            Some((None, overflow_count)) => (embedder(&key.symbol)?.into(), overflow_count),
+            None => (embedder(&key.symbol)?.into(), 0),
+        };
+        let payload = build_symbol_payload(&key, &values, occurrence_limit);
+        points.push(PointStruct {
+            id: Some(id),
+            vectors: Some(vectors),
+            payload: SymbolPayload {
+                branch: branch_name(branch).to_string(),
+                // the occurrences left out before aren't known by file, they stay counted.
+                overflow_count: payload.overflow_count + overflow_count,
+                ..payload
+            }
+            .convert_to_qdrant_fields(),
+        });
+    }
+
+    if !emptied.is_empty() {
+        client
+            .delete_matching(
+                COLLECTION_NAME_SYMBOLS,
+                PointsSelector {
+                    points_selector_one_of: Some(PointsSelectorOneOf::Points(PointsIdsList {
+                        ids: emptied,
+                    })),
+                },
+            )
+            .await?;
+    }
+    upsert_points(store, COLLECTION_NAME_SYMBOLS, points).await
+}
+
+// Commits the chunks of a file with their embeddings looked up in `cache` first, `embed` only
+// embeds the chunks the cache misses.
+pub(crate) async fn commit_cached_chunks(
+    chunks: &[Chunk<'_>],
+    file: &ChunkedFile<'_>,
+    cache: &EmbeddingCache,
+    embed: impl Fn(&str) -> anyhow::Result<Embedding>,
+    qdrant_client: &Option<impl PointSink>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let embedder = |c: &str| {
+        debug!("generating embedding");
+        cache.embed_with(c, &embed)
+    };
+    commit_chunk_points(
+        chunks,
+        file,
+        get_payload_compression(),
+        get_index_doc_chunks(),
+        embedder,
+        qdrant_client,
+    )
+    .await
+}
+
+// Embeds the chunks of a file, and its doc comments when `index_docs` is set, and upserts them.
+// The ids are derived from the file content and the chunk range, committing the same file again
+// overwrites its points.
+async fn commit_chunk_points(
+    chunks: &[Chunk<'_>],
+    file: &ChunkedFile<'_>,
+    compression: TextCompression,
+    index_docs: bool,
+    embedder: impl Fn(&str) -> anyhow::Result<Embedding>,
+    qdrant_client: &Option<impl PointSink>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut temp_payloads = Vec::new();
+    let docs = chunk_docs(chunks, file.doc_comments);
+    let class = PathClass::of(file.relative_path);
+    for (i, (chunk, doc)) in chunks.iter().zip(docs).enumerate() {
+        // the SQL of a string or the fenced code of markdown, its language is embedded with it.
+        let embedded_lang = embedded_lang::embedded_lang(chunk.data, file.lang);
+        let text = embedded_lang::embedding_text(chunk.data, embedded_lang.as_deref());
+        let payload = Payload {
+            repo_name: file.repo_name.to_owned(),
+            relative_path: file.relative_path.to_owned(),
+            content_hash: file.semantic_hash.to_string(),
+            text: chunk.data.to_owned(),
+            lang: file.lang.to_ascii_lowercase(),
+            start_line: chunk.range.start.line as u64,
+            end_line: chunk.range.end.line as u64,
+            start_byte: chunk.range.start.byte as u64,
+            end_byte: chunk.range.end.byte as u64,
+            doc,
+            key_path: file.key_paths.get(i).cloned().flatten(),
+            embedded_lang,
+            is_test: class.is_test,
+            is_vendored: class.is_vendored,
+            branch: branch_name(file.branch).to_string(),
+            last_modified: file.last_modified,
+            ..Default::default()
+        };
+
+        temp_payloads.push(PointStruct {
+            id: Some(PointId::from(chunk_point_id(file, &payload).to_string())),
+            vectors: Some(embedder(&text)?.into()),
+            payload: payload.convert_to_qdrant_fields(compression)?,
+        });
+    }
+
+    // the doc on its own, with the symbol name, matches queries about what the symbol does
+    // more closely than the chunk it is buried in.
+    if index_docs {
+        for doc_comment in file.doc_comments {
+            let text = format!("{}\n{}", doc_comment.symbol, doc_comment.doc);
+            let payload = Payload {
+                repo_name: file.repo_name.to_owned(),
+                relative_path: file.relative_path.to_owned(),
+                content_hash: file.semantic_hash.to_string(),
+                lang: file.lang.to_ascii_lowercase(),
+                start_line: doc_comment.start_line as u64,
+                end_line: doc_comment.end_line as u64,
+                start_byte: doc_comment.start_byte as u64,
+                end_byte: doc_comment.end_byte as u64,
+                kind: ChunkKind::Doc,
+                doc: Some(doc_comment.doc.clone()),
+                symbol: Some(doc_comment.symbol.clone()),
+                text,
+                is_test: class.is_test,
+                is_vendored: class.is_vendored,
+                branch: branch_name(file.branch).to_string(),
+                last_modified: file.last_modified,
+                ..Default::default()
+            };
+            temp_payloads.push(PointStruct {
+                id: Some(PointId::from(chunk_point_id(file, &payload).to_string())),
+                vectors: Some(embedder(&payload.text)?.into()),
+                payload: payload.convert_to_qdrant_fields(compression)?,
+            });
+        }
+    }
+
+    println!("length of the payload: {}", temp_payloads.len());
+    upsert_points(qdrant_client, COLLECTION_NAME, temp_payloads).await?;
+    println!("finished committing to qdrant");
+    Ok(())
+}
+
+fn chunk_point_id(file: &ChunkedFile<'_>, payload: &Payload) -> uuid::Uuid {
+    hash::chunk_point_id(
+        file.repo_name,
+        file.relative_path,
+        file.branch,
+        payload.kind.as_str(),
+        payload.start_byte..payload.end_byte,
+        file.semantic_hash,
+    )
+}
+
+// qdrant doesn't like empty payloads.
+async fn upsert_points(
+    qdrant_client: &Option<impl PointSink>,
+    collection: &str,
+    points: Vec<PointStruct>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(client) = qdrant_client else {
+        return Err(Box::new(CommitError::NoQdrantClient));
+    };
+    if points.is_empty() {
+        return Ok(());
+    }
+    let count = points.len();
+    client
+        .upsert_points(collection, points)
+        .await
+        .map_err(|_| {
+            metrics::record_ingestion_failure("qdrant");
+            Box::new(CommitError::QdrantError)
+        })?;
+    metrics::record_chunks_committed(collection, count);
+    Ok(())
+}
+
+// The doc comments of the definitions starting in each chunk. Chunks overlap, a definition
+// starting in the overlap is only attached to the first of them.
+fn chunk_docs(chunks: &[Chunk<'_>], doc_comments: &[DocComment]) -> Vec<Option<String>> {
+    let mut docs = vec![Vec::new(); chunks.len()];
+    for doc_comment in doc_comments {
+        if let Some(index) = chunks.iter().position(|chunk| {
+            (chunk.range.start.byte..chunk.range.end.byte).contains(&doc_comment.start_byte)
+        }) {
+            docs[index].push(doc_comment.doc.as_str());
+        }
+    }
+    docs.into_iter()
+        .map(|docs| (!docs.is_empty()).then(|| docs.join("\n\n")))
+        .collect()
+}
+
+fn is_stop_symbol(symbol: &str, stop_list: &[String]) -> bool {
+    stop_list.iter().any(|stop| stop.eq_ignore_ascii_case(symbol))
+}
+
+// Creates the qdrant payload of a symbol from all its occurrences in the repository.
+// The occurrences are written as the per field columns of the payload, see `common::symbol_payload`.
+// Only the first `occurrence_limit` occurrences are kept, ordered so the most useful ones survive:
+// global declarations first, then files closer to the repository root.
+// The number of dropped occurrences is stored as `overflow_count`, which ranking uses as a commonness signal.
+fn build_symbol_payload(
+    key: &SymbolKey,
+    values: &[SymbolValue],
+    occurrence_limit: usize,
+) -> SymbolPayload {
+    let mut occurrences = values.iter().collect::<Vec<_>>();
+    occurrences.sort_by(|a, b| {
+        b.is_global
+            .cmp(&a.is_global)
+            .then_with(|| path_depth(&a.relative_path).cmp(&path_depth(&b.relative_path)))
+            .then_with(|| a.relative_path.cmp(&b.relative_path))
+            .then_with(|| a.start_byte.cmp(&b.start_byte))
+    });
+    let overflow_count = occurrences.len().saturating_sub(occurrence_limit);
+    occurrences.truncate(occurrence_limit);
+
+    SymbolPayload {
+        repo_name: key.repo_name.clone(),
+        symbol: key.symbol.clone(),
+        occurrences: occurrences
+            .into_iter()
+            .map(|value| SymbolOccurrence {
+                path: value.relative_path.clone(),
+                start_byte: value.start_byte,
+                end_byte: value.end_byte,
+                is_global: value.is_global,
+                node_kind: value.node_kind.clone(),
+                lang: value.language_id.clone(),
+                symbol_type: value.symbol_type.clone(),
+                container_path: value.container_path.clone(),
+            })
+            .collect(),
+        overflow_count: overflow_count as i64,
+        ..Default::default()
+    }
+}
+
+// The occurrence of a point read back as the value it was built from, see `build_symbol_payload`.
+fn symbol_value(occurrence: SymbolOccurrence) -> SymbolValue {
+    SymbolValue {
+        symbol_type: occurrence.symbol_type,
+        language_id: occurrence.lang,
+        is_global: occurrence.is_global,
+        relative_path: occurrence.path,
+        start_byte: occurrence.start_byte,
+        end_byte: occurrence.end_byte,
+        node_kind: occurrence.node_kind,
+        container_path: occurrence.container_path,
+    }
+}
+
+fn path_depth(path: &str) -> usize {
+    path.matches('/').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::prelude::Value;
+
+    fn occurrence(path: &str, start_byte: usize, is_global: bool) -> SymbolValue {
+        SymbolValue {
+            symbol_type: "function".to_string(),
+            language_id: "rust".to_string(),
+            is_global,
+            relative_path: path.to_string(),
+            start_byte,
+            end_byte: start_byte + 10,
+            node_kind: "def".to_string(),
+            container_path: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_hot_symbol_is_capped_with_globals_first() {
+        let key = SymbolKey {
+            symbol: "handle".to_string(),
+            repo_name: "repo".to_string(),
+        };
+        // 500 local occurrences deep in the tree, and two global ones.
+        let mut values = (0..500)
+            .map(|i| occurrence(&format!("src/handlers/h{}.rs", i), i, false))
+            .collect::<Vec<_>>();
+        values.push(occurrence("src/deep/nested/server.rs", 40, true));
+        values.push(occurrence("src/lib.rs", 10, true));
+
+        let payload = build_symbol_payload(&key, &values, 50);
+
+        assert_eq!(payload.occurrences.len(), 50);
+        assert_eq!(payload.overflow_count, 452);
+        // globals first, the one closer to the root before the nested one.
+        assert_eq!(payload.occurrences[0].path, "src/lib.rs");
+        assert_eq!(payload.occurrences[1].path, "src/deep/nested/server.rs");
+        assert!(payload.occurrences[..2].iter().all(|o| o.is_global));
+        assert!(payload.occurrences[2..].iter().all(|o| !o.is_global));
+    }
+
+    #[test]
+    fn test_rare_symbol_is_not_capped() {
+        let key = SymbolKey {
+            symbol: "refresh_token".to_string(),
+            repo_name: "repo".to_string(),
+        };
+        let mut method = occurrence("src/auth/token.rs", 120, false);
+        method.container_path = vec!["TokenStore".to_string(), "Cache".to_string()];
+        let values = vec![method, occurrence("src/auth/token.rs", 20, true)];
+
+        let payload = build_symbol_payload(&key, &values, 50);
+
+        assert_eq!(payload.overflow_count, 0);
+        let start_bytes = payload.occurrences.iter().map(|o| o.start_byte).collect::<Vec<_>>();
+        assert_eq!(start_bytes, vec![20, 120]);
+
+        // written flat, the container paths along with the occurrences they belong to.
+        let fields = payload.convert_to_qdrant_fields();
+        assert_eq!(fields["start_byte"], Value::from(vec![20_i64, 120]));
+        assert_eq!(
+            fields["container_path"],
+            Value::from(vec!["".to_string(), "TokenStore::Cache".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_stop_list_is_case_insensitive() {
+        let stop_list = vec!["new".to_string(), "init".to_string()];
+        assert!(is_stop_symbol("new", &stop_list));
+        assert!(is_stop_symbol("Init", &stop_list));
+        assert!(!is_stop_symbol("new_client", &stop_list));
+    }
+
+    #[test]
+    fn test_docs_are_attached_to_the_first_chunk_of_the_definition() {
+        let src = "fn a() {}\nfn b() {}\nfn c() {}\nfn d() {}\n";
+        let chunks = SemanticIndex::by_lines(src, 2);
+        let doc_comment = |symbol: &str, doc: &str, start_byte: usize| DocComment {
+            symbol: symbol.to_string(),
+            doc: doc.to_string(),
+            start_byte,
+            end_byte: start_byte + 9,
+            start_line: start_byte / 10,
+            end_line: start_byte / 10,
+        };
+        let docs = chunk_docs(
+            &chunks,
+            &[
+                doc_comment("a", "Does a.", 0),
+                doc_comment("b", "Does b.", 10),
+                doc_comment("d", "Does d.", 30),
+            ],
+        );
+
+        assert_eq!(
+            docs,
+            vec![
+                Some("Does a.\n\nDoes b.".to_string()),
+                Some("Does d.".to_string()),
+                None
+            ]
+        );
+    }
+
+    // Records the ids of every upsert, by collection.
+    #[derive(Default)]
+    struct RecordingSink {
+        upserts: std::sync::Mutex<Vec<(String, Vec<String>)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl PointSink for RecordingSink {
+        async fn upsert_points(
+            &self,
+            collection: &str,
+            points: Vec<PointStruct>,
+        ) -> anyhow::Result<()> {
+            let ids = points
+                .into_iter()
+                .map(|point| format!("{:?}", point.id.unwrap().point_id_options))
+                .collect();
+            self.upserts
+                .lock()
+                .unwrap()
+                .push((collection.to_string(), ids));
+            Ok(())
+        }
+    }
+
+    fn embed(text: &str) -> anyhow::Result<Embedding> {
+        Ok(vec![text.len() as f32; 4])
+    }
+
+    #[tokio::test]
+    async fn test_committing_the_same_content_twice_upserts_the_same_ids() {
+        let src = "fn a() {}\nfn b() {}\nfn c() {}\nfn d() {}\n";
+        let doc_comments = [DocComment {
+            symbol: "a".to_string(),
+            doc: "Does a.".to_string(),
+            start_byte: 0,
+            end_byte: 9,
+            start_line: 0,
+            end_line: 0,
+        }];
+        let file = ChunkedFile {
+            repo_name: "repo",
+            relative_path: "src/lib.rs",
+            branch: "refs/heads/main",
+            semantic_hash: "hash",
+            lang: "Rust",
+            key_paths: &[],
+            doc_comments: &doc_comments,
+            last_modified: None,
+        };
+        let symbols = HashMap::from([(
+            SymbolKey {
+                symbol: "a".to_string(),
+                repo_name: "repo".to_string(),
+            },
+            vec![occurrence("src/lib.rs", 3, true)],
+        )]);
+        let sink = Some(RecordingSink::default());
+
+        for _ in 0..2 {
+            let chunks = SemanticIndex::by_lines(src, 2);
+            commit_chunk_points(&chunks, &file, TextCompression::None, true, embed, &sink)
+                .await
+                .unwrap();
+            commit_symbol_points(&symbols, "refs/heads/main", 50, &[], embed, &sink)
+                .await
+                .unwrap();
+        }
+
+        let upserts = sink.unwrap().upserts.into_inner().unwrap();
+        assert_eq!(upserts.len(), 4);
+        let (first_chunks, first_symbols) = (&upserts[0], &upserts[1]);
+        assert_eq!(first_chunks.0, COLLECTION_NAME);
+        // three chunks of code and the doc chunk, with ids of their own.
+        assert_eq!(first_chunks.1.len(), 4);
+        assert_eq!(
+            first_chunks.1.iter().collect::<std::collections::HashSet<_>>().len(),
+            4
+        );
+        assert_eq!(&upserts[2], first_chunks);
+        assert_eq!(first_symbols.0, COLLECTION_NAME_SYMBOLS);
+        assert_eq!(&upserts[3], first_symbols);
+
+        // other content in the file gets new ids.
+        let other = ChunkedFile {
+            semantic_hash: "other-hash",
+            ..file
+        };
+        let other_sink = Some(RecordingSink::default());
+        let chunks = SemanticIndex::by_lines(src, 2);
+        commit_chunk_points(&chunks, &other, TextCompression::None, true, embed, &other_sink)
+            .await
+            .unwrap();
+        let other_upserts = other_sink.unwrap().upserts.into_inner().unwrap();
+        assert!(other_upserts[0].1.iter().all(|id| !first_chunks.1.contains(id)));
+    }
+
+    use common::branch::BRANCH_FIELD;
+    use qdrant_client::qdrant::{value::Kind, Value};
+
+    // the points of the collections by id, upserts overwrite them like qdrant does.
+    #[derive(Default)]
+    struct CollectionSink {
+        points: std::sync::Mutex<HashMap<(String, String), HashMap<String, Value>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl PointSink for CollectionSink {
+        async fn upsert_points(
+            &self,
+            collection: &str,
+            points: Vec<PointStruct>,
+        ) -> anyhow::Result<()> {
+            let mut stored = self.points.lock().unwrap();
+            for point in points {
+                let id = format!("{:?}", point.id.unwrap().point_id_options);
+                stored.insert((collection.to_string(), id), point.payload);
+            }
+            Ok(())
+        }
+    }
+
+    impl CollectionSink {
+        // the string field of the points of the collection on the branch, like a filtered search.
+        fn on_branch(&self, collection: &str, branch: &str, field: &str) -> Vec<String> {
+            let string = |payload: &HashMap<String, Value>, field: &str| match payload
+                .get(field)
+                .and_then(|value| value.kind.clone())
+            {
+                Some(Kind::StringValue(value)) => value,
+                _ => String::new(),
+            };
+            let mut values = self
+                .points
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|((stored, _), payload)| {
+                    stored == collection && string(payload, BRANCH_FIELD) == branch
+                })
+                .map(|(_, payload)| string(payload, field))
+                .collect::<Vec<_>>();
+            values.sort();
+            values
+        }
+    }
+
+    #[tokio::test]
+    async fn test_branches_of_a_repo_are_indexed_side_by_side() {
+        // the branches only differ in src/refund.rs.
+        let main = [
+            ("src/lib.rs", "fn charge() {}\n"),
+            ("src/refund.rs", "fn refund() { full() }\n"),
+        ];
+        let release = [
+            ("src/lib.rs", "fn charge() {}\n"),
+            ("src/refund.rs", "fn refund() { partial() }\n"),
+        ];
+        let sink = Some(CollectionSink::default());
+
+        for (branch, files) in [("refs/heads/main", main), ("refs/heads/release-1.2", release)] {
+            let mut symbols: HashMap<SymbolKey, Vec<SymbolValue>> = HashMap::new();
+            for (path, src) in files {
+                let file = ChunkedFile {
+                    repo_name: "repo",
+                    relative_path: path,
+                    branch,
+                    semantic_hash: src,
+                    lang: "Rust",
+                    key_paths: &[],
+                    doc_comments: &[],
+                    last_modified: None,
+                };
+                let chunks = SemanticIndex::by_lines(src, 1);
+                commit_chunk_points(&chunks, &file, TextCompression::None, false, embed, &sink)
+                    .await
+                    .unwrap();
+                let name = src.split(['(', ' ']).nth(1).unwrap();
+                symbols
+                    .entry(SymbolKey {
+                        symbol: name.to_string(),
+                        repo_name: "repo".to_string(),
+                    })
+                    .or_default()
+                    .push(occurrence(path, 3, true));
+            }
+            commit_symbol_points(&symbols, branch, 50, &[], embed, &sink)
+                .await
+                .unwrap();
+        }
+
+        // indexing the release branch left the chunks and symbols of main as they were.
+        let sink = sink.unwrap();
+        assert_eq!(
+            sink.on_branch(COLLECTION_NAME, "main", "snippet"),
+            vec!["fn charge() {}", "fn refund() { full() }"]
+        );
+        assert_eq!(
+            sink.on_branch(COLLECTION_NAME, "release-1.2", "snippet"),
+            vec!["fn charge() {}", "fn refund() { partial() }"]
+        );
+        for branch in ["main", "release-1.2"] {
+            assert_eq!(
+                sink.on_branch(COLLECTION_NAME_SYMBOLS, branch, "symbol"),
+                vec!["charge", "refund"]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_the_code_after_a_license_header_is_embedded() {
+        let header = [
+            "// Copyright (c) 2023 Acme Inc.",
+            "//",
+            "// Permission is hereby granted, free of charge, to any person obtaining a copy",
+            "// of this software and associated documentation files (the \"Software\"), to deal",
+            "// in the Software without restriction, including without limitation the rights",
+            "// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell",
+            "// copies of the Software, and to permit persons to whom the Software is",
+            "// furnished to do so, subject to the following conditions:",
+            "//",
+            "// The above copyright notice and this permission notice shall be included in all",
+            "// copies or substantial portions of the Software.",
+            "//",
+            "// THE SOFTWARE IS PROVIDED \"AS IS\", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR",
+            "// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,",
+            "// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE",
+            "// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER",
+            "// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,",
+            "// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS.",
+        ];
+        let code = [
+            "pub fn refund(order: &Order, amount: u64) -> Result<Refund> { order.refund(amount) }",
+            "pub fn cancel(order: &Order) -> Result<()> { order.cancel() }",
+        ];
+        // 18 of the 20 lines are the license.
+        let src = format!("{}\n{}\n", header.join("\n"), code.join("\n"));
+        let file = ChunkedFile {
+            repo_name: "repo",
+            relative_path: "src/orders.rs",
+            branch: "refs/heads/main",
+            semantic_hash: "hash",
+            lang: "Rust",
+            key_paths: &[],
+            doc_comments: &[],
+            last_modified: None,
+        };
+
+        let chunks = SemanticIndex::by_lines(&src, 4)
+            .into_iter()
+            .map(|chunk| (chunk, ()))
+            .collect();
+        let (chunks, dropped) = chunk_quality::filter_chunks(
+            chunks,
+            &src,
+            file.lang,
+            &chunk_quality::ChunkQualityThresholds::default(),
+            |text| text.split_whitespace().count(),
+        );
+        assert_eq!(dropped, 4);
+
+        let embedded = std::sync::Mutex::new(Vec::new());
+        let embedder = |text: &str| -> anyhow::Result<Embedding> {
+            embedded.lock().unwrap().push(text.to_string());
+            embed(text)
+        };
+        let chunks = chunks.into_iter().map(|(chunk, _)| chunk).collect::<Vec<_>>();
+        let sink = Some(RecordingSink::default());
+        commit_chunk_points(&chunks, &file, TextCompression::None, false, embedder, &sink)
+            .await
+            .unwrap();
+
+        let embedded = embedded.into_inner().unwrap();
+        assert_eq!(embedded.len(), 1);
+        assert_eq!(embedded[0].trim(), code.join("\n"));
+        assert_eq!(chunks[0].range.start.line, header.len());
+    }
+
+    #[tokio::test]
+    async fn test_chunks_with_an_embedded_language_are_tagged() {
+        let src = concat!(
+            "const ORDERS: &str = \"SELECT id, total FROM orders WHERE customer_id = $1\";\n",
+            "fn total(order: &Order) -> u64 { order.lines.iter().map(|l| l.amount).sum() }\n",
+        );
+        let file = ChunkedFile {
+            repo_name: "repo",
+            relative_path: "src/orders.rs",
+            branch: "refs/heads/main",
+            semantic_hash: "hash",
+            lang: "Rust",
+            key_paths: &[],
+            doc_comments: &[],
+            last_modified: None,
+        };
+        let embedded = std::sync::Mutex::new(Vec::new());
+        let embedder = |text: &str| -> anyhow::Result<Embedding> {
+            embedded.lock().unwrap().push(text.to_string());
+            embed(text)
+        };
+        let sink = Some(CollectionSink::default());
+        let chunks = SemanticIndex::by_lines(src, 1);
+        commit_chunk_points(&chunks, &file, TextCompression::None, false, embedder, &sink)
+            .await
+            .unwrap();
+
+        let embedded = embedded.into_inner().unwrap();
+        assert!(embedded[0].starts_with("SQL\nconst ORDERS"));
+        assert!(embedded[1].trim_start().starts_with("fn total"));
+        let sink = sink.unwrap();
+        assert_eq!(
+            sink.on_branch(COLLECTION_NAME, "main", "embedded_lang"),
+            vec!["", "sql"]
+        );
+        // the payload keeps the text of the file.
+        assert!(sink
+            .on_branch(COLLECTION_NAME, "main", "snippet")
+            .iter()
+            .all(|text| !text.starts_with("SQL")));
+    }
+}
@@ -0,0 +1,380 @@
+// Filter of the chunks not worth an embedding: the ones that are nearly all whitespace, comment
+// banners or without code, and the license header of the file. They are only left out of qdrant,
+// the whole file is still in the quickwit content for full-text search.
+//
+// A license header is the leading comment block of the file when it holds one of the fingerprints
+// of the common licenses, it is always dropped. The thresholds never drop every chunk of a file,
+// the best of the low quality ones is kept when no other would be.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::semantic_index::chunking::Chunk;
+use crate::semantic_index::text_range::Point;
+
+// Phrases of the common license headers, matched on the lowercased comment text with the comment
+// markers removed and the whitespace collapsed.
+const LICENSE_FINGERPRINTS: &[&str] = &[
+    "spdx-license-identifier:",
+    "permission is hereby granted, free of charge",
+    "licensed under the apache license",
+    "this program is free software",
+    "gnu general public license",
+    "gnu lesser general public license",
+    "redistribution and use in source and binary forms",
+    "mozilla public license",
+    "all rights reserved",
+];
+
+/// Thresholds under which a chunk isn't embedded, set with `CHUNK_QUALITY_THRESHOLDS`,
+/// e.g. `non_whitespace=0.2,code_tokens=8,comments=0.9`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkQualityThresholds {
+    // least share of the characters that aren't whitespace.
+    pub non_whitespace: f64,
+    // least tokens in the lines that aren't comments.
+    pub code_tokens: usize,
+    // most share of the non blank lines that are comments.
+    pub comments: f64,
+}
+
+impl Default for ChunkQualityThresholds {
+    fn default() -> Self {
+        Self {
+            non_whitespace: 0.2,
+            code_tokens: 8,
+            comments: 0.9,
+        }
+    }
+}
+
+impl FromStr for ChunkQualityThresholds {
+    type Err = anyhow::Error;
+
+    /// The thresholds left out keep their default.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut thresholds = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected <feature>=<threshold>, got {}", pair))?;
+            let (name, value) = (name.trim(), value.trim());
+            let ratio = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|ratio| (0.0..=1.0).contains(ratio))
+                    .ok_or_else(|| anyhow!("the threshold of {} must be between 0 and 1", name))
+            };
+            match name {
+                "non_whitespace" => thresholds.non_whitespace = ratio()?,
+                "comments" => thresholds.comments = ratio()?,
+                "code_tokens" => {
+                    thresholds.code_tokens = value.parse().map_err(|_| {
+                        anyhow!("the threshold of code_tokens must be a number of tokens")
+                    })?
+                }
+                other => return Err(anyhow!("unknown chunk quality feature {}", other)),
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+/// How a language writes its comments.
+struct CommentSyntax {
+    line: &'static [&'static str],
+    block: Option<(&'static str, &'static str)>,
+}
+
+const C_LIKE: CommentSyntax = CommentSyntax {
+    line: &["//"],
+    block: Some(("/*", "*/")),
+};
+
+fn comment_syntax(lang: &str) -> CommentSyntax {
+    match lang.to_ascii_lowercase().as_str() {
+        "python" | "r" | "yaml" | "toml" => CommentSyntax {
+            line: &["#"],
+            block: None,
+        },
+        "ruby" => CommentSyntax {
+            line: &["#"],
+            block: Some(("=begin", "=end")),
+        },
+        "php" => CommentSyntax {
+            line: &["//", "#"],
+            block: Some(("/*", "*/")),
+        },
+        "json" => CommentSyntax {
+            line: &[],
+            block: None,
+        },
+        _ => C_LIKE,
+    }
+}
+
+impl CommentSyntax {
+    // The non blank lines of `text` and whether each is a comment. A chunk can start in the middle
+    // of a block comment, the `*` its lines usually start with gives them away.
+    fn classify<'a>(&self, text: &'a str) -> Vec<(&'a str, bool)> {
+        let mut in_block = false;
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let comment = if in_block {
+                    in_block = matches!(self.block, Some((_, close)) if !line.contains(close));
+                    true
+                } else if self.line.iter().any(|marker| line.starts_with(marker)) {
+                    true
+                } else if let Some((open, close)) = self.block {
+                    if let Some(rest) = line.strip_prefix(open) {
+                        in_block = !rest.contains(close);
+                        true
+                    } else {
+                        open == "/*"
+                            && (line == "*" || line.starts_with("* ") || line.starts_with("*/"))
+                    }
+                } else {
+                    false
+                };
+                (line, comment)
+            })
+            .collect()
+    }
+
+    // The text of a comment line without its markers.
+    fn strip<'a>(&self, line: &'a str) -> &'a str {
+        let markers = self
+            .line
+            .iter()
+            .chain(self.block.iter().flat_map(|(open, close)| [open, close]));
+        let line = markers.fold(line, |line, marker| {
+            line.strip_prefix(marker)
+                .or_else(|| line.strip_suffix(marker))
+                .unwrap_or(line)
+        });
+        line.trim_start_matches('*').trim()
+    }
+}
+
+/// The features a chunk is judged on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkFeatures {
+    pub non_whitespace: f64,
+    pub code_tokens: usize,
+    pub comments: f64,
+}
+
+impl ChunkFeatures {
+    /// `code_tokens` counts the tokens of the lines that aren't comments.
+    pub fn of(text: &str, lang: &str, code_tokens: impl Fn(&str) -> usize) -> Self {
+        let chars = text.chars().count();
+        let non_whitespace = text.chars().filter(|c| !c.is_whitespace()).count();
+        let lines = comment_syntax(lang).classify(text);
+        let comment_lines = lines.iter().filter(|(_, comment)| *comment).count();
+        let code = lines
+            .iter()
+            .filter(|(_, comment)| !comment)
+            .map(|(line, _)| *line)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Self {
+            non_whitespace: ratio(non_whitespace, chars),
+            code_tokens: if code.is_empty() {
+                0
+            } else {
+                code_tokens(&code)
+            },
+            comments: ratio(comment_lines, lines.len()),
+        }
+    }
+
+    pub fn is_low_quality(&self, thresholds: &ChunkQualityThresholds) -> bool {
+        self.non_whitespace < thresholds.non_whitespace
+            || self.code_tokens < thresholds.code_tokens
+            || self.comments > thresholds.comments
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+/// Where the license header at the top of the file ends, the byte and line right after it.
+/// None when the leading comment block of the file isn't a license.
+pub fn license_header_end(src: &str, lang: &str) -> Option<Point> {
+    let syntax = comment_syntax(lang);
+    let mut in_block = false;
+    let mut comment = Vec::new();
+    let (mut end, mut line_count, mut byte) = (None, 0, 0);
+    for (index, line) in src.split_inclusive('\n').enumerate() {
+        byte += line.len();
+        let trimmed = line.trim();
+        if trimmed.is_empty() || (index == 0 && trimmed.starts_with("#!")) {
+            continue;
+        }
+        // the lines are classified one at a time, the state of the block is carried over.
+        let is_comment = if in_block {
+            in_block = matches!(syntax.block, Some((_, close)) if !trimmed.contains(close));
+            true
+        } else {
+            match syntax.classify(trimmed).first() {
+                Some((_, true)) => {
+                    in_block = matches!(syntax.block, Some((open, close))
+                        if trimmed.starts_with(open) && !trimmed[open.len()..].contains(close));
+                    true
+                }
+                _ => false,
+            }
+        };
+        if !is_comment {
+            break;
+        }
+        comment.push(syntax.strip(trimmed));
+        end = Some(byte);
+        line_count = index + 1;
+    }
+
+    let text = comment.join(" ").to_lowercase();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let is_license = LICENSE_FINGERPRINTS
+        .iter()
+        .any(|fingerprint| text.contains(fingerprint));
+    end.filter(|_| is_license)
+        .map(|byte| Point::new(byte, line_count, 0))
+}
+
+/// Leaves out the license header and the low quality chunks, along with what is attached to them.
+/// Returns the chunks to embed and how many were dropped. A chunk overlapping the end of the
+/// license header is trimmed to the code after it.
+pub fn filter_chunks<'s, T>(
+    chunks: Vec<(Chunk<'s>, T)>,
+    src: &str,
+    lang: &str,
+    thresholds: &ChunkQualityThresholds,
+    code_tokens: impl Fn(&str) -> usize,
+) -> (Vec<(Chunk<'s>, T)>, usize) {
+    let header_end = license_header_end(src, lang);
+    let total = chunks.len();
+    let mut kept = Vec::with_capacity(total);
+    // the low quality chunk kept when no other is, the one with the most code.
+    let mut representative: Option<(usize, (Chunk<'s>, T))> = None;
+
+    for (chunk, attached) in chunks {
+        let chunk = match header_end {
+            Some(end) if chunk.range.end.byte <= end.byte => continue,
+            Some(end) if chunk.range.start.byte < end.byte => {
+                let data = &chunk.data[end.byte - chunk.range.start.byte..];
+                if data.trim().is_empty() {
+                    continue;
+                }
+                Chunk::new(data, end, chunk.range.end)
+            }
+            _ => chunk,
+        };
+
+        let features = ChunkFeatures::of(chunk.data, lang, &code_tokens);
+        if !features.is_low_quality(thresholds) {
+            kept.push((chunk, attached));
+        } else if !matches!(&representative, Some((tokens, _)) if features.code_tokens <= *tokens) {
+            representative = Some((features.code_tokens, (chunk, attached)));
+        }
+    }
+
+    if kept.is_empty() {
+        kept.extend(representative.map(|(_, chunk)| chunk));
+    }
+    let dropped = total - kept.len();
+    (kept, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    fn chunk(src: &str, range: std::ops::Range<usize>) -> (Chunk<'_>, ()) {
+        let line = |byte: usize| src[..byte].matches('\n').count();
+        (
+            Chunk::new(
+                &src[range.clone()],
+                Point::new(range.start, line(range.start), 0),
+                Point::new(range.end, line(range.end), 0),
+            ),
+            (),
+        )
+    }
+
+    #[test]
+    fn test_features_of_comment_banners_and_code() {
+        let banner = "// ==================\n// Handlers\n// ==================\n";
+        let features = ChunkFeatures::of(banner, "Rust", words);
+        assert_eq!(features.comments, 1.0);
+        assert_eq!(features.code_tokens, 0);
+        assert!(features.is_low_quality(&ChunkQualityThresholds::default()));
+
+        let code = "/* Refunds are capped.\n   at the captured amount */\nfn refund(order: &Order, amount: u64) -> Result<Refund> {\n    order.refund(amount)\n}\n";
+        let features = ChunkFeatures::of(code, "Rust", words);
+        assert_eq!(features.comments, 0.4);
+        assert_eq!(features.code_tokens, 10);
+        assert!(!features.is_low_quality(&ChunkQualityThresholds::default()));
+
+        let blank = "\n\n        \n    x\n\n\n";
+        assert!(ChunkFeatures::of(blank, "Python", words).non_whitespace < 0.2);
+    }
+
+    #[test]
+    fn test_the_only_chunk_of_a_small_file_is_kept() {
+        let src = "# settings\nDEBUG = True\n";
+        let (kept, dropped) = filter_chunks(
+            vec![chunk(src, 0..src.len())],
+            src,
+            "Python",
+            &ChunkQualityThresholds::default(),
+            words,
+        );
+        assert_eq!(kept.len(), 1);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn test_license_header_is_only_the_leading_comment_block() {
+        let src = "#!/usr/bin/env python\n# Copyright 2023 Acme\n# SPDX-License-Identifier: MIT\n\nimport os\n# all rights reserved\n";
+        let end = license_header_end(src, "Python").unwrap();
+        assert_eq!(&src[end.byte..], "\nimport os\n# all rights reserved\n");
+        assert_eq!(end.line, 3);
+
+        let src = "// Handles the refunds.\nfn refund() {}\n// all rights reserved\n";
+        assert_eq!(license_header_end(src, "Rust"), None);
+
+        let src = "/*\n * Licensed under the Apache License, Version 2.0\n */\npackage main\n";
+        let end = license_header_end(src, "Go").unwrap();
+        assert_eq!(&src[end.byte..], "package main\n");
+    }
+
+    #[test]
+    fn test_thresholds_are_parsed_from_pairs() {
+        let thresholds: ChunkQualityThresholds = "code_tokens=4, comments=1".parse().unwrap();
+        assert_eq!(
+            thresholds,
+            ChunkQualityThresholds {
+                code_tokens: 4,
+                comments: 1.0,
+                ..Default::default()
+            }
+        );
+        assert!("comments=2".parse::<ChunkQualityThresholds>().is_err());
+        assert!("tokens=4".parse::<ChunkQualityThresholds>().is_err());
+    }
+}
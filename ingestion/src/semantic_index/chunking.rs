@@ -6,6 +6,9 @@ use std::ops::Range;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
 // A Chunk type, containing the plain text (borrowed from the source)
 /// and a `TextRange` with byte, line and column positions
 #[derive(Debug)]
@@ -67,7 +70,7 @@ impl<'a> Chunk<'a> {
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(try_from = "&str", into = "String")]
 pub enum OverlapStrategy {
-    /// go back _ lines from the end
+    /// go back _ tokens from the end, named after the lines it used to count
     ByLines(usize),
     /// A value > 0 and < 1 that indicates the target overlap in tokens.
     Partial(f64),
@@ -78,7 +81,7 @@ impl Display for OverlapStrategy {
         match self {
             Self::ByLines(n) => n.fmt(f),
             Self::Partial(p) => {
-                (*p / 100.0).fmt(f)?;
+                (*p * 100.0).fmt(f)?;
                 f.write_char('%')
             }
         }
@@ -134,11 +137,16 @@ impl OverlapStrategy {
     // returns the next startpoint for overlong lines
     pub fn next_subdivision(&self, max_tokens: usize) -> usize {
         (match self {
-            OverlapStrategy::ByLines(n) => max_tokens - n,
+            OverlapStrategy::ByLines(n) => max_tokens.saturating_sub(*n),
             OverlapStrategy::Partial(part) => ((max_tokens as f64) * part) as usize,
         })
         .max(1) // ensure we make forward progress
     }
+
+    // tokens the next chunk goes back from the end of a chunk of `chunk_tokens`.
+    pub fn overlap(&self, chunk_tokens: usize) -> usize {
+        chunk_tokens.saturating_sub(self.next_subdivision(chunk_tokens))
+    }
 }
 
 impl Default for OverlapStrategy {
@@ -147,6 +155,147 @@ impl Default for OverlapStrategy {
     }
 }
 
+/// How consecutive chunks overlap, set with `CHUNK_OVERLAP`, e.g. `target=50%,min=8,max=128`.
+/// The overlap aims at `target` and always stays within `min` and `max` tokens, both capped
+/// below the length of the earlier chunk so that every chunk moves forward.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkOverlap {
+    // a share of the chunk with `%` or a number of tokens.
+    pub target: OverlapStrategy,
+    pub min_tokens: usize,
+    pub max_tokens: usize,
+}
+
+impl Default for ChunkOverlap {
+    fn default() -> Self {
+        Self {
+            target: OverlapStrategy::default(),
+            min_tokens: 8,
+            max_tokens: 128,
+        }
+    }
+}
+
+impl Display for ChunkOverlap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "target={},min={},max={}",
+            self.target, self.min_tokens, self.max_tokens
+        )
+    }
+}
+
+impl FromStr for ChunkOverlap {
+    type Err = anyhow::Error;
+
+    /// The settings left out keep their default.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut overlap = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected <setting>=<value>, got {}", pair))?;
+            let (name, value) = (name.trim(), value.trim());
+            let tokens = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("the {} overlap must be a number of tokens", name))
+            };
+            match name {
+                "target" => {
+                    overlap.target = OverlapStrategy::try_from(value)
+                        .map_err(|_| anyhow!("the target overlap must be tokens or a percentage"))?
+                }
+                "min" => overlap.min_tokens = tokens()?,
+                "max" => overlap.max_tokens = tokens()?,
+                other => return Err(anyhow!("unknown chunk overlap setting {}", other)),
+            }
+        }
+        if overlap.min_tokens > overlap.max_tokens {
+            return Err(anyhow!(
+                "the min overlap {} is above the max overlap {}",
+                overlap.min_tokens,
+                overlap.max_tokens
+            ));
+        }
+        Ok(overlap)
+    }
+}
+
+impl ChunkOverlap {
+    // the least and most tokens the chunk after one of `chunk_tokens` can share with it.
+    pub fn bounds(&self, chunk_tokens: usize) -> (usize, usize) {
+        let max = self.max_tokens.min(chunk_tokens.saturating_sub(1));
+        (self.min_tokens.min(max), max)
+    }
+}
+
+/// The token ranges of the chunks of a file of `tokens` tokens, each of at most `max_tokens`.
+/// A chunk ends at a line start in its last quarter, else at a word start in its last eighth,
+/// else at `max_tokens`. The next one starts the target overlap before its end, moved to the
+/// nearest line start within the overlap bounds, else to the next word start; in long lines
+/// without either it starts on the token position itself.
+/// The starts and the ends of the ranges strictly increase, so no range is repeated or within
+/// another one, and consecutive ranges overlap within the bounds of `overlap`. Ranges shorter
+/// than `min_tokens` are left out, only the last one of a file can be.
+/// `is_line_start(i)` and `is_word_start(i)` tell whether token `i` starts a line or a word.
+pub fn plan_chunks(
+    tokens: usize,
+    min_tokens: usize,
+    max_tokens: usize,
+    overlap: &ChunkOverlap,
+    is_line_start: impl Fn(usize) -> bool,
+    is_word_start: impl Fn(usize) -> bool,
+) -> Vec<Range<usize>> {
+    let max_tokens = max_tokens.max(1);
+    let max_newline_tokens = max_tokens * 3 / 4; //TODO: make this configurable
+    let max_boundary_tokens = max_tokens * 7 / 8; //TODO: make this configurable
+    let mut ranges = Vec::new();
+    let (mut start, mut previous_end) = (0, 0);
+    loop {
+        let limit = start + max_tokens;
+        let end = if limit >= tokens {
+            tokens
+        } else {
+            // past the end of the previous chunk, a chunk within it would repeat its code.
+            let newline_from = (start + max_newline_tokens).max(previous_end) + 1;
+            let boundary_from = (start + max_boundary_tokens).max(previous_end) + 1;
+            (newline_from..=limit)
+                .rfind(|&i| is_line_start(i))
+                .or_else(|| (boundary_from..=limit).rfind(|&i| is_word_start(i)))
+                .unwrap_or(limit)
+        };
+        if end - start >= min_tokens {
+            ranges.push(start..end);
+        }
+        if end == tokens {
+            return ranges;
+        }
+
+        let chunk_tokens = end - start;
+        let (min_overlap, max_overlap) = overlap.bounds(chunk_tokens);
+        let earliest = (end - max_overlap).max(start + 1);
+        let latest = end - min_overlap;
+        let target = (end - overlap.target.overlap(chunk_tokens)).clamp(earliest, latest);
+        let next_line = (target..=latest).find(|&i| is_line_start(i));
+        let previous_line = (earliest..target).rfind(|&i| is_line_start(i));
+        start = match (next_line, previous_line) {
+            (Some(n), None) | (None, Some(n)) => n,
+            (Some(n), Some(p)) => {
+                if n - target < target - p {
+                    n
+                } else {
+                    p
+                }
+            }
+            (None, None) => (target..=latest)
+                .find(|&i| is_word_start(i))
+                .unwrap_or(target),
+        };
+        previous_end = end;
+    }
+}
 
 /// This should take care of [CLS], [SEP] etc. which could be introduced during per-chunk tokenization
 pub const DEDUCT_SPECIAL_TOKENS: usize = 2;
@@ -210,4 +359,138 @@ pub fn point(src: &str, byte: usize, last_line: usize, last_byte: usize) -> Poin
         byte
     };
     Point { byte, column, line }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a small linear congruential generator, the layouts are the same on every run.
+    struct Layouts(u64);
+
+    impl Layouts {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % n.max(1)
+        }
+    }
+
+    fn check_invariants(
+        ranges: &[Range<usize>],
+        tokens: usize,
+        min_tokens: usize,
+        max_tokens: usize,
+        overlap: &ChunkOverlap,
+    ) {
+        for range in ranges {
+            assert!(range.end - range.start <= max_tokens, "{:?}", range);
+            assert!(range.end - range.start >= min_tokens, "{:?}", range);
+        }
+        for pair in ranges.windows(2) {
+            let (previous, next) = (&pair[0], &pair[1]);
+            assert!(next.start > previous.start, "{:?} then {:?}", previous, next);
+            assert!(next.end > previous.end, "{:?} then {:?}", previous, next);
+            let (min, max) = overlap.bounds(previous.end - previous.start);
+            let shared = previous.end - next.start;
+            assert!(
+                (min..=max).contains(&shared),
+                "{:?} then {:?} share {} tokens, not within {}..={}",
+                previous,
+                next,
+                shared,
+                min,
+                max
+            );
+        }
+        if let (Some(first), Some(last)) = (ranges.first(), ranges.last()) {
+            assert_eq!(first.start, 0);
+            // only a tail shorter than a chunk is left out.
+            assert!(tokens - last.end < min_tokens.max(1), "{:?} of {}", last, tokens);
+        }
+    }
+
+    #[test]
+    fn test_random_layouts_hold_the_invariants() {
+        let mut layouts = Layouts(7);
+        for _ in 0..2_000 {
+            let tokens = 1 + layouts.below(3_000);
+            let max_tokens = 4 + layouts.below(300);
+            let min_tokens = layouts.below(max_tokens / 2);
+            let min_overlap = layouts.below(64);
+            let overlap = ChunkOverlap {
+                target: match layouts.below(2) {
+                    0 => OverlapStrategy::Partial(layouts.below(101) as f64 / 100.0),
+                    _ => OverlapStrategy::ByLines(layouts.below(400)),
+                },
+                min_tokens: min_overlap,
+                max_tokens: min_overlap + layouts.below(200),
+            };
+            // from minified code without newlines to one token per line.
+            let line_every = [0, 1, 3, 40, 500][layouts.below(5)];
+            let word_every = [0, 1, 2, 7][layouts.below(4)];
+            let line_starts = (0..tokens)
+                .map(|_| line_every > 0 && layouts.below(line_every) == 0)
+                .collect::<Vec<_>>();
+            let word_starts = (0..tokens)
+                .map(|_| word_every > 0 && layouts.below(word_every) == 0)
+                .collect::<Vec<_>>();
+
+            let ranges = plan_chunks(
+                tokens,
+                min_tokens,
+                max_tokens,
+                &overlap,
+                |i| line_starts[i],
+                |i| word_starts[i],
+            );
+            check_invariants(&ranges, tokens, min_tokens, max_tokens, &overlap);
+        }
+    }
+
+    #[test]
+    fn test_long_lines_fall_back_to_token_positions() {
+        let overlap = ChunkOverlap::default();
+        // one minified line of word pieces, nowhere to break but on the tokens.
+        let ranges = plan_chunks(1_000, 50, 230, &overlap, |_| false, |_| false);
+        assert_eq!(
+            ranges[..4],
+            [0..230, 115..345, 230..460, 345..575],
+            "{:?}",
+            ranges
+        );
+        check_invariants(&ranges, 1_000, 50, 230, &overlap);
+
+        // a minified line with a few words, the chunks start on them.
+        let ranges = plan_chunks(1_000, 50, 230, &overlap, |_| false, |i| i % 37 == 0);
+        assert_eq!(ranges[..2], [0..222, 111..333], "{:?}", ranges);
+        check_invariants(&ranges, 1_000, 50, 230, &overlap);
+        let mut deduplicated = ranges.clone();
+        deduplicated.dedup();
+        assert_eq!(deduplicated, ranges);
+
+        // going back more tokens than the chunk holds still moves forward.
+        let overlap: ChunkOverlap = "target=400,min=0,max=500".parse().unwrap();
+        let ranges = plan_chunks(1_000, 50, 230, &overlap, |_| false, |_| false);
+        assert_eq!(ranges[..2], [0..230, 1..231], "{:?}", ranges);
+        check_invariants(&ranges, 1_000, 50, 230, &overlap);
+    }
+
+    #[test]
+    fn test_chunk_overlap_is_parsed() {
+        let overlap: ChunkOverlap = "target=25%, max=64".parse().unwrap();
+        assert_eq!(
+            overlap,
+            ChunkOverlap {
+                target: OverlapStrategy::Partial(0.25),
+                min_tokens: 8,
+                max_tokens: 64,
+            }
+        );
+        assert_eq!(overlap.to_string(), "target=25%,min=8,max=64");
+        assert_eq!(overlap.to_string().parse::<ChunkOverlap>().unwrap(), overlap);
+        assert!("min=32,max=16".parse::<ChunkOverlap>().is_err());
+        assert!("lines=2".parse::<ChunkOverlap>().is_err());
+    }
+}
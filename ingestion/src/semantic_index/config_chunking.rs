@@ -0,0 +1,806 @@
+// Chunking of YAML, TOML and JSON files on their keys instead of a token count, so a chunk holds
+// whole sections and knows the key path it is under (e.g. `spec.template.containers`).
+//
+// The file is parsed first, a file that doesn't parse is chunked like code. The key boundaries are
+// then found line by line: a section that fits in a chunk is kept whole, one that doesn't is split
+// on the keys below it, the smaller siblings being packed together.
+
+use std::ops::Range;
+use std::path::Path;
+
+/// Extensions of the config files and the language they are indexed with.
+pub const CONFIG_LANGUAGES: &[(&str, &str)] = &[
+    ("yaml", "YAML"),
+    ("yml", "YAML"),
+    ("toml", "TOML"),
+    ("json", "JSON"),
+];
+
+pub fn config_language(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?;
+    CONFIG_LANGUAGES
+        .iter()
+        .find(|(config_ext, _)| config_ext.eq_ignore_ascii_case(ext))
+        .map(|(_, language)| *language)
+}
+
+pub fn is_config_language(language: &str) -> bool {
+    CONFIG_LANGUAGES
+        .iter()
+        .any(|(_, config_language)| config_language.eq_ignore_ascii_case(language))
+}
+
+/// A chunk of a config file, the byte range of whole keys and the key path they are under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigChunk {
+    pub range: Range<usize>,
+    // none for the top of a document, or for top level keys packed together.
+    pub key_path: Option<String>,
+    // too large for a chunk and without keys to split on, chunked by tokens by the caller.
+    pub oversized: bool,
+}
+
+// A key of the file: where its line starts (with the comments right above it), how deep it is
+// and its path from the top.
+#[derive(Debug, Clone, PartialEq)]
+struct Key {
+    start: usize,
+    depth: usize,
+    path: String,
+}
+
+/// Splits a config file on its keys, into chunks for which `fits` holds unless they are `oversized`.
+/// Returns `None` when the file doesn't parse as `language`.
+pub fn config_chunks(
+    src: &str,
+    language: &str,
+    fits: impl Fn(&str) -> bool,
+) -> Option<Vec<ConfigChunk>> {
+    let (documents, keys) = match language.to_ascii_uppercase().as_str() {
+        "YAML" => {
+            let documents = yaml_documents(src);
+            for document in &documents {
+                serde_yaml::from_str::<serde_yaml::Value>(&src[document.clone()]).ok()?;
+            }
+            (documents, yaml_keys(src))
+        }
+        "TOML" => {
+            src.parse::<toml::Table>().ok()?;
+            (vec![0..src.len()], toml_keys(src))
+        }
+        "JSON" => {
+            serde_json::from_str::<serde_json::Value>(src).ok()?;
+            (vec![0..src.len()], json_keys(src))
+        }
+        _ => return None,
+    };
+
+    let mut chunks = Vec::new();
+    for document in documents {
+        split_section(src, document, None, 0, &keys, &fits, &mut chunks);
+    }
+    chunks.retain(|chunk| !src[chunk.range.clone()].trim().is_empty());
+    Some(chunks)
+}
+
+fn split_section(
+    src: &str,
+    range: Range<usize>,
+    path: Option<&str>,
+    depth: usize,
+    keys: &[Key],
+    fits: &impl Fn(&str) -> bool,
+    chunks: &mut Vec<ConfigChunk>,
+) {
+    if fits(&src[range.clone()]) {
+        chunks.push(ConfigChunk {
+            range,
+            key_path: path.map(str::to_string),
+            oversized: false,
+        });
+        return;
+    }
+
+    let children = keys
+        .iter()
+        .filter(|key| key.depth == depth + 1 && range.contains(&key.start))
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        chunks.push(ConfigChunk {
+            range,
+            key_path: path.map(str::to_string),
+            oversized: true,
+        });
+        return;
+    }
+
+    // each child runs until the next one, the lines before the first child go with it.
+    let sections = children.iter().enumerate().map(|(i, child)| {
+        let start = if i == 0 { range.start } else { child.start };
+        let end = children.get(i + 1).map_or(range.end, |next| next.start);
+        (start..end, *child)
+    });
+
+    let mut group: Vec<(Range<usize>, &Key)> = Vec::new();
+    for (section, key) in sections {
+        if let Some((first, _)) = group.first() {
+            if !fits(&src[first.start..section.end]) {
+                flush_group(src, std::mem::take(&mut group), path, keys, fits, chunks);
+            }
+        }
+        group.push((section, key));
+    }
+    flush_group(src, group, path, keys, fits, chunks);
+}
+
+// A single section is split further if it has to, packed siblings are under their parent's path.
+fn flush_group(
+    src: &str,
+    group: Vec<(Range<usize>, &Key)>,
+    parent: Option<&str>,
+    keys: &[Key],
+    fits: &impl Fn(&str) -> bool,
+    chunks: &mut Vec<ConfigChunk>,
+) {
+    match group.as_slice() {
+        [] => {}
+        [(range, key)] => split_section(
+            src,
+            range.clone(),
+            Some(&key.path),
+            key.depth,
+            keys,
+            fits,
+            chunks,
+        ),
+        [(first, _), .., (last, _)] => chunks.push(ConfigChunk {
+            range: first.start..last.end,
+            key_path: parent.map(str::to_string),
+            oversized: false,
+        }),
+    }
+}
+
+// The documents of a multi-document YAML file, each starting at its `---` line.
+fn yaml_documents(src: &str) -> Vec<Range<usize>> {
+    let mut starts = vec![0];
+    for (start, line) in lines(src) {
+        if start > 0 && is_document_marker(line) {
+            starts.push(start);
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| *start..starts.get(i + 1).copied().unwrap_or(src.len()))
+        .collect()
+}
+
+fn is_document_marker(line: &str) -> bool {
+    line == "---" || line.starts_with("--- ") || line.starts_with("---\t")
+}
+
+// Mapping keys and list items by their indentation, list items are `parent[n]`.
+// Lines of block scalars (`key: |`) are skipped.
+fn yaml_keys(src: &str) -> Vec<Key> {
+    // (indent, is a list item, key) of the keys the current line may be under.
+    let mut stack: Vec<(usize, bool, Key)> = Vec::new();
+    let mut items: Vec<(String, usize)> = Vec::new();
+    let mut keys = Vec::new();
+    let mut comment_start = None;
+    let mut block_indent: Option<usize> = None;
+
+    for (start, line) in lines(src) {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        if let Some(block) = block_indent {
+            if trimmed.is_empty() || indent > block {
+                continue;
+            }
+            block_indent = None;
+        }
+        if is_document_marker(line) || line == "..." {
+            stack.clear();
+            items.clear();
+            comment_start = None;
+            continue;
+        }
+        if trimmed.is_empty() {
+            comment_start = None;
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            comment_start.get_or_insert(start);
+            continue;
+        }
+
+        let (is_item, rest) = match trimmed.strip_prefix('-') {
+            Some(rest) if rest.is_empty() || rest.starts_with(' ') => (true, rest.trim_start()),
+            _ => (false, trimmed),
+        };
+        let name = yaml_key_name(rest);
+        if !is_item && name.is_none() {
+            comment_start = None;
+            continue;
+        }
+
+        // an item at the indentation of a key is still one of its items.
+        while let Some((top_indent, top_is_item, _)) = stack.last() {
+            if *top_indent > indent
+                || (*top_indent == indent && (is_item == *top_is_item || !is_item))
+            {
+                stack.pop();
+            } else {
+                break;
+            }
+        }
+        let parent = stack.last().map(|(_, _, key)| key);
+        let depth = parent.map_or(1, |key| key.depth + 1);
+        let parent_path = parent.map_or(String::new(), |key| key.path.clone());
+        let path = if is_item {
+            let index = match items.iter_mut().find(|(path, _)| *path == parent_path) {
+                Some((_, count)) => {
+                    *count += 1;
+                    *count - 1
+                }
+                None => {
+                    items.push((parent_path.clone(), 1));
+                    0
+                }
+            };
+            format!("{}[{}]", parent_path, index)
+        } else {
+            join_path(&parent_path, name.unwrap_or_default())
+        };
+
+        let key = Key {
+            start: comment_start.take().unwrap_or(start),
+            depth,
+            path,
+        };
+        keys.push(key.clone());
+        stack.push((indent, is_item, key));
+
+        let value = rest.split_once(':').map_or("", |(_, value)| value.trim());
+        if value.starts_with('|') || value.starts_with('>') {
+            block_indent = Some(indent);
+        }
+    }
+    keys
+}
+
+// `key:` or `key: value`, with the key optionally quoted.
+fn yaml_key_name(line: &str) -> Option<&str> {
+    if let Some(quote) = line.chars().next().filter(|c| *c == '"' || *c == '\'') {
+        let end = line[1..].find(quote)? + 1;
+        let after = &line[end + 1..];
+        return (after.starts_with(':') && is_yaml_value_start(&after[1..])).then(|| &line[1..end]);
+    }
+    if line.starts_with(['#', '[', '{', '&', '*', '!', '|', '>']) {
+        return None;
+    }
+    let colon = line
+        .match_indices(':')
+        .map(|(i, _)| i)
+        .find(|i| is_yaml_value_start(&line[i + 1..]))?;
+    let key = line[..colon].trim_end();
+    (!key.is_empty() && !key.contains(" #")).then_some(key)
+}
+
+fn is_yaml_value_start(rest: &str) -> bool {
+    rest.is_empty() || rest.starts_with(' ') || rest.starts_with('\t')
+}
+
+// Tables are top level keys, `[[array]]` tables are `array[n]`. The keys of a table are below it,
+// the keys before the first table are top level.
+fn toml_keys(src: &str) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut table: Option<Key> = None;
+    let mut arrays: Vec<(String, usize)> = Vec::new();
+    let mut comment_start = None;
+    // open brackets of a multi-line value, or inside a multi-line string.
+    let mut open_brackets = 0i32;
+    let mut in_string: Option<&str> = None;
+
+    for (start, line) in lines(src) {
+        let trimmed = line.trim();
+        if let Some(delimiter) = in_string {
+            if trimmed.matches(delimiter).count() % 2 == 1 {
+                in_string = None;
+            }
+            continue;
+        }
+        if open_brackets > 0 {
+            open_brackets += bracket_balance(trimmed);
+            continue;
+        }
+        if trimmed.is_empty() {
+            comment_start = None;
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            comment_start.get_or_insert(start);
+            continue;
+        }
+
+        let key_start = comment_start.take().unwrap_or(start);
+        if let Some(header) = trimmed.strip_prefix("[[") {
+            let name = header
+                .split("]]")
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            let index = match arrays.iter_mut().find(|(array, _)| *array == name) {
+                Some((_, count)) => {
+                    *count += 1;
+                    *count - 1
+                }
+                None => {
+                    arrays.push((name.clone(), 1));
+                    0
+                }
+            };
+            let key = Key {
+                start: key_start,
+                depth: 1,
+                path: format!("{}[{}]", name, index),
+            };
+            keys.push(key.clone());
+            table = Some(key);
+        } else if let Some(header) = trimmed.strip_prefix('[') {
+            let key = Key {
+                start: key_start,
+                depth: 1,
+                path: header
+                    .split(']')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            };
+            keys.push(key.clone());
+            table = Some(key);
+        } else if let Some((name, value)) = trimmed.split_once('=') {
+            let name = name.trim().trim_matches(['"', '\'']);
+            keys.push(Key {
+                start: key_start,
+                depth: table.as_ref().map_or(1, |table| table.depth + 1),
+                path: join_path(table.as_ref().map_or("", |table| table.path.as_str()), name),
+            });
+
+            let value = value.trim();
+            for delimiter in ["\"\"\"", "'''"] {
+                if value.matches(delimiter).count() % 2 == 1 {
+                    in_string = Some(delimiter);
+                }
+            }
+            if in_string.is_none() {
+                open_brackets = bracket_balance(value);
+            }
+        }
+    }
+    keys
+}
+
+// `[` and `{` opened minus closed on the line, outside of strings and comments.
+fn bracket_balance(line: &str) -> i32 {
+    let mut balance = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => break,
+            (None, '[' | '{') => balance += 1,
+            (None, ']' | '}') => balance -= 1,
+            (None, _) => {}
+        }
+        escaped = false;
+    }
+    balance
+}
+
+// Object keys and array items, only the first on a line since the chunks are made of whole lines.
+fn json_keys(src: &str) -> Vec<Key> {
+    enum Container {
+        Object { path: String, expecting_key: bool },
+        Array { path: String, items: usize },
+    }
+
+    let bytes = src.as_bytes();
+    let mut stack: Vec<Container> = Vec::new();
+    let mut keys: Vec<Key> = Vec::new();
+    let mut i = 0;
+
+    let line_start = |i: usize| src[..i].rfind('\n').map_or(0, |nl| nl + 1);
+    let push_key = |keys: &mut Vec<Key>, at: usize, depth: usize, path: String| {
+        let start = line_start(at);
+        if keys.last().map_or(true, |last| last.start < start) {
+            keys.push(Key { start, depth, path });
+        }
+    };
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() || c == b':' {
+            i += 1;
+            continue;
+        }
+
+        // an array item starts with any value.
+        let depth = stack.len();
+        if let Some(Container::Array { path, items }) = stack.last_mut() {
+            if c != b']' && c != b',' {
+                let item_path = format!("{}[{}]", path, items);
+                *items += 1;
+                // the path is only needed for the containers below the item.
+                if c == b'{' || c == b'[' {
+                    i += 1;
+                    push_key(&mut keys, i - 1, depth, item_path.clone());
+                    stack.push(if c == b'{' {
+                        Container::Object {
+                            path: item_path,
+                            expecting_key: true,
+                        }
+                    } else {
+                        Container::Array {
+                            path: item_path,
+                            items: 0,
+                        }
+                    });
+                    continue;
+                }
+                push_key(&mut keys, i, depth, item_path);
+            }
+        }
+
+        match c {
+            b'"' => {
+                let end = string_end(bytes, i);
+                if let Some(Container::Object {
+                    path,
+                    expecting_key,
+                }) = stack.last_mut()
+                {
+                    if *expecting_key {
+                        *expecting_key = false;
+                        let name = serde_json::from_str::<String>(&src[i..end])
+                            .unwrap_or_else(|_| src[i + 1..end - 1].to_string());
+                        let key_path = join_path(path, &name);
+                        let at = i;
+                        // the value of the key is a container under its path.
+                        let mut j = end;
+                        while j < bytes.len()
+                            && (bytes[j].is_ascii_whitespace() || bytes[j] == b':')
+                        {
+                            j += 1;
+                        }
+                        push_key(&mut keys, at, depth, key_path.clone());
+                        match bytes.get(j) {
+                            Some(b'{') => {
+                                stack.push(Container::Object {
+                                    path: key_path,
+                                    expecting_key: true,
+                                });
+                                i = j + 1;
+                                continue;
+                            }
+                            Some(b'[') => {
+                                stack.push(Container::Array {
+                                    path: key_path,
+                                    items: 0,
+                                });
+                                i = j + 1;
+                                continue;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                i = end;
+            }
+            b'{' => {
+                stack.push(Container::Object {
+                    path: String::new(),
+                    expecting_key: true,
+                });
+                i += 1;
+            }
+            b'[' => {
+                stack.push(Container::Array {
+                    path: String::new(),
+                    items: 0,
+                });
+                i += 1;
+            }
+            b'}' | b']' => {
+                stack.pop();
+                i += 1;
+            }
+            b',' => {
+                if let Some(Container::Object { expecting_key, .. }) = stack.last_mut() {
+                    *expecting_key = true;
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    keys
+}
+
+// the byte after the closing quote of the string starting at `start`.
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+// (byte offset, line without its line break) of every line.
+fn lines(src: &str) -> impl Iterator<Item = (usize, &str)> {
+    src.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line.trim_end_matches(['\n', '\r'])))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFESTS: &str = "\
+# the api and its service
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: api
+  labels:
+    app: api
+spec:
+  replicas: 3
+  selector:
+    matchLabels:
+      app: api
+  template:
+    metadata:
+      labels:
+        app: api
+    spec:
+      containers:
+        - name: api
+          image: registry.example.com/api:1.4.2
+          args:
+            - --port=8080
+            - --log-level=info
+          ports:
+            - containerPort: 8080
+          env:
+            - name: DATABASE_URL
+              valueFrom:
+                secretKeyRef:
+                  name: api-secrets
+                  key: database-url
+      volumes:
+        - name: config
+          configMap:
+            name: api-config
+---
+apiVersion: v1
+kind: Service
+metadata:
+  name: api
+spec:
+  selector:
+    app: api
+  ports:
+    - port: 80
+      targetPort: 8080
+";
+
+    // a made up Cargo.toml, large enough for the dependencies to need a few chunks.
+    fn cargo_toml() -> String {
+        let mut toml = String::from(
+            "\
+[package]
+name = \"server\"
+version = \"0.4.0\"
+edition = \"2021\"
+description = \"\"\"
+An HTTP server
+with a multi-line description.
+\"\"\"
+
+[features]
+default = [\"tls\"]
+tls = [
+    \"dep:rustls\",
+    \"dep:webpki-roots\",
+]
+
+# the runtime and the http stack
+[dependencies]
+",
+        );
+        for i in 0..24 {
+            toml.push_str(&format!(
+                "crate-{i} = {{ version = \"1.{i}\", features = [\"full\"] }}\n"
+            ));
+        }
+        toml.push_str("\n[dev-dependencies]\ntempfile = \"3\"\n\n[[bin]]\nname = \"server\"\n\n[[bin]]\nname = \"migrate\"\n");
+        toml
+    }
+
+    fn fits_lines(max_lines: usize) -> impl Fn(&str) -> bool {
+        move |text| text.lines().count() <= max_lines
+    }
+
+    // the first line of every chunk, with its key path.
+    fn boundaries<'a>(src: &'a str, chunks: &'a [ConfigChunk]) -> Vec<(&'a str, Option<&'a str>)> {
+        chunks
+            .iter()
+            .map(|chunk| {
+                let first_line = src[chunk.range.clone()].lines().next().unwrap_or_default();
+                (first_line, chunk.key_path.as_deref())
+            })
+            .collect()
+    }
+
+    fn assert_contiguous(src: &str, chunks: &[ConfigChunk]) {
+        for pair in chunks.windows(2) {
+            assert!(
+                src[pair[0].range.end..pair[1].range.start]
+                    .trim()
+                    .is_empty(),
+                "gap between {:?} and {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_languages() {
+        assert_eq!(config_language(Path::new("deploy/api.yml")), Some("YAML"));
+        assert_eq!(config_language(Path::new("Cargo.toml")), Some("TOML"));
+        assert_eq!(config_language(Path::new("package.JSON")), Some("JSON"));
+        assert_eq!(config_language(Path::new("src/main.rs")), None);
+        assert!(is_config_language("yaml"));
+    }
+
+    #[test]
+    fn test_yaml_documents_are_split_on_keys() {
+        let chunks = config_chunks(MANIFESTS, "YAML", fits_lines(12)).unwrap();
+
+        assert_eq!(
+            boundaries(MANIFESTS, &chunks),
+            vec![
+                ("# the api and its service", None),
+                ("spec:", Some("spec")),
+                ("  template:", Some("spec.template.metadata")),
+                ("    spec:", Some("spec.template.spec.containers[0]")),
+                (
+                    "          env:",
+                    Some("spec.template.spec.containers[0].env")
+                ),
+                ("      volumes:", Some("spec.template.spec.volumes")),
+                ("---", None),
+            ]
+        );
+        assert!(chunks.iter().all(|chunk| !chunk.oversized));
+        assert_contiguous(MANIFESTS, &chunks);
+        // the second document starts on its marker, no chunk crosses it.
+        let marker = MANIFESTS.find("---").unwrap();
+        assert!(chunks
+            .iter()
+            .all(|c| c.range.end <= marker || c.range.start >= marker));
+    }
+
+    #[test]
+    fn test_cargo_toml_is_split_on_tables_and_keys() {
+        let src = cargo_toml();
+        let chunks = config_chunks(&src, "TOML", fits_lines(10)).unwrap();
+
+        assert_eq!(
+            boundaries(&src, &chunks),
+            vec![
+                ("[package]", Some("package")),
+                ("[features]", Some("features")),
+                ("# the runtime and the http stack", Some("dependencies")),
+                (
+                    "crate-8 = { version = \"1.8\", features = [\"full\"] }",
+                    Some("dependencies")
+                ),
+                (
+                    "crate-18 = { version = \"1.18\", features = [\"full\"] }",
+                    Some("dependencies")
+                ),
+                ("[dev-dependencies]", None),
+            ]
+        );
+        assert_contiguous(&src, &chunks);
+        // every chunk starts on a key of the file.
+        let keys = toml_keys(&src);
+        assert!(chunks
+            .iter()
+            .all(|c| keys.iter().any(|k| k.start == c.range.start) || c.range.start == 0));
+        // the multi-line string and array aren't taken for keys.
+        assert!(!keys.iter().any(|k| k.path.contains("with a multi-line")));
+        assert_eq!(
+            keys.iter()
+                .filter(|k| k.path.starts_with("features."))
+                .count(),
+            2
+        );
+        assert_eq!(keys.last().unwrap().path, "bin[1].name");
+    }
+
+    #[test]
+    fn test_json_keys() {
+        let src = "{\n  \"name\": \"web\",\n  \"scripts\": {\n    \"build\": \"vite build\",\n    \"test\": \"vitest\"\n  },\n  \"files\": [\n    \"dist\",\n    {\"src\": \"lib\"}\n  ]\n}\n";
+        let paths = json_keys(src)
+            .into_iter()
+            .map(|key| (key.depth, key.path))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            paths,
+            vec![
+                (1, "name".to_string()),
+                (1, "scripts".to_string()),
+                (2, "scripts.build".to_string()),
+                (2, "scripts.test".to_string()),
+                (1, "files".to_string()),
+                (2, "files[0]".to_string()),
+                (2, "files[1]".to_string()),
+            ]
+        );
+        let chunks = config_chunks(src, "JSON", fits_lines(6)).unwrap();
+        assert_eq!(chunks[0].key_path, None);
+        assert_eq!(chunks[1].key_path, Some("files".to_string()));
+    }
+
+    #[test]
+    fn test_unparsable_files_and_leaves_too_large() {
+        assert_eq!(
+            config_chunks("key: [unclosed", "YAML", fits_lines(10)),
+            None
+        );
+        assert_eq!(
+            config_chunks("[package\nname = 1", "TOML", fits_lines(10)),
+            None
+        );
+
+        let src = "description: |\n  one\n  two\n  three\n  four\n";
+        let chunks = config_chunks(src, "YAML", fits_lines(2)).unwrap();
+        assert_eq!(
+            chunks,
+            vec![ConfigChunk {
+                range: 0..src.len(),
+                key_path: Some("description".to_string()),
+                oversized: true,
+            }]
+        );
+    }
+}
@@ -1,22 +1,29 @@
 use std::collections::HashMap;
 use qdrant_client::prelude::Value;
+use qdrant_client::qdrant::value::Kind;
 
+use common::branch::BRANCH_FIELD;
+use common::compression::TextCompression;
+use common::embedded_lang::EMBEDDED_LANG_FIELD;
+use common::symbol_payload::{FlatOccurrences, SymbolOccurrence};
 use common::tokenizer_onnx::Embedding;
 
+use crate::hash::{ID_SCHEME_FIELD, POINT_ID_SCHEME};
+
 // Payload format to write and deserialize data in and from qdrant.
 #[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct SymbolPayload {
 
     pub repo_name: String,
     pub symbol: String,
-
-    pub symbol_types: Vec<String>,
-    pub lang_ids: Vec<String>,
-    pub is_globals: Vec<bool>, 
-    pub start_bytes: Vec<i64>,
-    pub end_bytes: Vec<i64>,
-    pub relative_paths: Vec<String>,
-    pub node_kinds: Vec<String>,
+    // the branch the occurrences are on, see `common::branch`.
+    #[serde(default)]
+    pub branch: String,
+    // written as the parallel columns of the payload, see `common::symbol_payload`.
+    #[serde(flatten, with = "common::symbol_payload::flat")]
+    pub occurrences: Vec<SymbolOccurrence>,
+    // occurrences left out of the payload once the per symbol limit is reached.
+    pub overflow_count: i64,
 
     #[serde(skip)]
     pub id: Option<String>,
@@ -28,19 +35,77 @@ pub struct SymbolPayload {
 
 impl SymbolPayload {
     pub fn convert_to_qdrant_fields(self) -> HashMap<String, Value> {
+        // lowercased copy of the name for case-insensitive exact lookups.
+        let symbol_lower = self.symbol.to_lowercase();
+        let flat = FlatOccurrences::from(self.occurrences.as_slice());
         HashMap::from([
             ("repo_name".into(), self.repo_name.into()),
             ("symbol".into(), self.symbol.into()),
+            ("symbol_lower".into(), symbol_lower.into()),
+            (BRANCH_FIELD.into(), self.branch.into()),
 
-            ("lang".into(), self.lang_ids.into()),
-            ("symbol_type".into(), self.symbol_types.into()),
-            ("start_byte".into(), self.start_bytes.into()),
-            ("end_byte".into(), self.end_bytes.into()),
-            ("relative_path".into(), self.relative_paths.into()),
-            ("node_kind".into(), self.node_kinds.into()),
-            ("is_global".into(), self.is_globals.into()),
+            ("lang".into(), flat.lang.into()),
+            ("symbol_type".into(), flat.symbol_type.into()),
+            ("start_byte".into(), flat.start_byte.into()),
+            ("end_byte".into(), flat.end_byte.into()),
+            ("relative_path".into(), flat.relative_path.into()),
+            ("node_kind".into(), flat.node_kind.into()),
+            ("container_path".into(), flat.container_path.into()),
+            ("is_global".into(), flat.is_global.into()),
+            ("overflow_count".into(), self.overflow_count.into()),
+            (ID_SCHEME_FIELD.into(), POINT_ID_SCHEME.into()),
         ])
     }
+
+    // Reads back the payload of a symbol point, the fields added next to the columns are ignored.
+    pub fn from_qdrant_fields(payload: HashMap<String, Value>) -> anyhow::Result<Self> {
+        let fields = payload
+            .into_iter()
+            .map(|(key, value)| (key, kind_to_value(value.kind)))
+            .collect::<serde_json::Map<_, _>>();
+        Ok(serde_json::from_value(serde_json::Value::Object(fields))?)
+    }
+}
+
+fn kind_to_value(kind: Option<Kind>) -> serde_json::Value {
+    match kind {
+        Some(Kind::NullValue(_)) | None => serde_json::Value::Null,
+        Some(Kind::BoolValue(v)) => serde_json::Value::Bool(v),
+        Some(Kind::DoubleValue(v)) => serde_json::Number::from_f64(v)
+            .map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Some(Kind::IntegerValue(v)) => serde_json::Value::Number(v.into()),
+        Some(Kind::StringValue(v)) => serde_json::Value::String(v),
+        Some(Kind::ListValue(v)) => serde_json::Value::Array(
+            v.values
+                .into_iter()
+                .map(|v| kind_to_value(v.kind))
+                .collect(),
+        ),
+        Some(Kind::StructValue(v)) => serde_json::Value::Object(
+            v.fields
+                .into_iter()
+                .map(|(key, value)| (key, kind_to_value(value.kind)))
+                .collect(),
+        ),
+    }
+}
+
+// Whether a point of the chunk collection embeds code or the doc comment of a definition.
+#[derive(Default, Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkKind {
+    #[default]
+    Code,
+    Doc,
+}
+
+impl ChunkKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChunkKind::Code => "code",
+            ChunkKind::Doc => "doc",
+        }
+    }
 }
 
 #[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -56,6 +121,27 @@ pub struct Payload {
     pub start_byte: u64,
     pub end_byte: u64,
     pub branches: Vec<String>,
+    pub kind: ChunkKind,
+    // doc comments of the definitions starting in the chunk, the documented symbol of a doc chunk.
+    pub doc: Option<String>,
+    pub symbol: Option<String>,
+    // the keys a chunk of a config file is under, e.g. `spec.template.containers`.
+    pub key_path: Option<String>,
+    // the language written inside the chunk, e.g. `sql` for a query in a string, see
+    // `common::embedded_lang`.
+    #[serde(default)]
+    pub embedded_lang: Option<String>,
+    // test and vendored code, demoted by code search, see `common::path_class`.
+    #[serde(default)]
+    pub is_test: bool,
+    #[serde(default)]
+    pub is_vendored: bool,
+    // the branch the file is on, see `common::branch`.
+    #[serde(default)]
+    pub branch: String,
+    // unix seconds of the last commit changing the file, for the recency boost of code search.
+    #[serde(default)]
+    pub last_modified: Option<u64>,
 
     #[serde(skip)]
     pub id: Option<String>,
@@ -66,18 +152,45 @@ pub struct Payload {
 }
 
 impl Payload {
-    pub fn convert_to_qdrant_fields(self) -> HashMap<String, Value> {
-        HashMap::from([
+    // The text is written as `snippet`, or compressed into `text_z` depending on `compression`.
+    pub fn convert_to_qdrant_fields(
+        self,
+        compression: TextCompression,
+    ) -> anyhow::Result<HashMap<String, Value>> {
+        let mut fields = HashMap::from([
             ("lang".into(), self.lang.to_ascii_lowercase().into()),
             ("repo_name".into(), self.repo_name.into()),
             ("relative_path".into(), self.relative_path.into()),
+            (BRANCH_FIELD.into(), self.branch.into()),
             ("content_hash".into(), self.content_hash.into()),
-            ("snippet".into(), self.text.into()),
             ("start_line".into(), self.start_line.to_string().into()),
             ("end_line".into(), self.end_line.to_string().into()),
             ("start_byte".into(), self.start_byte.to_string().into()),
             ("end_byte".into(), self.end_byte.to_string().into()),
-        ])
+            ("kind".into(), self.kind.as_str().into()),
+            ("is_test".into(), self.is_test.into()),
+            ("is_vendored".into(), self.is_vendored.into()),
+            (ID_SCHEME_FIELD.into(), POINT_ID_SCHEME.into()),
+        ]);
+        for (field, value) in compression.text_fields(&self.text)? {
+            fields.insert(field.into(), value.into());
+        }
+        if let Some(doc) = self.doc {
+            fields.insert("doc".into(), doc.into());
+        }
+        if let Some(symbol) = self.symbol {
+            fields.insert("symbol".into(), symbol.into());
+        }
+        if let Some(key_path) = self.key_path {
+            fields.insert("key_path".into(), key_path.into());
+        }
+        if let Some(embedded_lang) = self.embedded_lang {
+            fields.insert(EMBEDDED_LANG_FIELD.into(), embedded_lang.into());
+        }
+        if let Some(last_modified) = self.last_modified {
+            fields.insert("last_modified".into(), (last_modified as i64).into());
+        }
+        Ok(fields)
     }
 }
 impl PartialEq for Payload {
@@ -93,6 +206,15 @@ impl PartialEq for Payload {
             && self.start_byte == other.start_byte
             && self.end_byte == other.end_byte
             && self.branches == other.branches
+            && self.branch == other.branch
+            && self.kind == other.kind
+            && self.doc == other.doc
+            && self.symbol == other.symbol
+            && self.key_path == other.key_path
+            && self.embedded_lang == other.embedded_lang
+            && self.is_test == other.is_test
+            && self.is_vendored == other.is_vendored
+            && self.last_modified == other.last_modified
         // ignoring deserialized fields that will not exist on a newly
         // created payload
     }
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::str::FromStr;
+
+use globset::{Glob, GlobMatcher};
+
+pub const AVG_LINE_LEN: u64 = 30;
+pub const DEFAULT_MAX_LINE_COUNT: usize = 20000;
+pub const DEFAULT_MAX_FILE_BYTES: u64 = AVG_LINE_LEN * DEFAULT_MAX_LINE_COUNT as u64;
+
+/// Size limits a file has to fit in to be indexed.
+/// Overrides are checked in order and the first glob matching the path wins,
+/// e.g. to allow large generated API clients that are still worth querying about.
+#[derive(Debug, Clone)]
+pub struct SizeLimits {
+    pub max_file_bytes: u64,
+    pub max_lines: usize,
+    pub overrides: Vec<SizeLimitOverride>,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: DEFAULT_MAX_FILE_BYTES,
+            max_lines: DEFAULT_MAX_LINE_COUNT,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+/// Custom limits for the paths matching a glob, parsed from `<glob>=<max_file_bytes>:<max_lines>`.
+#[derive(Debug, Clone)]
+pub struct SizeLimitOverride {
+    pub pattern: String,
+    matcher: GlobMatcher,
+    pub max_file_bytes: u64,
+    pub max_lines: usize,
+}
+
+impl FromStr for SizeLimitOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid size limit override: {}, expected <glob>=<max_file_bytes>:<max_lines>",
+                s
+            )
+        };
+        let (pattern, limits) = s.trim().rsplit_once('=').ok_or_else(invalid)?;
+        let (max_file_bytes, max_lines) = limits.split_once(':').ok_or_else(invalid)?;
+        let matcher = Glob::new(pattern)
+            .map_err(|e| format!("Invalid glob in size limit override {}: {}", s, e))?
+            .compile_matcher();
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            matcher,
+            max_file_bytes: max_file_bytes.trim().parse().map_err(|_| invalid())?,
+            max_lines: max_lines.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A file left out of the index because it's over the size limits, kept for the traversal report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedForSize {
+    pub path: String,
+    pub bytes: u64,
+    // only counted when the file is within the byte limit.
+    pub lines: Option<usize>,
+    pub max_file_bytes: u64,
+    pub max_lines: usize,
+}
+
+impl fmt::Display for SkippedForSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.lines {
+            Some(lines) => write!(
+                f,
+                "{}: {} lines (limit {})",
+                self.path, lines, self.max_lines
+            ),
+            None => write!(
+                f,
+                "{}: {} bytes (limit {})",
+                self.path, self.bytes, self.max_file_bytes
+            ),
+        }
+    }
+}
+
+impl SizeLimits {
+    /// Returns the byte and line limits that apply to the given path.
+    pub fn for_path(&self, path: &str) -> (u64, usize) {
+        self.overrides
+            .iter()
+            .find(|o| o.matcher.is_match(path))
+            .map(|o| (o.max_file_bytes, o.max_lines))
+            .unwrap_or((self.max_file_bytes, self.max_lines))
+    }
+
+    /// Checks the raw content of a file against its limits.
+    /// Only counts newlines, so it's cheap enough to run before decoding or parsing the file.
+    pub fn check(&self, path: &str, content: &[u8]) -> Result<(), SkippedForSize> {
+        let (max_file_bytes, max_lines) = self.for_path(path);
+        let skipped = |lines| SkippedForSize {
+            path: path.to_string(),
+            bytes: content.len() as u64,
+            lines,
+            max_file_bytes,
+            max_lines,
+        };
+
+        if content.len() as u64 > max_file_bytes {
+            return Err(skipped(None));
+        }
+
+        // a missing trailing newline is added before indexing, so the last line counts too.
+        let lines = content.iter().filter(|&&b| b == b'\n').count()
+            + usize::from(!content.is_empty() && !content.ends_with(b"\n"));
+        if lines > max_lines {
+            return Err(skipped(Some(lines)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::BUILD_AST_CALLS;
+    use crate::{process_file_content, SkipReason};
+    use common::run_manifest::IndexMode;
+    use std::path::Path;
+
+    fn limits(overrides: &[&str]) -> SizeLimits {
+        SizeLimits {
+            max_file_bytes: 1000,
+            max_lines: 10,
+            overrides: overrides.iter().map(|o| o.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_check_bytes_before_lines() {
+        let limits = limits(&[]);
+        let skipped = limits.check("src/big.rs", &[b'a'; 1001]).unwrap_err();
+        assert_eq!(skipped.bytes, 1001);
+        assert_eq!(skipped.lines, None);
+
+        let skipped = limits.check("src/long.rs", "a\n".repeat(11).as_bytes()).unwrap_err();
+        assert_eq!(skipped.lines, Some(11));
+
+        assert!(limits.check("src/ok.rs", "a\n".repeat(9).as_bytes()).is_ok());
+        // without a trailing newline the last line still counts.
+        assert!(limits.check("src/ok.rs", "a\n".repeat(10).trim_end().as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_first_matching_override_wins() {
+        let limits = limits(&["clients/generated/**=100000:5000", "clients/**=2000:20"]);
+        assert_eq!(limits.for_path("clients/generated/api.ts"), (100000, 5000));
+        assert_eq!(limits.for_path("clients/http.ts"), (2000, 20));
+        assert_eq!(limits.for_path("src/main.rs"), (1000, 10));
+        assert!(limits
+            .check("clients/generated/api.ts", "a\n".repeat(500).as_bytes())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_invalid_override() {
+        assert!("clients/**".parse::<SizeLimitOverride>().is_err());
+        assert!("clients/**=100".parse::<SizeLimitOverride>().is_err());
+        assert!("clients/**=big:10".parse::<SizeLimitOverride>().is_err());
+    }
+
+    #[test]
+    fn test_oversized_file_exits_before_ast() {
+        let limits = limits(&[]);
+        let content = "fn main() {}\n".repeat(20);
+        let calls = || BUILD_AST_CALLS.with(|calls| calls.get());
+
+        let before = calls();
+        let result = process_file_content(
+            "src/main.rs",
+            content.as_bytes(),
+            "repo",
+            "/tmp/repo",
+            Path::new("/tmp/repo"),
+            &limits,
+            IndexMode::Full,
+        );
+        assert!(matches!(result, Err(SkipReason::Size(_))));
+        assert_eq!(calls(), before);
+
+        // once the limit is raised for the path, the file is parsed.
+        let limits = SizeLimits {
+            max_lines: 100,
+            ..limits
+        };
+        assert!(process_file_content(
+            "src/main.rs",
+            content.as_bytes(),
+            "repo",
+            "/tmp/repo",
+            Path::new("/tmp/repo"),
+            &limits,
+            IndexMode::Full,
+        )
+        .is_ok());
+        assert_eq!(calls(), before + 1);
+    }
+
+    #[test]
+    fn test_files_within_the_limits_are_parsed_whatever_their_size() {
+        let limits = SizeLimits {
+            max_file_bytes: 2_000_000,
+            max_lines: 100_000,
+            overrides: Vec::new(),
+        };
+        let content = format!("fn main() {{}}\n{}", "// padding\n".repeat(60_000));
+        assert!(content.len() > 600_000);
+
+        let processed = process_file_content(
+            "src/main.rs",
+            content.as_bytes(),
+            "repo",
+            "/tmp/repo",
+            Path::new("/tmp/repo"),
+            &limits,
+            IndexMode::Full,
+        )
+        .unwrap_or_else(|_| panic!("the file is within the limits"));
+        assert!(processed
+            .symbol_metas
+            .iter()
+            .any(|(key, _)| key.symbol == "main"));
+    }
+}
@@ -0,0 +1,1115 @@
+// Snapshots of the index of a repo, to move an indexed repo to another environment without
+// embedding it again. The archive is a tar of a manifest and JSONL segments: the chunk and symbol
+// points of the repo with their vectors and payloads, and its quickwit documents, the run
+// manifests among them. The manifest comes first so an import checks it before loading anything.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use common::clock::unix_now;
+use common::run_manifest::{EmbeddingInfo, RunManifest, RUN_MANIFEST_PATH};
+use common::{metrics, telemetry};
+use qdrant_client::prelude::QdrantClient;
+use qdrant_client::qdrant::{
+    value::Kind, vectors::VectorsOptions, with_payload_selector, with_vectors_selector,
+    CountPoints, Filter, ListValue, PointId, PointStruct, RetrievedPoint, ScrollPoints, Struct,
+    Value, WithPayloadSelector, WithVectorsSelector,
+};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::index_processor;
+use crate::migrate::{MigrationStore, Offset, MIGRATED_COLLECTIONS};
+use crate::watch::make_kv_keyword_filter;
+use crate::{COLLECTION_NAME, COLLECTION_NAME_SYMBOLS};
+
+/// Version of the archive layout, an archive of another version can't be imported.
+pub const SNAPSHOT_SCHEMA_VERSION: u32 = 1;
+// Points or documents read and written per step, progress is checkpointed after each of them.
+pub const DEFAULT_SNAPSHOT_PAGE_SIZE: u32 = 256;
+
+const MANIFEST_ENTRY: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    Chunks,
+    Symbols,
+    Documents,
+}
+
+pub const SEGMENTS: [SegmentKind; 3] = [
+    SegmentKind::Chunks,
+    SegmentKind::Symbols,
+    SegmentKind::Documents,
+];
+
+impl SegmentKind {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            SegmentKind::Chunks => "chunks.jsonl",
+            SegmentKind::Symbols => "symbols.jsonl",
+            SegmentKind::Documents => "documents.jsonl",
+        }
+    }
+
+    // alias of the collection of the points, None for the quickwit documents.
+    fn collection(self) -> Option<&'static str> {
+        match self {
+            SegmentKind::Chunks => Some(COLLECTION_NAME),
+            SegmentKind::Symbols => Some(COLLECTION_NAME_SYMBOLS),
+            SegmentKind::Documents => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SegmentManifest {
+    pub kind: SegmentKind,
+    pub file: String,
+    // lines of the segment, one point or document each.
+    pub count: u64,
+}
+
+/// First entry of the archive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub schema_version: u32,
+    pub snapshot_id: String,
+    pub repo_name: String,
+    pub exported_at: u64,
+    pub indexer_version: String,
+    // the model the vectors were embedded with, the importing environment must use the same.
+    pub embedding: EmbeddingInfo,
+    // the run manifests of the exported branches, as they were indexed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runs: Vec<RunManifest>,
+    pub segments: Vec<SegmentManifest>,
+}
+
+/// A point of the chunk or symbol collection, a line of its segment.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotPoint {
+    pub id: Offset,
+    pub vector: Vec<f32>,
+    pub payload: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Qdrant and quickwit operations of the export and import, implemented by the qdrant client with
+/// the quickwit of the config and mocked in tests.
+#[async_trait]
+pub trait SnapshotStore: Send + Sync {
+    /// Maps every alias to the collection it points to.
+    async fn aliases(&self) -> anyhow::Result<HashMap<String, String>>;
+    /// One page of the points of the repo with their payload and vector, along with the offset of
+    /// the next page.
+    async fn scroll_repo(
+        &self,
+        collection: &str,
+        repo_name: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)>;
+    async fn count_repo(&self, collection: &str, repo_name: &str) -> anyhow::Result<u64>;
+    async fn ensure_collection(
+        &self,
+        collection: &str,
+        dimension: u64,
+        indexes: &[&str],
+    ) -> anyhow::Result<()>;
+    /// Returns once the points are written, so the checkpoint never gets ahead of the data.
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()>;
+    /// A page of the quickwit documents of the repo, along with the number of documents.
+    async fn documents(
+        &self,
+        repo_name: &str,
+        offset: u64,
+        limit: u32,
+    ) -> anyhow::Result<(u64, Vec<serde_json::Value>)>;
+    /// Creates the quickwit index of the repo when it doesn't exist, checks its schema otherwise.
+    async fn ensure_document_index(&self, repo_name: &str) -> anyhow::Result<()>;
+    /// Returns once the documents are committed.
+    async fn ingest_documents(
+        &self,
+        repo_name: &str,
+        documents: Vec<serde_json::Value>,
+    ) -> anyhow::Result<()>;
+}
+
+fn repo_filter(repo_name: &str) -> Filter {
+    Filter {
+        must: vec![make_kv_keyword_filter("repo_name", repo_name).into()],
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for QdrantClient {
+    async fn aliases(&self) -> anyhow::Result<HashMap<String, String>> {
+        MigrationStore::aliases(self).await
+    }
+
+    async fn scroll_repo(
+        &self,
+        collection: &str,
+        repo_name: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)> {
+        let start = Instant::now();
+        let response = QdrantClient::scroll(
+            self,
+            &ScrollPoints {
+                collection_name: collection.to_string(),
+                filter: Some(repo_filter(repo_name)),
+                offset,
+                limit: Some(limit),
+                with_payload: Some(WithPayloadSelector {
+                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+                }),
+                with_vectors: Some(WithVectorsSelector {
+                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+                }),
+                ..Default::default()
+            },
+        )
+        .instrument(telemetry::db_span("qdrant", "scroll"))
+        .await?;
+        metrics::observe_db_query("qdrant", "scroll", start.elapsed());
+        Ok((response.result, response.next_page_offset))
+    }
+
+    async fn count_repo(&self, collection: &str, repo_name: &str) -> anyhow::Result<u64> {
+        let response = QdrantClient::count(
+            self,
+            &CountPoints {
+                collection_name: collection.to_string(),
+                filter: Some(repo_filter(repo_name)),
+                exact: Some(true),
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(response.result.map(|result| result.count).unwrap_or(0))
+    }
+
+    async fn ensure_collection(
+        &self,
+        collection: &str,
+        dimension: u64,
+        indexes: &[&str],
+    ) -> anyhow::Result<()> {
+        MigrationStore::ensure_collection(self, collection, dimension, indexes).await
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+        MigrationStore::upsert(self, collection, points).await
+    }
+
+    async fn documents(
+        &self,
+        repo_name: &str,
+        offset: u64,
+        limit: u32,
+    ) -> anyhow::Result<(u64, Vec<serde_json::Value>)> {
+        index_processor::search_documents(repo_name, offset, limit).await
+    }
+
+    async fn ensure_document_index(&self, repo_name: &str) -> anyhow::Result<()> {
+        let index_id = common::hasher::generate_quikwit_index_name(repo_name);
+        index_processor::ensure_index(&crate::config::get_quickwit_url(), &index_id).await?;
+        Ok(())
+    }
+
+    async fn ingest_documents(
+        &self,
+        repo_name: &str,
+        documents: Vec<serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        index_processor::ingest_documents(repo_name, &documents).await
+    }
+}
+
+pub struct ExportOptions {
+    pub repo_name: String,
+    pub archive: PathBuf,
+    pub page_size: u32,
+    // the model of this environment, recorded when the index has no run manifest.
+    pub embedding: EmbeddingInfo,
+}
+
+/// Writes the points and documents of the repo to a snapshot archive.
+pub async fn export_index<S: SnapshotStore>(
+    store: &S,
+    options: &ExportOptions,
+) -> anyhow::Result<SnapshotManifest> {
+    let repo_name = options.repo_name.as_str();
+    // the segments are written next to the archive first, their counts go in the manifest.
+    let parts = options.archive.with_extension("parts");
+    std::fs::create_dir_all(&parts).with_context(|| format!("Failed to create {:?}", parts))?;
+
+    let aliases = store.aliases().await?;
+    let mut segments = Vec::new();
+    let mut runs = Vec::new();
+    for kind in SEGMENTS {
+        let path = parts.join(kind.file_name());
+        let mut writer = BufWriter::new(
+            File::create(&path).with_context(|| format!("Failed to create {:?}", path))?,
+        );
+        let count = match kind.collection() {
+            Some(alias) => {
+                let collection = aliases.get(alias).map(String::as_str).unwrap_or(alias);
+                export_points(store, collection, repo_name, options.page_size, &mut writer).await?
+            }
+            None => {
+                export_documents(store, repo_name, options.page_size, &mut writer, &mut runs)
+                    .await?
+            }
+        };
+        writer.flush()?;
+        segments.push(SegmentManifest {
+            kind,
+            file: kind.file_name().to_string(),
+            count,
+        });
+    }
+
+    let manifest = SnapshotManifest {
+        schema_version: SNAPSHOT_SCHEMA_VERSION,
+        snapshot_id: uuid::Uuid::new_v4().to_string(),
+        repo_name: repo_name.to_string(),
+        exported_at: unix_now(),
+        indexer_version: env!("CARGO_PKG_VERSION").to_string(),
+        embedding: snapshot_embedding(&runs, &options.embedding)?,
+        runs,
+        segments,
+    };
+    write_archive(&options.archive, &manifest, &parts)?;
+    std::fs::remove_dir_all(&parts)?;
+    log::info!(
+        "Exported {} to {:?}: {}",
+        repo_name,
+        options.archive,
+        manifest
+            .segments
+            .iter()
+            .map(|segment| format!("{} {}", segment.count, segment.file))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(manifest)
+}
+
+async fn export_points<S: SnapshotStore>(
+    store: &S,
+    collection: &str,
+    repo_name: &str,
+    page_size: u32,
+    writer: &mut impl Write,
+) -> anyhow::Result<u64> {
+    let total = store.count_repo(collection, repo_name).await?;
+    let mut exported = 0;
+    let mut offset = None;
+    loop {
+        let (points, next_offset) = store
+            .scroll_repo(collection, repo_name, offset, page_size)
+            .await?;
+        for point in points {
+            serde_json::to_writer(&mut *writer, &SnapshotPoint::try_from(point)?)?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        log::info!(
+            "Exported {}/{} points of {} from {}",
+            exported,
+            total,
+            repo_name,
+            collection
+        );
+        match next_offset {
+            Some(next) => offset = Some(next),
+            None => return Ok(exported),
+        }
+    }
+}
+
+// The run manifests are indexed as documents, they are kept in the manifest of the snapshot too.
+async fn export_documents<S: SnapshotStore>(
+    store: &S,
+    repo_name: &str,
+    page_size: u32,
+    writer: &mut impl Write,
+    runs: &mut Vec<RunManifest>,
+) -> anyhow::Result<u64> {
+    let mut exported = 0;
+    loop {
+        let (total, documents) = store.documents(repo_name, exported, page_size).await?;
+        if documents.is_empty() {
+            return Ok(exported);
+        }
+        for document in documents {
+            if document["relative_path"] == RUN_MANIFEST_PATH {
+                match document["content"]
+                    .as_str()
+                    .map(serde_json::from_str::<RunManifest>)
+                {
+                    Some(Ok(run)) => runs.push(run),
+                    _ => log::warn!("Skipping an unreadable run manifest of {}", repo_name),
+                }
+            }
+            serde_json::to_writer(&mut *writer, &document)?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        log::info!("Exported {}/{} documents of {}", exported, total, repo_name);
+    }
+}
+
+// The model of the runs that indexed the repo, the one of this environment for an index without
+// run manifests. Runs with different models left vectors that can't be searched together.
+fn snapshot_embedding(
+    runs: &[RunManifest],
+    environment: &EmbeddingInfo,
+) -> anyhow::Result<EmbeddingInfo> {
+    let Some(first) = runs.first() else {
+        return Ok(environment.clone());
+    };
+    if let Some(other) = runs.iter().find(|run| {
+        run.embedding.model_hash != first.embedding.model_hash
+            || run.embedding.dimension != first.embedding.dimension
+    }) {
+        return Err(anyhow!(
+            "The branches {} and {} were indexed with different models, re-embed the index with \
+             `ingestion migrate-embeddings` before exporting it",
+            first.branch,
+            other.branch
+        ));
+    }
+    Ok(first.embedding.clone())
+}
+
+fn write_archive(archive: &Path, manifest: &SnapshotManifest, parts: &Path) -> anyhow::Result<()> {
+    // written to a temporary file first, an interrupted export never leaves a partial archive.
+    let tmp = archive.with_extension("tmp");
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(&tmp)?));
+    let content = serde_json::to_vec_pretty(manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.exported_at);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_ENTRY, content.as_slice())?;
+    for segment in &manifest.segments {
+        builder.append_path_with_name(parts.join(&segment.file), &segment.file)?;
+    }
+    builder.into_inner()?.flush()?;
+    std::fs::rename(&tmp, archive)?;
+    Ok(())
+}
+
+pub struct ImportOptions {
+    pub archive: PathBuf,
+    pub checkpoint_path: PathBuf,
+    pub page_size: u32,
+    // the model of this environment, the snapshot must have been embedded with it.
+    pub embedding: EmbeddingInfo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportReport {
+    pub kind: SegmentKind,
+    // the collection or quickwit index the segment was loaded into.
+    pub target: String,
+    pub imported: u64,
+}
+
+/// How far the import of a snapshot got, enough to resume it after a crash.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ImportCheckpoint {
+    pub snapshot_id: String,
+    // lines of every segment already loaded, keyed by the file of the segment.
+    pub loaded: BTreeMap<String, u64>,
+}
+
+impl ImportCheckpoint {
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read checkpoint {:?}", path))?;
+        let checkpoint = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid checkpoint {:?}", path))?;
+        Ok(Some(checkpoint))
+    }
+
+    // written to a temporary file first, a crash while saving keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Checks that the snapshot can be searched in this environment: the same archive layout and the
+/// model the environment embeds the queries with.
+pub fn validate_manifest(
+    manifest: &SnapshotManifest,
+    environment: &EmbeddingInfo,
+) -> anyhow::Result<()> {
+    if manifest.schema_version != SNAPSHOT_SCHEMA_VERSION {
+        return Err(anyhow!(
+            "The snapshot has the schema version {}, this indexer reads version {}",
+            manifest.schema_version,
+            SNAPSHOT_SCHEMA_VERSION
+        ));
+    }
+    if manifest.embedding.dimension != environment.dimension {
+        return Err(anyhow!(
+            "The snapshot has vectors of dimension {}, this environment embeds with dimension {}",
+            manifest.embedding.dimension,
+            environment.dimension
+        ));
+    }
+    // a model that couldn't be hashed can't be told apart from another one.
+    if manifest.embedding.model_hash.is_empty()
+        || manifest.embedding.model_hash != environment.model_hash
+    {
+        return Err(anyhow!(
+            "The snapshot was embedded with the model {} ({}), this environment uses {} ({}). \
+             Re-embed the source index with this model with `ingestion migrate-embeddings`, \
+             then export it again",
+            manifest.embedding.model,
+            hash_or_unknown(&manifest.embedding.model_hash),
+            environment.model,
+            hash_or_unknown(&environment.model_hash)
+        ));
+    }
+    Ok(())
+}
+
+fn hash_or_unknown(hash: &str) -> &str {
+    match hash.is_empty() {
+        true => "unknown hash",
+        false => hash,
+    }
+}
+
+/// Loads a snapshot archive into the collections the aliases point to and the quickwit index of
+/// the repo, creating them when they don't exist. Progress is saved after every page, running it
+/// again with the same checkpoint resumes where it stopped.
+pub async fn import_index<S: SnapshotStore>(
+    store: &S,
+    options: &ImportOptions,
+) -> anyhow::Result<Vec<ImportReport>> {
+    let file = File::open(&options.archive)
+        .with_context(|| format!("Failed to open {:?}", options.archive))?;
+    let mut archive = tar::Archive::new(BufReader::new(file));
+    let mut entries = archive.entries()?;
+
+    let manifest: SnapshotManifest = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.to_str() != Some(MANIFEST_ENTRY) {
+                return Err(anyhow!(
+                    "{:?} doesn't start with a snapshot manifest",
+                    options.archive
+                ));
+            }
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            serde_json::from_slice(&content)
+                .with_context(|| format!("Invalid snapshot manifest in {:?}", options.archive))?
+        }
+        None => return Err(anyhow!("{:?} is empty", options.archive)),
+    };
+    validate_manifest(&manifest, &options.embedding)?;
+
+    let mut checkpoint = match ImportCheckpoint::load(&options.checkpoint_path)? {
+        Some(checkpoint) if checkpoint.snapshot_id != manifest.snapshot_id => {
+            return Err(anyhow!(
+                "The checkpoint {:?} is of the snapshot {}, not {}. Delete it to import this one",
+                options.checkpoint_path,
+                checkpoint.snapshot_id,
+                manifest.snapshot_id
+            ))
+        }
+        Some(checkpoint) => {
+            log::info!("Resuming the import of snapshot {}", manifest.snapshot_id);
+            checkpoint
+        }
+        None => ImportCheckpoint {
+            snapshot_id: manifest.snapshot_id.clone(),
+            ..Default::default()
+        },
+    };
+
+    let aliases = store.aliases().await?;
+    let mut reports = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let Some(segment) = manifest
+            .segments
+            .iter()
+            .find(|segment| segment.file == name)
+        else {
+            log::warn!(
+                "Skipping {} of {:?}, it isn't in the manifest",
+                name,
+                options.archive
+            );
+            continue;
+        };
+        let target = match segment.kind.collection() {
+            Some(alias) => {
+                let collection = aliases
+                    .get(alias)
+                    .cloned()
+                    .unwrap_or_else(|| alias.to_string());
+                let indexes = MIGRATED_COLLECTIONS
+                    .iter()
+                    .find(|migrated| migrated.alias == alias)
+                    .map(|migrated| migrated.indexes)
+                    .unwrap_or_default();
+                store
+                    .ensure_collection(&collection, manifest.embedding.dimension as u64, indexes)
+                    .await?;
+                collection
+            }
+            None => {
+                store.ensure_document_index(&manifest.repo_name).await?;
+                common::hasher::generate_quikwit_index_name(&manifest.repo_name)
+            }
+        };
+        let imported = import_segment(
+            store,
+            &manifest,
+            segment,
+            &target,
+            entry,
+            options,
+            &mut checkpoint,
+        )
+        .await?;
+        reports.push(ImportReport {
+            kind: segment.kind,
+            target,
+            imported,
+        });
+    }
+
+    if let Some(missing) = manifest
+        .segments
+        .iter()
+        .find(|segment| !reports.iter().any(|report| report.kind == segment.kind))
+    {
+        return Err(anyhow!(
+            "{:?} has no {} segment",
+            options.archive,
+            missing.file
+        ));
+    }
+    // the import is done, a new run starts over.
+    if options.checkpoint_path.exists() {
+        std::fs::remove_file(&options.checkpoint_path)?;
+    }
+    Ok(reports)
+}
+
+async fn import_segment<S: SnapshotStore>(
+    store: &S,
+    manifest: &SnapshotManifest,
+    segment: &SegmentManifest,
+    target: &str,
+    entry: impl Read,
+    options: &ImportOptions,
+    checkpoint: &mut ImportCheckpoint,
+) -> anyhow::Result<u64> {
+    let mut loaded = checkpoint.loaded.get(&segment.file).copied().unwrap_or(0);
+    if loaded > 0 {
+        log::info!(
+            "Skipping the {} lines of {} already imported",
+            loaded,
+            segment.file
+        );
+    }
+    let mut lines = BufReader::new(entry).lines().skip(loaded as usize);
+    let mut read = loaded;
+    loop {
+        let page = lines
+            .by_ref()
+            .take(options.page_size.max(1) as usize)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        if page.is_empty() {
+            break;
+        }
+        read += page.len() as u64;
+        match segment.kind {
+            SegmentKind::Documents => {
+                let documents = page
+                    .iter()
+                    .map(|line| serde_json::from_str(line))
+                    .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+                store
+                    .ingest_documents(&manifest.repo_name, documents)
+                    .await?;
+            }
+            SegmentKind::Chunks | SegmentKind::Symbols => {
+                let points = page
+                    .iter()
+                    .map(|line| Ok(serde_json::from_str::<SnapshotPoint>(line)?.into()))
+                    .collect::<anyhow::Result<Vec<PointStruct>>>()?;
+                store.upsert(target, points).await?;
+            }
+        }
+        loaded = read;
+        checkpoint.loaded.insert(segment.file.clone(), loaded);
+        checkpoint.save(&options.checkpoint_path)?;
+        log::info!(
+            "Imported {}/{} lines of {} into {}",
+            loaded,
+            segment.count,
+            segment.file,
+            target
+        );
+    }
+    if loaded != segment.count {
+        return Err(anyhow!(
+            "{} has {} lines but the manifest lists {}, the archive is truncated",
+            segment.file,
+            loaded,
+            segment.count
+        ));
+    }
+    Ok(loaded)
+}
+
+impl TryFrom<RetrievedPoint> for SnapshotPoint {
+    type Error = anyhow::Error;
+
+    fn try_from(point: RetrievedPoint) -> anyhow::Result<Self> {
+        let id = point
+            .id
+            .clone()
+            .ok_or_else(|| anyhow!("A point has no id"))?;
+        let vector = match point.vectors.and_then(|vectors| vectors.vectors_options) {
+            Some(VectorsOptions::Vector(vector)) => vector.data,
+            _ => return Err(anyhow!("Point {:?} has no single vector", point.id)),
+        };
+        Ok(Self {
+            id: Offset::from(id),
+            vector,
+            payload: point
+                .payload
+                .into_iter()
+                .map(|(key, value)| (key, json_value(value)))
+                .collect(),
+        })
+    }
+}
+
+impl From<SnapshotPoint> for PointStruct {
+    fn from(point: SnapshotPoint) -> Self {
+        PointStruct {
+            id: Some(PointId::from(point.id)),
+            vectors: Some(point.vector.into()),
+            payload: point
+                .payload
+                .into_iter()
+                .map(|(key, value)| (key, qdrant_value(value)))
+                .collect(),
+        }
+    }
+}
+
+fn json_value(value: Value) -> serde_json::Value {
+    match value.kind {
+        Some(Kind::BoolValue(v)) => serde_json::Value::Bool(v),
+        Some(Kind::IntegerValue(v)) => serde_json::Value::from(v),
+        Some(Kind::DoubleValue(v)) => serde_json::Value::from(v),
+        Some(Kind::StringValue(v)) => serde_json::Value::String(v),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.into_iter().map(json_value).collect())
+        }
+        Some(Kind::StructValue(object)) => serde_json::Value::Object(
+            object
+                .fields
+                .into_iter()
+                .map(|(key, value)| (key, json_value(value)))
+                .collect(),
+        ),
+        Some(Kind::NullValue(_)) | None => serde_json::Value::Null,
+    }
+}
+
+fn qdrant_value(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(v) => Kind::BoolValue(v),
+        serde_json::Value::Number(v) => match v.as_i64() {
+            Some(v) => Kind::IntegerValue(v),
+            None => Kind::DoubleValue(v.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(v) => Kind::StringValue(v),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.into_iter().map(qdrant_value).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(Struct {
+            fields: fields
+                .into_iter()
+                .map(|(key, value)| (key, qdrant_value(value)))
+                .collect(),
+        }),
+    };
+    Value { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    type Points = BTreeMap<u64, (HashMap<String, Value>, Vec<f32>)>;
+
+    #[derive(Default)]
+    struct MockStore {
+        // collection -> id -> (payload, vector), ids are scrolled in order.
+        collections: Mutex<HashMap<String, Points>>,
+        aliases: Mutex<HashMap<String, String>>,
+        // repo -> documents, in the order quickwit returns them.
+        documents: Mutex<HashMap<String, Vec<serde_json::Value>>>,
+        // fails the upsert once this many upserts went through.
+        fail_after_upserts: Mutex<Option<usize>>,
+        upserted: Mutex<usize>,
+    }
+
+    fn num(id: &Option<PointId>) -> u64 {
+        match id.clone().map(Offset::from) {
+            Some(Offset::Num(num)) => num,
+            _ => panic!("mock only uses numeric ids"),
+        }
+    }
+
+    fn in_repo(payload: &HashMap<String, Value>, repo_name: &str) -> bool {
+        payload.get("repo_name") == Some(&Value::from(repo_name))
+    }
+
+    #[async_trait]
+    impl SnapshotStore for MockStore {
+        async fn aliases(&self) -> anyhow::Result<HashMap<String, String>> {
+            Ok(self.aliases.lock().unwrap().clone())
+        }
+
+        async fn scroll_repo(
+            &self,
+            collection: &str,
+            repo_name: &str,
+            offset: Option<PointId>,
+            limit: u32,
+        ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)> {
+            let start = offset.as_ref().map(|_| num(&offset)).unwrap_or(0);
+            let collections = self.collections.lock().unwrap();
+            let mut page = collections[collection]
+                .range(start..)
+                .filter(|(_, (payload, _))| in_repo(payload, repo_name));
+            let points = page
+                .by_ref()
+                .take(limit as usize)
+                .map(|(id, (payload, vector))| RetrievedPoint {
+                    id: Some(PointId::from(*id)),
+                    payload: payload.clone(),
+                    vectors: Some(vector.clone().into()),
+                    ..Default::default()
+                })
+                .collect();
+            let next = page.next().map(|(id, _)| PointId::from(*id));
+            Ok((points, next))
+        }
+
+        async fn count_repo(&self, collection: &str, repo_name: &str) -> anyhow::Result<u64> {
+            Ok(self.collections.lock().unwrap()[collection]
+                .values()
+                .filter(|(payload, _)| in_repo(payload, repo_name))
+                .count() as u64)
+        }
+
+        async fn ensure_collection(
+            &self,
+            collection: &str,
+            _dimension: u64,
+            _indexes: &[&str],
+        ) -> anyhow::Result<()> {
+            self.collections
+                .lock()
+                .unwrap()
+                .entry(collection.to_string())
+                .or_default();
+            Ok(())
+        }
+
+        async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+            {
+                let mut fail_after = self.fail_after_upserts.lock().unwrap();
+                match fail_after.as_mut() {
+                    Some(0) => return Err(anyhow!("qdrant went away")),
+                    Some(remaining) => *remaining -= 1,
+                    None => {}
+                }
+            }
+            *self.upserted.lock().unwrap() += points.len();
+            let mut collections = self.collections.lock().unwrap();
+            let target = collections.get_mut(collection).unwrap();
+            for point in points {
+                let vector = match point.vectors.unwrap().vectors_options {
+                    Some(VectorsOptions::Vector(v)) => v.data,
+                    _ => panic!("expected a single vector"),
+                };
+                target.insert(num(&point.id), (point.payload, vector));
+            }
+            Ok(())
+        }
+
+        async fn documents(
+            &self,
+            repo_name: &str,
+            offset: u64,
+            limit: u32,
+        ) -> anyhow::Result<(u64, Vec<serde_json::Value>)> {
+            let documents = self.documents.lock().unwrap();
+            let documents = documents.get(repo_name).cloned().unwrap_or_default();
+            let page = documents
+                .iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect();
+            Ok((documents.len() as u64, page))
+        }
+
+        async fn ensure_document_index(&self, repo_name: &str) -> anyhow::Result<()> {
+            self.documents
+                .lock()
+                .unwrap()
+                .entry(repo_name.to_string())
+                .or_default();
+            Ok(())
+        }
+
+        async fn ingest_documents(
+            &self,
+            repo_name: &str,
+            documents: Vec<serde_json::Value>,
+        ) -> anyhow::Result<()> {
+            self.documents
+                .lock()
+                .unwrap()
+                .get_mut(repo_name)
+                .unwrap()
+                .extend(documents);
+            Ok(())
+        }
+    }
+
+    fn embedding(model_hash: &str) -> EmbeddingInfo {
+        EmbeddingInfo {
+            model: "model/".to_string(),
+            model_hash: model_hash.to_string(),
+            dimension: 2,
+        }
+    }
+
+    // the points of repo-a, and a few of repo-b that aren't exported.
+    fn source_store() -> MockStore {
+        let store = MockStore::default();
+        let point = |repo: &str, field: (&str, Value)| {
+            HashMap::from([
+                ("repo_name".to_string(), Value::from(repo)),
+                (field.0.to_string(), field.1),
+            ])
+        };
+        let mut chunks = Points::new();
+        for i in 0..300u64 {
+            let repo = if i % 10 == 9 { "repo-b" } else { "repo-a" };
+            let mut payload = point(
+                repo,
+                ("relative_path", Value::from(format!("src/file_{}.rs", i))),
+            );
+            payload.insert("start_line".to_string(), Value::from(i as i64 * 10));
+            payload.insert(
+                "symbols".to_string(),
+                qdrant_value(serde_json::json!([{ "name": format!("f{}", i), "score": 0.5 }])),
+            );
+            chunks.insert(i, (payload, vec![i as f32, 1.5]));
+        }
+        let symbols = (0..40u64)
+            .map(|i| {
+                (
+                    i,
+                    (
+                        point("repo-a", ("symbol", Value::from(format!("symbol_{}", i)))),
+                        vec![0.0, i as f32],
+                    ),
+                )
+            })
+            .collect();
+        store.collections.lock().unwrap().extend([
+            ("documents_v2".to_string(), chunks),
+            (COLLECTION_NAME_SYMBOLS.to_string(), symbols),
+        ]);
+        store
+            .aliases
+            .lock()
+            .unwrap()
+            .insert(COLLECTION_NAME.to_string(), "documents_v2".to_string());
+
+        let run = RunManifest {
+            repo_name: "repo-a".to_string(),
+            branch: "refs/heads/main".to_string(),
+            embedding: embedding("abc"),
+            ..Default::default()
+        };
+        let mut documents = (0..20)
+            .map(|i| serde_json::json!({ "repo_name": "repo-a", "relative_path": format!("src/file_{}.rs", i), "line_end_indices": [3, 0, 0, 0] }))
+            .collect::<Vec<_>>();
+        documents.push(serde_json::json!({
+            "repo_name": "repo-a",
+            "relative_path": RUN_MANIFEST_PATH,
+            "content": serde_json::to_string(&run).unwrap(),
+        }));
+        store
+            .documents
+            .lock()
+            .unwrap()
+            .insert("repo-a".to_string(), documents);
+        store
+    }
+
+    fn temp_path(name: &str, extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.{}", name, uuid::Uuid::new_v4(), extension))
+    }
+
+    async fn export(store: &MockStore, name: &str) -> (PathBuf, SnapshotManifest) {
+        let archive = temp_path(name, "tar");
+        let manifest = export_index(
+            store,
+            &ExportOptions {
+                repo_name: "repo-a".to_string(),
+                archive: archive.clone(),
+                page_size: 64,
+                embedding: embedding("local"),
+            },
+        )
+        .await
+        .unwrap();
+        (archive, manifest)
+    }
+
+    fn import_options(archive: &Path, model_hash: &str) -> ImportOptions {
+        ImportOptions {
+            archive: archive.to_path_buf(),
+            checkpoint_path: temp_path("import-checkpoint", "json"),
+            page_size: 64,
+            embedding: embedding(model_hash),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_keep_the_points_and_documents() {
+        let source = source_store();
+        let (archive, manifest) = export(&source, "snapshot-round-trip").await;
+
+        let counts = manifest
+            .segments
+            .iter()
+            .map(|segment| segment.count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![270, 40, 21]);
+        // the model of the run that indexed the repo, not the one of the exporting environment.
+        assert_eq!(manifest.embedding, embedding("abc"));
+        assert_eq!(manifest.runs[0].branch, "refs/heads/main");
+
+        let target = MockStore::default();
+        let reports = import_index(&target, &import_options(&archive, "abc"))
+            .await
+            .unwrap();
+
+        // no alias in the target, the collections are created under the alias names.
+        assert_eq!(reports[0].target, COLLECTION_NAME);
+        assert_eq!(
+            reports
+                .iter()
+                .map(|report| report.imported)
+                .collect::<Vec<_>>(),
+            counts
+        );
+        let imported = target.collections.lock().unwrap();
+        let source_chunks = &source.collections.lock().unwrap()["documents_v2"];
+        for id in [0, 42, 298] {
+            assert_eq!(imported[COLLECTION_NAME][&id], source_chunks[&id]);
+        }
+        assert!(!imported[COLLECTION_NAME].contains_key(&9));
+        assert_eq!(imported[COLLECTION_NAME_SYMBOLS].len(), 40);
+        assert_eq!(
+            target.documents.lock().unwrap()["repo-a"],
+            source.documents.lock().unwrap()["repo-a"]
+        );
+        std::fs::remove_file(archive).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_resumes_from_checkpoint() {
+        let (archive, _) = export(&source_store(), "snapshot-resume").await;
+        let target = MockStore::default();
+        *target.fail_after_upserts.lock().unwrap() = Some(2);
+        let options = import_options(&archive, "abc");
+
+        assert!(import_index(&target, &options).await.is_err());
+        let checkpoint = ImportCheckpoint::load(&options.checkpoint_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.loaded["chunks.jsonl"], 128);
+
+        *target.fail_after_upserts.lock().unwrap() = None;
+        import_index(&target, &options).await.unwrap();
+        // the pages loaded before the failure aren't loaded again.
+        assert_eq!(*target.upserted.lock().unwrap(), 270 + 40);
+        assert_eq!(
+            target.collections.lock().unwrap()[COLLECTION_NAME].len(),
+            270
+        );
+        assert!(!options.checkpoint_path.exists());
+        std::fs::remove_file(archive).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_of_another_model_fails_before_loading() {
+        let (archive, manifest) = export(&source_store(), "snapshot-model").await;
+        let target = MockStore::default();
+
+        let error = import_index(&target, &import_options(&archive, "def"))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("migrate-embeddings"), "{}", error);
+        assert!(target.collections.lock().unwrap().is_empty());
+
+        let other_dimension = EmbeddingInfo {
+            dimension: 384,
+            ..embedding("abc")
+        };
+        assert!(validate_manifest(&manifest, &other_dimension).is_err());
+        let other_version = SnapshotManifest {
+            schema_version: SNAPSHOT_SCHEMA_VERSION + 1,
+            ..manifest.clone()
+        };
+        assert!(validate_manifest(&other_version, &embedding("abc")).is_err());
+        std::fs::remove_file(archive).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Mines the terms of the repo from its README and docs while its tree is walked, see
+// `common::terminology`. The suggestions are indexed as a document of their own, like the summary,
+// and only expand the searches once an admin approves them into the dictionary of the repo.
+
+use std::path::PathBuf;
+
+use common::terminology::{
+    is_terminology_source, mine_suggestions, TermSuggestion, TERMINOLOGY_SUGGESTIONS_LANGUAGE,
+    TERMINOLOGY_SUGGESTIONS_PATH,
+};
+
+use crate::ast::symbol::SymbolLocations;
+use crate::config::get_tenant_id;
+use crate::encoding;
+use crate::hash::compute_hashes;
+use crate::FileFields;
+
+// Suggestions kept per repo, a glossary page shouldn't flood the admin.
+const MAX_TERM_SUGGESTIONS: usize = 200;
+
+#[derive(Default)]
+pub struct TerminologyMiner {
+    suggestions: Vec<TermSuggestion>,
+}
+
+impl TerminologyMiner {
+    /// Mines the file when it is a README or a markdown file under `docs/`, the first mention of
+    /// a term is kept.
+    pub fn add_file(&mut self, path: &str, content: &[u8]) {
+        if !is_terminology_source(path) {
+            return;
+        }
+        let Ok(decoded) = encoding::decode(content) else {
+            return;
+        };
+        let content = decoded.text.as_ref();
+        for suggestion in mine_suggestions(path, content) {
+            if self.suggestions.len() == MAX_TERM_SUGGESTIONS {
+                return;
+            }
+            let known = self.suggestions.iter().any(|other| {
+                other.term.eq_ignore_ascii_case(&suggestion.term)
+                    && other.expansion.eq_ignore_ascii_case(&suggestion.expansion)
+            });
+            if !known {
+                self.suggestions.push(suggestion);
+            }
+        }
+    }
+
+    /// The quickwit document of the suggestions, with the suggestions as JSON content.
+    pub fn suggestions_fields(&self, repo_name: &str, repo_path: &str, commit: &str) -> FileFields {
+        let content = serde_json::to_string(&self.suggestions)
+            .expect("Failed to serialize the terminology suggestions");
+        let line_end_indices = content
+            .match_indices('\n')
+            .flat_map(|(i, _)| u32::to_le_bytes(i as u32))
+            .collect::<Vec<_>>();
+        let (_, tantivy_hash) = compute_hashes(
+            PathBuf::from(TERMINOLOGY_SUGGESTIONS_PATH),
+            &content,
+            "main",
+        );
+
+        FileFields {
+            tenant_id: get_tenant_id(),
+            repo_name: repo_name.to_string(),
+            repo_disk_path: repo_path.to_string(),
+            repo_ref: String::new(),
+            relative_path: TERMINOLOGY_SUGGESTIONS_PATH.to_string(),
+            last_commit: commit.to_string(),
+            lang: TERMINOLOGY_SUGGESTIONS_LANGUAGE.to_string(),
+            is_directory: false,
+            avg_line_length: content.len() as f64,
+            line_end_indices,
+            content,
+            symbol_locations: bincode::serialize(&SymbolLocations::Empty).unwrap(),
+            unique_hash: tantivy_hash,
+            symbols: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggestions_land_in_the_metadata_document() {
+        let mut miner = TerminologyMiner::default();
+        miner.add_file(
+            "README.md",
+            b"Helios (also known as the billing engine) issues the invoices.\n",
+        );
+        // mentioned again in the docs, kept once.
+        miner.add_file(
+            "docs/billing.md",
+            b"Helios (aka the billing engine) retries.\nWF (aka workflow) owners.\n",
+        );
+        // not a doc, not mined.
+        miner.add_file("src/lib.rs", b"// Foo (aka bar)\n");
+
+        let fields = miner.suggestions_fields("acme/shop", "/repos/shop", "abc123");
+        assert_eq!(fields.relative_path, TERMINOLOGY_SUGGESTIONS_PATH);
+        assert_eq!(fields.lang, TERMINOLOGY_SUGGESTIONS_LANGUAGE);
+        assert_eq!(fields.last_commit, "abc123");
+        let stored: Vec<TermSuggestion> = serde_json::from_str(&fields.content).unwrap();
+        assert_eq!(
+            stored
+                .iter()
+                .map(|s| (s.term.as_str(), s.expansion.as_str(), s.path.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Helios", "billing engine", "README.md"),
+                ("WF", "workflow", "docs/billing.md"),
+            ]
+        );
+    }
+}
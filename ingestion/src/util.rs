@@ -4,8 +4,14 @@ use std::{
     path::Path,
 };
 
-// Detects the language of the given file.
+use crate::semantic_index::config_chunking::config_language;
+
+// Detects the language of the given file, config files by their extension so they are chunked
+// on their keys whatever the detection makes of them.
 pub fn detect_language(path: &Path, buf: &[u8]) -> Option<&'static str> {
+    if let Some(language) = config_language(path) {
+        return Some(language);
+    }
     detect_buffer(path, |_| Ok(Cursor::new(buf)))
         .ok()
         .flatten()
@@ -0,0 +1,639 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::branch::{branch_name, BRANCH_FIELD};
+use common::run_manifest::IndexMode;
+use common::{codeowners, metrics, shutdown};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use qdrant_client::qdrant::{
+    points_selector::PointsSelectorOneOf, r#match::MatchValue, FieldCondition, Filter, Match,
+    PointsSelector,
+};
+
+use crate::ast::symbol::{SymbolKey, SymbolValue};
+use crate::config::{get_size_limits, get_symbol_occurrence_limit, get_symbol_stop_list};
+use crate::error::{boxed, IngestionError, Result};
+use crate::index_filter::index_filter;
+use crate::index_processor;
+use crate::semantic_index::{replace_file_symbol_points, ChunkedFile, FileSymbols, SemanticIndex};
+use crate::{
+    codeowners_fields, process_file_content, write_metrics_textfile, FileFields, Repository, SkipReason,
+    COLLECTION_NAME,
+};
+
+// Default window used to coalesce rapid saves of the same files into one re-index.
+pub const DEFAULT_DEBOUNCE_MS: u64 = 500;
+
+/// Relative paths that changed since the last sync, coalesced until the debounce window passes.
+#[derive(Debug, Default)]
+pub struct PendingChanges {
+    paths: HashSet<String>,
+    last_event: Option<Instant>,
+}
+
+impl PendingChanges {
+    pub fn push(&mut self, relative_path: String, now: Instant) {
+        self.paths.insert(relative_path);
+        self.last_event = Some(now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    // The batch is ready once no new change arrived for the whole debounce window.
+    pub fn is_ready(&self, now: Instant, debounce: Duration) -> bool {
+        match self.last_event {
+            Some(last_event) => !self.is_empty() && now.duration_since(last_event) >= debounce,
+            None => false,
+        }
+    }
+
+    pub fn drain(&mut self) -> Vec<String> {
+        self.last_event = None;
+        let mut paths = self.paths.drain().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+}
+
+// Exact match filter
+pub(crate) fn make_kv_keyword_filter(key: &str, value: &str) -> FieldCondition {
+    FieldCondition {
+        key: key.to_owned(),
+        r#match: Some(Match {
+            match_value: MatchValue::Keyword(value.to_owned()).into(),
+        }),
+        ..Default::default()
+    }
+}
+
+// Matches the points of a file of the branch of the repo, the other branches keep theirs.
+pub fn file_points_filter(repo_name: &str, branch: &str, relative_path: &str) -> Filter {
+    Filter {
+        must: vec![
+            make_kv_keyword_filter("repo_name", repo_name).into(),
+            make_kv_keyword_filter(BRANCH_FIELD, branch_name(branch)).into(),
+            make_kv_keyword_filter("relative_path", relative_path).into(),
+        ],
+        ..Default::default()
+    }
+}
+
+// Selects the points of a file of the branch of the repo, see `file_points_filter`.
+pub fn file_points_selector(repo_name: &str, branch: &str, relative_path: &str) -> PointsSelector {
+    PointsSelector {
+        points_selector_one_of: Some(PointsSelectorOneOf::Filter(file_points_filter(
+            repo_name,
+            branch,
+            relative_path,
+        ))),
+    }
+}
+
+/// Selects every point of the branch of the repo, the points of its other branches stay.
+pub fn branch_points_selector(repo_name: &str, branch: &str) -> PointsSelector {
+    PointsSelector {
+        points_selector_one_of: Some(PointsSelectorOneOf::Filter(Filter {
+            must: vec![
+                make_kv_keyword_filter("repo_name", repo_name).into(),
+                make_kv_keyword_filter(BRANCH_FIELD, branch_name(branch)).into(),
+            ],
+            ..Default::default()
+        })),
+    }
+}
+
+impl Repository {
+    // Maps a path reported by the watcher to the repo relative path used in the indexes.
+    // Returns None for directories, git-ignored and filtered paths.
+    fn relative_watch_path(&self, path: &Path) -> Option<String> {
+        let relative_path = path.strip_prefix(&self.disk_path).ok()?;
+        if path.is_dir() || !index_filter(&relative_path) {
+            return None;
+        }
+        if self.git_repo.is_path_ignored(relative_path).unwrap_or(false) {
+            return None;
+        }
+        relative_path.to_str().map(ToOwned::to_owned)
+    }
+
+    // Deletes the chunk points of the given path, its symbols are replaced by `replace_file_symbols`.
+    async fn delete_file_chunks(&self, relative_path: &str) -> Result<()> {
+        if let Some(client) = &self.qdrant_client_code_chunk {
+            let selector = file_points_selector(&self.repo_name, &self.branch, relative_path);
+            client
+                .delete_points(COLLECTION_NAME, &selector, None)
+                .await
+                .map_err(IngestionError::QdrantCommit)?;
+        }
+        Ok(())
+    }
+
+    // Replaces the occurrences of the path in the symbol points with the given symbols.
+    // Only the symbols without a point yet are embedded, a removed file comes without a model.
+    async fn replace_file_symbols(
+        &self,
+        relative_path: &str,
+        symbols: &HashMap<SymbolKey, Vec<SymbolValue>>,
+        index: Option<&SemanticIndex>,
+    ) -> Result<()> {
+        if self.qdrant_client_symbol.is_none() {
+            return Ok(());
+        }
+        replace_file_symbol_points(
+            &self.qdrant_client_symbol,
+            &self.repo_name,
+            &self.branch,
+            FileSymbols {
+                relative_path,
+                symbols,
+            },
+            get_symbol_occurrence_limit(),
+            &get_symbol_stop_list(),
+            |symbol| match index {
+                Some(index) => index.embed(symbol),
+                None => Err(anyhow::anyhow!("no model to embed the symbol {}", symbol)),
+            },
+        )
+        .await
+        .map_err(|e| IngestionError::QdrantCommit(boxed(e)))
+    }
+
+    /// Re-indexes a single file from the working tree.
+    /// Stale chunks, the occurrences of the path in the symbol points and the quickwit document
+    /// for the path are always removed, and if the file still exists and passes the language
+    /// checks it's chunked, embedded and indexed again. A paths-only repo only gets its document back.
+    pub async fn reindex_file(&self, relative_path: &str) -> Result<()> {
+        log::debug!("Re-indexing {}", relative_path);
+        self.delete_file_chunks(relative_path).await?;
+        index_processor::delete_file_document(&self.repo_name, &self.branch, relative_path)
+            .await
+            .map_err(IngestionError::QuickwitCommit)?;
+
+        let full_path = self.disk_path.join(relative_path);
+        // the file was deleted, removing it from the indexes is all there is to do.
+        if !full_path.is_file() {
+            return self
+                .replace_file_symbols(relative_path, &HashMap::new(), None)
+                .await;
+        }
+
+        let content = std::fs::read(&full_path)?;
+        let repo_path = self.disk_path.to_string_lossy().to_string();
+        if codeowners::is_codeowners_path(relative_path) {
+            let fields = codeowners_fields(relative_path, &content, &self.repo_name, &repo_path)
+                .map(|fields| FileFields {
+                    repo_ref: branch_name(&self.branch).to_string(),
+                    ..fields
+                });
+            index_processor::ingest_entries(fields, &self.repo_name).await;
+            return Ok(());
+        }
+        let processed = match process_file_content(
+            relative_path,
+            &content,
+            &self.repo_name,
+            &repo_path,
+            &self.disk_path,
+            &get_size_limits(),
+            self.index_mode,
+        ) {
+            Ok(processed) => processed,
+            Err(SkipReason::Size(skipped)) => {
+                log::warn!("Not re-indexing file over the size limits: {}", skipped);
+                return self
+                    .replace_file_symbols(relative_path, &HashMap::new(), None)
+                    .await;
+            }
+            Err(SkipReason::Undecodable(_)) | Err(SkipReason::Unsupported) => {
+                return self
+                    .replace_file_symbols(relative_path, &HashMap::new(), None)
+                    .await;
+            }
+            Err(SkipReason::Failed(e)) => return Err(IngestionError::per_file(relative_path, e)),
+        };
+
+        // a paths-only repo has no embeddings, only its document is written again.
+        let mut semantic_index = None;
+        let mut symbols = HashMap::new();
+        if self.index_mode.is_full() {
+            let mut index = SemanticIndex::new(&0).map_err(IngestionError::Embedding)?;
+            let payload = &processed.semantic_payload;
+            let dropped = index
+                .tokenize_and_commit(
+                    &payload.buffer,
+                    ChunkedFile {
+                        repo_name: &self.repo_name,
+                        relative_path: &payload.path,
+                        branch: &self.branch,
+                        semantic_hash: &payload.semantic_hash,
+                        lang: &payload.language,
+                        key_paths: &[],
+                        doc_comments: &payload.doc_comments,
+                        // the file was just changed on disk, ahead of any commit.
+                        last_modified: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .ok()
+                            .map(|since| since.as_secs()),
+                    },
+                    &self.qdrant_client_code_chunk,
+                )
+                .await
+                .map_err(|e| IngestionError::QdrantCommit(boxed(e)))?;
+            if dropped > 0 {
+                log::info!("Dropped {} low quality chunks of {}", dropped, relative_path);
+            }
+            semantic_index = Some(index);
+            symbols = symbols_by_key(processed.symbol_metas);
+        }
+        self.replace_file_symbols(relative_path, &symbols, semantic_index.as_ref())
+            .await?;
+
+        let fields = FileFields {
+            repo_ref: branch_name(&self.branch).to_string(),
+            ..processed.file_fields
+        };
+        index_processor::ingest_entries([fields], &self.repo_name).await;
+        Ok(())
+    }
+}
+
+// The symbols of a processed file grouped by name, one symbol point each.
+fn symbols_by_key(
+    symbol_metas: Vec<(SymbolKey, SymbolValue)>,
+) -> HashMap<SymbolKey, Vec<SymbolValue>> {
+    symbol_metas.into_iter().fold(
+        HashMap::<SymbolKey, Vec<SymbolValue>>::new(),
+        |mut meta_map, (meta_key, meta_value)| {
+            meta_map.entry(meta_key).or_default().push(meta_value);
+            meta_map
+        },
+    )
+}
+
+// The watcher fails on the files it watches, or when the os runs out of watches.
+fn watch_error(error: notify::Error) -> IngestionError {
+    match error.kind {
+        notify::ErrorKind::Io(e) => IngestionError::Io(e),
+        kind => IngestionError::Io(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{:?}", kind),
+        )),
+    }
+}
+
+fn print_status(last_sync: Option<Instant>, pending: usize) {
+    let last_sync = match last_sync {
+        Some(last_sync) => format!("{}s ago", last_sync.elapsed().as_secs()),
+        None => "never".to_string(),
+    };
+    print!("\r[watch] last sync: {} | pending: {}    ", last_sync, pending);
+    let _ = std::io::stdout().flush();
+}
+
+/// Watches the repository working tree and re-indexes changed files in debounced batches.
+/// Runs until the watcher stops or a shutdown signal is received.
+pub async fn watch_repository(
+    repo: &Repository,
+    debounce: Duration,
+    metrics_textfile: Option<&Path>,
+) -> Result<()> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) => {
+            let _ = tx.send(event);
+        }
+        Err(e) => log::error!("Watch error: {:?}", e),
+    })
+    .map_err(watch_error)?;
+    watcher
+        .watch(&repo.disk_path, RecursiveMode::Recursive)
+        .map_err(watch_error)?;
+    log::info!("Watching {:?} for changes", repo.disk_path);
+
+    let mut pending = PendingChanges::default();
+    let mut last_sync: Option<Instant> = None;
+    let shutdown = shutdown::global();
+    shutdown.listen_for_signals();
+
+    loop {
+        // finish the batch that is being re-indexed, then stop on SIGINT/SIGTERM.
+        // changes that were still pending are picked up by the next full index.
+        if shutdown.is_triggered() {
+            println!();
+            log::info!(
+                "Stopping watch, {} pending changes were not re-indexed",
+                pending.len()
+            );
+            write_metrics_textfile(metrics_textfile);
+            break;
+        }
+
+        match tokio::time::timeout(debounce, rx.recv()).await {
+            Ok(Some(event)) => {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    for path in event.paths {
+                        if let Some(relative_path) = repo.relative_watch_path(&path) {
+                            pending.push(relative_path, Instant::now());
+                        }
+                    }
+                }
+            }
+            // the watcher was dropped, nothing more will arrive.
+            Ok(None) => break,
+            // no events within the debounce window.
+            Err(_) => {}
+        }
+
+        if pending.is_ready(Instant::now(), debounce) {
+            for relative_path in pending.drain() {
+                if let Err(e) = repo.reindex_file(&relative_path).await {
+                    log::error!("Failed to re-index {}: {}", relative_path, e);
+                    metrics::record_ingestion_failure("reindex");
+                }
+            }
+            last_sync = Some(Instant::now());
+            write_metrics_textfile(metrics_textfile);
+        }
+        print_status(last_sync, pending.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semantic_index::{PointSink, PointStore};
+    use qdrant_client::qdrant::{
+        condition::ConditionOneOf, value::Kind, PointStruct, RetrievedPoint,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    // The symbol collection in memory, filtered on the keyword fields like qdrant.
+    #[derive(Default)]
+    struct MemoryStore {
+        points: Mutex<HashMap<String, PointStruct>>,
+    }
+
+    fn matches(point: &PointStruct, filter: &Filter) -> bool {
+        filter.must.iter().all(|condition| {
+            let Some(ConditionOneOf::Field(field)) = &condition.condition_one_of else {
+                return false;
+            };
+            let Some(MatchValue::Keyword(keyword)) =
+                field.r#match.as_ref().and_then(|m| m.match_value.clone())
+            else {
+                return false;
+            };
+            match point
+                .payload
+                .get(&field.key)
+                .and_then(|value| value.kind.as_ref())
+            {
+                Some(Kind::StringValue(value)) => value == &keyword,
+                Some(Kind::ListValue(list)) => list.values.iter().any(
+                    |value| matches!(&value.kind, Some(Kind::StringValue(v)) if v == &keyword),
+                ),
+                _ => false,
+            }
+        })
+    }
+
+    #[async_trait::async_trait]
+    impl PointSink for MemoryStore {
+        async fn upsert_points(
+            &self,
+            _collection: &str,
+            points: Vec<PointStruct>,
+        ) -> anyhow::Result<()> {
+            let mut stored = self.points.lock().unwrap();
+            for point in points {
+                stored.insert(format!("{:?}", point.id), point);
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl PointStore for MemoryStore {
+        async fn scroll_matching(
+            &self,
+            _collection: &str,
+            filter: Filter,
+        ) -> anyhow::Result<Vec<RetrievedPoint>> {
+            Ok(self
+                .points
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|point| matches(point, &filter))
+                .map(|point| RetrievedPoint {
+                    id: point.id.clone(),
+                    payload: point.payload.clone(),
+                    vectors: point.vectors.clone(),
+                    ..Default::default()
+                })
+                .collect())
+        }
+
+        async fn delete_matching(
+            &self,
+            _collection: &str,
+            selector: PointsSelector,
+        ) -> anyhow::Result<()> {
+            let Some(PointsSelectorOneOf::Points(ids)) = selector.points_selector_one_of else {
+                anyhow::bail!("only deletes by id are expected");
+            };
+            let mut stored = self.points.lock().unwrap();
+            for id in ids.ids {
+                stored.remove(&format!("{:?}", Some(id)));
+            }
+            Ok(())
+        }
+    }
+
+    impl MemoryStore {
+        // the paths of the occurrences of the symbol, none once its point was deleted.
+        fn paths(&self, symbol: &str) -> Option<Vec<String>> {
+            let id = crate::hash::symbol_point_id("repo", "refs/heads/main", symbol);
+            let points = self.points.lock().unwrap();
+            let point = points.get(&format!(
+                "{:?}",
+                Some(qdrant_client::qdrant::PointId::from(id.to_string()))
+            ))?;
+            let Some(Kind::ListValue(paths)) = &point.payload["relative_path"].kind else {
+                panic!("relative_path is a column of the occurrences");
+            };
+            let mut paths = paths
+                .values
+                .iter()
+                .filter_map(|value| match &value.kind {
+                    Some(Kind::StringValue(path)) => Some(path.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            paths.sort();
+            Some(paths)
+        }
+    }
+
+    // Writes the file to the repo, or removes it when there is no content, and replaces its
+    // symbols like `reindex_file` does.
+    async fn reindex(
+        store: &Option<MemoryStore>,
+        disk_path: &Path,
+        relative_path: &str,
+        content: Option<&str>,
+        embedded: &AtomicUsize,
+    ) {
+        let full_path = disk_path.join(relative_path);
+        let symbols = match content {
+            Some(content) => {
+                std::fs::write(&full_path, content).unwrap();
+                let processed = process_file_content(
+                    relative_path,
+                    &std::fs::read(&full_path).unwrap(),
+                    "repo",
+                    &disk_path.to_string_lossy(),
+                    disk_path,
+                    &crate::size_limits::SizeLimits::default(),
+                    IndexMode::Full,
+                )
+                .unwrap_or_else(|_| panic!("{} is indexed", relative_path));
+                symbols_by_key(processed.symbol_metas)
+            }
+            None => {
+                std::fs::remove_file(&full_path).unwrap();
+                HashMap::new()
+            }
+        };
+        replace_file_symbol_points(
+            store,
+            "repo",
+            "refs/heads/main",
+            FileSymbols {
+                relative_path,
+                symbols: &symbols,
+            },
+            100,
+            &[],
+            |symbol| {
+                embedded.fetch_add(1, Ordering::SeqCst);
+                Ok(vec![symbol.len() as f32; 4])
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reindex_file_replaces_only_its_symbol_occurrences() {
+        let disk_path = std::env::temp_dir().join(format!("repo-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(disk_path.join("src")).unwrap();
+        let store = Some(MemoryStore::default());
+        let embedded = AtomicUsize::new(0);
+        let store_ref = store.as_ref().unwrap();
+
+        reindex(
+            &store,
+            &disk_path,
+            "src/a.rs",
+            Some("fn shared() {}\nfn gone() {}\n"),
+            &embedded,
+        )
+        .await;
+        reindex(
+            &store,
+            &disk_path,
+            "src/b.rs",
+            Some("fn shared() {}\n"),
+            &embedded,
+        )
+        .await;
+        assert_eq!(embedded.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            store_ref.paths("shared").unwrap(),
+            vec!["src/a.rs", "src/b.rs"]
+        );
+        assert_eq!(store_ref.paths("gone").unwrap(), vec!["src/a.rs"]);
+
+        // the symbols of the other file stay, the one only the file had is deleted.
+        reindex(
+            &store,
+            &disk_path,
+            "src/a.rs",
+            Some("fn shared() {}\nfn added() {}\n"),
+            &embedded,
+        )
+        .await;
+        assert_eq!(
+            store_ref.paths("shared").unwrap(),
+            vec!["src/a.rs", "src/b.rs"]
+        );
+        assert_eq!(store_ref.paths("gone"), None);
+        assert_eq!(store_ref.paths("added").unwrap(), vec!["src/a.rs"]);
+        // only the new symbol is embedded, the others keep their vectors.
+        assert_eq!(embedded.load(Ordering::SeqCst), 3);
+
+        reindex(&store, &disk_path, "src/a.rs", None, &embedded).await;
+        assert_eq!(store_ref.paths("shared").unwrap(), vec!["src/b.rs"]);
+        assert_eq!(store_ref.paths("added"), None);
+        assert_eq!(embedded.load(Ordering::SeqCst), 3);
+        std::fs::remove_dir_all(&disk_path).unwrap();
+    }
+
+    #[test]
+    fn test_pending_changes_coalesce_and_debounce() {
+        let debounce = Duration::from_millis(DEFAULT_DEBOUNCE_MS);
+        let start = Instant::now();
+        let mut pending = PendingChanges::default();
+        assert!(!pending.is_ready(start, debounce));
+
+        // rapid saves of the same file are coalesced.
+        pending.push("src/main.rs".to_string(), start);
+        pending.push(
+            "src/main.rs".to_string(),
+            start + Duration::from_millis(100),
+        );
+        pending.push("src/lib.rs".to_string(), start + Duration::from_millis(200));
+        assert_eq!(pending.len(), 2);
+
+        // every new change restarts the debounce window.
+        assert!(!pending.is_ready(start + Duration::from_millis(600), debounce));
+        assert!(pending.is_ready(start + Duration::from_millis(700), debounce));
+
+        assert_eq!(pending.drain(), vec!["src/lib.rs", "src/main.rs"]);
+        assert!(pending.is_empty());
+        assert!(!pending.is_ready(start + Duration::from_secs(5), debounce));
+    }
+
+    #[test]
+    fn test_oversized_files_are_not_processed() {
+        let limits = crate::size_limits::SizeLimits::default();
+        let content = vec![b'a'; limits.max_file_bytes as usize + 1];
+        assert!(matches!(
+            process_file_content(
+                "src/huge.rs",
+                &content,
+                "repo",
+                "/tmp/repo",
+                Path::new("/tmp/repo"),
+                &limits,
+                IndexMode::Full,
+            ),
+            Err(SkipReason::Size(_))
+        ));
+    }
+}